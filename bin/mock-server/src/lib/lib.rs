@@ -53,6 +53,7 @@ impl InstanceContext {
                 gen: 0,
                 state: api::InstanceState::Creating,
                 migration: None,
+                guest_panic: None,
             });
         let serial = serial::Serial::new(&properties.name);
 
@@ -100,6 +101,7 @@ impl InstanceContext {
                             gen: self.generation,
                             state: self.state,
                             migration: None,
+                            guest_panic: None,
                         })
                         .map_err(|_| Error::TransitionSendFail)
                 }
@@ -228,6 +230,7 @@ async fn instance_get(
         state: instance.state,
         disks: vec![],
         nics: vec![],
+        bootrom: None,
     };
     Ok(HttpResponseOk(api::InstanceGetResponse { instance: instance_info }))
 }
@@ -259,6 +262,7 @@ async fn instance_state_monitor(
                 gen: last.gen,
                 state: last.state,
                 migration: None,
+                guest_panic: None,
             };
             return Ok(HttpResponseOk(response));
         }
@@ -7,6 +7,7 @@ use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::{
     net::{IpAddr, SocketAddr, ToSocketAddrs},
+    num::NonZeroU64,
     os::unix::prelude::AsRawFd,
     time::Duration,
 };
@@ -27,7 +28,7 @@ use propolis_client::{
     types::{
         DiskRequest, InstanceEnsureRequest, InstanceMigrateInitiateRequest,
         InstanceProperties, InstanceStateRequested, InstanceVcrReplace,
-        MigrationState,
+        MigrationCompression, MigrationState,
     },
     Client,
 };
@@ -117,6 +118,14 @@ enum Command {
         /// File with a JSON array of DiskRequest structs
         #[clap(long, action)]
         crucible_disks: Option<PathBuf>,
+
+        /// Compress guest RAM in transit ('lz4' or 'zstd')
+        #[clap(long, value_parser = parse_compression)]
+        compression: Option<MigrationCompression>,
+
+        /// Limit the rate at which the source sends data, in bytes/second
+        #[clap(long, action)]
+        bandwidth_limit: Option<NonZeroU64>,
     },
 
     /// Monitor an instance's state in real time
@@ -148,6 +157,14 @@ fn parse_state(state: &str) -> anyhow::Result<InstanceStateRequested> {
     }
 }
 
+fn parse_compression(alg: &str) -> anyhow::Result<MigrationCompression> {
+    match alg.to_lowercase().as_str() {
+        "lz4" => Ok(MigrationCompression::Lz4),
+        "zstd" => Ok(MigrationCompression::Zstd),
+        _ => Err(anyhow!("invalid compression, must be one of: 'lz4', 'zstd'")),
+    }
+}
+
 fn parse_json_file<T: serde::de::DeserializeOwned>(
     path: &Path,
 ) -> anyhow::Result<T> {
@@ -198,6 +215,7 @@ async fn new_instance(
         bootrom_id: Uuid::default(),
         memory,
         vcpus,
+        metadata: Default::default(),
     };
 
     let request = InstanceEnsureRequest {
@@ -490,6 +508,8 @@ async fn migrate_instance(
     src_addr: SocketAddr,
     dst_uuid: Uuid,
     disks: Vec<DiskRequest>,
+    compression: Option<MigrationCompression>,
+    bandwidth_limit: Option<NonZeroU64>,
 ) -> anyhow::Result<()> {
     // Grab the instance details
     let src_instance =
@@ -511,6 +531,8 @@ async fn migrate_instance(
             migration_id: Uuid::new_v4(),
             src_addr: src_addr.to_string(),
             src_uuid,
+            compression,
+            bandwidth_limit,
         }),
         cloud_init_bytes: None,
     };
@@ -650,7 +672,14 @@ async fn main() -> anyhow::Result<()> {
         Command::Serial { byte_offset } => {
             serial(addr, byte_offset, log).await?
         }
-        Command::Migrate { dst_server, dst_port, dst_uuid, crucible_disks } => {
+        Command::Migrate {
+            dst_server,
+            dst_port,
+            dst_uuid,
+            crucible_disks,
+            compression,
+            bandwidth_limit,
+        } => {
             let dst_addr = SocketAddr::new(dst_server, dst_port);
             let dst_client = Client::new(&format!("http://{dst_addr}"));
             let dst_uuid = dst_uuid.unwrap_or_else(Uuid::new_v4);
@@ -659,7 +688,16 @@ async fn main() -> anyhow::Result<()> {
             } else {
                 vec![]
             };
-            migrate_instance(client, dst_client, addr, dst_uuid, disks).await?
+            migrate_instance(
+                client,
+                dst_client,
+                addr,
+                dst_uuid,
+                disks,
+                compression,
+                bandwidth_limit,
+            )
+            .await?
         }
         Command::Monitor => monitor(addr).await?,
         Command::InjectNmi => inject_nmi(&client).await?,
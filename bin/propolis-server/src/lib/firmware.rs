@@ -0,0 +1,279 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Unpacking of compressed bootroms and firmware bundles.
+//!
+//! A bootrom may be supplied in one of three forms:
+//!
+//! - A raw ROM image, used as-is.
+//! - A gzip-compressed ROM image.
+//! - A gzip-compressed tar archive (a "bundle") containing a `metadata.json`
+//!   file, the firmware's code ROM, and optionally a template for its NVRAM
+//!   (variable store).  The metadata names the other members and gives their
+//!   SHA-256 digests, so a matched set of code and NVRAM is distributed (and
+//!   validated) as a unit.
+//!
+//! For a bundle, the NVRAM template is kept apart from the code: the code is
+//! loaded into ROM, and the template into a writable region directly below it
+//! (as in OVMF's combined `OVMF.fd` image), where the firmware may update its
+//! variables.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use serde_derive::Deserialize;
+use thiserror::Error;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Name of the bundle member describing its contents
+pub const METADATA_FILE: &str = "metadata.json";
+
+#[derive(Debug, Error)]
+pub enum FirmwareError {
+    #[error("failed to decompress firmware: {0}")]
+    Decompress(std::io::Error),
+
+    #[error("firmware exceeds maximum size of {0:#x} when unpacked")]
+    TooLarge(usize),
+
+    #[error("failed to read firmware bundle: {0}")]
+    Archive(std::io::Error),
+
+    #[error("firmware bundle has no member {0}")]
+    MissingMember(String),
+
+    #[error("invalid firmware bundle metadata: {0}")]
+    Metadata(#[from] serde_json::Error),
+
+    #[error(
+        "bundle member {file} has SHA-256 digest {actual}, expected {expected}"
+    )]
+    ChecksumMismatch { file: String, expected: String, actual: String },
+}
+
+/// A member of a firmware bundle, as described by its metadata
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemberMetadata {
+    file: String,
+    sha256: String,
+}
+
+/// Contents of a firmware bundle's `metadata.json`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BundleMetadata {
+    /// Free-form version of the firmware, reported alongside the bootrom
+    version: Option<String>,
+    code: MemberMetadata,
+    nvram: Option<MemberMetadata>,
+}
+
+/// A firmware image ready to be loaded into the bootrom region
+#[derive(Debug)]
+pub struct Firmware {
+    pub image: Vec<u8>,
+
+    /// Initial contents of the firmware's variable store, if supplied as a
+    /// bundle which includes one
+    pub nvram: Option<Vec<u8>>,
+
+    /// The firmware's version, if supplied as a bundle which specifies it
+    pub version: Option<String>,
+}
+impl Firmware {
+    /// Combined length of the image and the variable store
+    pub fn total_len(&self) -> usize {
+        self.image.len() + self.nvram.as_ref().map_or(0, Vec::len)
+    }
+}
+
+/// Unpacks the firmware in `data`, which may be a raw ROM image, a compressed
+/// image, or a compressed bundle.  No more than `max_len` bytes of firmware are
+/// unpacked.
+pub fn unpack(
+    data: Vec<u8>,
+    max_len: usize,
+) -> Result<Firmware, FirmwareError> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(Firmware { image: data, nvram: None, version: None });
+    }
+
+    let mut unpacked = Vec::new();
+    flate2::read::GzDecoder::new(&data[..])
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut unpacked)
+        .map_err(FirmwareError::Decompress)?;
+    if unpacked.len() > max_len {
+        return Err(FirmwareError::TooLarge(max_len));
+    }
+
+    let is_bundle = unpacked
+        .get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len())
+        .is_some_and(|magic| magic == TAR_MAGIC);
+    if is_bundle {
+        unpack_bundle(&unpacked)
+    } else {
+        Ok(Firmware { image: unpacked, nvram: None, version: None })
+    }
+}
+
+fn unpack_bundle(archive: &[u8]) -> Result<Firmware, FirmwareError> {
+    let mut members = BTreeMap::new();
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries().map_err(FirmwareError::Archive)? {
+        let mut entry = entry.map_err(FirmwareError::Archive)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .map_err(FirmwareError::Archive)?
+            .to_string_lossy()
+            .trim_start_matches("./")
+            .to_string();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).map_err(FirmwareError::Archive)?;
+        members.insert(name, contents);
+    }
+
+    let metadata: BundleMetadata =
+        serde_json::from_slice(members.get(METADATA_FILE).ok_or_else(
+            || FirmwareError::MissingMember(METADATA_FILE.into()),
+        )?)?;
+
+    let mut member = |meta: &MemberMetadata| {
+        let contents = members
+            .remove(&meta.file)
+            .ok_or_else(|| FirmwareError::MissingMember(meta.file.clone()))?;
+        let actual =
+            hex::encode(ring::digest::digest(&ring::digest::SHA256, &contents));
+        if !meta.sha256.eq_ignore_ascii_case(&actual) {
+            return Err(FirmwareError::ChecksumMismatch {
+                file: meta.file.clone(),
+                expected: meta.sha256.clone(),
+                actual,
+            });
+        }
+        Ok(contents)
+    };
+
+    let image = member(&metadata.code)?;
+    let nvram = metadata.nvram.as_ref().map(member).transpose()?;
+
+    Ok(Firmware { image, nvram, version: metadata.version })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    const MAX_LEN: usize = 0x10000;
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(ring::digest::digest(&ring::digest::SHA256, data))
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut enc = flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        );
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    fn bundle(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in members {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *contents).unwrap();
+        }
+        gzip(&builder.into_inner().unwrap())
+    }
+
+    #[test]
+    fn raw_image() {
+        let fw = unpack(vec![0xaa; 0x1000], MAX_LEN).unwrap();
+        assert_eq!(fw.image, vec![0xaa; 0x1000]);
+        assert_eq!(fw.nvram, None);
+        assert_eq!(fw.version, None);
+    }
+
+    #[test]
+    fn compressed_image() {
+        let fw = unpack(gzip(&[0xbb; 0x1000]), MAX_LEN).unwrap();
+        assert_eq!(fw.image, vec![0xbb; 0x1000]);
+
+        assert!(matches!(
+            unpack(gzip(&[0xbb; MAX_LEN + 1]), MAX_LEN),
+            Err(FirmwareError::TooLarge(MAX_LEN))
+        ));
+    }
+
+    #[test]
+    fn bundle_with_nvram() {
+        let code = [0xcc; 0x2000];
+        let nvram = [0xdd; 0x1000];
+        let metadata = format!(
+            r#"{{
+                "version": "edk2-stable202311",
+                "code": {{ "file": "OVMF_CODE.fd", "sha256": "{}" }},
+                "nvram": {{ "file": "OVMF_VARS.fd", "sha256": "{}" }}
+            }}"#,
+            sha256(&code),
+            sha256(&nvram)
+        );
+        let data = bundle(&[
+            (METADATA_FILE, metadata.as_bytes()),
+            ("OVMF_CODE.fd", &code),
+            ("OVMF_VARS.fd", &nvram),
+        ]);
+
+        let fw = unpack(data, MAX_LEN).unwrap();
+        assert_eq!(fw.version.as_deref(), Some("edk2-stable202311"));
+        assert_eq!(fw.total_len(), 0x3000);
+        assert_eq!(fw.image, code);
+        assert_eq!(fw.nvram.as_deref(), Some(&nvram[..]));
+    }
+
+    #[test]
+    fn bundle_checksum_mismatch() {
+        let code = [0xcc; 0x1000];
+        let metadata = format!(
+            r#"{{ "code": {{ "file": "code.fd", "sha256": "{}" }} }}"#,
+            sha256(&[0; 0x1000])
+        );
+        let data =
+            bundle(&[(METADATA_FILE, metadata.as_bytes()), ("code.fd", &code)]);
+        assert!(matches!(
+            unpack(data, MAX_LEN),
+            Err(FirmwareError::ChecksumMismatch { file, .. })
+                if file == "code.fd"
+        ));
+    }
+
+    #[test]
+    fn bundle_missing_member() {
+        let metadata = r#"{ "code": { "file": "code.fd", "sha256": "00" } }"#;
+        let data = bundle(&[(METADATA_FILE, metadata.as_bytes())]);
+        assert!(matches!(
+            unpack(data, MAX_LEN),
+            Err(FirmwareError::MissingMember(file)) if file == "code.fd"
+        ));
+
+        let data = bundle(&[("code.fd", &[0; 0x1000])]);
+        assert!(matches!(
+            unpack(data, MAX_LEN),
+            Err(FirmwareError::MissingMember(file)) if file == METADATA_FILE
+        ));
+    }
+}
@@ -2,33 +2,53 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
-use std::fs::File;
 use std::io::{Error, ErrorKind};
-use std::num::NonZeroUsize;
+use std::num::{NonZeroU32, NonZeroU8, NonZeroUsize};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use crucible_client_types::VolumeConstructionRequest;
 use oximeter::types::ProducerRegistry;
 use propolis::block;
 use propolis::chardev::{self, BlockingSource, Source};
 use propolis::common::PAGE_SIZE;
+use propolis::cpuid;
+use propolis::firmware::smbios;
+use propolis::hw::acpi;
 use propolis::hw::chipset::i440fx;
 use propolis::hw::chipset::i440fx::I440Fx;
 use propolis::hw::chipset::Chipset;
 use propolis::hw::ibmpc;
+use propolis::hw::nvram::Nvram;
 use propolis::hw::pci;
 use propolis::hw::ps2::ctrl::PS2Ctrl;
-use propolis::hw::qemu::{debug::QemuDebugPort, fwcfg, ramfb};
+use propolis::hw::qemu::{
+    bochs, debug::QemuDebugPort, fwcfg, ivshmem::PciIvShmem, pvpanic, ramfb,
+};
+use propolis::hw::smbus;
+use propolis::hw::tpm;
 use propolis::hw::uart::LpcUart;
-use propolis::hw::{nvme, virtio};
+use propolis::hw::{ahci, nvme, virtio};
 use propolis::instance::Instance;
 use propolis::inventory::{self, EntityID, Inventory};
+use propolis::leveling;
+use propolis::topology::CpuTopology;
 use propolis::vmm::{self, Builder, Machine};
-use propolis_api_types::instance_spec::{self, v0::InstanceSpecV0};
-use slog::info;
-
+use propolis_api_types::instance_spec::{
+    self,
+    components::{
+        board::{CpuProfile, CpuidVendor},
+        devices::{DiskPriority, VirtioInputKind},
+    },
+    v0::InstanceSpecV0,
+};
+use propolis_api_types::{BootromInfo, InstanceProperties};
+use slog::{info, warn};
+use strum::IntoEnumIterator;
+
+use crate::config;
+use crate::firmware::{self, Firmware};
 use crate::serial::Serial;
 use crate::server::CrucibleBackendMap;
 pub use nexus_client::Client as NexusClient;
@@ -38,59 +58,257 @@ use anyhow::Result;
 // Arbitrary ROM limit for now
 const MAX_ROM_SIZE: usize = 0x20_0000;
 
-fn open_bootrom<P: AsRef<std::path::Path>>(path: P) -> Result<(File, usize)> {
-    let fp = File::open(path.as_ref())?;
-    let len = fp.metadata()?.len();
-    if len % (PAGE_SIZE as u64) != 0 {
-        Err(Error::new(
-            ErrorKind::InvalidData,
-            format!(
-                "rom {} length {:x} not aligned to {:x}",
-                path.as_ref().to_string_lossy(),
-                len,
-                PAGE_SIZE
-            ),
-        )
-        .into())
-    } else {
-        Ok((fp, len as usize))
+/// Name of the MMIO region holding the bootrom's variable store
+const NVRAM_REGION: &str = "nvram";
+
+/// Name of the region of hot-pluggable memory managed by a virtio-mem device
+const HOTPLUG_MEM_REGION: &str = "hotmem";
+
+/// Errors which can arise while selecting a bootrom.
+#[derive(Debug, thiserror::Error)]
+pub enum BootromError {
+    #[error("failed to read bootrom {0}: {1}")]
+    Io(String, std::io::Error),
+
+    #[error("bootrom {0} length {1:#x} not aligned to {PAGE_SIZE:#x}")]
+    Unaligned(String, u64),
+
+    #[error("bootrom {0} length {1:#x} exceeds maximum of {MAX_ROM_SIZE:#x}")]
+    TooLarge(String, u64),
+
+    #[error("bootrom {path} has SHA-256 digest {actual}, expected {expected}")]
+    ChecksumMismatch { path: String, expected: String, actual: String },
+
+    #[error("failed to unpack bootrom {0}: {1}")]
+    Firmware(String, firmware::FirmwareError),
+
+    #[error(
+        "no usable bootrom: {}",
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+    )]
+    NoneUsable(Vec<BootromError>),
+
+    #[error("failed to load bootrom into guest memory: {0}")]
+    Load(#[from] std::io::Error),
+}
+
+/// Reads, validates, and (if necessary) unpacks the bootrom described by
+/// `candidate`.  The checksum applies to the file as supplied, and the length
+/// restrictions to the unpacked image.
+fn load_bootrom(
+    candidate: &config::BootromCandidate,
+) -> Result<(Firmware, String), BootromError> {
+    let path = candidate.path.to_string_lossy().into_owned();
+    let data = std::fs::read(&candidate.path)
+        .map_err(|e| BootromError::Io(path.clone(), e))?;
+
+    let actual =
+        hex::encode(ring::digest::digest(&ring::digest::SHA256, &data));
+    if let Some(expected) = &candidate.sha256 {
+        if !expected.eq_ignore_ascii_case(&actual) {
+            return Err(BootromError::ChecksumMismatch {
+                path,
+                expected: expected.clone(),
+                actual,
+            });
+        }
+    }
+
+    let fw = firmware::unpack(data, MAX_ROM_SIZE)
+        .map_err(|e| BootromError::Firmware(path.clone(), e))?;
+    // The code and variable store are each mapped as regions of their own
+    for part in std::iter::once(&fw.image).chain(fw.nvram.as_ref()) {
+        let len = part.len() as u64;
+        if len % (PAGE_SIZE as u64) != 0 {
+            return Err(BootromError::Unaligned(path, len));
+        }
+    }
+    let len = fw.total_len() as u64;
+    if len > MAX_ROM_SIZE as u64 {
+        return Err(BootromError::TooLarge(path, len));
+    }
+    Ok((fw, actual))
+}
+
+/// Regions of the guest-physical address space holding `fw`, as (start,
+/// length): its code in ROM, ending at 4GiB, and its variable store (if any)
+/// in emulated flash directly below, where the firmware would find it in a
+/// combined flash image.  Without a variable store, the ROM spans all of the
+/// space reserved for it.
+fn rom_regions(fw: &Firmware) -> ((usize, usize), Option<(usize, usize)>) {
+    const ROM_END: usize = 0x1_0000_0000;
+    match fw.nvram.as_ref() {
+        Some(nvram) => {
+            let rom_start = ROM_END - fw.image.len();
+            (
+                (rom_start, fw.image.len()),
+                Some((rom_start - nvram.len(), nvram.len())),
+            )
+        }
+        None => ((ROM_END - MAX_ROM_SIZE, MAX_ROM_SIZE), None),
     }
 }
 
 fn get_spec_guest_ram_limits(spec: &InstanceSpecV0) -> (usize, usize) {
+    guest_ram_limits(spec.devices.board.memory_mb)
+}
+
+fn guest_ram_limits(memory_mb: u64) -> (usize, usize) {
     const MB: usize = 1024 * 1024;
     const GB: usize = 1024 * 1024 * 1024;
-    let memsize = spec.devices.board.memory_mb as usize * MB;
+    let memsize = memory_mb as usize * MB;
     let lowmem = memsize.min(3 * GB);
     let highmem = memsize.saturating_sub(3 * GB);
     (lowmem, highmem)
 }
 
+/// Size, in MiB, of the hot-pluggable memory region managed by an instance's
+/// virtio-mem device, if it has one.
+pub(crate) fn hotplug_memory_mb(spec: &InstanceSpecV0) -> u64 {
+    // Instances with more than one such device are rejected when their devices
+    // are initialized.
+    spec.devices.memory_devices.values().map(|dev| dev.region_mb).sum()
+}
+
+/// Location and size of a hot-pluggable memory region of `hotplug_mb` MiB, for
+/// an instance with `highmem` bytes of memory above 4GiB.  The region follows
+/// that memory (aligned as virtio-mem requires), ahead of the space for 64-bit
+/// MMIO, so that the guest's memory remains compact.
+fn hotplug_memory_region(highmem: usize, hotplug_mb: u64) -> (usize, usize) {
+    const MB: usize = 1024 * 1024;
+    let start = (0x1_0000_0000 + highmem)
+        .next_multiple_of(virtio::mem::MEM_REGION_ALIGN);
+    (start, (hotplug_mb as usize).saturating_mul(MB))
+}
+
+/// Reads the first usable bootrom among `candidates`, which is to be loaded
+/// into a machine built for it.
+pub fn select_rom(
+    candidates: &[config::BootromCandidate],
+    log: &slog::Logger,
+) -> Result<(Firmware, BootromInfo), BootromError> {
+    let mut failures = Vec::new();
+    let mut selected = None;
+    for candidate in candidates {
+        match load_bootrom(candidate) {
+            Ok(loaded) => {
+                selected = Some((candidate, loaded));
+                break;
+            }
+            Err(e) => {
+                warn!(log, "skipping bootrom candidate"; "error" => %e);
+                failures.push(e);
+            }
+        }
+    }
+    let Some((candidate, (fw, sha256))) = selected else {
+        return Err(BootromError::NoneUsable(failures));
+    };
+
+    let path = candidate.path.to_string_lossy().into_owned();
+    info!(log, "selected bootrom";
+          "path" => &path,
+          "sha256" => &sha256,
+          "version" => ?fw.version,
+          "nvram_len" => fw.nvram.as_ref().map(Vec::len));
+    let version = fw.version.clone();
+    Ok((fw, BootromInfo { path, sha256, version }))
+}
+
+/// The variable store of a bootrom, from which that of an instance is
+/// initialized, and its place in the guest-physical address space.
+pub struct NvramTemplate {
+    pub start: usize,
+    pub data: Vec<u8>,
+}
+
+/// Loads the code of `fw` into the ROM region of `machine`, which must have
+/// been built for it.  Its variable store (if any) is returned, to be emulated
+/// by a device of the instance.
+pub fn load_rom(
+    machine: &Machine,
+    fw: &Firmware,
+) -> Result<Option<NvramTemplate>, BootromError> {
+    let mem = machine.acc_mem.access().unwrap();
+    let mapping = mem.direct_writable_region_by_name("bootrom")?;
+    let offset = mapping.len() - fw.image.len();
+    let submapping = mapping.subregion(offset, fw.image.len()).unwrap();
+    submapping.write_bytes(&fw.image)?;
+
+    let (_rom, nvram) = rom_regions(fw);
+    Ok(nvram
+        .zip(fw.nvram.clone())
+        .map(|((start, _len), data)| NvramTemplate { start, data }))
+}
+
+/// Maps the priority class of a disk in the spec to that of its device.
+pub(crate) fn block_priority(priority: DiskPriority) -> block::Priority {
+    match priority {
+        DiskPriority::High => block::Priority::High,
+        DiskPriority::Normal => block::Priority::Normal,
+        DiskPriority::Low => block::Priority::Low,
+    }
+}
+
+/// Creates the VM (with its memory, but no devices) for an instance with
+/// `cpus` vCPUs and `memory_mb` MiB of memory, plus a region of `hotplug_mb`
+/// MiB from which memory may be hot-plugged by a virtio-mem device.
 pub fn build_instance(
     name: &str,
-    spec: &InstanceSpecV0,
+    cpus: u8,
+    memory_mb: u64,
+    hotplug_mb: u64,
+    bootrom: &Firmware,
     use_reservoir: bool,
     _log: slog::Logger,
 ) -> Result<Instance> {
-    let (lowmem, highmem) = get_spec_guest_ram_limits(spec);
+    let (lowmem, highmem) = guest_ram_limits(memory_mb);
     let create_opts = propolis::vmm::CreateOpts {
         force: true,
         use_reservoir,
         track_dirty: true,
     };
+    let ((rom_start, rom_len), nvram) = rom_regions(bootrom);
     let mut builder = Builder::new(name, create_opts)?
-        .max_cpus(spec.devices.board.cpus)?
+        .max_cpus(cpus)?
         .add_mem_region(0, lowmem, "lowmem")?
-        .add_rom_region(0x1_0000_0000 - MAX_ROM_SIZE, MAX_ROM_SIZE, "bootrom")?
+        .add_rom_region(rom_start, rom_len, "bootrom")?
         .add_mmio_region(0xc000_0000_usize, 0x2000_0000_usize, "dev32")?
-        .add_mmio_region(0xe000_0000_usize, 0x1000_0000_usize, "pcicfg")?;
+        .add_mmio_region(
+            i440fx::ADDR_PCIE_ECAM_REGION,
+            i440fx::LEN_PCIE_ECAM_REGION,
+            "pcicfg",
+        )?;
+    if let Some((nvram_start, nvram_len)) = nvram {
+        builder =
+            builder.add_mmio_region(nvram_start, nvram_len, NVRAM_REGION)?;
+    }
 
     let highmem_start = 0x1_0000_0000;
     if highmem > 0 {
         builder = builder.add_mem_region(highmem_start, highmem, "highmem")?;
     }
 
-    let dev64_start = highmem_start + highmem;
+    let mut dev64_start = highmem_start + highmem;
+    let (hotmem_start, hotmem) = hotplug_memory_region(highmem, hotplug_mb);
+    if hotmem % virtio::mem::MEM_REGION_ALIGN != 0 {
+        anyhow::bail!(
+            "hot-pluggable memory must be a multiple of {} MiB",
+            virtio::mem::MEM_REGION_ALIGN / (1024 * 1024)
+        );
+    }
+    if hotmem >= vmm::MAX_PHYSMEM - hotmem_start {
+        anyhow::bail!("hot-pluggable memory region is too large");
+    }
+    if hotmem > 0 {
+        builder = builder.add_hotplug_mem_region(
+            hotmem_start,
+            hotmem,
+            HOTPLUG_MEM_REGION,
+        )?;
+        dev64_start = hotmem_start + hotmem;
+    }
+
     builder = builder.add_mmio_region(
         dev64_start,
         vmm::MAX_PHYSMEM - dev64_start,
@@ -113,6 +331,25 @@ struct StorageBackendInstance {
     crucible: Option<(uuid::Uuid, Arc<block::CrucibleBackend>)>,
 }
 
+/// The storage devices created by
+/// [`MachineInitializer::initialize_storage_devices`].
+pub struct StorageDevices {
+    pub crucible_backends: CrucibleBackendMap,
+    /// The backends whose startup is deferred until the vCPUs are running,
+    /// with the names of the devices to which they are attached
+    pub deferred: BTreeMap<EntityID, String>,
+    /// The block devices presented to the guest, keyed by name
+    pub block_devices: BTreeMap<String, Arc<dyn block::Device>>,
+}
+
+/// The input devices created by
+/// [`MachineInitializer::initialize_input_devices`].
+#[derive(Default)]
+pub struct InputDevices {
+    pub keyboard: Option<Arc<virtio::PciVirtioInput>>,
+    pub tablet: Option<Arc<virtio::PciVirtioInput>>,
+}
+
 pub struct MachineInitializer<'a> {
     log: slog::Logger,
     machine: &'a Machine,
@@ -132,22 +369,46 @@ impl<'a> MachineInitializer<'a> {
         MachineInitializer { log, machine, inv, spec, producer_registry }
     }
 
-    pub fn initialize_rom<P: AsRef<std::path::Path>>(
+    /// Creates the device emulating the variable store of the bootrom, if it
+    /// has one, initialized from `template`.
+    ///
+    /// Given an `nvram_dir`, the store is kept in a file within it named for
+    /// the instance, from which it is restored when the instance is next
+    /// created, so that its variables outlive it.  A file which does not fit
+    /// the bootrom's store (as when the bootrom has changed) is replaced.
+    pub fn initialize_nvram(
         &self,
-        path: P,
+        template: Option<NvramTemplate>,
+        nvram_dir: Option<&std::path::Path>,
+        instance_id: uuid::Uuid,
     ) -> Result<(), Error> {
-        let (romfp, rom_len) = open_bootrom(path.as_ref())
-            .unwrap_or_else(|e| panic!("Cannot open bootrom: {}", e));
-
-        let mem = self.machine.acc_mem.access().unwrap();
-        let mapping = mem.direct_writable_region_by_name("bootrom")?;
-        let offset = mapping.len() - rom_len;
-        let submapping = mapping.subregion(offset, rom_len).unwrap();
-        let nread = submapping.pread(&romfp, rom_len, 0)?;
-        if nread != rom_len {
-            // TODO: Handle short read
-            return Err(Error::new(ErrorKind::InvalidData, "short read"));
+        let Some(NvramTemplate { start, mut data }) = template else {
+            return Ok(());
+        };
+        let backing =
+            nvram_dir.map(|dir| dir.join(format!("{instance_id}.fd")));
+        if let Some(path) = backing.as_ref() {
+            match std::fs::read(propolis::harden::host_path(path)?) {
+                Ok(saved) if saved.len() == data.len() => data = saved,
+                Ok(saved) => {
+                    warn!(self.log, "discarding NVRAM of mismatched size";
+                          "path" => %path.display(),
+                          "len" => saved.len(),
+                          "expected" => data.len());
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
         }
+
+        let nvram = Nvram::create(
+            start,
+            data,
+            backing.as_deref(),
+            self.log.new(slog::o!("dev" => "nvram")),
+        )?;
+        nvram.attach(&self.machine.bus_mmio);
+        self.inv.register(&nvram)?;
         Ok(())
     }
 
@@ -156,11 +417,7 @@ impl<'a> MachineInitializer<'a> {
 
         let rtc = &self.machine.kernel_devs.rtc;
         rtc.memsize_to_nvram(lowmem as u32, highmem as u64)?;
-        rtc.set_time(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("system time precedes UNIX epoch"),
-        )?;
+        rtc.set_time(self.machine.clock.unix_time())?;
 
         Ok(())
     }
@@ -168,6 +425,7 @@ impl<'a> MachineInitializer<'a> {
     pub fn initialize_chipset(
         &self,
         event_handler: &Arc<dyn super::vm::ChipsetEventHandler>,
+        post_codes: &config::PostCodes,
     ) -> Result<RegisteredChipset, Error> {
         let mut pci_builder = pci::topology::Builder::new();
         for (name, bridge) in &self.spec.devices.pci_pci_bridges {
@@ -217,9 +475,11 @@ impl<'a> MachineInitializer<'a> {
                         power_pin: Some(power_pin),
                         reset_pin: Some(reset_pin),
                         enable_pcie: i440fx.enable_pcie,
+                        post_code_alt: post_codes.capture_alt_port,
+                        enable_smbus: i440fx.enable_smbus,
                     },
                     self.log.new(slog::o!("dev" => "chipset")),
-                );
+                )?;
                 let id = self.inv.register(&chipset)?;
                 Ok(RegisteredChipset(chipset, id))
             }
@@ -243,8 +503,10 @@ impl<'a> MachineInitializer<'a> {
 
             let dev = LpcUart::new(chipset.device().irq_pin(irq).unwrap());
             dev.set_autodiscard(true);
+            dev.set_paced(serial_spec.paced);
             LpcUart::attach(&dev, &self.machine.bus_pio, port);
-            self.inv.register_instance(&dev, name)?;
+            let id = self.inv.register_instance(&dev, name)?;
+            self.inv.add_dependency(id, chipset.1)?;
             if matches!(serial_spec.num, SerialPortNumber::Com1) {
                 assert!(com1.is_none());
                 com1 = Some(dev);
@@ -263,23 +525,176 @@ impl<'a> MachineInitializer<'a> {
         let ps2_ctrl = PS2Ctrl::create();
         ps2_ctrl.attach(&self.machine.bus_pio, chipset.device().as_ref());
         let id = self.inv.register(&ps2_ctrl)?;
+        self.inv.add_dependency(id, chipset.1)?;
         Ok(id)
     }
 
-    pub fn initialize_qemu_debug_port(&self) -> Result<(), Error> {
-        let dbg = QemuDebugPort::create(&self.machine.bus_pio);
-        let debug_file = std::fs::File::create("debug.out")?;
-        let poller = chardev::BlockingFileOutput::new(debug_file);
-        poller.attach(Arc::clone(&dbg) as Arc<dyn BlockingSource>);
+    pub fn initialize_qemu_debug_port(
+        &self,
+        cfg: &config::DebugPort,
+    ) -> Result<(), Error> {
+        let dbg = QemuDebugPort::create(&self.machine.bus_pio, cfg.port)
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("debug port {:#x}: {e}", cfg.port),
+                )
+            })?;
+        match cfg.sink {
+            config::DebugPortSink::File => {
+                let path = cfg.path.as_deref().unwrap_or(std::path::Path::new(
+                    config::DebugPort::DEFAULT_PATH,
+                ));
+                let debug_file = std::fs::File::create(path)?;
+                let poller = chardev::BlockingFileOutput::new(debug_file);
+                poller.attach(Arc::clone(&dbg) as Arc<dyn BlockingSource>);
+            }
+            config::DebugPortSink::Log => {
+                // Lines which never end are emitted in pieces of this size
+                const MAX_LINE: usize = 1024;
+
+                let log = self.log.new(slog::o!("component" => "debug_port"));
+                let line = std::sync::Mutex::new(Vec::new());
+                dbg.set_consumer(Some(Box::new(move |data: &[u8]| {
+                    let mut line = line.lock().unwrap();
+                    for &c in data {
+                        if c != b'\n' {
+                            line.push(c);
+                        }
+                        if c == b'\n' || line.len() >= MAX_LINE {
+                            let text = String::from_utf8_lossy(&line);
+                            info!(log, "{}", text.trim_end());
+                            line.clear();
+                        }
+                    }
+                })));
+            }
+            config::DebugPortSink::Discard => {}
+        }
         self.inv.register(&dbg)?;
         Ok(())
     }
 
+    pub fn initialize_tpm(&self) -> Result<(), Error> {
+        let Some(spec) = self.spec.devices.tpm.as_ref() else {
+            return Ok(());
+        };
+        info!(
+            self.log,
+            "Creating TPM with state at {} via swtpm at {}",
+            spec.state_path,
+            spec.socket_path
+        );
+
+        let backend = tpm::Swtpm::new(
+            std::path::Path::new(&spec.socket_path),
+            spec.ctrl_socket_path.as_deref().map(std::path::Path::new),
+        );
+        let dev = tpm::TpmCrb::create(
+            Arc::new(backend),
+            self.log.new(slog::o!("dev" => "tpm-crb")),
+        )?;
+        dev.attach(&self.machine.bus_mmio);
+        self.inv.register(&dev)?;
+        Ok(())
+    }
+
+    pub fn initialize_qemu_pvpanic(
+        &self,
+        chipset: &RegisteredChipset,
+        event_handler: &Arc<dyn super::vm::ChipsetEventHandler>,
+    ) -> Result<(), Error> {
+        let Some(spec) = self.spec.devices.qemu_pvpanic.as_ref() else {
+            return Ok(());
+        };
+        info!(self.log, "Creating pvpanic device");
+        let bdf: pci::Bdf = spec.pci_path.try_into().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Couldn't get PCI BDF for pvpanic device: {}", e),
+            )
+        })?;
+
+        let dev = pvpanic::PciQemuPvpanic::create(
+            self.log.new(slog::o!("dev" => "pci-qemu-pvpanic")),
+        );
+        let handler_ref = Arc::downgrade(event_handler);
+        dev.set_handler(Some(Box::new(move |event| {
+            if let Some(handler) = handler_ref.upgrade() {
+                handler.guest_panic(event);
+            }
+        })));
+        let id = self.inv.register_instance(&dev, bdf.to_string())?;
+        self.inv.add_dependency(id, chipset.1)?;
+        chipset.device().pci_attach(bdf, dev);
+        Ok(())
+    }
+
+    /// Creates the instance's display device, if it has one.
+    pub fn initialize_display(
+        &self,
+        chipset: &RegisteredChipset,
+    ) -> Result<Option<Arc<bochs::PciBochsDisplay>>, Error> {
+        let Some(spec) = self.spec.devices.bochs_display.as_ref() else {
+            return Ok(None);
+        };
+        info!(self.log, "Creating display device");
+        let bdf: pci::Bdf = spec.pci_path.try_into().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Couldn't get PCI BDF for display device: {}", e),
+            )
+        })?;
+
+        let vram = self
+            .machine
+            .map_physmem
+            .create_device_mem("bochs-vram", bochs::VRAM_SIZE)?;
+        let display = bochs::PciBochsDisplay::create(vram);
+        let id = self.inv.register_instance(&display, bdf.to_string())?;
+        self.inv.add_dependency(id, chipset.1)?;
+        chipset.device().pci_attach(bdf, display.clone());
+        Ok(Some(display))
+    }
+
+    /// Creates the instance's vsock device, if it has one.
+    pub fn initialize_vsock(
+        &self,
+        chipset: &RegisteredChipset,
+    ) -> Result<Option<Arc<virtio::PciVirtioVsock>>, Error> {
+        let Some(spec) = self.spec.devices.vsock.as_ref() else {
+            return Ok(None);
+        };
+        if spec.guest_cid <= virtio::vsock::VSOCK_HOST_CID
+            || spec.guest_cid >= u64::from(u32::MAX)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid vsock guest CID {}", spec.guest_cid),
+            ));
+        }
+        info!(self.log, "Creating vsock device";
+              "guest_cid" => spec.guest_cid);
+        let bdf: pci::Bdf = spec.pci_path.try_into().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Couldn't get PCI BDF for vsock device: {}", e),
+            )
+        })?;
+
+        let vsock = virtio::PciVirtioVsock::new(0x100, spec.guest_cid);
+        let id = self.inv.register_instance(&vsock, bdf.to_string())?;
+        self.inv.add_dependency(id, chipset.1)?;
+        chipset.device().pci_attach(bdf, vsock.clone());
+        Ok(Some(vsock))
+    }
+
     fn create_storage_backend_from_spec(
         &self,
         backend_spec: &instance_spec::v0::StorageBackendV0,
         backend_name: &str,
         nexus_client: &Option<NexusClient>,
+        crucible_journal: Option<&config::CrucibleJournal>,
     ) -> Result<StorageBackendInstance, Error> {
         match backend_spec {
             instance_spec::v0::StorageBackendV0::Crucible(spec) => {
@@ -306,6 +721,51 @@ impl<'a> MachineInitializer<'a> {
                     vcr,
                     propolis::block::BackendOpts {
                         read_only: Some(spec.readonly),
+                        prefetch: spec.prefetch.map(|pf| {
+                            let dflt =
+                                propolis::block::prefetch::Policy::default();
+                            propolis::block::prefetch::Policy {
+                                max_bytes_per_sec: pf
+                                    .max_bytes_per_sec
+                                    .unwrap_or(dflt.max_bytes_per_sec),
+                                max_bytes: pf
+                                    .max_bytes
+                                    .unwrap_or(dflt.max_bytes),
+                                ..dflt
+                            }
+                        }),
+                        retry_policy: spec.retry.map(|rt| {
+                            let dflt =
+                                propolis::block::health::RetryPolicy::default();
+                            propolis::block::health::RetryPolicy {
+                                max_attempts: rt
+                                    .max_attempts
+                                    .unwrap_or(dflt.max_attempts),
+                                stall_timeout: rt
+                                    .stall_timeout_ms
+                                    .map(std::time::Duration::from_millis)
+                                    .unwrap_or(dflt.stall_timeout),
+                                not_ready_on_exhaust: rt.not_ready_on_exhaust,
+                                ..dflt
+                            }
+                        }),
+                        // Journals are named for the server's process, as
+                        // well as the backend, so that servers sharing the
+                        // directory do not collide.
+                        journal: crucible_journal.map(|cfg| {
+                            let path = cfg.dir.join(format!(
+                                "{}-{}.journal",
+                                std::process::id(),
+                                backend_name
+                            ));
+                            propolis::block::journal::Policy {
+                                max_bytes: cfg.max_bytes,
+                                max_outage: std::time::Duration::from_secs(
+                                    cfg.max_outage_secs,
+                                ),
+                                ..propolis::block::journal::Policy::new(path)
+                            }
+                        }),
                         ..Default::default()
                     },
                     self.producer_registry.clone(),
@@ -327,16 +787,50 @@ impl<'a> MachineInitializer<'a> {
                 info!(self.log, "Creating file disk backend";
                       "path" => &spec.path);
 
-                let nworkers = NonZeroUsize::new(8).unwrap();
-                let be = propolis::block::FileBackend::create(
-                    &spec.path,
-                    propolis::block::BackendOpts {
-                        read_only: Some(spec.readonly),
-                        ..Default::default()
-                    },
-                    nworkers,
-                )?;
+                let workers = spec.workers.unwrap_or(8) as usize;
+                let clamped = workers.clamp(1, block::MAX_FILE_WORKERS);
+                if clamped != workers {
+                    warn!(self.log, "clamping file backend worker count";
+                          "backend" => backend_name,
+                          "requested" => workers,
+                          "workers" => clamped);
+                }
+                let nworkers = NonZeroUsize::new(clamped).unwrap();
+                let opts = propolis::block::BackendOpts {
+                    read_only: Some(spec.readonly),
+                    ..Default::default()
+                };
+                if !spec.shared {
+                    let be = propolis::block::FileBackend::create(
+                        &spec.path, opts, nworkers,
+                    )?;
+                    let child = inventory::ChildRegister::new(
+                        &be,
+                        Some(spec.path.clone()),
+                    );
+                    return Ok(StorageBackendInstance {
+                        be,
+                        child,
+                        crucible: None,
+                    });
+                }
 
+                if !spec.readonly {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "shared file backend {} must be read-only",
+                            backend_name
+                        ),
+                    ));
+                }
+                let image = propolis::block::SharedImage::open(&spec.path)?;
+                info!(self.log, "Using shared image";
+                      "path" => &spec.path,
+                      "share_count" => image.share_count());
+                let be = propolis::block::SharedBackend::create(
+                    image, opts, nworkers,
+                )?;
                 let child =
                     inventory::ChildRegister::new(&be, Some(spec.path.clone()));
                 Ok(StorageBackendInstance { be, child, crucible: None })
@@ -376,27 +870,51 @@ impl<'a> MachineInitializer<'a> {
                     Some(backend_name.to_string()),
                 );
 
+                Ok(StorageBackendInstance { be, child, crucible: None })
+            }
+            instance_spec::v0::StorageBackendV0::Null(spec) => {
+                info!(self.log, "Creating null disk backend";
+                      "size" => spec.size);
+
+                let nworkers = NonZeroUsize::new(8).unwrap();
+                let be = propolis::block::NullBackend::create(
+                    spec.size,
+                    propolis::block::BackendOpts {
+                        read_only: Some(spec.readonly),
+                        ..Default::default()
+                    },
+                    nworkers,
+                )?;
+
+                let child = inventory::ChildRegister::new(
+                    &be,
+                    Some(backend_name.to_string()),
+                );
+
                 Ok(StorageBackendInstance { be, child, crucible: None })
             }
         }
     }
 
-    /// Initializes the storage devices and backends listed in this
-    /// initializer's instance spec.
-    ///
-    /// On success, returns a map from Crucible backend IDs to Crucible
-    /// backends.
+    /// Creates the instance's storage devices and their backends, returning
+    /// the Crucible backends among them and the entities of the backends whose
+    /// startup is deferred until after the guest begins to run.
     pub fn initialize_storage_devices(
         &self,
         chipset: &RegisteredChipset,
         nexus_client: Option<NexusClient>,
-    ) -> Result<CrucibleBackendMap, Error> {
+        crucible_journal: Option<&config::CrucibleJournal>,
+        audit_flushes: bool,
+    ) -> Result<StorageDevices, Error> {
         enum DeviceInterface {
             Virtio,
             Nvme,
+            Ahci,
         }
 
         let mut crucible_backends: CrucibleBackendMap = Default::default();
+        let mut deferred = BTreeMap::new();
+        let mut block_devices = BTreeMap::new();
         for (name, device_spec) in &self.spec.devices.storage_devices {
             info!(
                 self.log,
@@ -405,14 +923,44 @@ impl<'a> MachineInitializer<'a> {
                 device_spec
             );
 
-            let (device_interface, backend_name, pci_path) = match device_spec {
-                instance_spec::v0::StorageDeviceV0::VirtioDisk(disk) => {
-                    (DeviceInterface::Virtio, &disk.backend_name, disk.pci_path)
-                }
-                instance_spec::v0::StorageDeviceV0::NvmeDisk(disk) => {
-                    (DeviceInterface::Nvme, &disk.backend_name, disk.pci_path)
-                }
+            let (
+                device_interface,
+                backend_name,
+                pci_path,
+                write_protected,
+                priority,
+                disabled,
+                deferred_start,
+            ) = match device_spec {
+                instance_spec::v0::StorageDeviceV0::VirtioDisk(disk) => (
+                    DeviceInterface::Virtio,
+                    &disk.backend_name,
+                    disk.pci_path,
+                    disk.write_protected,
+                    disk.priority,
+                    disk.disabled,
+                    disk.deferred_start,
+                ),
+                instance_spec::v0::StorageDeviceV0::NvmeDisk(disk) => (
+                    DeviceInterface::Nvme,
+                    &disk.backend_name,
+                    disk.pci_path,
+                    disk.write_protected,
+                    disk.priority,
+                    disk.disabled,
+                    disk.deferred_start,
+                ),
+                instance_spec::v0::StorageDeviceV0::AhciDisk(disk) => (
+                    DeviceInterface::Ahci,
+                    &disk.backend_name,
+                    disk.pci_path,
+                    disk.write_protected,
+                    disk.priority,
+                    disk.disabled,
+                    disk.deferred_start,
+                ),
             };
+            let priority = block_priority(priority);
 
             let backend_spec = self
                 .spec
@@ -434,7 +982,14 @@ impl<'a> MachineInitializer<'a> {
                     backend_spec,
                     backend_name,
                     &nexus_client,
+                    crucible_journal,
                 )?;
+            if audit_flushes {
+                info!(self.log, "Auditing flushes of storage device {}", name);
+                backend.attachment().audit().enable(self.log.new(
+                    slog::o!("component" => format!("block-audit-{}", name)),
+                ));
+            }
 
             let bdf: pci::Bdf = pci_path.try_into().map_err(|e| {
                 Error::new(
@@ -446,29 +1001,83 @@ impl<'a> MachineInitializer<'a> {
                 )
             })?;
 
-            match device_interface {
+            let serial = block::disk_serial(name);
+            let backend_id = match device_interface {
                 DeviceInterface::Virtio => {
                     let vioblk = virtio::PciVirtioBlock::new(0x100);
+                    vioblk.set_serial(&serial);
                     let id =
                         self.inv.register_instance(&vioblk, bdf.to_string())?;
-                    let _ = self.inv.register_child(child, id).unwrap();
+                    let backend_id =
+                        self.inv.register_child(child, id).unwrap();
+                    self.inv.add_dependency(id, chipset.1)?;
+                    self.inv.add_dependency(id, backend_id)?;
+                    vioblk.set_write_protect(write_protected);
+                    block::Device::attachment(vioblk.as_ref())
+                        .set_priority(priority);
                     block::attach(backend, vioblk.clone());
+                    block_devices.insert(
+                        name.to_string(),
+                        vioblk.clone() as Arc<dyn block::Device>,
+                    );
                     chipset.device().pci_attach(bdf, vioblk);
+                    backend_id
                 }
                 DeviceInterface::Nvme => {
                     let nvme = nvme::PciNvme::create(
-                        name.to_string(),
+                        serial,
                         self.log.new(
                             slog::o!("component" => format!("nvme-{}", name)),
                         ),
                     );
                     let id =
                         self.inv.register_instance(&nvme, bdf.to_string())?;
-                    let _ = self.inv.register_child(child, id).unwrap();
+                    let backend_id =
+                        self.inv.register_child(child, id).unwrap();
+                    self.inv.add_dependency(id, chipset.1)?;
+                    self.inv.add_dependency(id, backend_id)?;
+                    nvme.set_write_protect(write_protected);
+                    block::Device::attachment(nvme.as_ref())
+                        .set_priority(priority);
                     block::attach(backend, nvme.clone());
+                    block_devices.insert(
+                        name.to_string(),
+                        nvme.clone() as Arc<dyn block::Device>,
+                    );
                     chipset.device().pci_attach(bdf, nvme);
+                    backend_id
+                }
+                DeviceInterface::Ahci => {
+                    let ahci = ahci::PciAhci::create(self.log.new(
+                        slog::o!("component" => format!("ahci-{}", name)),
+                    ));
+                    ahci.set_serial(&serial);
+                    let id =
+                        self.inv.register_instance(&ahci, bdf.to_string())?;
+                    let backend_id =
+                        self.inv.register_child(child, id).unwrap();
+                    self.inv.add_dependency(id, chipset.1)?;
+                    self.inv.add_dependency(id, backend_id)?;
+                    ahci.set_write_protect(write_protected);
+                    block::Device::attachment(ahci.as_ref())
+                        .set_priority(priority);
+                    block::attach(backend, ahci.clone());
+                    block_devices.insert(
+                        name.to_string(),
+                        ahci.clone() as Arc<dyn block::Device>,
+                    );
+                    chipset.device().pci_attach(bdf, ahci);
+                    backend_id
                 }
             };
+            if deferred_start {
+                info!(self.log, "Storage device {} starts deferred", name);
+                deferred.insert(backend_id, name.to_string());
+            }
+            if disabled {
+                info!(self.log, "Storage device {} is disabled", name);
+                chipset.device().pci_set_hidden(bdf, true);
+            }
             if let Some((id, backend)) = crucible {
                 let prev = crucible_backends.insert(id, backend);
                 if prev.is_some() {
@@ -479,7 +1088,7 @@ impl<'a> MachineInitializer<'a> {
                 }
             }
         }
-        Ok(crucible_backends)
+        Ok(StorageDevices { crucible_backends, deferred, block_devices })
     }
 
     pub fn initialize_network_devices(
@@ -512,32 +1121,361 @@ impl<'a> MachineInitializer<'a> {
                 )
             })?;
 
-            let vnic_name = match backend_spec {
+            let (id, nic): (_, Arc<dyn pci::Endpoint>) = match backend_spec {
                 instance_spec::v0::NetworkBackendV0::Virtio(spec) => {
-                    &spec.vnic_name
+                    let viona = virtio::PciVirtioViona::new(
+                        &spec.vnic_name,
+                        0x100,
+                        &self.machine.hdl,
+                    )?;
+                    (
+                        self.inv.register_instance(&viona, bdf.to_string())?,
+                        viona,
+                    )
+                }
+                instance_spec::v0::NetworkBackendV0::Null(_) => {
+                    info!(self.log, "Creating null vNIC {}", name);
+                    // A locally administered address, unique to the slot
+                    let mac_addr = [
+                        0x02,
+                        0x08,
+                        0x20,
+                        bdf.bus.get(),
+                        bdf.location.dev.get(),
+                        bdf.location.func.get(),
+                    ];
+                    let null = virtio::PciVirtioNullNet::new(0x100, mac_addr);
+                    (self.inv.register_instance(&null, bdf.to_string())?, null)
                 }
                 instance_spec::v0::NetworkBackendV0::Dlpi(_) => {
                     return Err(Error::new(
                         ErrorKind::InvalidInput,
-                        format!(
-                            "Network backend must be virtio for vNIC {}",
-                            name,
-                        ),
+                        format!("vNIC {} needs a virtio or null backend", name),
                     ));
                 }
             };
+            self.inv.add_dependency(id, chipset.1)?;
+            chipset.device().pci_attach(bdf, nic);
+            if vnic_spec.disabled {
+                info!(self.log, "vNIC {} is disabled", name);
+                chipset.device().pci_set_hidden(bdf, true);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn initialize_clock_devices(
+        &self,
+        chipset: &RegisteredChipset,
+    ) -> Result<(), Error> {
+        for (name, rtc_spec) in &self.spec.devices.clock_devices {
+            info!(self.log, "Creating clock device {}", name);
+            let bdf: pci::Bdf = rtc_spec.pci_path.try_into().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Couldn't get PCI BDF for clock device {}: {}",
+                        name, e
+                    ),
+                )
+            })?;
+
+            let rtc =
+                virtio::PciVirtioRtc::new(0x10, self.machine.clock.clone());
+            let id = self.inv.register_instance(&rtc, bdf.to_string())?;
+            self.inv.add_dependency(id, chipset.1)?;
+            chipset.device().pci_attach(bdf, rtc);
+        }
+        Ok(())
+    }
+
+    /// Attaches a temperature sensor and an FRU EEPROM describing the board
+    /// to the chipset's SMBus, if it has one.
+    pub fn initialize_smbus_devices(
+        &self,
+        chipset: &RegisteredChipset,
+        properties: &InstanceProperties,
+    ) -> Result<(), Error> {
+        let Some(bus) = chipset.device().smbus() else {
+            return Ok(());
+        };
+
+        let identity =
+            self.spec.devices.board.smbios.clone().unwrap_or_default();
+        let fru = smbus::fru::BoardInfo {
+            manufacturer: identity
+                .manufacturer
+                .unwrap_or_else(|| "Oxide".to_string()),
+            product: "OxVM".to_string(),
+            serial: identity
+                .serial_number
+                .unwrap_or_else(|| properties.id.to_string()),
+            part_number: String::new(),
+        };
+
+        let lm75 = smbus::Lm75::create(None);
+        let eeprom = smbus::Eeprom::create(&fru.to_image());
+        bus.attach(smbus::lm75::DEFAULT_ADDR, lm75.clone())
+            .and_then(|_| {
+                bus.attach(smbus::eeprom::DEFAULT_ADDR, eeprom.clone())
+            })
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+
+        for id in [self.inv.register(&lm75)?, self.inv.register(&eeprom)?] {
+            self.inv.add_dependency(id, chipset.1)?;
+        }
+        Ok(())
+    }
+
+    pub fn initialize_entropy_devices(
+        &self,
+        chipset: &RegisteredChipset,
+    ) -> Result<(), Error> {
+        for (name, rng_spec) in &self.spec.devices.entropy_devices {
+            info!(self.log, "Creating entropy device {}", name);
+            let bdf: pci::Bdf = rng_spec.pci_path.try_into().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Couldn't get PCI BDF for entropy device {}: {}",
+                        name, e
+                    ),
+                )
+            })?;
+            let rate = rng_spec
+                .bytes_per_sec
+                .unwrap_or(virtio::rng::DEFAULT_BYTES_PER_SEC);
+            let rate = NonZeroU32::new(rate).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Entropy device {} has a zero rate limit", name),
+                )
+            })?;
+
+            let rng = virtio::PciVirtioRng::new(0x10, rate)?;
+            let id = self.inv.register_instance(&rng, bdf.to_string())?;
+            self.inv.add_dependency(id, chipset.1)?;
+            chipset.device().pci_attach(bdf, rng);
+        }
+        Ok(())
+    }
+
+    /// Creates the instance's virtio-mem device, if it has one, to manage the
+    /// hot-pluggable memory region mapped by [`build_instance`].  As the
+    /// device is sized through the server's API, at most one may be present.
+    pub fn initialize_memory_device(
+        &self,
+        chipset: &RegisteredChipset,
+    ) -> Result<Option<Arc<virtio::PciVirtioMem>>, Error> {
+        let mut devices = self.spec.devices.memory_devices.iter();
+        let Some((name, mem_spec)) = devices.next() else {
+            return Ok(None);
+        };
+        if devices.next().is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "At most one memory device may be specified",
+            ));
+        }
+
+        info!(self.log, "Creating memory device {}", name);
+        let bdf: pci::Bdf = mem_spec.pci_path.try_into().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Couldn't get PCI BDF for memory device {}: {}",
+                    name, e
+                ),
+            )
+        })?;
+
+        let region = self
+            .machine
+            .map_physmem
+            .hotplug_region(HOTPLUG_MEM_REGION)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "memory device requires a hot-pluggable memory region",
+                )
+            })?;
+        let mem = virtio::PciVirtioMem::new(0x100, region);
+        let id = self.inv.register_instance(&mem, bdf.to_string())?;
+        self.inv.add_dependency(id, chipset.1)?;
+        chipset.device().pci_attach(bdf, mem.clone());
+        Ok(Some(mem))
+    }
+
+    /// Creates the instance's virtio-input devices.  As input is directed to
+    /// a device by kind, at most one of each kind may be present.
+    pub fn initialize_input_devices(
+        &self,
+        chipset: &RegisteredChipset,
+    ) -> Result<InputDevices, Error> {
+        let mut devices = InputDevices::default();
+        for (name, input_spec) in &self.spec.devices.input_devices {
+            let (slot, kind) = match input_spec.kind {
+                VirtioInputKind::Keyboard => {
+                    (&mut devices.keyboard, virtio::input::InputKind::Keyboard)
+                }
+                VirtioInputKind::Tablet => {
+                    (&mut devices.tablet, virtio::input::InputKind::Tablet)
+                }
+            };
+            if slot.is_some() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "At most one input device of kind {:?} may be \
+                        specified",
+                        input_spec.kind
+                    ),
+                ));
+            }
+
+            info!(self.log, "Creating input device {}", name);
+            let bdf: pci::Bdf =
+                input_spec.pci_path.try_into().map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Couldn't get PCI BDF for input device {}: {}",
+                            name, e
+                        ),
+                    )
+                })?;
+
+            let input = virtio::PciVirtioInput::new(0x40, kind);
+            let id = self.inv.register_instance(&input, bdf.to_string())?;
+            self.inv.add_dependency(id, chipset.1)?;
+            chipset.device().pci_attach(bdf, input.clone());
+            *slot = Some(input);
+        }
+        Ok(devices)
+    }
+
+    /// Creates the instance's shared memory devices, backed by files within
+    /// `dir`.
+    pub fn initialize_shared_memory_devices(
+        &self,
+        chipset: &RegisteredChipset,
+        dir: Option<&std::path::Path>,
+    ) -> Result<(), Error> {
+        for (name, shm_spec) in &self.spec.devices.shared_memory_devices {
+            info!(
+                self.log,
+                "Creating shared memory device {} backed by {}",
+                name,
+                shm_spec.path
+            );
+            let bdf: pci::Bdf = shm_spec.pci_path.try_into().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Couldn't get PCI BDF for shared memory device {}: {}",
+                        name, e
+                    ),
+                )
+            })?;
 
-            let viona = virtio::PciVirtioViona::new(
-                vnic_name,
-                0x100,
-                &self.machine.hdl,
-            )?;
-            let _ = self.inv.register_instance(&viona, bdf.to_string())?;
-            chipset.device().pci_attach(bdf, viona);
+            let Some(dir) = dir else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "shared memory device {}: no shared memory directory \
+                        is configured",
+                        name
+                    ),
+                ));
+            };
+            let shm = PciIvShmem::create(dir, &shm_spec.path, shm_spec.size)?;
+            let id = self.inv.register_instance(&shm, bdf.to_string())?;
+            self.inv.add_dependency(id, chipset.1)?;
+            chipset.device().pci_attach(bdf, shm);
         }
         Ok(())
     }
 
+    /// Creates the ACPI GPE0 block through which hotplug controllers signal
+    /// the guest.
+    pub fn initialize_gpe(
+        &self,
+        chipset: &RegisteredChipset,
+    ) -> Result<Arc<acpi::Gpe0>, Error> {
+        let gpe = acpi::Gpe0::create(chipset.device().sci_pin());
+        gpe.attach(&self.machine.bus_pio);
+        self.inv.register(&gpe)?;
+        Ok(gpe)
+    }
+
+    /// Creates the ACPI hotplug controller for bus 0, marking the slots of
+    /// storage and network devices as removable.
+    pub fn initialize_pci_hotplug(
+        &self,
+        gpe: &Arc<acpi::Gpe0>,
+    ) -> Result<Arc<pci::hotplug::AcpiPciHotplug>, Error> {
+        let hotplug = pci::hotplug::AcpiPciHotplug::create(gpe.clone());
+        let storage_paths = self
+            .spec
+            .devices
+            .storage_devices
+            .values()
+            .map(instance_spec::v0::StorageDeviceV0::pci_path);
+        let network_paths = self
+            .spec
+            .devices
+            .network_devices
+            .values()
+            .map(instance_spec::v0::NetworkDeviceV0::pci_path);
+        for pci_path in storage_paths.chain(network_paths) {
+            if let Ok(bdf) = pci::Bdf::try_from(pci_path) {
+                if bdf.bus.get() == 0 {
+                    hotplug.set_removable(bdf.location.dev.get(), true);
+                }
+            }
+        }
+        hotplug.attach(&self.machine.bus_pio);
+        self.inv.register(&hotplug)?;
+        Ok(hotplug)
+    }
+
+    /// Creates the ACPI CPU hotplug controller, which notifies
+    /// `event_handler` when the guest ejects a vCPU so that it can be retired.
+    pub fn initialize_cpu_hotplug(
+        &self,
+        gpe: &Arc<acpi::Gpe0>,
+        event_handler: &Arc<dyn super::vm::ChipsetEventHandler>,
+    ) -> Result<Arc<acpi::cpu_hotplug::CpuHotplug>, Error> {
+        let handler_ref = Arc::downgrade(event_handler);
+        let hotplug = acpi::cpu_hotplug::CpuHotplug::create(
+            self.spec.devices.board.cpus,
+            gpe.clone(),
+            Box::new(move |vcpu_id| {
+                if let Some(handler) = handler_ref.upgrade() {
+                    handler.vcpu_ejected(vcpu_id);
+                }
+            }),
+        );
+        hotplug.attach(&self.machine.bus_pio);
+        self.inv.register(&hotplug)?;
+        Ok(hotplug)
+    }
+
+    /// Creates the register block through which the guest is notified of
+    /// impending maintenance.
+    pub fn initialize_maintenance(
+        &self,
+        gpe: &Arc<acpi::Gpe0>,
+    ) -> Result<Arc<acpi::maintenance::MaintenanceNotifier>, Error> {
+        let notifier = acpi::maintenance::MaintenanceNotifier::create(
+            gpe.clone(),
+            self.machine.clock.clone(),
+        );
+        notifier.attach(&self.machine.bus_pio);
+        self.inv.register(&notifier)?;
+        Ok(notifier)
+    }
+
     #[cfg(feature = "falcon")]
     pub fn initialize_softnpu_ports(
         &self,
@@ -603,7 +1541,8 @@ impl<'a> MachineInitializer<'a> {
             LpcUart::new(chipset.device().irq_pin(ibmpc::IRQ_COM4).unwrap());
         uart.set_autodiscard(true);
         LpcUart::attach(&uart, pio, port);
-        self.inv.register_instance(&uart, "softnpu-uart")?;
+        let uart_id = self.inv.register_instance(&uart, "softnpu-uart")?;
+        self.inv.add_dependency(uart_id, chipset.1)?;
 
         // Start with no pipeline. The guest must load the initial P4 program.
         let pipeline = Arc::new(std::sync::Mutex::new(None));
@@ -619,7 +1558,8 @@ impl<'a> MachineInitializer<'a> {
         );
         let vio9p =
             virtio::p9fs::PciVirtio9pfs::new(0x40, Arc::new(p9_handler));
-        self.inv.register_instance(&vio9p, "softnpu-p9fs")?;
+        let p9fs_id = self.inv.register_instance(&vio9p, "softnpu-p9fs")?;
+        self.inv.add_dependency(p9fs_id, chipset.1)?;
         let bdf: pci::Bdf = self
             .spec
             .devices
@@ -661,9 +1601,12 @@ impl<'a> MachineInitializer<'a> {
                 format!("register softnpu: {}", io_err),
             )
         })?;
-        self.inv
+        let softnpu_id = self
+            .inv
             .register(&softnpu)
             .map_err(|e| -> std::io::Error { e.into() })?;
+        self.inv.add_dependency(softnpu_id, uart_id)?;
+        self.inv.add_dependency(softnpu_id, p9fs_id)?;
 
         // Create the SoftNpu PCI port.
         let bdf: pci::Bdf = pci_port.pci_path.try_into().map_err(|e| {
@@ -672,7 +1615,8 @@ impl<'a> MachineInitializer<'a> {
                 format!("Couldn't get PCI BDF for SoftNpu pci port: {}", e),
             )
         })?;
-        self.inv
+        let port_id = self
+            .inv
             .register_instance(&softnpu.pci_port, bdf.to_string())
             .map_err(|e| -> std::io::Error {
                 let io_err: std::io::Error = e.into();
@@ -681,6 +1625,7 @@ impl<'a> MachineInitializer<'a> {
                     format!("register softnpu port: {}", io_err),
                 )
             })?;
+        self.inv.add_dependency(port_id, chipset.1)?;
         chipset.device().pci_attach(bdf, softnpu.pci_port.clone());
 
         Ok(())
@@ -712,15 +1657,43 @@ impl<'a> MachineInitializer<'a> {
             self.log.clone(),
         );
         let vio9p = virtio::p9fs::PciVirtio9pfs::new(0x40, Arc::new(handler));
-        self.inv
+        let id = self
+            .inv
             .register(&vio9p)
             .map_err(|e| -> std::io::Error { e.into() })?;
+        self.inv.add_dependency(id, chipset.1)?;
 
         chipset.device().pci_attach(bdf, vio9p);
         Ok(())
     }
 
-    pub fn initialize_fwcfg(&self, cpus: u8) -> Result<EntityID, Error> {
+    /// Runs the supplied hooks, allowing custom builds to add plug-in devices
+    /// once the built-in devices have been created.
+    pub fn initialize_plugin_devices(
+        &self,
+        chipset: &RegisteredChipset,
+        hooks: &[pci::plugin::MachineHook],
+    ) -> Result<(), Error> {
+        let host = pci::plugin::PluginHost {
+            machine: self.machine,
+            inv: self.inv,
+            chipset: chipset.device().as_ref(),
+            log: &self.log,
+        };
+        for hook in hooks {
+            hook(&host)?;
+        }
+        Ok(())
+    }
+
+    pub fn initialize_fwcfg(
+        &self,
+        chipset: &RegisteredChipset,
+        pci_hotplug: &pci::hotplug::AcpiPciHotplug,
+        cpus: u8,
+        smbios: Option<&config::Smbios>,
+        properties: &InstanceProperties,
+    ) -> Result<EntityID, Error> {
         let mut fwcfg = fwcfg::FwCfgBuilder::new();
         fwcfg
             .add_legacy(
@@ -728,6 +1701,20 @@ impl<'a> MachineInitializer<'a> {
                 fwcfg::FixedItem::new_u32(cpus as u32),
             )
             .unwrap();
+        self.generate_acpi(chipset, pci_hotplug)?
+            .attach(&mut fwcfg)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        // Identification given in the spec is reported even if the server is
+        // not otherwise configured to provide SMBIOS tables.
+        if smbios.is_some() || self.spec.devices.board.smbios.is_some() {
+            self.generate_smbios(smbios, properties)?
+                .attach(&mut fwcfg)
+                .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        }
+        fwcfg.offload(
+            propolis::offload::Offload::new(NonZeroUsize::new(2).unwrap())?,
+            propolis::offload::Budget::default(),
+        );
 
         let ramfb = ramfb::RamFb::create(
             self.log.new(slog::o!("component" => "ramfb")),
@@ -742,9 +1729,249 @@ impl<'a> MachineInitializer<'a> {
         Ok(ramfb_id)
     }
 
+    /// Generates the ACPI tables describing the machine, including the GPE0
+    /// block through which its hotplug controllers signal the guest.
+    fn generate_acpi(
+        &self,
+        chipset: &RegisteredChipset,
+        pci_hotplug: &pci::hotplug::AcpiPciHotplug,
+    ) -> Result<propolis::firmware::acpi::Tables, Error> {
+        let board = &self.spec.devices.board;
+        let topology = match self.cpu_topology()? {
+            Some(topo) => topo,
+            None => CpuTopology::new(board.cpus, 1, 1).map_err(|e| {
+                Error::new(ErrorKind::InvalidInput, e.to_string())
+            })?,
+        };
+        // The vCPUs are described as running at the guest TSC frequency
+        let tsc_freq =
+            vmm::time::export_time_data(&self.machine.hdl)?.guest_freq;
+
+        // 64-bit BARs are placed above any hot-pluggable memory, as laid out
+        // by `build_instance`.
+        let (_lowmem, highmem) = get_spec_guest_ram_limits(self.spec);
+        let dev64_start = match hotplug_memory_region(
+            highmem,
+            hotplug_memory_mb(self.spec),
+        ) {
+            (_, 0) => 0x1_0000_0000 + highmem,
+            (start, len) => start + len,
+        };
+
+        // Devices may be hot-plugged into any empty slot on bus 0, and those
+        // marked removable may be removed.
+        let removable = pci_hotplug.removable_slots();
+        let pci_hotplug_slots = (0..32u8)
+            .filter(|&dev| {
+                let bdf = pci::Bdf::new(0, dev, 0).unwrap();
+                removable & (1 << dev) != 0
+                    || chipset.device().pci_device_at(bdf).is_none()
+            })
+            .fold(0, |slots, dev| slots | 1 << dev);
+
+        let cfg = propolis::firmware::acpi::Config {
+            topology,
+            cpu_freq_mhz: (tsc_freq / 1_000_000) as u32,
+            pm_base: chipset.device().pm_base(),
+            gpe0_port: Some(acpi::gpe::PORT_GPE0),
+            pci_hotplug_slots: Some(pci_hotplug_slots),
+            cpu_hotplug: true,
+            maintenance: true,
+            pci_intx_routes: chipset.device().pci_intx_routes(),
+            pcie_ecam: chipset.device().pcie_ecam_region(),
+            pci_window_32: 0xc000_0000..0xe000_0000,
+            pci_window_64: Some(dev64_start as u64..vmm::MAX_PHYSMEM as u64),
+            tpm_crb: self.spec.devices.tpm.is_some(),
+            // The server offers pvpanic only as a PCI device, which needs no
+            // description in ACPI.
+            pvpanic_port: None,
+            hpet_block_id: Some(self.machine.kernel_devs.hpet.capabilities()?),
+        };
+        Ok(propolis::firmware::acpi::build(&cfg))
+    }
+
+    fn generate_smbios(
+        &self,
+        cfg: Option<&config::Smbios>,
+        properties: &InstanceProperties,
+    ) -> Result<smbios::Tables, Error> {
+        let host_fields = cfg
+            .map(|cfg| cfg.host_fields.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|f| f.parse())
+            .collect::<Result<Vec<smbios::host::HostField>, _>>()?;
+
+        let board = &self.spec.devices.board;
+        let identity = board.smbios.clone().unwrap_or_default();
+        let manufacturer =
+            identity.manufacturer.unwrap_or_else(|| "Oxide".to_string());
+        let mut tables = smbios::Config {
+            system_manufacturer: manufacturer.clone(),
+            system_product: "OxVM".to_string(),
+            system_serial: identity
+                .serial_number
+                .unwrap_or_else(|| properties.id.to_string()),
+            system_uuid: identity.uuid.unwrap_or(properties.id),
+            baseboard_manufacturer: manufacturer.clone(),
+            baseboard_product: "OxVM".to_string(),
+            chassis_manufacturer: manufacturer,
+            oem_strings: vec![format!("instance-name={}", properties.name)],
+            cpu_topology: self.cpu_topology()?,
+            memory_mb: board.memory_mb,
+            ..Default::default()
+        };
+        if let Some(cfg) = cfg.filter(|_| !host_fields.is_empty()) {
+            let host =
+                smbios::host::HostInfo::read(&cfg.host_path, &host_fields)
+                    .map_err(|e| {
+                        Error::new(
+                            e.kind(),
+                            format!(
+                                "failed to read host SMBIOS from {}: {}",
+                                cfg.host_path.display(),
+                                e
+                            ),
+                        )
+                    })?;
+            info!(self.log, "passing host SMBIOS fields through to guest";
+                "fields" => ?host_fields);
+            host.apply(&mut tables);
+        }
+        // An asset tag given for the instance takes precedence over the host's
+        if let Some(tag) = identity.asset_tag {
+            tables.chassis_asset_tag = tag.clone();
+            tables.baseboard_asset_tag = tag;
+        }
+        Ok(smbios::build(&tables))
+    }
+
+    /// Returns the vCPU topology specified for the instance, if any, having
+    /// checked that it accounts for every vCPU.
+    fn cpu_topology(&self) -> Result<Option<CpuTopology>, Error> {
+        let board = &self.spec.devices.board;
+        let Some(spec) = board.cpu_topology.as_ref() else {
+            return Ok(None);
+        };
+        let topo = CpuTopology::new(
+            spec.sockets,
+            spec.cores_per_socket,
+            spec.threads_per_core,
+        )
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        if topo.num_vcpus().get() != board.cpus {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "CPU topology describes {} vCPUs, but the board has {}",
+                    topo.num_vcpus(),
+                    board.cpus
+                ),
+            ));
+        }
+        Ok(Some(topo))
+    }
+
     pub fn initialize_cpus(&self) -> Result<(), Error> {
+        // Without a profile or explicit leaves, the vCPUs are left with the
+        // kernel's legacy cpuid handling, which reports (a subset of) the
+        // host's features.
+        let board = &self.spec.devices.board;
+        let leveled = match (board.cpu_profile, board.cpuid.as_ref()) {
+            (Some(_), Some(_)) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "cpu_profile and cpuid cannot both be specified",
+                ));
+            }
+            (None, Some(spec)) => {
+                let vendor = match spec.vendor {
+                    CpuidVendor::Amd => cpuid::VendorKind::Amd,
+                    CpuidVendor::Intel => cpuid::VendorKind::Intel,
+                };
+                let mut set = cpuid::Set::new(vendor);
+                for entry in spec.entries.iter() {
+                    let ident = cpuid::Ident(entry.leaf, entry.subleaf);
+                    let values = cpuid::Entry {
+                        eax: entry.eax,
+                        ebx: entry.ebx,
+                        ecx: entry.ecx,
+                        edx: entry.edx,
+                    };
+                    if set.insert(ident, values).is_some() {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "duplicate cpuid leaf {:#x} (subleaf {:?})",
+                                entry.leaf, entry.subleaf
+                            ),
+                        ));
+                    }
+                }
+                info!(self.log, "using cpuid leaves from instance spec";
+                    "entries" => spec.entries.len());
+                Some(set)
+            }
+            (Some(profile), None) => {
+                let profile = match profile {
+                    CpuProfile::BaselineRome => leveling::Profile::BaselineRome,
+                    CpuProfile::BaselineMilan => {
+                        leveling::Profile::BaselineMilan
+                    }
+                };
+                info!(self.log, "leveling CPU features";
+                    "profile" => profile.name());
+                let set = profile.level_host().map_err(|e| {
+                    Error::new(ErrorKind::Unsupported, e.to_string())
+                })?;
+                Some(set)
+            }
+            (None, None) => None,
+        };
+
+        let num_vcpus = NonZeroU8::new(self.spec.devices.board.cpus)
+            .ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "no vCPUs specified")
+            })?;
+        let topology = self.cpu_topology()?;
+        if topology.is_some() && leveled.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cpu_topology requires either cpu_profile or cpuid",
+            ));
+        }
+        if board.steal_time && leveled.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "steal_time requires either cpu_profile or cpuid",
+            ));
+        }
         for vcpu in self.machine.vcpus.iter() {
+            if let Some(set) = leveled.as_ref() {
+                let specializer = match topology {
+                    Some(topo) => cpuid::Specializer::new()
+                        .with_topology(topo)
+                        .with_cpu_topo(cpuid::TopoKind::iter()),
+                    None => cpuid::Specializer::new()
+                        .with_vcpu_count(num_vcpus, true)
+                        .clear_cpu_topo(cpuid::TopoKind::iter()),
+                };
+                let specializer =
+                    specializer.with_vcpuid(vcpu.id).with_cache_topo();
+                let specializer = if board.steal_time {
+                    specializer.with_steal_time()
+                } else {
+                    specializer
+                };
+                let set = specializer.execute(set.clone()).map_err(|e| {
+                    Error::new(ErrorKind::InvalidInput, e.to_string())
+                })?;
+                vcpu.set_cpuid(set)?;
+            }
             vcpu.set_default_capabs().unwrap();
+            if board.pause_exits {
+                vcpu.set_pause_exits(true)?;
+            }
         }
         Ok(())
     }
@@ -3,10 +3,13 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod config;
+mod firmware;
 mod initializer;
+pub mod log_control;
 mod migrate;
 mod serial;
 pub mod server;
+mod snapshot;
 mod spec;
 mod stats;
 mod vcpu_tasks;
@@ -0,0 +1,192 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runtime-adjustable logging and tracing controls.
+//!
+//! The server's primary log drain is wrapped in a [`RuntimeLevelFilter`] whose
+//! threshold can be altered through the API, allowing verbose output to be
+//! enabled only while debugging.  The filter can only pass records which were
+//! compiled in: the server's `slog` features drop trace-level records from
+//! release builds, so its threshold is held at [`MAX_LEVEL`] at most.
+//! Toggling of high-volume USDT paths is delegated to [`propolis::trace`].
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use propolis::trace::TraceFlags;
+use propolis_api_types as api;
+use slog::{Drain, Level, OwnedKVList, Record};
+
+/// The most verbose level of record compiled into the server, following the
+/// `max_level_trace` and `release_max_level_debug` features with which it
+/// depends on `slog`.
+pub const MAX_LEVEL: Level =
+    if cfg!(debug_assertions) { Level::Trace } else { Level::Debug };
+
+/// A [`Drain`] which discards records below a level that can be changed while
+/// the drain is in use.
+pub struct RuntimeLevelFilter<D> {
+    drain: D,
+    level: Arc<AtomicUsize>,
+}
+
+impl<D: Drain> RuntimeLevelFilter<D> {
+    /// Wraps `drain`, returning the filter along with a [`LogLevelHandle`]
+    /// through which its threshold can be altered.
+    pub fn new(drain: D, level: Level) -> (Self, LogLevelHandle) {
+        let level = Arc::new(AtomicUsize::new(level.as_usize()));
+        let hdl = LogLevelHandle(level.clone());
+        (Self { drain, level }, hdl)
+    }
+}
+
+impl<D: Drain> Drain for RuntimeLevelFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &Record,
+        values: &OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        let current = Level::from_usize(self.level.load(Ordering::Relaxed))
+            .unwrap_or(Level::Info);
+        if record.level().is_at_least(current) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Handle used to query or alter the threshold of a [`RuntimeLevelFilter`].
+#[derive(Clone)]
+pub struct LogLevelHandle(Arc<AtomicUsize>);
+
+impl LogLevelHandle {
+    pub fn get(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+    }
+
+    /// Sets the threshold to `level`, or to [`MAX_LEVEL`] should `level` be
+    /// more verbose than the records compiled into the server.
+    pub fn set(&self, level: Level) {
+        let level =
+            if level.is_at_least(MAX_LEVEL) { level } else { MAX_LEVEL };
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn level_to_api(level: Level) -> api::LogLevel {
+    match level {
+        Level::Critical => api::LogLevel::Critical,
+        Level::Error => api::LogLevel::Error,
+        Level::Warning => api::LogLevel::Warning,
+        Level::Info => api::LogLevel::Info,
+        Level::Debug => api::LogLevel::Debug,
+        Level::Trace => api::LogLevel::Trace,
+    }
+}
+
+pub(crate) fn level_from_api(level: api::LogLevel) -> Level {
+    match level {
+        api::LogLevel::Critical => Level::Critical,
+        api::LogLevel::Error => Level::Error,
+        api::LogLevel::Warning => Level::Warning,
+        api::LogLevel::Info => Level::Info,
+        api::LogLevel::Debug => Level::Debug,
+        api::LogLevel::Trace => Level::Trace,
+    }
+}
+
+const TRACE_CATEGORIES: [(api::TraceCategory, TraceFlags); 4] = [
+    (api::TraceCategory::Pio, TraceFlags::PIO),
+    (api::TraceCategory::Mmio, TraceFlags::MMIO),
+    (api::TraceCategory::VmExit, TraceFlags::VM_EXIT),
+    (api::TraceCategory::Block, TraceFlags::BLOCK),
+];
+
+pub(crate) fn trace_to_api(flags: TraceFlags) -> Vec<api::TraceCategory> {
+    TRACE_CATEGORIES
+        .iter()
+        .filter(|(_, f)| flags.contains(*f))
+        .map(|(c, _)| *c)
+        .collect()
+}
+
+pub(crate) fn trace_from_api(cats: &[api::TraceCategory]) -> TraceFlags {
+    TRACE_CATEGORIES
+        .iter()
+        .filter(|(c, _)| cats.contains(c))
+        .fold(TraceFlags::empty(), |acc, (_, f)| acc | *f)
+}
+
+/// Emits the current debug settings in their API representation.
+pub(crate) fn current_settings(hdl: &LogLevelHandle) -> api::DebugSettings {
+    api::DebugSettings {
+        log_level: level_to_api(hdl.get()),
+        trace_categories: trace_to_api(propolis::trace::enabled()),
+        exit_stats: propolis::exit_stats::is_enabled(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use slog::{o, Logger};
+    use std::sync::Mutex;
+
+    /// Drain which counts the records which reach it.
+    struct Counter(Arc<Mutex<usize>>);
+    impl Drain for Counter {
+        type Ok = ();
+        type Err = slog::Never;
+        fn log(&self, _: &Record, _: &OwnedKVList) -> Result<(), slog::Never> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn level_change_applies() {
+        let count = Arc::new(Mutex::new(0));
+        let (filter, hdl) =
+            RuntimeLevelFilter::new(Counter(count.clone()), Level::Info);
+        let log = Logger::root(filter.fuse(), o!());
+
+        slog::debug!(log, "dropped");
+        slog::info!(log, "kept");
+        assert_eq!(*count.lock().unwrap(), 1);
+
+        hdl.set(Level::Debug);
+        slog::debug!(log, "kept");
+        assert_eq!(*count.lock().unwrap(), 2);
+        assert_eq!(hdl.get(), Level::Debug);
+    }
+
+    #[test]
+    fn trace_level_limited_to_compiled_records() {
+        let count = Arc::new(Mutex::new(0));
+        let (filter, hdl) =
+            RuntimeLevelFilter::new(Counter(count.clone()), Level::Info);
+        let log = Logger::root(filter.fuse(), o!());
+
+        // Trace records reach the drain only if they are compiled in, and
+        // the threshold reported is the one which takes effect.
+        hdl.set(Level::Trace);
+        slog::trace!(log, "kept if compiled in");
+        slog::debug!(log, "kept");
+        let compiled = usize::from(MAX_LEVEL == Level::Trace);
+        assert_eq!(*count.lock().unwrap(), compiled + 1);
+        assert_eq!(hdl.get(), MAX_LEVEL);
+    }
+
+    #[test]
+    fn trace_category_roundtrip() {
+        let cats = vec![api::TraceCategory::Pio, api::TraceCategory::Block];
+        let flags = trace_from_api(&cats);
+        assert_eq!(flags, TraceFlags::PIO | TraceFlags::BLOCK);
+        assert_eq!(trace_to_api(flags), cats);
+    }
+}
@@ -84,6 +84,8 @@ pub(crate) enum Message {
     MemFetch(u64, u64, Vec<u8>),
     MemXfer(u64, u64, Vec<u8>),
     MemDone,
+    /// A 4KiB page, compressed with the algorithm selected for the migration.
+    CompressedPage(Vec<u8>),
 }
 
 /// MessageType represents tags that are used in the protocol for
@@ -104,6 +106,7 @@ enum MessageType {
     MemFetch,
     MemXfer,
     MemDone,
+    CompressedPage,
 }
 
 /// By implementing `From<&Message>` on MessageType, we can translate
@@ -122,6 +125,7 @@ impl From<&Message> for MessageType {
             Message::MemFetch(_, _, _) => MessageType::MemFetch,
             Message::MemXfer(_, _, _) => MessageType::MemXfer,
             Message::MemDone => MessageType::MemDone,
+            Message::CompressedPage(_) => MessageType::CompressedPage,
         }
     }
 }
@@ -138,7 +142,9 @@ impl std::convert::TryInto<tungstenite::Message> for Message {
                 dst.extend(serialized.as_bytes());
             }
             Message::Serialized(s) => dst.put_slice(s.as_bytes()),
-            Message::Blob(bytes) | Message::Page(bytes) => {
+            Message::Blob(bytes)
+            | Message::Page(bytes)
+            | Message::CompressedPage(bytes) => {
                 dst.put_slice(&bytes);
             }
             Message::MemQuery(start, end) | Message::MemEnd(start, end) => {
@@ -245,6 +251,9 @@ impl std::convert::TryInto<Message> for tungstenite::Message {
                         }
                         Message::MemDone
                     }
+                    MessageType::CompressedPage => {
+                        Message::CompressedPage(src.to_vec())
+                    }
                 };
                 Ok(m)
             }
@@ -311,6 +320,13 @@ mod encoder_tests {
         assert_eq!(bytes, page);
     }
 
+    #[test]
+    fn encode_compressed_page() {
+        let mut bytes = encode(Message::CompressedPage(vec![1, 2, 3]));
+        assert_eq!(bytes.pop(), Some(MessageType::CompressedPage as u8));
+        assert_eq!(&bytes[..], &[1, 2, 3]);
+    }
+
     #[test]
     fn encode_mem_query() {
         let mut bytes = encode(Message::MemQuery(1, 2));
@@ -439,6 +455,15 @@ mod decoder_tests {
             if p.iter().all(|&b| b == 0)));
     }
 
+    #[test]
+    fn decode_compressed_page() {
+        let mut bytes = vec![4, 5, 6];
+        bytes.push(MessageType::CompressedPage as u8);
+        let decoded = tungstenite::Message::Binary(bytes).try_into().unwrap();
+        assert!(matches!(decoded, Message::CompressedPage(p)
+            if p == vec![4, 5, 6]));
+    }
+
     #[test]
     fn decode_mem_query() {
         let mut bytes = vec![1, 0, 0, 0, 0, 0, 0, 0];
@@ -0,0 +1,149 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compression of the guest RAM pages transferred during migration.
+//!
+//! Each page is compressed on its own, so the destination can decode the
+//! pages of a transfer in any grouping.  A page which does not shrink when
+//! compressed is sent as a plain [`Message::Page`] instead.
+
+use propolis::common::PAGE_SIZE;
+use propolis_api_types::MigrationCompression;
+
+use crate::migrate::codec::Message;
+use crate::migrate::MigrateError;
+
+#[derive(Default)]
+enum Compressor {
+    #[default]
+    None,
+    Lz4,
+    Zstd(zstd::bulk::Compressor<'static>),
+}
+
+/// Produces the messages carrying guest RAM pages on the source.  Pages are
+/// sent uncompressed by default.
+#[derive(Default)]
+pub(crate) struct PageCompressor(Compressor);
+
+impl PageCompressor {
+    pub fn new(
+        compression: Option<MigrationCompression>,
+    ) -> Result<Self, MigrateError> {
+        let inner = match compression {
+            None => Compressor::None,
+            Some(MigrationCompression::Lz4) => Compressor::Lz4,
+            Some(MigrationCompression::Zstd) => Compressor::Zstd(
+                zstd::bulk::Compressor::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+                    .map_err(|e| MigrateError::Compression(e.to_string()))?,
+            ),
+        };
+        Ok(Self(inner))
+    }
+
+    /// Returns the message with which to send `page`.
+    pub fn page_message(
+        &mut self,
+        page: &[u8],
+    ) -> Result<Message, MigrateError> {
+        let compressed = match &mut self.0 {
+            Compressor::None => None,
+            Compressor::Lz4 => Some(lz4_flex::block::compress(page)),
+            Compressor::Zstd(zstd) => Some(
+                zstd.compress(page)
+                    .map_err(|e| MigrateError::Compression(e.to_string()))?,
+            ),
+        };
+        Ok(match compressed {
+            Some(data) if data.len() < page.len() => {
+                Message::CompressedPage(data)
+            }
+            _ => Message::Page(page.to_vec()),
+        })
+    }
+}
+
+/// Decompresses the contents of a [`Message::CompressedPage`].
+pub(crate) fn decompress_page(
+    compression: MigrationCompression,
+    data: &[u8],
+) -> Result<Vec<u8>, MigrateError> {
+    let page = match compression {
+        MigrationCompression::Lz4 => {
+            lz4_flex::block::decompress(data, PAGE_SIZE)
+                .map_err(|e| MigrateError::Compression(e.to_string()))?
+        }
+        MigrationCompression::Zstd => {
+            zstd::bulk::decompress(data, PAGE_SIZE)
+                .map_err(|e| MigrateError::Compression(e.to_string()))?
+        }
+    };
+    if page.len() != PAGE_SIZE {
+        return Err(MigrateError::Compression(format!(
+            "page decompressed to {} bytes",
+            page.len()
+        )));
+    }
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(compression: MigrationCompression) {
+        let mut compressor = PageCompressor::new(Some(compression)).unwrap();
+
+        let mut page = vec![0u8; PAGE_SIZE];
+        page[..16].copy_from_slice(b"propolis-migrate");
+        match compressor.page_message(&page).unwrap() {
+            Message::CompressedPage(data) => {
+                assert!(data.len() < PAGE_SIZE);
+                assert_eq!(decompress_page(compression, &data).unwrap(), page);
+            }
+            msg => panic!("expected compressed page, got {msg:?}"),
+        }
+
+        // A page of noise does not compress, and is sent as-is.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..PAGE_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert!(matches!(
+            compressor.page_message(&noise).unwrap(),
+            Message::Page(p) if p == noise
+        ));
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        round_trip(MigrationCompression::Lz4);
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(MigrationCompression::Zstd);
+    }
+
+    #[test]
+    fn uncompressed_pages_sent_as_is() {
+        let mut compressor = PageCompressor::new(None).unwrap();
+        let page = vec![0u8; PAGE_SIZE];
+        assert!(matches!(
+            compressor.page_message(&page).unwrap(),
+            Message::Page(p) if p == page
+        ));
+    }
+
+    #[test]
+    fn short_page_rejected() {
+        let data = lz4_flex::block::compress(&[0u8; 512]);
+        assert!(decompress_page(MigrationCompression::Lz4, &data).is_err());
+    }
+}
@@ -19,16 +19,27 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::WebSocketStream;
 
 use crate::migrate::codec;
+use crate::migrate::compress;
 use crate::migrate::memx;
 use crate::migrate::preamble::Preamble;
 use crate::migrate::probes;
 use crate::migrate::{
     Device, MigrateError, MigratePhase, MigrateRole, MigrationState, PageIter,
+    RamTransferOptions,
 };
 use crate::vm::{MigrateTargetCommand, VmController};
 
 use super::protocol::Protocol;
 
+/// Iterative pre-copy (see [`Protocol::RonV1`]) ends once a round transfers no
+/// more than this many pages, leaving little to transfer once the source has
+/// paused.
+const PRECOPY_CONVERGED_PAGES: usize = 4096;
+
+/// The most rounds of iterative pre-copy run before the source is paused,
+/// whether or not they have converged.
+const PRECOPY_MAX_ROUNDS: usize = 8;
+
 /// Launches an attempt to migrate into a supplied instance using the supplied
 /// source connection.
 pub async fn migrate<T: AsyncRead + AsyncWrite + Unpin + Send>(
@@ -37,15 +48,22 @@ pub async fn migrate<T: AsyncRead + AsyncWrite + Unpin + Send>(
     conn: WebSocketStream<T>,
     local_addr: SocketAddr,
     protocol: Protocol,
+    options: RamTransferOptions,
+    log: slog::Logger,
 ) -> Result<(), MigrateError> {
     let err_tx = command_tx.clone();
     let mut proto = match protocol {
-        Protocol::RonV0 => DestinationProtocol::new(
-            vm_controller,
-            command_tx,
-            conn,
-            local_addr,
-        ),
+        Protocol::RonV0 | Protocol::RonV1 | Protocol::RonV2 => {
+            DestinationProtocol::new(
+                vm_controller,
+                command_tx,
+                conn,
+                local_addr,
+                protocol,
+                options,
+                log,
+            )
+        }
     };
 
     if let Err(err) = proto.run().await {
@@ -81,6 +99,16 @@ struct DestinationProtocol<T: AsyncRead + AsyncWrite + Unpin + Send> {
     /// Local propolis-server address
     /// (to inform the source-side where to redirect its clients)
     local_addr: SocketAddr,
+
+    /// The protocol negotiated with the source.
+    protocol: Protocol,
+
+    /// Options with which the source is to transfer guest RAM.
+    options: RamTransferOptions,
+
+    /// The migration task's logger, tagged with the ID of the request that
+    /// initiated the migration.
+    log: slog::Logger,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
@@ -89,12 +117,23 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         command_tx: tokio::sync::mpsc::Sender<MigrateTargetCommand>,
         conn: WebSocketStream<T>,
         local_addr: SocketAddr,
+        protocol: Protocol,
+        options: RamTransferOptions,
+        log: slog::Logger,
     ) -> Self {
-        Self { vm_controller, command_tx, conn, local_addr }
+        Self {
+            vm_controller,
+            command_tx,
+            conn,
+            local_addr,
+            protocol,
+            options,
+            log,
+        }
     }
 
     fn log(&self) -> &slog::Logger {
-        self.vm_controller.log()
+        &self.log
     }
 
     async fn update_state(&mut self, state: MigrationState) {
@@ -184,7 +223,23 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
             return Err(MigrateError::InvalidInstanceState);
         }
 
-        self.send_msg(codec::Message::Okay).await
+        self.send_msg(codec::Message::Okay).await?;
+
+        if self.protocol.ram_transfer_options() {
+            self.send_msg(codec::Message::Serialized(
+                ron::ser::to_string(&self.options)
+                    .map_err(codec::ProtocolError::from)?,
+            ))
+            .await?;
+        } else if self.options != RamTransferOptions::default() {
+            warn!(
+                self.log(),
+                "source does not support RAM transfer options, ignoring them";
+                "options" => ?self.options,
+            );
+            self.options = RamTransferOptions::default();
+        }
+        Ok(())
     }
 
     async fn ram_push(
@@ -200,7 +255,47 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
             }
             _ => unreachable!("should only push RAM in a RAM push phase"),
         }
+        self.plug_hotplug_memory()?;
 
+        let iterative = self.protocol.iterative_precopy()
+            && matches!(phase, MigratePhase::RamPushPrePause);
+        let mut rounds = 0;
+        let mut last_pages = usize::MAX;
+        loop {
+            let pages = self.ram_round(phase).await?;
+            rounds += 1;
+            if !iterative {
+                break;
+            }
+
+            // Keep asking for the pages the still-running guest has dirtied
+            // until few enough remain, or until the rounds stop shrinking (the
+            // guest is dirtying pages as fast as they can be transferred).
+            info!(
+                self.log(),
+                "ram_push: pre-copy round {} transferred {} pages",
+                rounds,
+                pages
+            );
+            if pages <= PRECOPY_CONVERGED_PAGES
+                || pages >= last_pages
+                || rounds >= PRECOPY_MAX_ROUNDS
+            {
+                self.send_msg(codec::Message::Okay).await?;
+                break;
+            }
+            last_pages = pages;
+        }
+        self.update_state(MigrationState::Pause).await;
+        Ok(())
+    }
+
+    /// Queries the source for the RAM it offers, fetches all of it, and
+    /// returns the number of pages transferred.
+    async fn ram_round(
+        &mut self,
+        phase: &MigratePhase,
+    ) -> Result<usize, MigrateError> {
         let (dirty, highest) = self.query_ram().await?;
         for (k, region) in dirty.as_raw_slice().chunks(4096).enumerate() {
             if region.iter().all(|&b| b == 0) {
@@ -243,8 +338,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
             };
         }
         self.send_msg(codec::Message::MemDone).await?;
-        self.update_state(MigrationState::Pause).await;
-        Ok(())
+        Ok(dirty.count_ones())
     }
 
     async fn query_ram(
@@ -626,8 +720,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
     }
 
     async fn read_page(&mut self) -> Result<Vec<u8>, MigrateError> {
-        match self.read_msg().await? {
-            codec::Message::Page(bytes) => Ok(bytes),
+        match (self.read_msg().await?, self.options.compression) {
+            (codec::Message::Page(bytes), _) => Ok(bytes),
+            (codec::Message::CompressedPage(data), Some(compression)) => {
+                compress::decompress_page(compression, &data)
+            }
             _ => Err(MigrateError::UnexpectedMessage),
         }
     }
@@ -639,6 +736,22 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> DestinationProtocol<T> {
         Ok(self.conn.send(m.try_into()?).await?)
     }
 
+    /// Backs all hot-pluggable memory, so that it can receive whatever the
+    /// source sends of it.  Whether any of it is plugged is not known until
+    /// the devices' state arrives, whereupon the memory device releases it if
+    /// none is.
+    fn plug_hotplug_memory(&self) -> Result<(), MigrateError> {
+        let instance_guard = self.vm_controller.instance().lock();
+        for region in instance_guard.machine().map_physmem.hotplug_regions() {
+            region.plug().map_err(|e| {
+                MigrateError::DeviceState(format!(
+                    "failed to back hot-pluggable memory: {e}"
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
     async fn write_guest_ram(
         &mut self,
         addr: GuestAddr,
@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::num::NonZeroU64;
 use std::sync::Arc;
 
 use bit_field::BitField;
@@ -24,11 +25,13 @@ use crate::{
 };
 
 mod codec;
+mod compress;
 pub mod destination;
 mod memx;
 mod preamble;
 pub mod protocol;
 pub mod source;
+mod throttle;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum MigrateRole {
@@ -143,6 +146,10 @@ pub enum MigrateError {
     #[error("received device state for unknown device ({0})")]
     UnknownDevice(String),
 
+    /// Failed to compress or decompress guest RAM
+    #[error("RAM compression error: {0}")]
+    Compression(String),
+
     /// The other end of the migration ran into an error
     #[error("{0:?} migration instance encountered error: {1}")]
     RemoteError(MigrateRole, String),
@@ -192,6 +199,7 @@ impl From<MigrateError> for HttpError {
             | MigrateError::Phase
             | MigrateError::TimeData(_)
             | MigrateError::DeviceState(_)
+            | MigrateError::Compression(_)
             | MigrateError::RemoteError(_, _)
             | MigrateError::StateMachine(_) => {
                 HttpError::for_internal_error(msg)
@@ -228,6 +236,14 @@ struct DevicePayload {
     pub data: String,
 }
 
+/// Options for the transfer of guest RAM, requested of the destination and
+/// sent on to the source (see [`protocol::Protocol::RonV2`]).
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RamTransferOptions {
+    pub compression: Option<api::MigrationCompression>,
+    pub bandwidth_limit: Option<NonZeroU64>,
+}
+
 /// Begin the migration process (source-side).
 ///
 /// This will check protocol version and then begin the migration in a separate task.
@@ -303,7 +319,12 @@ pub async fn source_start<
         }
     };
 
-    controller.request_migration_from(migration_id, conn, selected)?;
+    controller.request_migration_from(
+        migration_id,
+        conn,
+        selected,
+        &rqctx.request_id,
+    )?;
     Ok(())
 }
 
@@ -382,7 +403,12 @@ pub(crate) async fn dest_initiate(
             return Err(MigrateError::Initiate);
         }
     };
+    let options = RamTransferOptions {
+        compression: migrate_info.compression,
+        bandwidth_limit: migrate_info.bandwidth_limit,
+    };
     let local_addr = rqctx.server.local_addr;
+    let request_id = rqctx.request_id.clone();
     tokio::runtime::Handle::current()
         .spawn_blocking(move || -> Result<(), MigrateError> {
             // Now start using the websocket for the migration protocol
@@ -391,6 +417,8 @@ pub(crate) async fn dest_initiate(
                 conn,
                 local_addr,
                 selected,
+                options,
+                &request_id,
             )?;
             Ok(())
         })
@@ -24,9 +24,36 @@ use thiserror::Error;
 #[derive(Debug, Clone, Copy, EnumIter)]
 pub enum Protocol {
     RonV0,
+
+    /// Like [`Protocol::RonV0`], but the RAM push before the source pauses
+    /// runs in rounds: after the first round offers all of guest RAM, the
+    /// destination may query again for the pages dirtied in the meantime, and
+    /// replies `Okay` to end the phase once the rounds have converged.
+    RonV1,
+
+    /// Like [`Protocol::RonV1`], but once it has accepted the preamble, the
+    /// destination sends the options (compression and bandwidth limit) with
+    /// which the source is to transfer guest RAM.
+    RonV2,
 }
 
 impl Protocol {
+    /// Returns true if the pre-pause RAM push of this protocol is iterative.
+    pub(super) fn iterative_precopy(&self) -> bool {
+        match self {
+            Protocol::RonV0 => false,
+            Protocol::RonV1 | Protocol::RonV2 => true,
+        }
+    }
+
+    /// Returns true if this protocol negotiates RAM transfer options.
+    pub(super) fn ram_transfer_options(&self) -> bool {
+        match self {
+            Protocol::RonV0 | Protocol::RonV1 => false,
+            Protocol::RonV2 => true,
+        }
+    }
+
     /// Yields the offer string for this protocol variant. This can be sent to
     /// a migration counterpart to offer this protocol version.
     pub fn offer_string(&self) -> String {
@@ -42,6 +69,12 @@ impl TryFrom<ProtocolParts> for Protocol {
             ProtocolParts { encoding: Encoding::Ron, version: 0 } => {
                 Self::RonV0
             }
+            ProtocolParts { encoding: Encoding::Ron, version: 1 } => {
+                Self::RonV1
+            }
+            ProtocolParts { encoding: Encoding::Ron, version: 2 } => {
+                Self::RonV2
+            }
             _ => anyhow::bail!(format!(
                 "no protocol matching definition: {:?}",
                 value
@@ -141,6 +174,12 @@ impl From<Protocol> for ProtocolParts {
             Protocol::RonV0 => {
                 ProtocolParts { version: 0, encoding: Encoding::Ron }
             }
+            Protocol::RonV1 => {
+                ProtocolParts { version: 1, encoding: Encoding::Ron }
+            }
+            Protocol::RonV2 => {
+                ProtocolParts { version: 2, encoding: Encoding::Ron }
+            }
         }
     }
 }
@@ -312,6 +351,34 @@ mod tests {
         assert_eq!(set, PROTOCOLS_V2);
     }
 
+    #[test]
+    fn older_peer_selects_non_iterative_precopy() {
+        let selected = select_protocol_from_offer("propolis-migrate-ron/0")
+            .unwrap()
+            .unwrap();
+        assert!(!selected.iterative_precopy());
+
+        let selected = select_protocol_from_offer(&make_protocol_offer())
+            .unwrap()
+            .unwrap();
+        assert!(selected.iterative_precopy());
+    }
+
+    #[test]
+    fn older_peer_skips_ram_transfer_options() {
+        let selected = select_protocol_from_offer(
+            "propolis-migrate-ron/0,propolis-migrate-ron/1",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!selected.ram_transfer_options());
+
+        let selected = select_protocol_from_offer(&make_protocol_offer())
+            .unwrap()
+            .unwrap();
+        assert!(selected.ram_transfer_options());
+    }
+
     #[test]
     fn parse_failures() {
         assert!("not-a-prefix".parse::<ProtocolParts>().is_err());
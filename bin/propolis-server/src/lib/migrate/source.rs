@@ -15,22 +15,24 @@ use std::io;
 use std::ops::{Range, RangeInclusive};
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::{tungstenite, WebSocketStream};
 
 use crate::migrate::codec;
 use crate::migrate::codec::Message;
+use crate::migrate::compress::PageCompressor;
 use crate::migrate::memx;
 use crate::migrate::preamble::Preamble;
 use crate::migrate::probes;
 use crate::migrate::protocol::Protocol;
+use crate::migrate::throttle::Throttle;
 use crate::migrate::{
     Device, DevicePayload, MigrateError, MigratePhase, MigrateRole,
-    MigrationState, PageIter,
+    MigrationState, PageIter, RamTransferOptions,
 };
 use crate::vm::{MigrateSourceCommand, MigrateSourceResponse, VmController};
 
 /// Specifies which pages should be offered during a RAM transfer phase.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum RamOfferDiscipline {
     /// Offer all pages irrespective of whether they are dirty.
     OfferAll,
@@ -45,11 +47,19 @@ pub async fn migrate<T: AsyncRead + AsyncWrite + Unpin + Send>(
     response_rx: tokio::sync::mpsc::Receiver<MigrateSourceResponse>,
     conn: WebSocketStream<T>,
     protocol: super::protocol::Protocol,
+    log: slog::Logger,
 ) -> Result<(), MigrateError> {
     let err_tx = command_tx.clone();
     let mut proto = match protocol {
-        Protocol::RonV0 => {
-            SourceProtocol::new(vm_controller, command_tx, response_rx, conn)
+        Protocol::RonV0 | Protocol::RonV1 | Protocol::RonV2 => {
+            SourceProtocol::new(
+                vm_controller,
+                command_tx,
+                response_rx,
+                conn,
+                protocol,
+                log,
+            )
         }
     };
 
@@ -84,6 +94,19 @@ struct SourceProtocol<T: AsyncRead + AsyncWrite + Unpin + Send> {
 
     /// Transport to the destination Instance.
     conn: WebSocketStream<T>,
+
+    /// The protocol negotiated with the destination.
+    protocol: Protocol,
+
+    /// Compresses guest RAM pages, as requested by the destination.
+    compressor: PageCompressor,
+
+    /// Paces the data sent to the destination, if it requested a limit.
+    throttle: Option<Throttle>,
+
+    /// The migration task's logger, tagged with the ID of the request that
+    /// started the migration.
+    log: slog::Logger,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
@@ -92,12 +115,23 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         command_tx: tokio::sync::mpsc::Sender<MigrateSourceCommand>,
         response_rx: tokio::sync::mpsc::Receiver<MigrateSourceResponse>,
         conn: WebSocketStream<T>,
+        protocol: Protocol,
+        log: slog::Logger,
     ) -> Self {
-        Self { vm_controller, command_tx, response_rx, conn }
+        Self {
+            vm_controller,
+            command_tx,
+            response_rx,
+            conn,
+            protocol,
+            compressor: PageCompressor::default(),
+            throttle: None,
+            log,
+        }
     }
 
     fn log(&self) -> &slog::Logger {
-        self.vm_controller.log()
+        &self.log
     }
 
     async fn update_state(&mut self, state: MigrationState) {
@@ -158,8 +192,26 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         let s = ron::ser::to_string(&preamble)
             .map_err(codec::ProtocolError::from)?;
         self.send_msg(codec::Message::Serialized(s)).await?;
+        self.read_ok().await?;
 
-        self.read_ok().await
+        if self.protocol.ram_transfer_options() {
+            let options: RamTransferOptions = match self.read_msg().await? {
+                codec::Message::Serialized(s) => {
+                    ron::de::from_str(&s).map_err(codec::ProtocolError::from)?
+                }
+                msg => {
+                    error!(
+                        self.log(),
+                        "expected RAM transfer options but received: {msg:?}"
+                    );
+                    return Err(MigrateError::UnexpectedMessage);
+                }
+            };
+            info!(self.log(), "RAM transfer options: {:?}", options);
+            self.compressor = PageCompressor::new(options.compression)?;
+            self.throttle = options.bandwidth_limit.map(Throttle::new);
+        }
+        Ok(())
     }
 
     async fn ram_push(
@@ -177,14 +229,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         }
 
         let vmm_ram_range = self.vmm_ram_bounds().await?;
-        let req_ram_range = self.read_mem_query().await?;
-        info!(
-            self.log(),
-            "ram_push ({:?}): got query for range {:?}, vm range {:?}",
-            phase,
-            req_ram_range,
-            vmm_ram_range
-        );
+        let mut req_ram_range = self.read_mem_query().await?;
 
         // TODO(#387): Ideally, both the pre-pause and post-pause phases would
         // offer just dirty pages. To do this safely, the source must remember
@@ -194,18 +239,64 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         // Offering all pages before pausing guarantees that all modified pages
         // will be transferred without having to do any extra tracking (but uses
         // host CPU time and network bandwidth inefficiently).
-        self.offer_ram(
-            vmm_ram_range,
-            req_ram_range,
-            match phase {
-                MigratePhase::RamPushPrePause => RamOfferDiscipline::OfferAll,
-                MigratePhase::RamPushPostPause => {
-                    RamOfferDiscipline::OfferDirty
+        let mut discipline = match phase {
+            MigratePhase::RamPushPrePause => RamOfferDiscipline::OfferAll,
+            MigratePhase::RamPushPostPause => RamOfferDiscipline::OfferDirty,
+            _ => unreachable!(),
+        };
+        let iterative = self.protocol.iterative_precopy()
+            && matches!(phase, MigratePhase::RamPushPrePause);
+        loop {
+            info!(
+                self.log(),
+                "ram_push ({:?}): got query for range {:?}, vm range {:?}",
+                phase,
+                req_ram_range,
+                vmm_ram_range
+            );
+            self.ram_round(
+                phase,
+                vmm_ram_range.clone(),
+                req_ram_range,
+                discipline,
+            )
+            .await?;
+            if !iterative {
+                break;
+            }
+
+            // The guest kept running while that round was transferred, so the
+            // destination may ask for the pages it dirtied in the meantime.
+            match self.read_msg().await? {
+                codec::Message::Okay => break,
+                codec::Message::MemQuery(start, end) => {
+                    req_ram_range = Self::mem_query_range(start, end)?;
+                    discipline = RamOfferDiscipline::OfferDirty;
                 }
-                _ => unreachable!(),
-            },
-        )
-        .await?;
+                msg => {
+                    error!(
+                        self.log(),
+                        "expected `Okay` or `MemQuery` but received: {msg:?}"
+                    );
+                    return Err(MigrateError::UnexpectedMessage);
+                }
+            }
+        }
+        info!(self.log(), "ram_push: done sending ram");
+        self.update_state(MigrationState::Pause).await;
+        Ok(())
+    }
+
+    /// Offers the guest RAM within `req_ram_range` that `discipline` selects,
+    /// then transfers the pages the destination fetches until it is done.
+    async fn ram_round(
+        &mut self,
+        phase: &MigratePhase,
+        vmm_ram_range: RangeInclusive<GuestAddr>,
+        req_ram_range: Range<u64>,
+        discipline: RamOfferDiscipline,
+    ) -> Result<(), MigrateError> {
+        self.offer_ram(vmm_ram_range, req_ram_range, discipline).await?;
 
         loop {
             let m = self.read_msg().await?;
@@ -242,8 +333,6 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
                 _ => return Err(MigrateError::UnexpectedMessage),
             };
         }
-        info!(self.log(), "ram_push: done sending ram");
-        self.update_state(MigrationState::Pause).await;
         Ok(())
     }
 
@@ -310,7 +399,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         for addr in PageIter::new(start, end, bits) {
             let mut bytes = [0u8; PAGE_SIZE];
             self.read_guest_mem(GuestAddr(addr), &mut bytes).await?;
-            self.send_msg(codec::Message::Page(bytes.into())).await?;
+            let msg = self.compressor.page_message(&bytes)?;
+            self.send_msg(msg).await?;
             probes::migrate_xfer_ram_page!(|| (addr, PAGE_SIZE as u64));
         }
         Ok(())
@@ -523,12 +613,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
     async fn read_mem_query(&mut self) -> Result<Range<u64>, MigrateError> {
         match self.read_msg().await? {
             codec::Message::MemQuery(start, end) => {
-                if start % PAGE_SIZE as u64 != 0
-                    || (end % PAGE_SIZE as u64 != 0 && end != !0)
-                {
-                    return Err(MigrateError::Phase);
-                }
-                Ok(start..end)
+                Self::mem_query_range(start, end)
             }
             msg => {
                 error!(self.log(), "expected `MemQuery` but received: {msg:?}");
@@ -537,11 +622,28 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> SourceProtocol<T> {
         }
     }
 
+    /// Validates the range of a `MemQuery` message.
+    fn mem_query_range(
+        start: u64,
+        end: u64,
+    ) -> Result<Range<u64>, MigrateError> {
+        if start % PAGE_SIZE as u64 != 0
+            || (end % PAGE_SIZE as u64 != 0 && end != !0)
+        {
+            return Err(MigrateError::Phase);
+        }
+        Ok(start..end)
+    }
+
     async fn send_msg(
         &mut self,
         m: codec::Message,
     ) -> Result<(), MigrateError> {
-        Ok(self.conn.send(m.try_into()?).await?)
+        let m: tungstenite::Message = m.try_into()?;
+        if let Some(throttle) = &mut self.throttle {
+            throttle.consume(m.len()).await;
+        }
+        Ok(self.conn.send(m).await?)
     }
 
     async fn vmm_ram_bounds(
@@ -0,0 +1,79 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Pacing of the data sent over a migration connection.
+
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+/// Limits the rate at which data is sent to a number of bytes per second.
+pub(crate) struct Throttle {
+    rate: NonZeroU64,
+    start: Instant,
+    sent: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: NonZeroU64) -> Self {
+        Self { rate, start: Instant::now(), sent: 0 }
+    }
+
+    /// Waits until `len` more bytes may be sent without exceeding the rate.
+    pub async fn consume(&mut self, len: usize) {
+        let delay = self.delay(len, Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Accounts for `len` bytes to be sent at `now`, returning how long the
+    /// sender must wait before sending them.
+    fn delay(&mut self, len: usize, now: Instant) -> Duration {
+        let due =
+            Duration::from_secs_f64(self.sent as f64 / self.rate.get() as f64);
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= due {
+            // The sender has fallen behind the rate (while waiting on its
+            // peer, perhaps).  Start the accounting over, rather than allow a
+            // burst to catch up.
+            self.start = now;
+            self.sent = len as u64;
+            Duration::ZERO
+        } else {
+            self.sent += len as u64;
+            due - elapsed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paces_to_rate() {
+        let mut throttle = Throttle::new(NonZeroU64::new(1000).unwrap());
+        let start = throttle.start;
+
+        assert_eq!(throttle.delay(500, start), Duration::ZERO);
+        assert_eq!(throttle.delay(500, start), Duration::from_millis(500));
+        assert_eq!(throttle.delay(500, start), Duration::from_millis(1000));
+
+        let later = start + Duration::from_millis(1200);
+        assert_eq!(throttle.delay(500, later), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn idle_time_does_not_accrue() {
+        let mut throttle = Throttle::new(NonZeroU64::new(1000).unwrap());
+        let start = throttle.start;
+        assert_eq!(throttle.delay(1000, start), Duration::ZERO);
+
+        // Having sent nothing for ten seconds, the sender may not then send
+        // ten seconds' worth of data at once.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(throttle.delay(1000, later), Duration::ZERO);
+        assert_eq!(throttle.delay(1000, later), Duration::from_secs(1));
+    }
+}
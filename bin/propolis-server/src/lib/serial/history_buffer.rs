@@ -23,7 +23,7 @@ pub enum Error {
     },
 }
 
-const TTY_BUFFER_SIZE: usize = 1024 * 1024;
+pub(crate) const TTY_BUFFER_SIZE: usize = 1024 * 1024;
 const DEFAULT_MAX_LENGTH: isize = 16 * 1024;
 
 /// An abstraction for storing the contents of the instance's serial console
@@ -9,7 +9,6 @@ use crate::migrate::MigrateError;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::num::NonZeroUsize;
-use std::ops::Range;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -81,6 +80,80 @@ pub struct SerialTask {
     pub websocks_ch: mpsc::Sender<WebSocketStream<Upgraded>>,
 }
 
+/// Number of messages of console output which may be queued for a client
+/// before it is considered too slow, and disconnected.
+const CLIENT_QUEUE_DEPTH: usize = 64;
+
+/// Time allowed for a single message to be sent to a client.  A client which
+/// stops reading altogether would otherwise hold its sending task (and its
+/// connection) open indefinitely, beyond the reach of being kicked.
+const CLIENT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The sending half of a client connection, which is driven by its own task so
+/// that a slow client does not hold up reading from the UART (and thus the
+/// guest's writes to it).
+struct ClientSink {
+    queue: mpsc::Sender<Message>,
+    kick: oneshot::Sender<CloseFrame<'static>>,
+}
+impl ClientSink {
+    fn new(mut sink: SplitSink<WebSocketStream<Upgraded>, Message>) -> Self {
+        let (queue, mut queue_recv) = mpsc::channel(CLIENT_QUEUE_DEPTH);
+        let (kick, mut kick_recv) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut kickable = true;
+            loop {
+                tokio::select! {
+                    biased;
+
+                    frame = &mut kick_recv, if kickable => match frame {
+                        Ok(frame) => {
+                            let msg = Message::Close(Some(frame));
+                            let _ = tokio::time::timeout(
+                                CLIENT_SEND_TIMEOUT,
+                                sink.send(msg),
+                            )
+                            .await;
+                            return;
+                        }
+                        // Dropped without being closed: send what remains
+                        Err(_) => kickable = false,
+                    },
+                    msg = queue_recv.recv() => match msg {
+                        Some(msg) => {
+                            // A send which fails or stalls drops the
+                            // connection, and with it the queue, which the
+                            // serial task then notices.
+                            let sent = tokio::time::timeout(
+                                CLIENT_SEND_TIMEOUT,
+                                sink.send(msg),
+                            )
+                            .await;
+                            if !matches!(sent, Ok(Ok(()))) {
+                                return;
+                            }
+                        }
+                        None => {
+                            let _ = tokio::time::timeout(
+                                CLIENT_SEND_TIMEOUT,
+                                sink.close(),
+                            )
+                            .await;
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Self { queue, kick }
+    }
+
+    /// Close the connection without waiting for queued output to be sent
+    fn close(self, code: CloseCode, reason: &'static str) {
+        let _ = self.kick.send(CloseFrame { code, reason: reason.into() });
+    }
+}
+
 pub async fn instance_serial_task<Device: Sink + Source>(
     mut websocks_recv: mpsc::Receiver<WebSocketStream<Upgraded>>,
     mut control_recv: mpsc::Receiver<SerialTaskControlMessage>,
@@ -89,13 +162,9 @@ pub async fn instance_serial_task<Device: Sink + Source>(
 ) -> Result<(), SerialTaskError> {
     info!(log, "Entered serial task");
     let mut output = [0u8; 1024];
-    let mut cur_output: Option<Range<usize>> = None;
     let mut cur_input: Option<(Vec<u8>, usize)> = None;
 
-    let mut ws_sinks: HashMap<
-        usize,
-        SplitSink<WebSocketStream<Upgraded>, Message>,
-    > = HashMap::new();
+    let mut ws_sinks: HashMap<usize, ClientSink> = HashMap::new();
     let mut ws_streams: HashMap<
         usize,
         futures::stream::SplitStream<WebSocketStream<Upgraded>>,
@@ -106,28 +175,9 @@ pub async fn instance_serial_task<Device: Sink + Source>(
     let mut next_stream_id = 0usize;
 
     loop {
-        let (uart_read, ws_send) =
-            if ws_sinks.is_empty() || cur_output.is_none() {
-                (serial.read_source(&mut output).fuse(), Fuse::terminated())
-            } else {
-                let range = cur_output.clone().unwrap();
-                (
-                    Fuse::terminated(),
-                    if !ws_sinks.is_empty() {
-                        futures::stream::iter(
-                            ws_sinks.iter_mut().zip(std::iter::repeat(
-                                Vec::from(&output[range]),
-                            )),
-                        )
-                        .for_each_concurrent(4, |((_i, ws), bin)| {
-                            ws.send(Message::binary(bin)).map(|_| ())
-                        })
-                        .fuse()
-                    } else {
-                        Fuse::terminated()
-                    },
-                )
-            };
+        // Output is always read from the UART, and so into the history buffer,
+        // regardless of how quickly clients consume it.
+        let uart_read = serial.read_source(&mut output).fuse();
 
         let (ws_recv, uart_write) = match &cur_input {
             None => (
@@ -166,24 +216,20 @@ pub async fn instance_serial_task<Device: Sink + Source>(
                 match message {
                     Some(SerialTaskControlMessage::Stopping) | None => {
                         // Gracefully close the connections to any clients
-                        for (i, ws0) in ws_sinks.into_iter() {
-                            let ws1 = ws_streams.remove(&i).ok_or(SerialTaskError::MismatchedStreams)?;
-                            let mut ws = ws0.reunite(ws1).map_err(|_| SerialTaskError::MismatchedStreams)?;
-                            let _ = ws.close(Some(CloseFrame {
-                                code: CloseCode::Away,
-                                reason: "VM stopped".into(),
-                            })).await;
+                        for (_i, sink) in ws_sinks.drain() {
+                            sink.close(CloseCode::Away, "VM stopped");
                         }
                     }
                     Some(SerialTaskControlMessage::Migration { destination, from_start }) => {
+                        let msg = serde_json::to_string(
+                            &InstanceSerialConsoleControlMessage::Migrating {
+                                destination,
+                                from_start,
+                            }
+                        )?;
                         let mut failures = 0;
-                        for sink in ws_sinks.values_mut() {
-                            if sink.send(Message::Text(serde_json::to_string(
-                                &InstanceSerialConsoleControlMessage::Migrating {
-                                    destination,
-                                    from_start,
-                                }
-                            )?)).await.is_err() {
+                        for sink in ws_sinks.values() {
+                            if sink.queue.try_send(Message::Text(msg.clone())).is_err() {
                                 failures += 1;
                             }
                         }
@@ -200,7 +246,7 @@ pub async fn instance_serial_task<Device: Sink + Source>(
                 probes::serial_new_ws!(|| {});
                 if let Some(ws) = new_ws {
                     let (ws_sink, ws_stream) = ws.split();
-                    ws_sinks.insert(next_stream_id, ws_sink);
+                    ws_sinks.insert(next_stream_id, ClientSink::new(ws_sink));
                     ws_streams.insert(next_stream_id, ws_stream);
                     next_stream_id += 1;
                 }
@@ -223,13 +269,10 @@ pub async fn instance_serial_task<Device: Sink + Source>(
                 }
             }
 
-            // Transmit bytes from the UART through the WS
-            _ = ws_send => {
-                probes::serial_uart_out!(|| {});
-                cur_output = None;
-            }
-
-            // Read bytes from the UART to be transmitted out the WS
+            // Read bytes from the UART and queue them for transmission out the
+            // WS.  Clients whose queues are full have fallen too far behind,
+            // and are disconnected: they may reconnect and catch up from the
+            // history buffer.
             nread = uart_read => {
                 // N.B. Putting this probe inside the match arms below causes
                 //      the `break` arm to be taken unexpectedly. See
@@ -240,7 +283,23 @@ pub async fn instance_serial_task<Device: Sink + Source>(
                         break;
                     }
                     Some(n) => {
-                        cur_output = Some(0..n)
+                        probes::serial_uart_out!(|| {});
+                        // Clients whose sending task has given up on them are
+                        // removed along with those which have fallen behind.
+                        let mut lagging = Vec::new();
+                        for (i, sink) in ws_sinks.iter() {
+                            let msg = Message::binary(&output[..n]);
+                            if sink.queue.try_send(msg).is_err() {
+                                lagging.push(*i);
+                            }
+                        }
+                        for i in lagging {
+                            warn!(log, "Disconnecting lagging serial connection {}.", i);
+                            ws_streams.remove(&i);
+                            if let Some(sink) = ws_sinks.remove(&i) {
+                                sink.close(CloseCode::Policy, "client fell behind");
+                            }
+                        }
                     }
                 }
             }
@@ -258,11 +317,10 @@ pub async fn instance_serial_task<Device: Sink + Source>(
                         }
                         Some(Ok(Message::Close(..))) | None => {
                             info!(log, "Removing closed serial connection {}.", i);
-                            let sink = ws_sinks.remove(&i).ok_or(SerialTaskError::MismatchedStreams)?;
-                            let stream = ws_streams.remove(&i).ok_or(SerialTaskError::MismatchedStreams)?;
-                            if let Err(e) = sink.reunite(stream).map_err(|_| SerialTaskError::MismatchedStreams)?.close(None).await {
-                                warn!(log, "Failed while closing stream {}: {}", i, e);
-                            }
+                            // Dropping the sink's queue closes the connection
+                            // once any remaining output has been sent.
+                            ws_sinks.remove(&i).ok_or(SerialTaskError::MismatchedStreams)?;
+                            ws_streams.remove(&i).ok_or(SerialTaskError::MismatchedStreams)?;
                         },
                         _ => continue,
                     }
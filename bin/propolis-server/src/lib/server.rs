@@ -13,6 +13,7 @@ use std::convert::TryFrom;
 use std::net::Ipv6Addr;
 use std::net::SocketAddrV6;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{collections::BTreeMap, net::SocketAddr};
 
 use crate::migrate::MigrateError;
@@ -23,11 +24,16 @@ use dropshot::{
     HttpResponseOk, HttpResponseUpdatedNoContent, Path, Query, RequestContext,
     TypedBody, WebsocketConnection,
 };
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
 use internal_dns::resolver::{ResolveError, Resolver};
 use internal_dns::ServiceName;
 pub use nexus_client::Client as NexusClient;
 use oximeter::types::ProducerRegistry;
+use propolis::block::export::Exporter;
+use propolis::hw::pci::plugin::MachineHook;
+use propolis::hw::ps2::ctrl::{MouseButtons, PS2Ctrl};
+use propolis::hw::virtio::input::TABLET_ABS_MAX;
+use propolis::hw::virtio::mem::MEM_BLOCK_SIZE;
 use propolis_api_types as api;
 use propolis_api_types::instance_spec::{
     self, components::backends::CrucibleStorageBackend, v0::StorageBackendV0,
@@ -42,7 +48,10 @@ use tokio::sync::{mpsc, oneshot, MappedMutexGuard, Mutex, MutexGuard};
 use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
 use tokio_tungstenite::WebSocketStream;
 
+use crate::log_control::{self, LogLevelHandle};
 use crate::spec::{ServerSpecBuilder, ServerSpecBuilderError};
+use crate::vm::boot_watchdog;
+use crate::vm::standby::StandbyMachine;
 use crate::vm::VmController;
 use crate::vnc::PropolisVncServer;
 
@@ -80,6 +89,17 @@ pub struct StaticConfig {
     /// The configuration to use when setting up this server's Oximeter
     /// endpoint.
     metrics: Option<MetricsEndpointConfig>,
+
+    /// Handle used to adjust the level of the server's log output at runtime.
+    log_level: LogLevelHandle,
+
+    /// Callbacks through which custom builds add plug-in devices to each VM
+    /// created by this server.
+    machine_hooks: Vec<MachineHook>,
+
+    /// The directory holding instance snapshots, if one is configured.  It is
+    /// opened at startup, so it stays reachable once the server is hardened.
+    snapshot_dir: Option<Arc<crate::snapshot::SnapshotDir>>,
 }
 
 /// The state of the current VM controller in this server, if there is one, or
@@ -137,10 +157,11 @@ impl VmControllerState {
         if let VmControllerState::Created(vm) = self {
             let state = vm.state_watcher().borrow().state;
             let last_instance = api::Instance {
-                properties: vm.properties().clone(),
+                properties: vm.properties(),
                 state,
                 disks: vec![],
                 nics: vec![],
+                bootrom: Some(vm.bootrom().clone()),
             };
             let last_instance_spec = vm.instance_spec().await.clone();
 
@@ -191,8 +212,14 @@ pub struct ServiceProviders {
     /// exists irrespective of whether there is an instance. Creating an
     /// instance hooks this server up to the instance's framebuffer.
     vnc_server: Arc<VncServer<PropolisVncServer>>,
+
+    /// The creation of the standby VM, if one is configured and has yet to be
+    /// taken by an instance.
+    standby: Mutex<Option<StandbyTask>>,
 }
 
+type StandbyTask = tokio::task::JoinHandle<Option<StandbyMachine>>;
+
 impl ServiceProviders {
     /// Directs the current set of per-instance service providers to stop in an
     /// orderly fashion, then drops them all.
@@ -218,6 +245,11 @@ impl ServiceProviders {
             server.abort();
         }
         let _ = self.oximeter_stats.lock().await.take();
+        if let Some(standby) = self.standby.lock().await.take() {
+            // Creation cannot be interrupted, so wait for it to finish before
+            // the VM is dropped.
+            let _ = standby.await;
+        }
     }
 }
 
@@ -236,12 +268,22 @@ impl DropshotEndpointContext {
         use_reservoir: bool,
         log: slog::Logger,
         metric_config: Option<MetricsEndpointConfig>,
-    ) -> Self {
-        Self {
+        log_level: LogLevelHandle,
+    ) -> std::io::Result<Self> {
+        let snapshot_dir = config
+            .snapshot_dir
+            .as_deref()
+            .map(crate::snapshot::SnapshotDir::open)
+            .transpose()?
+            .map(Arc::new);
+        Ok(Self {
             static_config: StaticConfig {
                 vm: config,
                 use_reservoir,
                 metrics: metric_config,
+                log_level,
+                machine_hooks: Vec::new(),
+                snapshot_dir,
             },
             services: Arc::new(ServiceProviders {
                 vm: Mutex::new(VmControllerState::NotCreated),
@@ -249,9 +291,35 @@ impl DropshotEndpointContext {
                 oximeter_server_task: Mutex::new(None),
                 oximeter_stats: Mutex::new(None),
                 vnc_server,
+                standby: Mutex::new(None),
             }),
             log,
-        }
+        })
+    }
+
+    /// Begins creating the standby VM, if the server is configured with one.
+    pub async fn start_standby(&self) {
+        let Some(shape) = self.static_config.vm.standby.clone() else {
+            return;
+        };
+        let use_reservoir = self.static_config.use_reservoir;
+        let bootroms = self.static_config.vm.bootrom_candidates();
+        let log = self.log.new(o!("component" => "standby"));
+        let task = tokio::task::spawn_blocking(move || {
+            StandbyMachine::create(&shape, use_reservoir, &bootroms, &log)
+                .map_err(|e| {
+                    error!(log, "failed to create standby VM"; "error" => ?e)
+                })
+                .ok()
+        });
+        *self.services.standby.lock().await = Some(task);
+    }
+
+    /// Adds a hook to be run during creation of each VM, after its built-in
+    /// devices have been initialized.
+    pub fn with_machine_hook(mut self, hook: MachineHook) -> Self {
+        self.static_config.machine_hooks.push(hook);
+        self
     }
 
     /// Get access to the VM controller for this context, emitting a consistent
@@ -429,6 +497,7 @@ async fn instance_ensure_common(
     let server_context = rqctx.context();
     let api::InstanceSpecEnsureRequest { properties, instance_spec, migrate } =
         request;
+    validate_metadata(&properties.metadata)?;
 
     // Handle requests to an instance that has already been initialized. Treat
     // the instances as compatible (and return Ok) if they have the same
@@ -439,7 +508,12 @@ async fn instance_ensure_common(
     if let VmControllerState::Created(existing) =
         &*server_context.services.vm.lock().await
     {
-        let existing_properties = existing.properties();
+        // Metadata may have been updated since the instance was created, and
+        // has no bearing on the guest, so it is not considered here.
+        let existing_properties = api::InstanceProperties {
+            metadata: properties.metadata.clone(),
+            ..existing.properties()
+        };
         if existing_properties.id != properties.id {
             return Err(HttpError::for_status(
                 Some(format!(
@@ -450,7 +524,7 @@ async fn instance_ensure_common(
             ));
         }
 
-        if *existing_properties != properties {
+        if existing_properties != properties {
             return Err(HttpError::for_status(
                 Some("Cannot update running server".to_string()),
                 http::status::StatusCode::CONFLICT,
@@ -462,6 +536,9 @@ async fn instance_ensure_common(
         }));
     }
 
+    let harden_plan =
+        harden_plan(&server_context.static_config.vm, &instance_spec);
+
     let producer_registry = if let Some(cfg) =
         server_context.static_config.metrics.as_ref()
     {
@@ -495,10 +572,32 @@ async fn instance_ensure_common(
     // Since `block_on` will panic if called from an async context, as we are in
     // now, the whole process is wrapped up in `spawn_blocking`.  It is
     // admittedly a big kludge until this can be better refactored.
+    // Take over the standby VM, waiting for its creation to finish if it is
+    // still underway, since that takes no longer than starting afresh.
+    let standby = match server_context.services.standby.lock().await.take() {
+        Some(task) => task.await.ok().flatten(),
+        None => None,
+    };
+
     let vm = {
         let properties = properties.clone();
         let use_reservoir = server_context.static_config.use_reservoir;
-        let bootrom = server_context.static_config.vm.bootrom.clone();
+        let bootroms = server_context.static_config.vm.bootrom_candidates();
+        let debug_port = server_context.static_config.vm.debug_port.clone();
+        let post_codes = server_context.static_config.vm.post_codes.clone();
+        let boot_watchdog =
+            server_context.static_config.vm.boot_watchdog.clone();
+        let crucible_journal =
+            server_context.static_config.vm.crucible_journal.clone();
+        let audit_block_flushes =
+            server_context.static_config.vm.audit_block_flushes;
+        let smbios = server_context.static_config.vm.smbios.clone();
+        let memory_pressure =
+            server_context.static_config.vm.memory_pressure.clone();
+        let shared_memory_dir =
+            server_context.static_config.vm.shared_memory_dir.clone();
+        let nvram_dir = server_context.static_config.vm.nvram_dir.clone();
+        let machine_hooks = server_context.static_config.machine_hooks.clone();
         let log = server_context.log.clone();
         let hdl = tokio::runtime::Handle::current();
         let ctrl_hdl = hdl.clone();
@@ -508,9 +607,20 @@ async fn instance_ensure_common(
                 instance_spec,
                 properties,
                 use_reservoir,
-                bootrom,
+                bootroms,
+                standby,
+                debug_port,
+                post_codes,
+                boot_watchdog,
+                crucible_journal,
+                audit_block_flushes,
+                smbios,
+                memory_pressure,
+                shared_memory_dir,
+                nvram_dir,
                 producer_registry,
                 nexus_client,
+                machine_hooks,
                 log,
                 ctrl_hdl,
                 stop_ch,
@@ -526,7 +636,41 @@ async fn instance_ensure_common(
         ))
     })?;
 
-    if let Some(ramfb) = vm.framebuffer() {
+    // With the instance's devices initialized, the server no longer needs most
+    // of its ambient authority.  This must succeed before the instance is
+    // published, lest it run in a process which is only partly hardened.
+    if let Some(plan) = harden_plan {
+        let log = server_context.log.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            propolis::harden::apply(&plan, &log)
+        })
+        .await
+        .unwrap();
+        if let Err(e) = res {
+            error!(server_context.log, "failed to harden server process";
+                   "error" => %e);
+            let _ = vm.put_state(
+                api::InstanceStateRequested::Stop,
+                &rqctx.request_id,
+            );
+            return Err(HttpError::for_internal_error(format!(
+                "failed to harden server process: {}",
+                e
+            )));
+        }
+    }
+
+    if let Some(display) = vm.display() {
+        // The display device takes precedence over the ramfb, whose
+        // framebuffer the guest then has no reason to use.
+        let ps2ctrl = vm.ps2ctrl().unwrap();
+        server_context
+            .services
+            .vnc_server
+            .server
+            .initialize_display(display.clone(), ps2ctrl.clone(), vm.clone())
+            .await;
+    } else if let Some(ramfb) = vm.framebuffer() {
         // Get a framebuffer description from the wrapped instance.
         let fb_spec = ramfb.get_framebuffer_spec();
         let vnc_fb = crate::vnc::RamFb::new(fb_spec);
@@ -601,6 +745,46 @@ async fn instance_ensure_common(
     Ok(HttpResponseCreated(api::InstanceEnsureResponse { migrate }))
 }
 
+/// The hardening to apply once an instance built from `spec` has been
+/// initialized, if the server is configured to harden itself.
+///
+/// Host paths which the server opens after initialization must remain
+/// reachable: the files behind file-backed disks (including any disk later
+/// hot-added alongside them), the sockets of a TPM's swtpm process, and the
+/// directories holding NVRAM variable stores and Crucible journals.
+fn harden_plan(
+    config: &VmTomlConfig,
+    spec: &VersionedInstanceSpec,
+) -> Option<propolis::harden::Plan> {
+    let harden = config.harden.as_ref()?;
+    let VersionedInstanceSpec::V0(v0_spec) = spec;
+    let mut required_paths: Vec<std::path::PathBuf> = v0_spec
+        .backends
+        .storage_backends
+        .values()
+        .filter_map(|be| match be {
+            StorageBackendV0::File(file) => Some(file.path.clone().into()),
+            _ => None,
+        })
+        .collect();
+    if let Some(tpm) = v0_spec.devices.tpm.as_ref() {
+        required_paths.push(tpm.socket_path.clone().into());
+        required_paths.extend(tpm.ctrl_socket_path.clone().map(Into::into));
+    }
+    let required_dirs = config
+        .nvram_dir
+        .iter()
+        .chain(config.crucible_journal.iter().map(|j| &j.dir))
+        .cloned()
+        .collect();
+    Some(propolis::harden::Plan {
+        root: harden.root.clone(),
+        required_paths,
+        required_dirs,
+        ..Default::default()
+    })
+}
+
 #[endpoint {
     method = PUT,
     path = "/instance",
@@ -657,7 +841,7 @@ async fn instance_get_common(
         VmControllerState::Created(vm) => {
             Ok((
                 api::Instance {
-                    properties: vm.properties().clone(),
+                    properties: vm.properties(),
                     state: vm.external_instance_state(),
                     disks: vec![],
                     // TODO: Fix this; we need a way to enumerate attached NICs.
@@ -668,6 +852,7 @@ async fn instance_get_common(
                     // would make it difficult for Propolis to update any dynamic info
                     // (i.e., has the device faulted, etc).
                     nics: vec![],
+                    bootrom: Some(vm.bootrom().clone()),
                 },
                 vm.instance_spec().await.clone(),
             ))
@@ -773,7 +958,7 @@ async fn instance_state_put(
     let requested_state = request.into_inner();
     let vm = ctx.vm().await?;
     let result = vm
-        .put_state(requested_state)
+        .put_state(requested_state, &rqctx.request_id)
         .map(|_| HttpResponseUpdatedNoContent {})
         .map_err(|e| e.into());
 
@@ -872,6 +1057,102 @@ async fn instance_serial(
         .map_err(|e| format!("Serial socket hand-off failed: {}", e).into())
 }
 
+/// Relays a connection from the guest, through its vsock device, to a port of
+/// the host.
+///
+/// The port is listened on until the guest connects to it, after which the
+/// connection's data is carried in binary messages in either direction. The
+/// websocket is closed once the guest shuts down its end of the connection,
+/// and closing the websocket shuts down the connection.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instance/vsock/{port}",
+}]
+async fn instance_vsock(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::VsockPathParams>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    use std::io::ErrorKind;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let port = path_params.into_inner().port;
+    let vsock = rqctx
+        .context()
+        .vm()
+        .await?
+        .vsock()
+        .ok_or("Instance has no vsock device")?
+        .clone();
+    let mut listener = vsock
+        .listen(port)
+        .ok_or_else(|| format!("Port {} is already listened on", port))?;
+
+    let mut ws_stream = WebSocketStream::from_raw_socket(
+        websock.into_inner(),
+        Role::Server,
+        Some(WebSocketConfig::default()),
+    )
+    .await;
+
+    // Wait for the guest to connect, unless the client leaves first.  The
+    // port is freed for another client once the connection is made.
+    let conn = loop {
+        tokio::select! {
+            conn = listener.accept() => {
+                break conn.ok_or("Vsock device is gone")?;
+            }
+            msg = ws_stream.next() => match msg {
+                None | Some(Ok(Message::Close(_))) => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    };
+    drop(listener);
+    slog::info!(rqctx.log, "Guest connected to vsock port";
+                "port" => port, "guest_port" => conn.guest_port());
+
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut pending: Vec<u8> = Vec::new();
+    loop {
+        loop {
+            match conn.try_recv(&mut buf) {
+                Ok(0) => {
+                    ws_stream.send(Message::Close(None)).await?;
+                    return Ok(());
+                }
+                Ok(n) => {
+                    ws_stream.send(Message::Binary(buf[..n].to_vec())).await?
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        while !pending.is_empty() {
+            match conn.try_send(&pending) {
+                Ok(n) => {
+                    pending.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Data from the client is taken only once the last of it has been
+        // accepted, so that the guest's pace is imposed on the client.
+        tokio::select! {
+            _ = conn.changed() => {}
+            msg = ws_stream.next(), if pending.is_empty() => match msg {
+                Some(Ok(Message::Binary(data))) => pending = data,
+                None | Some(Ok(Message::Close(_))) => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+}
+
 // This endpoint is meant to only be called during a migration from the destination
 // instance to the source instance as part of the HTTP connection upgrade used to
 // establish the migration link. We don't actually want this exported via OpenAPI
@@ -959,6 +1240,106 @@ async fn instance_issue_crucible_snapshot_request(
     Ok(HttpResponseOk(()))
 }
 
+/// Gets the progress of the background prefetch of a crucible backend.
+#[endpoint {
+    method = GET,
+    path = "/instance/disk/{id}/prefetch",
+}]
+async fn instance_crucible_prefetch_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPrefetchPathParams>,
+) -> Result<HttpResponseOk<api::DiskPrefetchStatus>, HttpError> {
+    use propolis::block::prefetch::State;
+
+    let inst = rqctx.context().vm().await?;
+    let crucible_backends = inst.crucible_backends();
+    let path_params = path_params.into_inner();
+
+    let backend = crucible_backends.get(&path_params.id).ok_or_else(|| {
+        let s = format!("no disk with id {}!", path_params.id);
+        HttpError::for_not_found(Some(s.clone()), s)
+    })?;
+    let progress = backend.prefetch_progress().ok_or_else(|| {
+        let s = format!("no prefetch configured for disk {}", path_params.id);
+        HttpError::for_not_found(Some(s.clone()), s)
+    })?;
+
+    Ok(HttpResponseOk(api::DiskPrefetchStatus {
+        state: match progress.state {
+            State::Pending => api::DiskPrefetchState::Pending,
+            State::Running => api::DiskPrefetchState::Running,
+            State::Completed => api::DiskPrefetchState::Completed,
+            State::Canceled => api::DiskPrefetchState::Canceled,
+            State::Failed => api::DiskPrefetchState::Failed,
+        },
+        bytes_done: progress.bytes_done,
+        bytes_total: progress.bytes_total,
+    }))
+}
+
+/// Cancels the background prefetch of a crucible backend.
+#[endpoint {
+    method = DELETE,
+    path = "/instance/disk/{id}/prefetch",
+}]
+async fn instance_crucible_prefetch_cancel(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPrefetchPathParams>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let inst = rqctx.context().vm().await?;
+    let crucible_backends = inst.crucible_backends();
+    let path_params = path_params.into_inner();
+
+    let backend = crucible_backends.get(&path_params.id).ok_or_else(|| {
+        let s = format!("no disk with id {}!", path_params.id);
+        HttpError::for_not_found(Some(s.clone()), s)
+    })?;
+    if !backend.cancel_prefetch() {
+        let s = format!("no prefetch configured for disk {}", path_params.id);
+        return Err(HttpError::for_not_found(Some(s.clone()), s));
+    }
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Gets the health of the storage behind a crucible backend.
+#[endpoint {
+    method = GET,
+    path = "/instance/disk/{id}/health",
+}]
+async fn instance_crucible_health_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskHealthPathParams>,
+) -> Result<HttpResponseOk<api::DiskHealthStatus>, HttpError> {
+    use propolis::block::health::HealthState;
+
+    let inst = rqctx.context().vm().await?;
+    let crucible_backends = inst.crucible_backends();
+    let path_params = path_params.into_inner();
+
+    let backend = crucible_backends.get(&path_params.id).ok_or_else(|| {
+        let s = format!("no disk with id {}!", path_params.id);
+        HttpError::for_not_found(Some(s.clone()), s)
+    })?;
+    let health = backend.health_status().ok_or_else(|| {
+        let s = format!("no retry configured for disk {}", path_params.id);
+        HttpError::for_not_found(Some(s.clone()), s)
+    })?;
+
+    Ok(HttpResponseOk(api::DiskHealthStatus {
+        state: match health.state {
+            HealthState::Healthy => api::DiskHealthState::Healthy,
+            HealthState::Degraded => api::DiskHealthState::Degraded,
+            HealthState::Stalled => api::DiskHealthState::Stalled,
+            HealthState::Failed => api::DiskHealthState::Failed,
+        },
+        consecutive_failures: health.consecutive_failures,
+        ms_since_success: health
+            .last_success
+            .map(|t| t.elapsed().as_millis() as u64),
+    }))
+}
+
 /// Issues a volume_construction_request replace to a crucible backend.
 #[endpoint {
     method = PUT,
@@ -988,10 +1369,10 @@ async fn instance_issue_crucible_vcr_request(
     let mut spec = vm_controller.instance_spec().await;
     let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
 
-    let (readonly, old_vcr_json) = {
+    let (readonly, prefetch, retry, old_vcr_json) = {
         let bes = &v0_spec.backends.storage_backends.get(&disk_name);
         if let Some(StorageBackendV0::Crucible(bes)) = bes {
-            (bes.readonly, &bes.request_json)
+            (bes.readonly, bes.prefetch, bes.retry, &bes.request_json)
         } else {
             let s = format!("Crucible backend for {:?} not found", disk_name);
             return Err(HttpError::for_not_found(Some(s.clone()), s));
@@ -1026,6 +1407,8 @@ async fn instance_issue_crucible_vcr_request(
         StorageBackendV0::Crucible(CrucibleStorageBackend {
             readonly,
             request_json: new_vcr_json,
+            prefetch,
+            retry,
         });
     v0_spec.backends.storage_backends.insert(disk_name, new_storage_backend);
 
@@ -1048,6 +1431,1036 @@ async fn instance_issue_nmi(
     Ok(HttpResponseOk(()))
 }
 
+/// Removes a network device from the instance.
+///
+/// The guest is asked to release the device via ACPI hotplug. If it does not
+/// do so in time, the device is surprise-removed.
+#[endpoint {
+    method = POST,
+    path = "/instance/nics/{name}/remove",
+}]
+async fn instance_nic_remove(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicRemovePathParams>,
+    request: TypedBody<api::NicRemoveRequest>,
+) -> Result<HttpResponseOk<api::NicRemoveResponse>, HttpError> {
+    const DEFAULT_EJECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    let name = path_params.into_inner().name;
+    let timeout = request
+        .into_inner()
+        .eject_timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_EJECT_TIMEOUT);
+
+    let vm = rqctx.context().vm().await?;
+    let guest_ejected =
+        vm.remove_network_device(&name, timeout, &rqctx.request_id).await?;
+    Ok(HttpResponseOk(api::NicRemoveResponse { guest_ejected }))
+}
+
+/// Adds a network device, and its backend, to the running instance.
+///
+/// The device is started and the guest is notified of its arrival via ACPI
+/// hotplug.
+#[endpoint {
+    method = POST,
+    path = "/instance/nics/{name}/attach",
+}]
+async fn instance_nic_attach(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+    request: TypedBody<api::NicAttachRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let name = path_params.into_inner().name;
+    let api::NicAttachRequest { device, backend } = request.into_inner();
+
+    let vm = rqctx.context().vm().await?;
+    vm.attach_network_device(&name, device, backend, &rqctx.request_id).await?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Adds a disk, and its backend, to the running instance.
+///
+/// The disk is started and the guest is notified of its arrival via ACPI
+/// hotplug.
+#[endpoint {
+    method = POST,
+    path = "/instance/disks/{name}/attach",
+}]
+async fn instance_disk_attach(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::DiskAttachRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let name = path_params.into_inner().name;
+    let api::DiskAttachRequest { device, backend } = request.into_inner();
+
+    let vm = rqctx.context().vm().await?;
+    vm.attach_storage_device(&name, device, backend, &rqctx.request_id).await?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Removes a disk from the instance.
+///
+/// The guest is asked to release the disk via ACPI hotplug. If it does not do
+/// so in time, the disk is surprise-removed.
+#[endpoint {
+    method = POST,
+    path = "/instance/disks/{name}/remove",
+}]
+async fn instance_disk_remove(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::DiskRemoveRequest>,
+) -> Result<HttpResponseOk<api::DiskRemoveResponse>, HttpError> {
+    const DEFAULT_EJECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    let name = path_params.into_inner().name;
+    let timeout = request
+        .into_inner()
+        .eject_timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_EJECT_TIMEOUT);
+
+    let vm = rqctx.context().vm().await?;
+    let guest_ejected =
+        vm.remove_storage_device(&name, timeout, &rqctx.request_id).await?;
+    Ok(HttpResponseOk(api::DiskRemoveResponse { guest_ejected }))
+}
+
+/// Sets or clears write-protection on one of the instance's disks.
+///
+/// A write-protected disk fails any writes issued by the guest, and reports
+/// itself as read-only to the guest.
+#[endpoint {
+    method = PUT,
+    path = "/instance/disks/{name}/write-protect",
+}]
+async fn instance_disk_write_protect_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::DiskWriteProtectRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let name = path_params.into_inner().name;
+    let write_protected = request.into_inner().write_protected;
+
+    let vm = rqctx.context().vm().await?;
+    vm.set_disk_write_protect(&name, write_protected).await?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Sets the I/O priority class of one of the instance's disks.
+///
+/// While disks of a higher class have I/O in flight, the I/O of disks in
+/// lower classes is limited, so that it cannot starve them.
+#[endpoint {
+    method = PUT,
+    path = "/instance/disks/{name}/priority",
+}]
+async fn instance_disk_priority_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::DiskPriorityRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let name = path_params.into_inner().name;
+    let priority = request.into_inner().priority;
+
+    let vm = rqctx.context().vm().await?;
+    vm.set_disk_priority(&name, priority).await?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Exports the contents of one of the instance's disks.
+///
+/// The instance is paused until the export ends, so that the contents are
+/// crash-consistent, then resumes. The contents are streamed in a sparse
+/// format, in which ranges of the disk that read as zeroes are elided. Disks
+/// backed by Crucible cannot be exported.
+///
+/// An export may take a checkpoint of the disk, recorded in the stream's
+/// header, after which the disk's changed ranges are tracked. The checkpoint
+/// takes effect only if the stream is sent in full. A later export since that
+/// checkpoint holds only those ranges, and must name the disk's most recent
+/// checkpoint. Checkpoints do not survive migration.
+///
+/// So that a client cannot hold the instance paused indefinitely, the export
+/// is abandoned, and the instance resumed, if the client stops receiving it
+/// or if it outlasts the request's deadline or the server's ten-minute limit.
+/// A request to stop the instance also ends the export, and the instance then
+/// stops without resuming.
+#[endpoint {
+    method = POST,
+    path = "/instance/disks/{name}/export",
+}]
+async fn instance_disk_export(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DiskPathParams>,
+    request: TypedBody<api::DiskExportRequest>,
+) -> Result<http::Response<hyper::Body>, HttpError> {
+    // How long the client may leave a part of the stream unreceived before
+    // the export is abandoned.
+    const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+    let name = path_params.into_inner().name;
+    let request = request.into_inner();
+    let deadline = request
+        .deadline_secs
+        .map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs));
+
+    let vm = rqctx.context().vm().await?;
+    let export = vm
+        .pause_for_disk_export(
+            &name,
+            request.since,
+            request.checkpoint,
+            &rqctx.request_id,
+        )
+        .await?;
+    let backend = export.backend.clone();
+    let exporter = match export.delta.clone() {
+        Some(delta) => Exporter::delta(backend, delta),
+        None => Exporter::new(backend),
+    };
+    let exporter = match export.checkpoint {
+        Some(checkpoint) => exporter.with_checkpoint(checkpoint),
+        None => exporter,
+    };
+    let log = rqctx.log.new(o!("disk" => name));
+
+    // The backend is read on a blocking thread, from which each part of the
+    // stream is handed to the client as it is produced.
+    let (mut body_tx, body) = hyper::Body::channel();
+    let rt = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        // The instance resumes once the export is dropped, when the stream
+        // ends for whatever reason.  Any checkpoint it records only takes
+        // effect should the stream be sent in full.
+        for part in exporter {
+            let part = match part {
+                Ok(part) => part,
+                Err(e) => {
+                    error!(log, "disk export failed"; "error" => %e);
+                    body_tx.abort();
+                    return;
+                }
+            };
+            // A part read as the instance stopped waiting may not reflect a
+            // paused disk, so nothing more is sent once that happens.
+            if export.is_abandoned() {
+                warn!(log, "disk export abandoned by instance");
+                body_tx.abort();
+                return;
+            }
+            let now = tokio::time::Instant::now();
+            let timeout_at = match deadline {
+                Some(deadline) if deadline <= now => {
+                    warn!(log, "disk export passed its deadline");
+                    body_tx.abort();
+                    return;
+                }
+                Some(deadline) => deadline.min(now + STALL_TIMEOUT),
+                None => now + STALL_TIMEOUT,
+            };
+            let sent = rt.block_on(tokio::time::timeout_at(
+                timeout_at,
+                body_tx.send_data(part.into()),
+            ));
+            match sent {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => {
+                    warn!(log, "disk export abandoned by client");
+                    return;
+                }
+                Err(_) => {
+                    warn!(log, "disk export timed out");
+                    body_tx.abort();
+                    return;
+                }
+            }
+        }
+        export.commit_checkpoint();
+        slog::info!(log, "disk export complete");
+    });
+
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .body(body)
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+}
+
+/// Enables or disables one of the instance's storage or network devices.
+///
+/// A disabled device remains in the instance's inventory and spec, but is
+/// hidden from the guest as if its PCI slot were empty. The change takes
+/// effect when the instance next reboots.
+#[endpoint {
+    method = PUT,
+    path = "/instance/devices/{name}/enabled",
+}]
+async fn instance_device_enabled_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::DevicePathParams>,
+    request: TypedBody<api::DeviceEnabledRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let name = path_params.into_inner().name;
+    let enabled = request.into_inner().enabled;
+
+    let vm = rqctx.context().vm().await?;
+    vm.set_device_enabled(&name, enabled).await?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Removes a vCPU from the instance.
+///
+/// The guest is asked to offline and eject the vCPU via ACPI hotplug. If it
+/// does not do so in time, the request is abandoned and the vCPU remains in
+/// service.
+#[endpoint {
+    method = POST,
+    path = "/instance/vcpus/{id}/remove",
+}]
+async fn instance_vcpu_remove(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::VcpuRemovePathParams>,
+    request: TypedBody<api::VcpuRemoveRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    const DEFAULT_EJECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    let id = path_params.into_inner().id;
+    let timeout = request
+        .into_inner()
+        .eject_timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_EJECT_TIMEOUT);
+
+    let vm = rqctx.context().vm().await?;
+    vm.remove_vcpu(id.into(), timeout, &rqctx.request_id).await?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+fn maintenance_notice_to_api(
+    notice: propolis::hw::acpi::maintenance::MaintenanceNotice,
+) -> api::MaintenanceNotice {
+    use propolis::hw::acpi::maintenance::MaintenanceKind;
+    api::MaintenanceNotice {
+        kind: match notice.kind {
+            MaintenanceKind::Shutdown => api::MaintenanceKind::Shutdown,
+            MaintenanceKind::Migration => api::MaintenanceKind::Migration,
+        },
+        generation: notice.generation,
+        remaining_ms: notice
+            .deadline
+            .saturating_duration_since(std::time::Instant::now())
+            .as_millis() as u64,
+        guest_acknowledged: notice.acked,
+    }
+}
+
+/// Returns the maintenance notice currently posted to the guest, if any.
+#[endpoint {
+    method = GET,
+    path = "/instance/maintenance",
+}]
+async fn instance_maintenance_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::MaintenanceStatus>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    let notice = vm.maintenance_notice().map(maintenance_notice_to_api);
+    Ok(HttpResponseOk(api::MaintenanceStatus { notice }))
+}
+
+/// Notifies the guest of impending maintenance.
+///
+/// An ACPI event informs the guest of the kind of maintenance and its
+/// deadline, so that applications can drain work before the disruption. A
+/// notice replaces any which was previously posted.
+#[endpoint {
+    method = PUT,
+    path = "/instance/maintenance",
+}]
+async fn instance_maintenance_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::MaintenanceNoticeRequest>,
+) -> Result<HttpResponseOk<api::MaintenanceNotice>, HttpError> {
+    use propolis::hw::acpi::maintenance::MaintenanceKind;
+
+    let request = request.into_inner();
+    let kind = match request.kind {
+        api::MaintenanceKind::Shutdown => MaintenanceKind::Shutdown,
+        api::MaintenanceKind::Migration => MaintenanceKind::Migration,
+    };
+    let deadline = Duration::from_secs(request.deadline_secs.into());
+
+    let vm = rqctx.context().vm().await?;
+    let notice = vm.post_maintenance(kind, deadline);
+    Ok(HttpResponseOk(maintenance_notice_to_api(notice)))
+}
+
+/// Withdraws the maintenance notice posted to the guest, notifying it that the
+/// maintenance is no longer expected.
+#[endpoint {
+    method = DELETE,
+    path = "/instance/maintenance",
+}]
+async fn instance_maintenance_delete(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    vm.withdraw_maintenance().ok_or_else(|| {
+        HttpError::for_not_found(
+            None,
+            "no maintenance notice is posted".to_string(),
+        )
+    })?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Returns the limit on the fraction of time the instance's vCPUs may run.
+#[endpoint {
+    method = GET,
+    path = "/instance/duty-cycle",
+}]
+async fn instance_duty_cycle_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceDutyCycle>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    Ok(HttpResponseOk(api::InstanceDutyCycle {
+        limit_percent: vm.duty_cycle_limit(),
+    }))
+}
+
+/// Limits the fraction of time the instance's vCPUs may run.
+///
+/// vCPUs which have used their share of each brief period are forced to
+/// sleep until the next begins. A limit of 100 percent lifts the throttling.
+#[endpoint {
+    method = PUT,
+    path = "/instance/duty-cycle",
+}]
+async fn instance_duty_cycle_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceDutyCycle>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let request = request.into_inner();
+    let vm = rqctx.context().vm().await?;
+    vm.set_duty_cycle_limit(request.limit_percent)
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Returns the memory pressure on the instance's host, and the instance's
+/// response to it.
+#[endpoint {
+    method = GET,
+    path = "/instance/memory-pressure",
+}]
+async fn instance_memory_pressure_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceMemoryPressureStatus>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    let status = vm.memory_pressure_status().ok_or_else(|| {
+        HttpError::for_not_found(
+            None,
+            "memory pressure backstop is not configured".to_string(),
+        )
+    })?;
+    Ok(HttpResponseOk(status))
+}
+
+/// Reports the memory pressure on the instance's host.
+///
+/// The instance responds to the more severe of the pressure reported here and
+/// that which it observes on the host itself. The pressure is logged and
+/// recorded, and should it be severe, or persist beyond a grace period, the
+/// guest's vCPUs are throttled, slowing its demand for memory. No memory is
+/// reclaimed from the guest. The instance returns to its former duty-cycle
+/// limit once pressure subsides.
+#[endpoint {
+    method = PUT,
+    path = "/instance/memory-pressure",
+}]
+async fn instance_memory_pressure_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceMemoryPressureRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let request = request.into_inner();
+    let vm = rqctx.context().vm().await?;
+    if !vm.report_memory_pressure(request.level) {
+        return Err(HttpError::for_not_found(
+            None,
+            "memory pressure backstop is not configured".to_string(),
+        ));
+    }
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Injects keyboard and pointer input into the instance.
+///
+/// Keys are identified by their X11 keysyms, as over VNC, and are delivered to
+/// the instance's virtio-input keyboard if it has one, or its PS/2 keyboard
+/// otherwise. Pointer movement is relative, and is reported by the PS/2 mouse
+/// as its driver requests. Tablet positions are absolute, and require a
+/// virtio-input tablet.
+#[endpoint {
+    method = PUT,
+    path = "/instance/input",
+}]
+async fn instance_input_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceInputRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let request = request.into_inner();
+    let vm = rqctx.context().vm().await?;
+    let ps2ctrl = vm.ps2ctrl().ok_or_else(|| {
+        HttpError::for_not_found(
+            None,
+            "instance has no PS/2 controller".to_string(),
+        )
+    })?;
+
+    // Reject the request as a whole, rather than inject only part of it
+    for event in request.events.iter() {
+        match event {
+            api::InputEvent::Key(key) => {
+                if !PS2Ctrl::keysym_supported(key.keysym) {
+                    return Err(HttpError::for_bad_request(
+                        None,
+                        format!("unsupported keysym {:#x}", key.keysym),
+                    ));
+                }
+            }
+            api::InputEvent::Pointer(_) => {}
+            api::InputEvent::Tablet(tablet) => {
+                if vm.tablet().is_none() {
+                    return Err(HttpError::for_not_found(
+                        None,
+                        "instance has no tablet".to_string(),
+                    ));
+                }
+                if tablet.x > TABLET_ABS_MAX || tablet.y > TABLET_ABS_MAX {
+                    return Err(HttpError::for_bad_request(
+                        None,
+                        format!(
+                            "tablet position ({}, {}) exceeds {}",
+                            tablet.x, tablet.y, TABLET_ABS_MAX
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    for event in request.events {
+        match event {
+            api::InputEvent::Key(key) => {
+                if let Some(keyboard) = vm.keyboard() {
+                    keyboard.keysym_event(key.keysym, key.pressed);
+                } else {
+                    ps2ctrl.keysym_event(key.keysym, key.pressed);
+                }
+            }
+            api::InputEvent::Pointer(pointer) => {
+                let buttons =
+                    mouse_buttons(pointer.left, pointer.right, pointer.middle);
+                ps2ctrl.mouse_event(pointer.dx, pointer.dy, buttons);
+            }
+            api::InputEvent::Tablet(tablet) => {
+                let buttons =
+                    mouse_buttons(tablet.left, tablet.right, tablet.middle);
+                vm.tablet().unwrap().tablet_event(tablet.x, tablet.y, buttons);
+            }
+        }
+    }
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+fn mouse_buttons(left: bool, right: bool, middle: bool) -> MouseButtons {
+    let mut buttons = MouseButtons::empty();
+    buttons.set(MouseButtons::LEFT, left);
+    buttons.set(MouseButtons::RIGHT, right);
+    buttons.set(MouseButtons::MIDDLE, middle);
+    buttons
+}
+
+/// Returns the state of the instance's hot-pluggable memory.
+#[endpoint {
+    method = GET,
+    path = "/instance/hotplug-memory",
+}]
+async fn instance_hotplug_memory_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceHotplugMemoryStatus>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    let mem = vm.hotplug_memory().ok_or_else(|| {
+        HttpError::for_not_found(
+            None,
+            "instance has no hot-pluggable memory".to_string(),
+        )
+    })?;
+    Ok(HttpResponseOk(api::InstanceHotplugMemoryStatus {
+        region_bytes: mem.region_size() as u64,
+        requested_bytes: mem.requested_size() as u64,
+        plugged_bytes: mem.plugged_size() as u64,
+    }))
+}
+
+/// Asks the guest to plug (or unplug) hot-pluggable memory, so that it uses
+/// the requested amount.
+///
+/// The guest plugs memory in its own time, and may decline to do so entirely,
+/// so the request is complete once it has been relayed to the guest.
+#[endpoint {
+    method = PUT,
+    path = "/instance/hotplug-memory",
+}]
+async fn instance_hotplug_memory_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceHotplugMemoryRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let request = request.into_inner();
+    let vm = rqctx.context().vm().await?;
+    let region_bytes = vm
+        .hotplug_memory()
+        .ok_or_else(|| {
+            HttpError::for_not_found(
+                None,
+                "instance has no hot-pluggable memory".to_string(),
+            )
+        })?
+        .region_size() as u64;
+
+    let block_size = MEM_BLOCK_SIZE as u64;
+    if request.requested_bytes % block_size != 0 {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!(
+                "requested memory must be a multiple of {block_size} bytes"
+            ),
+        ));
+    }
+    if request.requested_bytes > region_bytes {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!(
+                "requested memory exceeds hot-pluggable region \
+                ({region_bytes} bytes)"
+            ),
+        ));
+    }
+
+    vm.set_hotplug_memory_request(request.requested_bytes as usize);
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Returns the server's snapshot directory, or an error if none is configured.
+fn snapshot_dir(
+    ctx: &DropshotEndpointContext,
+) -> Result<Arc<crate::snapshot::SnapshotDir>, HttpError> {
+    ctx.static_config.snapshot_dir.clone().ok_or_else(|| {
+        HttpError::for_bad_request(
+            None,
+            "no snapshot directory is configured".to_string(),
+        )
+    })
+}
+
+/// Saves a snapshot of the instance's state to a file in the server's
+/// snapshot directory.
+///
+/// The instance is paused while the snapshot is written. Once the snapshot has
+/// been saved, the instance either resumes or, if requested, stops.
+#[endpoint {
+    method = PUT,
+    path = "/instance/snapshot",
+}]
+async fn instance_snapshot_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceSnapshotRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let request = request.into_inner();
+    let dir = snapshot_dir(rqctx.context())?;
+    let vm = rqctx.context().vm().await?.clone();
+    let file = {
+        let (dir, name) = (dir.clone(), request.path.clone());
+        tokio::task::spawn_blocking(move || dir.create(&name)).await.unwrap()
+    }
+    .map_err(|e| {
+        HttpError::for_bad_request(
+            None,
+            format!("failed to create snapshot file {}: {}", request.path, e),
+        )
+    })?;
+
+    if let Err(e) =
+        vm.save_snapshot(file, request.stop, &rqctx.request_id).await
+    {
+        // Don't leave an incomplete snapshot behind to be restored later.
+        let name = request.path.clone();
+        let _ = tokio::task::spawn_blocking(move || dir.remove(&name)).await;
+        return Err(e.into());
+    }
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Creates the instance from a snapshot previously saved in the server's
+/// snapshot directory, then starts it.
+///
+/// The instance is created with the properties and spec recorded in the
+/// snapshot, so any backends it refers to must be available to this server.
+#[endpoint {
+    method = PUT,
+    path = "/instance/snapshot/restore",
+}]
+async fn instance_snapshot_restore(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceSnapshotRestoreRequest>,
+) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
+    let request = request.into_inner();
+    let server_context = Arc::clone(rqctx.context());
+    let request_id = rqctx.request_id.clone();
+    let dir = snapshot_dir(&server_context)?;
+
+    let (file, header) = {
+        let name = request.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = dir.open_file(&name).map_err(|e| {
+                format!("failed to open snapshot file {}: {}", name, e)
+            })?;
+            let header =
+                crate::snapshot::read_header(&mut file).map_err(|e| {
+                    format!("failed to read snapshot {}: {}", name, e)
+                })?;
+            Ok((file, header))
+        })
+        .await
+        .unwrap()
+        .map_err(|msg: String| HttpError::for_bad_request(None, msg))?
+    };
+
+    let response = instance_ensure_common(
+        rqctx,
+        api::InstanceSpecEnsureRequest {
+            properties: header.properties,
+            instance_spec: header.instance_spec,
+            migrate: None,
+        },
+    )
+    .await?;
+
+    server_context
+        .vm()
+        .await?
+        .request_restore_from_snapshot(file, &request_id)?;
+    Ok(response)
+}
+
+/// Limits on the metadata which may be attached to an instance.
+const METADATA_MAX_ENTRIES: usize = 64;
+const METADATA_MAX_KEY_LEN: usize = 128;
+const METADATA_MAX_VALUE_LEN: usize = 1024;
+
+fn validate_metadata(
+    metadata: &BTreeMap<String, String>,
+) -> Result<(), HttpError> {
+    let err = |msg: String| Err(HttpError::for_bad_request(None, msg));
+    if metadata.len() > METADATA_MAX_ENTRIES {
+        return err(format!(
+            "instance metadata has {} entries, exceeding limit of {}",
+            metadata.len(),
+            METADATA_MAX_ENTRIES
+        ));
+    }
+    for (key, value) in metadata.iter() {
+        if key.is_empty() || key.len() > METADATA_MAX_KEY_LEN {
+            return err(format!(
+                "metadata key {:?} must be 1-{} bytes long",
+                key, METADATA_MAX_KEY_LEN
+            ));
+        }
+        if value.len() > METADATA_MAX_VALUE_LEN {
+            return err(format!(
+                "metadata value for {:?} exceeds {} bytes",
+                key, METADATA_MAX_VALUE_LEN
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the key/value metadata attached to the instance.
+#[endpoint {
+    method = GET,
+    path = "/instance/metadata",
+}]
+async fn instance_metadata_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceMetadata>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    let metadata = vm.properties().metadata;
+    Ok(HttpResponseOk(api::InstanceMetadata { metadata }))
+}
+
+/// Replaces the key/value metadata attached to the instance.
+///
+/// Metadata has no effect on the guest; it is reported with the instance's
+/// properties, and tags the server's log output.
+#[endpoint {
+    method = PUT,
+    path = "/instance/metadata",
+}]
+async fn instance_metadata_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceMetadata>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let api::InstanceMetadata { metadata } = request.into_inner();
+    validate_metadata(&metadata)?;
+    let vm = rqctx.context().vm().await?;
+    vm.set_metadata(metadata);
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Returns the server's current runtime debugging settings.
+#[endpoint {
+    method = GET,
+    path = "/debug/settings",
+}]
+async fn debug_settings_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::DebugSettings>, HttpError> {
+    let ctx = rqctx.context();
+    Ok(HttpResponseOk(log_control::current_settings(
+        &ctx.static_config.log_level,
+    )))
+}
+
+/// Alters the server's log level and enabled trace categories without
+/// restarting the instance.
+#[endpoint {
+    method = PUT,
+    path = "/debug/settings",
+}]
+async fn debug_settings_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::DebugSettingsUpdate>,
+) -> Result<HttpResponseOk<api::DebugSettings>, HttpError> {
+    let ctx = rqctx.context();
+    let request = request.into_inner();
+    let hdl = &ctx.static_config.log_level;
+
+    if let Some(level) = request.log_level {
+        hdl.set(log_control::level_from_api(level));
+    }
+    if let Some(cats) = request.trace_categories.as_ref() {
+        propolis::trace::set_enabled(log_control::trace_from_api(cats));
+    }
+    if let Some(enabled) = request.exit_stats {
+        propolis::exit_stats::set_enabled(enabled);
+    }
+
+    let settings = log_control::current_settings(hdl);
+    slog::info!(ctx.log, "Debug settings updated"; "settings" => ?settings);
+
+    Ok(HttpResponseOk(settings))
+}
+
+/// Reports the time spent handling each kind of vCPU exit.
+///
+/// Latencies, and the time each vCPU spends blocked in the kernel, are only
+/// collected while enabled via the debug settings.
+#[endpoint {
+    method = GET,
+    path = "/debug/exit-latency",
+}]
+async fn debug_exit_latency_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::ExitLatencyResponse>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    let stats = vm.exit_stats();
+
+    let histograms = stats
+        .iter()
+        .flat_map(|(vcpu, sites)| {
+            sites.iter().map(|(site, hist)| api::ExitLatencyHistogram {
+                vcpu: *vcpu,
+                cause: site.cause.as_str().to_string(),
+                device: site.device_name(),
+                count: hist.count,
+                total_ns: hist.total_ns,
+                buckets: hist.buckets.to_vec(),
+            })
+        })
+        .collect();
+    let folded = propolis::exit_stats::folded(
+        stats.iter().map(|(vcpu, sites)| (*vcpu, sites)),
+    );
+    let halts = vm
+        .halt_stats()
+        .into_iter()
+        .map(|(vcpu, stats)| api::HaltResidency {
+            vcpu,
+            blocked_ns: stats.blocked_ns,
+        })
+        .collect();
+    Ok(HttpResponseOk(api::ExitLatencyResponse { histograms, folded, halts }))
+}
+
+/// Discards the vCPU exit latencies and halt residency collected thus far.
+#[endpoint {
+    method = DELETE,
+    path = "/debug/exit-latency",
+}]
+async fn debug_exit_latency_reset(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    vm.reset_exit_stats();
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Reports the host resources held by each device and backend.
+///
+/// Owners remain listed for as long as they hold any resources, so entries
+/// which persist after their device has been removed indicate a leak.
+#[endpoint {
+    method = GET,
+    path = "/debug/host-resources",
+}]
+async fn debug_host_resources_get(
+    _rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::HostResourcesResponse>, HttpError> {
+    let owners = propolis::hostres::snapshot()
+        .into_iter()
+        .map(|(owner, usage)| api::HostResourceUsage {
+            owner,
+            fds: usage.fds,
+            mapped_bytes: usage.mapped_bytes,
+            threads: usage.threads,
+            tasks: usage.tasks,
+            viona_links: usage.viona_links,
+        })
+        .collect();
+    Ok(HttpResponseOk(api::HostResourcesResponse { owners }))
+}
+
+/// Gets the most recent POST codes written by the guest's firmware.
+///
+/// These indicate how far the guest progressed through boot, which helps to
+/// diagnose instances which fail to boot.
+#[endpoint {
+    method = GET,
+    path = "/instance/post-codes",
+}]
+async fn instance_post_codes_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::PostCodesResponse>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    let (codes, total) = vm.post_codes();
+    let codes = codes.iter().map(boot_watchdog::post_code_entry).collect();
+    Ok(HttpResponseOk(api::PostCodesResponse { codes, total }))
+}
+
+/// Gets the guest's progress through boot, as judged by the boot watchdog.
+///
+/// If the guest failed to boot within the deadline configured for the server,
+/// the instance is flagged as degraded and the diagnostics captured at the
+/// deadline are included.
+#[endpoint {
+    method = GET,
+    path = "/instance/boot-status",
+}]
+async fn instance_boot_status_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::InstanceBootStatus>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    Ok(HttpResponseOk(vm.boot_status()))
+}
+
+/// Exports the configuration space of every PCI function in the instance.
+///
+/// Comparing the output of a migration source with that of its target can
+/// catch device state which was not faithfully transferred.
+#[endpoint {
+    method = GET,
+    path = "/debug/pci-config",
+}]
+async fn debug_pci_config_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::PciConfigResponse>, HttpError> {
+    let vm = rqctx.context().vm().await?;
+    let functions = vm
+        .pci_cfg_dump()
+        .into_iter()
+        .map(|(bdf, data)| api::PciConfigSpace {
+            pci_path: instance_spec::PciPath::new(
+                bdf.bus.get(),
+                bdf.location.dev.get(),
+                bdf.location.func.get(),
+            )
+            .expect("BDF is a valid PCI path"),
+            data: pci_hex_dump(&data),
+        })
+        .collect();
+    Ok(HttpResponseOk(api::PciConfigResponse { functions }))
+}
+
+/// Warps the clock from which the instance's userspace-emulated devices tell
+/// time, returning its state once warped.
+///
+/// The clock can be frozen, stepped forward, or run faster or slower than the
+/// host's, so that tests can exercise time-dependent device behavior without
+/// waiting on real time. Devices emulated by the kernel, such as the PIT and
+/// HPET, are unaffected. The clock can only be warped if the server has been
+/// configured to permit it.
+#[endpoint {
+    method = PUT,
+    path = "/debug/clock",
+}]
+async fn debug_clock_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::DebugClockRequest>,
+) -> Result<HttpResponseOk<api::DebugClock>, HttpError> {
+    let request = request.into_inner();
+    let ctx = rqctx.context();
+    if !ctx.static_config.vm.warpable_clock {
+        return Err(HttpError::for_not_found(
+            None,
+            "server is not configured with a warpable clock".to_string(),
+        ));
+    }
+    let vm = ctx.vm().await?;
+    let clock = vm.clock();
+    if let Some(rate) = request.rate_percent {
+        clock.set_rate(rate);
+    }
+    if let Some(ms) = request.step_ms {
+        clock.step(std::time::Duration::from_millis(ms));
+    }
+    slog::info!(ctx.log, "warped clock";
+        "rate_percent" => ?request.rate_percent,
+        "step_ms" => ?request.step_ms);
+
+    Ok(HttpResponseOk(api::DebugClock {
+        time_ns: clock.unix_time().as_nanos() as u64,
+        rate_percent: clock.rate(),
+        warped: clock.is_warped(),
+    }))
+}
+
+/// Formats `data` as lines of 16 bytes, each prefixed by its offset.
+fn pci_hex_dump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        out += &format!("{:02x}:", i * 16);
+        for byte in line {
+            out += &format!(" {:02x}", byte);
+        }
+        out.push('\n');
+    }
+    out
+}
+
 /// Returns a Dropshot [`ApiDescription`] object to launch a server.
 pub fn api() -> ApiDescription<Arc<DropshotEndpointContext>> {
     let mut api = ApiDescription::new();
@@ -1063,7 +2476,43 @@ pub fn api() -> ApiDescription<Arc<DropshotEndpointContext>> {
     api.register(instance_migrate_status).unwrap();
     api.register(instance_issue_crucible_snapshot_request).unwrap();
     api.register(instance_issue_crucible_vcr_request).unwrap();
+    api.register(instance_crucible_prefetch_get).unwrap();
+    api.register(instance_crucible_prefetch_cancel).unwrap();
+    api.register(instance_crucible_health_get).unwrap();
     api.register(instance_issue_nmi).unwrap();
+    api.register(instance_nic_remove).unwrap();
+    api.register(instance_nic_attach).unwrap();
+    api.register(instance_disk_attach).unwrap();
+    api.register(instance_disk_remove).unwrap();
+    api.register(instance_disk_write_protect_put).unwrap();
+    api.register(instance_disk_priority_put).unwrap();
+    api.register(instance_disk_export).unwrap();
+    api.register(instance_vsock).unwrap();
+    api.register(instance_device_enabled_put).unwrap();
+    api.register(instance_vcpu_remove).unwrap();
+    api.register(instance_post_codes_get).unwrap();
+    api.register(instance_boot_status_get).unwrap();
+    api.register(instance_maintenance_get).unwrap();
+    api.register(instance_maintenance_put).unwrap();
+    api.register(instance_maintenance_delete).unwrap();
+    api.register(instance_duty_cycle_get).unwrap();
+    api.register(instance_duty_cycle_put).unwrap();
+    api.register(instance_memory_pressure_get).unwrap();
+    api.register(instance_memory_pressure_put).unwrap();
+    api.register(instance_input_put).unwrap();
+    api.register(instance_hotplug_memory_get).unwrap();
+    api.register(instance_hotplug_memory_put).unwrap();
+    api.register(instance_snapshot_put).unwrap();
+    api.register(instance_snapshot_restore).unwrap();
+    api.register(instance_metadata_get).unwrap();
+    api.register(instance_metadata_put).unwrap();
+    api.register(debug_settings_get).unwrap();
+    api.register(debug_settings_put).unwrap();
+    api.register(debug_exit_latency_get).unwrap();
+    api.register(debug_exit_latency_reset).unwrap();
+    api.register(debug_host_resources_get).unwrap();
+    api.register(debug_pci_config_get).unwrap();
+    api.register(debug_clock_put).unwrap();
 
     api
 }
@@ -0,0 +1,581 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Saving a paused instance's state to a file and restoring it later.
+//!
+//! A snapshot captures the same state that live migration transfers: the
+//! instance's spec, its guest RAM, its VMM time data, and the exported state of
+//! every entity in its inventory (which includes its vCPUs). The contents of
+//! the instance's disks are not included; their backends are expected to still
+//! be available when the snapshot is restored.
+//!
+//! A snapshot file begins with an eight-byte magic number and a big-endian
+//! 32-bit format version. These are followed by a sequence of sections, each
+//! of which is a one-byte tag, a big-endian 64-bit length, and that many bytes
+//! of contents. The sections appear in this order:
+//!
+//! - `Header`: the instance's properties and spec, as JSON.
+//! - `Time`: the instance's VMM time data, as JSON.
+//! - `Memory`, once per region of guest DRAM: a one-byte length, the region's
+//!   name, and the region's contents.
+//! - `Devices`: the exported state of the instance's entities, as JSON.
+//! - `End`: an empty section marking the end of the snapshot.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path};
+
+use propolis::common::GuestRegion;
+use propolis::inventory::Order;
+use propolis::migrate::{
+    MigrateCtx, MigrateStateError, Migrator, PayloadOffer, PayloadOffers,
+    PayloadOutputs,
+};
+use propolis::vmm::{self, SubMapping};
+use propolis_api_types::instance_spec::VersionedInstanceSpec;
+use propolis_api_types::InstanceProperties;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use thiserror::Error;
+
+/// The magic number at the start of every snapshot file.
+const SNAPSHOT_MAGIC: [u8; 8] = *b"PROPSNAP";
+
+/// The version of the snapshot format written by this server.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The largest amount of guest memory moved in a single read or write.
+const MEM_CHUNK_SIZE: usize = 1 << 30;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Not a snapshot file")]
+    BadMagic,
+
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Malformed snapshot: {0}")]
+    Malformed(String),
+
+    #[error("Failed to serialize snapshot state: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Can't snapshot instance with non-migratable device {0}")]
+    NonMigratable(String),
+
+    #[error("Failed to transfer state for device {0}: {1}")]
+    DeviceState(String, MigrateStateError),
+
+    #[error("Failed to transfer VMM time data: {0}")]
+    TimeData(String),
+
+    #[error("Snapshot does not match instance: {0}")]
+    Mismatch(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum SectionTag {
+    Header = 0,
+    Time = 1,
+    Memory = 2,
+    Devices = 3,
+    End = 0xff,
+}
+
+impl SectionTag {
+    fn from_repr(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Self::Header),
+            1 => Some(Self::Time),
+            2 => Some(Self::Memory),
+            3 => Some(Self::Devices),
+            0xff => Some(Self::End),
+            _ => None,
+        }
+    }
+}
+
+/// The directory within which snapshots are saved, and from which they are
+/// restored.
+///
+/// It is held open for the life of the server, and snapshots are named
+/// relative to it, so that requests cannot reach files elsewhere on the host
+/// and the directory remains reachable once the server has been confined (via
+/// `chroot`) to another part of the filesystem.
+pub(crate) struct SnapshotDir(File);
+
+impl SnapshotDir {
+    pub(crate) fn open(path: &Path) -> std::io::Result<Self> {
+        let dir = File::open(path)?;
+        if !dir.metadata()?.is_dir() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        Ok(Self(dir))
+    }
+
+    /// Creates the snapshot file `name`, which must not already exist.
+    pub(crate) fn create(&self, name: &str) -> std::io::Result<File> {
+        self.open_at(
+            name,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC,
+        )
+    }
+
+    /// Opens the existing snapshot file `name` for reading.
+    pub(crate) fn open_file(&self, name: &str) -> std::io::Result<File> {
+        let file = self.open_at(name, libc::O_RDONLY)?;
+        if !file.metadata()?.is_file() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a regular file", name),
+            ));
+        }
+        Ok(file)
+    }
+
+    /// Removes the snapshot file `name`.
+    pub(crate) fn remove(&self, name: &str) -> std::io::Result<()> {
+        let name = Self::file_name(name)?;
+        match unsafe { libc::unlinkat(self.0.as_raw_fd(), name.as_ptr(), 0) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    fn open_at(&self, name: &str, flags: libc::c_int) -> std::io::Result<File> {
+        let name = Self::file_name(name)?;
+        let fd = unsafe {
+            libc::openat(
+                self.0.as_raw_fd(),
+                name.as_ptr(),
+                flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                0o600 as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Safety: The descriptor was just opened, and is owned by nothing else.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Checks that `name` names a file directly within the directory.
+    fn file_name(name: &str) -> std::io::Result<CString> {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => CString::new(name)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e)),
+            _ => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid snapshot file name {:?}", name),
+            )),
+        }
+    }
+}
+
+/// The description of the instance from which a snapshot was taken.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct SnapshotHeader {
+    pub properties: InstanceProperties,
+    pub instance_spec: VersionedInstanceSpec,
+}
+
+/// Serialized state for a single entity in the instance inventory.
+#[derive(Debug, Deserialize, Serialize)]
+struct SnapshotDevice {
+    instance_name: String,
+    payload: Vec<SnapshotDevicePayload>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct SnapshotDevicePayload {
+    kind: String,
+    version: u32,
+    data: Vec<u8>,
+}
+
+/// Writes a snapshot of `instance` to `file`.
+///
+/// The instance's vCPUs, entities, and kernel VMM must all be paused.
+pub(crate) fn save(
+    instance: &propolis::Instance,
+    header: &SnapshotHeader,
+    file: File,
+    log: &Logger,
+) -> Result<(), SnapshotError> {
+    let guard = instance.lock();
+    let machine = guard.machine();
+    let memctx = machine.acc_mem.access().unwrap();
+    let migrate_ctx = MigrateCtx { mem: &memctx };
+
+    // Export device state before writing anything so that an instance which
+    // can't be snapshotted doesn't leave a partial file behind.
+    let mut devices = Vec::new();
+    guard.inventory().for_each_node(Order::Pre, |_, rec| {
+        let name = rec.name();
+        let to_err = |e: MigrateStateError| {
+            SnapshotError::DeviceState(name.to_owned(), e)
+        };
+        let mut payload = Vec::new();
+        match rec.entity().migrate() {
+            Migrator::NonMigratable => {
+                return Err(SnapshotError::NonMigratable(name.to_owned()));
+            }
+            Migrator::Empty => return Ok(()),
+            Migrator::Single(mech) => {
+                let out = mech.export(&migrate_ctx).map_err(to_err)?;
+                payload.push(SnapshotDevicePayload {
+                    kind: out.kind.to_owned(),
+                    version: out.version,
+                    data: serde_json::to_vec(&out.payload)?,
+                });
+            }
+            Migrator::Multi(mech) => {
+                let mut outputs = PayloadOutputs::new();
+                mech.export(&mut outputs, &migrate_ctx).map_err(to_err)?;
+                for part in outputs {
+                    payload.push(SnapshotDevicePayload {
+                        kind: part.kind.to_owned(),
+                        version: part.version,
+                        data: serde_json::to_vec(&part.payload)?,
+                    });
+                }
+            }
+        }
+        devices
+            .push(SnapshotDevice { instance_name: name.to_owned(), payload });
+        Ok(())
+    })?;
+
+    let time_data = vmm::time::export_time_data(&machine.hdl)
+        .map_err(|e| SnapshotError::TimeData(e.to_string()))?;
+
+    let mut file = BufWriter::new(file);
+    file.write_all(&SNAPSHOT_MAGIC)?;
+    file.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+    write_section(&mut file, SectionTag::Header, &serde_json::to_vec(header)?)?;
+    write_section(
+        &mut file,
+        SectionTag::Time,
+        &serde_json::to_vec(&time_data)?,
+    )?;
+
+    for (name, GuestRegion(start, len)) in memctx.dram_regions() {
+        info!(log, "Writing guest memory region {}", name;
+              "start" => start.0, "len" => len);
+        let mapping = memctx.direct_readable_region_by_name(&name)?;
+        let name_len = u8::try_from(name.len()).map_err(|_| {
+            SnapshotError::Malformed(format!("region name {name} too long"))
+        })?;
+        write_section_header(
+            &mut file,
+            SectionTag::Memory,
+            1 + name.len() + mapping.len(),
+        )?;
+        file.write_all(&[name_len])?;
+        file.write_all(name.as_bytes())?;
+        file.flush()?;
+
+        let file = file.get_mut();
+        let offset = file.stream_position()?;
+        write_mapping(file, &mapping, offset)?;
+        file.seek(SeekFrom::Current(mapping.len() as i64))?;
+    }
+
+    info!(log, "Writing state for {} devices", devices.len());
+    write_section(
+        &mut file,
+        SectionTag::Devices,
+        &serde_json::to_vec(&devices)?,
+    )?;
+    write_section(&mut file, SectionTag::End, &[])?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// Reads the header from the snapshot in `file`, leaving the file positioned
+/// at the start of the snapshot's remaining sections.
+pub(crate) fn read_header(
+    file: &mut File,
+) -> Result<SnapshotHeader, SnapshotError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if magic != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+
+    let mut version = [0u8; 4];
+    file.read_exact(&mut version)?;
+    let version = u32::from_be_bytes(version);
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let len = read_section_header(file, SectionTag::Header)?;
+    let mut buf = vec![0u8; len];
+    file.read_exact(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// Loads the state in the snapshot in `file` into `instance`, which must have
+/// been created from the spec in the snapshot's header.
+///
+/// The instance's vCPUs must be activated and its kernel VMM paused.
+pub(crate) fn restore(
+    instance: &propolis::Instance,
+    mut file: File,
+    log: &Logger,
+) -> Result<(), SnapshotError> {
+    read_header(&mut file)?;
+
+    let guard = instance.lock();
+    let machine = guard.machine();
+    let memctx = machine.acc_mem.access().unwrap();
+
+    // Adjust the guest's time data to account for the time spent suspended, in
+    // the same manner as a migration between hosts.
+    let len = read_section_header(&mut file, SectionTag::Time)?;
+    let mut buf = vec![0u8; len];
+    file.read_exact(&mut buf)?;
+    let time_data: vmm::time::VmTimeData = serde_json::from_slice(&buf)?;
+    let (dst_hrt, dst_wc) = vmm::time::host_time_snapshot(&machine.hdl)
+        .map_err(|e| SnapshotError::TimeData(e.to_string()))?;
+    let (time_data, adjust) =
+        vmm::time::adjust_time_data(time_data, dst_hrt, dst_wc)
+            .map_err(|e| SnapshotError::TimeData(e.to_string()))?;
+    info!(log, "Restoring VMM time data";
+          "suspended_ns" => adjust.migrate_delta.as_nanos() as u64);
+    vmm::time::import_time_data(&machine.hdl, time_data)
+        .map_err(|e| SnapshotError::TimeData(e.to_string()))?;
+
+    let mut regions: Vec<String> = memctx
+        .dram_regions()
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| machine.map_physmem.hotplug_region(name).is_none())
+        .collect();
+    let len = loop {
+        let mut tag = [0u8; 1];
+        file.read_exact(&mut tag)?;
+        let len = read_len(&mut file)?;
+        match SectionTag::from_repr(tag[0]) {
+            Some(SectionTag::Memory) => {}
+            Some(SectionTag::Devices) => break len,
+            _ => {
+                return Err(SnapshotError::Malformed(format!(
+                    "unexpected section with tag {}",
+                    tag[0]
+                )))
+            }
+        }
+
+        let mut name_len = [0u8; 1];
+        file.read_exact(&mut name_len)?;
+        let mut name = vec![0u8; name_len[0] as usize];
+        file.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| {
+            SnapshotError::Malformed("region name is not UTF-8".to_string())
+        })?;
+        // Hot-pluggable memory is saved only if some of it was plugged, and
+        // is then backed to receive its contents.  The memory device releases
+        // it again if its state has none of it plugged.
+        if let Some(region) = machine.map_physmem.hotplug_region(&name) {
+            region.plug()?;
+        } else {
+            let Some(idx) = regions.iter().position(|r| *r == name) else {
+                return Err(SnapshotError::Mismatch(format!(
+                    "no memory region named {name}"
+                )));
+            };
+            regions.swap_remove(idx);
+        }
+
+        let mapping = memctx.direct_writable_region_by_name(&name)?;
+        let data_len = len.checked_sub(1 + name.len()).ok_or_else(|| {
+            SnapshotError::Malformed(format!("memory region {name} truncated"))
+        })?;
+        if data_len != mapping.len() {
+            return Err(SnapshotError::Mismatch(format!(
+                "memory region {} is {:#x} bytes, not {:#x}",
+                name,
+                mapping.len(),
+                data_len
+            )));
+        }
+        info!(log, "Reading guest memory region {}", name; "len" => data_len);
+        let offset = file.stream_position()?;
+        read_mapping(&file, &mapping, offset)?;
+        file.seek(SeekFrom::Current(data_len as i64))?;
+    };
+    if let Some(name) = regions.first() {
+        return Err(SnapshotError::Mismatch(format!(
+            "snapshot has no contents for memory region {name}"
+        )));
+    }
+
+    let mut buf = vec![0u8; len];
+    file.read_exact(&mut buf)?;
+    let devices: Vec<SnapshotDevice> = serde_json::from_slice(&buf)?;
+    read_section_header(&mut file, SectionTag::End)?;
+
+    let inv = guard.inventory();
+    let migrate_ctx = MigrateCtx { mem: &memctx };
+    for device in devices {
+        let name = device.instance_name.as_str();
+        info!(log, "Restoring state of device {}", name);
+        let target = inv.get_by_name(name).ok_or_else(|| {
+            SnapshotError::Mismatch(format!("no device named {name}"))
+        })?;
+        let to_err = |e: MigrateStateError| {
+            SnapshotError::DeviceState(name.to_owned(), e)
+        };
+        match target.migrate() {
+            Migrator::NonMigratable => {
+                return Err(SnapshotError::NonMigratable(name.to_owned()));
+            }
+            Migrator::Empty => {
+                warn!(log, "Unexpected state in snapshot for device {}", name);
+            }
+            Migrator::Single(mech) => {
+                let [payload] = device.payload.as_slice() else {
+                    return Err(SnapshotError::Malformed(format!(
+                        "device {} has {} payloads",
+                        name,
+                        device.payload.len()
+                    )));
+                };
+                let mut deser =
+                    serde_json::Deserializer::from_slice(&payload.data);
+                let offer = PayloadOffer {
+                    kind: &payload.kind,
+                    version: payload.version,
+                    payload: Box::new(<dyn erased_serde::Deserializer>::erase(
+                        &mut deser,
+                    )),
+                };
+                mech.import(offer, &migrate_ctx).map_err(to_err)?;
+            }
+            Migrator::Multi(mech) => {
+                let mut desers: Vec<_> = device
+                    .payload
+                    .iter()
+                    .map(|p| serde_json::Deserializer::from_slice(&p.data))
+                    .collect();
+                let offer_iter =
+                    device.payload.iter().zip(desers.iter_mut()).map(
+                        |(payload, deser)| PayloadOffer {
+                            kind: &payload.kind,
+                            version: payload.version,
+                            payload: Box::new(
+                                <dyn erased_serde::Deserializer>::erase(deser),
+                            ),
+                        },
+                    );
+                let mut offer = PayloadOffers::new(offer_iter);
+                mech.import(&mut offer, &migrate_ctx).map_err(to_err)?;
+
+                let remaining = offer.remaining().count();
+                if remaining != 0 {
+                    return Err(SnapshotError::Malformed(format!(
+                        "{remaining} unconsumed payload(s) for device {name}"
+                    )));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_section_header(
+    file: &mut impl Write,
+    tag: SectionTag,
+    len: usize,
+) -> std::io::Result<()> {
+    file.write_all(&[tag as u8])?;
+    file.write_all(&(len as u64).to_be_bytes())
+}
+
+fn write_section(
+    file: &mut impl Write,
+    tag: SectionTag,
+    contents: &[u8],
+) -> std::io::Result<()> {
+    write_section_header(file, tag, contents.len())?;
+    file.write_all(contents)
+}
+
+fn read_len(file: &mut impl Read) -> Result<usize, SnapshotError> {
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    usize::try_from(u64::from_be_bytes(len)).map_err(|_| {
+        SnapshotError::Malformed("section length out of range".to_string())
+    })
+}
+
+fn read_section_header(
+    file: &mut impl Read,
+    expected: SectionTag,
+) -> Result<usize, SnapshotError> {
+    let mut tag = [0u8; 1];
+    file.read_exact(&mut tag)?;
+    if SectionTag::from_repr(tag[0]) != Some(expected) {
+        return Err(SnapshotError::Malformed(format!(
+            "expected {:?} section, found tag {}",
+            expected, tag[0]
+        )));
+    }
+    read_len(file)
+}
+
+/// Writes the contents of `mapping` to `file` starting at `offset`.
+fn write_mapping(
+    file: &impl AsRawFd,
+    mapping: &SubMapping,
+    offset: u64,
+) -> std::io::Result<()> {
+    let mut done = 0;
+    while done < mapping.len() {
+        let len = usize::min(mapping.len() - done, MEM_CHUNK_SIZE);
+        let chunk = mapping.subregion(done, len).unwrap();
+        let written =
+            chunk.pwrite(file, len, (offset as usize + done) as i64)?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        done += written;
+    }
+    Ok(())
+}
+
+/// Fills `mapping` with the contents of `file` starting at `offset`.
+fn read_mapping(
+    file: &impl AsRawFd,
+    mapping: &SubMapping,
+    offset: u64,
+) -> std::io::Result<()> {
+    let mut done = 0;
+    while done < mapping.len() {
+        let len = usize::min(mapping.len() - done, MEM_CHUNK_SIZE);
+        let chunk = mapping.subregion(done, len).unwrap();
+        let read = chunk.pread(file, len, (offset as usize + done) as i64)?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        done += read;
+    }
+    Ok(())
+}
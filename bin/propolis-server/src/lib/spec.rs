@@ -124,6 +124,38 @@ fn make_storage_backend_from_config(
                     _ => None,
                 }
                 .unwrap_or(false),
+                shared: match backend.options.get("shared") {
+                    Some(toml::Value::Boolean(shared)) => Some(*shared),
+                    Some(toml::Value::String(v)) => v.parse().ok(),
+                    _ => None,
+                }
+                .unwrap_or(false),
+                workers: backend
+                    .options
+                    .get("workers")
+                    .and_then(|v| v.as_integer())
+                    .and_then(|v| u32::try_from(v).ok()),
+            })
+        }
+        "null" => {
+            StorageBackendV0::Null(components::backends::NullStorageBackend {
+                size: backend
+                    .options
+                    .get("size")
+                    .and_then(|v| v.as_integer())
+                    .and_then(|v| u64::try_from(v).ok())
+                    .ok_or_else(|| {
+                        ServerSpecBuilderError::ConfigTomlError(format!(
+                            "Couldn't get size for null backend {}",
+                            name
+                        ))
+                    })?,
+                readonly: match backend.options.get("readonly") {
+                    Some(toml::Value::Boolean(ro)) => Some(*ro),
+                    Some(toml::Value::String(v)) => v.parse().ok(),
+                    _ => None,
+                }
+                .unwrap_or(false),
             })
         }
         _ => {
@@ -143,11 +175,13 @@ fn make_storage_device_from_config(
     enum DeviceInterface {
         Virtio,
         Nvme,
+        Ahci,
     }
 
     let interface = match device.driver.as_str() {
         "pci-virtio-block" => DeviceInterface::Virtio,
         "pci-nvme" => DeviceInterface::Nvme,
+        "pci-ahci" => DeviceInterface::Ahci,
         _ => {
             return Err(ServerSpecBuilderError::ConfigTomlError(format!(
                 "storage device {} has invalid driver {}",
@@ -181,17 +215,63 @@ fn make_storage_device_from_config(
         ))
     })?;
 
+    let write_protected = device
+        .options
+        .get("write_protect")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let disabled = device
+        .options
+        .get("disabled")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let deferred_start = device
+        .options
+        .get("deferred_start")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let priority = match device.options.get("priority") {
+        None => components::devices::DiskPriority::default(),
+        Some(v) => v.clone().try_into().map_err(|_| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "storage device {} has invalid priority {}",
+                name, v
+            ))
+        })?,
+    };
+
     Ok(match interface {
         DeviceInterface::Virtio => {
             StorageDeviceV0::VirtioDisk(components::devices::VirtioDisk {
                 backend_name,
                 pci_path,
+                write_protected,
+                priority,
+                disabled,
+                deferred_start,
             })
         }
         DeviceInterface::Nvme => {
             StorageDeviceV0::NvmeDisk(components::devices::NvmeDisk {
                 backend_name,
                 pci_path,
+                write_protected,
+                priority,
+                disabled,
+                deferred_start,
+            })
+        }
+        DeviceInterface::Ahci => {
+            StorageDeviceV0::AhciDisk(components::devices::AhciDisk {
+                backend_name,
+                pci_path,
+                write_protected,
+                priority,
+                disabled,
+                deferred_start,
             })
         }
     })
@@ -200,6 +280,7 @@ fn make_storage_device_from_config(
 /// A helper for building instance specs out of component parts.
 pub struct ServerSpecBuilder {
     builder: SpecBuilder,
+    serial_paced: bool,
 }
 
 impl ServerSpecBuilder {
@@ -222,10 +303,37 @@ impl ServerSpecBuilder {
                 },
             )?;
 
-        let builder =
+        let enable_smbus =
+            config.chipset.options.get("enable-smbus").map_or_else(
+                || Ok(false),
+                |v| {
+                    v.as_bool().ok_or_else(|| {
+                        ServerSpecBuilderError::ConfigTomlError(format!(
+                            "Invalid value {} for enable-smbus flag in chipset",
+                            v
+                        ))
+                    })
+                },
+            )?;
+
+        let serial_paced =
+            config.chipset.options.get("serial-pacing").map_or_else(
+                || Ok(false),
+                |v| {
+                    v.as_bool().ok_or_else(|| {
+                        ServerSpecBuilderError::ConfigTomlError(format!(
+                            "Invalid value {} for serial-pacing flag in chipset",
+                            v
+                        ))
+                    })
+                },
+            )?;
+
+        let mut builder =
             SpecBuilder::new(properties.vcpus, properties.memory, enable_pcie);
+        builder.set_smbus(enable_smbus);
 
-        Ok(Self { builder })
+        Ok(Self { builder, serial_paced })
     }
 
     /// Converts an HTTP API request to add a NIC to an instance into
@@ -240,6 +348,7 @@ impl ServerSpecBuilder {
             NetworkDeviceV0::VirtioNic(components::devices::VirtioNic {
                 backend_name: backend_name.clone(),
                 pci_path,
+                disabled: false,
             });
 
         let backend_spec = NetworkBackendV0::Virtio(
@@ -279,6 +388,8 @@ impl ServerSpecBuilder {
                     )
                 })?,
                 readonly: disk.read_only,
+                prefetch: None,
+                retry: None,
             },
         );
 
@@ -288,12 +399,30 @@ impl ServerSpecBuilder {
                 StorageDeviceV0::VirtioDisk(components::devices::VirtioDisk {
                     backend_name: disk.name.to_string(),
                     pci_path,
+                    write_protected: false,
+                    priority: Default::default(),
+                    disabled: false,
+                    deferred_start: false,
                 })
             }
             "nvme" => {
                 StorageDeviceV0::NvmeDisk(components::devices::NvmeDisk {
                     backend_name: disk.name.to_string(),
                     pci_path,
+                    write_protected: false,
+                    priority: Default::default(),
+                    disabled: false,
+                    deferred_start: false,
+                })
+            }
+            "ahci" => {
+                StorageDeviceV0::AhciDisk(components::devices::AhciDisk {
+                    backend_name: disk.name.to_string(),
+                    pci_path,
+                    write_protected: false,
+                    priority: Default::default(),
+                    disabled: false,
+                    deferred_start: false,
                 })
             }
             _ => {
@@ -333,6 +462,10 @@ impl ServerSpecBuilder {
             StorageDeviceV0::VirtioDisk(components::devices::VirtioDisk {
                 backend_name: name.to_string(),
                 pci_path,
+                write_protected: false,
+                priority: Default::default(),
+                disabled: false,
+                deferred_start: false,
             });
 
         self.builder.add_storage_device(
@@ -350,12 +483,21 @@ impl ServerSpecBuilder {
         name: &str,
         device: &config::Device,
     ) -> Result<(), ServerSpecBuilderError> {
-        let vnic_name = device.get_string("vnic").ok_or_else(|| {
-            ServerSpecBuilderError::ConfigTomlError(format!(
-                "Failed to get vNIC name for device {}",
-                name
-            ))
-        })?;
+        let backend_spec = if device.driver == "pci-virtio-null-net" {
+            NetworkBackendV0::Null(components::backends::NullNetworkBackend {})
+        } else {
+            let vnic_name = device.get_string("vnic").ok_or_else(|| {
+                ServerSpecBuilderError::ConfigTomlError(format!(
+                    "Failed to get vNIC name for device {}",
+                    name
+                ))
+            })?;
+            NetworkBackendV0::Virtio(
+                components::backends::VirtioNetworkBackend {
+                    vnic_name: vnic_name.to_string(),
+                },
+            )
+        };
 
         let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
             ServerSpecBuilderError::ConfigTomlError(format!(
@@ -365,16 +507,18 @@ impl ServerSpecBuilder {
         })?;
 
         let (device_name, backend_name) = pci_path_to_nic_names(pci_path);
-        let backend_spec = NetworkBackendV0::Virtio(
-            components::backends::VirtioNetworkBackend {
-                vnic_name: vnic_name.to_string(),
-            },
-        );
+
+        let disabled = device
+            .options
+            .get("disabled")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
 
         let device_spec =
             NetworkDeviceV0::VirtioNic(components::devices::VirtioNic {
                 backend_name: backend_name.clone(),
                 pci_path,
+                disabled,
             });
 
         self.builder.add_network_device(
@@ -407,6 +551,248 @@ impl ServerSpecBuilder {
         Ok(())
     }
 
+    fn add_clock_device_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for clock device {}",
+                name
+            ))
+        })?;
+
+        self.builder.add_clock_device(
+            name.to_string(),
+            components::devices::VirtioRtc { pci_path },
+        )?;
+
+        Ok(())
+    }
+
+    fn add_entropy_device_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for entropy device {}",
+                name
+            ))
+        })?;
+        let bytes_per_sec = match device.options.get("bytes-per-sec") {
+            None => None,
+            Some(v) => Some(
+                v.as_integer()
+                    .and_then(|v| u32::try_from(v).ok())
+                    .filter(|v| *v != 0)
+                    .ok_or_else(|| {
+                        ServerSpecBuilderError::ConfigTomlError(format!(
+                            "Invalid bytes-per-sec for entropy device {}",
+                            name
+                        ))
+                    })?,
+            ),
+        };
+
+        self.builder.add_entropy_device(
+            name.to_string(),
+            components::devices::VirtioRng { pci_path, bytes_per_sec },
+        )?;
+
+        Ok(())
+    }
+
+    fn add_input_device_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+        kind: components::devices::VirtioInputKind,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for input device {}",
+                name
+            ))
+        })?;
+
+        self.builder.add_input_device(
+            name.to_string(),
+            components::devices::VirtioInput { pci_path, kind },
+        )?;
+
+        Ok(())
+    }
+
+    fn add_memory_device_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for memory device {}",
+                name
+            ))
+        })?;
+        let region_mb = device
+            .options
+            .get("region-mb")
+            .and_then(|v| v.as_integer())
+            .and_then(|v| u64::try_from(v).ok())
+            .ok_or_else(|| {
+                ServerSpecBuilderError::ConfigTomlError(format!(
+                    "Failed to get region size for memory device {}",
+                    name
+                ))
+            })?;
+
+        self.builder.add_memory_device(
+            name.to_string(),
+            components::devices::VirtioMem { pci_path, region_mb },
+        )?;
+
+        Ok(())
+    }
+
+    fn add_shared_memory_device_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let path = device.get_string("path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get path for shared memory device {}",
+                name
+            ))
+        })?;
+        let size = device
+            .options
+            .get("size")
+            .and_then(|v| v.as_integer())
+            .and_then(|v| u64::try_from(v).ok())
+            .ok_or_else(|| {
+                ServerSpecBuilderError::ConfigTomlError(format!(
+                    "Failed to get size for shared memory device {}",
+                    name
+                ))
+            })?;
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for shared memory device {}",
+                name
+            ))
+        })?;
+
+        self.builder.add_shared_memory_device(
+            name.to_string(),
+            components::devices::SharedMemory {
+                path: path.to_string(),
+                size,
+                pci_path,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    fn add_tpm_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let get_path = |key: &str| {
+            device.get_string(key).map(str::to_string).ok_or_else(|| {
+                ServerSpecBuilderError::ConfigTomlError(format!(
+                    "Failed to get {} for TPM {}",
+                    key, name
+                ))
+            })
+        };
+        let socket_path = get_path("socket")?;
+        let state_path = get_path("state")?;
+        let ctrl_socket_path =
+            device.get_string("ctrl-socket").map(str::to_string);
+
+        self.builder.set_tpm(components::devices::Tpm {
+            socket_path,
+            ctrl_socket_path,
+            state_path,
+        })?;
+
+        Ok(())
+    }
+
+    fn add_pvpanic_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for pvpanic device {}",
+                name
+            ))
+        })?;
+
+        self.builder
+            .set_qemu_pvpanic(components::devices::QemuPvpanic { pci_path })?;
+
+        Ok(())
+    }
+
+    fn add_display_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for display device {}",
+                name
+            ))
+        })?;
+
+        self.builder.set_bochs_display(components::devices::BochsDisplay {
+            pci_path,
+        })?;
+
+        Ok(())
+    }
+
+    fn add_vsock_from_config(
+        &mut self,
+        name: &str,
+        device: &config::Device,
+    ) -> Result<(), ServerSpecBuilderError> {
+        let pci_path: PciPath = device.get("pci-path").ok_or_else(|| {
+            ServerSpecBuilderError::ConfigTomlError(format!(
+                "Failed to get PCI path for vsock device {}",
+                name
+            ))
+        })?;
+        let guest_cid = device
+            .options
+            .get("guest-cid")
+            .and_then(|v| v.as_integer())
+            .and_then(|v| u64::try_from(v).ok())
+            .ok_or_else(|| {
+                ServerSpecBuilderError::ConfigTomlError(format!(
+                    "Failed to get guest CID for vsock device {}",
+                    name
+                ))
+            })?;
+
+        self.builder.set_vsock(components::devices::VirtioVsock {
+            pci_path,
+            guest_cid,
+        })?;
+
+        Ok(())
+    }
+
     /// Adds all the devices and backends specified in the supplied
     /// configuration TOML to the spec under construction.
     pub fn add_devices_from_config(
@@ -418,7 +804,7 @@ impl ServerSpecBuilder {
             match driver {
                 // If this is a storage device, parse its "block_dev" property
                 // to get the name of its corresponding backend.
-                "pci-virtio-block" | "pci-nvme" => {
+                "pci-virtio-block" | "pci-nvme" | "pci-ahci" => {
                     let device_spec =
                         make_storage_device_from_config(device_name, device)?;
 
@@ -429,6 +815,9 @@ impl ServerSpecBuilder {
                         StorageDeviceV0::NvmeDisk(disk) => {
                             disk.backend_name.clone()
                         }
+                        StorageDeviceV0::AhciDisk(disk) => {
+                            disk.backend_name.clone()
+                        }
                     };
 
                     let backend_config = config
@@ -453,9 +842,42 @@ impl ServerSpecBuilder {
                         backend_spec,
                     )?;
                 }
-                "pci-virtio-viona" => {
+                "pci-virtio-viona" | "pci-virtio-null-net" => {
                     self.add_network_device_from_config(device_name, device)?
                 }
+                "pci-virtio-rtc" => {
+                    self.add_clock_device_from_config(device_name, device)?
+                }
+                "pci-virtio-rng" => {
+                    self.add_entropy_device_from_config(device_name, device)?
+                }
+                "pci-virtio-mem" => {
+                    self.add_memory_device_from_config(device_name, device)?
+                }
+                "pci-virtio-keyboard" => self.add_input_device_from_config(
+                    device_name,
+                    device,
+                    components::devices::VirtioInputKind::Keyboard,
+                )?,
+                "pci-virtio-tablet" => self.add_input_device_from_config(
+                    device_name,
+                    device,
+                    components::devices::VirtioInputKind::Tablet,
+                )?,
+                "pci-ivshmem" => self.add_shared_memory_device_from_config(
+                    device_name,
+                    device,
+                )?,
+                "tpm-crb" => self.add_tpm_from_config(device_name, device)?,
+                "pci-qemu-pvpanic" => {
+                    self.add_pvpanic_from_config(device_name, device)?
+                }
+                "pci-bochs-display" => {
+                    self.add_display_from_config(device_name, device)?
+                }
+                "pci-virtio-vsock" => {
+                    self.add_vsock_from_config(device_name, device)?
+                }
                 #[cfg(feature = "falcon")]
                 "softnpu-pci-port" => {
                     self.add_softnpu_pci_port_from_config(device_name, device)?
@@ -587,12 +1009,13 @@ impl ServerSpecBuilder {
         Ok(())
     }
 
-    /// Adds a serial port specification to the spec under construction.
+    /// Adds a serial port specification to the spec under construction.  Its
+    /// output is paced if the config TOML's chipset enables `serial-pacing`.
     pub fn add_serial_port(
         &mut self,
         port: components::devices::SerialPortNumber,
     ) -> Result<(), ServerSpecBuilderError> {
-        self.builder.add_serial_port(port)?;
+        self.builder.add_serial_port_with_pacing(port, self.serial_paced)?;
         Ok(())
     }
 
@@ -622,6 +1045,7 @@ mod test {
                 bootrom_id: Default::default(),
                 memory: 512,
                 vcpus: 4,
+                metadata: Default::default(),
             },
             &Config::default(),
         )
@@ -10,12 +10,15 @@ use std::sync::{
 };
 
 use propolis::{
+    accessors::MemAccessor,
     bhyve_api,
+    duty::DutyCycle,
     exits::{self, SuspendDetail, VmExitKind},
+    steal::StealTime,
     vcpu::Vcpu,
     VmEntry,
 };
-use slog::{debug, error, info};
+use slog::{debug, error, info, warn};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -25,8 +28,10 @@ pub enum VcpuTaskError {
 }
 
 pub struct VcpuTasks {
-    tasks: Vec<(propolis::tasks::TaskCtrl, std::thread::JoinHandle<()>)>,
+    tasks: Vec<(i32, propolis::tasks::TaskCtrl, std::thread::JoinHandle<()>)>,
     generation: Arc<AtomicUsize>,
+    duty_cycle: Arc<DutyCycle>,
+    duty_cycle_thread: Option<std::thread::JoinHandle<()>>,
 }
 
 pub trait VcpuEventHandler: Send + Sync {
@@ -43,22 +48,39 @@ pub(crate) trait VcpuTaskController {
     fn pause_all(&mut self);
     fn resume_all(&mut self);
     fn exit_all(&mut self);
+
+    /// Stops the task for a single vCPU and waits for its thread to exit.  The
+    /// vCPU is excluded from all subsequent operations on the controller.
+    fn exit_vcpu(&mut self, vcpu_id: i32);
 }
 
 impl VcpuTasks {
+    /// Spawns a backing thread for each of the instance's vCPUs, which are
+    /// throttled according to `duty_cycle`.  If `steal_time` is set, time for
+    /// which the vCPUs are kept from running is reported to the guest.
     pub(crate) fn new(
         instance: propolis::instance::InstanceGuard,
         event_handler: Arc<super::vm::SharedVmState>,
+        duty_cycle: Arc<DutyCycle>,
+        steal_time: bool,
         log: slog::Logger,
     ) -> Result<Self, VcpuTaskError> {
         let generation = Arc::new(AtomicUsize::new(0));
         let mut tasks = Vec::new();
         for vcpu in instance.machine().vcpus.iter().map(Arc::clone) {
+            let vcpu_id = vcpu.id;
             let (task, ctrl) =
                 propolis::tasks::TaskHdl::new_held(Some(vcpu.barrier_fn()));
             let task_log = log.new(slog::o!("vcpu" => vcpu.id));
             let task_event_handler = event_handler.clone();
             let task_gen = generation.clone();
+            let task_duty = duty_cycle.clone();
+            let steal_mem = steal_time.then(|| {
+                instance
+                    .machine()
+                    .acc_mem
+                    .child(Some(format!("vcpu-{}", vcpu.id)))
+            });
             let thread = std::thread::Builder::new()
                 .name(format!("vcpu-{}", vcpu.id))
                 .spawn(move || {
@@ -67,24 +89,57 @@ impl VcpuTasks {
                         task,
                         task_event_handler,
                         task_gen,
+                        task_duty,
+                        steal_mem,
                         task_log,
                     )
                 })
                 .map_err(VcpuTaskError::BackingThreadSpawnFailed)?;
-            tasks.push((ctrl, thread));
+            tasks.push((vcpu_id, ctrl, thread));
         }
 
-        Ok(Self { tasks, generation })
+        // Once the vCPUs have used their share of each period, they must be
+        // kicked out of the guest in order to be throttled.
+        let vcpus: Vec<_> =
+            instance.machine().vcpus.iter().map(Arc::downgrade).collect();
+        let enforcer_duty = duty_cycle.clone();
+        let duty_cycle_thread = std::thread::Builder::new()
+            .name("vcpu-duty-cycle".to_string())
+            .spawn(move || {
+                enforcer_duty.enforce(|| {
+                    for vcpu in vcpus.iter().filter_map(|v| v.upgrade()) {
+                        let _ = vcpu.barrier();
+                    }
+                })
+            })
+            .map_err(VcpuTaskError::BackingThreadSpawnFailed)?;
+
+        Ok(Self {
+            tasks,
+            generation,
+            duty_cycle,
+            duty_cycle_thread: Some(duty_cycle_thread),
+        })
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn vcpu_loop(
         vcpu: &Vcpu,
         task: propolis::tasks::TaskHdl,
         event_handler: Arc<super::vm::SharedVmState>,
         generation: Arc<AtomicUsize>,
+        duty_cycle: Arc<DutyCycle>,
+        steal_mem: Option<MemAccessor>,
         log: slog::Logger,
     ) {
         info!(log, "Starting vCPU thread");
+        let mut steal = steal_mem.and_then(|acc_mem| {
+            StealTime::new(acc_mem)
+                .map_err(|e| {
+                    warn!(log, "steal time unavailable: {:?}", e);
+                })
+                .ok()
+        });
         let mut entry = VmEntry::Run;
         let mut exit = propolis::exits::VmExit::default();
         let mut local_gen = 0;
@@ -111,6 +166,9 @@ impl VcpuTasks {
                         if local_gen != current_gen {
                             entry = VmEntry::Run;
                             local_gen = current_gen;
+                            if let Some(steal) = steal.as_mut() {
+                                steal.reset();
+                            }
                         }
 
                         // This hold might have been satisfied by a request for the
@@ -123,6 +181,18 @@ impl VcpuTasks {
                 None => {}
             }
 
+            // A vCPU which must be driven to a consistent state in order to
+            // pause is not held back by the duty cycle.
+            if !force_exit_when_consistent {
+                let throttled = duty_cycle.throttle();
+                if let Some(steal) = steal.as_mut() {
+                    steal.add_injected(throttled);
+                }
+            }
+            if let Some(steal) = steal.as_mut() {
+                steal.update();
+            }
+
             exit = match vcpu.run(&entry, force_exit_when_consistent) {
                 Err(e) => {
                     event_handler.io_error_event(vcpu.id, e);
@@ -149,18 +219,29 @@ impl VcpuTasks {
                         ))
                     }
                     VmExitKind::Rdmsr(msr) => {
-                        debug!(&log, "Unhandled rdmsr {:08x}", msr;
-                                       "rip" => exit.rip);
+                        let val = steal.as_ref().and_then(|s| s.rdmsr(msr));
+                        if val.is_none() {
+                            debug!(&log, "Unhandled rdmsr {:08x}", msr;
+                                           "rip" => exit.rip);
+                        }
+                        let val = val.unwrap_or(0);
                         let _ = vcpu.set_reg(
                             bhyve_api::vm_reg_name::VM_REG_GUEST_RAX,
-                            0,
+                            val & 0xffff_ffff,
                         );
                         let _ = vcpu.set_reg(
                             bhyve_api::vm_reg_name::VM_REG_GUEST_RDX,
-                            0,
+                            val >> 32,
                         );
                         VmEntry::Run
                     }
+                    VmExitKind::Wrmsr(msr, val)
+                        if steal
+                            .as_mut()
+                            .map_or(false, |s| s.wrmsr(msr, val)) =>
+                    {
+                        VmEntry::Run
+                    }
                     VmExitKind::Wrmsr(msr, val) => {
                         debug!(&log, "Unhandled wrmsr {:08x} <- {:08x}", msr, val;
                                        "rip" => exit.rip);
@@ -208,7 +289,7 @@ impl VcpuTasks {
 
 impl VcpuTaskController for VcpuTasks {
     fn pause_all(&mut self) {
-        for task in self.tasks.iter_mut().map(|t| &mut t.0) {
+        for task in self.tasks.iter_mut().map(|t| &mut t.1) {
             task.hold().unwrap();
         }
     }
@@ -218,18 +299,32 @@ impl VcpuTaskController for VcpuTasks {
     }
 
     fn resume_all(&mut self) {
-        for task in self.tasks.iter_mut().map(|t| &mut t.0) {
+        for task in self.tasks.iter_mut().map(|t| &mut t.1) {
             task.run().unwrap();
         }
     }
 
     fn exit_all(&mut self) {
-        for task in self.tasks.iter_mut().map(|t| &mut t.0) {
+        for task in self.tasks.iter_mut().map(|t| &mut t.1) {
             task.exit();
         }
 
         for thread in self.tasks.drain(..) {
-            thread.1.join().unwrap();
+            thread.2.join().unwrap();
         }
+
+        self.duty_cycle.stop();
+        if let Some(thread) = self.duty_cycle_thread.take() {
+            thread.join().unwrap();
+        }
+    }
+
+    fn exit_vcpu(&mut self, vcpu_id: i32) {
+        let Some(idx) = self.tasks.iter().position(|t| t.0 == vcpu_id) else {
+            return;
+        };
+        let (_, mut ctrl, thread) = self.tasks.remove(idx);
+        ctrl.exit();
+        thread.join().unwrap();
     }
 }
@@ -0,0 +1,274 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Watches for the guest to show signs of having booted.
+//!
+//! A guest whose firmware or bootloader hangs looks, from outside the VM, much
+//! like one which is running normally: its vCPUs are busy and the instance is
+//! `Running`.  When configured with a [`config::BootWatchdog`], the server
+//! expects the guest to read from one of its disks, or to write a given
+//! pattern to its serial console, within a deadline of the instance starting.
+//! If it does neither, the watchdog captures the guest's most recent POST
+//! codes, the state of its vCPUs, its recent disk I/O and the tail of its
+//! console output, and flags the instance as degraded.  These are reported by
+//! the `/instance/boot-status` endpoint.
+//!
+//! The watchdog only arms for instances which start afresh: an instance which
+//! migrated in was booted by its source.
+
+use std::sync::Weak;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use propolis::bhyve_api::vm_reg_name;
+use propolis::block::{self, device::IoHistory};
+use propolis::hw::chipset::post_code::PostCode;
+use propolis_api_types::{
+    self as api, InstanceState as ApiInstanceState,
+    InstanceStateMonitorResponse as ApiMonitoredState,
+};
+use slog::{info, warn, Logger};
+use tokio::sync::watch;
+
+use super::VmController;
+use crate::config;
+use crate::serial::history_buffer::{SerialHistoryOffset, TTY_BUFFER_SIZE};
+
+/// Interval at which the guest is checked for signs of having booted
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Amount of console output included in boot diagnostics
+const CONSOLE_TAIL_BYTES: usize = 1024;
+
+pub(crate) fn post_code_entry(code: &PostCode) -> api::PostCodeEntry {
+    api::PostCodeEntry {
+        port: code.port,
+        code: code.code,
+        time_ns: code
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64),
+    }
+}
+
+/// Waits for the instance to start, then for the guest to boot, capturing
+/// diagnostics if it fails to do so within the configured deadline.
+pub(super) async fn run(
+    ctrl: Weak<VmController>,
+    cfg: config::BootWatchdog,
+    mut state_rx: watch::Receiver<ApiMonitoredState>,
+    log: Logger,
+) {
+    // A fresh start may pass through `Starting` too quickly to be observed,
+    // but an instance arriving by migration is always seen as `Migrating`
+    // before it is `Running`.
+    let mut migrated_in = false;
+    loop {
+        match state_rx.borrow_and_update().state {
+            ApiInstanceState::Starting => break,
+            ApiInstanceState::Running if !migrated_in => break,
+            ApiInstanceState::Running
+            | ApiInstanceState::Stopping
+            | ApiInstanceState::Stopped
+            | ApiInstanceState::Failed
+            | ApiInstanceState::Destroyed => return,
+            ApiInstanceState::Migrating => migrated_in = true,
+            _ => {}
+        }
+        if state_rx.changed().await.is_err() {
+            return;
+        }
+    }
+
+    let timeout = Duration::from_secs(cfg.timeout_secs);
+    let deadline = Instant::now() + timeout;
+    info!(log, "waiting for guest to boot"; "timeout" => ?timeout);
+
+    let mut console = ConsoleScanner::new(cfg.console_pattern.as_deref());
+    let mut expired = false;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if matches!(
+            state_rx.borrow().state,
+            ApiInstanceState::Stopping
+                | ApiInstanceState::Stopped
+                | ApiInstanceState::Failed
+                | ApiInstanceState::Destroyed
+        ) {
+            return;
+        }
+        let Some(ctrl) = ctrl.upgrade() else {
+            return;
+        };
+
+        let signal = if cfg.disk_read && any_disk_read(&ctrl) {
+            Some(api::BootSignal::DiskRead)
+        } else if console.matches(&ctrl).await {
+            Some(api::BootSignal::ConsolePattern)
+        } else {
+            None
+        };
+        if let Some(signal) = signal {
+            info!(log, "guest booted"; "signal" => ?signal);
+            ctrl.boot_status.lock().unwrap().booted = Some(signal);
+            return;
+        }
+
+        if !expired && Instant::now() >= deadline {
+            expired = true;
+            let diagnostics = capture_diagnostics(&ctrl).await;
+            warn!(log, "guest failed to boot within deadline";
+                  "timeout" => ?timeout,
+                  "diagnostics" => ?diagnostics);
+
+            // Keep watching, so that a guest which boots late is reported as
+            // having done so.
+            let mut status = ctrl.boot_status.lock().unwrap();
+            status.degraded = true;
+            status.diagnostics = Some(diagnostics);
+        }
+    }
+}
+
+fn any_disk_read(ctrl: &VmController) -> bool {
+    let devices = ctrl.vm_objects.block_devices.lock().unwrap();
+    devices.values().any(|dev| {
+        matches!(dev.io_history(), Some(hist) if hist.reads_completed > 0)
+    })
+}
+
+/// Searches the guest's console output for a pattern, picking up where the
+/// previous search left off.
+struct ConsoleScanner {
+    pattern: Option<Vec<u8>>,
+    /// Offset from the start of the console output at which to resume
+    offset: usize,
+}
+
+impl ConsoleScanner {
+    fn new(pattern: Option<&str>) -> Self {
+        let pattern =
+            pattern.filter(|p| !p.is_empty()).map(|p| p.as_bytes().to_vec());
+        Self { pattern, offset: 0 }
+    }
+
+    async fn matches(&mut self, ctrl: &VmController) -> bool {
+        let Some(pattern) = &self.pattern else {
+            return false;
+        };
+        let com1 = ctrl.com1();
+        loop {
+            // Back up so that a pattern split across reads is still found.
+            let from = self.offset.saturating_sub(pattern.len() - 1);
+            let (data, end) = match com1
+                .history_vec(SerialHistoryOffset::FromStart(from), None)
+                .await
+            {
+                Ok(res) => res,
+                // Output from `from` may have aged out of the history buffer,
+                // in which case resume from the oldest output retained.
+                Err(_) => match com1
+                    .history_vec(
+                        SerialHistoryOffset::MostRecent(TTY_BUFFER_SIZE),
+                        None,
+                    )
+                    .await
+                {
+                    Ok(res) => res,
+                    Err(_) => return false,
+                },
+            };
+            if data.windows(pattern.len()).any(|w| w == &pattern[..]) {
+                return true;
+            }
+            if end <= self.offset {
+                return false;
+            }
+            self.offset = end;
+        }
+    }
+}
+
+async fn capture_diagnostics(ctrl: &VmController) -> api::BootDiagnostics {
+    let now = Instant::now();
+    let time_ns = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+
+    let (codes, _total) = ctrl.post_codes();
+    let post_codes = codes.iter().map(post_code_entry).collect();
+
+    // Reading a vCPU's registers briefly kicks it out of the guest.
+    let vcpus = ctrl
+        .instance()
+        .lock()
+        .machine()
+        .vcpus
+        .iter()
+        .map(|vcpu| api::VcpuBootDiagnostics {
+            id: vcpu.id,
+            rip: vcpu.get_reg(vm_reg_name::VM_REG_GUEST_RIP).ok(),
+            rflags: vcpu.get_reg(vm_reg_name::VM_REG_GUEST_RFLAGS).ok(),
+            cr0: vcpu.get_reg(vm_reg_name::VM_REG_GUEST_CR0).ok(),
+            cs: vcpu.get_reg(vm_reg_name::VM_REG_GUEST_CS).ok(),
+        })
+        .collect();
+
+    let disks = ctrl
+        .vm_objects
+        .block_devices
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(name, dev)| {
+            let hist = dev.io_history()?;
+            Some(disk_diagnostics(name, hist, now))
+        })
+        .collect();
+
+    let console_tail = match ctrl
+        .com1()
+        .history_vec(
+            SerialHistoryOffset::MostRecent(CONSOLE_TAIL_BYTES),
+            Some(CONSOLE_TAIL_BYTES),
+        )
+        .await
+    {
+        Ok((data, _)) => String::from_utf8_lossy(&data).into_owned(),
+        Err(_) => String::new(),
+    };
+
+    api::BootDiagnostics { time_ns, post_codes, vcpus, disks, console_tail }
+}
+
+fn disk_diagnostics(
+    name: &str,
+    hist: IoHistory,
+    now: Instant,
+) -> api::DiskBootDiagnostics {
+    let recent = hist
+        .recent
+        .iter()
+        .map(|rec| api::DiskIoRecord {
+            op: match rec.op {
+                block::Operation::Read(..) => "read",
+                block::Operation::Write(..) => "write",
+                block::Operation::Flush => "flush",
+                block::Operation::Discard => "discard",
+                block::Operation::WriteZeroes(..) => "write-zeroes",
+            }
+            .to_string(),
+            result: format!("{:?}", rec.result),
+            latency_ns: rec.latency.as_nanos() as u64,
+            age_ns: now.saturating_duration_since(rec.completed).as_nanos()
+                as u64,
+        })
+        .collect();
+    api::DiskBootDiagnostics {
+        name: name.to_string(),
+        reads_completed: hist.reads_completed,
+        outstanding: hist.outstanding as u64,
+        recent,
+    }
+}
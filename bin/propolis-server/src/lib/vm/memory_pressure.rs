@@ -0,0 +1,358 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Backstop against memory pressure on the host.
+//!
+//! A host which runs short of memory would otherwise page out guest memory
+//! with no regard for how the guest uses it.  An instance configured with a
+//! [`config::MemoryPressure`] instead judges the pressure on its host from the
+//! share of the host's physical memory which is free, sampled periodically,
+//! and from any level reported through the `/instance/memory-pressure`
+//! endpoint, and responds to the more severe of the two:
+//!
+//! - Under moderate pressure, the pressure is logged and reported by the
+//!   endpoint.  Should it persist beyond a grace period, the guest's vCPUs are
+//!   also throttled, slowing its demand for memory.
+//! - Under severe pressure, the guest is throttled from the outset.
+//!
+//! The backstop only throttles and alerts: it does not ask the guest to give
+//! up memory, and returns none to the host.  bhyve offers no means of
+//! releasing part of a guest's memory, so there is no device through which
+//! memory could be reclaimed.  Throttling merely curbs the guest's use of
+//! memory it has not yet touched, and which the host has thus not yet had to
+//! provide.
+//!
+//! The response is layered over the duty-cycle limit set through the API, to
+//! which the instance returns once the pressure subsides.  Each change in the
+//! level of pressure or in the response is logged and reported by the
+//! endpoint.
+
+use std::collections::VecDeque;
+use std::sync::Weak;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use propolis_api_types::{
+    self as api, InstanceState as ApiInstanceState,
+    InstanceStateMonitorResponse as ApiMonitoredState, MemoryPressureLevel,
+};
+use slog::{info, Logger};
+use tokio::sync::watch;
+
+use super::VmController;
+use crate::config;
+
+/// Interval at which the host's free memory is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of changes in pressure or response which are retained
+const MAX_EVENTS: usize = 32;
+
+/// The duty-cycle limit applied to the instance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Response {
+    pub duty_pct: u8,
+}
+
+/// The pressure on the host, and the instance's response to it.
+pub(super) struct Backstop {
+    cfg: Option<config::MemoryPressure>,
+
+    /// Level of pressure most recently reported through the API
+    reported: MemoryPressureLevel,
+    /// Share of the host's memory which was free when last sampled
+    host_free_percent: Option<u8>,
+    /// The more severe of the reported and observed levels of pressure
+    level: MemoryPressureLevel,
+    /// When the current level of pressure was first reached
+    level_since: Instant,
+    /// The level of pressure as of the most recent event
+    recorded_level: MemoryPressureLevel,
+
+    /// Duty-cycle limit set through the API
+    requested_duty_pct: u8,
+
+    applied: Response,
+    events: VecDeque<api::MemoryPressureEvent>,
+}
+
+impl Backstop {
+    pub fn new(cfg: Option<config::MemoryPressure>) -> Self {
+        Self {
+            cfg,
+            reported: MemoryPressureLevel::None,
+            host_free_percent: None,
+            level: MemoryPressureLevel::None,
+            level_since: Instant::now(),
+            recorded_level: MemoryPressureLevel::None,
+            requested_duty_pct: 100,
+            applied: Response { duty_pct: 100 },
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Whether the instance responds to memory pressure at all
+    pub fn enabled(&self) -> bool {
+        self.cfg.is_some()
+    }
+
+    pub fn level(&self) -> MemoryPressureLevel {
+        self.level
+    }
+
+    /// Records the level of pressure reported through the API.
+    pub fn set_reported_level(
+        &mut self,
+        level: MemoryPressureLevel,
+        now: Instant,
+    ) {
+        self.reported = level;
+        self.refresh_level(now);
+    }
+
+    /// Records the share of the host's memory found to be free, returning
+    /// whether the level of pressure changed as a result.
+    pub fn set_host_free_percent(
+        &mut self,
+        free_pct: Option<u8>,
+        now: Instant,
+    ) -> bool {
+        self.host_free_percent = free_pct;
+        self.refresh_level(now)
+    }
+
+    /// The level of pressure observed on the host, judged by the configured
+    /// thresholds of free memory
+    fn observed_level(&self) -> MemoryPressureLevel {
+        match (self.cfg.as_ref(), self.host_free_percent) {
+            (Some(cfg), Some(free)) if free < cfg.severe_free_percent => {
+                MemoryPressureLevel::Severe
+            }
+            (Some(cfg), Some(free)) if free < cfg.moderate_free_percent => {
+                MemoryPressureLevel::Moderate
+            }
+            _ => MemoryPressureLevel::None,
+        }
+    }
+
+    fn refresh_level(&mut self, now: Instant) -> bool {
+        let level = self.reported.max(self.observed_level());
+        if level == self.level {
+            return false;
+        }
+        self.level = level;
+        self.level_since = now;
+        true
+    }
+
+    pub fn set_requested_duty_pct(&mut self, pct: u8) {
+        self.requested_duty_pct = pct;
+    }
+
+    /// The response called for by the current level of pressure.
+    fn desired(&self, now: Instant) -> Response {
+        let requested = Response { duty_pct: self.requested_duty_pct };
+        let Some(cfg) = self.cfg.as_ref() else {
+            return requested;
+        };
+
+        let throttle = match self.level {
+            MemoryPressureLevel::None => false,
+            MemoryPressureLevel::Moderate => {
+                now.duration_since(self.level_since)
+                    >= Duration::from_secs(cfg.grace_secs)
+            }
+            MemoryPressureLevel::Severe => true,
+        };
+        if !throttle {
+            return requested;
+        }
+        Response {
+            duty_pct: requested
+                .duty_pct
+                .min(cfg.throttle_percent.clamp(1, 100)),
+        }
+    }
+
+    /// Brings the response up to date, returning it if it has changed.  If
+    /// `record` is set, a change in the response or in the level of pressure
+    /// is recorded as an event.
+    pub fn update(&mut self, now: Instant, record: bool) -> Option<Response> {
+        let response = self.desired(now);
+        let changed = response != self.applied;
+        self.applied = response;
+
+        if record && (changed || self.level != self.recorded_level) {
+            if self.events.len() == MAX_EVENTS {
+                self.events.pop_front();
+            }
+            self.events.push_back(api::MemoryPressureEvent {
+                time_ns: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as u64),
+                level: self.level,
+                response: self.api_response(),
+            });
+            self.recorded_level = self.level;
+        }
+        changed.then_some(response)
+    }
+
+    /// The response most recently applied
+    pub fn response(&self) -> Response {
+        self.applied
+    }
+
+    fn api_response(&self) -> api::MemoryPressureResponse {
+        api::MemoryPressureResponse {
+            duty_cycle_limit_percent: self.applied.duty_pct,
+        }
+    }
+
+    pub fn status(&self) -> api::InstanceMemoryPressureStatus {
+        api::InstanceMemoryPressureStatus {
+            level: self.level,
+            host_free_percent: self.host_free_percent,
+            response: self.api_response(),
+            events: self.events.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Returns the percentage of the host's physical memory which is free, if it
+/// can be determined.
+pub(super) fn sample_host_free_percent() -> Option<u8> {
+    // Safety: sysconf() has no preconditions, and the names are valid.
+    let (total, avail) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_AVPHYS_PAGES),
+        )
+    };
+    if total <= 0 || avail < 0 {
+        return None;
+    }
+    Some((avail.min(total) as u128 * 100 / total as u128) as u8)
+}
+
+/// Keeps the instance's response to memory pressure up to date as the
+/// pressure on the host changes, until the instance stops.
+pub(super) async fn run(
+    ctrl: Weak<VmController>,
+    mut state_rx: watch::Receiver<ApiMonitoredState>,
+    log: Logger,
+) {
+    info!(log, "memory pressure backstop enabled");
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if matches!(
+            state_rx.borrow_and_update().state,
+            ApiInstanceState::Stopping
+                | ApiInstanceState::Stopped
+                | ApiInstanceState::Failed
+                | ApiInstanceState::Destroyed
+        ) {
+            return;
+        }
+        let Some(ctrl) = ctrl.upgrade() else {
+            return;
+        };
+        ctrl.observe_memory_pressure(sample_host_free_percent());
+        ctrl.update_memory_pressure_response(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backstop() -> Backstop {
+        let cfg = config::MemoryPressure {
+            moderate_free_percent: 10,
+            severe_free_percent: 4,
+            throttle_percent: 50,
+            grace_secs: 30,
+        };
+        Backstop::new(Some(cfg))
+    }
+
+    #[test]
+    fn moderate_pressure_throttles_after_grace() {
+        let mut bs = backstop();
+        let start = Instant::now();
+        bs.set_reported_level(MemoryPressureLevel::Moderate, start);
+
+        // The pressure is reported at once...
+        assert_eq!(bs.update(start, true), None);
+        assert_eq!(bs.status().events.len(), 1);
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bs.update(later, true), None);
+
+        // ...and the guest is throttled should it persist.
+        let expired = start + Duration::from_secs(30);
+        assert_eq!(bs.update(expired, true), Some(Response { duty_pct: 50 }));
+        assert_eq!(bs.status().events.len(), 2);
+
+        bs.set_reported_level(MemoryPressureLevel::None, expired);
+        assert_eq!(bs.update(expired, true), Some(Response { duty_pct: 100 }));
+        assert_eq!(bs.status().events.len(), 3);
+    }
+
+    #[test]
+    fn severe_pressure_throttles_at_once() {
+        let mut bs = backstop();
+        let now = Instant::now();
+        bs.set_reported_level(MemoryPressureLevel::Severe, now);
+        assert_eq!(bs.update(now, true), Some(Response { duty_pct: 50 }));
+    }
+
+    #[test]
+    fn requested_limits_restored_after_pressure() {
+        let mut bs = backstop();
+        let now = Instant::now();
+        bs.set_requested_duty_pct(30);
+        assert_eq!(bs.update(now, false), Some(Response { duty_pct: 30 }));
+        assert!(bs.status().events.is_empty());
+
+        // Stricter limits set through the API are left in place.
+        bs.set_reported_level(MemoryPressureLevel::Severe, now);
+        assert_eq!(bs.update(now, true), None);
+        bs.set_requested_duty_pct(100);
+        assert_eq!(bs.update(now, false), Some(Response { duty_pct: 50 }));
+
+        bs.set_reported_level(MemoryPressureLevel::None, now);
+        assert_eq!(bs.update(now, true), Some(Response { duty_pct: 100 }));
+    }
+
+    #[test]
+    fn observed_pressure_combines_with_reported() {
+        let mut bs = backstop();
+        let now = Instant::now();
+        assert!(!bs.set_host_free_percent(Some(50), now));
+        assert!(bs.set_host_free_percent(Some(8), now));
+        assert_eq!(bs.level(), MemoryPressureLevel::Moderate);
+
+        // The more severe of the two levels applies.
+        bs.set_reported_level(MemoryPressureLevel::Severe, now);
+        assert_eq!(bs.level(), MemoryPressureLevel::Severe);
+        assert_eq!(bs.update(now, true), Some(Response { duty_pct: 50 }));
+        assert!(!bs.set_host_free_percent(Some(2), now));
+        bs.set_reported_level(MemoryPressureLevel::None, now);
+        assert_eq!(bs.level(), MemoryPressureLevel::Severe);
+
+        // Pressure subsides once memory is freed on the host, or can no
+        // longer be judged.
+        assert!(bs.set_host_free_percent(None, now));
+        assert_eq!(bs.level(), MemoryPressureLevel::None);
+        assert_eq!(bs.update(now, true), Some(Response { duty_pct: 100 }));
+    }
+
+    #[test]
+    fn disabled_backstop_ignores_pressure() {
+        let mut bs = Backstop::new(None);
+        let now = Instant::now();
+        bs.set_reported_level(MemoryPressureLevel::Severe, now);
+        assert_eq!(bs.update(now, true), None);
+    }
+}
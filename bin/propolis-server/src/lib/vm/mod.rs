@@ -32,30 +32,61 @@
 
 use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     fmt::Debug,
+    fs::File,
     net::SocketAddr,
-    path::PathBuf,
     pin::Pin,
-    sync::{Arc, Condvar, Mutex, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex, Weak,
+    },
     task::{Context, Poll},
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use oximeter::types::ProducerRegistry;
 use propolis::{
-    hw::{ps2::ctrl::PS2Ctrl, qemu::ramfb::RamFb, uart::LpcUart},
+    block,
+    clock::Clock,
+    duty::{self, DutyCycle, DutyCycleError},
+    exit_stats,
+    hw::{
+        acpi::cpu_hotplug::{CpuHotplug, CpuHotplugError},
+        acpi::maintenance::{
+            MaintenanceKind, MaintenanceNotice, MaintenanceNotifier,
+        },
+        ahci::PciAhci,
+        chipset::{post_code::PostCode, Chipset},
+        ibmpc,
+        nvme::PciNvme,
+        pci::{self, hotplug::AcpiPciHotplug, plugin::MachineHook},
+        ps2::ctrl::PS2Ctrl,
+        qemu::{bochs::PciBochsDisplay, pvpanic::PanicEvent, ramfb::RamFb},
+        uart::LpcUart,
+        virtio::{
+            PciVirtioBlock, PciVirtioInput, PciVirtioMem, PciVirtioVsock,
+        },
+    },
+    vcpu::HaltStats,
     Instance,
 };
 use propolis_api_types::{
-    instance_spec::VersionedInstanceSpec, InstanceProperties,
-    InstanceState as ApiInstanceState,
+    instance_spec::{
+        components::devices::DiskPriority,
+        v0::{
+            InstanceSpecV0, NetworkBackendV0, NetworkDeviceV0,
+            StorageBackendV0, StorageDeviceV0,
+        },
+        PciPath, VersionedInstanceSpec,
+    },
+    BootromInfo, InstanceProperties, InstanceState as ApiInstanceState,
     InstanceStateMonitorResponse as ApiMonitoredState,
-    InstanceStateRequested as ApiInstanceStateRequested,
+    InstanceStateRequested as ApiInstanceStateRequested, MemoryPressureLevel,
     MigrationState as ApiMigrationState,
 };
-use slog::{error, info, Logger};
+use slog::{error, info, warn, Logger};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::oneshot;
@@ -63,16 +94,23 @@ use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
 use crate::{
-    initializer::{build_instance, MachineInitializer},
+    initializer::{
+        block_priority, build_instance, hotplug_memory_mb, load_rom,
+        select_rom, MachineInitializer, RegisteredChipset,
+    },
     migrate::MigrateError,
     serial::Serial,
+    snapshot::{SnapshotError, SnapshotHeader},
     vm::request_queue::ExternalRequest,
 };
 
 use self::request_queue::{ExternalRequestQueue, RequestDeniedReason};
 pub use nexus_client::Client as NexusClient;
 
+pub(crate) mod boot_watchdog;
+mod memory_pressure;
 mod request_queue;
+pub(crate) mod standby;
 mod state_driver;
 
 #[derive(Debug, Error)]
@@ -112,6 +150,42 @@ pub enum VmControllerError {
 
     #[error("Failed to create state worker: {0}")]
     StateWorkerCreationFailed(std::io::Error),
+
+    #[error("No device named {0}")]
+    NoSuchDevice(String),
+
+    #[error("Device {0} cannot be removed")]
+    DeviceNotRemovable(String),
+
+    #[error("A device or backend named {0} already exists")]
+    DeviceAlreadyExists(String),
+
+    #[error("Device {0} must be placed in an empty slot on bus 0")]
+    SlotUnavailable(String),
+
+    #[error("Failed to attach device {0}: {1}")]
+    DeviceAttachFailed(String, std::io::Error),
+
+    #[error("No vCPU with ID {0}")]
+    NoSuchVcpu(i32),
+
+    #[error("vCPU removal failed: {0}")]
+    VcpuNotRemovable(CpuHotplugError),
+
+    #[error("Guest did not offline vCPU {0}")]
+    VcpuRemovalTimedOut(i32),
+
+    #[error("Failed to save snapshot: {0}")]
+    SnapshotFailed(String),
+
+    #[error("Disk {0} cannot be exported: {1}")]
+    DiskNotExportable(String, std::io::Error),
+
+    #[error("Failed to export disk: {0}")]
+    DiskExportFailed(String),
+
+    #[error("Checkpoint {1} is not the most recent of disk {0}")]
+    StaleDiskCheckpoint(String, u64),
 }
 
 impl From<VmControllerError> for dropshot::HttpError {
@@ -132,9 +206,39 @@ impl From<VmControllerError> for dropshot::HttpError {
                     http::status::StatusCode::FORBIDDEN,
                 )
             }
+            VmControllerError::NoSuchDevice(_) => {
+                HttpError::for_not_found(None, vm_error.to_string())
+            }
+            VmControllerError::NoSuchVcpu(_) => {
+                HttpError::for_not_found(None, vm_error.to_string())
+            }
+            VmControllerError::DeviceNotRemovable(_)
+            | VmControllerError::SlotUnavailable(_)
+            | VmControllerError::VcpuNotRemovable(_)
+            | VmControllerError::DiskNotExportable(..) => {
+                HttpError::for_bad_request(None, vm_error.to_string())
+            }
+            VmControllerError::DeviceAlreadyExists(_)
+            | VmControllerError::StaleDiskCheckpoint(..) => {
+                HttpError::for_status(
+                    Some(vm_error.to_string()),
+                    http::status::StatusCode::CONFLICT,
+                )
+            }
+            VmControllerError::DeviceAttachFailed(_, ref e)
+                if e.kind() == std::io::ErrorKind::InvalidInput =>
+            {
+                HttpError::for_bad_request(None, vm_error.to_string())
+            }
+            VmControllerError::VcpuRemovalTimedOut(_) => {
+                HttpError::for_unavail(None, vm_error.to_string())
+            }
             VmControllerError::MigrationProtocolError(_)
+            | VmControllerError::DeviceAttachFailed(..)
             | VmControllerError::VcpuWorkerCreationFailed(_)
-            | VmControllerError::StateWorkerCreationFailed(_) => {
+            | VmControllerError::StateWorkerCreationFailed(_)
+            | VmControllerError::SnapshotFailed(_)
+            | VmControllerError::DiskExportFailed(_) => {
                 HttpError::for_internal_error(format!(
                     "Instance operation failed: {}",
                     vm_error
@@ -150,8 +254,12 @@ pub(crate) struct VmObjects {
     /// The underlying Propolis `Instance` this controller is managing.
     instance: Option<Instance>,
 
-    /// The instance properties supplied when this controller was created.
-    properties: InstanceProperties,
+    /// The instance properties supplied when this controller was created,
+    /// with any subsequent updates to the instance's metadata applied.
+    properties: Mutex<InstanceProperties>,
+
+    /// The bootrom with which the instance was initialized.
+    bootrom: BootromInfo,
 
     /// The instance spec used to create this controller's VM.
     spec: tokio::sync::Mutex<VersionedInstanceSpec>,
@@ -163,11 +271,65 @@ pub(crate) struct VmObjects {
     /// An optional reference to the guest's virtual framebuffer.
     framebuffer: Option<Arc<RamFb>>,
 
+    /// The instance's display device, if it has one.
+    display: Option<Arc<PciBochsDisplay>>,
+
     /// An optional reference to the guest's virtual ps2 controller.
     ps2ctrl: Option<Arc<PS2Ctrl>>,
 
+    /// The instance's virtio-input keyboard and tablet, if it has them.
+    keyboard: Option<Arc<PciVirtioInput>>,
+    tablet: Option<Arc<PciVirtioInput>>,
+
+    /// The instance's vsock device, if it has one.
+    vsock: Option<Arc<PciVirtioVsock>>,
+
     /// A map of the instance's active Crucible backends.
-    crucible_backends: BTreeMap<Uuid, Arc<propolis::block::CrucibleBackend>>,
+    crucible_backends:
+        Mutex<BTreeMap<Uuid, Arc<propolis::block::CrucibleBackend>>>,
+
+    /// The entities which are started in the background once the instance's
+    /// vCPUs are running, rather than before, with the names of the devices
+    /// to which they are attached.  Those devices are hidden from the guest
+    /// until their entities have started.
+    deferred_entities: BTreeMap<propolis::inventory::EntityID, String>,
+
+    /// The instance's virtio and NVMe disks, keyed by name.
+    block_devices: Mutex<BTreeMap<String, Arc<dyn block::Device>>>,
+
+    /// The instance's chipset, through which devices are attached and
+    /// detached.
+    chipset: RegisteredChipset,
+
+    /// The ACPI hotplug controller used to notify the guest of devices being
+    /// added and to coordinate their removal.
+    pci_hotplug: Arc<AcpiPciHotplug>,
+
+    /// The resources with which the backends of devices added while the
+    /// instance runs are created.
+    producer_registry: Option<ProducerRegistry>,
+    nexus_client: Option<NexusClient>,
+    crucible_journal: Option<crate::config::CrucibleJournal>,
+    audit_block_flushes: bool,
+
+    /// The ACPI hotplug controller used to coordinate vCPU removal with the
+    /// guest.
+    cpu_hotplug: Arc<CpuHotplug>,
+
+    /// The register block through which the guest is notified of impending
+    /// maintenance.
+    maintenance: Arc<MaintenanceNotifier>,
+
+    /// The limit on the fraction of time the instance's vCPUs may run.
+    duty_cycle: Arc<DutyCycle>,
+
+    /// The virtio-mem device through which memory is hot-plugged into the
+    /// guest, if the instance has one.
+    hotplug_memory: Option<Arc<PciVirtioMem>>,
+
+    /// Changes to the enablement of devices, keyed by device name, which take
+    /// effect when the instance next reboots.
+    pending_device_enables: Mutex<BTreeMap<String, bool>>,
 
     /// A notification receiver to which the state worker publishes the most
     /// recent instance state information.
@@ -230,6 +392,12 @@ enum GuestEvent {
     ChipsetHalt,
     /// Chipset signaled reboot condition
     ChipsetReset,
+    /// Guest ejected the vCPU with the given ID, which can now be retired
+    VcpuEjected(i32),
+    /// An entity whose startup was deferred failed to start
+    DeferredStartFailed,
+    /// Guest reported a panic through its pvpanic device
+    GuestPanic(PanicEvent, SystemTime),
 }
 
 /// Shared instance state guarded by the controller's state mutex. This state is
@@ -261,12 +429,65 @@ impl SharedVmStateInner {
     }
 }
 
+/// The ways in which the state driver's wait for a disk export can end.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ExportWaitOutcome {
+    /// The exporter is done.
+    Done,
+    /// The export outlasted the time for which the instance may be paused.
+    TimedOut,
+    /// A request to stop the instance was queued.
+    StopRequested,
+}
+
 #[derive(Debug)]
 pub(crate) struct SharedVmState {
     inner: Mutex<SharedVmStateInner>,
     cv: Condvar,
 }
 
+/// How long pausing, resetting, or halting the instance waits for the entities
+/// whose startup was deferred to finish starting before abandoning them.
+const DEFERRED_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The thread starting the instance's deferred entities.
+struct DeferredStart {
+    thread: JoinHandle<()>,
+    /// Set to stop the thread from starting any further entities or revealing
+    /// their devices to the guest.
+    cancel: Arc<AtomicBool>,
+    /// Signalled by the thread as it finishes.
+    done: mpsc::Receiver<()>,
+}
+
+/// The backend of a disk being exported from a paused instance.  The instance
+/// resumes once this is dropped.
+pub struct DiskExport {
+    pub backend: Arc<dyn block::Backend>,
+    /// The changes to export, if the export is incremental
+    pub delta: Option<block::dirty::Delta>,
+    /// The checkpoint begun as the export began, if one was requested.  It
+    /// takes effect only once committed, when the export has completed.
+    pub checkpoint: Option<block::dirty::CheckpointId>,
+    abandoned: Arc<AtomicBool>,
+    _done_tx: oneshot::Sender<()>,
+}
+impl DiskExport {
+    /// Whether the instance stopped waiting for this export, which should
+    /// then send nothing further: the instance may be running again.
+    pub fn is_abandoned(&self) -> bool {
+        self.abandoned.load(Ordering::Acquire)
+    }
+
+    /// Commits the checkpoint begun for this export, if any, once its contents
+    /// have been exported in full.
+    pub fn commit_checkpoint(&self) {
+        if let Some(id) = self.checkpoint {
+            self.backend.attachment().dirty().commit_checkpoint(id);
+        }
+    }
+}
+
 /// A VM controller: a wrapper around a Propolis instance that supplies the
 /// functions needed for the Propolis server to implement its own API.
 pub struct VmController {
@@ -287,6 +508,16 @@ pub struct VmController {
         Option<JoinHandle<tokio::sync::watch::Sender<ApiMonitoredState>>>,
     >,
 
+    /// The thread starting the instance's deferred entities, if one has been
+    /// spawned and not yet joined.
+    deferred_start: Mutex<Option<DeferredStart>>,
+
+    /// The guest's progress through boot, as judged by the boot watchdog.
+    boot_status: Mutex<propolis_api_types::InstanceBootStatus>,
+
+    /// The memory pressure on the host, and the instance's response to it.
+    memory_pressure: Mutex<memory_pressure::Backstop>,
+
     /// This controller's logger.
     log: Logger,
 
@@ -307,19 +538,25 @@ impl SharedVmState {
         }
     }
 
+    /// Asks to queue `request` to the state driver on behalf of the API
+    /// request with ID `request_id`, if it was made by one.
     fn queue_external_request(
         &self,
         request: ExternalRequest,
+        request_id: Option<&str>,
     ) -> Result<(), RequestDeniedReason> {
         let mut inner = self.inner.lock().unwrap();
-        let result = inner.external_request_queue.try_queue(request);
+        let result =
+            inner.external_request_queue.try_queue(request, request_id);
         if result.is_ok() {
             self.cv.notify_one();
         }
         result
     }
 
-    fn wait_for_next_event(&self) -> StateDriverEvent {
+    /// Waits for the next event for the state driver to process, returning it
+    /// along with the ID of the API request that raised it (if any).
+    fn wait_for_next_event(&self) -> (StateDriverEvent, Option<String>) {
         let guard = self.inner.lock().unwrap();
         let mut guard = self
             .cv
@@ -330,14 +567,46 @@ impl SharedVmState {
             .unwrap();
 
         if let Some(guest_event) = guard.guest_event_queue.pop_front() {
-            StateDriverEvent::Guest(guest_event)
+            (StateDriverEvent::Guest(guest_event), None)
         } else {
-            StateDriverEvent::External(
-                guard.external_request_queue.pop_front().unwrap(),
-            )
+            let (request, request_id) =
+                guard.external_request_queue.pop_front().unwrap();
+            (StateDriverEvent::External(request), request_id)
+        }
+    }
+
+    /// Waits for a disk export holding the instance paused to finish, as
+    /// indicated by `done`, until `deadline` at the latest.  The wait also ends
+    /// as soon as a request to stop the instance is queued.
+    fn wait_for_export(
+        &self,
+        done: &AtomicBool,
+        deadline: Instant,
+    ) -> ExportWaitOutcome {
+        let mut guard = self.inner.lock().unwrap();
+        loop {
+            if done.load(Ordering::Acquire) {
+                return ExportWaitOutcome::Done;
+            }
+            if guard.external_request_queue.stop_queued() {
+                return ExportWaitOutcome::StopRequested;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return ExportWaitOutcome::TimedOut;
+            }
+            guard = self.cv.wait_timeout(guard, deadline - now).unwrap().0;
         }
     }
 
+    /// Wakes the state driver to look again at the progress of an export.
+    fn notify_export_progress(&self) {
+        // Holding the lock ensures the driver is either yet to check on the
+        // export, or already waiting to be woken.
+        let _guard = self.inner.lock().unwrap();
+        self.cv.notify_one();
+    }
+
     /// Add a guest event to the queue, so long as it does not appear to be a
     /// duplicate of an existing event.
     fn enqueue_guest_event(&self, event: GuestEvent) {
@@ -374,6 +643,10 @@ impl SharedVmState {
     pub fn io_error_event(&self, vcpu_id: i32, error: std::io::Error) {
         panic!("vCPU {}: Unhandled vCPU error: {}", vcpu_id, error);
     }
+
+    pub fn deferred_start_failed(&self) {
+        self.enqueue_guest_event(GuestEvent::DeferredStartFailed);
+    }
 }
 
 /// Functions called by a Propolis chipset to notify another component that an
@@ -381,6 +654,8 @@ impl SharedVmState {
 pub trait ChipsetEventHandler: Send + Sync {
     fn chipset_halt(&self);
     fn chipset_reset(&self);
+    fn vcpu_ejected(&self, vcpu_id: i32);
+    fn guest_panic(&self, event: PanicEvent);
 }
 
 impl ChipsetEventHandler for SharedVmState {
@@ -391,6 +666,17 @@ impl ChipsetEventHandler for SharedVmState {
     fn chipset_reset(&self) {
         self.enqueue_guest_event(GuestEvent::ChipsetReset);
     }
+
+    fn vcpu_ejected(&self, vcpu_id: i32) {
+        self.enqueue_guest_event(GuestEvent::VcpuEjected(vcpu_id));
+    }
+
+    fn guest_panic(&self, event: PanicEvent) {
+        self.enqueue_guest_event(GuestEvent::GuestPanic(
+            event,
+            SystemTime::now(),
+        ));
+    }
 }
 
 impl VmController {
@@ -399,30 +685,69 @@ impl VmController {
         instance_spec: VersionedInstanceSpec,
         properties: InstanceProperties,
         use_reservoir: bool,
-        bootrom: PathBuf,
+        bootroms: Vec<crate::config::BootromCandidate>,
+        standby: Option<standby::StandbyMachine>,
+        debug_port: crate::config::DebugPort,
+        post_codes: crate::config::PostCodes,
+        boot_watchdog: Option<crate::config::BootWatchdog>,
+        crucible_journal: Option<crate::config::CrucibleJournal>,
+        audit_block_flushes: bool,
+        smbios: Option<crate::config::Smbios>,
+        memory_pressure: Option<crate::config::MemoryPressure>,
+        shared_memory_dir: Option<std::path::PathBuf>,
+        nvram_dir: Option<std::path::PathBuf>,
         oximeter_registry: Option<ProducerRegistry>,
         nexus_client: Option<NexusClient>,
+        machine_hooks: Vec<MachineHook>,
         log: Logger,
         runtime_hdl: tokio::runtime::Handle,
         stop_ch: oneshot::Sender<()>,
     ) -> anyhow::Result<Arc<Self>> {
+        // Tag everything logged on behalf of the VM with the metadata provided
+        // by the operator, so it can be correlated with external systems.
+        let log = if properties.metadata.is_empty() {
+            log
+        } else {
+            log.new(slog::o!(
+                "instance_metadata" => format!("{:?}", properties.metadata)
+            ))
+        };
         info!(log, "initializing new VM";
               "spec" => #?instance_spec,
               "properties" => #?properties,
               "use_reservoir" => use_reservoir,
-              "bootrom" => %bootrom.display());
+              "bootroms" => ?bootroms);
 
         let vmm_log = log.new(slog::o!("component" => "vmm"));
 
         // Set up the 'shell' instance into which the rest of this routine will
-        // add components.
+        // add components, taking over the standby VM if it fits.
         let VersionedInstanceSpec::V0(v0_spec) = &instance_spec;
-        let instance = build_instance(
-            &properties.id.to_string(),
-            v0_spec,
-            use_reservoir,
-            vmm_log,
-        )?;
+        let (instance, bootrom, nvram) = match standby {
+            Some(standby) if standby.fits(v0_spec) => {
+                info!(log, "using standby VM");
+                standby.into_parts()
+            }
+            standby => {
+                // Release the standby VM's memory before allocating anew
+                if let Some(standby) = standby {
+                    info!(log, "discarding standby VM of different shape");
+                    drop(standby);
+                }
+                let (fw, bootrom) = select_rom(&bootroms, &log)?;
+                let instance = build_instance(
+                    &properties.id.to_string(),
+                    v0_spec.devices.board.cpus,
+                    v0_spec.devices.board.memory_mb,
+                    hotplug_memory_mb(v0_spec),
+                    &fw,
+                    use_reservoir,
+                    vmm_log,
+                )?;
+                let nvram = load_rom(instance.lock().machine(), &fw)?;
+                (instance, bootrom, nvram)
+            }
+        };
 
         // Create the state monitor channel and the worker state struct that
         // depends on it. The state struct can then be passed to device
@@ -432,6 +757,7 @@ impl VmController {
                 gen: 0,
                 state: ApiInstanceState::Creating,
                 migration: None,
+                guest_panic: None,
             });
 
         let worker_state = Arc::new(SharedVmState::new(&log));
@@ -445,51 +771,118 @@ impl VmController {
             machine,
             inv,
             v0_spec,
-            oximeter_registry,
+            oximeter_registry.clone(),
         );
 
-        init.initialize_rom(bootrom)?;
+        init.initialize_nvram(nvram, nvram_dir.as_deref(), properties.id)?;
         init.initialize_kernel_devs()?;
-        let chipset = init.initialize_chipset(
-            &(worker_state.clone() as Arc<dyn ChipsetEventHandler>),
-        )?;
+        let chipset_event_handler =
+            worker_state.clone() as Arc<dyn ChipsetEventHandler>;
+        if post_codes.capture_alt_port
+            && debug_port.port == ibmpc::PORT_POST_CODE_ALT
+        {
+            anyhow::bail!(
+                "POST code capture conflicts with debug port at {:#x}",
+                debug_port.port
+            );
+        }
+        let chipset =
+            init.initialize_chipset(&chipset_event_handler, &post_codes)?;
 
         let com1 = Arc::new(init.initialize_uart(&chipset)?);
         let ps2ctrl_id = init.initialize_ps2(&chipset)?;
         let ps2ctrl: Option<Arc<PS2Ctrl>> = inv.get_concrete(ps2ctrl_id);
-        init.initialize_qemu_debug_port()?;
+        init.initialize_qemu_debug_port(&debug_port)?;
+        init.initialize_tpm()?;
+        init.initialize_qemu_pvpanic(&chipset, &chipset_event_handler)?;
+        let display = init.initialize_display(&chipset)?;
+        let input = init.initialize_input_devices(&chipset)?;
+        let vsock = init.initialize_vsock(&chipset)?;
         init.initialize_network_devices(&chipset)?;
+        init.initialize_clock_devices(&chipset)?;
+        init.initialize_smbus_devices(&chipset, &properties)?;
+        init.initialize_entropy_devices(&chipset)?;
+        let hotplug_memory = init.initialize_memory_device(&chipset)?;
+        init.initialize_shared_memory_devices(
+            &chipset,
+            shared_memory_dir.as_deref(),
+        )?;
         #[cfg(feature = "falcon")]
         init.initialize_softnpu_ports(&chipset)?;
         #[cfg(feature = "falcon")]
         init.initialize_9pfs(&chipset)?;
-        let crucible_backends =
-            init.initialize_storage_devices(&chipset, nexus_client)?;
-        let framebuffer_id =
-            init.initialize_fwcfg(v0_spec.devices.board.cpus)?;
+        let storage = init.initialize_storage_devices(
+            &chipset,
+            nexus_client.clone(),
+            crucible_journal.as_ref(),
+            audit_block_flushes,
+        )?;
+        init.initialize_plugin_devices(&chipset, &machine_hooks)?;
+        let gpe = init.initialize_gpe(&chipset)?;
+        let pci_hotplug = init.initialize_pci_hotplug(&gpe)?;
+        let cpu_hotplug =
+            init.initialize_cpu_hotplug(&gpe, &chipset_event_handler)?;
+        let maintenance = init.initialize_maintenance(&gpe)?;
+        let framebuffer_id = init.initialize_fwcfg(
+            &chipset,
+            &pci_hotplug,
+            v0_spec.devices.board.cpus,
+            smbios.as_ref(),
+            &properties,
+        )?;
         let framebuffer: Option<Arc<RamFb>> = inv.get_concrete(framebuffer_id);
         init.initialize_cpus()?;
+        let duty_cycle = Arc::new(DutyCycle::new(duty::DEFAULT_PERIOD));
         let vcpu_tasks = super::vcpu_tasks::VcpuTasks::new(
             instance_inner,
             worker_state.clone(),
+            duty_cycle.clone(),
+            v0_spec.devices.board.steal_time,
             log.new(slog::o!("component" => "vcpu_tasks")),
         )?;
 
+        let backstop_enabled = memory_pressure.is_some();
+        let backstop = memory_pressure::Backstop::new(memory_pressure);
+
         // The instance is fully set up; pass it to the new controller.
         let shared_state_for_worker = worker_state.clone();
         let controller = Arc::new_cyclic(|this| Self {
             vm_objects: VmObjects {
                 instance: Some(instance),
-                properties,
+                properties: Mutex::new(properties),
+                bootrom,
                 spec: tokio::sync::Mutex::new(instance_spec),
                 com1,
                 framebuffer,
+                display,
                 ps2ctrl,
-                crucible_backends,
+                keyboard: input.keyboard,
+                tablet: input.tablet,
+                vsock,
+                crucible_backends: Mutex::new(storage.crucible_backends),
+                deferred_entities: storage.deferred,
+                block_devices: Mutex::new(storage.block_devices),
+                chipset,
+                pci_hotplug,
+                producer_registry: oximeter_registry,
+                nexus_client,
+                crucible_journal,
+                audit_block_flushes,
+                cpu_hotplug,
+                maintenance,
+                duty_cycle,
+                hotplug_memory,
+                pending_device_enables: Mutex::new(BTreeMap::new()),
                 monitor_rx,
             },
             worker_state,
             worker_thread: Mutex::new(None),
+            deferred_start: Mutex::new(None),
+            boot_status: Mutex::new(propolis_api_types::InstanceBootStatus {
+                watchdog_enabled: boot_watchdog.is_some(),
+                ..Default::default()
+            }),
+            memory_pressure: Mutex::new(backstop),
             log: log.new(slog::o!("component" => "vm_controller")),
             runtime_hdl: runtime_hdl.clone(),
             this: this.clone(),
@@ -522,11 +915,38 @@ impl VmController {
             .map_err(VmControllerError::StateWorkerCreationFailed)?;
 
         *controller.worker_thread.lock().unwrap() = Some(worker_thread);
+
+        if let Some(cfg) = boot_watchdog {
+            let _ = controller.runtime_hdl.spawn(boot_watchdog::run(
+                controller.this.clone(),
+                cfg,
+                controller.vm_objects.monitor_rx.clone(),
+                log.new(slog::o!("component" => "boot_watchdog")),
+            ));
+        }
+        if backstop_enabled {
+            let _ = controller.runtime_hdl.spawn(memory_pressure::run(
+                controller.this.clone(),
+                controller.vm_objects.monitor_rx.clone(),
+                log.new(slog::o!("component" => "memory_pressure")),
+            ));
+        }
         Ok(controller)
     }
 
-    pub fn properties(&self) -> &InstanceProperties {
-        &self.vm_objects.properties
+    pub fn properties(&self) -> InstanceProperties {
+        self.vm_objects.properties.lock().unwrap().clone()
+    }
+
+    /// Replaces the key/value metadata attached to the instance.
+    pub fn set_metadata(&self, metadata: BTreeMap<String, String>) {
+        info!(self.log, "updating instance metadata";
+            "metadata" => ?metadata);
+        self.vm_objects.properties.lock().unwrap().metadata = metadata;
+    }
+
+    pub fn bootrom(&self) -> &BootromInfo {
+        &self.vm_objects.bootrom
     }
 
     pub fn instance(&self) -> &Instance {
@@ -552,14 +972,234 @@ impl VmController {
         self.vm_objects.framebuffer.as_ref()
     }
 
+    pub fn display(&self) -> Option<&Arc<PciBochsDisplay>> {
+        self.vm_objects.display.as_ref()
+    }
+
     pub fn ps2ctrl(&self) -> Option<&Arc<PS2Ctrl>> {
         self.vm_objects.ps2ctrl.as_ref()
     }
 
+    pub fn keyboard(&self) -> Option<&Arc<PciVirtioInput>> {
+        self.vm_objects.keyboard.as_ref()
+    }
+
+    pub fn tablet(&self) -> Option<&Arc<PciVirtioInput>> {
+        self.vm_objects.tablet.as_ref()
+    }
+
+    pub fn vsock(&self) -> Option<&Arc<PciVirtioVsock>> {
+        self.vm_objects.vsock.as_ref()
+    }
+
+    /// Reads the configuration space of each of the VM's PCI functions.
+    pub fn pci_cfg_dump(&self) -> Vec<(pci::Bdf, Vec<u8>)> {
+        self.vm_objects.chipset.device().pci_cfg_dump()
+    }
+
+    /// Gets the POST codes written by the guest, along with the total number
+    /// written (including those no longer retained).
+    /// Notifies the guest that `kind` maintenance is due in `deadline`,
+    /// replacing any notice already posted.
+    pub fn post_maintenance(
+        &self,
+        kind: MaintenanceKind,
+        deadline: Duration,
+    ) -> MaintenanceNotice {
+        info!(self.log, "posting maintenance notice";
+            "kind" => ?kind, "deadline" => ?deadline);
+        self.vm_objects.maintenance.post(kind, deadline)
+    }
+
+    /// Withdraws the posted maintenance notice, if any.
+    pub fn withdraw_maintenance(&self) -> Option<MaintenanceNotice> {
+        let notice = self.vm_objects.maintenance.withdraw();
+        if notice.is_some() {
+            info!(self.log, "withdrew maintenance notice");
+        }
+        notice
+    }
+
+    /// Returns the maintenance notice currently posted to the guest, if any.
+    pub fn maintenance_notice(&self) -> Option<MaintenanceNotice> {
+        self.vm_objects.maintenance.notice()
+    }
+
+    /// Returns the percentage of time the instance's vCPUs may spend running.
+    pub fn duty_cycle_limit(&self) -> u8 {
+        self.vm_objects.duty_cycle.limit()
+    }
+
+    /// Limits the instance's vCPUs to running for `limit_pct` percent of the
+    /// time, sleeping for the remainder.
+    pub fn set_duty_cycle_limit(
+        &self,
+        limit_pct: u8,
+    ) -> Result<(), DutyCycleError> {
+        if !(1..=100).contains(&limit_pct) {
+            return Err(DutyCycleError::InvalidLimit(limit_pct));
+        }
+        info!(self.log, "set vCPU duty cycle limit"; "percent" => limit_pct);
+
+        // The limit takes effect unless memory pressure calls for a stricter
+        // one.
+        self.memory_pressure.lock().unwrap().set_requested_duty_pct(limit_pct);
+        self.update_memory_pressure_response(false);
+        Ok(())
+    }
+
+    /// Returns the memory pressure on the host and the instance's response to
+    /// it, or `None` if the instance does not respond to memory pressure.
+    pub fn memory_pressure_status(
+        &self,
+    ) -> Option<propolis_api_types::InstanceMemoryPressureStatus> {
+        let backstop = self.memory_pressure.lock().unwrap();
+        backstop.enabled().then(|| backstop.status())
+    }
+
+    /// Responds to the host reporting memory pressure at `level`, returning
+    /// false if the instance does not respond to memory pressure.
+    pub fn report_memory_pressure(&self, level: MemoryPressureLevel) -> bool {
+        if !self.memory_pressure.lock().unwrap().enabled() {
+            return false;
+        }
+        self.change_memory_pressure(|backstop, now| {
+            backstop.set_reported_level(level, now);
+        });
+        warn!(self.log, "host reported memory pressure"; "level" => ?level);
+        self.update_memory_pressure_response(true);
+        true
+    }
+
+    /// Records the share of the host's memory found to be free (if it could
+    /// be sampled), from which the memory pressure on the host is judged.
+    fn observe_memory_pressure(&self, free_pct: Option<u8>) {
+        let mut changed = false;
+        let level = self.change_memory_pressure(|backstop, now| {
+            changed = backstop.set_host_free_percent(free_pct, now);
+        });
+        if changed {
+            warn!(self.log, "observed memory pressure on host";
+                "level" => ?level,
+                "free_percent" => ?free_pct);
+        }
+    }
+
+    /// Changes the memory pressure on the host through `change`, returning
+    /// the resulting level of pressure.
+    fn change_memory_pressure(
+        &self,
+        change: impl FnOnce(&mut memory_pressure::Backstop, Instant),
+    ) -> MemoryPressureLevel {
+        let mut backstop = self.memory_pressure.lock().unwrap();
+        change(&mut backstop, Instant::now());
+        backstop.level()
+    }
+
+    /// Applies the duty-cycle limit called for by the memory pressure on the
+    /// host.  If `record` is set, it is applied only if it has changed, and
+    /// any change in it or in the level of pressure is recorded as a memory
+    /// pressure event; otherwise it is applied regardless, so that a change
+    /// made through the API supersedes any limit set by other means.
+    fn update_memory_pressure_response(&self, record: bool) {
+        let mut backstop = self.memory_pressure.lock().unwrap();
+        let changed = backstop.update(Instant::now(), record);
+        let response = match changed {
+            Some(response) => response,
+            None if !record => backstop.response(),
+            None => return,
+        };
+
+        if let Err(e) = self.vm_objects.duty_cycle.set_limit(response.duty_pct)
+        {
+            error!(self.log, "failed to apply duty cycle limit"; "error" => %e);
+        }
+        if record {
+            let status = backstop.status();
+            warn!(self.log, "changed response to memory pressure";
+                "level" => ?status.level,
+                "response" => ?status.response);
+        }
+    }
+
+    /// Returns the instance's hot-pluggable memory device, if it has one.
+    pub fn hotplug_memory(&self) -> Option<&Arc<PciVirtioMem>> {
+        self.vm_objects.hotplug_memory.as_ref()
+    }
+
+    /// Asks the guest to use `bytes` of its hot-pluggable memory, returning
+    /// false if the instance has none.
+    pub fn set_hotplug_memory_request(&self, bytes: usize) -> bool {
+        let Some(mem) = &self.vm_objects.hotplug_memory else {
+            return false;
+        };
+        mem.set_requested_size(bytes);
+        info!(self.log, "set hot-pluggable memory request"; "bytes" => bytes);
+        true
+    }
+
+    pub fn post_codes(&self) -> (Vec<PostCode>, u64) {
+        self.vm_objects.chipset.device().post_codes()
+    }
+
+    /// Returns the guest's progress through boot, as judged by the boot
+    /// watchdog.
+    pub fn boot_status(&self) -> propolis_api_types::InstanceBootStatus {
+        self.boot_status.lock().unwrap().clone()
+    }
+
+    /// Snapshots the exit latencies recorded by each of the VM's vCPUs.
+    pub fn exit_stats(
+        &self,
+    ) -> Vec<(i32, BTreeMap<exit_stats::Site, exit_stats::Histogram>)> {
+        let Some(instance) = &self.vm_objects.instance else {
+            return Vec::new();
+        };
+        let instance = instance.lock();
+        instance
+            .machine()
+            .vcpus
+            .iter()
+            .map(|vcpu| (vcpu.id, vcpu.exit_stats().snapshot()))
+            .collect()
+    }
+
+    /// Snapshots the time each of the VM's vCPUs has spent blocked within
+    /// `VM_RUN`, whether halted or waiting for a host CPU.
+    pub fn halt_stats(&self) -> Vec<(i32, HaltStats)> {
+        let Some(instance) = &self.vm_objects.instance else {
+            return Vec::new();
+        };
+        let instance = instance.lock();
+        instance
+            .machine()
+            .vcpus
+            .iter()
+            .map(|vcpu| (vcpu.id, vcpu.halt_stats()))
+            .collect()
+    }
+
+    /// Discards the exit latencies and halt residency recorded by each of the
+    /// VM's vCPUs.
+    pub fn reset_exit_stats(&self) {
+        if let Some(instance) = &self.vm_objects.instance {
+            let instance = instance.lock();
+            for vcpu in instance.machine().vcpus.iter() {
+                vcpu.exit_stats().reset();
+                vcpu.reset_halt_stats();
+            }
+        }
+    }
+
+    /// The clock from which the VM's userspace-emulated devices tell time.
+    pub fn clock(&self) -> Arc<Clock> {
+        self.instance().lock().machine().clock.clone()
+    }
+
     pub fn crucible_backends(
         &self,
-    ) -> &BTreeMap<Uuid, Arc<propolis::block::CrucibleBackend>> {
-        &self.vm_objects.crucible_backends
+    ) -> BTreeMap<Uuid, Arc<propolis::block::CrucibleBackend>> {
+        self.vm_objects.crucible_backends.lock().unwrap().clone()
     }
 
     pub fn log(&self) -> &Logger {
@@ -584,6 +1224,593 @@ impl VmController {
         }
     }
 
+    /// Hot-adds the storage device named `name`, along with its backend, to
+    /// the running instance.
+    ///
+    /// The device must be placed in an empty slot on bus 0.  Once the device
+    /// and its backend have started, the guest is notified of its arrival via
+    /// ACPI hotplug, and both are added to the instance spec, so that they
+    /// carry over to any migration target.  A backend which would ordinarily
+    /// have its startup deferred is started immediately.
+    pub async fn attach_storage_device(
+        &self,
+        name: &str,
+        device: StorageDeviceV0,
+        backend: StorageBackendV0,
+        request_id: &str,
+    ) -> Result<(), VmControllerError> {
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let backend_name = match &device {
+            StorageDeviceV0::VirtioDisk(disk) => disk.backend_name.clone(),
+            StorageDeviceV0::NvmeDisk(disk) => disk.backend_name.clone(),
+            StorageDeviceV0::AhciDisk(disk) => disk.backend_name.clone(),
+        };
+        if v0_spec.backends.storage_backends.contains_key(&backend_name) {
+            return Err(VmControllerError::DeviceAlreadyExists(backend_name));
+        }
+        let bdf = self.hotplug_bdf(v0_spec, name, device.pci_path())?;
+
+        let mut dev_spec = InstanceSpecV0::default();
+        dev_spec
+            .devices
+            .storage_devices
+            .insert(name.to_string(), device.clone());
+        dev_spec
+            .backends
+            .storage_backends
+            .insert(backend_name.clone(), backend.clone());
+
+        let ctrl = self.this.upgrade().expect("controller is alive");
+        let log = self.log.new(slog::o!(
+            "device" => name.to_string(),
+            "req_id" => request_id.to_string(),
+        ));
+        let attach_name = name.to_string();
+        let req_id = request_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let objects = &ctrl.vm_objects;
+            let instance = ctrl.instance().lock();
+            let init = MachineInitializer::new(
+                log.clone(),
+                instance.machine(),
+                instance.inventory(),
+                &dev_spec,
+                objects.producer_registry.clone(),
+            );
+            let storage = init
+                .initialize_storage_devices(
+                    &objects.chipset,
+                    objects.nexus_client.clone(),
+                    objects.crucible_journal.as_ref(),
+                    objects.audit_block_flushes,
+                )
+                .map_err(|e| {
+                    VmControllerError::DeviceAttachFailed(
+                        attach_name.clone(),
+                        e,
+                    )
+                })?;
+            drop(instance);
+
+            let dup = {
+                let backends = objects.crucible_backends.lock().unwrap();
+                storage
+                    .crucible_backends
+                    .keys()
+                    .find(|id| backends.contains_key(id))
+                    .copied()
+            };
+            if let Some(id) = dup {
+                ctrl.detach_pci_device(bdf, &log);
+                return Err(VmControllerError::DeviceAttachFailed(
+                    attach_name,
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("multiple disks with id {}", id),
+                    ),
+                ));
+            }
+            ctrl.start_hotplugged_device(&attach_name, bdf, &log, &req_id)?;
+            objects
+                .crucible_backends
+                .lock()
+                .unwrap()
+                .extend(storage.crucible_backends);
+            objects.block_devices.lock().unwrap().extend(storage.block_devices);
+            Ok(())
+        })
+        .await
+        .expect("device attach task does not panic")?;
+
+        v0_spec.devices.storage_devices.insert(name.to_string(), device);
+        v0_spec.backends.storage_backends.insert(backend_name, backend);
+        Ok(())
+    }
+
+    /// Hot-adds the network device named `name`, along with its backend, to
+    /// the running instance, as for [`VmController::attach_storage_device`].
+    ///
+    /// This is refused once the server has hardened itself, since the device
+    /// nodes through which NICs are created are then out of its reach.
+    pub async fn attach_network_device(
+        &self,
+        name: &str,
+        device: NetworkDeviceV0,
+        backend: NetworkBackendV0,
+        request_id: &str,
+    ) -> Result<(), VmControllerError> {
+        if propolis::harden::is_confined() {
+            return Err(VmControllerError::DeviceAttachFailed(
+                name.to_string(),
+                std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "network devices cannot be added once the server is \
+                    hardened",
+                ),
+            ));
+        }
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let NetworkDeviceV0::VirtioNic(nic) = &device;
+        let backend_name = nic.backend_name.clone();
+        if v0_spec.backends.network_backends.contains_key(&backend_name) {
+            return Err(VmControllerError::DeviceAlreadyExists(backend_name));
+        }
+        let bdf = self.hotplug_bdf(v0_spec, name, nic.pci_path)?;
+
+        let mut dev_spec = InstanceSpecV0::default();
+        dev_spec
+            .devices
+            .network_devices
+            .insert(name.to_string(), device.clone());
+        dev_spec
+            .backends
+            .network_backends
+            .insert(backend_name.clone(), backend.clone());
+
+        let ctrl = self.this.upgrade().expect("controller is alive");
+        let log = self.log.new(slog::o!(
+            "device" => name.to_string(),
+            "req_id" => request_id.to_string(),
+        ));
+        let attach_name = name.to_string();
+        let req_id = request_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let instance = ctrl.instance().lock();
+            let init = MachineInitializer::new(
+                log.clone(),
+                instance.machine(),
+                instance.inventory(),
+                &dev_spec,
+                ctrl.vm_objects.producer_registry.clone(),
+            );
+            init.initialize_network_devices(&ctrl.vm_objects.chipset).map_err(
+                |e| {
+                    VmControllerError::DeviceAttachFailed(
+                        attach_name.clone(),
+                        e,
+                    )
+                },
+            )?;
+            drop(instance);
+            ctrl.start_hotplugged_device(&attach_name, bdf, &log, &req_id)
+        })
+        .await
+        .expect("device attach task does not panic")?;
+
+        v0_spec.devices.network_devices.insert(name.to_string(), device);
+        v0_spec.backends.network_backends.insert(backend_name, backend);
+        Ok(())
+    }
+
+    /// Checks that a device named `name` can be hot-added at `pci_path`,
+    /// returning the location at which it will be attached.
+    fn hotplug_bdf(
+        &self,
+        spec: &InstanceSpecV0,
+        name: &str,
+        pci_path: PciPath,
+    ) -> Result<pci::Bdf, VmControllerError> {
+        if self.external_instance_state() != ApiInstanceState::Running {
+            return Err(VmControllerError::InstanceNotActive);
+        }
+        if spec.devices.storage_devices.contains_key(name)
+            || spec.devices.network_devices.contains_key(name)
+        {
+            return Err(VmControllerError::DeviceAlreadyExists(
+                name.to_string(),
+            ));
+        }
+
+        // Devices which are disabled are hidden from the bus, but still
+        // occupy their slots.
+        let slot_unavailable =
+            || VmControllerError::SlotUnavailable(name.to_string());
+        let bdf = pci::Bdf::try_from(pci_path)
+            .ok()
+            .filter(|bdf| bdf.bus.get() == 0 && bdf.location.func.get() == 0)
+            .ok_or_else(slot_unavailable)?;
+        let in_use = spec
+            .devices
+            .storage_devices
+            .values()
+            .map(StorageDeviceV0::pci_path)
+            .chain(
+                spec.devices
+                    .network_devices
+                    .values()
+                    .map(NetworkDeviceV0::pci_path),
+            )
+            .any(|path| path == pci_path);
+        if in_use
+            || self.vm_objects.chipset.device().pci_device_at(bdf).is_some()
+        {
+            return Err(slot_unavailable());
+        }
+        Ok(bdf)
+    }
+
+    /// Starts the newly attached device at `bdf`, along with its backend, and
+    /// notifies the guest of its arrival.  A device which fails to start is
+    /// detached again.
+    fn start_hotplugged_device(
+        &self,
+        name: &str,
+        bdf: pci::Bdf,
+        log: &Logger,
+        request_id: &str,
+    ) -> Result<(), VmControllerError> {
+        let _rtguard = self.runtime_hdl.enter();
+        let entities = device_entities(self.instance(), bdf);
+        for (ent_name, ent) in entities.iter() {
+            info!(log, "Sending startup complete to {}", ent_name);
+            if let Err(e) = ent.start() {
+                error!(log, "Startup failed for {}: {:?}", ent_name, e);
+                self.detach_pci_device(bdf, log);
+                return Err(VmControllerError::DeviceAttachFailed(
+                    name.to_string(),
+                    std::io::Error::new(std::io::ErrorKind::Other, e),
+                ));
+            }
+        }
+
+        let slot = bdf.location.dev.get();
+        let hotplug = &self.vm_objects.pci_hotplug;
+        hotplug.set_removable(slot, true);
+        hotplug.notify_inserted(slot, Some(request_id));
+        info!(log, "attached device"; "bdf" => %bdf);
+        Ok(())
+    }
+
+    /// Removes the storage device named `name` from the VM, as for
+    /// [`VmController::remove_network_device`].
+    pub async fn remove_storage_device(
+        &self,
+        name: &str,
+        eject_timeout: Duration,
+        request_id: &str,
+    ) -> Result<bool, VmControllerError> {
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let disk =
+            v0_spec.devices.storage_devices.get(name).ok_or_else(|| {
+                VmControllerError::NoSuchDevice(name.to_string())
+            })?;
+        let backend_name = match disk {
+            StorageDeviceV0::VirtioDisk(disk) => disk.backend_name.clone(),
+            StorageDeviceV0::NvmeDisk(disk) => disk.backend_name.clone(),
+            StorageDeviceV0::AhciDisk(disk) => disk.backend_name.clone(),
+        };
+        let ejected = self
+            .eject_device(name, disk.pci_path(), eject_timeout, request_id)
+            .await?;
+
+        v0_spec.devices.storage_devices.remove(name);
+        v0_spec.backends.storage_backends.remove(&backend_name);
+        self.vm_objects.block_devices.lock().unwrap().remove(name);
+        Ok(ejected)
+    }
+
+    /// Removes the network device named `name` from the VM.
+    ///
+    /// The guest is first asked to release the device via ACPI hotplug.  If it
+    /// has not done so within `eject_timeout`, the device is surprise-removed.
+    /// In either case, the device is detached from the PCI bus, it and its
+    /// backend are halted (tearing down its in-kernel viona state), and both
+    /// are dropped from the instance spec.
+    ///
+    /// Returns whether the guest acknowledged the removal.
+    pub async fn remove_network_device(
+        &self,
+        name: &str,
+        eject_timeout: Duration,
+        request_id: &str,
+    ) -> Result<bool, VmControllerError> {
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let NetworkDeviceV0::VirtioNic(nic) =
+            v0_spec.devices.network_devices.get(name).ok_or_else(|| {
+                VmControllerError::NoSuchDevice(name.to_string())
+            })?;
+        let backend_name = nic.backend_name.clone();
+        let ejected = self
+            .eject_device(name, nic.pci_path, eject_timeout, request_id)
+            .await?;
+
+        v0_spec.devices.network_devices.remove(name);
+        v0_spec.backends.network_backends.remove(&backend_name);
+        Ok(ejected)
+    }
+
+    /// Asks the guest to release the device named `name` at `pci_path`, then
+    /// detaches it, returning whether the guest acknowledged the removal.
+    async fn eject_device(
+        &self,
+        name: &str,
+        pci_path: PciPath,
+        eject_timeout: Duration,
+        request_id: &str,
+    ) -> Result<bool, VmControllerError> {
+        let bdf = pci::Bdf::try_from(pci_path)
+            .ok()
+            .filter(|bdf| bdf.bus.get() == 0)
+            .ok_or_else(|| {
+                VmControllerError::DeviceNotRemovable(name.to_string())
+            })?;
+        let slot = bdf.location.dev.get();
+
+        let ctrl = self.this.upgrade().expect("controller is alive");
+        let log = self.log.new(slog::o!(
+            "device" => name.to_string(),
+            "req_id" => request_id.to_string(),
+        ));
+        let req_id = request_id.to_string();
+        let ejected = tokio::task::spawn_blocking(move || {
+            let hotplug = &ctrl.vm_objects.pci_hotplug;
+            hotplug.request_eject(slot, Some(&req_id));
+            let ejected =
+                hotplug.wait_ejected(slot, eject_timeout, Some(&req_id));
+            if ejected {
+                info!(log, "guest ejected device");
+            } else {
+                warn!(log, "guest did not eject device, surprise-removing";
+                    "timeout" => ?eject_timeout);
+            }
+            ctrl.detach_pci_device(bdf, &log);
+            ejected
+        })
+        .await
+        .expect("device removal task does not panic");
+        Ok(ejected)
+    }
+
+    /// Detaches the device at `bdf` from the PCI bus, halts it and its
+    /// backend, and removes them from the instance's inventory.
+    fn detach_pci_device(&self, bdf: pci::Bdf, log: &Logger) {
+        // Quiesce the device on the bus (releasing its BARs and interrupt
+        // sources) before tearing down the backend behind it.
+        let _dev = self.vm_objects.chipset.device().pci_detach(bdf);
+        let entities = device_entities(self.instance(), bdf);
+        for (ent_name, ent) in entities.iter() {
+            info!(log, "Sending pause request to {}", ent_name);
+            ent.pause();
+        }
+        for (ent_name, ent) in entities.iter() {
+            info!(log, "Sending halt request to {}", ent_name);
+            ent.halt();
+        }
+
+        self.vm_objects.crucible_backends.lock().unwrap().retain(|_, be| {
+            !entities.iter().any(|(_, ent)| {
+                std::ptr::eq(
+                    Arc::as_ptr(be) as *const (),
+                    Arc::as_ptr(ent) as *const (),
+                )
+            })
+        });
+        let instance = self.instance().lock();
+        let inv = instance.inventory();
+        if let Some(id) = inv.get_id_by_name(&bdf.to_string()) {
+            let _ = inv.deregister(id);
+        }
+        self.vm_objects
+            .pci_hotplug
+            .set_removable(bdf.location.dev.get(), false);
+    }
+
+    /// Sets (or clears) write-protection on the storage device named `name`.
+    ///
+    /// The device reflects the change to the guest immediately, and the
+    /// instance spec is updated so that the setting carries over to any
+    /// migration target.
+    pub async fn set_disk_write_protect(
+        &self,
+        name: &str,
+        write_protect: bool,
+    ) -> Result<(), VmControllerError> {
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let device =
+            v0_spec.devices.storage_devices.get_mut(name).ok_or_else(|| {
+                VmControllerError::NoSuchDevice(name.to_string())
+            })?;
+
+        let no_device = || VmControllerError::NoSuchDevice(name.to_string());
+        let bdf = pci::Bdf::try_from(device.pci_path())
+            .map_err(|_| no_device())?
+            .to_string();
+
+        let instance = self.instance().lock();
+        let inv = instance.inventory();
+        match device {
+            StorageDeviceV0::VirtioDisk(disk) => {
+                inv.get_concrete_by_name::<PciVirtioBlock>(&bdf)
+                    .ok_or_else(no_device)?
+                    .set_write_protect(write_protect);
+                disk.write_protected = write_protect;
+            }
+            StorageDeviceV0::NvmeDisk(disk) => {
+                inv.get_concrete_by_name::<PciNvme>(&bdf)
+                    .ok_or_else(no_device)?
+                    .set_write_protect(write_protect);
+                disk.write_protected = write_protect;
+            }
+            StorageDeviceV0::AhciDisk(disk) => {
+                inv.get_concrete_by_name::<PciAhci>(&bdf)
+                    .ok_or_else(no_device)?
+                    .set_write_protect(write_protect);
+                disk.write_protected = write_protect;
+            }
+        }
+        info!(self.log, "set disk write-protect";
+            "disk" => name, "write_protect" => write_protect);
+        Ok(())
+    }
+
+    /// Sets the I/O priority class of the storage device named `name`.
+    ///
+    /// Requests the device has already issued remain accounted against its
+    /// prior class.  The instance spec is updated to reflect the new class.
+    pub async fn set_disk_priority(
+        &self,
+        name: &str,
+        priority: DiskPriority,
+    ) -> Result<(), VmControllerError> {
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let device =
+            v0_spec.devices.storage_devices.get_mut(name).ok_or_else(|| {
+                VmControllerError::NoSuchDevice(name.to_string())
+            })?;
+
+        let no_device = || VmControllerError::NoSuchDevice(name.to_string());
+        let bdf = pci::Bdf::try_from(device.pci_path())
+            .map_err(|_| no_device())?
+            .to_string();
+
+        let instance = self.instance().lock();
+        let inv = instance.inventory();
+        let dev: Arc<dyn block::Device> = match device {
+            StorageDeviceV0::VirtioDisk(_) => inv
+                .get_concrete_by_name::<PciVirtioBlock>(&bdf)
+                .ok_or_else(no_device)?,
+            StorageDeviceV0::NvmeDisk(_) => inv
+                .get_concrete_by_name::<PciNvme>(&bdf)
+                .ok_or_else(no_device)?,
+            StorageDeviceV0::AhciDisk(_) => inv
+                .get_concrete_by_name::<PciAhci>(&bdf)
+                .ok_or_else(no_device)?,
+        };
+        dev.attachment().set_priority(block_priority(priority));
+        match device {
+            StorageDeviceV0::VirtioDisk(disk) => disk.priority = priority,
+            StorageDeviceV0::NvmeDisk(disk) => disk.priority = priority,
+            StorageDeviceV0::AhciDisk(disk) => disk.priority = priority,
+        }
+        info!(self.log, "set disk priority";
+            "disk" => name, "priority" => ?priority);
+        Ok(())
+    }
+
+    /// Enables or disables the storage or network device named `name`.
+    ///
+    /// A disabled device remains part of the instance, but is hidden from the
+    /// guest as if its PCI slot were empty.  The change takes effect, and is
+    /// reflected in the instance spec, when the instance next reboots.  Changes
+    /// which are still pending are not carried over to a migration target.
+    pub async fn set_device_enabled(
+        &self,
+        name: &str,
+        enabled: bool,
+    ) -> Result<(), VmControllerError> {
+        let mut spec = self.vm_objects.spec.lock().await;
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let (_, disabled) = device_disabled_flag(v0_spec, name)
+            .ok_or_else(|| VmControllerError::NoSuchDevice(name.to_string()))?;
+
+        let mut pending =
+            self.vm_objects.pending_device_enables.lock().unwrap();
+        if *disabled != enabled {
+            pending.remove(name);
+        } else {
+            pending.insert(name.to_string(), enabled);
+        }
+        info!(self.log, "set device enablement for next reboot";
+            "device" => name, "enabled" => enabled);
+        Ok(())
+    }
+
+    /// Hides or reveals the devices whose enablement was changed since the
+    /// last reboot, updating the instance spec to match.
+    fn apply_pending_device_enables(&self) {
+        if self.vm_objects.pending_device_enables.lock().unwrap().is_empty() {
+            return;
+        }
+
+        // The spec lock is taken first, as in `set_device_enabled`.
+        let mut spec = self.vm_objects.spec.blocking_lock();
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
+        let pending = std::mem::take(
+            &mut *self.vm_objects.pending_device_enables.lock().unwrap(),
+        );
+        for (name, enabled) in pending {
+            let Some((pci_path, disabled)) =
+                device_disabled_flag(v0_spec, &name)
+            else {
+                continue;
+            };
+            let Ok(bdf) = pci::Bdf::try_from(pci_path) else {
+                continue;
+            };
+            info!(self.log, "applying device enablement";
+                "device" => &name, "bdf" => %bdf, "enabled" => enabled);
+            self.vm_objects.chipset.device().pci_set_hidden(bdf, !enabled);
+            *disabled = !enabled;
+        }
+    }
+
+    /// Removes the vCPU with ID `vcpu_id` from the VM.
+    ///
+    /// The guest is asked to offline and eject the vCPU via ACPI hotplug.  If
+    /// it does so within `eject_timeout`, the vCPU's backing thread is retired
+    /// by the state driver and its in-kernel state is cleared.  Otherwise the
+    /// request is withdrawn and the vCPU remains in service.
+    pub async fn remove_vcpu(
+        &self,
+        vcpu_id: i32,
+        eject_timeout: Duration,
+        request_id: &str,
+    ) -> Result<(), VmControllerError> {
+        let ctrl = self.this.upgrade().expect("controller is alive");
+        let log = self.log.new(slog::o!(
+            "vcpu" => vcpu_id,
+            "req_id" => request_id.to_string(),
+        ));
+        let req_id = request_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let hotplug = &ctrl.vm_objects.cpu_hotplug;
+            hotplug.request_remove(vcpu_id, Some(&req_id)).map_err(
+                |e| match e {
+                    CpuHotplugError::NoSuchCpu(id) => {
+                        VmControllerError::NoSuchVcpu(id)
+                    }
+                    e => VmControllerError::VcpuNotRemovable(e),
+                },
+            )?;
+            if hotplug.wait_ejected(vcpu_id, eject_timeout, Some(&req_id)) {
+                info!(log, "guest ejected vCPU");
+                Ok(())
+            } else {
+                warn!(log, "guest did not eject vCPU";
+                    "timeout" => ?eject_timeout);
+                Err(VmControllerError::VcpuRemovalTimedOut(vcpu_id))
+            }
+        })
+        .await
+        .expect("vCPU removal task does not panic")
+    }
+
     pub fn state_watcher(
         &self,
     ) -> &tokio::sync::watch::Receiver<ApiMonitoredState> {
@@ -609,6 +1836,7 @@ impl VmController {
         migration_id: Uuid,
         conn: WebSocketStream<T>,
         protocol: crate::migrate::protocol::Protocol,
+        request_id: &str,
     ) -> Result<(), VmControllerError> {
         let mut inner = self.worker_state.inner.lock().unwrap();
 
@@ -618,11 +1846,18 @@ impl VmController {
             return Ok(());
         }
 
-        let migration_request =
-            self.launch_source_migration_task(migration_id, conn, protocol);
+        let migration_request = self.launch_source_migration_task(
+            migration_id,
+            conn,
+            protocol,
+            request_id,
+        );
 
         // Unwrap is safe because the queue state was checked under the lock.
-        inner.external_request_queue.try_queue(migration_request).unwrap();
+        inner
+            .external_request_queue
+            .try_queue(migration_request, Some(request_id))
+            .unwrap();
         self.worker_state.cv.notify_one();
         Ok(())
     }
@@ -637,9 +1872,12 @@ impl VmController {
         migration_id: Uuid,
         conn: WebSocketStream<T>,
         protocol: crate::migrate::protocol::Protocol,
+        request_id: &str,
     ) -> ExternalRequest {
-        let log_for_task =
-            self.log.new(slog::o!("component" => "migrate_source_task"));
+        let log_for_task = self.log.new(slog::o!(
+            "component" => "migrate_source_task",
+            "req_id" => request_id.to_string(),
+        ));
         let ctrl_for_task = self.this.upgrade().unwrap();
         let (start_tx, start_rx) = tokio::sync::oneshot::channel();
         let (command_tx, command_rx) = tokio::sync::mpsc::channel(1);
@@ -659,6 +1897,7 @@ impl VmController {
                 response_rx,
                 conn,
                 protocol,
+                log_for_task.clone(),
             )
             .await
             {
@@ -699,6 +1938,8 @@ impl VmController {
         conn: WebSocketStream<T>,
         local_addr: SocketAddr,
         protocol: crate::migrate::protocol::Protocol,
+        options: crate::migrate::RamTransferOptions,
+        request_id: &str,
     ) -> Result<(), VmControllerError> {
         let mut inner = self.worker_state.inner.lock().unwrap();
         if !inner.external_request_queue.migrate_as_target_will_enqueue()? {
@@ -712,10 +1953,15 @@ impl VmController {
             conn,
             local_addr,
             protocol,
+            options,
+            request_id,
         );
 
         // Unwrap is safe because the queue state was checked under the lock.
-        inner.external_request_queue.try_queue(migration_request).unwrap();
+        inner
+            .external_request_queue
+            .try_queue(migration_request, Some(request_id))
+            .unwrap();
         self.worker_state.cv.notify_one();
         Ok(())
     }
@@ -731,9 +1977,13 @@ impl VmController {
         conn: WebSocketStream<T>,
         local_addr: SocketAddr,
         protocol: crate::migrate::protocol::Protocol,
+        options: crate::migrate::RamTransferOptions,
+        request_id: &str,
     ) -> ExternalRequest {
-        let log_for_task =
-            self.log.new(slog::o!("component" => "migrate_source_task"));
+        let log_for_task = self.log.new(slog::o!(
+            "component" => "migrate_source_task",
+            "req_id" => request_id.to_string(),
+        ));
         let ctrl_for_task = self.this.upgrade().unwrap();
         let (start_tx, start_rx) = tokio::sync::oneshot::channel();
         let (command_tx, command_rx) = tokio::sync::mpsc::channel(1);
@@ -752,6 +2002,8 @@ impl VmController {
                 conn,
                 local_addr,
                 protocol,
+                options,
+                log_for_task.clone(),
             )
             .await
             {
@@ -774,15 +2026,135 @@ impl VmController {
     pub fn put_state(
         &self,
         requested: ApiInstanceStateRequested,
+        request_id: &str,
     ) -> Result<(), VmControllerError> {
-        info!(self.log(), "Requested state {:?} via API", requested);
-
+        info!(self.log(), "Requested state {:?} via API", requested;
+              "req_id" => request_id);
+
+        let request = match requested {
+            ApiInstanceStateRequested::Run => ExternalRequest::Start,
+            ApiInstanceStateRequested::Stop => ExternalRequest::Stop,
+            ApiInstanceStateRequested::Reboot => ExternalRequest::Reboot,
+        };
         self.worker_state
-            .queue_external_request(match requested {
-                ApiInstanceStateRequested::Run => ExternalRequest::Start,
-                ApiInstanceStateRequested::Stop => ExternalRequest::Stop,
-                ApiInstanceStateRequested::Reboot => ExternalRequest::Reboot,
+            .queue_external_request(request, Some(request_id))
+            .map_err(Into::into)
+    }
+
+    /// Asks the state driver to save a snapshot of the instance to `file`,
+    /// then waits for the snapshot to be written. If `stop` is set, the
+    /// instance halts once its snapshot is saved.
+    pub async fn save_snapshot(
+        &self,
+        file: File,
+        stop: bool,
+        request_id: &str,
+    ) -> Result<(), VmControllerError> {
+        info!(self.log(), "Requested snapshot via API";
+              "stop" => stop, "req_id" => request_id);
+
+        let (done_tx, done_rx) = oneshot::channel();
+        self.worker_state.queue_external_request(
+            ExternalRequest::SaveSnapshot { file, stop, done_tx },
+            Some(request_id),
+        )?;
+        match done_rx.await {
+            Ok(res) => res.map_err(VmControllerError::SnapshotFailed),
+            Err(_) => Err(VmControllerError::SnapshotFailed(
+                "state driver exited before saving snapshot".to_string(),
+            )),
+        }
+    }
+
+    /// Asks the state driver to pause the instance so that the contents of the
+    /// disk named `name` can be exported without the guest changing them, and
+    /// waits for it to be paused.
+    ///
+    /// If `since` is supplied, only the ranges of the disk changed since that
+    /// checkpoint, which must be the disk's most recent, are to be exported.
+    /// If `checkpoint` is set, a new checkpoint of the disk is begun once the
+    /// instance is paused, to be committed by the caller via
+    /// [`DiskExport::commit_checkpoint`] if the export completes.
+    ///
+    /// The instance remains paused, holding any other state changes requested
+    /// of it, until the returned [`DiskExport`] is dropped.
+    pub async fn pause_for_disk_export(
+        &self,
+        name: &str,
+        since: Option<block::dirty::CheckpointId>,
+        checkpoint: bool,
+        request_id: &str,
+    ) -> Result<DiskExport, VmControllerError> {
+        let backend = self
+            .vm_objects
+            .block_devices
+            .lock()
+            .unwrap()
+            .get(name)
+            .and_then(|dev| dev.attachment().backend())
+            .ok_or_else(|| VmControllerError::NoSuchDevice(name.to_string()))?;
+
+        // Refuse backends which can't be read directly before pausing the
+        // instance on their behalf.
+        backend.read_at(0, &mut []).map_err(|e| {
+            VmControllerError::DiskNotExportable(name.to_string(), e)
+        })?;
+
+        info!(self.log(), "Requested disk export via API";
+              "disk" => name, "since" => ?since, "req_id" => request_id);
+
+        let (paused_tx, paused_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        self.worker_state.queue_external_request(
+            ExternalRequest::PauseForExport {
+                paused_tx,
+                done_rx,
+                abandoned: abandoned.clone(),
+            },
+            Some(request_id),
+        )?;
+        paused_rx.await.map_err(|_| {
+            VmControllerError::DiskExportFailed(
+                "state driver exited before pausing instance".to_string(),
+            )
+        })?;
+
+        // With the instance paused, no requests are in flight to the disk, so
+        // its record of changed ranges is exact.
+        let dirty = backend.attachment().dirty();
+        let delta = since
+            .map(|base| {
+                dirty.changes_since(base).ok_or_else(|| {
+                    VmControllerError::StaleDiskCheckpoint(
+                        name.to_string(),
+                        base,
+                    )
+                })
             })
+            .transpose()?;
+        let checkpoint = checkpoint.then(|| dirty.begin_checkpoint());
+        Ok(DiskExport {
+            backend,
+            delta,
+            checkpoint,
+            abandoned,
+            _done_tx: done_tx,
+        })
+    }
+
+    /// Asks the state driver to load the instance's state from the snapshot in
+    /// `file` and start the instance.
+    pub fn request_restore_from_snapshot(
+        &self,
+        file: File,
+        request_id: &str,
+    ) -> Result<(), VmControllerError> {
+        self.worker_state
+            .queue_external_request(
+                ExternalRequest::RestoreSnapshot { file },
+                Some(request_id),
+            )
             .map_err(Into::into)
     }
 
@@ -816,7 +2188,21 @@ impl VmController {
         }
     }
 
-    fn for_each_entity<F>(&self, mut func: F) -> anyhow::Result<()>
+    /// Calls `func` for each entity in the instance's inventory, visiting
+    /// them in `order`.
+    ///
+    /// Entities are set running in [`Order::Startup`], after everything they
+    /// depend upon, and are quiesced (paused, reset or halted) in
+    /// [`Order::Teardown`], so that they stop producing work for their
+    /// dependencies before those dependencies stop.
+    ///
+    /// [`Order::Startup`]: propolis::inventory::Order::Startup
+    /// [`Order::Teardown`]: propolis::inventory::Order::Teardown
+    fn for_each_entity<F>(
+        &self,
+        order: propolis::inventory::Order,
+        mut func: F,
+    ) -> anyhow::Result<()>
     where
         F: FnMut(
             &Arc<dyn propolis::inventory::Entity>,
@@ -824,13 +2210,97 @@ impl VmController {
         ) -> anyhow::Result<()>,
     {
         self.instance().lock().inventory().for_each_node(
-            propolis::inventory::Order::Pre,
+            order,
             |_eid, record| -> Result<(), anyhow::Error> {
                 let ent = record.entity();
                 func(ent, record)
             },
         )
     }
+
+    /// Waits for the entities whose startup was deferred to finish starting,
+    /// so that they can be sent further requests.
+    ///
+    /// If they have not started within [`DEFERRED_START_TIMEOUT`], the thread
+    /// starting them is told to stop and is left to finish on its own: the
+    /// entities it has yet to start stay stopped, and their devices stay
+    /// hidden from the guest.
+    fn join_deferred_start(&self) {
+        let Some(start) = self.deferred_start.lock().unwrap().take() else {
+            return;
+        };
+        info!(self.log, "Waiting for deferred entities to start");
+        match start.done.recv_timeout(DEFERRED_START_TIMEOUT) {
+            // A disconnected channel means the thread exited without
+            // signalling, i.e. that it panicked.
+            Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                if start.thread.join().is_err() {
+                    error!(self.log, "Deferred entity startup thread panicked");
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                start.cancel.store(true, Ordering::Release);
+                error!(
+                    self.log,
+                    "Deferred entities did not start within {:?}, abandoning \
+                    their startup",
+                    DEFERRED_START_TIMEOUT
+                );
+            }
+        }
+    }
+}
+
+/// Returns the entity of the device attached at `bdf`, followed by those of its
+/// descendants (such as its backend), in the order in which they are started.
+fn device_entities(
+    instance: &Instance,
+    bdf: pci::Bdf,
+) -> Vec<(String, Arc<dyn propolis::inventory::Entity>)> {
+    let instance = instance.lock();
+    let inv = instance.inventory();
+    let Some(dev_id) = inv.get_id_by_name(&bdf.to_string()) else {
+        return Vec::new();
+    };
+    let mut ids = BTreeSet::from([dev_id]);
+    let mut entities = Vec::new();
+    inv.for_each_node(
+        propolis::inventory::Order::Pre,
+        |eid, rec| -> Result<(), std::convert::Infallible> {
+            if eid == dev_id || rec.parent().is_some_and(|p| ids.contains(&p)) {
+                ids.insert(eid);
+                entities
+                    .push((rec.name().to_string(), Arc::clone(rec.entity())));
+            }
+            Ok(())
+        },
+    )
+    .unwrap();
+    entities
+}
+
+/// Looks up the storage or network device named `name` in `spec`, returning
+/// its PCI path and its `disabled` setting.
+fn device_disabled_flag<'a>(
+    spec: &'a mut InstanceSpecV0,
+    name: &str,
+) -> Option<(PciPath, &'a mut bool)> {
+    if let Some(device) = spec.devices.storage_devices.get_mut(name) {
+        return Some(match device {
+            StorageDeviceV0::VirtioDisk(disk) => {
+                (disk.pci_path, &mut disk.disabled)
+            }
+            StorageDeviceV0::NvmeDisk(disk) => {
+                (disk.pci_path, &mut disk.disabled)
+            }
+            StorageDeviceV0::AhciDisk(disk) => {
+                (disk.pci_path, &mut disk.disabled)
+            }
+        });
+    }
+    let NetworkDeviceV0::VirtioNic(nic) =
+        spec.devices.network_devices.get_mut(name)?;
+    Some((nic.pci_path, &mut nic.disabled))
 }
 
 impl Drop for VmController {
@@ -902,7 +2372,10 @@ trait StateDriverVmController {
     /// reset command to the instance's bhyve VM.
     fn reset_entities_and_machine(&self);
 
-    /// Sends each entity a start request.
+    /// Sends each entity a start request.  Entities whose startup is deferred
+    /// are started on a separate thread, which is joined (for a bounded time)
+    /// before entities are next paused, reset, or halted.  Their devices are
+    /// hidden from the guest until they have started.
     fn start_entities(&self) -> anyhow::Result<()>;
 
     /// Sends each entity a pause request, then waits for all these requests to
@@ -917,6 +2390,17 @@ trait StateDriverVmController {
 
     /// Resets the state of each vCPU in the instance to its on-reboot state.
     fn reset_vcpu_state(&self);
+
+    /// Clears the in-kernel state of a vCPU whose backing task has exited
+    /// after the guest ejected it, leaving it halted.
+    fn retire_vcpu_state(&self, vcpu_id: i32);
+
+    /// Writes a snapshot of the (paused) instance's state to `file`.
+    fn save_snapshot(&self, file: File) -> Result<(), SnapshotError>;
+
+    /// Loads the instance's state from the snapshot in `file`. The instance's
+    /// vCPUs must already be activated and its kernel VMM paused.
+    fn restore_snapshot(&self, file: File) -> Result<(), SnapshotError>;
 }
 
 impl StateDriverVmController for VmController {
@@ -941,36 +2425,133 @@ impl StateDriverVmController for VmController {
     }
 
     fn reset_entities_and_machine(&self) {
+        self.join_deferred_start();
         let _rtguard = self.runtime_hdl.enter();
-        self.for_each_entity(|ent, rec| {
-            info!(self.log, "Sending reset request to {}", rec.name());
-            ent.reset();
-            Ok(())
-        })
+        self.for_each_entity(
+            propolis::inventory::Order::Teardown,
+            |ent, rec| {
+                info!(self.log, "Sending reset request to {}", rec.name());
+                ent.reset();
+                Ok(())
+            },
+        )
         .unwrap();
 
+        // With the devices quiesced by their reset, changes to their
+        // visibility can be made without the guest observing them.
+        self.apply_pending_device_enables();
+
         self.instance().lock().machine().reinitialize().unwrap();
     }
 
     fn start_entities(&self) -> anyhow::Result<()> {
+        // The devices of deferred entities are hidden from the guest until
+        // those entities have started.  The spec records which of the devices
+        // are disabled, and so should stay hidden regardless.
+        let mut spec = self.vm_objects.spec.blocking_lock();
+        let VersionedInstanceSpec::V0(v0_spec) = &mut *spec;
         let _rtguard = self.runtime_hdl.enter();
-        self.for_each_entity(|ent, rec| {
-            info!(self.log, "Sending startup complete to {}", rec.name());
-            let res = ent.start();
-            if let Err(e) = &res {
-                error!(self.log, "Startup failed for {}: {:?}", rec.name(), e);
-            }
-            res
-        })
+        let chipset = self.vm_objects.chipset.device();
+        let mut deferred = vec![];
+        self.instance().lock().inventory().for_each_node(
+            propolis::inventory::Order::Startup,
+            |eid, rec| -> anyhow::Result<()> {
+                if let Some(dev_name) =
+                    self.vm_objects.deferred_entities.get(&eid)
+                {
+                    info!(self.log, "Deferring startup of {}", rec.name());
+                    let gated = match device_disabled_flag(v0_spec, dev_name) {
+                        Some((pci_path, disabled)) if !*disabled => {
+                            pci::Bdf::try_from(pci_path).ok()
+                        }
+                        _ => None,
+                    };
+                    if let Some(bdf) = gated {
+                        chipset.pci_set_hidden(bdf, true);
+                    }
+                    deferred.push((
+                        rec.name().to_string(),
+                        Arc::clone(rec.entity()),
+                        gated,
+                    ));
+                    return Ok(());
+                }
+                info!(self.log, "Sending startup complete to {}", rec.name());
+                let res = rec.entity().start();
+                if let Err(e) = &res {
+                    error!(
+                        self.log,
+                        "Startup failed for {}: {:?}",
+                        rec.name(),
+                        e
+                    );
+                }
+                res
+            },
+        )?;
+        drop(spec);
+        if deferred.is_empty() {
+            return Ok(());
+        }
+
+        // Each deferred device is revealed to the guest, as if hot-plugged,
+        // once its backend has started, so the guest can run in the meantime.
+        // Should a backend fail to start, the state worker fails the instance.
+        let log = self.log.clone();
+        let rt = self.runtime_hdl.clone();
+        let worker_state = self.worker_state.clone();
+        let chipset = Arc::clone(chipset);
+        let hotplug = Arc::clone(&self.vm_objects.pci_hotplug);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (done_tx, done) = mpsc::channel();
+        let thread_cancel = cancel.clone();
+        let thread = std::thread::Builder::new()
+            .name("deferred_start".to_string())
+            .spawn(move || {
+                let _rtguard = rt.enter();
+                for (name, ent, gated) in deferred {
+                    if thread_cancel.load(Ordering::Acquire) {
+                        warn!(log, "Deferred startup of {} abandoned", name);
+                        break;
+                    }
+                    info!(log, "Sending deferred startup complete to {}", name);
+                    if let Err(e) = ent.start() {
+                        error!(
+                            log,
+                            "Deferred startup failed for {}: {:?}", name, e
+                        );
+                        worker_state.deferred_start_failed();
+                        break;
+                    }
+                    // The instance may have begun pausing or halting while the
+                    // entity started, in which case the guest is not told.
+                    if let Some(bdf) = gated {
+                        if !thread_cancel.load(Ordering::Acquire) {
+                            chipset.pci_set_hidden(bdf, false);
+                            hotplug
+                                .notify_inserted(bdf.location.dev.get(), None);
+                        }
+                    }
+                }
+                info!(log, "Deferred entity startup finished");
+                let _ = done_tx.send(());
+            })?;
+        *self.deferred_start.lock().unwrap() =
+            Some(DeferredStart { thread, cancel, done });
+        Ok(())
     }
 
     fn pause_entities(&self) {
+        self.join_deferred_start();
         let _rtguard = self.runtime_hdl.enter();
-        self.for_each_entity(|ent, rec| {
-            info!(self.log, "Sending pause request to {}", rec.name());
-            ent.pause();
-            Ok(())
-        })
+        self.for_each_entity(
+            propolis::inventory::Order::Teardown,
+            |ent, rec| {
+                info!(self.log, "Sending pause request to {}", rec.name());
+                ent.pause();
+                Ok(())
+            },
+        )
         .unwrap();
 
         // Create a Future that returns the name of the entity that has finished
@@ -1044,21 +2625,28 @@ impl StateDriverVmController for VmController {
 
     fn resume_entities(&self) {
         let _rtguard = self.runtime_hdl.enter();
-        self.for_each_entity(|ent, rec| {
-            info!(self.log, "Sending resume request to {}", rec.name());
-            ent.resume();
-            Ok(())
-        })
+        self.for_each_entity(
+            propolis::inventory::Order::Startup,
+            |ent, rec| {
+                info!(self.log, "Sending resume request to {}", rec.name());
+                ent.resume();
+                Ok(())
+            },
+        )
         .unwrap();
     }
 
     fn halt_entities(&self) {
+        self.join_deferred_start();
         let _rtguard = self.runtime_hdl.enter();
-        self.for_each_entity(|ent, rec| {
-            info!(self.log, "Sending halt request to {}", rec.name());
-            ent.halt();
-            Ok(())
-        })
+        self.for_each_entity(
+            propolis::inventory::Order::Teardown,
+            |ent, rec| {
+                info!(self.log, "Sending halt request to {}", rec.name());
+                ent.halt();
+                Ok(())
+            },
+        )
         .unwrap();
     }
 
@@ -1078,4 +2666,31 @@ impl StateDriverVmController for VmController {
             }
         }
     }
+
+    fn retire_vcpu_state(&self, vcpu_id: i32) {
+        let instance = self.instance().lock();
+        let Some(vcpu) =
+            instance.machine().vcpus.iter().find(|v| v.id == vcpu_id)
+        else {
+            return;
+        };
+        info!(self.log, "Retiring vCPU {}", vcpu_id);
+        vcpu.reboot_state().unwrap();
+        vcpu.set_run_state(propolis::bhyve_api::VRS_HALT, None).unwrap();
+    }
+
+    fn save_snapshot(&self, file: File) -> Result<(), SnapshotError> {
+        let header = SnapshotHeader {
+            properties: self.properties(),
+            instance_spec: self
+                .runtime_hdl
+                .block_on(self.instance_spec())
+                .clone(),
+        };
+        crate::snapshot::save(self.instance(), &header, file, &self.log)
+    }
+
+    fn restore_snapshot(&self, file: File) -> Result<(), SnapshotError> {
+        crate::snapshot::restore(self.instance(), file, &self.log)
+    }
 }
@@ -22,6 +22,9 @@
 //! of their choice.
 
 use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 use slog::{debug, info, Logger};
 use thiserror::Error;
@@ -84,6 +87,43 @@ pub enum ExternalRequest {
     /// Halts the VM. Note that this is not a graceful shutdown and does not
     /// coordinate with guest software.
     Stop,
+
+    /// Pauses the VM, writes its state to a snapshot file, and then either
+    /// resumes or halts it.
+    SaveSnapshot {
+        /// The file to which the snapshot is written.
+        file: File,
+
+        /// Whether to halt the VM once the snapshot is saved.
+        stop: bool,
+
+        /// The channel on which the outcome of the snapshot is reported.
+        done_tx: tokio::sync::oneshot::Sender<Result<(), String>>,
+    },
+
+    /// Pauses the VM while the contents of one of its disks are exported, then
+    /// resumes it.
+    ///
+    /// The VM is held paused for a limited time, and not at all once it is
+    /// asked to stop.
+    PauseForExport {
+        /// The channel on which the exporter is told that the VM is paused.
+        paused_tx: tokio::sync::oneshot::Sender<()>,
+
+        /// The channel which the exporter closes once it is done, allowing the
+        /// VM to resume.
+        done_rx: tokio::sync::oneshot::Receiver<()>,
+
+        /// Set if the export is abandoned before it is done, before the VM
+        /// resumes or stops, so that the exporter sends nothing further.
+        abandoned: Arc<AtomicBool>,
+    },
+
+    /// Initializes the VM from a previously-saved snapshot, then starts it.
+    RestoreSnapshot {
+        /// The snapshot file from which to load the VM's state.
+        file: File,
+    },
 }
 
 /// A set of reasons why a request to queue an external state transition can
@@ -110,6 +150,12 @@ pub enum RequestDeniedReason {
 
     #[error("Instance failed to start or halted due to a failure")]
     InstanceFailed,
+
+    #[error("Instance is saving a snapshot")]
+    SnapshotInProgress,
+
+    #[error("Instance is exporting a disk")]
+    ExportInProgress,
 }
 
 /// The set of instance state changes that should change the dispositions of
@@ -156,7 +202,8 @@ struct AllowedRequests {
 
 #[derive(Debug)]
 pub struct ExternalRequestQueue {
-    queue: VecDeque<ExternalRequest>,
+    /// Queued requests, each with the ID of the API request that made it.
+    queue: VecDeque<(ExternalRequest, Option<String>)>,
     allowed: AllowedRequests,
     log: Logger,
 }
@@ -181,8 +228,9 @@ impl ExternalRequestQueue {
         }
     }
 
-    /// Pops the request at the front of the queue.
-    pub fn pop_front(&mut self) -> Option<ExternalRequest> {
+    /// Pops the request at the front of the queue, along with the ID of the
+    /// API request that made it (if any).
+    pub fn pop_front(&mut self) -> Option<(ExternalRequest, Option<String>)> {
         self.queue.pop_front()
     }
 
@@ -191,11 +239,20 @@ impl ExternalRequestQueue {
         self.queue.is_empty()
     }
 
+    /// Indicates whether a request to stop the instance is queued.
+    pub fn stop_queued(&self) -> bool {
+        self.queue.iter().any(|(req, _)| matches!(req, ExternalRequest::Stop))
+    }
+
     /// Asks to place the supplied request on the queue. If the requests is
     /// enqueued, updates the dispositions to use for future requests.
+    ///
+    /// `request_id` identifies the API request that made this one, if any, so
+    /// that the state driver's handling of it can be traced back to its origin.
     pub fn try_queue(
         &mut self,
         request: ExternalRequest,
+        request_id: Option<&str>,
     ) -> Result<(), RequestDeniedReason> {
         let disposition = match request {
             ExternalRequest::MigrateAsTarget { .. } => {
@@ -207,6 +264,17 @@ impl ExternalRequestQueue {
             }
             ExternalRequest::Reboot => self.allowed.reboot,
 
+            // Restoring from a snapshot is another way of initializing the VM
+            // from state saved elsewhere, and saving a snapshot pauses a running
+            // VM in the same way as migrating out of it.
+            ExternalRequest::RestoreSnapshot { .. } => {
+                self.allowed.migrate_as_target
+            }
+            ExternalRequest::SaveSnapshot { .. }
+            | ExternalRequest::PauseForExport { .. } => {
+                self.allowed.migrate_as_source
+            }
+
             // Requests to stop always succeed. Note that a request to stop a VM
             // that hasn't started should still be queued to the state worker so
             // that the worker can exit and drop its references to the instance.
@@ -215,6 +283,7 @@ impl ExternalRequestQueue {
 
         info!(&self.log, "Queuing external request";
               "request" => ?request,
+              "req_id" => request_id,
               "disposition" => ?disposition);
 
         match disposition {
@@ -226,7 +295,7 @@ impl ExternalRequestQueue {
         self.allowed = self.get_new_dispositions(
             DispositionChangeReason::ApiRequest(&request),
         );
-        self.queue.push_back(request);
+        self.queue.push_back((request, request_id.map(str::to_string)));
         Ok(())
     }
 
@@ -295,13 +364,17 @@ impl ExternalRequestQueue {
         use RequestDeniedReason as DenyReason;
         use RequestDisposition as Disposition;
         match reason {
-            // Starting the instance, whether via migration or cold boot,
+            // Starting the instance, whether via migration, snapshot restore,
+            // or cold boot,
             // forecloses on further attempts to migrate in. For idempotency,
             // further requests to start are allowed when an instance-starting
             // transition is enqueued.
             ChangeReason::ApiRequest(ExternalRequest::MigrateAsTarget {
                 ..
             })
+            | ChangeReason::ApiRequest(ExternalRequest::RestoreSnapshot {
+                ..
+            })
             | ChangeReason::ApiRequest(ExternalRequest::Start) => {
                 let (migrate_as_target_disposition, deny_reason) = match reason
                 {
@@ -313,6 +386,9 @@ impl ExternalRequestQueue {
                         Disposition::Ignore,
                         DenyReason::MigrationTargetInProgress,
                     ),
+                    ChangeReason::ApiRequest(
+                        ExternalRequest::RestoreSnapshot { .. },
+                    ) => (Disposition::Ignore, DenyReason::StartInProgress),
                     ChangeReason::ApiRequest(ExternalRequest::Start) => (
                         Disposition::Deny(DenyReason::StartInProgress),
                         DenyReason::StartInProgress,
@@ -354,6 +430,29 @@ impl ExternalRequestQueue {
                 }
             }
 
+            // Saving a snapshot pauses the instance, so requests to migrate
+            // out of or reboot it are denied until it resumes running.
+            ChangeReason::ApiRequest(ExternalRequest::SaveSnapshot {
+                ..
+            }) => AllowedRequests {
+                migrate_as_source: Disposition::Deny(
+                    DenyReason::SnapshotInProgress,
+                ),
+                reboot: Disposition::Deny(DenyReason::SnapshotInProgress),
+                ..self.allowed
+            },
+
+            // Likewise while a disk is exported.
+            ChangeReason::ApiRequest(ExternalRequest::PauseForExport {
+                ..
+            }) => AllowedRequests {
+                migrate_as_source: Disposition::Deny(
+                    DenyReason::ExportInProgress,
+                ),
+                reboot: Disposition::Deny(DenyReason::ExportInProgress),
+                ..self.allowed
+            },
+
             // Requests to reboot prevent additional reboot requests from being
             // queued, but do not affect other operations.
             ChangeReason::ApiRequest(ExternalRequest::Reboot) => {
@@ -488,43 +587,51 @@ mod tests {
 
         // After queuing such a request, subsequent requests should be allowed
         // without enqueuing anything.
-        assert!(queue.try_queue(make_migrate_as_target_request()).is_ok());
+        assert!(queue
+            .try_queue(make_migrate_as_target_request(), None)
+            .is_ok());
         assert!(!queue.migrate_as_target_will_enqueue().unwrap());
 
         // Pop the request and tell the queue the instance is running.
         assert!(matches!(
             queue.pop_front(),
-            Some(ExternalRequest::MigrateAsTarget { .. })
+            Some((ExternalRequest::MigrateAsTarget { .. }, _))
         ));
         queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
 
         // Because the instance was started via migration in, future requests
         // to migrate in should be allowed.
-        assert!(queue.try_queue(make_migrate_as_target_request()).is_ok());
+        assert!(queue
+            .try_queue(make_migrate_as_target_request(), None)
+            .is_ok());
         assert!(!queue.migrate_as_target_will_enqueue().unwrap());
     }
 
     #[tokio::test]
     async fn migrate_as_target_is_forbidden_after_cold_boot() {
         let mut queue = ExternalRequestQueue::new(test_logger());
-        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Start, None).is_ok());
         queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
 
         assert!(queue.migrate_as_target_will_enqueue().is_err());
-        assert!(queue.try_queue(make_migrate_as_target_request()).is_err());
+        assert!(queue
+            .try_queue(make_migrate_as_target_request(), None)
+            .is_err());
     }
 
     #[tokio::test]
     async fn migrate_as_source_is_not_idempotent() {
         // Simulate a running instance.
         let mut queue = ExternalRequestQueue::new(test_logger());
-        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
-        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Start)));
+        assert!(queue.try_queue(ExternalRequest::Start, None).is_ok());
+        assert!(matches!(queue.pop_front(), Some((ExternalRequest::Start, _))));
         queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
 
         // Requests to migrate out should be allowed.
         assert!(queue.migrate_as_source_will_enqueue().unwrap());
-        assert!(queue.try_queue(make_migrate_as_source_request()).is_ok());
+        assert!(queue
+            .try_queue(make_migrate_as_source_request(), None)
+            .is_ok());
 
         // Once the request is queued, other requests to migrate out are
         // disallowed until the queued request is disposed of.
@@ -535,42 +642,112 @@ mod tests {
         // Propolis (which does not assume idempotency and issues only one
         // request per migration attempt).
         assert!(queue.migrate_as_source_will_enqueue().is_err());
-        assert!(queue.try_queue(make_migrate_as_source_request()).is_err());
+        assert!(queue
+            .try_queue(make_migrate_as_source_request(), None)
+            .is_err());
 
         // If migration fails, the instance resumes running, and then another
         // request to migrate out should be allowed.
         assert!(matches!(
             queue.pop_front(),
-            Some(ExternalRequest::MigrateAsSource { .. })
+            Some((ExternalRequest::MigrateAsSource { .. }, _))
         ));
         queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
         assert!(queue.migrate_as_source_will_enqueue().unwrap());
-        assert!(queue.try_queue(make_migrate_as_source_request()).is_ok());
+        assert!(queue
+            .try_queue(make_migrate_as_source_request(), None)
+            .is_ok());
 
         // A successful migration stops the instance, which forecloses on future
         // requests to migrate out.
         queue.pop_front();
         queue.notify_instance_state_change(InstanceStateChange::Stopped);
         assert!(queue.migrate_as_source_will_enqueue().is_err());
-        assert!(queue.try_queue(make_migrate_as_source_request()).is_err());
+        assert!(queue
+            .try_queue(make_migrate_as_source_request(), None)
+            .is_err());
+    }
+
+    fn make_save_snapshot_request() -> ExternalRequest {
+        let (done_tx, _) = tokio::sync::oneshot::channel();
+        ExternalRequest::SaveSnapshot {
+            file: File::open("/dev/null").unwrap(),
+            stop: false,
+            done_tx,
+        }
+    }
+
+    #[tokio::test]
+    async fn save_snapshot_denies_reboot_until_resumed() {
+        let mut queue = ExternalRequestQueue::new(test_logger());
+
+        // Snapshots can only be taken of running instances.
+        assert!(queue.try_queue(make_save_snapshot_request(), None).is_err());
+        assert!(queue.try_queue(ExternalRequest::Start, None).is_ok());
+        assert!(matches!(queue.pop_front(), Some((ExternalRequest::Start, _))));
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+
+        // While the snapshot is pending, the instance can't be rebooted or
+        // migrated, but can still be stopped.
+        assert!(queue.try_queue(make_save_snapshot_request(), None).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot, None).is_err());
+        assert!(queue.migrate_as_source_will_enqueue().is_err());
+        assert!(matches!(
+            queue.pop_front(),
+            Some((ExternalRequest::SaveSnapshot { .. }, _))
+        ));
+
+        // Once the instance resumes, these requests are allowed again.
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+        assert!(queue.try_queue(ExternalRequest::Reboot, None).is_ok());
+        assert!(queue.migrate_as_source_will_enqueue().unwrap());
+        assert!(queue.try_queue(ExternalRequest::Stop, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn export_denies_reboot_until_resumed() {
+        let mut queue = ExternalRequestQueue::new(test_logger());
+        let make_export_request = || {
+            let (paused_tx, _) = tokio::sync::oneshot::channel();
+            let (_, done_rx) = tokio::sync::oneshot::channel();
+            let abandoned = Default::default();
+            ExternalRequest::PauseForExport { paused_tx, done_rx, abandoned }
+        };
+
+        assert!(queue.try_queue(make_export_request(), None).is_err());
+        assert!(queue.try_queue(ExternalRequest::Start, None).is_ok());
+        assert!(matches!(queue.pop_front(), Some((ExternalRequest::Start, _))));
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+
+        assert!(queue.try_queue(make_export_request(), None).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot, None).is_err());
+        assert!(queue.migrate_as_source_will_enqueue().is_err());
+        assert!(matches!(
+            queue.pop_front(),
+            Some((ExternalRequest::PauseForExport { .. }, _))
+        ));
+
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+        assert!(queue.try_queue(ExternalRequest::Reboot, None).is_ok());
+        assert!(queue.migrate_as_source_will_enqueue().unwrap());
     }
 
     #[tokio::test]
     async fn stop_requests_enqueue_after_vm_failure() {
         let mut queue = ExternalRequestQueue::new(test_logger());
-        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
-        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Start)));
+        assert!(queue.try_queue(ExternalRequest::Start, None).is_ok());
+        assert!(matches!(queue.pop_front(), Some((ExternalRequest::Start, _))));
         queue.notify_instance_state_change(InstanceStateChange::Failed);
 
-        assert!(queue.try_queue(ExternalRequest::Stop).is_ok());
-        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Stop)));
+        assert!(queue.try_queue(ExternalRequest::Stop, None).is_ok());
+        assert!(matches!(queue.pop_front(), Some((ExternalRequest::Stop, _))));
     }
 
     #[tokio::test]
     async fn reboot_requests_are_idempotent_except_when_stopping() {
         let mut queue = ExternalRequestQueue::new(test_logger());
-        assert!(queue.try_queue(ExternalRequest::Start).is_ok());
-        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Start)));
+        assert!(queue.try_queue(ExternalRequest::Start, None).is_ok());
+        assert!(matches!(queue.pop_front(), Some((ExternalRequest::Start, _))));
         queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
 
         // Once the instance is started, reboot requests should be allowed, but
@@ -578,27 +755,52 @@ mod tests {
         // idempotency.
         assert!(queue.is_empty());
         for _ in 0..5 {
-            assert!(queue.try_queue(ExternalRequest::Reboot).is_ok());
+            assert!(queue.try_queue(ExternalRequest::Reboot, None).is_ok());
         }
-        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Reboot)));
+        assert!(matches!(
+            queue.pop_front(),
+            Some((ExternalRequest::Reboot, _))
+        ));
         assert!(queue.is_empty());
 
         // Once the instance has rebooted, new requests can be queued.
         queue.notify_instance_state_change(InstanceStateChange::Rebooted);
-        assert!(queue.try_queue(ExternalRequest::Reboot).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot, None).is_ok());
         assert!(!queue.is_empty());
-        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Reboot)));
+        assert!(matches!(
+            queue.pop_front(),
+            Some((ExternalRequest::Reboot, _))
+        ));
         queue.notify_instance_state_change(InstanceStateChange::Rebooted);
 
         // If a request to reboot is queued, and then a request to stop is
         // queued, new requests to reboot should always fail, even after the
         // instance finishes rebooting.
-        assert!(queue.try_queue(ExternalRequest::Reboot).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot, None).is_ok());
         assert!(!queue.is_empty());
-        assert!(queue.try_queue(ExternalRequest::Stop).is_ok());
-        assert!(queue.try_queue(ExternalRequest::Reboot).is_err());
-        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Reboot)));
+        assert!(queue.try_queue(ExternalRequest::Stop, None).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot, None).is_err());
+        assert!(matches!(
+            queue.pop_front(),
+            Some((ExternalRequest::Reboot, _))
+        ));
         queue.notify_instance_state_change(InstanceStateChange::Rebooted);
-        assert!(queue.try_queue(ExternalRequest::Reboot).is_err());
+        assert!(queue.try_queue(ExternalRequest::Reboot, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn request_ids_follow_their_requests() {
+        let mut queue = ExternalRequestQueue::new(test_logger());
+        assert!(queue.try_queue(ExternalRequest::Start, Some("start")).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Stop, None).is_ok());
+
+        assert!(matches!(
+            queue.pop_front(),
+            Some((ExternalRequest::Start, Some(id))) if id == "start"
+        ));
+        assert!(matches!(
+            queue.pop_front(),
+            Some((ExternalRequest::Stop, None))
+        ));
     }
 }
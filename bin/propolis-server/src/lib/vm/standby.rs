@@ -0,0 +1,79 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A VM created ahead of time, while the server awaits its instance.
+//!
+//! Much of the time taken to start an instance is spent creating its VM:
+//! allocating (and zeroing) guest memory, and reading and unpacking the
+//! bootrom.  Neither depends on the instance spec beyond the shape of its
+//! board, so a server configured with a [`config::Standby`] does this work as
+//! soon as it starts.  When the instance arrives, its devices are then added to
+//! the standby VM if the shapes match; otherwise the standby VM is discarded
+//! and a new one created as usual.
+
+use propolis::Instance;
+use propolis_api_types::instance_spec::v0::InstanceSpecV0;
+use propolis_api_types::BootromInfo;
+use slog::{info, Logger};
+
+use crate::config;
+use crate::initializer::{
+    build_instance, hotplug_memory_mb, load_rom, select_rom, NvramTemplate,
+};
+
+pub struct StandbyMachine {
+    instance: Instance,
+    bootrom: BootromInfo,
+    nvram: Option<NvramTemplate>,
+    shape: config::Standby,
+}
+
+impl StandbyMachine {
+    /// Creates a VM of the configured shape and loads a bootrom into it.
+    pub fn create(
+        shape: &config::Standby,
+        use_reservoir: bool,
+        bootroms: &[config::BootromCandidate],
+        log: &Logger,
+    ) -> anyhow::Result<Self> {
+        // VMs are named after the instance they host, which is not yet known.
+        // Using the server's PID ensures that standby VMs created by different
+        // servers on the host do not collide.
+        let name = format!("propolis-standby-{}", std::process::id());
+        info!(log, "creating standby VM";
+              "name" => &name,
+              "cpus" => shape.cpus,
+              "memory_mb" => shape.memory_mb);
+
+        let (fw, bootrom) = select_rom(bootroms, log)?;
+        let instance = build_instance(
+            &name,
+            shape.cpus,
+            shape.memory_mb,
+            0,
+            &fw,
+            use_reservoir,
+            log.clone(),
+        )?;
+        let nvram = load_rom(instance.lock().machine(), &fw)?;
+
+        Ok(Self { instance, bootrom, nvram, shape: shape.clone() })
+    }
+
+    /// Returns whether this VM can host an instance with the given spec.
+    /// Standby VMs have no hot-pluggable memory region, so cannot host an
+    /// instance which has one.
+    pub fn fits(&self, spec: &InstanceSpecV0) -> bool {
+        let board = &spec.devices.board;
+        board.cpus == self.shape.cpus
+            && board.memory_mb == self.shape.memory_mb
+            && hotplug_memory_mb(spec) == 0
+    }
+
+    /// Yields the VM, the bootrom loaded into it, and the bootrom's variable
+    /// store.
+    pub fn into_parts(self) -> (Instance, BootromInfo, Option<NvramTemplate>) {
+        (self.instance, self.bootrom, self.nvram)
+    }
+}
@@ -2,32 +2,43 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::fs::File;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use crate::migrate::MigrateError;
 use crate::vcpu_tasks::VcpuTaskController;
 
 use super::{
-    request_queue, ExternalRequest, GuestEvent, MigrateSourceCommand,
-    MigrateSourceResponse, MigrateTargetCommand, MigrateTaskEvent,
-    SharedVmState, StateDriverEvent,
+    request_queue, ExportWaitOutcome, ExternalRequest, GuestEvent,
+    MigrateSourceCommand, MigrateSourceResponse, MigrateTargetCommand,
+    MigrateTaskEvent, SharedVmState, StateDriverEvent,
 };
 
+use propolis::hw::qemu::pvpanic::PanicEvent;
 use propolis_api_types::{
+    GuestPanic as ApiGuestPanic, GuestPanicKind as ApiGuestPanicKind,
     InstanceMigrateStatusResponse as ApiMigrationStatus,
     InstanceState as ApiInstanceState,
     InstanceStateMonitorResponse as ApiMonitoredState,
     MigrationState as ApiMigrationState,
 };
-use slog::{error, info, Logger};
+use slog::{error, info, o, warn, Logger};
 use uuid::Uuid;
 
 #[usdt::provider(provider = "propolis")]
 mod probes {
     fn state_driver_pause() {}
     fn state_driver_resume() {}
+    fn state_driver_request_begin(req_id: &str) {}
+    fn state_driver_request_end(req_id: &str) {}
 }
 
+/// The longest an instance is held paused for a disk export, whatever deadline
+/// the client gave for it, if any.
+const EXPORT_PAUSE_LIMIT: Duration = Duration::from_secs(10 * 60);
+
 /// Tells the state driver whether or not to continue running after responding
 /// to an event.
 #[derive(Debug, PartialEq, Eq)]
@@ -39,6 +50,7 @@ enum HandleEventOutcome {
 #[derive(Debug, PartialEq, Eq)]
 enum VmStartReason {
     MigratedIn,
+    RestoredFromSnapshot,
     ExplicitRequest,
 }
 
@@ -65,6 +77,9 @@ pub(super) struct StateDriver<
     /// The state worker's logger.
     log: Logger,
 
+    /// The ID of the API request that raised the event being handled, if any.
+    request_id: Option<String>,
+
     /// The generation number to use when publishing externally-visible state
     /// updates.
     state_gen: u64,
@@ -97,6 +112,7 @@ where
             shared_state: shared_controller_state,
             vcpu_tasks,
             log,
+            request_id: None,
             state_gen: 0,
             paused: false,
             api_state_tx,
@@ -147,6 +163,19 @@ where
         });
     }
 
+    /// Publishes the most recent panic reported by the guest to the instance
+    /// state channel.
+    fn set_guest_panic(&mut self, guest_panic: Option<ApiGuestPanic>) {
+        let old = self.api_state_tx.borrow().clone();
+
+        self.state_gen += 1;
+        let _ = self.api_state_tx.send(ApiMonitoredState {
+            gen: self.state_gen,
+            guest_panic,
+            ..old
+        });
+    }
+
     /// Manages an instance's lifecycle once it has moved to the Running state.
     pub(super) fn run_state_worker(
         mut self,
@@ -154,11 +183,26 @@ where
         info!(self.log, "State worker launched");
 
         loop {
-            let event = self.shared_state.wait_for_next_event();
-            info!(self.log, "State worker handling event"; "event" => ?event);
+            let (event, request_id) = self.shared_state.wait_for_next_event();
+
+            // Tag everything logged while handling an event with the ID of the
+            // API request that raised it, so that the work done on its behalf
+            // can be traced back to it.
+            let worker_log = self.log.clone();
+            if let Some(id) = &request_id {
+                self.log = worker_log.new(o!("req_id" => id.clone()));
+                probes::state_driver_request_begin!(|| id.clone());
+            }
+            self.request_id = request_id;
 
+            info!(self.log, "State worker handling event"; "event" => ?event);
             let outcome = self.handle_event(event);
             info!(self.log, "State worker handled event"; "outcome" => ?outcome);
+
+            if let Some(id) = self.request_id.take() {
+                probes::state_driver_request_end!(|| id);
+            }
+            self.log = worker_log;
             if matches!(outcome, HandleEventOutcome::Exit) {
                 break;
             }
@@ -220,6 +264,22 @@ where
                 self.do_halt();
                 HandleEventOutcome::Exit
             }
+            ExternalRequest::SaveSnapshot { file, stop, done_tx } => {
+                self.save_snapshot(file, stop, done_tx);
+                HandleEventOutcome::Continue
+            }
+            ExternalRequest::PauseForExport {
+                paused_tx,
+                done_rx,
+                abandoned,
+            } => {
+                self.pause_for_export(paused_tx, done_rx, abandoned);
+                HandleEventOutcome::Continue
+            }
+            ExternalRequest::RestoreSnapshot { file } => {
+                self.restore_snapshot(file);
+                HandleEventOutcome::Continue
+            }
         }
     }
 
@@ -253,6 +313,38 @@ where
                 self.do_reboot();
                 HandleEventOutcome::Continue
             }
+            GuestEvent::VcpuEjected(vcpu_id) => {
+                info!(
+                    self.log,
+                    "Retiring vCPU {} after guest ejection", vcpu_id
+                );
+                self.vcpu_tasks.exit_vcpu(vcpu_id);
+                self.controller.retire_vcpu_state(vcpu_id);
+                HandleEventOutcome::Continue
+            }
+            GuestEvent::GuestPanic(event, when) => {
+                error!(self.log, "Guest reported a panic"; "event" => ?event);
+                self.set_guest_panic(Some(ApiGuestPanic {
+                    kind: match event {
+                        PanicEvent::Panicked => ApiGuestPanicKind::Panicked,
+                        PanicEvent::CrashLoaded => {
+                            ApiGuestPanicKind::CrashLoaded
+                        }
+                    },
+                    time_ns: when
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_nanos() as u64),
+                }));
+                HandleEventOutcome::Continue
+            }
+            GuestEvent::DeferredStartFailed => {
+                error!(self.log, "Failing instance after deferred start error");
+                if !self.paused {
+                    self.pause();
+                }
+                self.publish_steady_state(ApiInstanceState::Failed);
+                HandleEventOutcome::Continue
+            }
         }
     }
 
@@ -283,6 +375,11 @@ where
                 // consistency while migration state was loaded.
                 self.controller.resume_vm();
             }
+            VmStartReason::RestoredFromSnapshot => {
+                // As above, the kernel VMM was kept paused while the snapshot
+                // was loaded.
+                self.controller.resume_vm();
+            }
         }
 
         match self.controller.start_entities() {
@@ -302,6 +399,11 @@ where
 
         self.set_instance_state(ApiInstanceState::Rebooting);
 
+        // Any panic the guest reported was from before the reboot.
+        if self.api_state_tx.borrow().guest_panic.is_some() {
+            self.set_guest_panic(None);
+        }
+
         // Reboot is implemented as a pause -> reset -> resume transition.
         //
         // First, pause the vCPUs and all entities so no partially-completed
@@ -448,7 +550,10 @@ where
                         ));
 
                         self.shared_state
-                            .queue_external_request(ExternalRequest::Stop)
+                            .queue_external_request(
+                                ExternalRequest::Stop,
+                                self.request_id.as_deref(),
+                            )
                             .expect("can always queue a request to stop");
                     } else {
                         assert!(matches!(
@@ -481,6 +586,106 @@ where
         }
     }
 
+    fn save_snapshot(
+        &mut self,
+        file: File,
+        stop: bool,
+        done_tx: tokio::sync::oneshot::Sender<Result<(), String>>,
+    ) {
+        info!(self.log, "Saving snapshot"; "stop" => stop);
+
+        self.pause();
+        let res = self.controller.save_snapshot(file);
+        if let Err(e) = &res {
+            error!(self.log, "Failed to save snapshot: {}", e);
+        }
+        let saved = res.is_ok();
+
+        // The requester may have stopped waiting for the outcome, which doesn't
+        // change what happens to the instance.
+        let _ = done_tx.send(res.map_err(|e| e.to_string()));
+
+        // If the snapshot failed, the instance keeps running even if it was
+        // supposed to stop, so that its state isn't lost.
+        if saved && stop {
+            self.shared_state
+                .queue_external_request(
+                    ExternalRequest::Stop,
+                    self.request_id.as_deref(),
+                )
+                .expect("can always queue a request to stop");
+        } else {
+            self.resume();
+            self.publish_steady_state(ApiInstanceState::Running);
+        }
+    }
+
+    fn pause_for_export(
+        &mut self,
+        paused_tx: tokio::sync::oneshot::Sender<()>,
+        done_rx: tokio::sync::oneshot::Receiver<()>,
+        abandoned: Arc<AtomicBool>,
+    ) {
+        info!(self.log, "Pausing instance for disk export");
+
+        self.pause();
+        let deadline = Instant::now() + EXPORT_PAUSE_LIMIT;
+
+        // The exporter's done channel is watched from the runtime, so that the
+        // driver can also wait on the request queue for the instance to stop.
+        let done = Arc::new(AtomicBool::new(false));
+        let task_done = done.clone();
+        let shared_state = self.shared_state.clone();
+        self.runtime_hdl.spawn(async move {
+            let _ = done_rx.await;
+            task_done.store(true, Ordering::Release);
+            shared_state.notify_export_progress();
+        });
+
+        // If the exporter has gone away, there is nothing to wait for, and its
+        // done channel is closed as well.
+        let _ = paused_tx.send(());
+
+        match self.shared_state.wait_for_export(&done, deadline) {
+            ExportWaitOutcome::Done => {
+                info!(self.log, "Disk export finished, resuming instance");
+            }
+            ExportWaitOutcome::TimedOut => {
+                warn!(self.log, "Disk export ran out of time, resuming instance";
+                      "limit" => ?EXPORT_PAUSE_LIMIT);
+                abandoned.store(true, Ordering::Release);
+            }
+            ExportWaitOutcome::StopRequested => {
+                // The queued request halts the instance as it stands, paused.
+                info!(self.log, "Abandoning disk export to stop instance");
+                abandoned.store(true, Ordering::Release);
+                return;
+            }
+        }
+        self.resume();
+        self.publish_steady_state(ApiInstanceState::Running);
+    }
+
+    fn restore_snapshot(&mut self, file: File) {
+        info!(self.log, "Restoring instance from snapshot");
+        self.set_instance_state(ApiInstanceState::Starting);
+
+        // As when migrating in, activate the vCPUs before loading their state
+        // so that it isn't overwritten by reset, and keep the kernel VMM paused
+        // while device state is loaded.
+        self.reset_vcpus();
+        self.controller.pause_vm();
+
+        match self.controller.restore_snapshot(file) {
+            Ok(()) => self.start_vm(VmStartReason::RestoredFromSnapshot),
+            Err(e) => {
+                error!(self.log, "Failed to restore snapshot: {}", e);
+                self.controller.resume_vm();
+                self.publish_steady_state(ApiInstanceState::Failed);
+            }
+        }
+    }
+
     async fn next_migrate_task_event<E>(
         task: &mut tokio::task::JoinHandle<Result<(), MigrateError>>,
         command_rx: &mut tokio::sync::mpsc::Receiver<E>,
@@ -573,6 +778,7 @@ mod tests {
     use mockall::Sequence;
 
     use super::*;
+    use crate::snapshot::SnapshotError;
     use crate::vcpu_tasks::MockVcpuTaskController;
     use crate::vm::MockStateDriverVmController;
 
@@ -601,6 +807,7 @@ mod tests {
                 gen: 0,
                 state: ApiInstanceState::Creating,
                 migration: None,
+                guest_panic: None,
             });
 
         TestStateDriver {
@@ -722,6 +929,90 @@ mod tests {
         assert!(matches!(driver.api_state(), ApiInstanceState::Running));
     }
 
+    #[tokio::test]
+    async fn guest_vcpu_eject_retires_vcpu() {
+        let mut test_objects = make_default_mocks();
+        let mut seq = Sequence::new();
+        test_objects
+            .vcpu_ctrl
+            .expect_exit_vcpu()
+            .withf(|id| *id == 2)
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| ());
+        test_objects
+            .vm_ctrl
+            .expect_retire_vcpu_state()
+            .withf(|id| *id == 2)
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| ());
+
+        let mut driver = make_state_driver(test_objects);
+        driver
+            .driver
+            .handle_event(StateDriverEvent::Guest(GuestEvent::VcpuEjected(2)));
+    }
+
+    #[tokio::test]
+    async fn deferred_start_failure_fails_instance() {
+        let mut test_objects = make_default_mocks();
+        let mut seq = Sequence::new();
+        test_objects
+            .vcpu_ctrl
+            .expect_pause_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        test_objects
+            .vm_ctrl
+            .expect_pause_entities()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        test_objects
+            .vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.handle_event(StateDriverEvent::Guest(
+            GuestEvent::DeferredStartFailed,
+        ));
+
+        assert!(driver.driver.paused);
+        assert!(matches!(driver.api_state(), ApiInstanceState::Failed));
+    }
+
+    #[tokio::test]
+    async fn guest_panic_published_until_reboot() {
+        let mut test_objects = make_default_mocks();
+        add_reboot_expectations(
+            &mut test_objects.vm_ctrl,
+            &mut test_objects.vcpu_ctrl,
+        );
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.handle_event(StateDriverEvent::Guest(
+            GuestEvent::GuestPanic(
+                PanicEvent::CrashLoaded,
+                UNIX_EPOCH + std::time::Duration::from_secs(1),
+            ),
+        ));
+        let guest_panic = driver.state_rx.borrow().guest_panic.clone();
+        let guest_panic = guest_panic.expect("panic should be published");
+        assert_eq!(guest_panic.kind, ApiGuestPanicKind::CrashLoaded);
+        assert_eq!(guest_panic.time_ns, 1_000_000_000);
+
+        driver
+            .driver
+            .handle_event(StateDriverEvent::Guest(GuestEvent::ChipsetReset));
+        assert!(driver.state_rx.borrow().guest_panic.is_none());
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+    }
+
     #[tokio::test]
     async fn start_from_cold_boot() {
         let mut test_objects = make_default_mocks();
@@ -1272,4 +1563,262 @@ mod tests {
         assert!(matches!(new_state.state, ApiInstanceState::Running));
         assert_eq!(new_state.gen, migrating_gen + 1);
     }
+
+    #[tokio::test]
+    async fn vm_resumes_after_failed_snapshot_save() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+
+        // Even though the request asks to stop the instance once its snapshot
+        // is saved, the instance should resume if the snapshot can't be saved.
+        let mut seq = Sequence::new();
+        vcpu_ctrl
+            .expect_pause_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_entities()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_save_snapshot()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| {
+                Err(SnapshotError::Io(std::io::ErrorKind::Other.into()))
+            });
+        vm_ctrl
+            .expect_resume_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_resume_entities()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vcpu_ctrl
+            .expect_resume_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl.expect_halt_entities().never();
+
+        let mut driver = make_state_driver(test_objects);
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let outcome = driver.driver.handle_event(StateDriverEvent::External(
+            ExternalRequest::SaveSnapshot {
+                file: File::open("/dev/null").unwrap(),
+                stop: true,
+                done_tx,
+            },
+        ));
+
+        assert!(done_rx.await.unwrap().is_err());
+        assert_eq!(outcome, HandleEventOutcome::Continue);
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+    }
+
+    #[tokio::test]
+    async fn vm_resumes_after_disk_export() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+
+        let mut seq = Sequence::new();
+        vcpu_ctrl
+            .expect_pause_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_entities()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_resume_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_resume_entities()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vcpu_ctrl
+            .expect_resume_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        let (paused_tx, paused_rx) = tokio::sync::oneshot::channel();
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let request = ExternalRequest::PauseForExport {
+            paused_tx,
+            done_rx,
+            abandoned: abandoned.clone(),
+        };
+
+        // The driver blocks while waiting for the export to finish, so it must
+        // run outside the async runtime.
+        let hdl = std::thread::spawn(move || {
+            let outcome =
+                driver.driver.handle_event(StateDriverEvent::External(request));
+            (driver, outcome)
+        });
+
+        // The instance stays paused until the exporter is done.
+        paused_rx.await.unwrap();
+        drop(done_tx);
+
+        let (driver, outcome) =
+            tokio::task::spawn_blocking(move || hdl.join().unwrap())
+                .await
+                .unwrap();
+        assert_eq!(outcome, HandleEventOutcome::Continue);
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+        assert!(!abandoned.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn stop_abandons_disk_export() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+
+        // The instance is paused for the export, and then halted without
+        // being resumed.
+        let mut seq = Sequence::new();
+        vcpu_ctrl
+            .expect_pause_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_entities()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vcpu_ctrl
+            .expect_exit_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_halt_entities()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        let shared_state = driver.driver.shared_state.clone();
+        let (paused_tx, paused_rx) = tokio::sync::oneshot::channel();
+        let (_done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let request = ExternalRequest::PauseForExport {
+            paused_tx,
+            done_rx,
+            abandoned: abandoned.clone(),
+        };
+
+        let hdl = std::thread::spawn(move || {
+            let outcome =
+                driver.driver.handle_event(StateDriverEvent::External(request));
+            assert_eq!(outcome, HandleEventOutcome::Continue);
+            let (event, _) = driver.driver.shared_state.wait_for_next_event();
+            let outcome = driver.driver.handle_event(event);
+            (driver, outcome)
+        });
+
+        // A request to stop the instance ends the export, though the exporter
+        // is still holding on to it.
+        paused_rx.await.unwrap();
+        shared_state
+            .queue_external_request(ExternalRequest::Stop, None)
+            .unwrap();
+
+        let (driver, outcome) =
+            tokio::task::spawn_blocking(move || hdl.join().unwrap())
+                .await
+                .unwrap();
+        assert_eq!(outcome, HandleEventOutcome::Exit);
+        assert!(matches!(driver.api_state(), ApiInstanceState::Stopped));
+        assert!(abandoned.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn vm_starts_after_snapshot_restore() {
+        let mut test_objects = make_default_mocks();
+        let vm_ctrl = &mut test_objects.vm_ctrl;
+        let vcpu_ctrl = &mut test_objects.vcpu_ctrl;
+
+        // The vCPUs must be activated before their state is loaded, and the
+        // kernel VMM must stay paused until all the state has been loaded.
+        let mut seq = Sequence::new();
+        vcpu_ctrl
+            .expect_new_generation()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_reset_vcpu_state()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_pause_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_restore_snapshot()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(()));
+        vm_ctrl
+            .expect_resume_vm()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+        vm_ctrl
+            .expect_start_entities()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Ok(()));
+        vcpu_ctrl
+            .expect_resume_all()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| ());
+
+        let mut driver = make_state_driver(test_objects);
+        driver.driver.handle_event(StateDriverEvent::External(
+            ExternalRequest::RestoreSnapshot {
+                file: File::open("/dev/null").unwrap(),
+            },
+        ));
+
+        assert!(matches!(driver.api_state(), ApiInstanceState::Running));
+    }
 }
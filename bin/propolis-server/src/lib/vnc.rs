@@ -5,6 +5,7 @@
 use async_trait::async_trait;
 use propolis::common::GuestAddr;
 use propolis::hw::ps2::ctrl::PS2Ctrl;
+use propolis::hw::qemu::bochs::PciBochsDisplay;
 use propolis::hw::qemu::ramfb::{Config, FramebufferSpec};
 use rfb::encodings::RawEncoding;
 use rfb::pixel_formats::fourcc;
@@ -47,9 +48,29 @@ struct DefaultFb {
     height: u16,
 }
 
+impl DefaultFb {
+    /// A white screen, displayed until the guest is ready
+    fn update(&self) -> FramebufferUpdate {
+        let len: usize = self.width as usize * self.height as usize * 4;
+        let pixels = vec![0xffu8; len];
+
+        let r = Rectangle::new(
+            0,
+            0,
+            self.width,
+            self.height,
+            Box::new(RawEncoding::new(pixels)),
+        );
+        FramebufferUpdate::new(vec![r])
+    }
+}
+
 enum Framebuffer {
     Uninitialized(DefaultFb),
     Initialized(RamFb),
+    /// The framebuffer of the instance's display device, which is scraped
+    /// whenever the client asks for an update.
+    Display(Arc<PciBochsDisplay>),
 }
 
 struct PropolisVncServerInner {
@@ -91,6 +112,19 @@ impl PropolisVncServer {
         inner.vm = Some(vm);
     }
 
+    /// Serves the framebuffer of `display` in place of that of the ramfb.
+    pub async fn initialize_display(
+        &self,
+        display: Arc<PciBochsDisplay>,
+        ps2ctrl: Arc<PS2Ctrl>,
+        vm: Arc<VmController>,
+    ) {
+        let mut inner = self.inner.lock().await;
+        inner.framebuffer = Framebuffer::Display(display);
+        inner.ps2ctrl = Some(ps2ctrl);
+        inner.vm = Some(vm);
+    }
+
     pub async fn update(
         &self,
         config: &Config,
@@ -135,17 +169,26 @@ impl Server for PropolisVncServer {
         match &inner.framebuffer {
             Framebuffer::Uninitialized(fb) => {
                 debug!(self.log, "framebuffer: uninitialized");
-
-                // Display a white screen if the guest isn't ready yet.
-                let len: usize = fb.width as usize * fb.height as usize * 4;
-                let pixels = vec![0xffu8; len];
+                fb.update()
+            }
+            Framebuffer::Display(display) => {
+                let Some(frame) =
+                    tokio::task::block_in_place(|| display.frame())
+                else {
+                    debug!(self.log, "display: no readable mode set");
+                    let fb = DefaultFb {
+                        width: INITIAL_WIDTH,
+                        height: INITIAL_HEIGHT,
+                    };
+                    return fb.update();
+                };
 
                 let r = Rectangle::new(
                     0,
                     0,
-                    fb.width,
-                    fb.height,
-                    Box::new(RawEncoding::new(pixels)),
+                    frame.width as u16,
+                    frame.height as u16,
+                    Box::new(RawEncoding::new(frame.data)),
                 );
                 FramebufferUpdate::new(vec![r])
             }
@@ -181,8 +224,14 @@ impl Server for PropolisVncServer {
     async fn key_event(&self, ke: KeyEvent) {
         let inner = self.inner.lock().await;
         let ps2 = inner.ps2ctrl.as_ref();
+        // A virtio-input keyboard, if present, takes the input in place of
+        // the PS/2 keyboard.
+        let keyboard = inner.vm.as_ref().and_then(|vm| vm.keyboard());
 
-        if let Some(ps2) = ps2 {
+        if let Some(keyboard) = keyboard {
+            trace!(self.log, "keyevent: {:?}", ke);
+            keyboard.keysym_event(ke.keysym_raw(), ke.is_pressed());
+        } else if let Some(ps2) = ps2 {
             trace!(self.log, "keyevent: {:?}", ke);
             ps2.key_event(ke);
         } else {
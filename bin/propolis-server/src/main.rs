@@ -14,6 +14,7 @@ use std::sync::Arc;
 
 use propolis_server::{
     config,
+    log_control::{LogLevelHandle, RuntimeLevelFilter},
     server::{self, MetricsEndpointConfig},
     vnc::setup_vnc,
 };
@@ -63,6 +64,7 @@ async fn run_server(
     metrics_addr: Option<SocketAddr>,
     vnc_addr: SocketAddr,
     log: slog::Logger,
+    log_level: LogLevelHandle,
 ) -> anyhow::Result<()> {
     use propolis::api_version;
 
@@ -94,7 +96,10 @@ async fn run_server(
         use_reservoir,
         log.new(slog::o!()),
         config_metrics,
-    );
+        log_level,
+    )
+    .context("server context setup")?;
+    context.start_standby().await;
 
     info!(log, "Starting server...");
 
@@ -112,7 +117,7 @@ async fn run_server(
     server_res.map_err(|e| anyhow!("Server exited with an error: {}", e))
 }
 
-fn build_logger() -> slog::Logger {
+fn build_logger() -> (slog::Logger, LogLevelHandle) {
     use slog::Drain;
 
     let main_drain = if atty::is(atty::Stream::Stdout) {
@@ -133,7 +138,8 @@ fn build_logger() -> slog::Logger {
 
     let (dtrace_drain, probe_reg) = slog_dtrace::Dtrace::new();
 
-    let filtered_main = slog::LevelFilter::new(main_drain, slog::Level::Info);
+    let (filtered_main, log_level) =
+        RuntimeLevelFilter::new(main_drain, slog::Level::Info);
 
     let log = slog::Logger::root(
         slog::Duplicate::new(filtered_main.fuse(), dtrace_drain.fuse()).fuse(),
@@ -144,11 +150,23 @@ fn build_logger() -> slog::Logger {
         slog::error!(&log, "Error registering slog-dtrace probes: {:?}", err);
     }
 
-    log
+    (log, log_level)
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
+    // Descriptors inherited from our parent must be recorded before the async
+    // runtime (or anything else) opens descriptors of its own.
+    propolis::harden::capture_inherited()
+        .context("recording inherited descriptors")?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("building tokio runtime")?
+        .block_on(async_main())
+}
+
+async fn async_main() -> anyhow::Result<()> {
     // Ensure proper setup of USDT probes
     register_probes().unwrap();
 
@@ -168,10 +186,17 @@ async fn main() -> anyhow::Result<()> {
                 default_handler_task_mode: HandlerTaskMode::Detached,
             };
 
-            let log = build_logger();
-
-            run_server(config, config_dropshot, metric_addr, vnc_addr, log)
-                .await
+            let (log, log_level) = build_logger();
+
+            run_server(
+                config,
+                config_dropshot,
+                metric_addr,
+                vnc_addr,
+                log,
+                log_level,
+            )
+            .await
         }
     }
 }
@@ -14,21 +14,98 @@ use propolis::block;
 use propolis::cpuid;
 use propolis::hw::pci::Bdf;
 use propolis::inventory::ChildRegister;
+use propolis::topology::CpuTopology;
 
 use crate::cidata::build_cidata_be;
-pub use propolis_standalone_config::{Config, SnapshotTag};
-use propolis_standalone_config::{CpuVendor, CpuidEntry, Device};
+pub use propolis_standalone_config::{Config, ConsoleBreak, SnapshotTag};
+use propolis_standalone_config::{CpuVendor, CpuidEntry, Device, Harden};
 
 #[derive(Deserialize)]
 struct FileConfig {
     path: String,
     workers: Option<usize>,
+    shared: Option<bool>,
+}
+/// Policies of a crucible backend, configured alongside the options which
+/// describe its volume
+#[derive(Deserialize)]
+#[cfg_attr(not(feature = "crucible"), allow(dead_code))]
+struct CruciblePolicyConfig {
+    retry_max_attempts: Option<u32>,
+    retry_stall_timeout_ms: Option<u64>,
+    retry_not_ready_on_exhaust: Option<bool>,
+    prefetch: Option<bool>,
+    prefetch_rate_bytes: Option<u64>,
+    prefetch_max_bytes: Option<u64>,
+    journal_path: Option<String>,
+    journal_max_bytes: Option<u64>,
+}
+impl CruciblePolicyConfig {
+    /// Supervise the backend with a retry policy if any of its parameters were
+    /// specified.
+    fn retry_policy(
+        &self,
+    ) -> anyhow::Result<Option<block::health::RetryPolicy>> {
+        if self.retry_max_attempts.is_none()
+            && self.retry_stall_timeout_ms.is_none()
+            && self.retry_not_ready_on_exhaust.is_none()
+        {
+            return Ok(None);
+        }
+        let dflt = block::health::RetryPolicy::default();
+        let policy = block::health::RetryPolicy {
+            max_attempts: self.retry_max_attempts.unwrap_or(dflt.max_attempts),
+            stall_timeout: self
+                .retry_stall_timeout_ms
+                .map(std::time::Duration::from_millis)
+                .unwrap_or(dflt.stall_timeout),
+            not_ready_on_exhaust: self
+                .retry_not_ready_on_exhaust
+                .unwrap_or(dflt.not_ready_on_exhaust),
+            ..dflt
+        };
+        policy.validate().map_err(anyhow::Error::msg)?;
+        Ok(Some(policy))
+    }
+
+    /// Prefetch the start of the volume in the background if asked.
+    #[cfg(feature = "crucible")]
+    fn prefetch_policy(&self) -> Option<block::prefetch::Policy> {
+        let asked = self.prefetch_rate_bytes.is_some()
+            || self.prefetch_max_bytes.is_some();
+        self.prefetch.unwrap_or(asked).then(|| {
+            let dflt = block::prefetch::Policy::default();
+            block::prefetch::Policy {
+                max_bytes_per_sec: self
+                    .prefetch_rate_bytes
+                    .unwrap_or(dflt.max_bytes_per_sec),
+                max_bytes: self.prefetch_max_bytes.unwrap_or(dflt.max_bytes),
+                ..dflt
+            }
+        })
+    }
+
+    /// Journal writes to a local file, replaying them after an outage of the
+    /// downstairs, if asked.
+    #[cfg(feature = "crucible")]
+    fn journal_policy(&self) -> Option<block::journal::Policy> {
+        let dflt = block::journal::Policy::new(self.journal_path.as_ref()?);
+        Some(block::journal::Policy {
+            max_bytes: self.journal_max_bytes.unwrap_or(dflt.max_bytes),
+            ..dflt
+        })
+    }
 }
 #[derive(Deserialize)]
 struct MemAsyncConfig {
     size: u64,
     workers: Option<usize>,
 }
+#[derive(Deserialize)]
+struct NullConfig {
+    size: u64,
+    workers: Option<usize>,
+}
 
 // Try to turn unmatched flattened options into a config struct
 fn opt_deser<'de, T: Deserialize<'de>>(
@@ -47,19 +124,55 @@ pub fn block_backend(
     log: &slog::Logger,
 ) -> (Arc<dyn block::Backend>, ChildRegister) {
     let backend_name = dev.options.get("block_dev").unwrap().as_str().unwrap();
+    named_block_backend(config, backend_name, log)
+}
+
+/// Create the backend for the `[block_dev.<name>]` section of the config
+pub fn named_block_backend(
+    config: &Config,
+    backend_name: &str,
+    log: &slog::Logger,
+) -> (Arc<dyn block::Backend>, ChildRegister) {
     let be = config.block_devs.get(backend_name).unwrap();
     let opts = block::BackendOpts {
         block_size: be.block_opts.block_size,
         read_only: be.block_opts.read_only,
         skip_flush: be.block_opts.skip_flush,
+        ..Default::default()
     };
 
     match &be.bdtype as &str {
         "file" => {
             let parsed: FileConfig = opt_deser(&be.options).unwrap();
+            let workers = NonZeroUsize::new(
+                parsed
+                    .workers
+                    .unwrap_or(DEFAULT_WORKER_COUNT)
+                    .clamp(1, block::MAX_FILE_WORKERS),
+            )
+            .unwrap();
+
+            if parsed.shared.unwrap_or(false) {
+                let image = block::SharedImage::open(&parsed.path).unwrap();
+                let be =
+                    block::SharedBackend::create(image, opts, workers).unwrap();
+
+                let creg = ChildRegister::new(&be, Some(parsed.path));
+                return (be, creg);
+            }
+
+            let be = block::FileBackend::create(&parsed.path, opts, workers)
+                .unwrap();
 
-            let be = block::FileBackend::create(
-                &parsed.path,
+            let creg = ChildRegister::new(&be, Some(parsed.path));
+            (be, creg)
+        }
+        "crucible" => create_crucible_backend(be, opts, log),
+        "mem-async" => {
+            let parsed: MemAsyncConfig = opt_deser(&be.options).unwrap();
+
+            let be = block::MemAsyncBackend::create(
+                parsed.size,
                 opts,
                 NonZeroUsize::new(
                     parsed.workers.unwrap_or(DEFAULT_WORKER_COUNT),
@@ -68,14 +181,13 @@ pub fn block_backend(
             )
             .unwrap();
 
-            let creg = ChildRegister::new(&be, Some(parsed.path));
+            let creg = ChildRegister::new(&be, None);
             (be, creg)
         }
-        "crucible" => create_crucible_backend(be, opts, log),
-        "mem-async" => {
-            let parsed: MemAsyncConfig = opt_deser(&be.options).unwrap();
+        "null" => {
+            let parsed: NullConfig = opt_deser(&be.options).unwrap();
 
-            let be = block::MemAsyncBackend::create(
+            let be = block::NullBackend::create(
                 parsed.size,
                 opts,
                 NonZeroUsize::new(
@@ -99,13 +211,123 @@ pub fn block_backend(
     }
 }
 
+/// Deadline for block requests issued by a device, if one is configured via
+/// its `request_timeout_ms` option.
+pub fn request_timeout(dev: &Device) -> Option<std::time::Duration> {
+    dev.options.get("request_timeout_ms").map(|v| {
+        std::time::Duration::from_millis(v.as_integer().unwrap() as u64)
+    })
+}
+
+/// Whether a storage device should be write-protected, via its
+/// `write_protect` option.
+pub fn write_protect(dev: &Device) -> bool {
+    dev.options.get("write_protect").and_then(|v| v.as_bool()).unwrap_or(false)
+}
+
+/// I/O priority class of a storage device, via its `priority` option.
+pub fn priority(dev: &Device) -> block::Priority {
+    match dev.options.get("priority").and_then(|v| v.as_str()) {
+        None | Some("normal") => block::Priority::Normal,
+        Some("high") => block::Priority::High,
+        Some("low") => block::Priority::Low,
+        Some(other) => panic!("unrecognized priority {other}"),
+    }
+}
+
+/// Options for a device provided by a PCI plug-in, flattened to strings for
+/// the plug-in to interpret as it sees fit.
+pub fn plugin_options(dev: &Device) -> BTreeMap<String, String> {
+    dev.options
+        .iter()
+        .map(|(k, v)| {
+            let v = match v.as_str() {
+                Some(s) => s.to_string(),
+                None => v.to_string(),
+            };
+            (k.clone(), v)
+        })
+        .collect()
+}
+
+/// The absolute form of `path`, which is taken relative to the working
+/// directory.  Paths opened once the process is hardened must be absolute.
+pub fn absolute_path(path: impl AsRef<std::path::Path>) -> std::path::PathBuf {
+    std::env::current_dir().unwrap_or_default().join(path)
+}
+
+/// Hardening plan for an instance built from `config`, keeping reachable the
+/// paths it opens once running: its file-backed block devices, the sockets of
+/// its TPM's swtpm process, and the file holding its NVRAM variable store.
+pub fn harden_plan(config: &Config, harden: &Harden) -> propolis::harden::Plan {
+    let mut required_paths: Vec<_> = config
+        .block_devs
+        .values()
+        .filter(|be| be.bdtype == "file")
+        .filter_map(|be| opt_deser::<FileConfig>(&be.options).ok())
+        .map(|parsed| absolute_path(parsed.path))
+        .collect();
+    for dev in config.devices.values().filter(|dev| dev.driver == "tpm-crb") {
+        required_paths.extend(
+            ["socket", "ctrl-socket"]
+                .into_iter()
+                .filter_map(|key| dev.options.get(key)?.as_str())
+                .map(absolute_path),
+        );
+    }
+    required_paths.extend(config.main.nvram.as_ref().map(absolute_path));
+    propolis::harden::Plan {
+        root: harden.root.as_ref().map(absolute_path),
+        required_paths,
+        ..Default::default()
+    }
+}
+
 pub fn parse(path: &str) -> anyhow::Result<Config> {
     let file_data =
         std::fs::read(path).context("Failed to read given config.toml")?;
-    Ok(toml::from_str::<Config>(
+    let config = toml::from_str::<Config>(
         std::str::from_utf8(&file_data)
             .context("config should be valid utf-8")?,
-    )?)
+    )?;
+    validate_devices(&config)?;
+    validate_block_devs(&config)?;
+    Ok(config)
+}
+
+/// Check for devices which may only be configured once, as they occupy fixed
+/// resources of the machine.
+fn validate_devices(config: &Config) -> anyhow::Result<()> {
+    // The ISA pvpanic device sits at a fixed I/O port
+    let pvpanic: Vec<&str> = config
+        .devices
+        .iter()
+        .filter(|(_, dev)| dev.driver == "qemu-pvpanic")
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if pvpanic.len() > 1 {
+        anyhow::bail!(
+            "only one qemu-pvpanic device may be configured, found: {}",
+            pvpanic.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Check the policies of crucible backends, so that a malformed option is
+/// reported as the config is parsed rather than when the backend is created.
+fn validate_block_devs(config: &Config) -> anyhow::Result<()> {
+    for (name, be) in config.block_devs.iter() {
+        if be.bdtype != "crucible" {
+            continue;
+        }
+        let policies: CruciblePolicyConfig = opt_deser(&be.options)
+            .with_context(|| format!("invalid options for block_dev {name}"))?;
+        policies
+            .retry_policy()
+            .with_context(|| format!("invalid retry for block_dev {name}"))?;
+    }
+    Ok(())
 }
 
 pub fn parse_bdf(v: &str) -> Option<Bdf> {
@@ -125,6 +347,21 @@ pub fn parse_bdf(v: &str) -> Option<Bdf> {
     }
 }
 
+pub fn parse_topology(config: &Config) -> anyhow::Result<Option<CpuTopology>> {
+    let Some(spec) = config.main.topology.as_ref() else {
+        return Ok(None);
+    };
+    let topo = CpuTopology::new(spec.sockets, spec.cores, spec.threads)?;
+    if topo.num_vcpus().get() != config.main.cpus {
+        anyhow::bail!(
+            "topology describes {} vCPUs, but cpus is {}",
+            topo.num_vcpus(),
+            config.main.cpus
+        );
+    }
+    Ok(Some(topo))
+}
+
 pub fn parse_cpuid(config: &Config) -> anyhow::Result<Option<cpuid::Set>> {
     if let Some(profile) = config.cpuid_profile() {
         let vendor = match profile.vendor {
@@ -156,7 +393,7 @@ pub fn parse_cpuid(config: &Config) -> anyhow::Result<Option<cpuid::Set>> {
 #[cfg(feature = "crucible")]
 fn create_crucible_backend(
     be: &propolis_standalone_config::BlockDevice,
-    opts: block::BackendOpts,
+    mut opts: block::BackendOpts,
     log: &slog::Logger,
 ) -> (Arc<dyn block::Backend>, ChildRegister) {
     use slog::info;
@@ -259,10 +496,28 @@ fn create_crucible_backend(
         },
         gen: generation,
     };
+    // The backend's policies were checked as the config was parsed.
+    let policies: CruciblePolicyConfig = opt_deser(&be.options).unwrap();
+    opts.retry_policy = policies.retry_policy().unwrap();
+    opts.prefetch = policies.prefetch_policy();
+    opts.journal = policies.journal_policy();
+
     info!(log, "Creating Crucible disk from request {:?}", req);
     // QUESTION: is producer_registry: None correct here?
     let be = block::CrucibleBackend::create(req, opts, None, None, log.clone())
         .unwrap();
+
+    if let Some(mut health) = be.health() {
+        let log = log.clone();
+        tokio::spawn(async move {
+            while health.changed().await.is_ok() {
+                let ev = *health.borrow();
+                info!(log, "Crucible backend health changed";
+                    "state" => ?ev.state,
+                    "consecutive_failures" => ev.consecutive_failures);
+            }
+        });
+    }
     let creg =
         ChildRegister::new(&be, Some(be.get_uuid().unwrap().to_string()));
     (be, creg)
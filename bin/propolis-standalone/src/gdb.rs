@@ -0,0 +1,861 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A stub speaking the GDB remote serial protocol, through which a debugger
+//! may halt the vCPUs, inspect and modify their registers and guest memory,
+//! set software breakpoints, and single-step.
+//!
+//! Each vCPU is presented as a thread.  Addresses given by the debugger are
+//! guest-linear, and are translated through the page tables of the selected
+//! vCPU.  Breakpoints are implemented by writing `int3` into guest memory, so
+//! they cannot be placed in ROM (such as the bootrom's earliest code), and
+//! while any are set, the guest's own breakpoint instructions also stop it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use bhyve_api::vm_reg_name::{self, *};
+use propolis::accessors::MemAccessor;
+use propolis::common::{GuestAddr, PAGE_OFFSET, PAGE_SIZE};
+use propolis::vcpu::Vcpu;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The `int3` instruction, written over the guest's code at breakpoints
+const INT3: u8 = 0xcc;
+
+/// Time allowed for the vCPUs to stop once they have been asked to
+const PARK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Interval at which waiting parties check for other conditions
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Registers in GDB's numbering for amd64, and their sizes in bytes.
+///
+/// The x87 and SSE state which follows them is not provided.
+const REGS: [(vm_reg_name, usize); 24] = [
+    (VM_REG_GUEST_RAX, 8),
+    (VM_REG_GUEST_RBX, 8),
+    (VM_REG_GUEST_RCX, 8),
+    (VM_REG_GUEST_RDX, 8),
+    (VM_REG_GUEST_RSI, 8),
+    (VM_REG_GUEST_RDI, 8),
+    (VM_REG_GUEST_RBP, 8),
+    (VM_REG_GUEST_RSP, 8),
+    (VM_REG_GUEST_R8, 8),
+    (VM_REG_GUEST_R9, 8),
+    (VM_REG_GUEST_R10, 8),
+    (VM_REG_GUEST_R11, 8),
+    (VM_REG_GUEST_R12, 8),
+    (VM_REG_GUEST_R13, 8),
+    (VM_REG_GUEST_R14, 8),
+    (VM_REG_GUEST_R15, 8),
+    (VM_REG_GUEST_RIP, 8),
+    (VM_REG_GUEST_RFLAGS, 4),
+    (VM_REG_GUEST_CS, 4),
+    (VM_REG_GUEST_SS, 4),
+    (VM_REG_GUEST_DS, 4),
+    (VM_REG_GUEST_ES, 4),
+    (VM_REG_GUEST_FS, 4),
+    (VM_REG_GUEST_GS, 4),
+];
+const REG_RIP: usize = 16;
+
+/// Largest memory read served at once, as fits in a packet of the size
+/// advertised to the debugger
+const MAX_READ: usize = 0x1000;
+
+#[derive(Default)]
+struct State {
+    /// The vCPUs (other than one being single-stepped) are to stop
+    halt: bool,
+    /// vCPUs which have stopped
+    parked: BTreeSet<i32>,
+    /// vCPU being single-stepped
+    step: Option<i32>,
+    /// vCPU and signal reported to the debugger for the current stop
+    stop: Option<(i32, u8)>,
+    /// Software breakpoints by address, with the physical address and
+    /// original contents of the byte replaced by `int3`
+    breakpoints: BTreeMap<u64, (u64, u8)>,
+}
+
+/// Debugging state of an instance, shared between its vCPU threads and the
+/// stub serving the debugger.
+pub struct Debugger {
+    state: Mutex<State>,
+    cv: Condvar,
+    vcpus: Vec<Arc<Vcpu>>,
+    acc_mem: MemAccessor,
+    log: slog::Logger,
+}
+impl Debugger {
+    pub fn new(
+        vcpus: Vec<Arc<Vcpu>>,
+        acc_mem: MemAccessor,
+        log: slog::Logger,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(State::default()),
+            cv: Condvar::new(),
+            vcpus,
+            acc_mem,
+            log,
+        })
+    }
+
+    /// Should the vCPU stop, rather than enter the guest?
+    pub fn should_park(&self, vcpu: i32) -> bool {
+        let state = self.state.lock().unwrap();
+        state.halt && state.step != Some(vcpu)
+    }
+
+    /// Stop the vCPU until the debugger resumes it (possibly to single-step
+    /// it), or until `interrupted` (which is polled) is true.
+    ///
+    /// The vCPU must be in a consistent state.
+    pub fn park(&self, vcpu: &Vcpu, interrupted: impl Fn() -> bool) {
+        let mut state = self.state.lock().unwrap();
+        state.parked.insert(vcpu.id);
+        self.cv.notify_all();
+        while state.halt && state.step != Some(vcpu.id) && !interrupted() {
+            state = self.cv.wait_timeout(state, POLL_INTERVAL).unwrap().0;
+        }
+        state.parked.remove(&vcpu.id);
+        let step = state.step == Some(vcpu.id);
+        drop(state);
+
+        // A step interrupted by the debugger may have left single-stepping
+        // enabled, so it is set either way.
+        if let Err(e) = vcpu.set_single_step(step) {
+            if step {
+                slog::error!(self.log, "cannot single-step vCPU {}", vcpu.id;
+                    "error" => %e);
+                self.trap(vcpu);
+            }
+        }
+    }
+
+    /// Report that the vCPU stopped at a breakpoint or after a single step,
+    /// and stop all of the others.
+    pub fn trap(&self, vcpu: &Vcpu) {
+        let _ = vcpu.set_single_step(false);
+        let mut state = self.state.lock().unwrap();
+        if state.step == Some(vcpu.id) {
+            state.step = None;
+        }
+        state.stop.get_or_insert((vcpu.id, SIGTRAP));
+        state.halt = true;
+        self.cv.notify_all();
+        drop(state);
+        self.kick_all();
+    }
+
+    fn kick_all(&self) {
+        for vcpu in self.vcpus.iter() {
+            let _ = vcpu.barrier();
+        }
+    }
+
+    /// Stop all of the vCPUs, as if `vcpu` took signal `sig`
+    fn halt(&self, vcpu: i32, sig: u8) {
+        self.stop_all(vcpu, sig);
+        self.wait_parked();
+    }
+
+    /// Stop all of the vCPUs on behalf of the guest's console, as if the
+    /// first took SIGINT.  They remain stopped until a debugger resumes them,
+    /// attaching to do so if one is not already.
+    pub fn interrupt(&self) {
+        let vcpu = self.vcpus.first().map(|v| v.id).unwrap_or(0);
+        self.stop_all(vcpu, SIGINT);
+    }
+
+    fn stop_all(&self, vcpu: i32, sig: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.halt = true;
+        state.step = None;
+        state.stop.get_or_insert((vcpu, sig));
+        // A debugger waiting on the guest learns of the stop
+        self.cv.notify_all();
+        drop(state);
+        self.kick_all();
+    }
+
+    /// Wait (for a bounded time) for all vCPUs to stop.  Those which have not
+    /// yet been started (or are held by the instance) do not stop, but will
+    /// not run either.
+    fn wait_parked(&self) {
+        let count = self.vcpus.len();
+        let state = self.state.lock().unwrap();
+        let _ = self
+            .cv
+            .wait_timeout_while(state, PARK_TIMEOUT, |s| s.parked.len() < count)
+            .unwrap();
+    }
+
+    /// Resume the vCPUs, or single-step vCPU `step` alone
+    fn resume(&self, step: Option<i32>) {
+        let mut state = self.state.lock().unwrap();
+        state.stop = None;
+        match step {
+            Some(id) => state.step = Some(id),
+            None => state.halt = false,
+        }
+        self.cv.notify_all();
+    }
+
+    /// Wait up to `timeout` for the vCPUs to stop, returning the reason
+    fn wait_stop(&self, timeout: Duration) -> Option<(i32, u8)> {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .cv
+            .wait_timeout_while(state, timeout, |s| s.stop.is_none())
+            .unwrap();
+        state.stop
+    }
+
+    /// Remove any breakpoints and let the guest run freely
+    fn detach(&self) {
+        let addrs: Vec<u64> =
+            self.state.lock().unwrap().breakpoints.keys().copied().collect();
+        for addr in addrs {
+            let _ = self.remove_breakpoint(addr);
+        }
+        self.resume(None);
+    }
+
+    fn vcpu(&self, id: i32) -> Option<&Arc<Vcpu>> {
+        self.vcpus.iter().find(|v| v.id == id)
+    }
+
+    fn read_mem(&self, vcpu: &Vcpu, addr: u64, len: usize) -> Option<Vec<u8>> {
+        let mem = self.acc_mem.access()?;
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let gla = addr.wrapping_add(out.len() as u64);
+            let chunk = usize::min(
+                len - out.len(),
+                PAGE_SIZE - (gla as usize & PAGE_OFFSET),
+            );
+            let mut buf = vec![0u8; chunk];
+            let read =
+                vcpu.translate_gla(gla, false).ok().flatten().and_then(|gpa| {
+                    mem.read_into(GuestAddr(gpa), &mut buf, chunk)
+                });
+            match read {
+                Some(n) if n == chunk => out.extend_from_slice(&buf),
+                // A partial read is reported as such
+                _ if !out.is_empty() => break,
+                _ => return None,
+            }
+        }
+        Some(out)
+    }
+
+    /// Write `data` to guest memory, regardless of the guest's protection of
+    /// the pages it covers
+    fn write_mem(&self, vcpu: &Vcpu, addr: u64, data: &[u8]) -> Option<()> {
+        let mem = self.acc_mem.access()?;
+        let mut done = 0;
+        while done < data.len() {
+            let gla = addr.wrapping_add(done as u64);
+            let chunk = usize::min(
+                data.len() - done,
+                PAGE_SIZE - (gla as usize & PAGE_OFFSET),
+            );
+            let gpa = vcpu.translate_gla(gla, false).ok()??;
+            let written =
+                mem.write_from(GuestAddr(gpa), &data[done..], chunk)?;
+            if written != chunk {
+                return None;
+            }
+            done += chunk;
+        }
+        Some(())
+    }
+
+    fn insert_breakpoint(&self, vcpu: &Vcpu, addr: u64) -> Option<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.breakpoints.contains_key(&addr) {
+            return Some(());
+        }
+        let gpa = vcpu.translate_gla(addr, false).ok()??;
+        let mem = self.acc_mem.access()?;
+        let orig: u8 = mem.read(GuestAddr(gpa))?;
+        if !mem.write(GuestAddr(gpa), &INT3) {
+            return None;
+        }
+        if state.breakpoints.is_empty() {
+            for vcpu in self.vcpus.iter() {
+                if let Err(e) = vcpu.set_breakpoint_exits(true) {
+                    slog::error!(self.log,
+                        "cannot enable breakpoint exits on vCPU {}", vcpu.id;
+                        "error" => %e);
+                    mem.write(GuestAddr(gpa), &orig);
+                    return None;
+                }
+            }
+        }
+        state.breakpoints.insert(addr, (gpa, orig));
+        Some(())
+    }
+
+    fn remove_breakpoint(&self, addr: u64) -> Option<()> {
+        let mut state = self.state.lock().unwrap();
+        let (gpa, orig) = state.breakpoints.remove(&addr)?;
+        if state.breakpoints.is_empty() {
+            for vcpu in self.vcpus.iter() {
+                let _ = vcpu.set_breakpoint_exits(false);
+            }
+        }
+        let mem = self.acc_mem.access()?;
+        mem.write(GuestAddr(gpa), &orig).then_some(())
+    }
+}
+
+/// Listen for debugger connections at `addr`, a TCP address or the path of a
+/// UNIX socket, serving one at a time.
+pub fn listen(dbg: Arc<Debugger>, addr: &str) -> io::Result<()> {
+    let listener = match addr.parse::<SocketAddr>() {
+        Ok(sa) => Listener::Tcp(TcpListener::bind(sa)?),
+        Err(_) => {
+            // Clear out any socket left behind by a previous instance
+            let _ = std::fs::remove_file(addr);
+            Listener::Unix(UnixListener::bind(addr)?)
+        }
+    };
+    slog::info!(dbg.log, "Listening for GDB connections at {}", addr);
+
+    let _ = std::thread::Builder::new().name("gdb stub".to_string()).spawn(
+        move || loop {
+            let conn = match listener.accept() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    slog::error!(dbg.log, "GDB accept failed"; "error" => %e);
+                    return;
+                }
+            };
+            slog::info!(dbg.log, "GDB connected");
+            let res = Session::new(&dbg, conn).run();
+            dbg.detach();
+            slog::info!(dbg.log, "GDB disconnected"; "result" => ?res);
+        },
+    )?;
+    Ok(())
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+impl Listener {
+    fn accept(&self) -> io::Result<Conn> {
+        match self {
+            Listener::Tcp(l) => {
+                let (conn, _) = l.accept()?;
+                // Packets are small and latency-sensitive
+                conn.set_nodelay(true)?;
+                Ok(Conn::Tcp(conn))
+            }
+            Listener::Unix(l) => Ok(Conn::Unix(l.accept()?.0)),
+        }
+    }
+}
+
+enum Conn {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+impl Conn {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match self {
+            Conn::Tcp(s) => s.set_read_timeout(dur),
+            Conn::Unix(s) => s.set_read_timeout(dur),
+        }
+    }
+}
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(s) => s.read(buf),
+            Conn::Unix(s) => s.read(buf),
+        }
+    }
+}
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(s) => s.write(buf),
+            Conn::Unix(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Conn::Tcp(s) => s.flush(),
+            Conn::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Input received from the debugger
+#[derive(Debug, PartialEq, Eq)]
+enum Input {
+    Packet(Vec<u8>),
+    /// A request to stop the guest (Ctrl-C)
+    Interrupt,
+    Closed,
+}
+
+fn read_byte(r: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match r.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Read the next packet (or interrupt) from the debugger, acknowledging it
+fn read_input(conn: &mut (impl Read + Write)) -> io::Result<Input> {
+    loop {
+        match read_byte(conn)? {
+            None => return Ok(Input::Closed),
+            Some(0x03) => return Ok(Input::Interrupt),
+            Some(b'$') => {}
+            // Acknowledgements and stray bytes are ignored
+            Some(_) => continue,
+        }
+        let mut data = Vec::new();
+        loop {
+            match read_byte(conn)? {
+                None => return Ok(Input::Closed),
+                Some(b'#') => break,
+                Some(b) => data.push(b),
+            }
+        }
+        let mut sum = [0u8; 2];
+        for b in sum.iter_mut() {
+            match read_byte(conn)? {
+                None => return Ok(Input::Closed),
+                Some(v) => *b = v,
+            }
+        }
+        let valid = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok())
+            .is_some_and(|sum| sum == checksum(&data));
+        if valid {
+            conn.write_all(b"+")?;
+            return Ok(Input::Packet(data));
+        }
+        // Ask for retransmission
+        conn.write_all(b"-")?;
+    }
+}
+
+/// Frame `data` as a packet
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 4);
+    out.push(b'$');
+    out.extend_from_slice(data);
+    out.extend_from_slice(format!("#{:02x}", checksum(data)).as_bytes());
+    out
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| {
+            let s = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(s, 16).ok()
+        })
+        .collect()
+}
+
+fn parse_num(field: &[u8]) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(field).ok()?, 16).ok()
+}
+
+/// Parse `<addr>,<len>` as found in memory and breakpoint packets
+fn parse_addr_len(args: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, |b| *b == b',');
+    let addr = parse_num(parts.next()?)?;
+    let len = parse_num(parts.next()?)?;
+    Some((addr, len))
+}
+
+/// Parse a thread ID, where `None` stands for any or all threads
+fn parse_thread(field: &[u8]) -> Option<Option<i32>> {
+    match field {
+        b"-1" | b"0" => Some(None),
+        _ => Some(Some(parse_num(field)? as i32 - 1)),
+    }
+}
+
+const ERR: &[u8] = b"E01";
+const OK: &[u8] = b"OK";
+
+/// A debugger's connection to the stub
+struct Session<'a> {
+    dbg: &'a Debugger,
+    conn: Conn,
+    /// vCPU targeted by register and memory operations
+    cur: i32,
+    /// vCPU to single-step, if one was selected
+    step_target: Option<i32>,
+}
+impl<'a> Session<'a> {
+    fn new(dbg: &'a Debugger, conn: Conn) -> Self {
+        let cur = dbg.vcpus.first().map(|v| v.id).unwrap_or(0);
+        Self { dbg, conn, cur, step_target: None }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        // The guest is stopped while the debugger attaches
+        self.dbg.halt(self.cur, SIGINT);
+
+        loop {
+            let pkt = match read_input(&mut self.conn)? {
+                Input::Packet(pkt) => pkt,
+                // The guest is already stopped
+                Input::Interrupt => continue,
+                Input::Closed => return Ok(()),
+            };
+            let (cmd, args) = match pkt.split_first() {
+                Some((cmd, args)) => (*cmd, args),
+                None => continue,
+            };
+            let resp = match cmd {
+                b'?' => self.stop_reply(),
+                b'g' => self.read_regs(),
+                b'G' => self.write_regs(args),
+                b'p' => self.read_reg(args),
+                b'P' => self.write_reg(args),
+                b'm' => self.read_mem(args),
+                b'M' => self.write_mem(args),
+                b'c' | b's' => match self.resume(cmd == b's', args)? {
+                    Some(resp) => resp,
+                    None => return Ok(()),
+                },
+                b'H' => self.set_thread(args),
+                b'T' => match parse_thread(args) {
+                    Some(Some(id)) if self.dbg.vcpu(id).is_some() => OK.into(),
+                    _ => ERR.into(),
+                },
+                b'Z' | b'z' => self.breakpoint(cmd == b'Z', args),
+                b'q' => self.query(args),
+                b'D' => {
+                    self.send(OK)?;
+                    return Ok(());
+                }
+                b'k' => return Ok(()),
+                // Unsupported packets get an empty response
+                _ => Vec::new(),
+            };
+            self.send(&resp)?;
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.conn.write_all(&frame(data))?;
+        self.conn.flush()
+    }
+
+    fn stop_reply(&mut self) -> Vec<u8> {
+        let (vcpu, sig) =
+            self.dbg.state.lock().unwrap().stop.unwrap_or((self.cur, SIGINT));
+        self.cur = vcpu;
+        format!("T{:02x}thread:{:x};", sig, vcpu + 1).into_bytes()
+    }
+
+    fn cur_vcpu(&self) -> Option<&Arc<Vcpu>> {
+        self.dbg.vcpu(self.cur)
+    }
+
+    fn read_regs(&self) -> Vec<u8> {
+        let Some(vcpu) = self.cur_vcpu() else {
+            return ERR.into();
+        };
+        let mut data = Vec::new();
+        for (reg, size) in REGS {
+            match vcpu.get_reg(reg) {
+                Ok(val) => data.extend_from_slice(&val.to_le_bytes()[..size]),
+                Err(_) => return ERR.into(),
+            }
+        }
+        to_hex(&data).into_bytes()
+    }
+
+    fn write_regs(&self, args: &[u8]) -> Vec<u8> {
+        let (Some(vcpu), Some(data)) = (self.cur_vcpu(), from_hex(args)) else {
+            return ERR.into();
+        };
+        let mut off = 0;
+        for (reg, size) in REGS {
+            // The debugger may provide fewer registers than it received
+            let Some(bytes) = data.get(off..off + size) else {
+                break;
+            };
+            let mut val = [0u8; 8];
+            val[..size].copy_from_slice(bytes);
+            if vcpu.set_reg(reg, u64::from_le_bytes(val)).is_err() {
+                return ERR.into();
+            }
+            off += size;
+        }
+        OK.into()
+    }
+
+    fn read_reg(&self, args: &[u8]) -> Vec<u8> {
+        let vcpu = self.cur_vcpu();
+        let reg = parse_num(args).and_then(|n| REGS.get(n as usize));
+        let (Some(vcpu), Some((reg, size))) = (vcpu, reg) else {
+            return ERR.into();
+        };
+        match vcpu.get_reg(*reg) {
+            Ok(val) => to_hex(&val.to_le_bytes()[..*size]).into_bytes(),
+            Err(_) => ERR.into(),
+        }
+    }
+
+    fn write_reg(&self, args: &[u8]) -> Vec<u8> {
+        let mut parts = args.splitn(2, |b| *b == b'=');
+        let reg =
+            parts.next().and_then(parse_num).and_then(|n| REGS.get(n as usize));
+        let data = parts.next().and_then(from_hex);
+        let (Some(vcpu), Some((reg, size)), Some(data)) =
+            (self.cur_vcpu(), reg, data)
+        else {
+            return ERR.into();
+        };
+        if data.len() != *size {
+            return ERR.into();
+        }
+        let mut val = [0u8; 8];
+        val[..*size].copy_from_slice(&data);
+        match vcpu.set_reg(*reg, u64::from_le_bytes(val)) {
+            Ok(()) => OK.into(),
+            Err(_) => ERR.into(),
+        }
+    }
+
+    fn read_mem(&self, args: &[u8]) -> Vec<u8> {
+        let (Some(vcpu), Some((addr, len))) =
+            (self.cur_vcpu(), parse_addr_len(args))
+        else {
+            return ERR.into();
+        };
+        let len = usize::min(len as usize, MAX_READ);
+        match self.dbg.read_mem(vcpu, addr, len) {
+            Some(data) => to_hex(&data).into_bytes(),
+            None => ERR.into(),
+        }
+    }
+
+    fn write_mem(&self, args: &[u8]) -> Vec<u8> {
+        let mut parts = args.splitn(2, |b| *b == b':');
+        let range = parts.next().and_then(parse_addr_len);
+        let data = parts.next().and_then(from_hex);
+        let (Some(vcpu), Some((addr, len)), Some(data)) =
+            (self.cur_vcpu(), range, data)
+        else {
+            return ERR.into();
+        };
+        if data.len() as u64 != len {
+            return ERR.into();
+        }
+        match self.dbg.write_mem(vcpu, addr, &data) {
+            Some(()) => OK.into(),
+            None => ERR.into(),
+        }
+    }
+
+    fn set_thread(&mut self, args: &[u8]) -> Vec<u8> {
+        let Some((op, thread)) = args.split_first() else {
+            return ERR.into();
+        };
+        let thread = match parse_thread(thread) {
+            Some(Some(id)) if self.dbg.vcpu(id).is_none() => return ERR.into(),
+            Some(thread) => thread,
+            None => return ERR.into(),
+        };
+        match op {
+            b'g' => {
+                if let Some(id) = thread {
+                    self.cur = id;
+                }
+            }
+            b'c' => self.step_target = thread,
+            _ => return ERR.into(),
+        }
+        OK.into()
+    }
+
+    fn breakpoint(&self, insert: bool, args: &[u8]) -> Vec<u8> {
+        // Only software breakpoints (type 0) are supported
+        let Some(args) = args.strip_prefix(b"0,") else {
+            return Vec::new();
+        };
+        let (Some(vcpu), Some((addr, _kind))) =
+            (self.cur_vcpu(), parse_addr_len(args))
+        else {
+            return ERR.into();
+        };
+        let res = match insert {
+            true => self.dbg.insert_breakpoint(vcpu, addr),
+            false => self.dbg.remove_breakpoint(addr),
+        };
+        match res {
+            Some(()) => OK.into(),
+            None => ERR.into(),
+        }
+    }
+
+    fn query(&self, args: &[u8]) -> Vec<u8> {
+        if args.starts_with(b"Supported") {
+            b"PacketSize=4000".to_vec()
+        } else if args == b"Attached" {
+            b"1".to_vec()
+        } else if args == b"C" {
+            format!("QC{:x}", self.cur + 1).into_bytes()
+        } else if args == b"fThreadInfo" {
+            let ids: Vec<String> = self
+                .dbg
+                .vcpus
+                .iter()
+                .map(|v| format!("{:x}", v.id + 1))
+                .collect();
+            format!("m{}", ids.join(",")).into_bytes()
+        } else if args == b"sThreadInfo" {
+            b"l".to_vec()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Continue or single-step the guest, optionally from a new address, and
+    /// wait for it to stop.  Returns `None` if the debugger disconnects while
+    /// the guest is running.
+    fn resume(
+        &mut self,
+        step: bool,
+        args: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let target = match step {
+            true => self.step_target.unwrap_or(self.cur),
+            false => self.cur,
+        };
+        if !args.is_empty() {
+            let (Some(vcpu), Some(addr)) =
+                (self.dbg.vcpu(target), parse_num(args))
+            else {
+                return Ok(Some(ERR.into()));
+            };
+            if vcpu.set_reg(REGS[REG_RIP].0, addr).is_err() {
+                return Ok(Some(ERR.into()));
+            }
+        }
+        self.dbg.resume(step.then_some(target));
+
+        // Wait for the guest to stop, or for the debugger to interrupt it
+        self.conn.set_read_timeout(Some(POLL_INTERVAL))?;
+        let res = loop {
+            if self.dbg.wait_stop(POLL_INTERVAL).is_some() {
+                self.dbg.wait_parked();
+                break Some(self.stop_reply());
+            }
+            match read_byte(&mut self.conn) {
+                Ok(None) => break None,
+                Ok(Some(0x03)) => {
+                    self.dbg.halt(target, SIGINT);
+                    break Some(self.stop_reply());
+                }
+                // Nothing else is expected while the guest runs
+                Ok(Some(_)) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        };
+        self.conn.set_read_timeout(None)?;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// In-memory connection recording what the stub writes
+    struct Pipe {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+    impl Pipe {
+        fn new(input: &[u8]) -> Self {
+            Self { input: io::Cursor::new(input.to_vec()), output: Vec::new() }
+        }
+    }
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packet_framing() {
+        assert_eq!(frame(b"OK"), b"$OK#9a");
+        assert_eq!(frame(b""), b"$#00");
+
+        let mut pipe = Pipe::new(b"+$qC#b4\x03");
+        assert_eq!(read_input(&mut pipe).unwrap(), Input::Packet(b"qC".into()));
+        assert_eq!(read_input(&mut pipe).unwrap(), Input::Interrupt);
+        assert_eq!(read_input(&mut pipe).unwrap(), Input::Closed);
+        assert_eq!(pipe.output, b"+");
+    }
+
+    #[test]
+    fn bad_checksum_retransmitted() {
+        let mut pipe = Pipe::new(b"$g#00$g#67");
+        assert_eq!(read_input(&mut pipe).unwrap(), Input::Packet(b"g".into()));
+        assert_eq!(pipe.output, b"-+");
+    }
+
+    #[test]
+    fn argument_parsing() {
+        assert_eq!(parse_addr_len(b"fff0,10"), Some((0xfff0, 0x10)));
+        assert_eq!(parse_addr_len(b"fff0"), None);
+        assert_eq!(from_hex(b"00ff10"), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(from_hex(b"0"), None);
+        assert_eq!(to_hex(&[0xde, 0xad]), "dead");
+        assert_eq!(parse_thread(b"-1"), Some(None));
+        assert_eq!(parse_thread(b"2"), Some(Some(1)));
+    }
+}
@@ -5,11 +5,11 @@
 use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroU32;
 use std::path::Path;
 use std::process::ExitCode;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use clap::Parser;
@@ -31,6 +31,7 @@ use propolis::*;
 
 mod cidata;
 mod config;
+mod gdb;
 mod snapshot;
 
 const PAGE_OFFSET: u64 = 0xfff;
@@ -203,6 +204,7 @@ struct InstInner {
     eq: Arc<EventQueue>,
     cv: Condvar,
     config: config::Config,
+    debugger: Option<Arc<gdb::Debugger>>,
 }
 
 struct Instance(Arc<InstInner>);
@@ -213,6 +215,22 @@ impl Instance {
         from_restore: bool,
         log: slog::Logger,
     ) -> Self {
+        let debugger = config.main.gdb.as_ref().and_then(|addr| {
+            let guard = pinst.lock();
+            let machine = guard.machine();
+            let dbg = gdb::Debugger::new(
+                machine.vcpus.clone(),
+                machine.acc_mem.child(Some("gdb".to_string())),
+                log.new(slog::o!("component" => "gdb")),
+            );
+            match gdb::listen(dbg.clone(), addr) {
+                Ok(()) => Some(dbg),
+                Err(e) => {
+                    slog::error!(log, "GDB stub unavailable"; "error" => %e);
+                    None
+                }
+            }
+        });
         let this = Self(Arc::new(InstInner {
             state: Mutex::new(InstState {
                 instance: Some(pinst),
@@ -224,6 +242,7 @@ impl Instance {
             eq: EventQueue::new(),
             cv: Condvar::new(),
             config,
+            debugger,
         }));
 
         // Some gymnastics required for the split borrow through the MutexGuard
@@ -237,10 +256,19 @@ impl Instance {
 
             let inner = this.0.clone();
             let task_log = log.new(slog::o!("vcpu" => vcpu.id));
+            let steal_mem = this.0.config.main.steal_time.then(|| {
+                guard.machine().acc_mem.child(Some(format!("vcpu-{}", vcpu.id)))
+            });
             let _ = std::thread::Builder::new()
                 .name(format!("vcpu-{}", vcpu.id))
                 .spawn(move || {
-                    Instance::vcpu_loop(inner, vcpu.as_ref(), &task, task_log)
+                    Instance::vcpu_loop(
+                        inner,
+                        vcpu.as_ref(),
+                        &task,
+                        steal_mem,
+                        task_log,
+                    )
                 })
                 .unwrap();
             state.vcpu_tasks.push(ctrl);
@@ -270,7 +298,13 @@ impl Instance {
     ) {
         let inv_guard = inst_guard.inventory().lock();
 
-        for (_eid, record) in inv_guard.iter(propolis::inventory::Order::Pre) {
+        // Entities are set running after those they depend upon, and are
+        // quiesced before them.
+        let order = match state {
+            State::Run => propolis::inventory::Order::Startup,
+            _ => propolis::inventory::Order::Teardown,
+        };
+        for (_eid, record) in inv_guard.iter(order) {
             let ent = record.entity();
             match state {
                 State::Run => {
@@ -463,11 +497,19 @@ impl Instance {
         inner: Arc<InstInner>,
         vcpu: &Vcpu,
         task: &propolis::tasks::TaskHdl,
+        steal_mem: Option<propolis::accessors::MemAccessor>,
         log: slog::Logger,
     ) {
         use propolis::exits::{SuspendDetail, VmExitKind};
         use propolis::tasks::Event;
 
+        let mut steal = steal_mem.and_then(|acc_mem| {
+            propolis::steal::StealTime::new(acc_mem)
+                .map_err(|e| {
+                    slog::warn!(&log, "steal time unavailable: {:?}", e);
+                })
+                .ok()
+        });
         let mut entry = VmEntry::Run;
         let mut exit = VmExit::default();
         let mut local_gen = 0;
@@ -489,6 +531,9 @@ impl Instance {
                             // Reset occurred, discard any existing entry details.
                             entry = VmEntry::Run;
                             local_gen = cur_gen;
+                            if let Some(steal) = steal.as_mut() {
+                                steal.reset();
+                            }
                         }
                         continue;
                     }
@@ -499,6 +544,20 @@ impl Instance {
                 None => {}
             }
 
+            if let Some(dbg) = inner.debugger.as_ref() {
+                if !exit_when_consistent && dbg.should_park(vcpu.id) {
+                    if exit.kind.is_consistent() {
+                        dbg.park(vcpu, || task.pending_event().is_some());
+                        continue;
+                    }
+                    // The vCPU can only be inspected once consistent
+                    exit_when_consistent = true;
+                }
+            }
+
+            if let Some(steal) = steal.as_mut() {
+                steal.update();
+            }
             exit = match vcpu.run(&entry, exit_when_consistent) {
                 Err(e) => {
                     slog::error!(&log, "VM entry error {:?}", e);
@@ -540,20 +599,32 @@ impl Instance {
                         ))
                     }
                     VmExitKind::Rdmsr(msr) => {
-                        slog::error!(
-                            &log,
-                            "Unhandled rdmsr {:#08x}", msr; "rip" => exit.rip
-                        );
+                        let val = steal.as_ref().and_then(|s| s.rdmsr(msr));
+                        if val.is_none() {
+                            slog::error!(
+                                &log,
+                                "Unhandled rdmsr {:#08x}", msr;
+                                "rip" => exit.rip
+                            );
+                        }
+                        let val = val.unwrap_or(0);
                         let _ = vcpu.set_reg(
                             bhyve_api::vm_reg_name::VM_REG_GUEST_RAX,
-                            0,
+                            val & 0xffff_ffff,
                         );
                         let _ = vcpu.set_reg(
                             bhyve_api::vm_reg_name::VM_REG_GUEST_RDX,
-                            0,
+                            val >> 32,
                         );
                         VmEntry::Run
                     }
+                    VmExitKind::Wrmsr(msr, val)
+                        if steal
+                            .as_mut()
+                            .is_some_and(|s| s.wrmsr(msr, val)) =>
+                    {
+                        VmEntry::Run
+                    }
                     VmExitKind::Wrmsr(msr, val) => {
                         slog::error!(
                             &log,
@@ -562,6 +633,17 @@ impl Instance {
                         );
                         VmEntry::Run
                     }
+                    VmExitKind::Breakpoint | VmExitKind::Mtrap => {
+                        match inner.debugger.as_ref() {
+                            Some(dbg) => dbg.trap(vcpu),
+                            None => slog::error!(
+                                &log,
+                                "Unexpected debug exit {:?}", exit.kind;
+                                "rip" => exit.rip
+                            ),
+                        }
+                        VmEntry::Run
+                    }
                     VmExitKind::Suspended(SuspendDetail {
                         kind,
                         when: _when,
@@ -651,13 +733,30 @@ impl std::ops::DerefMut for InnerGuard<'_> {
     }
 }
 
+/// Start of the region of hot-pluggable memory, which follows the memory above
+/// 4GiB (aligned as virtio-mem requires), ahead of the space for 64-bit MMIO.
+fn hotmem_region_start(highmem: usize) -> usize {
+    (0x1_0000_0000 + highmem)
+        .next_multiple_of(hw::virtio::mem::MEM_REGION_ALIGN)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn build_instance(
     name: &str,
     max_cpu: u8,
     lowmem: usize,
     highmem: usize,
+    hotmem: usize,
+    rom_len: usize,
+    nvram_len: usize,
     use_reservoir: bool,
 ) -> Result<propolis::Instance> {
+    // A variable store lies directly below the bootrom's code, as in a
+    // combined flash image.  Without one, the ROM spans the whole window.
+    let (rom_start, rom_len) = match nvram_len {
+        0 => (0x1_0000_0000 - MAX_ROM_SIZE, MAX_ROM_SIZE),
+        _ => (0x1_0000_0000 - rom_len, rom_len),
+    };
     let mut builder = Builder::new(
         name,
         propolis::vmm::CreateOpts {
@@ -668,16 +767,35 @@ fn build_instance(
     )?
     .max_cpus(max_cpu)?
     .add_mem_region(0, lowmem, "lowmem")?
-    .add_rom_region(0x1_0000_0000 - MAX_ROM_SIZE, MAX_ROM_SIZE, "bootrom")?
+    .add_rom_region(rom_start, rom_len, "bootrom")?
     .add_mmio_region(0xc000_0000, 0x2000_0000, "dev32")?
-    .add_mmio_region(0xe000_0000, 0x1000_0000, "pcicfg")?;
+    .add_mmio_region(
+        i440fx::ADDR_PCIE_ECAM_REGION,
+        i440fx::LEN_PCIE_ECAM_REGION,
+        "pcicfg",
+    )?;
+    if nvram_len > 0 {
+        // The store is emulated as flash, rather than mapped as memory
+        builder = builder.add_mmio_region(
+            rom_start - nvram_len,
+            nvram_len,
+            "nvram",
+        )?;
+    }
 
     let highmem_start = 0x1_0000_0000;
     if highmem > 0 {
         builder = builder.add_mem_region(highmem_start, highmem, "highmem")?;
     }
 
-    let dev64_start = highmem_start + highmem;
+    let mut dev64_start = highmem_start + highmem;
+    if hotmem > 0 {
+        let hotmem_start = hotmem_region_start(highmem);
+        builder =
+            builder.add_hotplug_mem_region(hotmem_start, hotmem, "hotmem")?;
+        dev64_start = hotmem_start + hotmem;
+    }
+
     builder = builder.add_mmio_region(
         dev64_start,
         vmm::MAX_PHYSMEM - dev64_start,
@@ -767,12 +885,23 @@ fn setup_instance(
 ) -> anyhow::Result<(Instance, Arc<UDSock>)> {
     let vm_name = &config.main.name;
     let cpus = config.main.cpus;
+    let topology = config::parse_topology(&config)?;
 
     const GB: usize = 1024 * 1024 * 1024;
     const MB: usize = 1024 * 1024;
     let memsize: usize = config.main.memory * MB;
     let lowmem = memsize.min(3 * GB);
     let highmem = memsize.saturating_sub(3 * GB);
+    // Memory which may be hot-plugged through a virtio-mem device
+    let hotmem: usize = config
+        .devices
+        .values()
+        .filter(|dev| dev.driver == "pci-virtio-mem")
+        .map(|dev| {
+            let region_mb = dev.options.get("region-mb").unwrap();
+            region_mb.as_integer().unwrap() as usize * MB
+        })
+        .sum();
 
     let use_reservoir = config.main.use_reservoir.unwrap_or(false);
     if use_reservoir {
@@ -792,15 +921,46 @@ fn setup_instance(
         }
     }
 
+    let (romfp, rom_len) =
+        open_bootrom(&config.main.bootrom).context("Cannot open bootrom")?;
+    // The bootrom's variable store is loaded from its file, to which it is
+    // written back as the instance resets or halts.
+    let nvram = match config.main.nvram.as_ref() {
+        Some(path) => Some(
+            std::fs::read(path)
+                .with_context(|| format!("Cannot read NVRAM {path}"))?,
+        ),
+        None => None,
+    };
+    let nvram_len = nvram.as_ref().map_or(0, Vec::len);
+    if nvram_len % hw::nvram::BLOCK_SIZE != 0 {
+        anyhow::bail!(
+            "NVRAM length {nvram_len:#x} not aligned to {:#x}",
+            hw::nvram::BLOCK_SIZE
+        );
+    }
+    if nvram_len > 0 && rom_len + nvram_len > MAX_ROM_SIZE {
+        anyhow::bail!(
+            "bootrom and NVRAM together exceed maximum of {MAX_ROM_SIZE:#x}"
+        );
+    }
+
     slog::info!(log, "Creating VM with {} vCPUs, {} lowmem, {} highmem",
         cpus, lowmem, highmem;);
-    let pinst = build_instance(vm_name, cpus, lowmem, highmem, use_reservoir)
-        .context("Failed to create VM Instance")?;
+    let pinst = build_instance(
+        vm_name,
+        cpus,
+        lowmem,
+        highmem,
+        hotmem,
+        rom_len,
+        nvram_len,
+        use_reservoir,
+    )
+    .context("Failed to create VM Instance")?;
     let inst = Instance::new(pinst, config.clone(), from_restore, log.clone());
     slog::info!(log, "VM created"; "name" => vm_name);
 
-    let (romfp, rom_len) =
-        open_bootrom(&config.main.bootrom).context("Cannot open bootrom")?;
     let com1_sock =
         UDSock::bind(Path::new("./ttya")).context("Cannot open UD socket")?;
 
@@ -814,14 +974,27 @@ fn setup_instance(
     populate_rom(machine, "bootrom", &romfp, rom_len)?;
     drop(romfp);
 
+    if let (Some(path), Some(data)) = (config.main.nvram.as_ref(), nvram) {
+        let nvram = hw::nvram::Nvram::create(
+            0x1_0000_0000 - rom_len - nvram_len,
+            data,
+            Some(&config::absolute_path(path)),
+            log.new(slog::o!("dev" => "nvram")),
+        )?;
+        nvram.attach(&machine.bus_mmio);
+        inv.register(&nvram)?;
+    }
+
     let rtc = &machine.kernel_devs.rtc;
     rtc.memsize_to_nvram(lowmem as u32, highmem as u64)?;
-    rtc.set_time(
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("system time precedes UNIX epoch"),
-    )?;
+    rtc.set_time(machine.clock.unix_time())?;
 
+    if config.main.pcie && !config.main.acpi_tables {
+        slog::warn!(
+            log,
+            "pcie without acpi_tables relies on the bootrom to describe ECAM"
+        );
+    }
     let (power_pin, reset_pin) = inst.generate_pins();
     let pci_topo =
         propolis::hw::pci::topology::Builder::new().finish(inv, machine)?;
@@ -831,10 +1004,11 @@ fn setup_instance(
         i440fx::Opts {
             power_pin: Some(power_pin),
             reset_pin: Some(reset_pin),
+            enable_pcie: config.main.pcie,
             ..Default::default()
         },
         log.new(slog::o!("dev" => "chipset")),
-    );
+    )?;
     inv.register(&chipset)?;
 
     // UARTs
@@ -854,6 +1028,40 @@ fn setup_instance(
     com3.set_autodiscard(true);
     com4.set_autodiscard(true);
 
+    for com in [&com1, &com2, &com3, &com4] {
+        com.set_paced(config.main.serial_pacing);
+    }
+
+    if let Some(action) = config.main.console_break {
+        let break_log = log.new(slog::o!("component" => "console"));
+        let handler: chardev::BreakHandler = match action {
+            config::ConsoleBreak::Guest => {
+                let com1 = Arc::clone(&com1);
+                Box::new(move || com1.receive_break())
+            }
+            config::ConsoleBreak::Nmi => {
+                let vcpu = Arc::clone(&machine.vcpus[0]);
+                Box::new(move || {
+                    slog::info!(break_log, "Injecting NMI on console BREAK");
+                    if let Err(e) = vcpu.inject_nmi() {
+                        slog::error!(break_log, "Could not inject NMI";
+                            "error" => %e);
+                    }
+                })
+            }
+            config::ConsoleBreak::Gdb => {
+                let Some(dbg) = inst.0.debugger.clone() else {
+                    anyhow::bail!("console_break = \"gdb\" requires `gdb`");
+                };
+                Box::new(move || {
+                    slog::info!(break_log, "Stopping guest on console BREAK");
+                    dbg.interrupt();
+                })
+            }
+        };
+        com1_sock.set_break_handler(Some(handler));
+    }
+
     let pio = &machine.bus_pio;
     LpcUart::attach(&com1, pio, ibmpc::PORT_COM1);
     LpcUart::attach(&com2, pio, ibmpc::PORT_COM2);
@@ -871,10 +1079,16 @@ fn setup_instance(
 
     let debug_file = std::fs::File::create("debug.out")?;
     let debug_out = chardev::BlockingFileOutput::new(debug_file);
-    let debug_device = hw::qemu::debug::QemuDebugPort::create(pio);
+    let debug_device = hw::qemu::debug::QemuDebugPort::create(
+        pio,
+        hw::qemu::debug::QEMU_DEBUG_IOPORT,
+    )?;
     debug_out.attach(Arc::clone(&debug_device) as Arc<dyn BlockingSource>);
     inv.register(&debug_device)?;
 
+    let mut tpm_crb = false;
+    let mut pvpanic_port = None;
+    let plugins = hw::pci::plugin::registry();
     for (name, dev) in config.devices.iter() {
         let driver = &dev.driver as &str;
         let bdf = if driver.starts_with("pci-") {
@@ -890,13 +1104,50 @@ fn setup_instance(
                 let bdf = bdf.unwrap();
 
                 let vioblk = hw::virtio::PciVirtioBlock::new(0x100);
+                // Report the same serial as the disk would through NVMe
+                vioblk.set_serial(&block::disk_serial(
+                    dev.options.get("block_dev").unwrap().as_str().unwrap(),
+                ));
+                vioblk.set_request_timeout(config::request_timeout(dev));
+                vioblk.set_write_protect(config::write_protect(dev));
+                block::Device::attachment(vioblk.as_ref())
+                    .set_priority(config::priority(dev));
                 let id = inv.register_instance(&vioblk, bdf.to_string())?;
-                let _be_id = inv.register_child(creg, id)?;
+                let be_id = inv.register_child(creg, id)?;
+                inv.add_dependency(id, be_id)?;
 
                 block::attach(backend, vioblk.clone());
 
                 chipset.pci_attach(bdf, vioblk);
             }
+            "pci-virtio-scsi" => {
+                let luns = dev.options.get("luns").unwrap().as_array().unwrap();
+                let bdf = bdf.unwrap();
+                let max_luns = hw::virtio::scsi::MAX_LUNS as usize;
+                if luns.is_empty() || luns.len() > max_luns {
+                    anyhow::bail!(
+                        "{name}: luns must name between 1 and {max_luns} disks"
+                    );
+                }
+
+                let vioscsi =
+                    hw::virtio::PciVirtioScsi::new(0x100, luns.len() as u16);
+                let id = inv.register_instance(&vioscsi, bdf.to_string())?;
+                for (lun, name) in vioscsi.luns().iter().zip(luns.iter()) {
+                    let name = name.as_str().unwrap();
+                    let (backend, creg) =
+                        config::named_block_backend(&config, name, log);
+                    lun.set_serial(&block::disk_serial(name));
+                    lun.set_request_timeout(config::request_timeout(dev));
+                    lun.set_write_protect(config::write_protect(dev));
+                    let be_id = inv.register_child(creg, id)?;
+                    inv.add_dependency(id, be_id)?;
+
+                    block::attach(backend, lun.clone());
+                }
+
+                chipset.pci_attach(bdf, vioscsi);
+            }
             "pci-virtio-viona" => {
                 let vnic_name =
                     dev.options.get("vnic").unwrap().as_str().unwrap();
@@ -907,27 +1158,191 @@ fn setup_instance(
                 inv.register_instance(&viona, bdf.to_string())?;
                 chipset.pci_attach(bdf, viona);
             }
+            "pci-virtio-null-net" => {
+                let bdf = bdf.unwrap();
+
+                // A locally administered address, unique to the slot
+                let mac_addr = [
+                    0x02,
+                    0x08,
+                    0x20,
+                    bdf.bus.get(),
+                    bdf.location.dev.get(),
+                    bdf.location.func.get(),
+                ];
+                let nic = hw::virtio::PciVirtioNullNet::new(0x100, mac_addr);
+                inv.register_instance(&nic, bdf.to_string())?;
+                chipset.pci_attach(bdf, nic);
+            }
+            "pci-virtio-rtc" => {
+                let bdf = bdf.unwrap();
+
+                let rtc =
+                    hw::virtio::PciVirtioRtc::new(0x10, machine.clock.clone());
+                inv.register_instance(&rtc, bdf.to_string())?;
+                chipset.pci_attach(bdf, rtc);
+            }
+            "pci-virtio-rng" => {
+                let rate = dev
+                    .options
+                    .get("bytes-per-sec")
+                    .map(|v| v.as_integer().unwrap() as u32)
+                    .unwrap_or(hw::virtio::rng::DEFAULT_BYTES_PER_SEC);
+                let bdf = bdf.unwrap();
+
+                let rng = hw::virtio::PciVirtioRng::new(
+                    0x10,
+                    NonZeroU32::new(rate).expect("bytes-per-sec is non-zero"),
+                )?;
+                inv.register_instance(&rng, bdf.to_string())?;
+                chipset.pci_attach(bdf, rng);
+            }
+            "pci-virtio-mem" => {
+                // With no API through which to change it, the amount of
+                // memory requested of the guest is fixed at startup.
+                let requested_mb = dev
+                    .options
+                    .get("requested-mb")
+                    .map(|v| v.as_integer().unwrap() as usize)
+                    .unwrap_or(0);
+                let bdf = bdf.unwrap();
+
+                let region = machine
+                    .map_physmem
+                    .hotplug_region("hotmem")
+                    .context("virtio-mem requires a hotmem region")?;
+                let mem = hw::virtio::PciVirtioMem::new(0x100, region);
+                mem.set_requested_size(requested_mb * MB);
+                inv.register_instance(&mem, bdf.to_string())?;
+                chipset.pci_attach(bdf, mem);
+            }
+            "pci-ivshmem" => {
+                let path = Path::new(
+                    dev.options.get("path").unwrap().as_str().unwrap(),
+                );
+                let size =
+                    dev.options.get("size").unwrap().as_integer().unwrap();
+                let bdf = bdf.unwrap();
+
+                // The file is opened relative to the directory containing it.
+                let Some(name) = path.file_name().and_then(|n| n.to_str())
+                else {
+                    anyhow::bail!("invalid ivshmem path {}", path.display());
+                };
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                let shm = hw::qemu::ivshmem::PciIvShmem::create(
+                    dir,
+                    name,
+                    size as u64,
+                )?;
+                inv.register_instance(&shm, bdf.to_string())?;
+                chipset.pci_attach(bdf, shm);
+            }
             "pci-nvme" => {
                 let (backend, creg) = config::block_backend(&config, dev, log);
                 let bdf = bdf.unwrap();
 
-                let dev_serial = dev
-                    .options
-                    .get("block_dev")
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_string();
+                let dev_serial = block::disk_serial(
+                    dev.options.get("block_dev").unwrap().as_str().unwrap(),
+                );
                 let log = log.new(slog::o!("dev" => format!("nvme-{}", name)));
                 let nvme = hw::nvme::PciNvme::create(dev_serial, log);
+                nvme.set_request_timeout(config::request_timeout(dev));
+                nvme.set_write_protect(config::write_protect(dev));
+                block::Device::attachment(nvme.as_ref())
+                    .set_priority(config::priority(dev));
 
                 let id = inv.register_instance(&nvme, bdf.to_string())?;
-                let _be_id = inv.register_child(creg, id)?;
+                let be_id = inv.register_child(creg, id)?;
+                inv.add_dependency(id, be_id)?;
 
                 block::attach(backend, nvme.clone());
 
                 chipset.pci_attach(bdf, nvme);
             }
+            "pci-ahci" => {
+                let (backend, creg) = config::block_backend(&config, dev, log);
+                let bdf = bdf.unwrap();
+
+                let log = log.new(slog::o!("dev" => format!("ahci-{}", name)));
+                let ahci = hw::ahci::PciAhci::create(log);
+                // Report the same serial as the disk would through NVMe
+                ahci.set_serial(&block::disk_serial(
+                    dev.options.get("block_dev").unwrap().as_str().unwrap(),
+                ));
+                ahci.set_request_timeout(config::request_timeout(dev));
+                ahci.set_write_protect(config::write_protect(dev));
+                block::Device::attachment(ahci.as_ref())
+                    .set_priority(config::priority(dev));
+
+                let id = inv.register_instance(&ahci, bdf.to_string())?;
+                let be_id = inv.register_child(creg, id)?;
+                inv.add_dependency(id, be_id)?;
+
+                block::attach(backend, ahci.clone());
+
+                chipset.pci_attach(bdf, ahci);
+            }
+            "tpm-crb" => {
+                let opt_path = |key: &str| {
+                    dev.options.get(key).map(|v| v.as_str().unwrap())
+                };
+                let socket = opt_path("socket").unwrap();
+                let ctrl = opt_path("ctrl-socket");
+                let state = opt_path("state").unwrap();
+                slog::info!(log, "TPM state kept by swtpm at {}", state);
+
+                // swtpm is reached after the process may have been hardened,
+                // when only absolute paths can be resolved.
+                let backend = hw::tpm::Swtpm::new(
+                    &config::absolute_path(socket),
+                    ctrl.map(config::absolute_path).as_deref(),
+                );
+                let tpm = hw::tpm::TpmCrb::create(
+                    Arc::new(backend),
+                    log.new(slog::o!("dev" => "tpm-crb")),
+                )?;
+                tpm.attach(&machine.bus_mmio);
+                inv.register(&tpm)?;
+                tpm_crb = true;
+            }
+            "qemu-pvpanic" => {
+                let port = hw::qemu::pvpanic::PVPANIC_IOPORT;
+                let pvpanic = hw::qemu::pvpanic::QemuPvpanic::create(
+                    pio,
+                    port,
+                    log.new(slog::o!("dev" => "qemu-pvpanic")),
+                )?;
+                inv.register(&pvpanic)?;
+                pvpanic_port = Some(port);
+            }
+            "pci-qemu-pvpanic" => {
+                let bdf = bdf.unwrap();
+                let pvpanic = hw::qemu::pvpanic::PciQemuPvpanic::create(
+                    log.new(slog::o!("dev" => "pci-qemu-pvpanic")),
+                );
+                inv.register_instance(&pvpanic, bdf.to_string())?;
+                chipset.pci_attach(bdf, pvpanic);
+            }
+            _ if bdf.is_some() && plugins.get(driver).is_some() => {
+                let host = hw::pci::plugin::PluginHost {
+                    machine,
+                    inv,
+                    chipset: chipset.as_ref(),
+                    log,
+                };
+                let options = config::plugin_options(dev);
+                host.add_device(
+                    &plugins,
+                    driver,
+                    name,
+                    bdf.unwrap(),
+                    &options,
+                )?;
+            }
             _ => {
                 slog::error!(log, "unrecognized driver"; "name" => name);
                 return Err(Error::new(
@@ -946,6 +1361,48 @@ fn setup_instance(
             hw::qemu::fwcfg::FixedItem::new_u32(cpus as u32),
         )
         .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    fwcfg.offload(
+        propolis::offload::Offload::new(
+            std::num::NonZeroUsize::new(2).unwrap(),
+        )?,
+        propolis::offload::Budget::default(),
+    );
+
+    if config.main.acpi_tables {
+        let dev64_start = match hotmem {
+            0 => 0x1_0000_0000 + highmem,
+            _ => hotmem_region_start(highmem) + hotmem,
+        } as u64;
+        // The vCPUs are described as running at the guest TSC frequency
+        let tsc_freq = vmm::time::export_time_data(&hdl)?.guest_freq;
+        // The GPE0 block is described alongside the PM registers, though no
+        // device of this machine yet signals events through it.
+        let gpe = hw::acpi::Gpe0::create(chipset.sci_pin());
+        gpe.attach(pio);
+        inv.register(&gpe)?;
+        let acpi_cfg = propolis::firmware::acpi::Config {
+            topology: topology.unwrap_or_else(|| {
+                topology::CpuTopology::new(cpus, 1, 1)
+                    .expect("single-core sockets are valid")
+            }),
+            cpu_freq_mhz: (tsc_freq / 1_000_000) as u32,
+            pm_base: chipset.pm_base(),
+            gpe0_port: Some(hw::acpi::gpe::PORT_GPE0),
+            pci_hotplug_slots: None,
+            cpu_hotplug: false,
+            maintenance: false,
+            pci_intx_routes: chipset.pci_intx_routes(),
+            pcie_ecam: chipset.pcie_ecam_region(),
+            pci_window_32: 0xc000_0000..0xe000_0000,
+            pci_window_64: Some(dev64_start..vmm::MAX_PHYSMEM as u64),
+            tpm_crb,
+            pvpanic_port,
+            hpet_block_id: Some(machine.kernel_devs.hpet.capabilities()?),
+        };
+        propolis::firmware::acpi::build(&acpi_cfg)
+            .attach(&mut fwcfg)
+            .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    }
 
     let ramfb =
         hw::qemu::ramfb::RamFb::create(log.new(slog::o!("dev" => "ramfb")));
@@ -958,17 +1415,41 @@ fn setup_instance(
     inv.register(&ramfb)?;
 
     let cpuid_profile = config::parse_cpuid(&config)?;
+    if config.main.steal_time && cpuid_profile.is_none() {
+        slog::warn!(
+            log,
+            "steal_time requires a cpuid_profile, and will not be advertised"
+        );
+    }
+    if topology.is_some() && cpuid_profile.is_none() {
+        slog::warn!(
+            log,
+            "topology requires a cpuid_profile, and will not be reported \
+            through cpuid"
+        );
+    }
 
     for vcpu in machine.vcpus.iter() {
         let vcpu_profile = if let Some(profile) = cpuid_profile.as_ref() {
-            propolis::cpuid::Specializer::new()
-                .with_vcpu_count(
-                    std::num::NonZeroU8::new(config.main.cpus).unwrap(),
-                    true,
-                )
-                .with_vcpuid(vcpu.id)
-                .with_cache_topo()
-                .clear_cpu_topo(cpuid::TopoKind::iter())
+            let specializer = match topology {
+                Some(topo) => propolis::cpuid::Specializer::new()
+                    .with_topology(topo)
+                    .with_cpu_topo(cpuid::TopoKind::iter()),
+                None => propolis::cpuid::Specializer::new()
+                    .with_vcpu_count(
+                        std::num::NonZeroU8::new(config.main.cpus).unwrap(),
+                        true,
+                    )
+                    .clear_cpu_topo(cpuid::TopoKind::iter()),
+            };
+            let specializer =
+                specializer.with_vcpuid(vcpu.id).with_cache_topo();
+            let specializer = if config.main.steal_time {
+                specializer.with_steal_time()
+            } else {
+                specializer
+            };
+            specializer
                 .execute(profile.clone())
                 .context("failed to specialize cpuid profile")?
         } else {
@@ -978,6 +1459,9 @@ fn setup_instance(
         };
         vcpu.set_cpuid(vcpu_profile)?;
         vcpu.set_default_capabs()?;
+        if config.main.pause_exits {
+            vcpu.set_pause_exits(true)?;
+        }
     }
     drop(guard);
     drop(inst_inner);
@@ -1033,6 +1517,11 @@ struct Args {
 fn main() -> anyhow::Result<ExitCode> {
     let Args { target, snapshot, restore } = Args::parse();
 
+    // Descriptors inherited from our parent must be recorded before anything
+    // else opens descriptors of its own.
+    propolis::harden::capture_inherited()
+        .context("Failed to record inherited descriptors")?;
+
     // Ensure proper setup of USDT probes
     register_probes().context("Failed to setup USDT probes")?;
 
@@ -1054,7 +1543,18 @@ fn main() -> anyhow::Result<ExitCode> {
         (inst, com1_sock)
     } else {
         let config = config::parse(&target)?;
+        let harden_plan = match config.harden.as_ref() {
+            Some(_) if snapshot => {
+                anyhow::bail!("Snapshots cannot be written once hardened")
+            }
+            Some(harden) => Some(config::harden_plan(&config, harden)),
+            None => None,
+        };
         let (inst, com1_sock) = setup_instance(config, false, &log)?;
+        if let Some(plan) = harden_plan {
+            propolis::harden::apply(&plan, &log)
+                .context("Failed to harden process")?;
+        }
         (inst, com1_sock)
     };
 
@@ -128,3 +128,24 @@ pub enum vm_suspend_how {
     VM_SUSPEND_HALT,
     VM_SUSPEND_TRIPLEFAULT,
 }
+
+#[repr(i32)]
+#[allow(non_camel_case_types, unused)]
+#[derive(Copy, Clone, Debug)]
+pub enum vm_cpu_mode {
+    CPU_MODE_REAL,
+    CPU_MODE_PROTECTED,
+    CPU_MODE_COMPATIBILITY,
+    CPU_MODE_64BIT,
+}
+
+#[repr(i32)]
+#[allow(non_camel_case_types, unused)]
+#[derive(Copy, Clone, Debug)]
+pub enum vm_paging_mode {
+    PAGING_MODE_FLAT,
+    PAGING_MODE_32,
+    PAGING_MODE_PAE,
+    PAGING_MODE_64,
+    PAGING_MODE_64_LA57,
+}
@@ -177,6 +177,13 @@ pub struct vm_memmap {
     pub flags: c_int,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_munmap {
+    pub gpa: u64,
+    pub len: size_t,
+}
+
 pub const VM_MEMMAP_F_WIRED: c_int = 0x01;
 #[allow(unused)]
 pub const VM_MEMMAP_F_IOMMU: c_int = 0x02;
@@ -230,6 +237,26 @@ pub struct vm_exception {
     pub restart_instruction: c_int,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_guest_paging {
+    pub cr3: u64,
+    pub cpl: c_int,
+    pub cpu_mode: c_int,
+    pub paging_mode: c_int,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_gla2gpa {
+    pub vcpuid: c_int,
+    pub prot: c_int,
+    pub gla: u64,
+    pub paging: vm_guest_paging,
+    pub fault: c_int,
+    pub gpa: u64,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct vm_lapic_msi {
@@ -272,6 +299,13 @@ pub struct vm_rtc_data {
     pub value: u8,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_hpet_cap {
+    /// Lower 32 bits of the HPET General Capabilities and ID register
+    pub capabilities: u32,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
 pub struct vm_capability {
@@ -27,6 +27,50 @@ pub struct CrucibleStorageBackend {
 
     /// Indicates whether the storage is read-only.
     pub readonly: bool,
+
+    /// If present, read through the start of the volume in the background once
+    /// the instance starts, warming the page cache of the hosts serving its
+    /// storage.
+    #[serde(default)]
+    pub prefetch: Option<CruciblePrefetch>,
+
+    /// If present, retry requests which fail transiently or stall, rather than
+    /// failing them immediately, and track the health of the volume.
+    #[serde(default)]
+    pub retry: Option<CrucibleRetry>,
+}
+
+/// Configuration of the background prefetch of a Crucible volume.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CruciblePrefetch {
+    /// Upper bound on the rate at which the volume is read, in bytes per
+    /// second. Defaults to 32 MiB/s, and is clamped to at most 128 MiB/s.
+    pub max_bytes_per_sec: Option<u64>,
+
+    /// Number of bytes, from the start of the volume, to read. Defaults to
+    /// 1 GiB.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+/// Configuration of the retry of requests to a Crucible volume.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CrucibleRetry {
+    /// Maximum number of attempts (including the first) made for a request.
+    /// Defaults to 5.
+    pub max_attempts: Option<u32>,
+
+    /// Time after which an attempt with no result is considered stalled and
+    /// retried.  Defaults to 30 seconds.
+    pub stall_timeout_ms: Option<u64>,
+
+    /// Once retries are exhausted, report the volume as not ready, rather than
+    /// failing the request, to guests whose disk interface can express it.
+    /// Other guests have the request held until the volume recovers.
+    #[serde(default)]
+    pub not_ready_on_exhaust: bool,
 }
 
 impl MigrationElement for CrucibleStorageBackend {
@@ -58,6 +102,8 @@ impl std::fmt::Debug for CrucibleStorageBackend {
         f.debug_struct("CrucibleStorageBackend")
             .field("request_json", &"<redacted>".to_string())
             .field("readonly", &self.readonly)
+            .field("prefetch", &self.prefetch)
+            .field("retry", &self.retry)
             .finish()
     }
 }
@@ -71,6 +117,17 @@ pub struct FileStorageBackend {
 
     /// Indicates whether the storage is read-only.
     pub readonly: bool,
+
+    /// Share a single open descriptor for the file with any other read-only
+    /// backends using it in the same process, rather than opening it
+    /// separately.  Requires `readonly`.
+    #[serde(default)]
+    pub shared: bool,
+
+    /// The number of threads servicing I/O to the file.  Defaults to 8 if
+    /// omitted, and is clamped to between 1 and 32.
+    #[serde(default)]
+    pub workers: Option<u32>,
 }
 
 impl MigrationElement for FileStorageBackend {
@@ -138,6 +195,47 @@ impl MigrationElement for BlobStorageBackend {
     }
 }
 
+/// A storage backend which completes every request immediately without
+/// storing or returning any data, for benchmarking the emulated storage path
+/// independent of host storage.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NullStorageBackend {
+    /// The size of the disk presented to the guest, in bytes.
+    pub size: u64,
+
+    /// Indicates whether the storage is read-only.
+    pub readonly: bool,
+}
+
+impl MigrationElement for NullStorageBackend {
+    fn kind(&self) -> &'static str {
+        "NullStorageBackend"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        if self.size != other.size {
+            Err(MigrationCompatibilityError::ComponentConfiguration(format!(
+                "size mismatch (self: {}, other: {})",
+                self.size, other.size,
+            ))
+            .into())
+        } else if self.readonly != other.readonly {
+            Err(MigrationCompatibilityError::ComponentConfiguration(format!(
+                "read-only mismatch (self: {}, other: {})",
+                self.readonly, other.readonly,
+            ))
+            .into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// A network backend associated with a virtio-net (viona) VNIC on the host.
 #[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -160,6 +258,27 @@ impl MigrationElement for VirtioNetworkBackend {
     }
 }
 
+/// A network backend which drops every packet the guest transmits and never
+/// receives any, for benchmarking the emulated network path independent of
+/// host networking.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NullNetworkBackend {}
+
+impl MigrationElement for NullNetworkBackend {
+    fn kind(&self) -> &'static str {
+        "NullNetworkBackend"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        _other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        Ok(())
+    }
+}
+
 /// A network backend associated with a DLPI VNIC on the host.
 #[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -5,9 +5,12 @@
 //! VM mainboard components. Every VM has a board, even if it has no other
 //! peripherals.
 
+use std::collections::BTreeMap;
+
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use uuid::Uuid;
 
 use crate::instance_spec::migration::MigrationElement;
 
@@ -20,6 +23,12 @@ pub struct I440Fx {
     /// Specifies whether the chipset should allow PCI configuration space
     /// to be accessed through the PCIe extended configuration mechanism.
     pub enable_pcie: bool,
+
+    /// Specifies whether the chipset's power management function should
+    /// provide a PIIX4-compatible SMBus host controller, with a temperature
+    /// sensor and an FRU EEPROM describing the board attached.
+    #[serde(default)]
+    pub enable_smbus: bool,
 }
 
 impl MigrationElement for I440Fx {
@@ -38,6 +47,12 @@ impl MigrationElement for I440Fx {
                 other.enable_pcie,
             )
             .into())
+        } else if self.enable_smbus != other.enable_smbus {
+            Err(MigrationCompatibilityError::SmbusMismatch(
+                self.enable_smbus,
+                other.enable_smbus,
+            )
+            .into())
         } else {
             Ok(())
         }
@@ -76,6 +91,115 @@ impl MigrationElement for Chipset {
     }
 }
 
+/// A named CPU feature profile.  Instances with the same profile can migrate
+/// between any hosts which support it, regardless of processor generation.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum CpuProfile {
+    /// Features common to AMD EPYC 7002 (Rome) and later processors.
+    BaselineRome,
+    /// Features common to AMD EPYC 7003 (Milan) and later processors.
+    BaselineMilan,
+}
+
+/// The CPU vendor whose conventions a [`Cpuid`] leaf set follows.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CpuidVendor {
+    Amd,
+    Intel,
+}
+
+/// A single cpuid leaf, identified by function (%eax) and, if the function
+/// has sub-leaves, sub-function (%ecx).
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct CpuidEntry {
+    /// The function (%eax) value which selects this leaf.
+    pub leaf: u32,
+
+    /// The sub-function (%ecx) value which selects this leaf, if the function
+    /// is indexed by %ecx.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subleaf: Option<u32>,
+
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// A complete set of cpuid leaves to present to the guest.
+///
+/// Leaves absent from the set are reported as zero, so the set must include
+/// every leaf the guest is expected to query, including the vendor (0x0) and
+/// brand string (0x80000002-0x80000004) leaves.  Topology-related fields
+/// (APIC IDs, core counts, cache sharing) are filled in for each vCPU when
+/// the VM is created.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Cpuid {
+    pub entries: Vec<CpuidEntry>,
+    pub vendor: CpuidVendor,
+}
+
+impl Cpuid {
+    /// The values of each leaf, keyed by function and sub-function, so that
+    /// sets listing the same leaves in a different order compare as equal.
+    fn leaves(&self) -> BTreeMap<(u32, Option<u32>), [u32; 4]> {
+        self.entries
+            .iter()
+            .map(|e| ((e.leaf, e.subleaf), [e.eax, e.ebx, e.ecx, e.edx]))
+            .collect()
+    }
+}
+
+/// The arrangement of a VM's vCPUs into sockets, cores, and threads.
+///
+/// The cores per socket and threads per core must be powers of two, and the
+/// product of all three must equal the board's CPU count.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct CpuTopology {
+    pub sockets: u8,
+    pub cores_per_socket: u8,
+    pub threads_per_core: u8,
+}
+
+/// Identification of the platform reported to the guest in its SMBIOS tables,
+/// in place of that generated for the instance.  Fields which are unset take
+/// their generated values.
+#[derive(
+    Clone, Default, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct SmbiosIdentity {
+    /// The system serial number.  By default, the instance ID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+
+    /// The system UUID.  By default, the instance ID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<Uuid>,
+
+    /// The asset tag of the chassis and baseboard.  By default, none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset_tag: Option<String>,
+
+    /// The manufacturer of the system, baseboard, and chassis.  By default,
+    /// "Oxide".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+}
+
 /// A VM's mainboard.
 #[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -88,7 +212,43 @@ pub struct Board {
 
     /// The chipset to expose to guest software.
     pub chipset: Chipset,
-    // TODO: Guest platform and CPU feature identification.
+
+    /// The CPU feature profile to present to the guest.  If unset, the
+    /// features reported are those of the host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_profile: Option<CpuProfile>,
+
+    /// An explicit set of cpuid leaves to present to the guest.  This is
+    /// mutually exclusive with `cpu_profile`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuid: Option<Cpuid>,
+
+    /// The arrangement of the vCPUs presented to the guest.  This requires
+    /// either `cpu_profile` or `cpuid`, as it is conveyed in the cpuid leaves.
+    /// If unset, the vCPUs are not described as belonging to any particular
+    /// socket or core.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_topology: Option<CpuTopology>,
+
+    /// Whether to report time for which the vCPUs are kept from running,
+    /// whether waiting for a host CPU or throttled by a duty cycle limit, to
+    /// the guest as steal time.  This requires either `cpu_profile` or
+    /// `cpuid`, through which the feature is advertised.
+    #[serde(default)]
+    pub steal_time: bool,
+
+    /// Whether vCPUs spinning on the PAUSE instruction, as when waiting on a
+    /// contended lock, should exit so that a sibling vCPU may have their host
+    /// CPU.  This is a matter of host policy, invisible to the guest, and so
+    /// need not match across a migration.
+    #[serde(default)]
+    pub pause_exits: bool,
+
+    /// Identification of the platform reported to the guest through SMBIOS,
+    /// which licensing checks and cloud-init datasources commonly key off.
+    /// If unset, the identification generated for the instance is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smbios: Option<SmbiosIdentity>,
     // TODO: NUMA topology.
 }
 
@@ -97,7 +257,16 @@ impl Default for Board {
         Self {
             cpus: 0,
             memory_mb: 0,
-            chipset: Chipset::I440Fx(I440Fx { enable_pcie: false }),
+            chipset: Chipset::I440Fx(I440Fx {
+                enable_pcie: false,
+                enable_smbus: false,
+            }),
+            cpu_profile: None,
+            cpuid: None,
+            cpu_topology: None,
+            steal_time: false,
+            pause_exits: false,
+            smbios: None,
         }
     }
 }
@@ -125,6 +294,30 @@ impl MigrationElement for Board {
             self.chipset.can_migrate_from_element(&other.chipset)
         {
             Err(e)
+        } else if self.cpu_profile != other.cpu_profile {
+            Err(MigrationCompatibilityError::CpuProfile(
+                self.cpu_profile,
+                other.cpu_profile,
+            )
+            .into())
+        } else if self.cpuid.as_ref().map(|c| (c.vendor, c.leaves()))
+            != other.cpuid.as_ref().map(|c| (c.vendor, c.leaves()))
+        {
+            Err(MigrationCompatibilityError::Cpuid.into())
+        } else if self.cpu_topology != other.cpu_topology {
+            Err(MigrationCompatibilityError::CpuTopology(
+                self.cpu_topology,
+                other.cpu_topology,
+            )
+            .into())
+        } else if self.steal_time != other.steal_time {
+            Err(MigrationCompatibilityError::StealTime(
+                self.steal_time,
+                other.steal_time,
+            )
+            .into())
+        } else if self.smbios != other.smbios {
+            Err(MigrationCompatibilityError::SmbiosIdentity.into())
         } else {
             Ok(())
         }
@@ -141,6 +334,28 @@ pub enum MigrationCompatibilityError {
 
     #[error("Chipsets have different PCIe settings (self: {0}, other: {1})")]
     PcieMismatch(bool, bool),
+
+    #[error("Chipsets have different SMBus settings (self: {0}, other: {1})")]
+    SmbusMismatch(bool, bool),
+
+    #[error("Boards have different CPU profiles (self: {0:?}, other: {1:?})")]
+    CpuProfile(Option<CpuProfile>, Option<CpuProfile>),
+
+    #[error("Boards have different cpuid leaves")]
+    Cpuid,
+
+    #[error(
+        "Boards have different CPU topologies (self: {0:?}, other: {1:?})"
+    )]
+    CpuTopology(Option<CpuTopology>, Option<CpuTopology>),
+
+    #[error(
+        "Boards have different steal time settings (self: {0}, other: {1})"
+    )]
+    StealTime(bool, bool),
+
+    #[error("Boards have different SMBIOS identification")]
+    SmbiosIdentity,
 }
 
 #[cfg(test)]
@@ -152,7 +367,16 @@ mod test {
         let b1 = Board {
             cpus: 8,
             memory_mb: 8192,
-            chipset: Chipset::I440Fx(I440Fx { enable_pcie: false }),
+            chipset: Chipset::I440Fx(I440Fx {
+                enable_pcie: false,
+                enable_smbus: false,
+            }),
+            cpu_profile: None,
+            cpuid: None,
+            cpu_topology: None,
+            steal_time: false,
+            pause_exits: false,
+            smbios: None,
         };
 
         assert!(b1.can_migrate_from_element(&b1).is_ok());
@@ -163,19 +387,141 @@ mod test {
         let b1 = Board {
             cpus: 4,
             memory_mb: 4096,
-            chipset: Chipset::I440Fx(I440Fx { enable_pcie: true }),
+            chipset: Chipset::I440Fx(I440Fx {
+                enable_pcie: true,
+                enable_smbus: true,
+            }),
+            cpu_profile: Some(CpuProfile::BaselineRome),
+            cpuid: None,
+            cpu_topology: Some(CpuTopology {
+                sockets: 1,
+                cores_per_socket: 2,
+                threads_per_core: 2,
+            }),
+            steal_time: true,
+            pause_exits: true,
+            smbios: Some(SmbiosIdentity {
+                serial_number: Some("SN-1".to_string()),
+                ..Default::default()
+            }),
         };
 
-        let b2 = Board { cpus: 8, ..b1 };
+        let b2 = Board { cpus: 8, ..b1.clone() };
         assert!(b1.can_migrate_from_element(&b2).is_err());
 
-        let b2 = Board { memory_mb: b1.memory_mb * 2, ..b1 };
+        let b2 = Board { memory_mb: b1.memory_mb * 2, ..b1.clone() };
         assert!(b1.can_migrate_from_element(&b2).is_err());
 
         let b2 = Board {
-            chipset: Chipset::I440Fx(I440Fx { enable_pcie: false }),
+            chipset: Chipset::I440Fx(I440Fx {
+                enable_pcie: false,
+                enable_smbus: true,
+            }),
+            ..b1.clone()
+        };
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+
+        let b2 = Board {
+            chipset: Chipset::I440Fx(I440Fx {
+                enable_pcie: true,
+                enable_smbus: false,
+            }),
+            ..b1.clone()
+        };
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+
+        let b2 = Board {
+            cpu_profile: Some(CpuProfile::BaselineMilan),
+            ..b1.clone()
+        };
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+
+        let b2 = Board { cpu_profile: None, ..b1.clone() };
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+
+        let b2 = Board {
+            cpu_topology: Some(CpuTopology {
+                sockets: 2,
+                cores_per_socket: 2,
+                threads_per_core: 1,
+            }),
+            ..b1.clone()
+        };
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+
+        let b2 = Board { steal_time: false, ..b1.clone() };
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+
+        let b2 = Board {
+            smbios: Some(SmbiosIdentity {
+                serial_number: Some("SN-2".to_string()),
+                ..Default::default()
+            }),
+            ..b1.clone()
+        };
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+
+        let b2 = Board { smbios: None, ..b1.clone() };
+        assert!(b1.can_migrate_from_element(&b2).is_err());
+
+        // PAUSE exiting is host policy, and may differ.
+        let b2 = Board { pause_exits: false, ..b1.clone() };
+        assert!(b1.can_migrate_from_element(&b2).is_ok());
+
+        let entry = CpuidEntry {
+            leaf: 0x7,
+            subleaf: Some(0),
+            eax: 0,
+            ebx: 0x219c91a9,
+            ecx: 0x40068c,
+            edx: 0,
+        };
+        let b1 = Board {
+            cpu_profile: None,
+            cpuid: Some(Cpuid {
+                entries: vec![entry],
+                vendor: CpuidVendor::Amd,
+            }),
             ..b1
         };
+        assert!(b1.can_migrate_from_element(&b1).is_ok());
+
+        // The order in which leaves are listed does not matter
+        let leaf0 = CpuidEntry {
+            leaf: 0,
+            subleaf: None,
+            eax: 0x10,
+            ebx: 0x68747541,
+            ecx: 0x444d4163,
+            edx: 0x69746e65,
+        };
+        let b2 = Board {
+            cpuid: Some(Cpuid {
+                entries: vec![entry, leaf0],
+                vendor: CpuidVendor::Amd,
+            }),
+            ..b1.clone()
+        };
+        let b3 = Board {
+            cpuid: Some(Cpuid {
+                entries: vec![leaf0, entry],
+                vendor: CpuidVendor::Amd,
+            }),
+            ..b1.clone()
+        };
+        assert!(b2.can_migrate_from_element(&b3).is_ok());
+
+        // Hiding a feature (here, AVX2) makes the boards incompatible
+        let b2 = Board {
+            cpuid: Some(Cpuid {
+                entries: vec![CpuidEntry {
+                    ebx: entry.ebx & !(1 << 5),
+                    ..entry
+                }],
+                vendor: CpuidVendor::Amd,
+            }),
+            ..b1.clone()
+        };
         assert!(b1.can_migrate_from_element(&b2).is_err());
     }
 }
@@ -35,6 +35,52 @@ fn pci_path_matches(
     }
 }
 
+fn write_protect_matches(
+    this: bool,
+    other: bool,
+) -> Result<(), MigrationCompatibilityError> {
+    if this != other {
+        Err(MigrationCompatibilityError::WriteProtect(this, other))
+    } else {
+        Ok(())
+    }
+}
+
+fn disabled_matches(
+    this: bool,
+    other: bool,
+) -> Result<(), MigrationCompatibilityError> {
+    if this != other {
+        Err(MigrationCompatibilityError::Disabled(this, other))
+    } else {
+        Ok(())
+    }
+}
+
+/// The priority class of a disk's I/O, relative to the other disks on the
+/// host.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Serialize,
+    PartialEq,
+    Eq,
+    JsonSchema,
+)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub enum DiskPriority {
+    /// Latency-sensitive I/O, such as that of a boot disk.
+    High,
+    /// The default class.
+    #[default]
+    Normal,
+    /// Bulk I/O which yields to disks of all other classes.
+    Low,
+}
+
 /// A disk that presents a virtio-block interface to the guest.
 #[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
 #[serde(deny_unknown_fields)]
@@ -44,6 +90,27 @@ pub struct VirtioDisk {
 
     /// The PCI bus/device/function at which this disk should be attached.
     pub pci_path: PciPath,
+
+    /// Whether the disk is write-protected, causing the guest to see it as
+    /// read-only regardless of the capabilities of its backend.
+    #[serde(default)]
+    pub write_protected: bool,
+
+    /// The priority class of the disk's I/O.
+    #[serde(default)]
+    pub priority: DiskPriority,
+
+    /// Whether the disk is disabled: it is created, but hidden from the guest
+    /// as if its PCI slot were empty.
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// Whether the disk's backend may finish starting after the guest begins
+    /// to run.  Until then, the disk is hidden from the guest, to which it is
+    /// hot-plugged once ready.  Suitable for disks which are not needed to
+    /// boot.
+    #[serde(default)]
+    pub deferred_start: bool,
 }
 
 impl MigrationElement for VirtioDisk {
@@ -58,6 +125,8 @@ impl MigrationElement for VirtioDisk {
     {
         backend_name_matches(&self.backend_name, &other.backend_name)?;
         pci_path_matches(&self.pci_path, &other.pci_path)?;
+        write_protect_matches(self.write_protected, other.write_protected)?;
+        disabled_matches(self.disabled, other.disabled)?;
         Ok(())
     }
 }
@@ -71,6 +140,27 @@ pub struct NvmeDisk {
 
     /// The PCI bus/device/function at which this disk should be attached.
     pub pci_path: PciPath,
+
+    /// Whether the disk is write-protected, causing the guest to see it as
+    /// read-only regardless of the capabilities of its backend.
+    #[serde(default)]
+    pub write_protected: bool,
+
+    /// The priority class of the disk's I/O.
+    #[serde(default)]
+    pub priority: DiskPriority,
+
+    /// Whether the disk is disabled: it is created, but hidden from the guest
+    /// as if its PCI slot were empty.
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// Whether the disk's backend may finish starting after the guest begins
+    /// to run.  Until then, the disk is hidden from the guest, to which it is
+    /// hot-plugged once ready.  Suitable for disks which are not needed to
+    /// boot.
+    #[serde(default)]
+    pub deferred_start: bool,
 }
 
 impl MigrationElement for NvmeDisk {
@@ -85,6 +175,59 @@ impl MigrationElement for NvmeDisk {
     {
         backend_name_matches(&self.backend_name, &other.backend_name)?;
         pci_path_matches(&self.pci_path, &other.pci_path)?;
+        write_protect_matches(self.write_protected, other.write_protected)?;
+        disabled_matches(self.disabled, other.disabled)?;
+        Ok(())
+    }
+}
+
+/// A disk that presents an AHCI (Serial ATA) interface to the guest, for guests
+/// whose drivers cannot make use of a virtio or NVMe disk.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AhciDisk {
+    /// The name of the disk's backend component.
+    pub backend_name: String,
+
+    /// The PCI bus/device/function at which this disk should be attached.
+    pub pci_path: PciPath,
+
+    /// Whether the disk is write-protected, causing the guest to see it as
+    /// read-only regardless of the capabilities of its backend.
+    #[serde(default)]
+    pub write_protected: bool,
+
+    /// The priority class of the disk's I/O.
+    #[serde(default)]
+    pub priority: DiskPriority,
+
+    /// Whether the disk is disabled: it is created, but hidden from the guest
+    /// as if its PCI slot were empty.
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// Whether the disk's backend may finish starting after the guest begins
+    /// to run.  Until then, the disk is hidden from the guest, to which it is
+    /// hot-plugged once ready.  Suitable for disks which are not needed to
+    /// boot.
+    #[serde(default)]
+    pub deferred_start: bool,
+}
+
+impl MigrationElement for AhciDisk {
+    fn kind(&self) -> &'static str {
+        "AhciDisk"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        backend_name_matches(&self.backend_name, &other.backend_name)?;
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        write_protect_matches(self.write_protected, other.write_protected)?;
+        disabled_matches(self.disabled, other.disabled)?;
         Ok(())
     }
 }
@@ -98,6 +241,11 @@ pub struct VirtioNic {
 
     /// The PCI path at which to attach this device.
     pub pci_path: PciPath,
+
+    /// Whether the device is disabled: it is created, but hidden from the
+    /// guest as if its PCI slot were empty.
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 impl MigrationElement for VirtioNic {
@@ -112,6 +260,7 @@ impl MigrationElement for VirtioNic {
     {
         backend_name_matches(&self.backend_name, &other.backend_name)?;
         pci_path_matches(&self.pci_path, &other.pci_path)?;
+        disabled_matches(self.disabled, other.disabled)?;
         Ok(())
     }
 }
@@ -137,6 +286,11 @@ pub enum SerialPortNumber {
 pub struct SerialPort {
     /// The serial port number for this port.
     pub num: SerialPortNumber,
+
+    /// Whether the port's output is paced at the baud rate programmed by the
+    /// guest, as on hardware, rather than transmitted as fast as possible.
+    #[serde(default)]
+    pub paced: bool,
 }
 
 impl MigrationElement for SerialPort {
@@ -149,10 +303,10 @@ impl MigrationElement for SerialPort {
         other: &Self,
     ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
     {
-        if self != other {
+        if self.num != other.num {
             Err(MigrationCompatibilityError::ComponentConfiguration(format!(
                 "serial port number mismatch (self: {0:?}, other: {1:?})",
-                self, other
+                self.num, other.num
             ))
             .into())
         } else {
@@ -199,6 +353,325 @@ impl MigrationElement for PciPciBridge {
     }
 }
 
+/// A virtio-rtc device, through which the guest can read the host's clock
+/// (e.g. as a PTP clock under Linux).
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioRtc {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for VirtioRtc {
+    fn kind(&self) -> &'static str {
+        "VirtioRtc"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        Ok(())
+    }
+}
+
+/// A virtio-rng device, which supplies the guest with entropy from the host.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioRng {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+
+    /// The most entropy, in bytes per second, to supply to the guest. If not
+    /// specified, a default of 1 MiB/s is used.
+    #[serde(default)]
+    pub bytes_per_sec: Option<u32>,
+}
+
+impl MigrationElement for VirtioRng {
+    fn kind(&self) -> &'static str {
+        "VirtioRng"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        // The rate limit is a matter of host policy, which may differ
+        // between the source and target.
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        Ok(())
+    }
+}
+
+/// The kind of input a virtio-input device offers the guest.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub enum VirtioInputKind {
+    Keyboard,
+    Tablet,
+}
+
+/// A virtio-input device. A keyboard receives the keyboard input of VNC
+/// clients in place of the PS/2 keyboard, while a tablet reports the absolute
+/// position of a pointer, free of the guest's pointer acceleration.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioInput {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+
+    pub kind: VirtioInputKind,
+}
+
+impl MigrationElement for VirtioInput {
+    fn kind(&self) -> &'static str {
+        "VirtioInput"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        if self.kind != other.kind {
+            Err(MigrationCompatibilityError::ComponentConfiguration(format!(
+                "input device kind mismatch (self: {0:?}, other: {1:?})",
+                self.kind, other.kind
+            ))
+            .into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A virtio-mem device, through which memory may be added to (or removed from)
+/// the guest while it runs.
+///
+/// The device manages a dedicated region of guest-physical memory, apart from
+/// the instance's base memory, of which the guest is asked to use as much as
+/// the host requests.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioMem {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+
+    /// The size of the hot-pluggable memory region, in MiB.  Must be a
+    /// multiple of 1024.
+    pub region_mb: u64,
+}
+
+impl MigrationElement for VirtioMem {
+    fn kind(&self) -> &'static str {
+        "VirtioMem"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        if self.region_mb != other.region_mb {
+            Err(MigrationCompatibilityError::ComponentConfiguration(format!(
+                "memory region size mismatch (self: {0}, other: {1})",
+                self.region_mb, other.region_mb
+            ))
+            .into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A shared-memory device, compatible with QEMU's `ivshmem-plain`, which
+/// exposes a host file to the guest as memory. Instances on the same host
+/// which attach the same file share its contents.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SharedMemory {
+    /// The name of the file on the host which backs the shared memory, within
+    /// the server's shared memory directory. It is created if it does not
+    /// exist, and then removed once the last instance attached to it is torn
+    /// down.
+    pub path: String,
+
+    /// The size of the shared memory in bytes, which must be a power of two
+    /// no smaller than 4 KiB.
+    pub size: u64,
+
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for SharedMemory {
+    fn kind(&self) -> &'static str {
+        "SharedMemory"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        if self.path != other.path || self.size != other.size {
+            Err(MigrationCompatibilityError::ComponentConfiguration(format!(
+                "shared memory mismatch (self: {0} ({1:#x}), other: {2} ({3:#x}))",
+                self.path, self.size, other.path, other.size
+            ))
+            .into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A TPM 2.0 device, presented to the guest through the CRB interface, whose
+/// commands are executed by an external `swtpm` process.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Tpm {
+    /// The path of the Unix socket on which swtpm serves TPM commands.
+    pub socket_path: String,
+
+    /// The path of the Unix socket on which swtpm serves its control channel,
+    /// through which the TPM is reinitialized when the instance resets, and
+    /// its state is carried when the instance migrates. If not specified,
+    /// swtpm must be run with `--flags not-need-init`, and the instance cannot
+    /// be migrated.
+    #[serde(default)]
+    pub ctrl_socket_path: Option<String>,
+
+    /// The location at which swtpm keeps the TPM's persistent state. Propolis
+    /// does not access this state itself, but carries the TPM's state to the
+    /// target of a migration through the control channel.
+    pub state_path: String,
+}
+
+impl MigrationElement for Tpm {
+    fn kind(&self) -> &'static str {
+        "Tpm"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        _other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        // The sockets through which swtpm is reached, and the location of
+        // its state, are local to each host: the TPM's state is carried to
+        // the target through swtpm's control channel.
+        Ok(())
+    }
+}
+
+/// A pvpanic device, presented to the guest as a PCI function, through which
+/// the guest kernel reports that it has panicked.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct QemuPvpanic {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for QemuPvpanic {
+    fn kind(&self) -> &'static str {
+        "QemuPvpanic"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        Ok(())
+    }
+}
+
+/// A display device compatible with QEMU's `bochs-display`, whose framebuffer
+/// is served to clients of the server's VNC endpoint.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct BochsDisplay {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+impl MigrationElement for BochsDisplay {
+    fn kind(&self) -> &'static str {
+        "BochsDisplay"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        Ok(())
+    }
+}
+
+/// A virtio-vsock device, through which software in the guest can connect to
+/// sockets served by the host without any network configuration.
+#[derive(
+    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioVsock {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+
+    /// The context ID by which the guest is addressed. IDs 0 through 2 are
+    /// reserved, 2 being that of the host.
+    pub guest_cid: u64,
+}
+
+impl MigrationElement for VirtioVsock {
+    fn kind(&self) -> &'static str {
+        "VirtioVsock"
+    }
+
+    fn can_migrate_from_element(
+        &self,
+        other: &Self,
+    ) -> Result<(), crate::instance_spec::migration::ElementCompatibilityError>
+    {
+        pci_path_matches(&self.pci_path, &other.pci_path)?;
+        if self.guest_cid != other.guest_cid {
+            Err(MigrationCompatibilityError::ComponentConfiguration(format!(
+                "vsock guest CID mismatch (self: {0}, other: {1})",
+                self.guest_cid, other.guest_cid
+            ))
+            .into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum MigrationCompatibilityError {
     /// The two devices have mismatched backend names. This means that migration
@@ -210,6 +683,16 @@ pub enum MigrationCompatibilityError {
     #[error("PCI devices have different paths (self: {0:?}, other: {1:?})")]
     PciPath(PciPath, PciPath),
 
+    #[error(
+        "disks have different write-protect settings (self: {0}, other: {1})"
+    )]
+    WriteProtect(bool, bool),
+
+    #[error(
+        "devices have different disabled settings (self: {0}, other: {1})"
+    )]
+    Disabled(bool, bool),
+
     #[error("component configurations incompatible: {0}")]
     ComponentConfiguration(String),
 }
@@ -272,6 +755,10 @@ mod test {
         let d1 = VirtioDisk {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            write_protected: false,
+            priority: DiskPriority::Normal,
+            disabled: false,
+            deferred_start: false,
         };
         assert!(d1.can_migrate_from_element(&d1).is_ok());
     }
@@ -281,6 +768,10 @@ mod test {
         let d1 = VirtioDisk {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            write_protected: false,
+            priority: DiskPriority::Normal,
+            disabled: false,
+            deferred_start: false,
         };
 
         let d2 = VirtioDisk { backend_name: "other_backend".to_string(), ..d1 };
@@ -291,6 +782,12 @@ mod test {
             ..d1.clone()
         };
         assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioDisk { write_protected: true, ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioDisk { disabled: true, ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
     }
 
     #[test]
@@ -298,6 +795,10 @@ mod test {
         let d1 = NvmeDisk {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            write_protected: false,
+            priority: DiskPriority::Normal,
+            disabled: false,
+            deferred_start: false,
         };
         assert!(d1.can_migrate_from_element(&d1).is_ok());
     }
@@ -307,6 +808,10 @@ mod test {
         let d1 = NvmeDisk {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            write_protected: false,
+            priority: DiskPriority::Normal,
+            disabled: false,
+            deferred_start: false,
         };
 
         let d2 = NvmeDisk { backend_name: "other_backend".to_string(), ..d1 };
@@ -315,6 +820,50 @@ mod test {
         let d2 =
             NvmeDisk { pci_path: PciPath::new(0, 6, 0).unwrap(), ..d1.clone() };
         assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = NvmeDisk { write_protected: true, ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = NvmeDisk { disabled: true, ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+    }
+
+    #[test]
+    fn compatible_ahci_disk() {
+        let d1 = AhciDisk {
+            backend_name: "storage_backend".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+            write_protected: false,
+            priority: DiskPriority::Normal,
+            disabled: false,
+            deferred_start: false,
+        };
+        assert!(d1.can_migrate_from_element(&d1).is_ok());
+    }
+
+    #[test]
+    fn incompatible_ahci_disk() {
+        let d1 = AhciDisk {
+            backend_name: "storage_backend".to_string(),
+            pci_path: PciPath::new(0, 5, 0).unwrap(),
+            write_protected: false,
+            priority: DiskPriority::Normal,
+            disabled: false,
+            deferred_start: false,
+        };
+
+        let d2 = AhciDisk { backend_name: "other_backend".to_string(), ..d1 };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 =
+            AhciDisk { pci_path: PciPath::new(0, 6, 0).unwrap(), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = AhciDisk { write_protected: true, ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = AhciDisk { disabled: true, ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
     }
 
     #[test]
@@ -322,6 +871,8 @@ mod test {
         let d1 = VirtioNic {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            disabled: false,
+            deferred_start: false,
         };
         assert!(d1.can_migrate_from_element(&d1).is_ok());
     }
@@ -331,6 +882,8 @@ mod test {
         let d1 = VirtioNic {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            disabled: false,
+            deferred_start: false,
         };
 
         let d2 = VirtioNic { backend_name: "other_backend".to_string(), ..d1 };
@@ -341,6 +894,9 @@ mod test {
             ..d1.clone()
         };
         assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioNic { disabled: true, ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
     }
 
     #[test]
@@ -355,8 +911,8 @@ mod test {
         for (p1, p2) in
             ports.into_iter().flat_map(|p| std::iter::repeat(p).zip(ports))
         {
-            let can_migrate = SerialPort { num: p1 }
-                .can_migrate_from_element(&SerialPort { num: p2 });
+            let can_migrate = SerialPort { num: p1, paced: false }
+                .can_migrate_from_element(&SerialPort { num: p2, paced: true });
 
             assert_eq!(
                 p1 == p2,
@@ -385,4 +941,21 @@ mod test {
         b2.pci_path = PciPath::new(4, 5, 6).unwrap();
         assert!(b1.can_migrate_from_element(&b2).is_err());
     }
+
+    #[test]
+    fn tpm_compatibility() {
+        let t1 = Tpm {
+            socket_path: "/tmp/swtpm.sock".to_string(),
+            ctrl_socket_path: None,
+            state_path: "/var/tpm/vm0".to_string(),
+        };
+
+        // The sockets and state are local to each host, and may differ.
+        let t2 = Tpm {
+            socket_path: "/run/swtpm/vm0.sock".to_string(),
+            ctrl_socket_path: Some("/run/swtpm/vm0.ctrl".to_string()),
+            state_path: "/var/tpm/vm1".to_string(),
+        };
+        assert!(t1.can_migrate_from_element(&t2).is_ok());
+    }
 }
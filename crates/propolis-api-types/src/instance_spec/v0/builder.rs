@@ -26,6 +26,18 @@ pub enum SpecBuilderError {
 
     #[error("SoftNpu port {0:?} is already specified")]
     SoftNpuPortInUse(String),
+
+    #[error("A TPM is already specified")]
+    TpmInUse,
+
+    #[error("A pvpanic device is already specified")]
+    QemuPvpanicInUse,
+
+    #[error("A display device is already specified")]
+    BochsDisplayInUse,
+
+    #[error("A vsock device is already specified")]
+    VsockInUse,
 }
 
 /// A builder that constructs instance specs incrementally and catches basic
@@ -43,8 +55,14 @@ impl SpecBuilder {
             cpus,
             memory_mb,
             chipset: components::board::Chipset::I440Fx(
-                components::board::I440Fx { enable_pcie },
+                components::board::I440Fx { enable_pcie, enable_smbus: false },
             ),
+            cpu_profile: None,
+            cpuid: None,
+            cpu_topology: None,
+            steal_time: false,
+            pause_exits: false,
+            smbios: None,
         };
 
         Self {
@@ -56,6 +74,14 @@ impl SpecBuilder {
         }
     }
 
+    /// Sets whether the chipset provides an SMBus host controller.
+    pub fn set_smbus(&mut self, enable_smbus: bool) -> &Self {
+        let components::board::Chipset::I440Fx(i440fx) =
+            &mut self.spec.devices.board.chipset;
+        i440fx.enable_smbus = enable_smbus;
+        self
+    }
+
     /// Adds a PCI path to this builder's record of PCI locations with an
     /// attached device. If the path is already in use, returns an error.
     fn register_pci_device(
@@ -149,10 +175,168 @@ impl SpecBuilder {
         Ok(self)
     }
 
+    /// Adds a clock device.
+    pub fn add_clock_device(
+        &mut self,
+        device_name: String,
+        device_spec: components::devices::VirtioRtc,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.clock_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old =
+            self.spec.devices.clock_devices.insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds a shared memory device.
+    pub fn add_shared_memory_device(
+        &mut self,
+        device_name: String,
+        device_spec: components::devices::SharedMemory,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.shared_memory_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old = self
+            .spec
+            .devices
+            .shared_memory_devices
+            .insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds an entropy device.
+    pub fn add_entropy_device(
+        &mut self,
+        device_name: String,
+        device_spec: components::devices::VirtioRng,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.entropy_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old =
+            self.spec.devices.entropy_devices.insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds a hot-pluggable memory device.
+    pub fn add_memory_device(
+        &mut self,
+        device_name: String,
+        device_spec: components::devices::VirtioMem,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.memory_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old =
+            self.spec.devices.memory_devices.insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds an input device.
+    pub fn add_input_device(
+        &mut self,
+        device_name: String,
+        device_spec: components::devices::VirtioInput,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.input_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old =
+            self.spec.devices.input_devices.insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Sets the instance's TPM.
+    pub fn set_tpm(
+        &mut self,
+        tpm: components::devices::Tpm,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.tpm.is_some() {
+            return Err(SpecBuilderError::TpmInUse);
+        }
+
+        self.spec.devices.tpm = Some(tpm);
+        Ok(self)
+    }
+
+    /// Sets the instance's pvpanic device.
+    pub fn set_qemu_pvpanic(
+        &mut self,
+        pvpanic: components::devices::QemuPvpanic,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.qemu_pvpanic.is_some() {
+            return Err(SpecBuilderError::QemuPvpanicInUse);
+        }
+
+        self.register_pci_device(pvpanic.pci_path)?;
+        self.spec.devices.qemu_pvpanic = Some(pvpanic);
+        Ok(self)
+    }
+
+    /// Sets the instance's display device.
+    pub fn set_bochs_display(
+        &mut self,
+        display: components::devices::BochsDisplay,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.bochs_display.is_some() {
+            return Err(SpecBuilderError::BochsDisplayInUse);
+        }
+
+        self.register_pci_device(display.pci_path)?;
+        self.spec.devices.bochs_display = Some(display);
+        Ok(self)
+    }
+
+    /// Sets the instance's vsock device.
+    pub fn set_vsock(
+        &mut self,
+        vsock: components::devices::VirtioVsock,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.vsock.is_some() {
+            return Err(SpecBuilderError::VsockInUse);
+        }
+
+        self.register_pci_device(vsock.pci_path)?;
+        self.spec.devices.vsock = Some(vsock);
+        Ok(self)
+    }
+
     /// Adds a serial port.
     pub fn add_serial_port(
         &mut self,
         port: components::devices::SerialPortNumber,
+    ) -> Result<&Self, SpecBuilderError> {
+        self.add_serial_port_with_pacing(port, false)
+    }
+
+    /// Adds a serial port, whose output is optionally paced at the baud rate
+    /// programmed by the guest.
+    pub fn add_serial_port_with_pacing(
+        &mut self,
+        port: components::devices::SerialPortNumber,
+        paced: bool,
     ) -> Result<&Self, SpecBuilderError> {
         if self
             .spec
@@ -166,7 +350,7 @@ impl SpecBuilder {
                     components::devices::SerialPortNumber::Com4 => "com4",
                 }
                 .to_string(),
-                components::devices::SerialPort { num: port },
+                components::devices::SerialPort { num: port, paced },
             )
             .is_some()
         {
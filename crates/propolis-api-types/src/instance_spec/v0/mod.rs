@@ -39,13 +39,16 @@ pub mod builder;
 pub enum StorageDeviceV0 {
     VirtioDisk(components::devices::VirtioDisk),
     NvmeDisk(components::devices::NvmeDisk),
+    AhciDisk(components::devices::AhciDisk),
 }
 
 impl StorageDeviceV0 {
-    fn pci_path(&self) -> PciPath {
+    /// Returns the PCI path at which this device is attached.
+    pub fn pci_path(&self) -> PciPath {
         match self {
             Self::VirtioDisk(disk) => disk.pci_path,
             Self::NvmeDisk(disk) => disk.pci_path,
+            Self::AhciDisk(disk) => disk.pci_path,
         }
     }
 }
@@ -55,6 +58,7 @@ impl MigrationElement for StorageDeviceV0 {
         match self {
             StorageDeviceV0::VirtioDisk(_) => "StorageDevice(VirtioDisk)",
             StorageDeviceV0::NvmeDisk(_) => "StorageDevice(NvmeDisk)",
+            StorageDeviceV0::AhciDisk(_) => "StorageDevice(AhciDisk)",
         }
     }
 
@@ -69,6 +73,9 @@ impl MigrationElement for StorageDeviceV0 {
             (Self::NvmeDisk(this), Self::NvmeDisk(other)) => {
                 this.can_migrate_from_element(other)
             }
+            (Self::AhciDisk(this), Self::AhciDisk(other)) => {
+                this.can_migrate_from_element(other)
+            }
             (_, _) => Err(ElementCompatibilityError::ComponentsIncomparable(
                 self.kind(),
                 other.kind(),
@@ -84,7 +91,8 @@ pub enum NetworkDeviceV0 {
 }
 
 impl NetworkDeviceV0 {
-    fn pci_path(&self) -> PciPath {
+    /// Returns the PCI path at which this device is attached.
+    pub fn pci_path(&self) -> PciPath {
         match self {
             Self::VirtioNic(nic) => nic.pci_path,
         }
@@ -113,6 +121,25 @@ pub struct DeviceSpecV0 {
     pub network_devices: HashMap<SpecKey, NetworkDeviceV0>,
     pub serial_ports: HashMap<SpecKey, components::devices::SerialPort>,
     pub pci_pci_bridges: HashMap<SpecKey, components::devices::PciPciBridge>,
+    #[serde(default)]
+    pub clock_devices: HashMap<SpecKey, components::devices::VirtioRtc>,
+    #[serde(default)]
+    pub shared_memory_devices:
+        HashMap<SpecKey, components::devices::SharedMemory>,
+    #[serde(default)]
+    pub entropy_devices: HashMap<SpecKey, components::devices::VirtioRng>,
+    #[serde(default)]
+    pub memory_devices: HashMap<SpecKey, components::devices::VirtioMem>,
+    #[serde(default)]
+    pub input_devices: HashMap<SpecKey, components::devices::VirtioInput>,
+    #[serde(default)]
+    pub tpm: Option<components::devices::Tpm>,
+    #[serde(default)]
+    pub qemu_pvpanic: Option<components::devices::QemuPvpanic>,
+    #[serde(default)]
+    pub bochs_display: Option<components::devices::BochsDisplay>,
+    #[serde(default)]
+    pub vsock: Option<components::devices::VirtioVsock>,
 
     #[cfg(feature = "falcon")]
     pub softnpu_pci_port: Option<components::devices::SoftNpuPciPort>,
@@ -169,6 +196,147 @@ impl DeviceSpecV0 {
                 )
             })?;
 
+        self.clock_devices
+            .can_migrate_from_collection(&other.clock_devices)
+            .map_err(|e| {
+                MigrationCompatibilityError::CollectionMismatch(
+                    "clock devices".to_string(),
+                    e,
+                )
+            })?;
+
+        self.shared_memory_devices
+            .can_migrate_from_collection(&other.shared_memory_devices)
+            .map_err(|e| {
+                MigrationCompatibilityError::CollectionMismatch(
+                    "shared memory devices".to_string(),
+                    e,
+                )
+            })?;
+
+        self.entropy_devices
+            .can_migrate_from_collection(&other.entropy_devices)
+            .map_err(|e| {
+                MigrationCompatibilityError::CollectionMismatch(
+                    "entropy devices".to_string(),
+                    e,
+                )
+            })?;
+
+        self.memory_devices
+            .can_migrate_from_collection(&other.memory_devices)
+            .map_err(|e| {
+                MigrationCompatibilityError::CollectionMismatch(
+                    "memory devices".to_string(),
+                    e,
+                )
+            })?;
+
+        self.input_devices
+            .can_migrate_from_collection(&other.input_devices)
+            .map_err(|e| {
+                MigrationCompatibilityError::CollectionMismatch(
+                    "input devices".to_string(),
+                    e,
+                )
+            })?;
+
+        match (&self.tpm, &other.tpm) {
+            (None, None) => {}
+            (Some(this), Some(other)) => {
+                this.can_migrate_from_element(other).map_err(|e| {
+                    MigrationCompatibilityError::ElementMismatch(
+                        "tpm".to_string(),
+                        e,
+                    )
+                })?
+            }
+            (this, other) => {
+                let kind = |tpm: &Option<components::devices::Tpm>| {
+                    tpm.as_ref().map_or("None", |tpm| tpm.kind())
+                };
+                return Err(MigrationCompatibilityError::ElementMismatch(
+                    "tpm".to_string(),
+                    ElementCompatibilityError::ComponentsIncomparable(
+                        kind(this),
+                        kind(other),
+                    ),
+                ));
+            }
+        }
+
+        match (&self.qemu_pvpanic, &other.qemu_pvpanic) {
+            (None, None) => {}
+            (Some(this), Some(other)) => {
+                this.can_migrate_from_element(other).map_err(|e| {
+                    MigrationCompatibilityError::ElementMismatch(
+                        "qemu_pvpanic".to_string(),
+                        e,
+                    )
+                })?
+            }
+            (this, other) => {
+                let kind = |dev: &Option<components::devices::QemuPvpanic>| {
+                    dev.as_ref().map_or("None", |dev| dev.kind())
+                };
+                return Err(MigrationCompatibilityError::ElementMismatch(
+                    "qemu_pvpanic".to_string(),
+                    ElementCompatibilityError::ComponentsIncomparable(
+                        kind(this),
+                        kind(other),
+                    ),
+                ));
+            }
+        }
+
+        match (&self.bochs_display, &other.bochs_display) {
+            (None, None) => {}
+            (Some(this), Some(other)) => {
+                this.can_migrate_from_element(other).map_err(|e| {
+                    MigrationCompatibilityError::ElementMismatch(
+                        "bochs_display".to_string(),
+                        e,
+                    )
+                })?
+            }
+            (this, other) => {
+                let kind = |dev: &Option<components::devices::BochsDisplay>| {
+                    dev.as_ref().map_or("None", |dev| dev.kind())
+                };
+                return Err(MigrationCompatibilityError::ElementMismatch(
+                    "bochs_display".to_string(),
+                    ElementCompatibilityError::ComponentsIncomparable(
+                        kind(this),
+                        kind(other),
+                    ),
+                ));
+            }
+        }
+
+        match (&self.vsock, &other.vsock) {
+            (None, None) => {}
+            (Some(this), Some(other)) => {
+                this.can_migrate_from_element(other).map_err(|e| {
+                    MigrationCompatibilityError::ElementMismatch(
+                        "vsock".to_string(),
+                        e,
+                    )
+                })?
+            }
+            (this, other) => {
+                let kind = |dev: &Option<components::devices::VirtioVsock>| {
+                    dev.as_ref().map_or("None", |dev| dev.kind())
+                };
+                return Err(MigrationCompatibilityError::ElementMismatch(
+                    "vsock".to_string(),
+                    ElementCompatibilityError::ComponentsIncomparable(
+                        kind(this),
+                        kind(other),
+                    ),
+                ));
+            }
+        }
+
         Ok(())
     }
 }
@@ -179,6 +347,7 @@ pub enum StorageBackendV0 {
     Crucible(components::backends::CrucibleStorageBackend),
     File(components::backends::FileStorageBackend),
     Blob(components::backends::BlobStorageBackend),
+    Null(components::backends::NullStorageBackend),
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
@@ -186,6 +355,7 @@ pub enum StorageBackendV0 {
 pub enum NetworkBackendV0 {
     Virtio(components::backends::VirtioNetworkBackend),
     Dlpi(components::backends::DlpiNetworkBackend),
+    Null(components::backends::NullNetworkBackend),
 }
 
 #[derive(Default, Clone, Deserialize, Serialize, Debug, JsonSchema)]
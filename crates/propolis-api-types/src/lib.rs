@@ -4,7 +4,9 @@
 
 //! Definitions for types exposed by the propolis-server API
 
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::num::NonZeroU64;
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -65,6 +67,25 @@ pub struct InstanceMigrateInitiateRequest {
     pub migration_id: Uuid,
     pub src_addr: SocketAddr,
     pub src_uuid: Uuid,
+    /// Compression applied to guest RAM sent by the source.  Pages are sent
+    /// uncompressed if absent.
+    #[serde(default)]
+    pub compression: Option<MigrationCompression>,
+    /// Cap on the rate at which the source sends migration data, in bytes per
+    /// second.  Unlimited if absent.
+    #[serde(default)]
+    pub bandwidth_limit: Option<NonZeroU64>,
+}
+
+/// Algorithm used to compress guest RAM pages during migration.
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
+)]
+pub enum MigrationCompression {
+    /// Fast compression, at a modest ratio.
+    Lz4,
+    /// Better compression, at a greater CPU cost.
+    Zstd,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
@@ -135,6 +156,30 @@ pub struct InstanceStateMonitorResponse {
     pub gen: u64,
     pub state: InstanceState,
     pub migration: Option<InstanceMigrateStatusResponse>,
+    /// The most recent panic reported by the guest since the instance last
+    /// started or rebooted, if any.
+    #[serde(default)]
+    pub guest_panic: Option<GuestPanic>,
+}
+
+/// A kind of panic reported by the guest through its pvpanic device.
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
+)]
+pub enum GuestPanicKind {
+    /// The guest kernel panicked.
+    Panicked,
+    /// The guest kernel panicked and loaded a crash kernel to capture a dump.
+    CrashLoaded,
+}
+
+/// A panic reported by the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestPanic {
+    pub kind: GuestPanicKind,
+    /// Time at which the panic was reported, in nanoseconds since the UNIX
+    /// epoch.
+    pub time_ns: u64,
 }
 
 /// Requested state of an Instance.
@@ -184,6 +229,11 @@ pub struct InstanceProperties {
     pub memory: u64,
     /// Number of vCPUs to be allocated to the Instance.
     pub vcpus: u8,
+    /// Arbitrary key/value pairs with which operators can correlate the
+    /// Instance with external systems.  These have no effect on the guest,
+    /// and may be updated while the Instance runs.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
@@ -193,6 +243,23 @@ pub struct Instance {
 
     pub disks: Vec<DiskAttachment>,
     pub nics: Vec<NetworkInterface>,
+
+    /// The bootrom selected from the server's configured candidates, if the
+    /// instance has been initialized.
+    #[serde(default)]
+    pub bootrom: Option<BootromInfo>,
+}
+
+/// A bootrom used to initialize an instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+pub struct BootromInfo {
+    /// Path of the bootrom on the server's host.
+    pub path: String,
+    /// SHA-256 digest of the bootrom's contents, as a hex string.
+    pub sha256: String,
+    /// Version of the firmware, if supplied as a bundle which specifies it.
+    #[serde(default)]
+    pub version: Option<String>,
 }
 
 /// Request a specific range of an Instance's serial console output history.
@@ -380,3 +447,666 @@ pub struct SnapshotRequestPathParams {
 pub struct VCRRequestPathParams {
     pub id: Uuid,
 }
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DiskPrefetchPathParams {
+    pub id: Uuid,
+}
+
+/// Disposition of the background prefetch of a disk.
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
+)]
+pub enum DiskPrefetchState {
+    /// The prefetch is waiting for the instance to finish booting.
+    Pending,
+    Running,
+    /// The part of the disk covered by the prefetch has been read.
+    Completed,
+    Canceled,
+    /// A read from the disk's storage failed, ending the prefetch.
+    Failed,
+}
+
+/// Progress of the background prefetch of a disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskPrefetchStatus {
+    pub state: DiskPrefetchState,
+    /// Bytes of the disk read so far.
+    pub bytes_done: u64,
+    /// Bytes of the disk to be read in all.
+    pub bytes_total: u64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DiskHealthPathParams {
+    pub id: Uuid,
+}
+
+/// Health of the storage behind a disk, as observed by retrying its requests.
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
+)]
+pub enum DiskHealthState {
+    /// Requests are completing normally.
+    Healthy,
+    /// Recent requests have failed, and are being retried.
+    Degraded,
+    /// An outstanding request has not completed within the stall timeout.
+    Stalled,
+    /// Retries have been exhausted for at least one request.
+    Failed,
+}
+
+/// Health of the storage behind a disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskHealthStatus {
+    pub state: DiskHealthState,
+    /// Number of consecutive attempts which have failed or stalled.
+    pub consecutive_failures: u32,
+    /// Milliseconds since a request to the storage last succeeded, if one
+    /// has.
+    pub ms_since_success: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct NicRemovePathParams {
+    pub name: String,
+}
+
+/// Request to remove a network device from a running instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NicRemoveRequest {
+    /// Time to wait for the guest to release the device before it is removed
+    /// regardless.  Defaults to 10 seconds.
+    pub eject_timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NicRemoveResponse {
+    /// Whether the guest released the device prior to its removal.
+    pub guest_ejected: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct NicPathParams {
+    pub name: String,
+}
+
+/// Request to add a network device to a running instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NicAttachRequest {
+    /// The device to add, which must occupy an empty slot on bus 0.
+    pub device: instance_spec::v0::NetworkDeviceV0,
+    /// The backend named by the device.
+    pub backend: instance_spec::v0::NetworkBackendV0,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DiskPathParams {
+    pub name: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct VsockPathParams {
+    pub port: u32,
+}
+
+/// Options for exporting the contents of a disk.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct DiskExportRequest {
+    /// Export only the ranges of the disk changed since this checkpoint, which
+    /// must be the most recent one taken of the disk.
+    pub since: Option<u64>,
+    /// Whether to take a new checkpoint of the disk as the export begins,
+    /// which becomes the base of the next incremental export once this export
+    /// has been sent in full.
+    #[serde(default)]
+    pub checkpoint: bool,
+    /// Time, in seconds, within which the export must be sent in full, after
+    /// which it is abandoned and the instance resumes.  Whether or not this is
+    /// given, the server abandons an export that holds the instance paused for
+    /// ten minutes, or that the client stops receiving, and a request to stop
+    /// the instance ends the export as well.
+    pub deadline_secs: Option<u64>,
+}
+
+/// Request to add a disk to a running instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskAttachRequest {
+    /// The device to add, which must occupy an empty slot on bus 0.
+    pub device: instance_spec::v0::StorageDeviceV0,
+    /// The backend named by the device.
+    pub backend: instance_spec::v0::StorageBackendV0,
+}
+
+/// Request to remove a disk from a running instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskRemoveRequest {
+    /// Time to wait for the guest to release the disk before it is removed
+    /// regardless.  Defaults to 10 seconds.
+    pub eject_timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskRemoveResponse {
+    /// Whether the guest released the disk prior to its removal.
+    pub guest_ejected: bool,
+}
+
+/// Request to change the write-protect state of a disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskWriteProtectRequest {
+    /// Whether the guest should be prevented from writing to the disk.
+    pub write_protected: bool,
+}
+
+/// Request to change the I/O priority class of a disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskPriorityRequest {
+    pub priority: instance_spec::components::devices::DiskPriority,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct DevicePathParams {
+    pub name: String,
+}
+
+/// Request to enable or disable a device at the instance's next reboot.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DeviceEnabledRequest {
+    /// Whether the device should be visible to the guest.
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct VcpuRemovePathParams {
+    pub id: u8,
+}
+
+/// Request to remove a vCPU from a running instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct VcpuRemoveRequest {
+    /// Time to wait for the guest to offline and eject the vCPU before the
+    /// request is abandoned.  Defaults to 10 seconds.
+    pub eject_timeout_ms: Option<u64>,
+}
+
+/// The disruption of which a maintenance notice warns the guest.
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
+)]
+pub enum MaintenanceKind {
+    /// The instance will be stopped.
+    Shutdown,
+    /// The instance will be migrated to another host, pausing it briefly.
+    Migration,
+}
+
+/// Request to notify the guest of impending maintenance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct MaintenanceNoticeRequest {
+    pub kind: MaintenanceKind,
+    /// Time from now until the maintenance is due, by which the guest should
+    /// have drained its work.
+    pub deadline_secs: u32,
+}
+
+/// A maintenance notice posted to the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct MaintenanceNotice {
+    pub kind: MaintenanceKind,
+    /// Generation of the notice as seen by the guest, which changes each time
+    /// a notice is posted or withdrawn.
+    pub generation: u32,
+    /// Time remaining until the deadline, or 0 if it has passed.
+    pub remaining_ms: u64,
+    /// Whether the guest has acknowledged the notice.
+    pub guest_acknowledged: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct MaintenanceStatus {
+    /// The notice currently posted to the guest, if any.
+    pub notice: Option<MaintenanceNotice>,
+}
+
+/// The limit on the fraction of time an instance's vCPUs may run, used to hold
+/// the instance within a power or thermal budget.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceDutyCycle {
+    /// Percentage of time, from 1 to 100, for which the vCPUs may run.  They
+    /// sleep for the remainder, which is reported to the guest as steal time
+    /// if the instance's board enables it.
+    pub limit_percent: u8,
+}
+
+/// Severity of the memory pressure on an instance's host.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    JsonSchema,
+)]
+pub enum MemoryPressureLevel {
+    /// The host has memory to spare.
+    #[default]
+    None,
+    /// The host is running short of memory.
+    Moderate,
+    /// The host is about to page out guest memory.
+    Severe,
+}
+
+/// A report of the memory pressure on an instance's host.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceMemoryPressureRequest {
+    pub level: MemoryPressureLevel,
+}
+
+/// The instance's response to memory pressure on its host, which is layered
+/// over any duty-cycle limit set through the API.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct MemoryPressureResponse {
+    /// Percentage of time for which the vCPUs may run.
+    pub duty_cycle_limit_percent: u8,
+}
+
+/// A change in the memory pressure on an instance's host, or in the
+/// instance's response to it.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct MemoryPressureEvent {
+    /// Time of the change, in nanoseconds since the UNIX epoch.
+    pub time_ns: u64,
+    /// The level of pressure at the time.
+    pub level: MemoryPressureLevel,
+    pub response: MemoryPressureResponse,
+}
+
+/// The memory pressure on an instance's host, and its response to it.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceMemoryPressureStatus {
+    /// The level of pressure: the more severe of that observed on the host
+    /// and that most recently reported through the API.
+    pub level: MemoryPressureLevel,
+    /// Percentage of the host's physical memory which was free when last
+    /// sampled, if it could be sampled.
+    pub host_free_percent: Option<u8>,
+    pub response: MemoryPressureResponse,
+    /// The most recent changes in pressure or response, oldest first.
+    pub events: Vec<MemoryPressureEvent>,
+}
+
+/// A key pressed or released on an instance's keyboard: its virtio-input
+/// keyboard if it has one, and its PS/2 keyboard otherwise.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct KeyInput {
+    /// The key, identified by its X11 keysym (as in the VNC protocol). Shifted
+    /// characters are typed by pressing Shift along with the key.
+    pub keysym: u32,
+    pub pressed: bool,
+}
+
+/// Movement of an instance's PS/2 mouse, and the buttons held on it.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct PointerInput {
+    /// Horizontal movement, positive to the right.
+    #[serde(default)]
+    pub dx: i32,
+    /// Vertical movement, positive downward.
+    #[serde(default)]
+    pub dy: i32,
+    #[serde(default)]
+    pub left: bool,
+    #[serde(default)]
+    pub right: bool,
+    #[serde(default)]
+    pub middle: bool,
+}
+
+/// The position of the pointer of an instance's virtio-input tablet, and the
+/// buttons held on it.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct TabletInput {
+    /// Horizontal position, from 0 at the left edge of the screen to 32767 at
+    /// the right.
+    pub x: u32,
+    /// Vertical position, from 0 at the top edge of the screen to 32767 at
+    /// the bottom.
+    pub y: u32,
+    #[serde(default)]
+    pub left: bool,
+    #[serde(default)]
+    pub right: bool,
+    #[serde(default)]
+    pub middle: bool,
+}
+
+/// An input event to be injected into an instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub enum InputEvent {
+    Key(KeyInput),
+    Pointer(PointerInput),
+    Tablet(TabletInput),
+}
+
+/// Input to be injected into an instance's keyboard and pointing devices.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceInputRequest {
+    /// Events to be injected, in order.
+    pub events: Vec<InputEvent>,
+}
+
+/// A request to change the amount of hot-pluggable memory in use by an
+/// instance's guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceHotplugMemoryRequest {
+    /// Amount of hot-pluggable memory, in bytes, the guest is asked to use.
+    /// Must be a multiple of 2 MiB, and no more than the size of the region.
+    pub requested_bytes: u64,
+}
+
+/// The state of an instance's hot-pluggable memory.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceHotplugMemoryStatus {
+    /// Size, in bytes, of the region of memory which may be hot-plugged.
+    pub region_bytes: u64,
+    /// Amount of memory, in bytes, the guest is asked to use.
+    pub requested_bytes: u64,
+    /// Amount of memory, in bytes, the guest has plugged for its use.
+    pub plugged_bytes: u64,
+}
+
+/// A request to save a snapshot of an instance's state to a file.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSnapshotRequest {
+    /// Name of the file, within the server's snapshot directory, to which the
+    /// snapshot is written. The file must not already exist.
+    pub path: String,
+    /// Whether to stop the instance once its snapshot has been saved.
+    #[serde(default)]
+    pub stop: bool,
+}
+
+/// A request to initialize an instance from a previously saved snapshot.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSnapshotRestoreRequest {
+    /// Name of the snapshot file within the server's snapshot directory.
+    pub path: String,
+}
+
+/// Key/value metadata attached to an instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceMetadata {
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Severity threshold applied to the server's log output.  Release builds of
+/// the server omit trace-level messages, so `Trace` takes effect as `Debug` in
+/// them.
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
+)]
+pub enum LogLevel {
+    Critical,
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// A category of high-volume diagnostic probes which can be toggled at runtime.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    JsonSchema,
+)]
+pub enum TraceCategory {
+    /// Port IO dispatch
+    Pio,
+    /// MMIO dispatch
+    Mmio,
+    /// vCPU entry and exit
+    VmExit,
+    /// Block request submission and completion
+    Block,
+}
+
+/// Current runtime debugging settings of the server.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DebugSettings {
+    /// Minimum severity of messages emitted to the server log.
+    pub log_level: LogLevel,
+    /// Probe categories which currently fire for an attached consumer.
+    pub trace_categories: Vec<TraceCategory>,
+    /// Whether the latencies of vCPU exits are being collected.
+    pub exit_stats: bool,
+}
+
+/// Request to alter the runtime debugging settings of the server.  Fields which
+/// are not provided are left unchanged.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DebugSettingsUpdate {
+    pub log_level: Option<LogLevel>,
+    pub trace_categories: Option<Vec<TraceCategory>>,
+    pub exit_stats: Option<bool>,
+}
+
+/// Distribution of the time spent handling one kind of vCPU exit.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ExitLatencyHistogram {
+    pub vcpu: i32,
+    /// Kind of exit, such as `pio_in` or `mmio_write`.
+    pub cause: String,
+    /// Bus region of the device which handled the exit.
+    pub device: String,
+    /// Number of exits handled.
+    pub count: u64,
+    /// Total time spent handling the exits, in nanoseconds.
+    pub total_ns: u64,
+    /// Counts of exits whose handling took `[2^n, 2^(n+1))` nanoseconds, for
+    /// each bucket `n`.
+    pub buckets: Vec<u64>,
+}
+
+/// Time spent by one vCPU in the kernel without running.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct HaltResidency {
+    pub vcpu: i32,
+    /// Total time, in nanoseconds, the vCPU spent in the kernel without
+    /// running: halted after executing a HLT instruction, or waiting for a
+    /// host CPU.  The two are not told apart.
+    pub blocked_ns: u64,
+}
+
+/// Latencies of the vCPU exits handled by the server.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ExitLatencyResponse {
+    pub histograms: Vec<ExitLatencyHistogram>,
+    /// The time spent handling exits, in the folded stack format consumed by
+    /// flamegraph tooling.
+    pub folded: String,
+    /// Halt residency of each vCPU, which is collected only while exit
+    /// latencies are.
+    pub halts: Vec<HaltResidency>,
+}
+
+/// Host resources held by a single device or backend.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct HostResourceUsage {
+    /// Name identifying the holder of the resources.
+    pub owner: String,
+    /// Open file descriptors.
+    pub fds: u64,
+    /// Bytes of host memory mapped.
+    pub mapped_bytes: u64,
+    /// OS threads.
+    pub threads: u64,
+    /// Async tasks.
+    pub tasks: u64,
+    /// Links to the in-kernel viona driver.
+    pub viona_links: u64,
+}
+
+/// Host resources held by each living device and backend in the server.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct HostResourcesResponse {
+    pub owners: Vec<HostResourceUsage>,
+}
+
+/// The configuration space of a single PCI function.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct PciConfigSpace {
+    /// Location of the function.  Bus numbers are logical (as assigned in the
+    /// instance spec) rather than those programmed by the guest into bridges.
+    pub pci_path: instance_spec::PciPath,
+    /// Contents of the configuration space, as a hex dump of 16 bytes per line
+    /// in the style of `lspci -xxx` (e.g. `00: 86 80 37 12 ...`).
+    pub data: String,
+}
+
+/// The configuration space of every PCI function in the instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct PciConfigResponse {
+    pub functions: Vec<PciConfigSpace>,
+}
+
+/// The clock from which devices emulated in userspace tell time.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DebugClock {
+    /// Current wall-clock time on the clock, in nanoseconds since the UNIX
+    /// epoch.
+    pub time_ns: u64,
+    /// Rate at which the clock runs, as a percentage of the host's.
+    pub rate_percent: u32,
+    /// Whether the clock has been warped away from the host's.
+    pub warped: bool,
+}
+
+/// Request to warp the clock from which devices emulated in userspace tell
+/// time.  The rate, if provided, is applied before the step.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DebugClockRequest {
+    /// Rate at which the clock is to run, as a percentage of the host's.  A
+    /// rate of 0 freezes the clock.
+    pub rate_percent: Option<u32>,
+    /// Milliseconds by which to step the clock forward.
+    pub step_ms: Option<u64>,
+}
+
+/// A POST code written by the guest's firmware.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct PostCodeEntry {
+    /// IO port to which the code was written: 0x80, or 0xe9 if capture of
+    /// that port is enabled in the server's configuration.
+    pub port: u16,
+    pub code: u8,
+    /// Time at which the code was written, in nanoseconds since the UNIX
+    /// epoch.
+    pub time_ns: u64,
+}
+
+/// The most recent POST codes written by the guest's firmware.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct PostCodesResponse {
+    /// Retained codes, oldest first.
+    pub codes: Vec<PostCodeEntry>,
+    /// Number of codes written since the instance was created, including those
+    /// which are no longer retained.
+    pub total: u64,
+}
+
+/// The sign by which the guest was judged to have booted.
+#[derive(
+    Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
+)]
+pub enum BootSignal {
+    /// The guest completed a read from one of its disks.
+    DiskRead,
+    /// The guest wrote the configured pattern to its serial console.
+    ConsolePattern,
+}
+
+/// Architectural state of a vCPU, read when boot diagnostics were captured.
+/// Registers which could not be read are omitted.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct VcpuBootDiagnostics {
+    pub id: i32,
+    pub rip: Option<u64>,
+    pub rflags: Option<u64>,
+    pub cr0: Option<u64>,
+    /// Code segment selector
+    pub cs: Option<u64>,
+}
+
+/// A request recently completed by a disk.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskIoRecord {
+    /// Kind of request: "read", "write", "flush", "discard" or
+    /// "write-zeroes".
+    pub op: String,
+    /// Outcome of the request, e.g. "Success" or "Failure".
+    pub result: String,
+    /// Time taken by the backend to process the request.
+    pub latency_ns: u64,
+    /// Time between the completion of the request and the capture of the
+    /// diagnostics.
+    pub age_ns: u64,
+}
+
+/// Recent I/O activity of one of the instance's disks.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct DiskBootDiagnostics {
+    /// Name of the disk in the instance spec.
+    pub name: String,
+    pub reads_completed: u64,
+    /// Requests outstanding to the backend.
+    pub outstanding: u64,
+    /// The most recently completed requests, oldest first.
+    pub recent: Vec<DiskIoRecord>,
+}
+
+/// State captured when the guest failed to boot within the configured
+/// deadline.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct BootDiagnostics {
+    /// Time of capture, in nanoseconds since the UNIX epoch.
+    pub time_ns: u64,
+    /// The most recent POST codes written by the guest's firmware, oldest
+    /// first.
+    pub post_codes: Vec<PostCodeEntry>,
+    pub vcpus: Vec<VcpuBootDiagnostics>,
+    pub disks: Vec<DiskBootDiagnostics>,
+    /// The tail of the guest's serial console output.
+    pub console_tail: String,
+}
+
+/// Progress of the guest through boot, as judged by the boot watchdog.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceBootStatus {
+    /// Whether the server is configured with a boot watchdog.  If not, the
+    /// remaining fields are unset.
+    pub watchdog_enabled: bool,
+    /// How the guest was seen to have booted, if it has.
+    pub booted: Option<BootSignal>,
+    /// Set if the guest did not boot within the deadline.  This remains set
+    /// even if the guest boots later.
+    pub degraded: bool,
+    /// State captured upon the deadline expiring.
+    pub diagnostics: Option<BootDiagnostics>,
+}
@@ -18,6 +18,16 @@ pub use cpuid_profile_config::CpuidProfile;
 pub struct Config {
     pub bootrom: PathBuf,
 
+    /// Expected SHA-256 digest of `bootrom`, as a hex string.  If absent, the
+    /// bootrom's contents are not checked.
+    #[serde(default)]
+    pub bootrom_sha256: Option<String>,
+
+    /// Bootroms to try, in order, if `bootrom` cannot be opened or fails
+    /// validation.
+    #[serde(default, rename = "bootrom_fallback")]
+    pub bootrom_fallbacks: Vec<BootromCandidate>,
+
     #[serde(default, rename = "pci_bridge")]
     pub pci_bridges: Vec<PciBridge>,
 
@@ -32,20 +42,307 @@ pub struct Config {
 
     #[serde(default, rename = "cpuid")]
     pub cpuid_profiles: BTreeMap<String, CpuidProfile>,
+
+    /// If present, the server hardens itself once its instance's devices have
+    /// been initialized.
+    #[serde(default)]
+    pub harden: Option<Harden>,
+
+    /// The QEMU-style debug console port, to which firmware such as debug
+    /// builds of OVMF writes its diagnostic output.
+    #[serde(default)]
+    pub debug_port: DebugPort,
+
+    /// Capture of POST codes written by the guest's firmware.
+    #[serde(default)]
+    pub post_codes: PostCodes,
+
+    /// If present, a VM is created ahead of time, to be taken over by an
+    /// instance of the same shape.
+    #[serde(default)]
+    pub standby: Option<Standby>,
+
+    /// If present, the guest is expected to show signs of having booted
+    /// within a deadline, and diagnostics are captured if it does not.
+    #[serde(default)]
+    pub boot_watchdog: Option<BootWatchdog>,
+
+    /// If present, writes to Crucible volumes are journaled locally until
+    /// flushed, and replayed after an outage of the volume's downstairs.
+    #[serde(default)]
+    pub crucible_journal: Option<CrucibleJournal>,
+
+    /// If present, SMBIOS tables describing the instance are provided to the
+    /// guest's firmware.
+    #[serde(default)]
+    pub smbios: Option<Smbios>,
+
+    /// If present, the instance responds to memory pressure on its host by
+    /// reporting it and, should it persist or be severe, throttling the guest.
+    #[serde(default)]
+    pub memory_pressure: Option<MemoryPressure>,
+
+    /// Whether the clock from which devices emulated in userspace tell time
+    /// can be warped through the server's debug API.  For testing only.
+    #[serde(default)]
+    pub warpable_clock: bool,
+
+    /// Whether the flushes completed to the guest by each disk's backend are
+    /// checked against the writes the backend has made durable, with any
+    /// violations logged.  For validating backends only.
+    #[serde(default)]
+    pub audit_block_flushes: bool,
+
+    /// Directory holding the files which back shared memory devices, whose
+    /// specs name files within it.  Shared memory devices are refused if it
+    /// is absent.
+    #[serde(default)]
+    pub shared_memory_dir: Option<PathBuf>,
+
+    /// Directory within which instance snapshots are saved and from which
+    /// they are restored.  Snapshot requests name files within it, and are
+    /// refused if it is absent.
+    #[serde(default)]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Directory holding the variable stores of instances' bootroms, each in
+    /// a file named for its instance, so that guests' firmware variables
+    /// persist from one incarnation of an instance to the next.  If absent,
+    /// an instance's variable store starts from the bootrom's template.
+    #[serde(default)]
+    pub nvram_dir: Option<PathBuf>,
 }
 impl Default for Config {
     fn default() -> Self {
         Self {
             bootrom: PathBuf::new(),
+            bootrom_sha256: None,
+            bootrom_fallbacks: Vec::new(),
             pci_bridges: Vec::new(),
             chipset: Chipset { options: BTreeMap::new() },
             devices: BTreeMap::new(),
             block_devs: BTreeMap::new(),
             cpuid_profiles: BTreeMap::new(),
+            harden: None,
+            debug_port: DebugPort::default(),
+            post_codes: PostCodes::default(),
+            standby: None,
+            boot_watchdog: None,
+            crucible_journal: None,
+            smbios: None,
+            memory_pressure: None,
+            warpable_clock: false,
+            audit_block_flushes: false,
+            shared_memory_dir: None,
+            snapshot_dir: None,
+            nvram_dir: None,
+        }
+    }
+}
+
+impl Config {
+    /// All configured bootroms in the order in which they should be tried:
+    /// the primary `bootrom` followed by any fallbacks.
+    pub fn bootrom_candidates(&self) -> Vec<BootromCandidate> {
+        std::iter::once(BootromCandidate {
+            path: self.bootrom.clone(),
+            sha256: self.bootrom_sha256.clone(),
+        })
+        .chain(self.bootrom_fallbacks.iter().cloned())
+        .collect()
+    }
+}
+
+/// A bootrom which may be used to initialize an instance.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct BootromCandidate {
+    pub path: PathBuf,
+
+    /// Expected SHA-256 digest of the bootrom, as a hex string.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Process hardening applied after instance initialization.
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Harden {
+    /// Directory to which the server's view of the filesystem is restricted.
+    /// Defaults to the deepest directory containing all of the paths which
+    /// the server opens while its instance runs, or an empty directory if
+    /// there are none.  It may not be `/`.
+    pub root: Option<PathBuf>,
+}
+
+/// Shape of the VM which the server creates (with its memory allocated and
+/// bootrom loaded) while it awaits an instance.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Standby {
+    pub cpus: u8,
+    pub memory_mb: u64,
+}
+
+/// Deadline for the guest's progress through boot, and the signals taken to
+/// indicate that it has booted.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct BootWatchdog {
+    /// Seconds from the instance's start within which the guest must boot.
+    pub timeout_secs: u64,
+
+    /// Whether the guest's first read from any of its disks indicates that it
+    /// has booted.
+    #[serde(default = "BootWatchdog::default_disk_read")]
+    pub disk_read: bool,
+
+    /// Text which, once written by the guest to its serial console, indicates
+    /// that it has booted.
+    #[serde(default)]
+    pub console_pattern: Option<String>,
+}
+impl BootWatchdog {
+    fn default_disk_read() -> bool {
+        true
+    }
+}
+
+/// Local journaling of writes to Crucible volumes.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct CrucibleJournal {
+    /// Directory in which the journal of each writable volume is kept.
+    pub dir: PathBuf,
+
+    /// Size in bytes at which a journal is emptied by flushing its volume.
+    #[serde(default = "CrucibleJournal::default_max_bytes")]
+    pub max_bytes: u64,
+
+    /// Seconds for which the downstairs may be unreachable before requests
+    /// waiting on them are failed to the guest.
+    #[serde(default = "CrucibleJournal::default_max_outage_secs")]
+    pub max_outage_secs: u64,
+}
+impl CrucibleJournal {
+    fn default_max_bytes() -> u64 {
+        64 * 1024 * 1024
+    }
+    fn default_max_outage_secs() -> u64 {
+        300
+    }
+}
+
+/// Generation of SMBIOS tables for the guest.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Smbios {
+    /// Asset fields of the host (such as "chassis-serial") to pass through
+    /// to the guest.  None are passed through by default.
+    #[serde(default)]
+    pub host_fields: Vec<String>,
+
+    /// Image of the host's SMBIOS tables from which `host_fields` are read.
+    #[serde(default = "Smbios::default_host_path")]
+    pub host_path: PathBuf,
+}
+impl Smbios {
+    fn default_host_path() -> PathBuf {
+        PathBuf::from("/dev/smbios")
+    }
+}
+
+/// Response of the instance to memory pressure on its host.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct MemoryPressure {
+    /// Pressure is judged moderate while less than this percentage of the
+    /// host's physical memory is free.
+    #[serde(default = "MemoryPressure::default_moderate_free_percent")]
+    pub moderate_free_percent: u8,
+
+    /// Pressure is judged severe while less than this percentage of the
+    /// host's physical memory is free.
+    #[serde(default = "MemoryPressure::default_severe_free_percent")]
+    pub severe_free_percent: u8,
+
+    /// Percentage of time for which the vCPUs may run while the instance is
+    /// throttled: under severe pressure, or under moderate pressure which has
+    /// persisted for `grace_secs`.
+    #[serde(default = "MemoryPressure::default_throttle_percent")]
+    pub throttle_percent: u8,
+
+    /// Seconds for which moderate pressure is only reported, before the
+    /// instance is throttled.
+    #[serde(default = "MemoryPressure::default_grace_secs")]
+    pub grace_secs: u64,
+}
+impl MemoryPressure {
+    fn default_moderate_free_percent() -> u8 {
+        10
+    }
+    fn default_severe_free_percent() -> u8 {
+        4
+    }
+    fn default_throttle_percent() -> u8 {
+        50
+    }
+    fn default_grace_secs() -> u64 {
+        30
+    }
+}
+
+/// The QEMU-style debug console ("isa-debugcon") port.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct DebugPort {
+    /// The I/O port at which the device is located: 0x402 (as used by OVMF)
+    /// by default, or 0xe9 as used by Bochs and SeaBIOS.
+    #[serde(default = "DebugPort::default_port")]
+    pub port: u16,
+
+    /// Where output written to the port is sent.
+    #[serde(default)]
+    pub sink: DebugPortSink,
+
+    /// For the `file` sink, the path of the file to which output is written.
+    /// Defaults to `debug.out` in the server's working directory.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+impl DebugPort {
+    pub const DEFAULT_PATH: &'static str = "debug.out";
+
+    fn default_port() -> u16 {
+        0x402
+    }
+}
+impl Default for DebugPort {
+    fn default() -> Self {
+        Self {
+            port: Self::default_port(),
+            sink: DebugPortSink::default(),
+            path: None,
         }
     }
 }
 
+/// Destinations for output written to the debug console port.
+#[derive(
+    Clone, Copy, Default, Serialize, Deserialize, Debug, PartialEq, Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugPortSink {
+    /// Write output to a file, truncated when the instance is created.
+    #[default]
+    File,
+    /// Emit output to the server's log, a line at a time.
+    Log,
+    /// Discard output.  The port remains present to the guest.
+    Discard,
+}
+
+/// Capture of POST codes, which are always recorded from port 0x80.
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct PostCodes {
+    /// Also record codes written to port 0xe9, as some firmware does.  This
+    /// cannot be combined with a debug console port at the same location.
+    #[serde(default)]
+    pub capture_alt_port: bool,
+}
+
 /// The instance's chipset.
 #[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Chipset {
@@ -211,5 +508,176 @@ path = "/etc/passwd"
             bdev1.options.get("path").map(Value::as_str).unwrap(),
             Some("/etc/passwd")
         );
+
+        assert_eq!(cfg.debug_port, DebugPort::default());
+        assert!(!cfg.post_codes.capture_alt_port);
+    }
+
+    #[test]
+    fn parse_bootrom_fallbacks() {
+        let raw = r#"
+bootrom = "/path/to/bootrom-a"
+bootrom_sha256 = "aabbcc"
+
+[[bootrom_fallback]]
+path = "/path/to/bootrom-b"
+sha256 = "ddeeff"
+
+[[bootrom_fallback]]
+path = "/path/to/bootrom-c"
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        let candidates = cfg.bootrom_candidates();
+        assert_eq!(
+            candidates,
+            vec![
+                BootromCandidate {
+                    path: "/path/to/bootrom-a".into(),
+                    sha256: Some("aabbcc".to_string()),
+                },
+                BootromCandidate {
+                    path: "/path/to/bootrom-b".into(),
+                    sha256: Some("ddeeff".to_string()),
+                },
+                BootromCandidate {
+                    path: "/path/to/bootrom-c".into(),
+                    sha256: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_debug_port() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+[debug_port]
+port = 0xe9
+sink = "log"
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(cfg.debug_port.port, 0xe9);
+        assert_eq!(cfg.debug_port.sink, DebugPortSink::Log);
+        assert_eq!(cfg.debug_port.path, None);
+    }
+
+    #[test]
+    fn parse_standby() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+[standby]
+cpus = 4
+memory_mb = 4096
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(cfg.standby, Some(Standby { cpus: 4, memory_mb: 4096 }));
+
+        let cfg: Config = toml::de::from_str("bootrom = \"/boot\"").unwrap();
+        assert_eq!(cfg.standby, None);
+    }
+
+    #[test]
+    fn parse_boot_watchdog() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+[boot_watchdog]
+timeout_secs = 120
+console_pattern = "login:"
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(
+            cfg.boot_watchdog,
+            Some(BootWatchdog {
+                timeout_secs: 120,
+                disk_read: true,
+                console_pattern: Some("login:".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_memory_pressure() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+[memory_pressure]
+throttle_percent = 40
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(
+            cfg.memory_pressure,
+            Some(MemoryPressure {
+                moderate_free_percent: 10,
+                severe_free_percent: 4,
+                throttle_percent: 40,
+                grace_secs: 30,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_warpable_clock() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+warpable_clock = true
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert!(cfg.warpable_clock);
+
+        let raw = r#"bootrom = "/path/to/bootrom""#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert!(!cfg.warpable_clock);
+    }
+
+    #[test]
+    fn parse_audit_block_flushes() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+audit_block_flushes = true
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert!(cfg.audit_block_flushes);
+
+        let raw = r#"bootrom = "/path/to/bootrom""#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert!(!cfg.audit_block_flushes);
+    }
+
+    #[test]
+    fn parse_crucible_journal() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+[crucible_journal]
+dir = "/var/tmp/journal"
+max_bytes = 1048576
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(
+            cfg.crucible_journal,
+            Some(CrucibleJournal {
+                dir: PathBuf::from("/var/tmp/journal"),
+                max_bytes: 1048576,
+                max_outage_secs: 300,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_smbios() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+[smbios]
+host_fields = ["chassis-serial", "system-serial"]
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(
+            cfg.smbios,
+            Some(Smbios {
+                host_fields: vec![
+                    "chassis-serial".to_string(),
+                    "system-serial".to_string()
+                ],
+                host_path: PathBuf::from("/dev/smbios"),
+            })
+        );
     }
 }
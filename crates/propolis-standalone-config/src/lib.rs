@@ -33,6 +33,10 @@ pub struct Config {
     pub cpuid_profiles: BTreeMap<String, CpuidProfile>,
 
     pub cloudinit: Option<CloudInit>,
+
+    /// If present, the process hardens itself once the instance has been
+    /// set up.
+    pub harden: Option<Harden>,
 }
 impl Config {
     pub fn cpuid_profile(&self) -> Option<&CpuidProfile> {
@@ -48,6 +52,15 @@ pub struct Main {
     pub name: String,
     pub cpus: u8,
     pub bootrom: String,
+    /// File holding the bootrom's variable store (such as a copy of
+    /// `OVMF_VARS.fd`), which is emulated as CFI flash directly below the
+    /// bootrom, itself then holding only the firmware's code.  Changes made by
+    /// the guest are written back to the file when the instance resets or
+    /// halts.
+    ///
+    /// Default: None, the bootrom has no writable variable store
+    #[serde(default)]
+    pub nvram: Option<String>,
     pub memory: usize,
     pub use_reservoir: Option<bool>,
     pub cpuid_profile: Option<String>,
@@ -61,6 +74,88 @@ pub struct Main {
     /// Default: None, does not exit on reboot
     #[serde(default)]
     pub exit_on_reboot: Option<u8>,
+    /// Report host scheduling delays of vCPUs to the guest as steal time.
+    /// Requires a `cpuid_profile`, through which the feature is advertised.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub steal_time: bool,
+    /// Have vCPUs spinning on the PAUSE instruction exit, so that a sibling
+    /// vCPU may have their host CPU when the host is over-committed.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub pause_exits: bool,
+    /// Pace the output of the UARTs at the baud rate programmed by the guest,
+    /// as on hardware, rather than transmitting it as fast as possible.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub serial_pacing: bool,
+    /// Action taken when a BREAK is sent (as the telnet `IAC BRK` command)
+    /// through the COM1 console socket.
+    ///
+    /// Default: None, input to the console is passed to the guest untouched
+    #[serde(default)]
+    pub console_break: Option<ConsoleBreak>,
+    /// Generate ACPI tables describing the instance and provide them to the
+    /// bootrom via fw_cfg, rather than relying on its built-in tables.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub acpi_tables: bool,
+    /// Decode PCIe extended configuration space accesses made through the
+    /// ECAM (MMCONFIG) region.  The guest learns of the region from the MCFG
+    /// table, so this is of use only with `acpi_tables` or a bootrom which
+    /// describes the region itself.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub pcie: bool,
+    /// Listen for a GDB remote protocol connection through which to debug
+    /// the guest, at this TCP address (`host:port`) or, if it is not one, the
+    /// UNIX socket at this path.
+    ///
+    /// Default: None, no debugger may be attached
+    #[serde(default)]
+    pub gdb: Option<String>,
+    /// Arrangement of the vCPUs into sockets, cores, and threads, which must
+    /// account for all `cpus`.  It is reported to the guest through cpuid,
+    /// and so requires a `cpuid_profile`, and in the ACPI tables.
+    ///
+    /// Default: None, the vCPUs are not arranged into sockets or cores
+    #[serde(default)]
+    pub topology: Option<Topology>,
+}
+
+/// Action taken on a BREAK from the console.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleBreak {
+    /// Deliver the BREAK to the guest, through the UART
+    Guest,
+    /// Inject an NMI into the first vCPU
+    Nmi,
+    /// Stop the guest in the GDB stub, which must be enabled
+    Gdb,
+}
+
+/// Arrangement of vCPUs into sockets, cores, and threads.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Topology {
+    pub sockets: u8,
+    pub cores: u8,
+    pub threads: u8,
+}
+
+/// Process hardening applied after instance setup.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Harden {
+    /// Directory to which the view of the filesystem is restricted.
+    /// Defaults to the deepest directory containing all of the paths opened
+    /// while the instance runs, or an empty directory if there are none.  It
+    /// may not be `/`.
+    pub root: Option<String>,
 }
 
 /// A hard-coded device, either enabled by default or accessible locally
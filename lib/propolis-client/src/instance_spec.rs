@@ -9,9 +9,11 @@ use std::collections::BTreeSet;
 use thiserror::Error;
 
 use crate::types::{
-    Board, Chipset, DeviceSpecV0, I440Fx, InstanceSpecV0, NetworkBackendV0,
-    NetworkDeviceV0, PciPath, PciPciBridge, SerialPort, SerialPortNumber,
-    StorageBackendV0, StorageDeviceV0,
+    Board, BochsDisplay, Chipset, DeviceSpecV0, I440Fx, InstanceSpecV0,
+    NetworkBackendV0, NetworkDeviceV0, PciPath, PciPciBridge, QemuPvpanic,
+    SerialPort, SerialPortNumber, SharedMemory, StorageBackendV0,
+    StorageDeviceV0, Tpm, VirtioInput, VirtioMem, VirtioRng, VirtioRtc,
+    VirtioVsock,
 };
 
 #[cfg(feature = "falcon")]
@@ -36,6 +38,18 @@ pub enum SpecBuilderError {
 
     #[error("SoftNpu port {0:?} is already specified")]
     SoftNpuPortInUse(String),
+
+    #[error("A TPM is already specified")]
+    TpmInUse,
+
+    #[error("A pvpanic device is already specified")]
+    QemuPvpanicInUse,
+
+    #[error("A display device is already specified")]
+    BochsDisplayInUse,
+
+    #[error("A vsock device is already specified")]
+    VsockInUse,
 }
 
 /// A builder that constructs instance specs incrementally and catches basic
@@ -52,7 +66,16 @@ impl SpecBuilderV0 {
         let board = Board {
             cpus,
             memory_mb,
-            chipset: Chipset::I440Fx(I440Fx { enable_pcie }),
+            chipset: Chipset::I440Fx(I440Fx {
+                enable_pcie,
+                enable_smbus: false,
+            }),
+            cpu_profile: None,
+            cpuid: None,
+            cpu_topology: None,
+            steal_time: false,
+            pause_exits: false,
+            smbios: None,
         };
 
         Self {
@@ -64,6 +87,13 @@ impl SpecBuilderV0 {
         }
     }
 
+    /// Sets whether the chipset provides an SMBus host controller.
+    pub fn set_smbus(&mut self, enable_smbus: bool) -> &Self {
+        let Chipset::I440Fx(i440fx) = &mut self.spec.devices.board.chipset;
+        i440fx.enable_smbus = enable_smbus;
+        self
+    }
+
     /// Adds a PCI path to this builder's record of PCI locations with an
     /// attached device. If the path is already in use, returns an error.
     fn register_pci_device(
@@ -157,6 +187,151 @@ impl SpecBuilderV0 {
         Ok(self)
     }
 
+    /// Adds a clock device.
+    pub fn add_clock_device(
+        &mut self,
+        device_name: String,
+        device_spec: VirtioRtc,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.clock_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old =
+            self.spec.devices.clock_devices.insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds a shared memory device.
+    pub fn add_shared_memory_device(
+        &mut self,
+        device_name: String,
+        device_spec: SharedMemory,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.shared_memory_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old = self
+            .spec
+            .devices
+            .shared_memory_devices
+            .insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds an entropy device.
+    pub fn add_entropy_device(
+        &mut self,
+        device_name: String,
+        device_spec: VirtioRng,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.entropy_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old =
+            self.spec.devices.entropy_devices.insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds a hot-pluggable memory device.
+    pub fn add_memory_device(
+        &mut self,
+        device_name: String,
+        device_spec: VirtioMem,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.memory_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old =
+            self.spec.devices.memory_devices.insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds an input device.
+    pub fn add_input_device(
+        &mut self,
+        device_name: String,
+        device_spec: VirtioInput,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.input_devices.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(device_spec.pci_path)?;
+        let _old =
+            self.spec.devices.input_devices.insert(device_name, device_spec);
+
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Sets the instance's TPM.
+    pub fn set_tpm(&mut self, tpm: Tpm) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.tpm.is_some() {
+            return Err(SpecBuilderError::TpmInUse);
+        }
+
+        self.spec.devices.tpm = Some(tpm);
+        Ok(self)
+    }
+
+    /// Sets the instance's pvpanic device.
+    pub fn set_qemu_pvpanic(
+        &mut self,
+        pvpanic: QemuPvpanic,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.qemu_pvpanic.is_some() {
+            return Err(SpecBuilderError::QemuPvpanicInUse);
+        }
+
+        self.register_pci_device(pvpanic.pci_path)?;
+        self.spec.devices.qemu_pvpanic = Some(pvpanic);
+        Ok(self)
+    }
+
+    /// Sets the instance's display device.
+    pub fn set_bochs_display(
+        &mut self,
+        display: BochsDisplay,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.bochs_display.is_some() {
+            return Err(SpecBuilderError::BochsDisplayInUse);
+        }
+
+        self.register_pci_device(display.pci_path)?;
+        self.spec.devices.bochs_display = Some(display);
+        Ok(self)
+    }
+
+    /// Sets the instance's vsock device.
+    pub fn set_vsock(
+        &mut self,
+        vsock: VirtioVsock,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.vsock.is_some() {
+            return Err(SpecBuilderError::VsockInUse);
+        }
+
+        self.register_pci_device(vsock.pci_path)?;
+        self.spec.devices.vsock = Some(vsock);
+        Ok(self)
+    }
+
     /// Adds a serial port.
     pub fn add_serial_port(
         &mut self,
@@ -174,7 +349,7 @@ impl SpecBuilderV0 {
                     SerialPortNumber::Com4 => "com4",
                 }
                 .to_string(),
-                SerialPort { num: port },
+                SerialPort { num: port, paced: false },
             )
             .is_some()
         {
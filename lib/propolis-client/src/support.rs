@@ -44,7 +44,7 @@ impl PciPath {
 
 impl Default for Chipset {
     fn default() -> Self {
-        Self::I440Fx(I440Fx { enable_pcie: false })
+        Self::I440Fx(I440Fx { enable_pcie: false, enable_smbus: false })
     }
 }
 
@@ -60,6 +60,7 @@ impl StorageDeviceV0 {
         match self {
             StorageDeviceV0::VirtioDisk(dev) => dev.pci_path,
             StorageDeviceV0::NvmeDisk(dev) => dev.pci_path,
+            StorageDeviceV0::AhciDisk(dev) => dev.pci_path,
         }
     }
 }
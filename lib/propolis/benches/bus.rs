@@ -0,0 +1,168 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Costs of dispatching port IO and decoding PCI config accesses.
+//!
+//! The `pio/contended_in` group runs each case both through the bus and
+//! through [`LockedMap`], which performs the locked lookup the bus did before
+//! its routing was kept in a lock-free snapshot.  A single run therefore
+//! yields the before and after numbers to compare:
+//!
+//! ```text
+//! cargo bench -p propolis --bench bus -- pio/contended_in
+//! ```
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+};
+
+use propolis::common::{RWOp, ReadOp, WriteOp};
+use propolis::hw::pci;
+use propolis::pio::{PioBus, PioFn};
+use propolis::util::aspace::ASpace;
+
+/// Regions resembling those of a typical machine, so lookups are not trivially
+/// against a single entry.
+fn regions() -> impl Iterator<Item = (u16, u16, Arc<PioFn>)> {
+    (0x1000u16..0x2000).step_by(0x40).map(|port| {
+        let func: Arc<PioFn> = Arc::new(|_port, rwo| {
+            if let RWOp::Read(ro) = rwo {
+                ro.fill(0);
+            }
+        });
+        (port, 0x20, func)
+    })
+}
+
+/// Port map dispatched under a reader-writer lock, as the bus was prior to
+/// keeping its routing in a lock-free snapshot.  This is the baseline against
+/// which the bus itself is compared.
+struct LockedMap(RwLock<ASpace<Arc<PioFn>>>);
+impl LockedMap {
+    fn new() -> Self {
+        let mut map = ASpace::new(0, u16::MAX as usize);
+        for (port, len, func) in regions() {
+            map.register(port as usize, len as usize, func).unwrap();
+        }
+        Self(RwLock::new(map))
+    }
+
+    fn handle_in(&self, port: u16) -> u32 {
+        let map = self.0.read().unwrap();
+        let (start, _len, func) = map.region_at(port as usize).unwrap();
+        let func = Arc::clone(func);
+        drop(map);
+
+        let mut buf = [0xffu8; 4];
+        let mut ro = ReadOp::from_buf(port as usize - start, &mut buf);
+        func(port, RWOp::Read(&mut ro));
+        u32::from_le_bytes(buf)
+    }
+}
+
+/// Measures `f` while `contenders` other threads call it in a tight loop, as
+/// vCPUs exiting on the same port would.
+fn with_contenders<F>(contenders: usize, f: F, measure: impl FnOnce(&F))
+where
+    F: Fn() + Sync,
+{
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..contenders {
+            s.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    f();
+                }
+            });
+        }
+        measure(&f);
+        stop.store(true, Ordering::Relaxed);
+    });
+}
+
+fn pio_dispatch(c: &mut Criterion) {
+    let bus = PioBus::new();
+    for (port, len, func) in regions() {
+        bus.register(port, len, func).unwrap();
+    }
+
+    c.bench_function("pio/in", |b| {
+        b.iter(|| bus.handle_in(black_box(0x1804), 4).unwrap())
+    });
+    c.bench_function("pio/out", |b| {
+        b.iter(|| bus.handle_out(black_box(0x1804), 4, 0xffff_ffff).unwrap())
+    });
+
+    // Exit servicing latency as other vCPUs dispatch concurrently, with the
+    // routing in a snapshot (the bus) versus under a lock (the baseline).
+    let locked = LockedMap::new();
+    let mut group = c.benchmark_group("pio/contended_in");
+    for contenders in [0, 1, 3, 7] {
+        group.bench_function(BenchmarkId::new("snapshot", contenders), |b| {
+            let f = || {
+                black_box(bus.handle_in(black_box(0x1804), 4).unwrap());
+            };
+            with_contenders(contenders, f, |f| b.iter(f));
+        });
+        group.bench_function(BenchmarkId::new("locked", contenders), |b| {
+            let f = || {
+                black_box(locked.handle_in(black_box(0x1804)));
+            };
+            with_contenders(contenders, f, |f| b.iter(f));
+        });
+    }
+    group.finish();
+}
+
+struct NullDev {
+    pci_state: pci::DeviceState,
+}
+impl pci::Device for NullDev {
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+fn pci_cfg_decode(c: &mut Criterion) {
+    let pci_state = pci::Builder::new(pci::Ident {
+        vendor_id: 0x1de,
+        device_id: 0xffff,
+        ..Default::default()
+    })
+    .add_bar_mmio(pci::BarN::BAR0, 0x1000)
+    .finish();
+    let dev = NullDev { pci_state };
+    let ep: &dyn pci::Endpoint = &dev;
+
+    c.bench_function("pci/cfg_read_std", |b| {
+        let mut buf = [0u8; 4];
+        b.iter(|| {
+            let mut ro = ReadOp::from_buf(black_box(0x0), &mut buf);
+            ep.cfg_rw(RWOp::Read(&mut ro));
+        })
+    });
+    c.bench_function("pci/cfg_read_bar", |b| {
+        let mut buf = [0u8; 4];
+        b.iter(|| {
+            let mut ro = ReadOp::from_buf(black_box(0x10), &mut buf);
+            ep.cfg_rw(RWOp::Read(&mut ro));
+        })
+    });
+    // Writes to the command register or BARs require the device to be attached
+    // to a bus, so stick to the interrupt line register.
+    c.bench_function("pci/cfg_write_intr_line", |b| {
+        let buf = [0xau8];
+        b.iter(|| {
+            let mut wo = WriteOp::from_buf(black_box(0x3c), &buf);
+            ep.cfg_rw(RWOp::Write(&mut wo));
+        })
+    });
+}
+
+criterion_group!(benches, pio_dispatch, pci_cfg_decode);
+criterion_main!(benches);
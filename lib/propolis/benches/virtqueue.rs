@@ -0,0 +1,82 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use propolis::bench::*;
+use propolis::common::GuestAddr;
+
+const QUEUE_SIZE: u16 = 256;
+
+/// Build a chain of `count` descriptors shaped like a block request: a
+/// readable header, writable data buffers, and a writable status byte.
+fn block_like_chain(count: usize, data: u64) -> Vec<ChainBuf> {
+    let mut bufs = vec![ChainBuf::Readable(GuestAddr(data), 16)];
+    for i in 1..count.saturating_sub(1) {
+        let addr = data + (i as u64) * 0x1000;
+        bufs.push(ChainBuf::Writable(GuestAddr(addr), 0x1000));
+    }
+    if count > 1 {
+        bufs.push(ChainBuf::Writable(GuestAddr(data + 0x1000 * 64), 1));
+    }
+    bufs
+}
+
+fn pop_push(c: &mut Criterion) {
+    let machine = test_machine().expect("test machine created");
+    let mem = machine.acc_mem.access().unwrap();
+
+    let data = TEST_RAM_BASE + ScriptedRing::ring_len(QUEUE_SIZE) as u64;
+    let data = (data + 0xfff) & !0xfff;
+
+    let mut group = c.benchmark_group("virtqueue/pop_push");
+    for count in [1, 3, 16] {
+        let bufs = block_like_chain(count, data);
+        let mut ring = ScriptedRing::new(QUEUE_SIZE, GuestAddr(TEST_RAM_BASE));
+        let mut chain = Chain::with_capacity(count);
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(count),
+            &bufs,
+            |b, bufs| {
+                b.iter(|| {
+                    ring.post(&mem, bufs);
+                    let vq = ring.queue();
+                    vq.pop_avail(&mut chain, &mem).unwrap();
+                    vq.push_used(&mut chain, &mem);
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn chain_copy(c: &mut Criterion) {
+    let machine = test_machine().expect("test machine created");
+    let mem = machine.acc_mem.access().unwrap();
+
+    let data = TEST_RAM_BASE + ScriptedRing::ring_len(QUEUE_SIZE) as u64;
+    let data = (data + 0xfff) & !0xfff;
+    let bufs = [
+        ChainBuf::Readable(GuestAddr(data), 64),
+        ChainBuf::Writable(GuestAddr(data + 0x1000), 64),
+    ];
+    let mut ring = ScriptedRing::new(QUEUE_SIZE, GuestAddr(TEST_RAM_BASE));
+    let mut chain = Chain::with_capacity(bufs.len());
+
+    c.bench_function("virtqueue/read_write_64", |b| {
+        b.iter(|| {
+            ring.post(&mem, &bufs);
+            let vq = ring.queue();
+            vq.pop_avail(&mut chain, &mem).unwrap();
+            let mut req = [0u8; 64];
+            assert!(chain.read(&mut req, &mem));
+            assert!(chain.write(&req, &mem));
+            vq.push_used(&mut chain, &mem);
+        })
+    });
+}
+
+criterion_group!(benches, pop_push, chain_copy);
+criterion_main!(benches);
@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Hooks for driving emulation paths outside of a running VM.
+//!
+//! The benchmarks under `benches/` need guest memory and virtqueues to operate
+//! on without a bhyve instance behind them.  This module exposes the
+//! tempfile-backed test [Machine] along with a driver-side view of a split
+//! virtqueue, so descriptor chains can be scripted into synthetic guest memory
+//! and consumed by the device-side [VirtQueue].
+//!
+//! It is only built with the `bench-hooks` feature (or for unit tests).
+
+use std::mem::size_of;
+use std::num::Wrapping;
+use std::sync::Arc;
+
+use crate::common::{round_up_p2, GuestAddr, PAGE_SIZE};
+use crate::vmm::{Machine, MemCtx};
+
+pub use crate::hw::virtio::queue::{Chain, ChainBuf, VirtQueue};
+
+/// Base address of the RAM region in a [test_machine]
+pub const TEST_RAM_BASE: u64 = 1024 * 1024;
+/// Size of the RAM region in a [test_machine]
+pub const TEST_RAM_SIZE: u64 = 1024 * 1024;
+
+/// Create a [Machine] whose guest memory is backed by a tempfile, rather than
+/// by a VMM instance.  Its RAM is found at [TEST_RAM_BASE].
+pub fn test_machine() -> std::io::Result<Machine> {
+    Machine::new_test()
+}
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Split virtqueue descriptor, as laid out by the driver
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// The driver half of a split virtqueue placed in guest memory.
+///
+/// Chains are posted to the available ring as a guest driver would, so they
+/// can be popped (and later pushed) through the contained [VirtQueue].
+pub struct ScriptedRing {
+    queue: Arc<VirtQueue>,
+    desc_addr: GuestAddr,
+    avail_addr: GuestAddr,
+    used_addr: GuestAddr,
+    avail_idx: Wrapping<u16>,
+    next_desc: u16,
+}
+impl ScriptedRing {
+    /// Create a queue of `size` entries, mapped with the legacy layout at
+    /// `base`, which must be page-aligned.
+    pub fn new(size: u16, base: GuestAddr) -> Self {
+        let queue = Arc::new(VirtQueue::new(0, size));
+        assert!(queue.map_legacy(base.0));
+        let mapping = queue.get_state().mapping;
+
+        Self {
+            queue,
+            desc_addr: GuestAddr(mapping.desc_addr),
+            avail_addr: GuestAddr(mapping.avail_addr),
+            used_addr: GuestAddr(mapping.used_addr),
+            avail_idx: Wrapping(0),
+            next_desc: 0,
+        }
+    }
+
+    /// Bytes of guest memory occupied by a legacy ring of `size` entries
+    pub fn ring_len(size: u16) -> usize {
+        let size = size as usize;
+        let avail_end = size_of::<Desc>() * size + 2 * (size + 3);
+        round_up_p2(avail_end, PAGE_SIZE) + 8 * size + 2 * 3
+    }
+
+    pub fn queue(&self) -> &Arc<VirtQueue> {
+        &self.queue
+    }
+
+    /// Write descriptors for `bufs` and publish the resulting chain in the
+    /// available ring.  Returns the index of the chain's head descriptor.
+    ///
+    /// Descriptors are allocated round-robin, so the caller must keep fewer
+    /// than `size` descriptors outstanding at once.
+    pub fn post(&mut self, mem: &MemCtx, bufs: &[ChainBuf]) -> u16 {
+        let size = self.queue.size;
+        assert!(!bufs.is_empty() && bufs.len() <= size as usize);
+
+        let head = self.next_desc;
+        for (i, buf) in bufs.iter().enumerate() {
+            let idx = self.next_desc;
+            let next = (idx + 1) & (size - 1);
+            let (addr, len, mut flags) = match *buf {
+                ChainBuf::Readable(addr, len) => (addr, len, 0),
+                ChainBuf::Writable(addr, len) => {
+                    (addr, len, VIRTQ_DESC_F_WRITE)
+                }
+            };
+            if i + 1 < bufs.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let desc = Desc { addr: addr.0, len, flags, next };
+            mem.write(self.desc_addr.offset::<Desc>(idx as usize), &desc);
+            self.next_desc = next;
+        }
+
+        // avail ring entries follow the 16-bit flags and idx fields
+        let slot = self.avail_idx.0 & (size - 1);
+        let ring = GuestAddr(self.avail_addr.0 + 4);
+        mem.write(ring.offset::<u16>(slot as usize), &head);
+        self.avail_idx += Wrapping(1);
+        mem.write(GuestAddr(self.avail_addr.0 + 2), &self.avail_idx.0);
+
+        head
+    }
+
+    /// Read the index of the used ring, as published by the device
+    pub fn used_idx(&self, mem: &MemCtx) -> u16 {
+        mem.read(GuestAddr(self.used_addr.0 + 2)).unwrap()
+    }
+
+    /// Read the used ring entry at `slot`, as a (descriptor id, length) pair
+    pub fn used_entry(&self, mem: &MemCtx, slot: u16) -> (u32, u32) {
+        let slot = (slot & (self.queue.size - 1)) as u64;
+        let ent = GuestAddr(self.used_addr.0 + 4 + slot * 8);
+        let id = mem.read(ent).unwrap();
+        let len = mem.read(GuestAddr(ent.0 + 4)).unwrap();
+        (id, len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scripted_roundtrip() {
+        let machine = test_machine().unwrap();
+        let mem = machine.acc_mem.access().unwrap();
+
+        let size = 16;
+        let mut ring = ScriptedRing::new(size, GuestAddr(TEST_RAM_BASE));
+        let data = TEST_RAM_BASE + 0x10000;
+        let head = ring.post(
+            &mem,
+            &[
+                ChainBuf::Readable(GuestAddr(data), 16),
+                ChainBuf::Writable(GuestAddr(data + 0x1000), 512),
+            ],
+        );
+        assert_eq!(head, 0);
+        assert!(ScriptedRing::ring_len(size) < 0x10000);
+
+        let vq = ring.queue().clone();
+        let mut chain = Chain::with_capacity(4);
+        let (_, len) = vq.pop_avail(&mut chain, &mem).unwrap();
+        assert_eq!(len, 16 + 512);
+        assert_eq!(chain.remain_read_bytes(), 16);
+        assert_eq!(chain.remain_write_bytes(), 512);
+
+        assert!(chain.write(&0xdeadbeef_u32, &mem));
+        vq.push_used(&mut chain, &mem);
+        assert_eq!(ring.used_idx(&mem), 1);
+        assert_eq!(ring.used_entry(&mem, 0), (0, 4));
+        assert!(vq.pop_avail(&mut chain, &mem).is_none());
+
+        // the next chain starts after the descriptors of the first
+        let head = ring.post(&mem, &[ChainBuf::Readable(GuestAddr(data), 1)]);
+        assert_eq!(head, 2);
+        assert!(vq.pop_avail(&mut chain, &mem).is_some());
+    }
+}
@@ -0,0 +1,312 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Auditing of the flush ordering promised to the guest by a block backend
+//!
+//! When a flush completes successfully, the guest is entitled to expect that
+//! every write which had completed before the flush was issued is durable.
+//! Whether a backend keeps that promise is not visible from the requests
+//! alone, nor can the backend be trusted to say so itself, as the bugs being
+//! sought are those in which it wrongly believes its writes durable.  The
+//! audit instead observes the backend's storage directly: each request is
+//! reported [stored](block::Request::stored) as soon as its storage write
+//! returns, and each sync of the underlying storage is wrapped in a
+//! [`StorageSync`] from [`Auditor::begin_sync()`].  A write is durable only
+//! once a sync which *began* after it was stored has succeeded, regardless of
+//! which request (if any) the backend issued that sync on behalf of.
+//!
+//! While enabled (see [`Auditor::enable()`]), the [`Auditor`] of a backend
+//! compares each flush completed to the guest against the writes so observed
+//! to be durable, and logs any flush acknowledged ahead of them.  This is
+//! meant for validating the data-integrity guarantees of new backends, and
+//! adds a lock to the path of every request, so it is disabled by default.
+//!
+//! A backend which skips flushes (see
+//! [`BackendOpts::skip_flush`](super::BackendOpts::skip_flush)) waives these
+//! guarantees, and is expected to be reported as violating them.  Data lost
+//! within the storage itself, after it has acknowledged a write and a later
+//! sync, is beyond what can be observed here.
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::block::{self, Operation};
+
+use slog::warn;
+
+/// Counters of the flushes checked by an [`Auditor`]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AuditStats {
+    /// Successful flushes checked against the writes made durable
+    pub flushes: u64,
+    /// Flushes which completed before the writes they covered were durable
+    pub violations: u64,
+}
+
+#[derive(Default)]
+struct State {
+    log: Option<slog::Logger>,
+    /// Sequence number of the next mutating request
+    next_seq: u64,
+    /// Mutating requests not yet made durable by a sync
+    not_durable: BTreeSet<u64>,
+    /// Of those, the requests whose storage write has returned
+    stored: BTreeSet<u64>,
+    /// Of those, the requests completed successfully to the guest
+    completed: BTreeSet<u64>,
+    stats: AuditStats,
+}
+
+#[derive(Default)]
+struct Shared {
+    enabled: AtomicBool,
+    state: Mutex<State>,
+}
+
+/// Checker of the flushes completed by a backend against the writes it has
+/// made durable
+#[derive(Default)]
+pub struct Auditor(Arc<Shared>);
+impl Auditor {
+    /// Begin auditing requests taken from the device hereafter, logging any
+    /// violations to `log`.
+    pub fn enable(&self, log: slog::Logger) {
+        let mut state = self.0.state.lock().unwrap();
+        state.log = Some(log);
+        self.0.enabled.store(true, Ordering::Release);
+    }
+
+    /// Whether requests are being audited
+    pub fn is_enabled(&self) -> bool {
+        self.0.enabled.load(Ordering::Acquire)
+    }
+
+    /// Observe the start of a sync of the backend's underlying storage, which
+    /// is to be issued immediately hereafter.  Should it succeed, the backend
+    /// reports as much through [`StorageSync::succeeded()`].
+    pub fn begin_sync(&self) -> StorageSync {
+        if !self.is_enabled() {
+            return StorageSync { audit: None, writes: Vec::new() };
+        }
+        let state = self.0.state.lock().unwrap();
+        StorageSync {
+            audit: Some(self.0.clone()),
+            writes: state.stored.iter().copied().collect(),
+        }
+    }
+
+    /// Counters of the flushes checked so far
+    pub fn stats(&self) -> AuditStats {
+        self.0.state.lock().unwrap().stats
+    }
+
+    /// Issue a ticket through which the completion of a request for `op` is
+    /// audited, if auditing is enabled.
+    pub(super) fn ticket(&self, op: Operation) -> Option<Ticket> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut state = self.0.state.lock().unwrap();
+        let kind = match op {
+            // A flush covers the writes which completed before it was issued,
+            // and which were not yet durable
+            Operation::Flush => {
+                TicketKind::Flush(state.completed.iter().copied().collect())
+            }
+            op if op.is_mutating() => {
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                state.not_durable.insert(seq);
+                TicketKind::Write(seq)
+            }
+            _ => TicketKind::Read,
+        };
+        Some(Ticket { audit: self.0.clone(), kind })
+    }
+}
+
+/// A sync of a backend's storage, observed by its [`Auditor`]
+pub struct StorageSync {
+    audit: Option<Arc<Shared>>,
+    /// Writes stored before the sync began
+    writes: Vec<u64>,
+}
+impl StorageSync {
+    /// Record that the sync succeeded, making durable the writes stored
+    /// before it began.
+    pub fn succeeded(self) {
+        let Some(audit) = self.audit else {
+            return;
+        };
+        let mut state = audit.state.lock().unwrap();
+        for seq in self.writes {
+            state.not_durable.remove(&seq);
+            state.stored.remove(&seq);
+            state.completed.remove(&seq);
+        }
+    }
+}
+
+enum TicketKind {
+    Read,
+    Write(u64),
+    /// A flush, and the writes it covers
+    Flush(Vec<u64>),
+}
+
+/// Audit record carried by a [`block::Request`] until its completion
+pub(super) struct Ticket {
+    audit: Arc<Shared>,
+    kind: TicketKind,
+}
+impl Ticket {
+    /// Record that the storage write of the request has returned.
+    pub(super) fn stored(&self) {
+        if let TicketKind::Write(seq) = self.kind {
+            let mut state = self.audit.state.lock().unwrap();
+            if state.not_durable.contains(&seq) {
+                state.stored.insert(seq);
+            }
+        }
+    }
+
+    /// Record the completion of the request.  This must take place before
+    /// the completion is visible to the guest, so that any flush it then
+    /// issues is known to cover the request.
+    pub(super) fn complete(self, res: block::Result) {
+        let mut state = self.audit.state.lock().unwrap();
+        let covers = match self.kind {
+            TicketKind::Read => return,
+            TicketKind::Write(seq) if res.is_err() => {
+                // A failed write promises nothing, and is no longer tracked
+                state.not_durable.remove(&seq);
+                state.stored.remove(&seq);
+                return;
+            }
+            TicketKind::Write(seq) => {
+                if state.not_durable.contains(&seq) {
+                    state.completed.insert(seq);
+                }
+                return;
+            }
+            TicketKind::Flush(_) if res.is_err() => return,
+            TicketKind::Flush(covers) => covers,
+        };
+
+        state.stats.flushes += 1;
+        let pending =
+            covers.iter().filter(|seq| state.not_durable.contains(seq)).count();
+        if pending != 0 {
+            state.stats.violations += 1;
+            if let Some(log) = state.log.as_ref() {
+                warn!(log, "flush completed before writes were made durable";
+                    "writes" => pending,
+                    "violations" => state.stats.violations);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::Request;
+
+    fn test_log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn audited(audit: &Auditor, mut req: Request) -> Request {
+        req.audit = audit.ticket(req.oper());
+        req
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let audit = Auditor::default();
+        assert!(!audit.is_enabled());
+        assert!(audit.ticket(Operation::Flush).is_none());
+    }
+
+    /// Complete `req` as its backend would, once its storage write returns
+    fn stored(req: Request) {
+        req.stored();
+        req.complete(block::Result::Success);
+    }
+
+    #[test]
+    fn flush_ahead_of_durability() {
+        let audit = Auditor::default();
+        audit.enable(test_log());
+
+        stored(audited(&audit, Request::new_write(0, 512, Vec::new())));
+        audited(&audit, Request::new_flush()).complete(block::Result::Success);
+        assert_eq!(audit.stats().flushes, 1);
+        assert_eq!(audit.stats().violations, 1);
+
+        // Once synced, the next flush keeps its promise
+        audit.begin_sync().succeeded();
+        audited(&audit, Request::new_flush()).complete(block::Result::Success);
+        assert_eq!(audit.stats().flushes, 2);
+        assert_eq!(audit.stats().violations, 1);
+    }
+
+    #[test]
+    fn sync_covers_only_prior_writes() {
+        let audit = Auditor::default();
+        audit.enable(test_log());
+
+        // A write stored only after a sync began is not made durable by it,
+        // even if the backend completes a flush covering the write on the
+        // strength of that sync.
+        let sync = audit.begin_sync();
+        stored(audited(&audit, Request::new_write(0, 512, Vec::new())));
+        let flush = audited(&audit, Request::new_flush());
+        sync.succeeded();
+        flush.complete(block::Result::Success);
+        assert_eq!(audit.stats().violations, 1);
+
+        // A failed sync makes nothing durable
+        stored(audited(&audit, Request::new_write(0, 512, Vec::new())));
+        drop(audit.begin_sync());
+        audited(&audit, Request::new_flush()).complete(block::Result::Success);
+        assert_eq!(audit.stats().violations, 2);
+
+        audit.begin_sync().succeeded();
+        audited(&audit, Request::new_flush()).complete(block::Result::Success);
+        assert_eq!(audit.stats().violations, 2);
+
+        // Nor is a write which never reached storage made durable by any sync
+        audited(&audit, Request::new_write(0, 512, Vec::new()))
+            .complete(block::Result::Success);
+        audit.begin_sync().succeeded();
+        audited(&audit, Request::new_flush()).complete(block::Result::Success);
+        let stats = audit.stats();
+        assert_eq!(stats.flushes, 4);
+        assert_eq!(stats.violations, 3);
+    }
+
+    #[test]
+    fn flush_covers_prior_writes() {
+        let audit = Auditor::default();
+        audit.enable(test_log());
+
+        // A write completing after the flush was issued is not covered by it,
+        // nor is a failed one.
+        let write = audited(&audit, Request::new_write(0, 512, Vec::new()));
+        let flush = audited(&audit, Request::new_flush());
+        stored(write);
+        audited(&audit, Request::new_write_zeroes(0, 512, false))
+            .complete(block::Result::Failure);
+        flush.complete(block::Result::Success);
+
+        // Nor does a failed flush promise anything
+        audited(&audit, Request::new_flush()).complete(block::Result::Failure);
+
+        let stats = audit.stats();
+        assert_eq!(stats.flushes, 1);
+        assert_eq!(stats.violations, 0);
+    }
+}
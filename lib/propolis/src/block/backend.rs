@@ -10,7 +10,7 @@ use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::task::{Context, Poll};
 
 use crate::accessors::MemAccessor;
-use crate::block::{device, Device, Request};
+use crate::block::{audit, device, dirty, priority, Device, Request};
 
 use pin_project_lite::pin_project;
 use tokio::sync::{futures::Notified, Notify};
@@ -25,6 +25,9 @@ pub enum ReqError {
     Detached,
     /// Backend is halting workers
     Halted,
+    /// Requests of the device's priority class are being held back in favor of
+    /// those from higher-priority devices
+    Throttled,
 }
 
 pub(super) struct AttachState {
@@ -35,7 +38,7 @@ pub(super) struct AttachState {
     backend_is_halted: bool,
 }
 impl AttachState {
-    fn next_req(&self) -> Result<Request, ReqError> {
+    fn next_req(&self, waiter: &Arc<AttachInner>) -> Result<Request, ReqError> {
         if self.backend_is_halted {
             // The backend being halted is the most pressing status to consider,
             // so it must be checked first
@@ -45,7 +48,18 @@ impl AttachState {
             // in the paused state
             Err(ReqError::Paused)
         } else {
-            self.device.next().ok_or(ReqError::NonePending)
+            let class = self.device.attachment().priority();
+            let admission = priority::SCHEDULER
+                .try_admit(class, waiter)
+                .ok_or(ReqError::Throttled)?;
+            let Some(mut req) = self.device.next() else {
+                admission.retract();
+                return Err(ReqError::NonePending);
+            };
+            req.admission = Some(admission);
+            waiter.dirty.mark_req(&req);
+            req.audit = waiter.audit.ticket(req.oper());
+            Ok(req)
         }
     }
     pub(super) fn new(
@@ -53,7 +67,7 @@ impl AttachState {
         device: &Arc<dyn Device>,
     ) -> Self {
         Self {
-            sibling: Arc::downgrade(&dev_attach.0),
+            sibling: Arc::downgrade(&dev_attach.inner),
             device: device.clone(),
             acc_mem: device.accessor_mem(),
             dev_is_paused: false,
@@ -74,15 +88,29 @@ pub(super) struct AttachInner {
     pub(super) state: Mutex<Option<AttachState>>,
     req_notifier: Notify,
     cv: Condvar,
+    dirty: dirty::DirtyMap,
+    audit: audit::Auditor,
 }
 impl AttachInner {
-    fn new() -> Self {
+    pub(super) fn new() -> Self {
         Self {
             state: Mutex::new(None),
             req_notifier: Notify::new(),
             cv: Condvar::new(),
+            dirty: dirty::DirtyMap::default(),
+            audit: audit::Auditor::default(),
         }
     }
+
+    /// Notify any tasks blocked or waiting on this backend of a change.
+    pub(super) fn wake(&self) {
+        // Acquire (and release) the state lock prior to notifying, so that a
+        // thread in `block_for_req()` cannot miss the wake-up between checking
+        // for a request and waiting on the condvar.
+        drop(self.state.lock().unwrap());
+        self.req_notifier.notify_waiters();
+        self.cv.notify_all();
+    }
 }
 
 /// State held by the backend about the attached (if any) device
@@ -104,7 +132,7 @@ impl Attachment {
     pub fn next_req(&self) -> Result<Request, ReqError> {
         let guard = self.0.state.lock().unwrap();
         let inner = guard.as_ref().ok_or(ReqError::Detached)?;
-        inner.next_req()
+        inner.next_req(&self.0)
     }
 
     /// Block (synchronously) in order to retrieve the next [`Request`] from the
@@ -119,7 +147,7 @@ impl Attachment {
                 return None;
             }
 
-            if let Ok(req) = inner.next_req() {
+            if let Ok(req) = inner.next_req(&self.0) {
                 return Some(req);
             }
 
@@ -148,6 +176,28 @@ impl Attachment {
         }
     }
 
+    /// Whether the attached (if any) device can report
+    /// [`Result::NotReady`](crate::block::Result::NotReady) to the guest
+    pub fn device_reports_not_ready(&self) -> bool {
+        self.0
+            .state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(false, |inner| inner.device.reports_not_ready())
+    }
+
+    /// Record of the ranges of the device changed since the last checkpoint
+    pub fn dirty(&self) -> &dirty::DirtyMap {
+        &self.0.dirty
+    }
+
+    /// Checker of the flushes completed by the backend, to which it reports
+    /// the writes it has made durable
+    pub fn audit(&self) -> &audit::Auditor {
+        &self.0.audit
+    }
+
     /// Assert halted state on Attachment
     pub fn halt(&self) {
         if let Some(state) = self.0.state.lock().unwrap().as_mut() {
@@ -209,7 +259,9 @@ impl Future for WaitForReq<'_> {
                     // Let the consumer know that they should bail
                     return Poll::Ready(None);
                 }
-                Err(ReqError::NonePending) | Err(ReqError::Paused) => {
+                Err(ReqError::NonePending)
+                | Err(ReqError::Paused)
+                | Err(ReqError::Throttled) => {
                     if let Poll::Ready(_) =
                         Notified::poll(this.wait.as_mut(), cx)
                     {
@@ -5,9 +5,14 @@
 //! Implement a virtual block device backed by Crucible
 
 use std::io;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use crate::accessors::MemAccessor;
+use crate::block::health::{HealthEvent, HealthMonitor};
+use crate::block::journal::Journal;
+use crate::block::prefetch::{self, Prefetch};
 use crate::block::{self, DeviceInfo};
 use crate::inventory::Entity;
 use crate::vmm::MemCtx;
@@ -15,20 +20,31 @@ use crate::vmm::MemCtx;
 use crucible::{BlockIO, Buffer, CrucibleError, SnapshotDetails, Volume};
 use crucible_client_types::VolumeConstructionRequest;
 use oximeter::types::ProducerRegistry;
-use slog::{error, info};
+use slog::{error, info, warn};
 use thiserror::Error;
+use tokio::sync::{watch, RwLock};
 use uuid::Uuid;
 
 pub use nexus_client::Client as NexusClient;
 
 pub struct CrucibleBackend {
     state: Arc<WorkerState>,
+    prefetch_policy: Option<prefetch::Policy>,
+    prefetch: Mutex<Option<Prefetch>>,
 }
 struct WorkerState {
     attachment: block::backend::Attachment,
     volume: Volume,
     info: block::DeviceInfo,
     skip_flush: bool,
+    health: Option<HealthMonitor>,
+    journal: Option<Journal>,
+    /// Held shared by requests issued to the volume, and exclusively by
+    /// flushes and while the journal is replayed.
+    gate: RwLock<()>,
+    /// Number of times the journal has been replayed after a failure
+    replays: AtomicU64,
+    log: slog::Logger,
 }
 impl WorkerState {
     async fn process_loop(&self, acc_mem: MemAccessor) {
@@ -43,20 +59,41 @@ impl WorkerState {
                 }
             };
             let res = if let Some(memctx) = acc_mem.access() {
-                match process_request(
-                    &self.volume,
-                    read_only,
-                    skip_flush,
-                    &req,
-                    &memctx,
-                )
-                .await
-                {
-                    Ok(_) => block::Result::Success,
-                    Err(e) => {
-                        let mapped = block::Result::from(e);
-                        assert!(mapped.is_err());
-                        mapped
+                if let Some(journal) = self.journal.as_ref() {
+                    self.process_journaled(journal, &req, &memctx).await
+                } else if let Some(health) = self.health.as_ref() {
+                    let (volume, req, mem) = (&self.volume, &req, &*memctx);
+                    let audit = self.attachment.audit();
+                    let not_ready = self.attachment.device_reports_not_ready();
+                    health
+                        .supervise(
+                            move || {
+                                process_request(
+                                    volume, audit, read_only, skip_flush, req,
+                                    mem,
+                                )
+                            },
+                            Error::is_transient,
+                            not_ready,
+                        )
+                        .await
+                } else {
+                    match process_request(
+                        &self.volume,
+                        self.attachment.audit(),
+                        read_only,
+                        skip_flush,
+                        &req,
+                        &memctx,
+                    )
+                    .await
+                    {
+                        Ok(_) => block::Result::Success,
+                        Err(e) => {
+                            let mapped = block::Result::from(e);
+                            assert!(mapped.is_err());
+                            mapped
+                        }
                     }
                 }
             } else {
@@ -65,21 +102,210 @@ impl WorkerState {
             req.complete(res);
         }
     }
+
+    /// Process a request against a volume with a journal.  Transient failures
+    /// are ridden out by waiting for the volume to recover, replaying the
+    /// journal, and reissuing the request.
+    async fn process_journaled(
+        &self,
+        journal: &Journal,
+        req: &block::Request,
+        mem: &MemCtx,
+    ) -> block::Result {
+        loop {
+            let replays = self.replays.load(Ordering::Acquire);
+            if journal.is_full() {
+                self.checkpoint(journal).await;
+            }
+
+            let res = if req.oper().is_flush() {
+                // A flush retires the journaled writes preceding it, so those
+                // still in flight must complete before it is issued.
+                let _gate = self.gate.write().await;
+                self.process_journaled_once(journal, req, mem).await
+            } else {
+                let _gate = self.gate.read().await;
+                self.process_journaled_once(journal, req, mem).await
+            };
+            match res {
+                Ok(()) => return block::Result::Success,
+                Err(e) if !e.is_transient() => return e.into(),
+                Err(e) => {
+                    warn!(self.log, "request failed, replaying journal";
+                        "error" => %e);
+                    let _gate = self.gate.write().await;
+                    // Another worker may have replayed the journal while this
+                    // one waited for the gate, in which case only the request
+                    // itself need be reissued.
+                    if self.replays.load(Ordering::Acquire) == replays
+                        && !self.replay(journal).await
+                    {
+                        return block::Result::Failure;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn process_journaled_once(
+        &self,
+        journal: &Journal,
+        req: &block::Request,
+        mem: &MemCtx,
+    ) -> Result<(), Error> {
+        match req.oper() {
+            block::Operation::Write(off, len) if !self.info.read_only => {
+                let data = read_guest_data(req, mem, len)?;
+                journal.append(off as u64, &data).map_err(Error::Journal)?;
+
+                let offset =
+                    self.volume.byte_offset_to_block(off as u64).await?;
+                let _ = self
+                    .volume
+                    .write(offset, crucible::Bytes::from(data))
+                    .await?;
+                req.stored();
+                Ok(())
+            }
+            block::Operation::Flush => {
+                // With the gate held exclusively, every journaled write has
+                // completed, and so is durable once the flush completes.
+                let mark = journal.mark();
+                process_request(
+                    &self.volume,
+                    self.attachment.audit(),
+                    self.info.read_only,
+                    self.skip_flush,
+                    req,
+                    mem,
+                )
+                .await?;
+                if !self.skip_flush {
+                    journal.retire(mark).map_err(Error::Journal)?;
+                }
+                Ok(())
+            }
+            _ => {
+                process_request(
+                    &self.volume,
+                    self.attachment.audit(),
+                    self.info.read_only,
+                    self.skip_flush,
+                    req,
+                    mem,
+                )
+                .await
+            }
+        }
+    }
+
+    /// Flush the volume to empty a full journal, replaying the journal should
+    /// the flush fail.
+    async fn checkpoint(&self, journal: &Journal) {
+        let _gate = self.gate.write().await;
+        if !journal.is_full() {
+            // Emptied by another worker while this one waited for the gate
+            return;
+        }
+        let mark = journal.mark();
+        let sync = self.attachment.audit().begin_sync();
+        match self.volume.flush(None).await {
+            Ok(_) => {
+                sync.succeeded();
+                if let Err(e) = journal.retire(mark) {
+                    error!(self.log, "failed to truncate journal";
+                        "error" => %e);
+                }
+            }
+            Err(e) => {
+                warn!(self.log, "flush of full journal failed";
+                    "error" => %e);
+                // A failed replay leaves the journal full, to be retried by
+                // the next write.
+                let _ = self.replay(journal).await;
+            }
+        }
+    }
+
+    /// Wait for the volume to become reachable, then reissue each journaled
+    /// write and flush.  Must be called with the gate held exclusively.
+    ///
+    /// Returns `false` if the volume did not recover within the journal's
+    /// maximum outage.
+    async fn replay(&self, journal: &Journal) -> bool {
+        let policy = journal.policy();
+        let began = Instant::now();
+        loop {
+            match self.try_replay(journal).await {
+                Ok(n) => {
+                    self.replays.fetch_add(1, Ordering::Release);
+                    info!(self.log, "replayed journal";
+                        "writes" => n, "outage" => ?began.elapsed());
+                    return true;
+                }
+                Err(e) if began.elapsed() >= policy.max_outage => {
+                    error!(self.log, "abandoning journal replay";
+                        "error" => %e, "outage" => ?began.elapsed());
+                    return false;
+                }
+                Err(e) => {
+                    warn!(self.log, "journal replay failed"; "error" => %e);
+                    tokio::time::sleep(policy.retry_interval).await;
+                }
+            }
+        }
+    }
+
+    async fn try_replay(&self, journal: &Journal) -> Result<usize, Error> {
+        if !self.volume.query_is_active().await? {
+            self.volume.activate().await?;
+        }
+        let mark = journal.mark();
+        let entries = journal.read_entries().map_err(Error::Journal)?;
+        let n = entries.len();
+        for (off, data) in entries {
+            let offset = self.volume.byte_offset_to_block(off).await?;
+            let _ =
+                self.volume.write(offset, crucible::Bytes::from(data)).await?;
+        }
+        let sync = self.attachment.audit().begin_sync();
+        let _ = self.volume.flush(None).await?;
+        sync.succeeded();
+        journal.retire(mark).map_err(Error::Journal)?;
+        Ok(n)
+    }
 }
 
 impl CrucibleBackend {
     pub fn create(
         request: VolumeConstructionRequest,
-        opts: block::BackendOpts,
+        mut opts: block::BackendOpts,
         producer_registry: Option<ProducerRegistry>,
         nexus_client: Option<NexusClient>,
         log: slog::Logger,
     ) -> io::Result<Arc<Self>> {
+        if let Some(policy) = opts.retry_policy.as_ref() {
+            policy
+                .validate()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+
+        // There is nothing to journal for a read-only volume.
+        let journal = match opts.journal.take() {
+            Some(policy) if !opts.read_only.unwrap_or(false) => {
+                info!(log, "journaling writes";
+                    "path" => %policy.path.display());
+                Some(Journal::create(policy)?)
+            }
+            _ => None,
+        };
+
         let rt = tokio::runtime::Handle::current();
         rt.block_on(async move {
             CrucibleBackend::_create(
                 request,
                 opts,
+                journal,
                 producer_registry,
                 nexus_client,
                 log,
@@ -92,6 +318,7 @@ impl CrucibleBackend {
     async fn _create(
         request: VolumeConstructionRequest,
         opts: block::BackendOpts,
+        journal: Option<Journal>,
         producer_registry: Option<ProducerRegistry>,
         nexus_client: Option<NexusClient>,
         log: slog::Logger,
@@ -100,6 +327,14 @@ impl CrucibleBackend {
         let volume =
             Volume::construct(request, producer_registry, log.clone()).await?;
 
+        let journal_log = log.new(slog::o!("component" => "crucible-journal"));
+        let health = opts.retry_policy.map(|policy| {
+            HealthMonitor::new(
+                policy,
+                log.new(slog::o!("component" => "crucible-health")),
+            )
+        });
+
         // Decide if we need to scrub this volume or not.
         if volume.has_read_only_parent() {
             let vclone = volume.clone();
@@ -155,9 +390,19 @@ impl CrucibleBackend {
                     block_size: block_size as u32,
                     total_size: sectors,
                     read_only: opts.read_only.unwrap_or(false),
+                    // Crucible volumes cannot (yet) deallocate blocks
+                    supports_discard: false,
+                    supports_write_zeroes: false,
                 },
                 skip_flush: opts.skip_flush.unwrap_or(false),
+                health,
+                journal,
+                gate: RwLock::new(()),
+                replays: AtomicU64::new(0),
+                log: journal_log,
             }),
+            prefetch_policy: opts.prefetch,
+            prefetch: Mutex::new(None),
         }))
     }
 
@@ -221,6 +466,63 @@ impl CrucibleBackend {
             .map_err(CrucibleError::into)
     }
 
+    /// Subscribe to health events for this backend, if it was configured with
+    /// a retry policy.
+    pub fn health(&self) -> Option<watch::Receiver<HealthEvent>> {
+        self.state.health.as_ref().map(HealthMonitor::subscribe)
+    }
+
+    /// Current health of this backend, if it was configured with a retry
+    /// policy.
+    pub fn health_status(&self) -> Option<HealthEvent> {
+        self.state.health.as_ref().map(HealthMonitor::current)
+    }
+
+    /// Progress of the background prefetch of this volume, if one was
+    /// configured.
+    pub fn prefetch_progress(&self) -> Option<prefetch::Progress> {
+        self.prefetch.lock().unwrap().as_ref().map(Prefetch::progress)
+    }
+
+    /// Cancel the background prefetch of this volume, if one is running.
+    ///
+    /// Returns `false` if no prefetch was configured.
+    pub fn cancel_prefetch(&self) -> bool {
+        match self.prefetch.lock().unwrap().as_ref() {
+            Some(pf) => {
+                pf.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn spawn_prefetch(&self) {
+        let Some(policy) = self.prefetch_policy else {
+            return;
+        };
+        let mut guard = self.prefetch.lock().unwrap();
+        if guard.is_some() {
+            // Only one prefetch is run for the life of the backend
+            return;
+        }
+
+        let info = self.state.info;
+        let volume = self.state.volume.clone();
+        *guard = Some(Prefetch::spawn(
+            policy,
+            info.total_size * info.block_size as u64,
+            info.block_size,
+            move |off, len| {
+                let volume = volume.clone();
+                async move {
+                    let offset = volume.byte_offset_to_block(off).await?;
+                    volume.read(offset, Buffer::new(len)).await.map(|_| ())
+                }
+            },
+        ));
+    }
+
     fn spawn_workers(&self) {
         // TODO: make this tunable?
         let worker_count = 8;
@@ -258,10 +560,12 @@ impl Entity for CrucibleBackend {
 
         self.state.attachment.start();
         self.spawn_workers();
+        self.spawn_prefetch();
 
         Ok(())
     }
     fn halt(&self) {
+        self.cancel_prefetch();
         self.state.attachment.halt();
     }
 }
@@ -272,6 +576,8 @@ pub enum Error {
     BadGuestRegion,
     #[error("backend is read-only")]
     ReadOnly,
+    #[error("operation not supported")]
+    Unsupported,
 
     #[error("copied length {0} did not match expectation {1}")]
     CopyError(usize, usize),
@@ -279,13 +585,25 @@ pub enum Error {
     #[error("IO Error")]
     Io(#[from] io::Error),
 
+    #[error("journal error: {0}")]
+    Journal(io::Error),
+
     #[error("Crucible Error: {0}")]
     Crucible(#[from] CrucibleError),
 }
+impl Error {
+    /// Could this error be resolved by retrying the request?  Problems with the
+    /// request itself (rather than the communication with the downstairs) will
+    /// recur no matter how many attempts are made.
+    fn is_transient(&self) -> bool {
+        matches!(self, Error::Io(_) | Error::Crucible(_))
+    }
+}
 impl From<Error> for block::Result {
     fn from(value: Error) -> Self {
         match value {
             Error::ReadOnly => block::Result::ReadOnly,
+            Error::Unsupported => block::Result::Unsupported,
             _ => block::Result::Failure,
         }
     }
@@ -293,6 +611,7 @@ impl From<Error> for block::Result {
 
 async fn process_request(
     block: &(dyn BlockIO + Send + Sync),
+    audit: &block::audit::Auditor,
     read_only: bool,
     skip_flush: bool,
     req: &block::Request,
@@ -300,9 +619,6 @@ async fn process_request(
 ) -> Result<(), Error> {
     match req.oper() {
         block::Operation::Read(off, len) => {
-            let maps =
-                req.mappings(mem).ok_or_else(|| Error::BadGuestRegion)?;
-
             let offset = block.byte_offset_to_block(off as u64).await?;
 
             // Perform one large read from crucible, and write from data into
@@ -311,8 +627,12 @@ async fn process_request(
             let _ = block.read(offset, data.clone()).await?;
 
             let source = data.as_vec().await;
+            // Guest memory is mapped only once the data is in hand, so that a
+            // stalled read does not hold off the request deadline.
+            let maps =
+                req.mappings(mem).ok_or_else(|| Error::BadGuestRegion)?;
             let mut nwritten = 0;
-            for mapping in maps {
+            for mapping in maps.iter() {
                 nwritten += mapping.write_bytes(
                     &source[nwritten..(nwritten + mapping.len())],
                 )?;
@@ -329,27 +649,42 @@ async fn process_request(
 
             // Read from all the mappings into vec, and perform one large write
             // to crucible
-            let maps =
-                req.mappings(mem).ok_or_else(|| Error::BadGuestRegion)?;
-            let mut vec: Vec<u8> = vec![0; len];
-            let mut nread = 0;
-            for mapping in maps {
-                nread += mapping
-                    .read_bytes(&mut vec[nread..(nread + mapping.len())])?;
-            }
-            if nread != len {
-                return Err(Error::CopyError(nread, len));
-            }
+            let vec = read_guest_data(req, mem, len)?;
 
             let offset = block.byte_offset_to_block(off as u64).await?;
             let _ = block.write(offset, crucible::Bytes::from(vec)).await?;
+            req.stored();
         }
         block::Operation::Flush => {
             if !skip_flush {
                 // Send flush to crucible
+                let sync = audit.begin_sync();
                 let _ = block.flush(None).await?;
+                sync.succeeded();
             }
         }
+        block::Operation::Discard | block::Operation::WriteZeroes(..) => {
+            return Err(Error::Unsupported);
+        }
     }
     Ok(())
 }
+
+/// Copy the data of a write request out of guest memory
+fn read_guest_data(
+    req: &block::Request,
+    mem: &MemCtx,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let maps = req.mappings(mem).ok_or_else(|| Error::BadGuestRegion)?;
+    let mut vec: Vec<u8> = vec![0; len];
+    let mut nread = 0;
+    for mapping in maps.iter() {
+        nread +=
+            mapping.read_bytes(&mut vec[nread..(nread + mapping.len())])?;
+    }
+    if nread != len {
+        return Err(Error::CopyError(nread, len));
+    }
+    Ok(vec)
+}
@@ -4,18 +4,19 @@
 
 //! Mechanisms required to implement a block device (frontend)
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll, Waker};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::block::{
-    self, backend, probes, Backend, CacheMode, Device, DeviceInfo, Operation,
-    ReqId, Request,
+    self, backend, priority, probes, Backend, CacheMode, Device, DeviceInfo,
+    Operation, Priority, ReqId, Request,
 };
+use crate::trace::{self, TraceFlags};
 
 pub(super) struct AttachInner {
     sibling: Weak<backend::AttachInner>,
@@ -62,15 +63,67 @@ impl AttachInner {
 }
 
 /// State held by the device about the attached (if any) backend
-pub struct Attachment(pub(super) Arc<Mutex<Option<AttachInner>>>);
+pub struct Attachment {
+    pub(super) inner: Arc<Mutex<Option<AttachInner>>>,
+    write_protect: AtomicBool,
+    priority: AtomicU8,
+}
 impl Attachment {
     pub fn new() -> Self {
-        Attachment(Arc::new(Mutex::new(None)))
+        Attachment {
+            inner: Arc::new(Mutex::new(None)),
+            write_protect: AtomicBool::new(false),
+            priority: AtomicU8::new(Priority::default() as u8),
+        }
     }
 
     /// Query [`DeviceInfo`] from associated backend (if attached)
+    ///
+    /// If the device is write-protected, the info will report it as read-only,
+    /// regardless of the capabilities of the backend.
     pub fn info(&self) -> Option<DeviceInfo> {
-        self.0.lock().unwrap().as_ref().map(|inner| inner.backend.info())
+        let write_protected = self.write_protected();
+        self.inner.lock().unwrap().as_ref().map(|inner| {
+            let mut info = inner.backend.info();
+            info.read_only |= write_protected;
+            info
+        })
+    }
+
+    /// The backend (if any) to which this device is attached
+    pub fn backend(&self) -> Option<Arc<dyn Backend>> {
+        self.inner.lock().unwrap().as_ref().map(|inner| inner.backend.clone())
+    }
+
+    /// Set (or clear) write-protection on this device.
+    ///
+    /// While write-protected, the device is expected to fail any write
+    /// requests from the guest, rather than passing them to the backend.
+    /// Clearing write-protection does not allow writes to a backend which is
+    /// itself read-only.
+    pub fn set_write_protect(&self, write_protect: bool) {
+        self.write_protect.store(write_protect, Ordering::Release);
+    }
+
+    /// Is this device currently write-protected?
+    pub fn write_protected(&self) -> bool {
+        self.write_protect.load(Ordering::Acquire)
+    }
+
+    /// Set the [`Priority`] class of I/O issued by this device.
+    ///
+    /// Requests already in flight remain accounted against their prior class.
+    pub fn set_priority(&self, priority: Priority) {
+        let old = self.priority.swap(priority as u8, Ordering::AcqRel);
+        if old != priority as u8 {
+            // Backends throttled under the old priorities must reconsider
+            priority::SCHEDULER.wake_throttled();
+        }
+    }
+
+    /// Current [`Priority`] class of I/O issued by this device
+    pub fn priority(&self) -> Priority {
+        Priority::from_repr(self.priority.load(Ordering::Acquire))
     }
 
     /// Set cache mode on associated backend
@@ -84,7 +137,7 @@ impl Attachment {
 
     /// Notify attached backend of (new) pending requests
     pub fn notify(&self) {
-        let guard = self.0.lock().unwrap();
+        let guard = self.inner.lock().unwrap();
         if let Some(inner) = guard.as_ref() {
             if !inner.paused {
                 let be = inner.backend.clone();
@@ -100,7 +153,7 @@ impl Attachment {
     /// this device while paused.  The completions for any requests in flight,
     /// however, will be able to flow through.
     pub fn pause(&self) {
-        let mut guard = self.0.lock().unwrap();
+        let mut guard = self.inner.lock().unwrap();
         if let Some(inner) = guard.as_mut() {
             inner.paused = true;
             inner.lock_sibling(|sib| {
@@ -112,7 +165,7 @@ impl Attachment {
     /// Clear the paused state on this device, allowing the backend (if
     /// attached) to retrieve requests once again.
     pub fn resume(&self) {
-        let mut guard = self.0.lock().unwrap();
+        let mut guard = self.inner.lock().unwrap();
         if let Some(inner) = guard.as_mut() {
             if !inner.paused {
                 return;
@@ -130,12 +183,15 @@ impl Attachment {
 
     /// Detach from the associated (if any) backend.
     pub fn detach(&self) -> Option<()> {
-        AttachInner::detach(&self.0)
+        AttachInner::detach(&self.inner)
     }
 }
 
 static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(1);
 
+/// Number of completed requests retained by [`Tracking`] for diagnostics
+const IO_HISTORY_LEN: usize = 32;
+
 /// Tracking structure for outstanding block [`Request`]s.
 ///
 /// As requests are emitted to the associated backend, the corresponding data
@@ -146,8 +202,18 @@ static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(1);
 /// Although use of [`Tracking`] is not required by the block abstraction, it is
 /// here where the general USDT probes are attached.  A device which eschews its
 /// use will be missing calls into those probes.
+///
+/// A per-request deadline can be configured with [`Tracking::set_timeout()`].
+/// Requests which the backend fails to complete within that time are completed
+/// to the guest with [`block::Result::Failure`], so a single hung backend
+/// request cannot wedge the device queue.  Before that happens, the backend's
+/// access to the guest memory of the request is revoked, so the guest is free
+/// to reuse that memory once it sees the completion.  A backend which is in
+/// the midst of accessing that memory holds off the timeout until it is done.
+/// Should the backend eventually complete such a request, that completion is
+/// discarded.
 pub struct Tracking<T> {
-    inner: Mutex<TrackingInner<T>>,
+    inner: Arc<Mutex<TrackingInner<T>>>,
     wait: Arc<Mutex<TrackingWait>>,
 }
 struct TrackingInner<T> {
@@ -155,24 +221,79 @@ struct TrackingInner<T> {
     next_id: ReqId,
     dev: Weak<dyn Device>,
     outstanding: BTreeMap<ReqId, TrackingEntry<T>>,
+    timeout: Option<Duration>,
+    /// Generation of the timeout watchdog task.  Bumped whenever the timeout
+    /// is reconfigured, causing any prior watchdog to exit.
+    watchdog_gen: u64,
+    stats: TrackingStats,
+    /// Most recently completed requests, oldest first
+    history: VecDeque<IoRecord>,
+    reads_completed: u64,
 }
 struct TrackingEntry<T> {
     op: Operation,
     payload: T,
     /// When this request was submitted to the backend to be processed
     time_submitted: Instant,
+    /// Fence guarding backend access to the guest memory of this request
+    fence: Arc<MemFence>,
+    /// Has this request exceeded its deadline?
+    timed_out: bool,
+}
+
+/// Diagnostic counters regarding requests which exceeded their deadline.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TrackingStats {
+    /// Requests which were completed to the guest due to timeout
+    pub timeouts: u64,
+    /// Completions from the backend which arrived after their request had
+    /// already been completed due to timeout
+    pub late_completions: u64,
+    /// Age of the oldest request to have timed out
+    pub max_age: Duration,
+    /// Watchdog passes which found an overdue request whose backend was still
+    /// accessing its guest memory, deferring its completion to the guest
+    pub deferred: u64,
+}
+
+/// A request completed through [`Tracking`]
+#[derive(Copy, Clone, Debug)]
+pub struct IoRecord {
+    pub op: Operation,
+    pub result: block::Result,
+    /// Time between submission to the backend and completion
+    pub latency: Duration,
+    /// When the request was completed
+    pub completed: Instant,
+}
+
+/// Recent I/O activity through a [`Tracking`] structure, for diagnosing a
+/// device (or guest) which appears to be making no progress.
+#[derive(Clone, Debug, Default)]
+pub struct IoHistory {
+    /// Read requests completed since the device was created
+    pub reads_completed: u64,
+    /// Requests currently outstanding to the backend
+    pub outstanding: usize,
+    /// The most recently completed requests, oldest first
+    pub recent: Vec<IoRecord>,
 }
 
 impl<T> Tracking<T> {
     pub fn new(dev: Weak<dyn Device>) -> Self {
         let device_id = NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed);
         Self {
-            inner: Mutex::new(TrackingInner {
+            inner: Arc::new(Mutex::new(TrackingInner {
                 device_id,
                 next_id: ReqId::START,
                 dev,
                 outstanding: BTreeMap::new(),
-            }),
+                timeout: None,
+                watchdog_gen: 0,
+                stats: TrackingStats::default(),
+                history: VecDeque::with_capacity(IO_HISTORY_LEN),
+                reads_completed: 0,
+            })),
             wait: Arc::new(Mutex::new(TrackingWait::new())),
         }
     }
@@ -191,10 +312,17 @@ impl<T> Tracking<T> {
         let marker = TrackingMarker {
             id,
             dev: guard.dev.upgrade().expect("device still exists"),
+            fence: Arc::new(MemFence::new()),
         };
         guard.outstanding.insert(
             marker.id,
-            TrackingEntry { op: req.op, payload, time_submitted: now },
+            TrackingEntry {
+                op: req.op,
+                payload,
+                time_submitted: now,
+                fence: marker.fence.clone(),
+                timed_out: false,
+            },
         );
 
         let old = req.marker.replace(marker);
@@ -204,19 +332,30 @@ impl<T> Tracking<T> {
             self.wait.lock().unwrap().clear_empty()
         }
         let devid = guard.device_id;
-        match req.op {
-            Operation::Read(off, len) => {
-                probes::block_begin_read!(|| {
-                    (devid, id, off as u64, len as u64)
-                });
-            }
-            Operation::Write(off, len) => {
-                probes::block_begin_write!(|| {
-                    (devid, id, off as u64, len as u64)
-                });
-            }
-            Operation::Flush => {
-                probes::block_begin_flush!(|| { (devid, id) });
+        if trace::is_enabled(TraceFlags::BLOCK) {
+            match req.op {
+                Operation::Read(off, len) => {
+                    probes::block_begin_read!(|| {
+                        (devid, id, off as u64, len as u64)
+                    });
+                }
+                Operation::Write(off, len) => {
+                    probes::block_begin_write!(|| {
+                        (devid, id, off as u64, len as u64)
+                    });
+                }
+                Operation::Flush => {
+                    probes::block_begin_flush!(|| { (devid, id) });
+                }
+                Operation::Discard => {
+                    let nranges = req.ranges.len() as u64;
+                    probes::block_begin_discard!(|| { (devid, id, nranges) });
+                }
+                Operation::WriteZeroes(off, len) => {
+                    probes::block_begin_write_zeroes!(|| {
+                        (devid, id, off as u64, len as u64)
+                    });
+                }
             }
         }
 
@@ -226,43 +365,94 @@ impl<T> Tracking<T> {
     /// Indicate the completion of a pending [`Request`], retrieving the
     /// associated payload data.  The [`block::Result`] argument is used to
     /// communicate the result through the generic block USDT probe.
-    pub fn complete(&self, id: ReqId, res: block::Result) -> (Operation, T) {
+    ///
+    /// Returns [`None`] if the request was already completed because it
+    /// exceeded its deadline, in which case there is nothing further for the
+    /// device to do.
+    pub fn complete(
+        &self,
+        id: ReqId,
+        res: block::Result,
+    ) -> Option<(Operation, T)> {
         let now = Instant::now();
         let mut guard = self.inner.lock().unwrap();
-        let entry = guard
-            .outstanding
-            .remove(&id)
-            .expect("tracked request should be present");
-
         let devid = guard.device_id;
+        let Some(entry) = guard.outstanding.remove(&id) else {
+            // Only requests which were completed early due to a timeout can be
+            // completed twice.
+            guard.stats.late_completions += 1;
+            probes::block_late_complete!(|| (devid, id, res as u8));
+            return None;
+        };
+
         let proc_ns =
             now.duration_since(entry.time_submitted).as_nanos() as u64;
         // TODO: calculate queued time
         let queue_ns = 0;
         let rescode = res as u8;
-        match entry.op {
-            Operation::Read(..) => {
-                probes::block_complete_read!(|| {
-                    (devid, id, rescode, proc_ns, queue_ns)
-                });
-            }
-            Operation::Write(..) => {
-                probes::block_complete_write!(|| {
-                    (devid, id, rescode, proc_ns, queue_ns)
-                });
-            }
-            Operation::Flush => {
-                probes::block_complete_flush!(|| {
-                    (devid, id, rescode, proc_ns, queue_ns)
-                });
+        if trace::is_enabled(TraceFlags::BLOCK) {
+            match entry.op {
+                Operation::Read(..) => {
+                    probes::block_complete_read!(|| {
+                        (devid, id, rescode, proc_ns, queue_ns)
+                    });
+                }
+                Operation::Write(..) => {
+                    probes::block_complete_write!(|| {
+                        (devid, id, rescode, proc_ns, queue_ns)
+                    });
+                }
+                Operation::Flush => {
+                    probes::block_complete_flush!(|| {
+                        (devid, id, rescode, proc_ns, queue_ns)
+                    });
+                }
+                Operation::Discard => {
+                    probes::block_complete_discard!(|| {
+                        (devid, id, rescode, proc_ns, queue_ns)
+                    });
+                }
+                Operation::WriteZeroes(..) => {
+                    probes::block_complete_write_zeroes!(|| {
+                        (devid, id, rescode, proc_ns, queue_ns)
+                    });
+                }
             }
         }
 
+        if matches!(entry.op, Operation::Read(..)) {
+            guard.reads_completed += 1;
+        }
+        if guard.history.len() == IO_HISTORY_LEN {
+            guard.history.pop_front();
+        }
+        guard.history.push_back(IoRecord {
+            op: entry.op,
+            result: res,
+            latency: now.duration_since(entry.time_submitted),
+            completed: now,
+        });
+
         if guard.outstanding.is_empty() {
             self.wait.lock().unwrap().set_empty();
         }
 
-        (entry.op, entry.payload)
+        Some((entry.op, entry.payload))
+    }
+
+    /// Diagnostic counters regarding timed-out requests
+    pub fn stats(&self) -> TrackingStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    /// Recently completed requests, along with a count of completed reads
+    pub fn io_history(&self) -> IoHistory {
+        let guard = self.inner.lock().unwrap();
+        IoHistory {
+            reads_completed: guard.reads_completed,
+            outstanding: guard.outstanding.len(),
+            recent: guard.history.iter().copied().collect(),
+        }
     }
 
     /// Query if there are any tracked requests outstanding
@@ -278,6 +468,103 @@ impl<T> Tracking<T> {
     }
 }
 
+impl<T: Send + 'static> Tracking<T> {
+    /// Configure (or clear, with `None`) the deadline applied to requests
+    /// tracked by this structure.
+    ///
+    /// A watchdog task, spawned on the current tokio runtime, periodically
+    /// checks for requests which have been outstanding longer than `timeout`.
+    /// Once the backend is no longer accessing their guest memory, those
+    /// requests are completed to the guest with [`block::Result::Failure`]
+    /// without waiting on the backend.
+    ///
+    /// # Panics
+    ///
+    /// If called outside the context of a tokio runtime while setting a
+    /// timeout.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        let mut guard = self.inner.lock().unwrap();
+        guard.timeout = timeout;
+        guard.watchdog_gen += 1;
+        let gen = guard.watchdog_gen;
+        drop(guard);
+
+        if let Some(timeout) = timeout {
+            // Check at a fraction of the timeout, so requests are not left
+            // waiting much longer than their deadline.
+            let period = (timeout / 4).max(Duration::from_millis(10));
+            let inner = Arc::downgrade(&self.inner);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                interval.set_missed_tick_behavior(
+                    tokio::time::MissedTickBehavior::Delay,
+                );
+                loop {
+                    interval.tick().await;
+                    let Some(inner) = inner.upgrade() else {
+                        return;
+                    };
+                    if !Self::expire_overdue(&inner, gen) {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Complete any requests which have exceeded the configured timeout, once
+    /// their guest memory has been fenced off from the backend.  Returns
+    /// `false` if the watchdog of generation `gen` should exit.
+    fn expire_overdue(inner: &Mutex<TrackingInner<T>>, gen: u64) -> bool {
+        let now = Instant::now();
+        let mut guard = inner.lock().unwrap();
+        if guard.watchdog_gen != gen {
+            return false;
+        }
+        let Some(timeout) = guard.timeout else {
+            return false;
+        };
+        let Some(dev) = guard.dev.upgrade() else {
+            return false;
+        };
+
+        let devid = guard.device_id;
+        let mut expired = Vec::new();
+        let mut max_age = guard.stats.max_age;
+        let mut deferred = 0;
+        for (id, entry) in guard.outstanding.iter_mut() {
+            let age = now.duration_since(entry.time_submitted);
+            if age < timeout || entry.timed_out {
+                continue;
+            }
+            // Completing the request hands its guest memory back to the
+            // guest, so the backend must first be cut off from it.  If the
+            // backend is accessing that memory right now, try again later.
+            if !entry.fence.try_revoke() {
+                deferred += 1;
+                continue;
+            }
+            entry.timed_out = true;
+            max_age = max_age.max(age);
+            probes::block_timeout!(|| (devid, *id, age.as_nanos() as u64));
+            expired.push(*id);
+        }
+        guard.stats.timeouts += expired.len() as u64;
+        guard.stats.deferred += deferred;
+        guard.stats.max_age = max_age;
+        drop(guard);
+
+        // The tracking lock must be released prior to completing through the
+        // device, since it will call back into `complete()`.  Should the
+        // backend complete one of these requests in the interim, the
+        // subsequent completion here will be discarded.
+        for id in expired {
+            dev.complete(block::Result::Failure, id);
+        }
+        true
+    }
+}
+
 /// Record keeping for [`NoneOutstanding`] futures emitted by [`Tracking`]
 struct TrackingWait {
     empty: bool,
@@ -347,9 +634,84 @@ impl Future for NoneOutstanding {
 pub(super) struct TrackingMarker {
     id: ReqId,
     dev: Arc<dyn Device>,
+    fence: Arc<MemFence>,
 }
 impl TrackingMarker {
     pub(super) fn complete(self, res: block::Result) {
         self.dev.complete(res, self.id);
     }
+
+    /// Begin an access to the guest memory of the request, unless that access
+    /// has been revoked because the request exceeded its deadline.
+    pub(super) fn access_mem(&self) -> Option<MemFenceGuard> {
+        self.fence.enter().then(|| MemFenceGuard(self.fence.clone()))
+    }
+}
+
+/// Fence between backend access to the guest memory of a tracked request and
+/// completion of that request to the guest ahead of the backend.
+///
+/// The state holds a count of accesses in progress, along with a flag which,
+/// once set, refuses any further access.
+struct MemFence(AtomicU32);
+impl MemFence {
+    const REVOKED: u32 = 1 << 31;
+
+    fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    /// Attempt to begin an access, returning `false` if access was revoked
+    fn enter(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                (state & Self::REVOKED == 0).then_some(state + 1)
+            })
+            .is_ok()
+    }
+
+    fn exit(&self) {
+        self.0.fetch_sub(1, Ordering::Release);
+    }
+
+    /// Revoke access, provided that none is in progress
+    fn try_revoke(&self) -> bool {
+        match self.0.compare_exchange(
+            0,
+            Self::REVOKED,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => true,
+            Err(state) => state & Self::REVOKED != 0,
+        }
+    }
+}
+
+/// An access to the guest memory of a tracked request, during which the
+/// request cannot be completed due to timeout.
+pub(super) struct MemFenceGuard(Arc<MemFence>);
+impl Drop for MemFenceGuard {
+    fn drop(&mut self) {
+        self.0.exit();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fence_waits_on_access() {
+        let fence = Arc::new(MemFence::new());
+        let access_mem = || fence.enter().then(|| MemFenceGuard(fence.clone()));
+
+        let access = access_mem().expect("access is not yet revoked");
+        assert!(!fence.try_revoke(), "revocation waits on the access");
+        drop(access);
+
+        assert!(fence.try_revoke());
+        assert!(access_mem().is_none(), "access is refused once revoked");
+        assert!(fence.try_revoke(), "revocation is idempotent");
+    }
 }
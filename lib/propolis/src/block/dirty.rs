@@ -0,0 +1,272 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tracking of the ranges of a block backend changed since a checkpoint
+//!
+//! The [`Attachment`](super::backend::Attachment) of each backend records, in a
+//! [`DirtyMap`], which [`GRANULE`]-sized ranges of the device have been the
+//! target of a write, write-zeroes, or discard request since its last
+//! [checkpoint](DirtyMap::checkpoint).  The changes since that checkpoint can
+//! then be [exported](super::export::Exporter::delta) on their own, as an
+//! incremental backup of the device.
+//!
+//! Ranges are marked as requests are taken from the device, rather than as
+//! they complete, so a checkpoint is only exact while no requests are in
+//! flight, such as while the instance is paused.  Tracking begins with the
+//! first checkpoint, as there is no point of reference for any change before
+//! it.
+//!
+//! A checkpoint is taken in two steps: it is
+//! [begun](DirtyMap::begin_checkpoint) as the export recording it starts, and
+//! [committed](DirtyMap::commit_checkpoint) only once that export has
+//! completed.  Until then, the previous checkpoint remains the point of
+//! reference, so an export which fails or is abandoned loses no record of the
+//! changes made since it.
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::block::{export, ByteLen, ByteOffset, Operation, Request};
+
+/// Size of the ranges of the device tracked as changed or unchanged
+pub const GRANULE: usize = export::CHUNK_SIZE;
+
+/// Identifies a checkpoint of a [`DirtyMap`]
+///
+/// Identifiers are never zero, and are chosen such that those of checkpoints
+/// taken of different backends (or of the same backend in a later instance)
+/// are unlikely to coincide.
+pub type CheckpointId = u64;
+
+/// The ranges of a device changed since a checkpoint
+#[derive(Clone, Debug)]
+pub struct Delta {
+    /// The checkpoint which the changes are relative to
+    pub base: CheckpointId,
+    bits: Vec<u64>,
+}
+impl Delta {
+    /// Whether the granule at index `idx` (covering the bytes from
+    /// `idx * GRANULE`) has changed.
+    pub fn is_dirty(&self, idx: usize) -> bool {
+        self.bits
+            .get(idx / 64)
+            .map_or(false, |word| word & (1 << (idx % 64)) != 0)
+    }
+
+    /// Number of granules which have changed
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|word| word.count_ones() as usize).sum()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// The most recent checkpoint, if one has been taken
+    last: Option<CheckpointId>,
+    bits: Vec<u64>,
+    /// A checkpoint which has been begun but not committed, with the changes
+    /// made since it was begun
+    pending: Option<(CheckpointId, Vec<u64>)>,
+}
+impl Inner {
+    /// Identifier for the checkpoint following the most recent one
+    fn next_id(&self) -> CheckpointId {
+        match self.pending.as_ref().map(|(id, _)| *id).or(self.last) {
+            Some(last) => last.wrapping_add(1).max(1),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(1, |d| d.as_nanos() as u64)
+                .max(1),
+        }
+    }
+}
+
+/// Sets the bits of the granules from index `first` to `last` (inclusive)
+fn set_range(bits: &mut Vec<u64>, first: usize, last: usize) {
+    if bits.len() <= last / 64 {
+        bits.resize(last / 64 + 1, 0);
+    }
+    for idx in first..=last {
+        bits[idx / 64] |= 1 << (idx % 64);
+    }
+}
+
+/// Bitmap of the granules of a device changed since its last checkpoint
+#[derive(Default)]
+pub struct DirtyMap(Mutex<Inner>);
+impl DirtyMap {
+    /// Marks the ranges of the device altered by `req`, if any.
+    pub(super) fn mark_req(&self, req: &Request) {
+        match req.oper() {
+            Operation::Write(off, len) | Operation::WriteZeroes(off, len) => {
+                self.mark(off, len)
+            }
+            Operation::Discard => {
+                for (off, len) in req.ranges() {
+                    self.mark(*off, *len);
+                }
+            }
+            Operation::Read(..) | Operation::Flush => {}
+        }
+    }
+
+    /// Marks the `len` bytes of the device from `off` as changed.
+    pub fn mark(&self, off: ByteOffset, len: ByteLen) {
+        if len == 0 {
+            return;
+        }
+        let mut inner = self.0.lock().unwrap();
+        if inner.last.is_none() && inner.pending.is_none() {
+            return;
+        }
+
+        let first = off / GRANULE;
+        let last = (off + len - 1) / GRANULE;
+        set_range(&mut inner.bits, first, last);
+        if let Some((_, pending)) = inner.pending.as_mut() {
+            set_range(pending, first, last);
+        }
+    }
+
+    /// The most recent checkpoint, if one has been taken
+    pub fn last_checkpoint(&self) -> Option<CheckpointId> {
+        self.0.lock().unwrap().last
+    }
+
+    /// Returns the ranges changed since the checkpoint `base`, provided that
+    /// it is the most recent one.  The changes accumulate until the next
+    /// checkpoint is taken.
+    pub fn changes_since(&self, base: CheckpointId) -> Option<Delta> {
+        let inner = self.0.lock().unwrap();
+        (inner.last == Some(base))
+            .then(|| Delta { base, bits: inner.bits.clone() })
+    }
+
+    /// Begins a new checkpoint, returning its identifier.  Changes continue
+    /// to be recorded against the most recent checkpoint until the new one is
+    /// committed, from which point only those made since it was begun remain.
+    ///
+    /// A checkpoint begun previously, but not yet committed, is abandoned.
+    pub fn begin_checkpoint(&self) -> CheckpointId {
+        let mut inner = self.0.lock().unwrap();
+        let id = inner.next_id();
+        inner.pending = Some((id, Vec::new()));
+        id
+    }
+
+    /// Commits the checkpoint `id`, making it the most recent.  Returns
+    /// `false` if it is not the checkpoint most recently begun, or has already
+    /// been committed.
+    pub fn commit_checkpoint(&self, id: CheckpointId) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        match inner.pending.take() {
+            Some((pending, bits)) if pending == id => {
+                inner.last = Some(id);
+                inner.bits = bits;
+                true
+            }
+            other => {
+                inner.pending = other;
+                false
+            }
+        }
+    }
+
+    /// Takes a new checkpoint at once, clearing the record of changed ranges,
+    /// and returns its identifier.
+    pub fn checkpoint(&self) -> CheckpointId {
+        let id = self.begin_checkpoint();
+        self.commit_checkpoint(id);
+        id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn untracked_before_checkpoint() {
+        let map = DirtyMap::default();
+        map.mark(0, GRANULE);
+        assert_eq!(map.last_checkpoint(), None);
+
+        let base = map.checkpoint();
+        let delta = map.changes_since(base).unwrap();
+        assert_eq!(delta.count(), 0);
+    }
+
+    #[test]
+    fn marks_covered_granules() {
+        let map = DirtyMap::default();
+        let base = map.checkpoint();
+
+        // Straddles the boundary between the first two granules
+        map.mark(GRANULE - 512, 1024);
+        // Lies wholly within a distant granule
+        map.mark(GRANULE * 100 + 512, 512);
+        map.mark_req(&Request::new_discard(vec![(GRANULE * 3, GRANULE)]));
+        map.mark_req(&Request::new_read(GRANULE * 5, 512, Vec::new()));
+
+        let delta = map.changes_since(base).unwrap();
+        let dirty: Vec<_> = (0..128).filter(|i| delta.is_dirty(*i)).collect();
+        assert_eq!(dirty, [0, 1, 3, 100]);
+        assert!(!delta.is_dirty(100_000));
+    }
+
+    #[test]
+    fn checkpoint_resets() {
+        let map = DirtyMap::default();
+        let first = map.checkpoint();
+        map.mark(0, 512);
+
+        let second = map.checkpoint();
+        assert_ne!(first, second);
+        assert!(map.changes_since(first).is_none());
+        assert_eq!(map.changes_since(second).unwrap().count(), 0);
+
+        // Changes accumulate until the next checkpoint
+        map.mark(GRANULE, 512);
+        assert_eq!(map.changes_since(second).unwrap().count(), 1);
+        map.mark(GRANULE * 2, 512);
+        assert_eq!(map.changes_since(second).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn checkpoint_takes_effect_on_commit() {
+        let map = DirtyMap::default();
+        let first = map.checkpoint();
+        map.mark(0, 512);
+
+        // Until it is committed, the changes remain relative to the first
+        let second = map.begin_checkpoint();
+        map.mark(GRANULE, 512);
+        assert_eq!(map.last_checkpoint(), Some(first));
+        assert_eq!(map.changes_since(first).unwrap().count(), 2);
+
+        // An abandoned checkpoint is never committed
+        let third = map.begin_checkpoint();
+        assert!(!map.commit_checkpoint(second));
+        map.mark(GRANULE * 2, 512);
+        assert_eq!(map.changes_since(first).unwrap().count(), 3);
+
+        // Once committed, only the changes since it was begun remain
+        assert!(map.commit_checkpoint(third));
+        assert!(!map.commit_checkpoint(third));
+        assert!(map.changes_since(first).is_none());
+        let delta = map.changes_since(third).unwrap();
+        assert_eq!(delta.count(), 1);
+        assert!(delta.is_dirty(2));
+    }
+
+    #[test]
+    fn tracks_from_first_begun_checkpoint() {
+        let map = DirtyMap::default();
+        let first = map.begin_checkpoint();
+        map.mark(0, 512);
+        assert!(map.commit_checkpoint(first));
+        assert_eq!(map.changes_since(first).unwrap().count(), 1);
+    }
+}
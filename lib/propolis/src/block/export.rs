@@ -0,0 +1,479 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sparse export of the contents of a block backend
+//!
+//! A backend's contents are exported as a stream which begins with a
+//! [`Header`], followed by a series of extents covering the device in order,
+//! from its start to its end.  Each extent is introduced by a record of
+//! [`RECORD_LEN`] bytes: its kind (a little-endian `u32`), four reserved bytes
+//! of zero, and its length in bytes (a little-endian `u64`).
+//!
+//! - [`EXTENT_DATA`]: the record is followed by the contents of the extent
+//! - [`EXTENT_ZERO`]: the extent reads as zeroes, and nothing follows
+//! - [`EXTENT_UNCHANGED`]: the extent is unchanged since the base checkpoint
+//!   of an incremental export, and nothing follows
+//!
+//! A record of kind [`EXTENT_END`] (and length 0) closes the stream.  Ranges
+//! of the device which read as zeroes are found in units of [`CHUNK_SIZE`], so
+//! a sparsely-populated device exports as little more than its data, and can
+//! be [unpacked](unpack) into a sparse file.
+//!
+//! An incremental export holds only the ranges of the device which have
+//! [changed](crate::block::dirty) since a checkpoint, and is [applied](apply)
+//! to a copy of the device's contents as of that checkpoint.  An export may
+//! also record a checkpoint taken as it began, to serve as the base of the
+//! next incremental export.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use crate::block::dirty::{CheckpointId, Delta, GRANULE};
+use crate::block::Backend;
+
+/// Identifies a stream as an export of a block backend
+pub const MAGIC: [u8; 8] = *b"PRPLDISK";
+
+/// Version of the stream format
+pub const VERSION: u32 = 2;
+
+/// Length of the [`Header`] opening the stream
+pub const HEADER_LEN: usize = 40;
+
+/// Length of the header of streams of version 1, which carried no checkpoints
+const HEADER_V1_LEN: usize = 24;
+
+/// Length of the record introducing each extent
+pub const RECORD_LEN: usize = 16;
+
+/// Kind of the record closing the stream
+pub const EXTENT_END: u32 = 0;
+/// Kind of an extent whose contents follow its record
+pub const EXTENT_DATA: u32 = 1;
+/// Kind of an extent which reads as zeroes
+pub const EXTENT_ZERO: u32 = 2;
+/// Kind of an extent unchanged since the base of an incremental export
+pub const EXTENT_UNCHANGED: u32 = 3;
+
+/// Size of the units in which the device is read, and in which ranges reading
+/// as zeroes are found
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Description of the exported device, opening the stream
+///
+/// Encoded as [`MAGIC`], followed by [`VERSION`], the block size, the size of
+/// the device, the checkpoint, and the base checkpoint (as little-endian `u32`,
+/// `u32`, `u64`, `u64`, and `u64` respectively, with absent checkpoints
+/// encoded as 0).  Streams of version 1 end the header after the size of the
+/// device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Size of the device's blocks, in bytes
+    pub block_size: u32,
+    /// Size of the device, in bytes
+    pub size: u64,
+    /// Checkpoint taken of the device as the export began, if any
+    pub checkpoint: Option<CheckpointId>,
+    /// Checkpoint which an incremental export is relative to, or `None` if the
+    /// export holds the whole device
+    pub base: Option<CheckpointId>,
+}
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[..8].copy_from_slice(&MAGIC);
+        buf[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.block_size.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.size.to_le_bytes());
+        buf[24..32]
+            .copy_from_slice(&self.checkpoint.unwrap_or(0).to_le_bytes());
+        buf[32..].copy_from_slice(&self.base.unwrap_or(0).to_le_bytes());
+        buf
+    }
+
+    fn read(src: &mut impl Read) -> io::Result<Self> {
+        let mut buf = [0u8; HEADER_LEN];
+        src.read_exact(&mut buf[..HEADER_V1_LEN])?;
+        if buf[..8] != MAGIC {
+            return Err(invalid("not a disk export stream".to_string()));
+        }
+        let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        match version {
+            1 => {}
+            VERSION => src.read_exact(&mut buf[HEADER_V1_LEN..])?,
+            _ => return Err(invalid(format!("unsupported version {version}"))),
+        }
+        let checkpoint = |b: &[u8]| {
+            Some(u64::from_le_bytes(b.try_into().unwrap()))
+                .filter(|id| *id != 0)
+        };
+        Ok(Self {
+            block_size: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            size: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+            checkpoint: checkpoint(&buf[24..32]),
+            base: checkpoint(&buf[32..]),
+        })
+    }
+}
+
+fn record(kind: u32, len: u64) -> [u8; RECORD_LEN] {
+    let mut buf = [0u8; RECORD_LEN];
+    buf[..4].copy_from_slice(&kind.to_le_bytes());
+    buf[8..].copy_from_slice(&len.to_le_bytes());
+    buf
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Produces the export stream of a backend, as an iterator over its parts.
+///
+/// The backend is read through [`Backend::read_at()`], so the export is only
+/// consistent if the attached device issues no writes while it is underway.
+/// The stream ends after the first error in reading the backend.
+pub struct Exporter {
+    backend: Arc<dyn Backend>,
+    header: Header,
+    /// Changes since the base checkpoint, for an incremental export
+    delta: Option<Delta>,
+    started: bool,
+    done: bool,
+    /// Offset of the next byte of the device to be read
+    off: u64,
+    /// Chunk found in seeking the end of an extent, which is next to be
+    /// exported
+    pending: Option<Chunk>,
+}
+
+/// A [`CHUNK_SIZE`] portion of the device, as it is to be exported
+enum Chunk {
+    Data(Vec<u8>),
+    /// A chunk of the given length, exported by a record of the given kind
+    /// with no contents following it
+    Empty(u32, u64),
+}
+
+impl Exporter {
+    /// Exports the whole of the device.
+    pub fn new(backend: Arc<dyn Backend>) -> Self {
+        let info = backend.info();
+        let header = Header {
+            block_size: info.block_size,
+            size: info.total_size * u64::from(info.block_size),
+            checkpoint: None,
+            base: None,
+        };
+        Self {
+            backend,
+            header,
+            delta: None,
+            started: false,
+            done: false,
+            off: 0,
+            pending: None,
+        }
+    }
+
+    /// Exports only the ranges of the device changed since the checkpoint
+    /// which `delta` is relative to.
+    pub fn delta(backend: Arc<dyn Backend>, delta: Delta) -> Self {
+        let mut exporter = Self::new(backend);
+        exporter.header.base = Some(delta.base);
+        exporter.delta = Some(delta);
+        exporter
+    }
+
+    /// Records, in the header of the stream, a checkpoint taken of the device
+    /// as the export began.
+    pub fn with_checkpoint(mut self, checkpoint: CheckpointId) -> Self {
+        self.header.checkpoint = Some(checkpoint);
+        self
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// Reads the next chunk of the device, unless it is unchanged since the
+    /// base of an incremental export.
+    fn next_chunk(&mut self) -> io::Result<Chunk> {
+        let len = (self.header.size - self.off).min(CHUNK_SIZE as u64);
+        let idx = self.off as usize / GRANULE;
+        let off = self.off;
+        self.off += len;
+        if self.delta.as_ref().is_some_and(|delta| !delta.is_dirty(idx)) {
+            return Ok(Chunk::Empty(EXTENT_UNCHANGED, len));
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        self.backend.read_at(off as usize, &mut buf)?;
+        if buf.iter().any(|b| *b != 0) {
+            Ok(Chunk::Data(buf))
+        } else {
+            Ok(Chunk::Empty(EXTENT_ZERO, len))
+        }
+    }
+
+    fn next_part(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.started {
+            self.started = true;
+            return Ok(Some(self.header.to_bytes().to_vec()));
+        }
+        let chunk = match self.pending.take() {
+            Some(chunk) => chunk,
+            None if self.done => return Ok(None),
+            None if self.off == self.header.size => {
+                self.done = true;
+                return Ok(Some(record(EXTENT_END, 0).to_vec()));
+            }
+            None => self.next_chunk()?,
+        };
+        let (kind, mut len) = match chunk {
+            Chunk::Data(data) => return Ok(Some(data_part(data))),
+            Chunk::Empty(kind, len) => (kind, len),
+        };
+
+        // Coalesce the run of chunks exported by records of the same kind
+        while self.off < self.header.size {
+            match self.next_chunk()? {
+                Chunk::Empty(k, l) if k == kind => len += l,
+                chunk => {
+                    self.pending = Some(chunk);
+                    break;
+                }
+            }
+        }
+        Ok(Some(record(kind, len).to_vec()))
+    }
+}
+impl Iterator for Exporter {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_part() {
+            Ok(part) => part.map(Ok),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn data_part(data: Vec<u8>) -> Vec<u8> {
+    let mut part = Vec::with_capacity(RECORD_LEN + data.len());
+    part.extend_from_slice(&record(EXTENT_DATA, data.len() as u64));
+    part.extend_from_slice(&data);
+    part
+}
+
+/// Unpacks the export stream read from `src` into `dst`.
+///
+/// `dst` is truncated and sized to the device, and only its data extents are
+/// written, leaving the ranges which read as zeroes as holes in the file.
+/// Incremental exports are refused, as they must be [applied](apply) instead.
+pub fn unpack(mut src: impl Read, dst: &File) -> io::Result<Header> {
+    let header = Header::read(&mut src)?;
+    if header.base.is_some() {
+        return Err(invalid(
+            "incremental export must be applied to its base".to_string(),
+        ));
+    }
+    dst.set_len(0)?;
+    dst.set_len(header.size)?;
+    copy_extents(&mut src, dst, &header)?;
+    Ok(header)
+}
+
+/// Applies the incremental export stream read from `src` to `dst`, which must
+/// hold the contents of the device as of the export's base checkpoint.
+///
+/// The changed ranges of the device are written to `dst`, leaving it with the
+/// contents of the device as of the export.  Checking that `dst` is at the
+/// right checkpoint is left to the caller, which can compare the base in the
+/// returned [`Header`] to the checkpoint recorded by the preceding export.
+pub fn apply(mut src: impl Read, dst: &File) -> io::Result<Header> {
+    let header = Header::read(&mut src)?;
+    if header.base.is_none() {
+        return Err(invalid("not an incremental export".to_string()));
+    }
+    if dst.metadata()?.len() != header.size {
+        return Err(invalid("size of device has changed".to_string()));
+    }
+    copy_extents(&mut src, dst, &header)?;
+    Ok(header)
+}
+
+/// Writes the extents of the stream read from `src` to `dst`.  Zeroes are
+/// written out only for an incremental export, as `dst` is otherwise freshly
+/// sized, and so already reads as zeroes.
+fn copy_extents(
+    src: &mut impl Read,
+    dst: &File,
+    header: &Header,
+) -> io::Result<()> {
+    let incremental = header.base.is_some();
+    let mut off = 0u64;
+    let mut data = vec![0u8; CHUNK_SIZE];
+    let zeroes = vec![0u8; CHUNK_SIZE];
+    loop {
+        let mut rec = [0u8; RECORD_LEN];
+        src.read_exact(&mut rec)?;
+        let kind = u32::from_le_bytes(rec[..4].try_into().unwrap());
+        let len = u64::from_le_bytes(rec[8..].try_into().unwrap());
+        if off.checked_add(len).map_or(true, |end| end > header.size) {
+            return Err(invalid("extent beyond end of device".to_string()));
+        }
+
+        match kind {
+            EXTENT_END => break,
+            EXTENT_ZERO if incremental => {
+                let mut pos = off;
+                while pos < off + len {
+                    let n = (off + len - pos).min(CHUNK_SIZE as u64) as usize;
+                    dst.write_all_at(&zeroes[..n], pos)?;
+                    pos += n as u64;
+                }
+            }
+            EXTENT_ZERO => {}
+            EXTENT_UNCHANGED if incremental => {}
+            EXTENT_DATA => {
+                let mut pos = off;
+                while pos < off + len {
+                    let n = (off + len - pos).min(CHUNK_SIZE as u64) as usize;
+                    src.read_exact(&mut data[..n])?;
+                    dst.write_all_at(&data[..n], pos)?;
+                    pos += n as u64;
+                }
+            }
+            _ => return Err(invalid(format!("unknown extent kind {kind}"))),
+        }
+        off += len;
+    }
+
+    if off != header.size {
+        return Err(invalid("stream ends before end of device".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::dirty::DirtyMap;
+    use crate::block::{BackendOpts, InMemoryBackend};
+    use std::num::NonZeroUsize;
+
+    fn backend(bytes: Vec<u8>) -> Arc<dyn Backend> {
+        InMemoryBackend::create(
+            bytes,
+            BackendOpts::default(),
+            NonZeroUsize::new(1).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn zero_ranges_elided() {
+        // Data in the first and last chunks, and zeroes between
+        let mut bytes = vec![0u8; CHUNK_SIZE * 4];
+        bytes[0x10] = 0xaa;
+        bytes[CHUNK_SIZE * 3 + 0x20] = 0x55;
+
+        let parts = Exporter::new(backend(bytes.clone()))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let lens: Vec<_> = parts.iter().map(Vec::len).collect();
+        assert_eq!(
+            lens,
+            [
+                HEADER_LEN,
+                RECORD_LEN + CHUNK_SIZE,
+                RECORD_LEN,
+                RECORD_LEN + CHUNK_SIZE,
+                RECORD_LEN,
+            ]
+        );
+        assert_eq!(parts[2], record(EXTENT_ZERO, CHUNK_SIZE as u64 * 2));
+
+        let dir = tempfile::tempdir().unwrap();
+        let dst = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("disk.raw"))
+            .unwrap();
+        let header = unpack(parts.concat().as_slice(), &dst).unwrap();
+        assert_eq!(
+            header,
+            Header {
+                block_size: 512,
+                size: bytes.len() as u64,
+                checkpoint: None,
+                base: None,
+            }
+        );
+        let mut unpacked = vec![0u8; bytes.len()];
+        dst.read_exact_at(&mut unpacked, 0).unwrap();
+        assert_eq!(unpacked, bytes);
+    }
+
+    #[test]
+    fn incremental_applied_to_base() {
+        let mut old = vec![0u8; CHUNK_SIZE * 6];
+        old[CHUNK_SIZE * 2] = 0x22;
+        old[CHUNK_SIZE * 5] = 0x55;
+
+        // Write to the second chunk, and zero the third
+        let map = DirtyMap::default();
+        let base = map.checkpoint();
+        let mut new = old.clone();
+        new[CHUNK_SIZE + 0x10] = 0x11;
+        map.mark(CHUNK_SIZE + 0x10, 1);
+        new[CHUNK_SIZE * 2] = 0;
+        map.mark(CHUNK_SIZE * 2, CHUNK_SIZE);
+
+        let delta = map.changes_since(base).unwrap();
+        let exporter =
+            Exporter::delta(backend(new.clone()), delta).with_checkpoint(7);
+        let parts = exporter.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(parts[1], record(EXTENT_UNCHANGED, CHUNK_SIZE as u64));
+        assert_eq!(parts[3], record(EXTENT_ZERO, CHUNK_SIZE as u64));
+        assert_eq!(parts[4], record(EXTENT_UNCHANGED, CHUNK_SIZE as u64 * 3));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.raw");
+        std::fs::write(&path, &old).unwrap();
+        let dst = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let stream = parts.concat();
+        assert!(unpack(stream.as_slice(), &dst).is_err());
+        let header = apply(stream.as_slice(), &dst).unwrap();
+        assert_eq!(header.base, Some(base));
+        assert_eq!(header.checkpoint, Some(7));
+        let mut applied = vec![0u8; new.len()];
+        dst.read_exact_at(&mut applied, 0).unwrap();
+        assert_eq!(applied, new);
+    }
+
+    #[test]
+    fn truncated_stream_rejected() {
+        let bytes = vec![0x11u8; CHUNK_SIZE + 512];
+        let parts = Exporter::new(backend(bytes))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let stream = parts[..parts.len() - 1].concat();
+
+        let dir = tempfile::tempdir().unwrap();
+        let dst = File::create(dir.path().join("disk.raw")).unwrap();
+        assert!(unpack(stream.as_slice(), &dst).is_err());
+    }
+}
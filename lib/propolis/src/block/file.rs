@@ -5,17 +5,21 @@
 use std::fs::{metadata, File, OpenOptions};
 use std::io::{Error, ErrorKind, Result};
 use std::num::NonZeroUsize;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::sync::Arc;
 
 use crate::accessors::MemAccessor;
 use crate::block::{self, DeviceInfo};
+use crate::harden;
+use crate::hostres;
 use crate::inventory::Entity;
 use crate::vmm::{MappingExt, MemCtx};
 
-// XXX: completely arb for now
-const MAX_WORKERS: usize = 32;
+/// Size of the buffer of zeroes written by a write-zeroes request to a file
+/// which cannot be deallocated
+const ZERO_BUF_SIZE: usize = 1024 * 1024;
 
 pub struct FileBackend {
     state: Arc<WorkerState>,
@@ -25,6 +29,8 @@ pub struct FileBackend {
 struct WorkerState {
     attachment: block::backend::Attachment,
     fp: File,
+    res_owner: Arc<hostres::Owner>,
+    _fd_held: hostres::Held,
 
     info: block::DeviceInfo,
     skip_flush: bool,
@@ -32,7 +38,7 @@ struct WorkerState {
 impl WorkerState {
     fn processing_loop(&self, acc_mem: MemAccessor) {
         while let Some(req) = self.attachment.block_for_req() {
-            if self.info.read_only && req.oper().is_write() {
+            if self.info.read_only && req.oper().is_mutating() {
                 req.complete(block::Result::ReadOnly);
                 continue;
             }
@@ -80,14 +86,95 @@ impl WorkerState {
             }
             block::Operation::Flush => {
                 if !self.skip_flush {
+                    let sync = self.attachment.audit().begin_sync();
                     self.fp.sync_data().map_err(|_| "io error")?;
+                    sync.succeeded();
                 }
             }
+            block::Operation::Discard => {
+                if !self.info.supports_discard {
+                    return Err("discard unsupported");
+                }
+                for &(off, len) in req.ranges() {
+                    punch_hole(&self.fp, off, len).map_err(|_| "io error")?;
+                }
+            }
+            block::Operation::WriteZeroes(off, len) => {
+                // Deallocating a range of a regular file leaves a hole which
+                // reads back as zeroes, keeping a sparse image sparse, but only
+                // where the guest permits the range to be deallocated.
+                if !req.may_unmap()
+                    || !self.info.supports_discard
+                    || punch_hole(&self.fp, off, len).is_err()
+                {
+                    self.write_zeroes(off, len).map_err(|_| "io error")?;
+                }
+            }
+        }
+        if req.oper().is_mutating() {
+            req.stored();
+        }
+        Ok(())
+    }
+
+    fn write_zeroes(&self, mut off: usize, mut len: usize) -> Result<()> {
+        let buf = vec![0u8; len.min(ZERO_BUF_SIZE)];
+        while len > 0 {
+            let chunk = len.min(buf.len());
+            self.fp.write_all_at(&buf[..chunk], off as u64)?;
+            off += chunk;
+            len -= chunk;
         }
         Ok(())
     }
 }
 
+/// Deallocate `len` bytes of `fp` at `off`, which read back as zeroes
+/// thereafter.  The size of the file is unchanged.
+#[cfg(target_os = "illumos")]
+fn punch_hole(fp: &File, off: usize, len: usize) -> Result<()> {
+    // From <sys/fcntl.h>
+    const F_FREESP: libc::c_int = 11;
+
+    // A zero length would free everything from `off` to the end of the file.
+    if len == 0 {
+        return Ok(());
+    }
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = off as libc::off_t;
+    fl.l_len = len as libc::off_t;
+    match unsafe { libc::fcntl(fp.as_raw_fd(), F_FREESP, &fl) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn punch_hole(fp: &File, off: usize, len: usize) -> Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let res = unsafe {
+        libc::fallocate(
+            fp.as_raw_fd(),
+            mode,
+            off as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "illumos", target_os = "linux")))]
+fn punch_hole(_fp: &File, _off: usize, _len: usize) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "hole punching unsupported"))
+}
+
 impl FileBackend {
     /// Creates a new block device from a device at `path`.
     pub fn create(
@@ -95,15 +182,16 @@ impl FileBackend {
         opts: block::BackendOpts,
         worker_count: NonZeroUsize,
     ) -> Result<Arc<Self>> {
-        if worker_count.get() > MAX_WORKERS {
+        if worker_count.get() > block::MAX_FILE_WORKERS {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
                 "too many workers",
             ));
         }
         let p: &Path = path.as_ref();
+        let host = harden::host_path(p)?;
 
-        let meta = metadata(p)?;
+        let meta = metadata(&host)?;
         let read_only = match (opts.read_only, meta.permissions().readonly()) {
             (Some(false), true) => Err(Error::new(
                 ErrorKind::Other,
@@ -113,8 +201,11 @@ impl FileBackend {
             (_, file_ro) => Ok(file_ro),
         }?;
 
-        let fp = OpenOptions::new().read(true).write(!read_only).open(p)?;
+        let fp = OpenOptions::new().read(true).write(!read_only).open(host)?;
         let len = fp.metadata().unwrap().len();
+        let res_owner =
+            hostres::Owner::new(format!("block-file-{}", p.display()));
+        let _fd_held = res_owner.hold(hostres::Kind::Fd, 1);
         // TODO: attempt to query blocksize from underlying file/zvol
         let block_size = opts.block_size.unwrap_or(block::DEFAULT_BLOCK_SIZE);
 
@@ -123,12 +214,17 @@ impl FileBackend {
                 attachment: block::backend::Attachment::new(),
 
                 fp,
+                res_owner,
+                _fd_held,
 
                 skip_flush: opts.skip_flush.unwrap_or(false),
                 info: block::DeviceInfo {
                     block_size,
                     total_size: len / block_size as u64,
                     read_only,
+                    // Only regular files can have holes punched in them
+                    supports_discard: !read_only && meta.is_file(),
+                    supports_write_zeroes: !read_only,
                 },
             }),
             worker_count,
@@ -142,9 +238,11 @@ impl FileBackend {
                     .child(Some(format!("worker {n}")))
             });
 
+            let held = self.state.res_owner.hold(hostres::Kind::Thread, 1);
             let _join = std::thread::Builder::new()
                 .name(format!("file worker {n}"))
                 .spawn(move || {
+                    let _held = held;
                     worker_state.processing_loop(worker_acc);
                 })?;
         }
@@ -160,6 +258,10 @@ impl block::Backend for FileBackend {
     fn info(&self) -> DeviceInfo {
         self.state.info
     }
+
+    fn read_at(&self, off: block::ByteOffset, buf: &mut [u8]) -> Result<()> {
+        self.state.fp.read_exact_at(buf, off as u64)
+    }
 }
 impl Entity for FileBackend {
     fn type_name(&self) -> &'static str {
@@ -0,0 +1,392 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Health supervision for network-backed block backends
+//!
+//! Backends whose storage lives across a network (Crucible, or any future
+//! NBD/iSCSI implementation) can experience transient outages which are better
+//! ridden out than reported to the guest as hard I/O errors.  The
+//! [`HealthMonitor`] tracks the outcome of requests issued to such a backend,
+//! detects when it has stalled, and drives the retry policy described by
+//! [`RetryPolicy`].  Changes in health are published as [`HealthEvent`]s for
+//! consumption by interested parties (such as the server API).
+
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::block;
+
+use slog::{info, warn};
+use tokio::sync::watch;
+
+/// Policy governing how failed or stalled requests to a remote backend are
+/// retried before a result is returned to the guest.
+#[derive(Copy, Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts (including the first) for a request.
+    pub max_attempts: u32,
+    /// Delay prior to the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between retries
+    pub max_backoff: Duration,
+    /// Time after which an attempt with no result is considered stalled
+    pub stall_timeout: Duration,
+    /// When retries are exhausted, complete the request as
+    /// [`block::Result::NotReady`] (leaving the guest to retry against media
+    /// which is "not ready") rather than as a hard failure.  Devices with no
+    /// means of reporting such a condition to the guest instead have the
+    /// request held, and retried at `max_backoff`, until it completes.
+    pub not_ready_on_exhaust: bool,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            stall_timeout: Duration::from_secs(30),
+            not_ready_on_exhaust: false,
+        }
+    }
+}
+impl RetryPolicy {
+    /// Check that the policy allows requests to be issued and completed.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.max_attempts == 0 {
+            Err("retry policy must allow at least one attempt")
+        } else if self.stall_timeout.is_zero() {
+            Err("retry policy stall timeout must be non-zero")
+        } else if self.initial_backoff > self.max_backoff {
+            Err("retry policy initial backoff exceeds its maximum")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Delay to wait before issuing attempt number `attempt` (starting at 1 for
+    /// the first retry).  The delay doubles with each attempt, capped at
+    /// `max_backoff`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let shift = attempt.saturating_sub(1).min(31);
+        self.initial_backoff
+            .checked_mul(1u32 << shift)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// Health of a supervised backend
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HealthState {
+    /// Requests are completing normally
+    Healthy,
+    /// Recent requests have failed, but are being retried
+    Degraded,
+    /// An outstanding request has not completed within the stall timeout
+    Stalled,
+    /// Retries have been exhausted for at least one request
+    Failed,
+}
+
+/// Event published when the health of a supervised backend changes.
+#[derive(Copy, Clone, Debug)]
+pub struct HealthEvent {
+    pub state: HealthState,
+    /// Number of consecutive attempts which have failed or stalled
+    pub consecutive_failures: u32,
+    /// Time at which the most recent successful request completed
+    pub last_success: Option<Instant>,
+}
+
+struct MonitorState {
+    consecutive_failures: u32,
+    last_success: Option<Instant>,
+}
+
+/// Tracks request outcomes for a remote backend and applies a [`RetryPolicy`].
+pub struct HealthMonitor {
+    policy: RetryPolicy,
+    state: Mutex<MonitorState>,
+    events: watch::Sender<HealthEvent>,
+    log: slog::Logger,
+}
+impl HealthMonitor {
+    pub fn new(policy: RetryPolicy, log: slog::Logger) -> Self {
+        let (events, _) = watch::channel(HealthEvent {
+            state: HealthState::Healthy,
+            consecutive_failures: 0,
+            last_success: None,
+        });
+        Self {
+            policy,
+            state: Mutex::new(MonitorState {
+                consecutive_failures: 0,
+                last_success: None,
+            }),
+            events,
+            log,
+        }
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Subscribe to changes in backend health
+    pub fn subscribe(&self) -> watch::Receiver<HealthEvent> {
+        self.events.subscribe()
+    }
+
+    /// Current health of the backend
+    pub fn current(&self) -> HealthEvent {
+        *self.events.borrow()
+    }
+
+    fn publish(&self, state: HealthState, inner: &MonitorState) {
+        let event = HealthEvent {
+            state,
+            consecutive_failures: inner.consecutive_failures,
+            last_success: inner.last_success,
+        };
+        self.events.send_if_modified(|cur| {
+            let changed = cur.state != event.state;
+            *cur = event;
+            changed
+        });
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.state.lock().unwrap();
+        if inner.consecutive_failures != 0 {
+            info!(self.log, "backend recovered";
+                "failed_attempts" => inner.consecutive_failures);
+        }
+        inner.consecutive_failures = 0;
+        inner.last_success = Some(Instant::now());
+        self.publish(HealthState::Healthy, &inner);
+    }
+
+    fn record_failure(&self, state: HealthState) {
+        let mut inner = self.state.lock().unwrap();
+        inner.consecutive_failures += 1;
+        self.publish(state, &inner);
+    }
+
+    /// Run an operation against the backend under supervision.
+    ///
+    /// The future emitted by `attempt` is awaited for up to the configured
+    /// stall timeout.  Failures which `retryable` deems transient, as well as
+    /// stalls, are retried with exponential backoff until the attempt limit is
+    /// reached, at which point the request is completed according to the
+    /// policy.  `reports_not_ready` indicates whether the device which issued
+    /// the request can pass [`block::Result::NotReady`] on to the guest; if it
+    /// cannot, such a request is held and retried rather than failed.
+    pub async fn supervise<F, Fut, E>(
+        &self,
+        mut attempt: F,
+        retryable: impl Fn(&E) -> bool,
+        reports_not_ready: bool,
+    ) -> block::Result
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: Into<block::Result> + std::fmt::Display,
+    {
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            // A request which has exhausted its retries leaves the backend
+            // failed, even while it continues to be held.
+            let exhausted = tries >= self.policy.max_attempts;
+            match tokio::time::timeout(self.policy.stall_timeout, attempt())
+                .await
+            {
+                Ok(Ok(())) => {
+                    self.record_success();
+                    return block::Result::Success;
+                }
+                Ok(Err(e)) if !retryable(&e) => {
+                    // Errors such as writes to a read-only backend will not be
+                    // cured by retrying, so pass them through directly.
+                    return e.into();
+                }
+                Ok(Err(e)) => {
+                    warn!(self.log, "backend request failed";
+                        "attempt" => tries, "error" => %e);
+                    self.record_failure(if exhausted {
+                        HealthState::Failed
+                    } else {
+                        HealthState::Degraded
+                    });
+                }
+                Err(_) => {
+                    warn!(self.log, "backend request stalled";
+                        "attempt" => tries,
+                        "timeout" => ?self.policy.stall_timeout);
+                    self.record_failure(if exhausted {
+                        HealthState::Failed
+                    } else {
+                        HealthState::Stalled
+                    });
+                }
+            }
+
+            if exhausted {
+                match (self.policy.not_ready_on_exhaust, reports_not_ready) {
+                    (false, _) => return block::Result::Failure,
+                    (true, true) => return block::Result::NotReady,
+                    (true, false) => {}
+                }
+            }
+            tokio::time::sleep(self.policy.backoff(tries)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[derive(Debug)]
+    struct TestErr(bool);
+    impl std::fmt::Display for TestErr {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "test error (retryable: {})", self.0)
+        }
+    }
+    impl From<TestErr> for block::Result {
+        fn from(_: TestErr) -> Self {
+            block::Result::ReadOnly
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mon = HealthMonitor::new(policy, test_log());
+        let mut calls = 0;
+        let res = mon
+            .supervise(
+                || {
+                    calls += 1;
+                    let fail = calls < 3;
+                    async move {
+                        if fail {
+                            Err(TestErr(true))
+                        } else {
+                            Ok(())
+                        }
+                    }
+                },
+                |e| e.0,
+                true,
+            )
+            .await;
+        assert!(matches!(res, block::Result::Success));
+        assert_eq!(calls, 3);
+        assert_eq!(mon.current().state, HealthState::Healthy);
+    }
+
+    #[tokio::test]
+    async fn exhaustion_reports_not_ready() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            not_ready_on_exhaust: true,
+            ..Default::default()
+        };
+        let mon = HealthMonitor::new(policy, test_log());
+        let res = mon
+            .supervise(|| async { Err(TestErr(true)) }, |e: &TestErr| e.0, true)
+            .await;
+        assert!(matches!(res, block::Result::NotReady));
+        assert_eq!(mon.current().state, HealthState::Failed);
+        assert_eq!(mon.current().consecutive_failures, 2);
+    }
+
+    #[tokio::test]
+    async fn exhaustion_held_without_not_ready() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            not_ready_on_exhaust: true,
+            ..Default::default()
+        };
+        let mon = HealthMonitor::new(policy, test_log());
+        let mut calls = 0;
+        let res = mon
+            .supervise(
+                || {
+                    calls += 1;
+                    let fail = calls < 5;
+                    async move {
+                        if fail {
+                            Err(TestErr(true))
+                        } else {
+                            Ok(())
+                        }
+                    }
+                },
+                |e| e.0,
+                false,
+            )
+            .await;
+        assert!(matches!(res, block::Result::Success));
+        assert_eq!(calls, 5);
+        assert_eq!(mon.current().state, HealthState::Healthy);
+    }
+
+    #[test]
+    fn invalid_policies() {
+        let zero = RetryPolicy { max_attempts: 0, ..Default::default() };
+        assert!(zero.validate().is_err());
+        let stall =
+            RetryPolicy { stall_timeout: Duration::ZERO, ..Default::default() };
+        assert!(stall.validate().is_err());
+        assert!(RetryPolicy::default().validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn fatal_errors_not_retried() {
+        let mon = HealthMonitor::new(RetryPolicy::default(), test_log());
+        let mut calls = 0;
+        let res = mon
+            .supervise(
+                || {
+                    calls += 1;
+                    async { Err(TestErr(false)) }
+                },
+                |e| e.0,
+                true,
+            )
+            .await;
+        assert!(matches!(res, block::Result::ReadOnly));
+        assert_eq!(calls, 1);
+    }
+}
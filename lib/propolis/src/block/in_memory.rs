@@ -8,6 +8,7 @@ use std::sync::{Arc, Mutex};
 
 use crate::accessors::MemAccessor;
 use crate::block;
+use crate::hostres;
 use crate::inventory::Entity;
 use crate::vmm::{MemCtx, SubMapping};
 
@@ -20,11 +21,12 @@ struct WorkingState {
     attachment: block::backend::Attachment,
     bytes: Mutex<Vec<u8>>,
     info: block::DeviceInfo,
+    res_owner: Arc<hostres::Owner>,
 }
 impl WorkingState {
     fn processing_loop(&self, acc_mem: MemAccessor) {
         while let Some(req) = self.attachment.block_for_req() {
-            if self.info.read_only && req.oper().is_write() {
+            if self.info.read_only && req.oper().is_mutating() {
                 req.complete(block::Result::ReadOnly);
                 continue;
             }
@@ -74,8 +76,24 @@ impl WorkingState {
                 process_write_request(&mut bytes, off as u64, len, &maps)?;
             }
             block::Operation::Flush => {
-                // nothing to do
+                // Writes are applied in place as they complete, leaving
+                // nothing to sync
+                self.attachment.audit().begin_sync().succeeded();
             }
+            block::Operation::Discard => {
+                // Discarded ranges simply read back as zeroes
+                let mut bytes = self.bytes.lock().unwrap();
+                for &(off, len) in req.ranges() {
+                    zero_range(&mut bytes, off, len)?;
+                }
+            }
+            block::Operation::WriteZeroes(off, len) => {
+                let mut bytes = self.bytes.lock().unwrap();
+                zero_range(&mut bytes, off, len)?;
+            }
+        }
+        if req.oper().is_mutating() {
+            req.stored();
         }
 
         Ok(())
@@ -111,7 +129,10 @@ impl InMemoryBackend {
                     block_size,
                     total_size: len as u64 / block_size as u64,
                     read_only: opts.read_only.unwrap_or(false),
+                    supports_discard: true,
+                    supports_write_zeroes: true,
                 },
+                res_owner: hostres::Owner::new("block-in-memory"),
             }),
             worker_count,
         }))
@@ -124,9 +145,11 @@ impl InMemoryBackend {
                     .child(Some(format!("worker {n}")))
             });
 
+            let held = self.state.res_owner.hold(hostres::Kind::Thread, 1);
             let _join = std::thread::Builder::new()
                 .name(format!("in-memory worker {n}"))
                 .spawn(move || {
+                    let _held = held;
                     worker_state.processing_loop(worker_acc);
                 })?;
         }
@@ -141,6 +164,17 @@ impl block::Backend for InMemoryBackend {
     fn info(&self) -> block::DeviceInfo {
         self.state.info
     }
+    fn read_at(&self, off: block::ByteOffset, buf: &mut [u8]) -> Result<()> {
+        let bytes = self.state.bytes.lock().unwrap();
+        let data = off
+            .checked_add(buf.len())
+            .and_then(|end| bytes.get(off..end))
+            .ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "read beyond end of disk")
+            })?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
 }
 
 impl Entity for InMemoryBackend {
@@ -222,3 +256,22 @@ fn process_write_request(
 
     Ok(())
 }
+
+/// Zero `len` bytes at `offset`
+fn zero_range(bytes: &mut [u8], offset: usize, len: usize) -> Result<()> {
+    let total = bytes.len();
+    let data = offset
+        .checked_add(len)
+        .and_then(|end| bytes.get_mut(offset..end))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid offset {} and len {} when bytes len is {}",
+                    offset, len, total,
+                ),
+            )
+        })?;
+    data.fill(0);
+    Ok(())
+}
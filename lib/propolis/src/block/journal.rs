@@ -0,0 +1,253 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Local write-ahead journal for network-backed block backends
+//!
+//! A write acknowledged by remote storage is not durable until a subsequent
+//! flush has completed.  Should the connection to that storage be lost in the
+//! meantime, the write may be lost along with it, and the guest would see the
+//! outage as I/O errors.  A [`Journal`] keeps a copy, in a local file, of each
+//! write issued to the backend since its last successful flush.  When a request
+//! to the backend fails, I/O is paused until the backend is reachable again,
+//! at which point the journaled writes are replayed and flushed, and the
+//! failed request is reissued.  The guest sees only the delay.
+//!
+//! The journal is meant to ride out outages of the backend, not of the host:
+//! it is emptied when created, and is not synced to stable storage.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Parameters controlling the journal of a backend.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// File in which journaled writes are stored
+    pub path: PathBuf,
+    /// Size the journal may reach before the backend is flushed to empty it
+    pub max_bytes: u64,
+    /// Delay between attempts to replay the journal to an unreachable backend
+    pub retry_interval: Duration,
+    /// Length of an outage after which replay is abandoned, and the pending
+    /// request is failed to the guest
+    pub max_outage: Duration,
+}
+impl Policy {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: 64 * 1024 * 1024,
+            retry_interval: Duration::from_secs(1),
+            max_outage: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Position in a [`Journal`], covering each write appended before it was taken
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Mark(u64);
+
+const MAGIC: u32 = 0x4a524e4c; // "JRNL"
+const HDR_LEN: u64 = 16;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    seq: u64,
+    /// Position of the entry's header in the file
+    pos: u64,
+    off: u64,
+    len: u32,
+}
+
+struct Inner {
+    entries: VecDeque<Entry>,
+    next_seq: u64,
+    file_len: u64,
+}
+
+pub struct Journal {
+    policy: Policy,
+    fp: File,
+    inner: Mutex<Inner>,
+}
+impl Journal {
+    /// Create a journal as described by `policy`, discarding any prior contents
+    /// of its file.
+    pub fn create(policy: Policy) -> io::Result<Self> {
+        let fp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(crate::harden::host_path(&policy.path)?)?;
+        Ok(Self {
+            policy,
+            fp,
+            inner: Mutex::new(Inner {
+                entries: VecDeque::new(),
+                next_seq: 0,
+                file_len: 0,
+            }),
+        })
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Record a write of `data` to byte offset `off` of the backend
+    pub fn append(&self, off: u64, data: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(data.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "write too large")
+        })?;
+
+        let mut inner = self.inner.lock().unwrap();
+        let pos = inner.file_len;
+        let mut hdr = [0u8; HDR_LEN as usize];
+        hdr[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        hdr[4..8].copy_from_slice(&len.to_le_bytes());
+        hdr[8..16].copy_from_slice(&off.to_le_bytes());
+        self.fp.write_all_at(&hdr, pos)?;
+        self.fp.write_all_at(data, pos + HDR_LEN)?;
+
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.file_len = pos + HDR_LEN + len as u64;
+        inner.entries.push_back(Entry { seq, pos, off, len });
+        Ok(())
+    }
+
+    /// Take a [`Mark`] covering every write appended so far
+    pub fn mark(&self) -> Mark {
+        Mark(self.inner.lock().unwrap().next_seq)
+    }
+
+    /// Discard the writes covered by `mark`, which the backend has made
+    /// durable.  The file is truncated once no writes remain.
+    pub fn retire(&self, mark: Mark) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        while matches!(inner.entries.front(), Some(e) if e.seq < mark.0) {
+            inner.entries.pop_front();
+        }
+        if inner.entries.is_empty() && inner.file_len != 0 {
+            self.fp.set_len(0)?;
+            inner.file_len = 0;
+        }
+        Ok(())
+    }
+
+    /// Has the journal reached the size at which it should be emptied?
+    pub fn is_full(&self) -> bool {
+        self.inner.lock().unwrap().file_len >= self.policy.max_bytes
+    }
+
+    /// Number of writes held in the journal
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Read back the journaled writes, oldest first, as pairs of byte offset
+    /// and data.
+    pub fn read_entries(&self) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter()
+            .map(|e| {
+                let mut hdr = [0u8; HDR_LEN as usize];
+                self.fp.read_exact_at(&mut hdr, e.pos)?;
+                let magic = u32::from_le_bytes(hdr[0..4].try_into().unwrap());
+                let len = u32::from_le_bytes(hdr[4..8].try_into().unwrap());
+                let off = u64::from_le_bytes(hdr[8..16].try_into().unwrap());
+                if magic != MAGIC || len != e.len || off != e.off {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "corrupt journal entry",
+                    ));
+                }
+                let mut data = vec![0u8; len as usize];
+                self.fp.read_exact_at(&mut data, e.pos + HDR_LEN)?;
+                Ok((off, data))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_journal(dir: &tempfile::TempDir, max_bytes: u64) -> Journal {
+        let policy =
+            Policy { max_bytes, ..Policy::new(dir.path().join("journal")) };
+        Journal::create(policy).unwrap()
+    }
+
+    #[test]
+    fn entries_read_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = test_journal(&dir, 1024 * 1024);
+
+        journal.append(4096, &[1u8; 512]).unwrap();
+        journal.append(0, &[2u8; 1024]).unwrap();
+        assert_eq!(journal.len(), 2);
+
+        let entries = journal.read_entries().unwrap();
+        assert_eq!(entries, vec![(4096, vec![1u8; 512]), (0, vec![2u8; 1024])]);
+    }
+
+    #[test]
+    fn retire_discards_only_marked_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = test_journal(&dir, 1024 * 1024);
+
+        journal.append(0, &[1u8; 512]).unwrap();
+        let mark = journal.mark();
+        journal.append(512, &[2u8; 512]).unwrap();
+
+        journal.retire(mark).unwrap();
+        assert_eq!(
+            journal.read_entries().unwrap(),
+            vec![(512, vec![2u8; 512])]
+        );
+
+        journal.retire(journal.mark()).unwrap();
+        assert!(journal.is_empty());
+        assert_eq!(journal.fp.metadata().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn full_until_retired() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = test_journal(&dir, 1024);
+
+        journal.append(0, &[0u8; 512]).unwrap();
+        assert!(!journal.is_full());
+        journal.append(512, &[0u8; 512]).unwrap();
+        assert!(journal.is_full());
+
+        journal.retire(journal.mark()).unwrap();
+        assert!(!journal.is_full());
+    }
+
+    #[test]
+    fn create_discards_prior_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = test_journal(&dir, 1024 * 1024);
+        journal.append(0, &[1u8; 512]).unwrap();
+        drop(journal);
+
+        let journal = test_journal(&dir, 1024 * 1024);
+        assert!(journal.is_empty());
+        assert!(journal.read_entries().unwrap().is_empty());
+    }
+}
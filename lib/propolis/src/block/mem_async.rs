@@ -9,6 +9,7 @@ use std::sync::Arc;
 
 use crate::accessors::MemAccessor;
 use crate::block;
+use crate::hostres;
 use crate::inventory::Entity;
 use crate::vmm::MemCtx;
 
@@ -26,11 +27,12 @@ struct WorkingState {
     attachment: block::backend::Attachment,
     seg: MmapSeg,
     info: block::DeviceInfo,
+    res_owner: Arc<hostres::Owner>,
 }
 impl WorkingState {
     async fn processing_loop(&self, acc_mem: MemAccessor) {
         while let Some(req) = self.attachment.wait_for_req().await {
-            if self.info.read_only && req.oper().is_write() {
+            if self.info.read_only && req.oper().is_mutating() {
                 req.complete(block::Result::ReadOnly);
                 continue;
             }
@@ -56,7 +58,7 @@ impl WorkingState {
                 let maps = req.mappings(mem).ok_or("bad mapping")?;
 
                 let mut nread = 0;
-                for map in maps {
+                for map in maps.iter() {
                     unsafe {
                         let len = map.len();
                         let read_ptr = map
@@ -73,7 +75,7 @@ impl WorkingState {
                 let maps = req.mappings(mem).ok_or("bad mapping")?;
 
                 let mut nwritten = 0;
-                for map in maps {
+                for map in maps.iter() {
                     unsafe {
                         let len = map.len();
                         let write_ptr = map
@@ -87,8 +89,16 @@ impl WorkingState {
                 }
             }
             block::Operation::Flush => {
-                // nothing to do
+                // Writes are applied in place as they complete, leaving
+                // nothing to sync
+                self.attachment.audit().begin_sync().succeeded();
             }
+            block::Operation::Discard | block::Operation::WriteZeroes(..) => {
+                return Err("operation not supported");
+            }
+        }
+        if req.oper().is_mutating() {
+            req.stored();
         }
 
         Ok(())
@@ -115,7 +125,8 @@ impl MemAsyncBackend {
             ));
         }
 
-        let seg = MmapSeg::new(size as usize)?;
+        let res_owner = hostres::Owner::new("block-memory-async");
+        let seg = MmapSeg::new(size as usize, &res_owner)?;
 
         Ok(Arc::new(Self {
             work_state: Arc::new(WorkingState {
@@ -124,8 +135,11 @@ impl MemAsyncBackend {
                     block_size,
                     total_size: size / block_size as u64,
                     read_only: opts.read_only.unwrap_or(false),
+                    supports_discard: false,
+                    supports_write_zeroes: false,
                 },
                 seg,
+                res_owner,
             }),
 
             workers,
@@ -141,16 +155,18 @@ impl MemAsyncBackend {
                         .expect("backend is attached")
                         .child(Some(format!("worker {n}")))
                 });
+            let held = self.work_state.res_owner.hold(hostres::Kind::Task, 1);
             tokio::spawn(async move {
+                let _held = held;
                 worker_state.processing_loop(worker_acc).await
             });
         }
     }
 }
 
-struct MmapSeg(NonNull<u8>, usize);
+struct MmapSeg(NonNull<u8>, usize, hostres::Held);
 impl MmapSeg {
-    fn new(size: usize) -> Result<Self> {
+    fn new(size: usize, res_owner: &Arc<hostres::Owner>) -> Result<Self> {
         let ptr = unsafe {
             libc::mmap(
                 core::ptr::null_mut(),
@@ -165,7 +181,11 @@ impl MmapSeg {
         if ptr == libc::MAP_FAILED {
             return Err(Error::last_os_error());
         }
-        Ok(Self(NonNull::new(ptr as *mut u8).unwrap(), size))
+        Ok(Self(
+            NonNull::new(ptr as *mut u8).unwrap(),
+            size,
+            res_owner.hold(hostres::Kind::MappedBytes, size as u64),
+        ))
     }
     unsafe fn write(&self, off: usize, data: *const u8, sz: usize) -> bool {
         if (off + sz) > self.1 {
@@ -202,6 +222,18 @@ impl block::Backend for MemAsyncBackend {
     fn attachment(&self) -> &block::backend::Attachment {
         &self.work_state.attachment
     }
+    fn read_at(&self, off: block::ByteOffset, buf: &mut [u8]) -> Result<()> {
+        // Safety: `buf` is valid for writes of its own length
+        match unsafe {
+            self.work_state.seg.read(off, buf.as_mut_ptr(), buf.len())
+        } {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "read beyond end of segment",
+            )),
+        }
+    }
 }
 
 impl Entity for MemAsyncBackend {
@@ -25,8 +25,22 @@ pub use in_memory::InMemoryBackend;
 mod mem_async;
 pub use mem_async::MemAsyncBackend;
 
+mod null;
+pub use null::NullBackend;
+
+mod shared;
+pub use shared::{SharedBackend, SharedImage};
+
+pub mod audit;
 pub mod backend;
 pub mod device;
+pub mod dirty;
+pub mod export;
+pub mod health;
+pub mod journal;
+pub mod prefetch;
+pub mod priority;
+pub use priority::Priority;
 
 pub type ByteOffset = usize;
 pub type ByteLen = usize;
@@ -35,11 +49,23 @@ pub type ByteLen = usize;
 /// is not choosing a block size, a default of 512B is used.
 pub const DEFAULT_BLOCK_SIZE: u32 = 512;
 
+/// Most worker threads which a file-backed block backend may be created with.
+// XXX: completely arb for now
+pub const MAX_FILE_WORKERS: usize = 32;
+
 #[usdt::provider(provider = "propolis")]
 mod probes {
     fn block_begin_read(dev_id: u64, req_id: u64, offset: u64, len: u64) {}
     fn block_begin_write(dev_id: u64, req_id: u64, offset: u64, len: u64) {}
     fn block_begin_flush(dev_id: u64, req_id: u64) {}
+    fn block_begin_discard(dev_id: u64, req_id: u64, nranges: u64) {}
+    fn block_begin_write_zeroes(
+        dev_id: u64,
+        req_id: u64,
+        offset: u64,
+        len: u64,
+    ) {
+    }
 
     fn block_complete_read(
         dev_id: u64,
@@ -65,6 +91,25 @@ mod probes {
         queue_ns: u64,
     ) {
     }
+    fn block_complete_discard(
+        dev_id: u64,
+        req_id: u64,
+        result: u8,
+        proc_ns: u64,
+        queue_ns: u64,
+    ) {
+    }
+    fn block_complete_write_zeroes(
+        dev_id: u64,
+        req_id: u64,
+        result: u8,
+        proc_ns: u64,
+        queue_ns: u64,
+    ) {
+    }
+
+    fn block_timeout(dev_id: u64, req_id: u64, age_ns: u64) {}
+    fn block_late_complete(dev_id: u64, req_id: u64, result: u8) {}
 }
 
 /// Type of operations which may be issued to a virtual block device.
@@ -76,6 +121,11 @@ pub enum Operation {
     Write(ByteOffset, ByteLen),
     /// Flush buffer(s)
     Flush,
+    /// Deallocate the ranges listed in the request (see
+    /// [`Request::ranges()`]), whose contents become undefined
+    Discard,
+    /// Write zeroes to `offset` for `len`, deallocating the range if possible
+    WriteZeroes(ByteOffset, ByteLen),
 }
 impl Operation {
     pub const fn is_read(&self) -> bool {
@@ -87,6 +137,22 @@ impl Operation {
     pub const fn is_flush(&self) -> bool {
         matches!(self, Operation::Flush)
     }
+    pub const fn is_discard(&self) -> bool {
+        matches!(self, Operation::Discard)
+    }
+    pub const fn is_write_zeroes(&self) -> bool {
+        matches!(self, Operation::WriteZeroes(..))
+    }
+    /// Whether the operation alters the contents of the device, and so is
+    /// forbidden on one which is read-only.
+    pub const fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Operation::Write(..)
+                | Operation::Discard
+                | Operation::WriteZeroes(..)
+        )
+    }
 }
 
 /// Result of a block [`Request`]
@@ -100,6 +166,9 @@ pub enum Result {
     ReadOnly,
     /// Operation not supported by backend
     Unsupported,
+    /// Backend is temporarily unable to service requests (such as a remote
+    /// target which is unreachable)
+    NotReady,
 }
 impl Result {
     pub const fn is_err(&self) -> bool {
@@ -116,11 +185,26 @@ pub struct Request {
     /// request
     regions: Vec<GuestRegion>,
 
+    /// The byte ranges of the device to be deallocated by a discard request
+    ranges: Vec<(ByteOffset, ByteLen)>,
+
     /// Store [`device::TrackingMarker`] when this request is tracked by a
     /// [`device::Tracking`] for that device.  It is through this marker that
     /// the result of the block request is communicated back to the device
     /// emulation for processing.
     marker: Option<device::TrackingMarker>,
+
+    /// Accounting of this request against its device's priority class, held
+    /// for as long as the request is in flight.
+    admission: Option<priority::Admission>,
+
+    /// Record through which the completion of this request is audited, when
+    /// its backend is being audited.
+    audit: Option<audit::Ticket>,
+
+    /// Whether the range of a write-zeroes request may be deallocated, rather
+    /// than written
+    unmap: bool,
 }
 impl Request {
     pub fn new_read(
@@ -128,7 +212,15 @@ impl Request {
         len: ByteLen,
         regions: Vec<GuestRegion>,
     ) -> Self {
-        Self { op: Operation::Read(off, len), regions, marker: None }
+        Self {
+            op: Operation::Read(off, len),
+            regions,
+            ranges: Vec::new(),
+            marker: None,
+            admission: None,
+            audit: None,
+            unmap: false,
+        }
     }
 
     pub fn new_write(
@@ -136,12 +228,56 @@ impl Request {
         len: ByteLen,
         regions: Vec<GuestRegion>,
     ) -> Self {
-        Self { op: Operation::Write(off, len), regions, marker: None }
+        Self {
+            op: Operation::Write(off, len),
+            regions,
+            ranges: Vec::new(),
+            marker: None,
+            admission: None,
+            audit: None,
+            unmap: false,
+        }
     }
 
     pub fn new_flush() -> Self {
         let op = Operation::Flush;
-        Self { op, regions: Vec::new(), marker: None }
+        Self {
+            op,
+            regions: Vec::new(),
+            ranges: Vec::new(),
+            marker: None,
+            admission: None,
+            audit: None,
+            unmap: false,
+        }
+    }
+
+    pub fn new_discard(ranges: Vec<(ByteOffset, ByteLen)>) -> Self {
+        Self {
+            op: Operation::Discard,
+            regions: Vec::new(),
+            ranges,
+            marker: None,
+            admission: None,
+            audit: None,
+            unmap: false,
+        }
+    }
+
+    pub fn new_write_zeroes(
+        off: ByteOffset,
+        len: ByteLen,
+        unmap: bool,
+    ) -> Self {
+        Self {
+            op: Operation::WriteZeroes(off, len),
+            regions: Vec::new(),
+            ranges: Vec::new(),
+            marker: None,
+            admission: None,
+            audit: None,
+            unmap,
+        }
     }
 
     /// Type of operation being issued.
@@ -154,20 +290,62 @@ impl Request {
         &self.regions[..]
     }
 
-    pub fn mappings<'a>(&self, mem: &'a MemCtx) -> Option<Vec<SubMapping<'a>>> {
-        match &self.op {
-            Operation::Read(..) => {
-                self.regions.iter().map(|r| mem.writable_region(r)).collect()
-            }
-            Operation::Write(..) => {
-                self.regions.iter().map(|r| mem.readable_region(r)).collect()
-            }
-            Operation::Flush => None,
+    /// Byte ranges of the device to be deallocated by a discard request
+    pub fn ranges(&self) -> &[(ByteOffset, ByteLen)] {
+        &self.ranges[..]
+    }
+
+    /// Whether the guest permits the range of a write-zeroes request to be
+    /// deallocated.  If not, the backend must write zeroes to it, so that the
+    /// range remains allocated.
+    pub fn may_unmap(&self) -> bool {
+        self.unmap
+    }
+
+    /// Map the guest memory regions underlying the request.
+    ///
+    /// Returns [`None`] if the regions cannot be mapped, or if the request was
+    /// already completed to the guest for exceeding its deadline.  While the
+    /// returned [`Mappings`] are held, the request cannot be completed in
+    /// that way, so backends should not hold them across waits of unbounded
+    /// length, such as I/O to remote storage.
+    pub fn mappings<'a>(&self, mem: &'a MemCtx) -> Option<Mappings<'a>> {
+        let maps = match &self.op {
+            Operation::Read(..) => self
+                .regions
+                .iter()
+                .map(|r| mem.writable_region(r))
+                .collect::<Option<Vec<_>>>()?,
+            Operation::Write(..) => self
+                .regions
+                .iter()
+                .map(|r| mem.readable_region(r))
+                .collect::<Option<Vec<_>>>()?,
+            Operation::Flush
+            | Operation::Discard
+            | Operation::WriteZeroes(..) => return None,
+        };
+        let fence = match self.marker.as_ref() {
+            Some(marker) => Some(marker.access_mem()?),
+            None => None,
+        };
+        Some(Mappings { maps, _fence: fence })
+    }
+
+    /// Record that the data of this (mutating) request has been handed to
+    /// the backend's underlying storage, which is to be called as soon as the
+    /// storage write returns.  See [`audit`] for how this is used.
+    pub fn stored(&self) {
+        if let Some(ticket) = self.audit.as_ref() {
+            ticket.stored();
         }
     }
 
     /// Indicate disposition of completed request
     pub fn complete(mut self, res: Result) {
+        if let Some(ticket) = self.audit.take() {
+            ticket.complete(res);
+        }
         if let Some(marker) = self.marker.take() {
             marker.complete(res);
         }
@@ -181,6 +359,25 @@ impl Drop for Request {
     }
 }
 
+/// Guest memory underlying a [`Request`], as mapped by
+/// [`Request::mappings()`]
+pub struct Mappings<'a> {
+    maps: Vec<SubMapping<'a>>,
+    _fence: Option<device::MemFenceGuard>,
+}
+impl<'a> std::ops::Deref for Mappings<'a> {
+    type Target = [SubMapping<'a>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.maps
+    }
+}
+impl<'a> AsRef<[SubMapping<'a>]> for Mappings<'a> {
+    fn as_ref(&self) -> &[SubMapping<'a>] {
+        &self.maps
+    }
+}
+
 /// Metadata regarding a virtualized block device.
 #[derive(Default, Debug, Copy, Clone)]
 pub struct DeviceInfo {
@@ -190,6 +387,10 @@ pub struct DeviceInfo {
     pub total_size: u64,
     /// Is the device read-only
     pub read_only: bool,
+    /// Does the device support [`Operation::Discard`]
+    pub supports_discard: bool,
+    /// Does the device support [`Operation::WriteZeroes`]
+    pub supports_write_zeroes: bool,
 }
 
 /// Options to control behavior of block backend.
@@ -197,7 +398,7 @@ pub struct DeviceInfo {
 /// Values for omitted fields will be determined by the backend, likely by
 /// querying the underlying resource.  If values provided conflict with said
 /// resource, the backend may fail its initialization with an error.
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Clone)]
 pub struct BackendOpts {
     /// Size (in bytes) per block
     pub block_size: Option<u32>,
@@ -208,6 +409,20 @@ pub struct BackendOpts {
 
     /// Force flush requests to be skipped (turned into no-op)
     pub skip_flush: Option<bool>,
+
+    /// Retry policy for backends which access remote storage.  Backends which
+    /// are not network-attached will ignore this.
+    pub retry_policy: Option<health::RetryPolicy>,
+
+    /// Prefetch the start of the device in the background once started.
+    /// Backends which are not network-attached will ignore this.
+    pub prefetch: Option<prefetch::Policy>,
+
+    /// Journal writes locally until they are flushed, replaying them should
+    /// the backend fail in the meantime.  Backends which are not
+    /// network-attached will ignore this.  When set, transient failures are
+    /// ridden out by replaying the journal rather than by `retry_policy`.
+    pub journal: Option<journal::Policy>,
 }
 
 /// API to access a virtualized block device.
@@ -225,12 +440,41 @@ pub trait Device: Send + Sync + 'static {
 
     /// Optional on-attach handler to update device state with new `DeviceInfo`
     fn attach(&self, _info: DeviceInfo) {}
+
+    /// Recent I/O activity, for devices which track their requests
+    fn io_history(&self) -> Option<device::IoHistory> {
+        None
+    }
+
+    /// Whether the device can report [`Result::NotReady`] to the guest as a
+    /// condition distinct from failure
+    fn reports_not_ready(&self) -> bool {
+        false
+    }
 }
 
 pub trait Backend: Send + Sync + 'static {
     fn attachment(&self) -> &backend::Attachment;
 
     fn info(&self) -> DeviceInfo;
+
+    /// Read the contents of the backend at `off` into `buf`, outside of any
+    /// request from the attached device.
+    ///
+    /// The contents read are only consistent if the device is not issuing
+    /// writes at the same time, such as while the instance is paused.
+    /// Backends which cannot be read in this way fail with an error of kind
+    /// [`std::io::ErrorKind::Unsupported`].
+    fn read_at(
+        &self,
+        _off: ByteOffset,
+        _buf: &mut [u8],
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "backend does not support direct reads",
+        ))
+    }
 }
 
 pub enum CacheMode {
@@ -238,6 +482,17 @@ pub enum CacheMode {
     WriteBack,
 }
 
+/// The serial number by which a disk identifies itself to the guest: its name,
+/// limited to the 20 printable ASCII characters which every disk interface is
+/// able to report.  It is the same whichever interface the disk presents, so
+/// that the guest finds the same volume should the interface change.
+pub fn disk_serial(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .take(20)
+        .collect()
+}
+
 /// Attach a block backend to a corresponding device
 ///
 /// # Panics
@@ -247,7 +502,7 @@ pub fn attach(backend: Arc<dyn Backend>, device: Arc<dyn Device>) {
     let dev_attach = device.attachment();
     let backend_attach = backend.attachment();
 
-    let mut devlock = dev_attach.0.lock().unwrap();
+    let mut devlock = dev_attach.inner.lock().unwrap();
     let mut belock = backend_attach.0.state.lock().unwrap();
 
     if devlock.is_some() {
@@ -261,7 +516,8 @@ pub fn attach(backend: Arc<dyn Backend>, device: Arc<dyn Device>) {
     *belock = Some(backend::AttachState::new(&dev_attach, &device));
 
     // notify device that it has become attached
-    let binfo = backend.info();
+    let mut binfo = backend.info();
+    binfo.read_only |= dev_attach.write_protected();
     device.attach(binfo);
 }
 
@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A block backend which completes every request immediately, without
+//! transferring any data.
+//!
+//! Reads leave the guest buffers untouched and writes are discarded.  With the
+//! cost of storage removed, the time taken to service guest I/O is that of the
+//! emulated device and the block layer alone, which makes this backend useful
+//! for benchmarking those paths and detecting regressions in them.
+
+use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use crate::block;
+use crate::hostres;
+use crate::inventory::Entity;
+
+pub struct NullBackend {
+    state: Arc<WorkingState>,
+
+    worker_count: NonZeroUsize,
+}
+struct WorkingState {
+    attachment: block::backend::Attachment,
+    info: block::DeviceInfo,
+    res_owner: Arc<hostres::Owner>,
+}
+impl WorkingState {
+    fn processing_loop(&self) {
+        while let Some(req) = self.attachment.block_for_req() {
+            let res = match req.oper() {
+                op if op.is_mutating() && self.info.read_only => {
+                    block::Result::ReadOnly
+                }
+                block::Operation::Flush => {
+                    // Writes are discarded, and so as durable as they will
+                    // ever be
+                    self.attachment.audit().begin_sync().succeeded();
+                    block::Result::Success
+                }
+                op => {
+                    if op.is_mutating() {
+                        req.stored();
+                    }
+                    block::Result::Success
+                }
+            };
+            req.complete(res);
+        }
+    }
+}
+
+impl NullBackend {
+    /// Create a backend presenting a device of `size` bytes.
+    pub fn create(
+        size: u64,
+        opts: block::BackendOpts,
+        worker_count: NonZeroUsize,
+    ) -> Result<Arc<Self>> {
+        let block_size = opts.block_size.unwrap_or(block::DEFAULT_BLOCK_SIZE);
+
+        if size == 0 {
+            return Err(Error::new(ErrorKind::Other, "size cannot be 0"));
+        } else if (size % block_size as u64) != 0 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "size {} not multiple of block size {}!",
+                    size, block_size,
+                ),
+            ));
+        }
+
+        Ok(Arc::new(Self {
+            state: Arc::new(WorkingState {
+                attachment: block::backend::Attachment::new(),
+                info: block::DeviceInfo {
+                    block_size,
+                    total_size: size / block_size as u64,
+                    read_only: opts.read_only.unwrap_or(false),
+                    supports_discard: true,
+                    supports_write_zeroes: true,
+                },
+                res_owner: hostres::Owner::new("block-null"),
+            }),
+            worker_count,
+        }))
+    }
+    fn spawn_workers(&self) -> Result<()> {
+        for n in 0..self.worker_count.get() {
+            let worker_state = self.state.clone();
+
+            let held = self.state.res_owner.hold(hostres::Kind::Thread, 1);
+            let _join = std::thread::Builder::new()
+                .name(format!("null worker {n}"))
+                .spawn(move || {
+                    let _held = held;
+                    worker_state.processing_loop();
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl block::Backend for NullBackend {
+    fn attachment(&self) -> &block::backend::Attachment {
+        &self.state.attachment
+    }
+    fn info(&self) -> block::DeviceInfo {
+        self.state.info
+    }
+    fn read_at(&self, off: block::ByteOffset, buf: &mut [u8]) -> Result<()> {
+        let size =
+            self.state.info.total_size * self.state.info.block_size as u64;
+        match off.checked_add(buf.len()) {
+            Some(end) if end as u64 <= size => {
+                // The device holds no data, and so reads as zeroes
+                buf.fill(0);
+                Ok(())
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "read beyond end of device",
+            )),
+        }
+    }
+}
+
+impl Entity for NullBackend {
+    fn type_name(&self) -> &'static str {
+        "block-null"
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        self.state.attachment.start();
+        self.spawn_workers()?;
+        Ok(())
+    }
+    fn halt(&self) {
+        self.state.attachment.halt();
+    }
+}
@@ -0,0 +1,287 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Background prefetch of remote-backed disks
+//!
+//! A freshly provisioned disk whose storage is remote will pay the full
+//! latency of that storage on each first access by the guest.  A [`Prefetch`]
+//! reads through the start of the disk sequentially in the background,
+//! discarding the data, so that it is resident in the page cache of the hosts
+//! serving the storage by the time the guest gets to it.  Nothing is cached on
+//! this side of the network.
+//!
+//! That remote cache is of finite size, and reading past what it can hold only
+//! evicts what was read before, so a prefetch covers a bounded prefix of the
+//! disk (where the data read as the guest boots is most likely to be found).
+//! It is also always rate-limited, to avoid crowding out guest I/O, and can be
+//! canceled at any time.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+/// Highest rate at which a prefetch may read, whatever its [`Policy`]
+pub const MAX_BYTES_PER_SEC: u64 = 128 * 1024 * 1024;
+
+/// Parameters controlling a background prefetch.
+#[derive(Copy, Clone, Debug)]
+pub struct Policy {
+    /// Size of each read issued to the backend.  Rounded down to a multiple of
+    /// the block size of the disk.
+    pub chunk_size: usize,
+    /// Upper bound on the rate at which data is read.  Clamped to at most
+    /// [`MAX_BYTES_PER_SEC`].
+    pub max_bytes_per_sec: u64,
+    /// Number of bytes, from the start of the disk, to be read
+    pub max_bytes: u64,
+    /// Delay prior to the first read, leaving the guest to boot unimpeded
+    pub start_delay: Duration,
+}
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1024 * 1024,
+            max_bytes_per_sec: 32 * 1024 * 1024,
+            max_bytes: 1024 * 1024 * 1024,
+            start_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Disposition of a prefetch
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Waiting out the start delay
+    Pending,
+    Running,
+    /// The prefix of the disk covered by the prefetch has been read
+    Completed,
+    Canceled,
+    /// A read from the backend failed, ending the prefetch
+    Failed,
+}
+impl State {
+    pub const fn is_finished(&self) -> bool {
+        matches!(self, State::Completed | State::Canceled | State::Failed)
+    }
+}
+
+/// Progress of a prefetch through its disk
+#[derive(Copy, Clone, Debug)]
+pub struct Progress {
+    pub state: State,
+    /// Bytes read thus far
+    pub bytes_done: u64,
+    /// Bytes to be read in all: the size of the disk, if smaller than the
+    /// limit imposed by the [`Policy`]
+    pub bytes_total: u64,
+}
+
+struct Cancel {
+    canceled: AtomicBool,
+    notify: Notify,
+}
+
+/// Handle to a prefetch running in the background.
+///
+/// Dropping the handle does not stop the prefetch; [`Prefetch::cancel()`] must
+/// be called explicitly.
+pub struct Prefetch {
+    progress: watch::Receiver<Progress>,
+    cancel: Arc<Cancel>,
+}
+impl Prefetch {
+    /// Spawn a task (on the current tokio runtime) which prefetches a disk of
+    /// `disk_size` bytes, composed of `block_size` blocks, according to
+    /// `policy`.  The `read` function is called to read `len` bytes at byte
+    /// offset `off` from the backend.
+    ///
+    /// # Panics
+    ///
+    /// If called outside the context of a tokio runtime.
+    pub fn spawn<F, Fut, E>(
+        policy: Policy,
+        disk_size: u64,
+        block_size: u32,
+        mut read: F,
+    ) -> Self
+    where
+        F: FnMut(u64, usize) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send,
+    {
+        let bytes_total = disk_size.min(policy.max_bytes);
+        let rate = policy.max_bytes_per_sec.clamp(1, MAX_BYTES_PER_SEC);
+        let (tx, rx) = watch::channel(Progress {
+            state: State::Pending,
+            bytes_done: 0,
+            bytes_total,
+        });
+        let cancel = Arc::new(Cancel {
+            canceled: AtomicBool::new(false),
+            notify: Notify::new(),
+        });
+        let chunk = chunk_size(policy.chunk_size, block_size);
+
+        let task_cancel = cancel.clone();
+        tokio::spawn(async move {
+            let cancel = task_cancel;
+            let canceled = || cancel.canceled.load(Ordering::Acquire);
+            let set_state = |state| tx.send_modify(|p| p.state = state);
+
+            tokio::select! {
+                _ = tokio::time::sleep(policy.start_delay) => {}
+                _ = cancel.notify.notified() => {}
+            }
+
+            let start = Instant::now();
+            let mut off = 0;
+            while off < bytes_total {
+                if canceled() {
+                    set_state(State::Canceled);
+                    return;
+                }
+                set_state(State::Running);
+
+                let len = (bytes_total - off).min(chunk as u64) as usize;
+                if read(off, len).await.is_err() {
+                    set_state(State::Failed);
+                    return;
+                }
+                off += len as u64;
+                tx.send_modify(|p| p.bytes_done = off);
+
+                tokio::select! {
+                    _ = tokio::time::sleep_until(start + pace(off, rate)) => {}
+                    _ = cancel.notify.notified() => {}
+                }
+            }
+            set_state(State::Completed);
+        });
+
+        Self { progress: rx, cancel }
+    }
+
+    /// Stop the prefetch, if it has not already finished.
+    pub fn cancel(&self) {
+        self.cancel.canceled.store(true, Ordering::Release);
+        self.cancel.notify.notify_one();
+    }
+
+    /// Current progress of the prefetch
+    pub fn progress(&self) -> Progress {
+        *self.progress.borrow()
+    }
+
+    /// Subscribe to updates of the prefetch progress
+    pub fn subscribe(&self) -> watch::Receiver<Progress> {
+        self.progress.clone()
+    }
+}
+
+/// Size of reads issued for a requested `chunk` size, being a non-zero
+/// multiple of `block_size`.
+fn chunk_size(chunk: usize, block_size: u32) -> usize {
+    let block_size = block_size.max(1) as usize;
+    (chunk / block_size).max(1) * block_size
+}
+
+/// Time (since the start of the prefetch) at which `bytes_done` bytes may have
+/// been read without exceeding `rate` bytes per second.
+fn pace(bytes_done: u64, rate: u64) -> Duration {
+    if rate == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos(
+        (bytes_done as u128 * 1_000_000_000 / rate as u128) as u64,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunks_are_block_multiples() {
+        assert_eq!(chunk_size(1024 * 1024, 512), 1024 * 1024);
+        assert_eq!(chunk_size(1000, 512), 512);
+        assert_eq!(chunk_size(100, 4096), 4096);
+        assert_eq!(chunk_size(10000, 4096), 8192);
+    }
+
+    #[test]
+    fn pacing() {
+        assert_eq!(pace(0, 1024), Duration::ZERO);
+        assert_eq!(pace(1024, 1024), Duration::from_secs(1));
+        assert_eq!(pace(512, 1024), Duration::from_millis(500));
+        assert_eq!(pace(1024, 0), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn reads_entire_disk() {
+        let policy = Policy {
+            chunk_size: 4096,
+            max_bytes_per_sec: MAX_BYTES_PER_SEC,
+            start_delay: Duration::ZERO,
+            ..Policy::default()
+        };
+        let reads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let task_reads = reads.clone();
+        let pf = Prefetch::spawn(policy, 10240, 512, move |off, len| {
+            task_reads.lock().unwrap().push((off, len));
+            async { Ok::<(), ()>(()) }
+        });
+
+        let mut rx = pf.subscribe();
+        rx.wait_for(|p| p.state.is_finished()).await.unwrap();
+        assert_eq!(pf.progress().state, State::Completed);
+        assert_eq!(pf.progress().bytes_done, 10240);
+        assert_eq!(
+            *reads.lock().unwrap(),
+            vec![(0, 4096), (4096, 4096), (8192, 2048)]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_bounded_prefix() {
+        let policy = Policy {
+            chunk_size: 4096,
+            max_bytes_per_sec: u64::MAX,
+            max_bytes: 8192,
+            start_delay: Duration::ZERO,
+        };
+        let reads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let task_reads = reads.clone();
+        let pf = Prefetch::spawn(policy, 1 << 20, 512, move |off, len| {
+            task_reads.lock().unwrap().push((off, len));
+            async { Ok::<(), ()>(()) }
+        });
+
+        let mut rx = pf.subscribe();
+        rx.wait_for(|p| p.state.is_finished()).await.unwrap();
+        assert_eq!(pf.progress().state, State::Completed);
+        assert_eq!(pf.progress().bytes_total, 8192);
+        assert_eq!(*reads.lock().unwrap(), vec![(0, 4096), (4096, 4096)]);
+    }
+
+    #[tokio::test]
+    async fn cancel_before_start() {
+        let policy = Policy {
+            start_delay: Duration::from_secs(60),
+            ..Policy::default()
+        };
+        let pf = Prefetch::spawn(policy, 1 << 20, 512, |_, _| async {
+            Ok::<(), ()>(())
+        });
+        pf.cancel();
+
+        let mut rx = pf.subscribe();
+        rx.wait_for(|p| p.state.is_finished()).await.unwrap();
+        assert_eq!(pf.progress().state, State::Canceled);
+        assert_eq!(pf.progress().bytes_done, 0);
+    }
+}
@@ -0,0 +1,246 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! I/O priority classes for block devices
+//!
+//! Each block device is assigned a [`Priority`] class (via its
+//! [`device::Attachment`](super::device::Attachment)).  All backends in the
+//! process consult a common scheduler before retrieving a request from their
+//! device: while requests of a higher class are in flight, the number of
+//! requests of each lower class which may be in flight is capped.  A busy
+//! data disk is thus unable to monopolize the host I/O path at the expense of
+//! a boot disk, while still being allowed to make progress.
+//!
+//! Backends which are throttled are woken when the in-flight requests holding
+//! them back complete, or when the priority of a device changes.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::block::backend;
+
+use lazy_static::lazy_static;
+
+/// Priority class of the I/O issued by a block device
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    /// Latency-sensitive I/O, such as that of a boot disk
+    High = 0,
+    #[default]
+    Normal = 1,
+    /// Bulk I/O which should yield to all other classes
+    Low = 2,
+}
+impl Priority {
+    const COUNT: usize = 3;
+
+    pub(super) fn from_repr(val: u8) -> Self {
+        match val {
+            0 => Priority::High,
+            2 => Priority::Low,
+            _ => Priority::Normal,
+        }
+    }
+
+    /// Maximum number of requests of this class which may be in flight while
+    /// requests of a higher class are also in flight.
+    const fn limit_when_contended(&self) -> usize {
+        match self {
+            Priority::High => usize::MAX,
+            Priority::Normal => 8,
+            Priority::Low => 2,
+        }
+    }
+}
+
+/// Whether a request of `class` may be issued, given the number of requests of
+/// each class in flight.
+fn admissible(inflight: &[usize; Priority::COUNT], class: Priority) -> bool {
+    let higher_active = inflight[..class as usize].iter().any(|n| *n != 0);
+    !higher_active || inflight[class as usize] < class.limit_when_contended()
+}
+
+/// Arbiter of I/O between the block devices of differing priority classes.
+///
+/// Admission is decided from per-class atomic counters, so backends issuing
+/// and completing requests do not contend on a lock.  Only those which are
+/// throttled, and those waking them, take the lock guarding the list of
+/// backends to be woken.
+pub(super) struct Scheduler {
+    inflight: [AtomicUsize; Priority::COUNT],
+    /// Set while any backend is recorded in `throttled`
+    any_throttled: AtomicBool,
+    throttled: Mutex<Vec<Weak<backend::AttachInner>>>,
+}
+impl Scheduler {
+    fn new() -> Self {
+        Self {
+            inflight: Default::default(),
+            any_throttled: AtomicBool::new(false),
+            throttled: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Admit a request of `class` if it may be issued now, recording it as in
+    /// flight.  If not, `waiter` is recorded so that it may be notified once
+    /// capacity becomes available.
+    ///
+    /// The check and the increment of the in-flight count are made as one
+    /// atomic update, so that concurrent callers cannot exceed the limit.
+    pub(super) fn try_admit(
+        &'static self,
+        class: Priority,
+        waiter: &Arc<backend::AttachInner>,
+    ) -> Option<Admission> {
+        let count = &self.inflight[class as usize];
+        let mut registered = false;
+        loop {
+            let inflight = self.inflight();
+            if admissible(&inflight, class) {
+                let cur = inflight[class as usize];
+                if count
+                    .compare_exchange(
+                        cur,
+                        cur + 1,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    )
+                    .is_ok()
+                {
+                    return Some(Admission { sched: self, class });
+                }
+                continue;
+            }
+            if registered {
+                return None;
+            }
+
+            // Record the waiter before checking once more, so that capacity
+            // released in the meantime (whose wake-up would otherwise be
+            // missed) is not left unused.
+            let waiter = Arc::downgrade(waiter);
+            let mut throttled = self.throttled.lock().unwrap();
+            if !throttled.iter().any(|w| w.ptr_eq(&waiter)) {
+                throttled.push(waiter);
+            }
+            self.any_throttled.store(true, Ordering::SeqCst);
+            drop(throttled);
+            registered = true;
+        }
+    }
+
+    /// Wake all throttled backends, so they may reconsider if requests can be
+    /// issued.
+    pub(super) fn wake_throttled(&self) {
+        let mut guard = self.throttled.lock().unwrap();
+        self.any_throttled.store(false, Ordering::SeqCst);
+        let throttled = std::mem::take(&mut *guard);
+        drop(guard);
+        for waiter in throttled.iter().filter_map(Weak::upgrade) {
+            waiter.wake();
+        }
+    }
+
+    fn release(&self, class: Priority) {
+        self.inflight[class as usize].fetch_sub(1, Ordering::SeqCst);
+        if self.any_throttled.load(Ordering::SeqCst) {
+            self.wake_throttled();
+        }
+    }
+
+    /// Number of requests of each class currently in flight
+    pub(super) fn inflight(&self) -> [usize; Priority::COUNT] {
+        std::array::from_fn(|i| self.inflight[i].load(Ordering::SeqCst))
+    }
+}
+
+lazy_static! {
+    pub(super) static ref SCHEDULER: Scheduler = Scheduler::new();
+}
+
+/// Number of requests currently in flight, across all block devices in the
+/// process, for each priority class (in order of [`Priority`]).
+pub fn inflight() -> [usize; 3] {
+    SCHEDULER.inflight()
+}
+
+/// Token representing an in-flight request, held for the life of the
+/// [`Request`](super::Request).
+pub(super) struct Admission {
+    sched: &'static Scheduler,
+    class: Priority,
+}
+impl Admission {
+    /// Give up an admission for which no request was issued.
+    ///
+    /// Unlike dropping it, this does not wake throttled backends (which may
+    /// share the lock held by the caller).  Any backend refused while it was
+    /// held is held back by a request of a higher class, whose completion
+    /// will wake it.
+    pub(super) fn retract(self) {
+        self.sched.inflight[self.class as usize].fetch_sub(1, Ordering::SeqCst);
+        std::mem::forget(self);
+    }
+}
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.sched.release(self.class);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uncontended_is_unlimited() {
+        let inflight = [0, 0, 1000];
+        assert!(admissible(&inflight, Priority::Low));
+        let inflight = [0, 1000, 0];
+        assert!(admissible(&inflight, Priority::Normal));
+        assert!(admissible(&inflight, Priority::High));
+    }
+
+    #[test]
+    fn lower_classes_capped_under_contention() {
+        let inflight = [1, 8, 2];
+        assert!(admissible(&inflight, Priority::High));
+        assert!(!admissible(&inflight, Priority::Normal));
+        assert!(!admissible(&inflight, Priority::Low));
+
+        let inflight = [0, 1, 1];
+        assert!(admissible(&inflight, Priority::Low));
+        let inflight = [0, 1, 2];
+        assert!(!admissible(&inflight, Priority::Low));
+    }
+
+    #[test]
+    fn admission_is_bounded() {
+        let sched: &'static Scheduler = Box::leak(Box::new(Scheduler::new()));
+        let waiter = Arc::new(backend::AttachInner::new());
+
+        let high = sched.try_admit(Priority::High, &waiter).unwrap();
+        let low: Vec<_> = (0..4)
+            .filter_map(|_| sched.try_admit(Priority::Low, &waiter))
+            .collect();
+        assert_eq!(low.len(), Priority::Low.limit_when_contended());
+        assert_eq!(sched.throttled.lock().unwrap().len(), 1);
+
+        // Completing the high-priority request lifts the cap, waking (and
+        // forgetting) the throttled backend.
+        drop(high);
+        assert!(sched.throttled.lock().unwrap().is_empty());
+        assert!(sched.try_admit(Priority::Low, &waiter).is_some());
+        drop(low);
+        assert_eq!(sched.inflight(), [0, 0, 0]);
+    }
+
+    #[test]
+    fn repr_roundtrip() {
+        for p in [Priority::High, Priority::Normal, Priority::Low] {
+            assert_eq!(Priority::from_repr(p as u8), p);
+        }
+    }
+}
@@ -0,0 +1,355 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Read-only disk images shared between multiple block backends.
+//!
+//! Common base images (installation media, golden OS images) are often
+//! attached to several disks at once.  Rather than having each backend open
+//! the image on its own, a [`SharedImage`] opens the file once per process,
+//! and each [`SharedBackend`] attached to a device reads through that one
+//! descriptor.
+//!
+//! Reads are issued to the file rather than served from a mapping of it, so
+//! that an image truncated while in use fails the reads beyond its new end,
+//! rather than raising SIGBUS in the process.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroUsize;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::accessors::MemAccessor;
+use crate::block;
+use crate::harden;
+use crate::hostres;
+use crate::inventory::Entity;
+use crate::vmm::{MappingExt, MemCtx};
+
+use lazy_static::lazy_static;
+
+/// Images are identified by the device and inode of their backing file, so
+/// that differing paths to the same file still share a mapping.
+type ImageKey = (u64, u64);
+
+lazy_static! {
+    static ref IMAGES: Mutex<BTreeMap<ImageKey, Weak<SharedImage>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// A read-only file image, opened once per process.
+pub struct SharedImage {
+    key: ImageKey,
+    path: PathBuf,
+    len: usize,
+    fp: File,
+    _held: hostres::Held,
+}
+impl SharedImage {
+    /// Open the image at `path`, reusing an existing image of the same file if
+    /// one is still held elsewhere in the process.
+    pub fn open(path: impl AsRef<Path>) -> Result<Arc<Self>> {
+        let p: &Path = path.as_ref();
+        let fp = File::open(harden::host_path(p)?)?;
+        let meta = fp.metadata()?;
+        let key = (meta.dev(), meta.ino());
+
+        let mut images = IMAGES.lock().unwrap();
+        if let Some(image) = images.get(&key).and_then(Weak::upgrade) {
+            return Ok(image);
+        }
+
+        let len = meta.len() as usize;
+        if len == 0 {
+            return Err(Error::new(ErrorKind::Other, "size cannot be 0"));
+        }
+
+        let res_owner =
+            hostres::Owner::new(format!("block-shared-{}", p.display()));
+        let image = Arc::new(Self {
+            key,
+            path: p.to_path_buf(),
+            len,
+            fp,
+            _held: res_owner.hold(hostres::Kind::Fd, 1),
+        });
+        images.retain(|_, img| img.strong_count() != 0);
+        images.insert(key, Arc::downgrade(&image));
+        Ok(image)
+    }
+
+    /// Path through which the image was first opened
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Size of the image in bytes, as of when it was opened
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// Number of backends (and other holders) currently sharing this image
+    pub fn share_count(self: &Arc<Self>) -> usize {
+        Arc::strong_count(self)
+    }
+
+    /// Read the image at `off` into `buf`, failing if the image does not
+    /// (or no longer, having been truncated) extend to its end.
+    fn read_at(&self, off: usize, buf: &mut [u8]) -> Result<()> {
+        match off.checked_add(buf.len()) {
+            Some(end) if end <= self.len => {
+                self.fp.read_exact_at(buf, off as u64)
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "read beyond end of image",
+            )),
+        }
+    }
+}
+impl Drop for SharedImage {
+    fn drop(&mut self) {
+        let mut images = IMAGES.lock().unwrap();
+        if images.get(&self.key).is_some_and(|img| img.strong_count() == 0) {
+            images.remove(&self.key);
+        }
+    }
+}
+
+/// Block backend serving requests out of a [`SharedImage`].
+///
+/// As with all backends, a `SharedBackend` attaches to a single device.  It is
+/// the underlying image which is shared, so a backend must be created for each
+/// device using the image.  The backend is always read-only.
+pub struct SharedBackend {
+    state: Arc<WorkerState>,
+
+    worker_count: NonZeroUsize,
+}
+struct WorkerState {
+    attachment: block::backend::Attachment,
+    image: Arc<SharedImage>,
+    res_owner: Arc<hostres::Owner>,
+    info: block::DeviceInfo,
+}
+impl WorkerState {
+    fn processing_loop(&self, acc_mem: MemAccessor) {
+        while let Some(req) = self.attachment.block_for_req() {
+            if req.oper().is_mutating() {
+                req.complete(block::Result::ReadOnly);
+                continue;
+            }
+
+            let mem = match acc_mem.access() {
+                Some(m) => m,
+                None => {
+                    req.complete(block::Result::Failure);
+                    continue;
+                }
+            };
+            let res = match self.process_request(&req, &mem) {
+                Ok(_) => block::Result::Success,
+                Err(_) => block::Result::Failure,
+            };
+            req.complete(res);
+        }
+    }
+
+    fn process_request(
+        &self,
+        req: &block::Request,
+        mem: &MemCtx,
+    ) -> std::result::Result<(), &'static str> {
+        match req.oper() {
+            block::Operation::Read(off, len) => {
+                let end = off.checked_add(len).ok_or("bad read length")?;
+                if end > self.image.len {
+                    return Err("read beyond end of image");
+                }
+                let maps = req.mappings(mem).ok_or("bad guest region")?;
+
+                // A short read is the image having been truncated underneath
+                // us.
+                let nbytes = maps
+                    .preadv(self.image.fp.as_raw_fd(), off as i64)
+                    .map_err(|_| "io error")?;
+                if nbytes != len {
+                    return Err("bad read length");
+                }
+            }
+            block::Operation::Write(..)
+            | block::Operation::Discard
+            | block::Operation::WriteZeroes(..) => {
+                return Err("backend is read-only");
+            }
+            block::Operation::Flush => {
+                // nothing to do
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SharedBackend {
+    /// Creates a new (read-only) backend for `image`.
+    pub fn create(
+        image: Arc<SharedImage>,
+        opts: block::BackendOpts,
+        worker_count: NonZeroUsize,
+    ) -> Result<Arc<Self>> {
+        if worker_count.get() > block::MAX_FILE_WORKERS {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "too many workers",
+            ));
+        }
+        if opts.read_only == Some(false) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "shared images must be read-only",
+            ));
+        }
+
+        let block_size = opts.block_size.unwrap_or(block::DEFAULT_BLOCK_SIZE);
+        let res_owner = hostres::Owner::new(format!(
+            "block-shared-{}",
+            image.path().display()
+        ));
+        let total_size = image.size() as u64 / block_size as u64;
+
+        Ok(Arc::new(Self {
+            state: Arc::new(WorkerState {
+                attachment: block::backend::Attachment::new(),
+                image,
+                res_owner,
+                info: block::DeviceInfo {
+                    block_size,
+                    total_size,
+                    read_only: true,
+                    supports_discard: false,
+                    supports_write_zeroes: false,
+                },
+            }),
+            worker_count,
+        }))
+    }
+
+    /// The image from which this backend serves requests
+    pub fn image(&self) -> &Arc<SharedImage> {
+        &self.state.image
+    }
+
+    fn spawn_workers(&self) -> std::io::Result<()> {
+        for n in 0..self.worker_count.get() {
+            let worker_state = self.state.clone();
+            let worker_acc = self.state.attachment.accessor_mem(|mem| {
+                mem.expect("backend is attached")
+                    .child(Some(format!("worker {n}")))
+            });
+
+            let held = self.state.res_owner.hold(hostres::Kind::Thread, 1);
+            let _join = std::thread::Builder::new()
+                .name(format!("shared worker {n}"))
+                .spawn(move || {
+                    let _held = held;
+                    worker_state.processing_loop(worker_acc);
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl block::Backend for SharedBackend {
+    fn attachment(&self) -> &block::backend::Attachment {
+        &self.state.attachment
+    }
+
+    fn info(&self) -> block::DeviceInfo {
+        self.state.info
+    }
+
+    fn read_at(&self, off: block::ByteOffset, buf: &mut [u8]) -> Result<()> {
+        self.state.image.read_at(off, buf)
+    }
+}
+impl Entity for SharedBackend {
+    fn type_name(&self) -> &'static str {
+        "block-shared"
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        self.spawn_workers()?;
+        self.state.attachment.start();
+        Ok(())
+    }
+    fn halt(&self) {
+        self.state.attachment.halt();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn same_file_shares_image() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(&[0xa5; 4096]).unwrap();
+
+        let first = SharedImage::open(tmp.path()).unwrap();
+        let second = SharedImage::open(tmp.path()).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.share_count(), 2);
+        let mut buf = [0u8; 1];
+        first.read_at(4095, &mut buf).unwrap();
+        assert_eq!(buf[0], 0xa5);
+
+        drop(first);
+        drop(second);
+        let third = SharedImage::open(tmp.path()).unwrap();
+        assert_eq!(third.share_count(), 1);
+    }
+
+    #[test]
+    fn truncated_image_fails_reads() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(&[0xa5; 8192]).unwrap();
+
+        let image = SharedImage::open(tmp.path()).unwrap();
+        tmp.as_file().set_len(4096).unwrap();
+
+        let mut buf = [0u8; 512];
+        image.read_at(0, &mut buf).unwrap();
+        assert!(image.read_at(4096, &mut buf).is_err());
+        assert!(image.read_at(8192, &mut buf).is_err());
+    }
+
+    #[test]
+    fn writable_shared_backend_rejected() {
+        let mut tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.write_all(&[0; 4096]).unwrap();
+
+        let image = SharedImage::open(tmp.path()).unwrap();
+        let opts =
+            block::BackendOpts { read_only: Some(false), ..Default::default() };
+        assert!(SharedBackend::create(
+            image.clone(),
+            opts,
+            NonZeroUsize::new(1).unwrap()
+        )
+        .is_err());
+
+        let be = SharedBackend::create(
+            image,
+            Default::default(),
+            NonZeroUsize::new(1).unwrap(),
+        )
+        .unwrap();
+        assert!(block::Backend::info(&*be).read_only);
+        assert_eq!(block::Backend::info(&*be).total_size, 8);
+    }
+}
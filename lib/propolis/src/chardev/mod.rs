@@ -15,6 +15,7 @@ pub use sock::UDSock;
 pub type SinkNotifier = Box<dyn Fn(&dyn Sink) + Send + Sync + 'static>;
 pub type SourceNotifier = Box<dyn Fn(&dyn Source) + Send + Sync + 'static>;
 pub type BlockingSourceConsumer = Box<dyn Fn(&[u8]) + Send + Sync + 'static>;
+pub type BreakHandler = Box<dyn Fn() + Send + Sync + 'static>;
 
 pub trait Sink: Send + Sync + 'static {
     // XXX: make this slice based
@@ -10,7 +10,7 @@ use std::path::Path;
 use std::sync::{Arc, Condvar, Mutex};
 use std::time::Duration;
 
-use crate::chardev::{pollers, Sink, Source};
+use crate::chardev::{pollers, BreakHandler, Sink, Source};
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf, SocketAddr};
@@ -20,6 +20,55 @@ const BUF_SIZE: usize = 512;
 const POLL_INTERVAL_MS: usize = 10;
 const POLL_MISS_THRESH: usize = 5;
 
+/// Telnet "Interpret As Command" escape, and the command signalling a BREAK
+const TELNET_IAC: u8 = 0xff;
+const TELNET_BRK: u8 = 0xf3;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ClientInput {
+    Data(Vec<u8>),
+    Break,
+}
+
+/// Separates telnet BREAK commands (`IAC BRK`) from the client's input.  An
+/// escaped `IAC IAC` stands for a literal 0xff, and other commands are passed
+/// through untouched.
+#[derive(Default)]
+struct BreakFilter {
+    /// The last byte seen was an unpaired IAC
+    iac: bool,
+}
+impl BreakFilter {
+    fn filter(&mut self, data: &[u8]) -> Vec<ClientInput> {
+        let mut res = Vec::new();
+        let mut cur = Vec::with_capacity(data.len());
+        for &b in data {
+            if !self.iac {
+                match b {
+                    TELNET_IAC => self.iac = true,
+                    _ => cur.push(b),
+                }
+                continue;
+            }
+            self.iac = false;
+            match b {
+                TELNET_BRK => {
+                    if !cur.is_empty() {
+                        res.push(ClientInput::Data(std::mem::take(&mut cur)));
+                    }
+                    res.push(ClientInput::Break);
+                }
+                TELNET_IAC => cur.push(TELNET_IAC),
+                _ => cur.extend([TELNET_IAC, b]),
+            }
+        }
+        if !cur.is_empty() {
+            res.push(ClientInput::Data(cur));
+        }
+        res
+    }
+}
+
 struct Inner {
     std_sock: Option<StdUnixListener>,
     client: Option<SocketAddr>,
@@ -30,6 +79,7 @@ pub struct UDSock {
     cv: Condvar,
     sink_buf: Arc<pollers::SinkBuffer>,
     source_buf: Arc<pollers::SourceBuffer>,
+    on_break: Mutex<Option<Arc<dyn Fn() + Send + Sync + 'static>>>,
 }
 impl UDSock {
     pub fn bind(path: &Path) -> Result<Arc<Self>> {
@@ -57,6 +107,7 @@ impl UDSock {
                 poll_miss_thresh: POLL_MISS_THRESH,
                 buf_size: NonZeroUsize::new(BUF_SIZE).unwrap(),
             }),
+            on_break: Mutex::new(None),
         });
 
         Ok(this)
@@ -76,6 +127,13 @@ impl UDSock {
         });
     }
 
+    /// Recognize the telnet BREAK command (`IAC BRK`) in input from the
+    /// client, calling `f` in its place.  While a handler is set, a literal
+    /// 0xff must be sent escaped, as `IAC IAC`.
+    pub fn set_break_handler(&self, f: Option<BreakHandler>) {
+        *self.on_break.lock().unwrap() = f.map(Arc::from);
+    }
+
     fn notify_connected(&self, addr: Option<SocketAddr>) {
         let mut inner = self.inner.lock().unwrap();
         inner.client = addr;
@@ -107,11 +165,7 @@ impl UDSock {
             let (readh, writeh) = sock.into_split();
 
             tokio::select! {
-                _sink_done = Self::run_sink(
-                    sink.as_ref(),
-                    &self.sink_buf,
-                    readh,
-                ) => {},
+                _sink_done = self.run_sink(sink.as_ref(), readh) => {},
                 _source_done = Self::run_source(
                     source.as_ref(),
                     &self.source_buf,
@@ -124,14 +178,27 @@ impl UDSock {
         Ok(())
     }
     async fn run_sink(
+        &self,
         sink: &dyn Sink,
-        sink_buf: &pollers::SinkBuffer,
         mut readh: OwnedReadHalf,
     ) -> Result<()> {
         let mut buf = [0u8; BUF_SIZE];
+        let mut filter = BreakFilter::default();
         loop {
             let num = readh.read(&mut buf).await?;
-            sink_buf.write(&buf[..num], sink).await;
+            let on_break = self.on_break.lock().unwrap().clone();
+            let Some(on_break) = on_break else {
+                self.sink_buf.write(&buf[..num], sink).await;
+                continue;
+            };
+            for input in filter.filter(&buf[..num]) {
+                match input {
+                    ClientInput::Data(data) => {
+                        self.sink_buf.write(&data, sink).await;
+                    }
+                    ClientInput::Break => on_break(),
+                }
+            }
         }
     }
     async fn run_source(
@@ -147,3 +214,31 @@ impl UDSock {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn break_filter() {
+        let mut filter = BreakFilter::default();
+        assert_eq!(
+            filter.filter(b"ab\xff\xf3cd"),
+            [
+                ClientInput::Data(b"ab".to_vec()),
+                ClientInput::Break,
+                ClientInput::Data(b"cd".to_vec())
+            ]
+        );
+
+        // Escaped IAC, and other commands passed through
+        assert_eq!(
+            filter.filter(b"\xff\xffa\xff\xf1"),
+            [ClientInput::Data(b"\xffa\xff\xf1".to_vec())]
+        );
+
+        // A command split between reads
+        assert_eq!(filter.filter(b"a\xff"), [ClientInput::Data(b"a".to_vec())]);
+        assert_eq!(filter.filter(b"\xf3"), [ClientInput::Break]);
+    }
+}
@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Source of time for devices emulated in userspace
+//!
+//! Devices which tell time (the virtio-rtc clock, the time to which the RTC is
+//! initialized, and the deadline of a maintenance notice) read it from the
+//! machine's [`Clock`] rather than from the host directly.  The clock follows
+//! the host until it is warped: frozen, stepped forward, or run faster or
+//! slower than the host.  Warping exists so that tests can exercise
+//! time-dependent device behavior deterministically, and without waiting on
+//! the passage of real time.
+//!
+//! The clock never runs backward, so devices need not guard against it.
+//!
+//! The i8254 PIT, the HPET, the ACPI PM timer, and the running of the RTC are
+//! emulated by the kernel against the host's clock (see [`crate::hw::bhyve`]),
+//! and are unaffected by warping.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Rate at which a clock which follows the host runs, in percent
+pub const RATE_HOST: u32 = 100;
+
+/// A warped clock, as last rebased
+#[derive(Copy, Clone, Debug)]
+struct Warp {
+    /// Host time at which the clock was last rebased
+    host: Instant,
+    /// Monotonic time on the clock as of `host`
+    mono: Instant,
+    /// Wall-clock time on the clock as of `host`
+    wall: SystemTime,
+    /// Rate at which the clock runs relative to the host, in percent
+    rate_pct: u32,
+}
+impl Warp {
+    /// Time elapsed on the clock from its base until host time `now`
+    fn elapsed(&self, now: Instant) -> Duration {
+        let host_ns = now.saturating_duration_since(self.host).as_nanos();
+        let ns = host_ns * u128::from(self.rate_pct) / 100;
+        Duration::from_nanos(u64::try_from(ns).unwrap_or(u64::MAX))
+    }
+
+    fn rebase(&mut self, now: Instant) {
+        let elapsed = self.elapsed(now);
+        self.mono += elapsed;
+        self.wall += elapsed;
+        self.host = now;
+    }
+}
+
+/// The time as seen by a machine's devices
+#[derive(Default)]
+pub struct Clock {
+    warp: Mutex<Option<Warp>>,
+}
+impl Clock {
+    /// Creates a clock which follows the host until warped.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Current monotonic time on the clock
+    pub fn now(&self) -> Instant {
+        let now = Instant::now();
+        match self.warp.lock().unwrap().as_ref() {
+            Some(warp) => warp.mono + warp.elapsed(now),
+            None => now,
+        }
+    }
+
+    /// Current wall-clock time on the clock
+    pub fn wall(&self) -> SystemTime {
+        let now = Instant::now();
+        match self.warp.lock().unwrap().as_ref() {
+            Some(warp) => warp.wall + warp.elapsed(now),
+            None => SystemTime::now(),
+        }
+    }
+
+    /// Current wall-clock time on the clock, as the time since the UNIX epoch
+    pub fn unix_time(&self) -> Duration {
+        self.wall()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock time precedes UNIX epoch")
+    }
+
+    /// Rate at which the clock runs relative to the host, in percent
+    pub fn rate(&self) -> u32 {
+        self.warp.lock().unwrap().map_or(RATE_HOST, |warp| warp.rate_pct)
+    }
+
+    /// Whether the clock has been warped away from the host's
+    pub fn is_warped(&self) -> bool {
+        self.warp.lock().unwrap().is_some()
+    }
+
+    /// Runs the clock at `rate_pct` percent of the rate of the host's clock.
+    /// A rate of 0 freezes the clock.
+    pub fn set_rate(&self, rate_pct: u32) {
+        self.with_warp(|warp| warp.rate_pct = rate_pct);
+    }
+
+    /// Stops the clock, until it is stepped or its rate is set.
+    pub fn freeze(&self) {
+        self.set_rate(0);
+    }
+
+    /// Moves the clock forward by `delta`, at once.
+    pub fn step(&self, delta: Duration) {
+        self.with_warp(|warp| {
+            warp.mono += delta;
+            warp.wall += delta;
+        });
+    }
+
+    fn with_warp(&self, f: impl FnOnce(&mut Warp)) {
+        let now = Instant::now();
+        let mut guard = self.warp.lock().unwrap();
+        let warp = guard.get_or_insert_with(|| Warp {
+            host: now,
+            mono: now,
+            wall: SystemTime::now(),
+            rate_pct: RATE_HOST,
+        });
+        warp.rebase(now);
+        f(warp);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn follows_host() {
+        let clock = Clock::new();
+        let before = Instant::now();
+        let now = clock.now();
+        assert!(now >= before && now <= Instant::now());
+        assert_eq!(clock.rate(), RATE_HOST);
+        assert!(!clock.is_warped());
+    }
+
+    #[test]
+    fn frozen_and_stepped() {
+        let clock = Clock::new();
+        clock.freeze();
+        assert!(clock.is_warped());
+        let (mono, wall) = (clock.now(), clock.wall());
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(clock.now(), mono);
+        assert_eq!(clock.wall(), wall);
+
+        clock.step(Duration::from_secs(3600));
+        assert_eq!(clock.now(), mono + Duration::from_secs(3600));
+        assert_eq!(clock.wall(), wall + Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn accelerated() {
+        let start = Instant::now();
+        let warp =
+            Warp { host: start, mono: start, wall: UNIX_EPOCH, rate_pct: 1000 };
+        let later = start + Duration::from_secs(2);
+        assert_eq!(warp.elapsed(later), Duration::from_secs(20));
+
+        // Rebasing preserves the time on the clock
+        let mut rebased = warp;
+        rebased.rebase(later);
+        assert_eq!(rebased.mono, start + Duration::from_secs(20));
+        assert_eq!(rebased.elapsed(later), Duration::ZERO);
+
+        // Host time preceding the base does not run the clock backward
+        assert_eq!(rebased.elapsed(start), Duration::ZERO);
+    }
+}
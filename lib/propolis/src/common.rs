@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::ops::{Add, BitAnd};
+use std::ops::{Add, BitAnd, Range};
 use std::ops::{Bound::*, RangeBounds};
 use std::slice::SliceIndex;
 
@@ -45,6 +45,91 @@ fn numeric_bounds(
     }
 }
 
+/// Plain-old-data types, which can be copied directly to and from the bytes of
+/// an operation.
+///
+/// # Safety
+///
+/// Implementors must not contain padding, and every bit pattern must be a valid
+/// value of the type.  A `#[repr(C)]` or `#[repr(packed)]` struct composed
+/// solely of `Pod` fields, and free of padding, satisfies both.
+pub unsafe trait Pod: Copy + 'static {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+fn pod_bytes<T: Pod>(val: &T) -> &[u8] {
+    // Safety: `T` has no padding, so all of its bytes are initialized.
+    unsafe {
+        std::slice::from_raw_parts(
+            val as *const T as *const u8,
+            std::mem::size_of::<T>(),
+        )
+    }
+}
+
+fn pod_bytes_mut<T: Pod>(val: &mut T) -> &mut [u8] {
+    // Safety: `T` has no padding, and any bit pattern is a valid `T`.
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            val as *mut T as *mut u8,
+            std::mem::size_of::<T>(),
+        )
+    }
+}
+
+/// Split an access of `len` bytes at `offset` at each multiple of `chunk_len`.
+///
+/// Yields the index of each chunk touched, the offset of the access within
+/// that chunk, and the portion of the access which falls within it.
+fn chunk_pieces(
+    offset: usize,
+    len: usize,
+    chunk_len: usize,
+) -> impl Iterator<Item = (usize, usize, Range<usize>)> {
+    assert!(chunk_len != 0);
+    let end = offset + len;
+    let mut pos = offset;
+    std::iter::from_fn(move || {
+        if pos >= end {
+            return None;
+        }
+        let idx = pos / chunk_len;
+        let piece_end = end.min((idx + 1) * chunk_len);
+        let piece =
+            (idx, pos % chunk_len, (pos - offset)..(piece_end - offset));
+        pos = piece_end;
+        Some(piece)
+    })
+}
+
+/// Split an access of `len` bytes at `offset` over `segments`.
+///
+/// Yields the index of each segment the access overlaps, the offset of the
+/// access within that segment, and the portion of the access which falls
+/// within it.
+fn segment_pieces(
+    offset: usize,
+    len: usize,
+    segments: &[Range<usize>],
+) -> impl Iterator<Item = (usize, usize, Range<usize>)> + '_ {
+    let end = offset + len;
+    segments.iter().enumerate().filter_map(move |(idx, seg)| {
+        let start = seg.start.max(offset);
+        let stop = seg.end.min(end);
+        (start < stop).then(|| {
+            (idx, start - seg.start, (start - offset)..(stop - offset))
+        })
+    })
+}
+
 enum ROInner<'a> {
     Buf(&'a mut [u8]),
     Map(SubMapping<'a>),
@@ -149,6 +234,65 @@ impl<'a> ReadOp<'a> {
     pub fn write_u64(&mut self, val: u64) {
         self.write_bytes(&val.to_le_bytes()[..]);
     }
+    pub fn write_u16_be(&mut self, val: u16) {
+        self.write_bytes(&val.to_be_bytes()[..]);
+    }
+    pub fn write_u32_be(&mut self, val: u32) {
+        self.write_bytes(&val.to_be_bytes()[..]);
+    }
+    pub fn write_u64_be(&mut self, val: u64) {
+        self.write_bytes(&val.to_be_bytes()[..]);
+    }
+    /// Writes `val` in its in-memory representation.
+    pub fn write_struct<T: Pod>(&mut self, val: &T) {
+        self.write_bytes(pod_bytes(val));
+    }
+    /// Fulfills the remainder of the operation from `image`, the contents of
+    /// the entire region it addresses.  Any portion of the operation beyond
+    /// the end of `image` is filled with zeroes.
+    pub fn write_from(&mut self, image: &[u8]) {
+        let start = (self.offset + self.write_offset).min(image.len());
+        let end = (self.offset + self.len()).min(image.len());
+        self.write_bytes(&image[start..end]);
+        self.fill(0);
+    }
+    /// Splits the operation at each multiple of `chunk_len` within the region
+    /// it addresses.
+    ///
+    /// `f` is called with the index of each chunk the operation touches, and a
+    /// child operation covering that portion of the chunk, whose offset is
+    /// relative to the start of the chunk.
+    pub fn for_each_chunk(
+        &mut self,
+        chunk_len: usize,
+        mut f: impl FnMut(usize, &mut ReadOp),
+    ) {
+        for (idx, off, range) in
+            chunk_pieces(self.offset, self.len(), chunk_len)
+        {
+            f(idx, &mut ReadOp::new_child(off, self, range));
+        }
+        self.write_offset = self.len();
+    }
+    /// Splits the operation over `segments`, which need not be contiguous,
+    /// within the region it addresses.
+    ///
+    /// `f` is called with the index of each segment the operation overlaps,
+    /// and a child operation covering the overlapping portion, whose offset is
+    /// relative to the start of the segment.  Portions of the operation outside
+    /// of any segment are filled with zeroes.
+    pub fn for_each_segment(
+        &mut self,
+        segments: &[Range<usize>],
+        mut f: impl FnMut(usize, &mut ReadOp),
+    ) {
+        self.fill(0);
+        for (idx, off, range) in
+            segment_pieces(self.offset, self.len(), segments)
+        {
+            f(idx, &mut ReadOp::new_child(off, self, range));
+        }
+    }
     pub fn write_bytes(&mut self, data: &[u8]) {
         let copy_len = data.len();
         let data_len = self.len();
@@ -288,6 +432,60 @@ impl<'a> WriteOp<'a> {
     pub fn read_u64(&mut self) -> u64 {
         u64::from_le_bytes(self.read_val())
     }
+    pub fn read_u16_be(&mut self) -> u16 {
+        u16::from_be_bytes(self.read_val())
+    }
+    pub fn read_u32_be(&mut self) -> u32 {
+        u32::from_be_bytes(self.read_val())
+    }
+    pub fn read_u64_be(&mut self) -> u64 {
+        u64::from_be_bytes(self.read_val())
+    }
+    /// Reads a `T` from its in-memory representation.
+    pub fn read_struct<T: Pod>(&mut self) -> T {
+        // Safety: any bit pattern, including all-zeroes, is a valid `T`.
+        let mut val: T = unsafe { std::mem::zeroed() };
+        self.read_bytes(pod_bytes_mut(&mut val));
+        val
+    }
+    /// Stores the remainder of the operation into `image`, the contents of the
+    /// entire region it addresses.  Any portion of the operation beyond the
+    /// end of `image` is discarded.
+    pub fn read_into(&mut self, image: &mut [u8]) {
+        let start = (self.offset + self.read_offset).min(image.len());
+        let end = (self.offset + self.len()).min(image.len());
+        self.read_bytes(&mut image[start..end]);
+        self.read_offset = self.len();
+    }
+    /// Splits the operation at each multiple of `chunk_len` within the region
+    /// it addresses.  See [`ReadOp::for_each_chunk`].
+    pub fn for_each_chunk(
+        &mut self,
+        chunk_len: usize,
+        mut f: impl FnMut(usize, &mut WriteOp),
+    ) {
+        for (idx, off, range) in
+            chunk_pieces(self.offset, self.len(), chunk_len)
+        {
+            f(idx, &mut WriteOp::new_child(off, self, range));
+        }
+        self.read_offset = self.len();
+    }
+    /// Splits the operation over `segments`, which need not be contiguous,
+    /// within the region it addresses.  Portions of the operation outside of
+    /// any segment are discarded.  See [`ReadOp::for_each_segment`].
+    pub fn for_each_segment(
+        &mut self,
+        segments: &[Range<usize>],
+        mut f: impl FnMut(usize, &mut WriteOp),
+    ) {
+        for (idx, off, range) in
+            segment_pieces(self.offset, self.len(), segments)
+        {
+            f(idx, &mut WriteOp::new_child(off, self, range));
+        }
+        self.read_offset = self.len();
+    }
     pub fn read_bytes(&mut self, data: &mut [u8]) {
         let copy_len = data.len();
         if copy_len == 0 {
@@ -459,4 +657,98 @@ mod tests {
         assert_eq!(wo.read_u8(), 0x10);
         assert_eq!(wo.read_u8(), 0x20);
     }
+
+    #[test]
+    fn big_endian() {
+        let mut buf = [0u8; 8];
+        let mut ro = ReadOp::from_buf(0, &mut buf);
+        ro.write_u16_be(0x0102);
+        ro.write_u32_be(0x0304_0506);
+        drop(ro);
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 0, 0]);
+
+        let mut wo = WriteOp::from_buf(0, &buf);
+        assert_eq!(wo.read_u64_be(), 0x0102_0304_0506_0000);
+    }
+
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    #[repr(C, packed)]
+    struct Packed {
+        a: u8,
+        b: u32,
+        c: [u8; 3],
+    }
+    unsafe impl Pod for Packed {}
+
+    #[test]
+    fn structs() {
+        let val = Packed { a: 1, b: 0x0504_0302, c: [6, 7, 8] };
+        let mut buf = [0u8; 8];
+        ReadOp::from_buf(0, &mut buf).write_struct(&val);
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(WriteOp::from_buf(0, &buf).read_struct::<Packed>(), val);
+    }
+
+    #[test]
+    fn region_images() {
+        let image = [1u8, 2, 3, 4];
+
+        // Reads past the end of the image are zero-filled
+        let mut buf = [0xffu8; 4];
+        ReadOp::from_buf(2, &mut buf).write_from(&image);
+        assert_eq!(buf, [3, 4, 0, 0]);
+
+        // Writes past the end of the image are discarded
+        let mut image = [0u8; 4];
+        WriteOp::from_buf(3, &[9, 9, 9]).read_into(&mut image);
+        assert_eq!(image, [0, 0, 0, 9]);
+    }
+
+    #[test]
+    fn chunks() {
+        // 4-byte entries, each holding its index in every byte
+        let mut buf = [0u8; 6];
+        let mut ro = ReadOp::from_buf(3, &mut buf);
+        let mut seen = Vec::new();
+        ro.for_each_chunk(4, |idx, ro| {
+            seen.push((idx, ro.offset(), ro.len()));
+            ro.write_from(&[idx as u8; 4]);
+        });
+        assert_eq!(ro.avail(), 0);
+        drop(ro);
+        assert_eq!(seen, [(0, 3, 1), (1, 0, 4), (2, 0, 1)]);
+        assert_eq!(buf, [0, 1, 1, 1, 1, 2]);
+
+        let mut seen = Vec::new();
+        WriteOp::from_buf(2, &buf).for_each_chunk(4, |idx, wo| {
+            seen.push((idx, wo.offset(), wo.len()));
+        });
+        assert_eq!(seen, [(0, 2, 2), (1, 0, 4)]);
+    }
+
+    #[test]
+    fn segments() {
+        let segments = [0..2, 4..6, 6..8];
+
+        let mut buf = [0xffu8; 6];
+        let mut ro = ReadOp::from_buf(1, &mut buf);
+        let mut seen = Vec::new();
+        ro.for_each_segment(&segments, |idx, ro| {
+            seen.push((idx, ro.offset(), ro.len()));
+            ro.write_from(&[0x10 * (idx as u8 + 1); 2]);
+        });
+        drop(ro);
+        assert_eq!(seen, [(0, 1, 1), (1, 0, 2), (2, 0, 1)]);
+        // The gap between the first and second segments reads as zeroes
+        assert_eq!(buf, [0x10, 0, 0, 0x20, 0x20, 0x30]);
+
+        let mut images = [[0u8; 2]; 3];
+        let mut wo = WriteOp::from_buf(1, &[1, 2, 3, 4, 5, 6]);
+        wo.for_each_segment(&segments, |idx, wo| {
+            wo.read_into(&mut images[idx]);
+        });
+        assert_eq!(wo.avail(), 0);
+        assert_eq!(images, [[0, 1], [4, 5], [6, 0]]);
+    }
 }
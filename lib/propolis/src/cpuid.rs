@@ -12,6 +12,8 @@ use std::ops::Bound;
 
 use bhyve_api::vcpu_cpuid_entry;
 
+use crate::topology::CpuTopology;
+
 /// Values for a cpuid leaf
 #[derive(Copy, Clone, Debug)]
 pub struct Entry {
@@ -165,9 +167,11 @@ pub struct Specializer {
     num_vcpu: Option<NonZeroU8>,
     vcpuid: Option<i32>,
     vendor_kind: Option<VendorKind>,
+    topology: Option<CpuTopology>,
     cpu_topo_populate: BTreeSet<TopoKind>,
     cpu_topo_clear: BTreeSet<TopoKind>,
     do_cache_topo: bool,
+    do_steal_time: bool,
 }
 impl Specializer {
     pub fn new() -> Self {
@@ -179,6 +183,19 @@ impl Specializer {
         Self { num_vcpu: Some(count), has_smt, ..self }
     }
 
+    /// Specify the arrangement of vCPUs into sockets, cores, and threads
+    ///
+    /// This implies the vCPU count and SMT setting otherwise provided by
+    /// [`Self::with_vcpu_count()`].
+    pub fn with_topology(self, topo: CpuTopology) -> Self {
+        Self {
+            num_vcpu: Some(topo.num_vcpus()),
+            has_smt: topo.threads_per_core() > 1,
+            topology: Some(topo),
+            ..self
+        }
+    }
+
     /// Specify vCPU ID to specialize for
     pub fn with_vcpuid(self, vcpuid: i32) -> Self {
         assert!((vcpuid as usize) < bhyve_api::VM_MAXCPU);
@@ -228,6 +245,11 @@ impl Specializer {
         Self { do_cache_topo: true, ..self }
     }
 
+    /// Advertise paravirtualized steal-time reporting (see [`crate::steal`])
+    pub fn with_steal_time(self) -> Self {
+        Self { do_steal_time: true, ..self }
+    }
+
     /// Given the attributes and modifiers specified in this [Specializer],
     /// render an updated [Set] reflecting those data.
     pub fn execute(self, mut set: Set) -> Result<Set, SpecializeError> {
@@ -241,7 +263,11 @@ impl Specializer {
                     self.fix_amd_cache_topo(&mut set)?;
                 }
             }
-            _ => {}
+            VendorKind::Intel => {
+                if self.do_cache_topo && self.topology.is_some() {
+                    self.fix_intel_cache_topo(&mut set)?;
+                }
+            }
         }
 
         // apply any requested topo info fixups
@@ -256,8 +282,31 @@ impl Specializer {
             }
         }
 
-        // logical CPU count (if SMT is enabled)
-        if let Some(num_vcpu) = self.num_vcpu.as_ref() {
+        if let Some(topo) = self.topology.as_ref() {
+            let per_socket = topo.threads_per_socket() as u32;
+            if let Some(ent) = set.get_mut(Ident(0x1, None)) {
+                // bits 23:16 contain max IDs for logical CPUs in package, only
+                // valid if HTT is set
+                ent.ebx &= !0xff0000;
+                ent.ebx |= per_socket << 16;
+                if per_socket > 1 {
+                    ent.edx |= (0x1 << 28);
+                } else {
+                    ent.edx &= !(0x1 << 28);
+                }
+            }
+            if let (VendorKind::Amd, Some(ent)) =
+                (set.vendor, set.get_mut(Ident(0x8000_0008, None)))
+            {
+                // bits 7:0 hold the number of threads in the package (minus
+                // 1), and bits 15:12 the width of the thread and core fields
+                // in the APIC ID
+                ent.ecx &= !0xf0ff;
+                ent.ecx |= per_socket - 1;
+                ent.ecx |= (topo.thread_bits() + topo.core_bits()) << 12;
+            }
+        } else if let Some(num_vcpu) = self.num_vcpu.as_ref() {
+            // logical CPU count (if SMT is enabled)
             if self.has_smt {
                 if let Some(ent) = set.get_mut(Ident(0x1, None)) {
                     ent.edx |= (0x1 << 28);
@@ -268,6 +317,10 @@ impl Specializer {
             }
         }
 
+        if self.do_steal_time {
+            crate::steal::advertise(&mut set);
+        }
+
         Ok(set)
     }
 
@@ -279,19 +332,22 @@ impl Specializer {
                 None => break,
                 Some(vals) => {
                     // bits 7:5 hold the cache level
-                    let visible_count = match (vals.eax & 0b11100000 >> 5) {
+                    let visible_count = match ((vals.eax >> 5) & 0b111) {
                         0b001 | 0b010 => {
                             // L1/L2 shared by SMT siblings
-                            if self.has_smt {
-                                2
-                            } else {
-                                1
+                            match self.topology.as_ref() {
+                                Some(topo) => topo.threads_per_core() as u32,
+                                None if self.has_smt => 2,
+                                None => 1,
                             }
                         }
                         0b011 => {
-                            // L3 shared by all vCPUs
-                            // TODO: segregate by sockets, if configured
-                            num as u32
+                            // L3 shared by the vCPUs of a socket, or by all
+                            // vCPUs absent a topology
+                            match self.topology.as_ref() {
+                                Some(topo) => topo.threads_per_socket() as u32,
+                                None => num as u32,
+                            }
                         }
                         _ => {
                             // unceremonious handling of unexpected cache levels
@@ -307,6 +363,56 @@ impl Specializer {
         }
         Ok(())
     }
+    fn fix_intel_cache_topo(
+        &self,
+        set: &mut Set,
+    ) -> Result<(), SpecializeError> {
+        assert!(self.do_cache_topo);
+        let topo = self.topology.as_ref().unwrap();
+        for ecx in 0..u32::MAX {
+            match set.get_mut(Ident(0x4, Some(ecx))) {
+                // A cache type of 0 (in bits 4:0) marks the end of the list
+                Some(vals) if vals.eax & 0b11111 != 0 => {
+                    let sharing = match ((vals.eax >> 5) & 0b111) {
+                        0b001 | 0b010 => topo.threads_per_core(),
+                        0b011 => topo.threads_per_socket(),
+                        _ => {
+                            return Err(SpecializeError::UnsupportedCacheLevel);
+                        }
+                    } as u32;
+                    // bits 31:26 hold the number of cores in the package
+                    // (minus 1), and bits 25:14 the number of logical CPUs
+                    // sharing this cache (minus 1)
+                    vals.eax &= !(0xfc00_0000 | (0xfff << 14));
+                    vals.eax |= (topo.cores_per_socket() as u32 - 1) << 26;
+                    vals.eax |= (sharing - 1) << 14;
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+    /// Render leaf 0xB/0x1F levels (SMT, then core) from the topology
+    fn topo_levels(&self, topo: &CpuTopology) -> [Entry; 2] {
+        // The x2APIC ID of each vCPU matches its vCPU ID
+        let x2apic_id = self.vcpuid.unwrap_or(0) as u32;
+        [
+            Entry {
+                eax: topo.thread_bits(),
+                ebx: topo.threads_per_core() as u32,
+                // level type 1 (SMT) at level 0
+                ecx: 0x100,
+                edx: x2apic_id,
+            },
+            Entry {
+                eax: topo.thread_bits() + topo.core_bits(),
+                ebx: topo.threads_per_socket() as u32,
+                // level type 2 (core) at level 1
+                ecx: 0x201,
+                edx: x2apic_id,
+            },
+        ]
+    }
     fn fix_cpu_topo(&self, set: &mut Set) -> Result<(), SpecializeError> {
         for topo in self.cpu_topo_populate.union(&self.cpu_topo_clear) {
             // Nuke any existing info in order to potentially override it
@@ -322,6 +428,38 @@ impl Specializer {
                 .ok_or(SpecializeError::MissingVcpuCount)
                 .map(|n| n.get() as u32)?;
 
+            if let Some(cpu_topo) = self.topology.as_ref() {
+                match topo {
+                    TopoKind::StdB | TopoKind::Std1F => {
+                        // Queries with invalid ecx will get all-zeroes
+                        set.insert(Ident(leaf, None), Entry::zero());
+                        for (level, ent) in
+                            self.topo_levels(cpu_topo).into_iter().enumerate()
+                        {
+                            set.insert(Ident(leaf, Some(level as u32)), ent);
+                        }
+                    }
+                    TopoKind::Ext1E => {
+                        let id = self.vcpuid.unwrap_or(0);
+                        // bits 7:0 hold the core ID, and bits 15:8 the
+                        // zero-based threads-per-core
+                        let ebx = cpu_topo.core_of(id)
+                            | (cpu_topo.threads_per_core() as u32 - 1) << 8;
+                        set.insert(
+                            Ident(leaf, None),
+                            Entry {
+                                eax: id as u32,
+                                ebx,
+                                // one node per socket, numbered as the socket
+                                ecx: cpu_topo.socket_of(id),
+                                edx: 0,
+                            },
+                        );
+                    }
+                }
+                continue;
+            }
+
             match topo {
                 TopoKind::StdB => {
                     // Queries with invalid ecx will get all-zeroes
@@ -446,3 +584,56 @@ pub fn host_query(ident: Ident) -> Entry {
 pub fn host_query(_ident: Ident) -> Entry {
     panic!("this is not going to work on non-x86")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn topology_leaves() {
+        let topo = CpuTopology::new(2, 4, 2).unwrap();
+        let mut set = Set::new(VendorKind::Amd);
+        set.insert(Ident(0x1, None), Entry::zero());
+        set.insert(Ident(0x8000_0008, None), Entry::zero());
+        // L1d, L2, and L3 caches
+        for (i, level) in [1u32, 2, 3].iter().enumerate() {
+            set.insert(
+                Ident(0x8000_001d, Some(i as u32)),
+                Entry { eax: (level << 5) | 1, ..Entry::zero() },
+            );
+        }
+
+        let set = Specializer::new()
+            .with_topology(topo)
+            .with_vcpuid(13)
+            .with_cache_topo()
+            .with_cpu_topo([TopoKind::StdB, TopoKind::Ext1E].into_iter())
+            .execute(set)
+            .unwrap();
+
+        let std1 = set.get(Ident(0x1, None)).unwrap();
+        assert_eq!(std1.ebx >> 24, 13);
+        assert_eq!((std1.ebx >> 16) & 0xff, 8);
+        assert_ne!(std1.edx & (1 << 28), 0);
+
+        let ext8 = set.get(Ident(0x8000_0008, None)).unwrap();
+        assert_eq!(ext8.ecx & 0xff, 7);
+        assert_eq!((ext8.ecx >> 12) & 0xf, 3);
+
+        let smt = set.get(Ident(0xb, Some(0))).unwrap();
+        assert_eq!((smt.eax, smt.ebx, smt.edx), (1, 2, 13));
+        let core = set.get(Ident(0xb, Some(1))).unwrap();
+        assert_eq!((core.eax, core.ebx, core.edx), (3, 8, 13));
+
+        let ext1e = set.get(Ident(0x8000_001e, None)).unwrap();
+        assert_eq!(ext1e.ebx, 0x102);
+        assert_eq!(ext1e.ecx, 1);
+
+        // L1 and L2 are shared by the threads of a core, and L3 by a socket
+        let sharing = |i| {
+            let ent = set.get(Ident(0x8000_001d, Some(i))).unwrap();
+            ((ent.eax >> 14) & 0xfff) + 1
+        };
+        assert_eq!([sharing(0), sharing(1), sharing(2)], [2, 2, 8]);
+    }
+}
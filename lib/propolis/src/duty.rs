@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! vCPU duty-cycle limiting
+//!
+//! To hold an instance within a power or thermal budget, the host may limit
+//! the fraction of time its vCPUs spend in guest context.  Time is divided
+//! into fixed periods, common to all vCPUs of the instance.  In each, the vCPUs
+//! may run for the permitted fraction of the period, after which they are
+//! forced out of guest context and sleep until the next period begins.
+//! Throttling all vCPUs in unison, rather than each independently, avoids one
+//! spinning on a lock held by a sibling which has been put to sleep.
+//!
+//! Time which a vCPU spends throttled should be reported to the guest as steal
+//! time (see [`crate::steal`]), so that it is not accounted to guest tasks.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Period over which the duty cycle is enforced, unless otherwise specified
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum DutyCycleError {
+    #[error("duty cycle limit must be between 1 and 100 percent, not {0}")]
+    InvalidLimit(u8),
+}
+
+struct State {
+    limit_pct: u8,
+    stopped: bool,
+}
+
+/// A limit on the fraction of time an instance's vCPUs may run
+pub struct DutyCycle {
+    period: Duration,
+    epoch: Instant,
+    state: Mutex<State>,
+    cv: Condvar,
+}
+impl DutyCycle {
+    /// Create a duty cycle of `period`, initially unlimited.
+    pub fn new(period: Duration) -> Self {
+        assert!(!period.is_zero());
+        Self {
+            period,
+            epoch: Instant::now(),
+            state: Mutex::new(State { limit_pct: 100, stopped: false }),
+            cv: Condvar::new(),
+        }
+    }
+
+    /// The percentage of each period which vCPUs may spend running
+    pub fn limit(&self) -> u8 {
+        self.state.lock().unwrap().limit_pct
+    }
+
+    /// Set the percentage of each period which vCPUs may spend running.  A
+    /// limit of 100 lifts any throttling.
+    pub fn set_limit(&self, limit_pct: u8) -> Result<(), DutyCycleError> {
+        if !(1..=100).contains(&limit_pct) {
+            return Err(DutyCycleError::InvalidLimit(limit_pct));
+        }
+        self.state.lock().unwrap().limit_pct = limit_pct;
+        self.cv.notify_all();
+        Ok(())
+    }
+
+    /// Position of `now` within its period, and the portion of that period
+    /// during which vCPUs may run
+    fn phase(&self, now: Instant, limit_pct: u8) -> (Duration, Duration) {
+        let period_ns = self.period.as_nanos();
+        let elapsed_ns = now.saturating_duration_since(self.epoch).as_nanos();
+        let phase = Duration::from_nanos((elapsed_ns % period_ns) as u64);
+        let run = self.period * limit_pct as u32 / 100;
+        (phase, run)
+    }
+
+    /// If vCPUs may not run at `now`, the instant at which they may resume
+    fn resume_at(&self, now: Instant, limit_pct: u8) -> Option<Instant> {
+        if limit_pct >= 100 {
+            return None;
+        }
+        let (phase, run) = self.phase(now, limit_pct);
+        (phase >= run).then(|| now + (self.period - phase))
+    }
+
+    /// The next instant, after `now`, at which vCPUs must stop running
+    fn next_stop(&self, now: Instant, limit_pct: u8) -> Instant {
+        let (phase, run) = self.phase(now, limit_pct);
+        if phase < run {
+            now + (run - phase)
+        } else {
+            now + (self.period - phase) + run
+        }
+    }
+
+    /// Sleep through the remainder of the current period, if the vCPUs have
+    /// used their share of it, returning the time spent asleep.  To be called
+    /// by each vCPU thread prior to entering the guest.
+    pub fn throttle(&self) -> Duration {
+        let limit_pct = self.limit();
+        let start = Instant::now();
+        match self.resume_at(start, limit_pct) {
+            Some(resume) => {
+                std::thread::sleep(resume - start);
+                start.elapsed()
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Force the vCPUs out of guest context, by calling `kick`, whenever they
+    /// have used their share of a period, until [`Self::stop()`] is called.
+    pub fn enforce(&self, kick: impl Fn()) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stopped {
+                return;
+            }
+            if state.limit_pct >= 100 {
+                state = self.cv.wait(state).unwrap();
+                continue;
+            }
+
+            let now = Instant::now();
+            let stop = self.next_stop(now, state.limit_pct);
+            let (guard, res) = self.cv.wait_timeout(state, stop - now).unwrap();
+            state = guard;
+            if res.timed_out()
+                && !state.stopped
+                && self.resume_at(Instant::now(), state.limit_pct).is_some()
+            {
+                drop(state);
+                kick();
+                state = self.state.lock().unwrap();
+            }
+        }
+    }
+
+    /// Cause any caller of [`Self::enforce()`] to return.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.cv.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn limit_bounds() {
+        let duty = DutyCycle::new(DEFAULT_PERIOD);
+        assert_eq!(duty.limit(), 100);
+        assert!(duty.set_limit(25).is_ok());
+        assert_eq!(duty.limit(), 25);
+        assert!(duty.set_limit(0).is_err());
+        assert!(duty.set_limit(101).is_err());
+        assert_eq!(duty.limit(), 25);
+    }
+
+    #[test]
+    fn run_and_sleep_windows() {
+        let period = Duration::from_millis(10);
+        let duty = DutyCycle::new(period);
+        let at = |ms: u64| duty.epoch + Duration::from_millis(ms);
+
+        // Unlimited vCPUs never sleep
+        assert_eq!(duty.resume_at(at(9), 100), None);
+
+        // At 30%, vCPUs run for the first 3ms of each period
+        assert_eq!(duty.resume_at(at(2), 30), None);
+        assert_eq!(duty.resume_at(at(3), 30), Some(at(10)));
+        assert_eq!(duty.resume_at(at(17), 30), Some(at(20)));
+        assert_eq!(duty.resume_at(at(21), 30), None);
+
+        assert_eq!(duty.next_stop(at(1), 30), at(3));
+        assert_eq!(duty.next_stop(at(5), 30), at(13));
+    }
+
+    #[test]
+    fn stop_enforcement() {
+        let duty = Arc::new(DutyCycle::new(Duration::from_millis(1)));
+        duty.set_limit(50).unwrap();
+        let kicks = Arc::new(AtomicUsize::new(0));
+
+        let (d, k) = (duty.clone(), kicks.clone());
+        let enforcer = std::thread::spawn(move || {
+            d.enforce(|| {
+                k.fetch_add(1, Ordering::Relaxed);
+            })
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        duty.stop();
+        enforcer.join().unwrap();
+        assert!(kicks.load(Ordering::Relaxed) > 0);
+    }
+}
@@ -138,6 +138,18 @@ pub enum VmExitKind {
     Suspended(SuspendDetail),
     InstEmul(InstEmul),
     Debug,
+    /// The guest executed a breakpoint (`int3`) instruction, with exits for
+    /// them enabled.  The `rip` of the exit is that of the instruction.
+    Breakpoint,
+    /// The guest completed an instruction, with single-step (monitor trap)
+    /// exits enabled.
+    Mtrap,
+    /// The guest executed a HLT instruction, and is waiting for an interrupt
+    /// before it resumes (at the instruction which follows).
+    Hlt,
+    /// The guest spun in a loop of PAUSE instructions, with exits for them
+    /// enabled, as when waiting on a contended spinlock.
+    Pause,
     Paging(u64, i32),
     Unknown(i32),
 }
@@ -159,6 +171,10 @@ impl VmExitKind {
                 vm_exitcode::VM_EXITCODE_SUSPENDED as i32
             }
             VmExitKind::Debug => vm_exitcode::VM_EXITCODE_DEBUG as i32,
+            VmExitKind::Breakpoint => vm_exitcode::VM_EXITCODE_BPT as i32,
+            VmExitKind::Mtrap => vm_exitcode::VM_EXITCODE_MTRAP as i32,
+            VmExitKind::Hlt => vm_exitcode::VM_EXITCODE_HLT as i32,
+            VmExitKind::Pause => vm_exitcode::VM_EXITCODE_PAUSE as i32,
             VmExitKind::Paging(_, _) => vm_exitcode::VM_EXITCODE_PAGING as i32,
             VmExitKind::Unknown(code) => *code,
         }
@@ -182,6 +198,15 @@ impl VmExitKind {
             // progress can be made until the instance is reset.
             VmExitKind::Suspended(_) => true,
 
+            // Breakpoint and single-step exits are taken at instruction
+            // boundaries, for the debugger to inspect the vCPU.
+            VmExitKind::Breakpoint | VmExitKind::Mtrap => true,
+
+            // A halted vCPU has completed the HLT instruction, and will resume
+            // at the one which follows it.  Likewise for the PAUSE ending a
+            // spin loop.
+            VmExitKind::Hlt | VmExitKind::Pause => true,
+
             // The instruction emulation exits, by their nature, leave the vCPU
             // in an inconsistent state until they can be completed
             VmExitKind::Inout(_)
@@ -306,15 +331,10 @@ impl VmExitKind {
                 // is left to userspace.
                 todo!("Implement task-switching emulation on Intel")
             }
-            vm_exitcode::VM_EXITCODE_HLT | vm_exitcode::VM_EXITCODE_PAUSE => {
-                // Until propolis is changed to request userspace exits for HLT
-                // or PAUSE, we do not ever expect to see them.
-                panic!("Unexpected {:?}", code);
-            }
-            vm_exitcode::VM_EXITCODE_BPT | vm_exitcode::VM_EXITCODE_MTRAP => {
-                // Propolis is not using VMX breakpoints or mtraps (yet)
-                panic!("Unexpected {:?}", code);
-            }
+            vm_exitcode::VM_EXITCODE_HLT => VmExitKind::Hlt,
+            vm_exitcode::VM_EXITCODE_PAUSE => VmExitKind::Pause,
+            vm_exitcode::VM_EXITCODE_BPT => VmExitKind::Breakpoint,
+            vm_exitcode::VM_EXITCODE_MTRAP => VmExitKind::Mtrap,
             vm_exitcode::VM_EXITCODE_MWAIT
             | vm_exitcode::VM_EXITCODE_MONITOR
             | vm_exitcode::VM_EXITCODE_VMINSN
@@ -0,0 +1,829 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Minimal encoder for the ACPI Machine Language (AML).
+//!
+//! Only the constructs required by the generated tables are supported: scopes,
+//! devices, named objects and the data (integers, strings, buffers, packages,
+//! and resource templates) they hold, along with the operation regions,
+//! fields, and simple control methods through which the guest reaches the
+//! hotplug registers.  See ACPI 6.4 Section 20.
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const NULL_NAME: u8 = 0x00;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const METHOD_OP: u8 = 0x14;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const MUTEX_OP: u8 = 0x01;
+const ACQUIRE_OP: u8 = 0x23;
+const RELEASE_OP: u8 = 0x27;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const LOCAL0_OP: u8 = 0x60;
+const ARG0_OP: u8 = 0x68;
+const STORE_OP: u8 = 0x70;
+const AND_OP: u8 = 0x7b;
+const NOTIFY_OP: u8 = 0x86;
+const LEQUAL_OP: u8 = 0x93;
+const IF_OP: u8 = 0xa0;
+const RETURN_OP: u8 = 0xa4;
+const ONES_OP: u8 = 0xff;
+
+const ROOT_CHAR: u8 = b'\\';
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+
+/// Encode a name path (such as `\_SB.PCI0` or `_HID`) as a NameString.
+///
+/// Segments shorter than four characters are padded with underscores.
+fn name_string(path: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    let rel = match path.strip_prefix('\\') {
+        Some(rel) => {
+            out.push(ROOT_CHAR);
+            rel
+        }
+        None => path,
+    };
+    let segs: Vec<&str> = rel.split('.').filter(|s| !s.is_empty()).collect();
+    match segs.len() {
+        0 => out.push(ZERO_OP),
+        1 => {}
+        2 => out.push(DUAL_NAME_PREFIX),
+        n => {
+            out.push(MULTI_NAME_PREFIX);
+            out.push(n as u8);
+        }
+    }
+    for seg in segs {
+        assert!(seg.len() <= 4 && seg.is_ascii(), "invalid NameSeg {seg}");
+        let mut buf = [b'_'; 4];
+        buf[..seg.len()].copy_from_slice(seg.as_bytes());
+        out.extend_from_slice(&buf);
+    }
+    out
+}
+
+/// Encode `len` as a PkgLength.
+fn pkg_length(len: usize) -> Vec<u8> {
+    let extra = if len < 0x40 {
+        0
+    } else if len < 0x1000 {
+        1
+    } else if len < 0x10_0000 {
+        2
+    } else {
+        assert!(len < 0x1000_0000, "AML package too large");
+        3
+    };
+
+    if extra == 0 {
+        return vec![len as u8];
+    }
+    let mut out = vec![((extra as u8) << 6) | (len & 0xf) as u8];
+    for i in 0..extra {
+        out.push((len >> (4 + 8 * i)) as u8);
+    }
+    out
+}
+
+/// Prepend a PkgLength encoding to `body`.
+fn with_pkg_length(body: Vec<u8>) -> Vec<u8> {
+    // The encoded length includes the PkgLength bytes themselves
+    let len = body.len();
+    let extra = if len + 1 < 0x40 {
+        0
+    } else if len + 2 < 0x1000 {
+        1
+    } else if len + 3 < 0x10_0000 {
+        2
+    } else {
+        3
+    };
+
+    let mut out = pkg_length(len + extra + 1);
+    out.extend(body);
+    out
+}
+
+/// An encodable AML term
+pub trait Aml {
+    fn append_to(&self, out: &mut Vec<u8>);
+
+    fn to_aml(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.append_to(&mut out);
+        out
+    }
+}
+
+impl Aml for u64 {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        match *self {
+            0 => out.push(ZERO_OP),
+            1 => out.push(ONE_OP),
+            v if v <= u8::MAX as u64 => out.extend([BYTE_PREFIX, v as u8]),
+            v if v <= u16::MAX as u64 => {
+                out.push(WORD_PREFIX);
+                out.extend((v as u16).to_le_bytes());
+            }
+            v if v <= u32::MAX as u64 => {
+                out.push(DWORD_PREFIX);
+                out.extend((v as u32).to_le_bytes());
+            }
+            v => {
+                out.push(QWORD_PREFIX);
+                out.extend(v.to_le_bytes());
+            }
+        }
+    }
+}
+impl Aml for u32 {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        (*self as u64).append_to(out)
+    }
+}
+impl Aml for u8 {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        (*self as u64).append_to(out)
+    }
+}
+impl Aml for &str {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        assert!(self.is_ascii() && !self.contains('\0'));
+        out.push(STRING_PREFIX);
+        out.extend_from_slice(self.as_bytes());
+        out.push(0);
+    }
+}
+
+/// The `Ones` constant, with all bits set
+pub struct Ones;
+impl Aml for Ones {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        out.push(ONES_OP);
+    }
+}
+
+/// A buffer of raw bytes: `Buffer() { ... }`
+pub struct Buffer(pub Vec<u8>);
+impl Aml for Buffer {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        let mut body = Vec::new();
+        (self.0.len() as u64).append_to(&mut body);
+        body.extend_from_slice(&self.0);
+        out.push(BUFFER_OP);
+        out.extend(with_pkg_length(body));
+    }
+}
+
+/// A reference to a named object, such as a field or method, by its path
+pub struct Path(pub String);
+impl Path {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self(path.into())
+    }
+}
+impl Aml for Path {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        out.extend(name_string(&self.0))
+    }
+}
+
+/// Argument `ArgN` of the enclosing method
+pub struct Arg(pub u8);
+impl Aml for Arg {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        assert!(self.0 < 7);
+        out.push(ARG0_OP + self.0);
+    }
+}
+
+/// Local variable `LocalN` of the enclosing method
+pub struct Local(pub u8);
+impl Aml for Local {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        assert!(self.0 < 8);
+        out.push(LOCAL0_OP + self.0);
+    }
+}
+
+/// An operator applied to its operands, such as `Store(Arg0, Local0)`
+pub struct Op<'a> {
+    code: &'static [u8],
+    operands: Vec<Box<dyn Aml + 'a>>,
+    /// Trailing bytes which are not themselves terms, such as the timeout of
+    /// an `Acquire`
+    tail: Vec<u8>,
+}
+impl<'a> Op<'a> {
+    fn new(code: &'static [u8]) -> Self {
+        Self { code, operands: Vec::new(), tail: Vec::new() }
+    }
+    fn arg(mut self, operand: impl Aml + 'a) -> Self {
+        self.operands.push(Box::new(operand));
+        self
+    }
+    /// Results are not stored to a target, but only returned
+    fn no_target(mut self) -> Self {
+        self.tail.push(NULL_NAME);
+        self
+    }
+
+    /// `Store(src, dst)`
+    pub fn store(src: impl Aml + 'a, dst: impl Aml + 'a) -> Self {
+        Self::new(&[STORE_OP]).arg(src).arg(dst)
+    }
+    /// `And(a, b)`
+    pub fn and(a: impl Aml + 'a, b: impl Aml + 'a) -> Self {
+        Self::new(&[AND_OP]).arg(a).arg(b).no_target()
+    }
+    /// `LEqual(a, b)`
+    pub fn lequal(a: impl Aml + 'a, b: impl Aml + 'a) -> Self {
+        Self::new(&[LEQUAL_OP]).arg(a).arg(b)
+    }
+    /// `Notify(object, value)`
+    pub fn notify(object: impl Aml + 'a, value: impl Aml + 'a) -> Self {
+        Self::new(&[NOTIFY_OP]).arg(object).arg(value)
+    }
+    /// `Return(value)`
+    pub fn ret(value: impl Aml + 'a) -> Self {
+        Self::new(&[RETURN_OP]).arg(value)
+    }
+    /// `Acquire(mutex, timeout)`, with the timeout in milliseconds (0xffff
+    /// waiting indefinitely)
+    pub fn acquire(mutex: &str, timeout: u16) -> Self {
+        let mut op =
+            Self::new(&[EXT_OP_PREFIX, ACQUIRE_OP]).arg(Path::new(mutex));
+        op.tail.extend(timeout.to_le_bytes());
+        op
+    }
+    /// `Release(mutex)`
+    pub fn release(mutex: &str) -> Self {
+        Self::new(&[EXT_OP_PREFIX, RELEASE_OP]).arg(Path::new(mutex))
+    }
+}
+impl Aml for Op<'_> {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.code);
+        for operand in self.operands.iter() {
+            operand.append_to(out);
+        }
+        out.extend_from_slice(&self.tail);
+    }
+}
+
+/// Invocation of a method: `path(args...)`
+pub struct Call<'a> {
+    path: String,
+    args: Vec<Box<dyn Aml + 'a>>,
+}
+impl<'a> Call<'a> {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into(), args: Vec::new() }
+    }
+    pub fn with(mut self, arg: impl Aml + 'a) -> Self {
+        self.args.push(Box::new(arg));
+        self
+    }
+}
+impl Aml for Call<'_> {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        out.extend(name_string(&self.path));
+        for arg in self.args.iter() {
+            arg.append_to(out);
+        }
+    }
+}
+
+/// `Mutex(path, 0)`
+pub struct Mutex(pub &'static str);
+impl Aml for Mutex {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        out.extend([EXT_OP_PREFIX, MUTEX_OP]);
+        out.extend(name_string(self.0));
+        out.push(0);
+    }
+}
+
+/// `OperationRegion(path, space, offset, len)`
+pub struct OpRegion {
+    path: &'static str,
+    space: AddressSpace,
+    offset: u64,
+    len: u64,
+}
+impl OpRegion {
+    pub fn new(
+        path: &'static str,
+        space: AddressSpace,
+        offset: u64,
+        len: u64,
+    ) -> Self {
+        Self { path, space, offset, len }
+    }
+}
+impl Aml for OpRegion {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        out.extend([EXT_OP_PREFIX, OP_REGION_OP]);
+        out.extend(name_string(self.path));
+        out.push(self.space as u8);
+        self.offset.append_to(out);
+        self.len.append_to(out);
+    }
+}
+
+/// Access widths of a `Field`
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum FieldAccess {
+    Byte = 1,
+    DWord = 3,
+}
+
+/// `Field(region, access, NoLock, WriteAsZeros) { ... }`
+///
+/// Writes to a field never read the register first, so fields may cover
+/// registers in which writing 1 clears a bit.
+pub struct Field {
+    region: &'static str,
+    access: FieldAccess,
+    /// Named units, or unnamed reserved space, with their sizes in bits
+    units: Vec<(Option<&'static str>, usize)>,
+}
+impl Field {
+    pub fn new(region: &'static str, access: FieldAccess) -> Self {
+        Self { region, access, units: Vec::new() }
+    }
+    /// A field unit `name` of `bits` bits
+    pub fn unit(mut self, name: &'static str, bits: usize) -> Self {
+        self.units.push((Some(name), bits));
+        self
+    }
+    /// `bits` bits of space skipped before the next unit
+    pub fn skip(mut self, bits: usize) -> Self {
+        self.units.push((None, bits));
+        self
+    }
+}
+impl Aml for Field {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        const WRITE_AS_ZEROS: u8 = 2 << 5;
+
+        let mut body = name_string(self.region);
+        body.push(self.access as u8 | WRITE_AS_ZEROS);
+        for (name, bits) in self.units.iter() {
+            match name {
+                Some(name) => {
+                    assert_eq!(name.len(), 4, "invalid NameSeg {name}");
+                    body.extend_from_slice(name.as_bytes());
+                }
+                None => body.push(0),
+            }
+            body.extend(pkg_length(*bits));
+        }
+        out.extend([EXT_OP_PREFIX, FIELD_OP]);
+        out.extend(with_pkg_length(body));
+    }
+}
+
+/// A compressed EISA ID, such as `PNP0A03`, encoded as an integer
+pub struct EisaId(pub &'static str);
+impl EisaId {
+    fn value(&self) -> u32 {
+        let id = self.0.as_bytes();
+        assert!(id.len() == 7, "invalid EISA ID {}", self.0);
+        let vendor = id[..3].iter().fold(0u32, |acc, c| {
+            assert!(c.is_ascii_uppercase());
+            (acc << 5) | (*c - b'@') as u32
+        });
+        let product = u32::from_str_radix(&self.0[3..], 16).unwrap();
+        // The vendor and product portions are each stored big-endian
+        let raw = (vendor << 16) | product;
+        raw.swap_bytes()
+    }
+}
+impl Aml for EisaId {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        self.value().append_to(out)
+    }
+}
+
+/// `Name(path, value)`
+pub struct Name<'a> {
+    path: String,
+    value: Box<dyn Aml + 'a>,
+}
+impl<'a> Name<'a> {
+    pub fn new(path: impl Into<String>, value: impl Aml + 'a) -> Self {
+        Self { path: path.into(), value: Box::new(value) }
+    }
+}
+impl Aml for Name<'_> {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        out.push(NAME_OP);
+        out.extend(name_string(&self.path));
+        self.value.append_to(out);
+    }
+}
+
+/// `Package() { ... }`
+#[derive(Default)]
+pub struct Package<'a> {
+    elems: Vec<Box<dyn Aml + 'a>>,
+}
+impl<'a> Package<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with(mut self, elem: impl Aml + 'a) -> Self {
+        self.elems.push(Box::new(elem));
+        self
+    }
+    pub fn push(&mut self, elem: impl Aml + 'a) {
+        self.elems.push(Box::new(elem));
+    }
+}
+impl Aml for Package<'_> {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        assert!(self.elems.len() <= u8::MAX as usize);
+        let mut body = vec![self.elems.len() as u8];
+        for elem in self.elems.iter() {
+            elem.append_to(&mut body);
+        }
+        out.push(PACKAGE_OP);
+        out.extend(with_pkg_length(body));
+    }
+}
+
+/// A container of terms: `Scope()`, `Device()`, `Method()`, or `If()`
+pub struct Container<'a> {
+    ext_op: Option<u8>,
+    op: u8,
+    /// The encoded terms preceding the contents, such as the container's name
+    head: Vec<u8>,
+    terms: Vec<Box<dyn Aml + 'a>>,
+}
+impl<'a> Container<'a> {
+    /// `Scope(path) { ... }`
+    pub fn scope(path: impl Into<String>) -> Self {
+        Self {
+            ext_op: None,
+            op: SCOPE_OP,
+            head: name_string(&path.into()),
+            terms: Vec::new(),
+        }
+    }
+    /// `Device(path) { ... }`
+    pub fn device(path: impl Into<String>) -> Self {
+        Self {
+            ext_op: Some(EXT_OP_PREFIX),
+            op: DEVICE_OP,
+            head: name_string(&path.into()),
+            terms: Vec::new(),
+        }
+    }
+    /// `Method(path, args, NotSerialized) { ... }`
+    pub fn method(path: impl Into<String>, args: u8) -> Self {
+        assert!(args < 8);
+        let mut head = name_string(&path.into());
+        head.push(args);
+        Self { ext_op: None, op: METHOD_OP, head, terms: Vec::new() }
+    }
+    /// `If(predicate) { ... }`
+    pub fn if_then(predicate: impl Aml) -> Self {
+        Self {
+            ext_op: None,
+            op: IF_OP,
+            head: predicate.to_aml(),
+            terms: Vec::new(),
+        }
+    }
+    pub fn with(mut self, term: impl Aml + 'a) -> Self {
+        self.terms.push(Box::new(term));
+        self
+    }
+    pub fn push(&mut self, term: impl Aml + 'a) {
+        self.terms.push(Box::new(term));
+    }
+}
+impl Aml for Container<'_> {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        let mut body = self.head.clone();
+        for term in self.terms.iter() {
+            term.append_to(&mut body);
+        }
+        if let Some(ext) = self.ext_op {
+            out.push(ext);
+        }
+        out.push(self.op);
+        out.extend(with_pkg_length(body));
+    }
+}
+
+/// Address spaces of a Generic Register Descriptor
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum AddressSpace {
+    SystemMemory = 0,
+    SystemIo = 1,
+    FixedHardware = 0x7f,
+}
+
+/// A resource template, as held by `_CRS` objects.
+///
+/// See ACPI 6.4 Section 6.4 for the descriptor formats.
+#[derive(Default)]
+pub struct ResourceTemplate {
+    data: Vec<u8>,
+}
+impl ResourceTemplate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `IO(Decode16, base, base, 1, len)`
+    pub fn io(mut self, base: u16, len: u8) -> Self {
+        self.data.push(0x47);
+        self.data.push(1);
+        self.data.extend(base.to_le_bytes());
+        self.data.extend(base.to_le_bytes());
+        self.data.push(1);
+        self.data.push(len);
+        self
+    }
+
+    /// `IRQNoFlags() { irq }`: an edge-triggered, active-high ISA interrupt
+    pub fn irq(mut self, irq: u8) -> Self {
+        assert!(irq < 16);
+        self.data.push(0x22);
+        self.data.extend((1u16 << irq).to_le_bytes());
+        self
+    }
+
+    /// `Memory32Fixed(ReadWrite, base, len)`
+    pub fn mem32_fixed(mut self, base: u32, len: u32) -> Self {
+        self.data.push(0x86);
+        self.data.extend(9u16.to_le_bytes());
+        self.data.push(1);
+        self.data.extend(base.to_le_bytes());
+        self.data.extend(len.to_le_bytes());
+        self
+    }
+
+    /// `WordBusNumber(ResourceProducer, MinFixed, MaxFixed, PosDecode, ...)`
+    pub fn bus_range(mut self, first: u8, last: u8) -> Self {
+        self.word_addr(2, 0, first as u16, last as u16);
+        self
+    }
+
+    /// `WordIO(ResourceProducer, MinFixed, MaxFixed, PosDecode, EntireRange,
+    /// ...)` covering `start..=end`
+    pub fn io_window(mut self, start: u16, end: u16) -> Self {
+        self.word_addr(1, 3, start, end);
+        self
+    }
+
+    /// `DWordMemory` or `QWordMemory(ResourceProducer, PosDecode, MinFixed,
+    /// MaxFixed, NonCacheable, ReadWrite, ...)` covering `start..=end`,
+    /// depending on whether the window lies below 4GiB.
+    pub fn mem_window(mut self, start: u64, end: u64) -> Self {
+        assert!(start <= end);
+        if end <= u32::MAX as u64 {
+            self.data.push(0x87);
+            self.data.extend(23u16.to_le_bytes());
+            self.data.extend([0, 0x0c, 0x01]);
+            for v in [0, start as u32, end as u32, 0, (end - start + 1) as u32]
+            {
+                self.data.extend(v.to_le_bytes());
+            }
+        } else {
+            self.data.push(0x8a);
+            self.data.extend(43u16.to_le_bytes());
+            self.data.extend([0, 0x0c, 0x01]);
+            for v in [0, start, end, 0, end - start + 1] {
+                self.data.extend(v.to_le_bytes());
+            }
+        }
+        self
+    }
+
+    /// `Register(space, bit_width, bit_offset, address, access_size)`: a
+    /// Generic Register Descriptor
+    pub fn register(
+        mut self,
+        space: AddressSpace,
+        bit_width: u8,
+        bit_offset: u8,
+        address: u64,
+        access_size: u8,
+    ) -> Self {
+        self.data.push(0x82);
+        self.data.extend(12u16.to_le_bytes());
+        self.data.extend([space as u8, bit_width, bit_offset, access_size]);
+        self.data.extend(address.to_le_bytes());
+        self
+    }
+
+    fn word_addr(&mut self, kind: u8, type_flags: u8, start: u16, end: u16) {
+        assert!(start <= end);
+        self.data.push(0x88);
+        self.data.extend(13u16.to_le_bytes());
+        self.data.extend([kind, 0x0c, type_flags]);
+        for v in [0, start, end, 0, end - start + 1] {
+            self.data.extend(v.to_le_bytes());
+        }
+    }
+}
+impl Aml for ResourceTemplate {
+    fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = self.data.clone();
+        // End tag, with a zeroed checksum denoting the template is valid
+        data.extend([0x79, 0]);
+        Buffer(data).append_to(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(name_string("_HID"), b"_HID");
+        assert_eq!(name_string("_S5"), b"_S5_");
+        assert_eq!(name_string("\\"), [b'\\', 0]);
+        assert_eq!(name_string("\\_SB"), b"\\_SB_");
+        assert_eq!(name_string("\\_SB.PCI0"), b"\\\x2e_SB_PCI0");
+        assert_eq!(name_string("\\_SB.PCI0.ISA"), b"\\\x2f\x03_SB_PCI0ISA_");
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(0u64.to_aml(), [ZERO_OP]);
+        assert_eq!(1u64.to_aml(), [ONE_OP]);
+        assert_eq!(0x12u64.to_aml(), [BYTE_PREFIX, 0x12]);
+        assert_eq!(0x1234u64.to_aml(), [WORD_PREFIX, 0x34, 0x12]);
+        assert_eq!(0x10000u64.to_aml(), [DWORD_PREFIX, 0, 0, 1, 0]);
+        assert_eq!(
+            0x1_0000_0000u64.to_aml(),
+            [QWORD_PREFIX, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn eisa_id() {
+        // As compiled by iasl
+        assert_eq!(EisaId("PNP0A03").value(), 0x030ad041);
+        assert_eq!(EisaId("PNP0501").value(), 0x0105d041);
+    }
+
+    #[test]
+    fn pkg_length() {
+        let short = with_pkg_length(vec![0; 0x3e]);
+        assert_eq!(short[0], 0x3f);
+        assert_eq!(short.len(), 0x3f);
+
+        let long = with_pkg_length(vec![0; 0x3f]);
+        assert_eq!(&long[..2], [0x41, 0x04]);
+        assert_eq!(long.len(), 0x41);
+
+        let longer = with_pkg_length(vec![0; 0x1000]);
+        assert_eq!(&longer[..3], [0x83, 0x00, 0x01]);
+        assert_eq!(longer.len(), 0x1003);
+    }
+
+    #[test]
+    fn device() {
+        let dev = Container::device("COM1")
+            .with(Name::new("_HID", EisaId("PNP0501")))
+            .with(Name::new("_UID", 1u8))
+            .with(Name::new(
+                "_CRS",
+                ResourceTemplate::new().io(0x3f8, 8).irq(4),
+            ));
+        let expected = [
+            &[EXT_OP_PREFIX, DEVICE_OP, 0x2b][..],
+            b"COM1",
+            &[NAME_OP],
+            b"_HID",
+            &[DWORD_PREFIX, 0x41, 0xd0, 0x05, 0x01],
+            &[NAME_OP],
+            b"_UID",
+            &[ONE_OP],
+            &[NAME_OP],
+            b"_CRS",
+            &[BUFFER_OP, 0x10, BYTE_PREFIX, 0x0d],
+            &[0x47, 0x01, 0xf8, 0x03, 0xf8, 0x03, 0x01, 0x08],
+            &[0x22, 0x10, 0x00],
+            &[0x79, 0x00],
+        ]
+        .concat();
+        assert_eq!(dev.to_aml(), expected);
+    }
+
+    #[test]
+    fn register() {
+        let reg = ResourceTemplate::new().register(
+            AddressSpace::SystemIo,
+            16,
+            0,
+            0xb004,
+            2,
+        );
+        assert_eq!(
+            reg.to_aml(),
+            [
+                &[BUFFER_OP, 0x14, BYTE_PREFIX, 0x11][..],
+                &[0x82, 0x0c, 0x00, 0x01, 0x10, 0x00, 0x02],
+                &[0x04, 0xb0, 0, 0, 0, 0, 0, 0],
+                &[0x79, 0x00],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn method() {
+        let method = Container::method("_EJ0", 1)
+            .with(Op::store(0x20u8, Path::new("B0EJ")));
+        let expected = [
+            &[METHOD_OP, 0x0d][..],
+            b"_EJ0",
+            &[1, STORE_OP, BYTE_PREFIX, 0x20],
+            b"B0EJ",
+        ]
+        .concat();
+        assert_eq!(method.to_aml(), expected);
+
+        let cond = Container::if_then(Op::and(Local(0), 4u8))
+            .with(Op::notify(Path::new("C001"), 3u8));
+        let expected = [
+            &[IF_OP, 0x0d, AND_OP, LOCAL0_OP, BYTE_PREFIX, 4, NULL_NAME][..],
+            &[NOTIFY_OP],
+            b"C001",
+            &[BYTE_PREFIX, 3],
+        ]
+        .concat();
+        assert_eq!(cond.to_aml(), expected);
+    }
+
+    #[test]
+    fn region_and_field() {
+        let region =
+            OpRegion::new("PCST", AddressSpace::SystemIo, 0xae00, 0x14);
+        assert_eq!(
+            region.to_aml(),
+            [
+                &[EXT_OP_PREFIX, OP_REGION_OP][..],
+                b"PCST",
+                &[1, WORD_PREFIX, 0x00, 0xae, BYTE_PREFIX, 0x14],
+            ]
+            .concat()
+        );
+
+        let field =
+            Field::new("PRST", FieldAccess::Byte).skip(32).unit("CFLG", 8);
+        assert_eq!(
+            field.to_aml(),
+            [
+                &[EXT_OP_PREFIX, FIELD_OP, 0x0d][..],
+                b"PRST",
+                &[0x41, 0x00, 0x20],
+                b"CFLG",
+                &[0x08],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn package() {
+        let pkg = Package::new().with(0u8).with(Ones).with("ab");
+        assert_eq!(
+            pkg.to_aml(),
+            [
+                PACKAGE_OP,
+                0x08,
+                3,
+                ZERO_OP,
+                ONES_OP,
+                STRING_PREFIX,
+                b'a',
+                b'b',
+                0
+            ]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Commands for the QEMU table loader.
+//!
+//! The ACPI tables are exposed to firmware as fw_cfg files.  Since their final
+//! location in guest memory is chosen by the firmware, the `etc/table-loader`
+//! file provides a script of commands instructing it how to allocate space for
+//! each file, patch in the pointers between tables, and compute checksums.
+//! Each command occupies a fixed 128-byte record.
+
+const CMD_LEN: usize = 128;
+const FILE_NAME_LEN: usize = 56;
+
+const CMD_ALLOCATE: u32 = 1;
+const CMD_ADD_POINTER: u32 = 2;
+const CMD_ADD_CHECKSUM: u32 = 3;
+
+/// Region of memory in which the firmware should allocate a file
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Zone {
+    /// Anywhere in (32-bit addressable) memory
+    High = 1,
+    /// Within the legacy F-segment (0xf0000-0xfffff), as required of the RSDP
+    FSeg = 2,
+}
+
+#[derive(Default)]
+pub struct Loader {
+    cmds: Vec<u8>,
+}
+impl Loader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate memory for, and copy in the contents of, `file`
+    pub fn allocate(&mut self, file: &str, align: u32, zone: Zone) {
+        let mut cmd = Self::cmd(CMD_ALLOCATE);
+        cmd.extend(Self::file_name(file));
+        cmd.extend(align.to_le_bytes());
+        cmd.push(zone as u8);
+        self.push(cmd);
+    }
+
+    /// Add the guest-physical address of `src_file` to the `size`-byte
+    /// little-endian value found at `offset` in `dest_file`.
+    pub fn add_pointer(
+        &mut self,
+        dest_file: &str,
+        src_file: &str,
+        offset: u32,
+        size: u8,
+    ) {
+        assert!(matches!(size, 1 | 2 | 4 | 8));
+        let mut cmd = Self::cmd(CMD_ADD_POINTER);
+        cmd.extend(Self::file_name(dest_file));
+        cmd.extend(Self::file_name(src_file));
+        cmd.extend(offset.to_le_bytes());
+        cmd.push(size);
+        self.push(cmd);
+    }
+
+    /// Store the checksum of `len` bytes starting at `start` in `file` in the
+    /// byte found at `offset` of that file.
+    pub fn add_checksum(
+        &mut self,
+        file: &str,
+        offset: u32,
+        start: u32,
+        len: u32,
+    ) {
+        let mut cmd = Self::cmd(CMD_ADD_CHECKSUM);
+        cmd.extend(Self::file_name(file));
+        cmd.extend(offset.to_le_bytes());
+        cmd.extend(start.to_le_bytes());
+        cmd.extend(len.to_le_bytes());
+        self.push(cmd);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.cmds
+    }
+
+    fn cmd(kind: u32) -> Vec<u8> {
+        let mut cmd = Vec::with_capacity(CMD_LEN);
+        cmd.extend(kind.to_le_bytes());
+        cmd
+    }
+    fn file_name(name: &str) -> [u8; FILE_NAME_LEN] {
+        // Names must remain NUL-terminated
+        assert!(name.len() < FILE_NAME_LEN);
+        let mut buf = [0u8; FILE_NAME_LEN];
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        buf
+    }
+    fn push(&mut self, mut cmd: Vec<u8>) {
+        assert!(cmd.len() <= CMD_LEN);
+        cmd.resize(CMD_LEN, 0);
+        self.cmds.extend(cmd);
+    }
+}
+
+/// Interpreter for loader scripts, mimicking the firmware, for validating the
+/// generated tables.
+#[cfg(test)]
+pub(crate) mod interp {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    pub struct Loaded {
+        pub files: BTreeMap<String, (u64, Vec<u8>)>,
+    }
+    impl Loaded {
+        pub fn read(&self, addr: u64, len: usize) -> &[u8] {
+            for (base, data) in self.files.values() {
+                if addr >= *base && addr < base + data.len() as u64 {
+                    let off = (addr - base) as usize;
+                    return &data[off..off + len];
+                }
+            }
+            panic!("address {addr:#x} not backed by a loaded file");
+        }
+    }
+
+    fn name(raw: &[u8]) -> String {
+        let end = raw.iter().position(|b| *b == 0).unwrap();
+        String::from_utf8(raw[..end].to_vec()).unwrap()
+    }
+    fn u32_at(raw: &[u8], off: usize) -> u32 {
+        u32::from_le_bytes(raw[off..off + 4].try_into().unwrap())
+    }
+
+    /// Execute `script` against `files`, placing high allocations starting at
+    /// `high_base`.
+    pub fn run(
+        script: &[u8],
+        files: &BTreeMap<String, Vec<u8>>,
+        high_base: u64,
+    ) -> Loaded {
+        assert_eq!(script.len() % CMD_LEN, 0);
+        let mut out = Loaded { files: BTreeMap::new() };
+        let mut next_high = high_base;
+        let mut next_fseg = 0xf_0000u64;
+
+        for cmd in script.chunks(CMD_LEN) {
+            let body = &cmd[4..];
+            match u32_at(cmd, 0) {
+                CMD_ALLOCATE => {
+                    let file = name(&body[..FILE_NAME_LEN]);
+                    let align = u32_at(body, FILE_NAME_LEN) as u64;
+                    let next = match body[FILE_NAME_LEN + 4] {
+                        1 => &mut next_high,
+                        2 => &mut next_fseg,
+                        z => panic!("bad zone {z}"),
+                    };
+                    let base = (*next + align - 1) / align * align;
+                    let data = files[&file].clone();
+                    *next = base + data.len() as u64;
+                    out.files.insert(file, (base, data));
+                }
+                CMD_ADD_POINTER => {
+                    let dest = name(&body[..FILE_NAME_LEN]);
+                    let src = name(&body[FILE_NAME_LEN..2 * FILE_NAME_LEN]);
+                    let off = u32_at(body, 2 * FILE_NAME_LEN) as usize;
+                    let size = body[2 * FILE_NAME_LEN + 4] as usize;
+                    let src_base = out.files[&src].0;
+                    let data = &mut out.files.get_mut(&dest).unwrap().1;
+                    let mut raw = [0u8; 8];
+                    raw[..size].copy_from_slice(&data[off..off + size]);
+                    let val = u64::from_le_bytes(raw) + src_base;
+                    data[off..off + size]
+                        .copy_from_slice(&val.to_le_bytes()[..size]);
+                }
+                CMD_ADD_CHECKSUM => {
+                    let file = name(&body[..FILE_NAME_LEN]);
+                    let off = u32_at(body, FILE_NAME_LEN) as usize;
+                    let start = u32_at(body, FILE_NAME_LEN + 4) as usize;
+                    let len = u32_at(body, FILE_NAME_LEN + 8) as usize;
+                    let data = &mut out.files.get_mut(&file).unwrap().1;
+                    data[off] = super::super::tables::checksum(
+                        &data[start..start + len],
+                    );
+                }
+                c => panic!("unexpected loader command {c}"),
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,1121 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Generation of ACPI tables describing the machine.
+//!
+//! Rather than relying upon the bootrom to carry a static description of the
+//! platform, the RSDP, XSDT, FADT, FACS, MADT, MCFG (when PCIe ECAM is
+//! enabled), and DSDT are built from the machine configuration.  They are
+//! provided to the firmware through fw_cfg, using the QEMU table loader
+//! interface supported by OVMF, which places them in guest memory and installs
+//! them for the guest OS.
+//!
+//! Slots on bus 0 into which the host hot-plugs devices, or from which it
+//! removes them, are each described by a slot object, through which the guest
+//! is notified of changes to the slot and ejects its device.  Notifications
+//! are delivered by the handler of the PCI hotplug GPE, which reads the
+//! pending changes from the registers of
+//! [`AcpiPciHotplug`](crate::hw::pci::hotplug::AcpiPciHotplug).
+//!
+//! When vCPUs may be hot-removed, each processor object reports its presence
+//! (`_STA`, `_MAT`) and is ejected (`_EJ0`) through the registers of
+//! [`CpuHotplug`](crate::hw::acpi::cpu_hotplug::CpuHotplug), and the handler
+//! of the CPU hotplug GPE notifies the processors the host wants removed.
+//!
+//! Changes to the maintenance notice posted by the host are delivered, by the
+//! handler of the maintenance GPE, as notifications to the device describing
+//! the notice's registers.
+//!
+//! The vCPUs run at a fixed frequency, which is described to the guest through
+//! both CPPC (`_CPC`) and P-state (`_PSS`) objects, each offering a single
+//! performance level.  Neither provides any real control: they exist so that
+//! guest tools and schedulers asking after the CPU frequency get a consistent
+//! answer, rather than none at all.
+
+use std::ops::Range;
+
+use crate::hw::acpi::cpu_hotplug::{
+    CPU_HOTPLUG_LEN, FLAG_EJECT, FLAG_ENABLED, FLAG_REMOVE_EVENT,
+    PORT_CPU_HOTPLUG,
+};
+use crate::hw::acpi::gpe::{GPE_CPU_HOTPLUG, GPE_MAINTENANCE, GPE_PCI_HOTPLUG};
+use crate::hw::acpi::maintenance::{
+    MAINTENANCE_ACPI_HID, MAINTENANCE_LEN, PORT_MAINTENANCE,
+};
+use crate::hw::bhyve::{HPET_ADDR, HPET_LEN};
+use crate::hw::chipset::i440fx::PciIntxRoute;
+use crate::hw::ibmpc;
+use crate::hw::pci::hotplug::{PCI_HOTPLUG_LEN, PORT_PCI_HOTPLUG};
+use crate::hw::qemu::fwcfg::{self, FixedItem, FwCfgBuilder};
+use crate::hw::qemu::pvpanic::PVPANIC_ACPI_HID;
+use crate::hw::tpm::{TPM_CRB_ADDR, TPM_CRB_LEN};
+use crate::topology::CpuTopology;
+
+pub mod aml;
+mod loader;
+mod tables;
+
+use aml::{
+    AddressSpace, Aml, Arg, Buffer, Call, Container, EisaId, Field,
+    FieldAccess, Local, Mutex, Name, Op, OpRegion, Package, Path,
+    ResourceTemplate,
+};
+use loader::{Loader, Zone};
+use tables::Table;
+
+const FILE_RSDP: &str = "etc/acpi/rsdp";
+const FILE_TABLES: &str = "etc/acpi/tables";
+const FILE_LOADER: &str = "etc/table-loader";
+
+const ADDR_LAPIC: u32 = 0xfee0_0000;
+const ADDR_IOAPIC: u32 = 0xfec0_0000;
+
+/// ISA IRQ used for the SCI
+const SCI_IRQ: u8 = 9;
+/// Offsets of the fixed-feature registers within the PM IO block
+const PM1_EVT_OFF: u16 = 0x0;
+const PM1_CNT_OFF: u16 = 0x4;
+const PM_TMR_OFF: u16 = 0x8;
+
+/// The single performance level reported through CPPC, on its abstract scale
+const CPPC_PERF: u32 = 100;
+
+/// Offset of the control area within the TPM CRB interface
+const TPM_CRB_CTRL_OFF: u64 = 0x40;
+/// TPM2 table start method: Command Response Buffer
+const TPM2_START_CRB: u32 = 7;
+
+/// Machine configuration from which the ACPI tables are generated
+pub struct Config {
+    /// Arrangement of the vCPUs, whose APIC IDs are numbered from 0
+    pub topology: CpuTopology,
+    /// Fixed frequency, in MHz, at which the vCPUs are reported to run
+    pub cpu_freq_mhz: u32,
+    /// Base of the PIIX PM IO register block
+    pub pm_base: u16,
+    /// Base of the GPE0 register block, if one is attached
+    pub gpe0_port: Option<u16>,
+    /// Slots on bus 0 in which devices may be hot-plugged or removed, as a
+    /// bitmask, if the ACPI PCI hotplug controller is attached.  Its events
+    /// are delivered only if the GPE0 block is also attached.
+    pub pci_hotplug_slots: Option<u32>,
+    /// Whether the ACPI CPU hotplug controller is attached, through which
+    /// vCPUs other than the boot processor may be removed.  Its events are
+    /// delivered only if the GPE0 block is also attached.
+    pub cpu_hotplug: bool,
+    /// Whether the register block through which the guest is notified of
+    /// impending maintenance is attached.  Its events are delivered only if
+    /// the GPE0 block is also attached.
+    pub maintenance: bool,
+    /// Routing of the INTx lines of devices on the root PCI bus
+    pub pci_intx_routes: Vec<PciIntxRoute>,
+    /// Location of the PCIe ECAM region, if enabled
+    pub pcie_ecam: Option<Range<u64>>,
+    /// MMIO window for PCI BARs below 4GiB
+    pub pci_window_32: Range<u64>,
+    /// MMIO window for PCI BARs above 4GiB
+    pub pci_window_64: Option<Range<u64>>,
+    /// Whether a TPM CRB interface is attached at its standard location
+    pub tpm_crb: bool,
+    /// I/O port of the ISA pvpanic device, if one is attached
+    pub pvpanic_port: Option<u16>,
+    /// Event Timer Block ID (the lower 32 bits of the capabilities register)
+    /// of the HPET, if it is to be described to the guest
+    pub hpet_block_id: Option<u32>,
+}
+
+/// The generated tables, and the loader script directing the firmware in
+/// placing them in guest memory
+pub struct Tables {
+    rsdp: Vec<u8>,
+    tables: Vec<u8>,
+    loader: Vec<u8>,
+}
+impl Tables {
+    /// Expose the tables to the firmware via fw_cfg
+    pub fn attach(self, fwcfg: &mut FwCfgBuilder) -> fwcfg::Result {
+        fwcfg.add_named(FILE_RSDP, FixedItem::new_raw(self.rsdp))?;
+        fwcfg.add_named(FILE_TABLES, FixedItem::new_raw(self.tables))?;
+        fwcfg.add_named(FILE_LOADER, FixedItem::new_raw(self.loader))
+    }
+}
+
+/// Build the ACPI tables describing a machine with configuration `cfg`
+pub fn build(cfg: &Config) -> Tables {
+    let mut blob = Vec::new();
+    let mut loader = Loader::new();
+    loader.allocate(FILE_TABLES, 64, Zone::High);
+    loader.allocate(FILE_RSDP, 16, Zone::FSeg);
+
+    // The FACS requires 64-byte alignment, so place it first
+    let facs_off = append(&mut blob, build_facs());
+    let dsdt_off = append(&mut blob, build_dsdt(cfg));
+
+    let mut fadt = build_fadt(cfg);
+    put_u32(&mut fadt, tables::FADT_OFF_FIRMWARE_CTRL, facs_off as u32);
+    put_u32(&mut fadt, tables::FADT_OFF_DSDT, dsdt_off as u32);
+    put_u64(&mut fadt, tables::FADT_OFF_X_DSDT, dsdt_off as u64);
+    let fadt_off = append(&mut blob, fadt);
+    for (off, size) in [
+        (tables::FADT_OFF_FIRMWARE_CTRL, 4),
+        (tables::FADT_OFF_DSDT, 4),
+        (tables::FADT_OFF_X_DSDT, 8),
+    ] {
+        loader.add_pointer(
+            FILE_TABLES,
+            FILE_TABLES,
+            (fadt_off + off) as u32,
+            size,
+        );
+    }
+
+    let mut entries = vec![fadt_off, append(&mut blob, build_madt(cfg))];
+    if let Some(ecam) = cfg.pcie_ecam.as_ref() {
+        entries.push(append(&mut blob, build_mcfg(ecam)));
+    }
+    if cfg.tpm_crb {
+        entries.push(append(&mut blob, build_tpm2()));
+    }
+    if let Some(block_id) = cfg.hpet_block_id {
+        entries.push(append(&mut blob, build_hpet(block_id)));
+    }
+
+    let mut xsdt = Table::sdt(b"XSDT", 1);
+    let xsdt_entries = xsdt.len();
+    for off in entries.iter() {
+        xsdt.u64(*off as u64);
+    }
+    let xsdt_off = append(&mut blob, xsdt.finish_sdt());
+    for i in 0..entries.len() {
+        loader.add_pointer(
+            FILE_TABLES,
+            FILE_TABLES,
+            (xsdt_off + xsdt_entries + i * 8) as u32,
+            8,
+        );
+    }
+
+    // With all pointers in place, the table checksums can be computed
+    let mut sdts = vec![dsdt_off, xsdt_off];
+    sdts.extend(entries);
+    for off in sdts {
+        let len =
+            u32::from_le_bytes(blob[off + 4..off + 8].try_into().unwrap());
+        loader.add_checksum(
+            FILE_TABLES,
+            (off + tables::SDT_CHECKSUM_OFF) as u32,
+            off as u32,
+            len,
+        );
+    }
+
+    let rsdp = build_rsdp(xsdt_off as u64);
+    loader.add_pointer(FILE_RSDP, FILE_TABLES, tables::RSDP_OFF_XSDT as u32, 8);
+    loader.add_checksum(
+        FILE_RSDP,
+        tables::RSDP_OFF_CHECKSUM as u32,
+        0,
+        tables::RSDP_V1_LEN as u32,
+    );
+    loader.add_checksum(
+        FILE_RSDP,
+        tables::RSDP_OFF_EXT_CHECKSUM as u32,
+        0,
+        tables::RSDP_LEN as u32,
+    );
+
+    Tables { rsdp, tables: blob, loader: loader.finish() }
+}
+
+/// Append `table` to `blob`, returning its offset
+fn append(blob: &mut Vec<u8>, table: Vec<u8>) -> usize {
+    let off = blob.len();
+    blob.extend(table);
+    off
+}
+fn put_u32(data: &mut [u8], off: usize, val: u32) {
+    data[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
+fn put_u64(data: &mut [u8], off: usize, val: u64) {
+    data[off..off + 8].copy_from_slice(&val.to_le_bytes());
+}
+
+fn build_rsdp(xsdt_off: u64) -> Vec<u8> {
+    let mut rsdp = Table::raw();
+    rsdp.bytes(b"RSD PTR ")
+        .u8(0) // checksum
+        .bytes(tables::OEM_ID)
+        .u8(2) // revision
+        .u32(0) // RSDT address (none provided)
+        .u32(tables::RSDP_LEN as u32)
+        .u64(xsdt_off)
+        .u8(0) // extended checksum
+        .zeroes(3);
+    assert_eq!(rsdp.len(), tables::RSDP_LEN);
+    rsdp.finish()
+}
+
+fn build_facs() -> Vec<u8> {
+    let mut facs = Table::raw();
+    facs.bytes(b"FACS")
+        .u32(tables::FACS_LEN as u32)
+        .u32(0) // hardware signature
+        .u32(0) // firmware waking vector
+        .u32(0) // global lock
+        .u32(0) // flags
+        .u64(0) // X firmware waking vector
+        .u8(2) // version
+        .zeroes(3)
+        .u32(0) // OSPM flags
+        .zeroes(24);
+    assert_eq!(facs.len(), tables::FACS_LEN);
+    facs.finish()
+}
+
+fn build_fadt(cfg: &Config) -> Vec<u8> {
+    // Fixed feature flags
+    const WBINVD: u32 = 1 << 0;
+    const PROC_C1: u32 = 1 << 2;
+    // Sleep button, if any, is a control method device
+    const SLP_BUTTON: u32 = 1 << 5;
+
+    // IA-PC boot architecture flags
+    const LEGACY_DEVICES: u16 = 1 << 0;
+    const HAS_8042: u16 = 1 << 1;
+    const VGA_NOT_PRESENT: u16 = 1 << 2;
+
+    let pm1_evt = cfg.pm_base + PM1_EVT_OFF;
+    let pm1_cnt = cfg.pm_base + PM1_CNT_OFF;
+    let pm_tmr = cfg.pm_base + PM_TMR_OFF;
+    let (gpe0, gpe0_len) = match cfg.gpe0_port {
+        Some(port) => (port, 4),
+        None => (0, 0),
+    };
+
+    let mut fadt = Table::sdt(b"FACP", tables::FADT_REVISION);
+    fadt.u32(0) // FIRMWARE_CTRL, patched by loader
+        .u32(0) // DSDT, patched by loader
+        .u8(0)
+        .u8(0) // preferred PM profile: unspecified
+        .u16(SCI_IRQ as u16)
+        // No SMI command port: the platform is always in ACPI mode
+        .u32(0)
+        .u8(0)
+        .u8(0)
+        .u8(0)
+        .u8(0)
+        .u32(pm1_evt as u32)
+        .u32(0)
+        .u32(pm1_cnt as u32)
+        .u32(0)
+        .u32(0)
+        .u32(pm_tmr as u32)
+        .u32(gpe0 as u32)
+        .u32(0)
+        .u8(4) // PM1_EVT_LEN
+        .u8(2) // PM1_CNT_LEN
+        .u8(0) // PM2_CNT_LEN
+        .u8(4) // PM_TMR_LEN
+        .u8(gpe0_len)
+        .u8(0) // GPE1_BLK_LEN
+        .u8(0) // GPE1_BASE
+        .u8(0) // CST_CNT
+        // C2 and C3 are not supported
+        .u16(0x0fff)
+        .u16(0x0fff)
+        .u16(0)
+        .u16(0)
+        .u8(0)
+        .u8(0)
+        .u8(0) // DAY_ALRM
+        .u8(0) // MON_ALRM
+        .u8(0x32) // CENTURY in RTC CMOS
+        .u16(LEGACY_DEVICES | HAS_8042 | VGA_NOT_PRESENT)
+        .u8(0)
+        .u32(WBINVD | PROC_C1 | SLP_BUTTON)
+        .zeroes(12) // RESET_REG: reset is not supported
+        .u8(0) // RESET_VALUE
+        .u16(0) // ARM_BOOT_ARCH
+        .u8(tables::FADT_MINOR_REVISION)
+        .u64(0) // X_FIRMWARE_CTRL: FIRMWARE_CTRL is used instead
+        .u64(0) // X_DSDT, patched by loader
+        .gas_io(pm1_evt, 4)
+        .gas_io(0, 0)
+        .gas_io(pm1_cnt, 2)
+        .gas_io(0, 0)
+        .gas_io(0, 0)
+        .gas_io(pm_tmr, 4)
+        .gas_io(gpe0, gpe0_len)
+        .gas_io(0, 0)
+        .gas_io(0, 0) // SLEEP_CONTROL_REG
+        .gas_io(0, 0) // SLEEP_STATUS_REG
+        .u64(0); // hypervisor vendor identity
+    let fadt = fadt.finish_sdt();
+    assert_eq!(fadt.len(), tables::FADT_LEN);
+    fadt
+}
+
+fn build_madt(cfg: &Config) -> Vec<u8> {
+    const PCAT_COMPAT: u32 = 1 << 0;
+    const LAPIC_ENABLED: u32 = 1 << 0;
+    // MPS INTI flags
+    const ACTIVE_HIGH: u16 = 0b01;
+    const EDGE: u16 = 0b01 << 2;
+    const LEVEL: u16 = 0b11 << 2;
+
+    let mut madt = Table::sdt(b"APIC", 5);
+    madt.u32(ADDR_LAPIC).u32(PCAT_COMPAT);
+    for id in madt_apic_order(&cfg.topology) {
+        // Processor Local APIC, with ACPI UID matching the APIC ID
+        madt.u8(0).u8(8).u8(id).u8(id).u32(LAPIC_ENABLED);
+    }
+    // I/O APIC, with ID following those of the vCPUs
+    let ioapic_id = cfg.topology.num_vcpus().get();
+    madt.u8(1).u8(12).u8(ioapic_id).u8(0).u32(ADDR_IOAPIC).u32(0);
+    // Interrupt Source Overrides: the PIT is wired to pin 2, and the SCI is
+    // level-triggered
+    madt.u8(2).u8(10).u8(0).u8(0).u32(2).u16(ACTIVE_HIGH | EDGE);
+    madt.u8(2)
+        .u8(10)
+        .u8(0)
+        .u8(SCI_IRQ)
+        .u32(SCI_IRQ as u32)
+        .u16(ACTIVE_HIGH | LEVEL);
+    // Local APIC NMI on LINT1 of all processors
+    madt.u8(4).u8(6).u8(0xff).u16(0).u8(1);
+    madt.finish_sdt()
+}
+
+/// APIC IDs in the order their processors are listed in the MADT.
+///
+/// Guests commonly bring processors online in MADT order, and one which is
+/// limited in the number it will use should prefer separate cores over SMT
+/// siblings, so the first thread of every core is listed before any second
+/// thread.
+fn madt_apic_order(topo: &CpuTopology) -> impl Iterator<Item = u8> {
+    let threads = topo.threads_per_core();
+    let cores = topo.num_vcpus().get() / threads;
+    (0..threads).flat_map(move |t| (0..cores).map(move |c| c * threads + t))
+}
+
+fn build_mcfg(ecam: &Range<u64>) -> Vec<u8> {
+    let mut mcfg = Table::sdt(b"MCFG", 1);
+    mcfg.zeroes(8)
+        .u64(ecam.start)
+        .u16(0) // segment group
+        .u8(0)
+        .u8(last_ecam_bus(ecam))
+        .u32(0);
+    mcfg.finish_sdt()
+}
+
+/// Last bus number decoded by an ECAM region, with 1MiB of space per bus
+fn last_ecam_bus(ecam: &Range<u64>) -> u8 {
+    let buses = (ecam.end - ecam.start) >> 20;
+    assert!(buses > 0 && buses <= 256);
+    (buses - 1) as u8
+}
+
+/// TPM2 table (revision 4) for a TPM reached through its CRB interface.  See
+/// the TCG ACPI Specification, Section 8.3.
+fn build_tpm2() -> Vec<u8> {
+    let mut tpm2 = Table::sdt(b"TPM2", 4);
+    tpm2.u16(0) // platform class: client
+        .u16(0)
+        .u64(TPM_CRB_ADDR as u64 + TPM_CRB_CTRL_OFF)
+        .u32(TPM2_START_CRB)
+        .zeroes(12); // start method parameters
+    tpm2.finish_sdt()
+}
+
+/// HPET table (revision 1) describing the HPET emulated by the kernel.  See the
+/// IA-PC HPET Specification, Section 3.2.4.
+fn build_hpet(block_id: u32) -> Vec<u8> {
+    let mut hpet = Table::sdt(b"HPET", 1);
+    hpet.u32(block_id)
+        // Base address, as a GAS in system memory
+        .u8(0)
+        .u8(64)
+        .u8(0)
+        .u8(0)
+        .u64(HPET_ADDR)
+        .u8(0) // HPET number
+        .u16(0) // minimum clock tick in periodic mode
+        .u8(0); // page protection: none
+    hpet.finish_sdt()
+}
+
+fn build_dsdt(cfg: &Config) -> Vec<u8> {
+    let mut sb = Container::scope("\\_SB");
+
+    let mut pci0 = Container::device("PCI0");
+    match cfg.pcie_ecam {
+        Some(_) => {
+            pci0.push(Name::new("_HID", EisaId("PNP0A08")));
+            pci0.push(Name::new("_CID", EisaId("PNP0A03")));
+        }
+        None => pci0.push(Name::new("_HID", EisaId("PNP0A03"))),
+    }
+    pci0.push(Name::new("_ADR", 0u8));
+    pci0.push(Name::new("_UID", 0u8));
+    pci0.push(Name::new("_BBN", 0u8));
+
+    let last_bus = cfg.pcie_ecam.as_ref().map(last_ecam_bus).unwrap_or(0xff);
+    let cfg_port = crate::hw::pci::bits::PORT_PCI_CONFIG_ADDR;
+    let mut crs = ResourceTemplate::new()
+        .bus_range(0, last_bus)
+        .io(cfg_port, 8)
+        .io_window(0, cfg_port - 1)
+        .io_window(cfg_port + 8, 0xffff)
+        .mem_window(cfg.pci_window_32.start, cfg.pci_window_32.end - 1);
+    if let Some(win) = cfg.pci_window_64.as_ref() {
+        crs = crs.mem_window(win.start, win.end - 1);
+    }
+    pci0.push(Name::new("_CRS", crs));
+
+    // Only APIC-mode routing is described: a guest using the PIC is left to
+    // the PIRQ routing established by the firmware.
+    let mut prt = Package::new();
+    for route in cfg.pci_intx_routes.iter() {
+        prt.push(
+            Package::new()
+                .with(((route.dev as u32) << 16) | 0xffff)
+                .with(route.pin)
+                .with(0u8)
+                .with(route.gsi),
+        );
+    }
+    pci0.push(Name::new("_PRT", prt));
+    pci0.push(build_isa_devices());
+    if let Some(slots) = cfg.pci_hotplug_slots {
+        build_pci_hotplug(&mut pci0, slots);
+    }
+    sb.push(pci0);
+
+    if let Some(ecam) = cfg.pcie_ecam.as_ref() {
+        // Reserve the ECAM region as a motherboard resource
+        let len = (ecam.end - ecam.start) as u32;
+        sb.push(
+            Container::device("MRES")
+                .with(Name::new("_HID", EisaId("PNP0C02")))
+                .with(Name::new("_UID", 0u8))
+                .with(Name::new(
+                    "_CRS",
+                    ResourceTemplate::new().mem32_fixed(ecam.start as u32, len),
+                )),
+        );
+    }
+
+    if cfg.tpm_crb {
+        let crs = ResourceTemplate::new()
+            .mem32_fixed(TPM_CRB_ADDR as u32, TPM_CRB_LEN as u32);
+        sb.push(
+            Container::device("TPM")
+                .with(Name::new("_HID", "MSFT0101"))
+                .with(Name::new("_CRS", crs)),
+        );
+    }
+
+    if cfg.hpet_block_id.is_some() {
+        let crs = ResourceTemplate::new()
+            .mem32_fixed(HPET_ADDR as u32, HPET_LEN as u32);
+        sb.push(
+            Container::device("HPET")
+                .with(Name::new("_HID", EisaId("PNP0103")))
+                .with(Name::new("_UID", 0u8))
+                .with(Name::new("_CRS", crs)),
+        );
+    }
+
+    if let Some(port) = cfg.pvpanic_port {
+        sb.push(
+            Container::device("PEVT")
+                .with(Name::new("_HID", PVPANIC_ACPI_HID))
+                .with(Name::new("_CRS", ResourceTemplate::new().io(port, 1))),
+        );
+    }
+
+    if cfg.maintenance {
+        let crs =
+            ResourceTemplate::new().io(PORT_MAINTENANCE, MAINTENANCE_LEN as u8);
+        sb.push(
+            Container::device("MNTN")
+                .with(Name::new("_HID", MAINTENANCE_ACPI_HID))
+                .with(Name::new("_UID", 0u8))
+                .with(Name::new("_CRS", crs)),
+        );
+    }
+
+    let num_vcpus = cfg.topology.num_vcpus().get();
+    if cfg.cpu_hotplug {
+        build_cpu_hotplug(&mut sb, num_vcpus);
+    }
+    for id in 0..num_vcpus {
+        let mut cpu = Container::device(cpu_name(id))
+            .with(Name::new("_HID", "ACPI0007"))
+            .with(Name::new("_UID", id))
+            .with(Name::new("_CPC", build_cpc(cfg.cpu_freq_mhz)))
+            .with(Name::new("_PCT", build_pct()))
+            .with(Name::new("_PSS", build_pss(cfg.cpu_freq_mhz)))
+            .with(Name::new("_PPC", 0u8));
+        if cfg.cpu_hotplug {
+            build_cpu_presence(&mut cpu, id);
+        }
+        sb.push(cpu);
+    }
+
+    // Handlers of the events signaled through GPE0
+    let mut gpe = Container::scope("\\_GPE");
+    let has_gpe = cfg.gpe0_port.is_some();
+    if has_gpe && cfg.pci_hotplug_slots.is_some() {
+        gpe.push(
+            Container::method(gpe_handler(GPE_PCI_HOTPLUG), 0)
+                .with(Call::new("\\_SB.PCI0.PCNT")),
+        );
+    }
+    if has_gpe && cfg.cpu_hotplug {
+        gpe.push(
+            Container::method(gpe_handler(GPE_CPU_HOTPLUG), 0)
+                .with(Call::new("\\_SB.CSCN")),
+        );
+    }
+    if has_gpe && cfg.maintenance {
+        gpe.push(
+            Container::method(gpe_handler(GPE_MAINTENANCE), 0)
+                .with(Op::notify(Path::new("\\_SB.MNTN"), 0x80u8)),
+        );
+    }
+
+    let mut dsdt = Table::sdt(b"DSDT", 2);
+    dsdt.bytes(&sb.to_aml());
+    dsdt.bytes(&gpe.to_aml());
+    // Soft-off is reached by writing SLP_TYP 0 to PM1_CNT
+    dsdt.bytes(
+        &Name::new("\\_S5", Package::new().with(0u8).with(0u8)).to_aml(),
+    );
+    dsdt.finish_sdt()
+}
+
+/// Name of the method handling the (edge-triggered) event signaled by `bit`
+/// of the GPE0 status register
+fn gpe_handler(bit: u16) -> String {
+    assert!(bit.is_power_of_two());
+    format!("_E{:02X}", bit.trailing_zeros())
+}
+
+/// Hotplug of the devices in `slots` on bus 0, through the registers of the
+/// ACPI PCI hotplug controller.
+///
+/// Each slot is described by a device, through which the guest ejects its
+/// occupant, and the `PCNT` method, run by the GPE handler, notifies the slots
+/// into which the host has inserted devices, and those whose occupants it
+/// wants removed.
+fn build_pci_hotplug(pci0: &mut Container, slots: u32) {
+    pci0.push(OpRegion::new(
+        "PCST",
+        AddressSpace::SystemIo,
+        PORT_PCI_HOTPLUG as u64,
+        PCI_HOTPLUG_LEN as u64,
+    ));
+    pci0.push(
+        Field::new("PCST", FieldAccess::DWord)
+            .unit("PCIU", 32)
+            .unit("PCID", 32)
+            .unit("B0EJ", 32),
+    );
+
+    // Reading PCIU clears it, so each insertion is reported once
+    let mut pcnt = Container::method("PCNT", 0)
+        .with(Op::store(Path::new("PCIU"), Local(1)))
+        .with(Op::store(Path::new("PCID"), Local(0)));
+    for slot in (0..32u8).filter(|slot| slots & (1 << slot) != 0) {
+        let name = format!("S{slot:02X}");
+        let bit = 1u32 << slot;
+        // Device check, upon which the guest scans the slot for the inserted
+        // device
+        pcnt.push(
+            Container::if_then(Op::and(Local(1), bit))
+                .with(Op::notify(Path::new(name.clone()), 1u8)),
+        );
+        // Eject request
+        pcnt.push(
+            Container::if_then(Op::and(Local(0), bit))
+                .with(Op::notify(Path::new(name.clone()), 3u8)),
+        );
+        pci0.push(
+            Container::device(name)
+                .with(Name::new("_ADR", (slot as u32) << 16))
+                .with(Name::new("_SUN", slot))
+                .with(
+                    Container::method("_EJ0", 1)
+                        .with(Op::store(bit, Path::new("B0EJ"))),
+                ),
+        );
+    }
+    pci0.push(pcnt);
+}
+
+/// Name of the processor object for the vCPU with ID `id`
+fn cpu_name(id: u8) -> String {
+    format!("C{id:03X}")
+}
+
+/// Methods through which processor objects reach the registers of the CPU
+/// hotplug controller, which hold the state of the CPU selected by `CSEL`.
+///
+/// - `CSTA(id)` returns the `_STA` value of a CPU: present and enabled unless
+///   it has been ejected
+/// - `CEJ0(id)` ejects a CPU
+/// - `CSCN()`, run by the GPE handler, notifies the processors which the host
+///   wants removed
+fn build_cpu_hotplug(sb: &mut Container, num_vcpus: u8) {
+    sb.push(Mutex("CPLK"));
+    sb.push(OpRegion::new(
+        "PRST",
+        AddressSpace::SystemIo,
+        PORT_CPU_HOTPLUG as u64,
+        CPU_HOTPLUG_LEN as u64,
+    ));
+    sb.push(Field::new("PRST", FieldAccess::DWord).unit("CSEL", 32));
+    sb.push(Field::new("PRST", FieldAccess::Byte).skip(32).unit("CFLG", 8));
+
+    sb.push(
+        Container::method("CSTA", 1)
+            .with(Op::acquire("CPLK", 0xffff))
+            .with(Op::store(Arg(0), Path::new("CSEL")))
+            .with(Op::store(0u8, Local(0)))
+            .with(
+                Container::if_then(Op::and(Path::new("CFLG"), FLAG_ENABLED))
+                    .with(Op::store(0x0fu8, Local(0))),
+            )
+            .with(Op::release("CPLK"))
+            .with(Op::ret(Local(0))),
+    );
+    sb.push(
+        Container::method("CEJ0", 1)
+            .with(Op::acquire("CPLK", 0xffff))
+            .with(Op::store(Arg(0), Path::new("CSEL")))
+            .with(Op::store(FLAG_EJECT, Path::new("CFLG")))
+            .with(Op::release("CPLK")),
+    );
+
+    // The boot processor cannot be removed
+    let mut cscn =
+        Container::method("CSCN", 0).with(Op::acquire("CPLK", 0xffff));
+    for id in 1..num_vcpus {
+        cscn.push(Op::store(id, Path::new("CSEL")));
+        cscn.push(
+            Container::if_then(Op::and(Path::new("CFLG"), FLAG_REMOVE_EVENT))
+                .with(Op::notify(Path::new(cpu_name(id)), 3u8)),
+        );
+    }
+    cscn.push(Op::release("CPLK"));
+    sb.push(cscn);
+}
+
+/// Presence and ejection of the processor object `cpu` for the vCPU `id`.
+///
+/// Its `_MAT` repeats the vCPU's Local APIC entry in the MADT, marked disabled
+/// once the vCPU has been ejected.
+fn build_cpu_presence(cpu: &mut Container, id: u8) {
+    const LAPIC_ENABLED: u8 = 1 << 0;
+    let lapic = |flags: u8| Buffer(vec![0, 8, id, id, flags, 0, 0, 0]);
+
+    cpu.push(
+        Container::method("_STA", 0)
+            .with(Op::ret(Call::new("\\_SB.CSTA").with(id))),
+    );
+    cpu.push(
+        Container::method("_MAT", 0)
+            .with(
+                Container::if_then(Op::lequal(
+                    Call::new("\\_SB.CSTA").with(id),
+                    0x0fu8,
+                ))
+                .with(Op::ret(lapic(LAPIC_ENABLED))),
+            )
+            .with(Op::ret(lapic(0))),
+    );
+    if id != 0 {
+        cpu.push(
+            Container::method("_EJ0", 1).with(Call::new("\\_SB.CEJ0").with(id)),
+        );
+    }
+}
+
+/// A register which is not implemented
+fn null_register() -> ResourceTemplate {
+    ResourceTemplate::new().register(AddressSpace::SystemMemory, 0, 0, 0, 0)
+}
+
+/// Continuous Performance Control (revision 3), describing a single fixed
+/// performance level.  See ACPI 6.4 Section 8.4.6.1.
+///
+/// The desired performance and the feedback counters are given as constant
+/// integers, rather than registers: the former cannot be changed, and counters
+/// which never advance leave the guest to conclude that the delivered
+/// performance is that desired.
+fn build_cpc(freq_mhz: u32) -> Package<'static> {
+    Package::new()
+        .with(23u8) // NumEntries
+        .with(3u8) // Revision
+        .with(CPPC_PERF) // Highest Performance
+        .with(CPPC_PERF) // Nominal Performance
+        .with(CPPC_PERF) // Lowest Nonlinear Performance
+        .with(CPPC_PERF) // Lowest Performance
+        .with(null_register()) // Guaranteed Performance Register
+        .with(CPPC_PERF) // Desired Performance Register
+        .with(null_register()) // Minimum Performance Register
+        .with(null_register()) // Maximum Performance Register
+        .with(null_register()) // Performance Reduction Tolerance Register
+        .with(null_register()) // Time Window Register
+        .with(0u8) // Counter Wraparound Time: never
+        .with(1u8) // Reference Performance Counter Register
+        .with(1u8) // Delivered Performance Counter Register
+        .with(null_register()) // Performance Limited Register
+        .with(null_register()) // CPPC Enable Register
+        .with(0u8) // Autonomous Selection Enable
+        .with(null_register()) // Autonomous Activity Window Register
+        .with(null_register()) // Energy Performance Preference Register
+        .with(CPPC_PERF) // Reference Performance
+        .with(freq_mhz) // Lowest Frequency
+        .with(freq_mhz) // Nominal Frequency
+}
+
+/// Performance Control, through functional fixed hardware, which a guest
+/// without the corresponding CPU features (not advertised to it) will decline
+/// to use.
+fn build_pct() -> Package<'static> {
+    let ffh = || {
+        ResourceTemplate::new().register(
+            AddressSpace::FixedHardware,
+            0,
+            0,
+            0,
+            0,
+        )
+    };
+    Package::new().with(ffh()).with(ffh())
+}
+
+/// Performance Supported States, listing only the fixed frequency
+fn build_pss(freq_mhz: u32) -> Package<'static> {
+    Package::new().with(
+        Package::new()
+            .with(freq_mhz) // CoreFrequency
+            .with(0u8) // Power
+            .with(0u8) // Latency
+            .with(0u8) // BusMasterLatency
+            .with(0u8) // Control
+            .with(0u8), // Status
+    )
+}
+
+/// Devices decoded by the PIIX3 LPC bridge
+fn build_isa_devices() -> Container<'static> {
+    let uart = |name: &'static str, uid: u8, port: u16, irq: u8| {
+        Container::device(name)
+            .with(Name::new("_HID", EisaId("PNP0501")))
+            .with(Name::new("_UID", uid))
+            .with(Name::new(
+                "_CRS",
+                ResourceTemplate::new().io(port, 8).irq(irq),
+            ))
+    };
+
+    Container::device("ISA")
+        .with(Name::new("_ADR", 0x0001_0000u32))
+        .with(
+            Container::device("RTC")
+                .with(Name::new("_HID", EisaId("PNP0B00")))
+                .with(Name::new(
+                    "_CRS",
+                    ResourceTemplate::new().io(0x70, 2).irq(8),
+                )),
+        )
+        .with(
+            Container::device("KBD")
+                .with(Name::new("_HID", EisaId("PNP0303")))
+                .with(Name::new(
+                    "_CRS",
+                    ResourceTemplate::new()
+                        .io(ibmpc::PORT_PS2_DATA, 1)
+                        .io(ibmpc::PORT_PS2_CMD_STATUS, 1)
+                        .irq(ibmpc::IRQ_PS2_PRI),
+                )),
+        )
+        .with(
+            Container::device("MOU")
+                .with(Name::new("_HID", EisaId("PNP0F13")))
+                .with(Name::new(
+                    "_CRS",
+                    ResourceTemplate::new().irq(ibmpc::IRQ_PS2_AUX),
+                )),
+        )
+        .with(uart("COM1", 1, ibmpc::PORT_COM1, ibmpc::IRQ_COM1))
+        .with(uart("COM2", 2, ibmpc::PORT_COM2, ibmpc::IRQ_COM2))
+        .with(uart("COM3", 3, ibmpc::PORT_COM3, ibmpc::IRQ_COM3))
+        .with(uart("COM4", 4, ibmpc::PORT_COM4, ibmpc::IRQ_COM4))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn test_config(ecam: bool) -> Config {
+        Config {
+            topology: CpuTopology::new(1, 2, 2).unwrap(),
+            cpu_freq_mhz: 2450,
+            pm_base: 0xb000,
+            gpe0_port: Some(0xafe0),
+            pci_hotplug_slots: None,
+            cpu_hotplug: false,
+            maintenance: false,
+            pci_intx_routes: vec![PciIntxRoute {
+                dev: 3,
+                pin: 1,
+                link: 2,
+                gsi: 18,
+            }],
+            pcie_ecam: ecam.then_some(0xe000_0000..0xf000_0000),
+            pci_window_32: 0xc000_0000..0xe000_0000,
+            pci_window_64: Some(0x1_0000_0000..0x10_0000_0000),
+            tpm_crb: false,
+            pvpanic_port: None,
+            hpet_block_id: None,
+        }
+    }
+
+    fn load(tables: Tables) -> loader::interp::Loaded {
+        let files = BTreeMap::from([
+            (FILE_RSDP.to_string(), tables.rsdp),
+            (FILE_TABLES.to_string(), tables.tables),
+        ]);
+        loader::interp::run(&tables.loader, &files, 0x7ff0_0000)
+    }
+
+    fn read_u32(data: &[u8], off: usize) -> u32 {
+        u32::from_le_bytes(data[off..off + 4].try_into().unwrap())
+    }
+    fn read_u64(data: &[u8], off: usize) -> u64 {
+        u64::from_le_bytes(data[off..off + 8].try_into().unwrap())
+    }
+    fn sums_to_zero(data: &[u8]) -> bool {
+        data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) == 0
+    }
+
+    /// Walk from the RSDP, as a guest would, returning the tables found
+    fn walk(loaded: &loader::interp::Loaded) -> BTreeMap<String, Vec<u8>> {
+        let (rsdp_addr, rsdp) = &loaded.files[FILE_RSDP];
+        assert!((0xf_0000..0x10_0000).contains(rsdp_addr));
+        assert_eq!(rsdp_addr % 16, 0);
+        assert_eq!(&rsdp[..8], b"RSD PTR ");
+        assert!(sums_to_zero(&rsdp[..tables::RSDP_V1_LEN]));
+        assert!(sums_to_zero(rsdp));
+
+        let read_sdt = |addr: u64| {
+            let len = read_u32(loaded.read(addr, 8), 4) as usize;
+            let data = loaded.read(addr, len).to_vec();
+            assert!(sums_to_zero(&data));
+            data
+        };
+
+        let mut found = BTreeMap::new();
+        let xsdt = read_sdt(read_u64(rsdp, tables::RSDP_OFF_XSDT));
+        assert_eq!(&xsdt[..4], b"XSDT");
+        for entry in xsdt[tables::SDT_HEADER_LEN..].chunks(8) {
+            let table = read_sdt(u64::from_le_bytes(entry.try_into().unwrap()));
+            let sig = String::from_utf8(table[..4].to_vec()).unwrap();
+            if sig == "FACP" {
+                let dsdt_addr = read_u64(&table, tables::FADT_OFF_X_DSDT);
+                assert_eq!(
+                    dsdt_addr,
+                    read_u32(&table, tables::FADT_OFF_DSDT) as u64
+                );
+                found.insert("DSDT".to_string(), read_sdt(dsdt_addr));
+
+                let facs_addr =
+                    read_u32(&table, tables::FADT_OFF_FIRMWARE_CTRL) as u64;
+                assert_eq!(facs_addr % 64, 0);
+                let facs = loaded.read(facs_addr, tables::FACS_LEN).to_vec();
+                assert_eq!(&facs[..4], b"FACS");
+                found.insert("FACS".to_string(), facs);
+            }
+            found.insert(sig, table);
+        }
+        found
+    }
+
+    #[test]
+    fn loaded_tables() {
+        let found = walk(&load(build(&test_config(false))));
+        assert_eq!(
+            found.keys().collect::<Vec<_>>(),
+            ["APIC", "DSDT", "FACP", "FACS"]
+        );
+        assert_eq!(found["FACP"].len(), tables::FADT_LEN);
+
+        let found = walk(&load(build(&test_config(true))));
+        assert_eq!(
+            found.keys().collect::<Vec<_>>(),
+            ["APIC", "DSDT", "FACP", "FACS", "MCFG"]
+        );
+        let mcfg = &found["MCFG"];
+        assert_eq!(read_u64(mcfg, 44), 0xe000_0000);
+        assert_eq!(mcfg[55], 0xff);
+
+        let mut cfg = test_config(false);
+        cfg.tpm_crb = true;
+        let found = walk(&load(build(&cfg)));
+        assert_eq!(
+            found.keys().collect::<Vec<_>>(),
+            ["APIC", "DSDT", "FACP", "FACS", "TPM2"]
+        );
+        let tpm2 = &found["TPM2"];
+        assert_eq!(tpm2.len(), 64);
+        assert_eq!(read_u64(tpm2, 40), 0xfed4_0040);
+        assert_eq!(read_u32(tpm2, 48), 7);
+        assert!(found["DSDT"].windows(8).any(|w| w == b"MSFT0101"));
+
+        let mut cfg = test_config(false);
+        cfg.hpet_block_id = Some(0x8086_a701);
+        let found = walk(&load(build(&cfg)));
+        assert_eq!(
+            found.keys().collect::<Vec<_>>(),
+            ["APIC", "DSDT", "FACP", "FACS", "HPET"]
+        );
+        let hpet = &found["HPET"];
+        assert_eq!(hpet.len(), 56);
+        assert_eq!(read_u32(hpet, 36), 0x8086_a701);
+        assert_eq!(read_u64(hpet, 44), 0xfed0_0000);
+        assert!(found["DSDT"].windows(4).any(|w| w == b"HPET"));
+
+        let mut cfg = test_config(false);
+        cfg.pvpanic_port = Some(0x505);
+        let dsdt = build_dsdt(&cfg);
+        assert!(dsdt.windows(8).any(|w| w == b"QEMU0001"));
+
+        let mut cfg = test_config(false);
+        cfg.maintenance = true;
+        let dsdt = build_dsdt(&cfg);
+        assert!(dsdt.windows(8).any(|w| w == b"PRPL0001"));
+        assert!(dsdt.windows(4).any(|w| w == b"_E03"));
+    }
+
+    #[test]
+    fn madt_entries() {
+        let cfg = test_config(false);
+        let madt = build_madt(&cfg);
+
+        let mut counts = BTreeMap::<u8, usize>::new();
+        let mut off = 44;
+        while off < madt.len() {
+            *counts.entry(madt[off]).or_default() += 1;
+            off += madt[off + 1] as usize;
+        }
+        assert_eq!(off, madt.len());
+        // LAPICs, IOAPIC, overrides, and NMI
+        assert_eq!(
+            counts,
+            BTreeMap::from([
+                (0, cfg.topology.num_vcpus().get() as usize),
+                (1, 1),
+                (2, 2),
+                (4, 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn madt_thread_order() {
+        let order = |s, c, t| {
+            madt_apic_order(&CpuTopology::new(s, c, t).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(1, 4, 1), [0, 1, 2, 3]);
+        assert_eq!(order(1, 2, 2), [0, 2, 1, 3]);
+        assert_eq!(order(2, 2, 2), [0, 2, 4, 6, 1, 3, 5, 7]);
+    }
+
+    #[test]
+    fn processor_performance() {
+        let cfg = test_config(false);
+        let dsdt = build_dsdt(&cfg);
+        let count =
+            |name: &[u8]| dsdt.windows(4).filter(|w| w == &name).count();
+        let vcpus = cfg.topology.num_vcpus().get() as usize;
+        for name in [b"_CPC", b"_PCT", b"_PSS", b"_PPC"] {
+            assert_eq!(count(name), vcpus);
+        }
+
+        // NumEntries leads the package, and the nominal frequency ends it
+        let cpc = build_cpc(cfg.cpu_freq_mhz).to_aml();
+        assert_eq!(cpc[3..6], [0x17, 0x0a, 0x17]);
+        assert_eq!(cpc[cpc.len() - 3..], [0x0b, 0x92, 0x09]);
+    }
+
+    #[test]
+    fn pci_hotplug() {
+        let count = |dsdt: &[u8], name: &[u8]| {
+            dsdt.windows(name.len()).filter(|w| w == &name).count()
+        };
+
+        let mut cfg = test_config(false);
+        cfg.pci_hotplug_slots = Some(1 << 5 | 1 << 0x1f);
+        let dsdt = build_dsdt(&cfg);
+        assert_eq!(count(&dsdt, b"_E01"), 1);
+        assert_eq!(count(&dsdt, b"_EJ0"), 2);
+        assert_eq!(count(&dsdt, b"S05_"), 3);
+        assert_eq!(count(&dsdt, b"S1F_"), 3);
+        assert_eq!(count(&dsdt, b"PCIU"), 2);
+
+        // Without a GPE0 block, the guest is never notified of changes
+        cfg.gpe0_port = None;
+        let dsdt = build_dsdt(&cfg);
+        assert_eq!(count(&dsdt, b"_E01"), 0);
+
+        cfg.pci_hotplug_slots = None;
+        let dsdt = build_dsdt(&cfg);
+        assert_eq!(count(&dsdt, b"_EJ0"), 0);
+    }
+
+    #[test]
+    fn cpu_hotplug() {
+        let count = |dsdt: &[u8], name: &[u8]| {
+            dsdt.windows(name.len()).filter(|w| w == &name).count()
+        };
+        let mut cfg = test_config(false);
+        let vcpus = cfg.topology.num_vcpus().get() as usize;
+        let dsdt = build_dsdt(&cfg);
+        assert_eq!(count(&dsdt, b"_STA"), 0);
+
+        cfg.cpu_hotplug = true;
+        let dsdt = build_dsdt(&cfg);
+        assert_eq!(count(&dsdt, b"_E02"), 1);
+        assert_eq!(count(&dsdt, b"_STA"), vcpus);
+        assert_eq!(count(&dsdt, b"_MAT"), vcpus);
+        // The boot processor cannot be ejected
+        assert_eq!(count(&dsdt, b"_EJ0"), vcpus - 1);
+        assert_eq!(count(&dsdt, b"C000"), 1);
+        assert_eq!(count(&dsdt, b"C001"), 2);
+    }
+
+    #[test]
+    fn fadt_pm_blocks() {
+        let fadt = build_fadt(&test_config(false));
+        assert_eq!(read_u32(&fadt, 56), 0xb000);
+        assert_eq!(read_u32(&fadt, 64), 0xb004);
+        assert_eq!(read_u32(&fadt, 76), 0xb008);
+        assert_eq!(read_u32(&fadt, 80), 0xafe0);
+        // X_PM_TMR_BLK
+        assert_eq!(read_u64(&fadt, 208 + 4), 0xb008);
+
+        let mut cfg = test_config(false);
+        cfg.gpe0_port = None;
+        let fadt = build_fadt(&cfg);
+        assert_eq!(read_u32(&fadt, 80), 0);
+        assert_eq!(fadt[92], 0);
+    }
+}
@@ -0,0 +1,151 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Layout of the fixed-format ACPI tables.
+//!
+//! Checksum fields are left zeroed: they are computed by the firmware (at the
+//! direction of the table loader) once table pointers have been relocated.
+
+pub const OEM_ID: &[u8; 6] = b"OXIDE ";
+pub const OEM_TABLE_ID: &[u8; 8] = b"PROPOLIS";
+pub const OEM_REVISION: u32 = 1;
+pub const CREATOR_ID: &[u8; 4] = b"OXDE";
+pub const CREATOR_REVISION: u32 = 1;
+
+/// Length of the header common to all system description tables
+pub const SDT_HEADER_LEN: usize = 36;
+/// Offset of the checksum within the SDT header
+pub const SDT_CHECKSUM_OFF: usize = 9;
+
+pub const FACS_LEN: usize = 64;
+
+pub const FADT_REVISION: u8 = 6;
+pub const FADT_MINOR_REVISION: u8 = 4;
+pub const FADT_LEN: usize = 276;
+pub const FADT_OFF_FIRMWARE_CTRL: usize = 36;
+pub const FADT_OFF_DSDT: usize = 40;
+pub const FADT_OFF_X_DSDT: usize = 140;
+
+pub const RSDP_LEN: usize = 36;
+pub const RSDP_V1_LEN: usize = 20;
+pub const RSDP_OFF_CHECKSUM: usize = 8;
+pub const RSDP_OFF_XSDT: usize = 24;
+pub const RSDP_OFF_EXT_CHECKSUM: usize = 32;
+
+/// A table under construction, to which fields are appended in order
+pub struct Table {
+    data: Vec<u8>,
+}
+impl Table {
+    /// Begin a system description table with the standard header
+    pub fn sdt(signature: &[u8; 4], revision: u8) -> Self {
+        let mut this = Self { data: Vec::with_capacity(SDT_HEADER_LEN) };
+        this.bytes(signature)
+            .u32(0) // length, filled in by finish_sdt()
+            .u8(revision)
+            .u8(0) // checksum
+            .bytes(OEM_ID)
+            .bytes(OEM_TABLE_ID)
+            .u32(OEM_REVISION)
+            .bytes(CREATOR_ID)
+            .u32(CREATOR_REVISION);
+        this
+    }
+    /// Begin a table without the standard header (RSDP and FACS)
+    pub fn raw() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.data.extend_from_slice(data);
+        self
+    }
+    pub fn u8(&mut self, val: u8) -> &mut Self {
+        self.data.push(val);
+        self
+    }
+    pub fn u16(&mut self, val: u16) -> &mut Self {
+        self.bytes(&val.to_le_bytes())
+    }
+    pub fn u32(&mut self, val: u32) -> &mut Self {
+        self.bytes(&val.to_le_bytes())
+    }
+    pub fn u64(&mut self, val: u64) -> &mut Self {
+        self.bytes(&val.to_le_bytes())
+    }
+    pub fn zeroes(&mut self, count: usize) -> &mut Self {
+        self.data.resize(self.data.len() + count, 0);
+        self
+    }
+
+    /// Append a Generic Address Structure describing an IO port region
+    pub fn gas_io(&mut self, port: u16, len: u8) -> &mut Self {
+        if len == 0 {
+            return self.zeroes(12);
+        }
+        let access_size = match len {
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+        self.u8(1) // System I/O space
+            .u8(len * 8)
+            .u8(0)
+            .u8(access_size)
+            .u64(port as u64)
+    }
+
+    /// Complete an SDT, filling in its length
+    pub fn finish_sdt(mut self) -> Vec<u8> {
+        let len = self.data.len() as u32;
+        self.data[4..8].copy_from_slice(&len.to_le_bytes());
+        self.data
+    }
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Compute the value which, when stored in a zeroed checksum field, causes
+/// `data` to sum to zero.
+#[cfg(test)]
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)).wrapping_neg()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sdt_header() {
+        let mut tbl = Table::sdt(b"TEST", 2);
+        assert_eq!(tbl.len(), SDT_HEADER_LEN);
+        tbl.u32(0xdeadbeef);
+        let data = tbl.finish_sdt();
+        assert_eq!(&data[..4], b"TEST");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 40);
+        assert_eq!(data[8], 2);
+        assert_eq!(data[SDT_CHECKSUM_OFF], 0);
+        assert_eq!(&data[10..16], OEM_ID);
+    }
+
+    #[test]
+    fn gas() {
+        let mut tbl = Table::raw();
+        tbl.gas_io(0xb008, 4);
+        assert_eq!(tbl.finish(), [1, 32, 0, 3, 0x08, 0xb0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn checksum_zeroes_sum() {
+        let mut data = vec![1u8, 2, 3, 0xff, 0];
+        data[4] = checksum(&data);
+        assert_eq!(data.iter().fold(0u8, |a, b| a.wrapping_add(*b)), 0);
+    }
+}
@@ -0,0 +1,270 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reading of asset information from the host's SMBIOS tables.
+//!
+//! The host tables are read from an image in the format exposed by the
+//! illumos smbios(4D) driver: the entry point, followed by the structure
+//! table, whose address in the entry point is relative to the start of the
+//! image.
+
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::{
+    OFF_CHASSIS_ASSET_TAG, OFF_SERIAL, TYPE_BASEBOARD, TYPE_CHASSIS, TYPE_END,
+    TYPE_SYSTEM,
+};
+
+/// Host asset fields which may be passed through to guests
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum HostField {
+    /// Serial number of the system (type 1)
+    SystemSerial,
+    /// Serial number of the baseboard (type 2)
+    BaseboardSerial,
+    /// Serial number of the chassis (type 3)
+    ChassisSerial,
+    /// Asset tag of the chassis (type 3)
+    ChassisAssetTag,
+}
+impl HostField {
+    pub const ALL: [HostField; 4] = [
+        Self::SystemSerial,
+        Self::BaseboardSerial,
+        Self::ChassisSerial,
+        Self::ChassisAssetTag,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::SystemSerial => "system-serial",
+            Self::BaseboardSerial => "baseboard-serial",
+            Self::ChassisSerial => "chassis-serial",
+            Self::ChassisAssetTag => "chassis-asset-tag",
+        }
+    }
+
+    /// Structure type and string offset from which the field is read
+    fn location(&self) -> (u8, usize) {
+        match self {
+            Self::SystemSerial => (TYPE_SYSTEM, OFF_SERIAL),
+            Self::BaseboardSerial => (TYPE_BASEBOARD, OFF_SERIAL),
+            Self::ChassisSerial => (TYPE_CHASSIS, OFF_SERIAL),
+            Self::ChassisAssetTag => (TYPE_CHASSIS, OFF_CHASSIS_ASSET_TAG),
+        }
+    }
+}
+impl FromStr for HostField {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL.into_iter().find(|f| f.name() == s).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown host SMBIOS field: {s}"),
+            )
+        })
+    }
+}
+
+/// Asset fields read from the host
+#[derive(Clone, Debug, Default)]
+pub struct HostInfo {
+    fields: Vec<(HostField, String)>,
+}
+impl HostInfo {
+    /// Read the `wanted` fields from the host SMBIOS image at `path`.
+    ///
+    /// Fields absent from the host tables are omitted.
+    pub fn read(path: &Path, wanted: &[HostField]) -> Result<Self> {
+        let image = std::fs::read(path)?;
+        Self::from_image(&image, wanted)
+    }
+
+    fn from_image(image: &[u8], wanted: &[HostField]) -> Result<Self> {
+        let structs = parse_structures(table_from_image(image)?)?;
+        let fields = wanted
+            .iter()
+            .filter_map(|field| {
+                let (kind, off) = field.location();
+                let val = structs
+                    .iter()
+                    .filter(|s| s.kind() == kind)
+                    .find_map(|s| s.string_at(off))?
+                    .trim();
+                (!val.is_empty()).then(|| (*field, val.to_string()))
+            })
+            .collect();
+        Ok(Self { fields })
+    }
+
+    pub fn get(&self, field: HostField) -> Option<&str> {
+        self.fields.iter().find(|(f, _)| *f == field).map(|(_, v)| v.as_str())
+    }
+
+    /// Apply the fields to the guest configuration `cfg`.
+    ///
+    /// Chassis fields are exposed through the guest's chassis structure, as
+    /// the guest is housed in the host's chassis.  Others are exposed as OEM
+    /// strings of the form `host-<field>=<value>`, so they are not mistaken
+    /// for the identity of the guest itself.
+    pub fn apply(&self, cfg: &mut super::Config) {
+        for (field, val) in self.fields.iter() {
+            match field {
+                HostField::ChassisSerial => cfg.chassis_serial = val.clone(),
+                HostField::ChassisAssetTag => {
+                    cfg.chassis_asset_tag = val.clone()
+                }
+                _ => {}
+            }
+            cfg.oem_strings.push(format!("host-{}={}", field.name(), val));
+        }
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("bad SMBIOS image: {msg}"))
+}
+
+/// Locate the structure table within an image
+fn table_from_image(image: &[u8]) -> Result<&[u8]> {
+    let read_u16 = |off: usize| -> Result<usize> {
+        let b = image.get(off..off + 2).ok_or_else(|| invalid("truncated"))?;
+        Ok(u16::from_le_bytes(b.try_into().unwrap()) as usize)
+    };
+    let read_u32 = |off: usize| -> Result<usize> {
+        let b = image.get(off..off + 4).ok_or_else(|| invalid("truncated"))?;
+        Ok(u32::from_le_bytes(b.try_into().unwrap()) as usize)
+    };
+
+    let (addr, len) = if image.starts_with(b"_SM3_") {
+        // The 64-bit entry point specifies only a maximum table size, with
+        // the table terminated by its end-of-table structure.
+        let addr = read_u32(0x10)?;
+        (addr, read_u32(0x0c)?.min(image.len().saturating_sub(addr)))
+    } else if image.starts_with(b"_SM_") {
+        (read_u32(0x18)?, read_u16(0x16)?)
+    } else {
+        return Err(invalid("missing entry point"));
+    };
+    addr.checked_add(len)
+        .and_then(|end| image.get(addr..end))
+        .ok_or_else(|| invalid("table exceeds image"))
+}
+
+/// A structure parsed from an SMBIOS table
+pub(super) struct Structure<'a> {
+    formatted: &'a [u8],
+    strings: Vec<&'a str>,
+}
+impl<'a> Structure<'a> {
+    pub(super) fn kind(&self) -> u8 {
+        self.formatted[0]
+    }
+    #[cfg(test)]
+    pub(super) fn formatted(&self) -> &'a [u8] {
+        self.formatted
+    }
+    #[cfg(test)]
+    pub(super) fn strings(&self) -> &[&'a str] {
+        &self.strings
+    }
+    /// Get the string referenced by the string number at offset `off`
+    pub(super) fn string_at(&self, off: usize) -> Option<&'a str> {
+        let num = *self.formatted.get(off)? as usize;
+        num.checked_sub(1).and_then(|idx| self.strings.get(idx).copied())
+    }
+}
+
+/// Parse the structures from `table`, up to its end-of-table structure
+pub(super) fn parse_structures(mut table: &[u8]) -> Result<Vec<Structure>> {
+    let mut structs = Vec::new();
+    while table.len() >= 4 {
+        let len = table[1] as usize;
+        if len < 4 || len > table.len() {
+            return Err(invalid("bad structure length"));
+        }
+        let (formatted, rest) = table.split_at(len);
+
+        // The string set ends with a pair of NULs
+        let end = rest
+            .windows(2)
+            .position(|w| w == [0, 0])
+            .ok_or_else(|| invalid("unterminated strings"))?;
+        let strings = rest[..end]
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| std::str::from_utf8(s).unwrap_or(""))
+            .collect();
+        table = &rest[end + 2..];
+
+        let kind = formatted[0];
+        structs.push(Structure { formatted, strings });
+        if kind == TYPE_END {
+            break;
+        }
+    }
+    Ok(structs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firmware::smbios::{build, Config};
+
+    /// Lay out generated tables as a smbios(4D) image would be
+    fn host_image(cfg: &Config) -> Vec<u8> {
+        let mut tables = build(cfg);
+        let addr = tables.anchor.len() as u32;
+        tables.anchor[0x18..0x1c].copy_from_slice(&addr.to_le_bytes());
+        tables.anchor.extend(tables.tables);
+        tables.anchor
+    }
+
+    #[test]
+    fn read_host_fields() {
+        let host = Config {
+            system_serial: "SYS-1".to_string(),
+            chassis_serial: "  CH-1  ".to_string(),
+            ..Default::default()
+        };
+        let image = host_image(&host);
+
+        let info = HostInfo::from_image(&image, &HostField::ALL).unwrap();
+        assert_eq!(info.get(HostField::SystemSerial), Some("SYS-1"));
+        assert_eq!(info.get(HostField::ChassisSerial), Some("CH-1"));
+        assert_eq!(info.get(HostField::ChassisAssetTag), None);
+        assert_eq!(info.get(HostField::BaseboardSerial), None);
+
+        // Only the requested fields are read
+        let info =
+            HostInfo::from_image(&image, &[HostField::ChassisSerial]).unwrap();
+        assert_eq!(info.get(HostField::SystemSerial), None);
+
+        let mut guest = Config::default();
+        info.apply(&mut guest);
+        assert_eq!(guest.chassis_serial, "CH-1");
+        assert_eq!(guest.oem_strings, ["host-chassis-serial=CH-1"]);
+    }
+
+    #[test]
+    fn reject_bad_images() {
+        assert!(HostInfo::from_image(&[], &HostField::ALL).is_err());
+        assert!(HostInfo::from_image(b"_DMI_", &HostField::ALL).is_err());
+
+        let mut image = host_image(&Config::default());
+        image.truncate(image.len() - 8);
+        assert!(HostInfo::from_image(&image, &HostField::ALL).is_err());
+    }
+
+    #[test]
+    fn field_names() {
+        for field in HostField::ALL {
+            assert_eq!(field.name().parse::<HostField>().unwrap(), field);
+        }
+        assert!("rack".parse::<HostField>().is_err());
+    }
+}
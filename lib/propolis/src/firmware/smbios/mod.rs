@@ -0,0 +1,455 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Generation of SMBIOS tables describing the machine.
+//!
+//! The tables are provided to the firmware through the fw_cfg files used by
+//! QEMU, from which OVMF installs them for the guest: an SMBIOS 2.8 entry
+//! point (whose table address the firmware fills in) and the structure table.
+//! Only the BIOS (type 0), System (type 1), Baseboard (type 2), Chassis (type
+//! 3), Processor (type 4), OEM Strings (type 11), Physical Memory Array (type
+//! 16), Memory Device (type 17) and System Boot Information (type 32)
+//! structures are generated.  See DSP0134 for their formats.
+
+use crate::hw::qemu::fwcfg::{self, FixedItem, FwCfgBuilder};
+use crate::topology::CpuTopology;
+
+pub mod host;
+
+const FILE_ANCHOR: &str = "etc/smbios/smbios-anchor";
+const FILE_TABLES: &str = "etc/smbios/smbios-tables";
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BASEBOARD: u8 = 2;
+const TYPE_CHASSIS: u8 = 3;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_OEM_STRINGS: u8 = 11;
+const TYPE_MEMORY_ARRAY: u8 = 16;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_BOOT_INFO: u8 = 32;
+const TYPE_END: u8 = 127;
+
+/// Offset of the serial number string in the System, Baseboard and Chassis
+/// structures
+const OFF_SERIAL: usize = 0x07;
+/// Offset of the asset tag string in the Chassis structure
+const OFF_CHASSIS_ASSET_TAG: usize = 0x08;
+
+const HANDLE_CHASSIS: u16 = 0x2;
+const HANDLE_BASEBOARD: u16 = 0x200;
+/// Handle of the Processor structure for the first socket
+const HANDLE_PROCESSOR: u16 = 0x400;
+const HANDLE_MEMORY_ARRAY: u16 = 0x1000;
+/// Handle of the first Memory Device structure
+const HANDLE_MEMORY_DEVICE: u16 = 0x1100;
+const HANDLE_BOOT_INFO: u16 = 0x2000;
+
+/// Largest memory device described: guest memory is presented as a set of
+/// DIMMs of (at most) this size.
+const DIMM_MAX_MB: u64 = 16 * 1024;
+
+/// Machine configuration from which the SMBIOS tables are generated.
+///
+/// Strings which are empty are omitted from the tables.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub bios_vendor: String,
+    pub bios_version: String,
+    pub bios_release_date: String,
+    pub system_manufacturer: String,
+    pub system_product: String,
+    pub system_serial: String,
+    /// System UUID, which guests commonly use to identify the machine
+    pub system_uuid: uuid::Uuid,
+    pub baseboard_manufacturer: String,
+    pub baseboard_product: String,
+    pub baseboard_serial: String,
+    pub baseboard_asset_tag: String,
+    pub chassis_manufacturer: String,
+    pub chassis_serial: String,
+    pub chassis_asset_tag: String,
+    /// Arrangement of the vCPUs, described by a Processor structure for each
+    /// socket.  If absent, no Processor structures are generated.
+    pub cpu_topology: Option<CpuTopology>,
+    /// Free-form strings, each of which is exposed in the OEM Strings
+    /// structure
+    pub oem_strings: Vec<String>,
+    /// Amount of guest memory, described by the Physical Memory Array and
+    /// Memory Device structures.  If zero, those structures are omitted.
+    pub memory_mb: u64,
+}
+
+/// The generated entry point and structure table
+pub struct Tables {
+    anchor: Vec<u8>,
+    tables: Vec<u8>,
+}
+impl Tables {
+    /// Expose the tables to the firmware via fw_cfg
+    pub fn attach(self, fwcfg: &mut FwCfgBuilder) -> fwcfg::Result {
+        fwcfg.add_named(FILE_ANCHOR, FixedItem::new_raw(self.anchor))?;
+        fwcfg.add_named(FILE_TABLES, FixedItem::new_raw(self.tables))
+    }
+}
+
+/// Build the SMBIOS tables describing a machine with configuration `cfg`
+pub fn build(cfg: &Config) -> Tables {
+    let mut structs = Vec::new();
+
+    let mut bios = Structure::new(TYPE_BIOS, 0x18, 0);
+    bios.string(0x04, &cfg.bios_vendor);
+    bios.string(0x05, &cfg.bios_version);
+    bios.u16(0x06, 0xe800); // Starting address segment
+    bios.string(0x08, &cfg.bios_release_date);
+    // Characteristics are not supported
+    bios.u64(0x0a, 1 << 3);
+    // Extension byte 2: UEFI is supported, and the machine is a VM
+    bios.u8(0x13, (1 << 3) | (1 << 4));
+    // Major and minor releases of the system BIOS and the embedded controller
+    // are unspecified.
+    for off in 0x14..0x18 {
+        bios.u8(off, 0xff);
+    }
+    structs.push(bios);
+
+    let mut system = Structure::new(TYPE_SYSTEM, 0x1b, 1);
+    system.string(0x04, &cfg.system_manufacturer);
+    system.string(0x05, &cfg.system_product);
+    system.string(OFF_SERIAL, &cfg.system_serial);
+    system.bytes(0x08, &cfg.system_uuid.to_bytes_le());
+    system.u8(0x18, 0x06); // Woken by power switch
+    structs.push(system);
+
+    let mut baseboard = Structure::new(TYPE_BASEBOARD, 0x0f, HANDLE_BASEBOARD);
+    baseboard.string(0x04, &cfg.baseboard_manufacturer);
+    baseboard.string(0x05, &cfg.baseboard_product);
+    baseboard.string(OFF_SERIAL, &cfg.baseboard_serial);
+    baseboard.string(0x08, &cfg.baseboard_asset_tag);
+    baseboard.u8(0x09, 0x01); // Hosting board
+    baseboard.u16(0x0b, HANDLE_CHASSIS);
+    baseboard.u8(0x0d, 0x0a); // Motherboard
+    structs.push(baseboard);
+
+    let mut chassis = Structure::new(TYPE_CHASSIS, 0x16, HANDLE_CHASSIS);
+    chassis.string(0x04, &cfg.chassis_manufacturer);
+    chassis.u8(0x05, 0x01); // Other
+    chassis.string(OFF_SERIAL, &cfg.chassis_serial);
+    chassis.string(OFF_CHASSIS_ASSET_TAG, &cfg.chassis_asset_tag);
+    // Boot-up, power supply and thermal states are safe, and the security
+    // status is unknown.
+    chassis.bytes(0x09, &[0x03, 0x03, 0x03, 0x02]);
+    structs.push(chassis);
+
+    if let Some(topo) = cfg.cpu_topology.as_ref() {
+        for socket in 0..topo.sockets() {
+            structs.push(build_processor(topo, socket));
+        }
+    }
+
+    if !cfg.oem_strings.is_empty() {
+        let mut oem = Structure::new(TYPE_OEM_STRINGS, 0x05, 3);
+        assert!(cfg.oem_strings.len() <= u8::MAX as usize);
+        oem.u8(0x04, cfg.oem_strings.len() as u8);
+        for s in cfg.oem_strings.iter() {
+            oem.push_string(s);
+        }
+        structs.push(oem);
+    }
+
+    if cfg.memory_mb != 0 {
+        structs.extend(build_memory(cfg.memory_mb));
+    }
+
+    // Boot information, of which only the status (no errors) is given
+    structs.push(Structure::new(TYPE_BOOT_INFO, 0x0b, HANDLE_BOOT_INFO));
+
+    structs.push(Structure::new(TYPE_END, 0x04, 0xfeff));
+
+    let mut tables = Vec::new();
+    let mut max_len = 0;
+    for s in structs.iter() {
+        let data = s.finish();
+        max_len = max_len.max(data.len());
+        tables.extend(data);
+    }
+    let anchor = build_anchor(&tables, structs.len(), max_len);
+    Tables { anchor, tables }
+}
+
+/// Build the Processor structure for `socket`
+fn build_processor(topo: &CpuTopology, socket: u8) -> Structure {
+    // Processor characteristics
+    const CAPABLE_64BIT: u16 = 1 << 2;
+    const MULTI_CORE: u16 = 1 << 3;
+    const HW_THREAD: u16 = 1 << 4;
+
+    let mut proc =
+        Structure::new(TYPE_PROCESSOR, 0x2a, HANDLE_PROCESSOR + socket as u16);
+    proc.string(0x04, &format!("CPU {socket}"));
+    proc.u8(0x05, 0x03); // Central processor
+    proc.u8(0x06, 0x01); // Family: other
+    proc.u8(0x18, 0x41); // Socket populated, CPU enabled
+    proc.u8(0x19, 0x01); // Upgrade: other
+
+    // The processor ID, voltage and speeds are left unknown, and no cache
+    // information is provided.
+    for off in [0x1a, 0x1c, 0x1e] {
+        proc.u16(off, 0xffff);
+    }
+    let cores = topo.cores_per_socket();
+    proc.u8(0x23, cores);
+    proc.u8(0x24, cores);
+    proc.u8(0x25, topo.threads_per_socket());
+    let mut characteristics = CAPABLE_64BIT;
+    if cores > 1 {
+        characteristics |= MULTI_CORE;
+    }
+    if topo.threads_per_core() > 1 {
+        characteristics |= HW_THREAD;
+    }
+    proc.u16(0x26, characteristics);
+    proc.u16(0x28, 0x01); // Family 2: other
+    proc
+}
+
+/// Build the Physical Memory Array structure, and a Memory Device structure
+/// for each DIMM making up `memory_mb`
+fn build_memory(memory_mb: u64) -> Vec<Structure> {
+    let dimms = (memory_mb + DIMM_MAX_MB - 1) / DIMM_MAX_MB;
+    assert!(dimms < (HANDLE_BOOT_INFO - HANDLE_MEMORY_DEVICE) as u64);
+
+    let mut array =
+        Structure::new(TYPE_MEMORY_ARRAY, 0x17, HANDLE_MEMORY_ARRAY);
+    array.u8(0x04, 0x03); // Location: system board
+    array.u8(0x05, 0x03); // Use: system memory
+    array.u8(0x06, 0x03); // Error correction: none
+
+    // The capacity in KiB, or in bytes in the extended field if it is too
+    // large to be given that way.
+    match u32::try_from(memory_mb * 1024) {
+        Ok(kb) if kb < 0x8000_0000 => array.u32(0x07, kb),
+        _ => {
+            array.u32(0x07, 0x8000_0000);
+            array.u64(0x0f, memory_mb << 20);
+        }
+    }
+    array.u16(0x0b, 0xfffe); // No error information
+    array.u16(0x0d, dimms as u16);
+
+    let mut structs = vec![array];
+    for i in 0..dimms {
+        let size_mb = (memory_mb - i * DIMM_MAX_MB).min(DIMM_MAX_MB);
+        let mut dev = Structure::new(
+            TYPE_MEMORY_DEVICE,
+            0x28,
+            HANDLE_MEMORY_DEVICE + i as u16,
+        );
+        dev.u16(0x04, HANDLE_MEMORY_ARRAY);
+        dev.u16(0x06, 0xfffe); // No error information
+
+        // Total and data widths are unknown
+        dev.u16(0x08, 0xffff);
+        dev.u16(0x0a, 0xffff);
+        // The size in MiB, which a DIMM is never too large to be given as
+        dev.u16(0x0c, size_mb as u16);
+        dev.u8(0x0e, 0x09); // Form factor: DIMM
+        dev.string(0x10, &format!("DIMM {i}"));
+        dev.u8(0x12, 0x07); // Type: RAM
+        dev.u16(0x13, 1 << 1); // Type detail: other
+        structs.push(dev);
+    }
+    structs
+}
+
+/// Build a 32-bit (SMBIOS 2.x) entry point for `tables`
+fn build_anchor(tables: &[u8], count: usize, max_len: usize) -> Vec<u8> {
+    let mut ep = vec![0u8; 0x1f];
+    ep[0x00..0x04].copy_from_slice(b"_SM_");
+    ep[0x05] = 0x1f;
+    ep[0x06] = 2; // SMBIOS 2.8
+    ep[0x07] = 8;
+    ep[0x08..0x0a].copy_from_slice(&(max_len as u16).to_le_bytes());
+    ep[0x10..0x15].copy_from_slice(b"_DMI_");
+    ep[0x16..0x18].copy_from_slice(&(tables.len() as u16).to_le_bytes());
+    // The table address (at 0x18) is filled in by the firmware
+    ep[0x1c..0x1e].copy_from_slice(&(count as u16).to_le_bytes());
+    ep[0x1e] = 0x28;
+
+    // The intermediate checksum covers the `_DMI_` portion, and the entry
+    // point checksum the whole structure.
+    ep[0x15] = checksum(&ep[0x10..0x1f]);
+    ep[0x04] = checksum(&ep);
+    ep
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    0u8.wrapping_sub(data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)))
+}
+
+/// An SMBIOS structure: its formatted area, followed by the strings which
+/// the formatted area references by number
+struct Structure {
+    formatted: Vec<u8>,
+    strings: Vec<String>,
+}
+impl Structure {
+    fn new(kind: u8, len: u8, handle: u16) -> Self {
+        let mut formatted = vec![0u8; len as usize];
+        formatted[0] = kind;
+        formatted[1] = len;
+        formatted[2..4].copy_from_slice(&handle.to_le_bytes());
+        Self { formatted, strings: Vec::new() }
+    }
+    fn u8(&mut self, off: usize, val: u8) {
+        self.formatted[off] = val;
+    }
+    fn u16(&mut self, off: usize, val: u16) {
+        self.bytes(off, &val.to_le_bytes());
+    }
+    fn u32(&mut self, off: usize, val: u32) {
+        self.bytes(off, &val.to_le_bytes());
+    }
+    fn u64(&mut self, off: usize, val: u64) {
+        self.bytes(off, &val.to_le_bytes());
+    }
+    fn bytes(&mut self, off: usize, data: &[u8]) {
+        self.formatted[off..off + data.len()].copy_from_slice(data);
+    }
+    /// Reference `val` from the string number at `off`, leaving it zeroed
+    /// (denoting no string) if `val` is empty.
+    fn string(&mut self, off: usize, val: &str) {
+        if !val.is_empty() {
+            self.formatted[off] = self.push_string(val);
+        }
+    }
+    fn push_string(&mut self, val: &str) -> u8 {
+        // Strings are NUL-terminated, and so cannot contain NULs themselves
+        let val: String = val.chars().filter(|c| *c != '\0').collect();
+        self.strings.push(val);
+        assert!(self.strings.len() <= u8::MAX as usize);
+        self.strings.len() as u8
+    }
+    fn finish(&self) -> Vec<u8> {
+        let mut out = self.formatted.clone();
+        for s in self.strings.iter() {
+            out.extend_from_slice(s.as_bytes());
+            out.push(0);
+        }
+        // The string set is terminated by an additional NUL, or by a pair of
+        // them if there are no strings.
+        if self.strings.is_empty() {
+            out.push(0);
+        }
+        out.push(0);
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config {
+            bios_vendor: "Oxide".to_string(),
+            bios_version: "v0.8".to_string(),
+            system_manufacturer: "Oxide".to_string(),
+            system_product: "OxVM".to_string(),
+            system_uuid: uuid::Uuid::from_u128(
+                0x00112233_4455_6677_8899_aabbccddeeff,
+            ),
+            chassis_serial: "BRM42220004".to_string(),
+            oem_strings: vec!["a=1".to_string(), "b=2".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn anchor_checksums() {
+        let tables = build(&test_config());
+        let ep = &tables.anchor;
+        let sum =
+            |data: &[u8]| data.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+        assert_eq!(sum(ep), 0);
+        assert_eq!(sum(&ep[0x10..]), 0);
+        assert_eq!(
+            u16::from_le_bytes([ep[0x16], ep[0x17]]) as usize,
+            tables.tables.len()
+        );
+        assert_eq!(u16::from_le_bytes([ep[0x1c], ep[0x1d]]), 7);
+    }
+
+    #[test]
+    fn processor_structures() {
+        let cfg = Config {
+            cpu_topology: Some(CpuTopology::new(2, 4, 2).unwrap()),
+            ..test_config()
+        };
+        let tables = build(&cfg);
+        let structs = host::parse_structures(&tables.tables).unwrap();
+        let kinds: Vec<u8> = structs.iter().map(|s| s.kind()).collect();
+        assert_eq!(kinds, [0, 1, 2, 3, 4, 4, 11, 32, 127]);
+
+        let proc = &structs[5];
+        assert_eq!(proc.string_at(0x04), Some("CPU 1"));
+        let formatted = proc.formatted();
+        // Core count, cores enabled, and thread count
+        assert_eq!(formatted[0x23..0x26], [4, 4, 8]);
+        assert_eq!(formatted[0x26], 0b11100);
+    }
+
+    #[test]
+    fn structures_parse() {
+        let tables = build(&test_config());
+        let structs = host::parse_structures(&tables.tables).unwrap();
+        let kinds: Vec<u8> = structs.iter().map(|s| s.kind()).collect();
+        assert_eq!(kinds, [0, 1, 2, 3, 11, 32, 127]);
+
+        let system = &structs[1];
+        assert_eq!(system.string_at(0x05), Some("OxVM"));
+        // Empty strings are omitted
+        assert_eq!(system.string_at(OFF_SERIAL), None);
+        assert_eq!(
+            &system.formatted()[0x08..0x18],
+            [
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99,
+                0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff
+            ]
+        );
+        // The baseboard sits in the chassis
+        let baseboard = structs[2].formatted();
+        assert_eq!(baseboard[0x0b..0x0d], HANDLE_CHASSIS.to_le_bytes());
+        assert_eq!(structs[3].string_at(OFF_SERIAL), Some("BRM42220004"));
+        assert_eq!(structs[4].strings(), ["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn memory_structures() {
+        let cfg = Config { memory_mb: 40 * 1024, ..test_config() };
+        let tables = build(&cfg);
+        let structs = host::parse_structures(&tables.tables).unwrap();
+        let kinds: Vec<u8> = structs.iter().map(|s| s.kind()).collect();
+        assert_eq!(kinds, [0, 1, 2, 3, 11, 16, 17, 17, 17, 32, 127]);
+
+        let array = structs[5].formatted();
+        assert_eq!(array[0x07..0x0b], (40u32 << 20).to_le_bytes());
+        assert_eq!(array[0x0d..0x0f], [3, 0]);
+
+        // Two full DIMMs, and the remainder
+        let sizes: Vec<u16> = structs[6..9]
+            .iter()
+            .map(|s| {
+                u16::from_le_bytes([s.formatted()[0x0c], s.formatted()[0x0d]])
+            })
+            .collect();
+        assert_eq!(sizes, [16384, 16384, 8192]);
+        assert_eq!(structs[8].string_at(0x10), Some("DIMM 2"));
+
+        // Capacities of 2TiB or more are given in the extended field
+        let array = build_memory(4 << 20).remove(0);
+        assert_eq!(array.formatted[0x07..0x0b], 0x8000_0000u32.to_le_bytes());
+        assert_eq!(array.formatted[0x0f..0x17], (4u64 << 40).to_le_bytes());
+    }
+}
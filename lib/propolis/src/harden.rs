@@ -0,0 +1,355 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Process hardening, applied once an instance has been fully initialized.
+//!
+//! By the time its devices are set up, a propolis process holds descriptors
+//! for everything it needs to run the guest, and so it can give up much of
+//! its ambient authority:
+//!
+//! - Descriptors inherited from the parent process (other than stdio) are
+//!   closed.  These are identified by [`capture_inherited`], which must be
+//!   called early in `main`, before any descriptors are opened.
+//! - The filesystem view is restricted (via `chroot`) to the deepest directory
+//!   containing all of the paths which remain required, such as the files
+//!   behind storage backends, or to an empty directory if there are none.
+//!   Paths opened once the process is confined must first be translated with
+//!   [`host_path`].
+//! - On illumos, privileges are reduced to the basic set, less the ability to
+//!   fork, exec, or observe other processes.
+
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::RawFd;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+/// Directory used as the root when no paths are required
+pub const EMPTY_ROOT: &str = "/var/empty";
+
+/// Privileges retained once hardening is complete, in the format accepted by
+/// `priv_str_to_set(3C)`
+#[cfg(target_os = "illumos")]
+const RETAINED_PRIVS: &str =
+    "basic,!proc_exec,!proc_fork,!proc_info,!proc_session,!file_link_any";
+
+lazy_static! {
+    static ref INHERITED_FDS: Mutex<Option<Vec<RawFd>>> = Mutex::new(None);
+    /// Directory to which the process was confined by [`apply`], if it has
+    /// been
+    static ref CONFINED_ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Record the (non-stdio) descriptors which this process inherited, so they
+/// may be closed by [`apply`].
+pub fn capture_inherited() -> Result<()> {
+    let mut fds: Vec<RawFd> = std::fs::read_dir("/dev/fd")?
+        .filter_map(|ent| ent.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|fd| *fd > libc::STDERR_FILENO)
+        .collect();
+    // The listing of /dev/fd itself consumed a descriptor, which has since
+    // been closed.  Only retain those still open.
+    fds.retain(|fd| unsafe { libc::fcntl(*fd, libc::F_GETFD) } != -1);
+    *INHERITED_FDS.lock().unwrap() = Some(fds);
+    Ok(())
+}
+
+/// Hardening to be applied to the process
+#[derive(Clone, Debug, Default)]
+pub struct Plan {
+    /// Directory to which the filesystem view is restricted.  If unset, it
+    /// is derived from `required_paths` and `required_dirs`.
+    pub root: Option<PathBuf>,
+    /// Files which must remain reachable once hardening is applied
+    pub required_paths: Vec<PathBuf>,
+    /// Directories which must remain reachable once hardening is applied
+    pub required_dirs: Vec<PathBuf>,
+    /// Inherited descriptors which should be left open
+    pub keep_fds: Vec<RawFd>,
+}
+impl Plan {
+    /// Determine the directory which will become the process root.
+    ///
+    /// A root of `/` is refused, since it would leave the filesystem view
+    /// unrestricted.  Should the required paths have no other directory in
+    /// common, a root must be chosen explicitly.
+    pub fn effective_root(&self) -> Result<PathBuf> {
+        for path in self.required().chain(self.root.iter()) {
+            if !path.is_absolute() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("path {} is not absolute", path.display()),
+                ));
+            }
+        }
+        let root = match &self.root {
+            Some(root) => {
+                let root = normalize(root);
+                self.check_within(&root)?;
+                root
+            }
+            None => {
+                let dirs = self.required_paths.iter().map(|p| {
+                    let p = normalize(p);
+                    p.parent().map(Path::to_path_buf).unwrap_or(p)
+                });
+                let dirs =
+                    dirs.chain(self.required_dirs.iter().map(|d| normalize(d)));
+                common_ancestor(dirs)
+                    .unwrap_or_else(|| PathBuf::from(EMPTY_ROOT))
+            }
+        };
+        if root == Path::new("/") {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a root of / would leave the filesystem view unrestricted",
+            ));
+        }
+        Ok(root)
+    }
+
+    fn required(&self) -> impl Iterator<Item = &PathBuf> {
+        self.required_paths.iter().chain(self.required_dirs.iter())
+    }
+
+    /// Check that all required paths lie within `root`
+    fn check_within(&self, root: &Path) -> Result<()> {
+        match self.required().find(|p| !normalize(p).starts_with(root)) {
+            Some(path) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "required path {} is outside of root {}",
+                    path.display(),
+                    root.display()
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Translate `path`, as named on the host, to the path through which this
+/// process reaches it.  Until the process has been confined by [`apply`],
+/// that is `path` itself.
+///
+/// Once confined, the process can only reach absolute paths within its root,
+/// and any other path is refused.
+pub fn host_path(path: &Path) -> Result<PathBuf> {
+    let guard = CONFINED_ROOT.lock().unwrap();
+    let Some(root) = guard.as_ref() else {
+        return Ok(path.to_path_buf());
+    };
+    if !path.is_absolute() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "path {} must be absolute once the process is confined",
+                path.display()
+            ),
+        ));
+    }
+    match normalize(path).strip_prefix(root) {
+        Ok(rel) => Ok(Path::new("/").join(rel)),
+        Err(_) => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "path {} is outside of the confined root {}",
+                path.display(),
+                root.display()
+            ),
+        )),
+    }
+}
+
+/// Is the process confined to part of the filesystem?
+pub fn is_confined() -> bool {
+    CONFINED_ROOT.lock().unwrap().is_some()
+}
+
+/// Lexically normalize an absolute path, resolving `.` and `..` components.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::from("/");
+    for comp in path.components() {
+        match comp {
+            Component::Normal(c) => out.push(c),
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    out
+}
+
+/// Find the deepest directory containing all of `dirs`.
+fn common_ancestor(mut dirs: impl Iterator<Item = PathBuf>) -> Option<PathBuf> {
+    let mut common = dirs.next()?;
+    for dir in dirs {
+        while !dir.starts_with(&common) {
+            common.pop();
+        }
+    }
+    Some(common)
+}
+
+/// Apply `plan` to the current process.
+///
+/// This is irreversible, and should only be called once all devices and
+/// backends have been initialized.  Should it fail, the process may be left
+/// partially hardened, and must not go on to run an instance.
+///
+/// If the process was already confined by an earlier call, the confinement
+/// cannot be widened, and so the required paths must lie within it.
+pub fn apply(plan: &Plan, log: &slog::Logger) -> Result<()> {
+    let mut confined = CONFINED_ROOT.lock().unwrap();
+    if let Some(root) = confined.as_ref() {
+        return plan.check_within(root);
+    }
+
+    let root = plan.effective_root()?;
+    if !root.is_dir() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("root {} is not a directory", root.display()),
+        ));
+    }
+
+    let inherited = INHERITED_FDS.lock().unwrap().take().unwrap_or_default();
+    for fd in inherited.into_iter().filter(|fd| !plan.keep_fds.contains(fd)) {
+        slog::debug!(log, "closing inherited descriptor"; "fd" => fd);
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    slog::info!(log, "restricting filesystem view"; "root" => %root.display());
+    std::os::unix::fs::chroot(&root)?;
+    std::env::set_current_dir("/")?;
+    *confined = Some(root);
+    drop(confined);
+
+    drop_privileges()?;
+    slog::info!(log, "process hardening complete");
+    Ok(())
+}
+
+#[cfg(target_os = "illumos")]
+fn drop_privileges() -> Result<()> {
+    use libc::{c_char, c_int, c_void};
+    use std::ffi::CString;
+
+    extern "C" {
+        fn priv_str_to_set(
+            buf: *const c_char,
+            sep: *const c_char,
+            endptr: *mut *const c_char,
+        ) -> *mut c_void;
+        fn priv_freeset(sp: *mut c_void);
+        fn setppriv(
+            op: c_int,
+            which: *const c_char,
+            set: *const c_void,
+        ) -> c_int;
+    }
+    const PRIV_SET: c_int = 2;
+
+    let privs = CString::new(RETAINED_PRIVS).unwrap();
+    let sep = CString::new(",").unwrap();
+    let set = unsafe {
+        priv_str_to_set(privs.as_ptr(), sep.as_ptr(), std::ptr::null_mut())
+    };
+    if set.is_null() {
+        return Err(Error::last_os_error());
+    }
+    // Shrinking the permitted set implicitly shrinks the effective set.
+    let mut res = Ok(());
+    for which in ["Inheritable", "Limit", "Permitted"] {
+        let which = CString::new(which).unwrap();
+        if unsafe { setppriv(PRIV_SET, which.as_ptr(), set) } != 0 {
+            res = Err(Error::last_os_error());
+            break;
+        }
+    }
+    unsafe { priv_freeset(set) };
+    res
+}
+
+#[cfg(not(target_os = "illumos"))]
+fn drop_privileges() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paths(p: &[&str]) -> Vec<PathBuf> {
+        p.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn root_from_required_paths() {
+        let plan = Plan {
+            required_paths: paths(&[
+                "/data/vm1/disk0.raw",
+                "/data/vm1/iso/../disk1.raw",
+                "/data/vm1/iso/boot.iso",
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(plan.effective_root().unwrap(), Path::new("/data/vm1"));
+
+        let plan = Plan::default();
+        assert_eq!(plan.effective_root().unwrap(), Path::new(EMPTY_ROOT));
+
+        let plan = Plan {
+            required_paths: paths(&["/data/vm1/disk0.raw"]),
+            required_dirs: paths(&["/data/nvram"]),
+            ..Default::default()
+        };
+        assert_eq!(plan.effective_root().unwrap(), Path::new("/data"));
+
+        // Paths with nothing but / in common are refused, rather than leaving
+        // the process unconfined.
+        let plan = Plan {
+            required_paths: paths(&["/a/disk", "/b/disk"]),
+            ..Default::default()
+        };
+        assert!(plan.effective_root().is_err());
+    }
+
+    #[test]
+    fn explicit_root_must_contain_required() {
+        let plan = Plan {
+            root: Some("/data".into()),
+            required_paths: paths(&["/data/disk", "/other/disk"]),
+            ..Default::default()
+        };
+        assert!(plan.effective_root().is_err());
+
+        let plan = Plan {
+            root: Some("/data".into()),
+            required_paths: paths(&["/data/vm/disk"]),
+            ..Default::default()
+        };
+        assert_eq!(plan.effective_root().unwrap(), Path::new("/data"));
+
+        let plan = Plan {
+            root: Some("/data".into()),
+            required_dirs: paths(&["/other"]),
+            ..Default::default()
+        };
+        assert!(plan.effective_root().is_err());
+
+        let plan = Plan { root: Some("/data/..".into()), ..Default::default() };
+        assert!(plan.effective_root().is_err());
+
+        let plan = Plan {
+            required_paths: paths(&["relative/disk"]),
+            ..Default::default()
+        };
+        assert!(plan.effective_root().is_err());
+    }
+}
@@ -0,0 +1,151 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Accounting of host resources held by emulated devices.
+//!
+//! Devices (and their backends) acquire host resources such as file
+//! descriptors, memory mappings, worker threads, and viona links.  Each such
+//! acquisition is represented by a [`Held`] guard charged against an [`Owner`],
+//! releasing the charge when dropped.  Since guards keep their owner alive, an
+//! owner which lingers with non-zero [`Usage`] after its device has been
+//! detached indicates a leak.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use lazy_static::lazy_static;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Open file descriptors
+    Fd,
+    /// Bytes of host memory mapped for the device
+    MappedBytes,
+    /// OS threads
+    Thread,
+    /// Async tasks spawned on a tokio runtime
+    Task,
+    /// Links to viona, the in-kernel virtio network emulation
+    VionaLink,
+}
+const KIND_COUNT: usize = 5;
+
+/// Quantity of each kind of host resource held by an [`Owner`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub fds: u64,
+    pub mapped_bytes: u64,
+    pub threads: u64,
+    pub tasks: u64,
+    pub viona_links: u64,
+}
+impl Usage {
+    pub fn is_empty(&self) -> bool {
+        *self == Usage::default()
+    }
+}
+
+/// An entity (typically a device or backend) to which host resources are
+/// charged
+pub struct Owner {
+    label: String,
+    counts: [AtomicU64; KIND_COUNT],
+}
+impl Owner {
+    /// Create a new owner, identified by `label`, which will be included in
+    /// [`snapshot`] results for as long as it lives.
+    pub fn new(label: impl Into<String>) -> Arc<Self> {
+        let owner =
+            Arc::new(Self { label: label.into(), counts: Default::default() });
+        let mut owners = OWNERS.lock().unwrap();
+        owners.retain(|o| o.strong_count() != 0);
+        owners.push(Arc::downgrade(&owner));
+        owner
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Charge `amount` of resource `kind` to this owner until the returned
+    /// guard is dropped.
+    pub fn hold(self: &Arc<Self>, kind: Kind, amount: u64) -> Held {
+        self.counts[kind as usize].fetch_add(amount, Ordering::Relaxed);
+        Held { owner: self.clone(), kind, amount }
+    }
+
+    pub fn usage(&self) -> Usage {
+        let get = |k: Kind| self.counts[k as usize].load(Ordering::Relaxed);
+        Usage {
+            fds: get(Kind::Fd),
+            mapped_bytes: get(Kind::MappedBytes),
+            threads: get(Kind::Thread),
+            tasks: get(Kind::Task),
+            viona_links: get(Kind::VionaLink),
+        }
+    }
+}
+
+/// Guard representing a quantity of host resource charged to an [`Owner`]
+#[must_use]
+pub struct Held {
+    owner: Arc<Owner>,
+    kind: Kind,
+    amount: u64,
+}
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.owner.counts[self.kind as usize]
+            .fetch_sub(self.amount, Ordering::Relaxed);
+    }
+}
+
+lazy_static! {
+    static ref OWNERS: Mutex<Vec<Weak<Owner>>> = Mutex::new(Vec::new());
+}
+
+/// Report the usage of all living owners in this process, in order of
+/// creation.
+pub fn snapshot() -> Vec<(String, Usage)> {
+    let owners = OWNERS.lock().unwrap();
+    owners
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|o| (o.label.clone(), o.usage()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn held_releases_on_drop() {
+        let owner = Owner::new("test-release");
+        let fd = owner.hold(Kind::Fd, 1);
+        let map = owner.hold(Kind::MappedBytes, 4096);
+        assert_eq!(
+            owner.usage(),
+            Usage { fds: 1, mapped_bytes: 4096, ..Default::default() }
+        );
+        drop(fd);
+        drop(map);
+        assert!(owner.usage().is_empty());
+    }
+
+    #[test]
+    fn leaked_guard_keeps_owner_visible() {
+        let owner = Owner::new("test-leak");
+        let thread = owner.hold(Kind::Thread, 1);
+        drop(owner);
+
+        let snap = snapshot();
+        let (_, usage) =
+            snap.iter().find(|(l, _)| l == "test-leak").expect("owner lives");
+        assert_eq!(usage.threads, 1);
+
+        drop(thread);
+        assert!(snapshot().iter().all(|(l, _)| l != "test-leak"));
+    }
+}
@@ -0,0 +1,489 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ACPI-based CPU hot-remove
+//!
+//! The register block at [`PORT_CPU_HOTPLUG`] follows the layout of QEMU's
+//! "modern" CPU hotplug interface, so that guest AML written against it can be
+//! reused:
+//!
+//! - `0x0` (u32, write): selects the CPU to which other accesses apply
+//! - `0x4` (u8): status flags for the selected CPU.  Reads report whether it is
+//!   enabled and has pending insert or remove events; writes clear events or
+//!   acknowledge that the guest has ejected the CPU.
+//! - `0x5` (u8, write): command
+//! - `0x8` (u32, read): result of the last command
+//!
+//! Pending events are signaled to the guest via bit 2 of the shared [`Gpe0`]
+//! block.  As with PCI hotplug, removal is cooperative: the host posts a
+//! request via [`CpuHotplug::request_remove`] and waits for the guest to
+//! offline and eject the CPU with [`CpuHotplug::wait_ejected`].  Unlike a PCI
+//! device, a CPU cannot be pulled out from under a guest which fails to
+//! respond, so the request is simply withdrawn in that case.
+//!
+//! Once ejected, a CPU is reported as absent to the guest, including across
+//! resets of the machine.
+//!
+//! As for PCI hotplug, each operation takes the ID of the request on whose
+//! behalf it is made, if any, for its USDT probe to report.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+use crate::hw::acpi::gpe::{Gpe0, GPE_CPU_HOTPLUG};
+use crate::inventory::Entity;
+use crate::migrate::*;
+use crate::pio::{PioBus, PioFn};
+use crate::util::regmap::RegMap;
+
+use lazy_static::lazy_static;
+use thiserror::Error;
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn cpu_hotplug_remove_request(cpu: i32, req_id: &str) {}
+    fn cpu_hotplug_eject_done(cpu: i32, ejected: u8, req_id: &str) {}
+}
+
+pub const PORT_CPU_HOTPLUG: u16 = 0x0cd8;
+pub const CPU_HOTPLUG_LEN: u16 = 12;
+
+pub const FLAG_ENABLED: u8 = 1 << 0;
+pub const FLAG_REMOVE_EVENT: u8 = 1 << 2;
+pub const FLAG_EJECT: u8 = 1 << 3;
+
+/// Move the selector to the next CPU (at or after the current one) with a
+/// pending event
+const CMD_GET_NEXT_EVENT: u8 = 0;
+/// Report the APIC ID of the selected CPU
+const CMD_GET_CPU_ID: u8 = 3;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CpuHpReg {
+    Selector,
+    Flags,
+    Command,
+    Reserved,
+    Data,
+}
+
+lazy_static! {
+    static ref CPU_HP_REGS: RegMap<CpuHpReg> = {
+        let layout = [
+            (CpuHpReg::Selector, 4),
+            (CpuHpReg::Flags, 1),
+            (CpuHpReg::Command, 1),
+            (CpuHpReg::Reserved, 2),
+            (CpuHpReg::Data, 4),
+        ];
+        RegMap::create_packed(
+            CPU_HOTPLUG_LEN as usize,
+            &layout,
+            Some(CpuHpReg::Reserved),
+        )
+    };
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CpuHotplugError {
+    #[error("no vCPU with ID {0}")]
+    NoSuchCpu(i32),
+
+    #[error("vCPU {0} is the boot processor and cannot be removed")]
+    BootProcessor(i32),
+
+    #[error("vCPU {0} has already been removed")]
+    AlreadyRemoved(i32),
+}
+
+/// Callback invoked when the guest ejects a CPU, after which the host is free
+/// to retire it.
+pub type EjectFn = dyn Fn(i32) + Send + Sync + 'static;
+
+#[derive(Copy, Clone, Default)]
+struct Cpu {
+    present: bool,
+    remove_pending: bool,
+    ejected: bool,
+}
+
+struct State {
+    cpus: Vec<Cpu>,
+    selector: u32,
+    command: u8,
+}
+
+pub struct CpuHotplug {
+    state: Mutex<State>,
+    cv: Condvar,
+    gpe: Arc<Gpe0>,
+    on_eject: Box<EjectFn>,
+}
+impl CpuHotplug {
+    pub fn create(
+        num_cpus: u8,
+        gpe: Arc<Gpe0>,
+        on_eject: Box<EjectFn>,
+    ) -> Arc<Self> {
+        let cpus =
+            vec![Cpu { present: true, ..Default::default() }; num_cpus.into()];
+        Arc::new(Self {
+            state: Mutex::new(State { cpus, selector: 0, command: 0 }),
+            cv: Condvar::new(),
+            gpe,
+            on_eject,
+        })
+    }
+
+    pub fn attach(self: &Arc<Self>, pio: &PioBus) {
+        let this = Arc::clone(self);
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| this.pio_rw(rwo))
+            as Arc<PioFn>;
+        pio.register(PORT_CPU_HOTPLUG, CPU_HOTPLUG_LEN, piofn).unwrap();
+    }
+
+    /// Whether `cpu` is still present (i.e. has not been ejected)
+    pub fn is_present(&self, cpu: i32) -> bool {
+        let state = self.state.lock().unwrap();
+        usize::try_from(cpu)
+            .ok()
+            .and_then(|i| state.cpus.get(i))
+            .map_or(false, |c| c.present)
+    }
+
+    /// Ask the guest to offline and eject `cpu`.
+    pub fn request_remove(
+        &self,
+        cpu: i32,
+        req_id: Option<&str>,
+    ) -> Result<(), CpuHotplugError> {
+        probes::cpu_hotplug_remove_request!(|| (
+            cpu,
+            req_id.unwrap_or_default()
+        ));
+        let mut state = self.state.lock().unwrap();
+        let entry = usize::try_from(cpu)
+            .ok()
+            .and_then(|i| state.cpus.get_mut(i))
+            .ok_or(CpuHotplugError::NoSuchCpu(cpu))?;
+        if cpu == 0 {
+            return Err(CpuHotplugError::BootProcessor(cpu));
+        }
+        if !entry.present {
+            return Err(CpuHotplugError::AlreadyRemoved(cpu));
+        }
+        entry.remove_pending = true;
+        entry.ejected = false;
+        drop(state);
+        self.gpe.raise(GPE_CPU_HOTPLUG);
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the guest to eject `cpu`.
+    ///
+    /// Returns `true` if the guest ejected the CPU.  Otherwise, the pending
+    /// removal request is withdrawn.
+    pub fn wait_ejected(
+        &self,
+        cpu: i32,
+        timeout: Duration,
+        req_id: Option<&str>,
+    ) -> bool {
+        let Ok(idx) = usize::try_from(cpu) else {
+            return false;
+        };
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match state.cpus.get(idx) {
+                None => return false,
+                Some(c) if c.ejected => break,
+                Some(_) => {}
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.cv.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        let entry = &mut state.cpus[idx];
+        let ejected = entry.ejected;
+        entry.ejected = false;
+        entry.remove_pending = false;
+        drop(state);
+        probes::cpu_hotplug_eject_done!(|| (
+            cpu,
+            ejected as u8,
+            req_id.unwrap_or_default()
+        ));
+        ejected
+    }
+
+    fn selected(state: &mut State) -> Option<&mut Cpu> {
+        state.cpus.get_mut(state.selector as usize)
+    }
+
+    fn flags_read(state: &State) -> u8 {
+        match state.cpus.get(state.selector as usize) {
+            Some(c) => {
+                let mut flags = 0;
+                if c.present {
+                    flags |= FLAG_ENABLED;
+                }
+                if c.remove_pending {
+                    flags |= FLAG_REMOVE_EVENT;
+                }
+                flags
+            }
+            None => 0,
+        }
+    }
+
+    fn flags_write(&self, val: u8) {
+        let mut state = self.state.lock().unwrap();
+        let selector = state.selector;
+        let Some(cpu) = Self::selected(&mut state) else {
+            return;
+        };
+        if val & FLAG_EJECT != 0 && cpu.remove_pending {
+            cpu.present = false;
+            cpu.remove_pending = false;
+            cpu.ejected = true;
+            self.cv.notify_all();
+            drop(state);
+            (self.on_eject)(selector as i32);
+        }
+        // Insertion is not supported, so there is never an insert event to
+        // clear.  Clearing a remove event without ejecting the CPU leaves the
+        // request outstanding until the host withdraws it.
+    }
+
+    fn command_write(state: &mut State, cmd: u8) {
+        state.command = cmd;
+        if cmd == CMD_GET_NEXT_EVENT {
+            let start = state.selector as usize;
+            let count = state.cpus.len();
+            if let Some(next) = (0..count)
+                .map(|i| (start + i) % count)
+                .find(|&i| state.cpus[i].remove_pending)
+            {
+                state.selector = next as u32;
+            }
+        }
+    }
+
+    fn data_read(state: &State) -> u32 {
+        match state.command {
+            // CPU IDs and APIC IDs are identical for this machine model.
+            CMD_GET_NEXT_EVENT | CMD_GET_CPU_ID => state.selector,
+            _ => 0,
+        }
+    }
+
+    fn pio_rw(&self, mut rwo: RWOp) {
+        CPU_HP_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => {
+                let state = self.state.lock().unwrap();
+                match id {
+                    CpuHpReg::Flags => ro.write_u8(Self::flags_read(&state)),
+                    CpuHpReg::Data => ro.write_u32(Self::data_read(&state)),
+                    CpuHpReg::Selector
+                    | CpuHpReg::Command
+                    | CpuHpReg::Reserved => ro.fill(0),
+                }
+            }
+            RWOp::Write(wo) => match id {
+                CpuHpReg::Selector => {
+                    self.state.lock().unwrap().selector = wo.read_u32();
+                }
+                CpuHpReg::Flags => self.flags_write(wo.read_u8()),
+                CpuHpReg::Command => {
+                    let mut state = self.state.lock().unwrap();
+                    Self::command_write(&mut state, wo.read_u8());
+                }
+                CpuHpReg::Data | CpuHpReg::Reserved => {}
+            },
+        });
+    }
+}
+
+impl Entity for CpuHotplug {
+    fn type_name(&self) -> &'static str {
+        "acpi-cpu-hotplug"
+    }
+    fn reset(&self) {
+        // Ejected CPUs remain absent across a reset of the machine.
+        let mut state = self.state.lock().unwrap();
+        for cpu in state.cpus.iter_mut() {
+            cpu.remove_pending = false;
+            cpu.ejected = false;
+        }
+        state.selector = 0;
+        state.command = 0;
+        self.cv.notify_all();
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for CpuHotplug {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        let state = self.state.lock().unwrap();
+        let cpus = state
+            .cpus
+            .iter()
+            .map(|c| migrate::CpuV1 {
+                present: c.present,
+                remove_pending: c.remove_pending,
+                ejected: c.ejected,
+            })
+            .collect();
+        Ok(migrate::CpuHotplugV1 {
+            cpus,
+            selector: state.selector,
+            command: state.command,
+        }
+        .into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::CpuHotplugV1 = offer.parse()?;
+        let mut state = self.state.lock().unwrap();
+        if data.cpus.len() != state.cpus.len() {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "CPU hotplug: mismatched CPU count {} vs {}",
+                state.cpus.len(),
+                data.cpus.len()
+            )));
+        }
+        for (cpu, saved) in state.cpus.iter_mut().zip(data.cpus) {
+            *cpu = Cpu {
+                present: saved.present,
+                remove_pending: saved.remove_pending,
+                ejected: saved.ejected,
+            };
+        }
+        state.selector = data.selector;
+        state.command = data.command;
+        self.cv.notify_all();
+        Ok(())
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct CpuV1 {
+        pub present: bool,
+        pub remove_pending: bool,
+        pub ejected: bool,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct CpuHotplugV1 {
+        pub cpus: Vec<CpuV1>,
+        pub selector: u32,
+        pub command: u8,
+    }
+    impl Schema<'_> for CpuHotplugV1 {
+        fn id() -> SchemaId {
+            ("acpi-cpu-hotplug", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intr_pins::NoOpPin;
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    fn create(ejected: Arc<AtomicI32>) -> Arc<CpuHotplug> {
+        CpuHotplug::create(
+            4,
+            Gpe0::create(Arc::new(NoOpPin {})),
+            Box::new(move |cpu| ejected.store(cpu, Ordering::SeqCst)),
+        )
+    }
+
+    #[test]
+    fn remove_requests_validated() {
+        let hp = create(Arc::new(AtomicI32::new(-1)));
+        assert_eq!(
+            hp.request_remove(0, None),
+            Err(CpuHotplugError::BootProcessor(0))
+        );
+        assert_eq!(
+            hp.request_remove(4, None),
+            Err(CpuHotplugError::NoSuchCpu(4))
+        );
+        assert_eq!(
+            hp.request_remove(-1, None),
+            Err(CpuHotplugError::NoSuchCpu(-1))
+        );
+        assert_eq!(hp.request_remove(2, None), Ok(()));
+    }
+
+    #[test]
+    fn eject_acknowledged() {
+        let ejected = Arc::new(AtomicI32::new(-1));
+        let hp = create(ejected.clone());
+        hp.request_remove(2, None).unwrap();
+
+        // The guest locates the CPU with a pending event
+        {
+            let mut state = hp.state.lock().unwrap();
+            CpuHotplug::command_write(&mut state, CMD_GET_NEXT_EVENT);
+            assert_eq!(CpuHotplug::data_read(&state), 2);
+            assert_eq!(
+                CpuHotplug::flags_read(&state),
+                FLAG_ENABLED | FLAG_REMOVE_EVENT
+            );
+        }
+        hp.flags_write(FLAG_EJECT);
+
+        assert!(hp.wait_ejected(2, Duration::from_secs(1), None));
+        assert_eq!(ejected.load(Ordering::SeqCst), 2);
+        assert!(!hp.is_present(2));
+        assert_eq!(
+            hp.request_remove(2, None),
+            Err(CpuHotplugError::AlreadyRemoved(2))
+        );
+
+        // Removal persists across reset
+        hp.reset();
+        assert!(!hp.is_present(2));
+    }
+
+    #[test]
+    fn eject_without_request_ignored() {
+        let ejected = Arc::new(AtomicI32::new(-1));
+        let hp = create(ejected.clone());
+        hp.state.lock().unwrap().selector = 1;
+        hp.flags_write(FLAG_EJECT);
+        assert!(hp.is_present(1));
+        assert_eq!(ejected.load(Ordering::SeqCst), -1);
+    }
+
+    #[test]
+    fn eject_times_out() {
+        let hp = create(Arc::new(AtomicI32::new(-1)));
+        hp.request_remove(3, None).unwrap();
+        assert!(!hp.wait_ejected(3, Duration::from_millis(10), None));
+        assert!(hp.is_present(3));
+        // The request is withdrawn once the wait has concluded
+        assert!(!hp.state.lock().unwrap().cpus[3].remove_pending);
+    }
+}
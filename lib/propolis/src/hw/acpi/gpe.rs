@@ -0,0 +1,154 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! General-purpose event (GPE0) register block
+//!
+//! Devices which notify the guest of events via ACPI set their assigned bit in
+//! the status register, raising an SCI if the guest has enabled that bit.  The
+//! guest acknowledges an event by writing 1 to its status bit.
+
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::intr_pins::IntrPin;
+use crate::inventory::Entity;
+use crate::migrate::*;
+use crate::pio::{PioBus, PioFn};
+use crate::util::regmap::RegMap;
+
+use lazy_static::lazy_static;
+
+pub const PORT_GPE0: u16 = 0xafe0;
+const GPE0_LEN: u16 = 4;
+
+/// GPE0 bit used for PCI hotplug events
+pub const GPE_PCI_HOTPLUG: u16 = 1 << 1;
+/// GPE0 bit used for CPU hotplug events
+pub const GPE_CPU_HOTPLUG: u16 = 1 << 2;
+/// GPE0 bit used for maintenance notices
+pub const GPE_MAINTENANCE: u16 = 1 << 3;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum GpeReg {
+    Status,
+    Enable,
+}
+
+lazy_static! {
+    static ref GPE_REGS: RegMap<GpeReg> = {
+        let layout = [(GpeReg::Status, 2), (GpeReg::Enable, 2)];
+        RegMap::create_packed(GPE0_LEN as usize, &layout, None)
+    };
+}
+
+#[derive(Default)]
+struct Regs {
+    sts: u16,
+    en: u16,
+}
+
+pub struct Gpe0 {
+    regs: Mutex<Regs>,
+    sci_pin: Arc<dyn IntrPin>,
+}
+impl Gpe0 {
+    pub fn create(sci_pin: Arc<dyn IntrPin>) -> Arc<Self> {
+        Arc::new(Self { regs: Mutex::new(Regs::default()), sci_pin })
+    }
+
+    pub fn attach(self: &Arc<Self>, pio: &PioBus) {
+        let this = Arc::clone(self);
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| this.pio_rw(rwo))
+            as Arc<PioFn>;
+        pio.register(PORT_GPE0, GPE0_LEN, piofn).unwrap();
+    }
+
+    /// Latch the event(s) in `bits`, raising an SCI if they are enabled.
+    pub fn raise(&self, bits: u16) {
+        let mut regs = self.regs.lock().unwrap();
+        regs.sts |= bits;
+        self.update_sci(&regs);
+    }
+
+    fn update_sci(&self, regs: &Regs) {
+        if regs.sts & regs.en != 0 {
+            self.sci_pin.assert();
+        } else {
+            self.sci_pin.deassert();
+        }
+    }
+
+    fn pio_rw(&self, mut rwo: RWOp) {
+        GPE_REGS.process(&mut rwo, |id, rwo| {
+            let mut regs = self.regs.lock().unwrap();
+            match rwo {
+                RWOp::Read(ro) => match id {
+                    GpeReg::Status => ro.write_u16(regs.sts),
+                    GpeReg::Enable => ro.write_u16(regs.en),
+                },
+                RWOp::Write(wo) => {
+                    match id {
+                        // status bits are W1C
+                        GpeReg::Status => regs.sts &= !wo.read_u16(),
+                        GpeReg::Enable => regs.en = wo.read_u16(),
+                    }
+                    self.update_sci(&regs);
+                }
+            }
+        });
+    }
+}
+
+impl Entity for Gpe0 {
+    fn type_name(&self) -> &'static str {
+        "acpi-gpe0"
+    }
+    fn reset(&self) {
+        let mut regs = self.regs.lock().unwrap();
+        *regs = Regs::default();
+        self.update_sci(&regs);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for Gpe0 {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        let regs = self.regs.lock().unwrap();
+        Ok(migrate::Gpe0V1 { sts: regs.sts, en: regs.en }.into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::Gpe0V1 = offer.parse()?;
+        let mut regs = self.regs.lock().unwrap();
+        regs.sts = data.sts;
+        regs.en = data.en;
+        self.update_sci(&regs);
+        Ok(())
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Gpe0V1 {
+        pub sts: u16,
+        pub en: u16,
+    }
+    impl Schema<'_> for Gpe0V1 {
+        fn id() -> SchemaId {
+            ("acpi-gpe0", 1)
+        }
+    }
+}
@@ -0,0 +1,387 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ACPI notification of impending maintenance
+//!
+//! The host posts a notice that the instance is about to be shut down or
+//! migrated, along with a deadline, so that applications in the guest can
+//! drain work before the disruption.  Changes to the notice (including its
+//! withdrawal) are signaled to the guest via bit 3 of the shared [`Gpe0`]
+//! block, after which guest AML reads the register block at
+//! [`PORT_MAINTENANCE`]:
+//!
+//! - `0x0` (u8): status flags.  Reads report whether a notice is pending and
+//!   whether the guest has acknowledged it; writing [`FLAG_ACKED`] acknowledges
+//!   the current notice.
+//! - `0x1` (u8, read): kind of maintenance, as a [`MaintenanceKind`]
+//! - `0x4` (u32, read): generation of the notice, incremented each time it is
+//!   posted or withdrawn, so the guest can tell successive notices apart
+//! - `0x8` (u32, read): seconds remaining until the deadline, saturating at 0
+//!
+//! The register block is described to the guest by an ACPI device with ID
+//! [`MAINTENANCE_ACPI_HID`], to which the GPE handler sends a device-specific
+//! notification (0x80) on each change to the notice.
+//!
+//! Acknowledgment is purely informational: the host is free to proceed at the
+//! deadline whether or not the guest has responded.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::common::*;
+use crate::hw::acpi::gpe::{Gpe0, GPE_MAINTENANCE};
+use crate::inventory::Entity;
+use crate::migrate::*;
+use crate::pio::{PioBus, PioFn};
+use crate::util::regmap::RegMap;
+
+use lazy_static::lazy_static;
+
+pub const PORT_MAINTENANCE: u16 = 0x0ce4;
+pub const MAINTENANCE_LEN: u16 = 12;
+
+/// ACPI hardware ID of the device describing the register block
+pub const MAINTENANCE_ACPI_HID: &str = "PRPL0001";
+
+pub const FLAG_PENDING: u8 = 1 << 0;
+pub const FLAG_ACKED: u8 = 1 << 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum MaintReg {
+    Flags,
+    Kind,
+    Reserved,
+    Generation,
+    Remaining,
+}
+
+lazy_static! {
+    static ref MAINT_REGS: RegMap<MaintReg> = {
+        let layout = [
+            (MaintReg::Flags, 1),
+            (MaintReg::Kind, 1),
+            (MaintReg::Reserved, 2),
+            (MaintReg::Generation, 4),
+            (MaintReg::Remaining, 4),
+        ];
+        RegMap::create_packed(
+            MAINTENANCE_LEN as usize,
+            &layout,
+            Some(MaintReg::Reserved),
+        )
+    };
+}
+
+/// The disruption of which the guest is being warned
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MaintenanceKind {
+    Shutdown = 1,
+    Migration = 2,
+}
+
+/// A maintenance notice, as last posted by the host
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MaintenanceNotice {
+    pub kind: MaintenanceKind,
+    pub deadline: Instant,
+    pub generation: u32,
+    /// Whether the guest has acknowledged the notice
+    pub acked: bool,
+}
+
+struct State {
+    notice: Option<MaintenanceNotice>,
+    generation: u32,
+}
+
+pub struct MaintenanceNotifier {
+    state: Mutex<State>,
+    gpe: Arc<Gpe0>,
+    clock: Arc<Clock>,
+}
+impl MaintenanceNotifier {
+    /// Creates the notifier, measuring the time until deadlines by `clock`.
+    pub fn create(gpe: Arc<Gpe0>, clock: Arc<Clock>) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(State { notice: None, generation: 0 }),
+            gpe,
+            clock,
+        })
+    }
+
+    pub fn attach(self: &Arc<Self>, pio: &PioBus) {
+        let this = Arc::clone(self);
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| this.pio_rw(rwo))
+            as Arc<PioFn>;
+        pio.register(PORT_MAINTENANCE, MAINTENANCE_LEN, piofn).unwrap();
+    }
+
+    /// Notify the guest of `kind` maintenance, due in `deadline`, replacing
+    /// any notice already posted.
+    pub fn post(
+        &self,
+        kind: MaintenanceKind,
+        deadline: Duration,
+    ) -> MaintenanceNotice {
+        let mut state = self.state.lock().unwrap();
+        state.generation = state.generation.wrapping_add(1);
+        let notice = MaintenanceNotice {
+            kind,
+            deadline: self.clock.now() + deadline,
+            generation: state.generation,
+            acked: false,
+        };
+        state.notice = Some(notice);
+        drop(state);
+        self.gpe.raise(GPE_MAINTENANCE);
+        notice
+    }
+
+    /// Withdraw the posted notice, if any, notifying the guest that it no
+    /// longer applies.
+    pub fn withdraw(&self) -> Option<MaintenanceNotice> {
+        let mut state = self.state.lock().unwrap();
+        let notice = state.notice.take()?;
+        state.generation = state.generation.wrapping_add(1);
+        drop(state);
+        self.gpe.raise(GPE_MAINTENANCE);
+        Some(notice)
+    }
+
+    /// The currently posted notice, if any
+    pub fn notice(&self) -> Option<MaintenanceNotice> {
+        self.state.lock().unwrap().notice
+    }
+
+    fn flags_read(state: &State) -> u8 {
+        match &state.notice {
+            Some(n) if n.acked => FLAG_PENDING | FLAG_ACKED,
+            Some(_) => FLAG_PENDING,
+            None => 0,
+        }
+    }
+
+    fn remaining_read(state: &State, now: Instant) -> u32 {
+        state.notice.map_or(0, |n| {
+            let secs = n.deadline.saturating_duration_since(now).as_secs();
+            u32::try_from(secs).unwrap_or(u32::MAX)
+        })
+    }
+
+    fn pio_rw(&self, mut rwo: RWOp) {
+        MAINT_REGS.process(&mut rwo, |id, rwo| {
+            let mut state = self.state.lock().unwrap();
+            match rwo {
+                RWOp::Read(ro) => match id {
+                    MaintReg::Flags => ro.write_u8(Self::flags_read(&state)),
+                    MaintReg::Kind => {
+                        ro.write_u8(state.notice.map_or(0, |n| n.kind as u8))
+                    }
+                    MaintReg::Generation => ro.write_u32(state.generation),
+                    MaintReg::Remaining => ro.write_u32(Self::remaining_read(
+                        &state,
+                        self.clock.now(),
+                    )),
+                    MaintReg::Reserved => ro.fill(0),
+                },
+                RWOp::Write(wo) => match id {
+                    MaintReg::Flags if wo.read_u8() & FLAG_ACKED != 0 => {
+                        if let Some(notice) = state.notice.as_mut() {
+                            notice.acked = true;
+                        }
+                    }
+                    _ => {}
+                },
+            }
+        });
+    }
+}
+
+impl Entity for MaintenanceNotifier {
+    fn type_name(&self) -> &'static str {
+        "acpi-maintenance"
+    }
+    fn reset(&self) {
+        // A posted notice outlives a reboot of the guest, which must learn of
+        // it again (and acknowledge it anew) once its AML is running.
+        let mut state = self.state.lock().unwrap();
+        if let Some(notice) = state.notice.as_mut() {
+            notice.acked = false;
+        }
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for MaintenanceNotifier {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        let state = self.state.lock().unwrap();
+        // The deadline is carried as the time remaining until it, since
+        // instants are not comparable across hosts.
+        let now = self.clock.now();
+        let notice = state.notice.map(|n| migrate::MaintenanceNoticeV1 {
+            kind: n.kind as u8,
+            remaining_ms: n.deadline.saturating_duration_since(now).as_millis()
+                as u64,
+            generation: n.generation,
+            acked: n.acked,
+        });
+        Ok(migrate::MaintenanceV1 { notice, generation: state.generation }
+            .into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::MaintenanceV1 = offer.parse()?;
+        let notice = match data.notice {
+            Some(n) => {
+                let kind = match n.kind {
+                    k if k == MaintenanceKind::Shutdown as u8 => {
+                        MaintenanceKind::Shutdown
+                    }
+                    k if k == MaintenanceKind::Migration as u8 => {
+                        MaintenanceKind::Migration
+                    }
+                    k => {
+                        return Err(MigrateStateError::ImportFailed(format!(
+                            "maintenance: invalid kind {}",
+                            k
+                        )))
+                    }
+                };
+                Some(MaintenanceNotice {
+                    kind,
+                    deadline: self.clock.now()
+                        + Duration::from_millis(n.remaining_ms),
+                    generation: n.generation,
+                    acked: n.acked,
+                })
+            }
+            None => None,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.notice = notice;
+        state.generation = data.generation;
+        Ok(())
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct MaintenanceNoticeV1 {
+        pub kind: u8,
+        pub remaining_ms: u64,
+        pub generation: u32,
+        pub acked: bool,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct MaintenanceV1 {
+        pub notice: Option<MaintenanceNoticeV1>,
+        pub generation: u32,
+    }
+    impl Schema<'_> for MaintenanceV1 {
+        fn id() -> SchemaId {
+            ("acpi-maintenance", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intr_pins::NoOpPin;
+
+    fn create() -> Arc<MaintenanceNotifier> {
+        MaintenanceNotifier::create(
+            Gpe0::create(Arc::new(NoOpPin {})),
+            Clock::new(),
+        )
+    }
+
+    fn remaining(mn: &MaintenanceNotifier) -> u32 {
+        let mut buf = [0u8; 4];
+        mn.pio_rw(RWOp::Read(&mut ReadOp::from_buf(8, &mut buf)));
+        u32::from_le_bytes(buf)
+    }
+
+    fn flags(mn: &MaintenanceNotifier) -> u8 {
+        let mut buf = [0u8];
+        mn.pio_rw(RWOp::Read(&mut ReadOp::from_buf(0, &mut buf)));
+        buf[0]
+    }
+
+    #[test]
+    fn post_and_acknowledge() {
+        let mn = create();
+        assert_eq!(flags(&mn), 0);
+
+        let notice =
+            mn.post(MaintenanceKind::Migration, Duration::from_secs(60));
+        assert_eq!(notice.generation, 1);
+        assert_eq!(flags(&mn), FLAG_PENDING);
+        {
+            let state = mn.state.lock().unwrap();
+            let remaining =
+                MaintenanceNotifier::remaining_read(&state, Instant::now());
+            assert!(remaining > 0 && remaining <= 60);
+            assert_eq!(
+                MaintenanceNotifier::remaining_read(
+                    &state,
+                    notice.deadline + Duration::from_secs(1)
+                ),
+                0
+            );
+        }
+
+        let buf = [FLAG_ACKED];
+        mn.pio_rw(RWOp::Write(&mut WriteOp::from_buf(0, &buf)));
+        assert_eq!(flags(&mn), FLAG_PENDING | FLAG_ACKED);
+        assert!(mn.notice().unwrap().acked);
+
+        // Reposting clears the acknowledgment
+        let notice =
+            mn.post(MaintenanceKind::Shutdown, Duration::from_secs(10));
+        assert_eq!(notice.generation, 2);
+        assert_eq!(flags(&mn), FLAG_PENDING);
+    }
+
+    #[test]
+    fn withdraw() {
+        let mn = create();
+        assert_eq!(mn.withdraw(), None);
+
+        mn.post(MaintenanceKind::Shutdown, Duration::from_secs(10));
+        let notice = mn.withdraw().unwrap();
+        assert_eq!(notice.kind, MaintenanceKind::Shutdown);
+        assert_eq!(mn.notice(), None);
+        assert_eq!(flags(&mn), 0);
+        assert_eq!(mn.state.lock().unwrap().generation, 2);
+    }
+
+    #[test]
+    fn deadline_follows_clock() {
+        let mn = create();
+        mn.clock.freeze();
+        mn.post(MaintenanceKind::Shutdown, Duration::from_secs(60));
+        assert_eq!(remaining(&mn), 60);
+
+        mn.clock.step(Duration::from_secs(45));
+        assert_eq!(remaining(&mn), 15);
+        mn.clock.step(Duration::from_secs(30));
+        assert_eq!(remaining(&mn), 0);
+    }
+}
@@ -0,0 +1,12 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Register interfaces through which guest ACPI code is notified of, and
+//! responds to, platform events.
+
+pub mod cpu_hotplug;
+pub mod gpe;
+pub mod maintenance;
+
+pub use gpe::Gpe0;
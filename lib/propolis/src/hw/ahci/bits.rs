@@ -0,0 +1,145 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Definitions from the AHCI 1.3.1 and ATA8-ACS specifications
+
+/// Size of the HBA memory registers (ABAR)
+pub const ABAR_SIZE: u32 = 0x1000;
+
+/// Offset of the registers for port 0 within the ABAR
+pub const PORT_REGS_OFF: usize = 0x100;
+/// Size of the registers for each port
+pub const PORT_REGS_SIZE: usize = 0x80;
+
+/// Number of command slots per port
+pub const NUM_SLOTS: u32 = 32;
+
+// HBA Capabilities (CAP)
+pub const CAP_NCS_SHIFT: u32 = 8;
+pub const CAP_SAM: u32 = 1 << 18;
+pub const CAP_ISS_GEN3: u32 = 3 << 20;
+pub const CAP_S64A: u32 = 1 << 31;
+
+// Global HBA Control (GHC)
+pub const GHC_HR: u32 = 1 << 0;
+pub const GHC_IE: u32 = 1 << 1;
+pub const GHC_AE: u32 = 1 << 31;
+
+/// AHCI Version (VS): 1.3.1
+pub const AHCI_VERSION: u32 = 0x0001_0301;
+
+// Port Interrupt Status (PxIS)
+pub const PXIS_DHRS: u32 = 1 << 0;
+pub const PXIS_PSS: u32 = 1 << 1;
+pub const PXIS_TFES: u32 = 1 << 30;
+
+// Port Command and Status (PxCMD)
+pub const PXCMD_ST: u32 = 1 << 0;
+pub const PXCMD_SUD: u32 = 1 << 1;
+pub const PXCMD_POD: u32 = 1 << 2;
+pub const PXCMD_CLO: u32 = 1 << 3;
+pub const PXCMD_FRE: u32 = 1 << 4;
+pub const PXCMD_CCS_SHIFT: u32 = 8;
+pub const PXCMD_CCS_MASK: u32 = 0x1f << PXCMD_CCS_SHIFT;
+pub const PXCMD_FR: u32 = 1 << 14;
+pub const PXCMD_CR: u32 = 1 << 15;
+
+/// PxSSTS for a device present at Gen3 speed, with communication established
+/// and the interface in the active state
+pub const PXSSTS_ACTIVE: u32 = 0x133;
+/// PxSCTL.DET value requesting interface reset (COMRESET)
+pub const PXSCTL_DET_RESET: u32 = 1;
+pub const PXSCTL_DET_MASK: u32 = 0xf;
+
+/// PxSIG reported for an ATA (non-packet) device
+pub const SIG_ATA: u32 = 0x0000_0101;
+
+// Offsets of FISes in the received FIS area
+pub const RX_FIS_PIO_SETUP: u64 = 0x20;
+pub const RX_FIS_D2H: u64 = 0x40;
+
+/// Offset of the physical region descriptor table in a command table
+pub const CMD_TABLE_PRDT: u64 = 0x80;
+
+/// Byte count of a physical region descriptor (0-based)
+pub const PRD_DBC_MASK: u32 = 0x3f_ffff;
+
+/// Command header, of which each port has one per command slot
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct CmdHeader {
+    pub flags: u16,
+    /// Physical region descriptor table length, in entries
+    pub prdtl: u16,
+    /// Physical region descriptor byte count, as transferred
+    pub prdbc: u32,
+    /// Command table base address
+    pub ctba: u64,
+    pub rsvd: [u32; 4],
+}
+
+/// Physical region descriptor
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct PrdEntry {
+    pub dba: u64,
+    pub rsvd: u32,
+    pub dbc: u32,
+}
+
+// FIS types
+pub const FIS_REG_H2D: u8 = 0x27;
+pub const FIS_REG_D2H: u8 = 0x34;
+pub const FIS_PIO_SETUP: u8 = 0x5f;
+
+/// Register Host to Device FIS is a command (rather than a control update)
+pub const FIS_H2D_C: u8 = 1 << 7;
+/// Interrupt bit of Device to Host FISes
+pub const FIS_I: u8 = 1 << 6;
+/// PIO Setup FIS transfers data from device to host
+pub const FIS_PIO_D: u8 = 1 << 5;
+
+/// Software reset bit of the Device Control register
+pub const ATA_CTL_SRST: u8 = 1 << 2;
+
+/// LBA addressing bit of the Device register
+pub const ATA_DEV_LBA: u8 = 1 << 6;
+
+// ATA Status
+pub const ATA_STS_ERR: u8 = 1 << 0;
+pub const ATA_STS_DRQ: u8 = 1 << 3;
+pub const ATA_STS_DSC: u8 = 1 << 4;
+pub const ATA_STS_DRDY: u8 = 1 << 6;
+pub const ATA_STS_BSY: u8 = 1 << 7;
+
+// ATA Error
+pub const ATA_ERR_ABRT: u8 = 1 << 2;
+pub const ATA_ERR_IDNF: u8 = 1 << 4;
+pub const ATA_ERR_UNC: u8 = 1 << 6;
+
+// ATA commands
+pub const ATA_CMD_RECALIBRATE: u8 = 0x10;
+pub const ATA_CMD_READ_SECTORS: u8 = 0x20;
+pub const ATA_CMD_READ_SECTORS_EXT: u8 = 0x24;
+pub const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_CMD_WRITE_SECTORS: u8 = 0x30;
+pub const ATA_CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+pub const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_CMD_READ_VERIFY: u8 = 0x40;
+pub const ATA_CMD_READ_VERIFY_EXT: u8 = 0x42;
+pub const ATA_CMD_INIT_DEV_PARAMS: u8 = 0x91;
+pub const ATA_CMD_READ_DMA: u8 = 0xc8;
+pub const ATA_CMD_WRITE_DMA: u8 = 0xca;
+pub const ATA_CMD_STANDBY_IMMEDIATE: u8 = 0xe0;
+pub const ATA_CMD_IDLE_IMMEDIATE: u8 = 0xe1;
+pub const ATA_CMD_STANDBY: u8 = 0xe2;
+pub const ATA_CMD_IDLE: u8 = 0xe3;
+pub const ATA_CMD_CHECK_POWER_MODE: u8 = 0xe5;
+pub const ATA_CMD_FLUSH_CACHE: u8 = 0xe7;
+pub const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xea;
+pub const ATA_CMD_IDENTIFY: u8 = 0xec;
+pub const ATA_CMD_SET_FEATURES: u8 = 0xef;
+
+/// Size of the data returned by IDENTIFY DEVICE
+pub const IDENTIFY_SIZE: usize = 512;
@@ -0,0 +1,1163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! AHCI Serial ATA host bus adapter
+//!
+//! The HBA has a single port, to which a disk is attached.  It is meant for
+//! guests whose drivers (or firmware) cannot make use of a virtio or NVMe disk,
+//! and so favors compatibility over throughput: Native Command Queuing is not
+//! offered, the disk executing one command at a time, and interrupts are
+//! delivered through the INTx pin alone.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::accessors::MemAccessor;
+use crate::block;
+use crate::common::*;
+use crate::hw::ids::pci::{
+    ICH9_AHCI_DEV_ID, ICH9_AHCI_SUB_DEV_ID, VENDOR_INTEL, VENDOR_OXIDE,
+};
+use crate::hw::pci;
+use crate::intr_pins::IntrPin;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
+
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+
+mod bits;
+use bits::*;
+
+/// Model number reported by the disk
+const MODEL: &str = "Propolis Virtual Disk";
+/// Firmware revision reported by the disk
+const FIRMWARE_REV: &str = "1.0";
+
+/// HBA Capabilities: a single port with a full complement of command slots,
+/// 64-bit addressing, and no support for legacy (IDE) operation
+const HBA_CAP: u32 =
+    ((NUM_SLOTS - 1) << CAP_NCS_SHIFT) | CAP_SAM | CAP_ISS_GEN3 | CAP_S64A;
+
+struct CompletionPayload {
+    /// Command slot from which the request was issued
+    slot: u8,
+    /// Generation of the port when the request was issued
+    gen: u64,
+    /// Bytes transferred by the command, should it succeed
+    bytes: u32,
+    /// Command transfers data to the host by PIO
+    pio_in: bool,
+}
+
+/// Outcome of a command, as reported to the guest when it completes
+#[derive(Default)]
+struct Completion {
+    /// ATA Error, or zero if the command succeeded
+    error: u8,
+    /// Bytes transferred
+    bytes: u32,
+    /// Value returned in the Count field
+    count: u8,
+    /// Command transferred data to the host by PIO
+    pio_in: bool,
+}
+impl Completion {
+    fn error(error: u8) -> Self {
+        Self { error, ..Default::default() }
+    }
+}
+
+/// Disposition of a command taken from the command list
+enum Dispatch {
+    /// Issued to the block backend
+    Queued(block::Request),
+    /// Completed without involving the backend
+    Done(Completion),
+}
+
+/// The fields of a Register Host to Device FIS which describe a command
+struct AtaCmd {
+    command: u8,
+    lba: u64,
+    device: u8,
+    count: u16,
+}
+impl AtaCmd {
+    fn parse(fis: &[u8; 20]) -> Self {
+        Self {
+            command: fis[2],
+            lba: u64::from_le_bytes([
+                fis[4], fis[5], fis[6], fis[8], fis[9], fis[10], 0, 0,
+            ]),
+            device: fis[7],
+            count: u16::from_le_bytes([fis[12], fis[13]]),
+        }
+    }
+
+    /// Starting LBA and number of sectors addressed by a read or write, using
+    /// 48-bit addressing if `ext` is set.
+    fn extent(&self, ext: bool) -> (u64, u64) {
+        if ext {
+            let count = match self.count {
+                0 => 0x10000,
+                n => n as u64,
+            };
+            (self.lba, count)
+        } else {
+            let lba =
+                (self.lba & 0xff_ffff) | ((self.device as u64 & 0xf) << 24);
+            let count = match self.count & 0xff {
+                0 => 0x100,
+                n => n as u64,
+            };
+            (lba, count)
+        }
+    }
+}
+
+struct PortState {
+    /// Command list base address (PxCLB/PxCLBU)
+    clb: u64,
+    /// Received FIS base address (PxFB/PxFBU)
+    fb: u64,
+    is: u32,
+    ie: u32,
+    cmd: u32,
+    tfd: u32,
+    sig: u32,
+    sctl: u32,
+    serr: u32,
+    ci: u32,
+
+    /// Slot of the command issued to the backend, if any
+    active: Option<u8>,
+    /// A command failed, and no more are to be taken from the command list
+    /// until it is stopped
+    stalled: bool,
+    /// Advanced whenever the port is stopped or reset, so that completions of
+    /// requests issued beforehand can be discarded
+    gen: u64,
+}
+impl PortState {
+    fn new(gen: u64) -> Self {
+        Self {
+            clb: 0,
+            fb: 0,
+            is: 0,
+            ie: 0,
+            cmd: PXCMD_SUD | PXCMD_POD,
+            // The disk is ready, having sent its signature at power-on
+            tfd: (ATA_STS_DRDY | ATA_STS_DSC) as u32,
+            sig: SIG_ATA,
+            sctl: 0,
+            serr: 0,
+            ci: 0,
+            active: None,
+            stalled: false,
+            gen,
+        }
+    }
+
+    fn started(&self) -> bool {
+        self.cmd & PXCMD_ST != 0
+    }
+
+    /// Stop processing of the command list, abandoning any commands in it
+    fn stop(&mut self) {
+        self.cmd &= !(PXCMD_ST | PXCMD_CR | PXCMD_CCS_MASK);
+        self.ci = 0;
+        self.active = None;
+        self.stalled = false;
+        self.gen += 1;
+    }
+
+    /// Slot of the next command to be taken from the command list, if any
+    fn next_slot(&self) -> Option<u8> {
+        if !self.started() || self.stalled || self.active.is_some() {
+            return None;
+        }
+        // Commands are taken in turn, starting after the last one taken
+        let ccs = (self.cmd & PXCMD_CCS_MASK) >> PXCMD_CCS_SHIFT;
+        let start = (ccs + 1) % NUM_SLOTS;
+        let pending = self.ci.rotate_right(start);
+        if pending == 0 {
+            return None;
+        }
+        Some(((pending.trailing_zeros() + start) % NUM_SLOTS) as u8)
+    }
+
+    fn header_addr(&self, slot: u8) -> GuestAddr {
+        let hdr_sz = std::mem::size_of::<CmdHeader>() as u64;
+        GuestAddr(self.clb + slot as u64 * hdr_sz)
+    }
+
+    /// Record the signature sent by the disk as it comes out of reset
+    fn signal_reset(&mut self, mem: Option<&MemCtx>) {
+        self.tfd = (ATA_STS_DRDY | ATA_STS_DSC) as u32;
+        self.sig = SIG_ATA;
+        if let Some(mem) = mem.filter(|_| self.cmd & PXCMD_FRE != 0) {
+            let mut fis = d2h_fis(ATA_STS_DRDY | ATA_STS_DSC, 0, 1);
+            // The signature is conveyed by the LBA and Count fields
+            fis[1] = 0;
+            fis[4] = 1;
+            mem.write(GuestAddr(self.fb + RX_FIS_D2H), &fis);
+        }
+    }
+
+    /// Report the completion of the command in `slot`
+    fn finish(&mut self, mem: &MemCtx, slot: u8, done: Completion) {
+        let mut status = ATA_STS_DRDY | ATA_STS_DSC;
+        if done.error != 0 {
+            status |= ATA_STS_ERR;
+            // The failed command is left in PxCI, as the HBA stops taking
+            // commands until software restarts it
+            self.stalled = true;
+            self.is |= PXIS_TFES;
+        } else {
+            let hdr = self.header_addr(slot);
+            mem.write(GuestAddr(hdr.0 + 4), &done.bytes);
+            self.ci &= !(1 << slot);
+        }
+        self.tfd = ((done.error as u32) << 8) | status as u32;
+
+        if self.cmd & PXCMD_FRE != 0 {
+            if done.pio_in {
+                let fis = pio_setup_fis(status, done.error, done.bytes);
+                mem.write(GuestAddr(self.fb + RX_FIS_PIO_SETUP), &fis);
+            }
+            let fis = d2h_fis(status, done.error, done.count);
+            mem.write(GuestAddr(self.fb + RX_FIS_D2H), &fis);
+        }
+        if done.pio_in {
+            self.is |= PXIS_PSS;
+        }
+        self.is |= PXIS_DHRS;
+    }
+}
+
+struct HbaState {
+    ghc: u32,
+    port: PortState,
+
+    /// INTx delivery is enabled in the PCI configuration
+    intx: bool,
+    pin: Option<Arc<dyn IntrPin>>,
+}
+impl HbaState {
+    fn reset(&mut self) {
+        self.ghc = 0;
+        self.port = PortState::new(self.port.gen + 1);
+    }
+
+    /// Assert or deassert the INTx pin to reflect the pending interrupts
+    fn sync_intr(&self) {
+        let raised = self.intx
+            && self.ghc & GHC_IE != 0
+            && self.port.is & self.port.ie != 0;
+        if let Some(pin) = self.pin.as_ref() {
+            pin.set_state(raised);
+        }
+    }
+}
+
+pub struct PciAhci {
+    state: Mutex<HbaState>,
+
+    /// Serial number reported by the disk
+    serial: Mutex<String>,
+
+    pci_state: pci::DeviceState,
+
+    block_attach: block::device::Attachment,
+    block_tracking: block::device::Tracking<CompletionPayload>,
+
+    log: slog::Logger,
+}
+
+impl PciAhci {
+    /// Create a new pci-ahci device
+    pub fn create(log: slog::Logger) -> Arc<Self> {
+        let pci_state = pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_INTEL,
+            device_id: ICH9_AHCI_DEV_ID,
+            sub_vendor_id: VENDOR_OXIDE,
+            sub_device_id: ICH9_AHCI_SUB_DEV_ID,
+            class: pci::bits::CLASS_STORAGE,
+            subclass: pci::bits::SUBCLASS_STORAGE_SATA,
+            prog_if: pci::bits::PROGIF_AHCI,
+            ..Default::default()
+        })
+        // The HBA memory registers (ABAR) are always found in BAR5
+        .add_bar_mmio(pci::BarN::BAR5, ABAR_SIZE)
+        .add_lintr()
+        .finish();
+
+        let state = HbaState {
+            ghc: 0,
+            port: PortState::new(0),
+            intx: false,
+            pin: None,
+        };
+
+        Arc::new_cyclic(|weak| Self {
+            state: Mutex::new(state),
+            serial: Mutex::new(String::new()),
+            pci_state,
+            block_attach: block::device::Attachment::new(),
+            block_tracking: block::device::Tracking::new(
+                weak.clone() as Weak<dyn block::Device>
+            ),
+            log,
+        })
+    }
+
+    /// Set the serial number reported by the disk in its IDENTIFY data.
+    ///
+    /// # Panics
+    ///
+    /// If `serial` exceeds 20 bytes, or is not printable ASCII.
+    pub fn set_serial(&self, serial: &str) {
+        assert!(serial.len() <= 20);
+        assert!(serial.bytes().all(|c| c.is_ascii_graphic() || c == b' '));
+        *self.serial.lock().unwrap() = serial.to_string();
+    }
+
+    /// Set the deadline for I/O requests issued to the block backend.  See
+    /// [`block::device::Tracking::set_timeout()`].
+    pub fn set_request_timeout(&self, timeout: Option<std::time::Duration>) {
+        self.block_tracking.set_timeout(timeout);
+    }
+
+    /// Set (or clear) write-protection on the disk.
+    ///
+    /// While write-protected, writes are aborted without consulting the
+    /// backend.
+    pub fn set_write_protect(&self, write_protect: bool) {
+        self.block_attach.set_write_protect(write_protect);
+    }
+
+    fn reg_read(&self, id: &HbaReg, ro: &mut ReadOp) {
+        let state = self.state.lock().unwrap();
+        let port = &state.port;
+        let val = match id {
+            HbaReg::Reserved => {
+                ro.fill(0);
+                return;
+            }
+            HbaReg::Cap => HBA_CAP,
+            HbaReg::Ghc => state.ghc | GHC_AE,
+            // Port 0 is the only one implemented
+            HbaReg::Is => (port.is & port.ie != 0) as u32,
+            HbaReg::Pi => 1,
+            HbaReg::Vs => AHCI_VERSION,
+            HbaReg::Cap2 => 0,
+            HbaReg::Port(reg) => match reg {
+                PortReg::Clb => port.clb as u32,
+                PortReg::Clbu => (port.clb >> 32) as u32,
+                PortReg::Fb => port.fb as u32,
+                PortReg::Fbu => (port.fb >> 32) as u32,
+                PortReg::Is => port.is,
+                PortReg::Ie => port.ie,
+                PortReg::Cmd => port.cmd,
+                PortReg::Tfd => port.tfd,
+                PortReg::Sig => port.sig,
+                PortReg::Ssts => PXSSTS_ACTIVE,
+                PortReg::Sctl => port.sctl,
+                PortReg::Serr => port.serr,
+                PortReg::Ci => port.ci,
+                PortReg::Sact | PortReg::Sntf => 0,
+            },
+        };
+        ro.write_u32(val);
+    }
+
+    fn reg_write(&self, id: &HbaReg, wo: &mut WriteOp) {
+        if *id == HbaReg::Reserved {
+            return;
+        }
+        let val = wo.read_u32();
+
+        let mut state = self.state.lock().unwrap();
+        let mut notify = false;
+        match id {
+            HbaReg::Ghc => {
+                if val & GHC_HR != 0 {
+                    slog::info!(self.log, "HBA reset");
+                    state.reset();
+                } else {
+                    state.ghc = val & GHC_IE;
+                }
+            }
+            HbaReg::Port(reg) => {
+                let port = &mut state.port;
+                match reg {
+                    PortReg::Clb => {
+                        port.clb =
+                            (port.clb & !0xffff_ffff) | (val & !0x3ff) as u64;
+                    }
+                    PortReg::Clbu => {
+                        port.clb =
+                            (port.clb & 0xffff_ffff) | ((val as u64) << 32);
+                    }
+                    PortReg::Fb => {
+                        port.fb =
+                            (port.fb & !0xffff_ffff) | (val & !0xff) as u64;
+                    }
+                    PortReg::Fbu => {
+                        port.fb =
+                            (port.fb & 0xffff_ffff) | ((val as u64) << 32);
+                    }
+                    PortReg::Is => port.is &= !val,
+                    PortReg::Ie => port.ie = val,
+                    PortReg::Cmd => notify = Self::port_cmd_write(port, val),
+                    PortReg::Sctl => {
+                        let was_reset =
+                            port.sctl & PXSCTL_DET_MASK == PXSCTL_DET_RESET;
+                        port.sctl = val;
+                        if was_reset && val & PXSCTL_DET_MASK == 0 {
+                            slog::info!(self.log, "port reset");
+                            let mem = self.pci_state.acc_mem.access();
+                            port.signal_reset(mem.as_deref());
+                        }
+                    }
+                    PortReg::Serr => port.serr &= !val,
+                    PortReg::Ci => {
+                        if port.started() {
+                            port.ci |= val;
+                            notify = true;
+                        }
+                    }
+                    PortReg::Tfd
+                    | PortReg::Sig
+                    | PortReg::Ssts
+                    | PortReg::Sact
+                    | PortReg::Sntf => {}
+                }
+            }
+            HbaReg::Cap
+            | HbaReg::Is
+            | HbaReg::Pi
+            | HbaReg::Vs
+            | HbaReg::Cap2
+            | HbaReg::Reserved => {
+                // Read-only registers.  (IS is derived from the port state,
+                // and clears as the port interrupts are cleared.)
+            }
+        }
+        state.sync_intr();
+        drop(state);
+
+        if notify {
+            self.block_attach.notify();
+        }
+    }
+
+    /// Handle a write to PxCMD, returning true if the command list was started
+    fn port_cmd_write(port: &mut PortState, val: u32) -> bool {
+        let was_started = port.started();
+
+        let rw = PXCMD_ST | PXCMD_FRE;
+        port.cmd = (port.cmd & !rw) | (val & rw);
+        if val & PXCMD_CLO != 0 {
+            // Command List Override clears BSY and DRQ, then itself
+            port.tfd &= !((ATA_STS_BSY | ATA_STS_DRQ) as u32);
+        }
+        if port.cmd & PXCMD_FRE != 0 {
+            port.cmd |= PXCMD_FR;
+        } else {
+            port.cmd &= !PXCMD_FR;
+        }
+
+        match (was_started, port.started()) {
+            (true, false) => {
+                port.stop();
+                false
+            }
+            (false, true) => {
+                port.cmd |= PXCMD_CR;
+                // Begin with slot 0 once started
+                port.cmd |= (NUM_SLOTS - 1) << PXCMD_CCS_SHIFT;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn next_req(&self) -> Option<block::Request> {
+        let mem = self.pci_state.acc_mem.access()?;
+        let mut state = self.state.lock().unwrap();
+
+        let mut req = None;
+        while let Some(slot) = state.port.next_slot() {
+            let port = &mut state.port;
+            port.cmd = (port.cmd & !PXCMD_CCS_MASK)
+                | ((slot as u32) << PXCMD_CCS_SHIFT);
+            match self.start_cmd(port, &mem, slot) {
+                Dispatch::Queued(r) => {
+                    port.active = Some(slot);
+                    req = Some(r);
+                    break;
+                }
+                Dispatch::Done(done) => port.finish(&mem, slot, done),
+            }
+        }
+        state.sync_intr();
+        req
+    }
+
+    fn start_cmd(
+        &self,
+        port: &mut PortState,
+        mem: &MemCtx,
+        slot: u8,
+    ) -> Dispatch {
+        let abort = || Dispatch::Done(Completion::error(ATA_ERR_ABRT));
+
+        let Some(hdr) = mem.read::<CmdHeader>(port.header_addr(slot)) else {
+            return abort();
+        };
+        let Some(fis) = mem.read::<[u8; 20]>(GuestAddr(hdr.ctba)) else {
+            return abort();
+        };
+        if fis[0] != FIS_REG_H2D {
+            return abort();
+        }
+        if fis[1] & FIS_H2D_C == 0 {
+            // An update of the Device Control register, as when software
+            // resets the disk.  It signals afresh once SRST is cleared.
+            if fis[15] & ATA_CTL_SRST == 0 {
+                port.signal_reset(Some(mem));
+            }
+            return Dispatch::Done(Completion::default());
+        }
+
+        let cmd = AtaCmd::parse(&fis);
+        match cmd.command {
+            ATA_CMD_IDENTIFY => {
+                let info = self.block_attach.info().unwrap_or_default();
+                let serial = self.serial.lock().unwrap();
+                let data = identify_data(&info, &serial);
+                match prdt_regions(mem, &hdr, IDENTIFY_SIZE) {
+                    Some(regions) => {
+                        let mut data = &data[..];
+                        for GuestRegion(addr, len) in regions {
+                            let (chunk, rest) = data.split_at(len / 2);
+                            mem.write_many(addr, chunk);
+                            data = rest;
+                        }
+                        Dispatch::Done(Completion {
+                            bytes: IDENTIFY_SIZE as u32,
+                            pio_in: true,
+                            ..Default::default()
+                        })
+                    }
+                    None => abort(),
+                }
+            }
+            ATA_CMD_READ_DMA
+            | ATA_CMD_READ_DMA_EXT
+            | ATA_CMD_READ_SECTORS
+            | ATA_CMD_READ_SECTORS_EXT
+            | ATA_CMD_WRITE_DMA
+            | ATA_CMD_WRITE_DMA_EXT
+            | ATA_CMD_WRITE_SECTORS
+            | ATA_CMD_WRITE_SECTORS_EXT => {
+                self.rw_cmd(port, mem, slot, &hdr, &cmd)
+            }
+            ATA_CMD_FLUSH_CACHE | ATA_CMD_FLUSH_CACHE_EXT => {
+                Dispatch::Queued(self.block_tracking.track(
+                    block::Request::new_flush(),
+                    CompletionPayload {
+                        slot,
+                        gen: port.gen,
+                        bytes: 0,
+                        pio_in: false,
+                    },
+                ))
+            }
+            ATA_CMD_CHECK_POWER_MODE => {
+                // The disk is always active
+                Dispatch::Done(Completion { count: 0xff, ..Default::default() })
+            }
+            ATA_CMD_SET_FEATURES
+            | ATA_CMD_INIT_DEV_PARAMS
+            | ATA_CMD_RECALIBRATE
+            | ATA_CMD_READ_VERIFY
+            | ATA_CMD_READ_VERIFY_EXT
+            | ATA_CMD_STANDBY_IMMEDIATE
+            | ATA_CMD_IDLE_IMMEDIATE
+            | ATA_CMD_STANDBY
+            | ATA_CMD_IDLE => {
+                // Transfer modes, the write cache, and power management have
+                // no bearing on an emulated disk, and so are accepted as-is.
+                Dispatch::Done(Completion::default())
+            }
+            _ => {
+                slog::debug!(self.log, "unsupported command";
+                    "command" => cmd.command);
+                abort()
+            }
+        }
+    }
+
+    fn rw_cmd(
+        &self,
+        port: &PortState,
+        mem: &MemCtx,
+        slot: u8,
+        hdr: &CmdHeader,
+        cmd: &AtaCmd,
+    ) -> Dispatch {
+        let (write, ext, pio) = match cmd.command {
+            ATA_CMD_READ_DMA => (false, false, false),
+            ATA_CMD_READ_DMA_EXT => (false, true, false),
+            ATA_CMD_READ_SECTORS => (false, false, true),
+            ATA_CMD_READ_SECTORS_EXT => (false, true, true),
+            ATA_CMD_WRITE_DMA => (true, false, false),
+            ATA_CMD_WRITE_DMA_EXT => (true, true, false),
+            ATA_CMD_WRITE_SECTORS => (true, false, true),
+            ATA_CMD_WRITE_SECTORS_EXT => (true, true, true),
+            _ => unreachable!(),
+        };
+        let error = |error| Dispatch::Done(Completion::error(error));
+
+        // CHS addressing is not supported
+        if !ext && cmd.device & ATA_DEV_LBA == 0 {
+            return error(ATA_ERR_ABRT);
+        }
+        let Some(info) = self.block_attach.info() else {
+            return error(ATA_ERR_ABRT);
+        };
+        let (lba, count) = cmd.extent(ext);
+        if lba + count > info.total_size {
+            return error(ATA_ERR_IDNF);
+        }
+        if write && self.block_attach.write_protected() {
+            return error(ATA_ERR_ABRT);
+        }
+
+        let off = (lba * info.block_size as u64) as usize;
+        let len = (count * info.block_size as u64) as usize;
+        let Some(regions) = prdt_regions(mem, hdr, len) else {
+            return error(ATA_ERR_ABRT);
+        };
+        let req = if write {
+            block::Request::new_write(off, len, regions)
+        } else {
+            block::Request::new_read(off, len, regions)
+        };
+        Dispatch::Queued(self.block_tracking.track(
+            req,
+            CompletionPayload {
+                slot,
+                gen: port.gen,
+                bytes: len as u32,
+                pio_in: pio && !write,
+            },
+        ))
+    }
+}
+
+/// Gather the guest memory described by the physical region descriptor table
+/// of a command, up to `len` bytes.  Returns `None` if the table does not
+/// describe enough memory, or lies outside of guest memory.
+fn prdt_regions(
+    mem: &MemCtx,
+    hdr: &CmdHeader,
+    len: usize,
+) -> Option<Vec<GuestRegion>> {
+    let prd_sz = std::mem::size_of::<PrdEntry>() as u64;
+    let mut regions = Vec::new();
+    let mut remain = len;
+    for i in 0..hdr.prdtl as u64 {
+        if remain == 0 {
+            break;
+        }
+        // The table may be placed where its entries would wrap around the
+        // top of the address space, which fails the command.
+        let addr = hdr.ctba.checked_add(CMD_TABLE_PRDT + i * prd_sz)?;
+        let prd = mem.read::<PrdEntry>(GuestAddr(addr))?;
+        let sz = ((prd.dbc & PRD_DBC_MASK) as usize + 1).min(remain);
+        regions.push(GuestRegion(GuestAddr(prd.dba & !1), sz));
+        remain -= sz;
+    }
+    (remain == 0).then_some(regions)
+}
+
+fn d2h_fis(status: u8, error: u8, count: u8) -> [u8; 20] {
+    let mut fis = [0u8; 20];
+    fis[0] = FIS_REG_D2H;
+    fis[1] = FIS_I;
+    fis[2] = status;
+    fis[3] = error;
+    fis[12] = count;
+    fis
+}
+
+fn pio_setup_fis(status: u8, error: u8, bytes: u32) -> [u8; 20] {
+    let mut fis = [0u8; 20];
+    fis[0] = FIS_PIO_SETUP;
+    fis[1] = FIS_PIO_D | FIS_I;
+    fis[2] = ATA_STS_DRDY | ATA_STS_DSC | ATA_STS_DRQ;
+    fis[3] = error;
+    // Ending status, once the transfer is complete
+    fis[15] = status;
+    fis[16..18].copy_from_slice(&(bytes as u16).to_le_bytes());
+    fis
+}
+
+/// Store `s` in the words of an ATA string field, space-padded, with the
+/// first character of each pair in the upper byte.
+fn ata_string(words: &mut [u16], s: &str) {
+    let mut chars = s.bytes().chain(std::iter::repeat(b' '));
+    for word in words.iter_mut() {
+        let hi = chars.next().unwrap();
+        let lo = chars.next().unwrap();
+        *word = u16::from_be_bytes([hi, lo]);
+    }
+}
+
+/// Build the data returned by IDENTIFY DEVICE for a disk of the given size.
+fn identify_data(info: &block::DeviceInfo, serial: &str) -> [u16; 256] {
+    let mut id = [0u16; 256];
+    let sectors = info.total_size;
+
+    // Fixed ATA device
+    id[0] = 0x0040;
+    // Default CHS geometry, for software which still cares about it
+    id[1] = (sectors / (16 * 63)).min(16383) as u16;
+    id[3] = 16;
+    id[6] = 63;
+    ata_string(&mut id[10..20], serial);
+    ata_string(&mut id[23..27], FIRMWARE_REV);
+    ata_string(&mut id[27..47], MODEL);
+    // READ/WRITE MULTIPLE are not supported
+    id[47] = 0x8000;
+    // LBA and DMA are supported
+    id[49] = (1 << 9) | (1 << 8);
+    id[50] = 0x4000;
+    // Words 64-70 and 88 are valid
+    id[53] = (1 << 2) | (1 << 1);
+    let lba28 = sectors.min(0x0fff_ffff);
+    id[60] = lba28 as u16;
+    id[61] = (lba28 >> 16) as u16;
+    // Multiword DMA modes 0-2 and PIO modes 3-4
+    id[63] = 0x0007;
+    id[64] = 0x0003;
+    id[65..=68].fill(120);
+    // SATA Gen1 through Gen3 signalling
+    id[76] = 0x000e;
+    // ATA/ATAPI-4 through ATA8-ACS
+    id[80] = 0x01f0;
+    // A volatile write cache, which is enabled
+    id[82] = 1 << 5;
+    id[85] = 1 << 5;
+    // FLUSH CACHE (EXT) and 48-bit addressing, which are enabled
+    id[83] = (1 << 14) | (1 << 13) | (1 << 12) | (1 << 10);
+    id[86] = (1 << 13) | (1 << 12) | (1 << 10);
+    id[84] = 1 << 14;
+    id[87] = 1 << 14;
+    // Ultra DMA modes 0-5, with mode 5 selected
+    id[88] = 0x203f;
+    for (i, word) in id[100..104].iter_mut().enumerate() {
+        *word = (sectors >> (i * 16)) as u16;
+    }
+    if info.block_size > 512 {
+        // Logical sectors are larger than 256 words
+        id[106] = (1 << 14) | (1 << 12);
+        let words = info.block_size / 2;
+        id[117] = words as u16;
+        id[118] = (words >> 16) as u16;
+    }
+    // Non-rotating media
+    id[217] = 1;
+
+    // Integrity word: a signature, and a checksum which brings the sum of all
+    // the bytes of the data to zero
+    id[255] = 0xa5;
+    let sum = id
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .fold(0u8, |sum, b| sum.wrapping_add(b));
+    id[255] |= (sum.wrapping_neg() as u16) << 8;
+    id
+}
+
+impl pci::Device for PciAhci {
+    fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp) {
+        assert_eq!(bar, pci::BarN::BAR5);
+        HBA_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.reg_read(id, ro),
+            RWOp::Write(wo) => self.reg_write(id, wo),
+        });
+    }
+
+    fn attach(&self) {
+        self.state.lock().unwrap().pin = self.pci_state.lintr_pin();
+    }
+
+    fn interrupt_mode_change(&self, mode: pci::IntrMode) {
+        let mut state = self.state.lock().unwrap();
+        state.intx = mode == pci::IntrMode::INTxPin;
+        state.sync_intr();
+    }
+
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+impl block::Device for PciAhci {
+    fn attachment(&self) -> &block::device::Attachment {
+        &self.block_attach
+    }
+
+    fn next(&self) -> Option<block::Request> {
+        self.next_req()
+    }
+
+    fn complete(&self, res: block::Result, id: block::ReqId) {
+        let Some((op, payload)) = self.block_tracking.complete(id, res) else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        let port = &mut state.port;
+        if payload.gen != port.gen {
+            // Issued before the port was stopped or reset
+            return;
+        }
+        port.active = None;
+
+        let done = match res {
+            block::Result::Success => Completion {
+                bytes: payload.bytes,
+                pio_in: payload.pio_in,
+                ..Default::default()
+            },
+            _ if op.is_read() => Completion::error(ATA_ERR_UNC),
+            _ => Completion::error(ATA_ERR_ABRT),
+        };
+        if let Some(mem) = self.pci_state.acc_mem.access() {
+            port.finish(&mem, payload.slot, done);
+        }
+        state.sync_intr();
+        let pending = state.port.ci != 0;
+        drop(state);
+
+        // Commands are executed one at a time, so the next (if any) has been
+        // waiting on this one
+        if pending {
+            self.block_attach.notify();
+        }
+    }
+
+    fn accessor_mem(&self) -> MemAccessor {
+        self.pci_state.acc_mem.child(Some("block backend".to_string()))
+    }
+
+    fn io_history(&self) -> Option<block::device::IoHistory> {
+        Some(self.block_tracking.io_history())
+    }
+}
+
+impl MigrateMulti for PciAhci {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let state = self.state.lock().unwrap();
+        let port = &state.port;
+        output.push(
+            migrate::AhciHbaV1 {
+                ghc: state.ghc,
+                clb: port.clb,
+                fb: port.fb,
+                is: port.is,
+                ie: port.ie,
+                cmd: port.cmd,
+                tfd: port.tfd,
+                sig: port.sig,
+                sctl: port.sctl,
+                serr: port.serr,
+                ci: port.ci,
+                stalled: port.stalled,
+            }
+            .into(),
+        )?;
+        drop(state);
+
+        MigrateMulti::export(&self.pci_state, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::AhciHbaV1 = offer.take()?;
+
+        let mut state = self.state.lock().unwrap();
+        state.ghc = input.ghc & GHC_IE;
+        // No requests are outstanding while the device is paused, so any
+        // command left in the command list has yet to be taken from it.
+        let port = &mut state.port;
+        port.clb = input.clb;
+        port.fb = input.fb;
+        port.is = input.is;
+        port.ie = input.ie;
+        port.cmd = input.cmd;
+        port.tfd = input.tfd;
+        port.sig = input.sig;
+        port.sctl = input.sctl;
+        port.serr = input.serr;
+        port.ci = input.ci;
+        port.stalled = input.stalled;
+        port.active = None;
+        port.gen += 1;
+        drop(state);
+
+        MigrateMulti::import(&self.pci_state, offer, ctx)
+    }
+}
+
+impl Entity for PciAhci {
+    fn type_name(&self) -> &'static str {
+        "pci-ahci"
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.reset();
+        state.sync_intr();
+        drop(state);
+        self.pci_state.reset(self);
+    }
+
+    fn pause(&self) {
+        self.block_attach.pause();
+    }
+
+    fn resume(&self) {
+        self.block_attach.resume();
+    }
+
+    fn paused(&self) -> BoxFuture<'static, ()> {
+        Box::pin(self.block_tracking.none_outstanding())
+    }
+
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct AhciHbaV1 {
+        pub ghc: u32,
+
+        pub clb: u64,
+        pub fb: u64,
+        pub is: u32,
+        pub ie: u32,
+        pub cmd: u32,
+        pub tfd: u32,
+        pub sig: u32,
+        pub sctl: u32,
+        pub serr: u32,
+        pub ci: u32,
+        pub stalled: bool,
+    }
+    impl Schema<'_> for AhciHbaV1 {
+        fn id() -> SchemaId {
+            ("ahci-hba", 1)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum HbaReg {
+    Reserved,
+
+    Cap,
+    Ghc,
+    Is,
+    Pi,
+    Vs,
+    Cap2,
+
+    Port(PortReg),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum PortReg {
+    Clb,
+    Clbu,
+    Fb,
+    Fbu,
+    Is,
+    Ie,
+    Cmd,
+    Tfd,
+    Sig,
+    Ssts,
+    Sctl,
+    Serr,
+    Sact,
+    Ci,
+    Sntf,
+}
+
+lazy_static! {
+    static ref HBA_REGS: RegMap<HbaReg> = {
+        let layout = [
+            (HbaReg::Cap, 4),
+            (HbaReg::Ghc, 4),
+            (HbaReg::Is, 4),
+            (HbaReg::Pi, 4),
+            (HbaReg::Vs, 4),
+            // Command completion coalescing and enclosure management
+            (HbaReg::Reserved, 0x10),
+            (HbaReg::Cap2, 4),
+            (HbaReg::Reserved, PORT_REGS_OFF - 0x28),
+            (HbaReg::Port(PortReg::Clb), 4),
+            (HbaReg::Port(PortReg::Clbu), 4),
+            (HbaReg::Port(PortReg::Fb), 4),
+            (HbaReg::Port(PortReg::Fbu), 4),
+            (HbaReg::Port(PortReg::Is), 4),
+            (HbaReg::Port(PortReg::Ie), 4),
+            (HbaReg::Port(PortReg::Cmd), 4),
+            (HbaReg::Reserved, 4),
+            (HbaReg::Port(PortReg::Tfd), 4),
+            (HbaReg::Port(PortReg::Sig), 4),
+            (HbaReg::Port(PortReg::Ssts), 4),
+            (HbaReg::Port(PortReg::Sctl), 4),
+            (HbaReg::Port(PortReg::Serr), 4),
+            (HbaReg::Port(PortReg::Sact), 4),
+            (HbaReg::Port(PortReg::Ci), 4),
+            (HbaReg::Port(PortReg::Sntf), 4),
+            (HbaReg::Reserved, PORT_REGS_SIZE - 0x40),
+            (
+                HbaReg::Reserved,
+                ABAR_SIZE as usize - PORT_REGS_OFF - PORT_REGS_SIZE,
+            ),
+        ];
+        RegMap::create_packed(ABAR_SIZE as usize, &layout, Some(HbaReg::Reserved))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instance::Instance;
+
+    fn info(sectors: u64, block_size: u32) -> block::DeviceInfo {
+        block::DeviceInfo {
+            block_size,
+            total_size: sectors,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn identify_checksum() {
+        let id = identify_data(&info(1 << 21, 512), "disk0");
+        assert_eq!(id[255] & 0xff, 0xa5);
+        let sum = id
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .fold(0u8, |sum, b| sum.wrapping_add(b));
+        assert_eq!(sum, 0);
+    }
+
+    #[test]
+    fn identify_strings() {
+        let id = identify_data(&info(1 << 21, 512), "disk0");
+        let serial: Vec<u8> =
+            id[10..20].iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert_eq!(&serial[..], b"disk0               ");
+        let model: Vec<u8> =
+            id[27..47].iter().flat_map(|w| w.to_be_bytes()).collect();
+        assert!(model.starts_with(MODEL.as_bytes()));
+    }
+
+    #[test]
+    fn identify_capacity() {
+        // 48-bit capacity is reported in full, 28-bit capacity saturates
+        let sectors = 0x1_2345_6789;
+        let id = identify_data(&info(sectors, 512), "");
+        assert_eq!(id[60..62], [0xffff, 0x0fff]);
+        assert_eq!(id[100..104], [0x6789, 0x2345, 0x1, 0]);
+        assert_eq!(id[106], 0);
+
+        // Logical sector size is given in words
+        let id = identify_data(&info(1 << 18, 4096), "");
+        assert_eq!(id[106], (1 << 14) | (1 << 12));
+        assert_eq!(id[117..119], [2048, 0]);
+    }
+
+    #[test]
+    fn command_extent() {
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_REG_H2D;
+        fis[1] = FIS_H2D_C;
+        fis[2] = ATA_CMD_READ_DMA;
+        fis[4..7].copy_from_slice(&[0x56, 0x34, 0x12]);
+        fis[7] = ATA_DEV_LBA | 0x7;
+        fis[8..11].copy_from_slice(&[0x9a, 0x78, 0]);
+
+        // 28-bit commands take the top of the LBA from the Device register,
+        // and a count of zero means 256 sectors
+        let cmd = AtaCmd::parse(&fis);
+        assert_eq!(cmd.extent(false), (0x0712_3456, 0x100));
+        assert_eq!(cmd.extent(true), (0x78_9a12_3456, 0x10000));
+
+        fis[12..14].copy_from_slice(&[0x10, 0x02]);
+        let cmd = AtaCmd::parse(&fis);
+        assert_eq!(cmd.extent(false).1, 0x10);
+        assert_eq!(cmd.extent(true).1, 0x210);
+    }
+
+    #[test]
+    fn slots_taken_in_turn() {
+        let mut port = PortState::new(0);
+        port.ci = 0b1011;
+        assert_eq!(port.next_slot(), None);
+
+        port.cmd |= PXCMD_ST | ((NUM_SLOTS - 1) << PXCMD_CCS_SHIFT);
+        assert_eq!(port.next_slot(), Some(0));
+        port.cmd = (port.cmd & !PXCMD_CCS_MASK) | (1 << PXCMD_CCS_SHIFT);
+        assert_eq!(port.next_slot(), Some(3));
+        port.cmd = (port.cmd & !PXCMD_CCS_MASK) | (3 << PXCMD_CCS_SHIFT);
+        assert_eq!(port.next_slot(), Some(0));
+
+        // Nothing more is taken while a command is active, or after one fails
+        port.active = Some(0);
+        assert_eq!(port.next_slot(), None);
+        port.active = None;
+        port.stalled = true;
+        assert_eq!(port.next_slot(), None);
+        port.stop();
+        assert_eq!(port.ci, 0);
+        assert!(!port.stalled);
+    }
+
+    #[test]
+    fn prdt_at_top_of_address_space() {
+        let instance = Instance::new_test().unwrap();
+        let acc_mem = instance.lock().machine().acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        // A table whose entries would wrap around fails the command, rather
+        // than overflowing its address
+        let hdr =
+            CmdHeader { prdtl: 0xffff, ctba: !0x7f, ..Default::default() };
+        assert!(prdt_regions(&mem, &hdr, 512).is_none());
+    }
+}
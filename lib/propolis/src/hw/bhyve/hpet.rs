@@ -8,6 +8,11 @@ use crate::inventory::Entity;
 use crate::migrate::*;
 use crate::vmm::VmmHdl;
 
+/// Address at which the in-kernel HPET is mapped
+pub const HPET_ADDR: u64 = 0xfed0_0000;
+/// Size of the register block of the HPET
+pub const HPET_LEN: u64 = 0x400;
+
 pub struct BhyveHpet {
     hdl: Arc<VmmHdl>,
 }
@@ -15,6 +20,14 @@ impl BhyveHpet {
     pub fn create(hdl: Arc<VmmHdl>) -> Arc<Self> {
         Arc::new(Self { hdl })
     }
+
+    /// The lower 32 bits of the HPET's General Capabilities and ID register,
+    /// which describe its vendor, revision and number of comparators.  These
+    /// also serve as the Event Timer Block ID by which the HPET is described
+    /// in ACPI tables.
+    pub fn capabilities(&self) -> std::io::Result<u32> {
+        self.hdl.hpet_capabilities()
+    }
 }
 
 impl Entity for BhyveHpet {
@@ -2,6 +2,25 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! Devices emulated by the kernel portion of the VMM
+//!
+//! Accesses by the guest to these devices are handled entirely in the kernel,
+//! never reaching userspace.  The entities here exist to carry the devices'
+//! state through migration, and to configure them where the kernel allows:
+//!
+//! - The i8254 PIT ([`BhyveAtPit`]), at I/O ports 0x40-0x43, along with the
+//!   timer gate and speaker bits of port 0x61.
+//! - The HPET ([`BhyveHpet`]), whose registers are mapped at [`HPET_ADDR`].
+//!   It has a fixed set of 8 comparators, reported through its capabilities
+//!   register; guests find it through ACPI tables which describe it.
+//! - The ACPI PM timer ([`BhyvePmTimer`]), at a port relocated by the chipset
+//!   to lie within its PM register block.
+//! - The MC146818 RTC ([`BhyveRtc`]), at I/O ports 0x70-0x71.
+//! - The i8259 PICs ([`BhyveAtPic`]) and the I/O APIC ([`BhyveIoApic`]).
+//!
+//! As the kernel claims their ports and addresses, no userspace emulation of
+//! these devices could be reached by the guest.
+
 mod atpic;
 mod atpit;
 mod hpet;
@@ -11,7 +30,7 @@ mod rtc;
 
 pub use atpic::BhyveAtPic;
 pub use atpit::BhyveAtPit;
-pub use hpet::BhyveHpet;
+pub use hpet::{BhyveHpet, HPET_ADDR, HPET_LEN};
 pub use ioapic::BhyveIoApic;
 pub use pmtimer::BhyvePmTimer;
 pub use rtc::BhyveRtc;
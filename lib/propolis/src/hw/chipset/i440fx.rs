@@ -7,6 +7,7 @@ use std::sync::{Arc, Mutex};
 
 use crate::common::*;
 use crate::hw::bhyve::BhyvePmTimer;
+use crate::hw::chipset::post_code::{PostCode, PostCodeLog};
 use crate::hw::chipset::Chipset;
 use crate::hw::ibmpc;
 use crate::hw::ids::pci::{
@@ -18,11 +19,14 @@ use crate::hw::pci::topology::{LogicalBusId, RoutedBusId};
 use crate::hw::pci::{
     self, Bdf, BusLocation, INTxPinID, PcieCfgDecoder, PioCfgDecoder,
 };
-use crate::intr_pins::{IntrPin, LegacyPIC, LegacyPin, NoOpPin};
+use crate::hw::smbus::{self, Piix4Smbus};
+use crate::intr_pins::{
+    IntrPin, IoApic, IoApicPin, LegacyPIC, LegacyPin, NoOpPin, PinState,
+};
 use crate::inventory;
 use crate::migrate::*;
 use crate::mmio::MmioFn;
-use crate::pio::{PioBus, PioFn};
+use crate::pio::{self, PioBus, PioFn};
 use crate::util::regmap::RegMap;
 use crate::vmm::{Machine, VmmHdl};
 
@@ -35,20 +39,28 @@ const LPC_FUNC: u8 = 0;
 const PM_DEV: u8 = 1;
 const PM_FUNC: u8 = 3;
 
-const ADDR_PCIE_ECAM_REGION: usize = 0xe000_0000;
-const LEN_PCI_ECAM_REGION: usize = 0x1000_0000;
+/// Guest-physical base of the PCIe ECAM (MMCONFIG) region.  The machine's
+/// memory map must reserve this region, whether or not PCIe is enabled.
+pub const ADDR_PCIE_ECAM_REGION: usize = 0xe000_0000;
+/// Length of the PCIe ECAM region, enough for 256 buses
+pub const LEN_PCIE_ECAM_REGION: usize = 0x1000_0000;
 
 #[derive(Default)]
 pub struct Opts {
     pub enable_pcie: bool,
     pub power_pin: Option<Arc<dyn IntrPin>>,
     pub reset_pin: Option<Arc<dyn IntrPin>>,
+    /// Also capture POST codes written to the alternate port (0xE9)
+    pub post_code_alt: bool,
+    /// Provide the SMBus host controller of the PM function
+    pub enable_smbus: bool,
 }
 
 pub struct I440Fx {
     pci_topology: Arc<pci::topology::Topology>,
     pci_cfg: PioCfgDecoder,
     pcie_cfg: PcieCfgDecoder,
+    pcie_enabled: bool,
     irq_config: Arc<IrqConfig>,
 
     pin_power: Arc<dyn IntrPin>,
@@ -67,7 +79,7 @@ impl I440Fx {
         pci_topology: Arc<pci::topology::Topology>,
         opts: Opts,
         log: slog::Logger,
-    ) -> Arc<Self> {
+    ) -> pio::Result<Arc<Self>> {
         let hdl = machine.hdl.clone();
         let irq_config = IrqConfig::create(hdl.clone());
 
@@ -80,14 +92,15 @@ impl I440Fx {
             pcie_cfg: PcieCfgDecoder::new(
                 pci::bits::PCIE_MAX_BUSES_PER_ECAM_REGION,
             ),
+            pcie_enabled: opts.enable_pcie,
             irq_config: irq_config.clone(),
 
             pin_power: power_pin.clone(),
             pin_reset: reset_pin,
 
             dev_hb: Piix4HostBridge::create(),
-            dev_lpc: Piix3Lpc::create(irq_config),
-            dev_pm: Piix3PM::create(hdl, power_pin, log),
+            dev_lpc: Piix3Lpc::create(irq_config, opts.post_code_alt),
+            dev_pm: Piix3PM::create(hdl, power_pin, opts.enable_smbus, log),
         });
 
         this.pci_attach(
@@ -105,8 +118,8 @@ impl I440Fx {
 
         // Attach chipset devices
         let pio = &machine.bus_pio;
-        this.dev_lpc.attach(pio);
-        this.dev_pm.attach(pio);
+        this.dev_lpc.attach(pio)?;
+        this.dev_pm.attach(pio)?;
 
         let pio_dev = Arc::clone(&this);
         let piofn =
@@ -116,14 +129,12 @@ impl I440Fx {
             pci::bits::PORT_PCI_CONFIG_ADDR,
             pci::bits::LEN_PCI_CONFIG_ADDR,
             Arc::clone(&piofn),
-        )
-        .unwrap();
+        )?;
         pio.register(
             pci::bits::PORT_PCI_CONFIG_DATA,
             pci::bits::LEN_PCI_CONFIG_DATA,
             piofn,
-        )
-        .unwrap();
+        )?;
 
         if opts.enable_pcie {
             let mmio = &machine.bus_mmio;
@@ -133,13 +144,12 @@ impl I440Fx {
             }) as Arc<MmioFn>;
             mmio.register(
                 ADDR_PCIE_ECAM_REGION,
-                LEN_PCI_ECAM_REGION,
+                LEN_PCIE_ECAM_REGION,
                 mmio_ecam_fn,
-            )
-            .unwrap();
+            )?;
         }
 
-        this
+        Ok(this)
     }
 
     fn route_lintr(
@@ -153,9 +163,33 @@ impl I440Fx {
             3 => INTxPinID::IntD,
             _ => unreachable!(),
         };
-        // D->A->B->C starting at 0:0.0
-        let pin_route = (location.dev.get() + intx_pin as u8 + 2) % 4;
-        (intx_pin, self.irq_config.intr_pin(pin_route as usize))
+        let link = intx_link(location.dev.get(), intx_pin as u8);
+        (intx_pin, self.irq_config.intr_pin(link))
+    }
+
+    /// Routing of the INTx lines of each slot on the root bus, for the guest
+    /// to be told of via the ACPI `_PRT` method.
+    pub fn pci_intx_routes(&self) -> Vec<PciIntxRoute> {
+        (0..32u8)
+            .flat_map(|dev| {
+                (0..4u8).map(move |pin| {
+                    // _PRT numbers pins from 0 (INTA), rather than 1
+                    let link = intx_link(dev, pin + 1);
+                    PciIntxRoute {
+                        dev,
+                        pin,
+                        link: link as u8,
+                        gsi: IoApic::FIRST_GSI + link as u8,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// The SMBus of the PM function's host controller, if it is enabled, to
+    /// which devices may be attached
+    pub fn smbus(&self) -> Option<&Arc<smbus::Bus>> {
+        self.dev_pm.smbus.as_ref().map(|smbus| smbus.bus())
     }
 
     fn pci_cfg_rw(&self, bdf: &Bdf, rwo: RWOp) -> Option<()> {
@@ -183,6 +217,46 @@ impl I440Fx {
     fn pcie_ecam_rw(&self, rwo: RWOp) {
         self.pcie_cfg.service(rwo, |bdf, rwo| self.pci_cfg_rw(bdf, rwo));
     }
+
+    /// Guest-physical region decoded as PCIe ECAM, if enabled
+    pub fn pcie_ecam_region(&self) -> Option<std::ops::Range<u64>> {
+        self.pcie_enabled.then(|| {
+            let base = ADDR_PCIE_ECAM_REGION as u64;
+            base..(base + LEN_PCIE_ECAM_REGION as u64)
+        })
+    }
+
+    /// IO port base of the PIIX PM register block
+    pub fn pm_base(&self) -> u16 {
+        self.dev_pm.regs.lock().unwrap().pm_base
+    }
+
+    /// Reads the configuration space of every PCI device in the chipset's
+    /// topology.  See [`pci::topology::Topology::cfg_space_dump`].
+    pub fn pci_cfg_dump(&self) -> Vec<(Bdf, Vec<u8>)> {
+        self.pci_topology.cfg_space_dump()
+    }
+
+    /// POST codes written by the guest.  See [`PostCodeLog::snapshot`].
+    pub fn post_codes(&self) -> (Vec<PostCode>, u64) {
+        self.dev_lpc.post_codes.snapshot()
+    }
+
+    /// State of the legacy interrupt pins, ordered by IRQ
+    pub fn intr_pin_states(&self) -> Vec<PinState> {
+        self.irq_config.pic.pin_states()
+    }
+
+    /// State of the IOAPIC pins to which PCI INTx lines are routed, ordered by
+    /// GSI
+    pub fn ioapic_pin_states(&self) -> Vec<PinState> {
+        self.irq_config.ioapic.pin_states()
+    }
+
+    /// Pin used to signal ACPI System Control Interrupts to the guest
+    pub fn sci_pin(&self) -> Arc<dyn IntrPin> {
+        self.irq_config.sci_pin.clone()
+    }
 }
 impl Chipset for I440Fx {
     fn pci_attach(&self, bdf: Bdf, dev: Arc<dyn pci::Endpoint>) {
@@ -199,6 +273,43 @@ impl Chipset for I440Fx {
             )
             .unwrap();
     }
+    fn pci_attach_multifunc(
+        &self,
+        bus: pci::BusNum,
+        dev: pci::DevNum,
+        package: pci::MultiFunc,
+    ) {
+        self.pci_topology
+            .pci_attach_multifunc(
+                LogicalBusId(bus.get()),
+                dev,
+                package,
+                |location| match bus.get() {
+                    0 => Some(self.route_lintr(&location)),
+                    _ => None,
+                },
+            )
+            .unwrap();
+    }
+    fn pci_detach(&self, bdf: Bdf) -> Option<Arc<dyn pci::Endpoint>> {
+        self.pci_topology
+            .pci_detach(LogicalBusId(bdf.bus.get()), bdf.location)
+            .ok()
+            .flatten()
+    }
+    fn pci_device_at(&self, bdf: Bdf) -> Option<Arc<dyn pci::Endpoint>> {
+        self.pci_topology
+            .pci_device_at(LogicalBusId(bdf.bus.get()), bdf.location)
+            .ok()
+            .flatten()
+    }
+    fn pci_set_hidden(&self, bdf: Bdf, hidden: bool) {
+        let _ = self.pci_topology.pci_set_hidden(
+            LogicalBusId(bdf.bus.get()),
+            bdf.location,
+            hidden,
+        );
+    }
     fn irq_pin(&self, irq: u8) -> Option<Box<dyn IntrPin>> {
         self.irq_config
             .pic
@@ -248,16 +359,43 @@ impl Entity for I440Fx {
     }
 }
 
+/// Link between the INTx lines of PCI slots, numbered as in the interrupt pin
+/// register (1 = INTA), and the PIRQ links: D->A->B->C starting at 0:0.0
+fn intx_link(dev: u8, pin: u8) -> usize {
+    ((dev + pin + 2) % 4) as usize
+}
+
+/// Routing of a PCI INTx line on the root bus.
+///
+/// A guest using the legacy PIC reaches the line through the PIRQ link it is
+/// routed to, while one using the APIC receives it on a dedicated GSI.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PciIntxRoute {
+    /// Device number on bus 0
+    pub dev: u8,
+    /// Interrupt pin, as numbered in `_PRT` entries (0 = INTA)
+    pub pin: u8,
+    /// PIRQ link (0 = LNKA) carrying the line to an ISA IRQ
+    pub link: u8,
+    /// IOAPIC input carrying the line
+    pub gsi: u8,
+}
+
 struct LNKPin {
     inner: Mutex<LNKPinInner>,
+    /// IOAPIC pin driven by the link, regardless of its PIRQ routing
+    gsi_pin: Option<IoApicPin>,
 }
 struct LNKPinInner {
     asserted: bool,
     pin: Option<LegacyPin>,
 }
 impl LNKPin {
-    fn new() -> Self {
-        Self { inner: Mutex::new(LNKPinInner { asserted: false, pin: None }) }
+    fn new(gsi_pin: Option<IoApicPin>) -> Self {
+        Self {
+            inner: Mutex::new(LNKPinInner { asserted: false, pin: None }),
+            gsi_pin,
+        }
     }
     fn reassign(&self, new_pin: Option<LegacyPin>) {
         let mut inner = self.inner.lock().unwrap();
@@ -282,6 +420,9 @@ impl IntrPin for LNKPin {
         if let Some(pin) = inner.pin.as_ref() {
             pin.assert();
         }
+        if let Some(pin) = self.gsi_pin.as_ref() {
+            pin.assert();
+        }
     }
     fn deassert(&self) {
         let mut inner = self.inner.lock().unwrap();
@@ -289,12 +430,18 @@ impl IntrPin for LNKPin {
         if let Some(pin) = inner.pin.as_ref() {
             pin.deassert();
         }
+        if let Some(pin) = self.gsi_pin.as_ref() {
+            pin.deassert();
+        }
     }
     fn pulse(&self) {
         let inner = self.inner.lock().unwrap();
         if let Some(pin) = inner.pin.as_ref() {
             pin.pulse();
         }
+        if let Some(pin) = self.gsi_pin.as_ref() {
+            pin.pulse();
+        }
     }
     fn is_asserted(&self) -> bool {
         let inner = self.inner.lock().unwrap();
@@ -304,26 +451,29 @@ impl IntrPin for LNKPin {
 
 struct IrqConfig {
     pic: Arc<LegacyPIC>,
+    ioapic: Arc<IoApic>,
 
     lnk_pins: [Arc<LNKPin>; 4],
 
-    #[allow(unused)]
-    // XXX: wire up SCI notifications
     sci_pin: Arc<LNKPin>,
 }
 impl IrqConfig {
     fn create(hdl: Arc<VmmHdl>) -> Arc<Self> {
-        let pic = LegacyPIC::new(hdl);
-        let sci_pin = Arc::new(LNKPin::new());
+        let pic = LegacyPIC::new(hdl.clone());
+        let ioapic = IoApic::new(hdl);
+
+        // The SCI is an ISA IRQ, and so already reaches the IOAPIC via the PIC
+        let sci_pin = Arc::new(LNKPin::new(None));
         sci_pin.reassign(pic.pin_handle(SCI_IRQ));
+
+        let lnk_pin = |idx: u8| {
+            let gsi = IoApic::FIRST_GSI + idx;
+            Arc::new(LNKPin::new(ioapic.pin_handle(gsi)))
+        };
         Arc::new(Self {
+            lnk_pins: [lnk_pin(0), lnk_pin(1), lnk_pin(2), lnk_pin(3)],
             pic,
-            lnk_pins: [
-                Arc::new(LNKPin::new()),
-                Arc::new(LNKPin::new()),
-                Arc::new(LNKPin::new()),
-                Arc::new(LNKPin::new()),
-            ],
+            ioapic,
             sci_pin,
         })
     }
@@ -407,10 +557,12 @@ pub struct Piix3Lpc {
     pci_state: pci::DeviceState,
     reg_pir: Mutex<[u8; PIR_LEN]>,
     post_code: AtomicU8,
+    post_code_alt: bool,
+    post_codes: PostCodeLog,
     irq_config: Arc<IrqConfig>,
 }
 impl Piix3Lpc {
-    fn create(irq_config: Arc<IrqConfig>) -> Arc<Self> {
+    fn create(irq_config: Arc<IrqConfig>, post_code_alt: bool) -> Arc<Self> {
         let pci_state = pci::Builder::new(pci::Ident {
             vendor_id: VENDOR_INTEL,
             device_id: PIIX3_ISA_DEV_ID,
@@ -427,11 +579,13 @@ impl Piix3Lpc {
             pci_state,
             reg_pir: Mutex::new([0u8; PIR_LEN]),
             post_code: AtomicU8::new(0),
+            post_code_alt,
+            post_codes: PostCodeLog::new(),
             irq_config,
         })
     }
 
-    fn attach(self: &Arc<Self>, pio: &PioBus) {
+    fn attach(self: &Arc<Self>, pio: &PioBus) -> pio::Result<()> {
         let this = Arc::clone(self);
         let piofn = Arc::new(move |port: u16, rwo: RWOp| this.pio_rw(port, rwo))
             as Arc<PioFn>;
@@ -439,10 +593,15 @@ impl Piix3Lpc {
             ibmpc::PORT_FAST_A20,
             ibmpc::LEN_FAST_A20,
             Arc::clone(&piofn),
-        )
-        .unwrap();
+        )?;
+        if self.post_code_alt {
+            pio.register(
+                ibmpc::PORT_POST_CODE_ALT,
+                ibmpc::LEN_POST_CODE,
+                Arc::clone(&piofn),
+            )?;
+        }
         pio.register(ibmpc::PORT_POST_CODE, ibmpc::LEN_POST_CODE, piofn)
-            .unwrap();
     }
 
     fn pio_rw(&self, port: u16, rwo: RWOp) {
@@ -459,12 +618,14 @@ impl Piix3Lpc {
                     }
                 }
             }
-            ibmpc::PORT_POST_CODE => match rwo {
+            ibmpc::PORT_POST_CODE | ibmpc::PORT_POST_CODE_ALT => match rwo {
                 RWOp::Read(ro) => {
                     ro.write_u8(self.post_code.load(Ordering::SeqCst));
                 }
                 RWOp::Write(wo) => {
-                    self.post_code.store(wo.read_u8(), Ordering::SeqCst);
+                    let code = wo.read_u8();
+                    self.post_code.store(code, Ordering::SeqCst);
+                    self.post_codes.record(port, code);
                 }
             },
             _ => {}
@@ -565,7 +726,10 @@ const PMCFG_LEN: usize = 0x98;
 const PMBASE_DEFAULT: u16 = 0xb000;
 const PMBASE_LEN: u16 = 0x40;
 const SMBBASE_DEFAULT: u16 = 0xb100;
-// const SMBBASE_LEN: u16 = 0x40;
+
+/// SMBus host configuration: host controller enabled, signalling completion
+/// through SMI# (rather than IRQ9), which leaves the guest to poll it.
+const SMBHSTCFG_HST_EN: u8 = 0x1;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum PmCfg {
@@ -808,6 +972,7 @@ pub struct Piix3PM {
     pci_state: pci::DeviceState,
     regs: Mutex<PMRegs>,
     timer: Arc<BhyvePmTimer>,
+    smbus: Option<Arc<Piix4Smbus>>,
     power_pin: Arc<dyn IntrPin>,
     log: slog::Logger,
 }
@@ -815,6 +980,7 @@ impl Piix3PM {
     pub fn create(
         hdl: Arc<VmmHdl>,
         power_pin: Arc<dyn IntrPin>,
+        enable_smbus: bool,
         log: slog::Logger,
     ) -> Arc<Self> {
         let pci_state = pci::Builder::new(pci::Ident {
@@ -836,22 +1002,29 @@ impl Piix3PM {
 
         let regs = PMRegs::default();
         let timer = BhyvePmTimer::create(hdl, regs.pmtimer_port());
+        let smbus = enable_smbus
+            .then(|| Piix4Smbus::create(log.new(slog::o!("dev" => "smbus"))));
 
         Arc::new(Self {
             pci_state,
             regs: Mutex::new(regs),
             timer,
+            smbus,
             power_pin,
             log,
         })
     }
 
-    fn attach(self: &Arc<Self>, pio: &PioBus) {
+    fn attach(self: &Arc<Self>, pio: &PioBus) -> pio::Result<()> {
         // XXX: static registration for now
         let this = Arc::clone(&self);
         let piofn = Arc::new(move |port: u16, rwo: RWOp| this.pio_rw(port, rwo))
             as Arc<PioFn>;
-        pio.register(PMBASE_DEFAULT, PMBASE_LEN, piofn).unwrap();
+        pio.register(PMBASE_DEFAULT, PMBASE_LEN, piofn)?;
+        if let Some(smbus) = self.smbus.as_ref() {
+            smbus.attach(pio, SMBBASE_DEFAULT)?;
+        }
+        Ok(())
     }
 
     fn pio_rw(&self, _port: u16, mut rwo: RWOp) {
@@ -877,6 +1050,12 @@ impl Piix3PM {
                 // LSB hardwired to 1 to indicate PMBase in IO space
                 ro.write_u32(SMBBASE_DEFAULT as u32 | 0x1);
             }
+            PmCfg::SmbusHostCfg if self.smbus.is_some() => {
+                ro.write_u8(SMBHSTCFG_HST_EN);
+            }
+            PmCfg::SmbusRev if self.smbus.is_some() => {
+                ro.write_u8(0);
+            }
             PmCfg::DevResA => {
                 ro.write_u32(DevResA::KBC_EN_DEV11.bits());
             }
@@ -1015,7 +1194,12 @@ impl Entity for Piix3PM {
         "pci-piix3-pm"
     }
     fn child_register(&self) -> Option<Vec<inventory::ChildRegister>> {
-        Some(vec![inventory::ChildRegister::new(&self.timer, None)])
+        let mut children =
+            vec![inventory::ChildRegister::new(&self.timer, None)];
+        if let Some(smbus) = self.smbus.as_ref() {
+            children.push(inventory::ChildRegister::new(smbus, None));
+        }
+        Some(children)
     }
     fn reset(&self) {
         self.pci_state.reset(self);
@@ -1101,10 +1285,11 @@ mod migrate {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::hw::pci::conformance;
     use crate::hw::pci::device::test::*;
     use crate::hw::pci::test::Scaffold;
     use crate::hw::pci::Endpoint;
-    use crate::intr_pins::NoOpPin;
+    use crate::intr_pins::{NoOpPin, PinOp};
     use crate::vmm::VmmHdl;
 
     use slog::{Discard, Logger};
@@ -1134,7 +1319,7 @@ mod test {
         let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
         let scaffold = Scaffold::new();
 
-        let lpc = Piix3Lpc::create(IrqConfig::create(hdl));
+        let lpc = Piix3Lpc::create(IrqConfig::create(hdl), false);
         let _bus = setup_cfg(&scaffold, lpc.clone());
 
         cfg_read(lpc.as_ref() as &dyn Endpoint);
@@ -1145,7 +1330,7 @@ mod test {
         let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
         let scaffold = Scaffold::new();
 
-        let lpc = Piix3Lpc::create(IrqConfig::create(hdl));
+        let lpc = Piix3Lpc::create(IrqConfig::create(hdl), false);
         let _bus = setup_cfg(&scaffold, lpc.clone());
 
         cfg_write(lpc.as_ref() as &dyn Endpoint);
@@ -1158,7 +1343,7 @@ mod test {
         let log = Logger::root(Discard, slog::o!());
         let power_pin = Arc::new(NoOpPin {});
 
-        let pm = Piix3PM::create(hdl, power_pin, log);
+        let pm = Piix3PM::create(hdl, power_pin, true, log);
         let _bus = setup_cfg(&scaffold, pm.clone());
 
         cfg_read(pm.as_ref() as &dyn Endpoint);
@@ -1171,9 +1356,92 @@ mod test {
         let log = Logger::root(Discard, slog::o!());
         let power_pin = Arc::new(NoOpPin {});
 
-        let pm = Piix3PM::create(hdl, power_pin, log);
+        let pm = Piix3PM::create(hdl, power_pin, true, log);
         let _bus = setup_cfg(&scaffold, pm.clone());
 
         cfg_write(pm.as_ref() as &dyn Endpoint);
     }
+
+    #[test]
+    fn pci_conformance() {
+        let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
+        let log = Logger::root(Discard, slog::o!());
+        let devs: [(Arc<dyn Endpoint>, &str); 3] = [
+            (Piix4HostBridge::create(), "piix4-hb"),
+            (Piix3Lpc::create(IrqConfig::create(hdl.clone()), false), "lpc"),
+            (Piix3PM::create(hdl, Arc::new(NoOpPin {}), true, log), "pm"),
+        ];
+        for (dev, name) in devs {
+            let scaffold = Scaffold::new();
+            let _bus = setup_cfg(&scaffold, dev.clone());
+            conformance::check(dev.as_ref(), name);
+        }
+    }
+
+    #[test]
+    fn lnk_routing() {
+        let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
+        let irq_config = IrqConfig::create(hdl);
+        let level = |irq: u8| irq_config.pic.pin_states()[irq as usize].level;
+
+        // The SCI is routed from creation
+        irq_config.sci_pin.assert();
+        assert_eq!(level(SCI_IRQ), 1);
+        irq_config.sci_pin.deassert();
+        assert_eq!(level(SCI_IRQ), 0);
+
+        // An unrouted LNK pin asserts nothing, but is latched
+        let lnk = irq_config.intr_pin(0);
+        lnk.assert();
+        assert!(irq_config.pic.pin_states().iter().all(|s| s.level == 0));
+
+        // Routing carries the asserted state to the new pin...
+        irq_config.set_lnk_route(0, Some(10));
+        assert_eq!(level(10), 1);
+
+        // ...and rerouting moves it
+        irq_config.set_lnk_route(0, Some(11));
+        assert_eq!(level(10), 0);
+        assert_eq!(level(11), 1);
+
+        // An IRQ forced from elsewhere is shared with the routed pin
+        irq_config.pic.force(11, PinOp::Assert);
+        lnk.deassert();
+        assert_eq!(level(11), 1);
+        irq_config.pic.force(11, PinOp::Deassert);
+        assert_eq!(level(11), 0);
+    }
+
+    #[test]
+    fn lnk_gsi_routing() {
+        let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
+        let irq_config = IrqConfig::create(hdl);
+        let gsi_level = |gsi: u8| {
+            irq_config.ioapic.pin_states()[(gsi - IoApic::FIRST_GSI) as usize]
+                .level
+        };
+
+        // Each link drives its GSI, whether or not it is routed via PIRQ
+        let lnk = irq_config.intr_pin(2);
+        lnk.assert();
+        assert_eq!(gsi_level(18), 1);
+        irq_config.set_lnk_route(2, Some(10));
+        assert_eq!(gsi_level(18), 1);
+        lnk.deassert();
+        assert_eq!(gsi_level(18), 0);
+
+        // The SCI is not given a GSI of its own
+        irq_config.sci_pin.assert();
+        assert!(irq_config.ioapic.pin_states().iter().all(|s| s.level == 0));
+    }
+
+    #[test]
+    fn intx_routes() {
+        // The host bridge at 0:0.0 has INTA on LNKD, and each subsequent slot
+        // rotates through the links.
+        assert_eq!(intx_link(0, INTxPinID::IntA as u8), 3);
+        assert_eq!(intx_link(1, INTxPinID::IntA as u8), 0);
+        assert_eq!(intx_link(1, INTxPinID::IntB as u8), 1);
+        assert_eq!(intx_link(4, INTxPinID::IntD as u8), 2);
+    }
 }
@@ -4,13 +4,27 @@
 
 use std::sync::Arc;
 
-use crate::hw::pci::{Bdf, Endpoint};
+use crate::hw::pci::{Bdf, BusNum, DevNum, Endpoint, MultiFunc};
 use crate::intr_pins::IntrPin;
 
 pub mod i440fx;
+pub mod post_code;
 
 pub trait Chipset {
     fn pci_attach(&self, bdf: Bdf, dev: Arc<dyn Endpoint>);
+    /// Attach the functions of a multi-function `package` to slot `dev` of
+    /// `bus`, all together
+    fn pci_attach_multifunc(
+        &self,
+        bus: BusNum,
+        dev: DevNum,
+        package: MultiFunc,
+    );
+    fn pci_detach(&self, bdf: Bdf) -> Option<Arc<dyn Endpoint>>;
+    /// Look up the device (if any) attached at `bdf`
+    fn pci_device_at(&self, bdf: Bdf) -> Option<Arc<dyn Endpoint>>;
+    /// Hide (or reveal) an attached device from the guest, leaving it attached
+    fn pci_set_hidden(&self, bdf: Bdf, hidden: bool);
     fn irq_pin(&self, irq: u8) -> Option<Box<dyn IntrPin>>;
     fn power_pin(&self) -> Arc<dyn IntrPin>;
     fn reset_pin(&self) -> Arc<dyn IntrPin>;
@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Capture of POST (Power-On Self-Test) codes written by guest firmware
+//!
+//! Firmware reports its progress through boot by writing single-byte codes to
+//! IO port 0x80 (and, for some firmware, to the Bochs-style port 0xE9).  A
+//! bounded history of those writes makes it possible to tell how far a guest
+//! which "won't boot" actually got.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Number of codes retained by [`PostCodeLog::new`]
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// A single POST code written by the guest
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PostCode {
+    /// IO port to which the code was written
+    pub port: u16,
+    pub code: u8,
+    pub time: SystemTime,
+}
+
+struct Inner {
+    codes: VecDeque<PostCode>,
+    total: u64,
+}
+
+/// Ring buffer of the most recent POST codes
+pub struct PostCodeLog {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+impl PostCodeLog {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            capacity,
+            inner: Mutex::new(Inner {
+                codes: VecDeque::with_capacity(capacity),
+                total: 0,
+            }),
+        }
+    }
+
+    /// Record a code written to `port`, evicting the oldest entry if the log
+    /// is full.
+    pub fn record(&self, port: u16, code: u8) {
+        let entry = PostCode { port, code, time: SystemTime::now() };
+        let mut inner = self.inner.lock().unwrap();
+        if inner.codes.len() == self.capacity {
+            inner.codes.pop_front();
+        }
+        inner.codes.push_back(entry);
+        inner.total += 1;
+    }
+
+    /// Get the retained codes (oldest first), along with the total number of
+    /// codes recorded since the log was created or cleared.
+    pub fn snapshot(&self) -> (Vec<PostCode>, u64) {
+        let inner = self.inner.lock().unwrap();
+        (inner.codes.iter().copied().collect(), inner.total)
+    }
+
+    /// Discard all recorded codes
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.codes.clear();
+        inner.total = 0;
+    }
+}
+impl Default for PostCodeLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retains_most_recent() {
+        let log = PostCodeLog::with_capacity(4);
+        for code in 0..10u8 {
+            log.record(0x80, code);
+        }
+        let (codes, total) = log.snapshot();
+        assert_eq!(total, 10);
+        assert_eq!(
+            codes.iter().map(|c| c.code).collect::<Vec<_>>(),
+            vec![6, 7, 8, 9]
+        );
+        assert!(codes.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn clear() {
+        let log = PostCodeLog::new();
+        log.record(0x80, 0x11);
+        log.record(0xe9, 0x22);
+        let (codes, total) = log.snapshot();
+        assert_eq!(total, 2);
+        assert_eq!(codes[1].port, 0xe9);
+
+        log.clear();
+        assert_eq!(log.snapshot(), (Vec::new(), 0));
+    }
+}
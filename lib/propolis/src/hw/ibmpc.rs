@@ -15,6 +15,7 @@ pub const IRQ_COM4: u8 = 3;
 
 pub const PORT_FAST_A20: u16 = 0x92;
 pub const PORT_POST_CODE: u16 = 0x80;
+pub const PORT_POST_CODE_ALT: u16 = 0xe9;
 
 pub const LEN_FAST_A20: u16 = 1;
 pub const LEN_POST_CODE: u16 = 1;
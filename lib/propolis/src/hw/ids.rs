@@ -23,6 +23,13 @@ pub mod pci {
     /// See Virtio 1.1 Section 4.1.2 PCI Device Discovery
     pub const VENDOR_VIRTIO: u16 = 0x1AF4;
 
+    /// RedHat's PCI-SIG assigned Vendor ID for devices defined by QEMU.
+    pub const VENDOR_REDHAT: u16 = 0x1B36;
+
+    /// Vendor ID used by QEMU for its Bochs-derived display devices.  It is
+    /// not assigned by the PCI-SIG, but is what guest drivers expect.
+    pub const VENDOR_QEMU: u16 = 0x1234;
+
     /// Intel's PCI-SIG assigned Vendor ID.
     pub const VENDOR_INTEL: u16 = 0x8086;
 
@@ -37,6 +44,9 @@ pub mod pci {
     /// PCI Device ID for the PIIX4 ACPI PM Controller.
     pub const PIIX4_PM_DEV_ID: u16 = 0x7113;
 
+    /// PCI Device ID for the ICH9 AHCI Controller.
+    pub const ICH9_AHCI_DEV_ID: u16 = 0x2922;
+
     // Subsystem Device IDs (for devices emulated by propolis)
 
     /// PCI Subsystem Device ID for the PIIX4 Host Bridge as emulated by propolis.
@@ -54,6 +64,9 @@ pub mod pci {
     /// PCI Subsystem Device ID for the Propolis Virtio Block device.
     pub const VIRTIO_BLOCK_SUB_DEV_ID: u16 = 0xfffa;
 
+    /// PCI Subsystem Device ID for the ICH9 AHCI Controller as emulated by propolis.
+    pub const ICH9_AHCI_SUB_DEV_ID: u16 = 0xfff9;
+
     // Propolis-specific Device IDs
 
     /// PCI Device ID for the Propolis NVMe controller.
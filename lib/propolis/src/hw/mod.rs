@@ -2,13 +2,18 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod acpi;
+pub mod ahci;
 pub mod bhyve;
 pub mod chipset;
 pub mod ibmpc;
 pub mod ids;
 pub mod nvme;
+pub mod nvram;
 pub mod pci;
 pub mod ps2;
 pub mod qemu;
+pub mod smbus;
+pub mod tpm;
 pub mod uart;
 pub mod virtio;
@@ -530,6 +530,18 @@ pub const NVM_OPC_FLUSH: u8 = 0x00;
 pub const NVM_OPC_WRITE: u8 = 0x01;
 /// Read Command Opcode
 pub const NVM_OPC_READ: u8 = 0x02;
+/// Write Zeroes Command Opcode
+pub const NVM_OPC_WRITE_ZEROES: u8 = 0x08;
+/// Dataset Management Command Opcode
+pub const NVM_OPC_DATASET_MGMT: u8 = 0x09;
+
+/// Optional NVM Command Support: Dataset Management
+pub const ONCS_DATASET_MGMT: u16 = 1 << 2;
+/// Optional NVM Command Support: Write Zeroes
+pub const ONCS_WRITE_ZEROES: u16 = 1 << 3;
+
+/// Namespace Features: Thin Provisioning
+pub const NSFEAT_THIN_PROVISIONING: u8 = 1 << 0;
 
 // Generic Command Status values
 // See NVMe 1.0e Section 4.5.1.2.1, Figure 17 Status Code - Generic Command Status Values
@@ -599,6 +611,24 @@ pub const STS_INVALID_NS: u8 = 0xB;
 /// The command was aborted due to a protocol violation in a multi-command sequence.
 pub const STS_COMMAND_SEQ_ERR: u8 = 0xC;
 
+/// Namespace is Write Protected
+///
+/// The command is prohibited while the namespace is write protected.
+/// See NVMe 1.4 Section 4.6.1.2.1, Figure 128 Status Code - Generic Command
+/// Status Values
+pub const STS_NS_WRITE_PROTECTED: u8 = 0x20;
+
+/// LBA Out of Range
+///
+/// The command references an LBA that exceeds the size of the namespace.
+pub const STS_LBA_OUT_OF_RANGE: u8 = 0x80;
+
+/// Namespace Not Ready
+///
+/// The namespace is not currently able to process commands.  The host may
+/// retry the command, as the condition is expected to be transient.
+pub const STS_NS_NOT_READY: u8 = 0x82;
+
 // Command Specific Status values
 // See NVMe 1.0e Section 4.5.1.2.2, Figure 19 Status Code - Command Specific Status Values
 
@@ -921,7 +951,8 @@ pub struct IdentifyController {
     pub nn: u32,
     /// Option NVM Command Support (ONCS)
     ///
-    /// Bits 15:3 are reserved.
+    /// Bits 15:4 are reserved.
+    /// Bit 3 indicates Write Zeroes command support.
     /// Bit 2 indicates Dataset Management command support.
     /// Bit 1 indicates Write Uncorrectable command support.
     /// Bit 0 indicates Compare command support.
@@ -1119,8 +1150,16 @@ pub struct IdentifyNamespace {
     ///     100b-111b = Reserved
     /// See NVMe 1.0e Section 8.3 End-to-end Data Protection (Optional)
     pub dps: u8,
-    /// Reserved - Bytes 127:30
-    pub _resv1: [u8; 98],
+    /// Reserved - Bytes 98:30
+    pub _resv1: [u8; 69],
+    /// Namespace Attributes (NSATTR)
+    ///
+    /// Bits 7:1 are reserved.
+    /// Bit 0 indicates that the namespace is currently write protected.
+    /// See NVMe 1.4 Section 5.15.2, Figure 247 Identify - Identify Namespace
+    pub nsattr: u8,
+    /// Reserved - Bytes 127:100
+    pub _resv1_1: [u8; 28],
     /// LBA Formats (LBAF0-LBAF15)
     ///
     /// The list of supported LBA formats.
@@ -1145,10 +1184,12 @@ impl Default for IdentifyNamespace {
             mc: 0,
             dpc: 0,
             dps: 0,
+            nsattr: 0,
             lbaf: [LbaFormat::default(); 16],
             vs: [0; 3712],
 
-            _resv1: [0; 98],
+            _resv1: [0; 69],
+            _resv1_1: [0; 28],
             _resv2: [0; 192],
         }
     }
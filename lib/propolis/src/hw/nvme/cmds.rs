@@ -461,6 +461,10 @@ pub enum NvmCmd {
     Write(WriteCmd),
     /// Read data and metadata
     Read(ReadCmd),
+    /// Set logical blocks to zero
+    WriteZeroes(WriteZeroesCmd),
+    /// Indicate attributes (such as deallocation) for ranges of logical blocks
+    DatasetMgmt(DatasetMgmtCmd),
     /// An unknown NVM command
     Unknown(SubmissionQueueEntry),
 }
@@ -490,6 +494,23 @@ impl NvmCmd {
                 prp1: raw.prp1,
                 prp2: raw.prp2,
             }),
+            bits::NVM_OPC_WRITE_ZEROES => {
+                NvmCmd::WriteZeroes(WriteZeroesCmd {
+                    slba: (raw.cdw11 as u64) << 32 | raw.cdw10 as u64,
+                    // Convert from 0's based value
+                    nlb: raw.cdw12 as u16 as u32 + 1,
+                    deallocate: raw.cdw12 & (1 << 25) != 0,
+                })
+            }
+            bits::NVM_OPC_DATASET_MGMT => {
+                NvmCmd::DatasetMgmt(DatasetMgmtCmd {
+                    // Convert from 0's based value
+                    nr: (raw.cdw10 & 0xff) as u16 + 1,
+                    deallocate: raw.cdw11 & (1 << 2) != 0,
+                    prp1: raw.prp1,
+                    prp2: raw.prp2,
+                })
+            }
             _ => NvmCmd::Unknown(raw),
         };
         Ok(cmd)
@@ -560,6 +581,83 @@ impl ReadCmd {
     }
 }
 
+/// Write Zeroes Command Parameters
+#[derive(Debug)]
+pub struct WriteZeroesCmd {
+    /// Starting LBA (SLBA)
+    ///
+    /// 64-bit base address of the first logical block to be zeroed.
+    pub slba: u64,
+
+    /// Number of Logical Blocks (NLB)
+    ///
+    /// The number of logical blocks to be zeroed.
+    pub nlb: u32,
+
+    /// Deallocate (DEAC)
+    ///
+    /// Whether the host permits the zeroed blocks to be deallocated.
+    pub deallocate: bool,
+}
+
+/// Dataset Management Command Parameters
+#[derive(Debug)]
+pub struct DatasetMgmtCmd {
+    /// Number of Ranges (NR)
+    ///
+    /// The number of 16 byte range sets specified in the command's data.
+    pub nr: u16,
+
+    /// Attribute - Deallocate (AD)
+    ///
+    /// Whether the host indicates that the ranges may be deallocated.
+    pub deallocate: bool,
+
+    /// PRP Entry 1 (PRP1)
+    ///
+    /// The first PRP entry specifying the start of the range sets.
+    prp1: u64,
+
+    /// PRP Entry 2 (PRP2)
+    ///
+    /// If PRP1 specifies enough space, then PRP2 is reserved. Otherwise
+    /// PRP2 is another PRP entry.
+    prp2: u64,
+}
+
+impl DatasetMgmtCmd {
+    /// Size of a range set: context attributes, length, and starting LBA
+    const RANGE_SZ: usize = 16;
+
+    /// Reads the ranges specified by the command, returning the starting LBA
+    /// and number of logical blocks of each.
+    pub fn ranges(&self, mem: &MemCtx) -> Option<Vec<(u64, u32)>> {
+        let sz = self.nr as usize * Self::RANGE_SZ;
+        let mut buf = vec![0u8; sz];
+        let mut nread = 0;
+        for region in PrpIter::new(sz as u64, self.prp1, self.prp2, mem) {
+            let copied =
+                mem.read_into(region.0, &mut buf[nread..], region.1)?;
+            if copied != region.1 {
+                return None;
+            }
+            nread += copied;
+        }
+        if nread != sz {
+            return None;
+        }
+        let ranges = buf
+            .chunks_exact(Self::RANGE_SZ)
+            .map(|range| {
+                let nlb = u32::from_le_bytes(range[4..8].try_into().unwrap());
+                let slba = u64::from_le_bytes(range[8..].try_into().unwrap());
+                (slba, nlb)
+            })
+            .collect();
+        Some(ranges)
+    }
+}
+
 /// Indicates the possible states of a [`PrpIter`].
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 enum PrpNext {
@@ -870,6 +968,9 @@ impl From<block::Result> for Completion {
                 bits::StatusCodeType::CmdSpecific,
                 bits::STS_READ_CONFLICTING_ATTRS,
             ),
+            block::Result::NotReady => {
+                Completion::generic_err(bits::STS_NS_NOT_READY)
+            }
         }
     }
 }
@@ -881,7 +982,7 @@ mod test {
     use crate::common::*;
     use crate::vmm::mem::{MemCtx, PhysMap};
 
-    use super::PrpIter;
+    use super::{bits, DatasetMgmtCmd, NvmCmd, PrpIter};
 
     const VM_SIZE: usize = 256 * PAGE_SIZE;
     const PRP_PER_PAGE: usize = PAGE_SIZE / 8;
@@ -903,6 +1004,54 @@ mod test {
         PAGE_SIZE as u64 * c
     }
 
+    #[test]
+    fn test_dsm_ranges() {
+        let (_pmap, memctx) = setup();
+
+        // Range sets of context attributes, length, and starting LBA
+        let first: [u32; 4] = [0, 8, 0x100, 0];
+        let second: [u32; 4] = [0, 16, 0x2000, 0];
+        let expected = Some(vec![(0x100, 8), (0x2000, 16)]);
+
+        // Contiguous ranges within one page
+        memctx.write(GuestAddr(0x1000), &first);
+        memctx.write(GuestAddr(0x1010), &second);
+        let cmd =
+            DatasetMgmtCmd { nr: 2, deallocate: true, prp1: 0x1000, prp2: 0 };
+        assert_eq!(cmd.ranges(&memctx), expected);
+
+        // Ranges split across pages by PRP2
+        memctx.write(GuestAddr(0x2ff0), &first);
+        memctx.write(GuestAddr(0x5000), &second);
+        let cmd = DatasetMgmtCmd {
+            nr: 2,
+            deallocate: true,
+            prp1: 0x2ff0,
+            prp2: 0x5000,
+        };
+        assert_eq!(cmd.ranges(&memctx), expected);
+    }
+
+    #[test]
+    fn test_write_zeroes_deallocate() {
+        let mut raw = bits::SubmissionQueueEntry {
+            cdw0: bits::NVM_OPC_WRITE_ZEROES as u32,
+            cdw10: 0x100,
+            cdw12: 7,
+            ..Default::default()
+        };
+        let Ok(NvmCmd::WriteZeroes(cmd)) = NvmCmd::parse(raw) else {
+            panic!("expected write-zeroes command");
+        };
+        assert_eq!((cmd.slba, cmd.nlb, cmd.deallocate), (0x100, 8, false));
+
+        raw.cdw12 |= 1 << 25;
+        let Ok(NvmCmd::WriteZeroes(cmd)) = NvmCmd::parse(raw) else {
+            panic!("expected write-zeroes command");
+        };
+        assert_eq!((cmd.slba, cmd.nlb, cmd.deallocate), (0x100, 8, true));
+    }
+
     #[test]
     fn test_prp_single() {
         let (_pmap, memctx) = setup();
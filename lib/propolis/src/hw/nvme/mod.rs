@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::{BTreeMap, VecDeque};
 use std::convert::TryInto;
 use std::mem::size_of;
 use std::sync::{Arc, Mutex, MutexGuard, Weak};
@@ -161,6 +162,13 @@ struct NvmeCtrl {
     /// The list of Submission Queues handled by the controller
     sqs: [Option<Arc<SubQueue>>; MAX_NUM_QUEUES],
 
+    /// The I/O Submission Queues, in the order in which they are searched for
+    /// requests to service.  See [`NvmeCtrl::update_sq_order()`].
+    sq_order: Vec<QueueId>,
+
+    /// Position in `sq_order` at which the next search begins
+    sq_cursor: usize,
+
     /// The Identify structure returned for Identify controller commands
     ctrl_ident: IdentifyController,
 
@@ -241,6 +249,7 @@ impl NvmeCtrl {
         let cq = self.get_cq(cqid)?;
         let sq = SubQueue::new(sqid, cq, size, base, mem)?;
         self.sqs[sqid as usize] = Some(sq.clone());
+        self.update_sq_order();
         Ok(sq)
     }
 
@@ -277,9 +286,31 @@ impl NvmeCtrl {
 
         // Remove it from the authoritative list of SQs
         self.sqs[sqid as usize] = None;
+        self.update_sq_order();
         Ok(())
     }
 
+    /// Recompute the order in which I/O Submission Queues are serviced.
+    ///
+    /// A guest with many queues typically directs the interrupt vector of
+    /// each to a different vCPU.  Interleaving the queues by those
+    /// destinations means that back-to-back requests picked up by backend
+    /// workers complete to different vCPUs, rather than all of them being
+    /// drawn from (and completed through) the lowest numbered queue first.
+    /// This must be called whenever the set of queues or the programming of
+    /// their vectors changes.
+    fn update_sq_order(&mut self) {
+        let Some(hdl) = self.msix_hdl.as_ref() else {
+            self.sq_order.clear();
+            return;
+        };
+        let sqs = self.sqs.iter().skip(1).flatten();
+        self.sq_order = interleave_by_dest(
+            sqs.map(|sq| (hdl.read(sq.vector()).dest_id(), sq.id())),
+        );
+        self.sq_cursor = 0;
+    }
+
     /// Returns a reference to the [`CompQueue`] which corresponds to the given completion queue id (`cqid`).
     fn get_cq(&self, cqid: QueueId) -> Result<Arc<CompQueue>, NvmeError> {
         if (cqid as usize) >= MAX_NUM_QUEUES {
@@ -396,6 +427,7 @@ impl NvmeCtrl {
         for cq in &mut self.cqs {
             *cq = None;
         }
+        self.update_sq_order();
 
         // Clear the CC & CSTS registers
         // Sets CC.EN=0 and CSTS.RDY=0
@@ -413,14 +445,39 @@ impl NvmeCtrl {
 
     fn update_block_info(&mut self, info: block::DeviceInfo) {
         let nsze = info.total_size;
+        let discard = info.supports_discard && !info.read_only;
+        let write_zeroes = info.supports_write_zeroes && !info.read_only;
         self.ns_ident = bits::IdentifyNamespace {
-            // No thin provisioning so nsze == ncap == nuse
+            // Allocation is not tracked, so nsze == ncap == nuse even if the
+            // backend is thinly provisioned
             nsze,
             ncap: nsze,
             nuse: nsze,
+            nsfeat: if discard { bits::NSFEAT_THIN_PROVISIONING } else { 0 },
+            nsattr: info.read_only as u8,
             ..self.ns_ident
         };
         self.ns_ident.lbaf[0].lbads = info.block_size.trailing_zeros() as u8;
+
+        self.ctrl_ident.oncs = 0;
+        if discard {
+            self.ctrl_ident.oncs |= bits::ONCS_DATASET_MGMT;
+        }
+        if write_zeroes {
+            self.ctrl_ident.oncs |= bits::ONCS_WRITE_ZEROES;
+        }
+    }
+
+    /// Convert a range of logical blocks to a byte offset and length, provided
+    /// it lies within the namespace
+    fn lba_range(&self, slba: u64, nlb: u64) -> Option<(usize, usize)> {
+        match slba.checked_add(nlb) {
+            Some(end) if end <= self.ns_ident.nsze => Some((
+                self.nlb_to_size(slba as usize),
+                self.nlb_to_size(nlb as usize),
+            )),
+            _ => None,
+        }
     }
 
     fn export(&self) -> migrate::NvmeCtrlV1 {
@@ -478,6 +535,26 @@ impl NvmeCtrl {
     }
 }
 
+/// Order queue IDs, each paired with the APIC ID to which its completions are
+/// delivered, so that consecutive entries target different destinations
+/// wherever possible.
+fn interleave_by_dest(
+    queues: impl Iterator<Item = (u8, QueueId)>,
+) -> Vec<QueueId> {
+    let mut by_dest: BTreeMap<u8, VecDeque<QueueId>> = BTreeMap::new();
+    for (dest, qid) in queues {
+        by_dest.entry(dest).or_default().push_back(qid);
+    }
+    let mut order = Vec::new();
+    while !by_dest.is_empty() {
+        by_dest.retain(|_, qids| {
+            order.extend(qids.pop_front());
+            !qids.is_empty()
+        });
+    }
+    order
+}
+
 /// NVMe over PCIe
 pub struct PciNvme {
     /// NVMe Controller
@@ -584,6 +661,8 @@ impl PciNvme {
             msix_hdl: None,
             cqs: Default::default(),
             sqs: Default::default(),
+            sq_order: Vec::new(),
+            sq_cursor: 0,
             ctrl_ident,
             ns_ident,
         };
@@ -607,6 +686,24 @@ impl PciNvme {
         })
     }
 
+    /// Set the deadline for I/O requests issued to the block backend.  See
+    /// [`block::device::Tracking::set_timeout()`].
+    pub fn set_request_timeout(&self, timeout: Option<std::time::Duration>) {
+        self.block_tracking.set_timeout(timeout);
+    }
+
+    /// Set (or clear) write-protection on the namespace.
+    ///
+    /// While write-protected, Write commands are failed with the "Namespace
+    /// is Write Protected" status, and the namespace reports itself as write
+    /// protected in its Identify Namespace data.
+    pub fn set_write_protect(&self, write_protect: bool) {
+        self.block_attach.set_write_protect(write_protect);
+        if let Some(info) = self.block_attach.info() {
+            self.state.lock().unwrap().update_block_info(info);
+        }
+    }
+
     /// Service a write to the NVMe Controller Configuration from the VM
     fn ctrlr_cfg_write(&self, new: Configuration) -> Result<(), NvmeError> {
         let mut state = self.state.lock().unwrap();
@@ -972,6 +1069,10 @@ impl pci::Device for PciNvme {
     fn device_state(&self) -> &pci::DeviceState {
         &self.pci_state
     }
+
+    fn msi_update(&self, _info: pci::MsiUpdate) {
+        self.state.lock().unwrap().update_sq_order();
+    }
 }
 
 impl MigrateMulti for PciNvme {
@@ -1002,6 +1103,9 @@ impl MigrateMulti for PciNvme {
 
         MigrateMulti::import(&self.pci_state, offer, ctx)?;
 
+        // The queues were recreated before their vectors were restored
+        self.state.lock().unwrap().update_sq_order();
+
         Ok(())
     }
 }
@@ -1174,3 +1278,22 @@ lazy_static! {
         ), db_offset)
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::interleave_by_dest;
+
+    #[test]
+    fn interleave_queues() {
+        // Queues 1-3 complete to vCPU 0, 4-5 to vCPU 2, and 6 to vCPU 1
+        let queues = [(0, 1), (0, 2), (0, 3), (2, 4), (2, 5), (1, 6)];
+        assert_eq!(
+            interleave_by_dest(queues.into_iter()),
+            vec![1, 6, 4, 2, 5, 3]
+        );
+
+        // With a single destination, the queues are serviced in order
+        let queues = [(3, 1), (3, 2), (3, 3)];
+        assert_eq!(interleave_by_dest(queues.into_iter()), vec![1, 2, 3]);
+    }
+}
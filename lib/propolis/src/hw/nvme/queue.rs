@@ -453,6 +453,12 @@ impl SubQueue {
         self.id
     }
 
+    /// Returns the interrupt vector through which completions for this
+    /// Submission Queue are signaled.
+    pub(super) fn vector(&self) -> u16 {
+        self.cq.iv
+    }
+
     /// Annotate a CQE with data (ID and head index) from this SQ
     fn annotate_completion(&self, cqe: &mut CompletionQueueEntry) {
         let state = self.state.lock();
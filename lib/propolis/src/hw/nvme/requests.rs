@@ -21,6 +21,19 @@ mod probes {
     fn nvme_flush_enqueue(qid: u16, idx: u16, cid: u16) {}
     fn nvme_flush_complete(qid: u16, cid: u16, res: u8) {}
 
+    fn nvme_write_zeroes_enqueue(
+        qid: u16,
+        idx: u16,
+        cid: u16,
+        off: u64,
+        sz: u64,
+    ) {
+    }
+    fn nvme_write_zeroes_complete(qid: u16, cid: u16, res: u8) {}
+
+    fn nvme_discard_enqueue(qid: u16, idx: u16, cid: u16, nr: u16) {}
+    fn nvme_discard_complete(qid: u16, cid: u16, res: u8) {}
+
     fn nvme_raw_cmd(
         qid: u16,
         cdw0nsid: u64,
@@ -46,26 +59,44 @@ impl block::Device for PciNvme {
     }
 
     fn complete(&self, res: BlockResult, id: block::ReqId) {
-        let (op, permit) = self.block_tracking.complete(id, res);
+        let Some((op, permit)) = self.block_tracking.complete(id, res) else {
+            return;
+        };
         self.complete_req(op, res, permit);
     }
 
     fn accessor_mem(&self) -> MemAccessor {
         self.pci_state.acc_mem.child(Some("block backend".to_string()))
     }
+
+    fn io_history(&self) -> Option<block::device::IoHistory> {
+        Some(self.block_tracking.io_history())
+    }
+
+    fn reports_not_ready(&self) -> bool {
+        true
+    }
 }
 
 impl PciNvme {
     /// Pop an available I/O request off of a Submission Queue to begin
     /// processing by the underlying Block Device.
     fn next_req(&self) -> Option<(Request, Permit)> {
-        let state = self.state.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
 
         let mem = self.mem_access()?;
 
-        // Go through all the queues (skip admin as we just want I/O queues)
-        // looking for a request to service
-        for sq in state.sqs.iter().skip(1).flatten() {
+        // Go through the I/O queues, resuming after the last one to yield a
+        // request, so that work (and its completion interrupts) is spread
+        // across the guest's queues.  See `NvmeCtrl::update_sq_order()`.
+        let nqueues = state.sq_order.len();
+        for n in 0..nqueues {
+            let pos = (state.sq_cursor + n) % nqueues;
+            let Some(sq) = state.sqs[state.sq_order[pos] as usize].clone()
+            else {
+                continue;
+            };
+            state.sq_cursor = (pos + 1) % nqueues;
             while let Some((sub, permit, idx)) = sq.pop(&mem) {
                 let qid = sq.id();
                 probes::nvme_raw_cmd!(|| {
@@ -81,6 +112,16 @@ impl PciNvme {
                 let cmd = NvmCmd::parse(sub);
 
                 match cmd {
+                    Ok(
+                        NvmCmd::Write(_)
+                        | NvmCmd::WriteZeroes(_)
+                        | NvmCmd::DatasetMgmt(_),
+                    ) if self.block_attach.write_protected() => {
+                        let comp = Completion::generic_err_dnr(
+                            bits::STS_NS_WRITE_PROTECTED,
+                        );
+                        permit.complete(comp, Some(&mem));
+                    }
                     Ok(NvmCmd::Write(cmd)) => {
                         let off = state.nlb_to_size(cmd.slba as usize) as u64;
                         let size = state.nlb_to_size(cmd.nlb as usize) as u64;
@@ -117,6 +158,67 @@ impl PciNvme {
                         let req = Request::new_flush();
                         return Some((req, permit));
                     }
+                    Ok(NvmCmd::WriteZeroes(cmd)) => {
+                        let Some((off, size)) =
+                            state.lba_range(cmd.slba, cmd.nlb as u64)
+                        else {
+                            let comp = Completion::generic_err_dnr(
+                                bits::STS_LBA_OUT_OF_RANGE,
+                            );
+                            permit.complete(comp, Some(&mem));
+                            continue;
+                        };
+                        probes::nvme_write_zeroes_enqueue!(|| (
+                            qid,
+                            idx,
+                            cid,
+                            off as u64,
+                            size as u64
+                        ));
+                        let req = Request::new_write_zeroes(
+                            off,
+                            size,
+                            cmd.deallocate,
+                        );
+                        return Some((req, permit));
+                    }
+                    Ok(NvmCmd::DatasetMgmt(cmd)) if !cmd.deallocate => {
+                        // Only deallocation is acted upon.  Other attributes
+                        // are hints, which may be ignored.
+                        permit.complete(Completion::success(), Some(&mem));
+                    }
+                    Ok(NvmCmd::DatasetMgmt(cmd)) => {
+                        let ranges = cmd.ranges(&mem).map(|ranges| {
+                            ranges
+                                .into_iter()
+                                .map(|(slba, nlb)| {
+                                    state.lba_range(slba, nlb as u64)
+                                })
+                                .collect::<Option<Vec<_>>>()
+                        });
+                        let ranges = match ranges {
+                            Some(Some(ranges)) => ranges,
+                            Some(None) => {
+                                let comp = Completion::generic_err_dnr(
+                                    bits::STS_LBA_OUT_OF_RANGE,
+                                );
+                                permit.complete(comp, Some(&mem));
+                                continue;
+                            }
+                            None => {
+                                let comp = Completion::generic_err(
+                                    bits::STS_DATA_XFER_ERR,
+                                );
+                                permit.complete(comp, Some(&mem));
+                                continue;
+                            }
+                        };
+                        probes::nvme_discard_enqueue!(|| (
+                            qid, idx, cid, cmd.nr
+                        ));
+                        let req = Request::new_discard(ranges);
+                        return Some((req, permit));
+                    }
                     Ok(NvmCmd::Unknown(_)) | Err(_) => {
                         // For any other unrecognized or malformed command,
                         // just immediately complete it with an error
@@ -147,6 +249,12 @@ impl PciNvme {
             Operation::Flush => {
                 probes::nvme_flush_complete!(|| (qid, cid, resnum));
             }
+            Operation::Discard => {
+                probes::nvme_discard_complete!(|| (qid, cid, resnum));
+            }
+            Operation::WriteZeroes(..) => {
+                probes::nvme_write_zeroes_complete!(|| (qid, cid, resnum));
+            }
         }
 
         let guard = self.mem_access();
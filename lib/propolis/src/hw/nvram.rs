@@ -0,0 +1,430 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Variable store (NVRAM) of the bootrom
+//!
+//! Firmware such as OVMF keeps its variables (boot order, Secure Boot keys,
+//! and the like) in a flash region directly below its code.  That region is
+//! emulated as an 8-bit wide CFI flash part implementing the Intel command set
+//! (CFI primary command set 0x0001), much like QEMU's `pflash_cfi01`: the
+//! guest reads the store while the part is in read-array mode, and changes it
+//! only through program and block erase commands, whose progress it follows
+//! through the status register.  Firmware probing for flash (as OVMF does)
+//! therefore finds it, rather than mistaking the region for plain memory.
+//!
+//! The contents survive reinitialization of the machine, and so persist across
+//! guest reboots, and are carried in the device state of an instance being
+//! migrated.  If given a backing file, the store is also written out to it
+//! whenever the instance is reset or halted, so that its variables outlive the
+//! instance.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::migrate::*;
+use crate::mmio::{MmioBus, MmioFn};
+
+/// Size of the blocks into which the part is divided for erasure
+pub const BLOCK_SIZE: usize = 0x1000;
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_CFI_QUERY: u8 = 0x98;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_PROGRAM_ALT: u8 = 0x10;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_CONFIRM: u8 = 0xd0;
+const CMD_SUSPEND: u8 = 0xb0;
+
+/// Write state machine ready
+const STATUS_READY: u8 = 1 << 7;
+/// Erase failed (or, with `STATUS_PROGRAM_ERR`, bad command sequence)
+const STATUS_ERASE_ERR: u8 = 1 << 5;
+/// Program failed
+const STATUS_PROGRAM_ERR: u8 = 1 << 4;
+
+/// Identifiers reported in read-ID mode: Intel, and a 28F-series part
+const ID_MANUFACTURER: u8 = 0x89;
+const ID_DEVICE: u8 = 0x18;
+
+struct State {
+    data: Vec<u8>,
+    /// Last command written, which determines how reads are answered and how
+    /// the next write is taken
+    cmd: u8,
+    status: u8,
+}
+impl State {
+    fn read(&self, ro: &mut ReadOp, query: &[u8]) {
+        let off = ro.offset();
+        match self.cmd {
+            CMD_READ_ARRAY => {
+                let end = (off + ro.len()).min(self.data.len());
+                ro.write_bytes(&self.data[off.min(end)..end]);
+                ro.fill(0xff);
+            }
+            CMD_READ_ID => {
+                for addr in off..off + ro.len() {
+                    ro.write_u8(match addr & 0xff {
+                        0 => ID_MANUFACTURER,
+                        1 => ID_DEVICE,
+                        _ => 0,
+                    });
+                }
+            }
+            CMD_CFI_QUERY => {
+                for addr in off..off + ro.len() {
+                    ro.write_u8(query.get(addr & 0xff).copied().unwrap_or(0));
+                }
+            }
+            // The status register is returned at every address until another
+            // read mode is selected.
+            _ => ro.fill(self.status),
+        }
+    }
+
+    fn write(&mut self, wo: &mut WriteOp, log: &slog::Logger) {
+        let off = wo.offset();
+        match self.cmd {
+            CMD_PROGRAM | CMD_PROGRAM_ALT => {
+                let mut buf = vec![0u8; wo.len()];
+                wo.read_bytes(&mut buf);
+                match self.data.get_mut(off..off + buf.len()) {
+                    Some(dst) => {
+                        // Programming can only clear bits; setting them again
+                        // takes an erase.
+                        for (d, s) in dst.iter_mut().zip(buf) {
+                            *d &= s;
+                        }
+                    }
+                    None => self.status |= STATUS_PROGRAM_ERR,
+                }
+                self.cmd = CMD_READ_STATUS;
+            }
+            CMD_BLOCK_ERASE => {
+                if wo.read_u8() == CMD_CONFIRM && off < self.data.len() {
+                    let start = off - off % BLOCK_SIZE;
+                    let end = (start + BLOCK_SIZE).min(self.data.len());
+                    self.data[start..end].fill(0xff);
+                } else {
+                    self.status |= STATUS_ERASE_ERR | STATUS_PROGRAM_ERR;
+                }
+                self.cmd = CMD_READ_STATUS;
+            }
+            _ => match wo.read_u8() {
+                cmd @ (CMD_READ_ARRAY | CMD_READ_ID | CMD_CFI_QUERY
+                | CMD_READ_STATUS | CMD_PROGRAM | CMD_PROGRAM_ALT
+                | CMD_BLOCK_ERASE) => self.cmd = cmd,
+                CMD_CLEAR_STATUS => self.status = STATUS_READY,
+                // Operations complete as they are issued, so there is never
+                // one to suspend or resume.
+                CMD_SUSPEND | CMD_CONFIRM => {}
+                cmd => {
+                    slog::debug!(log, "unsupported flash command";
+                        "cmd" => cmd, "offset" => off);
+                    self.cmd = CMD_READ_ARRAY;
+                }
+            },
+        }
+    }
+}
+
+pub struct Nvram {
+    state: Mutex<State>,
+    start: usize,
+    query: Vec<u8>,
+    backing: Option<PathBuf>,
+    log: slog::Logger,
+}
+impl Nvram {
+    /// Creates the variable store at guest-physical address `start`, holding
+    /// `data`, which is written out to `backing` (if any) when the instance
+    /// resets or halts.  The store must be a whole number of blocks.
+    pub fn create(
+        start: usize,
+        data: Vec<u8>,
+        backing: Option<&Path>,
+        log: slog::Logger,
+    ) -> io::Result<Arc<Self>> {
+        if data.is_empty() || data.len() % BLOCK_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "NVRAM of {:#x} bytes is not a multiple of {BLOCK_SIZE:#x}",
+                    data.len()
+                ),
+            ));
+        }
+        Ok(Arc::new(Self {
+            query: cfi_query_table(data.len()),
+            state: Mutex::new(State {
+                data,
+                cmd: CMD_READ_ARRAY,
+                status: STATUS_READY,
+            }),
+            start,
+            backing: backing.map(Path::to_path_buf),
+            log,
+        }))
+    }
+
+    pub fn attach(self: &Arc<Self>, mmio: &MmioBus) {
+        let len = self.state.lock().unwrap().data.len();
+        let dev = Arc::clone(self);
+        let mmiofn = Arc::new(move |_addr: usize, rwo: RWOp| dev.mmio_rw(rwo))
+            as Arc<MmioFn>;
+        mmio.register(self.start, len, mmiofn).unwrap();
+    }
+
+    fn mmio_rw(&self, rwo: RWOp) {
+        let mut state = self.state.lock().unwrap();
+        match rwo {
+            RWOp::Read(ro) => state.read(ro, &self.query),
+            RWOp::Write(wo) => state.write(wo, &self.log),
+        }
+    }
+
+    /// Replaces the contents of the store with `data`, which must be exactly
+    /// the size of the store.
+    pub fn load(&self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if data.len() != state.data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "NVRAM contents of {:#x} bytes do not fill store of {:#x}",
+                    data.len(),
+                    state.data.len()
+                ),
+            ));
+        }
+        state.data.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Returns the current contents of the store.
+    pub fn contents(&self) -> Vec<u8> {
+        self.state.lock().unwrap().data.clone()
+    }
+
+    /// Writes the contents of the store to its backing file, if it has one.
+    /// The file is replaced as a whole, so that it is never left holding a
+    /// partially-written store.
+    pub fn persist(&self) -> io::Result<()> {
+        let Some(path) = self.backing.as_ref() else {
+            return Ok(());
+        };
+        let path = crate::harden::host_path(path)?;
+        let data = self.contents();
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".new");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    fn persist_logged(&self) {
+        if let Err(e) = self.persist() {
+            slog::error!(self.log, "failed to persist NVRAM"; "error" => %e);
+        }
+    }
+}
+impl Entity for Nvram {
+    fn type_name(&self) -> &'static str {
+        "nvram"
+    }
+    fn reset(&self) {
+        // The contents themselves are kept across the reset, which returns
+        // the part to read-array mode.
+        let mut state = self.state.lock().unwrap();
+        state.cmd = CMD_READ_ARRAY;
+        state.status = STATUS_READY;
+        drop(state);
+        self.persist_logged();
+    }
+    fn halt(&self) {
+        self.persist_logged();
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for Nvram {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        let state = self.state.lock().unwrap();
+        Ok(migrate::NvramV1 {
+            data: state.data.clone(),
+            cmd: state.cmd,
+            status: state.status,
+        }
+        .into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::NvramV1 = offer.parse()?;
+        self.load(&input.data)
+            .map_err(|e| MigrateStateError::ImportFailed(e.to_string()))?;
+        let mut state = self.state.lock().unwrap();
+        state.cmd = input.cmd;
+        state.status = input.status;
+        Ok(())
+    }
+}
+
+/// Builds the table returned in CFI query mode for a part of `len` bytes,
+/// consisting of a single region of uniform blocks.
+fn cfi_query_table(len: usize) -> Vec<u8> {
+    let blocks = (len / BLOCK_SIZE - 1) as u16;
+    let block_units = (BLOCK_SIZE / 256) as u16;
+    let mut table = vec![0u8; 0x31];
+    table[0x10..0x13].copy_from_slice(b"QRY");
+    // Primary command set: Intel/Sharp extended, with no extended table
+    table[0x13] = 0x01;
+    // Vcc minimum and maximum of 4.5V and 5.5V
+    table[0x1b] = 0x45;
+    table[0x1c] = 0x55;
+    // Typical and maximum timeouts (as powers of two) for a single program
+    // (in us) and a block erase (in ms)
+    table[0x1f] = 0x07;
+    table[0x21] = 0x0a;
+    table[0x23] = 0x04;
+    table[0x25] = 0x04;
+    table[0x27] = len.next_power_of_two().trailing_zeros() as u8;
+    // x8 interface, without multi-byte writes
+    table[0x28] = 0x00;
+    table[0x2a] = 0x00;
+    table[0x2c] = 1;
+    table[0x2d..0x2f].copy_from_slice(&blocks.to_le_bytes());
+    table[0x2f..0x31].copy_from_slice(&block_units.to_le_bytes());
+    table
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct NvramV1 {
+        pub data: Vec<u8>,
+        pub cmd: u8,
+        pub status: u8,
+    }
+    impl Schema<'_> for NvramV1 {
+        fn id() -> SchemaId {
+            ("nvram", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NVRAM_ADDR: usize = 0x8000;
+    const NVRAM_LEN: usize = 4 * BLOCK_SIZE;
+
+    fn test_nvram(backing: Option<&Path>) -> (MmioBus, Arc<Nvram>) {
+        let bus = MmioBus::new(0x10000);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let nvram =
+            Nvram::create(NVRAM_ADDR, vec![0xaa; NVRAM_LEN], backing, log)
+                .unwrap();
+        nvram.attach(&bus);
+        (bus, nvram)
+    }
+
+    fn read(bus: &MmioBus, off: usize) -> u8 {
+        bus.handle_read(NVRAM_ADDR + off, 1).unwrap() as u8
+    }
+
+    fn write(bus: &MmioBus, off: usize, val: u8) {
+        bus.handle_write(NVRAM_ADDR + off, 1, val as u64).unwrap();
+    }
+
+    #[test]
+    fn stray_writes_ignored() {
+        let (bus, nvram) = test_nvram(None);
+        // A plain store is taken as an (unsupported) command, not data
+        write(&bus, 0x10, 0x55);
+        assert_eq!(read(&bus, 0x10), 0xaa);
+        assert_eq!(nvram.contents(), vec![0xaa; NVRAM_LEN]);
+    }
+
+    #[test]
+    fn program_and_erase() {
+        let (bus, nvram) = test_nvram(None);
+
+        write(&bus, 0x10, CMD_PROGRAM);
+        write(&bus, 0x10, 0x0f);
+        assert_eq!(read(&bus, 0x10), STATUS_READY);
+        write(&bus, 0, CMD_READ_ARRAY);
+        // Programming only clears bits
+        assert_eq!(read(&bus, 0x10), 0x0a);
+
+        write(&bus, BLOCK_SIZE + 4, CMD_BLOCK_ERASE);
+        write(&bus, BLOCK_SIZE + 4, CMD_CONFIRM);
+        assert_eq!(read(&bus, 0), STATUS_READY);
+        write(&bus, 0, CMD_READ_ARRAY);
+
+        let data = nvram.contents();
+        assert_eq!(data[0x10], 0x0a);
+        assert!(data[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|b| *b == 0xff));
+        assert!(data[2 * BLOCK_SIZE..].iter().all(|b| *b == 0xaa));
+    }
+
+    #[test]
+    fn bad_erase_sequence() {
+        let (bus, nvram) = test_nvram(None);
+        write(&bus, 0, CMD_BLOCK_ERASE);
+        write(&bus, 0, CMD_READ_ARRAY);
+        assert_eq!(
+            read(&bus, 0),
+            STATUS_READY | STATUS_ERASE_ERR | STATUS_PROGRAM_ERR
+        );
+        write(&bus, 0, CMD_CLEAR_STATUS);
+        assert_eq!(read(&bus, 0), STATUS_READY);
+        assert_eq!(nvram.contents(), vec![0xaa; NVRAM_LEN]);
+    }
+
+    #[test]
+    fn cfi_query() {
+        let (bus, _nvram) = test_nvram(None);
+        write(&bus, 0x55, CMD_CFI_QUERY);
+        let qry: Vec<u8> = (0x10..0x13).map(|off| read(&bus, off)).collect();
+        assert_eq!(qry, b"QRY");
+        assert_eq!(read(&bus, 0x27), NVRAM_LEN.trailing_zeros() as u8);
+        assert_eq!(read(&bus, 0x2d), 3);
+        assert_eq!(read(&bus, 0x30), 0);
+        assert_eq!(read(&bus, 0x2f), (BLOCK_SIZE / 256) as u8);
+    }
+
+    #[test]
+    fn load_requires_exact_size() {
+        let (_bus, nvram) = test_nvram(None);
+        assert!(nvram.load(&[0; NVRAM_LEN - 1]).is_err());
+        assert!(nvram.load(&[0; NVRAM_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn persists_to_backing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vars.fd");
+        let (_bus, nvram) = test_nvram(Some(&path));
+        nvram.load(&[0xbb; NVRAM_LEN]).unwrap();
+
+        nvram.halt();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0xbb; NVRAM_LEN]);
+    }
+}
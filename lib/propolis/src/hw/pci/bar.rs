@@ -17,6 +17,9 @@ pub enum BarDefine {
     Pio(u16),
     Mmio(u32),
     Mmio64(u64),
+    /// 64-bit MMIO BAR with prefetchable contents (free of read side effects),
+    /// which may be placed in the prefetchable window of an upstream bridge
+    Mmio64Prefetch(u64),
 }
 impl BarDefine {
     /// Definition represent PIO-backed BAR
@@ -25,14 +28,23 @@ impl BarDefine {
     }
     /// Definition represent MMIO-backed (32-bit or 64-bit) BAR
     pub fn is_mmio(&self) -> bool {
-        matches!(self, BarDefine::Mmio(_) | BarDefine::Mmio64(_))
+        matches!(
+            self,
+            BarDefine::Mmio(_)
+                | BarDefine::Mmio64(_)
+                | BarDefine::Mmio64Prefetch(_)
+        )
+    }
+    /// Definition represent a prefetchable MMIO BAR
+    pub fn is_prefetchable(&self) -> bool {
+        matches!(self, BarDefine::Mmio64Prefetch(_))
     }
     /// Get the size of the BAR definition, regardless of type
     pub fn size(&self) -> u64 {
         match self {
             BarDefine::Pio(sz) => *sz as u64,
             BarDefine::Mmio(sz) => *sz as u64,
-            BarDefine::Mmio64(sz) => *sz,
+            BarDefine::Mmio64(sz) | BarDefine::Mmio64Prefetch(sz) => *sz,
         }
     }
 }
@@ -44,7 +56,12 @@ impl TryFrom<EntryKind> for BarDefine {
             EntryKind::Empty | EntryKind::Mmio64High => Err(()),
             EntryKind::Pio(sz) => Ok(BarDefine::Pio(sz)),
             EntryKind::Mmio(sz) => Ok(BarDefine::Mmio(sz)),
-            EntryKind::Mmio64(sz) => Ok(BarDefine::Mmio64(sz)),
+            EntryKind::Mmio64 { size, prefetch: false } => {
+                Ok(BarDefine::Mmio64(size))
+            }
+            EntryKind::Mmio64 { size, prefetch: true } => {
+                Ok(BarDefine::Mmio64Prefetch(size))
+            }
         }
     }
 }
@@ -54,7 +71,7 @@ enum EntryKind {
     Empty,
     Pio(u16),
     Mmio(u32),
-    Mmio64(u64),
+    Mmio64 { size: u64, prefetch: bool },
     Mmio64High,
 }
 
@@ -84,12 +101,16 @@ impl Bars {
                     this.entries[idx].kind = match def {
                         BarDefine::Pio(sz) => EntryKind::Pio(*sz),
                         BarDefine::Mmio(sz) => EntryKind::Mmio(*sz),
-                        BarDefine::Mmio64(sz) => {
+                        BarDefine::Mmio64(sz)
+                        | BarDefine::Mmio64Prefetch(sz) => {
                             // Make sure 64-bit BAR definitions are playing by
                             // the rules
                             assert!(idx < (BarN::BAR5 as usize));
                             this.entries[idx + 1].kind = EntryKind::Mmio64High;
-                            EntryKind::Mmio64(*sz)
+                            EntryKind::Mmio64 {
+                                size: *sz,
+                                prefetch: def.is_prefetchable(),
+                            }
                         }
                     }
                 }
@@ -105,11 +126,14 @@ impl Bars {
             EntryKind::Empty => 0,
             EntryKind::Pio(_) => (ent.value as u16) as u32 | bits::BAR_TYPE_IO,
             EntryKind::Mmio(_) => ent.value as u32 | bits::BAR_TYPE_MEM,
-            EntryKind::Mmio64(_) => ent.value as u32 | bits::BAR_TYPE_MEM64,
+            EntryKind::Mmio64 { prefetch, .. } => {
+                let pf = if prefetch { bits::BAR_PREFETCHABLE } else { 0 };
+                ent.value as u32 | bits::BAR_TYPE_MEM64 | pf
+            }
             EntryKind::Mmio64High => {
                 assert_ne!(idx, 0);
                 let ent = self.entries[idx - 1];
-                assert!(matches!(ent.kind, EntryKind::Mmio64(_)));
+                assert!(matches!(ent.kind, EntryKind::Mmio64 { .. }));
 
                 (ent.value >> 32) as u32
             }
@@ -136,25 +160,25 @@ impl Bars {
                 ent.value = (val & mask) as u64;
                 (BarDefine::Mmio(size), old, ent.value)
             }
-            EntryKind::Mmio64(size) => {
+            kind @ EntryKind::Mmio64 { size, .. } => {
                 let old = ent.value;
                 let mask = !(size - 1) as u32;
                 let low = val & mask;
                 ent.value = (old & (0xffffffff << 32)) | low as u64;
-                (BarDefine::Mmio64(size), old, ent.value)
+                (BarDefine::try_from(kind).unwrap(), old, ent.value)
             }
             EntryKind::Mmio64High => {
                 assert!(idx > 0);
                 let ent = &mut self.entries[idx - 1];
                 let size = match ent.kind {
-                    EntryKind::Mmio64(sz) => sz,
+                    EntryKind::Mmio64 { size, .. } => size,
                     _ => panic!(),
                 };
                 let mask = !(size - 1);
                 let old = ent.value;
                 let high = (((val as u64) << 32) & mask) & 0xffffffff00000000;
                 ent.value = high | (old & 0xffffffff);
-                (BarDefine::Mmio64(size), old, ent.value)
+                (BarDefine::try_from(ent.kind).unwrap(), old, ent.value)
             }
         };
         if old != new {
@@ -189,7 +213,7 @@ impl Bars {
             EntryKind::Mmio(_) => {
                 assert!(value <= u32::MAX as u64);
             }
-            EntryKind::Mmio64(_) => {}
+            EntryKind::Mmio64 { .. } => {}
         }
         ent.value = value;
     }
@@ -206,9 +230,13 @@ impl Bars {
                 size: sz as u64,
                 value: entry.value,
             },
-            EntryKind::Mmio64(sz) => migrate::BarEntryV1 {
-                kind: migrate::BarKindV1::Mmio64,
-                size: sz,
+            EntryKind::Mmio64 { size, prefetch } => migrate::BarEntryV1 {
+                kind: if prefetch {
+                    migrate::BarKindV1::Mmio64Prefetch
+                } else {
+                    migrate::BarKindV1::Mmio64
+                },
+                size,
                 value: entry.value,
             },
             // We encode `Mmio64High` as Empty here because it is always implied
@@ -253,7 +281,8 @@ impl Bars {
                     })?;
                     EntryKind::Mmio(sz)
                 }
-                migrate::BarKindV1::Mmio64 => {
+                kind @ (migrate::BarKindV1::Mmio64
+                | migrate::BarKindV1::Mmio64Prefetch) => {
                     // An `Mmio64` already implies the next should be `Mmio64High` so
                     // the export logic just leaves the slot empty.
                     match input_entries.next() {
@@ -275,7 +304,10 @@ impl Bars {
                                 .to_string(),
                         )),
                     }
-                    EntryKind::Mmio64(sz)
+                    EntryKind::Mmio64 {
+                        size: sz,
+                        prefetch: kind == migrate::BarKindV1::Mmio64Prefetch,
+                    }
                 }
             };
             entry.value = input_entry.value;
@@ -296,6 +328,7 @@ pub mod migrate {
         Pio,
         Mmio,
         Mmio64,
+        Mmio64Prefetch,
     }
 
     #[derive(Deserialize, Serialize)]
@@ -321,7 +354,7 @@ mod tests {
             Some(BarDefine::Mmio(0x20000)),
             Some(BarDefine::Mmio64(0x40000)),
             None, // high bits
-            Some(BarDefine::Mmio64(0x200000000)),
+            Some(BarDefine::Mmio64Prefetch(0x200000000)),
             None, // high bits
         ];
         let bars = Bars::new(&bar_defs);
@@ -345,7 +378,7 @@ mod tests {
         assert_eq!(bars.reg_read(BarN::BAR1), 0xc000000);
         assert_eq!(bars.reg_read(BarN::BAR2), 0xd000004);
         assert_eq!(bars.reg_read(BarN::BAR3), 0);
-        assert_eq!(bars.reg_read(BarN::BAR4), 0x4);
+        assert_eq!(bars.reg_read(BarN::BAR4), 0xc);
         assert_eq!(bars.reg_read(BarN::BAR5), 0x8);
     }
 
@@ -362,7 +395,7 @@ mod tests {
         assert_eq!(bars.reg_read(BarN::BAR1), 0xc000000);
         assert_eq!(bars.reg_read(BarN::BAR2), 0xd000004);
         assert_eq!(bars.reg_read(BarN::BAR3), 0);
-        assert_eq!(bars.reg_read(BarN::BAR4), 0x4);
+        assert_eq!(bars.reg_read(BarN::BAR4), 0xc);
         assert_eq!(bars.reg_read(BarN::BAR5), 0x8);
     }
 
@@ -376,7 +409,24 @@ mod tests {
         assert_eq!(bars.reg_read(BarN::BAR1), 0xfffe0000);
         assert_eq!(bars.reg_read(BarN::BAR2), 0xfffc0004);
         assert_eq!(bars.reg_read(BarN::BAR3), 0xffffffff);
-        assert_eq!(bars.reg_read(BarN::BAR4), 0x00000004);
+        assert_eq!(bars.reg_read(BarN::BAR4), 0x0000000c);
         assert_eq!(bars.reg_read(BarN::BAR5), 0xfffffffe);
     }
+
+    #[test]
+    fn prefetchable_migrate() {
+        let mut bars = setup();
+        bars.set(BarN::BAR4, 0x40_0000_0000);
+        let state = bars.export();
+        assert!(state.entries[4].kind == migrate::BarKindV1::Mmio64Prefetch);
+
+        let mut restored = setup();
+        restored.import(state).unwrap();
+        assert_eq!(
+            restored.get(BarN::BAR4),
+            Some((BarDefine::Mmio64Prefetch(0x200000000), 0x40_0000_0000))
+        );
+        assert_eq!(restored.reg_read(BarN::BAR4), 0xc);
+        assert_eq!(restored.reg_read(BarN::BAR5), 0x40);
+    }
 }
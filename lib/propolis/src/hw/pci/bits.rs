@@ -41,6 +41,7 @@ bitflags! {
 pub const BAR_TYPE_IO: u32 = 0b01;
 pub const BAR_TYPE_MEM: u32 = 0b000;
 pub const BAR_TYPE_MEM64: u32 = 0b100;
+pub const BAR_PREFETCHABLE: u32 = 0b1000;
 
 pub const CAP_ID_MSI: u8 = 0x05;
 pub const CAP_ID_VENDOR: u8 = 0x09;
@@ -53,19 +54,32 @@ pub const CLASS_DISPLAY: u8 = 3;
 pub const CLASS_MULTIMEDIA: u8 = 4;
 pub const CLASS_MEMORY: u8 = 5;
 pub const CLASS_BRIDGE: u8 = 6;
+pub const CLASS_COMMUNICATION: u8 = 7;
+pub const CLASS_SYSTEM: u8 = 8;
+pub const CLASS_INPUT: u8 = 9;
 
 // Sub-classes under CLASS_STORAGE
+pub const SUBCLASS_STORAGE_SATA: u8 = 6;
 pub const SUBCLASS_STORAGE_NVM: u8 = 8;
 
+// Sub-classes under CLASS_DISPLAY
+pub const SUBCLASS_DISPLAY_OTHER: u8 = 0x80;
+
 // Sub-classes under CLASS_BRIDGE
 pub const SUBCLASS_BRIDGE_HOST: u8 = 0;
 pub const SUBCLASS_BRIDGE_ISA: u8 = 1;
 pub const SUBCLASS_BRIDGE_OTHER: u8 = 0x80;
 
+// Sub-classes under CLASS_SYSTEM
+pub const SUBCLASS_SYSTEM_OTHER: u8 = 0x80;
+
 pub const HEADER_TYPE_DEVICE: u8 = 0b0;
 pub const HEADER_TYPE_BRIDGE: u8 = 0b1;
 pub const HEADER_TYPE_MULTIFUNC: u8 = 0b1000_0000;
 
+// Programming Interfaces for SUBCLASS_STORAGE_SATA
+pub const PROGIF_AHCI: u8 = 1;
+
 // Programming Interfaces for SUBCLASS_STORAGE_NVM
 pub const PROGIF_ENTERPRISE_NVME: u8 = 2;
 
@@ -102,3 +116,12 @@ pub const BRIDGE_SECONDARY_STATUS: u16 = 0x0000;
 /// Mask for the reserved bottom bits of the memory base and memory limit
 /// registers (SS3.2.5.8).
 pub const BRIDGE_MEMORY_REG_MASK: u16 = 0xfff0;
+
+/// Mask for the reserved bottom bits of the prefetchable memory base and limit
+/// registers, which instead report the addressing capability (SS3.2.5.9).
+pub const BRIDGE_PREF_MEMORY_REG_MASK: u16 = 0xfff0;
+
+/// Addressing capability reported by the prefetchable memory base and limit
+/// registers, indicating that the upper 32 bits of the window are held in the
+/// prefetchable base/limit upper registers (SS3.2.5.9).
+pub const BRIDGE_PREF_MEMORY_64BIT: u16 = 0x1;
@@ -14,7 +14,7 @@ use super::{bits::*, Endpoint, Ident};
 use super::{BarN, BusNum, StdCfgReg};
 use crate::common::{RWOp, ReadOp, WriteOp};
 use crate::inventory::Entity;
-use crate::migrate::Migrator;
+use crate::migrate::*;
 use crate::util::regmap::RegMap;
 
 use lazy_static::lazy_static;
@@ -216,10 +216,28 @@ impl Bridge {
                 let guard = self.inner.lock().unwrap();
                 ro.write_u16(guard.memory_limit & BRIDGE_MEMORY_REG_MASK);
             }
-            BridgeReg::PrefetchableMemoryBase
-            | BridgeReg::PrefetchableMemoryLimit => ro.write_u16(0),
-            BridgeReg::PrefetchableMemoryBaseUpper
-            | BridgeReg::PrefetchableMemoryLimitUpper => ro.write_u32(0),
+            BridgeReg::PrefetchableMemoryBase => {
+                let guard = self.inner.lock().unwrap();
+                ro.write_u16(
+                    (guard.pref_base & BRIDGE_PREF_MEMORY_REG_MASK)
+                        | BRIDGE_PREF_MEMORY_64BIT,
+                );
+            }
+            BridgeReg::PrefetchableMemoryLimit => {
+                let guard = self.inner.lock().unwrap();
+                ro.write_u16(
+                    (guard.pref_limit & BRIDGE_PREF_MEMORY_REG_MASK)
+                        | BRIDGE_PREF_MEMORY_64BIT,
+                );
+            }
+            BridgeReg::PrefetchableMemoryBaseUpper => {
+                let guard = self.inner.lock().unwrap();
+                ro.write_u32(guard.pref_base_upper);
+            }
+            BridgeReg::PrefetchableMemoryLimitUpper => {
+                let guard = self.inner.lock().unwrap();
+                ro.write_u32(guard.pref_limit_upper);
+            }
             BridgeReg::IoBaseUpper | BridgeReg::IoLimitUpper => ro.write_u16(0),
             BridgeReg::BridgeControl => ro.write_u16(0),
         }
@@ -297,15 +315,27 @@ impl Bridge {
                 let mut guard = self.inner.lock().unwrap();
                 guard.memory_limit = wo.read_u16();
             }
+            BridgeReg::PrefetchableMemoryBase => {
+                let mut guard = self.inner.lock().unwrap();
+                guard.pref_base = wo.read_u16();
+            }
+            BridgeReg::PrefetchableMemoryLimit => {
+                let mut guard = self.inner.lock().unwrap();
+                guard.pref_limit = wo.read_u16();
+            }
+            BridgeReg::PrefetchableMemoryBaseUpper => {
+                let mut guard = self.inner.lock().unwrap();
+                guard.pref_base_upper = wo.read_u32();
+            }
+            BridgeReg::PrefetchableMemoryLimitUpper => {
+                let mut guard = self.inner.lock().unwrap();
+                guard.pref_limit_upper = wo.read_u32();
+            }
 
             // Read-only bridge registers.
             BridgeReg::SecondaryLatencyTimer => {}
             BridgeReg::IoBase | BridgeReg::IoLimit => {}
             BridgeReg::SecondaryStatus => {}
-            BridgeReg::PrefetchableMemoryBase
-            | BridgeReg::PrefetchableMemoryLimit => {}
-            BridgeReg::PrefetchableMemoryBaseUpper
-            | BridgeReg::PrefetchableMemoryLimitUpper => {}
             BridgeReg::IoBaseUpper | BridgeReg::IoLimitUpper => {}
             BridgeReg::BridgeControl => {}
         }
@@ -349,10 +379,60 @@ impl Entity for Bridge {
         self.inner.lock().unwrap().reset();
     }
     fn migrate(&self) -> Migrator {
-        // TODO Should be migratable in theory: copy all the register state,
-        // then enumerate bridges on the target and reconstruct the routing
-        // table from their bus registers' values.
-        Migrator::NonMigratable
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for Bridge {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(migrate::PciBridgeV2 {
+            reg_command: inner.reg_command.bits(),
+            primary_bus: inner.primary_bus.get(),
+            secondary_bus: inner.secondary_bus.get(),
+            subordinate_bus: inner.subordinate_bus.get(),
+            memory_base: inner.memory_base,
+            memory_limit: inner.memory_limit,
+            pref_base: inner.pref_base,
+            pref_limit: inner.pref_limit,
+            pref_base_upper: inner.pref_base_upper,
+            pref_limit_upper: inner.pref_limit_upper,
+        }
+        .into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::PciBridgeV2 = offer.parse()?;
+        let bus = |n: u8| {
+            BusNum::new(n).ok_or_else(|| {
+                MigrateStateError::ImportFailed(format!(
+                    "PCI bridge: invalid bus number {}",
+                    n
+                ))
+            })
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.reg_command = RegCmd::from_bits_truncate(data.reg_command);
+        inner.primary_bus = bus(data.primary_bus)?;
+        inner.subordinate_bus = bus(data.subordinate_bus)?;
+        inner.memory_base = data.memory_base;
+        inner.memory_limit = data.memory_limit;
+        inner.pref_base = data.pref_base;
+        inner.pref_limit = data.pref_limit;
+        inner.pref_base_upper = data.pref_base_upper;
+        inner.pref_limit_upper = data.pref_limit_upper;
+
+        // Routing to the downstream bus is reconstructed from the secondary
+        // bus number, as it would be had the guest programmed it.
+        inner.set_secondary_bus(bus(data.secondary_bus)?);
+        Ok(())
     }
 }
 
@@ -370,6 +450,14 @@ struct Inner {
     subordinate_bus: BusNum,
     memory_base: u16,
     memory_limit: u16,
+
+    // The prefetchable memory window may be placed anywhere in the 64-bit
+    // address space, so that large prefetchable BARs of downstream devices can
+    // be mapped above 4GiB.
+    pref_base: u16,
+    pref_limit: u16,
+    pref_base_upper: u32,
+    pref_limit_upper: u32,
 }
 
 impl Inner {
@@ -384,6 +472,10 @@ impl Inner {
             subordinate_bus: BusNum::new(0).unwrap(),
             memory_base: 0,
             memory_limit: 0,
+            pref_base: 0,
+            pref_limit: 0,
+            pref_base_upper: 0,
+            pref_limit_upper: 0,
         }
     }
 
@@ -411,6 +503,35 @@ impl Inner {
         self.subordinate_bus = BusNum::new(0).unwrap();
         self.memory_base = 0;
         self.memory_limit = 0;
+        self.pref_base = 0;
+        self.pref_limit = 0;
+        self.pref_base_upper = 0;
+        self.pref_limit_upper = 0;
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct PciBridgeV2 {
+        pub reg_command: u16,
+        pub primary_bus: u8,
+        pub secondary_bus: u8,
+        pub subordinate_bus: u8,
+        pub memory_base: u16,
+        pub memory_limit: u16,
+        pub pref_base: u16,
+        pub pref_limit: u16,
+        pub pref_base_upper: u32,
+        pub pref_limit_upper: u32,
+    }
+    impl Schema<'_> for PciBridgeV2 {
+        fn id() -> SchemaId {
+            ("pci-bridge", 2)
+        }
     }
 }
 
@@ -427,6 +548,10 @@ mod test {
     const OFFSET_DEVICE_ID: usize = 0x02;
     const OFFSET_HEADER_TYPE: usize = 0x0E;
     const OFFSET_SECONDARY_BUS: usize = 0x19;
+    const OFFSET_PREF_MEMORY_BASE: usize = 0x24;
+    const OFFSET_PREF_MEMORY_LIMIT: usize = 0x26;
+    const OFFSET_PREF_BASE_UPPER: usize = 0x28;
+    const OFFSET_PREF_LIMIT_UPPER: usize = 0x2C;
 
     struct Env {
         _instance: Instance,
@@ -563,4 +688,52 @@ mod test {
         env.write_secondary_bus(Bdf::new(0, 1, 0).unwrap(), 0);
         assert_eq!(env.read_secondary_bus(Bdf::new(82, 1, 0).unwrap()), 0);
     }
+
+    fn read_cfg<const N: usize>(bridge: &Bridge, offset: usize) -> [u8; N] {
+        let mut buf = [0u8; N];
+        let mut ro = ReadOp::from_buf(offset, &mut buf);
+        Endpoint::cfg_rw(bridge, RWOp::Read(&mut ro));
+        buf
+    }
+
+    fn write_cfg(bridge: &Bridge, offset: usize, val: &[u8]) {
+        let mut buf = val.to_vec();
+        let mut wo = WriteOp::from_buf(offset, &mut buf);
+        Endpoint::cfg_rw(bridge, RWOp::Write(&mut wo));
+    }
+
+    #[test]
+    fn prefetchable_window() {
+        let env = Env::new(None);
+        let bridge = env.make_bridge();
+
+        // The window reports 64-bit addressing, even when unprogrammed
+        let base =
+            u16::from_le_bytes(read_cfg(&bridge, OFFSET_PREF_MEMORY_BASE));
+        assert_eq!(base, BRIDGE_PREF_MEMORY_64BIT);
+
+        // Place a 16GiB window at 64GiB: 0x10_0000_0000..=0x13_ffff_ffff
+        write_cfg(&bridge, OFFSET_PREF_MEMORY_BASE, &0x0000u16.to_le_bytes());
+        write_cfg(&bridge, OFFSET_PREF_MEMORY_LIMIT, &0xfff0u16.to_le_bytes());
+        write_cfg(&bridge, OFFSET_PREF_BASE_UPPER, &0x10u32.to_le_bytes());
+        write_cfg(&bridge, OFFSET_PREF_LIMIT_UPPER, &0x13u32.to_le_bytes());
+
+        let limit =
+            u16::from_le_bytes(read_cfg(&bridge, OFFSET_PREF_MEMORY_LIMIT));
+        assert_eq!(limit, 0xfff0 | BRIDGE_PREF_MEMORY_64BIT);
+        let base_hi =
+            u32::from_le_bytes(read_cfg(&bridge, OFFSET_PREF_BASE_UPPER));
+        let limit_hi =
+            u32::from_le_bytes(read_cfg(&bridge, OFFSET_PREF_LIMIT_UPPER));
+        assert_eq!((base_hi, limit_hi), (0x10, 0x13));
+
+        // The whole window can be read as a single dword
+        let window: [u8; 4] = read_cfg(&bridge, OFFSET_PREF_MEMORY_BASE);
+        assert_eq!(u32::from_le_bytes(window), 0xfff1_0001);
+
+        bridge.reset();
+        let base_hi =
+            u32::from_le_bytes(read_cfg(&bridge, OFFSET_PREF_BASE_UPPER));
+        assert_eq!(base_hi, 0);
+    }
 }
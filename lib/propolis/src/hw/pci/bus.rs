@@ -2,19 +2,25 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, Weak};
+use std::sync::{Arc, Weak};
 
 use super::bar::BarDefine;
-use super::{BarN, BusLocation, Endpoint, LintrCfg};
+use super::{BarN, BusLocation, DevNum, Endpoint, LintrCfg, MultiFunc};
 use crate::accessors::*;
 use crate::common::RWOp;
 use crate::mmio::{MmioBus, MmioFn};
-use crate::pio::{PioBus, PioFn};
+use crate::pio::{DoorbellFn, PioBus, PioFn};
+use crate::util::lockorder::{Rank, RwLock};
 
+/// A single PCI bus.
+///
+/// Configuration space accesses, which look up the device at a location, far
+/// outnumber changes to the bus (device attachment and BAR placement), so the
+/// bus state is held under a reader-writer lock.
 pub struct Bus {
-    inner: Arc<Mutex<Inner>>,
+    inner: Arc<RwLock<Inner>>,
 }
 
 impl Bus {
@@ -25,9 +31,10 @@ impl Bus {
         acc_msi: MsiAccessor,
     ) -> Self {
         Self {
-            inner: Arc::new(Mutex::new(Inner::new(
-                pio, mmio, acc_mem, acc_msi,
-            ))),
+            inner: Arc::new(RwLock::new(
+                Rank::PciBus,
+                Inner::new(pio, mmio, acc_mem, acc_msi),
+            )),
         }
     }
 
@@ -37,7 +44,7 @@ impl Bus {
         dev: Arc<dyn Endpoint>,
         lintr_cfg: Option<LintrCfg>,
     ) {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = self.inner.write().unwrap();
         let (slot_state, acc_msi, acc_mem) =
             inner.attach(location, dev.clone());
 
@@ -52,17 +59,98 @@ impl Bus {
         dev.attach(attached);
     }
 
+    /// Attach every function of a multi-function `package` to the slot `dev`,
+    /// with `lintr_cfg` providing the legacy interrupt configuration for each.
+    ///
+    /// The functions are attached together, with the slot marked as
+    /// multi-function beforehand, so the guest observes either all of the
+    /// package or none of it.  If any function the package would occupy is
+    /// already attached, nothing is attached, and the location of that
+    /// function is returned as an error.
+    pub fn attach_multifunc(
+        &self,
+        dev: DevNum,
+        package: MultiFunc,
+        lintr_cfg: impl Fn(BusLocation) -> Option<LintrCfg>,
+    ) -> Result<(), BusLocation> {
+        let mut inner = self.inner.write().unwrap();
+        if let Some((func, _)) = package.funcs().find(|(func, _)| {
+            inner.attached_at(BusLocation { dev, func: *func }).is_some()
+        }) {
+            return Err(BusLocation { dev, func });
+        }
+
+        inner.slots[dev.get() as usize]
+            .state
+            .is_multifunc
+            .store(true, Ordering::Release);
+        for (func, ep) in package.into_funcs() {
+            let location = BusLocation { dev, func };
+            let (slot_state, acc_msi, acc_mem) =
+                inner.attach(location, ep.clone());
+
+            let attached = Attachment {
+                inner: Arc::downgrade(&self.inner),
+                location,
+                lintr_cfg: lintr_cfg(location),
+                slot_state,
+                acc_msi,
+                acc_mem,
+            };
+            ep.attach(attached);
+        }
+        Ok(())
+    }
+
+    /// Remove the device at `location` from the bus, returning it (if any).
+    pub fn detach(&self, location: BusLocation) -> Option<Arc<dyn Endpoint>> {
+        let dev = self.inner.read().unwrap().attached_at(location)?;
+
+        // The device will unregister its own BARs as it is quiesced, so the
+        // bus lock must not be held when calling into it.
+        dev.detach();
+
+        let mut inner = self.inner.write().unwrap();
+        inner.detach(location)
+    }
+
+    /// Hide (or reveal) the device at `location` from the guest.
+    ///
+    /// A hidden device remains attached, but it is absent from the perspective
+    /// of the guest: configuration space accesses do not reach it, and it
+    /// cannot claim address space.  Any BARs it has registered are released
+    /// as it is hidden.
+    pub fn set_hidden(&self, location: BusLocation, hidden: bool) {
+        let mut inner = self.inner.write().unwrap();
+        inner.set_hidden(location, hidden);
+    }
+
     pub fn device_at(
         &self,
         location: BusLocation,
     ) -> Option<Arc<dyn Endpoint>> {
-        let inner = self.inner.lock().unwrap();
+        let inner = self.inner.read().unwrap();
         inner.device_at(location)
     }
+
+    /// Returns every device visible on the bus, ordered by location.
+    pub fn devices(&self) -> Vec<(BusLocation, Arc<dyn Endpoint>)> {
+        let inner = self.inner.read().unwrap();
+        let mut devs = Vec::new();
+        for dev in 0..SLOTS_PER_BUS {
+            for func in 0..FUNCS_PER_SLOT {
+                let loc = BusLocation::new(dev as u8, func as u8).unwrap();
+                if let Some(ep) = inner.device_at(loc) {
+                    devs.push((loc, ep));
+                }
+            }
+        }
+        devs
+    }
 }
 
 pub struct Attachment {
-    inner: Weak<Mutex<Inner>>,
+    inner: Weak<RwLock<Inner>>,
     location: BusLocation,
     lintr_cfg: Option<LintrCfg>,
     slot_state: Arc<SlotState>,
@@ -72,13 +160,13 @@ pub struct Attachment {
 impl Attachment {
     pub fn bar_register(&self, n: BarN, def: BarDefine, addr: u64) {
         if let Some(inner) = self.inner.upgrade() {
-            let mut guard = inner.lock().unwrap();
+            let mut guard = inner.write().unwrap();
             guard.bar_register(self.location, n, def, addr);
         }
     }
     pub fn bar_unregister(&self, n: BarN) {
         if let Some(inner) = self.inner.upgrade() {
-            let mut guard = inner.lock().unwrap();
+            let mut guard = inner.write().unwrap();
             guard.bar_unregister(self.location, n);
         }
     }
@@ -125,16 +213,34 @@ impl Slot {
         }
         self.state.clone()
     }
+    fn detach(&mut self, location: BusLocation) -> Option<Arc<dyn Endpoint>> {
+        self.funcs[location.func.get() as usize].take()
+    }
 }
 
 struct BarState {
     def: BarDefine,
     value: u64,
     live: bool,
+    doorbell: Option<DoorbellState>,
+    /// The endpoint which backs the (MMIO) BAR with memory mapped directly
+    /// into the guest, rather than through a handler on the MMIO bus
+    direct: Option<Arc<dyn Endpoint>>,
+}
+
+/// Placement of the doorbell within a port IO BAR
+struct DoorbellState {
+    port: u16,
+    /// Writes to the doorbell are serviced by the endpoint itself, rather than
+    /// by a doorbell registered on the PIO bus.
+    external: bool,
+    dev: Arc<dyn Endpoint>,
 }
 
 struct Inner {
     slots: [Slot; SLOTS_PER_BUS],
+    /// Locations of attached devices which are hidden from the guest
+    hidden: BTreeSet<BusLocation>,
     bar_state: BTreeMap<(BusLocation, BarN), BarState>,
     bus_pio: Weak<PioBus>,
     bus_mmio: Weak<MmioBus>,
@@ -151,6 +257,7 @@ impl Inner {
     ) -> Self {
         Self {
             slots: Default::default(),
+            hidden: BTreeSet::new(),
             bar_state: BTreeMap::new(),
             bus_pio: Arc::downgrade(pio),
             bus_mmio: Arc::downgrade(mmio),
@@ -160,12 +267,42 @@ impl Inner {
         }
     }
     fn device_at(&self, location: BusLocation) -> Option<Arc<dyn Endpoint>> {
+        if self.hidden.contains(&location) {
+            return None;
+        }
+        self.attached_at(location)
+    }
+    /// Like [`Inner::device_at()`], but including hidden devices
+    fn attached_at(&self, location: BusLocation) -> Option<Arc<dyn Endpoint>> {
         let res = self.slots[location.dev.get() as usize].funcs
             [location.func.get() as usize]
             .as_ref()
             .map(Arc::clone);
         res
     }
+    fn set_hidden(&mut self, location: BusLocation, hidden: bool) {
+        if !hidden {
+            self.hidden.remove(&location);
+            return;
+        }
+        if self.attached_at(location).is_none() {
+            return;
+        }
+        self.hidden.insert(location);
+        self.bars_release(location);
+    }
+    /// Unregister all BARs held by the device at `location`
+    fn bars_release(&mut self, location: BusLocation) {
+        let bars: Vec<BarN> = self
+            .bar_state
+            .keys()
+            .filter(|(loc, _)| *loc == location)
+            .map(|(_, n)| *n)
+            .collect();
+        for n in bars {
+            self.bar_unregister(location, n);
+        }
+    }
     fn attach(
         &mut self,
         location: BusLocation,
@@ -185,6 +322,14 @@ impl Inner {
             self.acc_mem.child(Some(acc_name)),
         )
     }
+    fn detach(&mut self, location: BusLocation) -> Option<Arc<dyn Endpoint>> {
+        let dev = self.slots[location.dev.get() as usize].detach(location)?;
+        self.hidden.remove(&location);
+
+        // Clean up any BARs which the device failed to unregister itself
+        self.bars_release(location);
+        Some(dev)
+    }
     fn bar_register(
         &mut self,
         location: BusLocation,
@@ -192,45 +337,84 @@ impl Inner {
         def: BarDefine,
         value: u64,
     ) {
-        let dev = self.device_at(location).unwrap();
+        // A device which has been detached (or hidden) may still hold its
+        // attachment, but must not be able to claim address space.
+        let Some(dev) = self.device_at(location) else {
+            return;
+        };
 
+        let mut doorbell = None;
+        let mut direct = None;
         let live = match def {
             BarDefine::Pio(sz) => {
                 if let Some(pio) = self.bus_pio.upgrade() {
+                    let bar_dev = dev.clone();
                     let func = Arc::new(move |_port: u16, rwo: RWOp| {
-                        dev.bar_rw(n, rwo)
+                        bar_dev.bar_rw(n, rwo)
                     }) as Arc<PioFn>;
-                    pio.register(value as u16, sz, func).is_ok()
-                } else {
-                    false
-                }
-            }
-            BarDefine::Mmio(sz) => {
-                if let Some(mmio) = self.bus_mmio.upgrade() {
-                    let func = Arc::new(move |_addr: usize, rwo: RWOp| {
-                        dev.bar_rw(n, rwo)
-                    }) as Arc<MmioFn>;
-                    mmio.register(value as usize, sz as usize, func).is_ok()
+                    let live = pio.register(value as u16, sz, func).is_ok();
+                    if live {
+                        doorbell =
+                            Self::doorbell_register(&pio, &dev, n, value);
+                    }
+                    live
                 } else {
                     false
                 }
             }
-            BarDefine::Mmio64(sz) => {
-                if let Some(mmio) = self.bus_mmio.upgrade() {
+            BarDefine::Mmio(_)
+            | BarDefine::Mmio64(_)
+            | BarDefine::Mmio64Prefetch(_) => {
+                if dev.bar_placed(n, Some(value)) {
+                    direct = Some(dev);
+                    true
+                } else if let Some(mmio) = self.bus_mmio.upgrade() {
                     let func = Arc::new(move |_addr: usize, rwo: RWOp| {
                         dev.bar_rw(n, rwo)
                     }) as Arc<MmioFn>;
-                    mmio.register(value as usize, sz as usize, func).is_ok()
+                    mmio.register(value as usize, def.size() as usize, func)
+                        .is_ok()
                 } else {
                     false
                 }
             }
         };
-        let _old =
-            self.bar_state.insert((location, n), BarState { def, value, live });
+        let _old = self.bar_state.insert(
+            (location, n),
+            BarState { def, value, live, doorbell, direct },
+        );
         // XXX be strict for now
         assert!(_old.is_none());
     }
+    /// Place the doorbell (if any) of port IO BAR `n`, now located at `value`
+    fn doorbell_register(
+        pio: &PioBus,
+        dev: &Arc<dyn Endpoint>,
+        n: BarN,
+        value: u64,
+    ) -> Option<DoorbellState> {
+        let offset = dev.bar_doorbell(n)?;
+        let port = (value as u16).checked_add(offset)?;
+
+        let external = dev.doorbell_placed(n, Some(port));
+        if !external {
+            let db_dev = dev.clone();
+            let func = Arc::new(move |val: u16| db_dev.doorbell_ring(n, val))
+                as Arc<DoorbellFn>;
+            if pio.register_doorbell(port, func).is_err() {
+                // Writes to the port will still reach the BAR handler
+                return None;
+            }
+        }
+        Some(DoorbellState { port, external, dev: dev.clone() })
+    }
+    fn doorbell_unregister(pio: &PioBus, n: BarN, db: DoorbellState) {
+        if db.external {
+            db.dev.doorbell_placed(n, None);
+        } else {
+            pio.unregister_doorbell(db.port).unwrap();
+        }
+    }
     fn bar_unregister(&mut self, location: BusLocation, n: BarN) {
         if let Some(state) = self.bar_state.remove(&(location, n)) {
             if !state.live {
@@ -241,11 +425,18 @@ impl Inner {
             match state.def {
                 BarDefine::Pio(_) => {
                     if let Some(pio) = self.bus_pio.upgrade() {
+                        if let Some(db) = state.doorbell {
+                            Self::doorbell_unregister(&pio, n, db);
+                        }
                         pio.unregister(state.value as u16).unwrap();
                     }
                 }
-                BarDefine::Mmio(_) | BarDefine::Mmio64(_) => {
-                    if let Some(mmio) = self.bus_mmio.upgrade() {
+                BarDefine::Mmio(_)
+                | BarDefine::Mmio64(_)
+                | BarDefine::Mmio64Prefetch(_) => {
+                    if let Some(dev) = state.direct {
+                        dev.bar_placed(n, None);
+                    } else if let Some(mmio) = self.bus_mmio.upgrade() {
                         mmio.unregister(state.value as usize).unwrap();
                     }
                 }
@@ -258,6 +449,7 @@ impl Inner {
 mod test {
     use super::*;
     use crate::hw::pci::test::Scaffold;
+    use std::sync::Mutex;
 
     #[derive(Default)]
     struct TestDev {
@@ -327,4 +519,170 @@ mod test {
         assert_eq!(same_slot.check_multifunc(), Some(true));
         assert_eq!(other_slot.check_multifunc(), Some(false));
     }
+
+    #[test]
+    fn attach_multifunc_package() {
+        let scaffold = Scaffold::new();
+        let bus = scaffold.create_bus();
+        let dev = DevNum::new(3).unwrap();
+
+        let func0 = Arc::new(TestDev::default());
+        let func2 = Arc::new(TestDev::default());
+        let package = MultiFunc::new(Arc::clone(&func0) as Arc<dyn Endpoint>)
+            .add_func(2, Arc::clone(&func2) as Arc<dyn Endpoint>);
+        bus.attach_multifunc(dev, package, |_| None).unwrap();
+        assert_eq!(func0.check_multifunc(), Some(true));
+        assert_eq!(func2.check_multifunc(), Some(true));
+        assert!(bus.device_at(BusLocation::new(3, 2).unwrap()).is_some());
+
+        // A package overlapping an occupied function is refused in full
+        let other_dev = DevNum::new(4).unwrap();
+        let occupant = Arc::new(TestDev::default());
+        bus.attach(
+            BusLocation::new(4, 1).unwrap(),
+            Arc::clone(&occupant) as Arc<dyn Endpoint>,
+            None,
+        );
+        let first = Arc::new(TestDev::default());
+        let package = MultiFunc::new(Arc::clone(&first) as Arc<dyn Endpoint>)
+            .add_func(1, Arc::new(TestDev::default()) as Arc<dyn Endpoint>);
+        assert_eq!(
+            bus.attach_multifunc(other_dev, package, |_| None),
+            Err(BusLocation::new(4, 1).unwrap())
+        );
+        assert_eq!(first.check_multifunc(), None);
+        assert!(bus.device_at(BusLocation::new(4, 0).unwrap()).is_none());
+        assert_eq!(occupant.check_multifunc(), Some(false));
+    }
+
+    #[derive(Default)]
+    struct DoorbellDev {
+        inner: Mutex<Option<Attachment>>,
+        rung: Mutex<Vec<u16>>,
+        bar_writes: Mutex<usize>,
+    }
+    impl Endpoint for DoorbellDev {
+        fn attach(&self, attachment: Attachment) {
+            self.inner.lock().unwrap().replace(attachment);
+        }
+        fn cfg_rw(&self, _op: RWOp) {}
+        fn bar_rw(&self, _bar: BarN, _rwo: RWOp) {
+            *self.bar_writes.lock().unwrap() += 1;
+        }
+        fn bar_doorbell(&self, bar: BarN) -> Option<u16> {
+            (bar == BarN::BAR0).then_some(0x10)
+        }
+        fn doorbell_ring(&self, _bar: BarN, val: u16) {
+            self.rung.lock().unwrap().push(val);
+        }
+    }
+
+    #[test]
+    fn doorbell_follows_bar() {
+        let scaffold = Scaffold::new();
+        let bus = scaffold.create_bus();
+        let pio = &scaffold.bus_pio;
+
+        let dev = Arc::new(DoorbellDev::default());
+        bus.attach(
+            BusLocation::new(0, 0).unwrap(),
+            Arc::clone(&dev) as Arc<dyn Endpoint>,
+            None,
+        );
+        let attach = dev.inner.lock().unwrap().take().unwrap();
+
+        attach.bar_register(BarN::BAR0, BarDefine::Pio(0x20), 0x1000);
+        pio.handle_out(0x1010, 2, 3).unwrap();
+        // Accesses of other sizes (or to other ports) go through the BAR
+        pio.handle_out(0x1010, 1, 4).unwrap();
+        pio.handle_out(0x1012, 2, 5).unwrap();
+        assert_eq!(*dev.rung.lock().unwrap(), vec![3]);
+        assert_eq!(*dev.bar_writes.lock().unwrap(), 2);
+
+        attach.bar_unregister(BarN::BAR0);
+        assert!(pio.handle_out(0x1010, 2, 6).is_err());
+
+        attach.bar_register(BarN::BAR0, BarDefine::Pio(0x20), 0x2000);
+        pio.handle_out(0x2010, 2, 7).unwrap();
+        assert_eq!(*dev.rung.lock().unwrap(), vec![3, 7]);
+    }
+
+    #[derive(Default)]
+    struct DirectDev {
+        inner: Mutex<Option<Attachment>>,
+        placed: Mutex<Vec<Option<u64>>>,
+    }
+    impl Endpoint for DirectDev {
+        fn attach(&self, attachment: Attachment) {
+            self.inner.lock().unwrap().replace(attachment);
+        }
+        fn cfg_rw(&self, _op: RWOp) {}
+        fn bar_rw(&self, _bar: BarN, _rwo: RWOp) {}
+        fn bar_placed(&self, bar: BarN, addr: Option<u64>) -> bool {
+            self.placed.lock().unwrap().push(addr);
+            bar == BarN::BAR0
+        }
+    }
+
+    #[test]
+    fn direct_bar_bypasses_mmio() {
+        let scaffold = Scaffold::new();
+        let bus = scaffold.create_bus();
+        let mmio = &scaffold.bus_mmio;
+
+        let dev = Arc::new(DirectDev::default());
+        bus.attach(
+            BusLocation::new(0, 0).unwrap(),
+            Arc::clone(&dev) as Arc<dyn Endpoint>,
+            None,
+        );
+        let attach = dev.inner.lock().unwrap().take().unwrap();
+
+        attach.bar_register(BarN::BAR0, BarDefine::Mmio(0x1000), 0x1000_0000);
+        attach.bar_register(BarN::BAR1, BarDefine::Mmio(0x1000), 0x2000_0000);
+        assert!(mmio.handle_read(0x1000_0000, 4).is_err());
+        assert!(mmio.handle_read(0x2000_0000, 4).is_ok());
+
+        attach.bar_unregister(BarN::BAR0);
+        attach.bar_unregister(BarN::BAR1);
+        assert_eq!(
+            *dev.placed.lock().unwrap(),
+            vec![Some(0x1000_0000), Some(0x2000_0000), None]
+        );
+    }
+
+    #[test]
+    fn hidden_device() {
+        let scaffold = Scaffold::new();
+        let bus = scaffold.create_bus();
+        let pio = &scaffold.bus_pio;
+        let location = BusLocation::new(3, 0).unwrap();
+
+        let dev = Arc::new(DoorbellDev::default());
+        bus.attach(location, Arc::clone(&dev) as Arc<dyn Endpoint>, None);
+        let attach = dev.inner.lock().unwrap().take().unwrap();
+        attach.bar_register(BarN::BAR1, BarDefine::Pio(0x20), 0x1000);
+        pio.handle_out(0x1000, 1, 1).unwrap();
+
+        // Hiding the device releases its BAR, and prevents it from claiming
+        // another one.
+        bus.set_hidden(location, true);
+        assert!(bus.device_at(location).is_none());
+        assert!(bus.devices().is_empty());
+        assert!(pio.handle_out(0x1000, 1, 2).is_err());
+        attach.bar_unregister(BarN::BAR1);
+        attach.bar_register(BarN::BAR1, BarDefine::Pio(0x20), 0x1000);
+        assert!(pio.handle_out(0x1000, 1, 3).is_err());
+
+        bus.set_hidden(location, false);
+        assert!(bus.device_at(location).is_some());
+        attach.bar_register(BarN::BAR1, BarDefine::Pio(0x20), 0x1000);
+        pio.handle_out(0x1000, 1, 4).unwrap();
+        assert_eq!(*dev.bar_writes.lock().unwrap(), 2);
+
+        // Hidden devices can still be detached
+        bus.set_hidden(location, true);
+        assert!(bus.detach(location).is_some());
+        assert!(bus.device_at(location).is_none());
+    }
 }
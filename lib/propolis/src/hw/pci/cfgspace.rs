@@ -115,6 +115,13 @@ impl CfgBuilder {
         self.cap_next_alloc = end;
     }
 
+    /// Adds a new capability, as with [`Self::add_capability`], whose body
+    /// consists of the fixed contents in `data`.
+    pub fn add_fixed_capability(&mut self, id: u8, data: &[u8]) {
+        self.add_capability(id, data.len() as u8);
+        self.caps.last_mut().unwrap().set_data(data);
+    }
+
     /// Constructs the configuration space and a description of its
     /// capabilities.
     pub fn finish(self) -> (RegMap<CfgReg>, Vec<Cap>) {
@@ -0,0 +1,420 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Conformance checks for PCI endpoints.
+//!
+//! [check] drives an [Endpoint] through the probing sequences a guest performs
+//! while enumerating the bus (identification, command register toggles, BAR
+//! sizing, and capability list walks) and asserts that the responses follow
+//! the PCI Local Bus Specification.  The endpoint must already be attached to a
+//! bus, and is left with its original register contents when the checks pass.
+
+use std::collections::BTreeSet;
+
+use super::bits::*;
+use super::Endpoint;
+use crate::common::{RWOp, ReadOp, WriteOp};
+
+const OFF_VENDOR_ID: usize = 0x00;
+const OFF_COMMAND: usize = 0x04;
+const OFF_STATUS: usize = 0x06;
+const OFF_CLASS: usize = 0x08;
+const OFF_HEADER_TYPE: usize = 0x0e;
+const OFF_BAR0: usize = 0x10;
+const OFF_CAP_PTR: usize = 0x34;
+const OFF_ROM_DEVICE: usize = 0x30;
+const OFF_ROM_BRIDGE: usize = 0x38;
+
+/// Upper bound on capabilities, given that each occupies at least one dword
+/// of the device-specific region of config space
+const MAX_CAPS: usize = (LEN_CFG - LEN_CFG_STD) / 4;
+
+struct Probe<'a> {
+    dev: &'a dyn Endpoint,
+    name: &'a str,
+}
+impl Probe<'_> {
+    fn read(&self, off: usize, buf: &mut [u8]) {
+        let mut ro = ReadOp::from_buf(off, buf);
+        self.dev.cfg_rw(RWOp::Read(&mut ro));
+    }
+    fn write(&self, off: usize, buf: &[u8]) {
+        let mut wo = WriteOp::from_buf(off, buf);
+        self.dev.cfg_rw(RWOp::Write(&mut wo));
+    }
+
+    fn read8(&self, off: usize) -> u8 {
+        let mut buf = [0u8; 1];
+        self.read(off, &mut buf);
+        buf[0]
+    }
+    fn read16(&self, off: usize) -> u16 {
+        let mut buf = [0u8; 2];
+        self.read(off, &mut buf);
+        u16::from_le_bytes(buf)
+    }
+    fn read32(&self, off: usize) -> u32 {
+        let mut buf = [0u8; 4];
+        self.read(off, &mut buf);
+        u32::from_le_bytes(buf)
+    }
+    fn write8(&self, off: usize, val: u8) {
+        self.write(off, &[val]);
+    }
+    fn write16(&self, off: usize, val: u16) {
+        self.write(off, &val.to_le_bytes());
+    }
+    fn write32(&self, off: usize, val: u32) {
+        self.write(off, &val.to_le_bytes());
+    }
+
+    fn header_type(&self) -> u8 {
+        self.read8(OFF_HEADER_TYPE) & !HEADER_TYPE_MULTIFUNC
+    }
+}
+
+/// Size and type of an implemented BAR, as discovered by sizing it
+#[derive(Copy, Clone, Debug)]
+enum BarSize {
+    Pio,
+    Mmio(u64),
+}
+
+/// Run all conformance checks against `dev`, panicking (with `name` in the
+/// message) upon any violation.
+pub(crate) fn check(dev: &dyn Endpoint, name: &str) {
+    let probe = Probe { dev, name };
+
+    check_ident(&probe);
+    check_subword_reads(&probe);
+    check_command(&probe);
+    let bars = check_bars(&probe);
+    check_rom(&probe);
+    check_caps(&probe, &bars);
+}
+
+/// Identification registers are populated and read-only.
+fn check_ident(p: &Probe) {
+    let name = p.name;
+    let ids = p.read32(OFF_VENDOR_ID);
+    let vendor = ids as u16;
+    assert!(
+        vendor != 0 && vendor != 0xffff,
+        "{name}: invalid vendor ID {vendor:#x}"
+    );
+
+    let class = p.read32(OFF_CLASS);
+    let htype = p.header_type();
+    assert!(
+        htype == HEADER_TYPE_DEVICE || htype == HEADER_TYPE_BRIDGE,
+        "{name}: unexpected header type {htype:#x}"
+    );
+
+    p.write32(OFF_VENDOR_ID, !ids);
+    p.write32(OFF_CLASS, !class);
+    assert_eq!(p.read32(OFF_VENDOR_ID), ids, "{name}: vendor/device writable");
+    assert_eq!(p.read32(OFF_CLASS), class, "{name}: class code writable");
+    assert_eq!(p.header_type(), htype, "{name}: header type changed");
+}
+
+/// Byte and word accesses to the standard header agree with dword accesses.
+fn check_subword_reads(p: &Probe) {
+    let name = p.name;
+    for off in (0..LEN_CFG_STD).step_by(4) {
+        let dword = p.read32(off).to_le_bytes();
+        for i in 0..4 {
+            assert_eq!(
+                p.read8(off + i),
+                dword[i],
+                "{name}: byte read at {:#x} disagrees with dword",
+                off + i
+            );
+        }
+        for i in [0, 2] {
+            assert_eq!(
+                p.read16(off + i).to_le_bytes(),
+                [dword[i], dword[i + 1]],
+                "{name}: word read at {:#x} disagrees with dword",
+                off + i
+            );
+        }
+    }
+}
+
+/// Decoding and bus mastering can be disabled, and the command register holds
+/// the value written to it.
+fn check_command(p: &Probe) {
+    let name = p.name;
+    let orig = p.read16(OFF_COMMAND);
+    let decode = RegCmd::IO_EN | RegCmd::MMIO_EN | RegCmd::BUSMSTR_EN;
+
+    let off = orig & !decode.bits();
+    p.write16(OFF_COMMAND, off);
+    assert_eq!(p.read16(OFF_COMMAND), off, "{name}: command not cleared");
+
+    p.write16(OFF_COMMAND, orig);
+    assert_eq!(p.read16(OFF_COMMAND), orig, "{name}: command not restored");
+}
+
+/// Size each BAR as a guest would: with decoding disabled, write all-1s,
+/// read back the mask, and restore the original value.
+fn check_bars(p: &Probe) -> [Option<BarSize>; 6] {
+    let name = p.name;
+    let mut found = [None; 6];
+    let count = match p.header_type() {
+        HEADER_TYPE_BRIDGE => 2,
+        _ => 6,
+    };
+
+    let cmd = p.read16(OFF_COMMAND);
+    let decode = RegCmd::IO_EN | RegCmd::MMIO_EN;
+    p.write16(OFF_COMMAND, cmd & !decode.bits());
+
+    let mut n = 0;
+    while n < count {
+        let off = OFF_BAR0 + n * 4;
+        let orig = p.read32(off);
+        p.write32(off, !0);
+        let mask = p.read32(off);
+
+        if mask == 0 {
+            assert_eq!(orig, 0, "{name}: unimplemented BAR{n} nonzero");
+            n += 1;
+            continue;
+        }
+
+        if mask & BAR_TYPE_IO != 0 {
+            assert_eq!(mask & 0b10, 0, "{name}: BAR{n} reserved bit set");
+            assert_eq!(orig & 0b11, mask & 0b11, "{name}: BAR{n} type changed");
+            let size = (!(mask & !0b11) as u16).wrapping_add(1) as u64;
+            assert!(
+                size.is_power_of_two() && size >= 4,
+                "{name}: BAR{n} has invalid IO size {size:#x}"
+            );
+            found[n] = Some(BarSize::Pio);
+            p.write32(off, orig);
+            assert_eq!(p.read32(off), orig, "{name}: BAR{n} not restored");
+            n += 1;
+            continue;
+        }
+
+        assert_eq!(orig & 0xf, mask & 0xf, "{name}: BAR{n} type changed");
+        let (size, width) = match mask & 0b110 {
+            0b000 => ((!(mask & !0xf)).wrapping_add(1) as u64, 1),
+            0b100 => {
+                assert!(n + 1 < count, "{name}: 64-bit BAR{n} has no upper");
+                let off_hi = off + 4;
+                let orig_hi = p.read32(off_hi);
+                p.write32(off_hi, !0);
+                let mask_hi = p.read32(off_hi);
+                p.write32(off_hi, orig_hi);
+                assert_eq!(
+                    p.read32(off_hi),
+                    orig_hi,
+                    "{name}: BAR{} not restored",
+                    n + 1
+                );
+
+                let mask = (mask_hi as u64) << 32 | (mask & !0xf) as u64;
+                ((!mask).wrapping_add(1), 2)
+            }
+            kind => panic!("{name}: BAR{n} has reserved memory type {kind:#b}"),
+        };
+        assert!(
+            size.is_power_of_two() && size >= 16,
+            "{name}: BAR{n} has invalid memory size {size:#x}"
+        );
+        found[n] = Some(BarSize::Mmio(size));
+        p.write32(off, orig);
+        assert_eq!(p.read32(off), orig, "{name}: BAR{n} not restored");
+        n += width;
+    }
+
+    p.write16(OFF_COMMAND, cmd);
+    found
+}
+
+/// The expansion ROM BAR is either unimplemented or sizes like a memory BAR.
+fn check_rom(p: &Probe) {
+    let name = p.name;
+    let off = match p.header_type() {
+        HEADER_TYPE_BRIDGE => OFF_ROM_BRIDGE,
+        _ => OFF_ROM_DEVICE,
+    };
+    let orig = p.read32(off);
+    p.write32(off, !1);
+    let mask = p.read32(off) & 0xffff_f800;
+    p.write32(off, orig);
+
+    if mask != 0 {
+        let size = (!mask).wrapping_add(1);
+        assert!(size.is_power_of_two(), "{name}: invalid ROM size {size:#x}");
+    }
+    assert_eq!(p.read32(off), orig, "{name}: ROM BAR not restored");
+}
+
+/// Walk the capability list, checking its structure and the contents of the
+/// capabilities which describe other device resources.
+fn check_caps(p: &Probe, bars: &[Option<BarSize>; 6]) {
+    let name = p.name;
+    let status = RegStatus::from_bits_truncate(p.read16(OFF_STATUS));
+    if !status.contains(RegStatus::CAP_LIST) {
+        return;
+    }
+
+    let mut seen = BTreeSet::new();
+    let mut ptr = p.read8(OFF_CAP_PTR) as usize;
+    assert_ne!(ptr, 0, "{name}: capability list advertised but empty");
+    while ptr != 0 {
+        assert!(
+            ptr >= LEN_CFG_STD && ptr < LEN_CFG - 1 && ptr & 0b11 == 0,
+            "{name}: invalid capability pointer {ptr:#x}"
+        );
+        assert!(seen.insert(ptr), "{name}: capability list loops at {ptr:#x}");
+        assert!(seen.len() <= MAX_CAPS, "{name}: too many capabilities");
+
+        let id = p.read8(ptr);
+        let next = p.read8(ptr + 1);
+        assert_ne!(id, 0, "{name}: null capability at {ptr:#x}");
+
+        // The ID and next pointer are read-only
+        p.write8(ptr, !id);
+        p.write8(ptr + 1, !next);
+        assert_eq!(p.read8(ptr), id, "{name}: cap ID at {ptr:#x} writable");
+        assert_eq!(p.read8(ptr + 1), next, "{name}: cap next writable");
+
+        match id {
+            CAP_ID_MSIX => check_cap_msix(p, ptr, bars),
+            CAP_ID_VENDOR => {
+                let len = p.read8(ptr + 2) as usize;
+                assert!(
+                    len >= 3 && ptr + len <= LEN_CFG,
+                    "{name}: vendor cap at {ptr:#x} has bad length {len}"
+                );
+            }
+            _ => {}
+        }
+        ptr = next as usize;
+    }
+}
+
+/// The MSI-X table and PBA must lie within an implemented memory BAR.
+fn check_cap_msix(p: &Probe, ptr: usize, bars: &[Option<BarSize>; 6]) {
+    let name = p.name;
+    let ctrl = p.read16(ptr + 2);
+    let count = (ctrl & 0x7ff) as u64 + 1;
+
+    let region = |reg: u32, len: u64, what: &str| {
+        let bir = (reg & 0b111) as usize;
+        let off = (reg & !0b111) as u64;
+        match bars.get(bir).copied().flatten() {
+            Some(BarSize::Mmio(size)) => assert!(
+                off + len <= size,
+                "{name}: MSI-X {what} exceeds BAR{bir} ({off:#x}+{len:#x})"
+            ),
+            other => panic!("{name}: MSI-X {what} in BAR{bir} ({other:?})"),
+        }
+    };
+    region(p.read32(ptr + 4), count * 16, "table");
+    region(p.read32(ptr + 8), (count + 63) / 64 * 8, "PBA");
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::check;
+    use crate::hw::ahci::PciAhci;
+    use crate::hw::ids;
+    use crate::hw::nvme::PciNvme;
+    use crate::hw::pci::bridge::Bridge;
+    use crate::hw::pci::device::test::setup_cfg;
+    use crate::hw::pci::test::Scaffold;
+    use crate::hw::pci::topology::{Builder, LogicalBusId};
+    use crate::hw::pci::Endpoint;
+    use crate::hw::qemu::bochs::{self, PciBochsDisplay};
+    use crate::hw::qemu::ivshmem::PciIvShmem;
+    use crate::hw::virtio::input::InputKind;
+    use crate::hw::virtio::{
+        PciVirtioBlock, PciVirtioInput, PciVirtioMem, PciVirtioNullNet,
+        PciVirtioRng, PciVirtioRtc, PciVirtioScsi, PciVirtioVsock,
+    };
+    use crate::instance::Instance;
+
+    use slog::{Discard, Logger};
+
+    fn check_attached(dev: Arc<dyn Endpoint>, name: &str) {
+        let scaffold = Scaffold::new();
+        let _bus = setup_cfg(&scaffold, dev.clone());
+        check(dev.as_ref(), name);
+    }
+
+    #[test]
+    fn ahci() {
+        let log = Logger::root(Discard, slog::o!());
+        check_attached(PciAhci::create(log), "ahci");
+    }
+
+    #[test]
+    fn nvme() {
+        let log = Logger::root(Discard, slog::o!());
+        check_attached(PciNvme::create("conformance".to_string(), log), "nvme");
+    }
+
+    // The i440fx functions are checked alongside their other tests.  Viona and
+    // the falcon-only devices require host resources to create, and so are not
+    // covered here.
+
+    #[test]
+    fn virtio() {
+        check_attached(PciVirtioBlock::new(0x100), "virtio-block");
+        let clock = crate::clock::Clock::new();
+        check_attached(PciVirtioRtc::new(0x10, clock), "virtio-rtc");
+        let rate = std::num::NonZeroU32::new(4096).unwrap();
+        check_attached(PciVirtioRng::new(0x10, rate).unwrap(), "virtio-rng");
+        let mut map = crate::vmm::PhysMap::new_test(2 << 30);
+        map.add_hotplug_mem("hotmem".to_string(), 1 << 30, 1 << 30).unwrap();
+        let region = map.hotplug_region("hotmem").unwrap();
+        check_attached(PciVirtioMem::new(0x100, region), "virtio-mem");
+        let kbd = PciVirtioInput::new(0x40, InputKind::Keyboard);
+        check_attached(kbd, "virtio-keyboard");
+        let tablet = PciVirtioInput::new(0x40, InputKind::Tablet);
+        check_attached(tablet, "virtio-tablet");
+        check_attached(PciVirtioVsock::new(0x100, 3), "virtio-vsock");
+        check_attached(PciVirtioScsi::new(0x100, 4), "virtio-scsi");
+        let mac = [0x02, 0x08, 0x20, 0, 5, 0];
+        check_attached(PciVirtioNullNet::new(0x100, mac), "virtio-null-net");
+    }
+
+    #[test]
+    fn ivshmem() {
+        let dir = tempfile::tempdir().unwrap();
+        let dev = PciIvShmem::create(dir.path(), "shm", 0x10000).unwrap();
+        check_attached(dev, "ivshmem");
+    }
+
+    #[test]
+    fn bochs_display() {
+        let map = crate::vmm::PhysMap::new_test(bochs::VRAM_SIZE);
+        let vram = map.create_test_device_mem(bochs::VRAM_SIZE).unwrap();
+        check_attached(PciBochsDisplay::create(vram), "bochs-display");
+    }
+
+    #[test]
+    fn bridge() {
+        let instance = Instance::new_test().unwrap();
+        let guard = instance.lock();
+        let topology =
+            Builder::new().finish(guard.inventory(), guard.machine()).unwrap();
+        drop(guard);
+
+        let bridge = Bridge::new(
+            ids::pci::VENDOR_OXIDE,
+            ids::pci::PROPOLIS_BRIDGE_DEV_ID,
+            &topology,
+            LogicalBusId(0xFF),
+        );
+        check_attached(bridge, "pci-bridge");
+    }
+}
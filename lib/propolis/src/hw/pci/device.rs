@@ -2,12 +2,13 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
 
 use super::bar::{BarDefine, Bars};
 use super::bits::*;
 use super::cfgspace::{CfgBuilder, CfgReg};
-use super::{bus, BarN, Endpoint};
+use super::irqfd::Route;
+use super::{bus, BarN, Endpoint, FuncNum};
 use crate::accessors::{MemAccessor, MsiAccessor};
 use crate::common::*;
 use crate::intr_pins::IntrPin;
@@ -46,6 +47,24 @@ pub trait Device: Send + Sync + 'static {
     fn interrupt_mode_change(&self, mode: IntrMode) {}
     #[allow(unused_variables)]
     fn msi_update(&self, info: MsiUpdate) {}
+    /// See [`Endpoint::bar_doorbell()`]
+    #[allow(unused_variables)]
+    fn bar_doorbell(&self, bar: BarN) -> Option<u16> {
+        None
+    }
+    /// See [`Endpoint::doorbell_ring()`]
+    #[allow(unused_variables)]
+    fn doorbell_ring(&self, bar: BarN, val: u16) {}
+    /// See [`Endpoint::doorbell_placed()`]
+    #[allow(unused_variables)]
+    fn doorbell_placed(&self, bar: BarN, port: Option<u16>) -> bool {
+        false
+    }
+    /// See [`Endpoint::bar_placed()`]
+    #[allow(unused_variables)]
+    fn bar_placed(&self, bar: BarN, addr: Option<u64>) -> bool {
+        false
+    }
     // TODO
     // fn cap_read(&self);
     // fn cap_write(&self);
@@ -57,6 +76,9 @@ impl<D: Device + Send + Sync + 'static> Endpoint for D {
         ds.attach(attachment);
         self.attach();
     }
+    fn detach(&self) {
+        self.device_state().detach(self);
+    }
     fn cfg_rw(&self, mut rwo: RWOp) {
         let ds = self.device_state();
         ds.cfg_space.process(&mut rwo, |id, mut rwo| match id {
@@ -82,6 +104,18 @@ impl<D: Device + Send + Sync + 'static> Endpoint for D {
         }
         Device::bar_rw(self, bar, rwo);
     }
+    fn bar_doorbell(&self, bar: BarN) -> Option<u16> {
+        Device::bar_doorbell(self, bar)
+    }
+    fn doorbell_ring(&self, bar: BarN, val: u16) {
+        Device::doorbell_ring(self, bar, val)
+    }
+    fn doorbell_placed(&self, bar: BarN, port: Option<u16>) -> bool {
+        Device::doorbell_placed(self, bar, port)
+    }
+    fn bar_placed(&self, bar: BarN, addr: Option<u64>) -> bool {
+        Device::bar_placed(self, bar, addr)
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -186,11 +220,16 @@ impl State {
 pub(super) struct Cap {
     id: u8,
     offset: u8,
+    /// Fixed contents of the capability body, if it is not emulated
+    data: Option<Box<[u8]>>,
 }
 
 impl Cap {
     pub(super) fn new(id: u8, offset: u8) -> Self {
-        Self { id, offset }
+        Self { id, offset, data: None }
+    }
+    pub(super) fn set_data(&mut self, data: &[u8]) {
+        self.data = Some(data.into());
     }
 }
 
@@ -509,6 +548,14 @@ impl DeviceState {
         assert!(idx < self.caps.len() as u8);
         // XXX: no fancy capability support for now
         let cap = &self.caps[idx as usize];
+        if let Some(data) = cap.data.as_ref() {
+            // Capabilities with fixed contents are read-only
+            if let RWOp::Read(ro) = rwo {
+                let off = ro.offset();
+                ro.write_bytes(&data[off..(off + ro.len())]);
+            }
+            return;
+        }
         match cap.id {
             CAP_ID_MSIX => {
                 let msix_cfg = self.msix_cfg.as_ref().unwrap();
@@ -566,6 +613,16 @@ impl DeviceState {
         attach.acc_msi.adopt(&self.acc_msi, None);
     }
 
+    /// Quiesce the device ahead of its removal from the bus: its BARs are
+    /// unregistered, MSI/MSI-X delivery is disabled, and any asserted INTx pin
+    /// is released.
+    fn detach(&self, dev: &dyn Device) {
+        self.reset(dev);
+        if let Some(pin) = self.lintr_pin() {
+            pin.deassert();
+        }
+    }
+
     pub fn lintr_pin(&self) -> Option<Arc<dyn IntrPin>> {
         let state = self.state.lock().unwrap();
         let attach = state.attach.as_ref()?;
@@ -747,6 +804,10 @@ struct MsixCfg {
     pba_off: u32,
     map: RegMap<MsixBarReg>,
     entries: Vec<Mutex<MsixEntry>>,
+    /// Lock-free view of `entries`, for delivery from device workers
+    routes: Vec<Route>,
+    /// MSI accessor through which deliverable routes are sent
+    acc_msi: OnceLock<MsiAccessor>,
     state: Mutex<MsixCfgState>,
 }
 #[derive(Debug, Default)]
@@ -807,6 +868,8 @@ impl MsixCfg {
 
         let mut entries = Vec::with_capacity(count as usize);
         entries.resize_with(count as usize, Default::default);
+        let mut routes = Vec::with_capacity(count as usize);
+        routes.resize_with(count as usize, Default::default);
 
         let this = Self {
             count,
@@ -814,6 +877,8 @@ impl MsixCfg {
             pba_off: pba_off as u32,
             map,
             entries,
+            routes,
+            acc_msi: OnceLock::new(),
             state: Default::default(),
         };
 
@@ -857,12 +922,14 @@ impl MsixCfg {
                     MsixBarReg::Addr(i) => {
                         let mut ent = self.entries[*i as usize].lock().unwrap();
                         ent.addr = wo.read_u64();
+                        self.publish(*i, &ent);
                         drop(ent);
                         updatef(MsiUpdate::Modify(*i));
                     }
                     MsixBarReg::Data(i) => {
                         let mut ent = self.entries[*i as usize].lock().unwrap();
                         ent.data = wo.read_u32();
+                        self.publish(*i, &ent);
                         drop(ent);
                         updatef(MsiUpdate::Modify(*i));
                     }
@@ -871,6 +938,7 @@ impl MsixCfg {
                         let val = wo.read_u32();
                         ent.mask_vec = val & MSIX_VEC_MASK != 0;
                         ent.check_mask();
+                        self.publish(*i, &ent);
                         drop(ent);
                         updatef(MsiUpdate::Modify(*i));
                     }
@@ -964,13 +1032,28 @@ impl MsixCfg {
         });
     }
     fn each_entry(&self, mut cb: impl FnMut(&mut MsixEntry)) {
-        for ent in self.entries.iter() {
+        for (idx, ent) in self.entries.iter().enumerate() {
             let mut locked = ent.lock().unwrap();
-            cb(&mut locked)
+            cb(&mut locked);
+            self.publish(idx as u16, &locked);
         }
     }
+    /// Update the [`Route`] for entry `idx`, which must be locked by the
+    /// caller.
+    fn publish(&self, idx: u16, ent: &MsixEntry) {
+        let deliverable = ent.enabled && !ent.mask_vec && !ent.mask_func;
+        self.routes[idx as usize].publish(ent.addr, ent.data, deliverable);
+    }
     fn fire(&self, idx: u16) {
         assert!(idx < self.count);
+        if let (Some((addr, data)), Some(acc)) =
+            (self.routes[idx as usize].load(), self.acc_msi.get())
+        {
+            // Delivery is still subject to the accessor hierarchy, so nothing
+            // is sent once the device has been detached from the machine.
+            let _ = acc.send(addr, data as u64);
+            return;
+        }
         let mut ent = self.entries[idx as usize].lock().unwrap();
         ent.fire();
     }
@@ -996,6 +1079,7 @@ impl MsixCfg {
         self.each_entry(|ent| ent.reset());
     }
     fn attach(&self, msi_acc: &MsiAccessor) {
+        let _ = self.acc_msi.set(msi_acc.child(None));
         for entry in self.entries.iter() {
             let mut guard = entry.lock().unwrap();
             guard.acc_msi = Some(msi_acc.child(None));
@@ -1041,7 +1125,9 @@ impl MsixCfg {
         }
         inner.enabled = state.is_enabled;
         inner.func_mask = state.is_func_masked;
-        for (entry, saved) in self.entries.iter().zip(state.entries) {
+        for (idx, (entry, saved)) in
+            self.entries.iter().zip(state.entries).enumerate()
+        {
             let mut entry = entry.lock().unwrap();
             entry.addr = saved.addr;
             entry.data = saved.data;
@@ -1049,6 +1135,7 @@ impl MsixCfg {
             entry.mask_func = state.is_func_masked;
             entry.enabled = state.is_enabled;
             entry.pending = saved.is_pending;
+            self.publish(idx as u16, &entry);
         }
 
         Ok(())
@@ -1062,6 +1149,13 @@ pub struct MsiEnt {
     pub masked: bool,
     pub pending: bool,
 }
+impl MsiEnt {
+    /// The APIC ID of the (v)CPU to which the guest has directed this vector
+    pub fn dest_id(&self) -> u8 {
+        // Destination ID lies in bits 19:12 of the message address
+        (self.addr >> 12) as u8
+    }
+}
 
 #[derive(Debug)]
 pub struct MsixHdl {
@@ -1084,6 +1178,10 @@ impl MsixHdl {
     pub fn count(&self) -> u16 {
         self.cfg.count
     }
+    /// Get an [`IrqFd`] for vector `idx`, or `None` if it is out of range.
+    pub fn irqfd(&self, idx: u16) -> Option<IrqFd> {
+        (idx < self.cfg.count).then(|| IrqFd { cfg: self.cfg.clone(), idx })
+    }
 }
 impl Clone for MsixHdl {
     fn clone(&self) -> Self {
@@ -1091,6 +1189,25 @@ impl Clone for MsixHdl {
     }
 }
 
+/// Handle for signaling a single MSI-X vector from a device worker.
+///
+/// While the vector is enabled and unmasked, firing it sends the message
+/// directly, without taking the locks shared with vCPUs accessing the MSI-X
+/// table.
+#[derive(Clone, Debug)]
+pub struct IrqFd {
+    cfg: Arc<MsixCfg>,
+    idx: u16,
+}
+impl IrqFd {
+    pub fn fire(&self) {
+        self.cfg.fire(self.idx);
+    }
+    pub fn read(&self) -> MsiEnt {
+        self.cfg.read(self.idx)
+    }
+}
+
 pub struct Builder {
     ident: Ident,
     lintr_support: bool,
@@ -1166,6 +1283,27 @@ impl Builder {
         self
     }
 
+    /// Add a 64-bit BAR which is accessible via MMIO, and whose contents are
+    /// prefetchable (reads have no side effects).  Such BARs, which can be
+    /// very large (GPU apertures, for example), may be placed in the
+    /// prefetchable memory window of a bridge above them.
+    ///
+    /// # Panics
+    ///
+    /// If `size` is < 16 or not a power of 2.
+    pub fn add_bar_mmio64_prefetch(mut self, bar: BarN, size: u64) -> Self {
+        assert!(size.is_power_of_two());
+        assert!(size >= 16);
+
+        let idx = bar as usize;
+        assert!(idx != 6);
+        assert!(self.bars[idx].is_none());
+        assert!(self.bars[idx + 1].is_none());
+
+        self.bars[idx] = Some(BarDefine::Mmio64Prefetch(size));
+        self
+    }
+
     /// Add a legacy (pin-based) interrupt
     pub fn add_lintr(mut self) -> Self {
         self.lintr_support = true;
@@ -1203,6 +1341,19 @@ impl Builder {
         self
     }
 
+    /// Add a vendor-specific capability with fixed (read-only) contents.
+    ///
+    /// The `data` is the body of the capability, following its ID and next
+    /// capability pointer registers.
+    ///
+    /// # Panics
+    ///
+    /// If the total size of the capability is not a multiple of 4 bytes.
+    pub fn add_cap_vendor(mut self, data: &[u8]) -> Self {
+        self.cfg_builder.add_fixed_capability(CAP_ID_VENDOR, data);
+        self
+    }
+
     pub fn finish(self) -> DeviceState {
         let (cfgmap, caps) = self.cfg_builder.finish();
         DeviceState::new(
@@ -1216,6 +1367,57 @@ impl Builder {
     }
 }
 
+/// A multi-function package: a set of PCI functions which occupy a single
+/// slot and are attached to it together.
+///
+/// Each function is a device in its own right, with an [`Ident`] and config
+/// space of its own (built with its own [`Builder`]).  When the package is
+/// attached (see [`bus::Bus::attach_multifunc()`]), its slot is marked as
+/// multi-function before any of its functions become visible, so the guest
+/// never observes function 0 without the multi-function bit set in its header
+/// type.
+pub struct MultiFunc {
+    funcs: [Option<Arc<dyn Endpoint>>; 8],
+}
+
+impl MultiFunc {
+    /// Start a package with the device which is to be its function 0.
+    pub fn new(func0: Arc<dyn Endpoint>) -> Self {
+        let mut funcs: [Option<Arc<dyn Endpoint>>; 8] = Default::default();
+        funcs[0] = Some(func0);
+        Self { funcs }
+    }
+
+    /// Add a device to the package as function `func`.
+    ///
+    /// # Panics
+    ///
+    /// If `func` is not a valid function number, or is already occupied.
+    pub fn add_func(mut self, func: u8, dev: Arc<dyn Endpoint>) -> Self {
+        let slot = &mut self.funcs[func as usize];
+        assert!(slot.is_none(), "function {func} already occupied");
+        *slot = Some(dev);
+        self
+    }
+
+    /// Iterate over the functions of the package, in order of function number.
+    pub fn funcs(
+        &self,
+    ) -> impl Iterator<Item = (FuncNum, &Arc<dyn Endpoint>)> + '_ {
+        self.funcs.iter().enumerate().filter_map(|(func, dev)| {
+            Some((FuncNum::new(func as u8).unwrap(), dev.as_ref()?))
+        })
+    }
+
+    pub(super) fn into_funcs(
+        self,
+    ) -> impl Iterator<Item = (FuncNum, Arc<dyn Endpoint>)> {
+        self.funcs.into_iter().enumerate().filter_map(|(func, dev)| {
+            Some((FuncNum::new(func as u8).unwrap(), dev?))
+        })
+    }
+}
+
 pub mod migrate {
     use crate::hw::pci::bar;
     use crate::migrate::*;
@@ -1278,6 +1480,53 @@ pub(crate) mod test {
         let (_cfg, bar_size) = MsixCfg::new(256, BarN::BAR1);
         assert_eq!(bar_size, 8192);
     }
+    #[test]
+    fn msix_masked_vectors_pending() {
+        let (cfg, _bar_size) = MsixCfg::new(16, BarN::BAR1);
+        let bar_write = |off: usize, buf: &[u8]| {
+            cfg.bar_rw(RWOp::Write(&mut WriteOp::from_buf(off, buf)), |_| {});
+        };
+        let msgctrl_write = |val: u16| {
+            let buf = val.to_le_bytes();
+            cfg.cfg_rw(RWOp::Write(&mut WriteOp::from_buf(0, &buf)), |_| {});
+        };
+        let read_pba = || {
+            let mut buf = [0u8; 2];
+            let mut ro = ReadOp::from_buf(cfg.pba_off as usize, &mut buf);
+            cfg.bar_rw(RWOp::Read(&mut ro), |_| {});
+            u16::from_le_bytes(buf)
+        };
+
+        // Program vector 9 and mask it
+        let ent = 9 * 16;
+        bar_write(ent, &0xfee0_0000u64.to_le_bytes());
+        bar_write(ent + 8, &0x41u32.to_le_bytes());
+        bar_write(ent + 12, &MSIX_VEC_MASK.to_le_bytes());
+
+        // Nothing is latched while MSI-X is disabled
+        cfg.fire(9);
+        assert_eq!(read_pba(), 0);
+
+        msgctrl_write(MSIX_MSGCTRL_ENABLE);
+        assert!(cfg.is_enabled());
+        cfg.fire(9);
+        assert_eq!(read_pba(), 1 << 9);
+        let read = cfg.read(9);
+        assert!(read.masked && read.pending);
+        assert_eq!((read.addr, read.data), (0xfee0_0000, 0x41));
+
+        // Unmasking the vector delivers the pending message
+        bar_write(ent + 12, &0u32.to_le_bytes());
+        assert_eq!(read_pba(), 0);
+
+        // The function mask latches all vectors, until it is cleared
+        msgctrl_write(MSIX_MSGCTRL_ENABLE | MSIX_MSGCTRL_FMASK);
+        cfg.fire(0);
+        cfg.fire(9);
+        assert_eq!(read_pba(), 1 | 1 << 9);
+        msgctrl_write(MSIX_MSGCTRL_ENABLE);
+        assert_eq!(read_pba(), 0);
+    }
 
     pub(crate) fn setup_cfg(
         scaffold: &Scaffold,
@@ -0,0 +1,333 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ACPI-based hotplug for devices on PCI bus 0
+//!
+//! The register interface mirrors the one exposed by QEMU for its PIIX4
+//! chipset, allowing guest AML written against it to be reused:
+//!
+//! - A block at [`PORT_PCI_HOTPLUG`] reports slots which have had devices
+//!   inserted (`PCIU`) or which the host wants removed (`PCID`), and accepts
+//!   guest acknowledgement that a slot has been ejected (`B0EJ`).
+//! - Bit 1 of the shared [`Gpe0`] block, which is raised to notify the guest
+//!   of pending changes.
+//!
+//! Insertion requires nothing of the guest: once a device has been attached
+//! to the bus, the host reports it with [`AcpiPciHotplug::notify_inserted`],
+//! and the guest discovers it by rescanning the slot.
+//!
+//! Removal is cooperative: the host posts a request via
+//! [`AcpiPciHotplug::request_eject`] and then waits for the guest to release
+//! the device with [`AcpiPciHotplug::wait_ejected`].  Should the guest not
+//! respond, it is left to the caller to decide whether to surprise-remove the
+//! device regardless.
+//!
+//! Each operation takes the ID of the request (such as an API call) on whose
+//! behalf it is made, if any, which is reported through its USDT probe so that
+//! the operation can be traced back to its origin.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+use crate::hw::acpi::gpe::{Gpe0, GPE_PCI_HOTPLUG};
+use crate::inventory::Entity;
+use crate::migrate::*;
+use crate::pio::{PioBus, PioFn};
+use crate::util::regmap::RegMap;
+
+use lazy_static::lazy_static;
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn pci_hotplug_insert(slot: u8, req_id: &str) {}
+    fn pci_hotplug_eject_request(slot: u8, req_id: &str) {}
+    fn pci_hotplug_eject_done(slot: u8, ejected: u8, req_id: &str) {}
+}
+
+pub const PORT_PCI_HOTPLUG: u16 = 0xae00;
+pub const PCI_HOTPLUG_LEN: u16 = 0x14;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum HpReg {
+    /// Slots with newly inserted devices, cleared when read
+    Up,
+    /// Slots with devices pending removal
+    Down,
+    /// Guest writes the bitmask of slots it has ejected
+    Eject,
+    /// Slots which support removal
+    Removable,
+    /// Bus selector (only bus 0 is supported)
+    BusSel,
+}
+
+lazy_static! {
+    static ref HP_REGS: RegMap<HpReg> = {
+        let layout = [
+            (HpReg::Up, 4),
+            (HpReg::Down, 4),
+            (HpReg::Eject, 4),
+            (HpReg::Removable, 4),
+            (HpReg::BusSel, 4),
+        ];
+        RegMap::create_packed(PCI_HOTPLUG_LEN as usize, &layout, None)
+    };
+}
+
+#[derive(Default)]
+struct State {
+    up: u32,
+    down: u32,
+    ejected: u32,
+    removable: u32,
+}
+
+pub struct AcpiPciHotplug {
+    state: Mutex<State>,
+    cv: Condvar,
+    gpe: Arc<Gpe0>,
+}
+impl AcpiPciHotplug {
+    pub fn create(gpe: Arc<Gpe0>) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(State::default()),
+            cv: Condvar::new(),
+            gpe,
+        })
+    }
+
+    pub fn attach(self: &Arc<Self>, pio: &PioBus) {
+        let this = Arc::clone(self);
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| this.hotplug_rw(rwo))
+            as Arc<PioFn>;
+        pio.register(PORT_PCI_HOTPLUG, PCI_HOTPLUG_LEN, piofn).unwrap();
+    }
+
+    /// Mark a slot on bus 0 as supporting removal.
+    pub fn set_removable(&self, slot: u8, removable: bool) {
+        let mut state = self.state.lock().unwrap();
+        let bit = slot_bit(slot);
+        if removable {
+            state.removable |= bit;
+        } else {
+            state.removable &= !bit;
+        }
+    }
+
+    /// Slots on bus 0 which support removal, as a bitmask
+    pub fn removable_slots(&self) -> u32 {
+        self.state.lock().unwrap().removable
+    }
+
+    /// Notify the guest that a device has been attached in `slot` on bus 0.
+    pub fn notify_inserted(&self, slot: u8, req_id: Option<&str>) {
+        probes::pci_hotplug_insert!(|| (slot, req_id.unwrap_or_default()));
+        let mut state = self.state.lock().unwrap();
+        let bit = slot_bit(slot);
+        // Any removal request for a prior occupant of the slot is moot
+        state.down &= !bit;
+        state.ejected &= !bit;
+        state.up |= bit;
+        drop(state);
+        self.gpe.raise(GPE_PCI_HOTPLUG);
+    }
+
+    /// Ask the guest to release the device in `slot` on bus 0.
+    pub fn request_eject(&self, slot: u8, req_id: Option<&str>) {
+        probes::pci_hotplug_eject_request!(|| (
+            slot,
+            req_id.unwrap_or_default()
+        ));
+        let mut state = self.state.lock().unwrap();
+        let bit = slot_bit(slot);
+        state.ejected &= !bit;
+        state.down |= bit;
+        drop(state);
+        self.gpe.raise(GPE_PCI_HOTPLUG);
+    }
+
+    /// Wait up to `timeout` for the guest to acknowledge ejection of `slot`.
+    ///
+    /// Returns `true` if the guest ejected the device.  In either case, the
+    /// pending removal request for the slot is withdrawn.
+    pub fn wait_ejected(
+        &self,
+        slot: u8,
+        timeout: Duration,
+        req_id: Option<&str>,
+    ) -> bool {
+        let bit = slot_bit(slot);
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.ejected & bit != 0 {
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.cv.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        let ejected = state.ejected & bit != 0;
+        state.ejected &= !bit;
+        state.down &= !bit;
+        drop(state);
+        probes::pci_hotplug_eject_done!(|| (
+            slot,
+            ejected as u8,
+            req_id.unwrap_or_default()
+        ));
+        ejected
+    }
+
+    fn eject_write(&self, mask: u32) {
+        let mut state = self.state.lock().unwrap();
+        // Only slots with an outstanding removal request can be ejected
+        let mask = mask & state.down;
+        state.ejected |= mask;
+        state.down &= !mask;
+        self.cv.notify_all();
+    }
+
+    fn hotplug_rw(&self, mut rwo: RWOp) {
+        HP_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => {
+                let mut state = self.state.lock().unwrap();
+                let val = match id {
+                    // Each insertion is reported to the guest once
+                    HpReg::Up => std::mem::take(&mut state.up),
+                    HpReg::Down => state.down,
+                    HpReg::Removable => state.removable,
+                    HpReg::Eject | HpReg::BusSel => 0,
+                };
+                ro.write_u32(val);
+            }
+            RWOp::Write(wo) => match id {
+                HpReg::Eject => self.eject_write(wo.read_u32()),
+                HpReg::Up | HpReg::Down | HpReg::Removable | HpReg::BusSel => {}
+            },
+        });
+    }
+}
+
+fn slot_bit(slot: u8) -> u32 {
+    assert!(slot < 32, "PCI slot out of range");
+    1 << slot
+}
+
+impl Entity for AcpiPciHotplug {
+    fn type_name(&self) -> &'static str {
+        "pci-acpi-hotplug"
+    }
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        let removable = state.removable;
+        *state = State { removable, ..Default::default() };
+        self.cv.notify_all();
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for AcpiPciHotplug {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        let state = self.state.lock().unwrap();
+        Ok(migrate::PciHotplugV1 {
+            up: state.up,
+            down: state.down,
+            ejected: state.ejected,
+        }
+        .into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::PciHotplugV1 = offer.parse()?;
+        // Which slots are removable follows from the configuration of the
+        // destination, rather than being carried over.
+        let mut state = self.state.lock().unwrap();
+        state.up = data.up;
+        state.down = data.down;
+        state.ejected = data.ejected;
+        self.cv.notify_all();
+        Ok(())
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct PciHotplugV1 {
+        pub up: u32,
+        pub down: u32,
+        pub ejected: u32,
+    }
+    impl Schema<'_> for PciHotplugV1 {
+        fn id() -> SchemaId {
+            ("pci-acpi-hotplug", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intr_pins::NoOpPin;
+
+    fn create() -> Arc<AcpiPciHotplug> {
+        AcpiPciHotplug::create(Gpe0::create(Arc::new(NoOpPin {})))
+    }
+
+    #[test]
+    fn eject_acknowledged() {
+        let hp = create();
+        hp.request_eject(5, None);
+        assert_eq!(hp.state.lock().unwrap().down, 1 << 5);
+
+        // Ejecting a slot with no pending request is ignored
+        hp.eject_write(1 << 6);
+        assert!(!hp.wait_ejected(6, Duration::ZERO, None));
+
+        hp.eject_write(1 << 5);
+        assert!(hp.wait_ejected(5, Duration::from_secs(1), None));
+        assert_eq!(hp.state.lock().unwrap().down, 0);
+    }
+
+    #[test]
+    fn insertion_reported_once() {
+        let hp = create();
+        hp.request_eject(4, None);
+        hp.notify_inserted(4, None);
+        assert_eq!(hp.state.lock().unwrap().down, 0);
+
+        let read_up = || {
+            let mut buf = [0u8; 4];
+            let mut ro = ReadOp::from_buf(0, &mut buf);
+            hp.hotplug_rw(RWOp::Read(&mut ro));
+            u32::from_le_bytes(buf)
+        };
+        assert_eq!(read_up(), 1 << 4);
+        assert_eq!(read_up(), 0);
+    }
+
+    #[test]
+    fn eject_times_out() {
+        let hp = create();
+        hp.request_eject(3, None);
+        assert!(!hp.wait_ejected(3, Duration::from_millis(10), None));
+        // The request is withdrawn once the wait has concluded
+        assert_eq!(hp.state.lock().unwrap().down, 0);
+    }
+}
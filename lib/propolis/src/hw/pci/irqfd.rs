@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Lock-free MSI-X delivery from device workers
+//!
+//! The state of an MSI-X vector (its address, data, and masking) is guarded by
+//! a lock which is also taken by vCPUs accessing the MSI-X table.  A device
+//! worker signaling completions under load would contend on it with the guest.
+//!
+//! Instead, each change to a vector publishes a [`Route`]: a snapshot of the
+//! message, readable without locks, which is present only while the vector is
+//! enabled and unmasked.  When a route is present, an [`IrqFd`] sends the
+//! message through the MSI accessor of the device, without taking the lock of
+//! the vector.  Otherwise, it falls back to the locked path, which takes care
+//! of recording the interrupt as pending.
+//!
+//! [`IrqFd`]: super::IrqFd
+
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+const DATA_MASK: u64 = u32::MAX as u64;
+const DELIVERABLE: u64 = 1 << 32;
+
+/// Snapshot of an MSI-X vector, published with a sequence lock so it may be
+/// read consistently without blocking.
+///
+/// Updates must be serialized by the caller (by the lock of the vector).
+#[derive(Debug, Default)]
+pub(super) struct Route {
+    seq: AtomicU32,
+    addr: AtomicU64,
+    /// Message data in the low 32 bits, plus the [`DELIVERABLE`] flag
+    data: AtomicU64,
+}
+impl Route {
+    /// Publish the state of the vector.  When not `deliverable`, readers will
+    /// fall back to the locked path.
+    pub(super) fn publish(&self, addr: u64, data: u32, deliverable: bool) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        let flags = if deliverable { DELIVERABLE } else { 0 };
+        self.addr.store(addr, Ordering::Relaxed);
+        self.data.store(data as u64 | flags, Ordering::Relaxed);
+
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Read the address and data of the vector, if it is deliverable.
+    pub(super) fn load(&self) -> Option<(u64, u32)> {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 != 0 {
+                // Update in progress
+                std::hint::spin_loop();
+                continue;
+            }
+            let addr = self.addr.load(Ordering::Relaxed);
+            let data = self.data.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) != before {
+                continue;
+            }
+
+            return (data & DELIVERABLE != 0)
+                .then_some((addr, (data & DATA_MASK) as u32));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn initially_undeliverable() {
+        assert_eq!(Route::default().load(), None);
+    }
+
+    #[test]
+    fn publish_and_load() {
+        let route = Route::default();
+        route.publish(0xfee0_0000, 0x4041, true);
+        assert_eq!(route.load(), Some((0xfee0_0000, 0x4041)));
+
+        route.publish(0xfee0_0000, 0x4041, false);
+        assert_eq!(route.load(), None);
+
+        route.publish(0xfee0_1000, u32::MAX, true);
+        assert_eq!(route.load(), Some((0xfee0_1000, u32::MAX)));
+    }
+}
@@ -19,7 +19,12 @@ pub mod bits;
 pub mod bridge;
 pub mod bus;
 mod cfgspace;
+#[cfg(test)]
+pub(crate) mod conformance;
 pub(crate) mod device;
+pub mod hotplug;
+mod irqfd;
+pub mod plugin;
 pub mod topology;
 
 #[cfg(test)]
@@ -209,8 +214,40 @@ pub type LintrCfg = (INTxPinID, Arc<dyn IntrPin>);
 
 pub trait Endpoint: Send + Sync {
     fn attach(&self, attachment: bus::Attachment);
+    /// Called when the endpoint is being removed from its bus, so that it may
+    /// release any interrupt sources and address space it holds.
+    fn detach(&self) {}
     fn cfg_rw(&self, op: RWOp<'_, '_>);
     fn bar_rw(&self, bar: BarN, rwo: RWOp);
+
+    /// Offset within (port IO) `bar` of a 16-bit doorbell register, if any.
+    ///
+    /// Writes to a doorbell are delivered via [`Endpoint::doorbell_ring()`]
+    /// rather than being decoded through [`Endpoint::bar_rw()`].
+    #[allow(unused_variables)]
+    fn bar_doorbell(&self, bar: BarN) -> Option<u16> {
+        None
+    }
+    /// The doorbell in `bar` was written with `val`
+    #[allow(unused_variables)]
+    fn doorbell_ring(&self, bar: BarN, val: u16) {}
+    /// The doorbell in `bar` has been placed at `port`, or removed from the
+    /// bus if `None`.  Returns `true` if the endpoint has arranged for writes
+    /// to the doorbell to be serviced without exiting to userspace (such as
+    /// by an in-kernel device emulation), in which case no userspace doorbell
+    /// is registered.
+    #[allow(unused_variables)]
+    fn doorbell_placed(&self, bar: BarN, port: Option<u16>) -> bool {
+        false
+    }
+    /// The MMIO `bar` has been placed at `addr`, or removed from the bus if
+    /// `None`.  Returns `true` if the endpoint has mapped memory into the
+    /// guest to back the BAR (see [`crate::vmm::DeviceMem`]), in which case no
+    /// MMIO handler is registered for it.
+    #[allow(unused_variables)]
+    fn bar_placed(&self, bar: BarN, addr: Option<u64>) -> bool {
+        false
+    }
 }
 
 fn cfg_addr_parse(addr: u32) -> Option<(Bdf, u8)> {
@@ -0,0 +1,284 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Plug-in interface for PCI device models
+//!
+//! Device models maintained outside this crate can be linked into a custom
+//! propolis build by implementing [`DevicePlugin`] and adding it to a
+//! [`Registry`] (or the process-wide one via [`register`]).  Consumers which
+//! assemble machines (propolis-server, propolis-standalone) look up drivers
+//! which they do not recognize in the registry, rather than requiring their
+//! device setup logic to be patched for each new model.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::{Bdf, Endpoint};
+use crate::hw::chipset::Chipset;
+use crate::inventory::{Inventory, RegistrationError};
+use crate::vmm::Machine;
+
+use lazy_static::lazy_static;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PluginError {
+    #[error("driver {0} is already registered")]
+    DuplicateDriver(String),
+
+    #[error("no plug-in registered for driver {0}")]
+    UnknownDriver(String),
+
+    #[error("missing required option {0}")]
+    MissingOption(String),
+
+    #[error("invalid value for option {0}: {1}")]
+    InvalidOption(String, String),
+
+    #[error("inventory registration failed: {0}")]
+    Registration(#[from] RegistrationError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<PluginError> for std::io::Error {
+    fn from(e: PluginError) -> Self {
+        match e {
+            PluginError::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::Other, e.to_string()),
+        }
+    }
+}
+
+/// Resources available to a [`DevicePlugin`] while it creates a device.
+pub struct PluginCtx<'a> {
+    /// Name of the device instance, as given in the machine configuration
+    pub name: &'a str,
+    /// Location at which the device will be attached
+    pub bdf: Bdf,
+    /// Driver-specific options from the machine configuration
+    pub options: &'a BTreeMap<String, String>,
+    pub machine: &'a Machine,
+    pub inv: &'a Inventory,
+    pub log: slog::Logger,
+}
+impl PluginCtx<'_> {
+    /// Fetch a required option, failing if it is absent.
+    pub fn option(&self, name: &str) -> Result<&str, PluginError> {
+        self.options
+            .get(name)
+            .map(String::as_str)
+            .ok_or_else(|| PluginError::MissingOption(name.to_string()))
+    }
+
+    /// Fetch and parse an optional option.
+    pub fn parse_option<T>(&self, name: &str) -> Result<Option<T>, PluginError>
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.options
+            .get(name)
+            .map(|v| {
+                v.parse().map_err(|e: T::Err| {
+                    PluginError::InvalidOption(name.to_string(), e.to_string())
+                })
+            })
+            .transpose()
+    }
+}
+
+/// A PCI device model which can be instantiated by driver name.
+pub trait DevicePlugin: Send + Sync + 'static {
+    /// Name by which machine configurations refer to this device model
+    fn driver(&self) -> &'static str;
+
+    /// Create an instance of the device.
+    ///
+    /// The plug-in is responsible for registering the device (and any
+    /// children, such as backends) with `ctx.inv`, since only it knows the
+    /// concrete type.  The returned endpoint is attached at `ctx.bdf` by the
+    /// caller.
+    fn create(
+        &self,
+        ctx: &PluginCtx<'_>,
+    ) -> Result<Arc<dyn Endpoint>, PluginError>;
+}
+
+/// Collection of [`DevicePlugin`]s, keyed by driver name
+#[derive(Clone, Default)]
+pub struct Registry {
+    plugins: BTreeMap<&'static str, Arc<dyn DevicePlugin>>,
+}
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(
+        &mut self,
+        plugin: Arc<dyn DevicePlugin>,
+    ) -> Result<(), PluginError> {
+        let driver = plugin.driver();
+        if self.plugins.contains_key(driver) {
+            return Err(PluginError::DuplicateDriver(driver.to_string()));
+        }
+        self.plugins.insert(driver, plugin);
+        Ok(())
+    }
+
+    pub fn get(&self, driver: &str) -> Option<&Arc<dyn DevicePlugin>> {
+        self.plugins.get(driver)
+    }
+
+    /// Names of all registered drivers
+    pub fn drivers(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.plugins.keys().copied()
+    }
+}
+
+lazy_static! {
+    static ref GLOBAL_REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+}
+
+/// Add a plug-in to the process-wide registry.
+///
+/// Custom builds are expected to call this early in `main`, prior to any
+/// machine being assembled.
+pub fn register(plugin: Arc<dyn DevicePlugin>) -> Result<(), PluginError> {
+    GLOBAL_REGISTRY.lock().unwrap().register(plugin)
+}
+
+/// Snapshot of the process-wide plug-in registry
+pub fn registry() -> Registry {
+    GLOBAL_REGISTRY.lock().unwrap().clone()
+}
+
+/// Machine resources handed to a [`MachineHook`], through which it can add
+/// plug-in devices.
+pub struct PluginHost<'a> {
+    pub machine: &'a Machine,
+    pub inv: &'a Inventory,
+    pub chipset: &'a dyn Chipset,
+    pub log: &'a slog::Logger,
+}
+impl PluginHost<'_> {
+    /// Create a device using the plug-in registered for `driver` and attach it
+    /// to the chipset at `bdf`.
+    pub fn add_device(
+        &self,
+        registry: &Registry,
+        driver: &str,
+        name: &str,
+        bdf: Bdf,
+        options: &BTreeMap<String, String>,
+    ) -> Result<(), PluginError> {
+        let plugin = registry
+            .get(driver)
+            .ok_or_else(|| PluginError::UnknownDriver(driver.to_string()))?;
+        let ctx = PluginCtx {
+            name,
+            bdf,
+            options,
+            machine: self.machine,
+            inv: self.inv,
+            log: self.log.new(slog::o!("dev" => name.to_string())),
+        };
+        let dev = plugin.create(&ctx)?;
+        self.chipset.pci_attach(bdf, dev);
+        Ok(())
+    }
+}
+
+/// Callback, invoked once the built-in devices of a machine have been created,
+/// through which a custom build may add further devices.
+pub type MachineHook =
+    Arc<dyn Fn(&PluginHost<'_>) -> Result<(), PluginError> + Send + Sync>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::ids::pci::VENDOR_OXIDE;
+    use crate::hw::pci;
+    use crate::instance::Instance;
+    use crate::inventory::Entity;
+
+    /// A device with nothing but its configuration space
+    struct DummyDev {
+        pci_state: pci::DeviceState,
+    }
+    impl pci::Device for DummyDev {
+        fn device_state(&self) -> &pci::DeviceState {
+            &self.pci_state
+        }
+    }
+    impl Entity for DummyDev {
+        fn type_name(&self) -> &'static str {
+            "pci-dummy"
+        }
+    }
+
+    struct Dummy;
+    impl DevicePlugin for Dummy {
+        fn driver(&self) -> &'static str {
+            "dummy"
+        }
+        fn create(
+            &self,
+            ctx: &PluginCtx<'_>,
+        ) -> Result<Arc<dyn Endpoint>, PluginError> {
+            let device_id = ctx.parse_option("device-id")?.unwrap_or(0xffff);
+            let pci_state = pci::Builder::new(pci::Ident {
+                vendor_id: VENDOR_OXIDE,
+                device_id,
+                class: pci::bits::CLASS_SYSTEM,
+                subclass: pci::bits::SUBCLASS_SYSTEM_OTHER,
+                ..Default::default()
+            })
+            .finish();
+            let dev = Arc::new(DummyDev { pci_state });
+            ctx.inv.register_instance(&dev, ctx.bdf.to_string())?;
+            Ok(dev)
+        }
+    }
+
+    #[test]
+    fn duplicate_driver_rejected() {
+        let mut reg = Registry::new();
+        reg.register(Arc::new(Dummy)).unwrap();
+        assert!(matches!(
+            reg.register(Arc::new(Dummy)),
+            Err(PluginError::DuplicateDriver(_))
+        ));
+        assert!(reg.get("dummy").is_some());
+        assert!(reg.get("other").is_none());
+        assert_eq!(reg.drivers().collect::<Vec<_>>(), vec!["dummy"]);
+    }
+
+    #[test]
+    fn create_with_options() {
+        let instance = Instance::new_test().unwrap();
+        let guard = instance.lock();
+        let options = BTreeMap::new();
+        let ctx = PluginCtx {
+            name: "dummy0",
+            bdf: Bdf::new(0, 5, 0).unwrap(),
+            options: &options,
+            machine: guard.machine(),
+            inv: guard.inventory(),
+            log: slog::Logger::root(slog::Discard, slog::o!()),
+        };
+        assert!(Dummy.create(&ctx).is_ok());
+
+        // Malformed options are reported, rather than defaulted
+        let options =
+            BTreeMap::from([("device-id".to_string(), "bogus".to_string())]);
+        let ctx = PluginCtx { name: "dummy1", options: &options, ..ctx };
+        assert!(matches!(
+            Dummy.create(&ctx),
+            Err(PluginError::InvalidOption(..))
+        ));
+    }
+}
@@ -6,15 +6,19 @@
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::io::{Error as IoError, ErrorKind};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use crate::common::RWOp;
+use crate::common::{RWOp, ReadOp};
 use crate::hw::ids;
 use crate::inventory::{Inventory, RegistrationError};
+use crate::util::lockorder::{Rank, RwLock};
 use crate::vmm::Machine;
 
+use super::bits::LEN_CFG;
 use super::bridge::Bridge;
-use super::{Bdf, Bus, BusLocation, Endpoint, LintrCfg};
+use super::{
+    Bdf, Bus, BusLocation, BusNum, DevNum, Endpoint, LintrCfg, MultiFunc,
+};
 
 use thiserror::Error;
 
@@ -73,7 +77,7 @@ impl From<PciTopologyError> for IoError {
 pub struct Topology {
     buses: Vec<Bus>,
     logical_buses: BTreeMap<LogicalBusId, BusIndex>,
-    inner: Mutex<Inner>,
+    inner: RwLock<Inner>,
 }
 
 impl Topology {
@@ -98,6 +102,112 @@ impl Topology {
         }
     }
 
+    /// Attaches every function of a multi-function package to slot `dev` of a
+    /// logical bus in this topology, with `lintr_cfg` supplying the legacy
+    /// interrupt configuration of each function.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the logical bus is not present in the topology, or if any
+    /// function the package would occupy is already attached (in which case
+    /// none of the package is attached).
+    pub fn pci_attach_multifunc(
+        &self,
+        bus: LogicalBusId,
+        dev: DevNum,
+        package: MultiFunc,
+        lintr_cfg: impl Fn(BusLocation) -> Option<LintrCfg>,
+    ) -> Result<(), PciTopologyError> {
+        let Some(bus_index) = self.logical_buses.get(&bus) else {
+            return Err(PciTopologyError::LogicalBusNotFound(bus));
+        };
+        self.buses[bus_index.0]
+            .attach_multifunc(dev, package, lintr_cfg)
+            .map_err(|location| {
+                PciTopologyError::DeviceAlreadyAttached(Bdf {
+                    bus: BusNum::new(bus.0).unwrap(),
+                    location,
+                })
+            })
+    }
+
+    /// Detaches the device (if any) at the given location on a logical bus in
+    /// this topology, returning it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the logical bus is not present in the topology.
+    pub fn pci_detach(
+        &self,
+        bus: LogicalBusId,
+        location: BusLocation,
+    ) -> Result<Option<Arc<dyn Endpoint>>, PciTopologyError> {
+        if let Some(bus_index) = self.logical_buses.get(&bus) {
+            Ok(self.buses[bus_index.0].detach(location))
+        } else {
+            Err(PciTopologyError::LogicalBusNotFound(bus))
+        }
+    }
+
+    /// Returns the device (if any) at the given location on a logical bus in
+    /// this topology.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the logical bus is not present in the topology.
+    pub fn pci_device_at(
+        &self,
+        bus: LogicalBusId,
+        location: BusLocation,
+    ) -> Result<Option<Arc<dyn Endpoint>>, PciTopologyError> {
+        if let Some(bus_index) = self.logical_buses.get(&bus) {
+            Ok(self.buses[bus_index.0].device_at(location))
+        } else {
+            Err(PciTopologyError::LogicalBusNotFound(bus))
+        }
+    }
+
+    /// Hides (or reveals) the device at the given location on a logical bus in
+    /// this topology.  See [`Bus::set_hidden()`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the logical bus is not present in the topology.
+    pub fn pci_set_hidden(
+        &self,
+        bus: LogicalBusId,
+        location: BusLocation,
+        hidden: bool,
+    ) -> Result<(), PciTopologyError> {
+        if let Some(bus_index) = self.logical_buses.get(&bus) {
+            self.buses[bus_index.0].set_hidden(location, hidden);
+            Ok(())
+        } else {
+            Err(PciTopologyError::LogicalBusNotFound(bus))
+        }
+    }
+
+    /// Reads the configuration space of every device in this topology, as the
+    /// guest would observe it.  Devices are identified by their logical bus
+    /// number, which matches the guest-visible one for bus 0 only.
+    pub fn cfg_space_dump(&self) -> Vec<(Bdf, Vec<u8>)> {
+        let mut res = Vec::new();
+        for (bus_id, bus_index) in self.logical_buses.iter() {
+            for (loc, dev) in self.buses[bus_index.0].devices() {
+                // Read a dword at a time, as a guest would
+                let mut data = vec![0u8; LEN_CFG];
+                for (i, chunk) in data.chunks_exact_mut(4).enumerate() {
+                    let mut ro = ReadOp::from_buf(i * 4, chunk);
+                    dev.cfg_rw(RWOp::Read(&mut ro));
+                }
+                let bdf =
+                    Bdf::new(bus_id.0, loc.dev.get(), loc.func.get()).unwrap();
+                res.push((bdf, data));
+            }
+        }
+        res
+    }
+
     /// Issues a configuration space I/O to a device at the supplied location.
     pub fn pci_cfg_rw(
         &self,
@@ -105,7 +215,7 @@ impl Topology {
         location: BusLocation,
         rwo: RWOp,
     ) -> Option<()> {
-        let guard = self.inner.lock().unwrap();
+        let guard = self.inner.read().unwrap();
         let device = match guard.routed_buses.get(&bus) {
             Some(bus_index) => {
                 let bus = &self.buses[bus_index.0];
@@ -145,11 +255,11 @@ impl Topology {
                         logical_id.0, routed_id.0
                     )
                 });
-            let mut guard = self.inner.lock().unwrap();
+            let mut guard = self.inner.write().unwrap();
             let _old = guard.routed_buses.insert(routed_id, *bus_index);
             assert!(_old.is_none());
         } else {
-            let mut guard = self.inner.lock().unwrap();
+            let mut guard = self.inner.write().unwrap();
             let _old = guard.routed_buses.remove(&routed_id);
             assert!(_old.is_some());
         }
@@ -296,7 +406,7 @@ impl Builder {
         let topology = Arc::new(Topology {
             buses,
             logical_buses,
-            inner: Mutex::new(inner),
+            inner: RwLock::new(Rank::PciTopology, inner),
         });
 
         for bridge in &self.bridges {
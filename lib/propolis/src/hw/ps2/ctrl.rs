@@ -3,7 +3,6 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::collections::VecDeque;
-use std::convert::TryFrom;
 use std::mem::replace;
 use std::sync::{Arc, Mutex};
 
@@ -78,6 +77,11 @@ use super::keyboard::KeyEventRep;
 /// controller, which translates the keysym into a scan code representation,
 /// then places the scan code in the output buffer. The controller also notifies
 /// the guest via a keyboard interrupt.
+///
+/// Input may also be injected by the host, as keysyms for the keyboard (see
+/// [PS2Ctrl::keysym_event]) and as relative movement and button state for the
+/// mouse (see [PS2Ctrl::mouse_event]).  Mouse movement is reported to the guest
+/// in packets as the mouse's reporting mode allows.
 
 #[usdt::provider(provider = "propolis")]
 mod probes {
@@ -115,6 +119,9 @@ mod probes {
     ) {
     }
 
+    // mouse event probes
+    fn ps2ctrl_mouseevent(dx: i32, dy: i32, buttons: u8) {}
+
     // internal device buffer writes
     fn ps2ctrl_keyboard_data(v: u8) {}
     fn ps2ctrl_mouse_data(v: u8) {}
@@ -331,11 +338,25 @@ impl PS2Ctrl {
     }
 
     pub fn key_event(&self, ke: KeyEvent) {
+        self.keysym_event(ke.keysym_raw(), ke.is_pressed());
+    }
+
+    /// Whether `keysym` identifies a key which the keyboard has
+    pub fn keysym_supported(keysym: u32) -> bool {
+        KeyEventRep::new(keysym, true).is_ok()
+    }
+
+    /// Press or release the key identified by `keysym`, as the keysyms of X11
+    /// (and VNC) identify keys, returning whether the key is recognized.
+    ///
+    /// The keysym serves only to identify the physical key: a shifted
+    /// character is typed by pressing Shift (`0xffe1`) along with the key.
+    pub fn keysym_event(&self, keysym: u32, is_pressed: bool) -> bool {
         let mut state = self.state.lock().unwrap();
         let translate = state.ctrl_cfg.contains(CtrlCfg::PRI_XLATE_EN);
         let key_rep;
 
-        match KeyEventRep::try_from(ke) {
+        match KeyEventRep::new(keysym, is_pressed) {
             Ok(kr) => {
                 key_rep = kr;
             }
@@ -343,10 +364,10 @@ impl PS2Ctrl {
                 // ignore any unrecognized keys
                 probes::ps2ctrl_keyevent_dropped!(|| {
                     let set = if translate { 1 } else { 2 };
-                    let is_pressed = if ke.is_pressed() { 1 } else { 0 };
-                    (ke.keysym_raw(), is_pressed, set)
+                    let is_pressed = if is_pressed { 1 } else { 0 };
+                    (keysym, is_pressed, set)
                 });
-                return;
+                return false;
             }
         };
 
@@ -388,6 +409,16 @@ impl PS2Ctrl {
 
         state.pri_port.recv_scancode(scan_code);
         self.update_intr(&mut state);
+        true
+    }
+
+    /// Move the mouse by `dx` and `dy` (with positive values moving right and
+    /// down, as on screen), with `buttons` held.
+    pub fn mouse_event(&self, dx: i32, dy: i32, buttons: MouseButtons) {
+        let mut state = self.state.lock().unwrap();
+        probes::ps2ctrl_mouseevent!(|| (dx, dy, buttons.bits()));
+        state.aux_port.recv_movement(dx, dy, buttons);
+        self.update_intr(&mut state);
     }
 
     fn pio_rw(&self, port: u16, rwo: RWOp) {
@@ -920,11 +951,13 @@ const PS2M_R_SELF_TEST_PASS: u8 = 0xaa;
 const PS2M_R_DEVID: u8 = 0x00;
 
 bitflags! {
-    #[derive(Default)]
+    #[derive(Default, Copy, Clone, PartialEq, Eq)]
     pub struct PS2MStatus: u8 {
         const B_LEFT = 1 << 0;
         const B_RIGHT = 1 << 1;
         const B_MID = 1 << 2;
+        const BUTTONS =
+            Self::B_LEFT.bits() | Self::B_RIGHT.bits() | Self::B_MID.bits();
 
         const SCALE2 = 1 << 4;
         const ENABLE = 1 << 5;
@@ -932,12 +965,35 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Buttons held on the mouse
+    #[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct MouseButtons: u8 {
+        const LEFT = PS2MStatus::B_LEFT.bits();
+        const RIGHT = PS2MStatus::B_RIGHT.bits();
+        const MIDDLE = PS2MStatus::B_MID.bits();
+    }
+}
+
+/// Largest movement along either axis reported in a single packet
+const PS2M_MAX_DELTA: i32 = 255;
+/// Size of a movement data packet
+const PS2M_PACKET_LEN: usize = 3;
+
+// Bits of the first byte of a movement data packet, along with the buttons
+const PS2M_PKT_ALWAYS1: u8 = 1 << 3;
+const PS2M_PKT_X_SIGN: u8 = 1 << 4;
+const PS2M_PKT_Y_SIGN: u8 = 1 << 5;
+
 struct PS2Mouse {
     buf: VecDeque<u8>,
     cur_cmd: Option<u8>,
     status: PS2MStatus,
     resolution: u8,
     sample_rate: u8,
+    /// Movement not yet reported to the guest, with positive `dy` being up
+    dx: i32,
+    dy: i32,
 }
 impl PS2Mouse {
     fn new() -> Self {
@@ -947,6 +1003,8 @@ impl PS2Mouse {
             status: PS2MStatus::empty(),
             resolution: 0,
             sample_rate: 10,
+            dx: 0,
+            dy: 0,
         }
     }
     fn cmd_input(&mut self, v: u8) {
@@ -1060,23 +1118,85 @@ impl PS2Mouse {
         self.status = PS2MStatus::empty();
         self.resolution = 0;
         self.sample_rate = 10;
+        self.dx = 0;
+        self.dy = 0;
     }
     fn has_output(&self) -> bool {
         !self.buf.is_empty()
     }
     fn read_output(&mut self) -> Option<u8> {
-        self.buf.pop_front()
+        let v = self.buf.pop_front();
+        // Movement which did not fit in the buffer is reported as it drains
+        if self.buf.is_empty() {
+            self.stream_movement();
+        }
+        v
     }
     fn loopback(&mut self, v: u8) {
         self.resp(v);
     }
+    fn recv_movement(&mut self, dx: i32, dy: i32, buttons: MouseButtons) {
+        let buttons_changed =
+            self.status & PS2MStatus::BUTTONS != buttons.into_status();
+        self.status.remove(PS2MStatus::BUTTONS);
+        self.status.insert(buttons.into_status());
+        if !self.streaming() && !self.status.contains(PS2MStatus::REMOTE) {
+            // Movement is discarded while data reporting is disabled
+            return;
+        }
+        self.dx = self.dx.saturating_add(dx);
+        // The guest expects positive movement along the Y axis to be upward
+        self.dy = self.dy.saturating_sub(dy);
+
+        if buttons_changed && self.streaming() && self.packet_fits() {
+            // Report the change in buttons even if the mouse did not move
+            self.movement();
+        }
+        self.stream_movement();
+    }
+    /// Whether movement is reported as it occurs, rather than when requested
+    fn streaming(&self) -> bool {
+        self.status.contains(PS2MStatus::ENABLE)
+            && !self.status.contains(PS2MStatus::REMOTE)
+    }
+    /// Report pending movement in as many packets as fit in the buffer
+    fn stream_movement(&mut self) {
+        if !self.streaming() {
+            return;
+        }
+        while (self.dx != 0 || self.dy != 0) && self.packet_fits() {
+            self.movement();
+        }
+    }
+    /// Whether a packet fits in the buffer, without it overflowing
+    fn packet_fits(&self) -> bool {
+        PS2_KBD_BUFSZ - self.buf.len() > PS2M_PACKET_LEN
+    }
+    /// Report (up to a packet's worth of) pending movement, along with the
+    /// buttons held
     fn movement(&mut self) {
-        // no buttons, just the always-one bit
-        self.resp(0b00001000);
-        // no X movement
-        self.resp(0x00);
-        // no Y movement
-        self.resp(0x00);
+        let dx = self.dx.clamp(-PS2M_MAX_DELTA, PS2M_MAX_DELTA);
+        let dy = self.dy.clamp(-PS2M_MAX_DELTA, PS2M_MAX_DELTA);
+        self.dx -= dx;
+        self.dy -= dy;
+
+        let mut flags = (self.status & PS2MStatus::BUTTONS).bits();
+        flags |= PS2M_PKT_ALWAYS1;
+        if dx < 0 {
+            flags |= PS2M_PKT_X_SIGN;
+        }
+        if dy < 0 {
+            flags |= PS2M_PKT_Y_SIGN;
+        }
+        self.resp(flags);
+        // The low 8 bits of each 9-bit two's complement delta
+        self.resp(dx as u8);
+        self.resp(dy as u8);
+    }
+}
+impl MouseButtons {
+    fn into_status(self) -> PS2MStatus {
+        PS2MStatus::from_bits_truncate(self.bits())
     }
 }
 impl Default for PS2Mouse {
@@ -1129,3 +1249,72 @@ pub mod migrate {
         pub sample_rate: u8,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn drain(mouse: &mut PS2Mouse) -> Vec<u8> {
+        std::iter::from_fn(|| mouse.read_output()).collect()
+    }
+
+    fn streaming_mouse() -> PS2Mouse {
+        let mut mouse = PS2Mouse::new();
+        mouse.cmd_input(PS2M_CMD_DATA_REP_ENA);
+        assert_eq!(drain(&mut mouse), [PS2M_R_ACK]);
+        mouse
+    }
+
+    #[test]
+    fn movement_reported_in_packets() {
+        let mut mouse = streaming_mouse();
+        mouse.recv_movement(5, 3, MouseButtons::empty());
+        // Downward movement on screen is negative to the guest
+        assert_eq!(
+            drain(&mut mouse),
+            [PS2M_PKT_ALWAYS1 | PS2M_PKT_Y_SIGN, 5, (-3i32) as u8]
+        );
+
+        mouse.recv_movement(0, 0, MouseButtons::LEFT);
+        assert_eq!(drain(&mut mouse), [PS2M_PKT_ALWAYS1 | 1, 0, 0]);
+
+        // Large movements are spread across packets
+        mouse.recv_movement(-300, 0, MouseButtons::LEFT);
+        assert_eq!(
+            drain(&mut mouse),
+            [
+                PS2M_PKT_ALWAYS1 | PS2M_PKT_X_SIGN | 1,
+                (-255i32) as u8,
+                0,
+                PS2M_PKT_ALWAYS1 | PS2M_PKT_X_SIGN | 1,
+                (-45i32) as u8,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn movement_held_until_buffer_drains() {
+        let mut mouse = streaming_mouse();
+        mouse.recv_movement(255 * 8, 0, MouseButtons::empty());
+        assert_eq!(mouse.buf.len(), 5 * PS2M_PACKET_LEN);
+        assert_eq!(drain(&mut mouse).len(), 8 * PS2M_PACKET_LEN);
+        assert_eq!(mouse.dx, 0);
+    }
+
+    #[test]
+    fn movement_reported_on_request() {
+        let mut mouse = PS2Mouse::new();
+        // Nothing is reported, nor accumulated, until reporting is enabled
+        mouse.recv_movement(10, 0, MouseButtons::empty());
+        assert!(drain(&mut mouse).is_empty());
+
+        mouse.cmd_input(PS2M_CMD_REMOTE_MODE_SET);
+        mouse.recv_movement(10, -10, MouseButtons::empty());
+        mouse.recv_movement(10, -10, MouseButtons::empty());
+        assert_eq!(drain(&mut mouse), [PS2M_R_ACK]);
+
+        mouse.cmd_input(PS2M_CMD_READ_DATA);
+        assert_eq!(drain(&mut mouse), [PS2M_R_ACK, PS2M_PKT_ALWAYS1, 20, 20]);
+    }
+}
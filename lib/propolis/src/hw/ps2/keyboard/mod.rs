@@ -99,13 +99,74 @@ impl KeyEventRep {
 
         bytes
     }
+
+    /// The code identifying the key among the events of Linux's input
+    /// subsystem, as are also reported by virtio-input devices.
+    ///
+    /// The keys of the original PC keyboard have codes matching their base
+    /// values in Scan Code Set 1, while the extended keys are assigned codes
+    /// of their own.
+    pub(crate) fn to_key_code(&self) -> u16 {
+        let base_val = self.scan_code_1.base_val;
+        if self.scan_code_1.prefix.is_none() {
+            return base_val.into();
+        }
+        match base_val {
+            SC1_KP_ENTER => KEY_KPENTER,
+            SC1_CTRL_RIGHT => KEY_RIGHTCTRL,
+            SC1_KP_SLASH => KEY_KPSLASH,
+            SC1_ALT_RIGHT => KEY_RIGHTALT,
+            SC1_HOME => KEY_HOME,
+            SC1_UP => KEY_UP,
+            SC1_PGUP => KEY_PAGEUP,
+            SC1_LEFT => KEY_LEFT,
+            SC1_RIGHT => KEY_RIGHT,
+            SC1_END => KEY_END,
+            SC1_DOWN => KEY_DOWN,
+            SC1_PGDN => KEY_PAGEDOWN,
+            SC1_INSERT => KEY_INSERT,
+            SC1_DELETE => KEY_DELETE,
+            SC1_SUPER_LEFT => KEY_LEFTMETA,
+            SC1_SUPER_RIGHT => KEY_RIGHTMETA,
+            _ => panic!("no key code for extended scan code {:#x}", base_val),
+        }
+    }
 }
 
+// Linux input event codes of the extended keys
+const KEY_KPENTER: u16 = 96;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_KPSLASH: u16 = 98;
+const KEY_RIGHTALT: u16 = 100;
+const KEY_HOME: u16 = 102;
+const KEY_UP: u16 = 103;
+const KEY_PAGEUP: u16 = 104;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_END: u16 = 107;
+const KEY_DOWN: u16 = 108;
+const KEY_PAGEDOWN: u16 = 109;
+const KEY_INSERT: u16 = 110;
+const KEY_DELETE: u16 = 111;
+const KEY_LEFTMETA: u16 = 125;
+const KEY_RIGHTMETA: u16 = 126;
+
 impl TryFrom<KeyEvent> for KeyEventRep {
     type Error = anyhow::Error;
 
     fn try_from(keyevent: KeyEvent) -> Result<Self, Self::Error> {
-        let (base_val_1, base_val_2) = match keyevent.keysym() {
+        Self::new(keyevent.keysym_raw(), keyevent.is_pressed())
+    }
+}
+
+impl KeyEventRep {
+    /// Represent the press or release of the key identified by the raw keysym
+    /// value `keysym_raw`.
+    pub fn new(keysym_raw: u32, is_pressed: bool) -> Result<Self> {
+        let keysym = KeySym::try_from(keysym_raw).map_err(|_| {
+            anyhow!("unrecognized keysym value: 0x{:x}", keysym_raw)
+        })?;
+        let (base_val_1, base_val_2) = match keysym {
             Ascii(ascii_char) => match ascii_char {
                 AsciiChar::BackSpace => (SC1_BACKSPACE, SC2_BACKSPACE),
                 AsciiChar::Tab => (SC1_TAB, SC2_TAB),
@@ -295,18 +356,18 @@ impl TryFrom<KeyEvent> for KeyEventRep {
         if matches!((base_val_1, base_val_2), (0x0, 0x0)) {
             return Err(anyhow!(
                 "unrecognized keysym value: 0x{:x}",
-                keyevent.keysym_raw()
+                keysym_raw
             ));
         }
 
-        let prefix_1 = match keyevent.keysym() {
+        let prefix_1 = match keysym {
             AltRight | ControlRight | Home | Insert | End | PageUp
             | PageDown | KeypadSlash | KeypadEnter | SuperLeft | SuperRight
             | Left | Right | Up | Down => Some(vec![SC1_EXTENDED_PREFIX_0]),
             _ => None,
         };
 
-        let prefix_2 = match keyevent.keysym() {
+        let prefix_2 = match keysym {
             AltRight | ControlRight | Home | Insert | End | PageUp
             | PageDown | KeypadSlash | KeypadEnter | SuperLeft | SuperRight
             | Left | Right | Up | Down => Some(vec![SC2_EXTENDED_PREFIX_0]),
@@ -317,9 +378,9 @@ impl TryFrom<KeyEvent> for KeyEventRep {
         let sc2 = ScanCodeBase { base_val: base_val_2, prefix: prefix_2 };
 
         Ok(Self {
-            keysym: keyevent.keysym(),
-            keysym_raw: keyevent.keysym_raw(),
-            is_pressed: keyevent.is_pressed(),
+            keysym,
+            keysym_raw,
+            is_pressed,
             scan_code_1: sc1,
             scan_code_2: sc2,
         })
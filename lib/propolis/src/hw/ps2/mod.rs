@@ -3,4 +3,4 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod ctrl;
-mod keyboard;
+pub(crate) mod keyboard;
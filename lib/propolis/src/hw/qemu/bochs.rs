@@ -0,0 +1,541 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Display device compatible with QEMU's `bochs-display`
+//!
+//! The guest draws into a linear framebuffer held in BAR0, having chosen its
+//! mode through the Bochs VBE ("DISPI") registers, which are found in BAR2
+//! along with QEMU's extended registers.  The device has no legacy VGA
+//! interface, and so is only of use to guests with a driver for it: OVMF
+//! (through whose GOP the bootloaders and installers of UEFI guests draw) and
+//! the `bochs` DRM driver of Linux among them.
+//!
+//! The framebuffer is memory mapped into the guest wherever BAR0 is placed
+//! (see [`DeviceMem`]), so the guest draws into it without exiting to
+//! userspace.  Should that mapping fail, accesses to the BAR are emulated
+//! against the same memory.  The host reads its visible portion through
+//! [`PciBochsDisplay::frame()`], such as to serve it over VNC.  Only modes of
+//! 32 bits per pixel can be read this way.
+
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::hw::ids::pci::{VENDOR_QEMU, VENDOR_VIRTIO};
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+use crate::vmm::DeviceMem;
+use bits::*;
+
+use lazy_static::lazy_static;
+
+/// PCI Device ID of the Bochs display devices, as assigned by QEMU
+pub const BOCHS_DISPLAY_DEV_ID: u16 = 0x1111;
+/// PCI Subsystem ID of the QEMU-defined devices under the RedHat Subsystem
+/// Vendor ID
+const QEMU_SUB_DEV_ID: u16 = 0x1100;
+
+/// Size of the framebuffer, in bytes
+pub const VRAM_SIZE: usize = 16 * 1024 * 1024;
+
+const VRAM_BAR: pci::BarN = pci::BarN::BAR0;
+const REGS_BAR: pci::BarN = pci::BarN::BAR2;
+const REGS_LEN: usize = 0x1000;
+
+/// Largest mode the guest may choose
+const MAX_XRES: u16 = 2560;
+const MAX_YRES: u16 = 1600;
+const MAX_BPP: u16 = 32;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Reg {
+    /// EDID blob describing the display, which is not offered
+    Edid,
+    /// A DISPI register, by index
+    Dispi(u16),
+    /// Size of the QEMU extended registers
+    ExtSize,
+    /// Byte order of the framebuffer
+    ExtEndian,
+    Reserved,
+}
+
+lazy_static! {
+    static ref REGS: RegMap<Reg> = {
+        let mut layout = vec![(Reg::Edid, 0x400), (Reg::Reserved, 0x100)];
+        layout.extend((0..DISPI_NUM_REGS).map(|idx| (Reg::Dispi(idx), 2)));
+        layout.extend([
+            (Reg::Reserved, 0x100 - 2 * DISPI_NUM_REGS as usize),
+            (Reg::ExtSize, 4),
+            (Reg::ExtEndian, 4),
+            (Reg::Reserved, 0x9f8),
+        ]);
+        RegMap::create_packed(REGS_LEN, &layout, Some(Reg::Reserved))
+    };
+}
+
+/// The DISPI registers, through which the guest chooses the display mode
+#[derive(Copy, Clone, Debug, Default)]
+struct Dispi {
+    xres: u16,
+    yres: u16,
+    bpp: u16,
+    enable: u16,
+    bank: u16,
+    virt_width: u16,
+    virt_height: u16,
+    x_offset: u16,
+    y_offset: u16,
+}
+impl Dispi {
+    fn read(&self, idx: u16) -> u16 {
+        let caps = self.enable & VBE_DISPI_GETCAPS != 0;
+        match idx {
+            VBE_DISPI_INDEX_ID => VBE_DISPI_ID5,
+            VBE_DISPI_INDEX_XRES if caps => MAX_XRES,
+            VBE_DISPI_INDEX_XRES => self.xres,
+            VBE_DISPI_INDEX_YRES if caps => MAX_YRES,
+            VBE_DISPI_INDEX_YRES => self.yres,
+            VBE_DISPI_INDEX_BPP if caps => MAX_BPP,
+            VBE_DISPI_INDEX_BPP => self.bpp,
+            VBE_DISPI_INDEX_ENABLE => self.enable,
+            VBE_DISPI_INDEX_BANK => self.bank,
+            VBE_DISPI_INDEX_VIRT_WIDTH => self.virt_width,
+            VBE_DISPI_INDEX_VIRT_HEIGHT => self.virt_height,
+            VBE_DISPI_INDEX_X_OFFSET => self.x_offset,
+            VBE_DISPI_INDEX_Y_OFFSET => self.y_offset,
+            VBE_DISPI_INDEX_VIDEO_MEMORY_64K => (VRAM_SIZE >> 16) as u16,
+            _ => 0,
+        }
+    }
+
+    /// Writes `val` to the register at `idx`, returning true if the
+    /// framebuffer is to be cleared as a result.
+    fn write(&mut self, idx: u16, val: u16) -> bool {
+        match idx {
+            VBE_DISPI_INDEX_XRES if val <= MAX_XRES && val % 8 == 0 => {
+                self.xres = val
+            }
+            VBE_DISPI_INDEX_YRES if val <= MAX_YRES => self.yres = val,
+            VBE_DISPI_INDEX_BPP => {
+                // A depth of 0 is taken to mean 8 bits per pixel
+                let val = if val == 0 { 8 } else { val };
+                if matches!(val, 4 | 8 | 15 | 16 | 24 | 32) {
+                    self.bpp = val;
+                }
+            }
+            VBE_DISPI_INDEX_ENABLE => {
+                let enabling = val & VBE_DISPI_ENABLED != 0
+                    && self.enable & VBE_DISPI_ENABLED == 0;
+                self.enable = val;
+                if enabling {
+                    self.virt_width = self.xres;
+                    self.virt_height = self.yres;
+                    self.x_offset = 0;
+                    self.y_offset = 0;
+                    return val & VBE_DISPI_NOCLEARMEM == 0;
+                }
+            }
+            VBE_DISPI_INDEX_BANK => self.bank = val,
+            VBE_DISPI_INDEX_VIRT_WIDTH => {
+                self.virt_width = val;
+                // The virtual height is whatever fits in the framebuffer
+                let line = usize::from(val) * usize::from(self.bpp.max(8)) / 8;
+                self.virt_height = match line {
+                    0 => 0,
+                    _ => (VRAM_SIZE / line).min(usize::from(u16::MAX)) as u16,
+                };
+            }
+            VBE_DISPI_INDEX_X_OFFSET => self.x_offset = val,
+            VBE_DISPI_INDEX_Y_OFFSET => self.y_offset = val,
+            _ => {}
+        }
+        false
+    }
+}
+
+/// The visible portion of the framebuffer
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels, line by line, in little-endian xRGB with 4 bytes per pixel
+    /// (the `XR24` fourcc)
+    pub data: Vec<u8>,
+}
+
+pub struct PciBochsDisplay {
+    pci_state: pci::DeviceState,
+    dispi: Mutex<Dispi>,
+    vram: Arc<DeviceMem>,
+}
+impl PciBochsDisplay {
+    /// Create a display whose framebuffer is held in `vram`.
+    ///
+    /// # Panics
+    ///
+    /// If `vram` is not [`VRAM_SIZE`] bytes in size.
+    pub fn create(vram: Arc<DeviceMem>) -> Arc<Self> {
+        assert_eq!(vram.size(), VRAM_SIZE);
+
+        let pci_state = pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_QEMU,
+            device_id: BOCHS_DISPLAY_DEV_ID,
+            sub_vendor_id: VENDOR_VIRTIO,
+            sub_device_id: QEMU_SUB_DEV_ID,
+            class: pci::bits::CLASS_DISPLAY,
+            subclass: pci::bits::SUBCLASS_DISPLAY_OTHER,
+            revision_id: 2,
+            ..Default::default()
+        })
+        .add_bar_mmio(VRAM_BAR, VRAM_SIZE as u32)
+        .add_bar_mmio(REGS_BAR, REGS_LEN as u32)
+        .finish();
+
+        Arc::new(Self { pci_state, dispi: Mutex::new(Dispi::default()), vram })
+    }
+
+    /// Reads the visible portion of the framebuffer, if the guest has enabled
+    /// a mode of 32 bits per pixel which fits within it.
+    pub fn frame(&self) -> Option<Frame> {
+        let dispi = *self.dispi.lock().unwrap();
+        if dispi.enable & VBE_DISPI_ENABLED == 0
+            || dispi.bpp != 32
+            || dispi.xres == 0
+            || dispi.yres == 0
+        {
+            return None;
+        }
+
+        let (width, height) =
+            (usize::from(dispi.xres), usize::from(dispi.yres));
+        let line = width * 4;
+        let stride = usize::from(dispi.virt_width.max(dispi.xres)) * 4;
+        let start = usize::from(dispi.y_offset) * stride
+            + usize::from(dispi.x_offset) * 4;
+        if start + (height - 1) * stride + line > VRAM_SIZE {
+            return None;
+        }
+
+        let vram = self.vram.region();
+        let mut data = vec![0u8; line * height];
+        for (row, buf) in data.chunks_exact_mut(line).enumerate() {
+            let off = start + row * stride;
+            vram.subregion(off, line)?.read_bytes(buf).ok()?;
+        }
+        Some(Frame { width: width as u32, height: height as u32, data })
+    }
+
+    /// Emulates an access to the framebuffer, which is only necessary should
+    /// it have failed to be mapped into the guest.
+    fn vram_rw(&self, rwo: RWOp) {
+        let vram = self.vram.region();
+        match rwo {
+            RWOp::Read(ro) => {
+                let mut buf = vec![0u8; ro.len()];
+                if let Some(sub) = vram.subregion(ro.offset(), ro.len()) {
+                    let _ = sub.read_bytes(&mut buf);
+                }
+                ro.write_bytes(&buf);
+            }
+            RWOp::Write(wo) => {
+                let mut buf = vec![0u8; wo.len()];
+                wo.read_bytes(&mut buf);
+                if let Some(sub) = vram.subregion(wo.offset(), wo.len()) {
+                    let _ = sub.write_bytes(&buf);
+                }
+            }
+        }
+    }
+
+    fn clear_vram(&self) {
+        let _ = self.vram.region().write_byte(0, VRAM_SIZE);
+    }
+
+    fn regs_rw(&self, mut rwo: RWOp) {
+        REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => match id {
+                Reg::Dispi(idx) => {
+                    ro.write_u16(self.dispi.lock().unwrap().read(*idx))
+                }
+                Reg::ExtSize => ro.write_u32(QEXT_SIZE),
+                Reg::ExtEndian => ro.write_u32(QEXT_LITTLE_ENDIAN),
+                Reg::Edid | Reg::Reserved => ro.fill(0),
+            },
+            RWOp::Write(wo) => {
+                // The framebuffer is always little-endian, so a request for
+                // any other byte order is ignored.
+                if let Reg::Dispi(idx) = id {
+                    let clear =
+                        self.dispi.lock().unwrap().write(*idx, wo.read_u16());
+                    if clear {
+                        self.clear_vram();
+                    }
+                }
+            }
+        });
+    }
+}
+impl pci::Device for PciBochsDisplay {
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+
+    fn bar_rw(&self, bar: pci::BarN, rwo: RWOp) {
+        match bar {
+            VRAM_BAR => self.vram_rw(rwo),
+            REGS_BAR => self.regs_rw(rwo),
+            _ => panic!("unexpected BAR {:?}", bar),
+        }
+    }
+
+    fn bar_placed(&self, bar: pci::BarN, addr: Option<u64>) -> bool {
+        // Accesses are emulated through `bar_rw()` should the framebuffer
+        // fail to be mapped.
+        bar == VRAM_BAR && self.vram.place(addr.map(|a| a as usize)).is_ok()
+    }
+}
+impl Entity for PciBochsDisplay {
+    fn type_name(&self) -> &'static str {
+        "pci-bochs-display"
+    }
+    fn reset(&self) {
+        *self.dispi.lock().unwrap() = Dispi::default();
+        self.clear_vram();
+        self.pci_state.reset(self);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciBochsDisplay {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let dispi = *self.dispi.lock().unwrap();
+        // Only the framebuffer up to its last non-zero byte is carried, as
+        // most of it is typically left untouched by the guest.
+        let mut vram = vec![0u8; VRAM_SIZE];
+        self.vram.region().read_bytes(&mut vram)?;
+        let used = vram.iter().rposition(|b| *b != 0).map_or(0, |pos| pos + 1);
+        vram.truncate(used);
+        output.push(
+            migrate::BochsDisplayV1 {
+                xres: dispi.xres,
+                yres: dispi.yres,
+                bpp: dispi.bpp,
+                enable: dispi.enable,
+                bank: dispi.bank,
+                virt_width: dispi.virt_width,
+                virt_height: dispi.virt_height,
+                x_offset: dispi.x_offset,
+                y_offset: dispi.y_offset,
+                vram,
+            }
+            .into(),
+        )?;
+
+        MigrateMulti::export(&self.pci_state, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::BochsDisplayV1 = offer.take()?;
+        if input.vram.len() > VRAM_SIZE {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "framebuffer of {:#x} bytes exceeds {:#x}",
+                input.vram.len(),
+                VRAM_SIZE
+            )));
+        }
+        *self.dispi.lock().unwrap() = Dispi {
+            xres: input.xres,
+            yres: input.yres,
+            bpp: input.bpp,
+            enable: input.enable,
+            bank: input.bank,
+            virt_width: input.virt_width,
+            virt_height: input.virt_height,
+            x_offset: input.x_offset,
+            y_offset: input.y_offset,
+        };
+        let vram = self.vram.region();
+        vram.write_byte(0, VRAM_SIZE)?;
+        vram.write_bytes(&input.vram)?;
+
+        MigrateMulti::import(&self.pci_state, offer, ctx)
+    }
+}
+
+mod bits {
+    #![allow(unused)]
+
+    pub const DISPI_NUM_REGS: u16 = 0xb;
+
+    pub const VBE_DISPI_INDEX_ID: u16 = 0x0;
+    pub const VBE_DISPI_INDEX_XRES: u16 = 0x1;
+    pub const VBE_DISPI_INDEX_YRES: u16 = 0x2;
+    pub const VBE_DISPI_INDEX_BPP: u16 = 0x3;
+    pub const VBE_DISPI_INDEX_ENABLE: u16 = 0x4;
+    pub const VBE_DISPI_INDEX_BANK: u16 = 0x5;
+    pub const VBE_DISPI_INDEX_VIRT_WIDTH: u16 = 0x6;
+    pub const VBE_DISPI_INDEX_VIRT_HEIGHT: u16 = 0x7;
+    pub const VBE_DISPI_INDEX_X_OFFSET: u16 = 0x8;
+    pub const VBE_DISPI_INDEX_Y_OFFSET: u16 = 0x9;
+    pub const VBE_DISPI_INDEX_VIDEO_MEMORY_64K: u16 = 0xa;
+
+    pub const VBE_DISPI_ID5: u16 = 0xb0c5;
+
+    pub const VBE_DISPI_ENABLED: u16 = 0x01;
+    pub const VBE_DISPI_GETCAPS: u16 = 0x02;
+    pub const VBE_DISPI_8BIT_DAC: u16 = 0x20;
+    pub const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
+    pub const VBE_DISPI_NOCLEARMEM: u16 = 0x80;
+
+    pub const QEXT_SIZE: u32 = 8;
+    pub const QEXT_LITTLE_ENDIAN: u32 = 0x1e1e_1e1e;
+    pub const QEXT_BIG_ENDIAN: u32 = 0xbebe_bebe;
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct BochsDisplayV1 {
+        pub xres: u16,
+        pub yres: u16,
+        pub bpp: u16,
+        pub enable: u16,
+        pub bank: u16,
+        pub virt_width: u16,
+        pub virt_height: u16,
+        pub x_offset: u16,
+        pub y_offset: u16,
+        pub vram: Vec<u8>,
+    }
+    impl Schema<'_> for BochsDisplayV1 {
+        fn id() -> SchemaId {
+            ("pci-bochs-display", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmm::PhysMap;
+
+    fn display() -> Arc<PciBochsDisplay> {
+        let map = PhysMap::new_test(VRAM_SIZE);
+        PciBochsDisplay::create(map.create_test_device_mem(VRAM_SIZE).unwrap())
+    }
+
+    fn dispi_write(dev: &PciBochsDisplay, idx: u16, val: u16) {
+        let buf = val.to_le_bytes();
+        let off = 0x500 + 2 * usize::from(idx);
+        dev.regs_rw(RWOp::Write(&mut WriteOp::from_buf(off, &buf)));
+    }
+
+    fn dispi_read(dev: &PciBochsDisplay, idx: u16) -> u16 {
+        let mut buf = [0u8; 2];
+        let off = 0x500 + 2 * usize::from(idx);
+        dev.regs_rw(RWOp::Read(&mut ReadOp::from_buf(off, &mut buf)));
+        u16::from_le_bytes(buf)
+    }
+
+    fn set_mode(dev: &PciBochsDisplay, xres: u16, yres: u16, bpp: u16) {
+        dispi_write(dev, VBE_DISPI_INDEX_ENABLE, 0);
+        dispi_write(dev, VBE_DISPI_INDEX_XRES, xres);
+        dispi_write(dev, VBE_DISPI_INDEX_YRES, yres);
+        dispi_write(dev, VBE_DISPI_INDEX_BPP, bpp);
+        dispi_write(
+            dev,
+            VBE_DISPI_INDEX_ENABLE,
+            VBE_DISPI_ENABLED | VBE_DISPI_LFB_ENABLED,
+        );
+    }
+
+    #[test]
+    fn identifies_and_reports_caps() {
+        let dev = display();
+        assert_eq!(dispi_read(&dev, VBE_DISPI_INDEX_ID), VBE_DISPI_ID5);
+        assert_eq!(
+            dispi_read(&dev, VBE_DISPI_INDEX_VIDEO_MEMORY_64K),
+            (VRAM_SIZE >> 16) as u16
+        );
+
+        dispi_write(&dev, VBE_DISPI_INDEX_ENABLE, VBE_DISPI_GETCAPS);
+        assert_eq!(dispi_read(&dev, VBE_DISPI_INDEX_XRES), MAX_XRES);
+        assert_eq!(dispi_read(&dev, VBE_DISPI_INDEX_BPP), MAX_BPP);
+        dispi_write(&dev, VBE_DISPI_INDEX_ENABLE, 0);
+        assert_eq!(dispi_read(&dev, VBE_DISPI_INDEX_XRES), 0);
+
+        let mut buf = [0u8; 4];
+        dev.regs_rw(RWOp::Read(&mut ReadOp::from_buf(0x604, &mut buf)));
+        assert_eq!(u32::from_le_bytes(buf), QEXT_LITTLE_ENDIAN);
+    }
+
+    #[test]
+    fn frame_follows_mode() {
+        let dev = display();
+        assert!(dev.frame().is_none());
+
+        set_mode(&dev, 16, 8, 32);
+        assert_eq!(dispi_read(&dev, VBE_DISPI_INDEX_VIRT_WIDTH), 16);
+
+        // Draw the second pixel of the second line
+        let pixel = [0x11, 0x22, 0x33, 0x00];
+        let off = 16 * 4 + 4;
+        dev.vram_rw(RWOp::Write(&mut WriteOp::from_buf(off, &pixel)));
+
+        let frame = dev.frame().unwrap();
+        assert_eq!((frame.width, frame.height), (16, 8));
+        assert_eq!(frame.data.len(), 16 * 8 * 4);
+        assert_eq!(frame.data[off..off + 4], pixel);
+
+        // Panning the display moves the pixel within the frame
+        dispi_write(&dev, VBE_DISPI_INDEX_Y_OFFSET, 1);
+        assert_eq!(dev.frame().unwrap().data[4..8], pixel);
+
+        // Modes of other depths cannot be read
+        set_mode(&dev, 16, 8, 16);
+        assert!(dev.frame().is_none());
+    }
+
+    #[test]
+    fn framebuffer_follows_bar() {
+        let dev = display();
+        let addr = 0xc000_0000;
+        assert!(pci::Device::bar_placed(dev.as_ref(), VRAM_BAR, Some(addr)));
+        assert_eq!(dev.vram.placement(), Some(addr as usize));
+        assert!(!pci::Device::bar_placed(dev.as_ref(), REGS_BAR, Some(addr)));
+
+        pci::Device::bar_placed(dev.as_ref(), VRAM_BAR, None);
+        assert_eq!(dev.vram.placement(), None);
+    }
+
+    #[test]
+    fn enabling_clears_framebuffer() {
+        let dev = display();
+        dev.vram_rw(RWOp::Write(&mut WriteOp::from_buf(0, &[0xff; 4])));
+        set_mode(&dev, 16, 8, 32);
+        assert_eq!(dev.frame().unwrap().data[..4], [0; 4]);
+
+        // ...unless asked not to
+        dev.vram_rw(RWOp::Write(&mut WriteOp::from_buf(0, &[0xff; 4])));
+        dispi_write(&dev, VBE_DISPI_INDEX_ENABLE, 0);
+        dispi_write(
+            &dev,
+            VBE_DISPI_INDEX_ENABLE,
+            VBE_DISPI_ENABLED | VBE_DISPI_NOCLEARMEM,
+        );
+        assert_eq!(dev.frame().unwrap().data[..4], [0xff; 4]);
+    }
+}
@@ -6,23 +6,25 @@ use std::sync::Arc;
 
 use crate::chardev::{BlockingSource, BlockingSourceConsumer, ConsumerCell};
 use crate::common::*;
-use crate::pio::{PioBus, PioFn};
+use crate::pio::{self, PioBus, PioFn};
 
-const QEMU_DEBUG_IOPORT: u16 = 0x0402;
+/// The port at which QEMU (and OVMF) place the debug console by default
+pub const QEMU_DEBUG_IOPORT: u16 = 0x0402;
 const QEMU_DEBUG_IDENT: u8 = 0xe9;
 
 pub struct QemuDebugPort {
     consumer: ConsumerCell,
 }
 impl QemuDebugPort {
-    pub fn create(pio: &PioBus) -> Arc<Self> {
+    /// Create a debug port at `port`, failing if it is already claimed.
+    pub fn create(pio: &PioBus, port: u16) -> pio::Result<Arc<Self>> {
         let this = Arc::new(Self { consumer: ConsumerCell::new() });
 
         let piodev = this.clone();
         let piofn = Arc::new(move |_port: u16, rwo: RWOp| piodev.pio_rw(rwo))
             as Arc<PioFn>;
-        pio.register(QEMU_DEBUG_IOPORT, 1, piofn).unwrap();
-        this
+        pio.register(port, 1, piofn)?;
+        Ok(this)
     }
 
     fn pio_rw(&self, rwo: RWOp) {
@@ -8,11 +8,13 @@ use std::sync::{Arc, Mutex, MutexGuard};
 use crate::accessors::MemAccessor;
 use crate::common::*;
 use crate::migrate::*;
+use crate::offload::{Budget, Offload};
 use crate::pio::{PioBus, PioFn};
 use crate::vmm::MemCtx;
 use bits::*;
 
 use byteorder::{ByteOrder, BE, LE};
+use futures::future::BoxFuture;
 
 pub type Result = std::result::Result<(), &'static str>;
 
@@ -239,14 +241,9 @@ impl ItemDir {
         if offset >= dir_limit || buf_limit > dir_limit {
             return Err("read beyond end");
         }
-        while ro.avail() > 0 {
-            let idx = offset / ent_size;
-            let entry_off = offset % ent_size;
-            let entry = self.file_entry(idx);
-            let entry_buf = entry.bytes();
-            let copy_len = usize::min(entry_buf.len() - entry_off, ro.avail());
-            ro.write_bytes(&entry_buf[entry_off..(entry_off + copy_len)]);
-        }
+        ro.for_each_chunk(ent_size, |idx, ro| {
+            ro.write_from(self.file_entry(idx).bytes())
+        });
         Ok(())
     }
     fn read(&self, ro: &mut ReadOp) -> Result {
@@ -283,6 +280,7 @@ pub struct FwCfgBuilder {
     entries: BTreeMap<u16, Entry>,
     name_to_sel: BTreeMap<String, u16>,
     next_sel: u16,
+    offload: Option<(Arc<Offload>, Budget)>,
 }
 
 impl FwCfgBuilder {
@@ -291,6 +289,7 @@ impl FwCfgBuilder {
             entries: BTreeMap::new(),
             name_to_sel: BTreeMap::new(),
             next_sel: ITEMS_FILE_START,
+            offload: None,
         };
         this.add_legacy(
             LegacyId::Signature,
@@ -349,6 +348,16 @@ impl FwCfgBuilder {
         }
     }
 
+    /// Perform DMA transfers which exceed `budget` on the workers of
+    /// `offload`, rather than on the vCPU which initiated them.
+    ///
+    /// The guest observes completion of a DMA transfer by polling for the
+    /// control field of its request to be cleared, so it is able to resume
+    /// execution while a large transfer (such as a kernel image) is underway.
+    pub fn offload(&mut self, offload: Arc<Offload>, budget: Budget) {
+        self.offload = Some((offload, budget));
+    }
+
     pub fn finalize(self) -> Arc<FwCfg> {
         let mut sorted_names: Vec<(String, u16)> =
             self.name_to_sel.into_iter().collect();
@@ -356,7 +365,7 @@ impl FwCfgBuilder {
         sorted_names.sort();
         let dir = ItemDir { entries: self.entries, sorted_names };
 
-        Arc::new(FwCfg::new(dir))
+        Arc::new(FwCfg::new(dir, self.offload))
     }
 }
 
@@ -377,13 +386,15 @@ pub struct FwCfg {
     dir: ItemDir,
     state: Mutex<AccessState>,
     acc_mem: MemAccessor,
+    offload: Option<(Arc<Offload>, Budget)>,
 }
 impl FwCfg {
-    fn new(dir: ItemDir) -> Self {
+    fn new(dir: ItemDir, offload: Option<(Arc<Offload>, Budget)>) -> Self {
         Self {
             dir,
             state: Mutex::new(Default::default()),
             acc_mem: MemAccessor::new_orphan(),
+            offload,
         }
     }
 
@@ -403,7 +414,7 @@ impl FwCfg {
         }
     }
 
-    fn pio_rw(&self, port: u16, rwo: RWOp) {
+    fn pio_rw(self: &Arc<Self>, port: u16, rwo: RWOp) {
         let mut state = self.state.lock().unwrap();
         match port {
             FW_CFG_IOP_SELECTOR => match rwo {
@@ -506,7 +517,10 @@ impl FwCfg {
         }
     }
 
-    fn dma_initiate(&self, mut state: MutexGuard<AccessState>) -> Result {
+    fn dma_initiate(
+        self: &Arc<Self>,
+        mut state: MutexGuard<AccessState>,
+    ) -> Result {
         let req_addr = state.dma_addr();
         // initiating a DMA transfer clears the addr contents
         state.addr_high = 0;
@@ -523,7 +537,38 @@ impl FwCfg {
 
         let dma_req = FwCfgDmaReq::from_bytes(&desc_buf);
 
-        let res = self.dma_operation(state, &dma_req, &mem);
+        if let Some((offload, budget)) = self.offload.as_ref() {
+            let ctrl = FwCfgDmaCtrl::from_bits_truncate(dma_req.ctrl);
+            if ctrl.intersects(FwCfgDmaCtrl::READ | FwCfgDmaCtrl::WRITE)
+                && !budget.permits(dma_req.len as usize)
+            {
+                // Leave the control field of the request untouched (and thus
+                // still pending, from the guest's perspective) until the
+                // transfer is complete.
+                drop(state);
+                drop(mem);
+                let this = self.clone();
+                offload.submit(move || {
+                    let Some(mem) = this.acc_mem.access() else {
+                        return;
+                    };
+                    let state = this.state.lock().unwrap();
+                    let _ = this.dma_complete(state, req_addr, &dma_req, &mem);
+                });
+                return Ok(());
+            }
+        }
+
+        self.dma_complete(state, req_addr, &dma_req, &mem)
+    }
+    fn dma_complete(
+        &self,
+        state: MutexGuard<AccessState>,
+        req_addr: u64,
+        dma_req: &FwCfgDmaReq,
+        mem: &MemCtx,
+    ) -> Result {
+        let res = self.dma_operation(state, dma_req, mem);
 
         if !mem.write(
             GuestAddr(req_addr),
@@ -657,6 +702,14 @@ impl Entity for FwCfg {
     fn type_name(&self) -> &'static str {
         "qemu-fwcfg"
     }
+    fn paused(&self) -> BoxFuture<'static, ()> {
+        // Offloaded DMA transfers write to guest memory, so they must all be
+        // complete before the device is considered paused.
+        match self.offload.as_ref() {
+            Some((offload, _)) => offload.idle(),
+            None => Box::pin(futures::future::ready(())),
+        }
+    }
     fn migrate(&self) -> Migrator {
         Migrator::Single(self)
     }
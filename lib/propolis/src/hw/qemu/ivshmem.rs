@@ -0,0 +1,350 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Inter-VM shared memory, compatible with QEMU's `ivshmem-plain`, device
+//!
+//! A file on the host backs a memory segment which is exposed to the guest
+//! through BAR2.  Instances on the same host which attach the same file share
+//! its contents, providing a channel through which they can exchange data
+//! without involving the network.  BAR0 holds the (interrupt) registers of the
+//! `ivshmem-doorbell` variant, which are inert here: the guests must agree on
+//! their own means of signaling, such as polling.
+//!
+//! The file is named relative to a directory chosen by the host, outside of
+//! which no file is opened (symbolic links included).  It is created if it does
+//! not already exist, and removed again when the last device attached to it is
+//! dropped (at instance teardown).  Placing the directory on a memory-backed
+//! filesystem (such as `/tmp`) avoids writes to disk.
+//!
+//! Guest accesses to the segment are emulated, as the VMM can only map into the
+//! guest memory segments which it owns, and so not one shared among instances.
+//! They are carried out with positioned reads and writes of the file rather
+//! than through a mapping of it, so that another process truncating the file
+//! cannot fault the VMM: reads past its end return zeroes.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+use crate::common::*;
+use crate::hostres;
+use crate::hw::ids::pci::VENDOR_VIRTIO;
+use crate::hw::pci;
+use crate::migrate::*;
+
+/// PCI Device ID of the ivshmem device, as assigned by QEMU
+pub const IVSHMEM_DEV_ID: u16 = 0x1110;
+
+const REGS_BAR: pci::BarN = pci::BarN::BAR0;
+const REGS_LEN: u32 = 0x100;
+const SEGMENT_BAR: pci::BarN = pci::BarN::BAR2;
+
+/// Attempts at opening the backing file, which is retried if it is removed by
+/// its last user in the meantime
+const OPEN_ATTEMPTS: usize = 4;
+
+/// A host file backing the shared segment
+///
+/// Each segment holds a shared `flock(2)` on the file, so that the last one
+/// dropped, being able to take the lock exclusively, knows to remove it.
+struct Segment {
+    name: CString,
+    /// The directory containing the file, through which it is removed even if
+    /// the process has since been confined elsewhere
+    dir: File,
+    fp: File,
+    len: usize,
+    _held: hostres::Held,
+}
+impl Segment {
+    fn open(dir: &Path, name: &str, len: usize) -> Result<Self> {
+        let mut components = Path::new(name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid shared memory file name {:?}", name),
+            ));
+        }
+        let name = CString::new(name.as_bytes())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let dir = File::open(crate::harden::host_path(dir)?)?;
+        if !dir.metadata()?.is_dir() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "shared memory directory is not a directory",
+            ));
+        }
+
+        for _ in 0..OPEN_ATTEMPTS {
+            let fp = Self::open_at(&dir, &name)?;
+            // Having been locked, the file cannot be removed by another user,
+            // but it may have been before the lock was taken.
+            if !Self::is_linked(&dir, &name, &fp)? {
+                continue;
+            }
+            let existing = fp.metadata()?.len();
+            if existing == 0 {
+                fp.set_len(len as u64)?;
+            } else if existing != len as u64 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{:?} has size {:#x}, expected {:#x}",
+                        name, existing, len
+                    ),
+                ));
+            }
+
+            let res_owner = hostres::Owner::new(format!("ivshmem-{:?}", name));
+            return Ok(Self {
+                name,
+                dir,
+                fp,
+                len,
+                _held: res_owner.hold(hostres::Kind::Fd, 2),
+            });
+        }
+        Err(Error::new(
+            ErrorKind::Other,
+            format!("{:?} was repeatedly removed while opening", name),
+        ))
+    }
+
+    /// Open (creating if need be) the file `name` in `dir`, and lock it shared
+    fn open_at(dir: &File, name: &CString) -> Result<File> {
+        let fd = unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_RDWR
+                    | libc::O_CREAT
+                    | libc::O_NOFOLLOW
+                    | libc::O_CLOEXEC,
+                0o600 as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        // Safety: The descriptor was just opened, and is owned by nothing else
+        let fp = unsafe { File::from_raw_fd(fd) };
+        if !fp.metadata()?.is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is not a regular file", name),
+            ));
+        }
+        if unsafe { libc::flock(fp.as_raw_fd(), libc::LOCK_SH) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(fp)
+    }
+
+    /// Whether `fp` is still the file named `name` in `dir`
+    fn is_linked(dir: &File, name: &CString, fp: &File) -> Result<bool> {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            libc::fstatat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                &mut st,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if res != 0 {
+            let err = Error::last_os_error();
+            return match err.kind() {
+                ErrorKind::NotFound => Ok(false),
+                _ => Err(err),
+            };
+        }
+        let meta = fp.metadata()?;
+        Ok(st.st_dev == meta.dev() && st.st_ino == meta.ino())
+    }
+
+    fn read(&self, ro: &mut ReadOp) {
+        let off = ro.offset();
+        let mut buf = vec![0u8; ro.len()];
+        assert!(off + buf.len() <= self.len);
+        // Whatever lies past the end of a file truncated by another process,
+        // or could not be read, reads as zeroes.
+        let mut done = 0;
+        while done < buf.len() {
+            match self.fp.read_at(&mut buf[done..], (off + done) as u64) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => done += n,
+            }
+        }
+        ro.write_bytes(&buf);
+    }
+
+    fn write(&self, wo: &mut WriteOp) {
+        let off = wo.offset();
+        let mut buf = vec![0u8; wo.len()];
+        assert!(off + buf.len() <= self.len);
+        wo.read_bytes(&mut buf);
+        // As with a write to memory, there is no means to report a failure to
+        // the guest, so the data is dropped.
+        let _ = self.fp.write_all_at(&buf, off as u64);
+    }
+}
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // Only once no other user holds the file is it removed.
+        let fd = self.fp.as_raw_fd();
+        if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } == 0
+            && Self::is_linked(&self.dir, &self.name, &self.fp).unwrap_or(false)
+        {
+            unsafe {
+                libc::unlinkat(self.dir.as_raw_fd(), self.name.as_ptr(), 0);
+            }
+        }
+    }
+}
+
+pub struct PciIvShmem {
+    pci_state: pci::DeviceState,
+    segment: Segment,
+}
+impl PciIvShmem {
+    /// Create a device sharing the `size` bytes of the file `name` within
+    /// `dir`.  The name must refer to a file directly within the directory.
+    ///
+    /// The size must be a power of two, of at least one page.
+    pub fn create(
+        dir: impl AsRef<Path>,
+        name: &str,
+        size: u64,
+    ) -> Result<Arc<Self>> {
+        if !size.is_power_of_two() || size < PAGE_SIZE as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid shared memory size {:#x}", size),
+            ));
+        }
+        let len = usize::try_from(size)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let segment = Segment::open(dir.as_ref(), name, len)?;
+
+        let pci_state = pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_VIRTIO,
+            device_id: IVSHMEM_DEV_ID,
+            sub_vendor_id: VENDOR_VIRTIO,
+            sub_device_id: IVSHMEM_DEV_ID,
+            class: pci::bits::CLASS_MEMORY,
+            revision_id: 1,
+            ..Default::default()
+        })
+        .add_bar_mmio(REGS_BAR, REGS_LEN)
+        .add_bar_mmio64(SEGMENT_BAR, size)
+        .finish();
+
+        Ok(Arc::new(Self { pci_state, segment }))
+    }
+}
+impl pci::Device for PciIvShmem {
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+
+    fn bar_rw(&self, bar: pci::BarN, rwo: RWOp) {
+        match (bar, rwo) {
+            (SEGMENT_BAR, RWOp::Read(ro)) => self.segment.read(ro),
+            (SEGMENT_BAR, RWOp::Write(wo)) => self.segment.write(wo),
+            // The doorbell registers read as zero and ignore writes
+            (_, RWOp::Read(ro)) => ro.fill(0),
+            (_, RWOp::Write(_)) => {}
+        }
+    }
+}
+impl Entity for PciIvShmem {
+    fn type_name(&self) -> &'static str {
+        "pci-ivshmem"
+    }
+    fn reset(&self) {
+        // The contents of the segment belong to all of the instances sharing
+        // it, so they are left untouched.
+        self.pci_state.reset(self);
+    }
+    fn migrate(&self) -> Migrator {
+        // The segment is only shared among instances on the same host.
+        Migrator::NonMigratable
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_between_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shm");
+
+        let first = PciIvShmem::create(dir.path(), "shm", 0x1000).unwrap();
+        let second = PciIvShmem::create(dir.path(), "shm", 0x1000).unwrap();
+        assert!(PciIvShmem::create(dir.path(), "shm", 0x2000).is_err());
+
+        let data = 0x1122_3344_5566_7788u64.to_le_bytes();
+        first.segment.write(&mut WriteOp::from_buf(0xff8, &data));
+        let mut buf = [0u8; 8];
+        second.segment.read(&mut ReadOp::from_buf(0xff8, &mut buf));
+        assert_eq!(buf, data);
+
+        // The file is removed along with the last device attached to it,
+        // whichever created it
+        drop(first);
+        assert!(path.exists());
+        drop(second);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn truncated_by_another_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let dev = PciIvShmem::create(dir.path(), "shm", 0x2000).unwrap();
+        let data = [0xa5u8; 8];
+        dev.segment.write(&mut WriteOp::from_buf(0x1ff8, &data));
+
+        std::fs::File::options()
+            .write(true)
+            .open(dir.path().join("shm"))
+            .unwrap()
+            .set_len(0x1000)
+            .unwrap();
+        let mut buf = [0xffu8; 8];
+        dev.segment.read(&mut ReadOp::from_buf(0x1ff8, &mut buf));
+        assert_eq!(buf, [0u8; 8]);
+    }
+
+    #[test]
+    fn confined_to_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = dir.path().join("inner");
+        std::fs::create_dir(&inner).unwrap();
+        for name in ["../shm", "/tmp/shm", "a/b", ".", ""] {
+            assert!(PciIvShmem::create(&inner, name, 0x1000).is_err());
+        }
+
+        std::os::unix::fs::symlink(dir.path().join("target"), inner.join("l"))
+            .unwrap();
+        assert!(PciIvShmem::create(&inner, "l", 0x1000).is_err());
+        assert!(!dir.path().join("target").exists());
+    }
+
+    #[test]
+    fn invalid_size() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(PciIvShmem::create(dir.path(), "shm", 0x1800).is_err());
+        assert!(PciIvShmem::create(dir.path(), "shm", 0x800).is_err());
+        assert!(!dir.path().join("shm").exists());
+    }
+}
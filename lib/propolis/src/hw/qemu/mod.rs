@@ -2,6 +2,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod bochs;
 pub mod debug;
 pub mod fwcfg;
+pub mod ivshmem;
+pub mod pvpanic;
 pub mod ramfb;
@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Guest panic notification, compatible with QEMU's `pvpanic` devices
+//!
+//! A guest kernel which panics writes to the device to report it, rather than
+//! leaving the host to guess at why the guest stopped making progress.  Linux
+//! (through its `pvpanic` drivers) and Windows (through that of virtio-win) do
+//! so when they find one.  Reading the device's register yields the set of
+//! events it supports; writing it reports one or more of those events.
+//!
+//! The device is offered in two forms: an ISA device with a single I/O port
+//! (conventionally 0x505), which the guest discovers through an ACPI device
+//! with the ID `QEMU0001`, or a PCI function (`pvpanic-pci`) whose BAR0 holds
+//! the register, which needs no description in firmware tables.
+
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::hw::ids::pci::VENDOR_REDHAT;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::pio::{self, PioBus, PioFn};
+
+/// The port at which QEMU places the ISA device by default
+pub const PVPANIC_IOPORT: u16 = 0x505;
+
+/// ACPI hardware ID of the ISA device
+pub const PVPANIC_ACPI_HID: &str = "QEMU0001";
+
+/// PCI Device ID of the `pvpanic-pci` device, as assigned by QEMU
+pub const PVPANIC_PCI_DEV_ID: u16 = 0x0011;
+
+const REGS_BAR: pci::BarN = pci::BarN::BAR0;
+const REGS_LEN: u32 = 0x10;
+
+/// The guest kernel has panicked
+const EVENT_PANICKED: u8 = 1 << 0;
+/// The guest kernel has loaded a crash kernel (such as for kdump) in response
+/// to a panic
+const EVENT_CRASH_LOADED: u8 = 1 << 1;
+const EVENTS_SUPPORTED: u8 = EVENT_PANICKED | EVENT_CRASH_LOADED;
+
+/// An event reported by the guest through the device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PanicEvent {
+    /// The guest kernel panicked, and will go no further
+    Panicked,
+    /// The guest kernel panicked, and loaded a crash kernel to capture a dump
+    CrashLoaded,
+}
+
+/// Called with each event reported by the guest
+pub type PanicHandler = Box<dyn Fn(PanicEvent) + Send + Sync>;
+
+/// State shared by both forms of the device
+struct Reporter {
+    handler: Mutex<Option<PanicHandler>>,
+    log: slog::Logger,
+}
+impl Reporter {
+    fn new(log: slog::Logger) -> Self {
+        Self { handler: Mutex::new(None), log }
+    }
+
+    fn read(&self, ro: &mut ReadOp) {
+        if ro.offset() == 0 {
+            ro.write_u8(EVENTS_SUPPORTED);
+        }
+        ro.fill(0);
+    }
+
+    fn write(&self, wo: &mut WriteOp) {
+        if wo.offset() != 0 {
+            return;
+        }
+        let val = wo.read_u8();
+        let events = [
+            (EVENT_PANICKED, PanicEvent::Panicked),
+            (EVENT_CRASH_LOADED, PanicEvent::CrashLoaded),
+        ];
+        let handler = self.handler.lock().unwrap();
+        for (_, event) in events.iter().filter(|(bit, _)| val & bit != 0) {
+            slog::error!(self.log, "guest reported panic"; "event" => ?event);
+            if let Some(handler) = handler.as_ref() {
+                handler(*event);
+            }
+        }
+    }
+}
+
+/// The ISA form of the device
+pub struct QemuPvpanic {
+    reporter: Reporter,
+}
+impl QemuPvpanic {
+    /// Create a pvpanic device at `port`, failing if it is already claimed.
+    pub fn create(
+        pio: &PioBus,
+        port: u16,
+        log: slog::Logger,
+    ) -> pio::Result<Arc<Self>> {
+        let this = Arc::new(Self { reporter: Reporter::new(log) });
+
+        let piodev = this.clone();
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| match rwo {
+            RWOp::Read(ro) => piodev.reporter.read(ro),
+            RWOp::Write(wo) => piodev.reporter.write(wo),
+        }) as Arc<PioFn>;
+        pio.register(port, 1, piofn)?;
+        Ok(this)
+    }
+
+    /// Sets the function to be called with each event reported by the guest
+    pub fn set_handler(&self, handler: Option<PanicHandler>) {
+        *self.reporter.handler.lock().unwrap() = handler;
+    }
+}
+impl Entity for QemuPvpanic {
+    fn type_name(&self) -> &'static str {
+        "qemu-pvpanic"
+    }
+}
+
+/// The PCI form of the device
+pub struct PciQemuPvpanic {
+    pci_state: pci::DeviceState,
+    reporter: Reporter,
+}
+impl PciQemuPvpanic {
+    pub fn create(log: slog::Logger) -> Arc<Self> {
+        let pci_state = pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_REDHAT,
+            device_id: PVPANIC_PCI_DEV_ID,
+            sub_vendor_id: VENDOR_REDHAT,
+            sub_device_id: PVPANIC_PCI_DEV_ID,
+            class: pci::bits::CLASS_SYSTEM,
+            subclass: pci::bits::SUBCLASS_SYSTEM_OTHER,
+            revision_id: 1,
+            ..Default::default()
+        })
+        .add_bar_mmio(REGS_BAR, REGS_LEN)
+        .finish();
+
+        Arc::new(Self { pci_state, reporter: Reporter::new(log) })
+    }
+
+    /// Sets the function to be called with each event reported by the guest
+    pub fn set_handler(&self, handler: Option<PanicHandler>) {
+        *self.reporter.handler.lock().unwrap() = handler;
+    }
+}
+impl pci::Device for PciQemuPvpanic {
+    fn device_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+
+    fn bar_rw(&self, _bar: pci::BarN, rwo: RWOp) {
+        match rwo {
+            RWOp::Read(ro) => self.reporter.read(ro),
+            RWOp::Write(wo) => self.reporter.write(wo),
+        }
+    }
+}
+impl Entity for PciQemuPvpanic {
+    fn type_name(&self) -> &'static str {
+        "pci-qemu-pvpanic"
+    }
+    fn reset(&self) {
+        self.pci_state.reset(self);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciQemuPvpanic {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        MigrateMulti::export(&self.pci_state, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        MigrateMulti::import(&self.pci_state, offer, ctx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reported(val: u8) -> Vec<PanicEvent> {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let dev = PciQemuPvpanic::create(log);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_for_handler = events.clone();
+        dev.set_handler(Some(Box::new(move |event| {
+            events_for_handler.lock().unwrap().push(event)
+        })));
+
+        dev.reporter.write(&mut WriteOp::from_buf(0, &[val]));
+        let events = events.lock().unwrap().clone();
+        events
+    }
+
+    #[test]
+    fn advertises_events() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let dev = PciQemuPvpanic::create(log);
+        let mut buf = [0xffu8; 2];
+        dev.reporter.read(&mut ReadOp::from_buf(0, &mut buf));
+        assert_eq!(buf, [EVENT_PANICKED | EVENT_CRASH_LOADED, 0]);
+    }
+
+    #[test]
+    fn reports_events() {
+        assert!(reported(0).is_empty());
+        assert_eq!(reported(EVENT_PANICKED), [PanicEvent::Panicked]);
+        assert_eq!(reported(EVENT_CRASH_LOADED), [PanicEvent::CrashLoaded]);
+        // Bits for events which are not supported are ignored
+        assert_eq!(reported(0x80 | EVENT_PANICKED), [PanicEvent::Panicked]);
+    }
+}
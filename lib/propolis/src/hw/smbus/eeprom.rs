@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Write-protected 24C02-style serial EEPROM
+//!
+//! The EEPROM holds 256 bytes of contents fixed at creation, such as an FRU
+//! image built by [`super::fru`].  Writes by the guest set its word address
+//! (the position of the next byte read), but their data is discarded, as with
+//! the write-protect pin of the part held high.
+
+use std::sync::{Arc, Mutex};
+
+use super::Target;
+use crate::common::*;
+use crate::migrate::*;
+
+/// Address customarily given to an FRU EEPROM
+pub const DEFAULT_ADDR: u8 = 0x50;
+
+/// Capacity of the EEPROM in bytes
+pub const EEPROM_SIZE: usize = 256;
+
+pub struct Eeprom {
+    contents: Box<[u8; EEPROM_SIZE]>,
+    /// Word address of the next byte read
+    pointer: Mutex<u8>,
+}
+impl Eeprom {
+    /// Create an EEPROM holding `contents`, which are padded out with 0xff
+    /// (the value of erased cells) or truncated to its capacity.
+    pub fn create(contents: &[u8]) -> Arc<Self> {
+        let mut buf = Box::new([0xffu8; EEPROM_SIZE]);
+        let len = contents.len().min(EEPROM_SIZE);
+        buf[..len].copy_from_slice(&contents[..len]);
+        Arc::new(Self { contents: buf, pointer: Mutex::new(0) })
+    }
+}
+impl Target for Eeprom {
+    fn write(&self, data: &[u8]) {
+        if let Some(addr) = data.first() {
+            *self.pointer.lock().unwrap() = *addr;
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) {
+        // Sequential reads wrap around at the end of the array
+        let mut pointer = self.pointer.lock().unwrap();
+        for b in buf.iter_mut() {
+            *b = self.contents[*pointer as usize];
+            *pointer = pointer.wrapping_add(1);
+        }
+    }
+}
+impl Entity for Eeprom {
+    fn type_name(&self) -> &'static str {
+        "smbus-eeprom"
+    }
+    fn reset(&self) {
+        *self.pointer.lock().unwrap() = 0;
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for Eeprom {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        Ok(migrate::EepromV1 { pointer: *self.pointer.lock().unwrap() }.into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::EepromV1 = offer.parse()?;
+        *self.pointer.lock().unwrap() = data.pointer;
+        Ok(())
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct EepromV1 {
+        pub pointer: u8,
+    }
+    impl Schema<'_> for EepromV1 {
+        fn id() -> SchemaId {
+            ("smbus-eeprom", 1)
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Platform Field Replaceable Unit (FRU) information
+//!
+//! Builds the image of an FRU information device, in the format of the IPMI
+//! Platform Management FRU Information Storage Definition (v1.0), as stored
+//! in an [`super::Eeprom`] for the guest to read with tools such as
+//! `ipmitool` or `decode-dimms`-style FRU decoders.  Only the Board Info Area
+//! is provided.
+
+/// Version of the storage definition in which the image is formatted
+const FORMAT_VERSION: u8 = 0x01;
+
+/// Areas of the image are sized and placed in multiples of 8 bytes.
+const AREA_UNIT: usize = 8;
+
+/// Type/length byte of a field of 8-bit ASCII, to which its length is added
+const TYPE_ASCII: u8 = 0xc0;
+/// Type/length byte marking the end of an area's fields
+const END_OF_FIELDS: u8 = 0xc1;
+
+/// Longest value of a field, keeping the image within a 256-byte EEPROM
+const FIELD_MAX: usize = 48;
+
+/// Contents of the Board Info Area
+#[derive(Clone, Debug, Default)]
+pub struct BoardInfo {
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+    pub part_number: String,
+}
+impl BoardInfo {
+    /// The FRU image describing the board
+    pub fn to_image(&self) -> Vec<u8> {
+        let mut area = vec![
+            FORMAT_VERSION,
+            // Length, filled in once known
+            0,
+            // Language code: English
+            0,
+            // Manufacturing date and time: unspecified
+            0,
+            0,
+            0,
+        ];
+        for field in
+            [&self.manufacturer, &self.product, &self.serial, &self.part_number]
+        {
+            push_field(&mut area, field);
+        }
+        // FRU file ID, left empty
+        push_field(&mut area, "");
+        area.push(END_OF_FIELDS);
+        finish_area(&mut area);
+
+        let mut image = vec![
+            FORMAT_VERSION,
+            // Offsets of the internal use and chassis info areas: absent
+            0,
+            0,
+            // The board info area follows the common header
+            1,
+            // Offsets of the product info and multirecord areas: absent
+            0,
+            0,
+            // Pad, and checksum filled in below
+            0,
+            0,
+        ];
+        image[7] = checksum(&image[..7]);
+        image.extend_from_slice(&area);
+        image
+    }
+}
+
+/// Append `value` to an area as a field of 8-bit ASCII.  Any other characters
+/// are dropped.
+fn push_field(area: &mut Vec<u8>, value: &str) {
+    let mut data: Vec<u8> = value
+        .bytes()
+        .filter(|b| b.is_ascii_graphic() || *b == b' ')
+        .take(FIELD_MAX)
+        .collect();
+    if data.len() == 1 {
+        // A single-byte field would be mistaken for the end of the fields.
+        data.push(b' ');
+    }
+    area.push(TYPE_ASCII | data.len() as u8);
+    area.extend_from_slice(&data);
+}
+
+/// Pad an area out to a whole number of units, then record its length and
+/// append its checksum.
+fn finish_area(area: &mut Vec<u8>) {
+    let len = (area.len() + AREA_UNIT) / AREA_UNIT * AREA_UNIT;
+    area.resize(len - 1, 0);
+    area[1] = (len / AREA_UNIT) as u8;
+    area.push(checksum(area));
+}
+
+/// Zero checksum: the byte which brings the sum of `data` to zero
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::smbus::eeprom::EEPROM_SIZE;
+
+    fn sum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+    }
+
+    #[test]
+    fn board_info_image() {
+        let info = BoardInfo {
+            manufacturer: "Oxide".to_string(),
+            product: "OxVM".to_string(),
+            serial: "X".to_string(),
+            part_number: String::new(),
+        };
+        let image = info.to_image();
+
+        let header = &image[..AREA_UNIT];
+        assert_eq!(header[0], FORMAT_VERSION);
+        assert_eq!(sum(header), 0);
+
+        let area = &image[header[3] as usize * AREA_UNIT..];
+        assert_eq!(area.len(), area[1] as usize * AREA_UNIT);
+        assert_eq!(sum(area), 0);
+        assert_eq!(&area[6..12], b"\xc5Oxide");
+        assert_eq!(&area[12..17], b"\xc4OxVM");
+        // Padded, so as not to be taken for the end marker
+        assert_eq!(&area[17..20], b"\xc2X ");
+        assert_eq!(&area[20..23], &[TYPE_ASCII, TYPE_ASCII, END_OF_FIELDS]);
+    }
+
+    #[test]
+    fn fits_eeprom() {
+        let long = "a".repeat(100);
+        let info = BoardInfo {
+            manufacturer: long.clone(),
+            product: long.clone(),
+            serial: long.clone(),
+            part_number: long,
+        };
+        assert!(info.to_image().len() <= EEPROM_SIZE);
+    }
+}
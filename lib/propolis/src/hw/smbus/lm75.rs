@@ -0,0 +1,269 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! LM75A digital temperature sensor
+//!
+//! The sensor reports a temperature set by the host, at the 0.5°C resolution
+//! of its 9-bit temperature register.  Its over-temperature output (O.S.) is
+//! not wired to anything, so the configuration and limit registers are
+//! retained for the guest but otherwise have no effect.
+
+use std::sync::{Arc, Mutex};
+
+use super::Target;
+use crate::common::*;
+use crate::migrate::*;
+
+/// Address customarily given to the sensor, with its address pins tied low
+pub const DEFAULT_ADDR: u8 = 0x48;
+
+/// Temperature reported until set otherwise, in millidegrees Celsius
+const NOMINAL_TEMP: i32 = 40_000;
+const TEMP_MIN: i32 = -55_000;
+const TEMP_MAX: i32 = 125_000;
+
+/// Value of the identification register of the LM75A
+const LM75A_ID: u8 = 0xa1;
+
+/// Bits of the configuration register which always read as zero
+const CONF_RSVD: u8 = 0xe0;
+/// Bits of the temperature registers not implemented at 9-bit resolution
+const TEMP_RSVD: u16 = 0x007f;
+
+const THYST_DEFAULT: u16 = 75 << 8;
+const TOS_DEFAULT: u16 = 80 << 8;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Reg {
+    Temp,
+    Conf,
+    Thyst,
+    Tos,
+    /// Registers 4-6, which read all ones on the LM75A
+    Unused,
+    Id,
+}
+impl Reg {
+    fn from_pointer(ptr: u8) -> Self {
+        match ptr & 0x7 {
+            0 => Reg::Temp,
+            1 => Reg::Conf,
+            2 => Reg::Thyst,
+            3 => Reg::Tos,
+            7 => Reg::Id,
+            _ => Reg::Unused,
+        }
+    }
+}
+
+struct State {
+    pointer: u8,
+    /// Position of the next byte read of the register selected by `pointer`
+    pos: usize,
+    conf: u8,
+    thyst: u16,
+    tos: u16,
+    /// Temperature in millidegrees Celsius
+    temp: i32,
+}
+impl State {
+    fn new(temp: i32) -> Self {
+        Self {
+            pointer: 0,
+            pos: 0,
+            conf: 0,
+            thyst: THYST_DEFAULT,
+            tos: TOS_DEFAULT,
+            temp,
+        }
+    }
+
+    /// Value of the temperature register: in half degrees, two's complement,
+    /// left-justified in 16 bits
+    fn temp_reg(&self) -> u16 {
+        (((self.temp / 500) as i16) << 7) as u16
+    }
+
+    fn reg_bytes(&self) -> ([u8; 2], usize) {
+        match Reg::from_pointer(self.pointer) {
+            Reg::Temp => (self.temp_reg().to_be_bytes(), 2),
+            Reg::Thyst => (self.thyst.to_be_bytes(), 2),
+            Reg::Tos => (self.tos.to_be_bytes(), 2),
+            Reg::Conf => ([self.conf, 0], 1),
+            Reg::Unused => ([0xff, 0], 1),
+            Reg::Id => ([LM75A_ID, 0], 1),
+        }
+    }
+}
+
+pub struct Lm75 {
+    state: Mutex<State>,
+}
+impl Lm75 {
+    /// Create a sensor reporting a temperature of `millidegrees` Celsius.
+    pub fn create(millidegrees: Option<i32>) -> Arc<Self> {
+        let temp =
+            millidegrees.unwrap_or(NOMINAL_TEMP).clamp(TEMP_MIN, TEMP_MAX);
+        Arc::new(Self { state: Mutex::new(State::new(temp)) })
+    }
+
+    /// Set the temperature reported, in millidegrees Celsius.  It is clamped
+    /// to the range of the sensor, -55°C to 125°C.
+    pub fn set_temperature(&self, millidegrees: i32) {
+        self.state.lock().unwrap().temp =
+            millidegrees.clamp(TEMP_MIN, TEMP_MAX);
+    }
+}
+impl Target for Lm75 {
+    fn write(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let Some((ptr, data)) = data.split_first() else {
+            return;
+        };
+        state.pointer = ptr & 0x7;
+        state.pos = 0;
+
+        match (Reg::from_pointer(state.pointer), data) {
+            (Reg::Conf, [conf, ..]) => state.conf = conf & !CONF_RSVD,
+            (Reg::Thyst, [msb, lsb, ..]) => {
+                state.thyst = u16::from_be_bytes([*msb, *lsb]) & !TEMP_RSVD
+            }
+            (Reg::Tos, [msb, lsb, ..]) => {
+                state.tos = u16::from_be_bytes([*msb, *lsb]) & !TEMP_RSVD
+            }
+            // The temperature and identification registers are read-only
+            _ => {}
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) {
+        let mut state = self.state.lock().unwrap();
+        let (bytes, len) = state.reg_bytes();
+        for b in buf.iter_mut() {
+            *b = bytes[state.pos % len];
+            state.pos += 1;
+        }
+    }
+}
+impl Entity for Lm75 {
+    fn type_name(&self) -> &'static str {
+        "smbus-lm75"
+    }
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = State::new(state.temp);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for Lm75 {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        let state = self.state.lock().unwrap();
+        Ok(migrate::Lm75V1 {
+            pointer: state.pointer,
+            conf: state.conf,
+            thyst: state.thyst,
+            tos: state.tos,
+            temp: state.temp,
+        }
+        .into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::Lm75V1 = offer.parse()?;
+        let mut state = self.state.lock().unwrap();
+        *state = State {
+            pointer: data.pointer & 0x7,
+            pos: 0,
+            conf: data.conf & !CONF_RSVD,
+            thyst: data.thyst & !TEMP_RSVD,
+            tos: data.tos & !TEMP_RSVD,
+            temp: data.temp.clamp(TEMP_MIN, TEMP_MAX),
+        };
+        Ok(())
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Lm75V1 {
+        pub pointer: u8,
+        pub conf: u8,
+        pub thyst: u16,
+        pub tos: u16,
+        pub temp: i32,
+    }
+    impl Schema<'_> for Lm75V1 {
+        fn id() -> SchemaId {
+            ("smbus-lm75", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_reg(dev: &Lm75, ptr: u8, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        dev.write(&[ptr]);
+        dev.read(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn temperature() {
+        let dev = Lm75::create(None);
+        assert_eq!(read_reg(&dev, 0, 2), [40, 0x00]);
+
+        dev.set_temperature(-25_500);
+        assert_eq!(read_reg(&dev, 0, 2), [0xe6, 0x80]);
+
+        // Clamped to the range of the sensor
+        dev.set_temperature(200_000);
+        assert_eq!(read_reg(&dev, 0, 2), [125, 0x00]);
+
+        // Not writable by the guest
+        dev.write(&[0, 0x10, 0x00]);
+        assert_eq!(read_reg(&dev, 0, 2), [125, 0x00]);
+    }
+
+    #[test]
+    fn limits_and_config() {
+        let dev = Lm75::create(None);
+        assert_eq!(read_reg(&dev, 2, 2), [75, 0x00]);
+        assert_eq!(read_reg(&dev, 3, 4), [80, 0x00, 80, 0x00]);
+
+        dev.write(&[3, 90, 0xff]);
+        assert_eq!(read_reg(&dev, 3, 2), [90, 0x80]);
+        dev.write(&[1, 0xff]);
+        assert_eq!(read_reg(&dev, 1, 1), [0x1f]);
+
+        dev.reset();
+        assert_eq!(read_reg(&dev, 1, 1), [0]);
+        assert_eq!(read_reg(&dev, 3, 2), [80, 0x00]);
+    }
+
+    #[test]
+    fn identification() {
+        // Registers probed by the Linux lm75 driver when detecting an LM75A
+        let dev = Lm75::create(None);
+        assert_eq!(read_reg(&dev, 7, 1), [LM75A_ID]);
+        for ptr in 4..=6 {
+            assert_eq!(read_reg(&dev, ptr, 1), [0xff]);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! System Management Bus (SMBus)
+//!
+//! SMBus is a two-wire bus, derived from I2C, over which a host controller
+//! talks to simple peripherals on a mainboard: temperature sensors, fan
+//! controllers, and EEPROMs describing the platform or its memory modules.
+//! Guest hardware-monitoring stacks (such as Linux's `lm-sensors`) probe the
+//! bus for such devices.
+//!
+//! The [`Bus`] here connects the [`piix4::Piix4Smbus`] host controller, which
+//! carries out SMBus transactions on behalf of the guest, to the [`Target`]s
+//! attached at each 7-bit address.  Targets are modeled at the level of I2C
+//! transfers, into which each SMBus transaction is broken down.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+pub mod eeprom;
+pub mod fru;
+pub mod lm75;
+pub mod piix4;
+
+pub use eeprom::Eeprom;
+pub use lm75::Lm75;
+pub use piix4::Piix4Smbus;
+
+/// Lowest address which may be assigned to a target.  Those below are
+/// reserved for the general call and other special uses.
+pub const ADDR_MIN: u8 = 0x08;
+/// Highest address which may be assigned to a target.  Those above are
+/// reserved for 10-bit addressing and future use.
+pub const ADDR_MAX: u8 = 0x77;
+
+/// A device attached to the bus, as seen through I2C transfers
+pub trait Target: Send + Sync + 'static {
+    /// Receives the bytes written by the host in a single transfer, which may
+    /// be empty (as in an SMBus quick command).
+    fn write(&self, data: &[u8]);
+
+    /// Supplies bytes read by the host.  A transfer may be read in several
+    /// parts, each continuing where the last left off, as a device's register
+    /// pointer advances with each byte read.
+    fn read(&self, buf: &mut [u8]);
+}
+
+#[derive(Debug, Error)]
+pub enum AttachError {
+    #[error("SMBus address {0:#x} is reserved")]
+    Reserved(u8),
+
+    #[error("SMBus address {0:#x} is already in use")]
+    InUse(u8),
+}
+
+/// The targets attached to an SMBus, by address
+#[derive(Default)]
+pub struct Bus {
+    targets: Mutex<BTreeMap<u8, Arc<dyn Target>>>,
+}
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attaches `target` to the bus at the 7-bit address `addr`.
+    pub fn attach(
+        &self,
+        addr: u8,
+        target: Arc<dyn Target>,
+    ) -> Result<(), AttachError> {
+        if !(ADDR_MIN..=ADDR_MAX).contains(&addr) {
+            return Err(AttachError::Reserved(addr));
+        }
+        let mut targets = self.targets.lock().unwrap();
+        if targets.contains_key(&addr) {
+            return Err(AttachError::InUse(addr));
+        }
+        targets.insert(addr, target);
+        Ok(())
+    }
+
+    /// The target attached at `addr`, if any
+    pub fn target(&self, addr: u8) -> Option<Arc<dyn Target>> {
+        self.targets.lock().unwrap().get(&addr).cloned()
+    }
+}
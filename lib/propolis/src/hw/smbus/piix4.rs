@@ -0,0 +1,472 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! SMBus host controller of the PIIX4 power management function
+//!
+//! The controller occupies 16 bytes of I/O space, at the base given in the
+//! PM function's `SMBBA` register.  The guest sets up a transaction (the
+//! target's address, a command byte, and any data) in its registers, then
+//! starts it through the host control register.  Transactions are carried out
+//! as soon as they are started, so the controller is never observed busy.
+//!
+//! The controller is reported as signalling completion through SMI#, which
+//! is not emulated, so the guest is left to poll the host status register.
+//! Linux's `i2c-piix4` driver does so regardless.
+
+use std::sync::{Arc, Mutex};
+
+use super::Bus;
+use crate::common::*;
+use crate::migrate::*;
+use crate::pio::{self, PioBus, PioFn};
+use crate::util::regmap::RegMap;
+
+use lazy_static::lazy_static;
+
+/// Size of the controller's window of I/O space
+pub const SMB_IO_LEN: u16 = 0x10;
+
+/// Largest count of bytes transferred by a block transaction
+const BLOCK_MAX: usize = 32;
+
+// Host status register bits
+const HSTSTS_HOST_BUSY: u8 = 1 << 0;
+const HSTSTS_INTR: u8 = 1 << 1;
+const HSTSTS_DEV_ERR: u8 = 1 << 2;
+const HSTSTS_BUS_ERR: u8 = 1 << 3;
+const HSTSTS_FAILED: u8 = 1 << 4;
+const HSTSTS_W1C: u8 =
+    HSTSTS_INTR | HSTSTS_DEV_ERR | HSTSTS_BUS_ERR | HSTSTS_FAILED;
+
+// Host control register bits
+const HSTCNT_INTEREN: u8 = 1 << 0;
+const HSTCNT_KILL: u8 = 1 << 1;
+const HSTCNT_PROT_SHIFT: u8 = 2;
+const HSTCNT_PROT_MASK: u8 = 0b111 << HSTCNT_PROT_SHIFT;
+const HSTCNT_START: u8 = 1 << 6;
+
+// Transaction protocols selected by the host control register
+const PROT_QUICK: u8 = 0b000;
+const PROT_BYTE: u8 = 0b001;
+const PROT_BYTE_DATA: u8 = 0b010;
+const PROT_WORD_DATA: u8 = 0b011;
+const PROT_BLOCK: u8 = 0b101;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum SmbReg {
+    HstSts,
+    SlvSts,
+    HstCnt,
+    HstCmd,
+    HstAdd,
+    HstDat0,
+    HstDat1,
+    BlkDat,
+    SlvCnt,
+    ShdwCmd,
+    SlvEvt,
+    SlvDat,
+    Reserved,
+}
+lazy_static! {
+    static ref SMB_REGS: RegMap<SmbReg> = {
+        let layout = [
+            (SmbReg::HstSts, 1),
+            (SmbReg::SlvSts, 1),
+            (SmbReg::HstCnt, 1),
+            (SmbReg::HstCmd, 1),
+            (SmbReg::HstAdd, 1),
+            (SmbReg::HstDat0, 1),
+            (SmbReg::HstDat1, 1),
+            (SmbReg::BlkDat, 1),
+            (SmbReg::SlvCnt, 1),
+            (SmbReg::ShdwCmd, 1),
+            (SmbReg::SlvEvt, 2),
+            (SmbReg::SlvDat, 2),
+            (SmbReg::Reserved, 2),
+        ];
+        RegMap::create_packed(
+            SMB_IO_LEN as usize,
+            &layout,
+            Some(SmbReg::Reserved),
+        )
+    };
+}
+
+#[derive(Copy, Clone, Default)]
+struct Regs {
+    status: u8,
+    control: u8,
+    command: u8,
+    /// Target address (in bits 7:1) and direction (1 for read, in bit 0)
+    address: u8,
+    data0: u8,
+    data1: u8,
+    block: [u8; BLOCK_MAX],
+    /// Index of the next byte of `block` accessed through the block data
+    /// register
+    block_idx: u8,
+}
+
+pub struct Piix4Smbus {
+    regs: Mutex<Regs>,
+    bus: Arc<Bus>,
+    log: slog::Logger,
+}
+impl Piix4Smbus {
+    pub fn create(log: slog::Logger) -> Arc<Self> {
+        Arc::new(Self {
+            regs: Mutex::new(Regs::default()),
+            bus: Arc::new(Bus::new()),
+            log,
+        })
+    }
+
+    /// The bus on which the controller carries out transactions
+    pub fn bus(&self) -> &Arc<Bus> {
+        &self.bus
+    }
+
+    /// Register the controller's registers in I/O space at `base`.
+    pub fn attach(
+        self: &Arc<Self>,
+        pio: &PioBus,
+        base: u16,
+    ) -> pio::Result<()> {
+        let this = Arc::clone(self);
+        let piofn = Arc::new(move |_port: u16, mut rwo: RWOp| {
+            SMB_REGS.process(&mut rwo, |id, rwo| match rwo {
+                RWOp::Read(ro) => this.reg_read(id, ro),
+                RWOp::Write(wo) => this.reg_write(id, wo),
+            })
+        }) as Arc<PioFn>;
+        pio.register(base, SMB_IO_LEN, piofn)
+    }
+
+    fn reg_read(&self, id: &SmbReg, ro: &mut ReadOp) {
+        let mut regs = self.regs.lock().unwrap();
+        match id {
+            SmbReg::HstSts => ro.write_u8(regs.status),
+            SmbReg::HstCnt => {
+                // Reading the control register rewinds the block data
+                regs.block_idx = 0;
+                ro.write_u8(regs.control);
+            }
+            SmbReg::HstCmd => ro.write_u8(regs.command),
+            SmbReg::HstAdd => ro.write_u8(regs.address),
+            SmbReg::HstDat0 => ro.write_u8(regs.data0),
+            SmbReg::HstDat1 => ro.write_u8(regs.data1),
+            SmbReg::BlkDat => {
+                let idx = regs.block_idx as usize;
+                ro.write_u8(regs.block[idx]);
+                regs.block_idx = ((idx + 1) % BLOCK_MAX) as u8;
+            }
+            // The controller never acts as a target itself
+            SmbReg::SlvSts
+            | SmbReg::SlvCnt
+            | SmbReg::ShdwCmd
+            | SmbReg::SlvEvt
+            | SmbReg::SlvDat
+            | SmbReg::Reserved => ro.fill(0),
+        }
+    }
+
+    fn reg_write(&self, id: &SmbReg, wo: &mut WriteOp) {
+        let mut regs = self.regs.lock().unwrap();
+        match id {
+            SmbReg::HstSts => {
+                regs.status &= !(wo.read_u8() & HSTSTS_W1C);
+            }
+            SmbReg::HstCnt => {
+                let val = wo.read_u8();
+                // START is write-only, and always reads as zero
+                regs.control =
+                    val & (HSTCNT_INTEREN | HSTCNT_KILL | HSTCNT_PROT_MASK);
+                if val & HSTCNT_START != 0 && val & HSTCNT_KILL == 0 {
+                    self.transact(&mut regs);
+                }
+            }
+            SmbReg::HstCmd => regs.command = wo.read_u8(),
+            SmbReg::HstAdd => regs.address = wo.read_u8(),
+            SmbReg::HstDat0 => regs.data0 = wo.read_u8(),
+            SmbReg::HstDat1 => regs.data1 = wo.read_u8(),
+            SmbReg::BlkDat => {
+                let idx = regs.block_idx as usize;
+                regs.block[idx] = wo.read_u8();
+                regs.block_idx = ((idx + 1) % BLOCK_MAX) as u8;
+            }
+            SmbReg::SlvSts
+            | SmbReg::SlvCnt
+            | SmbReg::ShdwCmd
+            | SmbReg::SlvEvt
+            | SmbReg::SlvDat
+            | SmbReg::Reserved => {}
+        }
+    }
+
+    /// Carry out the transaction described by the registers, reflecting its
+    /// outcome in the host status register.
+    fn transact(&self, regs: &mut Regs) {
+        let addr = regs.address >> 1;
+        let read = regs.address & 1 != 0;
+        let prot = (regs.control & HSTCNT_PROT_MASK) >> HSTCNT_PROT_SHIFT;
+        let Some(target) = self.bus.target(addr) else {
+            // No device acknowledged the address
+            regs.status |= HSTSTS_DEV_ERR;
+            return;
+        };
+
+        let cmd = regs.command;
+        match (prot, read) {
+            (PROT_QUICK, false) => target.write(&[]),
+            (PROT_QUICK, true) => target.read(&mut []),
+            // The command byte is itself the data sent
+            (PROT_BYTE, false) => target.write(&[cmd]),
+            (PROT_BYTE, true) => {
+                let mut buf = [0u8];
+                target.read(&mut buf);
+                regs.data0 = buf[0];
+            }
+            (PROT_BYTE_DATA, false) => target.write(&[cmd, regs.data0]),
+            (PROT_BYTE_DATA, true) => {
+                let mut buf = [0u8];
+                target.write(&[cmd]);
+                target.read(&mut buf);
+                regs.data0 = buf[0];
+            }
+            (PROT_WORD_DATA, false) => {
+                target.write(&[cmd, regs.data0, regs.data1])
+            }
+            (PROT_WORD_DATA, true) => {
+                let mut buf = [0u8; 2];
+                target.write(&[cmd]);
+                target.read(&mut buf);
+                regs.data0 = buf[0];
+                regs.data1 = buf[1];
+            }
+            (PROT_BLOCK, false) => {
+                let count = regs.data0 as usize;
+                if count == 0 || count > BLOCK_MAX {
+                    regs.status |= HSTSTS_DEV_ERR;
+                    return;
+                }
+                let mut buf = Vec::with_capacity(count + 2);
+                buf.extend_from_slice(&[cmd, regs.data0]);
+                buf.extend_from_slice(&regs.block[..count]);
+                target.write(&buf);
+            }
+            (PROT_BLOCK, true) => {
+                let mut count = [0u8];
+                target.write(&[cmd]);
+                target.read(&mut count);
+                let count = count[0] as usize;
+                if count == 0 || count > BLOCK_MAX {
+                    regs.status |= HSTSTS_DEV_ERR;
+                    return;
+                }
+                target.read(&mut regs.block[..count]);
+                regs.data0 = count as u8;
+                regs.block_idx = 0;
+            }
+            _ => {
+                slog::info!(self.log, "unsupported SMBus protocol";
+                    "protocol" => prot);
+                regs.status |= HSTSTS_DEV_ERR;
+                return;
+            }
+        }
+        regs.status |= HSTSTS_INTR;
+    }
+}
+impl Entity for Piix4Smbus {
+    fn type_name(&self) -> &'static str {
+        "piix4-smbus"
+    }
+    fn reset(&self) {
+        *self.regs.lock().unwrap() = Regs::default();
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Single(self)
+    }
+}
+impl MigrateSingle for Piix4Smbus {
+    fn export(
+        &self,
+        _ctx: &MigrateCtx,
+    ) -> Result<PayloadOutput, MigrateStateError> {
+        let regs = self.regs.lock().unwrap();
+        Ok(migrate::Piix4SmbusV1 {
+            status: regs.status,
+            control: regs.control,
+            command: regs.command,
+            address: regs.address,
+            data0: regs.data0,
+            data1: regs.data1,
+            block: regs.block,
+            block_idx: regs.block_idx,
+        }
+        .into())
+    }
+
+    fn import(
+        &self,
+        mut offer: PayloadOffer,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::Piix4SmbusV1 = offer.parse()?;
+        if data.status & HSTSTS_HOST_BUSY != 0
+            || data.block_idx as usize >= BLOCK_MAX
+        {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "PIIX4 SMBus: invalid state (status {:#x}, block index {})",
+                data.status, data.block_idx
+            )));
+        }
+
+        *self.regs.lock().unwrap() = Regs {
+            status: data.status,
+            control: data.control,
+            command: data.command,
+            address: data.address,
+            data0: data.data0,
+            data1: data.data1,
+            block: data.block,
+            block_idx: data.block_idx,
+        };
+        Ok(())
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Piix4SmbusV1 {
+        pub status: u8,
+        pub control: u8,
+        pub command: u8,
+        pub address: u8,
+        pub data0: u8,
+        pub data1: u8,
+        pub block: [u8; 32],
+        pub block_idx: u8,
+    }
+    impl Schema<'_> for Piix4SmbusV1 {
+        fn id() -> SchemaId {
+            ("piix4-smbus", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::smbus::Target;
+
+    /// A target holding 256 byte-wide registers, addressed by a pointer set
+    /// by the first byte written
+    #[derive(Default)]
+    struct Regfile(Mutex<(u8, Vec<u8>)>);
+    impl Target for Regfile {
+        fn write(&self, data: &[u8]) {
+            let mut state = self.0.lock().unwrap();
+            if let Some((ptr, rest)) = data.split_first() {
+                state.0 = *ptr;
+                state.1.extend_from_slice(rest);
+            }
+        }
+        fn read(&self, buf: &mut [u8]) {
+            let mut state = self.0.lock().unwrap();
+            for b in buf.iter_mut() {
+                *b = state.0;
+                state.0 = state.0.wrapping_add(1);
+            }
+        }
+    }
+
+    fn setup() -> (Arc<Piix4Smbus>, Arc<Regfile>) {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let smbus = Piix4Smbus::create(log);
+        let target = Arc::new(Regfile::default());
+        smbus.bus().attach(0x50, target.clone()).unwrap();
+        (smbus, target)
+    }
+
+    fn write(smbus: &Piix4Smbus, reg: SmbReg, val: u8) {
+        let buf = [val];
+        let mut wo = WriteOp::from_buf(0, &buf);
+        smbus.reg_write(&reg, &mut wo);
+    }
+
+    fn read(smbus: &Piix4Smbus, reg: SmbReg) -> u8 {
+        let mut buf = [0u8];
+        let mut ro = ReadOp::from_buf(0, &mut buf);
+        smbus.reg_read(&reg, &mut ro);
+        buf[0]
+    }
+
+    fn start(smbus: &Piix4Smbus, addr: u8, read: bool, prot: u8) -> u8 {
+        write(smbus, SmbReg::HstAdd, addr << 1 | read as u8);
+        write(smbus, SmbReg::HstCnt, prot << HSTCNT_PROT_SHIFT | HSTCNT_START);
+        let status = read_status(smbus);
+        write(smbus, SmbReg::HstSts, status);
+        status
+    }
+
+    fn read_status(smbus: &Piix4Smbus) -> u8 {
+        read(smbus, SmbReg::HstSts)
+    }
+
+    #[test]
+    fn absent_target() {
+        let (smbus, _target) = setup();
+        assert_eq!(start(&smbus, 0x51, false, PROT_QUICK), HSTSTS_DEV_ERR);
+        assert_eq!(start(&smbus, 0x50, false, PROT_QUICK), HSTSTS_INTR);
+        assert_eq!(read_status(&smbus), 0);
+    }
+
+    #[test]
+    fn data_transactions() {
+        let (smbus, target) = setup();
+
+        write(&smbus, SmbReg::HstCmd, 0x10);
+        assert_eq!(start(&smbus, 0x50, true, PROT_BYTE_DATA), HSTSTS_INTR);
+        assert_eq!(read(&smbus, SmbReg::HstDat0), 0x10);
+
+        assert_eq!(start(&smbus, 0x50, true, PROT_WORD_DATA), HSTSTS_INTR);
+        assert_eq!(read(&smbus, SmbReg::HstDat0), 0x10);
+        assert_eq!(read(&smbus, SmbReg::HstDat1), 0x11);
+
+        write(&smbus, SmbReg::HstDat0, 0xaa);
+        write(&smbus, SmbReg::HstDat1, 0xbb);
+        assert_eq!(start(&smbus, 0x50, false, PROT_WORD_DATA), HSTSTS_INTR);
+        assert_eq!(target.0.lock().unwrap().1, [0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn block_transactions() {
+        let (smbus, target) = setup();
+
+        // The block data register is rewound by reading the control register
+        write(&smbus, SmbReg::HstCmd, 0x20);
+        write(&smbus, SmbReg::HstDat0, 3);
+        read(&smbus, SmbReg::HstCnt);
+        for b in [1, 2, 3] {
+            write(&smbus, SmbReg::BlkDat, b);
+        }
+        assert_eq!(start(&smbus, 0x50, false, PROT_BLOCK), HSTSTS_INTR);
+        assert_eq!(target.0.lock().unwrap().1, [3, 1, 2, 3]);
+
+        // The first byte read is the count of those which follow
+        write(&smbus, SmbReg::HstCmd, 4);
+        assert_eq!(start(&smbus, 0x50, true, PROT_BLOCK), HSTSTS_INTR);
+        assert_eq!(read(&smbus, SmbReg::HstDat0), 4);
+        read(&smbus, SmbReg::HstCnt);
+        let data: Vec<u8> =
+            (0..4).map(|_| read(&smbus, SmbReg::BlkDat)).collect();
+        assert_eq!(data, [5, 6, 7, 8]);
+    }
+}
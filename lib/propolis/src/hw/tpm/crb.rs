@@ -0,0 +1,468 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! TPM Command Response Buffer (CRB) interface
+//!
+//! Only locality 0 is implemented, with its registers and command buffer in
+//! the page at the conventional TPM address.  Commands are executed on an
+//! [`Offload`] worker, so the vCPU which starts one is not held up by the
+//! backend; the guest polls the start register to learn of its completion, as
+//! interrupts are not offered.  Resets of the TPM are carried out on the same
+//! worker, in order with commands, so that the vCPU resetting the instance is
+//! not held up either.  See the TCG PC Client Platform TPM Profile
+//! Specification for TPM 2.0, Section 6.5.
+//!
+//! The backend bounds the time it takes to execute a command, so that pausing
+//! the instance (which waits for any command in flight) cannot hang.
+
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+
+use super::{failure_response, header_size, Backend, HEADER_LEN};
+use crate::common::*;
+use crate::hw::ids::pci::VENDOR_OXIDE;
+use crate::migrate::*;
+use crate::mmio::{MmioBus, MmioFn};
+use crate::offload::Offload;
+use crate::util::regmap::{Flags, RegMap};
+
+/// Guest-physical address of the CRB registers for locality 0
+pub const TPM_CRB_ADDR: usize = 0xfed4_0000;
+pub const TPM_CRB_LEN: usize = 0x1000;
+
+const DATA_OFF: usize = 0x80;
+const BUF_LEN: usize = TPM_CRB_LEN - DATA_OFF;
+
+const LOC_STATE_ESTABLISHED: u32 = 1 << 0;
+const LOC_STATE_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID: u32 = 1 << 7;
+
+const LOC_CTRL_REQUEST: u32 = 1 << 0;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+
+const LOC_STS_GRANTED: u32 = 1 << 0;
+
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+
+const CTRL_STS_IDLE: u32 = 1 << 1;
+
+/// Interface identifier: a CRB interface (type and version 1), locked to CRB,
+/// supporting 64-byte transfers
+const INTF_ID: u64 = 0x1
+    | (0x1 << 4)
+    | (0x3 << 11)
+    | (1 << 14)
+    | (0x1 << 17)
+    | (1 << 19)
+    | ((VENDOR_OXIDE as u64) << 32)
+    | (0x1 << 48);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Reg {
+    LocState,
+    LocCtrl,
+    LocSts,
+    IntfId,
+    CtrlExt,
+    CtrlReq,
+    CtrlSts,
+    CtrlCancel,
+    CtrlStart,
+    IntEnable,
+    IntSts,
+    CmdSize,
+    CmdLaddr,
+    CmdHaddr,
+    RspSize,
+    RspAddr,
+    Data,
+    Reserved,
+}
+
+lazy_static! {
+    static ref CRB_REGS: RegMap<Reg> = {
+        let layout = [
+            (Reg::LocState, 4),
+            (Reg::Reserved, 4),
+            (Reg::LocCtrl, 4),
+            (Reg::LocSts, 4),
+            (Reg::Reserved, 0x20),
+            (Reg::IntfId, 8),
+            (Reg::CtrlExt, 8),
+            (Reg::CtrlReq, 4),
+            (Reg::CtrlSts, 4),
+            (Reg::CtrlCancel, 4),
+            (Reg::CtrlStart, 4),
+            (Reg::IntEnable, 4),
+            (Reg::IntSts, 4),
+            (Reg::CmdSize, 4),
+            (Reg::CmdLaddr, 4),
+            (Reg::CmdHaddr, 4),
+            (Reg::RspSize, 4),
+            (Reg::RspAddr, 8),
+            (Reg::Reserved, 0x10),
+            (Reg::Data, BUF_LEN),
+        ];
+        let mut map = RegMap::new(TPM_CRB_LEN);
+        let mut off = 0;
+        for (id, len) in layout {
+            let flags = match id {
+                Reg::Data | Reg::Reserved => Flags::PASSTHRU,
+                _ => Flags::DEFAULT,
+            };
+            map.define_with_flags(off, len, id, flags);
+            off += len;
+        }
+        assert_eq!(off, TPM_CRB_LEN);
+        map
+    };
+}
+
+struct State {
+    loc_assigned: bool,
+    idle: bool,
+    /// A command has been started, and its response is not yet in `buf`
+    busy: bool,
+    cancel: bool,
+    buf: Vec<u8>,
+}
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            loc_assigned: false,
+            idle: true,
+            busy: false,
+            cancel: false,
+            buf: vec![0; BUF_LEN],
+        }
+    }
+}
+
+pub struct TpmCrb {
+    state: Mutex<State>,
+    backend: Arc<dyn Backend>,
+    offload: Arc<Offload>,
+    log: slog::Logger,
+}
+impl TpmCrb {
+    pub fn create(
+        backend: Arc<dyn Backend>,
+        log: slog::Logger,
+    ) -> std::io::Result<Arc<Self>> {
+        // A single worker keeps the commands in order
+        let offload = Offload::new(NonZeroUsize::new(1).unwrap())?;
+        Ok(Arc::new(Self {
+            state: Mutex::new(State::default()),
+            backend,
+            offload,
+            log,
+        }))
+    }
+
+    pub fn attach(self: &Arc<Self>, mmio: &MmioBus) {
+        let dev = Arc::clone(self);
+        let mmiofn =
+            Arc::new(move |_addr: usize, mut rwo: RWOp| dev.mmio_rw(&mut rwo))
+                as Arc<MmioFn>;
+        mmio.register(TPM_CRB_ADDR, TPM_CRB_LEN, mmiofn).unwrap();
+    }
+
+    fn mmio_rw(self: &Arc<Self>, rwo: &mut RWOp) {
+        let mut state = self.state.lock().unwrap();
+        let mut start = false;
+        CRB_REGS.process(rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => match id {
+                Reg::LocState => {
+                    let assigned =
+                        if state.loc_assigned { LOC_STATE_ASSIGNED } else { 0 };
+                    ro.write_u32(
+                        LOC_STATE_REG_VALID | LOC_STATE_ESTABLISHED | assigned,
+                    );
+                }
+                Reg::LocSts => ro.write_u32(if state.loc_assigned {
+                    LOC_STS_GRANTED
+                } else {
+                    0
+                }),
+                Reg::IntfId => ro.write_u64(INTF_ID),
+                Reg::CtrlSts => {
+                    ro.write_u32(if state.idle { CTRL_STS_IDLE } else { 0 })
+                }
+                Reg::CtrlCancel => ro.write_u32(state.cancel as u32),
+                Reg::CtrlStart => ro.write_u32(state.busy as u32),
+                Reg::CmdSize | Reg::RspSize => ro.write_u32(BUF_LEN as u32),
+                Reg::CmdLaddr => ro.write_u32((TPM_CRB_ADDR + DATA_OFF) as u32),
+                Reg::RspAddr => ro.write_u64((TPM_CRB_ADDR + DATA_OFF) as u64),
+                Reg::Data => {
+                    let off = ro.offset();
+                    ro.write_bytes(&state.buf[off..off + ro.len()]);
+                }
+                Reg::LocCtrl
+                | Reg::CtrlExt
+                | Reg::CtrlReq
+                | Reg::IntEnable
+                | Reg::IntSts
+                | Reg::CmdHaddr
+                | Reg::Reserved => ro.fill(0),
+            },
+            RWOp::Write(wo) => match id {
+                Reg::LocCtrl => {
+                    let val = wo.read_u32();
+                    if val & LOC_CTRL_REQUEST != 0 {
+                        state.loc_assigned = true;
+                    }
+                    if val & LOC_CTRL_RELINQUISH != 0 {
+                        state.loc_assigned = false;
+                    }
+                }
+                Reg::CtrlReq if state.loc_assigned => {
+                    let val = wo.read_u32();
+                    if val & CTRL_REQ_CMD_READY != 0 {
+                        state.idle = false;
+                    }
+                    if val & CTRL_REQ_GO_IDLE != 0 && !state.busy {
+                        state.idle = true;
+                    }
+                }
+                Reg::CtrlCancel if state.loc_assigned => {
+                    // Commands run to completion, so a cancellation is merely
+                    // recorded for the guest to clear.
+                    state.cancel = wo.read_u32() & 1 != 0;
+                }
+                Reg::CtrlStart if state.loc_assigned => {
+                    let go = wo.read_u32() & 1 != 0;
+                    if go && !state.busy && !state.idle {
+                        state.busy = true;
+                        start = true;
+                    }
+                }
+                Reg::Data if state.loc_assigned && !state.busy => {
+                    let off = wo.offset();
+                    let len = wo.len();
+                    wo.read_bytes(&mut state.buf[off..off + len]);
+                }
+                _ => {}
+            },
+        });
+        drop(state);
+
+        if start {
+            let this = Arc::clone(self);
+            self.offload.submit(move || this.execute());
+        }
+    }
+
+    /// Executes the command in the buffer, replacing it with the response.
+    fn execute(&self) {
+        let cmd = {
+            let state = self.state.lock().unwrap();
+            header_size(&state.buf)
+                .filter(|size| (HEADER_LEN..=BUF_LEN).contains(size))
+                .map(|size| state.buf[..size].to_vec())
+        };
+        let rsp = match cmd {
+            Some(cmd) => match self.backend.execute(&cmd) {
+                Ok(rsp) if rsp.len() <= BUF_LEN => rsp,
+                Ok(rsp) => {
+                    slog::warn!(self.log, "TPM response too large";
+                        "len" => rsp.len());
+                    failure_response()
+                }
+                Err(e) => {
+                    slog::warn!(self.log, "TPM command failed";
+                        "error" => %e);
+                    failure_response()
+                }
+            },
+            None => failure_response(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.buf[..rsp.len()].copy_from_slice(&rsp);
+        state.busy = false;
+    }
+}
+
+impl Entity for TpmCrb {
+    fn type_name(&self) -> &'static str {
+        "tpm-crb"
+    }
+    fn paused(&self) -> BoxFuture<'static, ()> {
+        // A command (or reset) in flight still has to complete
+        self.offload.idle()
+    }
+    fn reset(&self) {
+        *self.state.lock().unwrap() = State::default();
+        let backend = Arc::clone(&self.backend);
+        let log = self.log.clone();
+        self.offload.submit(move || {
+            if let Err(e) = backend.reset() {
+                slog::error!(log, "failed to reset TPM"; "error" => %e);
+            }
+        });
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for TpmCrb {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let state = self.state.lock().unwrap();
+        assert!(!state.busy, "TPM command outstanding while paused");
+        output.push(
+            migrate::TpmCrbV1 {
+                loc_assigned: state.loc_assigned,
+                idle: state.idle,
+                cancel: state.cancel,
+                buf: state.buf.clone(),
+            }
+            .into(),
+        )?;
+        drop(state);
+
+        // The state of the TPM itself, held by the backend
+        output.push(
+            migrate::TpmStateV1 { state: self.backend.export_state()? }.into(),
+        )
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let data: migrate::TpmCrbV1 = offer.take()?;
+        let tpm: migrate::TpmStateV1 = offer.take()?;
+        self.backend.import_state(&tpm.state).map_err(|e| {
+            MigrateStateError::ImportFailed(format!(
+                "failed to restore TPM state: {e}"
+            ))
+        })?;
+        if data.buf.len() != BUF_LEN {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "TPM CRB buffer length {} != {BUF_LEN}",
+                data.buf.len()
+            )));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.loc_assigned = data.loc_assigned;
+        state.idle = data.idle;
+        state.busy = false;
+        state.cancel = data.cancel;
+        state.buf = data.buf;
+        Ok(())
+    }
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct TpmCrbV1 {
+        pub loc_assigned: bool,
+        pub idle: bool,
+        pub cancel: bool,
+        pub buf: Vec<u8>,
+    }
+    impl Schema<'_> for TpmCrbV1 {
+        fn id() -> SchemaId {
+            ("tpm-crb", 1)
+        }
+    }
+
+    /// The state of the TPM itself, as exported by its backend
+    #[derive(Deserialize, Serialize)]
+    pub struct TpmStateV1 {
+        pub state: Vec<u8>,
+    }
+    impl Schema<'_> for TpmStateV1 {
+        fn id() -> SchemaId {
+            ("tpm-state", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::tpm::TPM_RC_FAILURE;
+    use std::time::{Duration, Instant};
+
+    /// A TPM which answers every command with its own body
+    struct Echo;
+    impl Backend for Echo {
+        fn execute(&self, cmd: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(cmd.to_vec())
+        }
+    }
+
+    fn read32(dev: &Arc<TpmCrb>, off: usize) -> u32 {
+        let mut buf = [0u8; 4];
+        dev.mmio_rw(&mut RWOp::Read(&mut ReadOp::from_buf(off, &mut buf)));
+        u32::from_le_bytes(buf)
+    }
+    fn write32(dev: &Arc<TpmCrb>, off: usize, val: u32) {
+        let buf = val.to_le_bytes();
+        dev.mmio_rw(&mut RWOp::Write(&mut WriteOp::from_buf(off, &buf)));
+    }
+
+    #[test]
+    fn command_round_trip() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let dev = TpmCrb::create(Arc::new(Echo), log).unwrap();
+
+        assert_eq!(read32(&dev, 0x30) & 0xf, 1);
+        assert_eq!(read32(&dev, 0x5c), (TPM_CRB_ADDR + DATA_OFF) as u32);
+
+        // Writes are ignored until the locality is granted
+        write32(&dev, 0x40, CTRL_REQ_CMD_READY);
+        assert_eq!(read32(&dev, 0x44), CTRL_STS_IDLE);
+        write32(&dev, 0x08, LOC_CTRL_REQUEST);
+        assert_eq!(read32(&dev, 0x0c), LOC_STS_GRANTED);
+        write32(&dev, 0x40, CTRL_REQ_CMD_READY);
+        assert_eq!(read32(&dev, 0x44), 0);
+
+        let cmd = [0x80, 0x01, 0, 0, 0, 0x0c, 0, 0, 0x01, 0x44, 0xaa, 0x55];
+        for (i, chunk) in cmd.chunks(4).enumerate() {
+            let val = u32::from_le_bytes(chunk.try_into().unwrap());
+            write32(&dev, DATA_OFF + i * 4, val);
+        }
+        write32(&dev, 0x4c, 1);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while read32(&dev, 0x4c) != 0 {
+            assert!(Instant::now() < deadline, "command did not complete");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(read32(&dev, DATA_OFF + 8), 0x55aa_4401);
+    }
+
+    #[test]
+    fn malformed_command_fails() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let dev = TpmCrb::create(Arc::new(Echo), log).unwrap();
+        write32(&dev, 0x08, LOC_CTRL_REQUEST);
+        write32(&dev, 0x40, CTRL_REQ_CMD_READY);
+
+        // A command claiming to be larger than the buffer
+        write32(&dev, DATA_OFF, 0xff00_0180);
+        write32(&dev, 0x4c, 1);
+        while read32(&dev, 0x4c) != 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let rc = read32(&dev, DATA_OFF + 6).swap_bytes();
+        assert_eq!(rc, TPM_RC_FAILURE);
+    }
+}
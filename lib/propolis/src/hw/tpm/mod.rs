@@ -0,0 +1,69 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! TPM 2.0 devices
+//!
+//! The TPM itself is not emulated.  Commands which the guest submits through
+//! the interface device ([`TpmCrb`]) are executed by a [`Backend`], such as an
+//! external `swtpm` process ([`Swtpm`]), which also holds the TPM's persistent
+//! state.
+
+use std::io;
+
+mod crb;
+mod swtpm;
+
+pub use crb::{TpmCrb, TPM_CRB_ADDR, TPM_CRB_LEN};
+pub use swtpm::Swtpm;
+
+/// Executes TPM commands on behalf of an interface device
+pub trait Backend: Send + Sync + 'static {
+    /// Executes `cmd`, a complete TPM command, returning the TPM's response.
+    fn execute(&self, cmd: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Resets the TPM, as for a platform reset (`_TPM_Init`).
+    fn reset(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the TPM's state, in a form which [`Backend::import_state()`]
+    /// accepts, for the instance to be migrated.  The TPM must not be
+    /// executing a command.
+    fn export_state(&self) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TPM state cannot be exported",
+        ))
+    }
+
+    /// Replaces the TPM's state with that exported from the migration source.
+    #[allow(unused_variables)]
+    fn import_state(&self, state: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TPM state cannot be imported",
+        ))
+    }
+}
+
+/// Length of the header leading every TPM 2.0 command and response
+const HEADER_LEN: usize = 10;
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_RC_FAILURE: u32 = 0x101;
+
+/// Size of a command or response, as given in its header
+fn header_size(header: &[u8]) -> Option<usize> {
+    let size = header.get(2..6)?;
+    Some(u32::from_be_bytes(size.try_into().unwrap()) as usize)
+}
+
+/// A response reporting that the TPM failed to execute a command
+fn failure_response() -> Vec<u8> {
+    let mut rsp = Vec::with_capacity(HEADER_LEN);
+    rsp.extend(TPM_ST_NO_SESSIONS.to_be_bytes());
+    rsp.extend((HEADER_LEN as u32).to_be_bytes());
+    rsp.extend(TPM_RC_FAILURE.to_be_bytes());
+    rsp
+}
@@ -0,0 +1,342 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! TPM backend which passes commands to an external `swtpm` process.
+//!
+//! swtpm must serve its data channel on a Unix socket, and be spared the need
+//! for initialization through its control channel, as with:
+//!
+//! ```text
+//! swtpm socket --tpm2 --tpmstate dir=<state> \
+//!     --server type=unixio,path=<socket> --flags not-need-init
+//! ```
+//!
+//! If the control channel is also served (`--ctrl type=unixio,path=...`), it
+//! is used to reinitialize the TPM when the instance is reset, as a platform
+//! reset would, and to carry the TPM's state when the instance is migrated.
+//!
+//! Every exchange with swtpm is bounded by a timeout, so that a wedged swtpm
+//! fails the command (or reset, or migration) in progress rather than holding
+//! it up indefinitely.
+
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{header_size, Backend, HEADER_LEN};
+
+/// Largest response accepted from swtpm
+const MAX_RESPONSE: usize = 0x10000;
+
+/// Largest state blob accepted from swtpm, or from a migration source
+const MAX_BLOB: usize = 0x100000;
+
+/// Time allowed for swtpm to execute a command, which may involve generating
+/// a key
+const DATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time allowed for swtpm to service a request on its control channel
+const CTRL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Control channel command: initialize the TPM
+const CMD_INIT: u32 = 0x2;
+/// Control channel command: read one of the TPM's state blobs
+const CMD_GET_STATEBLOB: u32 = 0xc;
+/// Control channel command: replace one of the TPM's state blobs
+const CMD_SET_STATEBLOB: u32 = 0xd;
+/// Control channel command: stop the TPM, so its state may be replaced
+const CMD_STOP: u32 = 0xe;
+
+/// State blobs making up the TPM's state: its permanent state, its volatile
+/// state, and any state saved by `TPM2_Shutdown(TPM_SU_STATE)`
+const BLOB_TYPES: [u32; 3] = [1, 2, 3];
+
+/// Blobs are requested unencrypted
+const STATE_FLAG_DECRYPTED: u32 = 0x1;
+
+pub struct Swtpm {
+    data_path: PathBuf,
+    ctrl_path: Option<PathBuf>,
+    conn: Mutex<Option<UnixStream>>,
+}
+impl Swtpm {
+    /// Creates a backend for the swtpm serving commands on `data_path`, and
+    /// (optionally) its control channel on `ctrl_path`.  No connection is made
+    /// until the first command is executed.
+    pub fn new(data_path: &Path, ctrl_path: Option<&Path>) -> Self {
+        Self {
+            data_path: data_path.to_path_buf(),
+            ctrl_path: ctrl_path.map(Path::to_path_buf),
+            conn: Mutex::new(None),
+        }
+    }
+
+    fn connect(path: &Path, timeout: Duration) -> io::Result<UnixStream> {
+        let conn = UnixStream::connect(crate::harden::host_path(path)?)?;
+        conn.set_read_timeout(Some(timeout))?;
+        conn.set_write_timeout(Some(timeout))?;
+        Ok(conn)
+    }
+
+    fn ctrl_connect(&self) -> io::Result<UnixStream> {
+        let path = self.ctrl_path.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "swtpm control channel not configured",
+            )
+        })?;
+        Self::connect(path, CTRL_TIMEOUT)
+    }
+
+    /// Issues `cmd`, with its request `body`, on the control channel.
+    fn ctrl_cmd(
+        ctrl: &mut UnixStream,
+        cmd: u32,
+        body: &[u8],
+    ) -> io::Result<()> {
+        let mut req = Vec::with_capacity(4 + body.len());
+        req.extend(cmd.to_be_bytes());
+        req.extend(body);
+        ctrl.write_all(&req)?;
+        Self::ctrl_result(ctrl, cmd)
+    }
+
+    /// Reads the result of control channel command `cmd`.
+    fn ctrl_result(ctrl: &mut UnixStream, cmd: u32) -> io::Result<()> {
+        match read_u32(ctrl)? {
+            0 => Ok(()),
+            rc => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("swtpm control command {cmd:#x} failed: {rc:#x}"),
+            )),
+        }
+    }
+
+    fn transact(conn: &mut UnixStream, cmd: &[u8]) -> io::Result<Vec<u8>> {
+        conn.write_all(cmd)?;
+
+        let mut rsp = vec![0u8; HEADER_LEN];
+        conn.read_exact(&mut rsp)?;
+        let size = header_size(&rsp).unwrap();
+        if !(HEADER_LEN..=MAX_RESPONSE).contains(&size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad TPM response size {size}"),
+            ));
+        }
+        rsp.resize(size, 0);
+        conn.read_exact(&mut rsp[HEADER_LEN..])?;
+        Ok(rsp)
+    }
+}
+impl Backend for Swtpm {
+    fn execute(&self, cmd: &[u8]) -> io::Result<Vec<u8>> {
+        let mut guard = self.conn.lock().unwrap();
+        let conn = match guard.as_mut() {
+            Some(conn) => conn,
+            None => guard.insert(Self::connect(&self.data_path, DATA_TIMEOUT)?),
+        };
+        let res = Self::transact(conn, cmd);
+        if res.is_err() {
+            // Leave the connection to be reestablished for the next command,
+            // rather than risk a response falling out of step with its command
+            *guard = None;
+        }
+        res
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        if self.ctrl_path.is_none() {
+            return Ok(());
+        }
+        // No initialization flags
+        Self::ctrl_cmd(&mut self.ctrl_connect()?, CMD_INIT, &0u32.to_be_bytes())
+    }
+
+    fn export_state(&self) -> io::Result<Vec<u8>> {
+        let mut ctrl = self.ctrl_connect()?;
+        let mut state = Vec::new();
+        for blob_type in BLOB_TYPES {
+            let mut req = Vec::with_capacity(16);
+            req.extend(CMD_GET_STATEBLOB.to_be_bytes());
+            req.extend(STATE_FLAG_DECRYPTED.to_be_bytes());
+            req.extend(blob_type.to_be_bytes());
+            // From the start of the blob, which is sent in its entirety
+            req.extend(0u32.to_be_bytes());
+            ctrl.write_all(&req)?;
+
+            Self::ctrl_result(&mut ctrl, CMD_GET_STATEBLOB)?;
+            let flags = read_u32(&mut ctrl)?;
+            let total = read_u32(&mut ctrl)? as usize;
+            let _len = read_u32(&mut ctrl)?;
+            if total > MAX_BLOB {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("swtpm state blob of {total:#x} bytes too large"),
+                ));
+            }
+            let mut data = vec![0u8; total];
+            ctrl.read_exact(&mut data)?;
+
+            state.extend(blob_type.to_be_bytes());
+            state.extend(flags.to_be_bytes());
+            state.extend((total as u32).to_be_bytes());
+            state.extend(data);
+        }
+        Ok(state)
+    }
+
+    fn import_state(&self, mut state: &[u8]) -> io::Result<()> {
+        // Each blob is checked before the TPM is disturbed
+        let mut requests = Vec::new();
+        while !state.is_empty() {
+            let blob_type = read_u32(&mut state)?;
+            let flags = read_u32(&mut state)?;
+            let len = read_u32(&mut state)? as usize;
+            if len > MAX_BLOB || len > state.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad swtpm state blob length {len:#x}"),
+                ));
+            }
+            let (data, rest) = state.split_at(len);
+            state = rest;
+
+            let mut body = Vec::with_capacity(12 + len);
+            body.extend(flags.to_be_bytes());
+            body.extend(blob_type.to_be_bytes());
+            body.extend((len as u32).to_be_bytes());
+            body.extend(data);
+            requests.push(body);
+        }
+
+        // The TPM must be stopped for its state to be replaced, and is then
+        // initialized anew from that state.
+        let mut ctrl = self.ctrl_connect()?;
+        Self::ctrl_cmd(&mut ctrl, CMD_STOP, &[])?;
+        for body in requests {
+            Self::ctrl_cmd(&mut ctrl, CMD_SET_STATEBLOB, &body)?;
+        }
+        Self::ctrl_cmd(&mut ctrl, CMD_INIT, &0u32.to_be_bytes())?;
+
+        // Any connection made before the TPM was stopped is not reused
+        *self.conn.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+fn read_u32(src: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    src.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn passes_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("swtpm.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // A TPM which answers each command with a fixed response
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            for _ in 0..2 {
+                let mut cmd = [0u8; 12];
+                conn.read_exact(&mut cmd).unwrap();
+                assert_eq!(header_size(&cmd), Some(12));
+                let rsp = [0x80, 0x01, 0, 0, 0, 0x0c, 0, 0, 0, 0, 0xab, 0xcd];
+                conn.write_all(&rsp).unwrap();
+            }
+        });
+
+        let tpm = Swtpm::new(&path, None);
+        let cmd = [0x80, 0x01, 0, 0, 0, 0x0c, 0, 0, 0x01, 0x7b, 0, 0];
+        for _ in 0..2 {
+            let rsp = tpm.execute(&cmd).unwrap();
+            assert_eq!(rsp.len(), 12);
+            assert_eq!(&rsp[10..], [0xab, 0xcd]);
+        }
+        server.join().unwrap();
+
+        // Without a control channel, there is nothing to reset
+        tpm.reset().unwrap();
+        assert!(tpm.export_state().is_err());
+    }
+
+    #[test]
+    fn carries_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("swtpm-ctrl.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // A control channel which hands out a blob of each type filled with
+        // its type, and records the commands it is sent
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            for blob_type in BLOB_TYPES {
+                let mut req = [0u8; 16];
+                conn.read_exact(&mut req).unwrap();
+                assert_eq!(req[..4], CMD_GET_STATEBLOB.to_be_bytes());
+                assert_eq!(req[8..12], blob_type.to_be_bytes());
+                let mut rsp = Vec::new();
+                for val in [0, STATE_FLAG_DECRYPTED, blob_type, blob_type] {
+                    rsp.extend(val.to_be_bytes());
+                }
+                rsp.extend(vec![blob_type as u8; blob_type as usize]);
+                conn.write_all(&rsp).unwrap();
+            }
+
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut cmds = Vec::new();
+            loop {
+                let cmd = read_u32(&mut conn).unwrap();
+                match cmd {
+                    CMD_STOP => {}
+                    CMD_SET_STATEBLOB => {
+                        let mut hdr = [0u8; 12];
+                        conn.read_exact(&mut hdr).unwrap();
+                        let len =
+                            u32::from_be_bytes(hdr[8..].try_into().unwrap());
+                        let mut data = vec![0u8; len as usize];
+                        conn.read_exact(&mut data).unwrap();
+                        assert!(data.iter().all(|b| u32::from(*b) == len));
+                    }
+                    CMD_INIT => {
+                        read_u32(&mut conn).unwrap();
+                    }
+                    _ => panic!("unexpected command {cmd:#x}"),
+                }
+                cmds.push(cmd);
+                conn.write_all(&0u32.to_be_bytes()).unwrap();
+                if cmd == CMD_INIT {
+                    return cmds;
+                }
+            }
+        });
+
+        let tpm = Swtpm::new(&dir.path().join("swtpm.sock"), Some(&path));
+        let state = tpm.export_state().unwrap();
+        tpm.import_state(&state).unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                CMD_STOP,
+                CMD_SET_STATEBLOB,
+                CMD_SET_STATEBLOB,
+                CMD_SET_STATEBLOB,
+                CMD_INIT
+            ]
+        );
+
+        // A truncated state is refused
+        assert!(tpm.import_state(&state[..6]).is_err());
+    }
+}
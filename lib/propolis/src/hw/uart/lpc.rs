@@ -2,7 +2,8 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Instant;
 
 use super::uart16550::{migrate, Uart};
 use crate::chardev::*;
@@ -23,6 +24,10 @@ struct UartState {
     // In the absence of better interfaces for chardev save/restore behavior,
     // allow the device to be coarsely paused (dropping all reads and writes).
     paused: bool,
+
+    /// When output is paced, the time at which the line will have finished
+    /// transmitting the last character taken from the UART
+    line_free_at: Option<Instant>,
 }
 
 impl UartState {
@@ -33,12 +38,100 @@ impl UartState {
             self.irq_pin.deassert()
         }
     }
+
+    /// Take the character (if any) awaiting transmission.  When output is
+    /// paced, the character is held until the line is free, the time of which
+    /// is returned instead.
+    fn tx_take(&mut self) -> Result<Option<u8>, Instant> {
+        let Some(line_free_at) = self.line_free_at else {
+            return Ok(self.uart.data_read());
+        };
+        if !self.uart.is_readable() {
+            return Ok(None);
+        }
+        let now = Instant::now();
+        if now < line_free_at {
+            return Err(line_free_at);
+        }
+        if let Some(char_time) = self.uart.char_time() {
+            self.line_free_at = Some(now + char_time);
+        }
+        Ok(self.uart.data_read())
+    }
+
+    /// Discard all characters awaiting transmission, returning the time at
+    /// which to try again if pacing holds any of them back.
+    fn tx_discard(&mut self, count: usize) -> (usize, Option<Instant>) {
+        let mut discarded = 0;
+        while discarded < count {
+            match self.tx_take() {
+                Ok(Some(_val)) => discarded += 1,
+                Ok(None) => break,
+                Err(deadline) => return (discarded, Some(deadline)),
+            }
+        }
+        (discarded, None)
+    }
+}
+
+/// Wakes a paced UART once its line is free to transmit again.
+struct Pacer {
+    state: Mutex<PacerState>,
+    cv: Condvar,
+}
+#[derive(Default)]
+struct PacerState {
+    deadline: Option<Instant>,
+    shutdown: bool,
+}
+impl Pacer {
+    fn new() -> Self {
+        Self { state: Mutex::new(PacerState::default()), cv: Condvar::new() }
+    }
+    fn arm(&self, deadline: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if state.deadline.map_or(true, |d| deadline < d) {
+            state.deadline = Some(deadline);
+            self.cv.notify_one();
+        }
+    }
+    fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = true;
+        self.cv.notify_one();
+    }
+    fn run(&self, uart: Weak<LpcUart>) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutdown {
+                return;
+            }
+            let Some(deadline) = state.deadline else {
+                state = self.cv.wait(state).unwrap();
+                continue;
+            };
+            let now = Instant::now();
+            if now < deadline {
+                state = self.cv.wait_timeout(state, deadline - now).unwrap().0;
+                continue;
+            }
+
+            state.deadline = None;
+            drop(state);
+            match uart.upgrade() {
+                Some(uart) => uart.line_free(),
+                None => return,
+            }
+            state = self.state.lock().unwrap();
+        }
+    }
 }
 
 pub struct LpcUart {
     state: Mutex<UartState>,
     notify_readable: NotifierCell<dyn Source>,
     notify_writable: NotifierCell<dyn Sink>,
+    pacer: Mutex<Option<Arc<Pacer>>>,
 }
 
 impl LpcUart {
@@ -49,11 +142,61 @@ impl LpcUart {
                 irq_pin,
                 auto_discard: true,
                 paused: false,
+                line_free_at: None,
             }),
             notify_readable: NotifierCell::new(),
             notify_writable: NotifierCell::new(),
+            pacer: Mutex::new(None),
         })
     }
+    /// Pace output at the baud rate (and character format) programmed by the
+    /// guest, rather than transmitting characters as fast as they are written.
+    ///
+    /// Guests observe the transmitter as busy while a character is on the
+    /// line, as they would on hardware.  Output is not paced until the guest
+    /// programs the baud rate divisor.
+    pub fn set_paced(self: &Arc<Self>, paced: bool) {
+        let mut pacer = self.pacer.lock().unwrap();
+        if paced == pacer.is_some() {
+            return;
+        }
+        if paced {
+            let p = Arc::new(Pacer::new());
+            let (worker, uart) = (p.clone(), Arc::downgrade(self));
+            std::thread::Builder::new()
+                .name("uart-pacer".to_string())
+                .spawn(move || worker.run(uart))
+                .expect("can spawn UART pacer thread");
+            *pacer = Some(p);
+            self.state.lock().unwrap().line_free_at = Some(Instant::now());
+        } else {
+            pacer.take().unwrap().shutdown();
+            self.state.lock().unwrap().line_free_at = None;
+        }
+    }
+    fn arm_pacer(&self, deadline: Option<Instant>) {
+        if let Some(deadline) = deadline {
+            if let Some(pacer) = self.pacer.lock().unwrap().as_ref() {
+                pacer.arm(deadline);
+            }
+        }
+    }
+    /// Called by the pacer once the line is free to transmit a held character
+    fn line_free(&self) {
+        let mut state = self.state.lock().unwrap();
+        let mut deadline = None;
+        if state.auto_discard {
+            (_, deadline) = state.tx_discard(usize::MAX);
+        }
+        state.sync_intr_pin();
+        let readable = state.uart.is_readable();
+        drop(state);
+
+        self.arm_pacer(deadline);
+        if readable {
+            self.notify_readable.notify(self as &dyn Source);
+        }
+    }
     pub fn attach(self: &Arc<Self>, bus: &PioBus, port: u16) {
         let this = self.clone();
         let piofn = Arc::new(move |_port: u16, rwo: RWOp| this.pio_rw(rwo))
@@ -75,8 +218,9 @@ impl LpcUart {
                 state.uart.reg_write(wo.offset() as u8, wo.read_u8());
             }
         }
+        let mut deadline = None;
         if state.auto_discard {
-            while let Some(_val) = state.uart.data_read() {}
+            (_, deadline) = state.tx_discard(usize::MAX);
         }
 
         state.sync_intr_pin();
@@ -88,6 +232,7 @@ impl LpcUart {
         // since those callbacks could immediately attempt to read/write the
         // pending data.
         drop(state);
+        self.arm_pacer(deadline);
         if read_notify {
             self.notify_readable.notify(self as &dyn Source);
         }
@@ -95,6 +240,16 @@ impl LpcUart {
             self.notify_writable.notify(self as &dyn Sink);
         }
     }
+    /// Signal a BREAK on the line into the UART, as a terminal attached to it
+    /// would send.
+    pub fn receive_break(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            return;
+        }
+        state.uart.break_received();
+        state.sync_intr_pin();
+    }
     fn reset(&self) {
         let mut state = self.state.lock().unwrap();
         state.uart.reset();
@@ -126,21 +281,23 @@ impl Source for LpcUart {
             return None;
         }
 
-        let res = state.uart.data_read();
+        let res = state.tx_take();
         state.sync_intr_pin();
-        res
+        drop(state);
+        match res {
+            Ok(val) => val,
+            Err(deadline) => {
+                self.arm_pacer(Some(deadline));
+                None
+            }
+        }
     }
     fn discard(&self, count: usize) -> usize {
         let mut state = self.state.lock().unwrap();
-        let mut discarded = 0;
-        while discarded < count {
-            if let Some(_val) = state.uart.data_read() {
-                discarded += 1;
-            } else {
-                break;
-            }
-        }
+        let (discarded, deadline) = state.tx_discard(count);
         state.sync_intr_pin();
+        drop(state);
+        self.arm_pacer(deadline);
         discarded
     }
     fn set_notifier(&self, f: Option<SourceNotifier>) {
@@ -152,6 +309,14 @@ impl Source for LpcUart {
     }
 }
 
+impl Drop for LpcUart {
+    fn drop(&mut self) {
+        if let Some(pacer) = self.pacer.get_mut().unwrap().take() {
+            pacer.shutdown();
+        }
+    }
+}
+
 impl Entity for LpcUart {
     fn type_name(&self) -> &'static str {
         "lpc-uart"
@@ -12,6 +12,7 @@
 
 use std::collections::VecDeque;
 use std::convert::AsRef;
+use std::time::Duration;
 
 use crate::migrate::MigrateStateError;
 
@@ -27,6 +28,10 @@ mod probes {
     fn uart_ign_read(offset: u8, is_dlab: u8) {}
 }
 
+/// Baud rate selected by a divisor of 1: the 1.8432 MHz reference clock, which
+/// the UART oversamples 16 times.
+const BAUD_BASE: u64 = 115200;
+
 pub struct Uart {
     reg_intr_enable: IntrEnaReg,
     reg_intr_ident: IntrIdentReg,
@@ -104,7 +109,8 @@ impl Uart {
             Some(UartReg::ModemCtrl) => self.reg_modem_ctrl.bits(),
             Some(UartReg::LineStatus) => {
                 let val = self.reg_line_status;
-                self.reg_line_status.remove(LineStatusReg::OE);
+                self.reg_line_status
+                    .remove(LineStatusReg::OE | LineStatusReg::BI);
                 self.update_isr();
 
                 val.bits()
@@ -222,9 +228,41 @@ impl Uart {
             res
         }
     }
+    /// Receive a break condition: the line held at zero for longer than a
+    /// character.  As on hardware, a zero character is received along with it.
+    pub fn break_received(&mut self) {
+        if self.is_loopback() {
+            return;
+        }
+        self.reg_line_status.insert(LineStatusReg::BI);
+        let _ = self.rx_fifo.write(0);
+        self.update_dr();
+        self.update_isr();
+    }
     pub fn intr_state(&self) -> bool {
         self.intr_pin
     }
+    /// Time taken to transmit one character on a physical line, given the
+    /// baud rate divisor and character format programmed by the guest.
+    /// Returns `None` while the divisor latch is zero (not yet programmed).
+    pub fn char_time(&self) -> Option<Duration> {
+        let divisor = u64::from(u16::from_le_bytes([
+            self.reg_div_low,
+            self.reg_div_high,
+        ]));
+        if divisor == 0 {
+            return None;
+        }
+
+        let lcr = self.reg_line_ctrl;
+        let data_bits = 5 + u64::from((lcr & LineCtrlReg::WLS).bits());
+        let parity_bits = u64::from(lcr.contains(LineCtrlReg::PEN));
+        // 1.5 stop bits (for 5-bit characters) are rounded up to 2
+        let stop_bits = 1 + u64::from(lcr.contains(LineCtrlReg::STB));
+        let bits = 1 + data_bits + parity_bits + stop_bits;
+
+        Some(Duration::from_nanos(bits * divisor * 1_000_000_000 / BAUD_BASE))
+    }
     pub fn is_readable(&self) -> bool {
         !self.tx_fifo.is_empty()
     }
@@ -262,9 +300,11 @@ impl Uart {
 
     fn next_intr(&self) -> Option<IntrIdent> {
         if self.reg_intr_enable.contains(IntrEnaReg::ELSI)
-            && self.reg_line_status.contains(LineStatusReg::OE)
+            && self
+                .reg_line_status
+                .intersects(LineStatusReg::OE | LineStatusReg::BI)
         {
-            // This ignores Parity Error, Framing Error, and Break
+            // This ignores Parity Error and Framing Error
             Some(IntrIdent::RLS)
         } else if self.reg_intr_enable.contains(IntrEnaReg::ERBFI)
             && self.reg_line_status.contains(LineStatusReg::DR)
@@ -469,6 +509,8 @@ bitflags! {
         const DR = 1 << 0;
         /// Overrun Error
         const OE = 1 << 1;
+        /// Break Interrupt
+        const BI = 1 << 4;
         /// Transmit Hold Register Empty
         const THRE = 1 << 5;
         /// Transmitter Empty
@@ -681,6 +723,7 @@ mod tests {
         // Line Status Register (LSR) bits
         pub const LSR_DR: u8 = 1 << 0; // Data Ready
         pub const LSR_OE: u8 = 1 << 1; // Overrun Error
+        pub const LSR_BI: u8 = 1 << 4; // Break Interrupt
         pub const LSR_THRE: u8 = 1 << 5; // THRE indicator
         pub const LSR_TEMT: u8 = 1 << 6; // Transmitter Empty indicator
         pub const LCR_DLAB: u8 = 0b10000000; // Divisor Latch Access Bit
@@ -741,6 +784,20 @@ mod tests {
         assert_eq!(uart.reg_read(REG_ISR) & MASK_ISRC, ISRC_NONE);
     }
     #[test]
+    fn intr_rls_on_break() {
+        let mut uart = Uart::new();
+
+        uart.reg_write(REG_IER, IER_ELSI);
+        uart.break_received();
+        assert_eq!(uart.intr_state(), true);
+        assert_eq!(uart.reg_read(REG_ISR) & MASK_ISRC, ISRC_RLS);
+        assert_eq!(uart.reg_read(REG_LSR) & (LSR_BI | LSR_DR), LSR_BI | LSR_DR);
+        // reading LSR clears the break indication, but not the zero character
+        assert_eq!(uart.intr_state(), false);
+        assert_eq!(uart.reg_read(REG_LSR) & (LSR_BI | LSR_DR), LSR_DR);
+        assert_eq!(uart.reg_read(REG_RHR), 0);
+    }
+    #[test]
     fn intr_thre_on_outgoing() {
         let mut uart = Uart::new();
         let tval = 0x20;
@@ -775,6 +832,30 @@ mod tests {
         let _ = uart.reg_write(1, 0xff);
     }
     #[test]
+    fn char_time() {
+        let mut uart = Uart::new();
+        assert_eq!(uart.char_time(), None);
+
+        // 115200 baud, 8N1: 10 bits per character
+        uart.reg_write(REG_LCR, LCR_DLAB | 0b11);
+        uart.reg_write(REG_DLL, 1);
+        uart.reg_write(REG_LCR, 0b11);
+        assert_eq!(uart.char_time(), Some(Duration::from_nanos(86_805)));
+
+        // 9600 baud, 7E2: 11 bits per character
+        uart.reg_write(REG_LCR, LCR_DLAB);
+        uart.reg_write(REG_DLL, 12);
+        uart.reg_write(REG_LCR, 0b1_1110);
+        assert_eq!(uart.char_time(), Some(Duration::from_nanos(1_145_833)));
+
+        // The divisor is honored in full
+        uart.reg_write(REG_LCR, LCR_DLAB);
+        uart.reg_write(REG_DLL, 0x80);
+        uart.reg_write(REG_DLH, 0x01);
+        uart.reg_write(REG_LCR, 0b11);
+        assert_eq!(uart.char_time(), Some(Duration::from_nanos(33_333_333)));
+    }
+    #[test]
     fn interrupt_codes() {
         let mut uart = Uart::new();
 
@@ -4,12 +4,25 @@
 
 pub const VIRTIO_DEV_NET: u16 = 0x1000;
 pub const VIRTIO_DEV_BLOCK: u16 = 0x1001;
+pub const VIRTIO_DEV_SCSI: u16 = 0x1004;
+pub const VIRTIO_DEV_RNG: u16 = 0x1005;
 pub const VIRTIO_DEV_9P: u16 = 0x1009;
+// Devices without a transitional ID may use any in the legacy range
+pub const VIRTIO_DEV_RTC: u16 = 0x1011;
+pub const VIRTIO_DEV_MEM: u16 = 0x1012;
+pub const VIRTIO_DEV_INPUT: u16 = 0x1013;
+pub const VIRTIO_DEV_VSOCK: u16 = 0x1014;
 
 // Legacy virtio-pci devices must present these sub-device-IDs
 pub const VIRTIO_SUB_DEV_NET: u16 = 0x1;
 pub const VIRTIO_SUB_DEV_BLOCK: u16 = 0x2;
+pub const VIRTIO_SUB_DEV_RNG: u16 = 0x4;
+pub const VIRTIO_SUB_DEV_SCSI: u16 = 0x8;
 pub const VIRTIO_SUB_DEV_9P_TRANSPORT: u16 = 0x9;
+pub const VIRTIO_SUB_DEV_RTC: u16 = 0x11;
+pub const VIRTIO_SUB_DEV_INPUT: u16 = 0x12;
+pub const VIRTIO_SUB_DEV_VSOCK: u16 = 0x13;
+pub const VIRTIO_SUB_DEV_MEM: u16 = 0x18;
 
 // Legacy interface feature bits
 pub const VIRTIO_F_NOTIFY_ON_EMPTY: usize = 1 << 24;
@@ -3,7 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::num::NonZeroU16;
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, Mutex, Weak};
 
 use crate::accessors::MemAccessor;
 use crate::block;
@@ -11,9 +11,10 @@ use crate::common::*;
 use crate::hw::pci;
 use crate::migrate::*;
 use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
 
 use super::bits::*;
-use super::pci::{PciVirtio, PciVirtioState};
+use super::pci::{PciVirtio, PciVirtioState, Transport};
 use super::queue::{Chain, VirtQueue, VirtQueues};
 use super::VirtioDevice;
 use bits::*;
@@ -24,6 +25,9 @@ use lazy_static::lazy_static;
 /// Sizing for virtio-block is specified in 512B sectors
 const SECTOR_SZ: usize = 512;
 
+/// Segments accepted in a single discard request
+const MAX_DISCARD_SEG: usize = 32;
+
 struct CompletionPayload {
     /// ID of original request.
     rid: u16,
@@ -35,6 +39,9 @@ pub struct PciVirtioBlock {
     virtio_state: PciVirtioState,
     pci_state: pci::DeviceState,
 
+    /// Serial number reported in answer to `VIRTIO_BLK_T_GET_ID`
+    serial: Mutex<String>,
+
     block_attach: block::device::Attachment,
     block_tracking: block::device::Tracking<CompletionPayload>,
 }
@@ -55,11 +62,13 @@ impl PciVirtioBlock {
             VIRTIO_SUB_DEV_BLOCK,
             pci::bits::CLASS_STORAGE,
             VIRTIO_BLK_CFG_SIZE,
+            Transport::Transitional,
         );
 
         Arc::new_cyclic(|weak| Self {
             pci_state,
             virtio_state,
+            serial: Mutex::new(String::new()),
             block_attach: block::device::Attachment::new(),
             block_tracking: block::device::Tracking::new(
                 weak.clone() as Weak<dyn block::Device>
@@ -67,6 +76,40 @@ impl PciVirtioBlock {
         })
     }
 
+    /// Set the deadline for I/O requests issued to the block backend.  See
+    /// [`block::device::Tracking::set_timeout()`].
+    pub fn set_request_timeout(&self, timeout: Option<std::time::Duration>) {
+        self.block_tracking.set_timeout(timeout);
+    }
+
+    /// Set the serial number reported by the device to `VIRTIO_BLK_T_GET_ID`
+    /// requests.
+    ///
+    /// # Panics
+    ///
+    /// If `serial` exceeds 20 bytes, or is not printable ASCII.
+    pub fn set_serial(&self, serial: &str) {
+        assert!(serial.len() <= VIRTIO_BLK_ID_BYTES);
+        assert!(serial.bytes().all(|c| c.is_ascii_graphic() || c == b' '));
+        *self.serial.lock().unwrap() = serial.to_string();
+    }
+
+    /// Set (or clear) write-protection on the device.
+    ///
+    /// While write-protected, write requests from the guest are failed with
+    /// an I/O error, and the device advertises `VIRTIO_BLK_F_RO` to guests
+    /// which (re)negotiate features.  A config-change interrupt is raised so
+    /// that a running guest may take notice of the change.
+    pub fn set_write_protect(&self, write_protect: bool) {
+        if self.block_attach.write_protected() == write_protect {
+            return;
+        }
+        self.block_attach.set_write_protect(write_protect);
+        if self.block_attach.info().is_some() {
+            self.virtio_state.notify_config_change(&self.pci_state);
+        }
+    }
+
     fn block_cfg_read(&self, id: &BlockReg, ro: &mut ReadOp) {
         let info = self.block_attach.info().unwrap_or_else(Default::default);
 
@@ -80,6 +123,15 @@ impl PciVirtioBlock {
                 ro.write_u32(128 - 2);
             }
             BlockReg::BlockSize => ro.write_u32(info.block_size),
+            BlockReg::MaxDiscardSectors | BlockReg::MaxZeroSectors => {
+                ro.write_u32(u32::MAX);
+            }
+            BlockReg::MaxDiscardSeg => ro.write_u32(MAX_DISCARD_SEG as u32),
+            BlockReg::DiscardSectorAlign => {
+                ro.write_u32(info.block_size / SECTOR_SZ as u32);
+            }
+            BlockReg::MaxZeroSeg => ro.write_u32(1),
+            BlockReg::ZeroMayUnmap => ro.write_u8(info.supports_discard as u8),
             BlockReg::Unused => {
                 ro.fill(0);
             }
@@ -94,15 +146,31 @@ impl PciVirtioBlock {
         let vq = &self.virtio_state.queues[0];
         let mem = self.pci_state.acc_mem.access()?;
 
+        // Requests answered without involving the backend are completed here,
+        // moving on to the next until one for the backend is found
+        loop {
+            if let Some(req) = self.pop_req(vq, &mem)? {
+                return Some(req);
+            }
+        }
+    }
+
+    /// Pop a request off the queue, returning `Some(None)` if it was
+    /// completed without being issued to the backend.
+    fn pop_req(
+        &self,
+        vq: &VirtQueue,
+        mem: &MemCtx,
+    ) -> Option<Option<block::Request>> {
         let mut chain = Chain::with_capacity(4);
         // Pop a request off the queue if there's one available.
         // For debugging purposes, we'll also use the returned index
         // as a psuedo-id for the request to associate it with its
         // subsequent completion
-        let (rid, _clen) = vq.pop_avail(&mut chain, &mem)?;
+        let (rid, _clen) = vq.pop_avail(&mut chain, mem)?;
 
         let mut breq = VbReq::default();
-        if !chain.read(&mut breq, &mem) {
+        if !chain.read(&mut breq, mem) {
             todo!("error handling");
         }
         let off = breq.sector as usize * SECTOR_SZ;
@@ -122,9 +190,14 @@ impl PciVirtioBlock {
                         CompletionPayload { rid, chain },
                     ))
                 } else {
-                    Err(chain)
+                    Err((chain, VIRTIO_BLK_S_UNSUPP))
                 }
             }
+            VIRTIO_BLK_T_OUT if self.block_attach.write_protected() => {
+                // Writes to a write-protected device fail without consulting
+                // the backend at all.
+                Err((chain, VIRTIO_BLK_S_IOERR))
+            }
             VIRTIO_BLK_T_OUT => {
                 // should be (blocksize * 512) remaining read bytes
                 let blocks = chain.remain_read_bytes() / SECTOR_SZ;
@@ -139,7 +212,7 @@ impl PciVirtioBlock {
                         CompletionPayload { rid, chain },
                     ))
                 } else {
-                    Err(chain)
+                    Err((chain, VIRTIO_BLK_S_UNSUPP))
                 }
             }
             VIRTIO_BLK_T_FLUSH => {
@@ -149,20 +222,114 @@ impl PciVirtioBlock {
                     CompletionPayload { rid, chain },
                 ))
             }
-            _ => Err(chain),
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+                if self.block_attach.write_protected() =>
+            {
+                Err((chain, VIRTIO_BLK_S_IOERR))
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                match self.read_segments(breq.rtype, &mut chain, mem) {
+                    Ok(req) => {
+                        match req.oper() {
+                            block::Operation::WriteZeroes(off, sz) => {
+                                probes::vioblk_write_zeroes_enqueue!(|| (
+                                    rid, off as u64, sz as u64
+                                ));
+                            }
+                            _ => {
+                                let nseg = req.ranges().len() as u64;
+                                probes::vioblk_discard_enqueue!(|| (rid, nseg));
+                            }
+                        }
+                        Ok(self
+                            .block_tracking
+                            .track(req, CompletionPayload { rid, chain }))
+                    }
+                    Err(status) => Err((chain, status)),
+                }
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                // The ID is answered by the device itself.  It is padded with
+                // NULs, but not terminated by one if a full 20 bytes long.
+                let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+                let serial = self.serial.lock().unwrap();
+                id[..serial.len()].copy_from_slice(serial.as_bytes());
+                if chain.remain_write_bytes() > VIRTIO_BLK_ID_BYTES
+                    && chain.write(&id, mem)
+                {
+                    Err((chain, VIRTIO_BLK_S_OK))
+                } else {
+                    Err((chain, VIRTIO_BLK_S_IOERR))
+                }
+            }
+            _ => Err((chain, VIRTIO_BLK_S_UNSUPP)),
         };
         match req {
-            Err(mut chain) => {
-                // try to set the status byte to failed
+            Err((mut chain, status)) => {
+                // Set the status byte of a request which is not going to the
+                // backend, be it failed or answered here
                 let remain = chain.remain_write_bytes();
                 if remain >= 1 {
                     chain.write_skip(remain - 1);
-                    chain.write(&VIRTIO_BLK_S_UNSUPP, &mem);
+                    chain.write(&status, mem);
+                }
+                vq.push_used(&mut chain, mem);
+                Some(None)
+            }
+            Ok(r) => Some(Some(r)),
+        }
+    }
+
+    /// Read the segments of a discard or write-zeroes request, checking them
+    /// against the capabilities and capacity of the backend.
+    fn read_segments(
+        &self,
+        rtype: u32,
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) -> Result<block::Request, u8> {
+        let info = self.block_attach.info().unwrap_or_else(Default::default);
+        let discard = rtype == VIRTIO_BLK_T_DISCARD;
+        let (supported, max_seg) = if discard {
+            (info.supports_discard, MAX_DISCARD_SEG)
+        } else {
+            (info.supports_write_zeroes, 1)
+        };
+        if !supported || info.read_only {
+            return Err(VIRTIO_BLK_S_UNSUPP);
+        }
+
+        let nseg = chain.remain_read_bytes() / std::mem::size_of::<VbSeg>();
+        if nseg == 0 || nseg > max_seg {
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        let capacity = info.total_size * info.block_size as u64;
+        let mut ranges = Vec::with_capacity(nseg);
+        let mut unmap = false;
+        for _ in 0..nseg {
+            let mut seg = VbSeg::default();
+            if !chain.read(&mut seg, mem) {
+                return Err(VIRTIO_BLK_S_IOERR);
+            }
+            // Discarded ranges are always unmapped; asking for it is an error.
+            if discard && seg.flags & VIRTIO_BLK_WZ_FLAG_UNMAP != 0 {
+                return Err(VIRTIO_BLK_S_UNSUPP);
+            }
+            unmap = seg.flags & VIRTIO_BLK_WZ_FLAG_UNMAP != 0;
+            let len = seg.num_sectors as u64 * SECTOR_SZ as u64;
+            let off = seg.sector.checked_mul(SECTOR_SZ as u64);
+            match (off, off.and_then(|off| off.checked_add(len))) {
+                (Some(off), Some(end)) if end <= capacity => {
+                    ranges.push((off as usize, len as usize));
                 }
-                vq.push_used(&mut chain, &mem);
-                None
+                _ => return Err(VIRTIO_BLK_S_IOERR),
             }
-            Ok(r) => Some(r),
+        }
+        if discard {
+            Ok(block::Request::new_discard(ranges))
+        } else {
+            let (off, len) = ranges[0];
+            Ok(block::Request::new_write_zeroes(off, len, unmap))
         }
     }
 
@@ -180,6 +347,10 @@ impl PciVirtioBlock {
                 block::Result::Failure => VIRTIO_BLK_S_IOERR,
                 block::Result::ReadOnly => VIRTIO_BLK_S_IOERR,
                 block::Result::Unsupported => VIRTIO_BLK_S_UNSUPP,
+                // virtio-blk has no notion of media which is not ready, so
+                // backends hold such requests rather than complete them (see
+                // `block::Device::reports_not_ready`).
+                block::Result::NotReady => VIRTIO_BLK_S_IOERR,
             };
             match op {
                 block::Operation::Read(..) => {
@@ -191,6 +362,12 @@ impl PciVirtioBlock {
                 block::Operation::Flush => {
                     probes::vioblk_flush_complete!(|| (rid, resnum));
                 }
+                block::Operation::Discard => {
+                    probes::vioblk_discard_complete!(|| (rid, resnum));
+                }
+                block::Operation::WriteZeroes(..) => {
+                    probes::vioblk_write_zeroes_complete!(|| (rid, resnum));
+                }
             }
             chain.write(&resnum, &mem);
             vq.push_used(chain, &mem);
@@ -215,6 +392,13 @@ impl VirtioDevice for PciVirtioBlock {
         let info = self.block_attach.info().unwrap_or_else(Default::default);
         if info.read_only {
             feat |= VIRTIO_BLK_F_RO;
+        } else {
+            if info.supports_discard {
+                feat |= VIRTIO_BLK_F_DISCARD;
+            }
+            if info.supports_write_zeroes {
+                feat |= VIRTIO_BLK_F_WRITE_ZEROES;
+            }
         }
         feat
     }
@@ -225,6 +409,10 @@ impl VirtioDevice for PciVirtioBlock {
     fn queue_notify(&self, _vq: &Arc<VirtQueue>) {
         self.block_attach.notify()
     }
+    fn notify_suppressible(&self) -> bool {
+        // Backend workers pull requests until the queue is empty
+        true
+    }
 }
 impl PciVirtio for PciVirtioBlock {
     fn virtio_state(&self) -> &PciVirtioState {
@@ -244,7 +432,10 @@ impl block::Device for PciVirtioBlock {
     }
 
     fn complete(&self, res: block::Result, id: block::ReqId) {
-        let (op, mut payload) = self.block_tracking.complete(id, res);
+        let Some((op, mut payload)) = self.block_tracking.complete(id, res)
+        else {
+            return;
+        };
         let CompletionPayload { rid, ref mut chain } = payload;
         self.complete_req(rid, op, res, chain);
     }
@@ -252,6 +443,10 @@ impl block::Device for PciVirtioBlock {
     fn accessor_mem(&self) -> MemAccessor {
         self.pci_state.acc_mem.child(Some("block backend".to_string()))
     }
+
+    fn io_history(&self) -> Option<block::device::IoHistory> {
+        Some(self.block_tracking.io_history())
+    }
 }
 impl Entity for PciVirtioBlock {
     fn type_name(&self) -> &'static str {
@@ -299,6 +494,15 @@ struct VbReq {
     sector: u64,
 }
 
+/// A segment of a discard or write-zeroes request
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VbSeg {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum BlockReg {
     Capacity,
@@ -359,14 +563,20 @@ mod bits {
     pub const VIRTIO_BLK_T_IN: u32 = 0;
     pub const VIRTIO_BLK_T_OUT: u32 = 1;
     pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+    pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
     pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
     pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
 
+    pub const VIRTIO_BLK_WZ_FLAG_UNMAP: u32 = 1 << 0;
+
     pub const VIRTIO_BLK_S_OK: u8 = 0;
     pub const VIRTIO_BLK_S_IOERR: u8 = 1;
     pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
 
     pub const VIRTIO_BLK_CFG_SIZE: usize = 0x3c;
+
+    /// Length of the device ID returned by `VIRTIO_BLK_T_GET_ID`
+    pub const VIRTIO_BLK_ID_BYTES: usize = 20;
 }
 
 #[usdt::provider(provider = "propolis")]
@@ -379,4 +589,10 @@ mod probes {
 
     fn vioblk_flush_enqueue(id: u16) {}
     fn vioblk_flush_complete(id: u16, res: u8) {}
+
+    fn vioblk_discard_enqueue(id: u16, nseg: u64) {}
+    fn vioblk_discard_complete(id: u16, res: u8) {}
+
+    fn vioblk_write_zeroes_enqueue(id: u16, off: u64, sz: u64) {}
+    fn vioblk_write_zeroes_complete(id: u16, res: u8) {}
 }
@@ -0,0 +1,577 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! virtio-input: keyboard and tablet devices
+//!
+//! Input is delivered to the guest as the events of Linux's input subsystem,
+//! each written to a buffer posted by the driver on the event queue, with
+//! every report closed by a `SYN_REPORT` event.  The driver learns what the
+//! device is capable of reporting through its configuration space, in which it
+//! selects the item (and sub-item) to be read.
+//!
+//! A keyboard offers the keys of the PS/2 keyboard, identified (as there) by
+//! X11 keysym.  A tablet reports the absolute position of the pointer, so that
+//! a guest's cursor tracks that of a VNC client exactly, rather than drifting
+//! as relative movement is subjected to the guest's pointer acceleration.
+//!
+//! Events are held until the driver posts buffers for them, up to a limit
+//! beyond which further input is dropped.  Whole reports are dropped, rather
+//! than parts of them, so a guest never sees a key pressed without the
+//! `SYN_REPORT` which follows.  The keyboard has no LEDs, so buffers posted on
+//! the status queue, through which the driver would set them, are returned
+//! unread.
+
+use std::collections::VecDeque;
+use std::num::NonZeroU16;
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::hw::ids::pci::VENDOR_OXIDE;
+use crate::hw::pci;
+use crate::hw::ps2::ctrl::MouseButtons;
+use crate::hw::ps2::keyboard::KeyEventRep;
+use crate::migrate::*;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState, Transport};
+use super::queue::{Chain, VirtQueue, VirtQueues};
+use super::{VirtioDevice, VqChange};
+use bits::*;
+
+/// Queue on which events are delivered to the driver
+const EVENT_QUEUE: u16 = 0;
+
+/// Largest position reported by a tablet along either axis, corresponding to
+/// the right (or bottom) edge of the screen
+pub const TABLET_ABS_MAX: u32 = 0x7fff;
+
+/// Number of events which may await buffers from the driver
+const MAX_PENDING_EVENTS: usize = 256;
+
+/// The kind of input device presented to the guest
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputKind {
+    Keyboard,
+    /// A pointing device reporting absolute positions, with left, right, and
+    /// middle buttons
+    Tablet,
+}
+impl InputKind {
+    fn name(&self) -> &'static str {
+        match self {
+            InputKind::Keyboard => "Propolis Virtio Keyboard",
+            InputKind::Tablet => "Propolis Virtio Tablet",
+        }
+    }
+    fn product_id(&self) -> u16 {
+        match self {
+            InputKind::Keyboard => 1,
+            InputKind::Tablet => 2,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+struct Event {
+    ev_type: u16,
+    code: u16,
+    value: u32,
+}
+impl Event {
+    fn new(ev_type: u16, code: u16, value: u32) -> Self {
+        Self {
+            ev_type: ev_type.to_le(),
+            code: code.to_le(),
+            value: value.to_le(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct InputState {
+    /// Item of the configuration space selected by the driver
+    select: u8,
+    subsel: u8,
+    /// Events awaiting buffers from the driver
+    pending: VecDeque<Event>,
+    /// Buttons of the tablet most recently reported as held
+    buttons: MouseButtons,
+    paused: bool,
+}
+
+pub struct PciVirtioInput {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    kind: InputKind,
+    state: Mutex<InputState>,
+}
+impl PciVirtioInput {
+    pub fn new(queue_size: u16, kind: InputKind) -> Arc<Self> {
+        // The event queue, and the status queue
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(2).unwrap(),
+        );
+        let msix_count = Some(3);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_INPUT,
+            VIRTIO_SUB_DEV_INPUT,
+            pci::bits::CLASS_INPUT,
+            VIRTIO_INPUT_CFG_SIZE,
+            Transport::Transitional,
+        );
+        Arc::new(Self {
+            virtio_state,
+            pci_state,
+            kind,
+            state: Mutex::new(InputState::default()),
+        })
+    }
+
+    pub fn kind(&self) -> InputKind {
+        self.kind
+    }
+
+    /// Press or release the key identified by `keysym`, returning whether the
+    /// key is recognized (and the device is a keyboard).
+    ///
+    /// Keys are identified as by [`PS2Ctrl::keysym_event()`].
+    ///
+    /// [`PS2Ctrl::keysym_event()`]: crate::hw::ps2::ctrl::PS2Ctrl::keysym_event
+    pub fn keysym_event(&self, keysym: u32, is_pressed: bool) -> bool {
+        if self.kind != InputKind::Keyboard {
+            return false;
+        }
+        let Ok(key_rep) = KeyEventRep::new(keysym, is_pressed) else {
+            return false;
+        };
+        let event =
+            Event::new(EV_KEY, key_rep.to_key_code(), is_pressed.into());
+        let mut state = self.state.lock().unwrap();
+        self.send_report(&mut state, &[event]);
+        true
+    }
+
+    /// Move the tablet's pointer to (`x`, `y`), with `buttons` held.
+    ///
+    /// Positions range from 0 to [`TABLET_ABS_MAX`] along each axis, from the
+    /// top-left corner of the screen.  Larger values are clamped.
+    pub fn tablet_event(&self, x: u32, y: u32, buttons: MouseButtons) {
+        if self.kind != InputKind::Tablet {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let events = tablet_events(x, y, state.buttons, buttons);
+        if self.send_report(&mut state, &events) {
+            state.buttons = buttons;
+        }
+    }
+
+    /// Queue `events` for delivery, followed by a `SYN_REPORT`, returning
+    /// whether there was room for them.
+    fn send_report(&self, state: &mut InputState, events: &[Event]) -> bool {
+        if state.pending.len() + events.len() + 1 > MAX_PENDING_EVENTS {
+            return false;
+        }
+        state.pending.extend(events);
+        state.pending.push_back(Event::new(EV_SYN, SYN_REPORT, 0));
+        self.deliver(state);
+        true
+    }
+
+    /// Write pending events into the buffers posted by the driver.
+    fn deliver(&self, state: &mut InputState) {
+        if state.paused {
+            return;
+        }
+        let vq = self.virtio_state.queues.get(EVENT_QUEUE).unwrap();
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(1);
+        while let Some(event) = state.pending.front() {
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+            chain.write(event, &mem);
+            vq.push_used(&mut chain, &mem);
+            state.pending.pop_front();
+        }
+    }
+
+    /// Discard the events sent by the driver on the status queue.
+    fn discard_status(&self, vq: &VirtQueue, mem: &MemCtx) {
+        let mut chain = Chain::with_capacity(1);
+        while vq.pop_avail(&mut chain, mem).is_some() {
+            vq.push_used(&mut chain, mem);
+        }
+    }
+}
+
+/// Events reporting the tablet's pointer at (`x`, `y`), and any change in its
+/// buttons from `held` to `buttons`.
+fn tablet_events(
+    x: u32,
+    y: u32,
+    held: MouseButtons,
+    buttons: MouseButtons,
+) -> Vec<Event> {
+    let mut events = vec![
+        Event::new(EV_ABS, ABS_X, x.min(TABLET_ABS_MAX)),
+        Event::new(EV_ABS, ABS_Y, y.min(TABLET_ABS_MAX)),
+    ];
+    for (button, code) in [
+        (MouseButtons::LEFT, BTN_LEFT),
+        (MouseButtons::RIGHT, BTN_RIGHT),
+        (MouseButtons::MIDDLE, BTN_MIDDLE),
+    ] {
+        if held.contains(button) != buttons.contains(button) {
+            events.push(Event::new(
+                EV_KEY,
+                code,
+                buttons.contains(button).into(),
+            ));
+        }
+    }
+    events
+}
+
+/// Contents of the configuration space of a device of `kind`, with the item
+/// identified by `select` and `subsel` selected.  An item the device does
+/// not have is of size zero.
+fn config_image(
+    kind: InputKind,
+    select: u8,
+    subsel: u8,
+) -> [u8; VIRTIO_INPUT_CFG_SIZE] {
+    let item = config_item(kind, select, subsel);
+    let len = item.len().min(VIRTIO_INPUT_CFG_SIZE - VIRTIO_INPUT_CFG_HDR_SIZE);
+
+    let mut image = [0u8; VIRTIO_INPUT_CFG_SIZE];
+    image[0] = select;
+    image[1] = subsel;
+    image[2] = len as u8;
+    image[VIRTIO_INPUT_CFG_HDR_SIZE..][..len].copy_from_slice(&item[..len]);
+    image
+}
+
+fn config_item(kind: InputKind, select: u8, subsel: u8) -> Vec<u8> {
+    match (select, kind) {
+        (VIRTIO_INPUT_CFG_ID_NAME, _) => kind.name().as_bytes().to_vec(),
+        (VIRTIO_INPUT_CFG_ID_DEVIDS, _) => {
+            [BUS_VIRTUAL, VENDOR_OXIDE, kind.product_id(), 1]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect()
+        }
+        (VIRTIO_INPUT_CFG_EV_BITS, InputKind::Keyboard) => {
+            match subsel as u16 {
+                EV_KEY => bitmap(KEY_ESC..=KEY_RIGHTMETA),
+                _ => Vec::new(),
+            }
+        }
+        (VIRTIO_INPUT_CFG_EV_BITS, InputKind::Tablet) => match subsel as u16 {
+            EV_KEY => bitmap([BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]),
+            EV_ABS => bitmap([ABS_X, ABS_Y]),
+            _ => Vec::new(),
+        },
+        (VIRTIO_INPUT_CFG_ABS_INFO, InputKind::Tablet) => match subsel as u16 {
+            ABS_X | ABS_Y => {
+                // Minimum, maximum, fuzz, flat, and resolution
+                [0, TABLET_ABS_MAX, 0, 0, 0]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect()
+            }
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// A bitmap with the given `bits` set, no longer than needed to hold them
+fn bitmap(bits: impl IntoIterator<Item = u16>) -> Vec<u8> {
+    let mut map = Vec::new();
+    for bit in bits {
+        let byte = bit as usize / 8;
+        if map.len() <= byte {
+            map.resize(byte + 1, 0);
+        }
+        map[byte] |= 1 << (bit % 8);
+    }
+    map
+}
+
+impl VirtioDevice for PciVirtioInput {
+    fn cfg_rw(&self, rwo: RWOp) {
+        let mut state = self.state.lock().unwrap();
+        match rwo {
+            RWOp::Read(ro) => ro.write_from(&config_image(
+                self.kind,
+                state.select,
+                state.subsel,
+            )),
+            RWOp::Write(wo) => {
+                // Only the selection is writable
+                let mut hdr = [state.select, state.subsel];
+                wo.read_into(&mut hdr);
+                state.select = hdr[0];
+                state.subsel = hdr[1];
+            }
+        }
+    }
+    fn get_features(&self) -> u32 {
+        0
+    }
+    fn set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        if vq.id == EVENT_QUEUE {
+            let mut state = self.state.lock().unwrap();
+            self.deliver(&mut state);
+        } else if let Some(mem) = vq.acc_mem.access() {
+            self.discard_status(vq, &mem);
+        }
+    }
+
+    fn queue_change(&self, vq: &Arc<VirtQueue>, change: VqChange) {
+        if vq.id == EVENT_QUEUE && matches!(change, VqChange::Reset) {
+            // Input from before the driver was (re)initialized is stale
+            let mut state = self.state.lock().unwrap();
+            let paused = state.paused;
+            *state = InputState { paused, ..Default::default() };
+        }
+    }
+}
+impl PciVirtio for PciVirtioInput {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+impl Entity for PciVirtioInput {
+    fn type_name(&self) -> &'static str {
+        match self.kind {
+            InputKind::Keyboard => "pci-virtio-keyboard",
+            InputKind::Tablet => "pci-virtio-tablet",
+        }
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn pause(&self) {
+        self.state.lock().unwrap().paused = true;
+    }
+    fn resume(&self) {
+        // Input may have arrived while paused (or prior to an import)
+        let mut state = self.state.lock().unwrap();
+        state.paused = false;
+        self.deliver(&mut state);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciVirtioInput {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let state = self.state.lock().unwrap();
+        output.push(
+            migrate::InputV1 {
+                select: state.select,
+                subsel: state.subsel,
+                pending: state
+                    .pending
+                    .iter()
+                    .map(|ev| {
+                        (
+                            u16::from_le(ev.ev_type),
+                            u16::from_le(ev.code),
+                            u32::from_le(ev.value),
+                        )
+                    })
+                    .collect(),
+                buttons: state.buttons.bits(),
+            }
+            .into(),
+        )?;
+        drop(state);
+
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::InputV1 = offer.take()?;
+        let mut state = self.state.lock().unwrap();
+        state.select = input.select;
+        state.subsel = input.subsel;
+        state.pending = input
+            .pending
+            .into_iter()
+            .map(|(ev_type, code, value)| Event::new(ev_type, code, value))
+            .collect();
+        state.buttons = MouseButtons::from_bits_truncate(input.buttons);
+        drop(state);
+
+        <dyn PciVirtio>::import(self, offer, ctx)
+    }
+}
+
+mod bits {
+    #![allow(unused)]
+
+    pub const VIRTIO_INPUT_CFG_UNSET: u8 = 0x00;
+    pub const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+    pub const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+    pub const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+    pub const VIRTIO_INPUT_CFG_PROP_BITS: u8 = 0x10;
+    pub const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+    pub const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+    /// Size of the select, subsel, and size fields, along with the reserved
+    /// bytes which precede the selected item
+    pub const VIRTIO_INPUT_CFG_HDR_SIZE: usize = 8;
+    pub const VIRTIO_INPUT_CFG_SIZE: usize = VIRTIO_INPUT_CFG_HDR_SIZE + 128;
+
+    // Event types, as in Linux's input subsystem
+    pub const EV_SYN: u16 = 0x00;
+    pub const EV_KEY: u16 = 0x01;
+    pub const EV_REL: u16 = 0x02;
+    pub const EV_ABS: u16 = 0x03;
+    pub const EV_LED: u16 = 0x11;
+
+    pub const SYN_REPORT: u16 = 0;
+
+    pub const KEY_ESC: u16 = 1;
+    pub const KEY_RIGHTMETA: u16 = 126;
+    pub const BTN_LEFT: u16 = 0x110;
+    pub const BTN_RIGHT: u16 = 0x111;
+    pub const BTN_MIDDLE: u16 = 0x112;
+
+    pub const ABS_X: u16 = 0x00;
+    pub const ABS_Y: u16 = 0x01;
+
+    pub const BUS_VIRTUAL: u16 = 0x06;
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct InputV1 {
+        pub select: u8,
+        pub subsel: u8,
+        /// Events awaiting delivery, as their type, code, and value
+        pub pending: Vec<(u16, u16, u32)>,
+        pub buttons: u8,
+    }
+    impl Schema<'_> for InputV1 {
+        fn id() -> SchemaId {
+            ("pci-virtio-input", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_selection() {
+        let image =
+            config_image(InputKind::Keyboard, VIRTIO_INPUT_CFG_ID_NAME, 0);
+        let name = InputKind::Keyboard.name().as_bytes();
+        assert_eq!(image[..3], [VIRTIO_INPUT_CFG_ID_NAME, 0, name.len() as u8]);
+        assert_eq!(&image[8..][..name.len()], name);
+
+        // Items the device lacks are of size zero
+        let image =
+            config_image(InputKind::Keyboard, VIRTIO_INPUT_CFG_EV_BITS, 3);
+        assert_eq!(image[2], 0);
+        let image =
+            config_image(InputKind::Keyboard, VIRTIO_INPUT_CFG_ABS_INFO, 0);
+        assert_eq!(image[2], 0);
+        let image = config_image(InputKind::Tablet, VIRTIO_INPUT_CFG_UNSET, 0);
+        assert_eq!(image[2], 0);
+    }
+
+    #[test]
+    fn tablet_capabilities() {
+        let keys = config_item(
+            InputKind::Tablet,
+            VIRTIO_INPUT_CFG_EV_BITS,
+            EV_KEY as u8,
+        );
+        assert_eq!(keys.len(), 35);
+        assert_eq!(keys[34], 0b111);
+        assert!(keys[..34].iter().all(|b| *b == 0));
+
+        let abs = config_item(
+            InputKind::Tablet,
+            VIRTIO_INPUT_CFG_EV_BITS,
+            EV_ABS as u8,
+        );
+        assert_eq!(abs, [0b11]);
+
+        let info = config_item(
+            InputKind::Tablet,
+            VIRTIO_INPUT_CFG_ABS_INFO,
+            ABS_Y as u8,
+        );
+        assert_eq!(info.len(), 20);
+        assert_eq!(info[4..8], TABLET_ABS_MAX.to_le_bytes());
+    }
+
+    #[test]
+    fn keyboard_keys() {
+        let keys = config_item(
+            InputKind::Keyboard,
+            VIRTIO_INPUT_CFG_EV_BITS,
+            EV_KEY as u8,
+        );
+        assert_eq!(keys.len(), 16);
+        // KEY_RESERVED is not reported
+        assert_eq!(keys[0], 0xfe);
+
+        // Plain and extended keys alike map onto the advertised codes
+        for (keysym, code) in [(0x61, 30), (0xff0d, 28), (0xff51, 105)] {
+            let rep = KeyEventRep::new(keysym, true).unwrap();
+            assert_eq!(rep.to_key_code(), code);
+        }
+    }
+
+    #[test]
+    fn tablet_button_changes() {
+        let events = tablet_events(
+            0x10000,
+            0x100,
+            MouseButtons::LEFT,
+            MouseButtons::RIGHT,
+        );
+        assert_eq!(
+            events,
+            [
+                Event::new(EV_ABS, ABS_X, TABLET_ABS_MAX),
+                Event::new(EV_ABS, ABS_Y, 0x100),
+                Event::new(EV_KEY, BTN_LEFT, 0),
+                Event::new(EV_KEY, BTN_RIGHT, 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,559 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! virtio-mem: hot-pluggable guest memory
+//!
+//! The device manages a region of guest-physical memory, apart from that which
+//! is reported to the guest at boot, in fixed-size blocks.  The host requests
+//! that some amount of the region be plugged, and the guest driver plugs (or
+//! unplugs) blocks to match, onlining the plugged memory for its own use.  The
+//! memory of an instance can thus be grown, and shrunk, while it runs.
+//!
+//! The region is backed by a memory segment of its own (see [`HotplugRegion`]),
+//! which is allocated and mapped into the guest when its first block is
+//! plugged, and unmapped once its last block is unplugged.  bhyve's memory
+//! segments are scarce, and cannot be freed individually, so the region is not
+//! backed block by block: while any of it is plugged, all of it is mapped.
+//! `VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE` is therefore not offered, and the
+//! guest may still access unplugged blocks in a region which is mapped (whose
+//! contents are then undefined), as the specification permits in the absence
+//! of that feature.
+
+use std::num::NonZeroU16;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+use crate::vmm::{HotplugRegion, MemCtx};
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState, Transport};
+use super::queue::{Chain, VirtQueue, VirtQueues};
+use super::VirtioDevice;
+use bits::*;
+
+use lazy_static::lazy_static;
+
+/// Granularity at which memory is plugged and unplugged
+pub const MEM_BLOCK_SIZE: usize = 2 * 1024 * 1024;
+
+/// Alignment required of the region in guest-physical memory, so that it spans
+/// whole memory blocks as onlined by guests (128MiB, in the case of Linux)
+pub const MEM_REGION_ALIGN: usize = 1024 * 1024 * 1024;
+
+/// Plugged state of the blocks in the device's region
+struct Blocks {
+    /// Guest-physical address of the region
+    base: u64,
+    plugged: Vec<bool>,
+    /// Number of blocks which are plugged
+    num_plugged: usize,
+    /// Number of blocks the host would like to be plugged
+    num_requested: usize,
+}
+impl Blocks {
+    fn new(base: u64, count: usize) -> Self {
+        Self {
+            base,
+            plugged: vec![false; count],
+            num_plugged: 0,
+            num_requested: 0,
+        }
+    }
+
+    /// Blocks addressed by a request for `count` blocks starting at `addr`, if
+    /// they lie within the region
+    fn range(&self, addr: u64, count: u16) -> Option<Range<usize>> {
+        let offset = addr.checked_sub(self.base)?;
+        if count == 0 || offset % MEM_BLOCK_SIZE as u64 != 0 {
+            return None;
+        }
+        let first = usize::try_from(offset / MEM_BLOCK_SIZE as u64).ok()?;
+        let end = first.checked_add(count as usize)?;
+        (end <= self.plugged.len()).then_some(first..end)
+    }
+
+    fn plug(&mut self, blocks: Range<usize>) -> u16 {
+        if self.plugged[blocks.clone()].iter().any(|p| *p) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+        if self.num_plugged + blocks.len() > self.num_requested {
+            return VIRTIO_MEM_RESP_NACK;
+        }
+        self.plugged[blocks.clone()].fill(true);
+        self.num_plugged += blocks.len();
+        VIRTIO_MEM_RESP_ACK
+    }
+
+    fn unplug(&mut self, blocks: Range<usize>) -> u16 {
+        if !self.plugged[blocks.clone()].iter().all(|p| *p) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+        self.plugged[blocks.clone()].fill(false);
+        self.num_plugged -= blocks.len();
+        VIRTIO_MEM_RESP_ACK
+    }
+
+    fn unplug_all(&mut self) {
+        self.plugged.fill(false);
+        self.num_plugged = 0;
+    }
+
+    fn state(&self, blocks: Range<usize>) -> u16 {
+        let plugged = &self.plugged[blocks];
+        if plugged.iter().all(|p| *p) {
+            VIRTIO_MEM_STATE_PLUGGED
+        } else if plugged.iter().any(|p| *p) {
+            VIRTIO_MEM_STATE_MIXED
+        } else {
+            VIRTIO_MEM_STATE_UNPLUGGED
+        }
+    }
+
+    /// Handle a request, returning the type of the response and the state of
+    /// the addressed blocks (for state requests).
+    fn handle(&mut self, req: &Req) -> (u16, u16) {
+        let req_type = u16::from_le(req.req_type);
+        if req_type == VIRTIO_MEM_REQ_UNPLUG_ALL {
+            self.unplug_all();
+            return (VIRTIO_MEM_RESP_ACK, 0);
+        }
+
+        let Some(blocks) =
+            self.range(u64::from_le(req.addr), u16::from_le(req.nb_blocks))
+        else {
+            return (VIRTIO_MEM_RESP_ERROR, 0);
+        };
+        match req_type {
+            VIRTIO_MEM_REQ_PLUG => (self.plug(blocks), 0),
+            VIRTIO_MEM_REQ_UNPLUG => (self.unplug(blocks), 0),
+            VIRTIO_MEM_REQ_STATE => (VIRTIO_MEM_RESP_ACK, self.state(blocks)),
+            _ => (VIRTIO_MEM_RESP_ERROR, 0),
+        }
+    }
+}
+
+pub struct PciVirtioMem {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    blocks: Mutex<Blocks>,
+    region: HotplugRegion,
+}
+impl PciVirtioMem {
+    /// Create a device managing the hot-pluggable memory `region`, which the
+    /// machine must not report to the guest as memory in its own right.
+    ///
+    /// # Panics
+    ///
+    /// If the region is not aligned to [`MEM_REGION_ALIGN`], or its size is
+    /// not a multiple of it.
+    pub fn new(queue_size: u16, region: HotplugRegion) -> Arc<Self> {
+        let (addr, size) = (region.addr(), region.size());
+        assert_eq!(addr % MEM_REGION_ALIGN, 0);
+        assert_eq!(size % MEM_REGION_ALIGN, 0);
+
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(1).unwrap(),
+        );
+        let msix_count = Some(2);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_MEM,
+            VIRTIO_SUB_DEV_MEM,
+            pci::bits::CLASS_MEMORY,
+            VIRTIO_MEM_CFG_SIZE,
+            Transport::Transitional,
+        );
+        Arc::new(Self {
+            virtio_state,
+            pci_state,
+            blocks: Mutex::new(Blocks::new(addr as u64, size / MEM_BLOCK_SIZE)),
+            region,
+        })
+    }
+
+    /// Size, in bytes, of the region of memory managed by the device
+    pub fn region_size(&self) -> usize {
+        self.blocks.lock().unwrap().plugged.len() * MEM_BLOCK_SIZE
+    }
+
+    /// Amount of memory, in bytes, the guest is asked to have plugged
+    pub fn requested_size(&self) -> usize {
+        self.blocks.lock().unwrap().num_requested * MEM_BLOCK_SIZE
+    }
+
+    /// Amount of memory, in bytes, the guest has plugged
+    pub fn plugged_size(&self) -> usize {
+        self.blocks.lock().unwrap().num_plugged * MEM_BLOCK_SIZE
+    }
+
+    /// Ask the guest to plug (or unplug) memory so that `size` bytes of the
+    /// region are plugged.  A config-change interrupt is raised so that the
+    /// driver takes notice of the new request.
+    ///
+    /// # Panics
+    ///
+    /// If `size` is not a multiple of [`MEM_BLOCK_SIZE`], or exceeds the size
+    /// of the region.
+    pub fn set_requested_size(&self, size: usize) {
+        assert_eq!(size % MEM_BLOCK_SIZE, 0);
+        let mut blocks = self.blocks.lock().unwrap();
+        let num_requested = size / MEM_BLOCK_SIZE;
+        assert!(num_requested <= blocks.plugged.len());
+        if blocks.num_requested == num_requested {
+            return;
+        }
+        blocks.num_requested = num_requested;
+        drop(blocks);
+        self.virtio_state.notify_config_change(&self.pci_state);
+    }
+
+    fn mem_cfg_read(&self, id: &MemReg, ro: &mut ReadOp) {
+        let blocks = self.blocks.lock().unwrap();
+        let size = |count: usize| (count * MEM_BLOCK_SIZE) as u64;
+        match id {
+            MemReg::BlockSize => ro.write_u64(MEM_BLOCK_SIZE as u64),
+            MemReg::NodeId => ro.write_u16(0),
+            MemReg::Addr => ro.write_u64(blocks.base),
+            MemReg::RegionSize | MemReg::UsableRegionSize => {
+                ro.write_u64(size(blocks.plugged.len()))
+            }
+            MemReg::PluggedSize => ro.write_u64(size(blocks.num_plugged)),
+            MemReg::RequestedSize => ro.write_u64(size(blocks.num_requested)),
+            MemReg::Reserved => ro.fill(0),
+        }
+    }
+
+    /// Backs the region with memory while any of it is plugged, and releases
+    /// it from the guest once none of it is.
+    fn update_backing(&self, blocks: &Blocks) -> std::io::Result<()> {
+        match blocks.num_plugged {
+            0 => self.region.unplug(),
+            _ => self.region.plug(),
+        }
+    }
+
+    fn process_request(&self, chain: &mut Chain, mem: &MemCtx) {
+        let mut req = Req::default();
+        let (resp_type, state) = if chain.read(&mut req, mem) {
+            let mut blocks = self.blocks.lock().unwrap();
+            // The region must be backed before any of it is reported plugged.
+            if u16::from_le(req.req_type) == VIRTIO_MEM_REQ_PLUG
+                && self.region.plug().is_err()
+            {
+                (VIRTIO_MEM_RESP_NACK, 0)
+            } else {
+                let resp = blocks.handle(&req);
+                // A region which cannot be unmapped remains accessible to the
+                // guest, as unplugged blocks of a plugged region are.
+                let _ = self.update_backing(&blocks);
+                resp
+            }
+        } else {
+            (VIRTIO_MEM_RESP_ERROR, 0)
+        };
+        let resp = Resp {
+            resp_type: resp_type.to_le(),
+            padding: [0; 3],
+            state: state.to_le(),
+        };
+        chain.write(&resp, mem);
+    }
+}
+
+impl VirtioDevice for PciVirtioMem {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        MEM_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.mem_cfg_read(id, ro),
+            // The configuration is read-only
+            RWOp::Write(_) => {}
+        });
+    }
+    fn get_features(&self) -> u32 {
+        0
+    }
+    fn set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(2);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            self.process_request(&mut chain, &mem);
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+}
+impl PciVirtio for PciVirtioMem {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+impl Entity for PciVirtioMem {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-mem"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+
+        // A rebooted guest begins with none of the region plugged, and plugs
+        // memory anew to meet the request, which persists across reset.
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.unplug_all();
+        let _ = self.update_backing(&blocks);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciVirtioMem {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let blocks = self.blocks.lock().unwrap();
+        let mut plugged = vec![0u64; (blocks.plugged.len() + 63) / 64];
+        for (idx, _) in blocks.plugged.iter().enumerate().filter(|(_, p)| **p) {
+            plugged[idx / 64] |= 1 << (idx % 64);
+        }
+        output.push(
+            migrate::MemV1 {
+                region_size: (blocks.plugged.len() * MEM_BLOCK_SIZE) as u64,
+                requested_size: (blocks.num_requested * MEM_BLOCK_SIZE) as u64,
+                plugged,
+            }
+            .into(),
+        )?;
+        drop(blocks);
+
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        let input: migrate::MemV1 = offer.take()?;
+        let mut blocks = self.blocks.lock().unwrap();
+        let count = blocks.plugged.len();
+        if input.region_size != (count * MEM_BLOCK_SIZE) as u64 {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "virtio-mem region size mismatch: {} vs {}",
+                input.region_size,
+                count * MEM_BLOCK_SIZE
+            )));
+        }
+        if input.requested_size % MEM_BLOCK_SIZE as u64 != 0
+            || input.requested_size > input.region_size
+            || input.plugged.len() != (count + 63) / 64
+        {
+            return Err(MigrateStateError::ImportFailed(
+                "virtio-mem: invalid plugged state".to_string(),
+            ));
+        }
+        for idx in 0..count {
+            blocks.plugged[idx] =
+                input.plugged[idx / 64] & (1 << (idx % 64)) != 0;
+        }
+        blocks.num_plugged = blocks.plugged.iter().filter(|p| **p).count();
+        blocks.num_requested = input.requested_size as usize / MEM_BLOCK_SIZE;
+        self.update_backing(&blocks).map_err(|e| {
+            MigrateStateError::ImportFailed(format!(
+                "virtio-mem: failed to back region: {e}"
+            ))
+        })?;
+        drop(blocks);
+
+        <dyn PciVirtio>::import(self, offer, ctx)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct Req {
+    req_type: u16,
+    padding: [u16; 3],
+    addr: u64,
+    nb_blocks: u16,
+    padding_body: [u16; 3],
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct Resp {
+    resp_type: u16,
+    padding: [u16; 3],
+    state: u16,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum MemReg {
+    BlockSize,
+    NodeId,
+    Addr,
+    RegionSize,
+    UsableRegionSize,
+    PluggedSize,
+    RequestedSize,
+    Reserved,
+}
+lazy_static! {
+    static ref MEM_DEV_REGS: RegMap<MemReg> = {
+        let layout = [
+            (MemReg::BlockSize, 8),
+            (MemReg::NodeId, 2),
+            (MemReg::Reserved, 6),
+            (MemReg::Addr, 8),
+            (MemReg::RegionSize, 8),
+            (MemReg::UsableRegionSize, 8),
+            (MemReg::PluggedSize, 8),
+            (MemReg::RequestedSize, 8),
+        ];
+        RegMap::create_packed(
+            VIRTIO_MEM_CFG_SIZE,
+            &layout,
+            Some(MemReg::Reserved),
+        )
+    };
+}
+
+mod bits {
+    #![allow(unused)]
+
+    pub const VIRTIO_MEM_F_ACPI_PXM: u32 = 1 << 0;
+    pub const VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE: u32 = 1 << 1;
+
+    pub const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+    pub const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+    pub const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+    pub const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+    pub const VIRTIO_MEM_RESP_ACK: u16 = 0;
+    pub const VIRTIO_MEM_RESP_NACK: u16 = 1;
+    pub const VIRTIO_MEM_RESP_BUSY: u16 = 2;
+    pub const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+    pub const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+    pub const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+    pub const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+    pub const VIRTIO_MEM_CFG_SIZE: usize = 0x38;
+}
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct MemV1 {
+        pub region_size: u64,
+        pub requested_size: u64,
+        /// Bitmap of plugged blocks
+        pub plugged: Vec<u64>,
+    }
+    impl Schema<'_> for MemV1 {
+        fn id() -> SchemaId {
+            ("pci-virtio-mem", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: u64 = 0x100_0000_0000;
+    const BLOCK: u64 = MEM_BLOCK_SIZE as u64;
+
+    fn req(req_type: u16, block: u64, nb_blocks: u16) -> Req {
+        Req {
+            req_type,
+            addr: BASE + block * BLOCK,
+            nb_blocks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plug_within_request() {
+        let mut blocks = Blocks::new(BASE, 16);
+
+        // Nothing may be plugged until the host requests it
+        let plug = req(VIRTIO_MEM_REQ_PLUG, 2, 4);
+        assert_eq!(blocks.handle(&plug).0, VIRTIO_MEM_RESP_NACK);
+
+        blocks.num_requested = 4;
+        assert_eq!(blocks.handle(&plug).0, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(blocks.num_plugged, 4);
+
+        // Blocks cannot be plugged twice, nor beyond the request
+        assert_eq!(blocks.handle(&plug).0, VIRTIO_MEM_RESP_ERROR);
+        let more = req(VIRTIO_MEM_REQ_PLUG, 8, 1);
+        assert_eq!(blocks.handle(&more).0, VIRTIO_MEM_RESP_NACK);
+
+        let mut state = |first, count| {
+            blocks.handle(&req(VIRTIO_MEM_REQ_STATE, first, count))
+        };
+        assert_eq!(
+            state(2, 4),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_PLUGGED)
+        );
+        assert_eq!(state(0, 4), (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_MIXED));
+        assert_eq!(
+            state(6, 10),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_UNPLUGGED)
+        );
+    }
+
+    #[test]
+    fn unplug() {
+        let mut blocks = Blocks::new(BASE, 16);
+        blocks.num_requested = 16;
+        blocks.handle(&req(VIRTIO_MEM_REQ_PLUG, 0, 8));
+
+        // Only plugged blocks may be unplugged
+        let unplug = req(VIRTIO_MEM_REQ_UNPLUG, 6, 4);
+        let resp = blocks.handle(&unplug);
+        assert_eq!(resp.0, VIRTIO_MEM_RESP_ERROR);
+
+        let unplug = req(VIRTIO_MEM_REQ_UNPLUG, 4, 4);
+        let resp = blocks.handle(&unplug);
+        assert_eq!(resp.0, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(blocks.state(4..8), VIRTIO_MEM_STATE_UNPLUGGED);
+        assert_eq!(blocks.num_plugged, 4);
+
+        let resp = blocks.handle(&req(VIRTIO_MEM_REQ_UNPLUG_ALL, 0, 0));
+        assert_eq!(resp.0, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(blocks.num_plugged, 0);
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let mut blocks = Blocks::new(BASE, 16);
+        blocks.num_requested = 16;
+        let mut check = |req: Req| {
+            let resp = blocks.handle(&req);
+            assert_eq!(resp.0, VIRTIO_MEM_RESP_ERROR);
+        };
+
+        check(req(VIRTIO_MEM_REQ_PLUG, 0, 0));
+        check(req(VIRTIO_MEM_REQ_PLUG, 15, 2));
+        check(req(VIRTIO_MEM_REQ_STATE, 16, 1));
+        check(Req { addr: BASE - BLOCK, ..req(VIRTIO_MEM_REQ_PLUG, 0, 1) });
+        check(Req { addr: BASE + 0x1000, ..req(VIRTIO_MEM_REQ_PLUG, 0, 1) });
+        check(req(0x10, 0, 1));
+    }
+}
@@ -8,19 +8,33 @@ use std::sync::Arc;
 mod bits;
 
 pub mod block;
+pub mod input;
+pub mod mem;
+pub mod null_net;
 #[cfg(feature = "falcon")]
 pub mod p9fs;
 pub mod pci;
-mod queue;
+pub(crate) mod queue;
+pub mod rng;
+pub mod rtc;
+pub mod scsi;
 #[cfg(feature = "falcon")]
 pub mod softnpu;
 pub mod viona;
+pub mod vsock;
 
 use crate::common::*;
 use queue::VirtQueue;
 
 pub use block::PciVirtioBlock;
+pub use input::PciVirtioInput;
+pub use mem::PciVirtioMem;
+pub use null_net::PciVirtioNullNet;
+pub use rng::PciVirtioRng;
+pub use rtc::PciVirtioRtc;
+pub use scsi::PciVirtioScsi;
 pub use viona::PciVirtioViona;
+pub use vsock::PciVirtioVsock;
 
 pub trait VirtioDevice: Send + Sync + 'static + Entity {
     /// Read/write device-specific virtio configuration space
@@ -32,6 +46,13 @@ pub trait VirtioDevice: Send + Sync + 'static + Entity {
     /// Service driver notification for a given virtqueue
     fn queue_notify(&self, vq: &Arc<VirtQueue>);
 
+    /// Does the device drain a virtqueue, until it is found empty, after each
+    /// notification?  If so, the driver is asked not to notify the device of
+    /// requests posted while the queue is being drained.
+    fn notify_suppressible(&self) -> bool {
+        false
+    }
+
     #[allow(unused_variables)]
     /// Notification of virtqueue configuration change
     fn queue_change(&self, vq: &Arc<VirtQueue>, change: VqChange) {}
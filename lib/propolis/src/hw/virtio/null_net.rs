@@ -0,0 +1,158 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A virtio-net device with no network behind it.
+//!
+//! Packets transmitted by the guest are completed as soon as they are popped
+//! off the TX queue, and then dropped.  Nothing is ever received, so buffers
+//! posted to the RX queue stay there.  With the host network out of the
+//! picture, the cost of guest transmits is that of the virtio emulation
+//! alone, which makes this device useful for benchmarking that path and
+//! detecting regressions in it.  (Guest NICs are otherwise backed by viona,
+//! whose data path lives in the kernel.)
+
+use std::num::NonZeroU16;
+use std::sync::Arc;
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState, Transport};
+use super::queue::{Chain, VirtQueue, VirtQueues};
+use super::viona::bits::VIRTIO_NET_S_LINK_UP;
+use super::VirtioDevice;
+
+use lazy_static::lazy_static;
+
+/// Queue on which the guest posts packets to transmit
+const TX_QUEUE: u16 = 1;
+
+pub struct PciVirtioNullNet {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    mac_addr: [u8; 6],
+}
+impl PciVirtioNullNet {
+    pub fn new(queue_size: u16, mac_addr: [u8; 6]) -> Arc<Self> {
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(2).unwrap(),
+        );
+        // interrupts for TX, RX, and device config
+        let msix_count = Some(3);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_NET,
+            VIRTIO_SUB_DEV_NET,
+            pci::bits::CLASS_NETWORK,
+            VIRTIO_NET_CFG_SIZE,
+            Transport::Transitional,
+        );
+        Arc::new(Self { virtio_state, pci_state, mac_addr })
+    }
+
+    fn net_cfg_read(&self, id: &NetReg, ro: &mut ReadOp) {
+        match id {
+            NetReg::Mac => ro.write_bytes(&self.mac_addr),
+            NetReg::Status => {
+                // Always report link up
+                ro.write_u16(VIRTIO_NET_S_LINK_UP);
+            }
+            NetReg::MaxVqPairs => ro.write_u16(1),
+        }
+    }
+}
+
+impl VirtioDevice for PciVirtioNullNet {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        NET_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.net_cfg_read(id, ro),
+            RWOp::Write(_) => {
+                //ignore writes
+            }
+        });
+    }
+    fn get_features(&self) -> u32 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+    fn set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        // Buffers posted for receipt are left in place, as no packets arrive
+        if vq.id != TX_QUEUE {
+            return;
+        }
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(4);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+    fn notify_suppressible(&self) -> bool {
+        // The TX queue is drained when notified, and the RX queue needs no
+        // notifications at all.
+        true
+    }
+}
+impl PciVirtio for PciVirtioNullNet {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+impl Entity for PciVirtioNullNet {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-null-net"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciVirtioNullNet {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum NetReg {
+    Mac,
+    Status,
+    MaxVqPairs,
+}
+lazy_static! {
+    static ref NET_DEV_REGS: RegMap<NetReg> = {
+        let layout =
+            [(NetReg::Mac, 6), (NetReg::Status, 2), (NetReg::MaxVqPairs, 2)];
+        RegMap::create_packed(VIRTIO_NET_CFG_SIZE, &layout, None)
+    };
+}
+
+mod bits {
+    pub const VIRTIO_NET_CFG_SIZE: usize = 0xa;
+}
+use bits::*;
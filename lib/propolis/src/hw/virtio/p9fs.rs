@@ -19,7 +19,7 @@ use crate::util::regmap::RegMap;
 use crate::vmm::MemCtx;
 
 use super::bits::*;
-use super::pci::{PciVirtio, PciVirtioState};
+use super::pci::{PciVirtio, PciVirtioState, Transport};
 use super::queue::{write_buf, Chain, VirtQueue, VirtQueues};
 use super::VirtioDevice;
 
@@ -81,6 +81,7 @@ impl PciVirtio9pfs {
             VIRTIO_SUB_DEV_9P_TRANSPORT,
             pci::bits::CLASS_STORAGE,
             VIRTIO_9P_CFG_SIZE,
+            Transport::Transitional,
         );
         Arc::new(Self { virtio_state, pci_state, handler })
     }
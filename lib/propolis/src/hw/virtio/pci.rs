@@ -15,7 +15,7 @@ use crate::hw::ids::pci::VENDOR_VIRTIO;
 use crate::hw::pci;
 use crate::intr_pins::IntrPin;
 use crate::migrate::*;
-use crate::util::regmap::RegMap;
+use crate::util::regmap::{Flags, RegMap};
 
 use lazy_static::lazy_static;
 
@@ -24,6 +24,25 @@ const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
 const VIRTIO_PCI_ISR_QUEUE: u8 = 1 << 0;
 const VIRTIO_PCI_ISR_CFG: u8 = 1 << 1;
 
+// Types of the regions described by `virtio_pci_cap` capabilities
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// The virtio-pci interface(s) through which a device is exposed
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Transport {
+    /// Only the legacy (pre-1.0) interface, in the I/O BAR
+    Legacy,
+    /// Both the legacy interface and the modern (1.0+) interface, the regions
+    /// of which are located in an MMIO BAR and described by vendor-specific
+    /// capabilities.  Drivers aware of the modern interface negotiate
+    /// `VIRTIO_F_VERSION_1` through it, while older drivers continue to use the
+    /// legacy interface.
+    Transitional,
+}
+
 bitflags! {
     #[derive(Default, PartialEq)]
     pub struct Status: u8 {
@@ -56,7 +75,11 @@ impl From<pci::IntrMode> for IntrMode {
 struct VirtioState {
     status: Status,
     queue_sel: u16,
-    nego_feat: u32,
+    nego_feat: u64,
+    /// Selected 32-bit half of the device features (modern interface)
+    dev_feat_sel: u32,
+    /// Selected 32-bit half of the driver features (modern interface)
+    drv_feat_sel: u32,
     intr_mode: IntrMode,
     intr_mode_updating: bool,
     msix_cfg_vec: u16,
@@ -71,6 +94,8 @@ impl VirtioState {
             status: Status::RESET,
             queue_sel: 0,
             nego_feat: 0,
+            dev_feat_sel: 0,
+            drv_feat_sel: 0,
             intr_mode: IntrMode::IsrOnly,
             intr_mode_updating: false,
             msix_cfg_vec: VIRTIO_MSI_NO_VECTOR,
@@ -81,6 +106,8 @@ impl VirtioState {
         self.status = Status::RESET;
         self.queue_sel = 0;
         self.nego_feat = 0;
+        self.dev_feat_sel = 0;
+        self.drv_feat_sel = 0;
         self.msix_cfg_vec = VIRTIO_MSI_NO_VECTOR;
     }
 }
@@ -88,6 +115,16 @@ impl VirtioState {
 pub trait PciVirtio: VirtioDevice + Send + Sync + 'static {
     fn virtio_state(&self) -> &PciVirtioState;
     fn pci_state(&self) -> &pci::DeviceState;
+
+    /// The QueueNotify register has been placed at `port` (or removed, if
+    /// `None`).  A device which can service queue notifications without the
+    /// involvement of userspace should arrange to do so, and return `true`.
+    /// Otherwise, notifications are delivered via
+    /// [`VirtioDevice::queue_notify()`] through a doorbell on the PIO bus.
+    #[allow(unused_variables)]
+    fn notify_port_update(&self, port: Option<u16>) -> bool {
+        false
+    }
 }
 
 impl<D: PciVirtio + Send + Sync + 'static> pci::Device for D {
@@ -97,22 +134,66 @@ impl<D: PciVirtio + Send + Sync + 'static> pci::Device for D {
     fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp) {
         let vs = self.virtio_state();
 
-        assert_eq!(bar, pci::BarN::BAR0);
-        let map = match vs.map_which.load(Ordering::SeqCst) {
-            false => &vs.map_nomsix,
-            true => &vs.map,
-        };
-        map.process(&mut rwo, |id, mut rwo| match id {
-            VirtioTop::LegacyConfig => {
-                LEGACY_REGS.process(&mut rwo, |id, rwo| match rwo {
-                    RWOp::Read(ro) => vs.legacy_read(self, id, ro),
-                    RWOp::Write(wo) => {
-                        vs.legacy_write(self.pci_state(), self, id, wo)
+        match bar {
+            pci::BarN::BAR0 => {
+                let map = match vs.map_which.load(Ordering::SeqCst) {
+                    false => &vs.map_nomsix,
+                    true => &vs.map,
+                };
+                map.process(&mut rwo, |id, mut rwo| match id {
+                    VirtioTop::LegacyConfig => {
+                        LEGACY_REGS.process(&mut rwo, |id, rwo| match rwo {
+                            RWOp::Read(ro) => vs.legacy_read(self, id, ro),
+                            RWOp::Write(wo) => {
+                                vs.legacy_write(self.pci_state(), self, id, wo)
+                            }
+                        })
                     }
-                })
+                    VirtioTop::DeviceConfig => self.cfg_rw(rwo),
+                });
             }
-            VirtioTop::DeviceConfig => self.cfg_rw(rwo),
-        });
+            MODERN_BAR => {
+                let map = vs.map_modern.as_ref().unwrap();
+                map.process(&mut rwo, |id, mut rwo| match id {
+                    ModernTop::CommonConfig => {
+                        COMMON_REGS.process(&mut rwo, |id, rwo| match rwo {
+                            RWOp::Read(ro) => vs.common_read(self, id, ro),
+                            RWOp::Write(wo) => {
+                                vs.common_write(self.pci_state(), self, id, wo)
+                            }
+                        })
+                    }
+                    ModernTop::Isr => {
+                        if let RWOp::Read(ro) = rwo {
+                            // reading ISR Status clears it as well
+                            ro.write_u8(vs.isr_state.read_clear());
+                        }
+                    }
+                    ModernTop::DeviceConfig => self.cfg_rw(rwo),
+                    ModernTop::Notify => match rwo {
+                        RWOp::Read(ro) => ro.fill(0),
+                        RWOp::Write(wo) => vs.queue_notify(self, wo.read_u16()),
+                    },
+                    ModernTop::Reserved => {
+                        if let RWOp::Read(ro) = rwo {
+                            ro.fill(0);
+                        }
+                    }
+                });
+            }
+            _ => panic!("unexpected virtio BAR {:?}", bar),
+        }
+    }
+    fn bar_doorbell(&self, bar: pci::BarN) -> Option<u16> {
+        (bar == pci::BarN::BAR0).then_some(LEGACY_REG_OFF_QUEUE_NOTIFY)
+    }
+    fn doorbell_ring(&self, bar: pci::BarN, val: u16) {
+        assert_eq!(bar, pci::BarN::BAR0);
+        self.virtio_state().queue_notify(self, val);
+    }
+    fn doorbell_placed(&self, bar: pci::BarN, port: Option<u16>) -> bool {
+        assert_eq!(bar, pci::BarN::BAR0);
+        self.notify_port_update(port)
     }
     fn attach(&self) {
         let ps = self.pci_state();
@@ -175,6 +256,10 @@ pub struct PciVirtioState {
 
     map: RegMap<VirtioTop>,
     map_nomsix: RegMap<VirtioTop>,
+
+    transport: Transport,
+    /// Register map of the modern interface BAR, if present
+    map_modern: Option<RegMap<ModernTop>>,
 }
 impl PciVirtioState {
     pub(super) fn create(
@@ -184,6 +269,7 @@ impl PciVirtioState {
         sub_dev_id: u16,
         dev_class: u8,
         cfg_sz: usize,
+        transport: Transport,
     ) -> (Self, pci::DeviceState) {
         let mut builder = pci::Builder::new(pci::Ident {
             vendor_id: VENDOR_VIRTIO,
@@ -201,16 +287,44 @@ impl PciVirtioState {
 
         // XXX: properly size the legacy cfg BAR
         builder = builder.add_bar_io(pci::BarN::BAR0, 0x200);
+
+        let map_modern = match transport {
+            Transport::Legacy => None,
+            Transport::Transitional => {
+                assert!(cfg_sz < MODERN_SLOT_SZ);
+                builder = builder
+                    .add_bar_mmio(MODERN_BAR, MODERN_BAR_SZ as u32)
+                    .add_cap_vendor(&modern_cap(
+                        VIRTIO_PCI_CAP_COMMON_CFG,
+                        MODERN_OFF_COMMON,
+                        COMMON_REG_SZ,
+                    ))
+                    .add_cap_vendor(&modern_cap(
+                        VIRTIO_PCI_CAP_ISR_CFG,
+                        MODERN_OFF_ISR,
+                        1,
+                    ))
+                    .add_cap_vendor(&modern_notify_cap());
+                if cfg_sz != 0 {
+                    builder = builder.add_cap_vendor(&modern_cap(
+                        VIRTIO_PCI_CAP_DEVICE_CFG,
+                        MODERN_OFF_DEVICE,
+                        cfg_sz,
+                    ));
+                }
+                Some(modern_map(cfg_sz))
+            }
+        };
         let pci_state = builder.finish();
 
-        let layout = [
-            (VirtioTop::LegacyConfig, LEGACY_REG_SZ),
-            (VirtioTop::DeviceConfig, cfg_sz),
-        ];
-        let layout_nomsix = [
-            (VirtioTop::LegacyConfig, LEGACY_REG_SZ_NO_MSIX),
-            (VirtioTop::DeviceConfig, cfg_sz),
-        ];
+        let mut layout = vec![(VirtioTop::LegacyConfig, LEGACY_REG_SZ)];
+        let mut layout_nomsix =
+            vec![(VirtioTop::LegacyConfig, LEGACY_REG_SZ_NO_MSIX)];
+        // Some devices have no device-specific configuration at all
+        if cfg_sz != 0 {
+            layout.push((VirtioTop::DeviceConfig, cfg_sz));
+            layout_nomsix.push((VirtioTop::DeviceConfig, cfg_sz));
+        }
 
         // Allow VQs to access memory through the PCI state
 
@@ -231,6 +345,9 @@ impl PciVirtioState {
                 &layout_nomsix,
             ),
             map_which: AtomicBool::new(false),
+
+            transport,
+            map_modern,
         };
 
         for queue in this.queues.iter() {
@@ -251,11 +368,13 @@ impl PciVirtioState {
     ) {
         match id {
             LegacyReg::FeatDevice => {
-                ro.write_u32(self.features_supported(dev));
+                // The legacy interface is limited to the lower 32 feature bits,
+                // which excludes VIRTIO_F_VERSION_1.
+                ro.write_u32(self.features_supported(dev) as u32);
             }
             LegacyReg::FeatDriver => {
                 let state = self.state.lock().unwrap();
-                ro.write_u32(state.nego_feat);
+                ro.write_u32(state.nego_feat as u32);
             }
             LegacyReg::QueuePfn => {
                 let state = self.state.lock().unwrap();
@@ -308,9 +427,9 @@ impl PciVirtioState {
     ) {
         match id {
             LegacyReg::FeatDriver => {
-                let nego = wo.read_u32() & self.features_supported(dev);
+                let nego = wo.read_u32() & self.features_supported(dev) as u32;
                 let mut state = self.state.lock().unwrap();
-                state.nego_feat = nego;
+                state.nego_feat = nego as u64;
                 dev.set_features(nego);
             }
             LegacyReg::QueuePfn => {
@@ -341,49 +460,241 @@ impl PciVirtioState {
                 state.msix_cfg_vec = wo.read_u16();
             }
             LegacyReg::MsixVectorQueue => {
-                let hdl = pci_state.msix_hdl().unwrap();
+                self.set_queue_msix_vec(pci_state, dev, wo.read_u16());
+            }
+
+            LegacyReg::FeatDevice
+            | LegacyReg::QueueSize
+            | LegacyReg::IsrStatus => {
+                // Read-only regs
+            }
+        }
+    }
+
+    fn common_read(
+        &self,
+        dev: &dyn VirtioDevice,
+        id: &CommonReg,
+        ro: &mut ReadOp,
+    ) {
+        match id {
+            CommonReg::DeviceFeatureSelect => {
+                let state = self.state.lock().unwrap();
+                ro.write_u32(state.dev_feat_sel);
+            }
+            CommonReg::DeviceFeature => {
+                let feat = self.features_supported(dev);
+                let state = self.state.lock().unwrap();
+                ro.write_u32(feat_half(feat, state.dev_feat_sel));
+            }
+            CommonReg::DriverFeatureSelect => {
+                let state = self.state.lock().unwrap();
+                ro.write_u32(state.drv_feat_sel);
+            }
+            CommonReg::DriverFeature => {
+                let state = self.state.lock().unwrap();
+                ro.write_u32(feat_half(state.nego_feat, state.drv_feat_sel));
+            }
+            CommonReg::MsixVectorConfig => {
+                let state = self.state.lock().unwrap();
+                ro.write_u16(state.msix_cfg_vec);
+            }
+            CommonReg::NumQueues => {
+                ro.write_u16(self.queues.count().get());
+            }
+            CommonReg::DeviceStatus => {
+                let state = self.state.lock().unwrap();
+                ro.write_u8(state.status.bits());
+            }
+            CommonReg::ConfigGeneration => {
+                // Device configuration is not altered while being read
+                ro.write_u8(0);
+            }
+            CommonReg::QueueSelect => {
+                let state = self.state.lock().unwrap();
+                ro.write_u16(state.queue_sel);
+            }
+            CommonReg::QueueSize => {
+                let state = self.state.lock().unwrap();
+                if self.queues.get(state.queue_sel).is_some() {
+                    ro.write_u16(self.queues.queue_size().get());
+                } else {
+                    // A size of 0 indicates the queue is unavailable
+                    ro.write_u16(0);
+                }
+            }
+            CommonReg::QueueMsixVector => {
+                let state = self.state.lock().unwrap();
+                let val = state
+                    .msix_queue_vec
+                    .get(state.queue_sel as usize)
+                    .unwrap_or(&VIRTIO_MSI_NO_VECTOR);
+                ro.write_u16(*val);
+            }
+            CommonReg::QueueEnable => {
+                let state = self.state.lock().unwrap();
+                let enabled = self
+                    .queues
+                    .get(state.queue_sel)
+                    .is_some_and(|queue| queue.get_state().mapping.valid);
+                ro.write_u16(enabled as u16);
+            }
+            CommonReg::QueueNotifyOff => {
+                // All queues share a single notification register
+                ro.write_u16(0);
+            }
+            CommonReg::QueueDesc
+            | CommonReg::QueueDriver
+            | CommonReg::QueueDevice => {
+                let state = self.state.lock().unwrap();
+                if let Some(queue) = self.queues.get(state.queue_sel) {
+                    let mapping = queue.get_state().mapping;
+                    ro.write_u64(match id {
+                        CommonReg::QueueDesc => mapping.desc_addr,
+                        CommonReg::QueueDriver => mapping.avail_addr,
+                        _ => mapping.used_addr,
+                    });
+                } else {
+                    // bogus queue
+                    ro.write_u64(0);
+                }
+            }
+        }
+    }
+    fn common_write(
+        &self,
+        pci_state: &pci::DeviceState,
+        dev: &dyn VirtioDevice,
+        id: &CommonReg,
+        wo: &mut WriteOp,
+    ) {
+        match id {
+            CommonReg::DeviceFeatureSelect => {
+                let mut state = self.state.lock().unwrap();
+                state.dev_feat_sel = wo.read_u32();
+            }
+            CommonReg::DriverFeatureSelect => {
                 let mut state = self.state.lock().unwrap();
-                let sel = state.queue_sel as usize;
+                state.drv_feat_sel = wo.read_u32();
+            }
+            CommonReg::DriverFeature => {
+                let val = wo.read_u32() as u64;
+                let supported = self.features_supported(dev);
+                let mut state = self.state.lock().unwrap();
+                let shift = match state.drv_feat_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                let nego = (state.nego_feat & !(0xffff_ffff << shift))
+                    | ((val << shift) & supported);
+                state.nego_feat = nego;
+                if shift == 0 {
+                    dev.set_features(nego as u32);
+                }
+            }
+            CommonReg::MsixVectorConfig => {
+                let mut state = self.state.lock().unwrap();
+                state.msix_cfg_vec = wo.read_u16();
+            }
+            CommonReg::DeviceStatus => {
+                self.set_status(dev, wo.read_u8());
+            }
+            CommonReg::QueueSelect => {
+                let mut state = self.state.lock().unwrap();
+                state.queue_sel = wo.read_u16();
+            }
+            CommonReg::QueueMsixVector => {
+                self.set_queue_msix_vec(pci_state, dev, wo.read_u16());
+            }
+            CommonReg::QueueEnable => {
+                let state = self.state.lock().unwrap();
+                if let Some(queue) = self.queues.get(state.queue_sel) {
+                    // Queues cannot be disabled, other than by device reset
+                    if wo.read_u16() == 1 {
+                        let mut info = queue.get_state();
+                        info.mapping.valid = true;
+                        queue.set_state(&info);
+                        dev.queue_change(queue, VqChange::Address);
+                    }
+                }
+            }
+            CommonReg::QueueDesc
+            | CommonReg::QueueDriver
+            | CommonReg::QueueDevice => {
+                let state = self.state.lock().unwrap();
                 if let Some(queue) = self.queues.get(state.queue_sel) {
-                    let val = wo.read_u16();
-
-                    if state.intr_mode != IntrMode::Msi {
-                        // Store the vector information for later
-                        state.msix_queue_vec[sel] = val;
-                    } else {
-                        state = self
-                            .state_cv
-                            .wait_while(state, |s| s.intr_mode_updating)
-                            .unwrap();
-                        state.intr_mode_updating = true;
-                        state.msix_queue_vec[sel] = val;
-
-                        // State lock cannot be held while updating queue
-                        // interrupt handlers due to deadlock possibility.
-                        drop(state);
-                        queue.set_intr(MsiIntr::new(hdl, val));
-                        state = self.state.lock().unwrap();
-
-                        // With the MSI configuration updated for the virtqueue,
-                        // notify the device of the change
-                        dev.queue_change(queue, VqChange::IntrCfg);
-
-                        state.intr_mode_updating = false;
-                        self.state_cv.notify_all();
+                    let addr = wo.read_u64();
+                    let mut info = queue.get_state();
+                    match id {
+                        CommonReg::QueueDesc => info.mapping.desc_addr = addr,
+                        CommonReg::QueueDriver => {
+                            info.mapping.avail_addr = addr
+                        }
+                        _ => info.mapping.used_addr = addr,
                     }
+                    queue.set_state(&info);
                 }
             }
 
-            LegacyReg::FeatDevice
-            | LegacyReg::QueueSize
-            | LegacyReg::IsrStatus => {
+            CommonReg::DeviceFeature
+            | CommonReg::NumQueues
+            | CommonReg::ConfigGeneration
+            | CommonReg::QueueSize
+            | CommonReg::QueueNotifyOff => {
                 // Read-only regs
+                //
+                // The queue size is fixed, so attempts by the driver to shrink
+                // it are ignored as well.
             }
         }
     }
 
-    fn features_supported(&self, dev: &dyn VirtioDevice) -> u32 {
-        dev.get_features() | VIRTIO_F_RING_INDIRECT_DESC as u32
+    /// Set the MSI-X vector for the selected queue
+    fn set_queue_msix_vec(
+        &self,
+        pci_state: &pci::DeviceState,
+        dev: &dyn VirtioDevice,
+        val: u16,
+    ) {
+        let hdl = pci_state.msix_hdl().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let sel = state.queue_sel as usize;
+        if let Some(queue) = self.queues.get(state.queue_sel) {
+            if state.intr_mode != IntrMode::Msi {
+                // Store the vector information for later
+                state.msix_queue_vec[sel] = val;
+            } else {
+                state = self
+                    .state_cv
+                    .wait_while(state, |s| s.intr_mode_updating)
+                    .unwrap();
+                state.intr_mode_updating = true;
+                state.msix_queue_vec[sel] = val;
+
+                // State lock cannot be held while updating queue
+                // interrupt handlers due to deadlock possibility.
+                drop(state);
+                queue.set_intr(MsiIntr::new(hdl, val));
+                state = self.state.lock().unwrap();
+
+                // With the MSI configuration updated for the virtqueue,
+                // notify the device of the change
+                dev.queue_change(queue, VqChange::IntrCfg);
+
+                state.intr_mode_updating = false;
+                self.state_cv.notify_all();
+            }
+        }
+    }
+
+    fn features_supported(&self, dev: &dyn VirtioDevice) -> u64 {
+        let mut feat =
+            (dev.get_features() | VIRTIO_F_RING_INDIRECT_DESC as u32) as u64;
+        if self.transport == Transport::Transitional {
+            feat |= VIRTIO_F_VERSION_1 as u64;
+        }
+        feat
     }
     fn set_status(&self, dev: &dyn VirtioDevice, status: u8) {
         let mut state = self.state.lock().unwrap();
@@ -402,6 +713,11 @@ impl PciVirtioState {
         ));
         if let Some(vq) = self.queues.get(queue) {
             vq.live.store(true, Ordering::Release);
+            if dev.notify_suppressible() {
+                if let Some(mem) = vq.acc_mem.access() {
+                    vq.suppress_notify(&mem);
+                }
+            }
             dev.queue_notify(vq);
         }
     }
@@ -488,9 +804,39 @@ impl PciVirtioState {
         self.state_cv.notify_all();
     }
 
+    /// The device-specific (lower 32) feature bits negotiated by the driver
     pub fn negotiated_features(&self) -> u32 {
         let state = self.state.lock().unwrap();
-        state.nego_feat
+        state.nego_feat as u32
+    }
+
+    /// Whether the driver has negotiated use of the modern interface
+    pub fn is_modern(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.nego_feat & VIRTIO_F_VERSION_1 as u64 != 0
+    }
+
+    /// Notify the guest that the device-specific configuration has changed.
+    ///
+    /// When operating with MSI-X, the configured config-change vector is
+    /// fired.  Otherwise, the config bit is set in the ISR.
+    pub fn notify_config_change(&self, pci_state: &pci::DeviceState) {
+        let state = self.state.lock().unwrap();
+        match state.intr_mode {
+            IntrMode::Msi => {
+                let vec = state.msix_cfg_vec;
+                drop(state);
+                if let Some(hdl) = pci_state.msix_hdl() {
+                    if vec < hdl.count() {
+                        hdl.fire(vec);
+                    }
+                }
+            }
+            _ => {
+                drop(state);
+                self.isr_state.raise_cfg();
+            }
+        }
     }
 }
 impl MigrateMulti for PciVirtioState {
@@ -505,7 +851,10 @@ impl MigrateMulti for PciVirtioState {
         let device = migrate::DeviceStateV1 {
             status: state.status.bits(),
             queue_sel: state.queue_sel,
-            nego_feat: state.nego_feat,
+            nego_feat: state.nego_feat as u32,
+            nego_feat_hi: (state.nego_feat >> 32) as u32,
+            dev_feat_sel: state.dev_feat_sel,
+            drv_feat_sel: state.drv_feat_sel,
             msix_cfg_vec: state.msix_cfg_vec,
             msix_queue_vec: state.msix_queue_vec.clone(),
             isr_queue,
@@ -534,7 +883,10 @@ impl MigrateMulti for PciVirtioState {
             ))
         })?;
         state.queue_sel = dev.queue_sel;
-        state.nego_feat = dev.nego_feat;
+        state.nego_feat =
+            ((dev.nego_feat_hi as u64) << 32) | dev.nego_feat as u64;
+        state.dev_feat_sel = dev.dev_feat_sel;
+        state.drv_feat_sel = dev.drv_feat_sel;
         state.msix_cfg_vec = dev.msix_cfg_vec;
         state.msix_queue_vec = dev.msix_queue_vec;
         self.isr_state.write(dev.isr_queue, dev.isr_cfg);
@@ -610,6 +962,12 @@ impl IsrState {
             inner.intr_queue = true;
         });
     }
+    /// Raise config-change ISR condition
+    fn raise_cfg(&self) {
+        self.sync_pin(|inner| {
+            inner.intr_cfg = true;
+        });
+    }
     /// Read ISR value, then clear it.
     fn read_clear(&self) -> u8 {
         let (mut queue, mut cfg) = (false, false);
@@ -713,23 +1071,22 @@ impl VirtioIntr for IsrIntr {
 }
 
 struct MsiIntr {
-    hdl: pci::MsixHdl,
-    index: u16,
+    irqfd: Option<pci::IrqFd>,
 }
 impl MsiIntr {
     fn new(hdl: pci::MsixHdl, index: u16) -> Box<Self> {
-        Box::new(Self { hdl, index })
+        Box::new(Self { irqfd: hdl.irqfd(index) })
     }
 }
 impl VirtioIntr for MsiIntr {
     fn notify(&self) {
-        if self.index < self.hdl.count() {
-            self.hdl.fire(self.index);
+        if let Some(irqfd) = self.irqfd.as_ref() {
+            irqfd.fire();
         }
     }
     fn read(&self) -> VqIntr {
-        if self.index < self.hdl.count() {
-            let data = self.hdl.read(self.index);
+        if let Some(irqfd) = self.irqfd.as_ref() {
+            let data = irqfd.read();
             VqIntr::Msi(data.addr, data.data, data.masked)
         } else {
             VqIntr::Pin
@@ -745,6 +1102,9 @@ enum VirtioTop {
 
 const LEGACY_REG_SZ: usize = 0x18;
 const LEGACY_REG_SZ_NO_MSIX: usize = 0x14;
+/// Offset of the QueueNotify register, which is at the same location (in BAR0)
+/// regardless of whether MSI-X is enabled.
+const LEGACY_REG_OFF_QUEUE_NOTIFY: u16 = 0x10;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum LegacyReg {
@@ -777,6 +1137,129 @@ lazy_static! {
     };
 }
 
+/// BAR holding the regions of the modern interface, each of which is placed
+/// in its own page-sized slot.
+const MODERN_BAR: pci::BarN = pci::BarN::BAR2;
+const MODERN_SLOT_SZ: usize = 0x1000;
+const MODERN_BAR_SZ: usize = 4 * MODERN_SLOT_SZ;
+const MODERN_OFF_COMMON: usize = 0x0000;
+const MODERN_OFF_ISR: usize = 0x1000;
+const MODERN_OFF_DEVICE: usize = 0x2000;
+const MODERN_OFF_NOTIFY: usize = 0x3000;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ModernTop {
+    CommonConfig,
+    Isr,
+    DeviceConfig,
+    Notify,
+    Reserved,
+}
+
+fn modern_map(cfg_sz: usize) -> RegMap<ModernTop> {
+    let regions = [
+        (ModernTop::CommonConfig, MODERN_OFF_COMMON, COMMON_REG_SZ),
+        (ModernTop::Isr, MODERN_OFF_ISR, 1),
+        (ModernTop::DeviceConfig, MODERN_OFF_DEVICE, cfg_sz),
+        (ModernTop::Notify, MODERN_OFF_NOTIFY, 2),
+    ];
+    let mut map = RegMap::new(MODERN_BAR_SZ);
+    for (id, off, len) in regions {
+        // The configuration regions handle accesses of any size themselves,
+        // while the ISR and notification registers are accessed whole.
+        let flags = match id {
+            ModernTop::CommonConfig | ModernTop::DeviceConfig => {
+                Flags::PASSTHRU
+            }
+            _ => Flags::DEFAULT,
+        };
+        if len != 0 {
+            map.define_with_flags(off, len, id, flags);
+        }
+        map.define_with_flags(
+            off + len,
+            MODERN_SLOT_SZ - len,
+            ModernTop::Reserved,
+            Flags::PASSTHRU,
+        );
+    }
+    map
+}
+
+/// Body of a `virtio_pci_cap` (following its ID and next pointer registers),
+/// describing the region of `len` bytes at `off` in the modern BAR
+fn modern_cap(cfg_type: u8, off: usize, len: usize) -> Vec<u8> {
+    // cap_len, cfg_type, bar, id, padding[2]
+    let mut data = vec![16, cfg_type, MODERN_BAR as u8, 0, 0, 0];
+    data.extend_from_slice(&(off as u32).to_le_bytes());
+    data.extend_from_slice(&(len as u32).to_le_bytes());
+    data
+}
+
+/// Body of the `virtio_pci_notify_cap`.  With a `notify_off_multiplier` of 0,
+/// all queues share a single notification register, to which the driver
+/// writes the index of the queue being notified.
+fn modern_notify_cap() -> Vec<u8> {
+    let mut data = modern_cap(VIRTIO_PCI_CAP_NOTIFY_CFG, MODERN_OFF_NOTIFY, 2);
+    data[0] = 20;
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data
+}
+
+/// The 32-bit half of `feat` chosen by a feature select register
+fn feat_half(feat: u64, sel: u32) -> u32 {
+    match sel {
+        0 => feat as u32,
+        1 => (feat >> 32) as u32,
+        _ => 0,
+    }
+}
+
+const COMMON_REG_SZ: usize = 0x38;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum CommonReg {
+    DeviceFeatureSelect,
+    DeviceFeature,
+    DriverFeatureSelect,
+    DriverFeature,
+    MsixVectorConfig,
+    NumQueues,
+    DeviceStatus,
+    ConfigGeneration,
+    QueueSelect,
+    QueueSize,
+    QueueMsixVector,
+    QueueEnable,
+    QueueNotifyOff,
+    QueueDesc,
+    QueueDriver,
+    QueueDevice,
+}
+lazy_static! {
+    static ref COMMON_REGS: RegMap<CommonReg> = {
+        let layout = [
+            (CommonReg::DeviceFeatureSelect, 4),
+            (CommonReg::DeviceFeature, 4),
+            (CommonReg::DriverFeatureSelect, 4),
+            (CommonReg::DriverFeature, 4),
+            (CommonReg::MsixVectorConfig, 2),
+            (CommonReg::NumQueues, 2),
+            (CommonReg::DeviceStatus, 1),
+            (CommonReg::ConfigGeneration, 1),
+            (CommonReg::QueueSelect, 2),
+            (CommonReg::QueueSize, 2),
+            (CommonReg::QueueMsixVector, 2),
+            (CommonReg::QueueEnable, 2),
+            (CommonReg::QueueNotifyOff, 2),
+            (CommonReg::QueueDesc, 8),
+            (CommonReg::QueueDriver, 8),
+            (CommonReg::QueueDevice, 8),
+        ];
+        RegMap::create_packed(COMMON_REG_SZ, &layout, None)
+    };
+}
+
 pub mod migrate {
     use crate::hw::virtio::queue;
     use crate::migrate::*;
@@ -787,6 +1270,14 @@ pub mod migrate {
         pub status: u8,
         pub queue_sel: u16,
         pub nego_feat: u32,
+        /// Upper 32 bits of the negotiated features, only set when the modern
+        /// interface is in use
+        #[serde(default)]
+        pub nego_feat_hi: u32,
+        #[serde(default)]
+        pub dev_feat_sel: u32,
+        #[serde(default)]
+        pub drv_feat_sel: u32,
         pub msix_cfg_vec: u16,
         pub msix_queue_vec: Vec<u16>,
         pub isr_queue: bool,
@@ -804,3 +1295,36 @@ pub mod migrate {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modern_caps() {
+        let cap =
+            modern_cap(VIRTIO_PCI_CAP_DEVICE_CFG, MODERN_OFF_DEVICE, 0x3c);
+        // Including the ID and next pointer, the capability is 16 bytes long
+        assert_eq!(cap.len() + 2, 16);
+        assert_eq!(cap[0], 16);
+        assert_eq!(cap[1], VIRTIO_PCI_CAP_DEVICE_CFG);
+        assert_eq!(cap[2], 2);
+        assert_eq!(cap[6..10], 0x2000u32.to_le_bytes());
+        assert_eq!(cap[10..14], 0x3cu32.to_le_bytes());
+
+        let cap = modern_notify_cap();
+        assert_eq!(cap.len() + 2, 20);
+        assert_eq!(cap[0], 20);
+        assert_eq!(cap[1], VIRTIO_PCI_CAP_NOTIFY_CFG);
+        assert_eq!(cap[14..18], [0; 4]);
+    }
+
+    #[test]
+    fn feature_halves() {
+        let feat =
+            VIRTIO_F_VERSION_1 as u64 | VIRTIO_F_RING_INDIRECT_DESC as u64;
+        assert_eq!(feat_half(feat, 0), VIRTIO_F_RING_INDIRECT_DESC as u32);
+        assert_eq!(feat_half(feat, 1), 1);
+        assert_eq!(feat_half(feat, 2), 0);
+    }
+}
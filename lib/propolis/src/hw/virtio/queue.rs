@@ -7,7 +7,7 @@ use std::num::{NonZeroU16, Wrapping};
 use std::ops::Index;
 use std::slice::SliceIndex;
 use std::sync::atomic::{fence, AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use super::bits::*;
 use super::probes;
@@ -15,6 +15,7 @@ use super::{VirtioIntr, VqIntr};
 use crate::accessors::MemAccessor;
 use crate::common::*;
 use crate::migrate::MigrateStateError;
+use crate::util::lockorder::{Mutex, Rank};
 use crate::vmm::MemCtx;
 
 #[repr(C)]
@@ -107,6 +108,11 @@ pub struct VqUsed {
     gpa_ring: GuestAddr,
     used_idx: Wrapping<u16>,
     interrupt: Option<Box<dyn VirtioIntr>>,
+
+    /// Flags of the avail ring, through which the driver suppresses interrupts
+    gpa_avail_flags: GuestAddr,
+    /// Has the driver been asked not to notify us of new avail entries?
+    notify_suppressed: bool,
 }
 impl VqUsed {
     fn write_used(&mut self, id: u16, len: u32, rsize: u16, mem: &MemCtx) {
@@ -125,21 +131,34 @@ impl VqUsed {
         mem.write(self.gpa_idx, &self.used_idx.0);
     }
     fn intr_supressed(&self, mem: &MemCtx) -> bool {
-        let flags: u16 = mem.read(self.gpa_flags).unwrap();
+        let flags: u16 = mem.read(self.gpa_avail_flags).unwrap();
         flags & VRING_AVAIL_F_NO_INTERRUPT != 0
     }
+    fn set_notify_suppressed(&mut self, suppressed: bool, mem: &MemCtx) {
+        // The used ring flags are otherwise unused, so NO_NOTIFY is written
+        // outright rather than merged with their current contents.
+        let flags = match suppressed {
+            true => VRING_USED_F_NO_NOTIFY,
+            false => 0,
+        };
+        mem.write(self.gpa_flags, &flags);
+        self.notify_suppressed = suppressed;
+    }
     fn reset(&mut self) {
         self.valid = false;
         self.gpa_flags = GuestAddr(0);
         self.gpa_idx = GuestAddr(0);
         self.gpa_ring = GuestAddr(0);
+        self.gpa_avail_flags = GuestAddr(0);
         self.used_idx = Wrapping(0);
+        self.notify_suppressed = false;
     }
-    fn map_split(&mut self, gpa: u64) {
+    fn map_split(&mut self, gpa: u64, avail_gpa: u64) {
         // 16-bit flags, followed by 16-bit idx, followed by used desc ring
         self.gpa_flags = GuestAddr(gpa);
         self.gpa_idx = GuestAddr(gpa + 2);
         self.gpa_ring = GuestAddr(gpa + 4);
+        self.gpa_avail_flags = GuestAddr(avail_gpa);
     }
 }
 
@@ -165,22 +184,30 @@ impl VirtQueue {
             id,
             size,
             live: AtomicBool::new(false),
-            avail: Mutex::new(VqAvail {
-                valid: false,
-                gpa_flags: GuestAddr(0),
-                gpa_idx: GuestAddr(0),
-                gpa_ring: GuestAddr(0),
-                cur_avail_idx: Wrapping(0),
-                gpa_desc: GuestAddr(0),
-            }),
-            used: Mutex::new(VqUsed {
-                valid: false,
-                gpa_flags: GuestAddr(0),
-                gpa_idx: GuestAddr(0),
-                gpa_ring: GuestAddr(0),
-                used_idx: Wrapping(0),
-                interrupt: None,
-            }),
+            avail: Mutex::new(
+                Rank::VirtqAvail,
+                VqAvail {
+                    valid: false,
+                    gpa_flags: GuestAddr(0),
+                    gpa_idx: GuestAddr(0),
+                    gpa_ring: GuestAddr(0),
+                    cur_avail_idx: Wrapping(0),
+                    gpa_desc: GuestAddr(0),
+                },
+            ),
+            used: Mutex::new(
+                Rank::VirtqUsed,
+                VqUsed {
+                    valid: false,
+                    gpa_flags: GuestAddr(0),
+                    gpa_idx: GuestAddr(0),
+                    gpa_ring: GuestAddr(0),
+                    used_idx: Wrapping(0),
+                    interrupt: None,
+                    gpa_avail_flags: GuestAddr(0),
+                    notify_suppressed: false,
+                },
+            ),
             acc_mem: MemAccessor::new_orphan(),
         }
     }
@@ -215,7 +242,7 @@ impl VirtQueue {
         let mut avail = self.avail.lock().unwrap();
         let mut used = self.used.lock().unwrap();
         avail.map_split(desc_addr, avail_addr);
-        used.map_split(used_addr);
+        used.map_split(used_addr, avail_addr);
         avail.valid = true;
         used.valid = true;
 
@@ -241,7 +268,7 @@ impl VirtQueue {
         let mut used = self.used.lock().unwrap();
 
         avail.map_split(info.mapping.desc_addr, info.mapping.avail_addr);
-        used.map_split(info.mapping.used_addr);
+        used.map_split(info.mapping.used_addr, info.mapping.avail_addr);
         avail.valid = info.mapping.valid;
         used.valid = info.mapping.valid;
         avail.cur_avail_idx = Wrapping(info.avail_idx);
@@ -254,7 +281,18 @@ impl VirtQueue {
     ) -> Option<(u16, u32)> {
         assert!(chain.idx.is_none());
         let mut avail = self.avail.lock().unwrap();
-        let req = avail.read_next_avail(self.size, mem)?;
+        let req = match avail.read_next_avail(self.size, mem) {
+            Some(req) => req,
+            None => {
+                // Before reporting the ring as empty, ask the driver to
+                // notify us again, and look for anything it added while it
+                // was told not to.
+                if !self.enable_notify(mem) {
+                    return None;
+                }
+                avail.read_next_avail(self.size, mem)?
+            }
+        };
 
         let mut desc = avail.read_ring_descr(req.desc_idx, self.size, mem)?;
         let mut flags = DescFlag::from_bits_truncate(desc.flags);
@@ -331,6 +369,35 @@ impl VirtQueue {
         }
         Some((req.avail_idx, len))
     }
+
+    /// Ask the driver not to notify the device of new avail entries.
+    ///
+    /// This is a hint, set when a notification is received by a device which
+    /// drains the queue until [`VirtQueue::pop_avail()`] finds it empty.  The
+    /// driver can then post further requests without a doorbell write (and
+    /// the VM exit it incurs) while those already posted are processed.
+    /// Notifications are enabled again once the ring is found empty.
+    pub(super) fn suppress_notify(&self, mem: &MemCtx) {
+        let mut used = self.used.lock().unwrap();
+        if used.valid && !used.notify_suppressed {
+            used.set_notify_suppressed(true, mem);
+        }
+    }
+
+    /// Allow the driver to notify the device of new avail entries again,
+    /// returning `true` if notifications had been suppressed.
+    fn enable_notify(&self, mem: &MemCtx) -> bool {
+        let mut used = self.used.lock().unwrap();
+        if !used.valid || !used.notify_suppressed {
+            return false;
+        }
+        used.set_notify_suppressed(false, mem);
+        // The flag must be visible to the driver before the avail index is
+        // read again, lest an entry posted in between go unnoticed.
+        fence(Ordering::SeqCst);
+        true
+    }
+
     pub fn push_used(&self, chain: &mut Chain, mem: &MemCtx) {
         assert!(chain.idx.is_some());
         let mut used = self.used.lock().unwrap();
@@ -413,9 +480,12 @@ impl VirtQueue {
         avail.valid = state.mapping_valid;
         avail.cur_avail_idx = Wrapping(state.avail_cur_idx);
 
-        used.map_split(state.used_gpa);
+        used.map_split(state.used_gpa, state.avail_gpa);
         used.valid = state.mapping_valid;
         used.used_idx = Wrapping(state.used_idx);
+        // The source may have left NO_NOTIFY set in the used ring.  Assume it
+        // did, so the first pop to find the ring empty clears it again.
+        used.notify_suppressed = state.mapping_valid;
         self.live.store(state.live, Ordering::Release);
 
         Ok(())
@@ -752,7 +822,28 @@ pub mod migrate {
     }
 }
 
-#[cfg(feature = "falcon")]
+/// Reads the readable remainder of `chain` into `buf`, as far as it will fit,
+/// returning the number of bytes read.
+pub(crate) fn read_buf(
+    buf: &mut [u8],
+    chain: &mut Chain,
+    mem: &MemCtx,
+) -> usize {
+    let mut done = 0;
+    chain.for_remaining_type(true, |addr, len| {
+        let remain = &mut buf[done..];
+        if let Some(copied) = mem.read_into(addr, remain, len) {
+            let need_more = copied != remain.len();
+
+            done += copied;
+            (copied, need_more)
+        } else {
+            // Copy failed, so do not attempt anything else
+            (0, false)
+        }
+    })
+}
+
 pub(crate) fn write_buf(buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
     // more copy pasta from Chain::write b/c like Chain:read a
     // statically sized type is expected.
@@ -770,3 +861,48 @@ pub(crate) fn write_buf(buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
         }
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instance::Instance;
+
+    // Within the RAM of the test machine, above its ROM
+    const RING_GPA: u64 = 0x100000;
+
+    #[test]
+    fn notify_suppressed_while_draining() {
+        let instance = Instance::new_test().unwrap();
+        let acc_mem = instance.lock().machine().acc_mem.child(None);
+        let mem = acc_mem.access().unwrap();
+
+        let vq = VirtQueue::new(0, 16);
+        assert!(vq.map_legacy(RING_GPA));
+        let mapping = vq.get_state().mapping;
+        let used_flags = GuestAddr(mapping.used_addr);
+
+        // Post a single request, held in the first descriptor
+        let desc = VqdDesc { addr: 0x120000, len: 512, flags: 0, next: 0 };
+        mem.write(GuestAddr(RING_GPA), &desc);
+        mem.write(GuestAddr(mapping.avail_addr + 4), &0u16);
+        mem.write(GuestAddr(mapping.avail_addr + 2), &1u16);
+
+        vq.suppress_notify(&mem);
+        assert_eq!(mem.read::<u16>(used_flags), Some(VRING_USED_F_NO_NOTIFY));
+
+        // Notifications stay suppressed while requests are being popped
+        let mut chain = Chain::with_capacity(1);
+        assert_eq!(vq.pop_avail(&mut chain, &mem), Some((0, 512)));
+        vq.push_used(&mut chain, &mem);
+        assert_eq!(mem.read::<u16>(used_flags), Some(VRING_USED_F_NO_NOTIFY));
+
+        // ... and are enabled again once the ring is found empty
+        assert_eq!(vq.pop_avail(&mut chain, &mem), None);
+        assert_eq!(mem.read::<u16>(used_flags), Some(0));
+
+        // Suppression does not leak into interrupt delivery, which is
+        // governed by the avail ring flags
+        vq.suppress_notify(&mem);
+        assert!(!vq.used.lock().unwrap().intr_supressed(&mem));
+    }
+}
@@ -0,0 +1,335 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! virtio-rng: an entropy device
+//!
+//! Guests fill the buffers posted to the device's single request queue with
+//! random bytes read from the host, so that they can seed their own random
+//! number generators early in boot, rather than stalling for lack of entropy.
+//!
+//! The host's non-blocking source (`/dev/urandom`) is used, so a request never
+//! waits on the host's entropy estimate.  The rate at which bytes are handed
+//! out is limited, however, so that a guest cannot consume an unbounded share
+//! of host CPU time generating them.  Requests are serviced by a worker thread,
+//! which leaves them on the queue while the limit is exhausted.
+
+use std::fs::File;
+use std::io::Read;
+use std::num::{NonZeroU16, NonZeroU32};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+use crate::hostres;
+use crate::hw::pci;
+use crate::migrate::*;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState, Transport};
+use super::queue::{write_buf, Chain, VirtQueue, VirtQueues};
+use super::VirtioDevice;
+
+/// Path of the host entropy source
+const ENTROPY_SOURCE: &str = "/dev/urandom";
+
+/// Rate at which entropy is offered, unless otherwise specified
+pub const DEFAULT_BYTES_PER_SEC: u32 = 1 << 20;
+
+/// Largest number of bytes to supply in response to a single request
+const MAX_REQ_BYTES: usize = 4096;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Limit on the rate at which bytes are supplied, permitting bursts of up to
+/// one second's worth
+struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    last: Instant,
+}
+impl TokenBucket {
+    fn new(rate: NonZeroU32, now: Instant) -> Self {
+        let rate = rate.get() as u64;
+        Self { rate, tokens: rate, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last);
+        let earned = (elapsed.as_nanos() * self.rate as u128
+            / NANOS_PER_SEC as u128) as u64;
+        if self.tokens + earned >= self.rate {
+            self.tokens = self.rate;
+            self.last = now;
+        } else if earned > 0 {
+            self.tokens += earned;
+            // Advance only by the time for which tokens were granted, so that
+            // fractions of a token are not lost to rounding.
+            self.last += Duration::from_nanos(
+                (earned as u128 * NANOS_PER_SEC as u128 / self.rate as u128)
+                    as u64,
+            );
+        }
+    }
+
+    /// Take up to `want` tokens, returning how many were taken
+    fn take(&mut self, want: usize, now: Instant) -> usize {
+        self.refill(now);
+        let taken = u64::min(want as u64, self.tokens);
+        self.tokens -= taken;
+        taken as usize
+    }
+
+    /// Time until at least one token is available
+    fn until_available(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens > 0 {
+            return Duration::ZERO;
+        }
+        let per_token = Duration::from_nanos(NANOS_PER_SEC / self.rate);
+        (self.last + per_token.max(Duration::from_nanos(1)))
+            .saturating_duration_since(now)
+    }
+}
+
+struct WorkerState {
+    /// The driver has notified the queue since the worker last drained it
+    notified: bool,
+    paused: bool,
+    halted: bool,
+    bucket: TokenBucket,
+}
+
+struct Worker {
+    state: Mutex<WorkerState>,
+    cv: Condvar,
+}
+impl Worker {
+    fn run(&self, vq: &VirtQueue, mut source: File) {
+        let mut buf = vec![0u8; MAX_REQ_BYTES];
+        let mut chain = Chain::with_capacity(1);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.halted {
+                return;
+            }
+            if state.paused || !state.notified {
+                state = self.cv.wait(state).unwrap();
+                continue;
+            }
+
+            let wait = state.bucket.until_available(Instant::now());
+            if !wait.is_zero() {
+                // Leave requests on the queue until entropy may be offered
+                state = self.cv.wait_timeout(state, wait).unwrap().0;
+                continue;
+            }
+
+            let Some(mem) = vq.acc_mem.access() else {
+                state.notified = false;
+                continue;
+            };
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                state.notified = false;
+                continue;
+            }
+            let want = usize::min(chain.remain_write_bytes(), MAX_REQ_BYTES);
+            let len = state.bucket.take(want, Instant::now());
+            // A short read leaves the remainder of the buffer unfilled, which
+            // the guest observes as a short response.
+            if let Ok(n) = source.read(&mut buf[..len]) {
+                write_buf(&buf[..n], &mut chain, &mem);
+            }
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+}
+
+pub struct PciVirtioRng {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    worker: Arc<Worker>,
+    /// The entropy source, until it is handed to the worker thread
+    source: Mutex<Option<(File, hostres::Held)>>,
+    res_owner: Arc<hostres::Owner>,
+}
+impl PciVirtioRng {
+    /// Create a device offering up to `bytes_per_sec` of entropy to the guest.
+    pub fn new(
+        queue_size: u16,
+        bytes_per_sec: NonZeroU32,
+    ) -> std::io::Result<Arc<Self>> {
+        let res_owner = hostres::Owner::new("pci-virtio-rng");
+        let source = File::open(ENTROPY_SOURCE)?;
+        let source_held = res_owner.hold(hostres::Kind::Fd, 1);
+
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(1).unwrap(),
+        );
+        let msix_count = Some(2);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_RNG,
+            VIRTIO_SUB_DEV_RNG,
+            pci::bits::CLASS_SYSTEM,
+            0,
+            Transport::Transitional,
+        );
+        let worker = Arc::new(Worker {
+            state: Mutex::new(WorkerState {
+                notified: false,
+                paused: false,
+                halted: false,
+                bucket: TokenBucket::new(bytes_per_sec, Instant::now()),
+            }),
+            cv: Condvar::new(),
+        });
+        Ok(Arc::new(Self {
+            virtio_state,
+            pci_state,
+            worker,
+            source: Mutex::new(Some((source, source_held))),
+            res_owner,
+        }))
+    }
+
+    fn update_worker(&self, f: impl FnOnce(&mut WorkerState)) {
+        let mut state = self.worker.state.lock().unwrap();
+        f(&mut state);
+        self.worker.cv.notify_all();
+    }
+}
+
+impl VirtioDevice for PciVirtioRng {
+    fn cfg_rw(&self, rwo: RWOp) {
+        // There is no device-specific configuration
+        if let RWOp::Read(ro) = rwo {
+            ro.fill(0);
+        }
+    }
+    fn get_features(&self) -> u32 {
+        0
+    }
+    fn set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, _vq: &Arc<VirtQueue>) {
+        self.update_worker(|state| state.notified = true);
+    }
+}
+impl PciVirtio for PciVirtioRng {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+impl Entity for PciVirtioRng {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-rng"
+    }
+    fn start(&self) -> anyhow::Result<()> {
+        let (source, source_held) =
+            self.source.lock().unwrap().take().ok_or_else(|| {
+                anyhow::anyhow!("entropy worker already started")
+            })?;
+        let worker = self.worker.clone();
+        let vq = self.virtio_state.queues.get(0).unwrap().clone();
+        let held = [source_held, self.res_owner.hold(hostres::Kind::Thread, 1)];
+        let _join = std::thread::Builder::new()
+            .name("virtio-rng worker".to_string())
+            .spawn(move || {
+                let _held = held;
+                worker.run(&vq, source);
+            })?;
+        Ok(())
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+        self.update_worker(|state| state.notified = false);
+    }
+    fn pause(&self) {
+        // Requests are serviced with the worker state locked, so none is in
+        // progress once the pause is recorded.
+        self.update_worker(|state| state.paused = true);
+    }
+    fn resume(&self) {
+        // Requests may have been posted while paused (or prior to an import),
+        // so check the queue regardless of notification.
+        self.update_worker(|state| {
+            state.paused = false;
+            state.notified = true;
+        });
+    }
+    fn halt(&self) {
+        self.update_worker(|state| state.halted = true);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciVirtioRng {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)
+    }
+}
+impl Drop for PciVirtioRng {
+    fn drop(&mut self) {
+        self.update_worker(|state| state.halted = true);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_limits_rate() {
+        let start = Instant::now();
+        let rate = NonZeroU32::new(1000).unwrap();
+        let mut bucket = TokenBucket::new(rate, start);
+
+        // A full second's worth is available up front
+        assert_eq!(bucket.take(600, start), 600);
+        assert_eq!(bucket.take(600, start), 400);
+        assert_eq!(bucket.take(1, start), 0);
+        assert_eq!(bucket.until_available(start), Duration::from_millis(1));
+
+        // Tokens accrue at the configured rate, to at most one second's worth
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.until_available(later), Duration::ZERO);
+        assert_eq!(bucket.take(1000, later), 250);
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(5000, much_later), 1000);
+    }
+
+    #[test]
+    fn bucket_keeps_fractions() {
+        let start = Instant::now();
+        let rate = NonZeroU32::new(3).unwrap();
+        let mut bucket = TokenBucket::new(rate, start);
+        assert_eq!(bucket.take(3, start), 3);
+
+        // Refilling in steps shorter than a token's worth does not lose time
+        let mut now = start;
+        for _ in 0..10 {
+            now += Duration::from_millis(100);
+            bucket.refill(now);
+        }
+        assert_eq!(bucket.take(3, now), 3);
+    }
+}
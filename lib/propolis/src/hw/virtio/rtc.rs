@@ -0,0 +1,301 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! virtio-rtc: a precision clock device
+//!
+//! The device exposes a single UTC clock, read from the machine's
+//! [`Clock`] on each request.  Unless that clock has been warped, it is the
+//! host's realtime clock.  Guests (such as Linux, which presents the clock as a PTP
+//! hardware clock) can discipline their own clocks against it directly rather
+//! than each running NTP against an external source.
+//!
+//! Cross-timestamping against the guest's TSC is not offered, so the guest
+//! estimates the read latency itself, as it would for any other PTP clock
+//! without that capability.
+
+use std::num::NonZeroU16;
+use std::sync::Arc;
+
+use crate::clock::Clock;
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState, Transport};
+use super::queue::{Chain, VirtQueue, VirtQueues};
+use super::VirtioDevice;
+use bits::*;
+
+/// Number of clocks offered by the device
+const NUM_CLOCKS: u16 = 1;
+/// ID of the UTC clock
+const CLOCK_ID_UTC: u16 = 0;
+
+pub struct PciVirtioRtc {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    clock: Arc<Clock>,
+}
+impl PciVirtioRtc {
+    pub fn new(queue_size: u16, clock: Arc<Clock>) -> Arc<Self> {
+        // Only the request queue is present, as alarms are not supported
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(1).unwrap(),
+        );
+        let msix_count = Some(2);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_RTC,
+            VIRTIO_SUB_DEV_RTC,
+            pci::bits::CLASS_SYSTEM,
+            0,
+            Transport::Transitional,
+        );
+        Arc::new(Self { virtio_state, pci_state, clock })
+    }
+
+    fn process_request(&self, chain: &mut Chain, mem: &MemCtx) {
+        let mut head = ReqHead::default();
+        let (status, body) = if chain.read(&mut head, mem) {
+            let mut body = [0u8; 8];
+            let body = chain.read(&mut body, mem).then_some(body);
+            handle_request(u16::from_le(head.msg_type), body, || {
+                self.clock.unix_time().as_nanos() as u64
+            })
+        } else {
+            (VIRTIO_RTC_S_EINVAL, None)
+        };
+
+        let resp = RespHead { status, reserved: [0; 7] };
+        chain.write(&resp, mem);
+        if let Some(body) = body {
+            chain.write(&body, mem);
+        }
+    }
+}
+
+/// Handle a request of type `msg_type`, with `body` holding the 8 bytes which
+/// follow the request header (if present).
+///
+/// Returns the status of the request and the body of the response (if any) to
+/// follow the response header.
+fn handle_request(
+    msg_type: u16,
+    body: Option<[u8; 8]>,
+    now: impl FnOnce() -> u64,
+) -> (u8, Option<[u8; 8]>) {
+    let mut resp = [0u8; 8];
+
+    if msg_type == VIRTIO_RTC_REQ_CFG {
+        resp[..2].copy_from_slice(&NUM_CLOCKS.to_le_bytes());
+        return (VIRTIO_RTC_S_OK, Some(resp));
+    }
+
+    // All other requests address a clock
+    let Some(body) = body else {
+        return (VIRTIO_RTC_S_EINVAL, None);
+    };
+    let clock_id = u16::from_le_bytes([body[0], body[1]]);
+    let known_type = matches!(
+        msg_type,
+        VIRTIO_RTC_REQ_READ
+            | VIRTIO_RTC_REQ_READ_CROSS
+            | VIRTIO_RTC_REQ_CLOCK_CAP
+            | VIRTIO_RTC_REQ_CROSS_CAP
+    );
+    if !known_type {
+        return (VIRTIO_RTC_S_EOPNOTSUPP, None);
+    }
+    if clock_id != CLOCK_ID_UTC {
+        return (VIRTIO_RTC_S_ENODEV, None);
+    }
+
+    match msg_type {
+        VIRTIO_RTC_REQ_READ => {
+            resp.copy_from_slice(&now().to_le_bytes());
+        }
+        VIRTIO_RTC_REQ_CLOCK_CAP => {
+            resp[0] = VIRTIO_RTC_CLOCK_UTC;
+            resp[1] = VIRTIO_RTC_SMEAR_UNSPECIFIED;
+            // No flags: alarms are not supported
+        }
+        VIRTIO_RTC_REQ_CROSS_CAP => {
+            // No flags: cross-timestamping is not supported for any counter
+        }
+        _ => return (VIRTIO_RTC_S_EOPNOTSUPP, None),
+    }
+    (VIRTIO_RTC_S_OK, Some(resp))
+}
+
+impl VirtioDevice for PciVirtioRtc {
+    fn cfg_rw(&self, rwo: RWOp) {
+        // There is no device-specific configuration
+        if let RWOp::Read(ro) = rwo {
+            ro.fill(0);
+        }
+    }
+    fn get_features(&self) -> u32 {
+        0
+    }
+    fn set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(2);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            self.process_request(&mut chain, &mem);
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+}
+impl PciVirtio for PciVirtioRtc {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+impl Entity for PciVirtioRtc {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-rtc"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciVirtioRtc {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct ReqHead {
+    msg_type: u16,
+    reserved: [u8; 6],
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct RespHead {
+    status: u8,
+    reserved: [u8; 7],
+}
+
+mod bits {
+    #![allow(unused)]
+
+    pub const VIRTIO_RTC_REQ_READ: u16 = 0x0001;
+    pub const VIRTIO_RTC_REQ_READ_CROSS: u16 = 0x0002;
+    pub const VIRTIO_RTC_REQ_CFG: u16 = 0x1000;
+    pub const VIRTIO_RTC_REQ_CLOCK_CAP: u16 = 0x1001;
+    pub const VIRTIO_RTC_REQ_CROSS_CAP: u16 = 0x1002;
+
+    pub const VIRTIO_RTC_S_OK: u8 = 0;
+    pub const VIRTIO_RTC_S_EOPNOTSUPP: u8 = 2;
+    pub const VIRTIO_RTC_S_ENODEV: u8 = 3;
+    pub const VIRTIO_RTC_S_EINVAL: u8 = 4;
+
+    pub const VIRTIO_RTC_CLOCK_UTC: u8 = 0;
+    pub const VIRTIO_RTC_CLOCK_TAI: u8 = 1;
+    pub const VIRTIO_RTC_CLOCK_MONOTONIC: u8 = 2;
+
+    pub const VIRTIO_RTC_SMEAR_UNSPECIFIED: u8 = 0;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn clock_body(clock_id: u16) -> Option<[u8; 8]> {
+        let mut body = [0u8; 8];
+        body[..2].copy_from_slice(&clock_id.to_le_bytes());
+        Some(body)
+    }
+
+    #[test]
+    fn cfg() {
+        let (status, body) = handle_request(VIRTIO_RTC_REQ_CFG, None, || 0);
+        assert_eq!(status, VIRTIO_RTC_S_OK);
+        assert_eq!(body.unwrap()[..2], NUM_CLOCKS.to_le_bytes());
+    }
+
+    #[test]
+    fn read() {
+        let now = 1_700_000_000_123_456_789u64;
+        let (status, body) = handle_request(
+            VIRTIO_RTC_REQ_READ,
+            clock_body(CLOCK_ID_UTC),
+            || now,
+        );
+        assert_eq!(status, VIRTIO_RTC_S_OK);
+        assert_eq!(body, Some(now.to_le_bytes()));
+    }
+
+    #[test]
+    fn clock_cap() {
+        let (status, body) = handle_request(
+            VIRTIO_RTC_REQ_CLOCK_CAP,
+            clock_body(CLOCK_ID_UTC),
+            || 0,
+        );
+        assert_eq!(status, VIRTIO_RTC_S_OK);
+        assert_eq!(body.unwrap()[0], VIRTIO_RTC_CLOCK_UTC);
+    }
+
+    #[test]
+    fn unsupported() {
+        // No such clock
+        let (status, _) =
+            handle_request(VIRTIO_RTC_REQ_READ, clock_body(1), || 0);
+        assert_eq!(status, VIRTIO_RTC_S_ENODEV);
+
+        // Missing clock ID
+        let (status, _) = handle_request(VIRTIO_RTC_REQ_READ, None, || 0);
+        assert_eq!(status, VIRTIO_RTC_S_EINVAL);
+
+        // No cross-timestamping
+        let (status, body) = handle_request(
+            VIRTIO_RTC_REQ_CROSS_CAP,
+            clock_body(CLOCK_ID_UTC),
+            || 0,
+        );
+        assert_eq!(status, VIRTIO_RTC_S_OK);
+        assert_eq!(body, Some([0; 8]));
+        let (status, _) = handle_request(
+            VIRTIO_RTC_REQ_READ_CROSS,
+            clock_body(CLOCK_ID_UTC),
+            || 0,
+        );
+        assert_eq!(status, VIRTIO_RTC_S_EOPNOTSUPP);
+
+        // Alarms
+        let (status, _) =
+            handle_request(0x1003, clock_body(CLOCK_ID_UTC), || 0);
+        assert_eq!(status, VIRTIO_RTC_S_EOPNOTSUPP);
+    }
+}
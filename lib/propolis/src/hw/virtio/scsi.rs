@@ -0,0 +1,1317 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! virtio-scsi: a SCSI host adapter exposing many disks through one function
+//!
+//! The controller presents a single target, whose logical units are each
+//! backed by their own block backend.  Commands which transfer data to or from
+//! the medium are issued to the backend of the addressed LUN, while the rest of
+//! the (small) SBC command set required by guests is emulated here.
+//!
+//! Logical block provisioning (and with it UNMAP) is only reported for units
+//! whose backend supports discarding storage.  Units backed by anything else
+//! reject UNMAP as an unsupported command.
+
+use std::collections::VecDeque;
+use std::num::NonZeroU16;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::accessors::MemAccessor;
+use crate::block;
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState, Transport};
+use super::queue::{write_buf, Chain, VirtQueue, VirtQueues};
+use super::VirtioDevice;
+use bits::*;
+
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+
+/// Queue indices: the control and event queues precede the request queue(s)
+const CONTROL_QUEUE: u16 = 0;
+const REQUEST_QUEUE: u16 = 2;
+const NUM_QUEUES: u16 = 3;
+
+/// Maximum number of logical units addressable through the single target
+pub const MAX_LUNS: u16 = 256;
+
+/// Block descriptors accepted in a single UNMAP command
+const MAX_UNMAP_DESCRIPTORS: usize = 64;
+
+struct CompletionPayload {
+    /// VirtIO chain in which we indicate the result
+    chain: Chain,
+    /// Guest memory holding the response header, preceding any data-in
+    resp: Vec<GuestRegion>,
+    /// Bytes to be transferred by the request
+    len: usize,
+}
+
+/// A logical unit of the controller, attached to a block backend.
+pub struct ScsiLun {
+    id: u16,
+    ctrl: Weak<PciVirtioScsi>,
+    serial: Mutex<String>,
+
+    block_attach: block::device::Attachment,
+    block_tracking: block::device::Tracking<CompletionPayload>,
+
+    /// Requests received from the guest, yet to be fetched by the backend
+    pending: Mutex<VecDeque<(block::Request, CompletionPayload)>>,
+    drained: tokio::sync::Notify,
+}
+impl ScsiLun {
+    fn new(id: u16, ctrl: Weak<PciVirtioScsi>) -> Arc<Self> {
+        Arc::new_cyclic(|weak| Self {
+            id,
+            ctrl,
+            serial: Mutex::new(format!("LUN{id}")),
+            block_attach: block::device::Attachment::new(),
+            block_tracking: block::device::Tracking::new(
+                weak.clone() as Weak<dyn block::Device>
+            ),
+            pending: Mutex::new(VecDeque::new()),
+            drained: tokio::sync::Notify::new(),
+        })
+    }
+
+    /// Logical unit number
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Set the serial number reported by the unit in its VPD pages.
+    ///
+    /// # Panics
+    ///
+    /// If `serial` exceeds 20 bytes, or is not printable ASCII.
+    pub fn set_serial(&self, serial: &str) {
+        assert!(serial.len() <= 20);
+        assert!(serial.bytes().all(|c| c.is_ascii_graphic() || c == b' '));
+        *self.serial.lock().unwrap() = serial.to_string();
+    }
+
+    /// Set the deadline for I/O requests issued to the block backend.  See
+    /// [`block::device::Tracking::set_timeout()`].
+    pub fn set_request_timeout(&self, timeout: Option<std::time::Duration>) {
+        self.block_tracking.set_timeout(timeout);
+    }
+
+    /// Set (or clear) write-protection on the unit.
+    ///
+    /// While write-protected, commands which would modify the medium fail with
+    /// DATA PROTECT sense, and MODE SENSE reports the unit as write-protected.
+    pub fn set_write_protect(&self, write_protect: bool) {
+        self.block_attach.set_write_protect(write_protect);
+    }
+
+    fn enqueue(&self, req: block::Request, payload: CompletionPayload) {
+        self.pending.lock().unwrap().push_back((req, payload));
+        self.block_attach.notify();
+    }
+
+    /// Wait for all requests from the guest to be fetched and completed by the
+    /// backend.
+    async fn drain(self: Arc<Self>) {
+        loop {
+            let drained = self.drained.notified();
+            if self.pending.lock().unwrap().is_empty() {
+                break;
+            }
+            drained.await;
+        }
+        self.block_tracking.none_outstanding().await;
+    }
+}
+impl block::Device for ScsiLun {
+    fn attachment(&self) -> &block::device::Attachment {
+        &self.block_attach
+    }
+
+    fn next(&self) -> Option<block::Request> {
+        let mut pending = self.pending.lock().unwrap();
+        let (req, payload) = pending.pop_front()?;
+        if pending.is_empty() {
+            self.drained.notify_waiters();
+        }
+        drop(pending);
+        Some(self.block_tracking.track(req, payload))
+    }
+
+    fn complete(&self, res: block::Result, id: block::ReqId) {
+        let Some((op, payload)) = self.block_tracking.complete(id, res) else {
+            return;
+        };
+        if let Some(ctrl) = self.ctrl.upgrade() {
+            ctrl.complete_io(self.id, op, res, payload);
+        }
+    }
+
+    fn accessor_mem(&self) -> MemAccessor {
+        match self.ctrl.upgrade() {
+            Some(ctrl) => ctrl
+                .pci_state
+                .acc_mem
+                .child(Some(format!("scsi lun {} backend", self.id))),
+            None => MemAccessor::new_orphan(),
+        }
+    }
+
+    fn io_history(&self) -> Option<block::device::IoHistory> {
+        Some(self.block_tracking.io_history())
+    }
+
+    fn reports_not_ready(&self) -> bool {
+        true
+    }
+}
+
+pub struct PciVirtioScsi {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    luns: Vec<Arc<ScsiLun>>,
+    paused: AtomicBool,
+}
+impl PciVirtioScsi {
+    /// Create a controller with LUNs numbered `0..num_luns`
+    pub fn new(queue_size: u16, num_luns: u16) -> Arc<Self> {
+        assert!(num_luns > 0 && num_luns <= MAX_LUNS);
+
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(NUM_QUEUES).unwrap(),
+        );
+        // One MSI-X entry for config changes, and one for each queue
+        let msix_count = Some(1 + NUM_QUEUES);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_SCSI,
+            VIRTIO_SUB_DEV_SCSI,
+            pci::bits::CLASS_STORAGE,
+            VIRTIO_SCSI_CFG_SIZE,
+            Transport::Transitional,
+        );
+
+        Arc::new_cyclic(|weak| Self {
+            virtio_state,
+            pci_state,
+            luns: (0..num_luns)
+                .map(|id| ScsiLun::new(id, weak.clone()))
+                .collect(),
+            paused: AtomicBool::new(false),
+        })
+    }
+
+    /// Logical units of the controller, to which block backends are attached
+    pub fn luns(&self) -> &[Arc<ScsiLun>] {
+        &self.luns
+    }
+
+    fn scsi_cfg_read(&self, id: &ScsiReg, ro: &mut ReadOp) {
+        match id {
+            ScsiReg::NumQueues => ro.write_u32(1),
+            // XXX: Copy the static limit from virtio-block for now
+            ScsiReg::SegMax => ro.write_u32(128 - 2),
+            ScsiReg::MaxSectors => ro.write_u32(0xffff),
+            ScsiReg::CmdPerLun => ro.write_u32(128),
+            ScsiReg::EventInfoSize => ro.write_u32(16),
+            ScsiReg::SenseSize => ro.write_u32(SENSE_SIZE as u32),
+            ScsiReg::CdbSize => ro.write_u32(CDB_SIZE as u32),
+            ScsiReg::MaxChannel => ro.write_u16(0),
+            ScsiReg::MaxTarget => ro.write_u16(0),
+            ScsiReg::MaxLun => ro.write_u32(self.luns.len() as u32 - 1),
+        }
+    }
+
+    fn lun_info(&self, lun: &ScsiLun) -> LunInfo {
+        LunInfo {
+            info: lun.block_attach.info(),
+            serial: lun.serial.lock().unwrap().clone(),
+        }
+    }
+
+    fn process_requests(&self, vq: &VirtQueue) {
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        while !self.paused.load(Ordering::Acquire) {
+            let mut chain = Chain::with_capacity(4);
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+            self.process_cmd(vq, chain, &mem);
+        }
+    }
+
+    /// Process a command from the request queue, either completing it
+    /// immediately or passing it to the backend of the addressed LUN.
+    fn process_cmd(&self, vq: &VirtQueue, mut chain: Chain, mem: &MemCtx) {
+        let mut raw = [0u8; CMD_REQ_SIZE];
+        if !chain.read(&mut raw, mem) {
+            let resp = Response::Transport(VIRTIO_SCSI_S_FAILURE);
+            return finish_cmd(vq, chain, resp, &[], mem);
+        }
+        let (lun, cdb) = (&raw[..8], &raw[CMD_REQ_CDB_OFF..]);
+
+        let Some(lun_id) = decode_lun(lun) else {
+            let resp = Response::Transport(VIRTIO_SCSI_S_BAD_TARGET);
+            return finish_cmd(vq, chain, resp, &[], mem);
+        };
+        let lun = self.luns.get(lun_id as usize);
+        let info = lun.map(|l| self.lun_info(l));
+        let lun_ids: Vec<u16> = self.luns.iter().map(|l| l.id).collect();
+
+        let io = match emulate(cdb, info.as_ref(), &lun_ids) {
+            Outcome::Data(data) => {
+                return finish_cmd(vq, chain, Response::good(), &data, mem);
+            }
+            Outcome::Check(sense) => {
+                return finish_cmd(vq, chain, Response::check(sense), &[], mem);
+            }
+            Outcome::Unmap(param_len) => {
+                // Emulation only yields UNMAP for attached LUNs
+                let info = info.as_ref().and_then(|i| i.info).unwrap();
+                let res = read_unmap_params(&mut chain, param_len, mem)
+                    .and_then(|descs| check_unmap(&descs, info).map(|_| descs));
+                match res {
+                    Ok(descs) if !descs.is_empty() => IoCmd::Unmap(descs),
+                    Ok(_) => {
+                        let resp = Response::good();
+                        return finish_cmd(vq, chain, resp, &[], mem);
+                    }
+                    Err(sense) => {
+                        let resp = Response::check(sense);
+                        return finish_cmd(vq, chain, resp, &[], mem);
+                    }
+                }
+            }
+            Outcome::Io(io) => io,
+        };
+
+        // As with UNMAP, I/O is only yielded for attached LUNs
+        let lun = lun.unwrap();
+        let info = info.and_then(|i| i.info).unwrap();
+        let Some(resp) = chain.writable_bufs(RESP_SIZE) else {
+            let resp = Response::Transport(VIRTIO_SCSI_S_FAILURE);
+            return finish_cmd(vq, chain, resp, &[], mem);
+        };
+        let (req, len) = match io {
+            IoCmd::Read { lba, blocks } | IoCmd::Write { lba, blocks } => {
+                let off = lba as usize * info.block_size as usize;
+                let len = blocks as usize * info.block_size as usize;
+                let req = match io {
+                    IoCmd::Read { .. } => {
+                        chain.writable_bufs(len).map(|regions| {
+                            block::Request::new_read(off, len, regions)
+                        })
+                    }
+                    _ => chain.readable_bufs(len).map(|regions| {
+                        block::Request::new_write(off, len, regions)
+                    }),
+                };
+                let Some(req) = req else {
+                    // The guest buffers do not cover the transfer length
+                    let status =
+                        Response::check(Sense::INVALID_FIELD_IN_CDB).bytes(len);
+                    write_regions(&status, &resp, mem);
+                    vq.push_used(&mut chain, mem);
+                    return;
+                };
+                (req, len)
+            }
+            IoCmd::Flush => (block::Request::new_flush(), 0),
+            IoCmd::Unmap(descs) => {
+                let bs = info.block_size as usize;
+                let ranges = descs
+                    .iter()
+                    .map(|&(lba, blocks)| {
+                        (lba as usize * bs, blocks as usize * bs)
+                    })
+                    .collect();
+                (block::Request::new_discard(ranges), 0)
+            }
+        };
+        probes::vioscsi_io_start!(|| (lun.id, cdb[0]));
+        lun.enqueue(req, CompletionPayload { chain, resp, len });
+    }
+
+    fn complete_io(
+        &self,
+        lun: u16,
+        op: block::Operation,
+        res: block::Result,
+        payload: CompletionPayload,
+    ) {
+        let CompletionPayload { mut chain, resp, len } = payload;
+        let vq = &self.virtio_state.queues[REQUEST_QUEUE as usize];
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        let status = match res {
+            block::Result::Success => Response::good().bytes(0),
+            block::Result::Failure => {
+                let sense = match op {
+                    block::Operation::Read(..) => Sense::UNRECOVERED_READ_ERROR,
+                    block::Operation::Write(..)
+                    | block::Operation::WriteZeroes(..) => Sense::WRITE_ERROR,
+                    block::Operation::Flush | block::Operation::Discard => {
+                        Sense::INTERNAL_TARGET_FAILURE
+                    }
+                };
+                Response::check(sense).bytes(len)
+            }
+            block::Result::ReadOnly => {
+                Response::check(Sense::WRITE_PROTECTED).bytes(len)
+            }
+            block::Result::Unsupported => {
+                Response::check(Sense::INVALID_OPCODE).bytes(len)
+            }
+            block::Result::NotReady => {
+                Response::check(Sense::NOT_READY).bytes(len)
+            }
+        };
+        probes::vioscsi_io_complete!(|| (lun, status[RESP_STATUS_OFF]));
+        write_regions(&status, &resp, &mem);
+        vq.push_used(&mut chain, &mem);
+    }
+
+    fn process_control(&self, vq: &VirtQueue) {
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        let mut chain = Chain::with_capacity(2);
+        while vq.pop_avail(&mut chain, &mem).is_some() {
+            let mut req_type = [0u8; 4];
+            if chain.read(&mut req_type, &mem) {
+                match u32::from_le_bytes(req_type) {
+                    VIRTIO_SCSI_T_TMF => self.process_tmf(&mut chain, &mem),
+                    VIRTIO_SCSI_T_AN_QUERY | VIRTIO_SCSI_T_AN_SUBSCRIBE => {
+                        // No asynchronous notifications are supported
+                        let resp = [0, 0, 0, 0, VIRTIO_SCSI_S_OK];
+                        chain.write(&resp, &mem);
+                    }
+                    _ => {
+                        chain.write(&VIRTIO_SCSI_S_FAILURE, &mem);
+                    }
+                }
+            }
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+
+    fn process_tmf(&self, chain: &mut Chain, mem: &MemCtx) {
+        // Subtype, LUN, and tag
+        let mut req = [0u8; 20];
+        if !chain.read(&mut req, mem) {
+            chain.write(&VIRTIO_SCSI_S_FAILURE, mem);
+            return;
+        }
+        let lun =
+            decode_lun(&req[4..12]).and_then(|id| self.luns.get(id as usize));
+        let resp = match lun {
+            None => VIRTIO_SCSI_S_BAD_TARGET,
+            // Requests issued to the backend cannot be aborted, so task
+            // management only succeeds if there is nothing to be done.
+            Some(lun)
+                if lun.block_tracking.any_outstanding()
+                    || !lun.pending.lock().unwrap().is_empty() =>
+            {
+                VIRTIO_SCSI_S_FUNCTION_REJECTED
+            }
+            Some(_) => VIRTIO_SCSI_S_FUNCTION_COMPLETE,
+        };
+        chain.write(&resp, mem);
+    }
+}
+
+/// Write a command response (and any accompanying data-in) to a chain and
+/// return it to the guest.
+fn finish_cmd(
+    vq: &VirtQueue,
+    mut chain: Chain,
+    resp: Response,
+    data: &[u8],
+    mem: &MemCtx,
+) {
+    let capacity = chain.remain_write_bytes().saturating_sub(RESP_SIZE);
+    let len = data.len().min(capacity);
+    chain.write(&resp.bytes(capacity - len), mem);
+    if len != 0 {
+        write_buf(&data[..len], &mut chain, mem);
+    }
+    vq.push_used(&mut chain, mem);
+}
+
+fn write_regions(data: &[u8], regions: &[GuestRegion], mem: &MemCtx) {
+    let mut done = 0;
+    for region in regions {
+        match mem.write_from(region.0, &data[done..], region.1) {
+            Some(n) => done += n,
+            None => return,
+        }
+    }
+}
+
+impl VirtioDevice for PciVirtioScsi {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        SCSI_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.scsi_cfg_read(id, ro),
+            RWOp::Write(_) => {
+                // The sense and CDB sizes are fixed, ignoring driver writes
+            }
+        });
+    }
+    fn get_features(&self) -> u32 {
+        0
+    }
+    fn set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        match vq.id {
+            CONTROL_QUEUE => self.process_control(vq),
+            REQUEST_QUEUE => self.process_requests(vq),
+            // Buffers posted to the event queue are left for events which
+            // this device never raises.
+            _ => {}
+        }
+    }
+    fn notify_suppressible(&self) -> bool {
+        // Both the control and request queues are drained when notified, and
+        // requests left behind by a pause are picked up on resume.
+        true
+    }
+}
+impl PciVirtio for PciVirtioScsi {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+impl Entity for PciVirtioScsi {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-scsi"
+    }
+    fn reset(&self) {
+        for lun in self.luns.iter() {
+            lun.pending.lock().unwrap().clear();
+            lun.drained.notify_waiters();
+        }
+        self.virtio_state.reset(self);
+    }
+    fn pause(&self) {
+        // Commands already taken from the request queue are left for their
+        // backends to drain, rather than pausing the LUNs' attachments, so
+        // that no state outside the virtqueues needs to be migrated.
+        self.paused.store(true, Ordering::Release);
+    }
+    fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+        // Pick up any requests posted while paused
+        self.process_requests(
+            &self.virtio_state.queues[REQUEST_QUEUE as usize],
+        );
+    }
+    fn paused(&self) -> BoxFuture<'static, ()> {
+        let luns = self.luns.clone();
+        Box::pin(async move {
+            for lun in luns {
+                lun.drain().await;
+            }
+        })
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciVirtioScsi {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)
+    }
+}
+
+/// Decode a virtio-scsi LUN address, which must name the single target
+fn decode_lun(raw: &[u8]) -> Option<u16> {
+    if raw[0] != 1 || raw[1] != 0 {
+        return None;
+    }
+    // Single-level LUN, in either peripheral or flat addressing
+    let lun = u16::from_be_bytes([raw[2], raw[3]]) & 0x3fff;
+    (lun < MAX_LUNS).then_some(lun)
+}
+
+/// Response to a command
+enum Response {
+    /// Command reached the target, completing with a SCSI status
+    Status(u8, Option<Sense>),
+    /// Command failed in transport, before reaching the target
+    Transport(u8),
+}
+impl Response {
+    fn good() -> Self {
+        Self::Status(SCSI_STATUS_GOOD, None)
+    }
+    fn check(sense: Sense) -> Self {
+        Self::Status(SCSI_STATUS_CHECK_CONDITION, Some(sense))
+    }
+
+    /// Encode as a `virtio_scsi_cmd_resp`, with `resid` bytes of the expected
+    /// transfer left untransferred.
+    fn bytes(&self, resid: usize) -> [u8; RESP_SIZE] {
+        let mut out = [0u8; RESP_SIZE];
+        out[4..8].copy_from_slice(&(resid as u32).to_le_bytes());
+        match self {
+            Response::Status(status, sense) => {
+                out[RESP_STATUS_OFF] = *status;
+                out[RESP_STATUS_OFF + 1] = VIRTIO_SCSI_S_OK;
+                if let Some(sense) = sense {
+                    let data = sense.fixed_format();
+                    out[..4]
+                        .copy_from_slice(&(data.len() as u32).to_le_bytes());
+                    out[RESP_SENSE_OFF..][..data.len()].copy_from_slice(&data);
+                }
+            }
+            Response::Transport(resp) => {
+                out[RESP_STATUS_OFF + 1] = *resp;
+            }
+        }
+        out
+    }
+}
+
+/// Sense key and additional sense code (and qualifier) reported with CHECK
+/// CONDITION status
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+impl Sense {
+    const NOT_READY: Self = Self::new(0x2, 0x04, 0x00);
+    const MEDIUM_NOT_PRESENT: Self = Self::new(0x2, 0x3a, 0x00);
+    const WRITE_ERROR: Self = Self::new(0x3, 0x0c, 0x00);
+    const UNRECOVERED_READ_ERROR: Self = Self::new(0x3, 0x11, 0x00);
+    const INTERNAL_TARGET_FAILURE: Self = Self::new(0x4, 0x44, 0x00);
+    const INVALID_OPCODE: Self = Self::new(0x5, 0x20, 0x00);
+    const LBA_OUT_OF_RANGE: Self = Self::new(0x5, 0x21, 0x00);
+    const INVALID_FIELD_IN_CDB: Self = Self::new(0x5, 0x24, 0x00);
+    const LUN_NOT_SUPPORTED: Self = Self::new(0x5, 0x25, 0x00);
+    const INVALID_FIELD_IN_PARAMS: Self = Self::new(0x5, 0x26, 0x00);
+    const PARAM_LIST_LENGTH_ERROR: Self = Self::new(0x5, 0x1a, 0x00);
+    const WRITE_PROTECTED: Self = Self::new(0x7, 0x27, 0x00);
+
+    const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        Self { key, asc, ascq }
+    }
+
+    /// Encode as fixed-format sense data
+    fn fixed_format(&self) -> [u8; 18] {
+        let mut out = [0u8; 18];
+        // Current error
+        out[0] = 0x70;
+        out[2] = self.key;
+        // Additional sense length
+        out[7] = 10;
+        out[12] = self.asc;
+        out[13] = self.ascq;
+        out
+    }
+}
+
+/// What is known of the LUN addressed by a command
+struct LunInfo {
+    /// Details of the attached backend, if any
+    info: Option<block::DeviceInfo>,
+    serial: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum IoCmd {
+    Read {
+        lba: u64,
+        blocks: u32,
+    },
+    Write {
+        lba: u64,
+        blocks: u32,
+    },
+    Flush,
+    /// Deallocate the listed extents (starting LBA and block count)
+    Unmap(Vec<(u64, u32)>),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Outcome {
+    /// Command completes with GOOD status, returning data-in to the guest
+    Data(Vec<u8>),
+    /// Command completes with CHECK CONDITION status
+    Check(Sense),
+    /// Command is to be issued to the backend
+    Io(IoCmd),
+    /// UNMAP, with a parameter list of the given length in the data-out
+    Unmap(usize),
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes(b[..2].try_into().unwrap())
+}
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes(b[..4].try_into().unwrap())
+}
+fn be64(b: &[u8]) -> u64 {
+    u64::from_be_bytes(b[..8].try_into().unwrap())
+}
+
+/// Truncate `data` to the allocation length specified in a CDB
+fn alloc(mut data: Vec<u8>, alloc_len: usize) -> Outcome {
+    data.truncate(alloc_len);
+    Outcome::Data(data)
+}
+
+/// Emulate the command `cdb` addressed to a LUN (`None` if the LUN does not
+/// exist), where `luns` lists those which do.
+fn emulate(cdb: &[u8], lun: Option<&LunInfo>, luns: &[u16]) -> Outcome {
+    let opcode = cdb[0];
+
+    // Commands which are valid regardless of the LUN addressed
+    match opcode {
+        SCSI_INQUIRY => return inquiry(cdb, lun),
+        SCSI_REPORT_LUNS => {
+            let mut data = Vec::with_capacity(8 + luns.len() * 8);
+            data.extend(((luns.len() * 8) as u32).to_be_bytes());
+            data.extend([0; 4]);
+            for lun in luns {
+                let mut entry = [0u8; 8];
+                if *lun < 256 {
+                    entry[1] = *lun as u8;
+                } else {
+                    // Flat space addressing
+                    entry[..2].copy_from_slice(&(0x4000 | *lun).to_be_bytes());
+                }
+                data.extend(entry);
+            }
+            return alloc(data, be32(&cdb[6..]) as usize);
+        }
+        SCSI_REQUEST_SENSE => {
+            // Sense data is always returned with the failed command, so there
+            // is none pending here.
+            let sense = match lun {
+                Some(_) => Sense::new(0, 0, 0),
+                None => Sense::LUN_NOT_SUPPORTED,
+            };
+            return alloc(sense.fixed_format().to_vec(), cdb[4] as usize);
+        }
+        _ => {}
+    }
+
+    let Some(lun) = lun else {
+        return Outcome::Check(Sense::LUN_NOT_SUPPORTED);
+    };
+    let Some(info) = lun.info else {
+        return Outcome::Check(Sense::MEDIUM_NOT_PRESENT);
+    };
+
+    let rw = |write: bool, lba: u64, blocks: u32| {
+        let end = lba.checked_add(blocks as u64);
+        if !matches!(end, Some(end) if end <= info.total_size) {
+            Outcome::Check(Sense::LBA_OUT_OF_RANGE)
+        } else if write && info.read_only {
+            Outcome::Check(Sense::WRITE_PROTECTED)
+        } else if blocks == 0 {
+            Outcome::Data(Vec::new())
+        } else if write {
+            Outcome::Io(IoCmd::Write { lba, blocks })
+        } else {
+            Outcome::Io(IoCmd::Read { lba, blocks })
+        }
+    };
+    let last_lba = info.total_size.saturating_sub(1);
+
+    match opcode {
+        SCSI_TEST_UNIT_READY
+        | SCSI_START_STOP_UNIT
+        | SCSI_PREVENT_ALLOW_REMOVAL
+        | SCSI_VERIFY_10
+        | SCSI_VERIFY_16 => Outcome::Data(Vec::new()),
+
+        SCSI_READ_6 | SCSI_WRITE_6 => {
+            let lba = (be32(cdb) & 0x1f_ffff) as u64;
+            // A transfer length of 0 denotes 256 blocks
+            let blocks = match cdb[4] {
+                0 => 256,
+                n => n as u32,
+            };
+            rw(opcode == SCSI_WRITE_6, lba, blocks)
+        }
+        SCSI_READ_10 | SCSI_WRITE_10 => rw(
+            opcode == SCSI_WRITE_10,
+            be32(&cdb[2..]) as u64,
+            be16(&cdb[7..]) as u32,
+        ),
+        SCSI_READ_12 | SCSI_WRITE_12 => {
+            rw(opcode == SCSI_WRITE_12, be32(&cdb[2..]) as u64, be32(&cdb[6..]))
+        }
+        SCSI_READ_16 | SCSI_WRITE_16 => {
+            rw(opcode == SCSI_WRITE_16, be64(&cdb[2..]), be32(&cdb[10..]))
+        }
+        SCSI_SYNCHRONIZE_CACHE_10 | SCSI_SYNCHRONIZE_CACHE_16 => {
+            Outcome::Io(IoCmd::Flush)
+        }
+
+        SCSI_READ_CAPACITY_10 => {
+            let mut data = Vec::with_capacity(8);
+            data.extend((last_lba.min(u32::MAX as u64) as u32).to_be_bytes());
+            data.extend(info.block_size.to_be_bytes());
+            Outcome::Data(data)
+        }
+        SCSI_SERVICE_ACTION_IN_16 if cdb[1] & 0x1f == SAI_READ_CAPACITY_16 => {
+            let mut data = vec![0u8; 32];
+            data[..8].copy_from_slice(&last_lba.to_be_bytes());
+            data[8..12].copy_from_slice(&info.block_size.to_be_bytes());
+            // Logical block provisioning management is enabled (LBPME)
+            if info.supports_discard {
+                data[14] = 0x80;
+            }
+            alloc(data, be32(&cdb[10..]) as usize)
+        }
+
+        SCSI_MODE_SENSE_6 | SCSI_MODE_SENSE_10 => mode_sense(cdb, &info),
+
+        SCSI_UNMAP if !info.supports_discard => {
+            Outcome::Check(Sense::INVALID_OPCODE)
+        }
+        SCSI_UNMAP if info.read_only => Outcome::Check(Sense::WRITE_PROTECTED),
+        SCSI_UNMAP if cdb[1] & 0x1 != 0 => {
+            // Anchored unmapping is not supported
+            Outcome::Check(Sense::INVALID_FIELD_IN_CDB)
+        }
+        SCSI_UNMAP => Outcome::Unmap(be16(&cdb[7..]) as usize),
+
+        _ => Outcome::Check(Sense::INVALID_OPCODE),
+    }
+}
+
+fn inquiry(cdb: &[u8], lun: Option<&LunInfo>) -> Outcome {
+    let alloc_len = be16(&cdb[3..]) as usize;
+    let evpd = cdb[1] & 0x1 != 0;
+
+    let Some(lun) = lun else {
+        if evpd {
+            return Outcome::Check(Sense::LUN_NOT_SUPPORTED);
+        }
+        // Peripheral qualifier indicating no unit is present
+        let mut data = vec![0u8; 36];
+        data[0] = 0x7f;
+        return alloc(data, alloc_len);
+    };
+
+    if !evpd {
+        if cdb[2] != 0 {
+            return Outcome::Check(Sense::INVALID_FIELD_IN_CDB);
+        }
+        let mut data = vec![0u8; 36];
+        // Direct-access block device, conforming to SPC-4
+        data[2] = 0x06;
+        data[3] = 0x02;
+        data[4] = 36 - 5;
+        // Command queuing is supported
+        data[7] = 0x02;
+        data[8..16].copy_from_slice(VENDOR_ID);
+        data[16..32].copy_from_slice(b"VIRTUAL DISK    ");
+        data[32..36].copy_from_slice(b"1.0 ");
+        return alloc(data, alloc_len);
+    }
+
+    let supports_discard = lun.info.is_some_and(|i| i.supports_discard);
+    let page = cdb[2];
+    let mut data = vec![0, page, 0, 0];
+    match page {
+        VPD_SUPPORTED_PAGES => data.extend([
+            VPD_SUPPORTED_PAGES,
+            VPD_SERIAL_NUMBER,
+            VPD_DEVICE_ID,
+            VPD_BLOCK_LIMITS,
+            VPD_BLOCK_DEVICE_CHARACTERISTICS,
+            VPD_LOGICAL_BLOCK_PROVISIONING,
+        ]),
+        VPD_SERIAL_NUMBER => data.extend(lun.serial.as_bytes()),
+        VPD_DEVICE_ID => {
+            // T10 vendor ID designator, associated with the unit
+            let mut id = VENDOR_ID.to_vec();
+            id.extend(lun.serial.as_bytes());
+            data.extend([0x02, 0x01, 0, id.len() as u8]);
+            data.extend(id);
+        }
+        VPD_BLOCK_LIMITS => {
+            let mut limits = [0u8; 0x3c];
+            if supports_discard {
+                // Maximum unmap LBA count, and block descriptor count
+                limits[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+                limits[20..24].copy_from_slice(
+                    &(MAX_UNMAP_DESCRIPTORS as u32).to_be_bytes(),
+                );
+            }
+            data.extend(limits);
+        }
+        VPD_BLOCK_DEVICE_CHARACTERISTICS => {
+            let mut chars = [0u8; 0x3c];
+            // Non-rotating medium
+            chars[1] = 0x01;
+            data.extend(chars);
+        }
+        VPD_LOGICAL_BLOCK_PROVISIONING => {
+            // Unmapping is supported via UNMAP (LBPU)
+            let lbpu = if supports_discard { 0x80 } else { 0 };
+            data.extend([0, lbpu, 0, 0]);
+        }
+        _ => return Outcome::Check(Sense::INVALID_FIELD_IN_CDB),
+    }
+    let page_len = (data.len() - 4) as u16;
+    data[2..4].copy_from_slice(&page_len.to_be_bytes());
+    alloc(data, alloc_len)
+}
+
+fn mode_sense(cdb: &[u8], info: &block::DeviceInfo) -> Outcome {
+    let long = cdb[0] == SCSI_MODE_SENSE_10;
+    let page = cdb[2] & 0x3f;
+    let changeable = cdb[2] >> 6 == 1;
+    let alloc_len = match long {
+        true => be16(&cdb[7..]) as usize,
+        false => cdb[4] as usize,
+    };
+
+    let mut pages = Vec::new();
+    if page == MODE_PAGE_CACHING || page == MODE_PAGE_ALL {
+        let mut caching = [0u8; 20];
+        caching[0] = MODE_PAGE_CACHING;
+        caching[1] = 18;
+        if !changeable {
+            // Write cache enabled, as flushes are passed to the backend
+            caching[2] = 0x04;
+        }
+        pages.extend(caching);
+    }
+    if page == MODE_PAGE_CONTROL || page == MODE_PAGE_ALL {
+        let mut control = [0u8; 12];
+        control[0] = MODE_PAGE_CONTROL;
+        control[1] = 10;
+        pages.extend(control);
+    }
+    if pages.is_empty() || cdb[3] != 0 {
+        return Outcome::Check(Sense::INVALID_FIELD_IN_CDB);
+    }
+
+    // Device-specific parameter, noting write-protection
+    let dev_param = if info.read_only { 0x80 } else { 0 };
+    let mut data = if long {
+        let mut hdr = vec![0u8; 8];
+        hdr[3] = dev_param;
+        hdr
+    } else {
+        vec![0, 0, dev_param, 0]
+    };
+    data.extend(pages);
+    if long {
+        let len = (data.len() - 2) as u16;
+        data[..2].copy_from_slice(&len.to_be_bytes());
+    } else {
+        data[0] = (data.len() - 1) as u8;
+    }
+    alloc(data, alloc_len)
+}
+
+/// Read the block descriptors of an UNMAP parameter list of `param_len` bytes
+fn read_unmap_params(
+    chain: &mut Chain,
+    param_len: usize,
+    mem: &MemCtx,
+) -> Result<Vec<(u64, u32)>, Sense> {
+    if param_len == 0 {
+        return Ok(Vec::new());
+    }
+    let mut hdr = [0u8; 8];
+    if param_len < hdr.len() || !chain.read(&mut hdr, mem) {
+        return Err(Sense::PARAM_LIST_LENGTH_ERROR);
+    }
+    let desc_len = be16(&hdr[2..]) as usize;
+    if desc_len & 0xf != 0 || desc_len + hdr.len() > param_len {
+        return Err(Sense::PARAM_LIST_LENGTH_ERROR);
+    }
+    if desc_len / 16 > MAX_UNMAP_DESCRIPTORS {
+        return Err(Sense::INVALID_FIELD_IN_PARAMS);
+    }
+    let mut descs = Vec::with_capacity(desc_len / 16);
+    for _ in 0..desc_len / 16 {
+        let mut desc = [0u8; 16];
+        if !chain.read(&mut desc, mem) {
+            return Err(Sense::PARAM_LIST_LENGTH_ERROR);
+        }
+        descs.push((be64(&desc), be32(&desc[8..])));
+    }
+    Ok(descs)
+}
+
+/// Validate the extents of an UNMAP command against the unit's capacity
+fn check_unmap(
+    descs: &[(u64, u32)],
+    info: block::DeviceInfo,
+) -> Result<(), Sense> {
+    for (lba, blocks) in descs {
+        let end = lba.checked_add(*blocks as u64);
+        if !matches!(end, Some(end) if end <= info.total_size) {
+            return Err(Sense::LBA_OUT_OF_RANGE);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ScsiReg {
+    NumQueues,
+    SegMax,
+    MaxSectors,
+    CmdPerLun,
+    EventInfoSize,
+    SenseSize,
+    CdbSize,
+    MaxChannel,
+    MaxTarget,
+    MaxLun,
+}
+lazy_static! {
+    static ref SCSI_DEV_REGS: RegMap<ScsiReg> = {
+        let layout = [
+            (ScsiReg::NumQueues, 4),
+            (ScsiReg::SegMax, 4),
+            (ScsiReg::MaxSectors, 4),
+            (ScsiReg::CmdPerLun, 4),
+            (ScsiReg::EventInfoSize, 4),
+            (ScsiReg::SenseSize, 4),
+            (ScsiReg::CdbSize, 4),
+            (ScsiReg::MaxChannel, 2),
+            (ScsiReg::MaxTarget, 2),
+            (ScsiReg::MaxLun, 4),
+        ];
+        RegMap::create_packed(VIRTIO_SCSI_CFG_SIZE, &layout, None)
+    };
+}
+
+const VENDOR_ID: &[u8; 8] = b"OXIDE   ";
+
+/// Sizes of the sense and CDB fields, as fixed by the device configuration
+const SENSE_SIZE: usize = 96;
+const CDB_SIZE: usize = 32;
+
+/// Size of `virtio_scsi_cmd_req` (LUN, tag, task attribute, priority, CRN, and
+/// CDB), and the offset of its CDB
+const CMD_REQ_SIZE: usize = 19 + CDB_SIZE;
+const CMD_REQ_CDB_OFF: usize = 19;
+/// Size of `virtio_scsi_cmd_resp`, and the offsets of its fields
+const RESP_SIZE: usize = 12 + SENSE_SIZE;
+const RESP_STATUS_OFF: usize = 10;
+const RESP_SENSE_OFF: usize = 12;
+
+mod bits {
+    #![allow(unused)]
+
+    pub const VIRTIO_SCSI_CFG_SIZE: usize = 0x24;
+
+    pub const VIRTIO_SCSI_T_TMF: u32 = 0;
+    pub const VIRTIO_SCSI_T_AN_QUERY: u32 = 1;
+    pub const VIRTIO_SCSI_T_AN_SUBSCRIBE: u32 = 2;
+
+    pub const VIRTIO_SCSI_S_OK: u8 = 0;
+    pub const VIRTIO_SCSI_S_FUNCTION_COMPLETE: u8 = 0;
+    pub const VIRTIO_SCSI_S_OVERRUN: u8 = 1;
+    pub const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
+    pub const VIRTIO_SCSI_S_FAILURE: u8 = 9;
+    pub const VIRTIO_SCSI_S_FUNCTION_REJECTED: u8 = 11;
+
+    pub const SCSI_STATUS_GOOD: u8 = 0x00;
+    pub const SCSI_STATUS_CHECK_CONDITION: u8 = 0x02;
+
+    pub const SCSI_TEST_UNIT_READY: u8 = 0x00;
+    pub const SCSI_REQUEST_SENSE: u8 = 0x03;
+    pub const SCSI_READ_6: u8 = 0x08;
+    pub const SCSI_WRITE_6: u8 = 0x0a;
+    pub const SCSI_INQUIRY: u8 = 0x12;
+    pub const SCSI_MODE_SENSE_6: u8 = 0x1a;
+    pub const SCSI_START_STOP_UNIT: u8 = 0x1b;
+    pub const SCSI_PREVENT_ALLOW_REMOVAL: u8 = 0x1e;
+    pub const SCSI_READ_CAPACITY_10: u8 = 0x25;
+    pub const SCSI_READ_10: u8 = 0x28;
+    pub const SCSI_WRITE_10: u8 = 0x2a;
+    pub const SCSI_VERIFY_10: u8 = 0x2f;
+    pub const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+    pub const SCSI_UNMAP: u8 = 0x42;
+    pub const SCSI_MODE_SENSE_10: u8 = 0x5a;
+    pub const SCSI_READ_16: u8 = 0x88;
+    pub const SCSI_WRITE_16: u8 = 0x8a;
+    pub const SCSI_VERIFY_16: u8 = 0x8f;
+    pub const SCSI_SYNCHRONIZE_CACHE_16: u8 = 0x91;
+    pub const SCSI_SERVICE_ACTION_IN_16: u8 = 0x9e;
+    pub const SCSI_REPORT_LUNS: u8 = 0xa0;
+    pub const SCSI_READ_12: u8 = 0xa8;
+    pub const SCSI_WRITE_12: u8 = 0xaa;
+
+    pub const SAI_READ_CAPACITY_16: u8 = 0x10;
+
+    pub const VPD_SUPPORTED_PAGES: u8 = 0x00;
+    pub const VPD_SERIAL_NUMBER: u8 = 0x80;
+    pub const VPD_DEVICE_ID: u8 = 0x83;
+    pub const VPD_BLOCK_LIMITS: u8 = 0xb0;
+    pub const VPD_BLOCK_DEVICE_CHARACTERISTICS: u8 = 0xb1;
+    pub const VPD_LOGICAL_BLOCK_PROVISIONING: u8 = 0xb2;
+
+    pub const MODE_PAGE_CACHING: u8 = 0x08;
+    pub const MODE_PAGE_CONTROL: u8 = 0x0a;
+    pub const MODE_PAGE_ALL: u8 = 0x3f;
+}
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn vioscsi_io_start(lun: u16, opcode: u8) {}
+    fn vioscsi_io_complete(lun: u16, status: u8) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DISK: block::DeviceInfo = block::DeviceInfo {
+        block_size: 512,
+        total_size: 2048,
+        read_only: false,
+        supports_discard: false,
+        supports_write_zeroes: false,
+    };
+
+    fn lun(info: Option<block::DeviceInfo>) -> LunInfo {
+        LunInfo { info, serial: "DISK0".to_string() }
+    }
+    fn cdb(prefix: &[u8]) -> [u8; CDB_SIZE] {
+        let mut cdb = [0u8; CDB_SIZE];
+        cdb[..prefix.len()].copy_from_slice(prefix);
+        cdb
+    }
+
+    #[test]
+    fn lun_addressing() {
+        assert_eq!(decode_lun(&[1, 0, 0x40, 0x05, 0, 0, 0, 0]), Some(5));
+        assert_eq!(decode_lun(&[1, 0, 0x41, 0x00, 0, 0, 0, 0]), None);
+        assert_eq!(decode_lun(&[1, 1, 0x40, 0x00, 0, 0, 0, 0]), None);
+        assert_eq!(decode_lun(&[0, 0, 0x40, 0x00, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn report_luns() {
+        let luns = [0, 1, 2];
+        let cmd = cdb(&[SCSI_REPORT_LUNS, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        let Outcome::Data(data) = emulate(&cmd, None, &luns) else {
+            panic!("REPORT LUNS failed");
+        };
+        assert_eq!(be32(&data), 24);
+        assert_eq!(data.len(), 8 + 24);
+        assert_eq!(&data[16..24], &[0, 1, 0, 0, 0, 0, 0, 0]);
+
+        // Truncated to the allocation length
+        let cmd = cdb(&[SCSI_REPORT_LUNS, 0, 0, 0, 0, 0, 0, 0, 0, 16]);
+        assert!(matches!(
+            emulate(&cmd, None, &luns),
+            Outcome::Data(d) if d.len() == 16
+        ));
+    }
+
+    #[test]
+    fn read_capacity() {
+        let info = lun(Some(DISK));
+        let cmd = cdb(&[SCSI_READ_CAPACITY_10]);
+        assert_eq!(
+            emulate(&cmd, Some(&info), &[0]),
+            Outcome::Data(vec![0, 0, 0x07, 0xff, 0, 0, 0x02, 0])
+        );
+
+        let mut cmd = cdb(&[SCSI_SERVICE_ACTION_IN_16, SAI_READ_CAPACITY_16]);
+        cmd[13] = 32;
+        let Outcome::Data(data) = emulate(&cmd, Some(&info), &[0]) else {
+            panic!("READ CAPACITY(16) failed");
+        };
+        assert_eq!(data.len(), 32);
+        assert_eq!(be64(&data), 2047);
+        assert_eq!(be32(&data[8..]), 512);
+        assert_eq!(data[14] & 0x80, 0);
+
+        // Provisioning is only reported when storage can be discarded
+        let info =
+            lun(Some(block::DeviceInfo { supports_discard: true, ..DISK }));
+        let Outcome::Data(data) = emulate(&cmd, Some(&info), &[0]) else {
+            panic!("READ CAPACITY(16) failed");
+        };
+        assert_eq!(data[14] & 0x80, 0x80);
+    }
+
+    #[test]
+    fn read_write() {
+        let info = lun(Some(DISK));
+        let cmd = cdb(&[SCSI_READ_10, 0, 0, 0, 0x07, 0xf0, 0, 0, 0x10]);
+        assert_eq!(
+            emulate(&cmd, Some(&info), &[0]),
+            Outcome::Io(IoCmd::Read { lba: 0x7f0, blocks: 0x10 })
+        );
+        let cmd = cdb(&[SCSI_READ_10, 0, 0, 0, 0x07, 0xf0, 0, 0, 0x11]);
+        assert_eq!(
+            emulate(&cmd, Some(&info), &[0]),
+            Outcome::Check(Sense::LBA_OUT_OF_RANGE)
+        );
+
+        // READ(6) with a zero length transfers 256 blocks
+        let cmd = cdb(&[SCSI_READ_6, 0, 0, 1, 0]);
+        assert_eq!(
+            emulate(&cmd, Some(&info), &[0]),
+            Outcome::Io(IoCmd::Read { lba: 1, blocks: 256 })
+        );
+
+        let mut cmd = cdb(&[SCSI_WRITE_16]);
+        cmd[9] = 8;
+        cmd[13] = 4;
+        assert_eq!(
+            emulate(&cmd, Some(&info), &[0]),
+            Outcome::Io(IoCmd::Write { lba: 8, blocks: 4 })
+        );
+        let ro = lun(Some(block::DeviceInfo { read_only: true, ..DISK }));
+        assert_eq!(
+            emulate(&cmd, Some(&ro), &[0]),
+            Outcome::Check(Sense::WRITE_PROTECTED)
+        );
+    }
+
+    #[test]
+    fn absent_units() {
+        let cmd = cdb(&[SCSI_INQUIRY, 0, 0, 0, 36]);
+        let Outcome::Data(data) = emulate(&cmd, None, &[0]) else {
+            panic!("INQUIRY failed");
+        };
+        assert_eq!(data[0], 0x7f);
+
+        let cmd = cdb(&[SCSI_TEST_UNIT_READY]);
+        assert_eq!(
+            emulate(&cmd, None, &[0]),
+            Outcome::Check(Sense::LUN_NOT_SUPPORTED)
+        );
+        assert_eq!(
+            emulate(&cmd, Some(&lun(None)), &[0]),
+            Outcome::Check(Sense::MEDIUM_NOT_PRESENT)
+        );
+    }
+
+    #[test]
+    fn vpd_pages() {
+        let info = lun(Some(DISK));
+        let cmd = cdb(&[SCSI_INQUIRY, 1, VPD_SERIAL_NUMBER, 0, 0xff]);
+        assert_eq!(
+            emulate(&cmd, Some(&info), &[0]),
+            Outcome::Data(b"\0\x80\0\x05DISK0".to_vec())
+        );
+
+        let cmd = cdb(&[SCSI_INQUIRY, 1, VPD_SUPPORTED_PAGES, 0, 0xff]);
+        let Outcome::Data(data) = emulate(&cmd, Some(&info), &[0]) else {
+            panic!("INQUIRY failed");
+        };
+        for page in &data[4..] {
+            let cmd = cdb(&[SCSI_INQUIRY, 1, *page, 0, 0xff]);
+            let Outcome::Data(data) = emulate(&cmd, Some(&info), &[0]) else {
+                panic!("VPD page {page:#x} failed");
+            };
+            assert_eq!(data[1], *page);
+            assert_eq!(be16(&data[2..]) as usize, data.len() - 4);
+        }
+    }
+
+    #[test]
+    fn mode_sense_write_protect() {
+        let cmd = cdb(&[SCSI_MODE_SENSE_6, 0, MODE_PAGE_ALL, 0, 0xff]);
+        let Outcome::Data(data) = emulate(&cmd, Some(&lun(Some(DISK))), &[0])
+        else {
+            panic!("MODE SENSE failed");
+        };
+        assert_eq!(data[0] as usize, data.len() - 1);
+        assert_eq!(data[2], 0);
+        assert_eq!(data[4], MODE_PAGE_CACHING);
+
+        let ro = lun(Some(block::DeviceInfo { read_only: true, ..DISK }));
+        let cmd = cdb(&[
+            SCSI_MODE_SENSE_10,
+            0,
+            MODE_PAGE_CONTROL,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0xff,
+        ]);
+        let Outcome::Data(data) = emulate(&cmd, Some(&ro), &[0]) else {
+            panic!("MODE SENSE failed");
+        };
+        assert_eq!(be16(&data) as usize, data.len() - 2);
+        assert_eq!(data[3], 0x80);
+        assert_eq!(data[8], MODE_PAGE_CONTROL);
+    }
+
+    #[test]
+    fn unmap_validation() {
+        let cmd = cdb(&[SCSI_UNMAP, 0, 0, 0, 0, 0, 0, 0, 24]);
+        assert_eq!(
+            emulate(&cmd, Some(&lun(Some(DISK))), &[0]),
+            Outcome::Check(Sense::INVALID_OPCODE)
+        );
+        let info =
+            lun(Some(block::DeviceInfo { supports_discard: true, ..DISK }));
+        assert_eq!(emulate(&cmd, Some(&info), &[0]), Outcome::Unmap(24));
+
+        assert_eq!(check_unmap(&[(0, 2048)], DISK), Ok(()));
+        assert_eq!(
+            check_unmap(&[(16, 8), (2040, 9)], DISK),
+            Err(Sense::LBA_OUT_OF_RANGE)
+        );
+    }
+
+    #[test]
+    fn response_encoding() {
+        let resp = Response::check(Sense::INVALID_OPCODE).bytes(512);
+        assert_eq!(u32::from_le_bytes(resp[..4].try_into().unwrap()), 18);
+        assert_eq!(u32::from_le_bytes(resp[4..8].try_into().unwrap()), 512);
+        assert_eq!(resp[RESP_STATUS_OFF], SCSI_STATUS_CHECK_CONDITION);
+        assert_eq!(resp[RESP_STATUS_OFF + 1], VIRTIO_SCSI_S_OK);
+        assert_eq!(resp[RESP_SENSE_OFF], 0x70);
+        assert_eq!(resp[RESP_SENSE_OFF + 2], 0x5);
+        assert_eq!(resp[RESP_SENSE_OFF + 12], 0x20);
+
+        let resp = Response::Transport(VIRTIO_SCSI_S_BAD_TARGET).bytes(0);
+        assert_eq!(resp[RESP_STATUS_OFF + 1], VIRTIO_SCSI_S_BAD_TARGET);
+        assert_eq!(&resp[..4], &[0; 4]);
+    }
+}
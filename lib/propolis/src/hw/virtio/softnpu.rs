@@ -23,7 +23,7 @@ use crate::{
 
 use super::{
     bits::*,
-    pci::{PciVirtio, PciVirtioState},
+    pci::{PciVirtio, PciVirtioState, Transport},
     queue::{write_buf, Chain, VirtQueue, VirtQueues},
     viona::bits::VIRTIO_NET_S_LINK_UP,
     VirtioDevice,
@@ -157,6 +157,7 @@ impl PortVirtioState {
             VIRTIO_SUB_DEV_NET,
             pci::bits::CLASS_NETWORK,
             VIRTIO_NET_CFG_SIZE,
+            Transport::Legacy,
         );
         Self { pci_virtio_state, pci_state }
     }
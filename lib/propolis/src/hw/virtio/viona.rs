@@ -11,13 +11,14 @@ use std::sync::atomic::Ordering;
 use std::sync::{Arc, Condvar, Mutex, Weak};
 
 use crate::common::*;
+use crate::hostres;
 use crate::hw::pci;
 use crate::migrate::*;
 use crate::util::regmap::RegMap;
 use crate::vmm::VmmHdl;
 
 use super::bits::*;
-use super::pci::{PciVirtio, PciVirtioState};
+use super::pci::{PciVirtio, PciVirtioState, Transport};
 use super::queue::{self, VirtQueue, VirtQueues};
 use super::{VirtioDevice, VqChange, VqIntr};
 
@@ -32,10 +33,13 @@ const ETHERADDRL: usize = 6;
 struct Inner {
     poller: Option<PollerHdl>,
     ring_paused: [bool; 2],
+    /// Queue notifications are handled by the kernel, via a hook on the port of
+    /// the QueueNotify register, rather than exiting to userspace.
+    notify_in_kernel: bool,
 }
 impl Inner {
     fn new() -> Self {
-        Self { poller: None, ring_paused: [false; 2] }
+        Self { poller: None, ring_paused: [false; 2], notify_in_kernel: false }
     }
 }
 
@@ -50,6 +54,9 @@ pub struct PciVirtioViona {
     mtu: Option<u16>,
     hdl: VionaHdl,
     inner: Mutex<Inner>,
+
+    res_owner: Arc<hostres::Owner>,
+    _res_held: [hostres::Held; 2],
 }
 impl PciVirtioViona {
     pub fn new(
@@ -60,6 +67,11 @@ impl PciVirtioViona {
         let dlhdl = dladm::Handle::new()?;
         let info = dlhdl.query_vnic(vnic_name)?;
         let hdl = VionaHdl::new(info.link_id, vm.fd())?;
+        let res_owner = hostres::Owner::new(format!("viona-{}", vnic_name));
+        let res_held = [
+            res_owner.hold(hostres::Kind::Fd, 1),
+            res_owner.hold(hostres::Kind::VionaLink, 1),
+        ];
 
         // TX and RX
         let queue_count = NonZeroU16::new(2).unwrap();
@@ -76,6 +88,8 @@ impl PciVirtioViona {
             VIRTIO_SUB_DEV_NET,
             pci::bits::CLASS_NETWORK,
             VIRTIO_NET_CFG_SIZE,
+            // The in-kernel emulation only supports the legacy queue layout
+            Transport::Legacy,
         );
 
         let mut this = PciVirtioViona {
@@ -87,14 +101,20 @@ impl PciVirtioViona {
             mtu: info.mtu,
             hdl,
             inner: Mutex::new(Inner::new()),
+
+            res_owner,
+            _res_held: res_held,
         };
         this.mac_addr.copy_from_slice(&info.mac_addr);
         let this = Arc::new(this);
 
         // Spawn the interrupt poller
         let mut inner = this.inner.lock().unwrap();
-        inner.poller =
-            Some(Poller::spawn(this.hdl.as_raw_fd(), Arc::downgrade(&this))?);
+        inner.poller = Some(Poller::spawn(
+            this.hdl.as_raw_fd(),
+            Arc::downgrade(&this),
+            &this.res_owner,
+        )?);
         drop(inner);
 
         Ok(this)
@@ -269,6 +289,13 @@ impl VirtioDevice for PciVirtioViona {
                     self.hdl
                         .ring_init(vq.id, vq.size, info.mapping.desc_addr)
                         .unwrap_or_else(|_| todo!("viona error handling"));
+
+                    // Kicks handled in-kernel are never observed here, so the
+                    // ring must be assumed live (for the purposes of syncing
+                    // its state) as soon as the guest could kick it.
+                    if self.inner.lock().unwrap().notify_in_kernel {
+                        vq.live.store(true, Ordering::Release);
+                    }
                 }
             }
             VqChange::IntrCfg => {
@@ -320,6 +347,19 @@ impl PciVirtio for PciVirtioViona {
     fn pci_state(&self) -> &pci::DeviceState {
         &self.pci_state
     }
+    fn notify_port_update(&self, port: Option<u16>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let in_kernel = self.hdl.set_notify_iop(port.unwrap_or(0)).is_ok();
+        inner.notify_in_kernel = port.is_some() && in_kernel;
+        if inner.notify_in_kernel {
+            for vq in self.virtio_state.queues.iter() {
+                if vq.get_state().mapping.valid {
+                    vq.live.store(true, Ordering::Release);
+                }
+            }
+        }
+        inner.notify_in_kernel
+    }
 }
 
 impl MigrateMulti for PciVirtioViona {
@@ -406,6 +446,13 @@ impl VionaHdl {
         self.0.ioctl_usize(viona_api::VNA_IOC_RING_KICK, idx as usize)?;
         Ok(())
     }
+    /// Hook writes to IO port `port` (or clear the hook, if zero), so that they
+    /// kick the ring indicated by the value written, without exiting to
+    /// userspace.
+    fn set_notify_iop(&self, port: u16) -> io::Result<()> {
+        self.0.ioctl_usize(viona_api::VNA_IOC_SET_NOTIFY_IOP, port as usize)?;
+        Ok(())
+    }
     fn ring_pause(&self, idx: u16) -> io::Result<()> {
         self.0.ioctl_usize(viona_api::VNA_IOC_RING_PAUSE, idx as usize)?;
         Ok(())
@@ -505,6 +552,7 @@ struct Poller {
     receiver: watch::Receiver<TargetState>,
     dev: Weak<PciVirtioViona>,
     state: Arc<PollerState>,
+    _res_held: [hostres::Held; 2],
 }
 
 enum TargetState {
@@ -545,6 +593,7 @@ impl Poller {
     fn spawn(
         viona_fd: RawFd,
         dev: Weak<PciVirtioViona>,
+        res_owner: &Arc<hostres::Owner>,
     ) -> io::Result<PollerHdl> {
         let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) } as RawFd;
         if epfd == -1 {
@@ -564,7 +613,14 @@ impl Poller {
             running: Mutex::new(false),
         });
         let (sender, receiver) = watch::channel(TargetState::Pause);
-        let mut poller = Poller { epfd, receiver, dev, state: state.clone() };
+        // The epoll fd and the task are both released when the poller is
+        // dropped at the conclusion of its task.
+        let _res_held = [
+            res_owner.hold(hostres::Kind::Fd, 1),
+            res_owner.hold(hostres::Kind::Task, 1),
+        ];
+        let mut poller =
+            Poller { epfd, receiver, dev, state: state.clone(), _res_held };
 
         let _join = tokio::spawn(async move {
             poller.poll_interrupts().await;
@@ -654,6 +710,7 @@ impl Poller {
     fn spawn(
         _viona_fd: RawFd,
         _dev: Weak<PciVirtioViona>,
+        _res_owner: &Arc<hostres::Owner>,
     ) -> io::Result<PollerHdl> {
         Err(Error::new(
             ErrorKind::Other,
@@ -0,0 +1,958 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! virtio-vsock: stream sockets between the guest and the host
+//!
+//! The guest is addressed by the context ID (CID) given to the device, and the
+//! host by [`VSOCK_HOST_CID`].  Software in the guest connects to a port of the
+//! host, where the connection is handed to whichever [`VsockListener`] the
+//! host has opened on that port, or reset if there is none.  No network
+//! configuration is needed on either side.
+//!
+//! Each packet on the queues begins with a header naming the connection's
+//! endpoints, the operation, and the sender's credit: the buffer space of its
+//! socket, and the count of bytes it has consumed from it.  A peer sends data
+//! only while the other has room for it.  Data sent by the host is held until
+//! the driver posts buffers on the RX queue, and the guest has credit for it.
+//!
+//! Connections do not survive migration: the guest is instead sent a transport
+//! reset event once the instance resumes, upon which it closes its sockets.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::num::NonZeroU16;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState, Transport};
+use super::queue::{read_buf, write_buf, Chain, VirtQueue, VirtQueues};
+use super::{VirtioDevice, VqChange};
+use bits::*;
+
+use tokio::sync::{mpsc, Notify};
+
+/// CID by which the guest addresses the host
+pub const VSOCK_HOST_CID: u64 = 2;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+const EVENT_QUEUE: u16 = 2;
+
+/// Buffer space of the host's end of each connection, in each direction
+const HOST_BUF_ALLOC: u32 = 256 * 1024;
+
+/// Largest payload placed in a single packet to the guest
+const MAX_PKT_PAYLOAD: usize = 64 * 1024;
+
+/// Number of resets, in reply to packets from the guest, which may await
+/// buffers from the driver
+const MAX_PENDING_RESETS: usize = 256;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    ty: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+impl Header {
+    fn parse(buf: &[u8; VIRTIO_VSOCK_HDR_LEN]) -> Self {
+        let u16_at = |off: usize| {
+            u16::from_le_bytes(buf[off..off + 2].try_into().unwrap())
+        };
+        let u32_at = |off: usize| {
+            u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+        };
+        let u64_at = |off: usize| {
+            u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+        };
+        Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            ty: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; VIRTIO_VSOCK_HDR_LEN] {
+        let mut buf = [0u8; VIRTIO_VSOCK_HDR_LEN];
+        buf[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.len.to_le_bytes());
+        buf[28..30].copy_from_slice(&self.ty.to_le_bytes());
+        buf[30..32].copy_from_slice(&self.op.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        buf
+    }
+
+    /// A reset in reply to `self`, from the endpoint it was addressed to
+    fn reset_reply(&self) -> Self {
+        Self {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            ty: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_RST,
+            ..Default::default()
+        }
+    }
+}
+
+/// Ports of the guest and host at either end of a connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct ConnKey {
+    guest_port: u32,
+    host_port: u32,
+}
+
+struct Conn {
+    /// Distinguishes this connection from any earlier one between the same
+    /// ports, whose host handle may linger
+    id: u64,
+    /// Data sent by the guest, awaiting the host
+    from_guest: VecDeque<u8>,
+    /// Data sent by the host, awaiting delivery to the guest
+    to_guest: VecDeque<u8>,
+    /// Bytes of data sent to the guest
+    tx_cnt: u32,
+    /// Bytes of the guest's data consumed by the host
+    fwd_cnt: u32,
+    /// The buffer space of the guest's socket, and the bytes it has consumed,
+    /// as last reported by the guest
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// A response to the guest's connection request is due
+    respond: bool,
+    /// The guest is due an update of the host's credit
+    credit_update: bool,
+    /// The guest will send no more data
+    guest_done: bool,
+    /// The guest will receive no more data
+    guest_closed: bool,
+    /// The host has dropped its handle, and is to shut the connection down
+    /// once its remaining data is delivered
+    host_closed: bool,
+    shutdown_sent: bool,
+    /// The connection is reset, or fully shut down
+    reset: bool,
+    /// Wakes the host's handle upon any change to the connection
+    notify: Arc<Notify>,
+}
+impl Conn {
+    fn new(id: u64, hdr: &Header) -> Self {
+        Self {
+            id,
+            from_guest: VecDeque::new(),
+            to_guest: VecDeque::new(),
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            peer_buf_alloc: hdr.buf_alloc,
+            peer_fwd_cnt: hdr.fwd_cnt,
+            respond: true,
+            credit_update: false,
+            guest_done: false,
+            guest_closed: false,
+            host_closed: false,
+            shutdown_sent: false,
+            reset: false,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Bytes of data the guest has room for
+    fn peer_credit(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    fn set_reset(&mut self) {
+        self.reset = true;
+        self.to_guest.clear();
+        self.notify.notify_one();
+    }
+}
+
+/// A packet due to be sent to the guest
+enum Output {
+    /// A reset in reply to a packet from the guest
+    Reset,
+    Response(ConnKey),
+    Data(ConnKey),
+    CreditUpdate(ConnKey),
+    Shutdown(ConnKey),
+}
+
+#[derive(Default)]
+struct VsockState {
+    listeners: HashMap<u32, mpsc::UnboundedSender<VsockConn>>,
+    conns: HashMap<ConnKey, Conn>,
+    /// Resets awaiting buffers from the driver
+    resets: VecDeque<Header>,
+    /// A transport reset event awaits delivery on the event queue
+    reset_event: bool,
+    next_id: u64,
+    paused: bool,
+}
+impl VsockState {
+    fn queue_reset(&mut self, hdr: &Header) {
+        if hdr.op != VIRTIO_VSOCK_OP_RST
+            && self.resets.len() < MAX_PENDING_RESETS
+        {
+            self.resets.push_back(hdr.reset_reply());
+        }
+    }
+
+    /// Drops every connection, as the guest's end of them is gone.
+    fn clear_conns(&mut self) {
+        for (_, conn) in self.conns.drain() {
+            conn.notify.notify_one();
+        }
+        self.resets.clear();
+    }
+
+    fn next_output(&self) -> Option<Output> {
+        if !self.resets.is_empty() {
+            return Some(Output::Reset);
+        }
+        for (key, conn) in self.conns.iter().filter(|(_, c)| !c.reset) {
+            if conn.respond {
+                return Some(Output::Response(*key));
+            }
+            if !conn.to_guest.is_empty() && conn.peer_credit() > 0 {
+                return Some(Output::Data(*key));
+            }
+            if conn.credit_update {
+                return Some(Output::CreditUpdate(*key));
+            }
+            if conn.host_closed
+                && !conn.shutdown_sent
+                && conn.to_guest.is_empty()
+            {
+                return Some(Output::Shutdown(*key));
+            }
+        }
+        None
+    }
+}
+
+pub struct PciVirtioVsock {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    guest_cid: u64,
+    state: Mutex<VsockState>,
+    this: Weak<Self>,
+}
+impl PciVirtioVsock {
+    pub fn new(queue_size: u16, guest_cid: u64) -> Arc<Self> {
+        // The RX, TX, and event queues
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(3).unwrap(),
+        );
+        let msix_count = Some(4);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_VSOCK,
+            VIRTIO_SUB_DEV_VSOCK,
+            pci::bits::CLASS_COMMUNICATION,
+            VIRTIO_VSOCK_CFG_SIZE,
+            Transport::Transitional,
+        );
+        Arc::new_cyclic(|this| Self {
+            virtio_state,
+            pci_state,
+            guest_cid,
+            state: Mutex::new(VsockState::default()),
+            this: this.clone(),
+        })
+    }
+
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    /// Listen for connections from the guest to `port` of the host.  Returns
+    /// `None` if another listener is open on the port.
+    pub fn listen(&self, port: u32) -> Option<VsockListener> {
+        let mut state = self.state.lock().unwrap();
+        if state.listeners.get(&port).is_some_and(|tx| !tx.is_closed()) {
+            return None;
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        state.listeners.insert(port, tx);
+        Some(VsockListener { dev: self.this.clone(), port, rx })
+    }
+
+    /// Process the packets sent by the guest on the TX queue.
+    fn process_tx(&self, vq: &VirtQueue, mem: &MemCtx) {
+        let mut accepted = Vec::new();
+        let mut state = self.state.lock().unwrap();
+        let mut chain = Chain::with_capacity(4);
+        let mut payload = vec![0u8; MAX_PKT_PAYLOAD];
+        while vq.pop_avail(&mut chain, mem).is_some() {
+            let mut buf = [0u8; VIRTIO_VSOCK_HDR_LEN];
+            if chain.read(&mut buf, mem) {
+                let hdr = Header::parse(&buf);
+                // Payload beyond what the device accepts is left unread,
+                // leaving the packet short of its stated length, for which
+                // its connection is reset.
+                let len = (hdr.len as usize).min(MAX_PKT_PAYLOAD);
+                let len = read_buf(&mut payload[..len], &mut chain, mem);
+                self.handle_packet(
+                    &mut state,
+                    &hdr,
+                    &payload[..len],
+                    &mut accepted,
+                );
+            }
+            vq.push_used(&mut chain, mem);
+        }
+        self.deliver(&mut state);
+        drop(state);
+
+        // Connections are handed to their listeners with the state unlocked,
+        // as one dropped by a listener which has gone away must lock it to
+        // be closed.
+        for (tx, conn) in accepted {
+            let _ = tx.send(conn);
+        }
+    }
+
+    fn handle_packet(
+        &self,
+        state: &mut VsockState,
+        hdr: &Header,
+        payload: &[u8],
+        accepted: &mut Vec<(mpsc::UnboundedSender<VsockConn>, VsockConn)>,
+    ) {
+        if hdr.src_cid != self.guest_cid
+            || hdr.dst_cid != VSOCK_HOST_CID
+            || hdr.ty != VIRTIO_VSOCK_TYPE_STREAM
+        {
+            state.queue_reset(hdr);
+            return;
+        }
+        let key = ConnKey { guest_port: hdr.src_port, host_port: hdr.dst_port };
+
+        if hdr.op == VIRTIO_VSOCK_OP_REQUEST {
+            let listener = state
+                .listeners
+                .get(&key.host_port)
+                .filter(|tx| !tx.is_closed())
+                .cloned();
+            let Some(tx) = listener else {
+                state.queue_reset(hdr);
+                return;
+            };
+            if let Some(old) = state.conns.get_mut(&key) {
+                // The guest has moved on from any earlier connection
+                old.set_reset();
+            }
+            let id = state.next_id;
+            state.next_id += 1;
+            let conn = Conn::new(id, hdr);
+            let handle = VsockConn {
+                dev: self.this.clone(),
+                key,
+                id,
+                notify: conn.notify.clone(),
+            };
+            state.conns.insert(key, conn);
+            accepted.push((tx, handle));
+            return;
+        }
+
+        let Some(conn) = state.conns.get_mut(&key).filter(|c| !c.reset) else {
+            state.queue_reset(hdr);
+            return;
+        };
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+        let reply_reset = match hdr.op {
+            VIRTIO_VSOCK_OP_RW => {
+                let room = HOST_BUF_ALLOC as usize - conn.from_guest.len();
+                // A guest sending more than it was given credit for (or after
+                // shutting down) is reset, as is one whose data did not
+                // arrive whole, rather than passing on a corrupted stream.
+                let overrun = conn.guest_done
+                    || payload.len() > room
+                    || payload.len() != hdr.len as usize;
+                if !overrun {
+                    conn.from_guest.extend(payload);
+                }
+                overrun
+            }
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => false,
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                conn.credit_update = true;
+                false
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                conn.guest_done |= hdr.flags & VIRTIO_VSOCK_SHUTDOWN_SEND != 0;
+                if hdr.flags & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 {
+                    conn.guest_closed = true;
+                    conn.to_guest.clear();
+                }
+                // Once neither end has anything more to say, the guest awaits
+                // a reset to finish closing its socket.
+                conn.guest_done && conn.guest_closed
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                conn.set_reset();
+                false
+            }
+            _ => true,
+        };
+        if reply_reset {
+            conn.set_reset();
+        }
+        conn.notify.notify_one();
+        if conn.reset && conn.host_closed {
+            state.conns.remove(&key);
+        }
+        if reply_reset {
+            state.queue_reset(hdr);
+        }
+    }
+
+    /// Write pending packets into the buffers posted by the driver.
+    fn deliver(&self, state: &mut VsockState) {
+        if state.paused {
+            return;
+        }
+        let rxq = self.virtio_state.queues.get(RX_QUEUE).unwrap();
+        let Some(mem) = rxq.acc_mem.access() else {
+            return;
+        };
+
+        let mut chain = Chain::with_capacity(4);
+        if state.reset_event {
+            let evq = self.virtio_state.queues.get(EVENT_QUEUE).unwrap();
+            if evq.pop_avail(&mut chain, &mem).is_some() {
+                chain.write(&VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.to_le(), &mem);
+                evq.push_used(&mut chain, &mem);
+                state.reset_event = false;
+            }
+        }
+
+        while let Some(output) = state.next_output() {
+            if rxq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+            let room =
+                chain.remain_write_bytes().saturating_sub(VIRTIO_VSOCK_HDR_LEN);
+            let (hdr, payload) = self.take_output(state, output, room);
+            chain.write(&hdr.to_bytes(), &mem);
+            write_buf(&payload, &mut chain, &mem);
+            rxq.push_used(&mut chain, &mem);
+        }
+    }
+
+    /// Remove `output` from the packets pending for the guest, returning its
+    /// header and payload, which is at most `room` bytes.
+    fn take_output(
+        &self,
+        state: &mut VsockState,
+        output: Output,
+        room: usize,
+    ) -> (Header, Vec<u8>) {
+        let key = match output {
+            Output::Reset => {
+                return (state.resets.pop_front().unwrap(), Vec::new())
+            }
+            Output::Response(key)
+            | Output::Data(key)
+            | Output::CreditUpdate(key)
+            | Output::Shutdown(key) => key,
+        };
+        let conn = state.conns.get_mut(&key).unwrap();
+        let mut payload = Vec::new();
+        let (op, flags) = match output {
+            Output::Response(_) => {
+                conn.respond = false;
+                (VIRTIO_VSOCK_OP_RESPONSE, 0)
+            }
+            Output::Data(_) => {
+                let len = conn
+                    .to_guest
+                    .len()
+                    .min(conn.peer_credit() as usize)
+                    .min(MAX_PKT_PAYLOAD)
+                    .min(room);
+                payload.extend(conn.to_guest.drain(..len));
+                conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+                // The host may have been waiting for room to send more
+                conn.notify.notify_one();
+                if len == 0 {
+                    // A buffer too small for any data still carries credit
+                    (VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0)
+                } else {
+                    (VIRTIO_VSOCK_OP_RW, 0)
+                }
+            }
+            Output::CreditUpdate(_) => (VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0),
+            Output::Shutdown(_) => {
+                conn.shutdown_sent = true;
+                (
+                    VIRTIO_VSOCK_OP_SHUTDOWN,
+                    VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND,
+                )
+            }
+            Output::Reset => unreachable!(),
+        };
+        // Every packet carries the host's credit
+        conn.credit_update = false;
+        let hdr = Header {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: key.host_port,
+            dst_port: key.guest_port,
+            len: payload.len() as u32,
+            ty: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: HOST_BUF_ALLOC,
+            fwd_cnt: conn.fwd_cnt,
+        };
+        (hdr, payload)
+    }
+
+    /// Apply `f` to the connection of the host's handle with `key` and `id`,
+    /// if it remains, then deliver any packets due to the guest as a result.
+    fn with_conn<R>(
+        &self,
+        key: ConnKey,
+        id: u64,
+        f: impl FnOnce(Option<&mut Conn>) -> R,
+    ) -> R {
+        let mut state = self.state.lock().unwrap();
+        let res = f(state.conns.get_mut(&key).filter(|c| c.id == id));
+        self.deliver(&mut state);
+        res
+    }
+}
+
+impl VirtioDevice for PciVirtioVsock {
+    fn cfg_rw(&self, rwo: RWOp) {
+        match rwo {
+            RWOp::Read(ro) => ro.write_from(&self.guest_cid.to_le_bytes()),
+            // The guest's CID is fixed by the device
+            RWOp::Write(_) => {}
+        }
+    }
+    fn get_features(&self) -> u32 {
+        0
+    }
+    fn set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        match vq.id {
+            TX_QUEUE => self.process_tx(vq, &mem),
+            // Buffers posted for received packets or events
+            _ => {
+                let mut state = self.state.lock().unwrap();
+                self.deliver(&mut state);
+            }
+        }
+    }
+
+    fn queue_change(&self, vq: &Arc<VirtQueue>, change: VqChange) {
+        if vq.id == RX_QUEUE && matches!(change, VqChange::Reset) {
+            // The driver's sockets are gone with its (re)initialization
+            self.state.lock().unwrap().clear_conns();
+        }
+    }
+}
+impl PciVirtio for PciVirtioVsock {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+impl Entity for PciVirtioVsock {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-vsock"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+        self.state.lock().unwrap().reset_event = false;
+    }
+    fn pause(&self) {
+        self.state.lock().unwrap().paused = true;
+    }
+    fn resume(&self) {
+        // Data (or a transport reset) may have arrived while paused
+        let mut state = self.state.lock().unwrap();
+        state.paused = false;
+        self.deliver(&mut state);
+    }
+    fn migrate(&self) -> Migrator {
+        Migrator::Multi(self)
+    }
+}
+impl MigrateMulti for PciVirtioVsock {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::export(self, output, ctx)
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)?;
+
+        // The guest's connections were to the source's host, and cannot be
+        // carried over.  It is told so as it resumes.
+        let mut state = self.state.lock().unwrap();
+        state.clear_conns();
+        state.reset_event = true;
+        Ok(())
+    }
+}
+
+/// A listener for connections from the guest to a port of the host
+///
+/// The port is freed when the listener is dropped.
+pub struct VsockListener {
+    dev: Weak<PciVirtioVsock>,
+    port: u32,
+    rx: mpsc::UnboundedReceiver<VsockConn>,
+}
+impl VsockListener {
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Wait for the next connection from the guest.  Returns `None` if the
+    /// device is gone.
+    pub async fn accept(&mut self) -> Option<VsockConn> {
+        self.rx.recv().await
+    }
+}
+impl Drop for VsockListener {
+    fn drop(&mut self) {
+        self.rx.close();
+        if let Some(dev) = self.dev.upgrade() {
+            let mut state = dev.state.lock().unwrap();
+            if state.listeners.get(&self.port).is_some_and(|tx| tx.is_closed())
+            {
+                state.listeners.remove(&self.port);
+            }
+        }
+        // Close any connections accepted before the listener was
+        while self.rx.try_recv().is_ok() {}
+    }
+}
+
+/// The host's end of a connection from the guest
+///
+/// The connection is shut down when this is dropped, once the data sent to the
+/// guest has been delivered.
+pub struct VsockConn {
+    dev: Weak<PciVirtioVsock>,
+    key: ConnKey,
+    id: u64,
+    notify: Arc<Notify>,
+}
+impl VsockConn {
+    /// The port of the guest from which the connection was made
+    pub fn guest_port(&self) -> u32 {
+        self.key.guest_port
+    }
+
+    /// The port of the host to which the connection was made
+    pub fn port(&self) -> u32 {
+        self.key.host_port
+    }
+
+    /// Wait for a change in the state of the connection: the arrival of data
+    /// from the guest, room for more data to the guest, or the connection
+    /// closing.
+    pub async fn changed(&self) {
+        self.notify.notified().await
+    }
+
+    /// Read data sent by the guest into `buf`.
+    ///
+    /// Returns the number of bytes read, which is 0 once the guest has shut
+    /// down its end and all of its data has been read, or an error of kind
+    /// [`io::ErrorKind::WouldBlock`] if no data is available.  An error of kind
+    /// [`io::ErrorKind::ConnectionReset`] indicates that the connection has
+    /// been reset, and any of its unread data is lost.
+    pub fn try_recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let dev = self.dev.upgrade().ok_or(io::ErrorKind::ConnectionReset)?;
+        dev.with_conn(self.key, self.id, |conn| {
+            let conn = conn.ok_or(io::ErrorKind::ConnectionReset)?;
+            let len = buf.len().min(conn.from_guest.len());
+            if len == 0 {
+                return if conn.guest_done {
+                    Ok(0)
+                } else if conn.reset {
+                    Err(io::ErrorKind::ConnectionReset.into())
+                } else if buf.is_empty() {
+                    Ok(0)
+                } else {
+                    Err(io::ErrorKind::WouldBlock.into())
+                };
+            }
+            for (dst, src) in buf.iter_mut().zip(conn.from_guest.drain(..len)) {
+                *dst = src;
+            }
+            conn.fwd_cnt = conn.fwd_cnt.wrapping_add(len as u32);
+            // The guest learns of the host's consumption along with any data
+            // sent its way, or else once the host's buffer is drained.
+            conn.credit_update |= conn.from_guest.is_empty() && !conn.reset;
+            Ok(len)
+        })
+    }
+
+    /// Send as much of `data` to the guest as there is room for.
+    ///
+    /// Returns the number of bytes accepted, or an error of kind
+    /// [`io::ErrorKind::WouldBlock`] if there is no room.  An error of kind
+    /// [`io::ErrorKind::BrokenPipe`] indicates that the guest will receive no
+    /// more data.
+    pub fn try_send(&self, data: &[u8]) -> io::Result<usize> {
+        let dev = self.dev.upgrade().ok_or(io::ErrorKind::BrokenPipe)?;
+        dev.with_conn(self.key, self.id, |conn| {
+            let conn = conn
+                .filter(|c| !c.reset && !c.guest_closed)
+                .ok_or(io::ErrorKind::BrokenPipe)?;
+            let room = HOST_BUF_ALLOC as usize - conn.to_guest.len();
+            let len = data.len().min(room);
+            if len == 0 && !data.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            conn.to_guest.extend(&data[..len]);
+            Ok(len)
+        })
+    }
+}
+impl Drop for VsockConn {
+    fn drop(&mut self) {
+        let Some(dev) = self.dev.upgrade() else {
+            return;
+        };
+        let mut state = dev.state.lock().unwrap();
+        match state.conns.get_mut(&self.key) {
+            Some(conn) if conn.id == self.id && conn.reset => {
+                // Nothing remains to be said to the guest
+                state.conns.remove(&self.key);
+            }
+            Some(conn) if conn.id == self.id => {
+                conn.host_closed = true;
+                dev.deliver(&mut state);
+            }
+            _ => {}
+        }
+    }
+}
+
+mod bits {
+    #![allow(unused)]
+
+    /// The guest's CID, as a little-endian `u64`
+    pub const VIRTIO_VSOCK_CFG_SIZE: usize = 8;
+
+    pub const VIRTIO_VSOCK_HDR_LEN: usize = 44;
+
+    pub const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+    pub const VIRTIO_VSOCK_OP_INVALID: u16 = 0;
+    pub const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+    pub const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+    pub const VIRTIO_VSOCK_OP_RST: u16 = 3;
+    pub const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+    pub const VIRTIO_VSOCK_OP_RW: u16 = 5;
+    pub const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+    pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+    pub const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+    pub const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+    pub const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GUEST_CID: u64 = 3;
+
+    fn guest_packet(op: u16, guest_port: u32, host_port: u32) -> Header {
+        Header {
+            src_cid: GUEST_CID,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: guest_port,
+            dst_port: host_port,
+            ty: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: 0x1000,
+            ..Default::default()
+        }
+    }
+
+    /// Hand `hdr` and `payload` to the device as if sent on the TX queue,
+    /// returning any connections accepted.
+    fn send(
+        dev: &PciVirtioVsock,
+        hdr: Header,
+        payload: &[u8],
+    ) -> Vec<VsockConn> {
+        let mut accepted = Vec::new();
+        let mut state = dev.state.lock().unwrap();
+        dev.handle_packet(&mut state, &hdr, payload, &mut accepted);
+        drop(state);
+        accepted.into_iter().map(|(_tx, conn)| conn).collect()
+    }
+
+    #[test]
+    fn header_layout() {
+        let hdr = Header {
+            len: 5,
+            flags: VIRTIO_VSOCK_SHUTDOWN_SEND,
+            fwd_cnt: 0x1234,
+            ..guest_packet(VIRTIO_VSOCK_OP_RW, 1024, 80)
+        };
+        let buf = hdr.to_bytes();
+        assert_eq!(buf[8..16], VSOCK_HOST_CID.to_le_bytes());
+        assert_eq!(buf[30..32], VIRTIO_VSOCK_OP_RW.to_le_bytes());
+        assert_eq!(buf[40..44], 0x1234u32.to_le_bytes());
+        assert_eq!(Header::parse(&buf), hdr);
+    }
+
+    #[test]
+    fn refuses_unheard_port() {
+        let dev = PciVirtioVsock::new(0x10, GUEST_CID);
+        let req = guest_packet(VIRTIO_VSOCK_OP_REQUEST, 1024, 80);
+        assert!(send(&dev, req, &[]).is_empty());
+
+        let state = dev.state.lock().unwrap();
+        assert_eq!(state.resets.len(), 1);
+        let rst = state.resets[0];
+        assert_eq!(rst.op, VIRTIO_VSOCK_OP_RST);
+        assert_eq!((rst.src_port, rst.dst_port), (80, 1024));
+        assert_eq!(rst.dst_cid, GUEST_CID);
+    }
+
+    #[test]
+    fn one_listener_per_port() {
+        let dev = PciVirtioVsock::new(0x10, GUEST_CID);
+        let listener = dev.listen(80).unwrap();
+        assert!(dev.listen(80).is_none());
+        drop(listener);
+        assert!(dev.listen(80).is_some());
+    }
+
+    #[test]
+    fn exchanges_data() {
+        let dev = PciVirtioVsock::new(0x10, GUEST_CID);
+        let _listener = dev.listen(80).unwrap();
+        let req = guest_packet(VIRTIO_VSOCK_OP_REQUEST, 1024, 80);
+        let conn = send(&dev, req, &[]).pop().unwrap();
+        assert_eq!((conn.guest_port(), conn.port()), (1024, 80));
+        assert!(matches!(
+            dev.state.lock().unwrap().next_output(),
+            Some(Output::Response(_))
+        ));
+
+        let mut buf = [0u8; 16];
+        let err = conn.try_recv(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        let rw =
+            Header { len: 5, ..guest_packet(VIRTIO_VSOCK_OP_RW, 1024, 80) };
+        send(&dev, rw, b"hello");
+        assert_eq!(conn.try_recv(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        // The host may send no more than the guest has room for
+        let data = vec![0u8; HOST_BUF_ALLOC as usize * 2];
+        assert_eq!(conn.try_send(&data).unwrap(), HOST_BUF_ALLOC as usize);
+        let err = conn.try_send(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let mut state = dev.state.lock().unwrap();
+        state.conns.values_mut().for_each(|c| c.respond = false);
+        let Some(Output::Data(key)) = state.next_output() else {
+            panic!("expected data to be due to the guest");
+        };
+        let (hdr, payload) =
+            dev.take_output(&mut state, Output::Data(key), 0x10000);
+        assert_eq!(hdr.op, VIRTIO_VSOCK_OP_RW);
+        assert_eq!(hdr.fwd_cnt, 5);
+        assert_eq!(payload.len(), 0x1000);
+        assert!(!matches!(state.next_output(), Some(Output::Data(_))));
+        drop(state);
+
+        // Once the guest shuts down its end, the host reads to the end
+        let shutdown = Header {
+            flags: VIRTIO_VSOCK_SHUTDOWN_SEND,
+            ..guest_packet(VIRTIO_VSOCK_OP_SHUTDOWN, 1024, 80)
+        };
+        send(&dev, shutdown, &[]);
+        assert_eq!(conn.try_recv(&mut buf).unwrap(), 0);
+
+        let rst = guest_packet(VIRTIO_VSOCK_OP_RST, 1024, 80);
+        send(&dev, rst, &[]);
+        let err = conn.try_send(b"more").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        drop(conn);
+        assert!(dev.state.lock().unwrap().conns.is_empty());
+    }
+
+    #[test]
+    fn resets_short_packet() {
+        let dev = PciVirtioVsock::new(0x10, GUEST_CID);
+        let _listener = dev.listen(80).unwrap();
+        let req = guest_packet(VIRTIO_VSOCK_OP_REQUEST, 1024, 80);
+        let conn = send(&dev, req, &[]).pop().unwrap();
+
+        // Less payload than the header claims, as when it is too large to
+        // be taken in whole, is not passed on
+        let rw = Header {
+            len: MAX_PKT_PAYLOAD as u32 + 1,
+            ..guest_packet(VIRTIO_VSOCK_OP_RW, 1024, 80)
+        };
+        send(&dev, rw, b"hello");
+        let mut buf = [0u8; 16];
+        let err = conn.try_recv(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let state = dev.state.lock().unwrap();
+        assert_eq!(state.resets.len(), 1);
+        assert_eq!(state.resets[0].op, VIRTIO_VSOCK_OP_RST);
+    }
+}
@@ -4,9 +4,10 @@
 
 //! Structures related VM instances management.
 
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::Arc;
 
 use crate::inventory::Inventory;
+use crate::util::lockorder::{Mutex, MutexGuard, Rank};
 use crate::vmm::Machine;
 
 struct Inner {
@@ -27,7 +28,10 @@ impl Instance {
             inv.register_instance(vcpu, vcpu.cpuid()).unwrap();
         }
 
-        Self(Mutex::new(Inner { machine: Some(machine), inventory: inv }))
+        Self(Mutex::new(
+            Rank::Machine,
+            Inner { machine: Some(machine), inventory: inv },
+        ))
     }
 
     pub fn lock(&self) -> InstanceGuard {
@@ -10,6 +10,9 @@ use crate::vmm::VmmHdl;
 
 const PIN_COUNT: u8 = 16;
 
+/// Number of pins on the bhyve IOAPIC
+const IOAPIC_PIN_COUNT: u8 = 32;
+
 pub trait IntrPin: Send + Sync + 'static {
     fn assert(&self);
     fn deassert(&self);
@@ -51,10 +54,11 @@ struct Inner {
 #[derive(Default, Copy, Clone)]
 struct Entry {
     level: usize,
+    asserts: u64,
 }
 impl Entry {
     fn process_op(&mut self, op: &PinOp) -> bool {
-        match op {
+        let notify = match op {
             PinOp::Assert => {
                 self.level += 1;
                 // Notify if going 0->1
@@ -70,7 +74,27 @@ impl Entry {
                 // Notify if going 0->1->0
                 self.level == 0
             }
+        };
+        if notify && !matches!(op, PinOp::Deassert) {
+            self.asserts += 1;
         }
+        notify
+    }
+}
+
+/// State of a pin on the [`LegacyPIC`] or [`IoApic`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PinState {
+    pub irq: u8,
+    /// Number of sources currently asserting the pin
+    pub level: usize,
+    /// Number of rising edges (including pulses) delivered to the interrupt
+    /// controllers via the pin
+    pub asserts: u64,
+}
+impl PinState {
+    pub fn is_asserted(&self) -> bool {
+        self.level != 0
     }
 }
 
@@ -92,6 +116,34 @@ impl LegacyPIC {
         Some(LegacyPin::new(irq, Arc::downgrade(self)))
     }
 
+    /// Get the state of each of the PIC's pins, ordered by IRQ.
+    pub fn pin_states(&self) -> Vec<PinState> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .pins
+            .iter()
+            .enumerate()
+            .map(|(irq, e)| PinState {
+                irq: irq as u8,
+                level: e.level,
+                asserts: e.asserts,
+            })
+            .collect()
+    }
+
+    /// Drive `irq` directly, as if by a source other than the pin handles
+    /// given out by the PIC, so that interrupt routing logic can be exercised
+    /// in tests.  Deasserting a pin which is not asserted has no effect.
+    #[cfg(test)]
+    pub(crate) fn force(&self, irq: u8, op: PinOp) {
+        if matches!(op, PinOp::Deassert)
+            && self.inner.lock().unwrap().pins[irq as usize].level == 0
+        {
+            return;
+        }
+        self.do_irq(op, irq);
+    }
+
     fn do_irq(&self, op: PinOp, irq: u8) {
         assert!(irq < PIN_COUNT);
 
@@ -155,6 +207,107 @@ impl IntrPin for LegacyPin {
     }
 }
 
+/// Pins of the IOAPIC which are not shared with the legacy PIC.
+///
+/// The first 16 IOAPIC pins correspond to the ISA IRQs, and are driven through
+/// the [`LegacyPIC`] so that both controllers observe them.  The remaining pins
+/// are only reachable by guests using the APIC, and serve as the GSIs to which
+/// PCI INTx lines are routed when the PIC is not in use.
+pub struct IoApic {
+    inner: Mutex<Vec<Entry>>,
+    hdl: Arc<VmmHdl>,
+}
+impl IoApic {
+    /// First GSI which is not shared with an ISA IRQ
+    pub const FIRST_GSI: u8 = PIN_COUNT;
+
+    pub fn new(hdl: Arc<VmmHdl>) -> Arc<Self> {
+        let count = (IOAPIC_PIN_COUNT - Self::FIRST_GSI) as usize;
+        Arc::new(Self { inner: Mutex::new(vec![Entry::default(); count]), hdl })
+    }
+
+    /// Get a handle to the pin for `gsi`, which must not be shared with an ISA
+    /// IRQ.
+    pub fn pin_handle(self: &Arc<Self>, gsi: u8) -> Option<IoApicPin> {
+        if gsi < Self::FIRST_GSI || gsi >= IOAPIC_PIN_COUNT {
+            return None;
+        }
+        Some(IoApicPin {
+            gsi,
+            asserted: Mutex::new(false),
+            ioapic: Arc::downgrade(self),
+        })
+    }
+
+    /// Get the state of each of the IOAPIC's pins, ordered by GSI.
+    pub fn pin_states(&self) -> Vec<PinState> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .iter()
+            .enumerate()
+            .map(|(idx, e)| PinState {
+                irq: Self::FIRST_GSI + idx as u8,
+                level: e.level,
+                asserts: e.asserts,
+            })
+            .collect()
+    }
+
+    fn do_irq(&self, op: PinOp, gsi: u8) {
+        let mut inner = self.inner.lock().unwrap();
+        let ent = &mut inner[(gsi - Self::FIRST_GSI) as usize];
+        if ent.process_op(&op) {
+            match op {
+                PinOp::Assert => self.hdl.ioapic_assert_irq(gsi).unwrap(),
+                PinOp::Deassert => self.hdl.ioapic_deassert_irq(gsi).unwrap(),
+                PinOp::Pulse => self.hdl.ioapic_pulse_irq(gsi).unwrap(),
+            }
+        }
+    }
+}
+
+pub struct IoApicPin {
+    gsi: u8,
+    asserted: Mutex<bool>,
+    ioapic: Weak<IoApic>,
+}
+impl IoApicPin {
+    pub fn gsi(&self) -> u8 {
+        self.gsi
+    }
+}
+impl IntrPin for IoApicPin {
+    fn assert(&self) {
+        let mut asserted = self.asserted.lock().unwrap();
+        if !*asserted {
+            *asserted = true;
+            if let Some(ioapic) = Weak::upgrade(&self.ioapic) {
+                ioapic.do_irq(PinOp::Assert, self.gsi);
+            }
+        }
+    }
+    fn deassert(&self) {
+        let mut asserted = self.asserted.lock().unwrap();
+        if *asserted {
+            *asserted = false;
+            if let Some(ioapic) = Weak::upgrade(&self.ioapic) {
+                ioapic.do_irq(PinOp::Deassert, self.gsi);
+            }
+        }
+    }
+    fn pulse(&self) {
+        let asserted = self.asserted.lock().unwrap();
+        if !*asserted {
+            if let Some(ioapic) = Weak::upgrade(&self.ioapic) {
+                ioapic.do_irq(PinOp::Pulse, self.gsi);
+            }
+        }
+    }
+    fn is_asserted(&self) -> bool {
+        *self.asserted.lock().unwrap()
+    }
+}
+
 /// Interrupt pin which calls a provided function on rising and falling edges.
 ///
 /// The consumer-provided function is called when the pin undergoes a state
@@ -204,3 +357,93 @@ impl IntrPin for NoOpPin {
         false
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(pic: &LegacyPIC, irq: u8) -> PinState {
+        pic.pin_states()[irq as usize]
+    }
+
+    #[test]
+    fn pin_states() {
+        let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
+        let pic = LegacyPIC::new(hdl);
+        assert_eq!(pic.pin_states().len(), PIN_COUNT as usize);
+
+        let pin_a = pic.pin_handle(5).unwrap();
+        let pin_b = pic.pin_handle(5).unwrap();
+
+        pin_a.assert();
+        pin_a.assert();
+        assert_eq!(state(&pic, 5), PinState { irq: 5, level: 1, asserts: 1 });
+
+        // A shared pin remains asserted until all of its sources deassert
+        pin_b.assert();
+        assert_eq!(state(&pic, 5).level, 2);
+        pin_a.deassert();
+        assert!(state(&pic, 5).is_asserted());
+        pin_b.deassert();
+        assert_eq!(state(&pic, 5), PinState { irq: 5, level: 0, asserts: 1 });
+
+        pin_a.pulse();
+        assert_eq!(state(&pic, 5), PinState { irq: 5, level: 0, asserts: 2 });
+
+        // Other pins are unaffected
+        assert!(pic
+            .pin_states()
+            .iter()
+            .filter(|s| s.irq != 5)
+            .all(|s| *s == PinState { irq: s.irq, ..Default::default() }));
+    }
+
+    #[test]
+    fn forced_transitions() {
+        let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
+        let pic = LegacyPIC::new(hdl);
+        let pin = pic.pin_handle(9).unwrap();
+
+        // Spurious deassertions are ignored
+        pic.force(9, PinOp::Deassert);
+        assert_eq!(state(&pic, 9).level, 0);
+
+        pic.force(9, PinOp::Assert);
+        assert_eq!(state(&pic, 9), PinState { irq: 9, level: 1, asserts: 1 });
+
+        // A pulse on an asserted pin delivers no edge
+        pin.pulse();
+        assert_eq!(state(&pic, 9).asserts, 1);
+
+        pic.force(9, PinOp::Deassert);
+        pic.force(9, PinOp::Pulse);
+        assert_eq!(state(&pic, 9), PinState { irq: 9, level: 0, asserts: 2 });
+    }
+
+    #[test]
+    fn ioapic_pins() {
+        let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
+        let ioapic = IoApic::new(hdl);
+        let states = ioapic.pin_states();
+        assert_eq!(states.first().unwrap().irq, IoApic::FIRST_GSI);
+        assert_eq!(states.last().unwrap().irq, IOAPIC_PIN_COUNT - 1);
+
+        // Pins shared with the ISA IRQs are only available through the PIC
+        assert!(ioapic.pin_handle(11).is_none());
+        assert!(ioapic.pin_handle(IOAPIC_PIN_COUNT).is_none());
+
+        let pin_a = ioapic.pin_handle(17).unwrap();
+        let pin_b = ioapic.pin_handle(17).unwrap();
+        let state = |gsi: u8| ioapic.pin_states()[(gsi - 16) as usize];
+
+        pin_a.assert();
+        pin_b.assert();
+        assert_eq!(state(17), PinState { irq: 17, level: 2, asserts: 1 });
+        pin_a.deassert();
+        assert!(state(17).is_asserted());
+        pin_b.deassert();
+        pin_b.pulse();
+        assert_eq!(state(17), PinState { irq: 17, level: 0, asserts: 2 });
+        assert_eq!(state(16), PinState { irq: 16, ..Default::default() });
+    }
+}
@@ -30,6 +30,9 @@ pub enum RegistrationError {
 
     #[error("Cannot insert root into non-empty inventory")]
     NotEmpty,
+
+    #[error("Dependency of {0:?} on {1:?} would form a cycle")]
+    DependencyCycle(EntityID, EntityID),
 }
 
 impl From<RegistrationError> for IoError {
@@ -52,6 +55,9 @@ impl From<RegistrationError> for IoError {
             NotEmpty => {
                 IoError::new(ErrorKind::AlreadyExists, "non-empty inventory")
             }
+            DependencyCycle(_, _) => {
+                IoError::new(ErrorKind::InvalidInput, "dependency cycle")
+            }
         }
     }
 }
@@ -155,6 +161,12 @@ impl Inventory {
         inv.get_by_name(instance_name).map(|rec| Arc::clone(rec.entity()))
     }
 
+    /// Lookup the ID of an entity by its instance name.
+    pub fn get_id_by_name(&self, instance_name: &str) -> Option<EntityID> {
+        let inv = self.inner.lock().unwrap();
+        inv.reverse_name.get(instance_name).copied()
+    }
+
     /// Return a list of entity instance names
     pub fn get_names(&self) -> Vec<String> {
         let inv = self.inner.lock().unwrap();
@@ -169,6 +181,24 @@ impl Inventory {
         inv.deregister(id)
     }
 
+    /// Records that the entity `dependent` relies upon `dependency` (such as a
+    /// device upon its backend, or a UART upon the sink it feeds).
+    ///
+    /// Dependencies are honored by [`Order::Startup`] and [`Order::Teardown`],
+    /// so that an entity is started only after everything it depends upon,
+    /// and is halted before any of it.
+    ///
+    /// Returns an error if either entity is not registered, or if the
+    /// dependency would form a cycle.
+    pub fn add_dependency(
+        &self,
+        dependent: EntityID,
+        dependency: EntityID,
+    ) -> Result<(), RegistrationError> {
+        let mut inv = self.inner.lock().unwrap();
+        inv.add_dependency(dependent, dependency)
+    }
+
     /// Returns true if the inventory is empty.
     pub fn is_empty(&self) -> bool {
         let inv = self.inner.lock().unwrap();
@@ -379,6 +409,41 @@ impl InventoryInner {
         self.reverse_name.keys().cloned().collect()
     }
 
+    /// Records that `dependent` relies upon `dependency`.
+    fn add_dependency(
+        &mut self,
+        dependent: EntityID,
+        dependency: EntityID,
+    ) -> Result<(), RegistrationError> {
+        for id in [dependent, dependency] {
+            if !self.entities.contains_key(&id) {
+                return Err(RegistrationError::MissingEntity(id));
+            }
+        }
+
+        // Refuse the dependency if `dependent` is already among the
+        // (transitive) dependencies of `dependency`.
+        let mut stack = vec![dependency];
+        let mut seen = BTreeSet::new();
+        while let Some(id) = stack.pop() {
+            if id == dependent {
+                return Err(RegistrationError::DependencyCycle(
+                    dependent, dependency,
+                ));
+            }
+            if seen.insert(id) {
+                stack.extend(self.entities[&id].dependencies.iter().copied());
+            }
+        }
+
+        self.entities
+            .get_mut(&dependent)
+            .unwrap()
+            .dependencies
+            .insert(dependency);
+        Ok(())
+    }
+
     /// Removes an entity (and all its children) from the inventory.
     ///
     /// No-op if the entity does not exist.
@@ -400,6 +465,9 @@ impl InventoryInner {
         let entptr = record.ent.object_id();
         self.reverse.remove(&entptr);
         self.reverse_name.remove(record.name());
+        for other in self.entities.values_mut() {
+            other.dependencies.remove(&id);
+        }
 
         // If this entity exists in the parent, remove it.
         //
@@ -466,6 +534,12 @@ pub enum Order {
     Post,
     /// Parent before any child nodes
     Pre,
+    /// Each entity after all of those it depends upon, but otherwise in the
+    /// same order as [`Order::Pre`]
+    Startup,
+    /// The reverse of [`Order::Startup`]: each entity before any of those it
+    /// depends upon
+    Teardown,
 }
 
 struct IterNode<'a> {
@@ -477,22 +551,65 @@ pub struct Iter<'a> {
     inv: &'a InventoryInner,
     stack: Vec<IterNode<'a>>,
     order: Order,
+    /// Entities in dependency order, for [`Order::Startup`] and
+    /// [`Order::Teardown`]
+    sorted: Option<std::vec::IntoIter<EntityID>>,
 }
 
 impl<'a> Iter<'a> {
     fn new(inv: &'a InventoryInner, order: Order) -> Self {
+        let sorted = match order {
+            Order::Startup => Some(Self::dependency_order(inv)),
+            Order::Teardown => {
+                let mut ids = Self::dependency_order(inv);
+                ids.reverse();
+                Some(ids)
+            }
+            _ => None,
+        };
         Self {
             stack: vec![IterNode { post_visit: None, items: inv.roots.iter() }],
             inv,
             order,
+            sorted: sorted.map(Vec::into_iter),
         }
     }
+
+    /// Walks the inventory in pre-order, emitting the (not yet emitted)
+    /// dependencies of each entity ahead of it.
+    fn dependency_order(inv: &InventoryInner) -> Vec<EntityID> {
+        fn visit(
+            inv: &InventoryInner,
+            id: EntityID,
+            done: &mut BTreeSet<EntityID>,
+            out: &mut Vec<EntityID>,
+        ) {
+            if !done.insert(id) {
+                return;
+            }
+            for dep in inv.entities[&id].dependencies.iter() {
+                visit(inv, *dep, done, out);
+            }
+            out.push(id);
+        }
+
+        let mut done = BTreeSet::new();
+        let mut out = Vec::with_capacity(inv.entities.len());
+        for (id, _) in Iter::new(inv, Order::Pre) {
+            visit(inv, id, &mut done, &mut out);
+        }
+        out
+    }
 }
 
 impl<'a> Iterator for Iter<'a> {
     type Item = (EntityID, &'a Record);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(sorted) = self.sorted.as_mut() {
+            let id = sorted.next()?;
+            return Some((id, self.inv.entities.get(&id).unwrap()));
+        }
         while let Some(mut node) = self.stack.pop() {
             if let Some(eid) = node.items.next() {
                 self.stack.push(node);
@@ -537,6 +654,7 @@ pub struct Record {
     ent: Arc<dyn Entity>,
     parent: Option<EntityID>,
     children: BTreeSet<EntityID>,
+    dependencies: BTreeSet<EntityID>,
     name: String,
 }
 
@@ -547,7 +665,14 @@ impl Record {
         name: String,
         parent: Option<EntityID>,
     ) -> Self {
-        Record { any, ent, parent, children: BTreeSet::new(), name }
+        Record {
+            any,
+            ent,
+            parent,
+            children: BTreeSet::new(),
+            dependencies: BTreeSet::new(),
+            name,
+        }
     }
 
     /// Returns the concrete type of a record, or None if the wrong type is
@@ -569,6 +694,11 @@ impl Record {
     pub fn parent(&self) -> Option<EntityID> {
         self.parent
     }
+
+    /// Returns the IDs of the entities which this record depends upon.
+    pub fn dependencies(&self) -> impl Iterator<Item = EntityID> + '_ {
+        self.dependencies.iter().copied()
+    }
 }
 
 /// General trait for emulated devices in the system.
@@ -695,3 +825,55 @@ impl From<EntityID> for u64 {
         id.num
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestEnt(&'static str);
+    impl Entity for TestEnt {
+        fn type_name(&self) -> &'static str {
+            self.0
+        }
+    }
+
+    fn names(inv: &Inventory, order: Order) -> Vec<String> {
+        inv.lock().iter(order).map(|(_, rec)| rec.name().to_string()).collect()
+    }
+
+    #[test]
+    fn dependency_order() {
+        let inv = Inventory::new();
+        let dev = inv.register(&Arc::new(TestEnt("dev"))).unwrap();
+        let be = inv
+            .register_child(
+                ChildRegister::new(&Arc::new(TestEnt("be")), None),
+                dev,
+            )
+            .unwrap();
+        let sink = inv.register(&Arc::new(TestEnt("sink"))).unwrap();
+        let link = inv.register(&Arc::new(TestEnt("link"))).unwrap();
+
+        // Without dependencies, startup follows the pre-order walk
+        assert_eq!(names(&inv, Order::Startup), ["dev", "be", "sink", "link"]);
+
+        inv.add_dependency(dev, be).unwrap();
+        inv.add_dependency(dev, sink).unwrap();
+        inv.add_dependency(be, link).unwrap();
+        assert_eq!(names(&inv, Order::Startup), ["link", "be", "sink", "dev"]);
+        assert_eq!(names(&inv, Order::Teardown), ["dev", "sink", "be", "link"]);
+
+        assert_eq!(
+            inv.add_dependency(link, dev),
+            Err(RegistrationError::DependencyCycle(link, dev))
+        );
+        assert_eq!(
+            inv.add_dependency(link, link),
+            Err(RegistrationError::DependencyCycle(link, link))
+        );
+
+        // Removing an entity drops the dependencies upon it
+        inv.deregister(link).unwrap();
+        assert_eq!(names(&inv, Order::Startup), ["be", "sink", "dev"]);
+    }
+}
@@ -0,0 +1,448 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! CPU feature leveling
+//!
+//! A guest which discovers a CPU feature on one host may come to depend on it,
+//! and so cannot safely be migrated to a host which lacks that feature.  Each
+//! [`Profile`] describes a baseline CPU: the cpuid leaves it reports, its
+//! family, model, and stepping, and the exact set of features advertised
+//! within those leaves.  Any host which supports all of the baseline features
+//! can run a guest with that profile, and guests using the same profile can be
+//! migrated freely between such hosts.
+//!
+//! The model-specific registers accessible to the guest are those implied by
+//! the advertised features (e.g. `IA32_SPEC_CTRL` through IBRS, or `TSC_AUX`
+//! through RDTSCP), so masking the cpuid features also levels the MSRs exposed
+//! by bhyve.  Other MSRs are left to the userspace exit handlers, which treat
+//! them identically regardless of host.
+
+use crate::cpuid::{Entry, Ident, Set, VendorKind};
+
+/// Named baseline CPU profiles
+#[derive(Copy, Clone, Debug, Eq, PartialEq, strum::EnumIter)]
+pub enum Profile {
+    /// Features common to AMD EPYC 7002 (Rome) and later processors
+    BaselineRome,
+    /// Features common to AMD EPYC 7003 (Milan) and later processors
+    BaselineMilan,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LevelError {
+    #[error("profile {0} requires an AMD host")]
+    UnsupportedVendor(&'static str),
+
+    #[error(
+        "host lacks features required by profile {profile} \
+        (leaf {leaf:#x}, {reg}: {missing:#010x})"
+    )]
+    MissingFeatures {
+        profile: &'static str,
+        leaf: u32,
+        reg: &'static str,
+        missing: u32,
+    },
+}
+
+/// How the contents of a leaf are derived
+#[derive(Copy, Clone)]
+enum Rule {
+    /// Copy the leaf from the host
+    Host,
+    /// Report exactly the specified bits (all of which the host must support)
+    /// in each register with a mask, and the host value in the others.
+    Features([Option<u32>; 4]),
+}
+
+struct Def {
+    name: &'static str,
+    brand: &'static str,
+    /// Processor signature (family, model, and stepping), as reported in
+    /// %eax of leaves 0x1 and 0x80000001
+    signature: u32,
+    max_std: u32,
+    max_ext: u32,
+    leaves: &'static [(Ident, Rule)],
+}
+
+/// Encode a processor signature, splitting the family and model into their
+/// base and extended fields
+const fn signature(family: u32, model: u32, stepping: u32) -> u32 {
+    let (base_family, ext_family) =
+        if family >= 0xf { (0xf, family - 0xf) } else { (family, 0) };
+    (ext_family << 20)
+        | ((model >> 4) << 16)
+        | (base_family << 8)
+        | ((model & 0xf) << 4)
+        | stepping
+}
+
+const fn bits(list: &[u32]) -> u32 {
+    let mut val = 0;
+    let mut i = 0;
+    while i < list.len() {
+        val |= 1 << list[i];
+        i += 1;
+    }
+    val
+}
+
+const LEAF_STD_MAX: u32 = 0x0;
+const LEAF_EXT_MAX: u32 = 0x8000_0000;
+const LEAF_EXT_BRAND: [u32; 3] = [0x8000_0002, 0x8000_0003, 0x8000_0004];
+
+/// Leaf 0x1 %ecx: SSE3, PCLMULQDQ, SSSE3, FMA, CX16, SSE4.1, SSE4.2, MOVBE,
+/// POPCNT, AES, XSAVE, AVX, F16C, RDRAND
+const STD1_ECX: u32 =
+    bits(&[0, 1, 9, 12, 13, 19, 20, 22, 23, 25, 26, 28, 29, 30]);
+/// Leaf 0x1 %ecx: hypervisor present
+const STD1_ECX_HV: u32 = 1 << 31;
+/// Leaf 0x1 %edx: FPU, VME, DE, PSE, TSC, MSR, PAE, MCE, CX8, APIC, SEP, MTRR,
+/// PGE, MCA, CMOV, PAT, PSE36, CLFSH, MMX, FXSR, SSE, SSE2, HTT
+const STD1_EDX: u32 = bits(&[
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 11, 12, 13, 14, 15, 16, 17, 19, 23, 24, 25,
+    26, 28,
+]);
+
+/// Leaf 0x7 %ebx: FSGSBASE, BMI1, AVX2, SMEP, BMI2, RDSEED, ADX, SMAP,
+/// CLFLUSHOPT, CLWB, SHA
+const STD7_EBX_ROME: u32 = bits(&[0, 3, 5, 7, 8, 18, 19, 20, 23, 24, 29]);
+/// Leaf 0x7 %ecx: UMIP, RDPID
+const STD7_ECX_ROME: u32 = bits(&[2, 22]);
+/// Leaf 0x7 %ebx: Rome, plus ERMS and INVPCID
+const STD7_EBX_MILAN: u32 = STD7_EBX_ROME | bits(&[9, 10]);
+/// Leaf 0x7 %ecx: Rome, plus PKU, VAES and VPCLMULQDQ
+const STD7_ECX_MILAN: u32 = STD7_ECX_ROME | bits(&[3, 9, 10]);
+/// Leaf 0x7 %edx: FSRM
+const STD7_EDX_MILAN: u32 = bits(&[4]);
+
+/// Leaf 0xD.0 %eax: x87, SSE, and AVX state
+const XCR0_ROME: u32 = bits(&[0, 1, 2]);
+/// Leaf 0xD.0 %eax: Rome, plus PKRU state
+const XCR0_MILAN: u32 = XCR0_ROME | bits(&[9]);
+/// Leaf 0xD.1 %eax: XSAVEOPT, XSAVEC, XGETBV1
+const XSAVE_EXT: u32 = bits(&[0, 1, 2]);
+
+/// Leaf 0x80000001 %ecx: LAHF/SAHF, CmpLegacy, AltMovCr8, ABM, SSE4A,
+/// MisAlignSse, 3DNowPrefetch, TopologyExtensions
+const EXT1_ECX: u32 = bits(&[0, 1, 4, 5, 6, 7, 8, 22]);
+/// Leaf 0x80000001 %edx: legacy features mirrored from leaf 0x1, SYSCALL, NX,
+/// MmxExt, FFXSR, Page1GB, RDTSCP, LM
+const EXT1_EDX: u32 = bits(&[
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 11, 12, 13, 14, 15, 16, 17, 20, 22, 23, 24,
+    25, 26, 27, 29,
+]);
+/// Leaf 0x80000007 %edx: invariant TSC
+const EXT7_EDX: u32 = bits(&[8]);
+/// Leaf 0x80000008 %ebx: CLZERO, RstrFpErrPtrs, WBNOINVD, IBPB, IBRS, STIBP
+const EXT8_EBX: u32 = bits(&[0, 2, 9, 12, 14, 15]);
+
+const NONE: Option<u32> = None;
+
+const ROME: Def = Def {
+    name: "baseline-rome",
+    brand: "AMD EPYC Processor (Rome baseline)",
+    signature: signature(0x17, 0x31, 0),
+    max_std: 0xd,
+    max_ext: 0x8000_001e,
+    leaves: &[
+        (
+            Ident(0x1, None),
+            Rule::Features([NONE, NONE, Some(STD1_ECX), Some(STD1_EDX)]),
+        ),
+        (
+            Ident(0x7, Some(0)),
+            Rule::Features([
+                Some(0),
+                Some(STD7_EBX_ROME),
+                Some(STD7_ECX_ROME),
+                Some(0),
+            ]),
+        ),
+        (
+            Ident(0xd, Some(0)),
+            Rule::Features([Some(XCR0_ROME), NONE, NONE, Some(0)]),
+        ),
+        (
+            Ident(0xd, Some(1)),
+            Rule::Features([Some(XSAVE_EXT), NONE, Some(0), Some(0)]),
+        ),
+        (Ident(0xd, Some(2)), Rule::Host),
+        (
+            Ident(0x8000_0001, None),
+            Rule::Features([NONE, NONE, Some(EXT1_ECX), Some(EXT1_EDX)]),
+        ),
+        (Ident(0x8000_0005, None), Rule::Host),
+        (Ident(0x8000_0006, None), Rule::Host),
+        (
+            Ident(0x8000_0007, None),
+            Rule::Features([Some(0), Some(0), Some(0), Some(EXT7_EDX)]),
+        ),
+        (
+            Ident(0x8000_0008, None),
+            Rule::Features([NONE, Some(EXT8_EBX), NONE, Some(0)]),
+        ),
+        (Ident(0x8000_001d, Some(0)), Rule::Host),
+        (Ident(0x8000_001d, Some(1)), Rule::Host),
+        (Ident(0x8000_001d, Some(2)), Rule::Host),
+        (Ident(0x8000_001d, Some(3)), Rule::Host),
+    ],
+};
+
+const MILAN: Def = Def {
+    name: "baseline-milan",
+    brand: "AMD EPYC Processor (Milan baseline)",
+    signature: signature(0x19, 0x01, 1),
+    max_std: 0xd,
+    max_ext: 0x8000_001e,
+    leaves: &[
+        (
+            Ident(0x1, None),
+            Rule::Features([NONE, NONE, Some(STD1_ECX), Some(STD1_EDX)]),
+        ),
+        (
+            Ident(0x7, Some(0)),
+            Rule::Features([
+                Some(0),
+                Some(STD7_EBX_MILAN),
+                Some(STD7_ECX_MILAN),
+                Some(STD7_EDX_MILAN),
+            ]),
+        ),
+        (
+            Ident(0xd, Some(0)),
+            Rule::Features([Some(XCR0_MILAN), NONE, NONE, Some(0)]),
+        ),
+        (
+            Ident(0xd, Some(1)),
+            Rule::Features([Some(XSAVE_EXT), NONE, Some(0), Some(0)]),
+        ),
+        (Ident(0xd, Some(2)), Rule::Host),
+        (Ident(0xd, Some(9)), Rule::Host),
+        (
+            Ident(0x8000_0001, None),
+            Rule::Features([NONE, NONE, Some(EXT1_ECX), Some(EXT1_EDX)]),
+        ),
+        (Ident(0x8000_0005, None), Rule::Host),
+        (Ident(0x8000_0006, None), Rule::Host),
+        (
+            Ident(0x8000_0007, None),
+            Rule::Features([Some(0), Some(0), Some(0), Some(EXT7_EDX)]),
+        ),
+        (
+            Ident(0x8000_0008, None),
+            Rule::Features([NONE, Some(EXT8_EBX), NONE, Some(0)]),
+        ),
+        (Ident(0x8000_001d, Some(0)), Rule::Host),
+        (Ident(0x8000_001d, Some(1)), Rule::Host),
+        (Ident(0x8000_001d, Some(2)), Rule::Host),
+        (Ident(0x8000_001d, Some(3)), Rule::Host),
+    ],
+};
+
+impl Profile {
+    fn def(self) -> &'static Def {
+        match self {
+            Profile::BaselineRome => &ROME,
+            Profile::BaselineMilan => &MILAN,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        self.def().name
+    }
+
+    /// Render the cpuid leaves for this profile on the current host.
+    pub fn level_host(self) -> Result<Set, LevelError> {
+        self.level(crate::cpuid::host_query)
+    }
+
+    /// Render the cpuid leaves for this profile, using `host` to query the
+    /// values reported by the host CPU.
+    ///
+    /// Fails if the host does not support every feature in the profile.
+    pub fn level(
+        self,
+        host: impl Fn(Ident) -> Entry,
+    ) -> Result<Set, LevelError> {
+        let def = self.def();
+
+        let vendor = host(Ident(LEAF_STD_MAX, None));
+        if !matches!(VendorKind::try_from(vendor), Ok(VendorKind::Amd)) {
+            return Err(LevelError::UnsupportedVendor(def.name));
+        }
+        let mut set = Set::new(VendorKind::Amd);
+        set.insert(
+            Ident(LEAF_STD_MAX, None),
+            Entry { eax: def.max_std, ..vendor },
+        );
+        set.insert(
+            Ident(LEAF_EXT_MAX, None),
+            Entry { eax: def.max_ext, ..host(Ident(LEAF_EXT_MAX, None)) },
+        );
+
+        for (ident, rule) in def.leaves.iter() {
+            let host_ent = host(*ident);
+            let ent = match rule {
+                Rule::Host => host_ent,
+                Rule::Features(masks) => {
+                    let host_regs = [
+                        host_ent.eax,
+                        host_ent.ebx,
+                        host_ent.ecx,
+                        host_ent.edx,
+                    ];
+                    let mut regs = host_regs;
+                    for (i, mask) in masks.iter().enumerate() {
+                        let Some(mask) = mask else {
+                            continue;
+                        };
+                        let missing = mask & !host_regs[i];
+                        if missing != 0 {
+                            return Err(LevelError::MissingFeatures {
+                                profile: def.name,
+                                leaf: ident.0,
+                                reg: ["eax", "ebx", "ecx", "edx"][i],
+                                missing,
+                            });
+                        }
+                        regs[i] = *mask;
+                    }
+                    Entry::from(regs)
+                }
+            };
+            set.insert(*ident, ent);
+        }
+
+        if let Some(ent) = set.get_mut(Ident(0x1, None)) {
+            ent.eax = def.signature;
+            ent.ecx |= STD1_ECX_HV;
+        }
+        // Report the family, model, and stepping of the baseline processor,
+        // rather than those of the host, whose errata and model-specific
+        // behavior the guest might otherwise come to expect.
+        if let Some(ent) = set.get_mut(Ident(0x8000_0001, None)) {
+            ent.eax = def.signature;
+        }
+
+        // Report a brand string which identifies the profile, rather than the
+        // host processor.
+        let mut brand = [0u8; 48];
+        brand[..def.brand.len()].copy_from_slice(def.brand.as_bytes());
+        for (i, leaf) in LEAF_EXT_BRAND.iter().enumerate() {
+            let chunk = &brand[i * 16..(i + 1) * 16];
+            let reg = |n: usize| {
+                u32::from_le_bytes(chunk[n * 4..n * 4 + 4].try_into().unwrap())
+            };
+            set.insert(
+                Ident(*leaf, None),
+                Entry { eax: reg(0), ebx: reg(1), ecx: reg(2), edx: reg(3) },
+            );
+        }
+
+        Ok(set)
+    }
+}
+
+impl std::str::FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use strum::IntoEnumIterator;
+        Profile::iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| format!("unknown CPU profile {s}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // "AuthenticAMD"
+    const AMD_VENDOR: Entry =
+        Entry { eax: 0x10, ebx: 0x68747541, ecx: 0x444d4163, edx: 0x69746e65 };
+
+    fn capable_host(ident: Ident) -> Entry {
+        match ident.0 {
+            LEAF_STD_MAX => AMD_VENDOR,
+            _ => Entry { eax: !0, ebx: !0, ecx: !STD1_ECX_HV, edx: !0 },
+        }
+    }
+
+    #[test]
+    fn masks_to_baseline() {
+        let set = Profile::BaselineRome.level(capable_host).unwrap();
+
+        let max = set.get(Ident(LEAF_STD_MAX, None)).unwrap();
+        assert_eq!(max.eax, 0xd);
+        assert_eq!(max.ebx, AMD_VENDOR.ebx);
+
+        let std1 = set.get(Ident(0x1, None)).unwrap();
+        assert_eq!(std1.ecx, STD1_ECX | STD1_ECX_HV);
+        assert_eq!(std1.edx, STD1_EDX);
+        // Registers without a mask are passed through
+        assert_eq!(std1.ebx, !0);
+        // Family 17h, model 31h, stepping 0
+        assert_eq!(std1.eax, 0x0083_0f10);
+        let ext1 = set.get(Ident(0x8000_0001, None)).unwrap();
+        assert_eq!(ext1.eax, std1.eax);
+
+        let std7 = set.get(Ident(0x7, Some(0))).unwrap();
+        assert_eq!(std7.ebx, STD7_EBX_ROME);
+        assert_eq!(std7.edx, 0);
+
+        // Leaves not in the profile are absent
+        assert!(set.get(Ident(0x8000_000a, None)).is_none());
+
+        let milan = Profile::BaselineMilan.level(capable_host).unwrap();
+        let std7 = milan.get(Ident(0x7, Some(0))).unwrap();
+        assert_eq!(std7.ecx, STD7_ECX_MILAN);
+        // Family 19h, model 01h, stepping 1
+        assert_eq!(milan.get(Ident(0x1, None)).unwrap().eax, 0x00a0_0f11);
+    }
+
+    #[test]
+    fn host_missing_feature() {
+        // A Rome-like host lacks features required by the Milan profile
+        let rome_host = |ident: Ident| match ident {
+            Ident(0x7, Some(0)) => {
+                Entry { eax: 0, ebx: STD7_EBX_ROME, ecx: STD7_ECX_ROME, edx: 0 }
+            }
+            _ => capable_host(ident),
+        };
+        assert!(Profile::BaselineRome.level(rome_host).is_ok());
+        match Profile::BaselineMilan.level(rome_host) {
+            Err(LevelError::MissingFeatures { leaf: 0x7, reg, .. }) => {
+                assert_eq!(reg, "ebx");
+            }
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        let intel_host = |ident: Ident| match ident.0 {
+            // "GenuineIntel"
+            LEAF_STD_MAX => Entry {
+                eax: 0x1b,
+                ebx: 0x756e6547,
+                ecx: 0x6c65746e,
+                edx: 0x49656e69,
+            },
+            _ => capable_host(ident),
+        };
+        assert!(matches!(
+            Profile::BaselineRome.level(intel_host),
+            Err(LevelError::UnsupportedVendor(_))
+        ));
+    }
+
+    #[test]
+    fn profile_names() {
+        assert_eq!(
+            "baseline-milan".parse::<Profile>().unwrap(),
+            Profile::BaselineMilan
+        );
+        assert!("baseline-genoa".parse::<Profile>().is_err());
+    }
+}
@@ -16,19 +16,33 @@ extern crate bitflags;
 
 pub mod accessors;
 pub mod api_version;
+#[cfg(any(test, feature = "bench-hooks"))]
+pub mod bench;
 pub mod block;
 pub mod chardev;
+pub mod clock;
 pub mod common;
 pub mod cpuid;
+pub mod duty;
+pub mod exit_stats;
 pub mod exits;
+pub mod firmware;
+pub mod harden;
+pub mod hostres;
 pub mod hw;
 pub mod instance;
 pub mod intr_pins;
 pub mod inventory;
+pub mod leveling;
 pub mod migrate;
 pub mod mmio;
+pub mod offload;
 pub mod pio;
+pub mod spin;
+pub mod steal;
 pub mod tasks;
+pub mod topology;
+pub mod trace;
 pub mod util;
 pub mod vcpu;
 pub mod vmm;
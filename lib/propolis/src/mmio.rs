@@ -2,11 +2,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use crate::common::*;
+use crate::trace::{self, TraceFlags};
 use crate::util::aspace::ASpace;
 pub use crate::util::aspace::{Error, Result};
+use crate::util::lockorder::Rank;
+use crate::util::snapshot::Snapshot;
 
 use byteorder::{ByteOrder, LE};
 
@@ -18,13 +21,17 @@ mod probes {
 
 pub type MmioFn = dyn Fn(usize, RWOp) + Send + Sync + 'static;
 
+/// MMIO bus.
+///
+/// As with [`PioBus`](crate::pio::PioBus), the address map is kept in a
+/// [`Snapshot`], so dispatch takes no lock.
 pub struct MmioBus {
-    map: Mutex<ASpace<Arc<MmioFn>>>,
+    map: Snapshot<ASpace<Arc<MmioFn>>>,
 }
 impl MmioBus {
     pub fn new(max: usize) -> Self {
         assert!(max != 0);
-        Self { map: Mutex::new(ASpace::new(0, max)) }
+        Self { map: Snapshot::new(Rank::Dispatch, ASpace::new(0, max)) }
     }
 
     pub fn register(
@@ -33,10 +40,10 @@ impl MmioBus {
         len: usize,
         func: Arc<MmioFn>,
     ) -> Result<()> {
-        self.map.lock().unwrap().register(start, len, func)
+        self.map.update(|map| map.register(start, len, func))
     }
     pub fn unregister(&self, addr: usize) -> Result<()> {
-        self.map.lock().unwrap().unregister(addr).map(|_| ())
+        self.map.update(|map| map.unregister(addr).map(|_| ()))
     }
 
     pub fn handle_write(&self, addr: usize, bytes: u8, val: u64) -> Result<()> {
@@ -53,12 +60,14 @@ impl MmioBus {
             func(a, RWOp::Write(&mut wo))
         });
 
-        probes::mmio_write!(|| (
-            addr as u64,
-            bytes,
-            val,
-            handled.is_ok() as u8
-        ));
+        if trace::is_enabled(TraceFlags::MMIO) {
+            probes::mmio_write!(|| (
+                addr as u64,
+                bytes,
+                val,
+                handled.is_ok() as u8
+            ));
+        }
         handled
     }
     pub fn handle_read(&self, addr: usize, bytes: u8) -> Result<u64> {
@@ -76,25 +85,36 @@ impl MmioBus {
         });
 
         let val = LE::read_u64(&buf);
-        probes::mmio_read!(|| (addr as u64, bytes, val, handled.is_ok() as u8));
+        if trace::is_enabled(TraceFlags::MMIO) {
+            probes::mmio_read!(|| (
+                addr as u64,
+                bytes,
+                val,
+                handled.is_ok() as u8
+            ));
+        }
         handled.map(|_| val)
     }
 
+    /// Base address of the region (if any) registered to handle `addr`
+    pub fn region_base(&self, addr: usize) -> Option<usize> {
+        let map = self.map.load();
+        map.region_at(addr).ok().map(|(start, ..)| start)
+    }
+
     fn do_mmio<F>(&self, addr: usize, f: F) -> Result<()>
     where
         F: FnOnce(usize, usize, &Arc<MmioFn>),
     {
-        let map = self.map.lock().unwrap();
+        // The snapshot is held (without locking) while in the handler, which
+        // is free to alter the registrations of the bus.
+        let map = self.map.load();
         let (start, _len, func) = map.region_at(addr)?;
-        let func = Arc::clone(func);
-        // unlock map before entering handler
-        drop(map);
-        f(start, addr - start, &func);
+        f(start, addr - start, func);
         Ok(())
     }
 
     pub(crate) fn clear(&self) {
-        let mut map = self.map.lock().unwrap();
-        map.clear();
+        self.map.update(|map| map.clear());
     }
 }
@@ -0,0 +1,164 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Offloading of long-running emulation from vCPU threads
+//!
+//! An exit is emulated on the thread of the vCPU which took it, so slow work
+//! done by a device handler directly delays that vCPU (and anything in the
+//! guest waiting on it, such as an IPI).  Where the semantics of a device allow
+//! an operation to complete asynchronously, its handler may consult a
+//! [`Budget`] and hand work exceeding it to an [`Offload`], resuming the vCPU
+//! with the operation still pending.
+//!
+//! Work handed to an [`Offload`] may access guest memory, so devices using one
+//! must not report themselves paused until [`Offload::idle()`] resolves.
+
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Condvar, Mutex};
+
+use futures::future::BoxFuture;
+use tokio::sync::watch;
+
+use crate::hostres;
+
+/// Bound on the work a device may perform inline while handling an exit.
+#[derive(Copy, Clone, Debug)]
+pub struct Budget {
+    /// Largest transfer (in bytes) to be performed on the vCPU thread
+    pub max_inline_bytes: usize,
+}
+impl Budget {
+    /// Does the budget permit a transfer of `bytes` to be done inline?
+    pub const fn permits(&self, bytes: usize) -> bool {
+        bytes <= self.max_inline_bytes
+    }
+}
+impl Default for Budget {
+    fn default() -> Self {
+        Self { max_inline_bytes: 64 * 1024 }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    halted: bool,
+}
+
+struct Inner {
+    queue: Mutex<Queue>,
+    cv: Condvar,
+    /// Count of jobs submitted but not yet completed
+    pending: watch::Sender<usize>,
+}
+impl Inner {
+    fn worker_loop(&self) {
+        loop {
+            let mut queue = self.queue.lock().unwrap();
+            let job = loop {
+                if queue.halted {
+                    return;
+                }
+                if let Some(job) = queue.jobs.pop_front() {
+                    break job;
+                }
+                queue = self.cv.wait(queue).unwrap();
+            };
+            drop(queue);
+
+            job();
+            self.pending.send_modify(|n| *n -= 1);
+        }
+    }
+}
+
+/// Pool of worker threads to which emulation work may be offloaded.
+///
+/// The workers exit when the `Offload` is dropped, abandoning any jobs which
+/// have not yet started.
+pub struct Offload {
+    inner: Arc<Inner>,
+    _res_owner: Arc<hostres::Owner>,
+}
+impl Offload {
+    pub fn new(workers: NonZeroUsize) -> std::io::Result<Arc<Self>> {
+        let (pending, _) = watch::channel(0);
+        let inner = Arc::new(Inner {
+            queue: Mutex::new(Queue { jobs: VecDeque::new(), halted: false }),
+            cv: Condvar::new(),
+            pending,
+        });
+        let res_owner = hostres::Owner::new("emulation-offload");
+        for n in 0..workers.get() {
+            let worker_inner = inner.clone();
+            let held = res_owner.hold(hostres::Kind::Thread, 1);
+            let _join = std::thread::Builder::new()
+                .name(format!("offload {n}"))
+                .spawn(move || {
+                    let _held = held;
+                    worker_inner.worker_loop();
+                })?;
+        }
+        Ok(Arc::new(Self { inner, _res_owner: res_owner }))
+    }
+
+    /// Queue `job` to be run on a worker thread.
+    pub fn submit(&self, job: impl FnOnce() + Send + 'static) {
+        self.inner.pending.send_modify(|n| *n += 1);
+        let mut queue = self.inner.queue.lock().unwrap();
+        queue.jobs.push_back(Box::new(job));
+        drop(queue);
+        self.inner.cv.notify_one();
+    }
+
+    /// Number of jobs submitted but not yet completed
+    pub fn pending(&self) -> usize {
+        *self.inner.pending.borrow()
+    }
+
+    /// Returns a future which resolves once no jobs are pending.
+    pub fn idle(&self) -> BoxFuture<'static, ()> {
+        let mut rx = self.inner.pending.subscribe();
+        Box::pin(async move {
+            let _ = rx.wait_for(|n| *n == 0).await;
+        })
+    }
+}
+impl Drop for Offload {
+    fn drop(&mut self) {
+        self.inner.queue.lock().unwrap().halted = true;
+        self.inner.cv.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn budget() {
+        let budget = Budget { max_inline_bytes: 4096 };
+        assert!(budget.permits(0));
+        assert!(budget.permits(4096));
+        assert!(!budget.permits(4097));
+    }
+
+    #[tokio::test]
+    async fn jobs_complete() {
+        let offload = Offload::new(NonZeroUsize::new(2).unwrap()).unwrap();
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..16 {
+            let done = done.clone();
+            offload.submit(move || {
+                done.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        offload.idle().await;
+        assert_eq!(done.load(Ordering::Relaxed), 16);
+        assert_eq!(offload.pending(), 0);
+    }
+}
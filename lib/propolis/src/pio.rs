@@ -2,11 +2,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
 use crate::common::*;
+use crate::trace::{self, TraceFlags};
 use crate::util::aspace::ASpace;
 pub use crate::util::aspace::{Error, Result};
+use crate::util::lockorder::Rank;
+use crate::util::snapshot::Snapshot;
 
 use byteorder::{ByteOrder, LE};
 
@@ -18,14 +22,43 @@ mod probes {
 
 pub type PioFn = dyn Fn(u16, RWOp<'_, '_>) + Send + Sync + 'static;
 
+/// Handler for a write to a doorbell port, receiving the value written
+pub type DoorbellFn = dyn Fn(u16) + Send + Sync + 'static;
+
 /// Port IO bus.
+///
+/// Registrations are rare compared to dispatch, so the routing of ports to
+/// their handlers is kept in a [`Snapshot`], rebuilt on each registration.
+/// Dispatch takes no lock at all: vCPUs concurrently performing port IO
+/// neither serialize on one another nor contend on a shared lock word.
+///
+/// Individual ports may additionally be registered as doorbells: 16-bit writes
+/// to such a port are handed directly to a [`DoorbellFn`], bypassing the
+/// decoding of the region (and register map) containing it.  This is intended
+/// for registers such as virtio queue notifications, where a guest kick needs
+/// to do nothing more than wake the worker servicing the queue.
 pub struct PioBus {
-    map: Mutex<ASpace<Arc<PioFn>>>,
+    routes: Snapshot<Routes>,
+}
+
+/// Routing of ports to their handlers
+#[derive(Clone)]
+struct Routes {
+    map: ASpace<Arc<PioFn>>,
+    doorbells: BTreeMap<u16, Arc<DoorbellFn>>,
+}
+impl Routes {
+    fn new() -> Self {
+        Self {
+            map: ASpace::new(0, u16::MAX as usize),
+            doorbells: BTreeMap::new(),
+        }
+    }
 }
 
 impl PioBus {
     pub fn new() -> Self {
-        Self { map: Mutex::new(ASpace::new(0, u16::MAX as usize)) }
+        Self { routes: Snapshot::new(Rank::Dispatch, Routes::new()) }
     }
 
     pub fn register(
@@ -34,13 +67,41 @@ impl PioBus {
         len: u16,
         func: Arc<PioFn>,
     ) -> Result<()> {
-        self.map.lock().unwrap().register(start as usize, len as usize, func)
+        self.routes.update(|routes| {
+            routes.map.register(start as usize, len as usize, func)
+        })
     }
     pub fn unregister(&self, start: u16) -> Result<()> {
-        self.map.lock().unwrap().unregister(start as usize).map(|_| ())
+        self.routes
+            .update(|routes| routes.map.unregister(start as usize).map(|_| ()))
+    }
+
+    pub fn register_doorbell(
+        &self,
+        port: u16,
+        func: Arc<DoorbellFn>,
+    ) -> Result<()> {
+        self.routes.update(|routes| {
+            if routes.doorbells.contains_key(&port) {
+                return Err(Error::Conflict);
+            }
+            routes.doorbells.insert(port, func);
+            Ok(())
+        })
+    }
+    pub fn unregister_doorbell(&self, port: u16) -> Result<()> {
+        self.routes.update(|routes| {
+            routes.doorbells.remove(&port).map(|_| ()).ok_or(Error::NotFound)
+        })
     }
 
     pub fn handle_out(&self, port: u16, bytes: u8, val: u32) -> Result<()> {
+        if bytes == 2 && self.ring_doorbell(port, val as u16) {
+            if trace::is_enabled(TraceFlags::PIO) {
+                probes::pio_out!(|| (port, bytes, val, 1));
+            }
+            return Ok(());
+        }
         let buf = val.to_le_bytes();
         let data = match bytes {
             1 => &buf[0..1],
@@ -52,7 +113,9 @@ impl PioBus {
             let mut wo = WriteOp::from_buf(o as usize, data);
             func(a, RWOp::Write(&mut wo))
         });
-        probes::pio_out!(|| (port, bytes, val, handled.is_ok() as u8));
+        if trace::is_enabled(TraceFlags::PIO) {
+            probes::pio_out!(|| (port, bytes, val, handled.is_ok() as u8));
+        }
         handled
     }
 
@@ -70,25 +133,46 @@ impl PioBus {
         });
 
         let val = LE::read_u32(&buf);
-        probes::pio_in!(|| (port, bytes, val, handled.is_ok() as u8));
+        if trace::is_enabled(TraceFlags::PIO) {
+            probes::pio_in!(|| (port, bytes, val, handled.is_ok() as u8));
+        }
         handled.map(|_| val)
     }
 
+    fn ring_doorbell(&self, port: u16, val: u16) -> bool {
+        let routes = self.routes.load();
+        if routes.doorbells.is_empty() {
+            return false;
+        }
+        let Some(func) = routes.doorbells.get(&port) else {
+            return false;
+        };
+        func(val);
+        true
+    }
+
+    /// Base port of the region (if any) registered to handle `port`
+    pub fn region_base(&self, port: u16) -> Option<u16> {
+        let routes = self.routes.load();
+        routes.map.region_at(port as usize).ok().map(|(start, ..)| start as u16)
+    }
+
     fn do_pio<F>(&self, port: u16, f: F) -> Result<()>
     where
         F: FnOnce(u16, u16, &Arc<PioFn>),
     {
-        let map = self.map.lock().unwrap();
-        let (start, _len, func) = map.region_at(port as usize)?;
-        let func = Arc::clone(func);
-        // unlock map before entering handler
-        drop(map);
-        f(start as u16, port - start as u16, &func);
+        // The snapshot is held (without locking) while in the handler, which
+        // is free to alter the registrations of the bus.
+        let routes = self.routes.load();
+        let (start, _len, func) = routes.map.region_at(port as usize)?;
+        f(start as u16, port - start as u16, func);
         Ok(())
     }
 
     pub(crate) fn clear(&self) {
-        let mut map = self.map.lock().unwrap();
-        map.clear();
+        self.routes.update(|routes| {
+            routes.map.clear();
+            routes.doorbells.clear();
+        });
     }
 }
@@ -0,0 +1,148 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Directed yielding of vCPUs which exit from guest spin loops
+//!
+//! With PAUSE-loop exiting enabled (see
+//! [`Vcpu::set_pause_exits()`](crate::vcpu::Vcpu::set_pause_exits)), a vCPU
+//! which spins on the PAUSE instruction for long enough, as when waiting on a
+//! contended spinlock, exits to userspace.  On an over-committed host, the
+//! sibling vCPU holding that lock may itself be waiting for a host CPU, and
+//! continuing to spin only delays it further.
+//!
+//! Userspace has no means of handing its host CPU to a particular thread, so
+//! the yield is directed only in the sense that it is taken when some sibling
+//! may be able to use the CPU: one which is not itself spinning.  (Halted
+//! siblings sleep within the kernel, where userspace cannot see them, and so
+//! are counted among those which may.)  When every sibling is spinning, the
+//! vCPU reenters the guest at once.
+//! A vCPU which goes on spinning backs off from plain yields to brief sleeps,
+//! as a yield only cedes the CPU to threads already runnable on it.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// PAUSE exits taken within this long of one another belong to the same spin.
+const SPIN_WINDOW: Duration = Duration::from_micros(100);
+
+/// Number of successive PAUSE exits answered with a plain yield, before the
+/// vCPU backs off to sleeping.
+const YIELD_LIMIT: u32 = 16;
+
+/// Shortest and longest sleeps taken by a vCPU backing off from a spin.
+const BACKOFF_MIN: Duration = Duration::from_micros(20);
+const BACKOFF_MAX: Duration = Duration::from_millis(1);
+
+#[derive(Default)]
+struct Sibling {
+    /// Time of the last PAUSE exit, in nanoseconds since [`SpinYield::base`]
+    /// plus one, or zero if there has been none
+    last_pause: AtomicU64,
+    /// Number of PAUSE exits taken in the current spin
+    streak: AtomicU32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reenter,
+    Yield,
+    Sleep(Duration),
+}
+
+/// Yield policy shared among the vCPUs of a machine
+pub struct SpinYield {
+    base: Instant,
+    vcpus: Vec<Sibling>,
+}
+
+impl SpinYield {
+    pub(crate) fn new(count: usize) -> Self {
+        Self {
+            base: Instant::now(),
+            vcpus: (0..count).map(|_| Sibling::default()).collect(),
+        }
+    }
+
+    /// Handles a PAUSE exit taken by vCPU `id`, returning once it should
+    /// reenter the guest.
+    pub(crate) fn pause_exit(&self, id: usize) {
+        match self.decide(id, self.base.elapsed()) {
+            Action::Reenter => {}
+            Action::Yield => std::thread::yield_now(),
+            Action::Sleep(period) => std::thread::sleep(period),
+        }
+    }
+
+    fn decide(&self, id: usize, now: Duration) -> Action {
+        let now = u64::try_from(now.as_nanos()).unwrap_or(u64::MAX - 1) + 1;
+        let window = SPIN_WINDOW.as_nanos() as u64;
+        let spinning =
+            |last: u64| last != 0 && now.saturating_sub(last) <= window;
+
+        let me = &self.vcpus[id];
+        let streak = if spinning(me.last_pause.swap(now, Ordering::Relaxed)) {
+            me.streak.fetch_add(1, Ordering::Relaxed) + 1
+        } else {
+            me.streak.store(1, Ordering::Relaxed);
+            1
+        };
+
+        let runnable = self.vcpus.iter().enumerate().any(|(i, sib)| {
+            i != id && !spinning(sib.last_pause.load(Ordering::Relaxed))
+        });
+        if !runnable {
+            Action::Reenter
+        } else if streak <= YIELD_LIMIT {
+            Action::Yield
+        } else {
+            let shift = (streak - YIELD_LIMIT - 1).min(16);
+            Action::Sleep((BACKOFF_MIN * (1 << shift)).min(BACKOFF_MAX))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const US: Duration = Duration::from_micros(1);
+
+    #[test]
+    fn yields_to_runnable_sibling() {
+        let spin = SpinYield::new(2);
+        assert_eq!(spin.decide(0, 10 * US), Action::Yield);
+
+        // With the other vCPU spinning too, nobody can use the CPU.
+        assert_eq!(spin.decide(1, 11 * US), Action::Reenter);
+
+        // Once it has stopped spinning, it may again
+        let later = 11 * US + 2 * SPIN_WINDOW;
+        assert_eq!(spin.decide(0, later), Action::Yield);
+    }
+
+    #[test]
+    fn long_spin_backs_off() {
+        let spin = SpinYield::new(2);
+        let mut now = 10 * US;
+        for _ in 0..YIELD_LIMIT {
+            assert_eq!(spin.decide(0, now), Action::Yield);
+            now += US;
+        }
+        assert_eq!(spin.decide(0, now), Action::Sleep(BACKOFF_MIN));
+        assert_eq!(spin.decide(0, now + US), Action::Sleep(BACKOFF_MIN * 2));
+        for i in 2..32 {
+            now += US;
+            assert!(
+                matches!(
+                    spin.decide(0, now),
+                    Action::Sleep(d) if d <= BACKOFF_MAX
+                ),
+                "iteration {i}"
+            );
+        }
+
+        // A fresh spin starts over with plain yields
+        assert_eq!(spin.decide(0, now + 2 * SPIN_WINDOW), Action::Yield);
+    }
+}
@@ -0,0 +1,238 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Paravirtualized steal-time reporting
+//!
+//! Time which a vCPU spends runnable, but waiting for the host to schedule its
+//! backing thread, is invisible to the guest, which would otherwise account it
+//! to whatever task happened to be running.  Guests which support the KVM
+//! steal-time enlightenment register a per-vCPU structure via
+//! [`MSR_KVM_STEAL_TIME`], into which the host publishes the cumulative
+//! scheduling delay of that vCPU.
+//!
+//! The delay is measured from the host's accounting of the backing thread,
+//! so a [`StealTime`] must be created on (and only used from) the thread which
+//! runs the vCPU.  Time during which the vCPU is deliberately kept from running,
+//! such as by a [duty cycle](crate::duty) limit, is not visible in that
+//! accounting, and is added separately.  The registration held by the MSR is not carried across a
+//! migration; the guest will observe no further steal time on the target
+//! until it re-registers (such as when the vCPU is next onlined).
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{fence, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::accessors::MemAccessor;
+use crate::common::GuestAddr;
+use crate::cpuid::{Entry, Ident, Set};
+
+pub const MSR_KVM_STEAL_TIME: u32 = 0x4b56_4d03;
+
+/// The KVM leaves are placed at an offset from the base of the hypervisor
+/// range, which bhyve describes itself with.  Guests search the range for the
+/// KVM signature in steps of 0x100.
+const KVM_CPUID_SIGNATURE: u32 = 0x4000_0100;
+const KVM_CPUID_FEATURES: u32 = KVM_CPUID_SIGNATURE + 1;
+const KVM_FEATURE_STEAL_TIME: u32 = 1 << 5;
+
+const MSR_ENABLE: u64 = 1 << 0;
+/// The steal-time structure must be 64-byte aligned
+const MSR_ADDR_MASK: u64 = !0x3f;
+
+/// Minimum interval between updates to the guest-visible structure, bounding
+/// the cost of querying the host on frequent VM exits.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Size of the guest-visible `struct kvm_steal_time`, which begins with the
+/// cumulative steal time (u64, in nanoseconds) followed by a version (u32).
+/// The remaining fields are not populated.
+const STEAL_TIME_LEN: usize = 64;
+const VERSION_OFFSET: u64 = 8;
+
+/// Add the hypervisor leaves through which the steal-time feature is
+/// advertised to guests, leaving bhyve's own leaves in place.
+pub fn advertise(set: &mut Set) {
+    let sig = *b"KVMKVMKVM\0\0\0";
+    let word = |i: usize| u32::from_le_bytes(sig[i..i + 4].try_into().unwrap());
+    set.insert(
+        Ident(KVM_CPUID_SIGNATURE, None),
+        Entry {
+            eax: KVM_CPUID_FEATURES,
+            ebx: word(0),
+            ecx: word(4),
+            edx: word(8),
+        },
+    );
+    let features =
+        set.get(Ident(KVM_CPUID_FEATURES, None)).map_or(0, |ent| ent.eax);
+    set.insert(
+        Ident(KVM_CPUID_FEATURES, None),
+        Entry { eax: features | KVM_FEATURE_STEAL_TIME, ..Entry::zero() },
+    );
+}
+
+/// Cumulative time the calling thread has spent waiting for a host CPU
+struct WaitClock(File);
+impl WaitClock {
+    #[cfg(target_os = "illumos")]
+    fn open() -> Result<Self> {
+        extern "C" {
+            fn _lwp_self() -> libc::c_uint;
+        }
+        let lwpid = unsafe { _lwp_self() };
+        File::open(format!("/proc/self/lwp/{lwpid}/lwpusage")).map(Self)
+    }
+
+    #[cfg(target_os = "illumos")]
+    fn wait_ns(&self) -> Result<u64> {
+        // Offset of `pr_wtime` (a `timestruc_t`) within `prusage_t`
+        const WTIME_OFFSET: usize = 200;
+        let mut buf = [0u8; WTIME_OFFSET + 16];
+        self.0.read_exact_at(&mut buf, 0)?;
+        let field = |off: usize| {
+            i64::from_ne_bytes(buf[off..off + 8].try_into().unwrap()) as u64
+        };
+        let (sec, nsec) = (field(WTIME_OFFSET), field(WTIME_OFFSET + 8));
+        Ok(sec * 1_000_000_000 + nsec)
+    }
+
+    #[cfg(not(target_os = "illumos"))]
+    fn open() -> Result<Self> {
+        File::open("/proc/thread-self/schedstat").map(Self)
+    }
+
+    #[cfg(not(target_os = "illumos"))]
+    fn wait_ns(&self) -> Result<u64> {
+        // Fields are: time on CPU, time waiting on a runqueue, timeslices run
+        let mut buf = [0u8; 128];
+        let len = self.0.read_at(&mut buf, 0)?;
+        std::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|s| s.split_whitespace().nth(1))
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(|| {
+                Error::new(ErrorKind::InvalidData, "malformed schedstat")
+            })
+    }
+}
+
+/// Steal-time state for a single vCPU
+pub struct StealTime {
+    clock: WaitClock,
+    acc_mem: MemAccessor,
+    msr: u64,
+    version: u32,
+    last_update: Option<Instant>,
+    /// Time the vCPU was kept from running, beyond that waiting for the host
+    injected_ns: u64,
+}
+impl StealTime {
+    /// Begin tracking steal time for the vCPU run by the calling thread.
+    pub fn new(acc_mem: MemAccessor) -> Result<Self> {
+        Ok(Self {
+            clock: WaitClock::open()?,
+            acc_mem,
+            msr: 0,
+            version: 0,
+            last_update: None,
+            injected_ns: 0,
+        })
+    }
+
+    /// Emulate a read of `msr`, if it is one handled by steal-time reporting.
+    pub fn rdmsr(&self, msr: u32) -> Option<u64> {
+        (msr == MSR_KVM_STEAL_TIME).then_some(self.msr)
+    }
+
+    /// Emulate a write of `val` to `msr`, returning whether it was handled.
+    pub fn wrmsr(&mut self, msr: u32, val: u64) -> bool {
+        if msr != MSR_KVM_STEAL_TIME {
+            return false;
+        }
+        self.msr = val;
+        self.version = 0;
+        self.last_update = None;
+        if val & MSR_ENABLE != 0 {
+            if let Some(mem) = self.acc_mem.access() {
+                let base = GuestAddr(val & MSR_ADDR_MASK);
+                mem.write(base, &[0u8; STEAL_TIME_LEN]);
+            }
+            self.update();
+        }
+        true
+    }
+
+    /// Account `delay`, for which the vCPU was kept from running, as stolen.
+    pub fn add_injected(&mut self, delay: Duration) {
+        self.injected_ns += delay.as_nanos() as u64;
+    }
+
+    /// Publish the current steal time to the guest, if it has registered a
+    /// structure to receive it.  Intended to be called prior to each entry
+    /// into the guest.
+    pub fn update(&mut self) {
+        if self.msr & MSR_ENABLE == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if matches!(self.last_update, Some(t) if now - t < UPDATE_INTERVAL) {
+            return;
+        }
+        self.last_update = Some(now);
+
+        let Ok(wait) = self.clock.wait_ns() else {
+            return;
+        };
+        let steal = wait + self.injected_ns;
+        let Some(mem) = self.acc_mem.access() else {
+            return;
+        };
+        let base = self.msr & MSR_ADDR_MASK;
+        let version_addr = GuestAddr(base + VERSION_OFFSET);
+
+        // The guest retries its read if it observes an odd version, or one
+        // which changed while it was reading.
+        self.version = self.version.wrapping_add(1) | 1;
+        mem.write(version_addr, &self.version);
+        fence(Ordering::Release);
+        mem.write(GuestAddr(base), &steal);
+        fence(Ordering::Release);
+        self.version = self.version.wrapping_add(1);
+        mem.write(version_addr, &self.version);
+    }
+
+    /// Clear the guest registration, as on reset of the vCPU.
+    pub fn reset(&mut self) {
+        self.msr = 0;
+        self.version = 0;
+        self.last_update = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpuid::VendorKind;
+
+    #[test]
+    fn advertise_leaves() {
+        let mut set = Set::new(VendorKind::Amd);
+        advertise(&mut set);
+        let sig = set.get(Ident(KVM_CPUID_SIGNATURE, None)).unwrap();
+        let mut bytes = Vec::new();
+        for r in [sig.ebx, sig.ecx, sig.edx] {
+            bytes.extend_from_slice(&r.to_le_bytes());
+        }
+        assert_eq!(&bytes, b"KVMKVMKVM\0\0\0");
+        assert_eq!(sig.eax, KVM_CPUID_FEATURES);
+
+        let feat = set.get(Ident(KVM_CPUID_FEATURES, None)).unwrap();
+        assert_eq!(feat.eax & KVM_FEATURE_STEAL_TIME, KVM_FEATURE_STEAL_TIME);
+
+        // The base of the hypervisor range is left to bhyve
+        assert!(set.get(Ident(0x4000_0000, None)).is_none());
+    }
+}
@@ -0,0 +1,160 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! vCPU topology
+//!
+//! Guests learn how their vCPUs are arranged into sockets, cores, and threads
+//! from cpuid, the ACPI tables, and SMBIOS.  A [`CpuTopology`] describes that
+//! arrangement so each of those sources can report it consistently.
+//!
+//! bhyve assigns each vCPU the APIC ID matching its vCPU ID, while guests
+//! decode APIC IDs as packed (socket, core, thread) fields, each field as wide
+//! as is needed to hold its count.  For the two to agree without gaps in the
+//! ID space, the number of cores per socket and threads per core must be
+//! powers of two.
+
+use std::num::NonZeroU8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TopologyError {
+    #[error("topology counts must be non-zero")]
+    ZeroCount,
+
+    #[error("{0} per {1} ({2}) must be a power of two")]
+    NotPowerOfTwo(&'static str, &'static str, u8),
+
+    #[error("topology describes more than {} vCPUs", u8::MAX)]
+    TooManyVcpus,
+}
+
+/// The arrangement of vCPUs into sockets, cores, and threads.
+///
+/// vCPUs are numbered with threads varying fastest: vCPU IDs 0 through
+/// `threads - 1` are the threads of core 0 in socket 0, and so on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CpuTopology {
+    sockets: NonZeroU8,
+    cores: NonZeroU8,
+    threads: NonZeroU8,
+}
+
+impl CpuTopology {
+    pub fn new(
+        sockets: u8,
+        cores_per_socket: u8,
+        threads_per_core: u8,
+    ) -> Result<Self, TopologyError> {
+        let (Some(sockets), Some(cores), Some(threads)) = (
+            NonZeroU8::new(sockets),
+            NonZeroU8::new(cores_per_socket),
+            NonZeroU8::new(threads_per_core),
+        ) else {
+            return Err(TopologyError::ZeroCount);
+        };
+        if !cores.is_power_of_two() {
+            return Err(TopologyError::NotPowerOfTwo(
+                "cores",
+                "socket",
+                cores.get(),
+            ));
+        }
+        if !threads.is_power_of_two() {
+            return Err(TopologyError::NotPowerOfTwo(
+                "threads",
+                "core",
+                threads.get(),
+            ));
+        }
+        let total =
+            sockets.get() as u32 * cores.get() as u32 * threads.get() as u32;
+        if total > u8::MAX as u32 {
+            return Err(TopologyError::TooManyVcpus);
+        }
+        Ok(Self { sockets, cores, threads })
+    }
+
+    pub fn sockets(&self) -> u8 {
+        self.sockets.get()
+    }
+    pub fn cores_per_socket(&self) -> u8 {
+        self.cores.get()
+    }
+    pub fn threads_per_core(&self) -> u8 {
+        self.threads.get()
+    }
+    pub fn threads_per_socket(&self) -> u8 {
+        self.cores.get() * self.threads.get()
+    }
+    pub fn num_vcpus(&self) -> NonZeroU8 {
+        // Bounded by the check in `new()`
+        NonZeroU8::new(self.sockets.get() * self.threads_per_socket()).unwrap()
+    }
+
+    /// Width of the thread field in an APIC ID
+    pub fn thread_bits(&self) -> u32 {
+        self.threads.get().trailing_zeros()
+    }
+    /// Width of the core field in an APIC ID
+    pub fn core_bits(&self) -> u32 {
+        self.cores.get().trailing_zeros()
+    }
+
+    /// The socket containing vCPU `vcpuid`
+    pub fn socket_of(&self, vcpuid: i32) -> u32 {
+        vcpuid as u32 >> (self.thread_bits() + self.core_bits())
+    }
+    /// The index, within its socket, of the core containing vCPU `vcpuid`
+    pub fn core_of(&self, vcpuid: i32) -> u32 {
+        (vcpuid as u32 >> self.thread_bits()) & (self.cores.get() as u32 - 1)
+    }
+    /// The index, within its core, of vCPU `vcpuid`
+    pub fn thread_of(&self, vcpuid: i32) -> u32 {
+        vcpuid as u32 & (self.threads.get() as u32 - 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert!(CpuTopology::new(2, 4, 2).is_ok());
+        assert!(CpuTopology::new(3, 1, 1).is_ok());
+        assert!(matches!(
+            CpuTopology::new(1, 3, 2),
+            Err(TopologyError::NotPowerOfTwo("cores", _, 3))
+        ));
+        assert!(matches!(
+            CpuTopology::new(1, 2, 3),
+            Err(TopologyError::NotPowerOfTwo("threads", _, 3))
+        ));
+        assert!(matches!(
+            CpuTopology::new(0, 1, 1),
+            Err(TopologyError::ZeroCount)
+        ));
+        assert!(matches!(
+            CpuTopology::new(16, 16, 2),
+            Err(TopologyError::TooManyVcpus)
+        ));
+    }
+
+    #[test]
+    fn decompose_ids() {
+        let topo = CpuTopology::new(2, 4, 2).unwrap();
+        assert_eq!(topo.num_vcpus().get(), 16);
+        assert_eq!((topo.thread_bits(), topo.core_bits()), (1, 2));
+
+        // vCPU 13 = 0b1_10_1: socket 1, core 2, thread 1
+        assert_eq!(topo.socket_of(13), 1);
+        assert_eq!(topo.core_of(13), 2);
+        assert_eq!(topo.thread_of(13), 1);
+
+        let flat = CpuTopology::new(4, 1, 1).unwrap();
+        assert_eq!((flat.thread_bits(), flat.core_bits()), (0, 0));
+        assert_eq!(flat.socket_of(3), 3);
+        assert_eq!(flat.core_of(3), 0);
+        assert_eq!(flat.thread_of(3), 0);
+    }
+}
@@ -0,0 +1,52 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runtime control over high-volume diagnostic paths.
+//!
+//! Some USDT probes sit on paths which are hit for nearly every guest exit
+//! (port IO, MMIO, VM entry/exit).  The probes already check whether they are
+//! enabled, and marshal their arguments only if they are, so the categories
+//! here save nothing while no consumer is attached.  Rather, they mute those
+//! probes for a consumer which is: one enabling every `propolis` probe, say,
+//! can leave the hot paths out until an operator asks for them, without
+//! restarting the instance.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct TraceFlags: u32 {
+        /// Port IO dispatch (`pio_in`/`pio_out` probes)
+        const PIO = 1 << 0;
+        /// MMIO dispatch (`mmio_read`/`mmio_write` probes)
+        const MMIO = 1 << 1;
+        /// vCPU entry and exit (`vm_entry`/`vm_exit` probes)
+        const VM_EXIT = 1 << 2;
+        /// Block request submission and completion
+        const BLOCK = 1 << 3;
+    }
+}
+
+/// All categories are enabled by default, preserving the behavior prior to the
+/// introduction of runtime toggling.
+static ENABLED: AtomicU32 = AtomicU32::new(TraceFlags::all().bits());
+
+/// Returns the set of currently enabled trace categories.
+pub fn enabled() -> TraceFlags {
+    TraceFlags::from_bits_truncate(ENABLED.load(Ordering::Relaxed))
+}
+
+/// Returns `true` if all of the categories in `flags` are enabled.
+#[inline]
+pub fn is_enabled(flags: TraceFlags) -> bool {
+    TraceFlags::from_bits_truncate(ENABLED.load(Ordering::Relaxed))
+        .contains(flags)
+}
+
+/// Replaces the set of enabled trace categories, returning the prior set.
+pub fn set_enabled(flags: TraceFlags) -> TraceFlags {
+    TraceFlags::from_bits_truncate(
+        ENABLED.swap(flags.bits(), Ordering::Relaxed),
+    )
+}
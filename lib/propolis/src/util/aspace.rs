@@ -15,7 +15,7 @@ use thiserror::Error;
 ///
 /// Stores ranges by (start, length), but also allows association
 /// of generic objects with each region.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ASpace<T> {
     start: usize,
     end: usize,
@@ -0,0 +1,304 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Lock hierarchy checking
+//!
+//! Callbacks between devices, buses, and the machine make it easy to introduce
+//! a lock ordering inversion which only deadlocks under rare interleavings.
+//! The major subsystem locks are therefore assigned a [`Rank`], and must only
+//! be acquired in increasing order of rank.
+//!
+//! In debug builds, each thread tracks the ranked locks it holds, and panics if
+//! a lock is acquired while one of equal or higher rank is already held.  The
+//! panic message includes the stack at which the violation occurred.  Since
+//! capturing a backtrace on every acquisition is costly, the stack at which
+//! the conflicting lock was acquired is only recorded (and included) when
+//! `PROPOLIS_LOCKORDER_BACKTRACE` is set in the environment.  In release
+//! builds, the wrappers here are plain `std` locks.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{self, LockResult, PoisonError};
+
+/// Position of a lock in the hierarchy.  A lock may only be acquired while the
+/// locks held by the thread are all of a lower rank.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rank {
+    /// The [`Instance`](crate::Instance), holding the machine and inventory
+    Machine,
+    /// Bus routing within a PCI topology
+    PciTopology,
+    /// Device and BAR state of a single PCI bus
+    PciBus,
+    /// Updates to the address maps through which port IO and MMIO are
+    /// dispatched
+    Dispatch,
+    /// Available ring of a virtqueue
+    VirtqAvail,
+    /// Used ring of a virtqueue
+    VirtqUsed,
+}
+
+#[cfg(debug_assertions)]
+mod check {
+    use super::Rank;
+    use std::backtrace::Backtrace;
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::OnceLock;
+
+    struct HeldLock {
+        rank: Rank,
+        id: u64,
+        /// Stack at which the lock was acquired, if recording them is enabled
+        acquired_at: Option<Backtrace>,
+    }
+
+    /// Whether to record the stack at which each lock is acquired
+    fn record_backtraces() -> bool {
+        static RECORD: OnceLock<bool> = OnceLock::new();
+        *RECORD.get_or_init(|| {
+            std::env::var_os("PROPOLIS_LOCKORDER_BACKTRACE").is_some()
+        })
+    }
+
+    thread_local! {
+        static HELD: RefCell<Vec<HeldLock>> =
+            const { RefCell::new(Vec::new()) };
+    }
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    /// Record of a ranked lock held by the current thread
+    pub(super) struct Held {
+        id: u64,
+    }
+    impl Held {
+        /// Check that a lock of `rank` may be acquired by the current thread.
+        ///
+        /// This is done prior to blocking on the lock itself, so that an
+        /// ordering violation is reported even if the interleaving required
+        /// to deadlock does not occur.
+        pub(super) fn acquire(rank: Rank) -> Self {
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            HELD.with(|held| {
+                let mut held = held.borrow_mut();
+                if let Some(conflict) = held.iter().find(|h| h.rank >= rank) {
+                    let conflict_at = match conflict.acquired_at.as_ref() {
+                        Some(bt) => bt.to_string(),
+                        None => "(set PROPOLIS_LOCKORDER_BACKTRACE to record)"
+                            .to_string(),
+                    };
+                    let msg = format!(
+                        "lock ordering violation: acquiring {:?} while \
+                        holding {:?}\n\
+                        --- {:?} acquired at:\n{}\n\
+                        --- {:?} acquired at:\n{}",
+                        rank,
+                        conflict.rank,
+                        conflict.rank,
+                        conflict_at,
+                        rank,
+                        Backtrace::force_capture(),
+                    );
+                    // Release the borrow so any locks dropped during unwinding
+                    // can be removed from the held list.
+                    drop(held);
+                    panic!("{msg}");
+                }
+                held.push(HeldLock {
+                    rank,
+                    id,
+                    acquired_at: record_backtraces()
+                        .then(Backtrace::force_capture),
+                });
+            });
+            Self { id }
+        }
+    }
+    impl Drop for Held {
+        fn drop(&mut self) {
+            // Guards may be dropped in any order, so search for this one
+            // rather than assuming it is last.
+            let _ = HELD.try_with(|held| {
+                let mut held = held.borrow_mut();
+                if let Some(pos) = held.iter().rposition(|h| h.id == self.id) {
+                    held.remove(pos);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod check {
+    use super::Rank;
+
+    pub(super) struct Held;
+    impl Held {
+        #[inline(always)]
+        pub(super) fn acquire(_rank: Rank) -> Self {
+            Self
+        }
+    }
+}
+
+use check::Held;
+
+/// A [`std::sync::Mutex`] with a position in the lock hierarchy
+pub struct Mutex<T> {
+    rank: Rank,
+    inner: sync::Mutex<T>,
+}
+impl<T> Mutex<T> {
+    pub const fn new(rank: Rank, val: T) -> Self {
+        Self { rank, inner: sync::Mutex::new(val) }
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let held = Held::acquire(self.rank);
+        match self.inner.lock() {
+            Ok(inner) => Ok(MutexGuard { inner, _held: held }),
+            Err(e) => Err(PoisonError::new(MutexGuard {
+                inner: e.into_inner(),
+                _held: held,
+            })),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    inner: sync::MutexGuard<'a, T>,
+    _held: Held,
+}
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+/// A [`std::sync::RwLock`] with a position in the lock hierarchy
+///
+/// Shared acquisitions are checked in the same manner as exclusive ones, since
+/// a pending writer can cause nested readers to deadlock.
+pub struct RwLock<T> {
+    rank: Rank,
+    inner: sync::RwLock<T>,
+}
+impl<T> RwLock<T> {
+    pub const fn new(rank: Rank, val: T) -> Self {
+        Self { rank, inner: sync::RwLock::new(val) }
+    }
+
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        let held = Held::acquire(self.rank);
+        match self.inner.read() {
+            Ok(inner) => Ok(RwLockReadGuard { inner, _held: held }),
+            Err(e) => Err(PoisonError::new(RwLockReadGuard {
+                inner: e.into_inner(),
+                _held: held,
+            })),
+        }
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        let held = Held::acquire(self.rank);
+        match self.inner.write() {
+            Ok(inner) => Ok(RwLockWriteGuard { inner, _held: held }),
+            Err(e) => Err(PoisonError::new(RwLockWriteGuard {
+                inner: e.into_inner(),
+                _held: held,
+            })),
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    inner: sync::RwLockReadGuard<'a, T>,
+    _held: Held,
+}
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    inner: sync::RwLockWriteGuard<'a, T>,
+    _held: Held,
+}
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn increasing_rank() {
+        let bus = RwLock::new(Rank::PciBus, ());
+        let avail = Mutex::new(Rank::VirtqAvail, ());
+        let used = Mutex::new(Rank::VirtqUsed, ());
+
+        let _b = bus.write().unwrap();
+        let _a = avail.lock().unwrap();
+        let _u = used.lock().unwrap();
+    }
+
+    #[test]
+    fn release_out_of_order() {
+        let avail = Mutex::new(Rank::VirtqAvail, ());
+        let used = Mutex::new(Rank::VirtqUsed, ());
+
+        let a = avail.lock().unwrap();
+        let u = used.lock().unwrap();
+        drop(a);
+        drop(u);
+
+        // With everything released, any order is acceptable again
+        let _u = used.lock().unwrap();
+        drop(_u);
+        let _a = avail.lock().unwrap();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "lock ordering violation")]
+    fn decreasing_rank() {
+        let bus = RwLock::new(Rank::PciBus, ());
+        let dispatch = RwLock::new(Rank::Dispatch, ());
+
+        let _d = dispatch.read().unwrap();
+        let _b = bus.read().unwrap();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "lock ordering violation")]
+    fn same_rank() {
+        let first = Mutex::new(Rank::VirtqAvail, ());
+        let second = Mutex::new(Rank::VirtqAvail, ());
+
+        let _f = first.lock().unwrap();
+        let _s = second.lock().unwrap();
+    }
+}
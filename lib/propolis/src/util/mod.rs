@@ -3,4 +3,6 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod aspace;
+pub mod lockorder;
 pub mod regmap;
+pub mod snapshot;
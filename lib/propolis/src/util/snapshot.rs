@@ -0,0 +1,82 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Read-mostly state published as immutable snapshots
+//!
+//! State which is consulted far more often than it changes, such as the maps
+//! through which port IO and MMIO are dispatched, can be kept in a
+//! [`Snapshot`].  Readers load the current snapshot without taking any lock or
+//! writing to memory shared with other readers, so they neither block nor
+//! contend with one another.  Each update copies the state, modifies the copy,
+//! and publishes it as the next snapshot, leaving readers of the prior one
+//! undisturbed.  A snapshot is freed once the last reader holding it is done.
+
+use std::sync::Arc;
+
+use super::lockorder::{Mutex, Rank};
+
+use arc_swap::{ArcSwap, Guard};
+
+pub struct Snapshot<T> {
+    current: ArcSwap<T>,
+    /// Serializes updates, so that none is lost to a concurrent one
+    update: Mutex<()>,
+}
+impl<T: Clone> Snapshot<T> {
+    /// Creates a snapshot of `val`, updates to which are ranked as `rank` in
+    /// the lock hierarchy.
+    pub fn new(rank: Rank, val: T) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(val),
+            update: Mutex::new(rank, ()),
+        }
+    }
+
+    /// Loads the current snapshot.
+    ///
+    /// The snapshot is unaffected by subsequent updates, and may be held while
+    /// updates are made (including by the holder).
+    pub fn load(&self) -> Guard<Arc<T>> {
+        self.current.load()
+    }
+
+    /// Applies `f` to a copy of the current state, and publishes the result as
+    /// the next snapshot.
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _guard = self.update.lock().unwrap();
+        let mut next = T::clone(&self.current.load());
+        let res = f(&mut next);
+        self.current.store(Arc::new(next));
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn held_snapshot_unchanged() {
+        let snap = Snapshot::new(Rank::Dispatch, vec![1u32]);
+        let held = snap.load();
+        snap.update(|v| v.push(2));
+
+        assert_eq!(**held, [1]);
+        assert_eq!(**snap.load(), [1, 2]);
+    }
+
+    #[test]
+    fn update_while_held() {
+        let snap = Snapshot::new(Rank::Dispatch, 0u32);
+        let held = snap.load();
+        // Holding a snapshot takes no ranked lock, so updates may be made from
+        // within a reader (such as a handler reprogramming a BAR).
+        let res = snap.update(|v| {
+            *v += 1;
+            *v
+        });
+        assert_eq!(res, 1);
+        assert_eq!(**held, 0);
+    }
+}
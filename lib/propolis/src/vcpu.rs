@@ -5,15 +5,20 @@
 //! Virtual CPU functionality.
 
 use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::cpuid;
+use crate::exit_stats::{self, Cause, ExitStats, Site};
 use crate::exits::*;
 use crate::inventory::Entity;
 use crate::migrate::*;
 use crate::mmio::MmioBus;
 use crate::pio::PioBus;
+use crate::spin::SpinYield;
 use crate::tasks;
+use crate::trace::{self, TraceFlags};
 use crate::vmm::VmmHdl;
 use migrate::VcpuReadWrite;
 
@@ -32,12 +37,40 @@ pub const MAXCPU: usize = bhyve_api::VM_MAXCPU;
 #[cfg(feature = "omicron-build")]
 pub const MAXCPU: usize = 64;
 
+/// Time a vCPU spends within `VM_RUN` without running
+///
+/// bhyve handles HLT exits itself, sleeping the vCPU thread within `VM_RUN`
+/// until an interrupt (from any source, in-kernel or userspace) is pending.
+/// Userspace cannot observe those halts individually, so this counts all of
+/// the time the thread spends inside `VM_RUN` without consuming CPU: halted,
+/// but also waiting for a host CPU to run on.  It is only collected while
+/// [exit statistics](exit_stats) are enabled, as it adds two clock reads to
+/// every entry.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HaltStats {
+    /// Total time spent blocked within `VM_RUN`
+    pub blocked_ns: u64,
+}
+
+/// CPU time consumed by the calling thread, including time spent running the
+/// guest within `VM_RUN`
+fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // Safety: `ts` is valid for the call to fill in
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
 /// A handle to a virtual CPU.
 pub struct Vcpu {
     hdl: Arc<VmmHdl>,
     pub id: i32,
     pub bus_mmio: Arc<MmioBus>,
     pub bus_pio: Arc<PioBus>,
+    exit_stats: ExitStats,
+    /// Time spent blocked within `VM_RUN`, in nanoseconds
+    blocked_ns: AtomicU64,
+    spin: Arc<SpinYield>,
 }
 
 impl Vcpu {
@@ -47,8 +80,17 @@ impl Vcpu {
         id: i32,
         bus_mmio: Arc<MmioBus>,
         bus_pio: Arc<PioBus>,
+        spin: Arc<SpinYield>,
     ) -> Arc<Self> {
-        Arc::new(Self { hdl, id, bus_mmio, bus_pio })
+        Arc::new(Self {
+            hdl,
+            id,
+            bus_mmio,
+            bus_pio,
+            exit_stats: ExitStats::default(),
+            blocked_ns: AtomicU64::new(0),
+            spin,
+        })
     }
 
     /// ID of the virtual CPU.
@@ -73,6 +115,39 @@ impl Vcpu {
         unsafe { self.hdl.ioctl(bhyve_api::VM_SET_CAPABILITY, &mut cap) }
     }
 
+    /// Enable or disable exits on breakpoint (`int3`) instructions executed
+    /// by the guest, reported as [`VmExitKind::Breakpoint`].
+    pub fn set_breakpoint_exits(&self, enabled: bool) -> Result<()> {
+        self.set_capab(bhyve_api::vm_cap_type::VM_CAP_BPT_EXIT, enabled)
+    }
+
+    /// Enable or disable exits after each instruction executed by the guest
+    /// (single-stepping), reported as [`VmExitKind::Mtrap`].
+    pub fn set_single_step(&self, enabled: bool) -> Result<()> {
+        self.set_capab(bhyve_api::vm_cap_type::VM_CAP_MTRAP_EXIT, enabled)
+    }
+
+    /// Enable or disable exits when the guest spins in a loop of PAUSE
+    /// instructions, reported as [`VmExitKind::Pause`].  The vCPU then yields
+    /// its host CPU to its siblings, per the policy in [`crate::spin`].
+    pub fn set_pause_exits(&self, enabled: bool) -> Result<()> {
+        self.set_capab(bhyve_api::vm_cap_type::VM_CAP_PAUSE_EXIT, enabled)
+    }
+
+    fn set_capab(
+        &self,
+        cap: bhyve_api::vm_cap_type,
+        enabled: bool,
+    ) -> Result<()> {
+        let mut cap = bhyve_api::vm_capability {
+            cpuid: self.id,
+            captype: cap as i32,
+            capval: enabled as i32,
+            allcpus: 0,
+        };
+        unsafe { self.hdl.ioctl(bhyve_api::VM_SET_CAPABILITY, &mut cap) }
+    }
+
     /// Sets the value of a register within the CPU.
     pub fn set_reg(&self, reg: bhyve_api::vm_reg_name, val: u64) -> Result<()> {
         let mut regcmd = bhyve_api::vm_register {
@@ -142,6 +217,69 @@ impl Vcpu {
         Ok(req.desc)
     }
 
+    /// Translate the guest-linear address `gla` to a guest-physical address,
+    /// walking the guest's page tables according to the vCPU's current mode.
+    ///
+    /// Returns `None` if the access (a write if `write` is set) would fault.
+    pub fn translate_gla(&self, gla: u64, write: bool) -> Result<Option<u64>> {
+        use bhyve_api::vm_reg_name::*;
+        use bhyve_api::{vm_cpu_mode, vm_paging_mode};
+
+        const CR0_PE: u64 = 1 << 0;
+        const CR0_PG: u64 = 1 << 31;
+        const CR4_PAE: u64 = 1 << 5;
+        const CR4_LA57: u64 = 1 << 12;
+        const EFER_LMA: u64 = 1 << 10;
+        // Long-mode bit in the (VMX-style) segment access rights
+        const SEG_ACCESS_L: u32 = 1 << 13;
+
+        let cr0 = self.get_reg(VM_REG_GUEST_CR0)?;
+        let cr4 = self.get_reg(VM_REG_GUEST_CR4)?;
+        let efer = self.get_reg(VM_REG_GUEST_EFER)?;
+        let cs = self.get_segreg(VM_REG_GUEST_CS)?;
+        let ss = self.get_segreg(VM_REG_GUEST_SS)?;
+        let long_mode = efer & EFER_LMA != 0;
+
+        let cpu_mode = if cr0 & CR0_PE == 0 {
+            vm_cpu_mode::CPU_MODE_REAL
+        } else if !long_mode {
+            vm_cpu_mode::CPU_MODE_PROTECTED
+        } else if cs.access & SEG_ACCESS_L != 0 {
+            vm_cpu_mode::CPU_MODE_64BIT
+        } else {
+            vm_cpu_mode::CPU_MODE_COMPATIBILITY
+        };
+        let paging_mode = if cr0 & CR0_PG == 0 {
+            vm_paging_mode::PAGING_MODE_FLAT
+        } else if cr4 & CR4_PAE == 0 {
+            vm_paging_mode::PAGING_MODE_32
+        } else if !long_mode {
+            vm_paging_mode::PAGING_MODE_PAE
+        } else if cr4 & CR4_LA57 != 0 {
+            vm_paging_mode::PAGING_MODE_64_LA57
+        } else {
+            vm_paging_mode::PAGING_MODE_64
+        };
+
+        let mut req = bhyve_api::vm_gla2gpa {
+            vcpuid: self.id,
+            prot: if write { libc::PROT_WRITE } else { libc::PROT_READ },
+            gla,
+            paging: bhyve_api::vm_guest_paging {
+                cr3: self.get_reg(VM_REG_GUEST_CR3)?,
+                // The privilege level is that of the stack segment
+                cpl: ((ss.access >> 5) & 0x3) as i32,
+                cpu_mode: cpu_mode as i32,
+                paging_mode: paging_mode as i32,
+            },
+            ..Default::default()
+        };
+        unsafe {
+            self.hdl.ioctl(bhyve_api::VM_GLA2GPA_NOFAULT, &mut req)?;
+        }
+        Ok((req.fault == 0).then_some(req.gpa))
+    }
+
     /// Configure the (in-kernel) `cpuid` emulation state for this vCPU.
     ///
     /// If `values` contains no cpuid entries, then legacy emulation handling
@@ -325,9 +463,30 @@ impl Vcpu {
                 // that can be done.
             }
         }
-        probes::vm_entry!(|| (self.id as u32));
-        let _res = unsafe { self.hdl.ioctl(bhyve_api::VM_RUN, &mut entry)? };
-        probes::vm_exit!(|| (self.id as u32, exit.rip, exit.exitcode as u32));
+        let traced = trace::is_enabled(TraceFlags::VM_EXIT);
+        if traced {
+            probes::vm_entry!(|| (self.id as u32));
+        }
+        let start = exit_stats::is_enabled()
+            .then(|| (Instant::now(), thread_cpu_time()));
+        let res = unsafe { self.hdl.ioctl(bhyve_api::VM_RUN, &mut entry) };
+        if let Some((start, cpu_start)) = start {
+            let blocked = start
+                .elapsed()
+                .saturating_sub(thread_cpu_time().saturating_sub(cpu_start));
+            self.blocked_ns.fetch_add(
+                u64::try_from(blocked.as_nanos()).unwrap_or(u64::MAX),
+                Ordering::Relaxed,
+            );
+        }
+        let _res = res?;
+        if traced {
+            probes::vm_exit!(|| (
+                self.id as u32,
+                exit.rip,
+                exit.exitcode as u32
+            ));
+        }
 
         Ok(VmExit::parse(&exit, api_version))
     }
@@ -373,9 +532,58 @@ impl Vcpu {
         unsafe { self.hdl.ioctl(bhyve_api::VM_INJECT_NMI, &mut vm_nmi) }
     }
 
+    /// Latencies of the exits processed by this vCPU, if their collection has
+    /// been enabled via [`exit_stats::set_enabled()`].
+    pub fn exit_stats(&self) -> &ExitStats {
+        &self.exit_stats
+    }
+
+    /// Time spent by this vCPU blocked within `VM_RUN`, if its collection has
+    /// been enabled via [`exit_stats::set_enabled()`].
+    pub fn halt_stats(&self) -> HaltStats {
+        HaltStats { blocked_ns: self.blocked_ns.load(Ordering::Relaxed) }
+    }
+
+    /// Discard the accumulated [`HaltStats`]
+    pub fn reset_halt_stats(&self) {
+        self.blocked_ns.store(0, Ordering::Relaxed);
+    }
+
     /// Process [`VmExit`] in the context of this vCPU, emitting a [`VmEntry`]
     /// if the parameters of the exit were such that they could be handled.
     pub fn process_vmexit(&self, exit: &VmExit) -> Option<VmEntry> {
+        if !exit_stats::is_enabled() {
+            return self.handle_vmexit(exit);
+        }
+
+        let start = Instant::now();
+        let res = self.handle_vmexit(exit);
+        let elapsed = start.elapsed();
+
+        let site = match exit.kind {
+            VmExitKind::Inout(io) => {
+                let (cause, port) = match io {
+                    InoutReq::In(io) => (Cause::PioIn, io.port),
+                    InoutReq::Out(io, _) => (Cause::PioOut, io.port),
+                };
+                let device = self.bus_pio.region_base(port).map(usize::from);
+                Site { cause, device }
+            }
+            VmExitKind::Mmio(mmio) => {
+                let (cause, addr) = match mmio {
+                    MmioReq::Read(read) => (Cause::MmioRead, read.addr),
+                    MmioReq::Write(write) => (Cause::MmioWrite, write.addr),
+                };
+                let device = self.bus_mmio.region_base(addr as usize);
+                Site { cause, device }
+            }
+            _ => return res,
+        };
+        self.exit_stats.record(site, elapsed);
+        res
+    }
+
+    fn handle_vmexit(&self, exit: &VmExit) -> Option<VmEntry> {
         match exit.kind {
             VmExitKind::Bogus => Some(VmEntry::Run),
             VmExitKind::Inout(io) => match io {
@@ -424,6 +632,16 @@ impl Vcpu {
                 // detached from this vCPU).
                 Some(VmEntry::Run)
             }
+            VmExitKind::Hlt => {
+                // bhyve waits for an interrupt itself, returning a halted vCPU
+                // to userspace only once that wait has been cut short, so
+                // there is nothing more to wait for here.
+                Some(VmEntry::Run)
+            }
+            VmExitKind::Pause => {
+                self.spin.pause_exit(self.id as usize);
+                Some(VmEntry::Run)
+            }
             VmExitKind::Suspended(_) => None,
 
             VmExitKind::InstEmul(_)
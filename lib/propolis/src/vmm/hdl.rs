@@ -79,7 +79,7 @@ pub(crate) fn create_vm(name: &str, opts: CreateOpts) -> Result<VmmHdl> {
         inner,
         destroyed: AtomicBool::new(false),
         name: name.to_string(),
-        #[cfg(test)]
+        #[cfg(any(test, feature = "bench-hooks"))]
         is_test_hdl: false,
     })
 }
@@ -110,7 +110,7 @@ pub struct VmmHdl {
     destroyed: AtomicBool,
     name: String,
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "bench-hooks"))]
     /// Track if this VmmHdl belongs to a wholly fictitious Instance/Machine.
     is_test_hdl: bool,
 }
@@ -125,7 +125,7 @@ impl VmmHdl {
             return Err(Error::new(ErrorKind::NotFound, "instance destroyed"));
         }
 
-        #[cfg(test)]
+        #[cfg(any(test, feature = "bench-hooks"))]
         if self.is_test_hdl {
             // Lie about all ioctl results, since there is no real vmm resource
             // underlying this handle.
@@ -142,7 +142,7 @@ impl VmmHdl {
             return Err(Error::new(ErrorKind::NotFound, "instance destroyed"));
         }
 
-        #[cfg(test)]
+        #[cfg(any(test, feature = "bench-hooks"))]
         if self.is_test_hdl {
             // Lie about all ioctl results, since there is no real vmm resource
             // underlying this handle.
@@ -214,6 +214,13 @@ impl VmmHdl {
         unsafe { self.ioctl(bhyve_api::VM_MMAP_MEMSEG, &mut map) }
     }
 
+    /// Unmaps whatever memory segment(s) are mapped within the guest address
+    /// space from `gpa` for `len` bytes.  The segments themselves persist.
+    pub fn unmap_memseg(&self, gpa: usize, len: usize) -> Result<()> {
+        let mut unmap = bhyve_api::vm_munmap { gpa: gpa as u64, len };
+        unsafe { self.ioctl(bhyve_api::VM_MUNMAP_MEMSEG, &mut unmap) }
+    }
+
     /// Looks up a segment by `segid` and returns the offset
     /// within the guest's address virtual address space where
     /// it is mapped.
@@ -318,17 +325,17 @@ impl VmmHdl {
         unsafe { self.ioctl(bhyve_api::VM_ISA_SET_IRQ_TRIGGER, &mut data) }
     }
 
-    #[allow(unused)]
+    /// Asserts the requested pin of the IOAPIC, without involving the PIC.
     pub fn ioapic_assert_irq(&self, irq: u8) -> Result<()> {
         let mut data = bhyve_api::vm_ioapic_irq { irq: irq as i32 };
         unsafe { self.ioctl(bhyve_api::VM_IOAPIC_ASSERT_IRQ, &mut data) }
     }
-    #[allow(unused)]
+    /// Deasserts the requested pin of the IOAPIC.
     pub fn ioapic_deassert_irq(&self, irq: u8) -> Result<()> {
         let mut data = bhyve_api::vm_ioapic_irq { irq: irq as i32 };
         unsafe { self.ioctl(bhyve_api::VM_IOAPIC_DEASSERT_IRQ, &mut data) }
     }
-    #[allow(unused)]
+    /// Pulses the requested pin of the IOAPIC, turning it on then off.
     pub fn ioapic_pulse_irq(&self, irq: u8) -> Result<()> {
         let mut data = bhyve_api::vm_ioapic_irq { irq: irq as i32 };
         unsafe { self.ioctl(bhyve_api::VM_IOAPIC_PULSE_IRQ, &mut data) }
@@ -351,6 +358,14 @@ impl VmmHdl {
         unsafe { self.ioctl(bhyve_api::VM_PMTMR_LOCATE, port as *mut usize) }
     }
 
+    /// Reads the lower 32 bits of the capabilities register of the in-kernel
+    /// HPET.
+    pub fn hpet_capabilities(&self) -> Result<u32> {
+        let mut data = bhyve_api::vm_hpet_cap::default();
+        unsafe { self.ioctl(bhyve_api::VM_GET_HPET_CAPABILITIES, &mut data)? };
+        Ok(data.capabilities)
+    }
+
     pub fn suspend(
         &self,
         how: bhyve_api::vm_suspend_how,
@@ -420,7 +435,7 @@ impl VmmHdl {
     }
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "bench-hooks"))]
 impl VmmHdl {
     /// Build a VmmHdl instance suitable for unit tests, but nothing else, since
     /// it will not be backed by any real vmm resources.
@@ -9,9 +9,11 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use crate::accessors::*;
+use crate::clock::Clock;
 use crate::hw;
 use crate::mmio::MmioBus;
 use crate::pio::PioBus;
+use crate::spin::SpinYield;
 use crate::vcpu::{Vcpu, MAXCPU};
 use crate::vmm::{create_vm, CreateOpts, PhysMap, VmmHdl};
 
@@ -65,6 +67,9 @@ pub struct Machine {
     pub acc_mem: MemAccessor,
     pub acc_msi: MsiAccessor,
 
+    /// Time as seen by the devices emulated in userspace
+    pub clock: Arc<Clock>,
+
     // Track if machine has been destroyed prior to drop
     destroyed: AtomicBool,
 }
@@ -130,7 +135,7 @@ impl Drop for Machine {
     }
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "bench-hooks"))]
 impl Machine {
     pub(crate) fn new_test() -> Result<Self> {
         // Create a test handle with 2M tempfile to use as our VM "memory"
@@ -144,8 +149,13 @@ impl Machine {
         let bus_mmio = Arc::new(MmioBus::new(MAX_PHYSMEM));
         let bus_pio = Arc::new(PioBus::new());
 
-        let vcpus =
-            vec![Vcpu::new(hdl.clone(), 0, bus_mmio.clone(), bus_pio.clone())];
+        let vcpus = vec![Vcpu::new(
+            hdl.clone(),
+            0,
+            bus_mmio.clone(),
+            bus_pio.clone(),
+            Arc::new(SpinYield::new(1)),
+        )];
 
         let acc_mem = MemAccessor::new(map.memctx());
         let acc_msi = MsiAccessor::new(hdl.clone());
@@ -158,6 +168,7 @@ impl Machine {
 
             acc_mem,
             acc_msi,
+            clock: Clock::new(),
 
             bus_mmio,
             bus_pio,
@@ -233,6 +244,23 @@ impl Builder {
         self.physmap.as_mut().unwrap().add_rom(name.to_string(), start, len)?;
         Ok(self)
     }
+
+    /// Reserves a region of the guest's address space for hot-pluggable
+    /// memory, which is backed by a memory segment only once plugged (see
+    /// [`PhysMap::hotplug_region()`]).
+    pub fn add_hotplug_mem_region(
+        mut self,
+        start: usize,
+        len: usize,
+        name: &str,
+    ) -> Result<Self> {
+        self.physmap.as_mut().unwrap().add_hotplug_mem(
+            name.to_string(),
+            start,
+            len,
+        )?;
+        Ok(self)
+    }
     /// Registers a region of memory for MMIO.
     pub fn add_mmio_region(
         mut self,
@@ -269,6 +297,7 @@ impl Builder {
         let acc_mem = MemAccessor::new(map.memctx());
         let acc_msi = MsiAccessor::new(hdl.clone());
 
+        let spin = Arc::new(SpinYield::new(self.max_cpu as usize));
         let vcpus = (0..self.max_cpu)
             .map(|id| {
                 Vcpu::new(
@@ -276,6 +305,7 @@ impl Builder {
                     id as i32,
                     bus_mmio.clone(),
                     bus_pio.clone(),
+                    spin.clone(),
                 )
             })
             .collect();
@@ -288,6 +318,7 @@ impl Builder {
 
             acc_mem,
             acc_msi,
+            clock: Clock::new(),
 
             bus_mmio,
             bus_pio,
@@ -10,6 +10,7 @@ use std::mem::{size_of, size_of_val};
 use std::ops::RangeInclusive;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr::{copy_nonoverlapping, NonNull};
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::{Arc, Mutex};
 
 use libc::iovec;
@@ -50,8 +51,33 @@ pub(crate) struct MapSeg {
 pub(crate) enum MapKind {
     Dram(MapSeg),
     Rom(MapSeg),
+    /// Hot-pluggable memory, which is backed and mapped into the guest only
+    /// while plugged
+    Hotplug(HotplugSeg),
     MmioReserve,
 }
+impl MapKind {
+    /// The segment backing this region, if it is (plugged) guest DRAM
+    fn dram(&self) -> Option<&MapSeg> {
+        match self {
+            MapKind::Dram(seg)
+            | MapKind::Hotplug(HotplugSeg { mapped: Some(seg), .. }) => {
+                Some(seg)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct HotplugSeg {
+    id: i32,
+    /// Whether the segment has been allocated.  bhyve offers no means of
+    /// freeing a single segment, so once allocated, it persists until the VM
+    /// is destroyed, and is reused each time the region is plugged.
+    allocated: bool,
+    /// The segment, while the region is plugged
+    mapped: Option<MapSeg>,
+}
 
 pub(crate) struct MapEnt {
     name: String,
@@ -61,7 +87,7 @@ pub(crate) struct MapEnt {
 pub struct PhysMap {
     map: Arc<Mutex<ASpace<MapEnt>>>,
     hdl: Arc<VmmHdl>,
-    next_segid: i32,
+    next_segid: AtomicI32,
     memctx: Arc<MemCtx>,
 }
 impl PhysMap {
@@ -71,7 +97,7 @@ impl PhysMap {
 
         let map = Arc::new(Mutex::new(ASpace::new(0, size - 1)));
         let memctx = Arc::new(MemCtx { map: map.clone() });
-        Self { map, hdl, next_segid: 0, memctx }
+        Self { map, hdl, next_segid: AtomicI32::new(0), memctx }
     }
 
     /// Create and map a memory region for the guest
@@ -141,6 +167,77 @@ impl PhysMap {
             .map_err(Error::from)
     }
 
+    /// Reserve a region of the guest address space for hot-pluggable memory,
+    /// which is neither backed nor mapped until it is plugged through its
+    /// [`HotplugRegion`].
+    pub(crate) fn add_hotplug_mem(
+        &mut self,
+        name: String,
+        addr: usize,
+        size: usize,
+    ) -> Result<()> {
+        let id = self.next_segid.fetch_add(1, Ordering::Relaxed);
+
+        let mut guard = self.map.lock().unwrap();
+        guard
+            .register(
+                addr,
+                size,
+                MapEnt {
+                    name,
+                    kind: MapKind::Hotplug(HotplugSeg {
+                        id,
+                        allocated: false,
+                        mapped: None,
+                    }),
+                },
+            )
+            .map_err(Error::from)
+    }
+
+    /// Returns a handle through which the hot-pluggable memory region `name`
+    /// is plugged and unplugged, if there is such a region.
+    pub fn hotplug_region(&self, name: &str) -> Option<HotplugRegion> {
+        self.hotplug_regions().into_iter().find(|region| region.name == name)
+    }
+
+    /// Returns handles to all of the hot-pluggable memory regions.
+    pub fn hotplug_regions(&self) -> Vec<HotplugRegion> {
+        let guard = self.map.lock().unwrap();
+        guard
+            .iter()
+            .filter_map(|(addr, len, ent)| match &ent.kind {
+                MapKind::Hotplug(_) => Some(HotplugRegion {
+                    map: self.map.clone(),
+                    hdl: self.hdl.clone(),
+                    name: ent.name.clone(),
+                    addr,
+                    len,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Allocate `size` bytes of memory for a device to map into the guest at
+    /// an address of the guest's choosing, such as within a BAR.
+    pub fn create_device_mem(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> Result<Arc<DeviceMem>> {
+        let id = self.next_segid.fetch_add(1, Ordering::Relaxed);
+        self.hdl.create_memseg(id, size, Some(name))?;
+        let seg_off = self.hdl.devmem_offset(id)?;
+        let map_seg = Mapping::new(size, Prot::RW, &self.hdl, seg_off as i64)?;
+        Ok(Arc::new(DeviceMem {
+            hdl: self.hdl.clone(),
+            id,
+            map_seg,
+            placed: Mutex::new(None),
+        }))
+    }
+
     pub(crate) fn post_reinit(&self) -> Result<()> {
         // Since VM_REINIT unmaps all non-sysmem segments from the address space
         // of the VM, we must reestablish the ROM mapping(s) now.
@@ -156,6 +253,15 @@ impl PhysMap {
                 )?;
             }
         }
+        drop(guard);
+
+        // Hot-pluggable memory is left unplugged, as a rebooted guest expects.
+        for region in self.hotplug_regions() {
+            region.with_seg(|hot| {
+                hot.mapped = None;
+                Ok(())
+            })?;
+        }
         Ok(())
     }
 
@@ -176,10 +282,9 @@ impl PhysMap {
             None => Prot::ALL,
         };
 
-        let segid = self.next_segid;
+        let segid = self.next_segid.fetch_add(1, Ordering::Relaxed);
         self.hdl.create_memseg(segid, size, rom_name)?;
         self.hdl.map_memseg(segid, addr, size, 0, prot)?;
-        self.next_segid += 1;
         // TODO: if we somehow fail the later stages of this operation, the
         // memseg and its mapping established in the VMM will persist
 
@@ -196,8 +301,178 @@ impl PhysMap {
     }
 }
 
-#[cfg(test)]
+/// Handle to a region of hot-pluggable memory, obtained through
+/// [`PhysMap::hotplug_region()`].
+///
+/// The memory segment backing the region is allocated when the region is
+/// first plugged, and is unmapped from the guest when it is unplugged.  bhyve
+/// offers no means of freeing a single segment, so the host memory backing a
+/// region which has been plugged is only released when the VM is destroyed.
+#[derive(Clone)]
+pub struct HotplugRegion {
+    map: Arc<Mutex<ASpace<MapEnt>>>,
+    hdl: Arc<VmmHdl>,
+    name: String,
+    addr: usize,
+    len: usize,
+}
+impl HotplugRegion {
+    /// Guest-physical address of the region
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Size of the region, in bytes
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the region is backed and mapped into the guest.
+    pub fn is_plugged(&self) -> bool {
+        let guard = self.map.lock().unwrap();
+        matches!(
+            guard.region_at(self.addr),
+            Ok((
+                _,
+                _,
+                MapEnt {
+                    kind: MapKind::Hotplug(HotplugSeg { mapped: Some(_), .. }),
+                    ..
+                }
+            ))
+        )
+    }
+
+    /// Backs the region with memory, and maps it into the guest as DRAM, if it
+    /// is not already.
+    pub fn plug(&self) -> Result<()> {
+        self.with_seg(|hot| {
+            if hot.mapped.is_some() {
+                return Ok(());
+            }
+            if !hot.allocated {
+                self.hdl.create_memseg(hot.id, self.len, None)?;
+                hot.allocated = true;
+            }
+            self.hdl.map_memseg(hot.id, self.addr, self.len, 0, Prot::ALL)?;
+            let mapped = self.hdl.devmem_offset(hot.id).and_then(|seg_off| {
+                let map_guest = Mapping::new(
+                    self.len,
+                    Prot::ALL,
+                    &self.hdl,
+                    self.addr as i64,
+                )?;
+                let map_seg = Mapping::new(
+                    self.len,
+                    Prot::RW,
+                    &self.hdl,
+                    seg_off as i64,
+                )?;
+                Ok(MapSeg { id: hot.id, map_guest, map_seg })
+            });
+            match mapped {
+                Ok(seg) => {
+                    hot.mapped = Some(seg);
+                    Ok(())
+                }
+                Err(e) => {
+                    let _ = self.hdl.unmap_memseg(self.addr, self.len);
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    /// Unmaps the region from the guest, if it is plugged.  Its segment is
+    /// retained, with its contents, to back the region when next plugged.
+    pub fn unplug(&self) -> Result<()> {
+        self.with_seg(|hot| {
+            if hot.mapped.is_some() {
+                self.hdl.unmap_memseg(self.addr, self.len)?;
+                hot.mapped = None;
+            }
+            Ok(())
+        })
+    }
+
+    fn with_seg(
+        &self,
+        f: impl FnOnce(&mut HotplugSeg) -> Result<()>,
+    ) -> Result<()> {
+        // The address space offers no mutable access to its entries, so the
+        // region's entry is removed while it is altered.
+        let mut guard = self.map.lock().unwrap();
+        let mut ent = guard.unregister(self.addr).map_err(Error::from)?;
+        let res = match &mut ent.kind {
+            MapKind::Hotplug(hot) => f(hot),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not hot-pluggable memory", self.name),
+            )),
+        };
+        guard
+            .register(self.addr, self.len, ent)
+            .expect("entry was just removed");
+        res
+    }
+}
+
+/// Memory which a device maps into the guest, at an address which may change
+/// over the life of the device, obtained through
+/// [`PhysMap::create_device_mem()`].
+///
+/// Guest accesses to the memory, while it is placed, are serviced directly,
+/// without exiting to userspace.  The device reaches its contents through
+/// [`DeviceMem::region()`] regardless of where (or whether) it is placed.
+pub struct DeviceMem {
+    hdl: Arc<VmmHdl>,
+    id: i32,
+    map_seg: Arc<Mapping>,
+    /// Guest-physical address at which the memory is mapped, if any
+    placed: Mutex<Option<usize>>,
+}
+impl DeviceMem {
+    /// Size of the memory, in bytes
+    pub fn size(&self) -> usize {
+        self.map_seg.len
+    }
+
+    /// Maps the memory into the guest at `addr`, or unmaps it from the guest
+    /// if `None`, in place of wherever it was mapped before.
+    pub fn place(&self, addr: Option<usize>) -> Result<()> {
+        let mut placed = self.placed.lock().unwrap();
+        if let Some(old) = placed.take() {
+            // The mapping may already have been removed by VM_REINIT, which
+            // unmaps all segments other than guest DRAM.
+            let _ = self.hdl.unmap_memseg(old, self.size());
+        }
+        if let Some(addr) = addr {
+            self.hdl.map_memseg(self.id, addr, self.size(), 0, Prot::RW)?;
+            *placed = Some(addr);
+        }
+        Ok(())
+    }
+
+    /// Returns the guest-physical address at which the memory is mapped, if
+    /// it is.
+    pub fn placement(&self) -> Option<usize> {
+        *self.placed.lock().unwrap()
+    }
+
+    /// Access to the contents of the memory
+    pub fn region(&self) -> SubMapping<'_> {
+        SubMapping {
+            backing: Backing::Base(self.map_seg.clone()),
+            ptr: self.map_seg.ptr,
+            len: self.map_seg.len,
+            prot: self.map_seg.prot,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "bench-hooks"))]
 impl PhysMap {
+    #[cfg(test)]
     pub(crate) fn new_test(size: usize) -> Self {
         let hdl =
             VmmHdl::new_test(size).expect("create tempfile backed test hdl");
@@ -246,6 +521,21 @@ impl PhysMap {
             .map_err(Error::from)
     }
 
+    /// Create device memory on an instance backed with a fake VmmHdl
+    #[cfg(test)]
+    pub(crate) fn create_test_device_mem(
+        &self,
+        size: usize,
+    ) -> Result<Arc<DeviceMem>> {
+        let map_seg = Mapping::new(size, Prot::RW, &self.hdl, 0)?;
+        Ok(Arc::new(DeviceMem {
+            hdl: self.hdl.clone(),
+            id: -1,
+            map_seg,
+            placed: Mutex::new(None),
+        }))
+    }
+
     /// Make fake VmmHdl (backed with tempfile) for use in testing
     fn seg_test_map(
         &mut self,
@@ -885,8 +1175,8 @@ impl MemCtx {
         let ent = guard
             .iter()
             .find_map(|(_addr, _len, ent)| match &ent.kind {
-                MapKind::Dram(seg) if ent.name == name => Some(&seg.map_seg),
                 MapKind::Rom(seg) if ent.name == name => Some(&seg.map_seg),
+                kind if ent.name == name => kind.dram().map(|seg| &seg.map_seg),
                 _ => None,
             })
             .ok_or_else(|| {
@@ -898,6 +1188,42 @@ impl MemCtx {
         Ok(SubMapping::new_base(self, ent).constrain_access(Prot::WRITE))
     }
 
+    /// Like `direct_readable_region`, but looks up the region by name.
+    pub fn direct_readable_region_by_name(
+        &self,
+        name: &str,
+    ) -> Result<SubMapping> {
+        let guard = self.map.lock().unwrap();
+        let ent = guard
+            .iter()
+            .find_map(|(_addr, _len, ent)| match &ent.kind {
+                MapKind::Rom(seg) if ent.name == name => Some(&seg.map_seg),
+                kind if ent.name == name => kind.dram().map(|seg| &seg.map_seg),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("memory region {} not found", name),
+                )
+            })?;
+        Ok(SubMapping::new_base(self, ent).constrain_access(Prot::READ))
+    }
+
+    /// Returns the name and extent of each region of guest DRAM (including
+    /// plugged hot-pluggable memory), in order of ascending address.
+    pub fn dram_regions(&self) -> Vec<(String, GuestRegion)> {
+        let guard = self.map.lock().unwrap();
+        guard
+            .iter()
+            .filter_map(|(addr, len, ent)| {
+                ent.kind.dram().map(|_| {
+                    (ent.name.clone(), GuestRegion(GuestAddr(addr as u64), len))
+                })
+            })
+            .collect()
+    }
+
     /// Like `writable_region`, but accesses the underlying memory segment
     /// directly, bypassing protection enforced to the guest and tracking of
     /// dirty pages in the guest-physical address space.
@@ -939,6 +1265,9 @@ impl MemCtx {
             let (prot, seg) = match &ent.kind {
                 MapKind::Dram(seg) => Some((Prot::RW, seg)),
                 MapKind::Rom(seg) => Some((Prot::READ, seg)),
+                MapKind::Hotplug(hot) => {
+                    hot.mapped.as_ref().map(|seg| (Prot::RW, seg))
+                }
                 MapKind::MmioReserve => None,
             }?;
 
@@ -980,12 +1309,10 @@ impl MemCtx {
     /// inclusive range.
     pub fn mem_bounds(&self) -> Option<RangeInclusive<GuestAddr>> {
         let guard = self.map.lock().unwrap();
-        let lowest = guard
-            .lowest_addr(|entry| matches!(entry.kind, MapKind::Dram(_)))?
-            as u64;
-        let highest = guard
-            .highest_addr(|entry| matches!(entry.kind, MapKind::Dram(_)))?
-            as u64;
+        let lowest =
+            guard.lowest_addr(|entry| entry.kind.dram().is_some())? as u64;
+        let highest =
+            guard.highest_addr(|entry| entry.kind.dram().is_some())? as u64;
         Some(GuestAddr(lowest)..=GuestAddr(highest))
     }
 }
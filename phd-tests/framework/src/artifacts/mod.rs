@@ -13,6 +13,10 @@ pub use store::Store as ArtifactStore;
 
 pub const DEFAULT_PROPOLIS_ARTIFACT: &str = "__DEFAULT_PROPOLIS";
 
+/// The artifact name used for a base Propolis server supplied by a local path,
+/// rather than by an entry in the artifact manifest.
+pub const BASE_PROPOLIS_ARTIFACT: &str = "__BASE_PROPOLIS";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum ArtifactKind {
@@ -212,10 +212,23 @@ impl Store {
         &mut self,
         propolis_server_cmd: &Utf8Path,
     ) -> anyhow::Result<()> {
-        if self.artifacts.contains_key(DEFAULT_PROPOLIS_ARTIFACT) {
+        self.add_propolis_artifact_from_local_cmd(
+            DEFAULT_PROPOLIS_ARTIFACT,
+            propolis_server_cmd,
+        )
+    }
+
+    /// Adds the Propolis server at `propolis_server_cmd` to the store under
+    /// the name `artifact_name`.
+    pub fn add_propolis_artifact_from_local_cmd(
+        &mut self,
+        artifact_name: &str,
+        propolis_server_cmd: &Utf8Path,
+    ) -> anyhow::Result<()> {
+        if self.artifacts.contains_key(artifact_name) {
             anyhow::bail!(
                 "artifact store already contains key {}",
-                DEFAULT_PROPOLIS_ARTIFACT
+                artifact_name
             );
         }
 
@@ -243,7 +256,7 @@ impl Store {
         };
 
         let _old = self.artifacts.insert(
-            DEFAULT_PROPOLIS_ARTIFACT.to_string(),
+            artifact_name.to_string(),
             Mutex::new(StoredArtifact::new(artifact)),
         );
         assert!(_old.is_none());
@@ -283,6 +283,8 @@ impl super::DiskConfig for CrucibleDisk {
                 request_json: serde_json::to_string(&vcr)
                     .expect("VolumeConstructionRequest should serialize"),
                 readonly: false,
+                prefetch: None,
+                retry: None,
             }),
         )
     }
@@ -68,6 +68,8 @@ impl super::DiskConfig for FileBackedDisk {
             StorageBackendV0::File(FileStorageBackend {
                 path: self.disk_path.to_string_lossy().to_string(),
                 readonly: false,
+                shared: false,
+                workers: None,
             }),
         )
     }
@@ -0,0 +1,45 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Guest OS adaptations for minimal images which boot a kernel and a
+//! busybox-based initramfs, with no further userland.
+//!
+//! These images boot in a fraction of the time taken by a full distribution,
+//! which makes them well-suited to tests of device behavior that need nothing
+//! more than a shell.  Login prompts and busybox's default shell prompt vary
+//! with how the image was assembled, so the adapter logs in as root and then
+//! sets a prompt of its own.
+
+use super::{CommandSequence, CommandSequenceEntry, GuestOs};
+
+pub(super) struct MinimalInitramfs;
+
+impl GuestOs for MinimalInitramfs {
+    fn get_login_sequence(&self) -> CommandSequence {
+        CommandSequence(vec![
+            CommandSequenceEntry::WaitFor("login: "),
+            CommandSequenceEntry::WriteStr("root"),
+            CommandSequenceEntry::WaitFor("# "),
+            // Split the prompt string in two so that the echoed command does
+            // not itself match the new prompt.
+            CommandSequenceEntry::WriteStr("export PS1='phd-minimal''# '"),
+            CommandSequenceEntry::WaitFor(self.get_shell_prompt()),
+        ])
+    }
+
+    fn get_shell_prompt(&self) -> &'static str {
+        "phd-minimal# "
+    }
+
+    fn read_only_fs(&self) -> bool {
+        // The initramfs is unpacked into a writable root file system.
+        false
+    }
+
+    fn persistent_fs(&self) -> bool {
+        // The root file system lives only in memory, so it can't be used for
+        // tests which expect data to persist on disk.
+        false
+    }
+}
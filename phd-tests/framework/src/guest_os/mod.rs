@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 
 mod alpine;
 mod debian11_nocloud;
+mod minimal_initramfs;
 mod ubuntu22_04;
 
 /// An entry in a sequence of interactions with the guest's command prompt.
@@ -34,6 +35,12 @@ pub(super) trait GuestOs {
 
     /// Indicates whether the guest has a read-only filesystem.
     fn read_only_fs(&self) -> bool;
+
+    /// Indicates whether files written by the guest are stored on its boot
+    /// disk, and so persist across a reboot.
+    fn persistent_fs(&self) -> bool {
+        !self.read_only_fs()
+    }
 }
 
 #[allow(dead_code)]
@@ -43,6 +50,7 @@ pub enum GuestOsKind {
     Alpine,
     Debian11NoCloud,
     Ubuntu2204,
+    MinimalInitramfs,
 }
 
 impl FromStr for GuestOsKind {
@@ -53,6 +61,7 @@ impl FromStr for GuestOsKind {
             "alpine" => Ok(Self::Alpine),
             "debian11nocloud" => Ok(Self::Debian11NoCloud),
             "ubuntu2204" => Ok(Self::Ubuntu2204),
+            "minimalinitramfs" => Ok(Self::MinimalInitramfs),
             _ => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 format!("Unrecognized guest OS kind {}", s),
@@ -68,5 +77,8 @@ pub(super) fn get_guest_os_adapter(kind: GuestOsKind) -> Box<dyn GuestOs> {
             Box::new(debian11_nocloud::Debian11NoCloud)
         }
         GuestOsKind::Ubuntu2204 => Box::new(ubuntu22_04::Ubuntu2204),
+        GuestOsKind::MinimalInitramfs => {
+            Box::new(minimal_initramfs::MinimalInitramfs)
+        }
     }
 }
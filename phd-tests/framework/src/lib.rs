@@ -26,11 +26,16 @@
 //! separate Propolis server process that may have been spawned in a different
 //! environment. The `spawn_successor_vm` function provides a shorthand way to
 //! do this.
+//!
+//! A run may also be supplied with a "base" Propolis server, generally an
+//! older build than the one under test. Tests can spawn VMs on it via
+//! `base_environment_builder` to check that VMs migrate successfully between
+//! the two versions.
 
 use std::{ops::Range, rc::Rc};
 
 use anyhow::Context;
-use artifacts::DEFAULT_PROPOLIS_ARTIFACT;
+use artifacts::{BASE_PROPOLIS_ARTIFACT, DEFAULT_PROPOLIS_ARTIFACT};
 use camino::Utf8PathBuf;
 
 use disk::DiskFactory;
@@ -60,6 +65,8 @@ pub struct Framework {
     pub(crate) default_guest_memory_mib: u64,
     pub(crate) default_guest_os_artifact: String,
     pub(crate) default_bootrom_artifact: String,
+    pub(crate) base_propolis_artifact: Option<String>,
+    pub(crate) vnics: Vec<String>,
 
     // The disk factory used to be a freestanding struct that took references to
     // an artifact store and port allocator that were owned by someone else.
@@ -72,9 +79,22 @@ pub struct Framework {
     pub(crate) port_allocator: Rc<PortAllocator>,
 }
 
+/// Where to find the base Propolis server for a test run.
+#[derive(Clone, Debug)]
+pub enum BasePropolisSource {
+    /// Use the Propolis server artifact with the given name from the artifact
+    /// manifest.
+    Artifact(String),
+
+    /// Use the Propolis server at the given local path.
+    LocalCmd(Utf8PathBuf),
+}
+
 pub struct FrameworkParameters {
     pub propolis_server_path: Utf8PathBuf,
+    pub base_propolis: Option<BasePropolisSource>,
     pub crucible_downstairs_cmd: Option<Utf8PathBuf>,
+    pub vnics: Vec<String>,
 
     pub tmp_directory: Utf8PathBuf,
     pub artifact_toml: Utf8PathBuf,
@@ -111,6 +131,29 @@ impl Framework {
                 )
             })?;
 
+        let base_propolis_artifact = match params.base_propolis {
+            None => None,
+            Some(BasePropolisSource::Artifact(name)) => {
+                // Make sure the artifact exists up front, rather than failing
+                // each of the tests that need it.
+                artifact_store
+                    .get_propolis_server(&name)
+                    .context("getting base Propolis server artifact")?;
+                Some(name)
+            }
+            Some(BasePropolisSource::LocalCmd(path)) => {
+                artifact_store
+                    .add_propolis_artifact_from_local_cmd(
+                        BASE_PROPOLIS_ARTIFACT,
+                        &path,
+                    )
+                    .with_context(|| {
+                        format!("adding base Propolis server '{}'", &path)
+                    })?;
+                Some(BASE_PROPOLIS_ARTIFACT.to_owned())
+            }
+        };
+
         let artifact_store = Rc::new(artifact_store);
         let port_allocator = Rc::new(PortAllocator::new(params.port_range));
         let disk_factory = DiskFactory::new(
@@ -128,6 +171,8 @@ impl Framework {
             default_guest_memory_mib: params.default_guest_memory_mib,
             default_guest_os_artifact: params.default_guest_os_artifact,
             default_bootrom_artifact: params.default_bootrom_artifact,
+            base_propolis_artifact,
+            vnics: params.vnics,
             artifact_store,
             disk_factory,
             port_allocator,
@@ -158,6 +203,14 @@ impl Framework {
         EnvironmentSpec::new(VmLocation::Local, DEFAULT_PROPOLIS_ARTIFACT)
     }
 
+    /// Yields an environment builder that runs VMs using the base Propolis
+    /// server supplied to this run, or `None` if no base server was supplied.
+    pub fn base_environment_builder(&self) -> Option<EnvironmentSpec> {
+        self.base_propolis_artifact
+            .as_deref()
+            .map(|name| EnvironmentSpec::new(VmLocation::Local, name))
+    }
+
     /// Spawns a test VM using the default configuration returned from
     /// `vm_builder` and the default environment returned from
     /// `environment_builder`.
@@ -221,4 +274,11 @@ impl Framework {
     pub fn crucible_enabled(&self) -> bool {
         self.disk_factory.crucible_enabled()
     }
+
+    /// Yields the names of the host VNICs that tests may attach to their
+    /// guests' network devices. This can be used to skip tests that need more
+    /// VNICs than the runner was given.
+    pub fn vnics(&self) -> &[String] {
+        &self.vnics
+    }
 }
@@ -7,7 +7,11 @@ use std::sync::Arc;
 use anyhow::Context;
 use propolis_client::{
     instance_spec::SpecBuilderV0,
-    types::{NvmeDisk, PciPath, SerialPortNumber, StorageDeviceV0, VirtioDisk},
+    types::{
+        AhciDisk, DiskPriority, NetworkBackendV0, NetworkDeviceV0, NvmeDisk,
+        PciPath, SerialPortNumber, StorageDeviceV0, VirtioDisk,
+        VirtioNetworkBackend, VirtioNic,
+    },
 };
 
 use crate::{
@@ -21,6 +25,7 @@ use crate::{
 pub enum DiskInterface {
     Virtio,
     Nvme,
+    Ahci,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -37,6 +42,12 @@ struct DiskRequest {
     pci_device_num: u8,
 }
 
+#[derive(Clone, Debug)]
+struct NicRequest {
+    vnic_name: String,
+    pci_device_num: u8,
+}
+
 pub struct VmConfig {
     vm_name: String,
     cpus: u8,
@@ -44,6 +55,7 @@ pub struct VmConfig {
     bootrom_artifact: String,
     boot_disk: DiskRequest,
     data_disks: Vec<DiskRequest>,
+    nics: Vec<NicRequest>,
 }
 
 impl VmConfig {
@@ -68,6 +80,7 @@ impl VmConfig {
             bootrom_artifact: bootrom.to_owned(),
             boot_disk,
             data_disks: Vec::new(),
+            nics: Vec::new(),
         }
     }
 
@@ -118,6 +131,15 @@ impl VmConfig {
         self
     }
 
+    /// Adds a virtio NIC backed by the host VNIC `vnic_name`.
+    pub fn nic(&mut self, vnic_name: &str, pci_device_num: u8) -> &mut Self {
+        self.nics.push(NicRequest {
+            vnic_name: vnic_name.to_owned(),
+            pci_device_num,
+        });
+        self
+    }
+
     pub(crate) fn vm_spec(
         &self,
         framework: &Framework,
@@ -208,11 +230,27 @@ impl VmConfig {
                     StorageDeviceV0::VirtioDisk(VirtioDisk {
                         backend_name: backend_name.clone(),
                         pci_path,
+                        write_protected: false,
+                        priority: DiskPriority::Normal,
+                        disabled: false,
+                        deferred_start: false,
                     })
                 }
                 DiskInterface::Nvme => StorageDeviceV0::NvmeDisk(NvmeDisk {
                     backend_name: backend_name.clone(),
                     pci_path,
+                    write_protected: false,
+                    priority: DiskPriority::Normal,
+                    disabled: false,
+                    deferred_start: false,
+                }),
+                DiskInterface::Ahci => StorageDeviceV0::AhciDisk(AhciDisk {
+                    backend_name: backend_name.clone(),
+                    pci_path,
+                    write_protected: false,
+                    priority: DiskPriority::Normal,
+                    disabled: false,
+                    deferred_start: false,
                 }),
             };
 
@@ -226,6 +264,27 @@ impl VmConfig {
                 .context("adding storage device to spec")?;
         }
 
+        for (idx, nic) in self.nics.iter().enumerate() {
+            let backend_name = format!("nic-backend{}", idx);
+            let device_spec = NetworkDeviceV0::VirtioNic(VirtioNic {
+                backend_name: backend_name.clone(),
+                pci_path: PciPath::new(0, nic.pci_device_num, 0).unwrap(),
+                disabled: false,
+            });
+            let backend_spec = NetworkBackendV0::Virtio(VirtioNetworkBackend {
+                vnic_name: nic.vnic_name.clone(),
+            });
+
+            spec_builder
+                .add_network_device(
+                    format!("nic-device{}", idx),
+                    device_spec,
+                    backend_name,
+                    backend_spec,
+                )
+                .context("adding network device to spec")?;
+        }
+
         spec_builder
             .add_serial_port(SerialPortNumber::Com1)
             .context("adding serial port to spec")?;
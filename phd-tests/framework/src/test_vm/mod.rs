@@ -22,7 +22,7 @@ use propolis_client::types::{
     InstanceGetResponse, InstanceMigrateInitiateRequest, InstanceProperties,
     InstanceSerialConsoleHistoryResponse, InstanceSpecEnsureRequest,
     InstanceSpecGetResponse, InstanceState, InstanceStateRequested,
-    MigrationState, VersionedInstanceSpec,
+    MigrationState, PciConfigSpace, VersionedInstanceSpec,
 };
 use propolis_client::{Client, ResponseValue};
 use thiserror::Error;
@@ -36,13 +36,12 @@ type PropolisClientResult<T> = StdResult<ResponseValue<T>, PropolisClientError>;
 
 pub(crate) mod config;
 pub(crate) mod environment;
+pub mod pci_config;
 mod server;
 pub(crate) mod spec;
 
 pub use config::*;
-pub use environment::VmLocation;
-
-use self::environment::EnvironmentSpec;
+pub use environment::{EnvironmentSpec, VmLocation};
 
 #[derive(Debug, Error)]
 pub enum VmStateError {
@@ -216,6 +215,7 @@ impl TestVm {
             bootrom_id: Uuid::default(),
             memory: memory_mib,
             vcpus,
+            metadata: Default::default(),
         };
 
         let versioned_spec =
@@ -379,6 +379,8 @@ impl TestVm {
                             migration_id,
                             src_addr: source.server.server_addr().to_string(),
                             src_uuid: Uuid::default(),
+                            compression: None,
+                            bandwidth_limit: None,
                         },
                     ))
                     .await
@@ -455,6 +457,19 @@ impl TestVm {
         })
     }
 
+    /// Fetches the configuration space of each of the VM's PCI functions.
+    pub fn get_pci_config(&self) -> Result<Vec<PciConfigSpace>> {
+        self.rt.block_on(async {
+            Ok(self
+                .client
+                .debug_pci_config_get()
+                .send()
+                .await?
+                .into_inner()
+                .functions)
+        })
+    }
+
     pub fn wait_for_state(
         &self,
         target: InstanceState,
@@ -627,6 +642,12 @@ impl TestVm {
     pub fn guest_os_has_read_only_fs(&self) -> bool {
         self.guest_os.read_only_fs()
     }
+
+    /// Indicates whether files written by this VM's guest OS persist on its
+    /// boot disk across a reboot.
+    pub fn guest_os_has_persistent_fs(&self) -> bool {
+        self.guest_os.persistent_fs()
+    }
 }
 
 impl Drop for TestVm {
@@ -0,0 +1,74 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for comparing the PCI configuration spaces reported by two VMs.
+
+use std::collections::BTreeMap;
+
+use propolis_client::types::{PciConfigSpace, PciPath};
+
+/// Parses a hex dump returned by the server's PCI config debug API.
+fn parse_hex_dump(dump: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for line in dump.lines() {
+        let (_offset, data) = line.split_once(':')?;
+        for byte in data.split_whitespace() {
+            bytes.push(u8::from_str_radix(byte, 16).ok()?);
+        }
+    }
+    Some(bytes)
+}
+
+fn path_str(path: &PciPath) -> String {
+    format!("{}.{}.{}", path.bus, path.device, path.function)
+}
+
+/// Compares the configuration spaces reported by a migration source with
+/// those reported by its target, returning a description of each difference.
+/// An empty result indicates the two match.
+pub fn diff_pci_config(
+    source: &[PciConfigSpace],
+    target: &[PciConfigSpace],
+) -> Vec<String> {
+    let index = |spaces: &[PciConfigSpace]| {
+        spaces
+            .iter()
+            .map(|s| (path_str(&s.pci_path), parse_hex_dump(&s.data)))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let source = index(source);
+    let mut target = index(target);
+
+    let mut diffs = Vec::new();
+    for (path, src) in source {
+        let Some(dst) = target.remove(&path) else {
+            diffs.push(format!("{}: missing from target", path));
+            continue;
+        };
+        let (Some(src), Some(dst)) = (src, dst) else {
+            diffs.push(format!("{}: malformed hex dump", path));
+            continue;
+        };
+        if src.len() != dst.len() {
+            diffs.push(format!(
+                "{}: length {:#x} in source, {:#x} in target",
+                path,
+                src.len(),
+                dst.len()
+            ));
+        }
+        for (off, (s, d)) in src.iter().zip(dst.iter()).enumerate() {
+            if s != d {
+                diffs.push(format!(
+                    "{} @ {:#04x}: {:02x} in source, {:02x} in target",
+                    path, off, s, d
+                ));
+            }
+        }
+    }
+    for path in target.into_keys() {
+        diffs.push(format!("{}: missing from source", path));
+    }
+    diffs
+}
@@ -35,10 +35,27 @@ pub struct RunOptions {
     #[clap(long, value_parser)]
     pub propolis_server_cmd: Utf8PathBuf,
 
+    /// The command to use to launch a "base" Propolis server, against which
+    /// migrations to and from the server under test are tested.  Typically,
+    /// this is a build of the most recently released version.
+    #[clap(long, value_parser, conflicts_with = "base_propolis_artifact")]
+    pub base_propolis_cmd: Option<Utf8PathBuf>,
+
+    /// The artifact store key of a Propolis server to use as the base server
+    /// for cross-version migration tests (see `base-propolis-cmd`).
+    #[clap(long, value_parser)]
+    pub base_propolis_artifact: Option<String>,
+
     /// The command to use to launch Crucible downstairs servers.
     #[clap(long, value_parser)]
     pub crucible_downstairs_cmd: Option<Utf8PathBuf>,
 
+    /// The name of a host VNIC that tests may attach to their guests. Can be
+    /// specified multiple times. Tests needing more VNICs than were supplied
+    /// are skipped.
+    #[clap(long, value_parser)]
+    pub vnic: Vec<String>,
+
     /// The directory into which to write temporary files (config TOMLs, log
     /// files, etc.) generated during test execution.
     #[clap(long, value_parser)]
@@ -8,7 +8,9 @@ mod fixtures;
 
 use clap::Parser;
 use config::{ListOptions, ProcessArgs, RunOptions};
-use phd_tests::phd_testcase::{Framework, FrameworkParameters};
+use phd_tests::phd_testcase::{
+    BasePropolisSource, Framework, FrameworkParameters,
+};
 use tracing::{debug, info, warn};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_subscriber::layer::SubscriberExt;
@@ -44,7 +46,18 @@ fn main() {
 fn run_tests(run_opts: &RunOptions) -> ExecutionStats {
     let ctx_params = FrameworkParameters {
         propolis_server_path: run_opts.propolis_server_cmd.clone(),
+        base_propolis: match (
+            &run_opts.base_propolis_cmd,
+            &run_opts.base_propolis_artifact,
+        ) {
+            (Some(cmd), _) => Some(BasePropolisSource::LocalCmd(cmd.clone())),
+            (None, Some(name)) => {
+                Some(BasePropolisSource::Artifact(name.clone()))
+            }
+            (None, None) => None,
+        },
         crucible_downstairs_cmd: run_opts.crucible_downstairs_cmd.clone(),
+        vnics: run_opts.vnic.clone(),
         tmp_directory: run_opts.tmp_directory.clone(),
         artifact_toml: run_opts.artifact_toml_path.clone(),
         server_log_mode: run_opts.server_logging_mode,
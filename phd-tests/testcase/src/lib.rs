@@ -8,6 +8,7 @@ pub use phd_framework;
 pub use phd_testcase_macros::*;
 use thiserror::Error;
 
+pub use phd_framework::BasePropolisSource;
 pub use phd_framework::Framework;
 pub use phd_framework::FrameworkParameters;
 
@@ -22,10 +22,10 @@ fn shutdown_persistence_test(ctx: &Framework) {
         ctx.vm_config_builder("crucible_shutdown_persistence_test");
     super::add_default_boot_disk(ctx, &mut config)?;
     let mut vm = ctx.spawn_vm(&config, None)?;
-    if vm.guest_os_has_read_only_fs() {
+    if !vm.guest_os_has_persistent_fs() {
         phd_skip!(
-            "Can't run data persistence test on a guest with a read-only file
-             system"
+            "Can't run data persistence test on a guest whose file system
+             doesn't persist to disk"
         );
     }
 
@@ -4,8 +4,11 @@
 
 use std::time::Duration;
 
+use phd_framework::test_vm::pci_config::diff_pci_config;
+use phd_framework::test_vm::EnvironmentSpec;
 use phd_testcase::*;
 use propolis_client::types::MigrationState;
+use tracing::info;
 use uuid::Uuid;
 
 #[phd_testcase]
@@ -25,6 +28,10 @@ fn smoke_test(ctx: &Framework) {
     let serial_hist_pre = source.get_serial_console_history(0)?;
     assert!(!serial_hist_pre.data.is_empty());
 
+    // The guest is idle at its shell prompt, so its PCI configuration should
+    // be unchanged by the time the migration completes.
+    let pci_config_pre = source.get_pci_config()?;
+
     let migration_id = Uuid::new_v4();
     target.migrate_from(&source, migration_id, Duration::from_secs(60))?;
 
@@ -44,6 +51,13 @@ fn smoke_test(ctx: &Framework) {
         serial_hist_pre.last_byte_offset <= serial_hist_post.last_byte_offset
     );
 
+    let pci_diffs = diff_pci_config(&pci_config_pre, &target.get_pci_config()?);
+    assert!(
+        pci_diffs.is_empty(),
+        "PCI config diverged across migration:\n{}",
+        pci_diffs.join("\n")
+    );
+
     let lsout = target.run_shell_command("ls foo.bar")?;
     assert_eq!(lsout, "foo.bar");
 }
@@ -105,3 +119,193 @@ fn multiple_migrations(ctx: &Framework) {
         "I have migrated!"
     );
 }
+
+#[phd_testcase]
+fn migration_under_io_load(ctx: &Framework) {
+    const NIC_A_SLOT: u8 = 8;
+    const NIC_B_SLOT: u8 = 9;
+    // Allow for a guest with a busy disk taking longer to quiesce, but catch
+    // regressions which leave the guest paused for substantially longer.
+    const MAX_DOWNTIME: Duration = Duration::from_secs(10);
+
+    // Network load is carried between two NICs of the guest, each backed by a
+    // host VNIC, so that it passes through the device emulation (and the host
+    // link joining the VNICs) rather than the guest's loopback interface.
+    let [vnic_a, vnic_b, ..] = ctx.vnics() else {
+        phd_skip!("migration under network load needs two VNICs");
+    };
+    let mut source = ctx.spawn_vm(
+        ctx.vm_config_builder("migration_io_load_source")
+            .nic(vnic_a, NIC_A_SLOT)
+            .nic(vnic_b, NIC_B_SLOT),
+        None,
+    )?;
+    if source.guest_os_has_read_only_fs() {
+        phd_skip!("Can't generate disk load on a read-only file system");
+    }
+
+    source.launch()?;
+    source.wait_to_boot()?;
+    let tools = source.run_shell_command(
+        "command -v fio iperf3 ip > /dev/null && echo found",
+    )?;
+    if tools != "found" {
+        phd_skip!("guest OS does not have fio, iperf3 and ip installed");
+    }
+
+    // Move the second NIC into its own network namespace, so that traffic
+    // between the two addresses leaves through one NIC and arrives through
+    // the other instead of being short-circuited by the guest's stack.
+    source.run_shell_command(&format!(
+        "a=$(ls /sys/bus/pci/devices/0000:00:{NIC_A_SLOT:02x}.0/net) && \
+         b=$(ls /sys/bus/pci/devices/0000:00:{NIC_B_SLOT:02x}.0/net) && \
+         ip netns add peer && ip link set $b netns peer && \
+         ip addr add 192.168.240.1/24 dev $a && ip link set $a up && \
+         ip netns exec peer ip addr add 192.168.240.2/24 dev $b && \
+         ip netns exec peer ip link set $b up"
+    ))?;
+    let ping = source.run_shell_command(
+        "ping -c 1 -W 5 192.168.240.2 > /dev/null && echo ok",
+    )?;
+    assert_eq!(ping, "ok", "guest NICs cannot reach each other");
+
+    // Write some random data, which will be copied during the migration and
+    // compared afterwards.
+    source.run_shell_command(
+        "dd if=/dev/urandom of=./migrate_src.dat bs=1M count=64 2> /dev/null",
+    )?;
+    let sum_out = source.run_shell_command("sha256sum migrate_src.dat")?;
+    let checksum = sum_out.split_whitespace().next().unwrap().to_owned();
+
+    // Leave a heartbeat in the guest, so the time for which it was paused can
+    // be measured from its perspective: the guest's clock is adjusted for the
+    // time spent migrating, so the downtime appears as a gap between beats.
+    source.run_shell_command(
+        "(while true; do cut -d' ' -f1 /proc/uptime; sleep 0.1; done) \
+         > /tmp/heartbeat &",
+    )?;
+
+    // Start the load generators.  fio verifies the data it has written as it
+    // goes, and exits with an error on any mismatch or I/O error.
+    source.run_shell_command(
+        "ip netns exec peer iperf3 -s -D > /dev/null 2>&1",
+    )?;
+    source.run_shell_command(
+        "iperf3 -c 192.168.240.2 -t 60 > /tmp/iperf.out 2>&1 & iperf_pid=$!",
+    )?;
+    source.run_shell_command(
+        "fio --name=migrate --filename=./migrate_fio.dat --size=256M \
+         --rw=randwrite --bs=4k --ioengine=psync --direct=1 --time_based \
+         --runtime=60 --verify=crc32c --verify_backlog=1024 \
+         --verify_fatal=1 > /tmp/fio.out 2>&1 & fio_pid=$!",
+    )?;
+    source.run_shell_command(
+        "dd if=./migrate_src.dat of=./migrate_dst.dat bs=64k 2> /dev/null & \
+         dd_pid=$!",
+    )?;
+
+    let mut target =
+        ctx.spawn_successor_vm("migration_io_load_target", &source, None)?;
+    target.migrate_from(&source, Uuid::new_v4(), Duration::from_secs(120))?;
+
+    // Each load generator should finish cleanly on the target.
+    for (name, pid, log) in [
+        ("fio", "fio_pid", "/tmp/fio.out"),
+        ("iperf3", "iperf_pid", "/tmp/iperf.out"),
+    ] {
+        let status =
+            target.run_shell_command(&format!("wait ${}; echo $?", pid))?;
+        let log = target.run_shell_command(&format!("cat {}", log))?;
+        // The shell may report on finished jobs ahead of the status itself
+        assert_eq!(
+            status.lines().last(),
+            Some("0"),
+            "{} failed after migration:\n{}",
+            name,
+            log
+        );
+    }
+    target.run_shell_command("wait $dd_pid")?;
+    target.run_shell_command("sync")?;
+
+    let sum_out = target.run_shell_command("sha256sum migrate_dst.dat")?;
+    assert_eq!(sum_out.split_whitespace().next().unwrap(), checksum);
+
+    // Stop the heartbeat and find the largest gap between beats.
+    target.run_shell_command("kill %1")?;
+    let beats = target.run_shell_command("cat /tmp/heartbeat")?;
+    let beats: Vec<f64> =
+        beats.lines().filter_map(|l| l.trim().parse().ok()).collect();
+    assert!(beats.len() > 1, "heartbeat was not recorded");
+    let max_gap = beats.windows(2).map(|w| w[1] - w[0]).fold(0.0_f64, f64::max);
+    let downtime = Duration::from_secs_f64(max_gap);
+    info!(?downtime, "measured guest downtime during migration");
+    assert!(
+        downtime < MAX_DOWNTIME,
+        "guest was paused for {:?} (limit {:?})",
+        downtime,
+        MAX_DOWNTIME
+    );
+}
+
+/// Boots a VM on the server from `source_env`, writes a file in the guest,
+/// migrates it to a VM on the server from `target_env`, and checks that the
+/// guest and its file survived the trip.
+fn migrate_between_versions(
+    ctx: &Framework,
+    name: &str,
+    source_env: &EnvironmentSpec,
+    target_env: &EnvironmentSpec,
+) -> phd_testcase::Result<()> {
+    let mut source = ctx.spawn_vm(
+        &ctx.vm_config_builder(&format!("{}_source", name)),
+        Some(source_env),
+    )?;
+    source.launch()?;
+    source.wait_to_boot()?;
+    source.run_shell_command("touch ./foo.bar")?;
+    source.run_shell_command("sync ./foo.bar")?;
+
+    let mut target = ctx.spawn_successor_vm(
+        &format!("{}_target", name),
+        &source,
+        Some(target_env),
+    )?;
+    let migration_id = Uuid::new_v4();
+    target.migrate_from(&source, migration_id, Duration::from_secs(60))?;
+    assert_eq!(
+        target.get_migration_state(migration_id)?,
+        MigrationState::Finish
+    );
+
+    let lsout = target.run_shell_command("ls foo.bar")?;
+    assert_eq!(lsout, "foo.bar");
+    assert_eq!(target.run_shell_command("echo Hello world")?, "Hello world");
+    Ok(())
+}
+
+#[phd_testcase]
+fn from_base(ctx: &Framework) {
+    let Some(base_env) = ctx.base_environment_builder() else {
+        phd_skip!("No base Propolis server supplied");
+    };
+    migrate_between_versions(
+        ctx,
+        "migration_from_base",
+        &base_env,
+        &ctx.environment_builder(),
+    )?;
+}
+
+#[phd_testcase]
+fn to_base(ctx: &Framework) {
+    let Some(base_env) = ctx.base_environment_builder() else {
+        phd_skip!("No base Propolis server supplied");
+    };
+    migrate_between_versions(
+        ctx,
+        "migration_to_base",
+        &ctx.environment_builder(),
+        &base_env,
+    )?;
+}